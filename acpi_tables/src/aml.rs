@@ -587,6 +587,42 @@ impl Aml for Interrupt {
     }
 }
 
+pub struct Register {
+    space_id: u8,
+    bit_width: u8,
+    bit_offset: u8,
+    access_size: u8,
+    address: u64,
+}
+
+impl Register {
+    pub fn new(space_id: u8, bit_width: u8, bit_offset: u8, access_size: u8, address: u64) -> Self {
+        Register {
+            space_id,
+            bit_width,
+            bit_offset,
+            access_size,
+            address,
+        }
+    }
+}
+
+impl Aml for Register {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.push(0x82); /* Generic Register Descriptor */
+        bytes.append(&mut 12u16.to_le_bytes().to_vec());
+        bytes.push(self.space_id);
+        bytes.push(self.bit_width);
+        bytes.push(self.bit_offset);
+        bytes.push(self.access_size);
+        bytes.append(&mut self.address.to_le_bytes().to_vec());
+
+        bytes
+    }
+}
+
 pub struct Device<'a> {
     path: Path,
     children: Vec<&'a dyn Aml>,
@@ -1482,6 +1518,26 @@ mod tests {
             .to_aml_bytes(),
             &interrupt_io_data[..]
         );
+
+        /*
+        ResourceTemplate ()
+        {
+            Register (FFixedHW,
+                0x00,               // Bit Width
+                0x00,               // Bit Offset
+                0x0000000000000000, // Address
+                ,)
+        }
+        */
+        let register_data = [
+            0x11, 0x14, 0x0A, 0x11, 0x82, 0x0C, 0x00, 0x7F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x79, 0x00,
+        ];
+
+        assert_eq!(
+            ResourceTemplate::new(vec![&Register::new(0x7f, 0, 0, 0, 0)]).to_aml_bytes(),
+            &register_data[..]
+        );
     }
 
     #[test]
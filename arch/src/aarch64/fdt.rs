@@ -15,6 +15,7 @@ use std::{io, result};
 
 use super::super::DeviceType;
 use super::super::InitramfsConfig;
+use super::super::{numa_distance, numa_node_of_cpu, NumaNodes};
 use super::get_fdt_addr;
 use super::gic::GICDevice;
 use super::layout::{
@@ -30,6 +31,8 @@ const GIC_PHANDLE: u32 = 1;
 const MSI_PHANDLE: u32 = 2;
 // This is a value for uniquely identifying the FDT node containing the clock definition.
 const CLOCK_PHANDLE: u32 = 3;
+// The vCPU nodes are identified by the values starting from this one.
+const FIRST_VCPU_PHANDLE: u32 = 8;
 
 // Read the documentation specified when appending the root node to the FDT.
 const ADDRESS_CELLS: u32 = 0x2;
@@ -94,6 +97,8 @@ pub fn create_fdt<T: DeviceInfoForFDT + Clone + Debug>(
     guest_mem: &GuestMemoryMmap,
     cmdline: &CStr,
     vcpu_mpidr: Vec<u64>,
+    vcpu_topology: Option<(u8, u8, u8)>,
+    numa_nodes: &NumaNodes,
     device_info: &HashMap<(DeviceType, String), T>,
     gic_device: &Box<dyn GICDevice>,
     initrd: &Option<InitramfsConfig>,
@@ -118,8 +123,11 @@ pub fn create_fdt<T: DeviceInfoForFDT + Clone + Debug>(
     // This is not mandatory but we use it to point the root node to the node
     // containing description of the interrupt controller for this VM.
     append_property_u32(&mut fdt, "interrupt-parent", GIC_PHANDLE)?;
-    create_cpu_nodes(&mut fdt, &vcpu_mpidr)?;
-    create_memory_node(&mut fdt, guest_mem)?;
+    create_cpu_nodes(&mut fdt, &vcpu_mpidr, vcpu_topology, numa_nodes)?;
+    create_memory_node(&mut fdt, guest_mem, numa_nodes)?;
+    if numa_nodes.len() > 1 {
+        create_distance_map_node(&mut fdt, numa_nodes)?;
+    }
     create_chosen_node(&mut fdt, cmdline, initrd)?;
     create_gic_node(&mut fdt, gic_device)?;
     create_timer_node(&mut fdt)?;
@@ -311,7 +319,12 @@ fn generate_prop64(cells: &[u64]) -> Vec<u8> {
 }
 
 // Following are the auxiliary function for creating the different nodes that we append to our FDT.
-fn create_cpu_nodes(fdt: &mut Vec<u8>, vcpu_mpidr: &Vec<u64>) -> Result<()> {
+fn create_cpu_nodes(
+    fdt: &mut Vec<u8>,
+    vcpu_mpidr: &Vec<u64>,
+    vcpu_topology: Option<(u8, u8, u8)>,
+    numa_nodes: &NumaNodes,
+) -> Result<()> {
     // See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/arm/cpus.yaml.
     append_begin_node(fdt, "cpus")?;
     // As per documentation, on ARM v8 64-bit systems value should be set to 2.
@@ -331,13 +344,73 @@ fn create_cpu_nodes(fdt: &mut Vec<u8>, vcpu_mpidr: &Vec<u64>) -> Result<()> {
         // Set the field to first 24 bits of the MPIDR - Multiprocessor Affinity Register.
         // See http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0488c/BABHBJCI.html.
         append_property_u64(fdt, "reg", vcpu_mpidr[cpu_index] & 0x7FFFFF)?;
+        append_property_u32(fdt, "phandle", FIRST_VCPU_PHANDLE + cpu_index as u32)?;
+        if !numa_nodes.is_empty() {
+            append_property_u32(
+                fdt,
+                "numa-node-id",
+                numa_node_of_cpu(numa_nodes, cpu_index as u8),
+            )?;
+        }
         append_end_node(fdt)?;
     }
+
+    if let Some(topology) = vcpu_topology {
+        create_cpu_map_node(fdt, num_cpus, topology)?;
+    }
+
     append_end_node(fdt)?;
     Ok(())
 }
 
-fn create_memory_node(fdt: &mut Vec<u8>, guest_mem: &GuestMemoryMmap) -> Result<()> {
+// See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/cpu/cpu-topology.txt.
+// Each die becomes a cluster, as not every kernel knows about the socket
+// nodes, and the clusters of all the packages are laid out one after the
+// other.
+fn create_cpu_map_node(
+    fdt: &mut Vec<u8>,
+    num_cpus: usize,
+    (threads_per_core, cores_per_die, _): (u8, u8, u8),
+) -> Result<()> {
+    let threads_per_core = usize::from(threads_per_core);
+    let threads_per_die = threads_per_core * usize::from(cores_per_die);
+
+    append_begin_node(fdt, "cpu-map")?;
+    for die in 0..(num_cpus + threads_per_die - 1) / threads_per_die {
+        append_begin_node(fdt, &format!("cluster{}", die))?;
+        for core in 0..usize::from(cores_per_die) {
+            let first_cpu = die * threads_per_die + core * threads_per_core;
+            if first_cpu >= num_cpus {
+                break;
+            }
+            append_begin_node(fdt, &format!("core{}", core))?;
+            if threads_per_core == 1 {
+                append_property_u32(fdt, "cpu", FIRST_VCPU_PHANDLE + first_cpu as u32)?;
+            } else {
+                for thread in 0..threads_per_core {
+                    let cpu = first_cpu + thread;
+                    if cpu >= num_cpus {
+                        break;
+                    }
+                    append_begin_node(fdt, &format!("thread{}", thread))?;
+                    append_property_u32(fdt, "cpu", FIRST_VCPU_PHANDLE + cpu as u32)?;
+                    append_end_node(fdt)?;
+                }
+            }
+            append_end_node(fdt)?;
+        }
+        append_end_node(fdt)?;
+    }
+    append_end_node(fdt)?;
+
+    Ok(())
+}
+
+fn create_memory_node(
+    fdt: &mut Vec<u8>,
+    guest_mem: &GuestMemoryMmap,
+    numa_nodes: &NumaNodes,
+) -> Result<()> {
     let mem_size = guest_mem.last_addr().raw_value() - super::layout::RAM_64BIT_START + 1;
     // See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/booting-without-of.txt#L960
     // for an explanation of this.
@@ -346,6 +419,34 @@ fn create_memory_node(fdt: &mut Vec<u8>, guest_mem: &GuestMemoryMmap) -> Result<
     append_begin_node(fdt, "memory")?;
     append_property_string(fdt, "device_type", "memory")?;
     append_property(fdt, "reg", &mem_reg_prop)?;
+    // Once a NUMA node is described, the guest expects every memory node to
+    // belong to one. All the memory belongs to the node 0, the other nodes
+    // having vCPUs only.
+    if !numa_nodes.is_empty() {
+        append_property_u32(fdt, "numa-node-id", 0)?;
+    }
+    append_end_node(fdt)?;
+    Ok(())
+}
+
+// See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/numa.txt.
+// This is the counterpart of the ACPI SLIT, the matrix listing the distance
+// between each pair of nodes, including a node and itself.
+fn create_distance_map_node(fdt: &mut Vec<u8>, numa_nodes: &NumaNodes) -> Result<()> {
+    let mut matrix = Vec::new();
+    for from in numa_nodes.keys() {
+        for to in numa_nodes.keys() {
+            matrix.extend_from_slice(&[
+                *from,
+                *to,
+                u32::from(numa_distance(numa_nodes, *from, *to)),
+            ]);
+        }
+    }
+
+    append_begin_node(fdt, "distance-map")?;
+    append_property_string(fdt, "compatible", "numa-distance-map-v1")?;
+    append_property(fdt, "distance-matrix", &generate_prop32(&matrix))?;
     append_end_node(fdt)?;
     Ok(())
 }
@@ -593,3 +694,55 @@ fn create_pci_nodes(fdt: &mut Vec<u8>, pci_device_base: u64, pci_device_size: u6
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NumaNode;
+    use std::collections::BTreeMap;
+
+    fn contains(fdt: &[u8], bytes: &[u8]) -> bool {
+        fdt.windows(bytes.len()).any(|w| w == bytes)
+    }
+
+    fn distance_map(numa_nodes: &NumaNodes) -> Vec<u8> {
+        let mut fdt = vec![0; FDT_MAX_SIZE];
+        allocate_fdt(&mut fdt).unwrap();
+        create_distance_map_node(&mut fdt, numa_nodes).unwrap();
+        fdt
+    }
+
+    #[test]
+    fn test_distance_map_node() {
+        let mut numa_nodes = NumaNodes::new();
+        for (id, cpus) in [(0, vec![0, 1]), (1, vec![2, 3])].iter() {
+            let mut distances = BTreeMap::new();
+            distances.insert(1 - *id, 30);
+            numa_nodes.insert(
+                *id,
+                NumaNode {
+                    cpus: cpus.clone(),
+                    distances,
+                },
+            );
+        }
+
+        let fdt = distance_map(&numa_nodes);
+        assert!(contains(&fdt, b"distance-map\0"));
+        assert!(contains(&fdt, b"numa-distance-map-v1\0"));
+        assert!(contains(
+            &fdt,
+            &generate_prop32(&[0, 0, 10, 0, 1, 30, 1, 0, 30, 1, 1, 10])
+        ));
+
+        // Without any distance, the nodes are the default distance apart.
+        for node in numa_nodes.values_mut() {
+            node.distances.clear();
+        }
+        let fdt = distance_map(&numa_nodes);
+        assert!(contains(
+            &fdt,
+            &generate_prop32(&[0, 0, 10, 0, 1, 20, 1, 0, 20, 1, 1, 10])
+        ));
+    }
+}
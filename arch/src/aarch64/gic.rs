@@ -150,6 +150,13 @@ pub mod kvm {
         }
     }
 
+    /// Guest physical range, inclusive, of the GICv3-ITS translation
+    /// registers the MSIs are written to.
+    pub fn its_doorbell_range(vcpu_count: u64) -> (u64, u64) {
+        let start = KvmGICv3ITS::get_msi_addr(vcpu_count);
+        (start, start + KvmGICv3ITS::get_msi_size() - 1)
+    }
+
     /// Create a GIC device.
     ///
     /// It will try to create by default a GICv3 device. If that fails it will try
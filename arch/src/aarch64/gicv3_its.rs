@@ -27,11 +27,11 @@ pub mod kvm {
     impl KvmGICv3ITS {
         const KVM_VGIC_V3_ITS_SIZE: u64 = (2 * KvmGICv3::SZ_64K);
 
-        fn get_msi_size() -> u64 {
+        pub(crate) fn get_msi_size() -> u64 {
             KvmGICv3ITS::KVM_VGIC_V3_ITS_SIZE
         }
 
-        fn get_msi_addr(vcpu_count: u64) -> u64 {
+        pub(crate) fn get_msi_addr(vcpu_count: u64) -> u64 {
             KvmGICv3::get_redists_addr(vcpu_count) - KvmGICv3ITS::get_msi_size()
         }
     }
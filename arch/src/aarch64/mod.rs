@@ -142,6 +142,8 @@ pub fn configure_system<T: DeviceInfoForFDT + Clone + Debug>(
     cmdline_cstring: &CStr,
     vcpu_count: u64,
     vcpu_mpidr: Vec<u64>,
+    vcpu_topology: Option<(u8, u8, u8)>,
+    numa_nodes: &super::NumaNodes,
     device_info: &HashMap<(DeviceType, String), T>,
     initrd: &Option<super::InitramfsConfig>,
     pci_space_address: &Option<(u64, u64)>,
@@ -156,6 +158,8 @@ pub fn configure_system<T: DeviceInfoForFDT + Clone + Debug>(
         guest_mem,
         cmdline_cstring,
         vcpu_mpidr,
+        vcpu_topology,
+        numa_nodes,
         device_info,
         &gic_device,
         initrd,
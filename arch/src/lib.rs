@@ -25,6 +25,7 @@ extern crate arch_gen;
 extern crate linux_loader;
 extern crate vm_memory;
 
+use std::collections::BTreeMap;
 use std::fmt;
 use std::result;
 
@@ -116,6 +117,46 @@ pub struct InitramfsConfig {
     pub size: usize,
 }
 
+/// Distance from a NUMA node to itself.
+pub const NUMA_LOCAL_DISTANCE: u8 = 10;
+/// Distance between two NUMA nodes assumed by the guest when not given.
+pub const NUMA_REMOTE_DISTANCE: u8 = 20;
+
+/// Guest NUMA node, as described to the guest along with the vCPUs and the
+/// memory it holds.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NumaNode {
+    /// vCPUs belonging to the node.
+    pub cpus: Vec<u8>,
+    /// Distances from the node to the other ones.
+    pub distances: BTreeMap<u32, u8>,
+}
+
+/// Guest NUMA nodes, by identifier.
+pub type NumaNodes = BTreeMap<u32, NumaNode>;
+
+/// Distance from the NUMA node `from` to the node `to`.
+pub fn numa_distance(numa_nodes: &NumaNodes, from: u32, to: u32) -> u8 {
+    if from == to {
+        return NUMA_LOCAL_DISTANCE;
+    }
+
+    numa_nodes
+        .get(&from)
+        .and_then(|node| node.distances.get(&to))
+        .copied()
+        .unwrap_or(NUMA_REMOTE_DISTANCE)
+}
+
+/// Guest NUMA node holding the vCPU `cpu`, the vCPUs not assigned to any
+/// node belonging to the node 0.
+pub fn numa_node_of_cpu(numa_nodes: &NumaNodes, cpu: u8) -> u32 {
+    numa_nodes
+        .iter()
+        .find(|(_, node)| node.cpus.contains(&cpu))
+        .map_or(0, |(id, _)| *id)
+}
+
 /// Types of devices that can get attached to this platform.
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Copy)]
 pub enum DeviceType {
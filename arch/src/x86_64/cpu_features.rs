@@ -0,0 +1,576 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Masking of the CPU features exposed to the guest.
+//!
+//! The features are named after the flags of `/proc/cpuinfo`. They can be
+//! hidden one by one, or by selecting a baseline model, in which case every
+//! known feature not part of the model is hidden. Bits with no name here are
+//! left untouched.
+//!
+//! The feature registers exposed to a guest also get compared, bit by bit,
+//! with the ones of the host it's restored on, the guest having no way to
+//! tell it lost a feature it found at boot time.
+
+use super::CpuidReg::{self, EAX, EBX, ECX, EDX};
+use hypervisor::{CpuId, CpuIdEntry};
+use std::fmt;
+
+pub struct CpuFeature {
+    pub name: &'static str,
+    pub function: u32,
+    pub index: u32,
+    pub reg: CpuidReg,
+    pub bit: u8,
+    // The feature can't be used without XSAVE.
+    xsave: bool,
+}
+
+const fn feature(
+    name: &'static str,
+    function: u32,
+    index: u32,
+    reg: CpuidReg,
+    bit: u8,
+    xsave: bool,
+) -> CpuFeature {
+    CpuFeature {
+        name,
+        function,
+        index,
+        reg,
+        bit,
+        xsave,
+    }
+}
+
+const FEATURES: &[CpuFeature] = &[
+    feature("fpu", 0x1, 0, EDX, 0, false),
+    feature("tsc", 0x1, 0, EDX, 4, false),
+    feature("cx8", 0x1, 0, EDX, 8, false),
+    feature("apic", 0x1, 0, EDX, 9, false),
+    feature("sse", 0x1, 0, EDX, 25, false),
+    feature("sse2", 0x1, 0, EDX, 26, false),
+    feature("pni", 0x1, 0, ECX, 0, false),
+    feature("pclmulqdq", 0x1, 0, ECX, 1, false),
+    feature("ssse3", 0x1, 0, ECX, 9, false),
+    feature("fma", 0x1, 0, ECX, 12, true),
+    feature("cx16", 0x1, 0, ECX, 13, false),
+    feature("pcid", 0x1, 0, ECX, 17, false),
+    feature("sse4_1", 0x1, 0, ECX, 19, false),
+    feature("sse4_2", 0x1, 0, ECX, 20, false),
+    feature("x2apic", 0x1, 0, ECX, 21, false),
+    feature("movbe", 0x1, 0, ECX, 22, false),
+    feature("popcnt", 0x1, 0, ECX, 23, false),
+    feature("tsc_deadline_timer", 0x1, 0, ECX, 24, false),
+    feature("aes", 0x1, 0, ECX, 25, false),
+    feature("xsave", 0x1, 0, ECX, 26, false),
+    feature("avx", 0x1, 0, ECX, 28, true),
+    feature("f16c", 0x1, 0, ECX, 29, true),
+    feature("rdrand", 0x1, 0, ECX, 30, false),
+    feature("hypervisor", 0x1, 0, ECX, 31, false),
+    feature("fsgsbase", 0x7, 0, EBX, 0, false),
+    feature("bmi1", 0x7, 0, EBX, 3, false),
+    feature("hle", 0x7, 0, EBX, 4, false),
+    feature("avx2", 0x7, 0, EBX, 5, true),
+    feature("smep", 0x7, 0, EBX, 7, false),
+    feature("bmi2", 0x7, 0, EBX, 8, false),
+    feature("erms", 0x7, 0, EBX, 9, false),
+    feature("invpcid", 0x7, 0, EBX, 10, false),
+    feature("rtm", 0x7, 0, EBX, 11, false),
+    feature("mpx", 0x7, 0, EBX, 14, true),
+    feature("avx512f", 0x7, 0, EBX, 16, true),
+    feature("avx512dq", 0x7, 0, EBX, 17, true),
+    feature("rdseed", 0x7, 0, EBX, 18, false),
+    feature("adx", 0x7, 0, EBX, 19, false),
+    feature("smap", 0x7, 0, EBX, 20, false),
+    feature("avx512ifma", 0x7, 0, EBX, 21, true),
+    feature("clflushopt", 0x7, 0, EBX, 23, false),
+    feature("clwb", 0x7, 0, EBX, 24, false),
+    feature("avx512cd", 0x7, 0, EBX, 28, true),
+    feature("sha_ni", 0x7, 0, EBX, 29, false),
+    feature("avx512bw", 0x7, 0, EBX, 30, true),
+    feature("avx512vl", 0x7, 0, EBX, 31, true),
+    feature("avx512vbmi", 0x7, 0, ECX, 1, true),
+    feature("umip", 0x7, 0, ECX, 2, false),
+    feature("pku", 0x7, 0, ECX, 3, true),
+    feature("waitpkg", 0x7, 0, ECX, 5, false),
+    feature("avx512_vbmi2", 0x7, 0, ECX, 6, true),
+    feature("gfni", 0x7, 0, ECX, 8, false),
+    feature("vaes", 0x7, 0, ECX, 9, true),
+    feature("vpclmulqdq", 0x7, 0, ECX, 10, true),
+    feature("avx512_vnni", 0x7, 0, ECX, 11, true),
+    feature("avx512_bitalg", 0x7, 0, ECX, 12, true),
+    feature("avx512_vpopcntdq", 0x7, 0, ECX, 14, true),
+    feature("la57", 0x7, 0, ECX, 16, false),
+    feature("rdpid", 0x7, 0, ECX, 22, false),
+    feature("movdiri", 0x7, 0, ECX, 27, false),
+    feature("movdir64b", 0x7, 0, ECX, 28, false),
+    feature("avx512_4vnniw", 0x7, 0, EDX, 2, true),
+    feature("avx512_4fmaps", 0x7, 0, EDX, 3, true),
+    feature("md_clear", 0x7, 0, EDX, 10, false),
+    feature("serialize", 0x7, 0, EDX, 14, false),
+    feature("tsxldtrk", 0x7, 0, EDX, 16, false),
+    feature("amx_bf16", 0x7, 0, EDX, 22, true),
+    feature("avx512_fp16", 0x7, 0, EDX, 23, true),
+    feature("amx_tile", 0x7, 0, EDX, 24, true),
+    feature("amx_int8", 0x7, 0, EDX, 25, true),
+    feature("xsaveopt", 0xd, 1, EAX, 0, true),
+    feature("xsavec", 0xd, 1, EAX, 1, true),
+    feature("xgetbv1", 0xd, 1, EAX, 2, true),
+    feature("xsaves", 0xd, 1, EAX, 3, true),
+    feature("lahf_lm", 0x8000_0001, 0, ECX, 0, false),
+    feature("abm", 0x8000_0001, 0, ECX, 5, false),
+    feature("3dnowprefetch", 0x8000_0001, 0, ECX, 8, false),
+    feature("nx", 0x8000_0001, 0, EDX, 20, false),
+    feature("pdpe1gb", 0x8000_0001, 0, EDX, 26, false),
+    feature("rdtscp", 0x8000_0001, 0, EDX, 27, false),
+    feature("lm", 0x8000_0001, 0, EDX, 29, false),
+];
+
+// Registers of the CPUID leaves made of feature bits only.
+const FEATURE_REGISTERS: &[(u32, u32, CpuidReg)] = &[
+    (0x1, 0, ECX),
+    (0x1, 0, EDX),
+    (0x7, 0, EBX),
+    (0x7, 0, ECX),
+    (0x7, 0, EDX),
+    (0x7, 1, EAX),
+    (0xd, 1, EAX),
+    (0x8000_0001, 0, ECX),
+    (0x8000_0001, 0, EDX),
+    (0x8000_0008, 0, EBX),
+];
+
+// Bits of the feature registers following the control registers of the
+// guest, OSXSAVE and OSPKE, rather than what the host supports.
+const DYNAMIC_BITS: &[(u32, u32, CpuidReg, u8)] = &[(0x1, 0, ECX, 27), (0x7, 0, ECX, 4)];
+
+// Features cloud-hypervisor relies on: the guest runs in long mode, and the
+// LAPIC TSC deadline timer and the paravirtualized features are its only
+// timers.
+const REQUIRED_FEATURES: &[&str] = &["lm", "apic", "tsc_deadline_timer", "hypervisor"];
+
+const SANDYBRIDGE: &[&str] = &[
+    "fpu",
+    "tsc",
+    "cx8",
+    "apic",
+    "sse",
+    "sse2",
+    "pni",
+    "pclmulqdq",
+    "ssse3",
+    "cx16",
+    "pcid",
+    "sse4_1",
+    "sse4_2",
+    "x2apic",
+    "popcnt",
+    "tsc_deadline_timer",
+    "aes",
+    "xsave",
+    "avx",
+    "hypervisor",
+    "xsaveopt",
+    "lahf_lm",
+    "nx",
+    "rdtscp",
+    "lm",
+];
+
+const HASWELL: &[&str] = &[
+    "fma", "movbe", "f16c", "rdrand", "fsgsbase", "bmi1", "avx2", "smep", "bmi2", "erms",
+    "invpcid", "abm", "pdpe1gb",
+];
+
+const SKYLAKE_SERVER: &[&str] = &[
+    "rdseed",
+    "adx",
+    "smap",
+    "clflushopt",
+    "clwb",
+    "avx512f",
+    "avx512dq",
+    "avx512cd",
+    "avx512bw",
+    "avx512vl",
+    "pku",
+    "xsavec",
+    "xgetbv1",
+    "xsaves",
+    "3dnowprefetch",
+];
+
+const ICELAKE_SERVER: &[&str] = &[
+    "avx512ifma",
+    "sha_ni",
+    "avx512vbmi",
+    "umip",
+    "avx512_vbmi2",
+    "gfni",
+    "vaes",
+    "vpclmulqdq",
+    "avx512_vnni",
+    "avx512_bitalg",
+    "avx512_vpopcntdq",
+    "la57",
+    "rdpid",
+    "md_clear",
+];
+
+// Baseline models, each one made of the features of the previous one and a
+// few more. TSX is never part of them, as it gets disabled on many hosts.
+const MODELS: &[(&str, &[&[&str]])] = &[
+    ("sandybridge", &[SANDYBRIDGE]),
+    ("haswell", &[SANDYBRIDGE, HASWELL]),
+    ("skylake-server", &[SANDYBRIDGE, HASWELL, SKYLAKE_SERVER]),
+    (
+        "icelake-server",
+        &[SANDYBRIDGE, HASWELL, SKYLAKE_SERVER, ICELAKE_SERVER],
+    ),
+];
+
+#[derive(Debug)]
+pub enum Error {
+    /// Unknown feature, or feature not prefixed with '+' or '-'.
+    UnknownFeature(String),
+    /// Unknown baseline model.
+    UnknownModel(String),
+    /// The feature can't be hidden from the guest.
+    RequiredFeature(&'static str),
+    /// XSAVE is hidden while the feature relying on it isn't.
+    XsaveRequired(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            UnknownFeature(s) => write!(f, "Unknown CPU feature {}", s),
+            UnknownModel(s) => write!(f, "Unknown CPU model {}", s),
+            RequiredFeature(s) => write!(f, "CPU feature {} can't be hidden", s),
+            XsaveRequired(s) => write!(f, "CPU feature {} can't be exposed without xsave", s),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub fn find_feature(name: &str) -> Option<&'static CpuFeature> {
+    FEATURES.iter().find(|f| f.name == name)
+}
+
+/// Features hidden by `model`, along with the `features` prefixed with '-'
+/// and without those prefixed with '+'. The features are applied in order,
+/// after the model.
+pub fn masked_features(
+    model: Option<&str>,
+    features: &[String],
+) -> Result<Vec<&'static CpuFeature>> {
+    let mut masked: Vec<&'static CpuFeature> = Vec::new();
+
+    if let Some(model) = model {
+        let (_, parts) = MODELS
+            .iter()
+            .find(|(name, _)| *name == model)
+            .ok_or_else(|| Error::UnknownModel(model.to_owned()))?;
+        for feature in FEATURES {
+            if !parts.iter().any(|p| p.contains(&feature.name))
+                && !REQUIRED_FEATURES.contains(&feature.name)
+            {
+                masked.push(feature);
+            }
+        }
+    }
+
+    for toggle in features {
+        let unknown = || Error::UnknownFeature(toggle.clone());
+        let (enabled, name) = match toggle.chars().next() {
+            Some('+') => (true, &toggle[1..]),
+            Some('-') => (false, &toggle[1..]),
+            _ => return Err(unknown()),
+        };
+        let feature = find_feature(name).ok_or_else(unknown)?;
+        masked.retain(|f| f.name != feature.name);
+        if !enabled {
+            if REQUIRED_FEATURES.contains(&feature.name) {
+                return Err(Error::RequiredFeature(feature.name));
+            }
+            masked.push(feature);
+        }
+    }
+
+    if masked.iter().any(|f| f.name == "xsave") {
+        if let Some(feature) = FEATURES
+            .iter()
+            .find(|f| f.xsave && !masked.iter().any(|m| m.name == f.name))
+        {
+            return Err(Error::XsaveRequired(feature.name));
+        }
+    }
+
+    Ok(masked)
+}
+
+fn reg_value(entry: &CpuIdEntry, reg: CpuidReg) -> u32 {
+    match reg {
+        CpuidReg::EAX => entry.eax,
+        CpuidReg::EBX => entry.ebx,
+        CpuidReg::ECX => entry.ecx,
+        CpuidReg::EDX => entry.edx,
+    }
+}
+
+/// Clear the bits of the `features` from `cpuid`.
+pub fn mask_cpuid(cpuid: &mut CpuId, features: &[&CpuFeature]) {
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        for feature in features {
+            if entry.function == feature.function && entry.index == feature.index {
+                let mask = !(1u32 << feature.bit);
+                match feature.reg {
+                    CpuidReg::EAX => entry.eax &= mask,
+                    CpuidReg::EBX => entry.ebx &= mask,
+                    CpuidReg::ECX => entry.ecx &= mask,
+                    CpuidReg::EDX => entry.edx &= mask,
+                }
+            }
+        }
+    }
+}
+
+fn reg_name(reg: CpuidReg) -> &'static str {
+    match reg {
+        CpuidReg::EAX => "eax",
+        CpuidReg::EBX => "ebx",
+        CpuidReg::ECX => "ecx",
+        CpuidReg::EDX => "edx",
+    }
+}
+
+// Value of a register of `entries`, the registers of the missing leaves
+// holding no feature.
+fn find_reg(entries: &[CpuIdEntry], function: u32, index: u32, reg: CpuidReg) -> u32 {
+    entries
+        .iter()
+        .find(|e| e.function == function && e.index == index)
+        .map_or(0, |e| reg_value(e, reg))
+}
+
+/// The leaves of `cpuid` holding feature bits, with only their feature
+/// registers set.
+pub fn feature_leaves(cpuid: &CpuId) -> Vec<CpuIdEntry> {
+    let mut leaves: Vec<CpuIdEntry> = Vec::new();
+    for (function, index, reg) in FEATURE_REGISTERS.iter().copied() {
+        let value = find_reg(cpuid.as_slice(), function, index, reg);
+        let pos = match leaves
+            .iter()
+            .position(|e| e.function == function && e.index == index)
+        {
+            Some(pos) => pos,
+            None => {
+                leaves.push(CpuIdEntry {
+                    function,
+                    index,
+                    ..Default::default()
+                });
+                leaves.len() - 1
+            }
+        };
+        let leaf = &mut leaves[pos];
+        match reg {
+            CpuidReg::EAX => leaf.eax = value,
+            CpuidReg::EBX => leaf.ebx = value,
+            CpuidReg::ECX => leaf.ecx = value,
+            CpuidReg::EDX => leaf.edx = value,
+        }
+    }
+
+    leaves
+}
+
+/// Features set in the feature `leaves` exposed to a guest which `cpuid`
+/// lacks. The known features are given by name, the other ones as their
+/// CPUID leaf, register and bit, such as "cpuid 0x7.0 ebx bit 13".
+pub fn missing_features(leaves: &[CpuIdEntry], cpuid: &CpuId) -> Vec<String> {
+    let mut missing = Vec::new();
+    for (function, index, reg) in FEATURE_REGISTERS.iter().copied() {
+        let lost = find_reg(leaves, function, index, reg)
+            & !find_reg(cpuid.as_slice(), function, index, reg);
+        for bit in (0..32u8).filter(|bit| lost & (1 << bit) != 0) {
+            let same_bit =
+                |f: u32, i: u32, r: CpuidReg, b: u8| (f, i, r, b) == (function, index, reg, bit);
+            if DYNAMIC_BITS.iter().any(|d| same_bit(d.0, d.1, d.2, d.3)) {
+                continue;
+            }
+            match FEATURES
+                .iter()
+                .find(|f| same_bit(f.function, f.index, f.reg, f.bit))
+            {
+                Some(feature) => missing.push(feature.name.to_string()),
+                None => missing.push(format!(
+                    "cpuid {:#x}.{} {} bit {}",
+                    function,
+                    index,
+                    reg_name(reg),
+                    bit
+                )),
+            }
+        }
+    }
+
+    missing
+}
+
+/// Clear the feature bits of `cpuid` which aren't set in the feature
+/// `leaves` exposed to a guest, named or not.
+pub fn restrict_cpuid(cpuid: &mut CpuId, leaves: &[CpuIdEntry]) {
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        for (function, index, reg) in FEATURE_REGISTERS.iter().copied() {
+            if entry.function == function && entry.index == index {
+                let mask = find_reg(leaves, function, index, reg);
+                match reg {
+                    CpuidReg::EAX => entry.eax &= mask,
+                    CpuidReg::EBX => entry.ebx &= mask,
+                    CpuidReg::ECX => entry.ecx &= mask,
+                    CpuidReg::EDX => entry.edx &= mask,
+                }
+            }
+        }
+    }
+}
+
+/// Names of the known features exposed by `cpuid`.
+pub fn enabled_features(cpuid: &CpuId) -> Vec<&'static str> {
+    FEATURES
+        .iter()
+        .filter(|feature| {
+            cpuid.as_slice().iter().any(|entry| {
+                entry.function == feature.function
+                    && entry.index == feature.index
+                    && reg_value(entry, feature.reg) & (1 << feature.bit) != 0
+            })
+        })
+        .map(|feature| feature.name)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(features: &[&CpuFeature]) -> Vec<&'static str> {
+        features.iter().map(|f| f.name).collect()
+    }
+
+    fn toggles(toggles: &[&str]) -> Vec<String> {
+        toggles.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_masked_features() {
+        assert_eq!(
+            names(&masked_features(None, &toggles(&["-avx512f", "-waitpkg"])).unwrap()),
+            vec!["avx512f", "waitpkg"]
+        );
+
+        // A model hides everything it doesn't know about, unless asked for.
+        let masked = masked_features(Some("haswell"), &toggles(&["+rdseed"])).unwrap();
+        assert!(names(&masked).contains(&"avx512f"));
+        assert!(!names(&masked).contains(&"avx2"));
+        assert!(!names(&masked).contains(&"rdseed"));
+        assert!(!names(&masked).contains(&"hypervisor"));
+
+        assert!(masked_features(Some("pentium"), &[]).is_err());
+        assert!(masked_features(None, &toggles(&["-foo"])).is_err());
+        assert!(masked_features(None, &toggles(&["avx"])).is_err());
+        assert!(masked_features(None, &toggles(&["-tsc_deadline_timer"])).is_err());
+
+        // XSAVE can only go along with the features relying on it.
+        assert!(masked_features(None, &toggles(&["-xsave"])).is_err());
+        let mut xsave = vec!["-xsave".to_string()];
+        xsave.extend(
+            FEATURES
+                .iter()
+                .filter(|f| f.xsave)
+                .map(|f| format!("-{}", f.name)),
+        );
+        assert!(masked_features(None, &xsave).is_ok());
+    }
+
+    #[test]
+    fn test_mask_cpuid() {
+        let mut cpuid = CpuId::new(2);
+        cpuid.as_mut_slice()[0] = CpuIdEntry {
+            function: 0x1,
+            ecx: 1 << 28 | 1 << 26,
+            ..Default::default()
+        };
+        cpuid.as_mut_slice()[1] = CpuIdEntry {
+            function: 0x7,
+            ebx: 1 << 16 | 1 << 5,
+            ..Default::default()
+        };
+        assert_eq!(
+            enabled_features(&cpuid),
+            vec!["xsave", "avx", "avx2", "avx512f"]
+        );
+
+        let masked = masked_features(None, &toggles(&["-avx512f", "-avx"])).unwrap();
+        mask_cpuid(&mut cpuid, &masked);
+        assert_eq!(enabled_features(&cpuid), vec!["xsave", "avx2"]);
+        assert_eq!(cpuid.as_slice()[1].ebx, 1 << 5);
+    }
+
+    #[test]
+    fn test_missing_features() {
+        let mut guest = CpuId::new(2);
+        guest.as_mut_slice()[0] = CpuIdEntry {
+            function: 0x1,
+            // APIC id and OSXSAVE along with xsave and avx.
+            ebx: 0xff << 24,
+            ecx: 1 << 28 | 1 << 27 | 1 << 26,
+            ..Default::default()
+        };
+        guest.as_mut_slice()[1] = CpuIdEntry {
+            function: 0x7,
+            ebx: 1 << 16 | 1 << 13 | 1 << 5,
+            ..Default::default()
+        };
+        let leaves = feature_leaves(&guest);
+        assert_eq!(leaves[0].ebx, 0);
+        assert_eq!(leaves[0].ecx, 1 << 28 | 1 << 27 | 1 << 26);
+
+        // The host lacks avx512f and the unnamed bit 13.
+        let mut host = CpuId::new(3);
+        host.as_mut_slice()[0] = CpuIdEntry {
+            function: 0x1,
+            ecx: 1 << 28 | 1 << 26,
+            ..Default::default()
+        };
+        host.as_mut_slice()[1] = CpuIdEntry {
+            function: 0x7,
+            ebx: 1 << 18 | 1 << 5,
+            ..Default::default()
+        };
+        host.as_mut_slice()[2] = CpuIdEntry {
+            function: 0x8000_0001,
+            edx: 1 << 29,
+            ..Default::default()
+        };
+        assert_eq!(
+            missing_features(&leaves, &host),
+            vec!["cpuid 0x7.0 ebx bit 13", "avx512f"]
+        );
+        assert!(missing_features(&feature_leaves(&host), &host).is_empty());
+
+        // The host exposes the same features as the guest, rdseed and lm
+        // being hidden as the guest didn't have them.
+        restrict_cpuid(&mut host, &leaves);
+        assert_eq!(enabled_features(&host), vec!["xsave", "avx", "avx2"]);
+        assert!(missing_features(&feature_leaves(&host), &guest).is_empty());
+    }
+}
@@ -93,6 +93,8 @@ pub const MEM_32BIT_DEVICES_SIZE: GuestUsize = 640 << 20;
 pub const PCI_MMCONFIG_START: GuestAddress =
     GuestAddress(MEM_32BIT_DEVICES_START.0 + MEM_32BIT_DEVICES_SIZE);
 pub const PCI_MMCONFIG_SIZE: GuestUsize = 256 << 20;
+// One bus of 32 devices with 8 functions each per PCI segment
+pub const PCI_MMIO_CONFIG_SIZE_PER_SEGMENT: GuestUsize = 256 << 12;
 
 // IOAPIC
 pub const IOAPIC_START: GuestAddress = GuestAddress(0xfec0_0000);
@@ -7,6 +7,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 use std::sync::Arc;
+pub mod cpu_features;
 mod gdt;
 pub mod interrupts;
 pub mod layout;
@@ -20,7 +21,7 @@ use linux_loader::loader::bootparam::{boot_params, setup_header};
 use linux_loader::loader::elf::start_info::{
     hvm_memmap_table_entry, hvm_modlist_entry, hvm_start_info,
 };
-use std::mem;
+use std::{cmp, mem};
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
     GuestMemoryMmap, GuestMemoryRegion, GuestUsize,
@@ -183,7 +184,7 @@ impl From<Error> for super::Error {
 }
 
 #[allow(dead_code)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum CpuidReg {
     EAX,
     EBX,
@@ -329,14 +330,14 @@ impl CpuidPatch {
 
 pub fn configure_vcpu(
     fd: &Arc<dyn hypervisor::Vcpu>,
-    id: u8,
+    apic_id: u8,
     kernel_entry_point: Option<EntryPoint>,
     vm_memory: &GuestMemoryAtomic<GuestMemoryMmap>,
     cpuid: CpuId,
 ) -> super::Result<()> {
     let mut cpuid = cpuid;
-    CpuidPatch::set_cpuid_reg(&mut cpuid, 0xb, None, CpuidReg::EDX, u32::from(id));
-    CpuidPatch::set_cpuid_reg(&mut cpuid, 0x1f, None, CpuidReg::EDX, u32::from(id));
+    CpuidPatch::set_cpuid_reg(&mut cpuid, 0xb, None, CpuidReg::EDX, u32::from(apic_id));
+    CpuidPatch::set_cpuid_reg(&mut cpuid, 0x1f, None, CpuidReg::EDX, u32::from(apic_id));
     fd.set_cpuid2(&cpuid)
         .map_err(|e| Error::SetSupportedCpusFailed(e.into()))?;
 
@@ -421,6 +422,7 @@ pub fn configure_system(
     cmdline_size: usize,
     initramfs: &Option<InitramfsConfig>,
     _num_cpus: u8,
+    topology: Option<(u8, u8, u8)>,
     setup_hdr: Option<setup_header>,
     rsdp_addr: Option<GuestAddress>,
     boot_prot: BootProtocol,
@@ -430,7 +432,7 @@ pub fn configure_system(
 
     // Note that this puts the mptable at the last 1k of Linux's 640k base RAM
     #[cfg(not(feature = "acpi"))]
-    mptable::setup_mptable(guest_mem, _num_cpus).map_err(Error::MpTableSetup)?;
+    mptable::setup_mptable(guest_mem, _num_cpus, topology).map_err(Error::MpTableSetup)?;
 
     // Check that the RAM is not smaller than the RSDP start address
     if let Some(rsdp_addr) = rsdp_addr {
@@ -774,6 +776,37 @@ pub fn get_host_cpu_phys_bits() -> u8 {
     }
 }
 
+/// Returns the x2APIC id of the vCPU `cpu_id` for the given topology
+/// (threads per core, cores per die, dies per package).
+///
+/// Each level of the topology is given as many bits of the APIC id as
+/// required to hold its count, which matches the shift widths reported
+/// through CPUID leaves 0xb and 0x1f by `update_cpuid_topology()`. Without
+/// a topology the APIC id is the vCPU index.
+pub fn get_x2apic_id(cpu_id: u32, topology: Option<(u8, u8, u8)>) -> u32 {
+    if let Some((threads_per_core, cores_per_die, dies_per_package)) = topology {
+        let thread_width = 8 - (threads_per_core - 1).leading_zeros();
+        let core_width = (8 - (cores_per_die - 1).leading_zeros()) + thread_width;
+        let die_width = (8 - (dies_per_package - 1).leading_zeros()) + core_width;
+
+        let threads_per_core = u32::from(threads_per_core);
+        let threads_per_die = threads_per_core * u32::from(cores_per_die);
+        let threads_per_package = threads_per_die * u32::from(dies_per_package);
+
+        let thread_id = cpu_id % threads_per_core;
+        let core_id = cpu_id % threads_per_die / threads_per_core;
+        let die_id = cpu_id % threads_per_package / threads_per_die;
+        let package_id = cpu_id / threads_per_package;
+
+        return thread_id
+            | (core_id << thread_width)
+            | (die_id << core_width)
+            | (package_id << die_width);
+    }
+
+    cpu_id
+}
+
 pub fn update_cpuid_topology(
     cpuid: &mut CpuId,
     threads_per_core: u8,
@@ -784,57 +817,92 @@ pub fn update_cpuid_topology(
     let core_width = (8 - (cores_per_die - 1).leading_zeros()) + thread_width;
     let die_width = (8 - (dies_per_package - 1).leading_zeros()) + core_width;
 
-    // CPU Topology leaf 0xb
-    CpuidPatch::set_cpuid_reg(cpuid, 0xb, Some(0), CpuidReg::EAX, thread_width);
-    CpuidPatch::set_cpuid_reg(
-        cpuid,
-        0xb,
-        Some(0),
-        CpuidReg::EBX,
-        u32::from(threads_per_core),
-    );
-    CpuidPatch::set_cpuid_reg(cpuid, 0xb, Some(0), CpuidReg::ECX, 1 << 8);
-
-    CpuidPatch::set_cpuid_reg(cpuid, 0xb, Some(1), CpuidReg::EAX, die_width);
-    CpuidPatch::set_cpuid_reg(
-        cpuid,
-        0xb,
-        Some(1),
-        CpuidReg::EBX,
-        u32::from(dies_per_package * cores_per_die * threads_per_core),
-    );
-    CpuidPatch::set_cpuid_reg(cpuid, 0xb, Some(1), CpuidReg::ECX, 2 << 8);
-
-    // CPU Topology leaf 0x1f
-    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(0), CpuidReg::EAX, thread_width);
-    CpuidPatch::set_cpuid_reg(
-        cpuid,
-        0x1f,
-        Some(0),
-        CpuidReg::EBX,
-        u32::from(threads_per_core),
-    );
-    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(0), CpuidReg::ECX, 1 << 8);
-
-    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(1), CpuidReg::EAX, core_width);
-    CpuidPatch::set_cpuid_reg(
-        cpuid,
-        0x1f,
-        Some(1),
-        CpuidReg::EBX,
-        u32::from(cores_per_die * threads_per_core),
-    );
-    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(1), CpuidReg::ECX, 2 << 8);
-
-    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(2), CpuidReg::EAX, die_width);
-    CpuidPatch::set_cpuid_reg(
-        cpuid,
-        0x1f,
-        Some(2),
-        CpuidReg::EBX,
-        u32::from(dies_per_package * cores_per_die * threads_per_core),
-    );
-    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(2), CpuidReg::ECX, 5 << 8);
+    let threads_per_core = u32::from(threads_per_core);
+    let threads_per_die = threads_per_core * u32::from(cores_per_die);
+    let threads_per_package = threads_per_die * u32::from(dies_per_package);
+
+    // Each level is described by the shift of the APIC id to get to the
+    // next level, the number of logical processors it holds and its type.
+    // Leaf 0xb has no die level, its core level spanning the whole package.
+    let leaf_0xb = [
+        (thread_width, threads_per_core, 1),
+        (die_width, threads_per_package, 2),
+    ];
+    let leaf_0x1f = [
+        (thread_width, threads_per_core, 1),
+        (core_width, threads_per_die, 2),
+        (die_width, threads_per_package, 5),
+    ];
+
+    for (function, levels) in [(0xb, &leaf_0xb[..]), (0x1f, &leaf_0x1f[..])].iter() {
+        for (index, (shift, count, level_type)) in levels.iter().enumerate() {
+            let index = index as u32;
+            CpuidPatch::set_cpuid_reg(cpuid, *function, Some(index), CpuidReg::EAX, *shift);
+            CpuidPatch::set_cpuid_reg(cpuid, *function, Some(index), CpuidReg::EBX, *count);
+            CpuidPatch::set_cpuid_reg(
+                cpuid,
+                *function,
+                Some(index),
+                CpuidReg::ECX,
+                (level_type << 8) | index,
+            );
+        }
+
+        // The first invalid level terminates the enumeration, whatever
+        // levels the host has beyond ours.
+        let index = levels.len() as u32;
+        CpuidPatch::set_cpuid_reg(cpuid, *function, Some(index), CpuidReg::EAX, 0);
+        CpuidPatch::set_cpuid_reg(cpuid, *function, Some(index), CpuidReg::EBX, 0);
+        CpuidPatch::set_cpuid_reg(cpuid, *function, Some(index), CpuidReg::ECX, index);
+    }
+
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        match entry.function {
+            // The guest only looks for the dies in leaf 0x1f if the host
+            // supports it, or we pretend it does.
+            0 if dies_per_package > 1 && entry.eax < 0x1f => entry.eax = 0x1f,
+            // Number of addressable logical processors per package.
+            1 => {
+                entry.ebx = (entry.ebx & !0x00ff_0000) | (cmp::min(1 << die_width, 0xff) << 16);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Bus (reference) frequency reported along with the CPU frequencies, in MHz.
+const CPUID_BUS_FREQUENCY_MHZ: u32 = 100;
+
+/// Report the base and maximum frequencies of the vCPUs, in MHz, through
+/// CPUID leaf 0x16, which KVM leaves empty.
+pub fn update_cpuid_frequency(cpuid: &mut CpuId, base_mhz: u16, max_mhz: u16) {
+    let mut leaf_found = false;
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        match entry.function {
+            0 if entry.eax < 0x16 => entry.eax = 0x16,
+            0x16 => {
+                entry.eax = u32::from(base_mhz);
+                entry.ebx = u32::from(max_mhz);
+                entry.ecx = CPUID_BUS_FREQUENCY_MHZ;
+                entry.edx = 0;
+                leaf_found = true;
+            }
+            _ => {}
+        }
+    }
+
+    if !leaf_found {
+        let entry = CpuIdEntry {
+            function: 0x16,
+            eax: u32::from(base_mhz),
+            ebx: u32::from(max_mhz),
+            ecx: CPUID_BUS_FREQUENCY_MHZ,
+            ..Default::default()
+        };
+        if let Err(e) = cpuid.push(entry) {
+            error!("Failed adding new CPUID entry: {:?}", e);
+        }
+    }
 }
 
 // The goal is to update the CPUID sub-leaves to reflect the number of EPC
@@ -915,6 +983,7 @@ mod tests {
             &None,
             1,
             None,
+            None,
             Some(layout::RSDP_POINTER),
             BootProtocol::LinuxBoot,
             None,
@@ -938,6 +1007,7 @@ mod tests {
             no_vcpus,
             None,
             None,
+            None,
             BootProtocol::LinuxBoot,
             None,
         )
@@ -951,6 +1021,7 @@ mod tests {
             no_vcpus,
             None,
             None,
+            None,
             BootProtocol::PvhBoot,
             None,
         )
@@ -973,6 +1044,7 @@ mod tests {
             no_vcpus,
             None,
             None,
+            None,
             BootProtocol::LinuxBoot,
             None,
         )
@@ -986,6 +1058,7 @@ mod tests {
             no_vcpus,
             None,
             None,
+            None,
             BootProtocol::PvhBoot,
             None,
         )
@@ -1008,6 +1081,7 @@ mod tests {
             no_vcpus,
             None,
             None,
+            None,
             BootProtocol::LinuxBoot,
             None,
         )
@@ -1021,6 +1095,7 @@ mod tests {
             no_vcpus,
             None,
             None,
+            None,
             BootProtocol::PvhBoot,
             None,
         )
@@ -1091,4 +1166,117 @@ mod tests {
 
         assert_eq!(format!("{:?}", memmap), format!("{:?}", expected_memmap));
     }
+
+    #[test]
+    fn test_get_x2apic_id() {
+        // No topology, the APIC id is the vCPU index.
+        for cpu_id in 0..8 {
+            assert_eq!(get_x2apic_id(cpu_id, None), cpu_id);
+        }
+
+        // 2 sockets, 4 cores per socket, 2 threads per core.
+        let topology = Some((2, 4, 1));
+        let apic_ids: Vec<u32> = (0..16).map(|i| get_x2apic_id(i, topology)).collect();
+        assert_eq!(apic_ids, (0..16).collect::<Vec<u32>>());
+        // Thread siblings only differ in the lowest bit.
+        assert_eq!(get_x2apic_id(6, topology), 0b0110);
+        assert_eq!(get_x2apic_id(7, topology), 0b0111);
+        // First thread of the second socket.
+        assert_eq!(get_x2apic_id(8, topology), 0b1000);
+
+        // A core count that is not a power of two leaves holes in the
+        // APIC id space between sockets.
+        let topology = Some((2, 3, 1));
+        let apic_ids: Vec<u32> = (0..12).map(|i| get_x2apic_id(i, topology)).collect();
+        assert_eq!(apic_ids, vec![0, 1, 2, 3, 4, 5, 8, 9, 10, 11, 12, 13]);
+
+        // 2 dies per package of 3 cores each.
+        let topology = Some((1, 3, 2));
+        let apic_ids: Vec<u32> = (0..12).map(|i| get_x2apic_id(i, topology)).collect();
+        assert_eq!(apic_ids, vec![0, 1, 2, 4, 5, 6, 8, 9, 10, 12, 13, 14]);
+    }
+
+    fn cpuid_entry(cpuid: &CpuId, function: u32, index: u32) -> (u32, u32, u32) {
+        let entry = cpuid
+            .as_slice()
+            .iter()
+            .find(|e| e.function == function && e.index == index)
+            .unwrap();
+        (entry.eax, entry.ebx, entry.ecx)
+    }
+
+    #[test]
+    fn test_update_cpuid_topology() {
+        let mut cpuid = CpuId::new(0);
+        for function in 0..2 {
+            cpuid
+                .push(CpuIdEntry {
+                    function,
+                    eax: 0xd,
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        // 2 threads per core, 3 cores per die and 2 dies per package.
+        update_cpuid_topology(&mut cpuid, 2, 3, 2);
+
+        assert_eq!(cpuid_entry(&cpuid, 0xb, 0), (1, 2, 0x100));
+        assert_eq!(cpuid_entry(&cpuid, 0xb, 1), (4, 12, 0x201));
+        assert_eq!(cpuid_entry(&cpuid, 0xb, 2), (0, 0, 2));
+        assert_eq!(cpuid_entry(&cpuid, 0x1f, 0), (1, 2, 0x100));
+        assert_eq!(cpuid_entry(&cpuid, 0x1f, 1), (3, 6, 0x201));
+        assert_eq!(cpuid_entry(&cpuid, 0x1f, 2), (4, 12, 0x502));
+        assert_eq!(cpuid_entry(&cpuid, 0x1f, 3), (0, 0, 3));
+        // Leaf 0x1f is made visible, and the package spans 16 APIC ids.
+        assert_eq!(cpuid_entry(&cpuid, 0, 0).0, 0x1f);
+        assert_eq!(cpuid_entry(&cpuid, 1, 0).1, 16 << 16);
+    }
+
+    #[test]
+    fn test_update_cpuid_frequency() {
+        let mut cpuid = CpuId::new(0);
+        cpuid
+            .push(CpuIdEntry {
+                function: 0,
+                eax: 0xd,
+                ..Default::default()
+            })
+            .unwrap();
+
+        // The leaf is added, and made visible.
+        update_cpuid_frequency(&mut cpuid, 2400, 3200);
+        assert_eq!(cpuid_entry(&cpuid, 0x16, 0), (2400, 3200, 100));
+        assert_eq!(cpuid_entry(&cpuid, 0, 0).0, 0x16);
+
+        // Whatever the host reported is replaced.
+        update_cpuid_frequency(&mut cpuid, 2000, 2000);
+        assert_eq!(cpuid_entry(&cpuid, 0x16, 0), (2000, 2000, 100));
+        assert_eq!(
+            cpuid
+                .as_slice()
+                .iter()
+                .filter(|e| e.function == 0x16)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_x2apic_id_hotplug_order() {
+        // Booting with 4 vCPUs out of a maximum of 16 on a 2 sockets,
+        // 4 cores, 2 threads layout: hotplugged vCPUs must fill the first
+        // socket before moving to the second one and never reuse an id.
+        let topology = Some((2, 4, 1));
+        let boot_vcpus = 4;
+        let max_vcpus = 16;
+
+        let mut previous = get_x2apic_id(boot_vcpus - 1, topology);
+        for cpu_id in boot_vcpus..max_vcpus {
+            let apic_id = get_x2apic_id(cpu_id, topology);
+            assert!(apic_id > previous);
+            assert_eq!(apic_id >> 3, cpu_id / 8);
+            previous = apic_id;
+        }
+    }
 }
@@ -13,6 +13,10 @@ extern crate log;
 #[macro_use]
 extern crate serde_derive;
 
+mod striped;
+
+pub use striped::StripedDisk;
+
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use std::cmp;
 use std::io::{self, Read, Seek, SeekFrom, Write};
@@ -26,6 +30,10 @@ use vm_virtio::DescriptorChain;
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
 
+// From include/uapi/linux/virtio_blk.h
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
 #[derive(Debug)]
 pub enum Error {
     /// Guest gave us bad memory addresses.
@@ -44,6 +52,8 @@ pub enum Error {
     GetFileMetadata,
     /// The requested operation would cause a seek beyond disk end.
     InvalidOffset,
+    /// Guest gave us an invalid indirect descriptor table.
+    InvalidIndirectDescriptor(vm_virtio::Error),
 }
 
 fn build_device_id(disk_path: &PathBuf) -> result::Result<String, Error> {
@@ -78,6 +88,16 @@ pub fn build_disk_image_id(disk_path: &PathBuf) -> Vec<u8> {
     default_disk_image_id
 }
 
+pub fn build_serial(serial: &str) -> Vec<u8> {
+    // The serial is padded with zeroes up to VIRTIO_BLK_ID_BYTES, and it is
+    // not NUL terminated if it uses the full length.
+    let mut disk_image_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
+    let serial = serial.as_bytes();
+    let bytes_to_copy = cmp::min(serial.len(), VIRTIO_BLK_ID_BYTES as usize);
+    disk_image_id[..bytes_to_copy].clone_from_slice(&serial[..bytes_to_copy]);
+    disk_image_id
+}
+
 #[derive(Debug)]
 pub enum ExecuteError {
     BadRequest(Error),
@@ -86,6 +106,8 @@ pub enum ExecuteError {
     Seek(io::Error),
     Write(GuestMemoryError),
     Unsupported(u32),
+    /// The request would modify a read-only disk.
+    ReadOnly(RequestType),
 }
 
 impl ExecuteError {
@@ -97,6 +119,7 @@ impl ExecuteError {
             ExecuteError::Seek(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Write(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+            ExecuteError::ReadOnly(_) => VIRTIO_BLK_S_IOERR,
         }
     }
 }
@@ -107,6 +130,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -120,6 +145,8 @@ pub fn request_type(
         VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
         VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
         VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceID),
+        VIRTIO_BLK_T_DISCARD => Ok(RequestType::Discard),
+        VIRTIO_BLK_T_WRITE_ZEROES => Ok(RequestType::WriteZeroes),
         t => Ok(RequestType::Unsupported(t)),
     }
 }
@@ -141,13 +168,43 @@ pub struct Request {
     pub data_len: u32,
     pub status_addr: GuestAddress,
     writeback: bool,
+    read_only: bool,
 }
 
 impl Request {
+    pub fn new(
+        request_type: RequestType,
+        sector: u64,
+        data_addr: GuestAddress,
+        data_len: u32,
+    ) -> Request {
+        Request {
+            request_type,
+            sector,
+            data_addr,
+            data_len,
+            status_addr: GuestAddress(0),
+            writeback: true,
+            read_only: false,
+        }
+    }
+
     pub fn parse(
         avail_desc: &DescriptorChain,
         mem: &GuestMemoryMmap,
     ) -> result::Result<Request, Error> {
+        // The whole request is described by the indirect table when the
+        // driver uses indirect descriptors.
+        let indirect_desc;
+        let avail_desc = if avail_desc.is_indirect() {
+            indirect_desc = avail_desc
+                .new_from_indirect()
+                .map_err(Error::InvalidIndirectDescriptor)?;
+            &indirect_desc
+        } else {
+            avail_desc
+        };
+
         // The head contains the request type which MUST be readable.
         if avail_desc.is_write_only() {
             return Err(Error::UnexpectedWriteOnlyDescriptor);
@@ -160,6 +217,7 @@ impl Request {
             data_len: 0,
             status_addr: GuestAddress(0),
             writeback: true,
+            read_only: false,
         };
 
         let data_desc;
@@ -216,6 +274,19 @@ impl Request {
         mem: &GuestMemoryMmap,
         disk_id: &Vec<u8>,
     ) -> result::Result<u32, ExecuteError> {
+        // Nothing can change a read-only disk, which the guest has been told
+        // through VIRTIO_BLK_F_RO, so the backend isn't even reached. There's
+        // nothing to flush either.
+        if self.read_only {
+            match self.request_type {
+                RequestType::Out | RequestType::Discard | RequestType::WriteZeroes => {
+                    return Err(ExecuteError::ReadOnly(self.request_type))
+                }
+                RequestType::Flush => return Ok(0),
+                _ => {}
+            }
+        }
+
         let mut top: u64 = u64::from(self.data_len) / SECTOR_SIZE;
         if u64::from(self.data_len) % SECTOR_SIZE != 0 {
             top += 1;
@@ -251,6 +322,10 @@ impl Request {
                 mem.write_slice(&disk_id.as_slice(), self.data_addr)
                     .map_err(ExecuteError::Write)?;
             }
+            RequestType::Discard => return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_DISCARD)),
+            RequestType::WriteZeroes => {
+                return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES))
+            }
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         };
         Ok(0)
@@ -259,6 +334,10 @@ impl Request {
     pub fn set_writeback(&mut self, writeback: bool) {
         self.writeback = writeback
     }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only
+    }
 }
 
 #[derive(Copy, Clone, Debug, Default, Deserialize)]
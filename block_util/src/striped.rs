@@ -0,0 +1,233 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Disk striped across several backends, RAID0 style.
+//!
+//! The disk is split in chunks of a fixed size, distributed in turn to each
+//! backend: with two backends, the even chunks live on the first one and the
+//! odd chunks on the second one. Each access is split on the chunk boundaries,
+//! so that a guest request spanning several chunks only completes once every
+//! backend involved was accessed.
+
+use super::SECTOR_SIZE;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+#[derive(Clone)]
+pub struct StripedDisk<T> {
+    backends: Vec<T>,
+    chunk_size: u64,
+    size: u64,
+    offset: u64,
+}
+
+impl<T: Read + Seek + Write> StripedDisk<T> {
+    /// Stripe `backends` in chunks of `chunk_size` bytes, which must be a
+    /// multiple of the sector size. The disk spans as many full chunks as
+    /// the smallest backend can hold, times the number of backends.
+    pub fn new(mut backends: Vec<T>, chunk_size: u64) -> io::Result<Self> {
+        if backends.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "striped disk without any backend",
+            ));
+        }
+        if chunk_size == 0 || chunk_size % SECTOR_SIZE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid stripe chunk size {}", chunk_size),
+            ));
+        }
+
+        let mut backend_size = std::u64::MAX;
+        for backend in backends.iter_mut() {
+            backend_size = cmp::min(backend_size, backend.seek(SeekFrom::End(0))?);
+        }
+        let size = backend_size / chunk_size * chunk_size * backends.len() as u64;
+
+        Ok(StripedDisk {
+            backends,
+            chunk_size,
+            size,
+            offset: 0,
+        })
+    }
+
+    // Backend holding the current offset, along with the offset within that
+    // backend and what is left of the chunk from there.
+    fn locate(&self) -> (usize, u64, u64) {
+        let chunk = self.offset / self.chunk_size;
+        let chunk_offset = self.offset % self.chunk_size;
+        let num_backends = self.backends.len() as u64;
+        let remaining = cmp::min(self.chunk_size - chunk_offset, self.size - self.offset);
+
+        (
+            (chunk % num_backends) as usize,
+            chunk / num_backends * self.chunk_size + chunk_offset,
+            remaining,
+        )
+    }
+}
+
+impl<T: Read + Seek + Write> Read for StripedDisk<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let (index, backend_offset, remaining) = self.locate();
+        let len = cmp::min(buf.len() as u64, remaining) as usize;
+        let backend = &mut self.backends[index];
+        backend.seek(SeekFrom::Start(backend_offset))?;
+        let count = backend.read(&mut buf[..len])?;
+        self.offset += count as u64;
+
+        Ok(count)
+    }
+}
+
+impl<T: Read + Seek + Write> Write for StripedDisk<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.offset >= self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "write beyond the end of the striped disk",
+            ));
+        }
+
+        let (index, backend_offset, remaining) = self.locate();
+        let len = cmp::min(buf.len() as u64, remaining) as usize;
+        let backend = &mut self.backends[index];
+        backend.seek(SeekFrom::Start(backend_offset))?;
+        let count = backend.write(&buf[..len])?;
+        self.offset += count as u64;
+
+        Ok(count)
+    }
+
+    // Every backend gets flushed, even if one of them fails.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        for backend in self.backends.iter_mut() {
+            if let Err(e) = backend.flush() {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<T: Read + Seek + Write> Seek for StripedDisk<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => add_offset(self.size, offset),
+            SeekFrom::Current(offset) => add_offset(self.offset, offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek in the striped disk",
+            )
+        })?;
+
+        self.offset = offset;
+        Ok(offset)
+    }
+}
+
+fn add_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const CHUNK_SIZE: u64 = 2 * SECTOR_SIZE;
+
+    fn striped_disk(backend_size: usize) -> StripedDisk<Cursor<Vec<u8>>> {
+        StripedDisk::new(
+            vec![
+                Cursor::new(vec![0u8; backend_size]),
+                Cursor::new(vec![0u8; backend_size]),
+            ],
+            CHUNK_SIZE,
+        )
+        .unwrap()
+    }
+
+    // Data written at `offset`, each sector being filled with its index.
+    fn sectors(offset: u64, len: u64) -> Vec<u8> {
+        (offset..offset + len)
+            .map(|o| (o / SECTOR_SIZE) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn test_striped_disk_size() {
+        // The part of the backends not filling a chunk is left out.
+        let mut disk = striped_disk(5 * SECTOR_SIZE as usize);
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), 8 * SECTOR_SIZE);
+
+        assert!(StripedDisk::<Cursor<Vec<u8>>>::new(Vec::new(), CHUNK_SIZE).is_err());
+        assert!(StripedDisk::new(vec![Cursor::new(Vec::new())], SECTOR_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn test_striped_disk_write() {
+        let mut disk = striped_disk(4 * SECTOR_SIZE as usize);
+
+        // Write the whole disk starting from the middle of the first chunk,
+        // and then the first sector, so that every access spans backends.
+        disk.seek(SeekFrom::Start(SECTOR_SIZE)).unwrap();
+        disk.write_all(&sectors(SECTOR_SIZE, 7 * SECTOR_SIZE))
+            .unwrap();
+        assert!(disk.write(&[0u8]).is_err());
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.write_all(&sectors(0, SECTOR_SIZE)).unwrap();
+        disk.flush().unwrap();
+
+        // Chunks 0 and 2 are on the first backend, 1 and 3 on the second.
+        let chunk = |index: u64| sectors(index * CHUNK_SIZE, CHUNK_SIZE);
+        assert_eq!(
+            disk.backends[0].get_ref().as_slice(),
+            [chunk(0), chunk(2)].concat().as_slice()
+        );
+        assert_eq!(
+            disk.backends[1].get_ref().as_slice(),
+            [chunk(1), chunk(3)].concat().as_slice()
+        );
+    }
+
+    #[test]
+    fn test_striped_disk_read() {
+        let mut disk = striped_disk(4 * SECTOR_SIZE as usize);
+        let chunk = |index: u64| sectors(index * CHUNK_SIZE, CHUNK_SIZE);
+        disk.backends[0] = Cursor::new([chunk(0), chunk(2)].concat());
+        disk.backends[1] = Cursor::new([chunk(1), chunk(3)].concat());
+
+        // Read across the second and third chunks, which live on different
+        // backends, and then up to the end of the disk.
+        let mut buf = vec![0u8; 2 * SECTOR_SIZE as usize];
+        disk.seek(SeekFrom::Start(3 * SECTOR_SIZE)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, sectors(3 * SECTOR_SIZE, 2 * SECTOR_SIZE));
+
+        let mut buf = Vec::new();
+        disk.seek(SeekFrom::Current(-(SECTOR_SIZE as i64))).unwrap();
+        disk.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, sectors(4 * SECTOR_SIZE, 4 * SECTOR_SIZE));
+    }
+}
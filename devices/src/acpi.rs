@@ -58,9 +58,15 @@ impl BusDevice for AcpiShutdownDevice {
 }
 
 /// A device for handling ACPI GED event generation
+///
+/// Notifications happening while the guest runs the _EVT method are
+/// coalesced, and the interrupt is raised again once the guest acknowledged
+/// the end of the method, so that none of them can be lost.
 pub struct AcpiGEDDevice {
     interrupt: Arc<Box<dyn InterruptSourceGroup>>,
     notification_type: HotPlugNotificationFlags,
+    // The guest read the notifications, but didn't acknowledge them yet.
+    in_service: bool,
     ged_irq: u32,
 }
 
@@ -69,6 +75,7 @@ impl AcpiGEDDevice {
         AcpiGEDDevice {
             interrupt,
             notification_type: HotPlugNotificationFlags::NO_DEVICES_CHANGED,
+            in_service: false,
             ged_irq,
         }
     }
@@ -77,7 +84,22 @@ impl AcpiGEDDevice {
         &mut self,
         notification_type: HotPlugNotificationFlags,
     ) -> Result<(), std::io::Error> {
+        let raised = !self.notification_type.is_empty() || self.in_service;
         self.notification_type |= notification_type;
+        if raised {
+            // The pending interrupt, or the acknowledgment of the one being
+            // serviced, takes care of this notification.
+            return Ok(());
+        }
+        self.interrupt.trigger(0)
+    }
+
+    /// Raise the interrupt again if the guest didn't consume the pending
+    /// notifications yet, in case the previous one went unnoticed.
+    pub fn retrigger(&self) -> Result<(), std::io::Error> {
+        if self.notification_type.is_empty() || self.in_service {
+            return Ok(());
+        }
         self.interrupt.trigger(0)
     }
 
@@ -92,9 +114,18 @@ impl BusDevice for AcpiGEDDevice {
     fn read(&mut self, _base: u64, _offset: u64, data: &mut [u8]) {
         data[0] = self.notification_type.bits();
         self.notification_type = HotPlugNotificationFlags::NO_DEVICES_CHANGED;
+        self.in_service = true;
     }
 
-    fn write(&mut self, _base: u64, _offset: u64, _data: &[u8]) {}
+    // Written by the guest at the end of the _EVT method
+    fn write(&mut self, _base: u64, _offset: u64, _data: &[u8]) {
+        self.in_service = false;
+        if !self.notification_type.is_empty() {
+            if let Err(e) = self.interrupt.trigger(0) {
+                error!("Error raising the GED interrupt: {}", e);
+            }
+        }
+    }
 }
 
 #[cfg(feature = "acpi")]
@@ -141,8 +172,10 @@ impl Aml for AcpiGEDDevice {
                         &aml::And::new(&aml::Local(1), &aml::Local(0), &4usize),
                         &aml::If::new(
                             &aml::Equal::new(&aml::Local(1), &4usize),
-                            vec![&aml::MethodCall::new("\\_SB_.PCI0.PCNT".into(), vec![])],
+                            vec![&aml::MethodCall::new("\\_SB_.PHPR.PSCN".into(), vec![])],
                         ),
+                        // Acknowledge the notifications were handled
+                        &aml::Store::new(&aml::Path::new("GDAT"), &aml::ZERO),
                     ],
                 ),
             ],
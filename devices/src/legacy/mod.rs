@@ -10,6 +10,7 @@ mod cmos;
 #[cfg(feature = "fwdebug")]
 mod fwdebug;
 mod i8042;
+mod pvpanic;
 #[cfg(target_arch = "aarch64")]
 mod rtc_pl031;
 mod serial;
@@ -19,6 +20,7 @@ pub use self::cmos::Cmos;
 #[cfg(feature = "fwdebug")]
 pub use self::fwdebug::FwDebugDevice;
 pub use self::i8042::I8042Device;
+pub use self::pvpanic::{Pvpanic, PvpanicInfo, PVPANIC_CRASH_LOADED, PVPANIC_PANICKED};
 pub use self::serial::Serial;
 
 #[cfg(target_arch = "aarch64")]
@@ -0,0 +1,123 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use vmm_sys_util::eventfd::EventFd;
+
+use BusDevice;
+
+/// The guest kernel panicked.
+pub const PVPANIC_PANICKED: u8 = 1 << 0;
+/// The guest kernel panicked, and loaded its crash kernel.
+pub const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+const PVPANIC_EVENTS: u8 = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+
+/// Guest panics reported through the pvpanic device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct PvpanicInfo {
+    pub panicked: bool,
+    pub crash_loaded: bool,
+}
+
+/// A pvpanic device, through which the guest kernel reports its panics on
+/// a single I/O port. It reads the events it supports from the port, and
+/// writes the ones happening to it.
+pub struct Pvpanic {
+    panic_evt: EventFd,
+    events: u8,
+}
+
+impl Pvpanic {
+    /// Constructs a pvpanic device signaling `panic_evt` whenever the guest
+    /// reports a panic.
+    pub fn new(panic_evt: EventFd) -> Self {
+        Pvpanic {
+            panic_evt,
+            events: 0,
+        }
+    }
+
+    /// The panics reported since the device was created or last cleared,
+    /// if any.
+    pub fn info(&self) -> Option<PvpanicInfo> {
+        if self.events == 0 {
+            return None;
+        }
+
+        Some(PvpanicInfo {
+            panicked: self.events & PVPANIC_PANICKED != 0,
+            crash_loaded: self.events & PVPANIC_CRASH_LOADED != 0,
+        })
+    }
+
+    /// Forget about the panics reported so far.
+    pub fn clear(&mut self) {
+        self.events = 0;
+    }
+}
+
+impl BusDevice for Pvpanic {
+    fn read(&mut self, _base: u64, _offset: u64, data: &mut [u8]) {
+        if data.len() == 1 {
+            data[0] = PVPANIC_EVENTS;
+        } else {
+            error!("Invalid read size on pvpanic port: {}", data.len())
+        }
+    }
+
+    fn write(&mut self, _base: u64, _offset: u64, data: &[u8]) {
+        if data.len() != 1 {
+            error!("Invalid write size on pvpanic port: {}", data.len());
+            return;
+        }
+
+        // Unknown events are ignored, as done by the other implementations.
+        let events = data[0] & PVPANIC_EVENTS;
+        if events == 0 {
+            return;
+        }
+        self.events |= events;
+        if let Err(e) = self.panic_evt.write(1) {
+            error!("Error triggering pvpanic event: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::EFD_NONBLOCK;
+
+    #[test]
+    fn test_pvpanic() {
+        let panic_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let mut pvpanic = Pvpanic::new(panic_evt.try_clone().unwrap());
+
+        let mut data = [0u8];
+        pvpanic.read(0x505, 0, &mut data);
+        assert_eq!(data[0], PVPANIC_PANICKED | PVPANIC_CRASH_LOADED);
+        assert_eq!(pvpanic.info(), None);
+
+        // Unknown events don't count as panics.
+        pvpanic.write(0x505, 0, &[1 << 4]);
+        assert_eq!(pvpanic.info(), None);
+        assert!(panic_evt.read().is_err());
+
+        pvpanic.write(0x505, 0, &[PVPANIC_PANICKED]);
+        assert_eq!(panic_evt.read().unwrap(), 1);
+        pvpanic.write(0x505, 0, &[PVPANIC_CRASH_LOADED]);
+        assert_eq!(panic_evt.read().unwrap(), 1);
+        assert_eq!(
+            pvpanic.info(),
+            Some(PvpanicInfo {
+                panicked: true,
+                crash_loaded: true,
+            })
+        );
+
+        pvpanic.clear();
+        assert_eq!(pvpanic.info(), None);
+    }
+}
@@ -11,6 +11,8 @@
 // Copyright © 2020, Microsoft Corporation
 //
 
+use serde_derive::{Deserialize, Serialize};
+
 #[allow(non_upper_case_globals)]
 #[allow(non_camel_case_types)]
 #[allow(non_snake_case)]
@@ -34,3 +36,35 @@ pub const MTRR_MEM_TYPE_WB: u64 = 0x6;
 
 // IOAPIC pins
 pub const NUM_IOAPIC_PINS: usize = 24;
+
+/// Format of the state of the nested guests of a vCPU, which follows the
+/// virtualization extensions exposed to it.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum NestedStateFormat {
+    /// Intel VMX
+    Vmx,
+    /// AMD SVM
+    Svm,
+}
+
+/// Accesses a hardware breakpoint triggers on. x86 has no breakpoint
+/// triggering on reads only.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HwBreakpointKind {
+    /// Executing the instruction at the address.
+    Execute,
+    /// Writing to the range.
+    Write,
+    /// Reading from or writing to the range.
+    Access,
+}
+
+/// Hardware breakpoint at a guest virtual address. Unless it's an execution
+/// breakpoint, it watches `len` bytes, 1, 2, 4 or 8, `addr` being aligned on
+/// them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HwBreakpoint {
+    pub addr: u64,
+    pub len: u64,
+    pub kind: HwBreakpointKind,
+}
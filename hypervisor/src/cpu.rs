@@ -12,6 +12,8 @@
 use crate::aarch64::VcpuInit;
 use crate::{CpuState, MpState};
 
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86::HwBreakpoint;
 #[cfg(target_arch = "x86_64")]
 use crate::x86_64::{
     CpuId, ExtendedControlRegisters, FpuState, LapicState, MsrEntries, SpecialRegisters,
@@ -84,6 +86,11 @@ pub enum HypervisorCpuError {
     #[error("Failed to get Msr entries: {0}")]
     GetMsrEntries(#[source] anyhow::Error),
     ///
+    /// Setting guest debug error
+    ///
+    #[error("Failed to set guest debug: {0}")]
+    SetGuestDebug(#[source] anyhow::Error),
+    ///
     /// Setting MSR entries error
     ///
     #[error("Failed to set MP state: {0}")]
@@ -148,6 +155,21 @@ pub enum HypervisorCpuError {
     ///
     #[error("Failed to notify guest its clock was paused: {0}")]
     NotifyGuestClockPaused(#[source] anyhow::Error),
+    ///
+    /// Getting nested state error
+    ///
+    #[error("Failed to get nested state: {0}")]
+    GetNestedState(#[source] anyhow::Error),
+    ///
+    /// Setting nested state error
+    ///
+    #[error("Failed to set nested state: {0}")]
+    SetNestedState(#[source] anyhow::Error),
+    ///
+    /// Mapping the dirty ring error
+    ///
+    #[error("Failed to map the dirty ring: {0}")]
+    MapDirtyRing(#[source] anyhow::Error),
 }
 
 #[derive(Debug)]
@@ -160,6 +182,8 @@ pub enum VmExit<'a> {
     IoapicEoi(u8 /* vector */),
     MmioRead(u64 /* address */, &'a mut [u8]),
     MmioWrite(u64 /* address */, &'a [u8]),
+    #[cfg(target_arch = "x86_64")]
+    Debug(Option<usize> /* hardware breakpoint hit */),
     Ignore,
     Reset,
 }
@@ -304,6 +328,25 @@ pub trait Vcpu: Send + Sync {
     /// This function is required when restoring the VM
     ///
     fn set_state(&self, state: &CpuState) -> Result<()>;
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Set the hardware breakpoints and watchpoints of the vCPU, and whether
+    /// it should single-step. No more than 4 of them are supported, the
+    /// debug exits reporting the index of the one hit.
+    ///
+    fn set_guest_debug(&self, breakpoints: &[HwBreakpoint], singlestep: bool) -> Result<()>;
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Harvest the dirty ring of the vCPU again once it is plugged back,
+    /// if the pages are tracked through dirty rings.
+    ///
+    fn add_dirty_ring(&self) -> Result<()>;
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Stop harvesting the dirty ring of an unplugged vCPU, the pages it
+    /// logged being kept for the next harvest.
+    ///
+    fn remove_dirty_ring(&self);
 
     ///
     /// Triggers the running of the current virtual CPU returning an exit reason.
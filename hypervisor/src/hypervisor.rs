@@ -7,6 +7,8 @@
 // Copyright 2018-2019 CrowdStrike, Inc.
 //
 //
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86::NestedStateFormat;
 use crate::vm::Vm;
 #[cfg(target_arch = "x86_64")]
 use crate::x86_64::{CpuId, MsrList};
@@ -113,4 +115,10 @@ pub trait Hypervisor: Send + Sync {
     /// Retrieve the list of MSRs supported by the hypervisor.
     ///
     fn get_msr_list(&self) -> Result<MsrList>;
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Get the format of the state of the nested guests, unless the
+    /// hypervisor can't run them or save their state.
+    ///
+    fn nested_state_format(&self) -> Option<NestedStateFormat>;
 }
@@ -13,8 +13,12 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 use std::sync::Arc;
 #[cfg(target_arch = "x86_64")]
+use std::sync::Mutex;
+#[cfg(target_arch = "x86_64")]
 use vm_memory::Address;
 use vmm_sys_util::eventfd::EventFd;
+#[cfg(target_arch = "x86_64")]
+use vmm_sys_util::ioctl::ioctl_with_ref;
 
 #[cfg(target_arch = "aarch64")]
 pub use crate::aarch64::{check_required_kvm_extensions, VcpuInit, VcpuKvmState as CpuState};
@@ -28,7 +32,9 @@ pub mod x86_64;
 
 #[cfg(target_arch = "x86_64")]
 use x86_64::{
-    check_required_kvm_extensions, FpuState, SpecialRegisters, StandardRegisters, KVM_TSS_ADDRESS,
+    check_required_kvm_extensions, debug,
+    dirty_ring::{DirtyRingLog, KVM_EXIT_DIRTY_RING_FULL},
+    nested, FpuState, SpecialRegisters, StandardRegisters, KVM_TSS_ADDRESS,
 };
 
 #[cfg(target_arch = "x86_64")]
@@ -41,7 +47,7 @@ pub use x86_64::{
 use kvm_bindings::{kvm_enable_cap, MsrList, KVM_CAP_SPLIT_IRQCHIP};
 
 #[cfg(target_arch = "x86_64")]
-use crate::arch::x86::NUM_IOAPIC_PINS;
+use crate::arch::x86::{HwBreakpoint, NestedStateFormat, NUM_IOAPIC_PINS};
 
 // aarch64 dependencies
 #[cfg(target_arch = "aarch64")]
@@ -50,7 +56,8 @@ pub mod aarch64;
 pub use kvm_bindings;
 pub use kvm_bindings::{
     kvm_create_device, kvm_device_type_KVM_DEV_TYPE_VFIO, kvm_irq_routing, kvm_irq_routing_entry,
-    kvm_userspace_memory_region, KVM_IRQ_ROUTING_MSI, KVM_MEM_READONLY, KVM_MSI_VALID_DEVID,
+    kvm_userspace_memory_region, KVM_IRQ_ROUTING_MSI, KVM_MEM_LOG_DIRTY_PAGES, KVM_MEM_READONLY,
+    KVM_MSI_VALID_DEVID,
 };
 pub use kvm_ioctls;
 pub use kvm_ioctls::{Cap, Kvm};
@@ -72,6 +79,8 @@ pub struct KvmVm {
     fd: Arc<VmFd>,
     #[cfg(target_arch = "x86_64")]
     msrs: MsrEntries,
+    #[cfg(target_arch = "x86_64")]
+    dirty_ring: Mutex<Option<Arc<DirtyRingLog>>>,
 }
 
 // Returns a `Vec<T>` with a size in bytes at least as large as `size_in_bytes`.
@@ -98,6 +107,13 @@ fn vec_with_size_in_bytes<T: Default>(size_in_bytes: usize) -> Vec<T> {
 // as a `Foo`. The remaining memory in the `Vec<Foo>` is for `entries`, which must be contiguous
 // with `Foo`. This function is used to make the `Vec<Foo>` with enough space for `count` entries.
 use std::mem::size_of;
+
+// KVM_SET_GUEST_DEBUG isn't exposed by kvm-ioctls yet.
+#[cfg(target_arch = "x86_64")]
+const KVMIO: std::os::raw::c_uint = 0xAE;
+#[cfg(target_arch = "x86_64")]
+ioctl_iow_nr!(KVM_SET_GUEST_DEBUG, KVMIO, 0x9b, kvm_guest_debug);
+
 fn vec_with_array_field<T: Default, F>(count: usize) -> Vec<T> {
     let element_space = count * size_of::<F>();
     let vec_size_bytes = size_of::<T>() + element_space;
@@ -156,10 +172,27 @@ impl vm::Vm for KvmVm {
             .fd
             .create_vcpu(id)
             .map_err(|e| vm::HypervisorVmError::CreateVcpu(e.into()))?;
+        #[cfg(target_arch = "x86_64")]
+        let dirty_ring = self.dirty_ring.lock().unwrap().clone();
+        #[cfg(target_arch = "x86_64")]
+        if let Some(dirty_ring) = &dirty_ring {
+            dirty_ring
+                .add_vcpu(&vc)
+                .map_err(|e| vm::HypervisorVmError::CreateVcpu(e.into()))?;
+        }
+        #[cfg(target_arch = "x86_64")]
+        let run_debug = debug::KvmRunDebug::new(&vc)
+            .map_err(|e| vm::HypervisorVmError::CreateVcpu(e.into()))?;
         let vcpu = KvmVcpu {
             fd: vc,
             #[cfg(target_arch = "x86_64")]
             msrs: self.msrs.clone(),
+            #[cfg(target_arch = "x86_64")]
+            dirty_ring,
+            #[cfg(target_arch = "x86_64")]
+            nested_state_size: nested::max_nested_state_size(&*self.fd),
+            #[cfg(target_arch = "x86_64")]
+            run_debug,
         };
         Ok(Arc::new(vcpu))
     }
@@ -227,13 +260,19 @@ impl vm::Vm for KvmVm {
         memory_size: u64,
         userspace_addr: u64,
         readonly: bool,
+        log_dirty_pages: bool,
     ) -> MemoryRegion {
         MemoryRegion {
             slot,
             guest_phys_addr,
             memory_size,
             userspace_addr,
-            flags: if readonly { KVM_MEM_READONLY } else { 0 },
+            flags: if readonly { KVM_MEM_READONLY } else { 0 }
+                | if log_dirty_pages {
+                    KVM_MEM_LOG_DIRTY_PAGES
+                } else {
+                    0
+                },
         }
     }
     ///
@@ -314,6 +353,53 @@ impl vm::Vm for KvmVm {
         self.create_device(&mut vfio_dev)
             .map_err(|e| vm::HypervisorVmError::CreatePassthroughDevice(e.into()))
     }
+    ///
+    /// Get the bitmap of the pages dirtied in a memory slot since the last
+    /// call, through the `KVM_GET_DIRTY_LOG` ioctl. The slot must have been
+    /// registered with dirty pages logging enabled.
+    ///
+    fn get_dirty_log(&self, slot: u32, memory_size: u64) -> vm::Result<Vec<u64>> {
+        self.fd
+            .get_dirty_log(slot, memory_size as usize)
+            .map_err(|e| vm::HypervisorVmError::GetDirtyLog(e.into()))
+    }
+    ///
+    /// Enable the dirty rings, through `KVM_CAP_DIRTY_LOG_RING`, unless
+    /// KVM doesn't support them. The vCPUs must not be created yet, and
+    /// `KVM_GET_DIRTY_LOG` can't be used from then on.
+    ///
+    fn enable_dirty_ring(&self) -> vm::Result<bool> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut dirty_ring = self.dirty_ring.lock().unwrap();
+            if dirty_ring.is_none() {
+                *dirty_ring = DirtyRingLog::enable(&self.fd)
+                    .map_err(|e| vm::HypervisorVmError::EnableDirtyRing(e.into()))?
+                    .map(Arc::new);
+            }
+            Ok(dirty_ring.is_some())
+        }
+
+        #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+        {
+            Ok(false)
+        }
+    }
+    ///
+    /// Harvest the dirty rings of all the vCPUs, resetting them.
+    ///
+    fn harvest_dirty_log(&self) -> vm::Result<Vec<(u32, u64)>> {
+        #[cfg(target_arch = "x86_64")]
+        if let Some(dirty_ring) = &*self.dirty_ring.lock().unwrap() {
+            return dirty_ring
+                .harvest()
+                .map_err(|e| vm::HypervisorVmError::HarvestDirtyLog(e.into()));
+        }
+
+        Err(vm::HypervisorVmError::HarvestDirtyLog(anyhow!(
+            "Dirty rings not enabled"
+        )))
+    }
 }
 /// Wrapper over KVM system ioctls.
 pub struct KvmHypervisor {
@@ -381,7 +467,11 @@ impl hypervisor::Hypervisor for KvmHypervisor {
                 msr_entries[pos].index = *index;
             }
 
-            Ok(Arc::new(KvmVm { fd: vm_fd, msrs }))
+            Ok(Arc::new(KvmVm {
+                fd: vm_fd,
+                msrs,
+                dirty_ring: Mutex::new(None),
+            }))
         }
 
         #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
@@ -394,6 +484,17 @@ impl hypervisor::Hypervisor for KvmHypervisor {
         check_required_kvm_extensions(&self.kvm).expect("Missing KVM capabilities");
         Ok(())
     }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// KVM runs nested guests when it exposes the virtualization extensions
+    /// of the host, and can save their state.
+    ///
+    fn nested_state_format(&self) -> Option<NestedStateFormat> {
+        if nested::max_nested_state_size(&self.kvm) == 0 {
+            return None;
+        }
+        nested::nested_state_format(&self.get_cpuid().ok()?)
+    }
 
     ///
     /// Returns the KVM API version.
@@ -452,6 +553,28 @@ pub struct KvmVcpu {
     fd: VcpuFd,
     #[cfg(target_arch = "x86_64")]
     msrs: MsrEntries,
+    #[cfg(target_arch = "x86_64")]
+    dirty_ring: Option<Arc<DirtyRingLog>>,
+    #[cfg(target_arch = "x86_64")]
+    nested_state_size: usize,
+    #[cfg(target_arch = "x86_64")]
+    run_debug: debug::KvmRunDebug,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl KvmVcpu {
+    // Format of the nested state, unless KVM can't save it or the vCPU
+    // isn't exposed the virtualization extensions.
+    fn nested_state_format(&self) -> cpu::Result<Option<NestedStateFormat>> {
+        if self.nested_state_size == 0 {
+            return Ok(None);
+        }
+        let cpuid = self
+            .fd
+            .get_cpuid2(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
+            .map_err(|e| cpu::HypervisorCpuError::GetCpuid(e.into()))?;
+        Ok(nested::nested_state_format(&cpuid))
+    }
 }
 /// Implementation of Vcpu trait for KVM
 /// Example:
@@ -625,6 +748,49 @@ impl cpu::Vcpu for KvmVcpu {
             .set_xcrs(&xcrs)
             .map_err(|e| cpu::HypervisorCpuError::SetXcsr(e.into()))
     }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Program the hardware breakpoints and watchpoints of the vCPU, and
+    /// enable single-step if requested, through the `KVM_SET_GUEST_DEBUG`
+    /// ioctl.
+    ///
+    fn set_guest_debug(&self, breakpoints: &[HwBreakpoint], singlestep: bool) -> cpu::Result<()> {
+        let dbg = debug::guest_debug(breakpoints, singlestep)
+            .map_err(cpu::HypervisorCpuError::SetGuestDebug)?;
+
+        // Safe because the kernel only reads the structure we pass it, and
+        // we check the return value.
+        let ret = unsafe { ioctl_with_ref(&self.fd, KVM_SET_GUEST_DEBUG(), &dbg) };
+        if ret < 0 {
+            return Err(cpu::HypervisorCpuError::SetGuestDebug(
+                std::io::Error::last_os_error().into(),
+            ));
+        }
+
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Map the dirty ring of the vCPU again, resuming from the entry it was
+    /// removed at.
+    ///
+    fn add_dirty_ring(&self) -> cpu::Result<()> {
+        if let Some(dirty_ring) = &self.dirty_ring {
+            dirty_ring
+                .add_vcpu(&self.fd)
+                .map_err(|e| cpu::HypervisorCpuError::MapDirtyRing(e.into()))?;
+        }
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Harvest the dirty ring of the vCPU one last time and unmap it.
+    ///
+    fn remove_dirty_ring(&self) {
+        if let Some(dirty_ring) = &self.dirty_ring {
+            dirty_ring.remove_vcpu(&self.fd);
+        }
+    }
     ///
     /// Triggers the running of the current virtual CPU returning an exit reason.
     ///
@@ -639,6 +805,8 @@ impl cpu::Vcpu for KvmVcpu {
                 VcpuExit::IoapicEoi(vector) => Ok(cpu::VmExit::IoapicEoi(vector)),
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::Shutdown | VcpuExit::Hlt => Ok(cpu::VmExit::Reset),
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::Debug => Ok(cpu::VmExit::Debug(self.run_debug.breakpoint_hit())),
 
                 #[cfg(target_arch = "aarch64")]
                 VcpuExit::SystemEvent(event_type, flags) => {
@@ -659,6 +827,18 @@ impl cpu::Vcpu for KvmVcpu {
                 VcpuExit::MmioRead(addr, data) => Ok(cpu::VmExit::MmioRead(addr, data)),
                 VcpuExit::MmioWrite(addr, data) => Ok(cpu::VmExit::MmioWrite(addr, data)),
 
+                // The vCPU can only run again once the rings were reset,
+                // their entries being kept for the next harvest.
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::Unsupported(KVM_EXIT_DIRTY_RING_FULL) if self.dirty_ring.is_some() => {
+                    self.dirty_ring
+                        .as_ref()
+                        .unwrap()
+                        .drain()
+                        .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()))?;
+                    Ok(cpu::VmExit::Ignore)
+                }
+
                 r => Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
                     "Unexpected exit reason on vcpu run: {:?}",
                     r
@@ -777,6 +957,14 @@ impl cpu::Vcpu for KvmVcpu {
         let mut msrs = self.msrs.clone();
         self.get_msrs(&mut msrs)?;
         let vcpu_events = self.get_vcpu_events()?;
+        let nested_state = if self.nested_state_format()?.is_some() {
+            Some(
+                nested::get_nested_state(&self.fd, self.nested_state_size)
+                    .map_err(|e| cpu::HypervisorCpuError::GetNestedState(e.into()))?,
+            )
+        } else {
+            None
+        };
 
         Ok(CpuState {
             msrs,
@@ -788,6 +976,7 @@ impl cpu::Vcpu for KvmVcpu {
             xsave,
             xcrs,
             mp_state,
+            nested_state,
         })
     }
     #[cfg(target_arch = "aarch64")]
@@ -819,6 +1008,11 @@ impl cpu::Vcpu for KvmVcpu {
     /// SET_LAPIC must come before SET_MSRS, because the TSC deadline MSR
     /// only restores successfully, when the LAPIC is correctly configured.
     ///
+    /// SET_NESTED_STATE must come after SET_SREGS and SET_MSRS, which
+    /// restore EFER.SVME and IA32_FEATURE_CONTROL the nested state relies
+    /// on, and before SET_VCPU_EVENTS, since the pending events may target
+    /// the nested guest.
+    ///
     /// Arguments: CpuState
     /// # Example
     ///
@@ -843,6 +1037,19 @@ impl cpu::Vcpu for KvmVcpu {
         self.set_lapic(&state.lapic_state)?;
         self.set_fpu(&state.fpu)?;
         self.set_msrs(&state.msrs)?;
+        if let Some(nested_state) = &state.nested_state {
+            let format = nested::saved_nested_state_format(nested_state);
+            let supported = self.nested_state_format()?;
+            if format.is_none() || format != supported {
+                return Err(cpu::HypervisorCpuError::SetNestedState(anyhow!(
+                    "Nested state of format {:?}, the vCPU supports {:?}",
+                    format,
+                    supported
+                )));
+            }
+            nested::set_nested_state(&self.fd, nested_state)
+                .map_err(|e| cpu::HypervisorCpuError::SetNestedState(e.into()))?;
+        }
         self.set_vcpu_events(&state.vcpu_events)?;
 
         Ok(())
@@ -0,0 +1,125 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Hardware breakpoints and watchpoints, programmed in the debug registers
+//! through `KVM_SET_GUEST_DEBUG`. KVM reports the debug register hit by a
+//! debug exit in the `kvm_run` structure of the vCPU, which kvm-ioctls
+//! doesn't expose yet.
+
+use crate::arch::x86::{HwBreakpoint, HwBreakpointKind};
+use anyhow::anyhow;
+use kvm_bindings::{
+    kvm_guest_debug, kvm_run, KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW_BP,
+};
+use kvm_ioctls::VcpuFd;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::ptr::{null_mut, read_volatile};
+
+/// Number of breakpoints the debug registers hold, in DR0 to DR3.
+pub const MAX_HW_BREAKPOINTS: usize = 4;
+
+// DR7 fields of the breakpoint `i`: its local enable bit, then its R/W and
+// LEN fields telling the accesses it triggers on.
+fn dr7_fields(i: usize, breakpoint: &HwBreakpoint) -> anyhow::Result<u64> {
+    let rw = match breakpoint.kind {
+        // An execution breakpoint covers a single byte.
+        HwBreakpointKind::Execute => return Ok(1 << (i * 2)),
+        HwBreakpointKind::Write => 0b01,
+        HwBreakpointKind::Access => 0b11,
+    };
+    let len = match breakpoint.len {
+        1 => 0b00,
+        2 => 0b01,
+        4 => 0b11,
+        8 => 0b10,
+        len => return Err(anyhow!("Unsupported watchpoint length: {}", len)),
+    };
+    if breakpoint.addr % breakpoint.len != 0 {
+        return Err(anyhow!(
+            "Watchpoint at {:#x} not aligned on its length",
+            breakpoint.addr
+        ));
+    }
+
+    Ok(1 << (i * 2) | (rw | len << 2) << (16 + i * 4))
+}
+
+/// Debug state programming `breakpoints`, and single-stepping if asked.
+pub fn guest_debug(
+    breakpoints: &[HwBreakpoint],
+    singlestep: bool,
+) -> anyhow::Result<kvm_guest_debug> {
+    if breakpoints.len() > MAX_HW_BREAKPOINTS {
+        return Err(anyhow!(
+            "Too many hardware breakpoints: {}",
+            breakpoints.len()
+        ));
+    }
+
+    let mut dbg = kvm_guest_debug::default();
+    if !breakpoints.is_empty() || singlestep {
+        dbg.control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_HW_BP;
+        if singlestep {
+            dbg.control |= KVM_GUESTDBG_SINGLESTEP;
+        }
+    }
+    for (i, breakpoint) in breakpoints.iter().enumerate() {
+        dbg.arch.debugreg[i] = breakpoint.addr;
+        dbg.arch.debugreg[7] |= dr7_fields(i, breakpoint)?;
+    }
+
+    Ok(dbg)
+}
+
+/// Read-only mapping of the `kvm_run` structure of a vCPU.
+pub struct KvmRunDebug {
+    run: *const kvm_run,
+}
+
+// The mapping is only read from, by the thread running the vCPU.
+unsafe impl Send for KvmRunDebug {}
+unsafe impl Sync for KvmRunDebug {}
+
+impl KvmRunDebug {
+    pub fn new(vcpu: &VcpuFd) -> io::Result<Self> {
+        // Safe because the mapping is checked, and only accessed within the
+        // size of the structure, which KVM maps at offset 0.
+        let run = unsafe {
+            libc::mmap(
+                null_mut(),
+                size_of::<kvm_run>(),
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                vcpu.as_raw_fd(),
+                0,
+            )
+        };
+        if run == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(KvmRunDebug {
+            run: run as *const kvm_run,
+        })
+    }
+
+    /// The breakpoint hit by the last debug exit, according to its DR6,
+    /// unless the vCPU completed a single step.
+    pub fn breakpoint_hit(&self) -> Option<usize> {
+        // Safe because the structure is mapped, and the debug member of
+        // its union is the one KVM fills on debug exits.
+        let dr6 = unsafe { read_volatile(&(*self.run).__bindgen_anon_1.debug.arch.dr6) };
+        (0..MAX_HW_BREAKPOINTS).find(|i| dr6 & (1 << i) != 0)
+    }
+}
+
+impl Drop for KvmRunDebug {
+    fn drop(&mut self) {
+        // Safe because the structure was mapped with this size.
+        unsafe { libc::munmap(self.run as *mut libc::c_void, size_of::<kvm_run>()) };
+    }
+}
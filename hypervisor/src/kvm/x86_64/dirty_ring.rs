@@ -0,0 +1,375 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! KVM dirty ring: every vCPU pushes the pages it dirties to a ring shared
+//! with userspace, rather than KVM setting them in a bitmap per memory slot,
+//! which `KVM_GET_DIRTY_LOG` has to copy and clear in one go, stalling the
+//! vCPUs of big VMs.
+
+use kvm_bindings::kvm_enable_cap;
+use kvm_ioctls::{VcpuFd, VmFd};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io;
+use std::mem::size_of;
+use std::os::raw::c_ulong;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::{null_mut, read_volatile};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_val};
+
+use super::KVM_CHECK_EXTENSION;
+use crate::kvm::KVMIO;
+
+// Neither the capability nor the ioctl are exposed by kvm-ioctls yet.
+ioctl_io_nr!(KVM_RESET_DIRTY_RINGS, KVMIO, 0xc7);
+
+// Capability of the dirty ring, which KVM reports with the largest size of
+// a ring, in bytes.
+const KVM_CAP_DIRTY_LOG_RING: u32 = 192;
+/// Exit reason of a vCPU whose dirty ring is full.
+pub const KVM_EXIT_DIRTY_RING_FULL: u32 = 31;
+// Offset of the ring in the mapping of the vCPU, in pages.
+const KVM_DIRTY_LOG_PAGE_OFFSET: i64 = 64;
+
+const KVM_DIRTY_GFN_F_DIRTY: u32 = 1;
+const KVM_DIRTY_GFN_F_RESET: u32 = 2;
+
+// Entries of every ring, unless KVM supports fewer.
+const DIRTY_RING_ENTRIES: u32 = 4096;
+
+// Entry of a dirty ring. KVM fills the slot and offset of the page before
+// flagging the entry dirty, and collects it once flagged for reset.
+#[repr(C)]
+struct KvmDirtyGfn {
+    flags: AtomicU32,
+    slot: u32,
+    offset: u64,
+}
+
+// Ring of a vCPU, mapped from its file descriptor.
+struct DirtyRing {
+    vcpu_fd: RawFd,
+    gfns: *mut KvmDirtyGfn,
+    entries: u32,
+    // Index of the next entry to harvest.
+    fetch: u32,
+}
+
+// The ring is only accessed with the lock of the log held.
+unsafe impl Send for DirtyRing {}
+
+impl DirtyRing {
+    fn new(vcpu: &VcpuFd, entries: u32) -> io::Result<Self> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        // Safe because the mapping is checked, and only accessed within the
+        // size of the ring.
+        let gfns = unsafe {
+            libc::mmap(
+                null_mut(),
+                entries as usize * size_of::<KvmDirtyGfn>(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                vcpu.as_raw_fd(),
+                KVM_DIRTY_LOG_PAGE_OFFSET * page_size,
+            )
+        };
+        if gfns == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(DirtyRing {
+            vcpu_fd: vcpu.as_raw_fd(),
+            gfns: gfns as *mut KvmDirtyGfn,
+            entries,
+            fetch: 0,
+        })
+    }
+
+    // Move the (slot, offset) of the dirty entries to `dirty`, flagging
+    // them for KVM to reuse once the rings are reset.
+    fn harvest(&mut self, dirty: &mut Vec<(u32, u64)>) {
+        loop {
+            // Safe because the index is within the ring, the number of
+            // entries being a power of 2.
+            let gfn = unsafe { &*self.gfns.add((self.fetch & (self.entries - 1)) as usize) };
+            if gfn.flags.load(Ordering::Acquire) & KVM_DIRTY_GFN_F_DIRTY == 0 {
+                break;
+            }
+            // Safe because KVM doesn't write a dirty entry until reset.
+            unsafe { dirty.push((read_volatile(&gfn.slot), read_volatile(&gfn.offset))) };
+            gfn.flags.store(KVM_DIRTY_GFN_F_RESET, Ordering::Release);
+            self.fetch = self.fetch.wrapping_add(1);
+        }
+    }
+}
+
+impl Drop for DirtyRing {
+    fn drop(&mut self) {
+        // Safe because the ring was mapped with this size.
+        unsafe {
+            libc::munmap(
+                self.gfns as *mut libc::c_void,
+                self.entries as usize * size_of::<KvmDirtyGfn>(),
+            )
+        };
+    }
+}
+
+struct DirtyRingState {
+    rings: Vec<DirtyRing>,
+    // Entries moved out of the rings since the last harvest.
+    dirty: Vec<(u32, u64)>,
+    // Index of the next entry to harvest from the rings of the unplugged
+    // vCPUs, KVM carrying on from there once they're plugged back.
+    parked: BTreeMap<RawFd, u32>,
+}
+
+impl DirtyRingState {
+    fn contains(&self, vcpu_fd: RawFd) -> bool {
+        self.rings.iter().any(|ring| ring.vcpu_fd == vcpu_fd)
+    }
+
+    fn insert(&mut self, mut ring: DirtyRing) {
+        if let Some(fetch) = self.parked.remove(&ring.vcpu_fd) {
+            ring.fetch = fetch;
+        }
+        self.rings.push(ring);
+    }
+
+    // Harvest the ring one last time before unmapping it.
+    fn remove(&mut self, vcpu_fd: RawFd) {
+        if let Some(index) = self.rings.iter().position(|ring| ring.vcpu_fd == vcpu_fd) {
+            let mut ring = self.rings.remove(index);
+            ring.harvest(&mut self.dirty);
+            self.parked.insert(vcpu_fd, ring.fetch);
+        }
+    }
+}
+
+/// Dirty rings of the vCPUs of a VM, along with the pages harvested from
+/// them whenever one got full.
+pub struct DirtyRingLog {
+    vm: Arc<VmFd>,
+    entries: u32,
+    state: Mutex<DirtyRingState>,
+}
+
+impl DirtyRingLog {
+    /// Enable the dirty rings of `vm`, which must not have any vCPU yet.
+    /// Returns `None` when KVM doesn't support them.
+    pub fn enable(vm: &Arc<VmFd>) -> io::Result<Option<Self>> {
+        // Safe because the capability is only checked.
+        let max_size = unsafe {
+            ioctl_with_val(
+                &**vm,
+                KVM_CHECK_EXTENSION(),
+                KVM_CAP_DIRTY_LOG_RING as c_ulong,
+            )
+        };
+        if max_size <= 0 {
+            return Ok(None);
+        }
+        // Both sizes are powers of 2.
+        let entries = cmp::min(
+            DIRTY_RING_ENTRIES,
+            max_size as u32 / size_of::<KvmDirtyGfn>() as u32,
+        );
+
+        let mut cap: kvm_enable_cap = Default::default();
+        cap.cap = KVM_CAP_DIRTY_LOG_RING;
+        cap.args[0] = u64::from(entries) * size_of::<KvmDirtyGfn>() as u64;
+        vm.enable_cap(&cap)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+
+        Ok(Some(DirtyRingLog {
+            vm: vm.clone(),
+            entries,
+            state: Mutex::new(DirtyRingState {
+                rings: Vec::new(),
+                dirty: Vec::new(),
+                parked: BTreeMap::new(),
+            }),
+        }))
+    }
+
+    /// Map the ring of a newly created vCPU, or of one plugged back. This
+    /// does nothing if the ring is mapped already.
+    pub fn add_vcpu(&self, vcpu: &VcpuFd) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.contains(vcpu.as_raw_fd()) {
+            state.insert(DirtyRing::new(vcpu, self.entries)?);
+        }
+        Ok(())
+    }
+
+    /// Unmap the ring of an unplugged vCPU, the entries it holds being kept
+    /// for the next harvest.
+    pub fn remove_vcpu(&self, vcpu: &VcpuFd) {
+        self.state.lock().unwrap().remove(vcpu.as_raw_fd());
+    }
+
+    /// Move the entries of all the rings aside, and have KVM reuse them,
+    /// for the vCPUs whose ring was full to run again.
+    pub fn drain(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let DirtyRingState { rings, dirty, .. } = &mut *state;
+        for ring in rings.iter_mut() {
+            ring.harvest(dirty);
+        }
+
+        // Safe because KVM only collects the entries flagged for reset.
+        let ret = unsafe { ioctl(&*self.vm, KVM_RESET_DIRTY_RINGS()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Pages dirtied since the previous harvest, as (slot, page offset
+    /// within the slot) pairs, possibly more than once.
+    pub fn harvest(&self) -> io::Result<Vec<(u32, u64)>> {
+        self.drain()?;
+        Ok(std::mem::replace(
+            &mut self.state.lock().unwrap().dirty,
+            Vec::new(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Ring backed by anonymous memory, standing for the one KVM maps.
+    fn test_ring(vcpu_fd: RawFd, entries: u32) -> DirtyRing {
+        // Safe because the mapping is checked, and unmapped by the ring.
+        let gfns = unsafe {
+            libc::mmap(
+                null_mut(),
+                entries as usize * size_of::<KvmDirtyGfn>(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(gfns, libc::MAP_FAILED);
+
+        DirtyRing {
+            vcpu_fd,
+            gfns: gfns as *mut KvmDirtyGfn,
+            entries,
+            fetch: 0,
+        }
+    }
+
+    fn gfn(ring: &DirtyRing, index: u32) -> &KvmDirtyGfn {
+        // Safe because the index is within the ring.
+        unsafe { &*ring.gfns.add((index & (ring.entries - 1)) as usize) }
+    }
+
+    // Push an entry as KVM does, at the index it is at.
+    fn push(ring: &DirtyRing, index: u32, slot: u32, offset: u64) {
+        let entry = ring
+            .gfns
+            .wrapping_add((index & (ring.entries - 1)) as usize);
+        // Safe because the index is within the ring, and nothing else
+        // accesses it.
+        unsafe {
+            (*entry).slot = slot;
+            (*entry).offset = offset;
+        }
+        gfn(ring, index)
+            .flags
+            .store(KVM_DIRTY_GFN_F_DIRTY, Ordering::Release);
+    }
+
+    // Collect the entries flagged for reset, as KVM_RESET_DIRTY_RINGS does.
+    fn reset(ring: &DirtyRing) {
+        for index in 0..ring.entries {
+            let flags = &gfn(ring, index).flags;
+            if flags.load(Ordering::Acquire) == KVM_DIRTY_GFN_F_RESET {
+                flags.store(0, Ordering::Release);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dirty_ring_harvest() {
+        let mut ring = test_ring(0, 4);
+        let mut dirty = Vec::new();
+
+        // The index wraps, both within the ring and as an integer.
+        ring.fetch = u32::MAX - 1;
+        push(&ring, u32::MAX - 1, 1, 0x10);
+        push(&ring, u32::MAX, 1, 0x11);
+        push(&ring, 0, 2, 0x20);
+        ring.harvest(&mut dirty);
+        assert_eq!(dirty, vec![(1, 0x10), (1, 0x11), (2, 0x20)]);
+        assert_eq!(ring.fetch, 1);
+
+        // The harvested entries are handed back to KVM, and aren't
+        // harvested again until KVM pushes to them.
+        for index in [u32::MAX - 1, u32::MAX, 0].iter() {
+            assert_eq!(
+                gfn(&ring, *index).flags.load(Ordering::Acquire),
+                KVM_DIRTY_GFN_F_RESET
+            );
+        }
+        assert_eq!(gfn(&ring, 1).flags.load(Ordering::Acquire), 0);
+        dirty.clear();
+        ring.harvest(&mut dirty);
+        assert!(dirty.is_empty());
+
+        // Harvesting stops at the first entry KVM didn't push yet.
+        reset(&ring);
+        push(&ring, 1, 3, 0x30);
+        push(&ring, 3, 3, 0x33);
+        ring.harvest(&mut dirty);
+        assert_eq!(dirty, vec![(3, 0x30)]);
+        assert_eq!(ring.fetch, 2);
+    }
+
+    #[test]
+    fn test_dirty_ring_unplug() {
+        let mut state = DirtyRingState {
+            rings: Vec::new(),
+            dirty: Vec::new(),
+            parked: BTreeMap::new(),
+        };
+        state.insert(test_ring(10, 4));
+        state.insert(test_ring(11, 4));
+        assert!(state.contains(10) && state.contains(11));
+
+        // The ring of an unplugged vCPU is harvested one last time.
+        push(&state.rings[0], 0, 1, 0x10);
+        push(&state.rings[0], 1, 1, 0x11);
+        state.remove(10);
+        assert!(!state.contains(10));
+        assert_eq!(state.rings.len(), 1);
+        assert_eq!(state.dirty, vec![(1, 0x10), (1, 0x11)]);
+
+        // Removing it again, or a ring which isn't there, does nothing.
+        state.remove(10);
+        state.remove(12);
+        assert_eq!(state.rings.len(), 1);
+
+        // Once plugged back, its ring is harvested from where KVM carries
+        // on.
+        let ring = test_ring(10, 4);
+        push(&ring, 2, 1, 0x12);
+        state.insert(ring);
+        assert!(state.parked.is_empty());
+        let DirtyRingState { rings, dirty, .. } = &mut state;
+        dirty.clear();
+        for ring in rings.iter_mut() {
+            ring.harvest(dirty);
+        }
+        assert_eq!(*dirty, vec![(1, 0x12)]);
+    }
+}
@@ -8,12 +8,19 @@
 //
 //
 
+pub mod debug;
+pub mod dirty_ring;
+pub mod nested;
+
 use vm_memory::GuestAddress;
 
 use crate::arch::x86::{msr_index, MTRR_ENABLE, MTRR_MEM_TYPE_WB};
-use crate::kvm::{Cap, Kvm, KvmError, KvmResult};
+use crate::kvm::{Cap, Kvm, KvmError, KvmResult, KVMIO};
 use serde_derive::{Deserialize, Serialize};
 
+// Also checks the capabilities kvm-ioctls doesn't know about yet.
+ioctl_io_nr!(KVM_CHECK_EXTENSION, KVMIO, 0x03);
+
 ///
 /// Export generically-named wrappers of kvm-bindings for Unix-based platforms
 ///
@@ -94,4 +101,8 @@ pub struct VcpuKvmState {
     pub xsave: Xsave,
     pub xcrs: ExtendedControlRegisters,
     pub mp_state: MpState,
+    /// State of the nested guests, only saved when the vCPU is exposed
+    /// the virtualization extensions.
+    #[serde(default)]
+    pub nested_state: Option<Vec<u8>>,
 }
@@ -0,0 +1,138 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! State of the nested guests a vCPU runs, such as the VMCS of the L2
+//! guest and whether the L1 guest entered VMX operation, which KVM keeps
+//! out of the other vCPU registers. It's saved and restored as an opaque
+//! blob, starting with the header telling its format.
+
+use kvm_bindings::CpuId;
+use std::cmp;
+use std::io;
+use std::mem::size_of;
+use std::os::raw::c_ulong;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ptr, ioctl_with_ptr, ioctl_with_val};
+
+use super::KVM_CHECK_EXTENSION;
+use crate::arch::x86::NestedStateFormat;
+use crate::kvm::KVMIO;
+
+// Capability of the nested state, which KVM reports with the largest size
+// of the state, in bytes.
+const KVM_CAP_NESTED_STATE: u32 = 157;
+
+const KVM_STATE_NESTED_FORMAT_VMX: u16 = 0;
+const KVM_STATE_NESTED_FORMAT_SVM: u16 = 1;
+
+// Bits of the virtualization extensions in CPUID: VMX in ECX of leaf 0x1,
+// SVM in ECX of leaf 0x8000_0001.
+const VMX_ECX_BIT: u32 = 5;
+const SVM_ECX_BIT: u32 = 2;
+
+// Header of the nested state, the data of the format following it.
+#[repr(C)]
+struct KvmNestedState {
+    flags: u16,
+    format: u16,
+    size: u32,
+    hdr: [u64; 15],
+}
+
+ioctl_iowr_nr!(KVM_GET_NESTED_STATE, KVMIO, 0xbe, KvmNestedState);
+ioctl_iow_nr!(KVM_SET_NESTED_STATE, KVMIO, 0xbf, KvmNestedState);
+
+/// Largest size of the nested state, or 0 when KVM can't save it. Works
+/// with both the KVM and VM file descriptors.
+pub fn max_nested_state_size<F: AsRawFd>(fd: &F) -> usize {
+    // Safe because the capability is only checked.
+    let size =
+        unsafe { ioctl_with_val(fd, KVM_CHECK_EXTENSION(), KVM_CAP_NESTED_STATE as c_ulong) };
+    if size <= 0 {
+        return 0;
+    }
+    cmp::max(size as usize, size_of::<KvmNestedState>())
+}
+
+/// Format of the nested state of a vCPU given `cpuid`, unless it isn't
+/// exposed the virtualization extensions.
+pub fn nested_state_format(cpuid: &CpuId) -> Option<NestedStateFormat> {
+    let has_bit = |function, bit| {
+        cpuid
+            .as_slice()
+            .iter()
+            .any(|entry| entry.function == function && entry.ecx & (1 << bit) != 0)
+    };
+
+    if has_bit(0x1, VMX_ECX_BIT) {
+        Some(NestedStateFormat::Vmx)
+    } else if has_bit(0x8000_0001, SVM_ECX_BIT) {
+        Some(NestedStateFormat::Svm)
+    } else {
+        None
+    }
+}
+
+/// Save the nested state of a vCPU, of at most `max_size` bytes.
+pub fn get_nested_state<F: AsRawFd>(vcpu: &F, max_size: usize) -> io::Result<Vec<u8>> {
+    // The buffer is aligned for the header.
+    let mut buffer = vec![0u64; (max_size + 7) / 8];
+    let state = buffer.as_mut_ptr() as *mut KvmNestedState;
+    // Safe because the buffer holds at least the header, and KVM doesn't
+    // write beyond the size given in it.
+    let ret = unsafe {
+        (*state).size = max_size as u32;
+        ioctl_with_mut_ptr(vcpu, KVM_GET_NESTED_STATE(), state)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because KVM wrote the size of the state, which is checked.
+    let size = cmp::min(unsafe { (*state).size } as usize, max_size);
+    let mut bytes = vec![0u8; size];
+    // Safe because both buffers hold at least `size` bytes.
+    unsafe { ptr::copy_nonoverlapping(buffer.as_ptr() as *const u8, bytes.as_mut_ptr(), size) };
+    Ok(bytes)
+}
+
+/// Restore the nested state of a vCPU, as saved by `get_nested_state()`.
+pub fn set_nested_state<F: AsRawFd>(vcpu: &F, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() < size_of::<KvmNestedState>() {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let mut buffer = vec![0u64; (bytes.len() + 7) / 8];
+    // Safe because the buffer holds at least as many bytes.
+    unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), buffer.as_mut_ptr() as *mut u8, bytes.len())
+    };
+    // Safe because the buffer holds the whole state, as sized in its header.
+    let ret = unsafe {
+        ioctl_with_ptr(
+            vcpu,
+            KVM_SET_NESTED_STATE(),
+            buffer.as_ptr() as *const KvmNestedState,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Format of a nested state saved by `get_nested_state()`.
+pub fn saved_nested_state_format(bytes: &[u8]) -> Option<NestedStateFormat> {
+    if bytes.len() < size_of::<KvmNestedState>() {
+        return None;
+    }
+    match u16::from_le_bytes([bytes[2], bytes[3]]) {
+        KVM_STATE_NESTED_FORMAT_VMX => Some(NestedStateFormat::Vmx),
+        KVM_STATE_NESTED_FORMAT_SVM => Some(NestedStateFormat::Svm),
+        _ => None,
+    }
+}
@@ -24,6 +24,8 @@ extern crate serde_json;
 extern crate thiserror;
 #[macro_use]
 extern crate anyhow;
+#[macro_use]
+extern crate vmm_sys_util;
 
 /// KVM implementation module
 pub mod kvm;
@@ -116,6 +116,21 @@ pub enum HypervisorVmError {
     ///
     #[error("Failed to create passthrough device: {0}")]
     CreatePassthroughDevice(#[source] anyhow::Error),
+    ///
+    /// Get dirty log error
+    ///
+    #[error("Failed to get dirty log: {0}")]
+    GetDirtyLog(#[source] anyhow::Error),
+    ///
+    /// Enable dirty ring error
+    ///
+    #[error("Failed to enable dirty ring: {0}")]
+    EnableDirtyRing(#[source] anyhow::Error),
+    ///
+    /// Harvest dirty log error
+    ///
+    #[error("Failed to harvest dirty log: {0}")]
+    HarvestDirtyLog(#[source] anyhow::Error),
 }
 ///
 /// Result type for returning from a function
@@ -158,6 +173,7 @@ pub trait Vm: Send + Sync {
         memory_size: u64,
         userspace_addr: u64,
         readonly: bool,
+        log_dirty_pages: bool,
     ) -> MemoryRegion;
     /// Creates/modifies a guest physical memory slot.
     fn set_user_memory_region(&self, user_memory_region: MemoryRegion) -> Result<()>;
@@ -179,4 +195,14 @@ pub trait Vm: Send + Sync {
     fn check_extension(&self, c: Cap) -> bool;
     /// Create a device that is used for passthrough
     fn create_passthrough_device(&self) -> Result<Arc<dyn Device>>;
+    /// Get the dirty pages bitmap (one bit per page) of a memory slot and
+    /// reset it.
+    fn get_dirty_log(&self, slot: u32, memory_size: u64) -> Result<Vec<u64>>;
+    /// Have the vCPUs created from then on log the pages they dirty to
+    /// rings rather than to the bitmaps of the memory slots, returning
+    /// whether the hypervisor supports it.
+    fn enable_dirty_ring(&self) -> Result<bool>;
+    /// Get the pages dirtied since the previous call, as (slot, page offset
+    /// within the slot) pairs, once the dirty rings are enabled.
+    fn harvest_dirty_log(&self) -> Result<Vec<(u32, u64)>>;
 }
@@ -0,0 +1,157 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::cmp;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Number of buckets of a `LatencyHistogram`, enough for any latency
+/// expressed in nanoseconds on 64 bits.
+pub const LATENCY_BUCKETS: usize = 64;
+
+/// Histogram of latencies, updated and read without any lock. The bucket `i`
+/// counts the latencies from 2^i nanoseconds up to 2^(i+1) excluded, the
+/// first one counting those below a nanosecond too.
+pub struct LatencyHistogram {
+    buckets: Vec<AtomicU64>,
+    min_ns: AtomicU64,
+    max_ns: AtomicU64,
+}
+
+/// Aggregate of the latencies recorded by a `LatencyHistogram`, all of them
+/// in nanoseconds. The percentiles are only as precise as the buckets, the
+/// upper bound of the bucket they fall into being reported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub min_ns: u64,
+    pub p50_ns: u64,
+    pub p99_ns: u64,
+    pub max_ns: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        LatencyHistogram {
+            buckets: (0..LATENCY_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            min_ns: AtomicU64::new(u64::MAX),
+            max_ns: AtomicU64::new(0),
+        }
+    }
+
+    /// Bucket counting the latencies of `latency_ns` nanoseconds.
+    pub fn bucket(latency_ns: u64) -> usize {
+        if latency_ns == 0 {
+            0
+        } else {
+            63 - latency_ns.leading_zeros() as usize
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let latency_ns = cmp::min(latency.as_nanos(), u128::from(u64::MAX)) as u64;
+        self.buckets[Self::bucket(latency_ns)].fetch_add(1, Ordering::Relaxed);
+        self.min_ns.fetch_min(latency_ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(latency_ns, Ordering::Relaxed);
+    }
+
+    /// Number of latencies counted by each bucket.
+    pub fn buckets(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// The latencies being recorded while the buckets are read, the summary
+    /// may not account for the very last of them.
+    pub fn summary(&self) -> LatencySummary {
+        let buckets = self.buckets();
+        let count = buckets.iter().sum();
+        if count == 0 {
+            return LatencySummary::default();
+        }
+
+        let min_ns = self.min_ns.load(Ordering::Relaxed);
+        let max_ns = self.max_ns.load(Ordering::Relaxed);
+        let percentile = |percent: u64| {
+            // Rank of the latency the percentile stands for, the first one
+            // being 1.
+            let rank = cmp::max((count * percent + 99) / 100, 1);
+            let mut seen = 0;
+            for (i, bucket) in buckets.iter().enumerate() {
+                seen += bucket;
+                if seen >= rank {
+                    let upper_bound = if i == LATENCY_BUCKETS - 1 {
+                        u64::MAX
+                    } else {
+                        (2 << i) - 1
+                    };
+                    return cmp::max(cmp::min(upper_bound, max_ns), min_ns);
+                }
+            }
+            max_ns
+        };
+
+        LatencySummary {
+            count,
+            min_ns,
+            p50_ns: percentile(50),
+            p99_ns: percentile(99),
+            max_ns,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_buckets() {
+        assert_eq!(LatencyHistogram::bucket(0), 0);
+        assert_eq!(LatencyHistogram::bucket(1), 0);
+        assert_eq!(LatencyHistogram::bucket(2), 1);
+        assert_eq!(LatencyHistogram::bucket(3), 1);
+        assert_eq!(LatencyHistogram::bucket(1023), 9);
+        assert_eq!(LatencyHistogram::bucket(1024), 10);
+        assert_eq!(LatencyHistogram::bucket(u64::MAX), 63);
+    }
+
+    #[test]
+    fn test_latency_summary() {
+        let histogram = LatencyHistogram::new();
+        assert_eq!(histogram.summary(), LatencySummary::default());
+
+        // 98 latencies of 1.5us, one of 100us and one of 10ms.
+        for _ in 0..98 {
+            histogram.record(Duration::from_nanos(1500));
+        }
+        histogram.record(Duration::from_micros(100));
+        histogram.record(Duration::from_millis(10));
+
+        let buckets = histogram.buckets();
+        assert_eq!(buckets[10], 98);
+        assert_eq!(buckets[16], 1);
+        assert_eq!(buckets[23], 1);
+        assert_eq!(buckets.iter().sum::<u64>(), 100);
+
+        assert_eq!(
+            histogram.summary(),
+            LatencySummary {
+                count: 100,
+                min_ns: 1500,
+                p50_ns: 2047,
+                p99_ns: 131_071,
+                max_ns: 10_000_000,
+            }
+        );
+    }
+}
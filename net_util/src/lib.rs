@@ -15,24 +15,32 @@ extern crate libc;
 extern crate log;
 extern crate net_gen;
 extern crate rand;
+extern crate rate_limiter;
 extern crate serde;
 extern crate virtio_bindings;
 extern crate vm_memory;
 extern crate vm_virtio;
 extern crate vmm_sys_util;
 
+mod latency;
 mod mac;
 mod open_tap;
 mod queue_pair;
+mod splice;
 mod tap;
 
 use std::io::Error as IoError;
 use std::os::unix::io::{FromRawFd, RawFd};
 use std::{io, mem, net};
 
+pub use latency::{LatencyHistogram, LatencySummary, LATENCY_BUCKETS};
 pub use mac::{MacAddr, MAC_ADDR_LEN};
 pub use open_tap::{open_tap, Error as OpenTapError};
-pub use queue_pair::{NetCounters, NetQueuePair, NetQueuePairError, RxVirtio, TxVirtio};
+pub use queue_pair::{
+    NetCounters, NetQueuePair, NetQueuePairError, RxStarvation, RxStarvationPolicy, RxVirtio,
+    TxVirtio,
+};
+pub use splice::{TxSplice, SPLICE_MIN_FRAME_SIZE};
 pub use tap::{Error as TapError, Tap};
 
 #[derive(Debug)]
@@ -74,6 +82,21 @@ fn vnet_hdr_len() -> usize {
     std::mem::size_of::<virtio_net_hdr_v1>()
 }
 
+/// Length of the virtio net header preceding each frame, according to the
+/// `features` negotiated with the driver. Legacy drivers, which negotiated
+/// neither VIRTIO_F_VERSION_1 nor VIRTIO_NET_F_MRG_RXBUF, use a header
+/// lacking the number of buffers.
+pub fn virtio_net_hdr_len(features: u64) -> usize {
+    use virtio_bindings::bindings::virtio_net::{
+        virtio_net_hdr, VIRTIO_F_VERSION_1, VIRTIO_NET_F_MRG_RXBUF,
+    };
+    if features & (1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_NET_F_MRG_RXBUF) != 0 {
+        vnet_hdr_len()
+    } else {
+        std::mem::size_of::<virtio_net_hdr>()
+    }
+}
+
 pub fn register_listener(
     epoll_fd: RawFd,
     fd: RawFd,
@@ -77,6 +77,12 @@ impl MacAddr {
         &self.bytes
     }
 
+    /// Whether the address is a group one, the broadcast address included,
+    /// which can't be assigned to an interface.
+    pub fn is_multicast(&self) -> bool {
+        self.bytes[0] & 0x1 != 0
+    }
+
     pub fn local_random() -> MacAddr {
         // Generate a fully random MAC
         let mut random_bytes = rand::thread_rng().gen::<[u8; MAC_ADDR_LEN]>();
@@ -161,6 +167,18 @@ mod tests {
         assert_eq!(bytes, [0x12u8, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
     }
 
+    #[test]
+    fn test_mac_addr_multicast() {
+        let unicast = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        assert!(!unicast.is_multicast());
+        assert!(!MacAddr::local_random().is_multicast());
+
+        let multicast = MacAddr::parse_str("01:00:5e:00:00:01").unwrap();
+        assert!(multicast.is_multicast());
+        let broadcast = MacAddr::parse_str("ff:ff:ff:ff:ff:ff").unwrap();
+        assert!(broadcast.is_multicast());
+    }
+
     #[test]
     fn test_from_bytes() {
         let src1 = [0x01, 0x02, 0x03, 0x04, 0x05];
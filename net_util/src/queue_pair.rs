@@ -2,8 +2,11 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use super::{register_listener, unregister_listener, vnet_hdr_len, Tap};
+use super::{
+    register_listener, unregister_listener, vnet_hdr_len, LatencyHistogram, Tap, TxSplice,
+};
 use libc::EAGAIN;
+use rate_limiter::RateLimiter;
 use std::cmp;
 use std::io;
 use std::io::{Read, Write};
@@ -11,20 +14,93 @@ use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use virtio_bindings::bindings::virtio_net::{
+    VIRTIO_NET_HDR_F_DATA_VALID, VIRTIO_NET_HDR_F_NEEDS_CSUM,
+};
 use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vm_virtio::{DescriptorChain, Queue};
+use vmm_sys_util::timerfd::TimerFd;
 
 /// The maximum buffer size when segmentation offload is enabled. This
 /// includes the 12-byte virtio net header.
 /// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html#x1-1740003
 const MAX_BUFFER_SIZE: usize = 65562;
 
+// Offsets of the virtio_net_hdr_v1 fields describing the checksum, which the
+// legacy virtio_net_hdr shares.
+const VNET_HDR_FLAGS_OFFSET: usize = 0;
+const VNET_HDR_CSUM_START_OFFSET: usize = 6;
+const VNET_HDR_CSUM_OFFSET_OFFSET: usize = 8;
+// Offset of the number of buffers a frame was merged into.
+const VNET_HDR_NUM_BUFFERS_OFFSET: usize = 10;
+
+// Ones' complement sum of `data` taken as big endian 16 bits words, the last
+// odd byte being padded with zero.
+fn ones_complement_sum(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for word in data.chunks(2) {
+        let high = u32::from(word[0]) << 8;
+        let low = word.get(1).map_or(0, |b| u32::from(*b));
+        sum += high | low;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+// Fix up the checksum reported by the `hdr_len` bytes header of a frame read
+// from the TAP device, whether the driver negotiated VIRTIO_NET_F_GUEST_CSUM
+// or not.
+//
+// The host kernel either leaves the checksum partial, to be completed from
+// csum_start (NEEDS_CSUM), or reports it as already validated (DATA_VALID).
+// Those flags are only defined with VIRTIO_NET_F_GUEST_CSUM, so any other
+// driver gets the checksum completed here and the flags cleared.
+fn fixup_rx_checksum(frame: &mut [u8], hdr_len: usize, guest_csum: bool) {
+    if frame.len() < hdr_len {
+        return;
+    }
+
+    let flags = u32::from(frame[VNET_HDR_FLAGS_OFFSET]);
+    if flags & VIRTIO_NET_HDR_F_NEEDS_CSUM == 0 {
+        if !guest_csum {
+            frame[VNET_HDR_FLAGS_OFFSET] &= !(VIRTIO_NET_HDR_F_DATA_VALID as u8);
+        }
+        return;
+    }
+
+    if guest_csum {
+        return;
+    }
+
+    let read_u16 = |offset: usize| u16::from_le_bytes([frame[offset], frame[offset + 1]]);
+    let csum_start = hdr_len + read_u16(VNET_HDR_CSUM_START_OFFSET) as usize;
+    let csum_field = csum_start + read_u16(VNET_HDR_CSUM_OFFSET_OFFSET) as usize;
+    if csum_field + 2 > frame.len() {
+        warn!("Invalid checksum location in received frame");
+    } else {
+        // The checksum field holds the pseudo header sum already.
+        let csum = !ones_complement_sum(&frame[csum_start..]);
+        frame[csum_field..csum_field + 2].copy_from_slice(&csum.to_be_bytes());
+    }
+
+    frame[VNET_HDR_FLAGS_OFFSET] = 0;
+    for b in &mut frame[VNET_HDR_CSUM_START_OFFSET..VNET_HDR_CSUM_OFFSET_OFFSET + 2] {
+        *b = 0;
+    }
+}
+
 #[derive(Clone)]
 pub struct TxVirtio {
     pub iovec: Vec<(GuestAddress, usize)>,
     pub frame_buf: [u8; MAX_BUFFER_SIZE],
     pub counter_bytes: Wrapping<u64>,
     pub counter_frames: Wrapping<u64>,
+    /// Sends the large frames without copying them to `frame_buf`.
+    pub splice: TxSplice,
+    /// Length of the virtio net header preceding each frame, which depends
+    /// on the features negotiated with the driver.
+    pub hdr_len: usize,
 }
 
 impl Default for TxVirtio {
@@ -40,10 +116,45 @@ impl TxVirtio {
             frame_buf: [0u8; MAX_BUFFER_SIZE],
             counter_bytes: Wrapping(0),
             counter_frames: Wrapping(0),
+            splice: TxSplice::new(),
+            hdr_len: vnet_hdr_len(),
         }
     }
 
-    pub fn process_desc_chain(&mut self, mem: &GuestMemoryMmap, tap: &mut Tap, queue: &mut Queue) {
+    /// Send the frames of the queue to the TAP device. A frame the rate
+    /// limiter refuses is left in the queue, along with the ones following
+    /// it, until the rate limiter timer expires.
+    ///
+    /// The large frames are spliced rather than copied when possible, their
+    /// descriptors only being given back to the guest once the TAP device
+    /// got them.
+    pub fn process_desc_chain<T: Write + AsRawFd>(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        tap: &mut T,
+        queue: &mut Queue,
+        rate_limiter: Option<&mut RateLimiter>,
+    ) -> Result<(), NetQueuePairError> {
+        // The frames are sent in order, which lets the used ring be written
+        // in a single batch when VIRTIO_F_IN_ORDER was negotiated.
+        let mut used = Vec::new();
+        let result = self.send_frames(mem, tap, queue, rate_limiter, &mut used);
+        if !used.is_empty() {
+            queue.add_used_batch(&mem, &used);
+            queue.update_avail_event(&mem);
+        }
+
+        result
+    }
+
+    fn send_frames<T: Write + AsRawFd>(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        tap: &mut T,
+        queue: &mut Queue,
+        mut rate_limiter: Option<&mut RateLimiter>,
+        used: &mut Vec<(u16, u32)>,
+    ) -> Result<(), NetQueuePairError> {
         while let Some(avail_desc) = queue.iter(&mem).next() {
             let head_index = avail_desc.index;
             let mut read_count = 0;
@@ -59,6 +170,28 @@ impl TxVirtio {
                 next_desc = desc.next_descriptor();
             }
 
+            if let Some(rate_limiter) = rate_limiter.as_mut() {
+                let bytes = read_count.saturating_sub(self.hdr_len) as u64;
+                if !rate_limiter
+                    .consume_request(bytes)
+                    .map_err(NetQueuePairError::RateLimiter)?
+                {
+                    queue.go_to_previous_position();
+                    break;
+                }
+            }
+
+            if read_count <= MAX_BUFFER_SIZE
+                && self
+                    .splice
+                    .send(mem, &self.iovec, read_count, tap.as_raw_fd())
+            {
+                self.counter_bytes += Wrapping(read_count.saturating_sub(self.hdr_len) as u64);
+                self.counter_frames += Wrapping(1);
+                used.push((head_index, 0));
+                continue;
+            }
+
             read_count = 0;
             // Copy buffer from across multiple descriptors.
             // TODO(performance - Issue #420): change this to use `writev()` instead of `write()`
@@ -88,12 +221,13 @@ impl TxVirtio {
                 }
             };
 
-            self.counter_bytes += Wrapping((read_count - vnet_hdr_len()) as u64);
+            self.counter_bytes += Wrapping(read_count.saturating_sub(self.hdr_len) as u64);
             self.counter_frames += Wrapping(1);
 
-            queue.add_used(&mem, head_index, 0);
-            queue.update_avail_event(&mem);
+            used.push((head_index, 0));
         }
+
+        Ok(())
     }
 }
 
@@ -105,6 +239,23 @@ pub struct RxVirtio {
     pub frame_buf: [u8; MAX_BUFFER_SIZE],
     pub counter_bytes: Wrapping<u64>,
     pub counter_frames: Wrapping<u64>,
+    /// Whether VIRTIO_NET_F_GUEST_CSUM was negotiated, letting frames reach
+    /// the driver with a partial checksum.
+    pub guest_csum: bool,
+    /// Whether VIRTIO_NET_F_MRG_RXBUF was negotiated, letting frames be
+    /// spread over several descriptor chains.
+    pub mergeable: bool,
+    /// Length of the virtio net header preceding each frame, which depends
+    /// on the features negotiated with the driver.
+    pub hdr_len: usize,
+    /// Whether the frame held in `frame_buf` went through the rate limiter
+    /// already, so that it isn't accounted for again once deferred.
+    pub frame_admitted: bool,
+    /// Records the time from reading each frame from the TAP device until
+    /// it's put in the used ring, when timestamping the received frames.
+    pub latency: Option<Arc<LatencyHistogram>>,
+    /// When the frame held in `frame_buf` was read from the TAP device.
+    pub read_at: Option<Instant>,
 }
 
 impl Default for RxVirtio {
@@ -122,6 +273,19 @@ impl RxVirtio {
             frame_buf: [0u8; MAX_BUFFER_SIZE],
             counter_bytes: Wrapping(0),
             counter_frames: Wrapping(0),
+            guest_csum: false,
+            mergeable: false,
+            hdr_len: vnet_hdr_len(),
+            frame_admitted: false,
+            latency: None,
+            read_at: None,
+        }
+    }
+
+    // The whole frame has been put in the used ring.
+    fn frame_received(&mut self) {
+        if let (Some(latency), Some(read_at)) = (&self.latency, self.read_at.take()) {
+            latency.record(read_at.elapsed());
         }
     }
 
@@ -167,7 +331,7 @@ impl RxVirtio {
             }
         }
 
-        self.counter_bytes += Wrapping((write_count - vnet_hdr_len()) as u64);
+        self.counter_bytes += Wrapping(write_count.saturating_sub(self.hdr_len) as u64);
         self.counter_frames += Wrapping(1);
 
         queue.add_used(&mem, head_index, write_count as u32);
@@ -183,9 +347,80 @@ impl RxVirtio {
             false
         } else {
             self.bytes_read = 0;
+            self.frame_received();
             true
         }
     }
+
+    /// Spread the frame over as many descriptor chains as needed to hold it
+    /// entirely, the header telling the driver how many of them it takes.
+    /// Returns false, leaving the queue untouched, if the driver didn't make
+    /// enough of them available yet.
+    pub fn process_mergeable_desc_chains(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        queue: &mut Queue,
+    ) -> bool {
+        let mut buffers = Vec::new();
+        let mut capacity = 0;
+
+        while capacity < self.bytes_read {
+            let avail_desc = match queue.iter(&mem).next() {
+                Some(avail_desc) => avail_desc,
+                None => {
+                    for _ in 0..buffers.len() {
+                        queue.go_to_previous_position();
+                    }
+                    return false;
+                }
+            };
+
+            let head_index = avail_desc.index;
+            let mut iovec = Vec::new();
+            let mut next_desc = Some(avail_desc);
+            while let Some(desc) = next_desc {
+                if !desc.is_write_only() {
+                    break;
+                }
+                iovec.push((desc.addr, desc.len as usize));
+                capacity += desc.len as usize;
+                next_desc = desc.next_descriptor();
+            }
+            buffers.push((head_index, iovec));
+        }
+
+        let num_buffers = buffers.len() as u16;
+        self.frame_buf[VNET_HDR_NUM_BUFFERS_OFFSET..VNET_HDR_NUM_BUFFERS_OFFSET + 2]
+            .copy_from_slice(&num_buffers.to_le_bytes());
+
+        let mut write_count = 0;
+        let mut used = Vec::with_capacity(buffers.len());
+        for (head_index, iovec) in buffers {
+            let mut len = 0;
+            for (desc_addr, desc_len) in iovec {
+                let limit = cmp::min(write_count + desc_len, self.bytes_read);
+                if let Err(e) = mem.write_slice(&self.frame_buf[write_count..limit], desc_addr) {
+                    error!("Failed to write slice: {:?}", e);
+                }
+                len += limit - write_count;
+                write_count = limit;
+            }
+            used.push((head_index, len as u32));
+        }
+
+        self.counter_bytes += Wrapping(write_count.saturating_sub(self.hdr_len) as u64);
+        self.counter_frames += Wrapping(1);
+
+        queue.add_used_batch(&mem, &used);
+        queue.update_avail_event(&mem);
+
+        // Mark that we have at least one pending packet and we need to interrupt the guest.
+        self.deferred_irqs = true;
+        self.bytes_read = 0;
+        self.frame_received();
+
+        true
+    }
 }
 
 #[derive(Default, Clone)]
@@ -194,6 +429,131 @@ pub struct NetCounters {
     pub tx_frames: Arc<AtomicU64>,
     pub rx_bytes: Arc<AtomicU64>,
     pub rx_frames: Arc<AtomicU64>,
+    /// Frames dropped as the driver didn't provide any RX buffer for them.
+    pub rx_dropped: Arc<AtomicU64>,
+}
+
+/// What happens to the frames read from the TAP device while the driver
+/// doesn't provide any RX buffer to receive them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RxStarvationPolicy {
+    /// Stop reading from the TAP device until the driver provides buffers,
+    /// leaving the frames queued on the host.
+    Pause,
+    /// Keep reading from the TAP device, dropping the frames.
+    Drop,
+    /// Pause for up to the given delay, and then drop the frames until the
+    /// driver provides buffers.
+    BlockWithTimeout(Duration),
+}
+
+impl Default for RxStarvationPolicy {
+    fn default() -> Self {
+        RxStarvationPolicy::Pause
+    }
+}
+
+/// Decides, according to its policy, whether the frames the RX queue has
+/// no buffer for must wait for buffers or be dropped.
+pub struct RxStarvation {
+    policy: RxStarvationPolicy,
+    // Wakes the device up when the frames must start being dropped, with
+    // the BlockWithTimeout policy.
+    timer: Option<TimerFd>,
+    // The timer is never rearmed before it expires, so that reading it when
+    // its event is reported can't block.
+    timer_armed: bool,
+    // Since when the queue is starved.
+    since: Option<Instant>,
+}
+
+impl Default for RxStarvation {
+    fn default() -> Self {
+        RxStarvation {
+            policy: RxStarvationPolicy::Pause,
+            timer: None,
+            timer_armed: false,
+            since: None,
+        }
+    }
+}
+
+impl RxStarvation {
+    pub fn new(policy: RxStarvationPolicy) -> io::Result<Self> {
+        let timer = match policy {
+            RxStarvationPolicy::BlockWithTimeout(_) => {
+                Some(TimerFd::new().map_err(|e| io::Error::from_raw_os_error(e.errno()))?)
+            }
+            _ => None,
+        };
+
+        Ok(RxStarvation {
+            policy,
+            timer,
+            timer_armed: false,
+            since: None,
+        })
+    }
+
+    /// Timer to listen to, whose expiry must be reported through
+    /// `NetQueuePair::rx_starvation_timeout()`.
+    pub fn timer_fd(&self) -> Option<RawFd> {
+        self.timer.as_ref().map(|timer| timer.as_raw_fd())
+    }
+
+    // The RX queue has no buffer for a frame at `now`. Returns whether the
+    // frame must be dropped rather than wait for buffers.
+    fn starved(&mut self, now: Instant) -> io::Result<bool> {
+        let timeout = match self.policy {
+            RxStarvationPolicy::Pause => return Ok(false),
+            RxStarvationPolicy::Drop => return Ok(true),
+            RxStarvationPolicy::BlockWithTimeout(timeout) => timeout,
+        };
+
+        let elapsed = now.duration_since(*self.since.get_or_insert(now));
+        if elapsed >= timeout {
+            return Ok(true);
+        }
+
+        // A timer still running from a previous starvation expires earlier,
+        // in which case it gets rearmed for what's left of the delay.
+        self.arm_timer(timeout - elapsed)?;
+        Ok(false)
+    }
+
+    // The driver provided buffers.
+    fn replenished(&mut self) {
+        self.since = None;
+    }
+
+    // The timer expired at `now`. Returns whether the frames waiting for
+    // buffers must now be dropped.
+    fn timer_expired(&mut self, now: Instant) -> io::Result<bool> {
+        if let Some(timer) = &self.timer {
+            timer
+                .wait()
+                .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+        }
+        self.timer_armed = false;
+
+        if self.since.is_some() {
+            self.starved(now)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn arm_timer(&mut self, delay: Duration) -> io::Result<()> {
+        if let Some(timer) = &self.timer {
+            if !self.timer_armed {
+                timer
+                    .reset(delay, None)
+                    .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+                self.timer_armed = true;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -206,6 +566,10 @@ pub enum NetQueuePairError {
     UnregisterListener(io::Error),
     /// Error reading from the TAP device
     FailedReadTap,
+    /// Error using the RX starvation timer
+    RxStarvationTimer(io::Error),
+    /// Error using the rate limiter
+    RateLimiter(rate_limiter::Error),
 }
 
 pub struct NetQueuePair {
@@ -217,6 +581,9 @@ pub struct NetQueuePair {
     pub rx_tap_listening: bool,
     pub counters: NetCounters,
     pub tap_event_id: u16,
+    pub rx_starvation: RxStarvation,
+    pub rx_rate_limiter: Option<RateLimiter>,
+    pub tx_rate_limiter: Option<RateLimiter>,
 }
 
 impl NetQueuePair {
@@ -224,38 +591,108 @@ impl NetQueuePair {
     // if a buffer was used, and false if the frame must be deferred until a buffer
     // is made available by the driver.
     fn rx_single_frame(&mut self, mut queue: &mut Queue) -> Result<bool, NetQueuePairError> {
+        // The TAP device isn't listened to while the rate limiter refuses
+        // the frame, until its timer expires.
+        if !self.rx.frame_admitted {
+            if let Some(rate_limiter) = self.rx_rate_limiter.as_mut() {
+                let bytes = self.rx.bytes_read.saturating_sub(self.rx.hdr_len) as u64;
+                if !rate_limiter
+                    .consume_request(bytes)
+                    .map_err(NetQueuePairError::RateLimiter)?
+                {
+                    self.unlisten_tap()?;
+                    return Ok(false);
+                }
+            }
+            self.rx.frame_admitted = true;
+        }
+
         let mem = self
             .mem
             .as_ref()
             .ok_or(NetQueuePairError::NoMemoryConfigured)
             .map(|m| m.memory())?;
-        let next_desc = queue.iter(&mem).next();
+
+        let next_desc = if self.rx.mergeable {
+            if self.rx.process_mergeable_desc_chains(&mem, &mut queue) {
+                return Ok(true);
+            }
+            None
+        } else {
+            queue.iter(&mem).next()
+        };
 
         if next_desc.is_none() {
-            // Queue has no available descriptors
-            if self.rx_tap_listening {
-                unregister_listener(
-                    self.epoll_fd.unwrap(),
-                    self.tap.as_raw_fd(),
-                    epoll::Events::EPOLLIN,
-                    u64::from(self.tap_event_id),
-                )
-                .map_err(NetQueuePairError::UnregisterListener)?;
-                self.rx_tap_listening = false;
-                info!("Listener unregistered");
+            // Queue has not enough available descriptors, until the driver
+            // provides more of them.
+            if self
+                .rx_starvation
+                .starved(Instant::now())
+                .map_err(NetQueuePairError::RxStarvationTimer)?
+            {
+                self.drop_rx_frame();
+                return Ok(true);
             }
+
+            self.unlisten_tap()?;
             return Ok(false);
         }
 
         Ok(self.rx.process_desc_chain(&mem, next_desc, &mut queue))
     }
 
+    fn drop_rx_frame(&mut self) {
+        self.rx.bytes_read = 0;
+        self.counters.rx_dropped.fetch_add(1, Ordering::AcqRel);
+    }
+
+    // Listen to the TAP device again, if it isn't already.
+    fn listen_tap(&mut self) -> Result<(), NetQueuePairError> {
+        if !self.rx_tap_listening {
+            register_listener(
+                self.epoll_fd.unwrap(),
+                self.tap.as_raw_fd(),
+                epoll::Events::EPOLLIN,
+                u64::from(self.tap_event_id),
+            )
+            .map_err(NetQueuePairError::RegisterListener)?;
+            self.rx_tap_listening = true;
+            info!("Listener registered");
+        }
+        Ok(())
+    }
+
+    // Stop listening to the TAP device, if it isn't already.
+    fn unlisten_tap(&mut self) -> Result<(), NetQueuePairError> {
+        if self.rx_tap_listening {
+            unregister_listener(
+                self.epoll_fd.unwrap(),
+                self.tap.as_raw_fd(),
+                epoll::Events::EPOLLIN,
+                u64::from(self.tap_event_id),
+            )
+            .map_err(NetQueuePairError::UnregisterListener)?;
+            self.rx_tap_listening = false;
+            info!("Listener unregistered");
+        }
+        Ok(())
+    }
+
     fn process_rx(&mut self, queue: &mut Queue) -> Result<bool, NetQueuePairError> {
         // Read as many frames as possible.
         loop {
             match self.read_tap() {
                 Ok(count) => {
+                    if self.rx.latency.is_some() {
+                        self.rx.read_at = Some(Instant::now());
+                    }
                     self.rx.bytes_read = count;
+                    self.rx.frame_admitted = false;
+                    fixup_rx_checksum(
+                        &mut self.rx.frame_buf[..count],
+                        self.rx.hdr_len,
+                        self.rx.guest_csum,
+                    );
                     if !self.rx_single_frame(queue)? {
                         self.rx.deferred_frame = true;
                         break;
@@ -302,17 +739,8 @@ impl NetQueuePair {
     }
 
     pub fn resume_rx(&mut self, queue: &mut Queue) -> Result<bool, NetQueuePairError> {
-        if !self.rx_tap_listening {
-            register_listener(
-                self.epoll_fd.unwrap(),
-                self.tap.as_raw_fd(),
-                epoll::Events::EPOLLIN,
-                u64::from(self.tap_event_id),
-            )
-            .map_err(NetQueuePairError::RegisterListener)?;
-            self.rx_tap_listening = true;
-            info!("Listener registered");
-        }
+        self.rx_starvation.replenished();
+        self.listen_tap()?;
         if self.rx.deferred_frame {
             if self.rx_single_frame(queue)? {
                 self.rx.deferred_frame = false;
@@ -341,7 +769,12 @@ impl NetQueuePair {
             .as_ref()
             .ok_or(NetQueuePairError::NoMemoryConfigured)
             .map(|m| m.memory())?;
-        self.tx.process_desc_chain(&mem, &mut self.tap, &mut queue);
+        self.tx.process_desc_chain(
+            &mem,
+            &mut self.tap,
+            &mut queue,
+            self.tx_rate_limiter.as_mut(),
+        )?;
 
         self.counters
             .tx_bytes
@@ -374,7 +807,555 @@ impl NetQueuePair {
         }
     }
 
+    /// The RX starvation timer expired: with the BlockWithTimeout policy, the
+    /// frames the RX queue has no buffer for start being dropped.
+    pub fn rx_starvation_timeout(&mut self, queue: &mut Queue) -> Result<bool, NetQueuePairError> {
+        if !self
+            .rx_starvation
+            .timer_expired(Instant::now())
+            .map_err(NetQueuePairError::RxStarvationTimer)?
+        {
+            return Ok(false);
+        }
+
+        if self.rx.deferred_frame {
+            self.drop_rx_frame();
+            self.rx.deferred_frame = false;
+        }
+        self.listen_tap()?;
+        self.process_rx(queue)
+    }
+
+    /// The RX rate limiter timer expired: the frames can be received again.
+    pub fn rx_rate_limiter_event(&mut self, queue: &mut Queue) -> Result<bool, NetQueuePairError> {
+        if let Some(rate_limiter) = self.rx_rate_limiter.as_mut() {
+            rate_limiter
+                .event_handler()
+                .map_err(NetQueuePairError::RateLimiter)?;
+        }
+        self.listen_tap()?;
+        self.process_rx_tap(queue)
+    }
+
+    /// The TX rate limiter timer expired: the frames can be sent again.
+    pub fn tx_rate_limiter_event(&mut self, queue: &mut Queue) -> Result<bool, NetQueuePairError> {
+        if let Some(rate_limiter) = self.tx_rate_limiter.as_mut() {
+            rate_limiter
+                .event_handler()
+                .map_err(NetQueuePairError::RateLimiter)?;
+        }
+        self.process_tx(queue)
+    }
+
     fn read_tap(&mut self) -> io::Result<usize> {
         self.tap.read(&mut self.rx.frame_buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SPLICE_MIN_FRAME_SIZE;
+    use std::io::{Seek, SeekFrom};
+    use vm_virtio::queue::testing::VirtQueue;
+    use vm_virtio::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use vmm_sys_util::tempfile::TempFile;
+
+    const ETH_HDR_LEN: usize = 14;
+    const IPV4_HDR_LEN: usize = 20;
+    const IPPROTO_TCP: u8 = 6;
+    const IPPROTO_UDP: u8 = 17;
+    const SRC_IP: [u8; 4] = [192, 168, 249, 1];
+    const DST_IP: [u8; 4] = [192, 168, 249, 2];
+
+    fn l4_offsets(protocol: u8) -> (usize, usize) {
+        // Header length and checksum offset.
+        match protocol {
+            IPPROTO_TCP => (20, 16),
+            _ => (8, 6),
+        }
+    }
+
+    fn pseudo_header(protocol: u8, l4_len: usize) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&SRC_IP);
+        header.extend_from_slice(&DST_IP);
+        header.extend_from_slice(&[0, protocol]);
+        header.extend_from_slice(&(l4_len as u16).to_be_bytes());
+        header
+    }
+
+    // Frame as read from the TAP device with a `hdr_len` bytes header, whose
+    // TCP or UDP checksum is left partial, only holding the pseudo header sum.
+    fn partial_csum_frame(hdr_len: usize, protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let (l4_hdr_len, csum_offset) = l4_offsets(protocol);
+        let l4_len = l4_hdr_len + payload.len();
+
+        let mut frame = vec![0u8; hdr_len];
+        frame[VNET_HDR_FLAGS_OFFSET] = VIRTIO_NET_HDR_F_NEEDS_CSUM as u8;
+        frame[VNET_HDR_CSUM_START_OFFSET..VNET_HDR_CSUM_START_OFFSET + 2]
+            .copy_from_slice(&((ETH_HDR_LEN + IPV4_HDR_LEN) as u16).to_le_bytes());
+        frame[VNET_HDR_CSUM_OFFSET_OFFSET..VNET_HDR_CSUM_OFFSET_OFFSET + 2]
+            .copy_from_slice(&(csum_offset as u16).to_le_bytes());
+
+        frame.extend_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        frame.extend_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x57]);
+        frame.extend_from_slice(&[0x08, 0x00]);
+
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&((IPV4_HDR_LEN + l4_len) as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]);
+        frame.extend_from_slice(&SRC_IP);
+        frame.extend_from_slice(&DST_IP);
+
+        let mut l4 = vec![0u8; l4_hdr_len];
+        l4[0..2].copy_from_slice(&1234u16.to_be_bytes());
+        l4[2..4].copy_from_slice(&80u16.to_be_bytes());
+        if protocol == IPPROTO_TCP {
+            l4[12] = 0x50;
+        } else {
+            l4[4..6].copy_from_slice(&(l4_len as u16).to_be_bytes());
+        }
+        l4.extend_from_slice(payload);
+        let pseudo_sum = ones_complement_sum(&pseudo_header(protocol, l4_len));
+        l4[csum_offset..csum_offset + 2].copy_from_slice(&pseudo_sum.to_be_bytes());
+        frame.extend_from_slice(&l4);
+
+        frame
+    }
+
+    fn l4_csum_valid(frame: &[u8], hdr_len: usize, protocol: u8) -> bool {
+        let l4 = &frame[hdr_len + ETH_HDR_LEN + IPV4_HDR_LEN..];
+        let mut data = pseudo_header(protocol, l4.len());
+        data.extend_from_slice(l4);
+        ones_complement_sum(&data) == 0xffff
+    }
+
+    const RX_BUFFER_SIZE: u32 = 0x100;
+
+    fn rx_buffer_addr(index: u16) -> GuestAddress {
+        GuestAddress(0x4000 + u64::from(index) * 0x1000)
+    }
+
+    // Make `count` buffers available from descriptor `first` onwards.
+    fn add_rx_buffers(vq: &VirtQueue, first: u16, count: u16) {
+        for i in first..first + count {
+            vq.dtable[i as usize].set(rx_buffer_addr(i).0, RX_BUFFER_SIZE, VIRTQ_DESC_F_WRITE, 0);
+            vq.avail.ring[i as usize].set(i);
+        }
+        vq.avail.idx.set(first + count);
+    }
+
+    // Frame of `len` bytes as read from the TAP device.
+    fn rx_frame(rx: &mut RxVirtio, len: usize) -> Vec<u8> {
+        for (i, b) in rx.frame_buf[..len].iter_mut().enumerate() {
+            *b = if i < vnet_hdr_len() { 0 } else { i as u8 };
+        }
+        rx.bytes_read = len;
+        rx.frame_buf[..len].to_vec()
+    }
+
+    // Check the frame was spread over the first `num_buffers` buffers.
+    fn check_merged_frame(m: &GuestMemoryMmap, vq: &VirtQueue, frame: &[u8], num_buffers: u16) {
+        assert_eq!(vq.used.idx.get(), num_buffers);
+
+        let mut expected = frame.to_vec();
+        expected[VNET_HDR_NUM_BUFFERS_OFFSET..VNET_HDR_NUM_BUFFERS_OFFSET + 2]
+            .copy_from_slice(&num_buffers.to_le_bytes());
+
+        let mut merged = Vec::new();
+        for i in 0..num_buffers {
+            let used = vq.used.ring[i as usize].get();
+            assert_eq!(used.id, u32::from(i));
+            let mut buf = vec![0u8; used.len as usize];
+            m.read_slice(&mut buf, rx_buffer_addr(i)).unwrap();
+            merged.extend(buf);
+        }
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn test_rx_mergeable_buffers() {
+        for (len, num_buffers) in &[(0x180, 2), (0x250, 3)] {
+            let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), m, 16);
+            let mut q = vq.create_queue();
+            add_rx_buffers(&vq, 0, 4);
+
+            let mut rx = RxVirtio::new();
+            rx.mergeable = true;
+            let frame = rx_frame(&mut rx, *len);
+            assert!(rx.process_mergeable_desc_chains(m, &mut q));
+            assert_eq!(rx.bytes_read, 0);
+            assert_eq!(q.next_avail.0, *num_buffers);
+            check_merged_frame(m, &vq, &frame, *num_buffers);
+        }
+    }
+
+    // With VIRTIO_F_IN_ORDER, only the last buffer of a frame is written to
+    // the used ring, the ones before it being full.
+    #[test]
+    fn test_rx_mergeable_buffers_in_order() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+        q.set_in_order(true);
+        add_rx_buffers(&vq, 0, 4);
+
+        let mut rx = RxVirtio::new();
+        rx.mergeable = true;
+        let frame = rx_frame(&mut rx, 0x250);
+        assert!(rx.process_mergeable_desc_chains(m, &mut q));
+        assert_eq!(vq.used.idx.get(), 3);
+        for i in 0..2 {
+            let used = vq.used.ring[i].get();
+            assert_eq!((used.id, used.len), (0, 0));
+        }
+        let used = vq.used.ring[2].get();
+        assert_eq!((used.id, used.len), (2, 0x50));
+
+        let mut merged = vec![0u8; 0x250];
+        for i in 0..3 {
+            let start = i * RX_BUFFER_SIZE as usize;
+            let end = cmp::min(start + RX_BUFFER_SIZE as usize, merged.len());
+            m.read_slice(&mut merged[start..end], rx_buffer_addr(i as u16))
+                .unwrap();
+        }
+        assert_eq!(&merged[vnet_hdr_len()..], &frame[vnet_hdr_len()..]);
+    }
+
+    // Each frame is read from the TAP device `delay` before being received,
+    // the latency being recorded at once, in the bucket matching it.
+    #[test]
+    fn test_rx_latency() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+        add_rx_buffers(&vq, 0, 8);
+
+        let latency = Arc::new(LatencyHistogram::new());
+        let mut rx = RxVirtio::new();
+        rx.mergeable = true;
+        rx.latency = Some(latency.clone());
+
+        let delays = [
+            Duration::from_micros(300),
+            Duration::from_micros(300),
+            Duration::from_millis(3),
+            Duration::from_millis(30),
+        ];
+        for (i, delay) in delays.iter().enumerate() {
+            let before = latency.buckets();
+            let start = Instant::now();
+            rx_frame(&mut rx, 0x80);
+            rx.read_at = Some(start - *delay);
+            assert!(rx.process_mergeable_desc_chains(m, &mut q));
+            let elapsed = start.elapsed();
+            assert!(rx.read_at.is_none());
+
+            // Only one bucket changed, within the bounds of the latency.
+            let after = latency.buckets();
+            let changed: Vec<usize> = (0..after.len())
+                .filter(|b| after[*b] != before[*b])
+                .collect();
+            assert_eq!(changed.len(), 1);
+            assert_eq!(after[changed[0]], before[changed[0]] + 1);
+            let lowest = LatencyHistogram::bucket(delay.as_nanos() as u64);
+            let highest = LatencyHistogram::bucket((*delay + elapsed).as_nanos() as u64);
+            assert!(changed[0] >= lowest && changed[0] <= highest);
+            assert_eq!(latency.summary().count, i as u64 + 1);
+        }
+
+        let summary = latency.summary();
+        assert!(summary.min_ns >= 300_000);
+        assert!(summary.p50_ns >= 300_000 && summary.p50_ns < 3_000_000);
+        assert!(summary.max_ns >= 30_000_000);
+
+        // Frames only waiting for buffers aren't recorded until received.
+        let mut rx = RxVirtio::new();
+        rx.mergeable = true;
+        rx.latency = Some(latency.clone());
+        rx_frame(&mut rx, 0x1000);
+        rx.read_at = Some(Instant::now());
+        assert!(!rx.process_mergeable_desc_chains(m, &mut q));
+        assert!(rx.read_at.is_some());
+        assert_eq!(latency.summary().count, 4);
+    }
+
+    #[test]
+    fn test_rx_mergeable_buffers_starved() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+        add_rx_buffers(&vq, 0, 2);
+
+        // The frame needs three buffers, so it waits for the driver to make
+        // the third one available, without any buffer being consumed.
+        let mut rx = RxVirtio::new();
+        rx.mergeable = true;
+        let frame = rx_frame(&mut rx, 0x250);
+        assert!(!rx.process_mergeable_desc_chains(m, &mut q));
+        assert_eq!(rx.bytes_read, frame.len());
+        assert_eq!(q.next_avail.0, 0);
+        assert_eq!(vq.used.idx.get(), 0);
+
+        add_rx_buffers(&vq, 2, 1);
+        assert!(rx.process_mergeable_desc_chains(m, &mut q));
+        check_merged_frame(m, &vq, &frame, 3);
+    }
+
+    // The driver stops providing RX buffers, while frames keep coming.
+    #[test]
+    fn test_rx_starvation_pause() {
+        let mut starvation = RxStarvation::new(RxStarvationPolicy::Pause).unwrap();
+        assert!(starvation.timer_fd().is_none());
+
+        let now = Instant::now();
+        assert!(!starvation.starved(now).unwrap());
+        assert!(!starvation.starved(now + Duration::from_secs(3600)).unwrap());
+    }
+
+    #[test]
+    fn test_rx_starvation_drop() {
+        let mut starvation = RxStarvation::new(RxStarvationPolicy::Drop).unwrap();
+        assert!(starvation.timer_fd().is_none());
+
+        assert!(starvation.starved(Instant::now()).unwrap());
+        starvation.replenished();
+        assert!(starvation.starved(Instant::now()).unwrap());
+    }
+
+    #[test]
+    fn test_rx_starvation_block_with_timeout() {
+        let timeout = Duration::from_millis(20);
+        let mut starvation =
+            RxStarvation::new(RxStarvationPolicy::BlockWithTimeout(timeout)).unwrap();
+        assert!(starvation.timer_fd().is_some());
+
+        // The frames wait for buffers until the timer expires.
+        let start = Instant::now();
+        assert!(!starvation.starved(start).unwrap());
+        assert!(starvation.timer_armed);
+        assert!(!starvation.starved(start + timeout / 2).unwrap());
+        assert!(starvation.timer_expired(start + timeout).unwrap());
+        assert!(start.elapsed() >= timeout);
+        assert!(starvation.starved(start + timeout * 2).unwrap());
+
+        // Buffers coming back start a new delay, and a timer expiring
+        // afterwards doesn't drop anything.
+        starvation.replenished();
+        let start = Instant::now();
+        assert!(!starvation.starved(start).unwrap());
+        starvation.replenished();
+        assert!(!starvation.timer_expired(start + timeout).unwrap());
+        assert!(!starvation.timer_armed);
+    }
+
+    #[test]
+    fn test_ones_complement_sum() {
+        // Example from RFC 1071.
+        assert_eq!(
+            ones_complement_sum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]),
+            0xddf2
+        );
+        assert_eq!(ones_complement_sum(&[0x12, 0x34, 0x56]), 0x6834);
+    }
+
+    #[test]
+    fn test_rx_csum_not_negotiated() {
+        for protocol in &[IPPROTO_TCP, IPPROTO_UDP] {
+            let mut frame = partial_csum_frame(vnet_hdr_len(), *protocol, b"hello, world!");
+            assert!(!l4_csum_valid(&frame, vnet_hdr_len(), *protocol));
+
+            fixup_rx_checksum(&mut frame, vnet_hdr_len(), false);
+            assert!(l4_csum_valid(&frame, vnet_hdr_len(), *protocol));
+            assert_eq!(frame[..vnet_hdr_len()], [0u8; 12]);
+        }
+    }
+
+    #[test]
+    fn test_rx_csum_negotiated() {
+        for protocol in &[IPPROTO_TCP, IPPROTO_UDP] {
+            let mut frame = partial_csum_frame(vnet_hdr_len(), *protocol, b"hello, world!");
+            let expected = frame.clone();
+
+            fixup_rx_checksum(&mut frame, vnet_hdr_len(), true);
+            assert_eq!(frame, expected);
+            assert!(!l4_csum_valid(&frame, vnet_hdr_len(), *protocol));
+        }
+    }
+
+    #[test]
+    fn test_rx_csum_data_valid() {
+        for protocol in &[IPPROTO_TCP, IPPROTO_UDP] {
+            let mut frame = partial_csum_frame(vnet_hdr_len(), *protocol, b"hello, world!");
+            fixup_rx_checksum(&mut frame, vnet_hdr_len(), false);
+            frame[VNET_HDR_FLAGS_OFFSET] = VIRTIO_NET_HDR_F_DATA_VALID as u8;
+            let expected = frame.clone();
+
+            fixup_rx_checksum(&mut frame, vnet_hdr_len(), true);
+            assert_eq!(frame, expected);
+
+            fixup_rx_checksum(&mut frame, vnet_hdr_len(), false);
+            assert_eq!(frame[VNET_HDR_FLAGS_OFFSET], 0);
+            assert_eq!(frame[1..], expected[1..]);
+            assert!(l4_csum_valid(&frame, vnet_hdr_len(), *protocol));
+        }
+    }
+
+    // Legacy drivers exchange frames with a 10 bytes header, the others with a
+    // 12 bytes one, which the byte counters and the checksum fixup skip.
+    #[test]
+    fn test_vnet_hdr_len() {
+        use crate::virtio_net_hdr_len;
+        use virtio_bindings::bindings::virtio_net::{VIRTIO_F_VERSION_1, VIRTIO_NET_F_MRG_RXBUF};
+
+        assert_eq!(virtio_net_hdr_len(0), 10);
+        assert_eq!(virtio_net_hdr_len(1 << VIRTIO_NET_F_MRG_RXBUF), 12);
+        assert_eq!(virtio_net_hdr_len(1 << VIRTIO_F_VERSION_1), 12);
+
+        for hdr_len in &[10, 12] {
+            let mut frame = partial_csum_frame(*hdr_len, IPPROTO_UDP, b"hello, world!");
+            fixup_rx_checksum(&mut frame, *hdr_len, false);
+            assert!(l4_csum_valid(&frame, *hdr_len, IPPROTO_UDP));
+            assert_eq!(frame[..*hdr_len], vec![0u8; *hdr_len][..]);
+            let payload_len = (frame.len() - hdr_len) as u64;
+
+            // Received as is.
+            let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), m, 16);
+            let mut q = vq.create_queue();
+            add_rx_buffers(&vq, 0, 1);
+
+            let mut rx = RxVirtio::new();
+            rx.hdr_len = *hdr_len;
+            rx.frame_buf[..frame.len()].copy_from_slice(&frame);
+            rx.bytes_read = frame.len();
+            let next_desc = q.iter(m).next();
+            assert!(rx.process_desc_chain(m, next_desc, &mut q));
+            let mut received = vec![0u8; frame.len()];
+            m.read_slice(&mut received, rx_buffer_addr(0)).unwrap();
+            assert_eq!(received, frame);
+            assert_eq!(rx.counter_bytes.0, payload_len);
+
+            // Sent as is.
+            let vq = VirtQueue::new(GuestAddress(0), m, 16);
+            let mut q = vq.create_queue();
+            m.write_slice(&frame, GuestAddress(0x8000)).unwrap();
+            vq.dtable[0].set(0x8000, frame.len() as u32, 0, 0);
+            vq.avail.ring[0].set(0);
+            vq.avail.idx.set(1);
+
+            let temp_file = TempFile::new().unwrap();
+            let mut file = temp_file.as_file().try_clone().unwrap();
+            let mut tx = TxVirtio::new();
+            tx.hdr_len = *hdr_len;
+            tx.process_desc_chain(m, &mut file, &mut q, None).unwrap();
+            let mut sent = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut sent).unwrap();
+            assert_eq!(sent, frame);
+            assert_eq!(tx.counter_bytes.0, payload_len);
+        }
+    }
+
+    // Make frames of the given lengths available, each spread over a
+    // descriptor holding the virtio net header, and one holding the rest.
+    // Returns the frames, as the TAP device must get them.
+    fn add_tx_frames(m: &GuestMemoryMmap, vq: &VirtQueue, lens: &[usize]) -> Vec<u8> {
+        let mut frames = Vec::new();
+        for (i, len) in lens.iter().enumerate() {
+            let addr = 0x10000 + i as u64 * 0x20000;
+            let frame: Vec<u8> = (0..*len).map(|b| (b as u8) ^ (i as u8)).collect();
+            m.write_slice(&frame, GuestAddress(addr)).unwrap();
+            frames.extend(frame);
+
+            let desc = 2 * i as u16;
+            vq.dtable[desc as usize].set(addr, vnet_hdr_len() as u32, VIRTQ_DESC_F_NEXT, desc + 1);
+            vq.dtable[desc as usize + 1].set(
+                addr + vnet_hdr_len() as u64,
+                (len - vnet_hdr_len()) as u32,
+                0,
+                0,
+            );
+            vq.avail.ring[i].set(desc);
+        }
+        vq.avail.idx.set(lens.len() as u16);
+
+        frames
+    }
+
+    // Send the frames to a file, returning what it got, and how many frames
+    // were spliced.
+    fn send_tx_frames(lens: &[usize], zero_copy: bool) -> (Vec<u8>, Vec<u8>, u64) {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x110000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+        let frames = add_tx_frames(m, &vq, lens);
+
+        let temp_file = TempFile::new().unwrap();
+        let mut file = temp_file.as_file().try_clone().unwrap();
+        let mut tx = TxVirtio::new();
+        if !zero_copy {
+            tx.splice.disable();
+        }
+        tx.process_desc_chain(m, &mut file, &mut q, None).unwrap();
+        assert_eq!(vq.used.idx.get(), lens.len() as u16);
+        assert_eq!(tx.counter_frames.0, lens.len() as u64);
+
+        let mut sent = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut sent).unwrap();
+
+        (frames, sent, tx.splice.frames)
+    }
+
+    #[test]
+    fn test_tx_zero_copy() {
+        // Only the frames above the threshold are spliced.
+        let lens = [0x100, SPLICE_MIN_FRAME_SIZE, 0x8000, MAX_BUFFER_SIZE];
+        let (frames, sent, spliced) = send_tx_frames(&lens, true);
+        assert_eq!(spliced, 3);
+        assert!(sent == frames);
+
+        let (frames, sent, spliced) = send_tx_frames(&lens, false);
+        assert_eq!(spliced, 0);
+        assert!(sent == frames);
+    }
+
+    // Compare the throughput of both paths, through:
+    // cargo test -p net_util --release -- --ignored --nocapture bench_tx
+    #[test]
+    #[ignore]
+    fn bench_tx_zero_copy() {
+        const ROUNDS: u32 = 2000;
+        let lens = [MAX_BUFFER_SIZE; 8];
+
+        for zero_copy in &[false, true] {
+            let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x110000)]).unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), m, 16);
+            let temp_file = TempFile::new().unwrap();
+            let mut file = temp_file.as_file().try_clone().unwrap();
+            let mut tx = TxVirtio::new();
+            if !zero_copy {
+                tx.splice.disable();
+            }
+
+            let start = Instant::now();
+            for _ in 0..ROUNDS {
+                let mut q = vq.create_queue();
+                add_tx_frames(m, &vq, &lens);
+                tx.process_desc_chain(m, &mut file, &mut q, None).unwrap();
+                file.seek(SeekFrom::Start(0)).unwrap();
+            }
+            let elapsed = start.elapsed();
+
+            let bytes = (ROUNDS as usize * lens.len() * MAX_BUFFER_SIZE) as f64;
+            println!(
+                "zero-copy {}: {:.0} MiB/s",
+                if *zero_copy { "on" } else { "off" },
+                bytes / elapsed.as_secs_f64() / (1024.0 * 1024.0)
+            );
+        }
+    }
+}
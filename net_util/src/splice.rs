@@ -0,0 +1,300 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Zero-copy transmission of the large frames. Rather than being copied to an
+//! intermediate buffer and written from it, the guest pages holding a frame
+//! are spliced to a pipe with vmsplice(2), and from the pipe to the TAP
+//! device with splice(2), the kernel only copying them once, to the socket
+//! buffer.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr::null_mut;
+use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap};
+
+/// Smallest frame worth splicing, below which copying it is cheaper than
+/// mapping its pages in the pipe.
+pub const SPLICE_MIN_FRAME_SIZE: usize = 16384;
+
+// Size of the pipe, which must hold a whole frame for the TAP device to get
+// it through a single write.
+const PIPE_SIZE: libc::c_int = 256 * 1024;
+
+// Pipe the frames go through, owning both its ends.
+struct Pipe {
+    read: File,
+    write: File,
+    // Pages the pipe holds at most, each buffer of the pipe referencing a
+    // single page.
+    buffers: usize,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // Safe because the return value is checked, and the file descriptors
+        // are owned from then on.
+        let (read, write) = unsafe {
+            if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))
+        };
+
+        // Safe because the return value is checked.
+        let size = unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, PIPE_SIZE) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Pipe {
+            read,
+            write,
+            buffers: size as usize / page_size(),
+        })
+    }
+
+    // Discard whatever is left in the pipe, releasing the guest pages it
+    // references.
+    fn drain(&mut self) {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.read.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+    }
+
+    // Splice the whole frame described by `iovecs` to `fd`.
+    fn send(&mut self, iovecs: &[libc::iovec], len: usize, fd: RawFd) -> Result<(), Error> {
+        // Safe because the guest pages remain mapped for as long as the guest
+        // memory is, and only referenced by the pipe until drained.
+        let ret = unsafe {
+            libc::vmsplice(
+                self.write.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len(),
+                libc::SPLICE_F_NONBLOCK,
+            )
+        };
+        if ret < 0 || ret as usize != len {
+            let e = io::Error::last_os_error();
+            self.drain();
+            return Err(Error::Failed(if ret < 0 {
+                e
+            } else {
+                io::Error::new(io::ErrorKind::WriteZero, "frame partially spliced")
+            }));
+        }
+
+        // Safe because the return value is checked.
+        let ret = unsafe {
+            libc::splice(
+                self.read.as_raw_fd(),
+                null_mut(),
+                fd,
+                null_mut(),
+                len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            self.drain();
+            return Err(if e.raw_os_error() == Some(libc::EINVAL) {
+                Error::Unsupported(e)
+            } else {
+                Error::Failed(e)
+            });
+        }
+        if ret as usize != len {
+            // Part of the frame went out already, which can't be undone.
+            warn!("net: tx: frame truncated to {} bytes out of {}", ret, len);
+            self.drain();
+        }
+
+        Ok(())
+    }
+}
+
+enum Error {
+    // The destination doesn't support splice(2).
+    Unsupported(io::Error),
+    // The frame couldn't be spliced, and must be copied instead.
+    Failed(io::Error),
+}
+
+fn page_size() -> usize {
+    // Safe because sysconf() has no side effect.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Splices the large frames to the TAP device, the pipe being created on
+/// the first one. Zero-copy gets disabled for good when the host kernel
+/// doesn't support it, every frame being copied from then on.
+pub struct TxSplice {
+    pipe: Option<Pipe>,
+    disabled: bool,
+    /// Frames sent without being copied.
+    pub frames: u64,
+}
+
+impl Default for TxSplice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Each copy gets its own pipe.
+impl Clone for TxSplice {
+    fn clone(&self) -> Self {
+        TxSplice {
+            pipe: None,
+            disabled: self.disabled,
+            frames: 0,
+        }
+    }
+}
+
+impl TxSplice {
+    pub fn new() -> Self {
+        TxSplice {
+            pipe: None,
+            disabled: false,
+            frames: 0,
+        }
+    }
+
+    /// Copy every frame.
+    pub fn disable(&mut self) {
+        self.pipe = None;
+        self.disabled = true;
+    }
+
+    /// Send the frame of `len` bytes spread over `descs` to `fd`, unless
+    /// it's too small, or its pages can't be spliced, or `fd` doesn't
+    /// support it. Returns whether the frame was sent, the caller copying it
+    /// otherwise.
+    ///
+    /// Once this returns, the pipe doesn't reference the guest pages anymore,
+    /// which the descriptors can be given back to the guest for.
+    pub fn send(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        descs: &[(GuestAddress, usize)],
+        len: usize,
+        fd: RawFd,
+    ) -> bool {
+        if self.disabled || len < SPLICE_MIN_FRAME_SIZE {
+            return false;
+        }
+
+        if self.pipe.is_none() {
+            match Pipe::new() {
+                Ok(pipe) => self.pipe = Some(pipe),
+                Err(e) => {
+                    warn!("net: tx: zero-copy disabled, failed to create pipe: {}", e);
+                    self.disable();
+                    return false;
+                }
+            }
+        }
+        let pipe = self.pipe.as_mut().unwrap();
+
+        let iovecs = match guest_iovecs(mem, descs, pipe.buffers) {
+            Some(iovecs) => iovecs,
+            None => return false,
+        };
+
+        match pipe.send(&iovecs, len, fd) {
+            Ok(()) => {
+                self.frames += 1;
+                true
+            }
+            Err(Error::Unsupported(e)) => {
+                warn!("net: tx: zero-copy disabled, splice not supported: {}", e);
+                self.disable();
+                false
+            }
+            Err(Error::Failed(e)) => {
+                debug!("net: tx: failed to splice frame: {}", e);
+                false
+            }
+        }
+    }
+}
+
+// Host ranges of the descriptors, provided each of them is contiguous in the
+// guest memory mapping, and they take at most `max_pages` pages in a pipe.
+fn guest_iovecs(
+    mem: &GuestMemoryMmap,
+    descs: &[(GuestAddress, usize)],
+    max_pages: usize,
+) -> Option<Vec<libc::iovec>> {
+    let page_size = page_size() as u64;
+    let mut pages = 0;
+    let mut iovecs = Vec::with_capacity(descs.len());
+    for (addr, len) in descs.iter().filter(|(_, len)| *len > 0) {
+        let last = addr.checked_add(*len as u64 - 1)?;
+        let start = mem.get_host_address(*addr).ok()?;
+        let end = mem.get_host_address(last).ok()?;
+        if (end as usize).wrapping_sub(start as usize) != len - 1 {
+            return None;
+        }
+
+        pages += (last.0 / page_size - addr.0 / page_size + 1) as usize;
+        if pages > max_pages {
+            return None;
+        }
+
+        iovecs.push(libc::iovec {
+            iov_base: start as *mut libc::c_void,
+            iov_len: *len,
+        });
+    }
+
+    Some(iovecs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_iovecs() {
+        let page = page_size() as u64;
+        let second = 32 * page;
+        let mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 16 * page as usize),
+            (GuestAddress(second), 16 * page as usize),
+        ])
+        .unwrap();
+        let host = |addr: u64| mem.get_host_address(GuestAddress(addr)).unwrap() as usize;
+
+        // The first descriptor straddles two pages.
+        let descs = [
+            (GuestAddress(page / 2), page as usize),
+            (GuestAddress(second), 0x10),
+        ];
+        let iovecs = guest_iovecs(&mem, &descs, 3).unwrap();
+        let ranges: Vec<(usize, usize)> = iovecs
+            .iter()
+            .map(|iovec| (iovec.iov_base as usize, iovec.iov_len))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![(host(page / 2), page as usize), (host(second), 0x10)]
+        );
+        assert!(guest_iovecs(&mem, &descs, 2).is_none());
+
+        // Crossing the end of a region.
+        assert!(guest_iovecs(&mem, &[(GuestAddress(15 * page), 2 * page as usize)], 64).is_none());
+        // Out of the guest memory.
+        assert!(guest_iovecs(&mem, &[(GuestAddress(64 * page), 0x10)], 64).is_none());
+    }
+}
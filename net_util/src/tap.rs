@@ -148,6 +148,32 @@ impl Tap {
         })
     }
 
+    /// Take over the file descriptor of an interface opened already, such
+    /// as one handed over by another process, its name being queried from
+    /// the kernel.
+    pub fn from_tap_fd(fd: RawFd) -> Result<Tap> {
+        // Safe because the caller hands the ownership of the fd over.
+        let tap_file = unsafe { File::from_raw_fd(fd) };
+
+        let mut ifreq: net_gen::ifreq = Default::default();
+        // ioctl is safe since we call it with a valid tap fd and check the return
+        // value.
+        let ret = unsafe { ioctl_with_mut_ref(&tap_file, net_gen::TUNGETIFF(), &mut ifreq) };
+        if ret < 0 {
+            return Err(Error::ConfigureTap(IoError::last_os_error()));
+        }
+
+        // Safe since only the name is accessed, and it's cloned out.
+        let if_name_temp = unsafe { *ifreq.ifr_ifrn.ifrn_name.as_ref() };
+        let if_name = if_name_temp
+            .iter()
+            .take_while(|c| **c != 0)
+            .cloned()
+            .collect();
+
+        Ok(Tap { tap_file, if_name })
+    }
+
     /// Create a new tap interface.
     pub fn new(num_queue_pairs: usize) -> Result<Tap> {
         Self::open_named("vmtap%d", num_queue_pairs)
@@ -280,6 +306,19 @@ impl Tap {
         Ok(())
     }
 
+    /// Attach the eBPF program picking the queue of each frame, or detach
+    /// it if `prog_fd` is -1. The program is shared by all the queues.
+    pub fn set_steering_ebpf(&self, prog_fd: RawFd) -> Result<()> {
+        // ioctl is safe. Called with a valid tap fd, and we check the return.
+        let ret =
+            unsafe { ioctl_with_ref(&self.tap_file, net_gen::TUNSETSTEERINGEBPF(), &prog_fd) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+
     /// Enable the tap interface.
     pub fn enable(&self) -> Result<()> {
         let sock = create_socket().map_err(Error::NetUtil)?;
@@ -378,6 +417,7 @@ mod tests {
     extern crate pnet;
 
     use std::net::Ipv4Addr;
+    use std::os::unix::io::IntoRawFd;
     use std::str;
     use std::sync::{mpsc, Mutex};
     use std::thread;
@@ -582,6 +622,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_tap_from_fd() {
+        let tap = Tap::new(1).unwrap();
+        let fd = unsafe { libc::dup(tap.as_raw_fd()) };
+        assert!(fd >= 0);
+
+        let taken_over = Tap::from_tap_fd(fd).unwrap();
+        assert_eq!(taken_over.get_if_name(), tap.get_if_name());
+        assert_eq!(taken_over.as_raw_fd(), fd);
+
+        let null = std::fs::File::open("/dev/null").unwrap();
+        assert!(Tap::from_tap_fd(null.into_raw_fd()).is_err());
+    }
+
     #[test]
     fn test_raw_fd() {
         let tap = Tap::new(1).unwrap();
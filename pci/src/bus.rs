@@ -139,7 +139,9 @@ impl PciBus {
         pci_device_bdf: u32,
         device: Arc<Mutex<dyn PciDevice>>,
     ) -> Result<()> {
-        self.devices.insert(pci_device_bdf >> 3, device);
+        // Only the device number matters, the segment being implied by the
+        // bus itself.
+        self.devices.insert((pci_device_bdf >> 3) & 0x1f, device);
         Ok(())
     }
 
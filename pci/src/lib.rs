@@ -18,6 +18,7 @@ mod configuration;
 mod device;
 mod msi;
 mod msix;
+mod nvme;
 mod vfio;
 
 pub use self::bus::{PciBus, PciConfigIo, PciConfigMmio, PciRoot, PciRootError};
@@ -31,7 +32,8 @@ pub use self::device::{
 };
 pub use self::msi::{msi_num_enabled_vectors, MsiCap, MsiConfig};
 pub use self::msix::{MsixCap, MsixConfig, MsixTableEntry, MSIX_TABLE_ENTRY_SIZE};
-pub use self::vfio::{VfioPciDevice, VfioPciError};
+pub use self::nvme::{NvmeDisk, NvmeNamespace, NvmePciDevice, NVME_MAX_IO_QUEUES};
+pub use self::vfio::{AerStatus, VfioPciDevice, VfioPciError, VFIO_HUGE_BAR_SIZE};
 
 /// PCI has four interrupt pins A->D.
 #[derive(Copy, Clone)]
@@ -0,0 +1,2019 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Emulated NVMe 1.4 controller.
+//!
+//! This is a minimal controller, exposing each disk as a namespace. The
+//! controller registers, the doorbells and the MSI-X structures all live in
+//! the same memory BAR. The admin submission queue is processed from the
+//! doorbell write, while the I/O submission queues are processed by a worker
+//! thread so that the vCPU writing the doorbell doesn't wait for the disk.
+//! The data transfers rely on the `block_util` request execution.
+
+extern crate devices;
+extern crate vm_allocator;
+
+use crate::{
+    BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarRegionType,
+    PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType,
+    PciMassStorageSubclass, PciProgrammingInterface,
+};
+use anyhow::anyhow;
+use block_util::{Request, RequestType};
+use byteorder::{ByteOrder, LittleEndian};
+use devices::BusDevice;
+use std::any::Any;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::result;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use vm_allocator::SystemAllocator;
+use vm_device::interrupt::{
+    InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
+};
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
+    GuestMemoryError, GuestMemoryMmap, GuestUsize,
+};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+
+const SECTOR_SHIFT: u8 = 9;
+const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
+
+// Same identifiers as the legacy QEMU NVMe controller, which guests already
+// know how to drive.
+const NVME_PCI_VENDOR_ID: u16 = 0x8086;
+const NVME_PCI_DEVICE_ID: u16 = 0x5845;
+
+// BAR layout: controller registers, doorbells, MSI-X table and PBA.
+const NVME_REG_SIZE: u64 = 0x40;
+const NVME_DOORBELL_BAR_OFFSET: u64 = 0x1000;
+const NVME_DOORBELL_SIZE: u64 = 0x1000;
+const MSIX_TABLE_BAR_OFFSET: u64 = 0x2000;
+// The table can hold up to 256 entries, each entry being 128 bits.
+const MSIX_TABLE_SIZE: u64 = 0x1000;
+const MSIX_PBA_BAR_OFFSET: u64 = 0x3000;
+const MSIX_PBA_SIZE: u64 = 0x1000;
+// The BAR size must be a power of 2.
+const NVME_BAR_SIZE: u64 = 0x4000;
+
+/// Maximum number of I/O queue pairs, limited by the size of the MSI-X table
+/// as each completion queue gets its own vector.
+pub const NVME_MAX_IO_QUEUES: u16 = 64;
+
+// Controller registers.
+const NVME_REG_CAP: usize = 0x00;
+const NVME_REG_VS: usize = 0x08;
+const NVME_REG_INTMS: usize = 0x0c;
+const NVME_REG_INTMC: usize = 0x10;
+const NVME_REG_CC: usize = 0x14;
+const NVME_REG_CSTS: usize = 0x1c;
+const NVME_REG_AQA: usize = 0x24;
+const NVME_REG_ASQ: usize = 0x28;
+const NVME_REG_ACQ: usize = 0x30;
+
+const NVME_VERSION: u32 = 0x0001_0400;
+
+// CAP fields.
+const NVME_CAP_CQR: u64 = 1 << 16;
+const NVME_CAP_TO_SHIFT: u64 = 24;
+// Worst case time to wait for CSTS.RDY, in 500ms units.
+const NVME_CAP_TO: u64 = 0xf;
+const NVME_CAP_CSS_NVM: u64 = 1 << 37;
+
+// CC fields.
+const NVME_CC_EN: u32 = 1;
+const NVME_CC_CSS_SHIFT: u32 = 4;
+const NVME_CC_MPS_SHIFT: u32 = 7;
+const NVME_CC_SHN_SHIFT: u32 = 14;
+const NVME_CC_IOSQES_SHIFT: u32 = 16;
+const NVME_CC_IOCQES_SHIFT: u32 = 20;
+
+// CSTS fields.
+const NVME_CSTS_RDY: u32 = 1;
+const NVME_CSTS_CFS: u32 = 1 << 1;
+const NVME_CSTS_SHST_COMPLETE: u32 = 2 << 2;
+
+// Only 4KiB memory pages are supported.
+const NVME_PAGE_SHIFT: u64 = 12;
+const NVME_PAGE_SIZE: u64 = 1 << NVME_PAGE_SHIFT;
+// Maximum data transfer size, as a power of two of the page size.
+const NVME_MDTS: u8 = 5;
+const NVME_MAX_TRANSFER_SIZE: u64 = NVME_PAGE_SIZE << NVME_MDTS;
+
+// Log2 of the submission and completion queue entry sizes.
+const NVME_SQES: u32 = 6;
+const NVME_CQES: u32 = 4;
+const NVME_SQ_ENTRY_SIZE: u64 = 1 << NVME_SQES;
+const NVME_CQ_ENTRY_SIZE: u64 = 1 << NVME_CQES;
+
+const NVME_IDENTIFY_DATA_SIZE: usize = 4096;
+// Asynchronous Event Request Limit, 0's based.
+const NVME_AERL: u8 = 3;
+
+// Admin command set.
+const NVME_ADM_DELETE_SQ: u8 = 0x00;
+const NVME_ADM_CREATE_SQ: u8 = 0x01;
+const NVME_ADM_GET_LOG_PAGE: u8 = 0x02;
+const NVME_ADM_DELETE_CQ: u8 = 0x04;
+const NVME_ADM_CREATE_CQ: u8 = 0x05;
+const NVME_ADM_IDENTIFY: u8 = 0x06;
+const NVME_ADM_ABORT: u8 = 0x08;
+const NVME_ADM_SET_FEATURES: u8 = 0x09;
+const NVME_ADM_GET_FEATURES: u8 = 0x0a;
+const NVME_ADM_ASYNC_EVENT: u8 = 0x0c;
+
+// NVM command set.
+const NVME_CMD_FLUSH: u8 = 0x00;
+const NVME_CMD_WRITE: u8 = 0x01;
+const NVME_CMD_READ: u8 = 0x02;
+
+// Identify CNS values.
+const NVME_ID_CNS_NS: u32 = 0x00;
+const NVME_ID_CNS_CTRL: u32 = 0x01;
+const NVME_ID_CNS_NS_ACTIVE_LIST: u32 = 0x02;
+const NVME_ID_CNS_NS_DESC_LIST: u32 = 0x03;
+
+// Log pages.
+const NVME_LOG_ERROR: u32 = 0x01;
+const NVME_LOG_SMART: u32 = 0x02;
+const NVME_LOG_FW_SLOT: u32 = 0x03;
+
+// Features.
+const NVME_FEAT_VOLATILE_WC: usize = 0x06;
+const NVME_FEAT_NUM_QUEUES: usize = 0x07;
+const NVME_FEAT_ASYNC_EVENT: usize = 0x0b;
+const NVME_FEAT_SAVE: u32 = 1 << 31;
+
+// Status codes, as found in the status field of a completion entry.
+const NVME_SC_SUCCESS: u16 = 0x0;
+const NVME_SC_INVALID_OPCODE: u16 = 0x1;
+const NVME_SC_INVALID_FIELD: u16 = 0x2;
+const NVME_SC_DATA_XFER_ERROR: u16 = 0x4;
+const NVME_SC_INTERNAL: u16 = 0x6;
+const NVME_SC_INVALID_NS: u16 = 0xb;
+const NVME_SC_NS_WRITE_PROTECTED: u16 = 0x20;
+const NVME_SC_LBA_RANGE: u16 = 0x80;
+const NVME_SC_CQ_INVALID: u16 = 0x100;
+const NVME_SC_QID_INVALID: u16 = 0x101;
+const NVME_SC_QUEUE_SIZE: u16 = 0x102;
+const NVME_SC_AER_LIMIT: u16 = 0x105;
+const NVME_SC_INVALID_VECTOR: u16 = 0x108;
+const NVME_SC_INVALID_QUEUE_DELETION: u16 = 0x10c;
+const NVME_SC_FEATURE_NOT_SAVEABLE: u16 = 0x10d;
+const NVME_SC_DNR: u16 = 0x4000;
+
+#[derive(Copy, Clone)]
+enum PciNvmeProgrammingInterface {
+    NvmExpress = 0x02,
+}
+
+impl PciProgrammingInterface for PciNvmeProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+// Submission queue entry.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct NvmeCommand {
+    opcode: u8,
+    flags: u8,
+    cid: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    mptr: u64,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for NvmeCommand {}
+
+// Completion queue entry.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct NvmeCompletion {
+    result: u32,
+    rsvd: u32,
+    sq_head: u16,
+    sq_id: u16,
+    cid: u16,
+    status: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for NvmeCompletion {}
+
+#[derive(Copy, Clone)]
+struct SubmissionQueue {
+    addr: GuestAddress,
+    size: u16,
+    head: u16,
+    tail: u16,
+    cqid: u16,
+}
+
+#[derive(Copy, Clone)]
+struct CompletionQueue {
+    addr: GuestAddress,
+    size: u16,
+    head: u16,
+    tail: u16,
+    phase: bool,
+    vector: u16,
+    irq_enabled: bool,
+}
+
+impl CompletionQueue {
+    fn is_full(&self) -> bool {
+        (self.tail + 1) % self.size == self.head
+    }
+}
+
+// Queues, indexed by their identifier, the admin queues being 0. They are
+// shared between the vCPU threads writing the doorbells and the I/O worker.
+struct NvmeQueues {
+    sqs: Vec<Option<SubmissionQueue>>,
+    cqs: Vec<Option<CompletionQueue>>,
+    // A command couldn't be fetched, which is reported through CSTS.CFS.
+    fatal: bool,
+}
+
+impl NvmeQueues {
+    // Fetch the next command of a submission queue, provided its completion
+    // queue has room left. The command is returned along with the
+    // identifier of the completion queue and the completion entry, yet to
+    // be given a status.
+    fn pop(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        qid: usize,
+    ) -> Option<(NvmeCommand, usize, NvmeCompletion)> {
+        if self.fatal {
+            return None;
+        }
+
+        let sq = self.sqs.get_mut(qid)?.as_mut()?;
+        if sq.head == sq.tail {
+            return None;
+        }
+
+        // Stop processing until the driver frees some completion entries.
+        let cqid = sq.cqid as usize;
+        match self.cqs.get(cqid).and_then(|cq| *cq) {
+            Some(cq) if !cq.is_full() => {}
+            _ => return None,
+        }
+
+        let addr = sq
+            .addr
+            .unchecked_add(u64::from(sq.head) * NVME_SQ_ENTRY_SIZE);
+        let cmd: NvmeCommand = match mem.read_obj(addr) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                error!("Failed to read NVMe command: {}", e);
+                self.fatal = true;
+                return None;
+            }
+        };
+        sq.head = (sq.head + 1) % sq.size;
+
+        let entry = NvmeCompletion {
+            sq_head: sq.head,
+            sq_id: qid as u16,
+            cid: cmd.cid,
+            ..Default::default()
+        };
+        Some((cmd, cqid, entry))
+    }
+
+    // Write the entry to the completion queue, returning the vector to
+    // signal if any.
+    fn post_completion(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        cqid: usize,
+        mut entry: NvmeCompletion,
+    ) -> Option<u16> {
+        let cq = self.cqs.get_mut(cqid)?.as_mut()?;
+        entry.status = entry.status << 1 | cq.phase as u16;
+
+        // The last dword, holding the phase tag, must be written after the
+        // rest of the entry, since the driver relies on it to detect new
+        // entries.
+        let addr = cq
+            .addr
+            .unchecked_add(u64::from(cq.tail) * NVME_CQ_ENTRY_SIZE);
+        let (head, last) = entry.as_slice().split_at(12);
+        if let Err(e) = mem.write_slice(head, addr) {
+            error!("Failed to write NVMe completion: {}", e);
+            return None;
+        }
+        fence(Ordering::Release);
+        if let Err(e) = mem.write_slice(last, addr.unchecked_add(12)) {
+            error!("Failed to write NVMe completion: {}", e);
+            return None;
+        }
+
+        cq.tail += 1;
+        if cq.tail == cq.size {
+            cq.tail = 0;
+            cq.phase = !cq.phase;
+        }
+
+        if cq.irq_enabled {
+            Some(cq.vector)
+        } else {
+            None
+        }
+    }
+}
+
+pub trait NvmeDisk: Read + Seek + Write + Send {}
+impl<D: Read + Seek + Write + Send> NvmeDisk for D {}
+
+/// A namespace exposed by the controller, backed by a disk image.
+pub struct NvmeNamespace {
+    disk: Box<dyn NvmeDisk>,
+    nsectors: u64,
+    readonly: bool,
+    writeback: bool,
+}
+
+impl NvmeNamespace {
+    pub fn new(mut disk: Box<dyn NvmeDisk>, readonly: bool) -> io::Result<Self> {
+        let disk_size = disk.seek(SeekFrom::End(0))?;
+        if disk_size % SECTOR_SIZE != 0 {
+            warn!(
+                "Disk size {} is not a multiple of sector size {}; \
+                 the remainder will not be visible to the guest.",
+                disk_size, SECTOR_SIZE
+            );
+        }
+
+        Ok(NvmeNamespace {
+            disk,
+            nsectors: disk_size / SECTOR_SIZE,
+            readonly,
+            writeback: true,
+        })
+    }
+
+    fn execute(&mut self, mem: &GuestMemoryMmap, cmd: &NvmeCommand) -> result::Result<(), u16> {
+        let request_type = match cmd.opcode {
+            NVME_CMD_FLUSH => {
+                return Request::new(RequestType::Flush, 0, GuestAddress(0), 0)
+                    .execute(&mut self.disk, self.nsectors, mem, &Vec::new())
+                    .map(|_| ())
+                    .map_err(|e| {
+                        error!("Failed to flush NVMe namespace: {:?}", e);
+                        NVME_SC_INTERNAL
+                    });
+            }
+            NVME_CMD_WRITE => RequestType::Out,
+            NVME_CMD_READ => RequestType::In,
+            opcode => {
+                warn!("Unsupported NVMe I/O command 0x{:x}", opcode);
+                return Err(NVME_SC_INVALID_OPCODE | NVME_SC_DNR);
+            }
+        };
+
+        let slba = u64::from(cmd.cdw11) << 32 | u64::from(cmd.cdw10);
+        let nlb = u64::from(cmd.cdw12 & 0xffff) + 1;
+        let len = nlb << SECTOR_SHIFT;
+        if len > NVME_MAX_TRANSFER_SIZE {
+            return Err(NVME_SC_INVALID_FIELD | NVME_SC_DNR);
+        }
+        match slba.checked_add(nlb) {
+            Some(end) if end <= self.nsectors => {}
+            _ => return Err(NVME_SC_LBA_RANGE | NVME_SC_DNR),
+        }
+        if request_type == RequestType::Out && self.readonly {
+            return Err(NVME_SC_NS_WRITE_PROTECTED | NVME_SC_DNR);
+        }
+
+        // The data segments don't have to be aligned on the sector size,
+        // since only the first one may start in the middle of a page. Whole
+        // sectors are transferred directly, while the sectors straddling two
+        // segments go through a bounce buffer.
+        let mut sector = slba;
+        let mut partial = Vec::new();
+        let mut partial_len = 0;
+        for (mut addr, mut seg_len) in prp_segments(mem, cmd.prp1, cmd.prp2, len)? {
+            if partial_len > 0 {
+                let chunk = cmp::min(seg_len, SECTOR_SIZE - partial_len);
+                partial.push((addr, chunk));
+                partial_len += chunk;
+                addr = addr.unchecked_add(chunk);
+                seg_len -= chunk;
+                if partial_len == SECTOR_SIZE {
+                    self.bounce_sector(mem, request_type, sector, &partial)?;
+                    partial.clear();
+                    partial_len = 0;
+                    sector += 1;
+                }
+            }
+
+            let whole = seg_len & !(SECTOR_SIZE - 1);
+            if whole > 0 {
+                let mut request = Request::new(request_type, sector, addr, whole as u32);
+                request.set_writeback(self.writeback);
+                request
+                    .execute(&mut self.disk, self.nsectors, mem, &Vec::new())
+                    .map_err(|e| {
+                        error!("Failed to execute NVMe request: {:?}", e);
+                        NVME_SC_DATA_XFER_ERROR
+                    })?;
+                sector += whole >> SECTOR_SHIFT;
+                addr = addr.unchecked_add(whole);
+                seg_len -= whole;
+            }
+
+            if seg_len > 0 {
+                partial.push((addr, seg_len));
+                partial_len += seg_len;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Transfer a single sector, scattered across several guest memory
+    // segments.
+    fn bounce_sector(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        request_type: RequestType,
+        sector: u64,
+        segments: &[(GuestAddress, u64)],
+    ) -> result::Result<(), u16> {
+        let disk_error = |e: io::Error| {
+            error!("Failed to execute NVMe request: {}", e);
+            NVME_SC_DATA_XFER_ERROR
+        };
+        let mem_error = |e: GuestMemoryError| {
+            error!("Failed to access NVMe data in guest memory: {}", e);
+            NVME_SC_DATA_XFER_ERROR
+        };
+
+        let mut data = [0u8; SECTOR_SIZE as usize];
+        self.disk
+            .seek(SeekFrom::Start(sector << SECTOR_SHIFT))
+            .map_err(disk_error)?;
+
+        let mut offset = 0;
+        if request_type == RequestType::In {
+            self.disk.read_exact(&mut data).map_err(disk_error)?;
+            for (addr, len) in segments {
+                let end = offset + *len as usize;
+                mem.write_slice(&data[offset..end], *addr)
+                    .map_err(mem_error)?;
+                offset = end;
+            }
+        } else {
+            for (addr, len) in segments {
+                let end = offset + *len as usize;
+                mem.read_slice(&mut data[offset..end], *addr)
+                    .map_err(mem_error)?;
+                offset = end;
+            }
+            self.disk.write_all(&data).map_err(disk_error)?;
+            if !self.writeback {
+                self.disk.flush().map_err(disk_error)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Namespace identifiers start from 1.
+fn namespace_index(nsid: u32, count: usize) -> result::Result<usize, u16> {
+    if nsid == 0 || nsid as usize > count {
+        return Err(NVME_SC_INVALID_NS | NVME_SC_DNR);
+    }
+    Ok(nsid as usize - 1)
+}
+
+// Copy `value`, padded with spaces, into an ASCII field of the identify data.
+fn set_ascii_field(field: &mut [u8], value: &str) {
+    for (i, b) in field.iter_mut().enumerate() {
+        *b = *value.as_bytes().get(i).unwrap_or(&b' ');
+    }
+}
+
+// Split a data transfer described by a pair of PRP entries into contiguous
+// guest memory segments.
+fn prp_segments(
+    mem: &GuestMemoryMmap,
+    prp1: u64,
+    prp2: u64,
+    len: u64,
+) -> result::Result<Vec<(GuestAddress, u64)>, u16> {
+    let mut segments = Vec::new();
+
+    let first = cmp::min(len, NVME_PAGE_SIZE - (prp1 & (NVME_PAGE_SIZE - 1)));
+    segments.push((GuestAddress(prp1), first));
+    let mut remaining = len - first;
+    if remaining == 0 {
+        return Ok(segments);
+    }
+
+    if remaining <= NVME_PAGE_SIZE {
+        segments.push((GuestAddress(prp2), remaining));
+        return Ok(segments);
+    }
+
+    // PRP2 points to a list of pages. When more entries are needed than what
+    // the list page can hold, its last entry points to the next list.
+    let mut entry = GuestAddress(prp2);
+    while remaining > 0 {
+        let addr: u64 = mem.read_obj(entry).map_err(|e| {
+            error!("Failed to read PRP list entry: {}", e);
+            NVME_SC_DATA_XFER_ERROR
+        })?;
+
+        let last_entry = (entry.raw_value() + 8) & (NVME_PAGE_SIZE - 1) == 0;
+        if last_entry && remaining > NVME_PAGE_SIZE {
+            entry = GuestAddress(addr);
+            continue;
+        }
+
+        let chunk = cmp::min(remaining, NVME_PAGE_SIZE);
+        segments.push((GuestAddress(addr), chunk));
+        remaining -= chunk;
+        entry = entry.unchecked_add(8);
+    }
+
+    Ok(segments)
+}
+
+fn signal(
+    msix_config: &Mutex<MsixConfig>,
+    interrupt_source_group: &dyn InterruptSourceGroup,
+    vector: u16,
+) {
+    let config = &mut msix_config.lock().unwrap();
+    let masked = match config.table_entries.get(vector as usize) {
+        Some(entry) => entry.masked(),
+        None => return,
+    };
+    // A masked vector only gets its pending bit set, the interrupt will be
+    // injected when unmasking it.
+    if config.masked() || masked {
+        config.set_pba_bit(vector, false);
+        return;
+    }
+
+    if let Err(e) = interrupt_source_group.trigger(vector as InterruptIndex) {
+        error!("Failed to signal NVMe completion: {}", e);
+    }
+}
+
+#[derive(Default)]
+struct WorkerState {
+    // The I/O submission queues might hold new commands.
+    kicked: bool,
+    paused: bool,
+    // Commands are being processed.
+    busy: bool,
+    exit: bool,
+}
+
+// Synchronization between the controller and its I/O worker.
+#[derive(Default)]
+struct WorkerControl {
+    state: Mutex<WorkerState>,
+    cond: Condvar,
+}
+
+impl WorkerControl {
+    fn kick(&self) {
+        self.state.lock().unwrap().kicked = true;
+        self.cond.notify_all();
+    }
+
+    // Stop processing commands, waiting for the one in flight to complete.
+    fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.paused = true;
+        while state.busy {
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+
+    fn resume(&self) {
+        self.state.lock().unwrap().paused = false;
+        self.cond.notify_all();
+    }
+
+    fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().exit = true;
+        self.cond.notify_all();
+    }
+
+    // Wait for commands to process, returning false once the worker must
+    // exit.
+    fn wait(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.busy = false;
+        self.cond.notify_all();
+        while !state.exit && (state.paused || !state.kicked) {
+            state = self.cond.wait(state).unwrap();
+        }
+        state.kicked = false;
+        state.busy = !state.exit;
+        state.busy
+    }
+}
+
+// Processes the I/O submission queues, on its own thread.
+struct IoWorker {
+    control: Arc<WorkerControl>,
+    queues: Arc<Mutex<NvmeQueues>>,
+    namespaces: Arc<Mutex<Vec<NvmeNamespace>>>,
+    memory: GuestMemoryAtomic<GuestMemoryMmap>,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+}
+
+impl IoWorker {
+    fn run(&self) {
+        while self.control.wait() {
+            self.process_queues();
+        }
+    }
+
+    // Take a command from each submission queue in turn, until they're all
+    // empty or their completion queues full. The completions are signaled
+    // after each round.
+    fn process_queues(&self) {
+        let mem = self.memory.memory();
+        let nqueues = self.queues.lock().unwrap().sqs.len();
+
+        loop {
+            let mut vectors = Vec::new();
+            let mut processed = false;
+
+            for qid in 1..nqueues {
+                // Leave the remaining commands for after the resume.
+                if self.control.is_paused() {
+                    self.control.kick();
+                    break;
+                }
+
+                let next = self.queues.lock().unwrap().pop(&mem, qid);
+                let (cmd, cqid, mut entry) = match next {
+                    Some(next) => next,
+                    None => continue,
+                };
+                processed = true;
+
+                let mut namespaces = self.namespaces.lock().unwrap();
+                let result = namespace_index(cmd.nsid, namespaces.len())
+                    .and_then(|index| namespaces[index].execute(&mem, &cmd));
+                drop(namespaces);
+                if let Err(status) = result {
+                    entry.status = status;
+                }
+
+                let vector = self
+                    .queues
+                    .lock()
+                    .unwrap()
+                    .post_completion(&mem, cqid, entry);
+                if let Some(vector) = vector {
+                    if !vectors.contains(&vector) {
+                        vectors.push(vector);
+                    }
+                }
+            }
+
+            for vector in vectors {
+                signal(&self.msix_config, &**self.interrupt_source_group, vector);
+            }
+
+            if !processed || self.control.is_paused() {
+                break;
+            }
+        }
+    }
+}
+
+pub struct NvmePciDevice {
+    id: String,
+
+    // PCI configuration registers.
+    configuration: PciConfiguration,
+
+    // MSI-X config
+    msix_config: Arc<Mutex<MsixConfig>>,
+    msix_num: u16,
+    interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+
+    // Guest memory
+    memory: GuestMemoryAtomic<GuestMemoryMmap>,
+
+    // Controller registers
+    cap: u64,
+    cc: u32,
+    csts: u32,
+    intms: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+
+    queues: Arc<Mutex<NvmeQueues>>,
+    max_io_queues: u16,
+
+    // The I/O worker holds the lock while executing a command.
+    namespaces: Arc<Mutex<Vec<NvmeNamespace>>>,
+    features: [u32; NVME_FEAT_ASYNC_EVENT + 1],
+    // Asynchronous event requests are never completed since the controller
+    // doesn't generate any event.
+    pending_aers: Vec<u16>,
+
+    worker: Arc<WorkerControl>,
+    worker_thread: Option<thread::JoinHandle<()>>,
+
+    // Details of bar regions to free
+    bar_regions: Vec<(GuestAddress, GuestUsize, PciBarRegionType)>,
+}
+
+impl NvmePciDevice {
+    /// Constructs a new NVMe controller exposing the given namespaces.
+    pub fn new(
+        id: String,
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        namespaces: Vec<NvmeNamespace>,
+        max_io_queues: u16,
+        queue_size: u16,
+        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        pci_device_bdf: u32,
+    ) -> io::Result<Self> {
+        let max_io_queues = cmp::max(1, cmp::min(max_io_queues, NVME_MAX_IO_QUEUES));
+        // One vector per completion queue, including the admin one.
+        let msix_num = max_io_queues + 1;
+
+        let interrupt_source_group = interrupt_manager.create_group(MsiIrqGroupConfig {
+            base: 0,
+            count: msix_num as InterruptIndex,
+        })?;
+
+        let msix_config = Arc::new(Mutex::new(MsixConfig::new(
+            msix_num,
+            interrupt_source_group.clone(),
+            pci_device_bdf,
+        )));
+
+        let configuration = PciConfiguration::new(
+            NVME_PCI_VENDOR_ID,
+            NVME_PCI_DEVICE_ID,
+            0x2,
+            PciClassCode::MassStorage,
+            &PciMassStorageSubclass::NVMController,
+            Some(&PciNvmeProgrammingInterface::NvmExpress),
+            PciHeaderType::Device,
+            NVME_PCI_VENDOR_ID,
+            NVME_PCI_DEVICE_ID,
+            Some(msix_config.clone()),
+        );
+
+        let cap = u64::from(queue_size - 1)
+            | NVME_CAP_CQR
+            | NVME_CAP_TO << NVME_CAP_TO_SHIFT
+            | NVME_CAP_CSS_NVM;
+
+        let mut features = [0u32; NVME_FEAT_ASYNC_EVENT + 1];
+        features[NVME_FEAT_VOLATILE_WC] = 1;
+
+        let queues = Arc::new(Mutex::new(NvmeQueues {
+            sqs: vec![None; max_io_queues as usize + 1],
+            cqs: vec![None; max_io_queues as usize + 1],
+            fatal: false,
+        }));
+        let namespaces = Arc::new(Mutex::new(namespaces));
+
+        let worker = Arc::new(WorkerControl::default());
+        let io_worker = IoWorker {
+            control: worker.clone(),
+            queues: queues.clone(),
+            namespaces: namespaces.clone(),
+            memory: memory.clone(),
+            msix_config: msix_config.clone(),
+            interrupt_source_group: interrupt_source_group.clone(),
+        };
+        let worker_thread = thread::Builder::new()
+            .name(id.clone())
+            .spawn(move || io_worker.run())?;
+
+        Ok(NvmePciDevice {
+            id,
+            configuration,
+            msix_config,
+            msix_num,
+            interrupt_source_group,
+            memory,
+            cap,
+            cc: 0,
+            csts: 0,
+            intms: 0,
+            aqa: 0,
+            asq: 0,
+            acq: 0,
+            queues,
+            max_io_queues,
+            namespaces,
+            features,
+            pending_aers: Vec::new(),
+            worker,
+            worker_thread: Some(worker_thread),
+            bar_regions: vec![],
+        })
+    }
+
+    fn read_regs(&self, offset: usize, data: &mut [u8]) {
+        let mut regs = [0u8; NVME_REG_SIZE as usize];
+        LittleEndian::write_u64(&mut regs[NVME_REG_CAP..], self.cap);
+        LittleEndian::write_u32(&mut regs[NVME_REG_VS..], NVME_VERSION);
+        LittleEndian::write_u32(&mut regs[NVME_REG_INTMS..], self.intms);
+        LittleEndian::write_u32(&mut regs[NVME_REG_INTMC..], self.intms);
+        LittleEndian::write_u32(&mut regs[NVME_REG_CC..], self.cc);
+        let mut csts = self.csts;
+        if self.queues.lock().unwrap().fatal {
+            csts |= NVME_CSTS_CFS;
+        }
+        LittleEndian::write_u32(&mut regs[NVME_REG_CSTS..], csts);
+        LittleEndian::write_u32(&mut regs[NVME_REG_AQA..], self.aqa);
+        LittleEndian::write_u64(&mut regs[NVME_REG_ASQ..], self.asq);
+        LittleEndian::write_u64(&mut regs[NVME_REG_ACQ..], self.acq);
+
+        if let Some(src) = regs.get(offset..offset + data.len()) {
+            data.copy_from_slice(src);
+        }
+    }
+
+    fn write_regs(&mut self, offset: usize, data: &[u8]) {
+        let value = match data.len() {
+            4 => u64::from(LittleEndian::read_u32(data)),
+            8 => LittleEndian::read_u64(data),
+            _ => {
+                warn!("Invalid NVMe register access size {}", data.len());
+                return;
+            }
+        };
+
+        match offset {
+            NVME_REG_INTMS => self.intms |= value as u32,
+            NVME_REG_INTMC => self.intms &= !(value as u32),
+            NVME_REG_CC => self.write_cc(value as u32),
+            NVME_REG_AQA => self.aqa = value as u32,
+            NVME_REG_ASQ if data.len() == 8 => self.asq = value,
+            NVME_REG_ASQ => self.asq = (self.asq & !0xffff_ffff) | value,
+            o if o == NVME_REG_ASQ + 4 => self.asq = (self.asq & 0xffff_ffff) | value << 32,
+            NVME_REG_ACQ if data.len() == 8 => self.acq = value,
+            NVME_REG_ACQ => self.acq = (self.acq & !0xffff_ffff) | value,
+            o if o == NVME_REG_ACQ + 4 => self.acq = (self.acq & 0xffff_ffff) | value << 32,
+            _ => warn!("Write to read-only NVMe register 0x{:x}", offset),
+        }
+    }
+
+    fn write_cc(&mut self, cc: u32) {
+        let was_enabled = self.cc & NVME_CC_EN != 0;
+        self.cc = cc;
+
+        if !was_enabled && cc & NVME_CC_EN != 0 {
+            self.enable();
+        } else if was_enabled && cc & NVME_CC_EN == 0 {
+            self.reset();
+        }
+
+        if (cc >> NVME_CC_SHN_SHIFT) & 0x3 != 0 {
+            self.shutdown();
+            self.csts |= NVME_CSTS_SHST_COMPLETE;
+        }
+    }
+
+    fn enable(&mut self) {
+        let asq_size = (self.aqa & 0xfff) as u16 + 1;
+        let acq_size = ((self.aqa >> 16) & 0xfff) as u16 + 1;
+        let css = (self.cc >> NVME_CC_CSS_SHIFT) & 0x7;
+        let mps = (self.cc >> NVME_CC_MPS_SHIFT) & 0xf;
+
+        if css != 0
+            || mps != 0
+            || self.asq == 0
+            || self.acq == 0
+            || self.asq & (NVME_PAGE_SIZE - 1) != 0
+            || self.acq & (NVME_PAGE_SIZE - 1) != 0
+            || asq_size < 2
+            || acq_size < 2
+        {
+            error!("Invalid NVMe controller configuration, cc 0x{:x}", self.cc);
+            self.csts |= NVME_CSTS_CFS;
+            return;
+        }
+
+        let mut queues = self.queues.lock().unwrap();
+        queues.sqs[0] = Some(SubmissionQueue {
+            addr: GuestAddress(self.asq),
+            size: asq_size,
+            head: 0,
+            tail: 0,
+            cqid: 0,
+        });
+        queues.cqs[0] = Some(CompletionQueue {
+            addr: GuestAddress(self.acq),
+            size: acq_size,
+            head: 0,
+            tail: 0,
+            phase: true,
+            vector: 0,
+            irq_enabled: true,
+        });
+
+        self.csts = NVME_CSTS_RDY;
+    }
+
+    fn reset(&mut self) {
+        // The commands in flight can't complete into the former queues.
+        self.worker.pause();
+        let mut queues = self.queues.lock().unwrap();
+        queues.sqs.iter_mut().for_each(|sq| *sq = None);
+        queues.cqs.iter_mut().for_each(|cq| *cq = None);
+        queues.fatal = false;
+        drop(queues);
+        self.worker.resume();
+
+        self.pending_aers.clear();
+        self.features = [0u32; NVME_FEAT_ASYNC_EVENT + 1];
+        self.features[NVME_FEAT_VOLATILE_WC] = 1;
+        self.namespaces
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .for_each(|ns| ns.writeback = true);
+        self.intms = 0;
+        self.csts = 0;
+    }
+
+    fn shutdown(&mut self) {
+        for ns in self.namespaces.lock().unwrap().iter_mut() {
+            if let Err(e) = ns.disk.flush() {
+                error!("Failed to flush NVMe namespace on shutdown: {}", e);
+            }
+        }
+    }
+
+    fn write_doorbell(&mut self, offset: u64, data: &[u8]) {
+        if data.len() != 4 || self.csts & NVME_CSTS_RDY == 0 {
+            return;
+        }
+
+        let value = LittleEndian::read_u32(data);
+        let index = (offset / 4) as usize;
+        let qid = index / 2;
+        let mut queues = self.queues.lock().unwrap();
+
+        if index % 2 == 0 {
+            match queues.sqs.get_mut(qid) {
+                Some(Some(sq)) if value < u32::from(sq.size) => sq.tail = value as u16,
+                _ => {
+                    warn!("Invalid submission queue {} doorbell 0x{:x}", qid, value);
+                    return;
+                }
+            }
+        } else {
+            match queues.cqs.get_mut(qid) {
+                Some(Some(cq)) if value < u32::from(cq.size) => cq.head = value as u16,
+                _ => {
+                    warn!("Invalid completion queue {} doorbell 0x{:x}", qid, value);
+                    return;
+                }
+            }
+            // Entries have been consumed, which might unblock submission
+            // queues waiting for room in this completion queue.
+            let blocked = queues
+                .sqs
+                .iter()
+                .flatten()
+                .any(|sq| sq.cqid as usize == qid && sq.head != sq.tail);
+            if !blocked {
+                return;
+            }
+        }
+        drop(queues);
+
+        // Only the admin submission queue uses the admin completion queue.
+        if qid == 0 {
+            self.process_admin_queue();
+        } else {
+            self.worker.kick();
+        }
+    }
+
+    fn process_admin_queue(&mut self) {
+        let memory = self.memory.clone();
+        let mem = memory.memory();
+        let mut vector = None;
+
+        loop {
+            let next = self.queues.lock().unwrap().pop(&mem, 0);
+            let (cmd, cqid, mut entry) = match next {
+                Some(next) => next,
+                None => break,
+            };
+
+            if let Some((status, result)) = self.admin_command(&mem, &cmd) {
+                entry.status = status;
+                entry.result = result;
+                let posted = self
+                    .queues
+                    .lock()
+                    .unwrap()
+                    .post_completion(&mem, cqid, entry);
+                vector = vector.or(posted);
+            }
+        }
+
+        if let Some(vector) = vector {
+            signal(&self.msix_config, &**self.interrupt_source_group, vector);
+        }
+    }
+
+    fn write_to_guest(
+        &self,
+        mem: &GuestMemoryMmap,
+        cmd: &NvmeCommand,
+        data: &[u8],
+    ) -> result::Result<(), u16> {
+        let mut offset = 0;
+        for (addr, len) in prp_segments(mem, cmd.prp1, cmd.prp2, data.len() as u64)? {
+            let end = offset + len as usize;
+            mem.write_slice(&data[offset..end], addr).map_err(|e| {
+                error!("Failed to write NVMe data to guest: {}", e);
+                NVME_SC_DATA_XFER_ERROR
+            })?;
+            offset = end;
+        }
+
+        Ok(())
+    }
+
+    fn admin_command(&mut self, mem: &GuestMemoryMmap, cmd: &NvmeCommand) -> Option<(u16, u32)> {
+        let result = match cmd.opcode {
+            NVME_ADM_DELETE_SQ => self.delete_sq(cmd),
+            NVME_ADM_CREATE_SQ => self.create_sq(cmd),
+            NVME_ADM_GET_LOG_PAGE => self.get_log_page(mem, cmd),
+            NVME_ADM_DELETE_CQ => self.delete_cq(cmd),
+            NVME_ADM_CREATE_CQ => self.create_cq(cmd),
+            NVME_ADM_IDENTIFY => self.identify(mem, cmd),
+            // Queued commands are never aborted, which the specification
+            // allows, as they complete soon enough.
+            NVME_ADM_ABORT => Ok(1),
+            NVME_ADM_SET_FEATURES => self.set_features(cmd),
+            NVME_ADM_GET_FEATURES => self.get_features(cmd),
+            NVME_ADM_ASYNC_EVENT => {
+                if self.pending_aers.len() > NVME_AERL as usize {
+                    Err(NVME_SC_AER_LIMIT | NVME_SC_DNR)
+                } else {
+                    self.pending_aers.push(cmd.cid);
+                    return None;
+                }
+            }
+            opcode => {
+                warn!("Unsupported NVMe admin command 0x{:x}", opcode);
+                Err(NVME_SC_INVALID_OPCODE | NVME_SC_DNR)
+            }
+        };
+
+        match result {
+            Ok(result) => Some((NVME_SC_SUCCESS, result)),
+            Err(status) => Some((status, 0)),
+        }
+    }
+
+    // Validate the identifier and the size of an I/O queue to create.
+    fn new_queue_params(&self, cmd: &NvmeCommand) -> result::Result<(usize, u16), u16> {
+        let qid = (cmd.cdw10 & 0xffff) as usize;
+        let size = (cmd.cdw10 >> 16) + 1;
+
+        if qid == 0 || qid > self.max_io_queues as usize {
+            return Err(NVME_SC_QID_INVALID | NVME_SC_DNR);
+        }
+        if size < 2 || size > (self.cap & 0xffff) as u32 + 1 {
+            return Err(NVME_SC_QUEUE_SIZE | NVME_SC_DNR);
+        }
+        // Only physically contiguous queues are supported.
+        if cmd.cdw11 & 0x1 == 0 || cmd.prp1 == 0 || cmd.prp1 & (NVME_PAGE_SIZE - 1) != 0 {
+            return Err(NVME_SC_INVALID_FIELD | NVME_SC_DNR);
+        }
+
+        Ok((qid, size as u16))
+    }
+
+    fn create_cq(&mut self, cmd: &NvmeCommand) -> result::Result<u32, u16> {
+        let (qid, size) = self.new_queue_params(cmd)?;
+        let mut queues = self.queues.lock().unwrap();
+        if queues.cqs[qid].is_some() {
+            return Err(NVME_SC_QID_INVALID | NVME_SC_DNR);
+        }
+        if (self.cc >> NVME_CC_IOCQES_SHIFT) & 0xf != NVME_CQES {
+            return Err(NVME_SC_INVALID_FIELD | NVME_SC_DNR);
+        }
+
+        let vector = (cmd.cdw11 >> 16) as u16;
+        if vector >= self.msix_num {
+            return Err(NVME_SC_INVALID_VECTOR | NVME_SC_DNR);
+        }
+
+        queues.cqs[qid] = Some(CompletionQueue {
+            addr: GuestAddress(cmd.prp1),
+            size,
+            head: 0,
+            tail: 0,
+            phase: true,
+            vector,
+            irq_enabled: cmd.cdw11 & 0x2 != 0,
+        });
+
+        Ok(0)
+    }
+
+    fn create_sq(&mut self, cmd: &NvmeCommand) -> result::Result<u32, u16> {
+        let (qid, size) = self.new_queue_params(cmd)?;
+        let mut queues = self.queues.lock().unwrap();
+        if queues.sqs[qid].is_some() {
+            return Err(NVME_SC_QID_INVALID | NVME_SC_DNR);
+        }
+        if (self.cc >> NVME_CC_IOSQES_SHIFT) & 0xf != NVME_SQES {
+            return Err(NVME_SC_INVALID_FIELD | NVME_SC_DNR);
+        }
+
+        let cqid = (cmd.cdw11 >> 16) as usize;
+        match queues.cqs.get(cqid) {
+            Some(Some(_)) if cqid != 0 => {}
+            _ => return Err(NVME_SC_CQ_INVALID | NVME_SC_DNR),
+        }
+
+        queues.sqs[qid] = Some(SubmissionQueue {
+            addr: GuestAddress(cmd.prp1),
+            size,
+            head: 0,
+            tail: 0,
+            cqid: cqid as u16,
+        });
+
+        Ok(0)
+    }
+
+    fn delete_sq(&mut self, cmd: &NvmeCommand) -> result::Result<u32, u16> {
+        let qid = (cmd.cdw10 & 0xffff) as usize;
+        // The command in flight must complete before the deletion does.
+        self.worker.pause();
+        let result = match self.queues.lock().unwrap().sqs.get_mut(qid) {
+            Some(sq) if qid != 0 && sq.is_some() => {
+                *sq = None;
+                Ok(0)
+            }
+            _ => Err(NVME_SC_QID_INVALID | NVME_SC_DNR),
+        };
+        self.worker.resume();
+        result
+    }
+
+    fn delete_cq(&mut self, cmd: &NvmeCommand) -> result::Result<u32, u16> {
+        let qid = (cmd.cdw10 & 0xffff) as usize;
+        let mut queues = self.queues.lock().unwrap();
+        match queues.cqs.get(qid) {
+            Some(Some(_)) if qid != 0 => {}
+            _ => return Err(NVME_SC_QID_INVALID | NVME_SC_DNR),
+        }
+
+        // The submission queues must be deleted first.
+        if queues
+            .sqs
+            .iter()
+            .flatten()
+            .any(|sq| sq.cqid as usize == qid)
+        {
+            return Err(NVME_SC_INVALID_QUEUE_DELETION | NVME_SC_DNR);
+        }
+
+        queues.cqs[qid] = None;
+        Ok(0)
+    }
+
+    fn identify(&self, mem: &GuestMemoryMmap, cmd: &NvmeCommand) -> result::Result<u32, u16> {
+        let mut data = [0u8; NVME_IDENTIFY_DATA_SIZE];
+
+        match cmd.cdw10 & 0xff {
+            NVME_ID_CNS_CTRL => self.identify_controller(&mut data),
+            NVME_ID_CNS_NS => {
+                let namespaces = self.namespaces.lock().unwrap();
+                let ns = &namespaces[namespace_index(cmd.nsid, namespaces.len())?];
+                LittleEndian::write_u64(&mut data[0x00..], ns.nsectors);
+                LittleEndian::write_u64(&mut data[0x08..], ns.nsectors);
+                LittleEndian::write_u64(&mut data[0x10..], ns.nsectors);
+                // Write protected namespace.
+                data[0x63] = ns.readonly as u8;
+                // Single LBA format, with 512 bytes sectors and no metadata.
+                LittleEndian::write_u32(&mut data[0x80..], u32::from(SECTOR_SHIFT) << 16);
+            }
+            NVME_ID_CNS_NS_ACTIVE_LIST => {
+                let nsids = (cmd.nsid + 1)..=self.namespace_count() as u32;
+                for (i, nsid) in nsids.take(NVME_IDENTIFY_DATA_SIZE / 4).enumerate() {
+                    LittleEndian::write_u32(&mut data[i * 4..], nsid);
+                }
+            }
+            // No namespace identification descriptor is reported.
+            NVME_ID_CNS_NS_DESC_LIST => {
+                namespace_index(cmd.nsid, self.namespace_count())?;
+            }
+            cns => {
+                warn!("Unsupported NVMe identify CNS 0x{:x}", cns);
+                return Err(NVME_SC_INVALID_FIELD | NVME_SC_DNR);
+            }
+        }
+
+        self.write_to_guest(mem, cmd, &data)?;
+        Ok(0)
+    }
+
+    fn identify_controller(&self, data: &mut [u8]) {
+        LittleEndian::write_u16(&mut data[0x00..], NVME_PCI_VENDOR_ID);
+        LittleEndian::write_u16(&mut data[0x02..], NVME_PCI_VENDOR_ID);
+        set_ascii_field(&mut data[0x04..0x18], &self.id);
+        set_ascii_field(&mut data[0x18..0x40], "Cloud Hypervisor NVMe Controller");
+        set_ascii_field(&mut data[0x40..0x48], "1.0");
+        // Recommended arbitration burst.
+        data[0x48] = 6;
+        data[0x4d] = NVME_MDTS;
+        LittleEndian::write_u32(&mut data[0x50..], NVME_VERSION);
+        // Abort command limit, 0's based.
+        data[0x102] = 3;
+        data[0x103] = NVME_AERL;
+        // A single read-only firmware slot.
+        data[0x104] = 0x3;
+        data[0x200] = (NVME_SQES << 4 | NVME_SQES) as u8;
+        data[0x201] = (NVME_CQES << 4 | NVME_CQES) as u8;
+        LittleEndian::write_u32(&mut data[0x204..], self.namespace_count() as u32);
+        // Volatile write cache present.
+        data[0x20d] = 1;
+        let subnqn = format!("nqn.2020-10.io.cloudhypervisor:nvme:{}", self.id);
+        let len = cmp::min(subnqn.len(), 0xff);
+        data[0x300..0x300 + len].copy_from_slice(&subnqn.as_bytes()[..len]);
+    }
+
+    fn namespace_count(&self) -> usize {
+        self.namespaces.lock().unwrap().len()
+    }
+
+    fn get_log_page(&self, mem: &GuestMemoryMmap, cmd: &NvmeCommand) -> result::Result<u32, u16> {
+        let numd = ((cmd.cdw11 & 0xffff) << 16 | cmd.cdw10 >> 16) as u64 + 1;
+        let len = numd * 4;
+        if len > NVME_MAX_TRANSFER_SIZE {
+            return Err(NVME_SC_INVALID_FIELD | NVME_SC_DNR);
+        }
+
+        // There is no error, health information or firmware to report.
+        match cmd.cdw10 & 0xff {
+            NVME_LOG_ERROR | NVME_LOG_SMART | NVME_LOG_FW_SLOT => {
+                self.write_to_guest(mem, cmd, &vec![0u8; len as usize])?;
+                Ok(0)
+            }
+            _ => Err(NVME_SC_INVALID_FIELD | NVME_SC_DNR),
+        }
+    }
+
+    fn num_queues(&self) -> u32 {
+        let n = u32::from(self.max_io_queues - 1);
+        n << 16 | n
+    }
+
+    fn get_features(&self, cmd: &NvmeCommand) -> result::Result<u32, u16> {
+        match (cmd.cdw10 & 0xff) as usize {
+            NVME_FEAT_NUM_QUEUES => Ok(self.num_queues()),
+            fid if fid != 0 && fid < self.features.len() => Ok(self.features[fid]),
+            _ => Err(NVME_SC_INVALID_FIELD | NVME_SC_DNR),
+        }
+    }
+
+    fn set_features(&mut self, cmd: &NvmeCommand) -> result::Result<u32, u16> {
+        if cmd.cdw10 & NVME_FEAT_SAVE != 0 {
+            return Err(NVME_SC_FEATURE_NOT_SAVEABLE | NVME_SC_DNR);
+        }
+
+        match (cmd.cdw10 & 0xff) as usize {
+            NVME_FEAT_NUM_QUEUES => {
+                if cmd.cdw11 & 0xffff == 0xffff || cmd.cdw11 >> 16 == 0xffff {
+                    return Err(NVME_SC_INVALID_FIELD | NVME_SC_DNR);
+                }
+                // All the supported queues are always allocated.
+                Ok(self.num_queues())
+            }
+            NVME_FEAT_VOLATILE_WC => {
+                let writeback = cmd.cdw11 & 0x1 != 0;
+                self.namespaces
+                    .lock()
+                    .unwrap()
+                    .iter_mut()
+                    .for_each(|ns| ns.writeback = writeback);
+                self.features[NVME_FEAT_VOLATILE_WC] = writeback as u32;
+                Ok(0)
+            }
+            fid if fid != 0 && fid < self.features.len() => {
+                self.features[fid] = cmd.cdw11;
+                Ok(0)
+            }
+            _ => Err(NVME_SC_INVALID_FIELD | NVME_SC_DNR),
+        }
+    }
+}
+
+impl PciDevice for NvmePciDevice {
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn allocate_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+    ) -> std::result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError>
+    {
+        // Use a 32-bit BAR so that the controller is easily accessible to
+        // firmware, as it is expected to be a boot device.
+        let region_type = PciBarRegionType::Memory32BitRegion;
+        let addr = allocator
+            .allocate_mmio_hole_addresses(None, NVME_BAR_SIZE, Some(NVME_BAR_SIZE))
+            .ok_or(PciDeviceError::IoAllocationFailed(NVME_BAR_SIZE))?;
+
+        let config = PciBarConfiguration::default()
+            .set_register_index(0)
+            .set_address(addr.raw_value())
+            .set_size(NVME_BAR_SIZE)
+            .set_region_type(region_type);
+        let bar = self
+            .configuration
+            .add_pci_bar(&config)
+            .map_err(|e| PciDeviceError::IoRegistrationFailed(addr.raw_value(), e))?
+            as u8;
+        self.bar_regions.push((addr, NVME_BAR_SIZE, region_type));
+
+        let msix_cap = MsixCap::new(
+            bar,
+            self.msix_num,
+            MSIX_TABLE_BAR_OFFSET as u32,
+            bar,
+            MSIX_PBA_BAR_OFFSET as u32,
+        );
+        self.configuration
+            .add_capability(&msix_cap)
+            .map_err(PciDeviceError::CapabilitiesSetup)?;
+
+        Ok(vec![(addr, NVME_BAR_SIZE, region_type)])
+    }
+
+    fn free_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        for (addr, length, _) in self.bar_regions.drain(..) {
+            allocator.free_mmio_hole_addresses(addr, length);
+        }
+        Ok(())
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> result::Result<(), std::io::Error> {
+        for (addr, _, _) in self.bar_regions.iter_mut() {
+            if (*addr).0 == old_base {
+                *addr = GuestAddress(new_base);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        match offset {
+            o if o < NVME_REG_SIZE => self.read_regs(o as usize, data),
+            o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .read_table(o - MSIX_TABLE_BAR_OFFSET, data);
+            }
+            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + MSIX_PBA_SIZE => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .read_pba(o - MSIX_PBA_BAR_OFFSET, data);
+            }
+            // Doorbells are write only.
+            _ => (),
+        }
+    }
+
+    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        match offset {
+            o if o < NVME_REG_SIZE => self.write_regs(o as usize, data),
+            o if NVME_DOORBELL_BAR_OFFSET <= o
+                && o < NVME_DOORBELL_BAR_OFFSET + NVME_DOORBELL_SIZE =>
+            {
+                self.write_doorbell(o - NVME_DOORBELL_BAR_OFFSET, data)
+            }
+            o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .write_table(o - MSIX_TABLE_BAR_OFFSET, data);
+            }
+            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + MSIX_PBA_SIZE => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .write_pba(o - MSIX_PBA_BAR_OFFSET, data);
+            }
+            _ => (),
+        }
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl BusDevice for NvmePciDevice {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl Drop for NvmePciDevice {
+    fn drop(&mut self) {
+        self.worker.stop();
+        if let Some(thread) = self.worker_thread.take() {
+            if let Err(e) = thread.join() {
+                error!("Error joining the NVMe I/O worker: {:?}", e);
+            }
+        }
+    }
+}
+
+// The vCPUs being paused first, only the I/O worker could still access the
+// guest memory.
+impl Pausable for NvmePciDevice {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.worker.pause();
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.worker.resume();
+        Ok(())
+    }
+}
+
+impl Snapshottable for NvmePciDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    // The queues state is not saved yet, refuse to produce a snapshot which
+    // couldn't be restored.
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "NVMe controller {} does not support snapshot",
+            self.id
+        )))
+    }
+
+    fn restore(&mut self, _snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        Err(MigratableError::Restore(anyhow!(
+            "NVMe controller {} does not support restore",
+            self.id
+        )))
+    }
+}
+
+impl Transportable for NvmePciDevice {}
+impl Migratable for NvmePciDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::time::Duration;
+    use vm_device::interrupt::InterruptSourceConfig;
+
+    const MEM_SIZE: usize = 0x100_0000;
+    const DISK_SIZE: usize = 0x10_0000;
+    const ASQ_ADDR: u64 = 0x1_0000;
+    const ACQ_ADDR: u64 = 0x2_0000;
+    const IO_SQ_ADDR: u64 = 0x3_0000;
+    const IO_CQ_ADDR: u64 = 0x4_0000;
+    const DATA_ADDR: u64 = 0x10_0000;
+    const QUEUE_SIZE: u16 = 16;
+
+    struct TestInterrupt {
+        triggered: Arc<Mutex<Vec<InterruptIndex>>>,
+    }
+
+    impl InterruptSourceGroup for TestInterrupt {
+        fn trigger(&self, index: InterruptIndex) -> result::Result<(), std::io::Error> {
+            self.triggered.lock().unwrap().push(index);
+            Ok(())
+        }
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+        ) -> result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    struct TestInterruptManager {
+        group: Arc<Box<dyn InterruptSourceGroup>>,
+    }
+
+    impl InterruptManager for TestInterruptManager {
+        type GroupConfig = MsiIrqGroupConfig;
+
+        fn create_group(
+            &self,
+            _config: Self::GroupConfig,
+        ) -> result::Result<Arc<Box<dyn InterruptSourceGroup>>, std::io::Error> {
+            Ok(self.group.clone())
+        }
+
+        fn destroy_group(
+            &self,
+            _group: Arc<Box<dyn InterruptSourceGroup>>,
+        ) -> result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    struct TestController {
+        device: NvmePciDevice,
+        mem: GuestMemoryMmap,
+        triggered: Arc<Mutex<Vec<InterruptIndex>>>,
+        sq_tails: [u16; 2],
+        cq_heads: [u16; 2],
+    }
+
+    // Blocks the writes until opened.
+    #[derive(Clone, Default)]
+    struct Gate(Arc<(Mutex<bool>, Condvar)>);
+
+    impl Gate {
+        fn open(&self) {
+            *(self.0).0.lock().unwrap() = true;
+            (self.0).1.notify_all();
+        }
+
+        fn wait(&self) {
+            let mut open = (self.0).0.lock().unwrap();
+            while !*open {
+                open = (self.0).1.wait(open).unwrap();
+            }
+        }
+    }
+
+    struct GatedDisk {
+        disk: Cursor<Vec<u8>>,
+        gate: Gate,
+    }
+
+    impl Read for GatedDisk {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.disk.read(buf)
+        }
+    }
+
+    impl Seek for GatedDisk {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.disk.seek(pos)
+        }
+    }
+
+    impl Write for GatedDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.gate.wait();
+            self.disk.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.disk.flush()
+        }
+    }
+
+    impl TestController {
+        fn new(readonly: bool) -> Self {
+            let disk = Box::new(Cursor::new(vec![0u8; DISK_SIZE]));
+            Self::with_disk(disk, readonly)
+        }
+
+        fn with_disk(disk: Box<dyn NvmeDisk>, readonly: bool) -> Self {
+            let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+            let triggered = Arc::new(Mutex::new(Vec::new()));
+            let interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>> =
+                Arc::new(TestInterruptManager {
+                    group: Arc::new(Box::new(TestInterrupt {
+                        triggered: triggered.clone(),
+                    })),
+                });
+            let mut device = NvmePciDevice::new(
+                String::from("_nvme0"),
+                GuestMemoryAtomic::new(mem.clone()),
+                vec![NvmeNamespace::new(disk, readonly).unwrap()],
+                1,
+                QUEUE_SIZE,
+                &interrupt_manager,
+                0,
+            )
+            .unwrap();
+
+            let aqa = u32::from(QUEUE_SIZE - 1) << 16 | u32::from(QUEUE_SIZE - 1);
+            device.write_bar(0, NVME_REG_AQA as u64, &aqa.to_le_bytes());
+            device.write_bar(0, NVME_REG_ASQ as u64, &ASQ_ADDR.to_le_bytes());
+            device.write_bar(0, NVME_REG_ACQ as u64, &ACQ_ADDR.to_le_bytes());
+            let cc =
+                NVME_CC_EN | NVME_SQES << NVME_CC_IOSQES_SHIFT | NVME_CQES << NVME_CC_IOCQES_SHIFT;
+            device.write_bar(0, NVME_REG_CC as u64, &cc.to_le_bytes());
+
+            TestController {
+                device,
+                mem,
+                triggered,
+                sq_tails: [0; 2],
+                cq_heads: [0; 2],
+            }
+        }
+
+        fn csts(&mut self) -> u32 {
+            let mut data = [0u8; 4];
+            self.device.read_bar(0, NVME_REG_CSTS as u64, &mut data);
+            u32::from_le_bytes(data)
+        }
+
+        // Ring the submission queue doorbell for a command, returning the
+        // command identifier.
+        fn ring(&mut self, qid: usize, mut cmd: NvmeCommand) -> u16 {
+            let sq_addr = if qid == 0 { ASQ_ADDR } else { IO_SQ_ADDR };
+
+            let slot = self.sq_tails[qid];
+            cmd.cid = slot + 0x100;
+            self.mem
+                .write_obj(
+                    cmd,
+                    GuestAddress(sq_addr + u64::from(slot) * NVME_SQ_ENTRY_SIZE),
+                )
+                .unwrap();
+            self.sq_tails[qid] = (slot + 1) % QUEUE_SIZE;
+            let tail = u32::from(self.sq_tails[qid]);
+            self.device.write_bar(
+                0,
+                NVME_DOORBELL_BAR_OFFSET + qid as u64 * 8,
+                &tail.to_le_bytes(),
+            );
+
+            cmd.cid
+        }
+
+        // Read the next completion entry of a queue, if it has been posted.
+        fn poll(&self, qid: usize) -> Option<NvmeCompletion> {
+            let cq_addr = if qid == 0 { ACQ_ADDR } else { IO_CQ_ADDR };
+            let head = self.cq_heads[qid];
+            let entry: NvmeCompletion = self
+                .mem
+                .read_obj(GuestAddress(cq_addr + u64::from(head) * NVME_CQ_ENTRY_SIZE))
+                .unwrap();
+
+            // The queues don't wrap in the tests, the phase tag of the new
+            // entries is always set.
+            if entry.status & 0x1 == 1 {
+                Some(entry)
+            } else {
+                None
+            }
+        }
+
+        // Wait for the next completion entry of a queue, and consume it.
+        fn complete(&mut self, qid: usize) -> NvmeCompletion {
+            // The I/O commands complete from the worker thread.
+            let mut entry = self.poll(qid);
+            for _ in 0..5000 {
+                if entry.is_some() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(1));
+                entry = self.poll(qid);
+            }
+            let entry = entry.expect("NVMe command didn't complete");
+            assert_eq!(entry.sq_id, qid as u16);
+
+            self.cq_heads[qid] = (self.cq_heads[qid] + 1) % QUEUE_SIZE;
+            let head = u32::from(self.cq_heads[qid]);
+            self.device.write_bar(
+                0,
+                NVME_DOORBELL_BAR_OFFSET + qid as u64 * 8 + 4,
+                &head.to_le_bytes(),
+            );
+
+            entry
+        }
+
+        // Submit a command and return its completion entry.
+        fn submit(&mut self, qid: usize, cmd: NvmeCommand) -> NvmeCompletion {
+            let cid = self.ring(qid, cmd);
+            let entry = self.complete(qid);
+            assert_eq!(entry.cid, cid);
+            entry
+        }
+
+        fn create_io_queues(&mut self) {
+            let cmd = NvmeCommand {
+                opcode: NVME_ADM_CREATE_CQ,
+                prp1: IO_CQ_ADDR,
+                cdw10: u32::from(QUEUE_SIZE - 1) << 16 | 1,
+                cdw11: 1 << 16 | 0x3,
+                ..Default::default()
+            };
+            assert_eq!(self.submit(0, cmd).status >> 1, NVME_SC_SUCCESS);
+
+            let cmd = NvmeCommand {
+                opcode: NVME_ADM_CREATE_SQ,
+                prp1: IO_SQ_ADDR,
+                cdw10: u32::from(QUEUE_SIZE - 1) << 16 | 1,
+                cdw11: 1 << 16 | 0x1,
+                ..Default::default()
+            };
+            assert_eq!(self.submit(0, cmd).status >> 1, NVME_SC_SUCCESS);
+        }
+
+        fn rw_command(opcode: u8, slba: u64, nlb: u16, prp1: u64, prp2: u64) -> NvmeCommand {
+            NvmeCommand {
+                opcode,
+                nsid: 1,
+                prp1,
+                prp2,
+                cdw10: slba as u32,
+                cdw11: (slba >> 32) as u32,
+                cdw12: u32::from(nlb - 1),
+                ..Default::default()
+            }
+        }
+    }
+
+    #[test]
+    fn test_nvme_identify() {
+        let mut ctrl = TestController::new(false);
+        assert_eq!(ctrl.csts() & NVME_CSTS_RDY, NVME_CSTS_RDY);
+
+        let cmd = NvmeCommand {
+            opcode: NVME_ADM_IDENTIFY,
+            prp1: DATA_ADDR,
+            cdw10: NVME_ID_CNS_CTRL,
+            ..Default::default()
+        };
+        assert_eq!(ctrl.submit(0, cmd).status >> 1, NVME_SC_SUCCESS);
+        let vid: u16 = ctrl.mem.read_obj(GuestAddress(DATA_ADDR)).unwrap();
+        let nn: u32 = ctrl.mem.read_obj(GuestAddress(DATA_ADDR + 0x204)).unwrap();
+        assert_eq!(vid, NVME_PCI_VENDOR_ID);
+        assert_eq!(nn, 1);
+
+        let cmd = NvmeCommand {
+            opcode: NVME_ADM_IDENTIFY,
+            nsid: 1,
+            prp1: DATA_ADDR,
+            cdw10: NVME_ID_CNS_NS,
+            ..Default::default()
+        };
+        assert_eq!(ctrl.submit(0, cmd).status >> 1, NVME_SC_SUCCESS);
+        let nsze: u64 = ctrl.mem.read_obj(GuestAddress(DATA_ADDR)).unwrap();
+        assert_eq!(nsze, DISK_SIZE as u64 / SECTOR_SIZE);
+
+        // Namespaces are numbered from 1.
+        let cmd = NvmeCommand {
+            opcode: NVME_ADM_IDENTIFY,
+            nsid: 2,
+            prp1: DATA_ADDR,
+            cdw10: NVME_ID_CNS_NS,
+            ..Default::default()
+        };
+        assert_eq!(
+            ctrl.submit(0, cmd).status >> 1,
+            NVME_SC_INVALID_NS | NVME_SC_DNR
+        );
+
+        let cmd = NvmeCommand {
+            opcode: NVME_ADM_GET_FEATURES,
+            cdw10: NVME_FEAT_NUM_QUEUES as u32,
+            ..Default::default()
+        };
+        let entry = ctrl.submit(0, cmd);
+        assert_eq!(entry.status >> 1, NVME_SC_SUCCESS);
+        assert_eq!(entry.result, 0);
+    }
+
+    #[test]
+    fn test_nvme_read_write() {
+        let mut ctrl = TestController::new(false);
+        ctrl.create_io_queues();
+
+        // Write 3 pages, which requires a PRP list.
+        let len = 3 * NVME_PAGE_SIZE;
+        let pattern: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        ctrl.mem
+            .write_slice(&pattern, GuestAddress(DATA_ADDR))
+            .unwrap();
+        let prp_list = DATA_ADDR + 0x10_0000;
+        ctrl.mem
+            .write_obj(DATA_ADDR + NVME_PAGE_SIZE, GuestAddress(prp_list))
+            .unwrap();
+        ctrl.mem
+            .write_obj(DATA_ADDR + 2 * NVME_PAGE_SIZE, GuestAddress(prp_list + 8))
+            .unwrap();
+        let nlb = (len / SECTOR_SIZE) as u16;
+        let cmd = TestController::rw_command(NVME_CMD_WRITE, 8, nlb, DATA_ADDR, prp_list);
+        assert_eq!(ctrl.submit(1, cmd).status >> 1, NVME_SC_SUCCESS);
+
+        // Read it back at a different location, in two pages.
+        let read_addr = DATA_ADDR + 0x8_0000;
+        let cmd =
+            TestController::rw_command(NVME_CMD_READ, 8, 16, read_addr, read_addr + NVME_PAGE_SIZE);
+        assert_eq!(ctrl.submit(1, cmd).status >> 1, NVME_SC_SUCCESS);
+        let mut data = vec![0u8; 2 * NVME_PAGE_SIZE as usize];
+        ctrl.mem
+            .read_slice(&mut data, GuestAddress(read_addr))
+            .unwrap();
+        assert_eq!(data[..], pattern[..data.len()]);
+
+        let cmd = TestController::rw_command(NVME_CMD_FLUSH, 0, 1, 0, 0);
+        assert_eq!(ctrl.submit(1, cmd).status >> 1, NVME_SC_SUCCESS);
+
+        // Accessing past the end of the namespace must fail.
+        let last = DISK_SIZE as u64 / SECTOR_SIZE;
+        let cmd = TestController::rw_command(NVME_CMD_READ, last, 1, read_addr, 0);
+        assert_eq!(
+            ctrl.submit(1, cmd).status >> 1,
+            NVME_SC_LBA_RANGE | NVME_SC_DNR
+        );
+
+        // Admin completions are signaled through vector 0, while the I/O
+        // queue uses the vector it has been created with. Pausing waits for
+        // the worker to be done signaling.
+        ctrl.device.pause().unwrap();
+        let triggered = ctrl.triggered.lock().unwrap();
+        assert_eq!(triggered[..2], [0, 0]);
+        assert!(triggered[2..].iter().all(|v| *v == 1));
+    }
+
+    #[test]
+    fn test_nvme_unaligned_prp() {
+        let mut ctrl = TestController::new(false);
+        ctrl.create_io_queues();
+
+        // Write 2 pages from the middle of a sector, which leaves a sector
+        // straddling each page boundary. The pages being contiguous, the
+        // data can be checked in place.
+        let len = 2 * NVME_PAGE_SIZE;
+        let pattern: Vec<u8> = (0..len).map(|i| (i % 253) as u8).collect();
+        let write_addr = DATA_ADDR + 0x300;
+        ctrl.mem
+            .write_slice(&pattern, GuestAddress(write_addr))
+            .unwrap();
+        let prp_list = DATA_ADDR + 0x10_0000;
+        ctrl.mem
+            .write_obj(DATA_ADDR + NVME_PAGE_SIZE, GuestAddress(prp_list))
+            .unwrap();
+        ctrl.mem
+            .write_obj(DATA_ADDR + 2 * NVME_PAGE_SIZE, GuestAddress(prp_list + 8))
+            .unwrap();
+        let nlb = (len / SECTOR_SIZE) as u16;
+        let cmd = TestController::rw_command(NVME_CMD_WRITE, 3, nlb, write_addr, prp_list);
+        assert_eq!(ctrl.submit(1, cmd).status >> 1, NVME_SC_SUCCESS);
+
+        // Read it back in aligned pages.
+        let read_addr = DATA_ADDR + 0x4_0000;
+        let cmd = TestController::rw_command(
+            NVME_CMD_READ,
+            3,
+            nlb,
+            read_addr,
+            read_addr + NVME_PAGE_SIZE,
+        );
+        assert_eq!(ctrl.submit(1, cmd).status >> 1, NVME_SC_SUCCESS);
+        let mut data = vec![0u8; len as usize];
+        ctrl.mem
+            .read_slice(&mut data, GuestAddress(read_addr))
+            .unwrap();
+        assert_eq!(data, pattern);
+
+        // And from the middle of another sector.
+        let read_base = DATA_ADDR + 0x8_0000;
+        let read_addr = read_base + 0x100;
+        ctrl.mem
+            .write_obj(read_base + NVME_PAGE_SIZE, GuestAddress(prp_list))
+            .unwrap();
+        ctrl.mem
+            .write_obj(read_base + 2 * NVME_PAGE_SIZE, GuestAddress(prp_list + 8))
+            .unwrap();
+        let cmd = TestController::rw_command(NVME_CMD_READ, 3, nlb, read_addr, prp_list);
+        assert_eq!(ctrl.submit(1, cmd).status >> 1, NVME_SC_SUCCESS);
+        ctrl.mem
+            .read_slice(&mut data, GuestAddress(read_addr))
+            .unwrap();
+        assert_eq!(data, pattern);
+    }
+
+    #[test]
+    fn test_nvme_io_worker() {
+        let gate = Gate::default();
+        let disk = Box::new(GatedDisk {
+            disk: Cursor::new(vec![0u8; DISK_SIZE]),
+            gate: gate.clone(),
+        });
+        let mut ctrl = TestController::with_disk(disk, false);
+        ctrl.create_io_queues();
+
+        // The doorbell write returns while the disk is blocked.
+        let cmd = TestController::rw_command(NVME_CMD_WRITE, 0, 1, DATA_ADDR, 0);
+        let cid = ctrl.ring(1, cmd);
+        thread::sleep(Duration::from_millis(50));
+        assert!(ctrl.poll(1).is_none());
+
+        gate.open();
+        let entry = ctrl.complete(1);
+        assert_eq!(entry.cid, cid);
+        assert_eq!(entry.status >> 1, NVME_SC_SUCCESS);
+
+        // Nothing is processed while paused.
+        ctrl.device.pause().unwrap();
+        let cmd = TestController::rw_command(NVME_CMD_READ, 0, 1, DATA_ADDR, 0);
+        let cid = ctrl.ring(1, cmd);
+        thread::sleep(Duration::from_millis(50));
+        assert!(ctrl.poll(1).is_none());
+
+        ctrl.device.resume().unwrap();
+        let entry = ctrl.complete(1);
+        assert_eq!(entry.cid, cid);
+        assert_eq!(entry.status >> 1, NVME_SC_SUCCESS);
+    }
+
+    #[test]
+    fn test_nvme_readonly_namespace() {
+        let mut ctrl = TestController::new(true);
+        ctrl.create_io_queues();
+
+        let cmd = TestController::rw_command(NVME_CMD_WRITE, 0, 1, DATA_ADDR, 0);
+        assert_eq!(
+            ctrl.submit(1, cmd).status >> 1,
+            NVME_SC_NS_WRITE_PROTECTED | NVME_SC_DNR
+        );
+        let cmd = TestController::rw_command(NVME_CMD_READ, 0, 1, DATA_ADDR, 0);
+        assert_eq!(ctrl.submit(1, cmd).status >> 1, NVME_SC_SUCCESS);
+    }
+
+    #[test]
+    fn test_nvme_snapshot_refused() {
+        let ctrl = TestController::new(false);
+        assert!(ctrl.device.snapshot().is_err());
+    }
+}
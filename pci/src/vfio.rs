@@ -8,17 +8,18 @@ extern crate vm_allocator;
 
 use crate::{
     msi_num_enabled_vectors, BarReprogrammingParams, MsiConfig, MsixCap, MsixConfig,
-    PciBarConfiguration, PciBarRegionType, PciCapabilityID, PciClassCode, PciConfiguration,
-    PciDevice, PciDeviceError, PciHeaderType, PciSubclass, MSIX_TABLE_ENTRY_SIZE,
+    PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciCapabilityID, PciClassCode,
+    PciConfiguration, PciDevice, PciDeviceError, PciHeaderType, PciSubclass, MSIX_TABLE_ENTRY_SIZE,
 };
 use byteorder::{ByteOrder, LittleEndian};
 use devices::BusDevice;
 use std::any::Any;
 use std::ops::Deref;
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::ptr::null_mut;
 use std::sync::Arc;
-use std::{fmt, io, result};
+use std::{cmp, fmt, io, result};
 use vfio_bindings::bindings::vfio::*;
 use vfio_ioctls::{VfioDevice, VfioError};
 use vm_allocator::SystemAllocator;
@@ -34,6 +35,9 @@ use vmm_sys_util::eventfd::EventFd;
 #[derive(Debug)]
 pub enum VfioPciError {
     AllocateGsi,
+    DisableMsi(VfioError),
+    DisableMsix(VfioError),
+    EnableErrorIrq(VfioError),
     EventFd(io::Error),
     InterruptSourceGroupCreate(io::Error),
     IrqFd(hypervisor::HypervisorVmError),
@@ -45,6 +49,8 @@ pub enum VfioPciError {
     UpdateMemory(VfioError),
     UpdateMsiEventFd,
     UpdateMsixEventFd,
+    ResetDevice(io::Error),
+    UnmapDma(VfioError),
 }
 pub type Result<T> = std::result::Result<T, VfioPciError>;
 
@@ -52,6 +58,11 @@ impl fmt::Display for VfioPciError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             VfioPciError::AllocateGsi => write!(f, "failed to allocate GSI"),
+            VfioPciError::DisableMsi(e) => write!(f, "failed to disable MSI: {}", e),
+            VfioPciError::DisableMsix(e) => write!(f, "failed to disable MSI-X: {}", e),
+            VfioPciError::EnableErrorIrq(e) => {
+                write!(f, "failed to enable the error notification: {}", e)
+            }
             VfioPciError::EventFd(e) => write!(f, "failed to create eventfd: {}", e),
             VfioPciError::InterruptSourceGroupCreate(e) => {
                 write!(f, "failed to create interrupt source group: {}", e)
@@ -67,6 +78,10 @@ impl fmt::Display for VfioPciError {
             VfioPciError::UpdateMemory(e) => write!(f, "failed to update memory: {}", e),
             VfioPciError::UpdateMsiEventFd => write!(f, "failed to update MSI eventfd"),
             VfioPciError::UpdateMsixEventFd => write!(f, "failed to update MSI-X eventfd"),
+            VfioPciError::ResetDevice(e) => write!(f, "failed to reset the device: {}", e),
+            VfioPciError::UnmapDma(e) => {
+                write!(f, "failed to remove the DMA mappings of the device: {}", e)
+            }
         }
     }
 }
@@ -227,6 +242,8 @@ struct MmioRegion {
     length: GuestUsize,
     type_: PciBarRegionType,
     index: u32,
+    // Allocated from the high MMIO window.
+    huge: bool,
     mem_slot: Option<u32>,
     host_addr: Option<u64>,
     mmap_size: Option<usize>,
@@ -256,20 +273,6 @@ impl VfioPciConfig {
 
         u16::from_le_bytes(data)
     }
-
-    fn read_config_dword(&self, offset: u32) -> u32 {
-        let mut data: [u8; 4] = [0, 0, 0, 0];
-        self.device
-            .region_read(VFIO_PCI_CONFIG_REGION_INDEX, data.as_mut(), offset.into());
-
-        u32::from_le_bytes(data)
-    }
-
-    fn write_config_dword(&self, buf: u32, offset: u32) {
-        let data: [u8; 4] = buf.to_le_bytes();
-        self.device
-            .region_write(VFIO_PCI_CONFIG_REGION_INDEX, &data, offset.into())
-    }
 }
 
 /// VfioPciDevice represents a VFIO PCI device.
@@ -286,6 +289,14 @@ pub struct VfioPciDevice {
     mmio_regions: Vec<MmioRegion>,
     interrupt: Interrupt,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    // Config registers of the Resizable BAR capability.
+    rebar_registers: Option<(usize, usize)>,
+    // Offset of the Advanced Error Reporting capability.
+    aer_cap: Option<u32>,
+    // Signaled by VFIO when the host detects an error on the device.
+    error_evt: Option<EventFd>,
+    // The device has been cleaned up already, through release().
+    released: bool,
 }
 
 impl VfioPciDevice {
@@ -325,9 +336,17 @@ impl VfioPciDevice {
                 msix: None,
             },
             mem,
+            rebar_registers: None,
+            aer_cap: None,
+            error_evt: None,
+            released: false,
         };
 
         vfio_pci_device.parse_capabilities(interrupt_manager);
+        vfio_pci_device.rebar_registers =
+            find_rebar_capability(&vfio_pci_device.vfio_pci_configuration);
+        vfio_pci_device.aer_cap =
+            find_ext_capability(&vfio_pci_device.vfio_pci_configuration, PCI_EXT_CAP_ID_AER);
 
         Ok(vfio_pci_device)
     }
@@ -565,6 +584,7 @@ impl VfioPciDevice {
                     mmap_size as u64,
                     host_addr as u64,
                     false,
+                    false,
                 );
 
                 vm.set_user_memory_region(mem_region)
@@ -594,6 +614,7 @@ impl VfioPciDevice {
                     0,
                     host_addr as u64,
                     false,
+                    false,
                 );
 
                 if let Err(e) = self.vm.set_user_memory_region(kvm_region) {
@@ -620,10 +641,99 @@ impl VfioPciDevice {
             .extend_dma_map(new_region)
             .map_err(VfioPciError::UpdateMemory)
     }
+
+    /// Bring the device back to a clean state before it gets detached from
+    /// the VM, for the host or another VM to use it right away: its
+    /// interrupts are disabled, the device gets reset and its DMA mappings
+    /// are removed from the container. Devices without any function level
+    /// reset are reset through `sysfs_path`, which lets the host reset the
+    /// slot or the bus instead. Dropping the device afterwards closes its
+    /// VFIO group.
+    pub fn release(&mut self, sysfs_path: &Path) -> Result<()> {
+        self.unmap_mmio_regions();
+
+        if let Some(msix) = &self.interrupt.msix {
+            if msix.bar.enabled() {
+                self.device
+                    .disable_msix()
+                    .map_err(VfioPciError::DisableMsix)?;
+            }
+        }
+
+        if let Some(msi) = &self.interrupt.msi {
+            if msi.cfg.enabled() {
+                self.device
+                    .disable_msi()
+                    .map_err(VfioPciError::DisableMsi)?;
+            }
+        }
+
+        // The DMA mappings must go whether the device could be reset or not.
+        let reset = self.reset(sysfs_path);
+        self.device
+            .unset_dma_map(self.mem.memory().deref())
+            .map_err(VfioPciError::UnmapDma)?;
+        self.released = true;
+
+        reset
+    }
+
+    /// Whether the device reports its errors through an Advanced Error
+    /// Reporting capability, which the guest sees in the config space.
+    pub fn has_aer(&self) -> bool {
+        self.aer_cap.is_some()
+    }
+
+    /// Have VFIO signal the returned eventfd whenever the host detects an
+    /// error on the device, after which handle_error_notification() tells
+    /// which errors the device reported. Writing to the eventfd simulates an
+    /// error notification.
+    pub fn enable_error_notification(&mut self) -> Result<EventFd> {
+        let evt = EventFd::new(libc::EFD_NONBLOCK).map_err(VfioPciError::EventFd)?;
+        self.device
+            .enable_irq(VFIO_PCI_ERR_IRQ_INDEX, vec![&evt])
+            .map_err(VfioPciError::EnableErrorIrq)?;
+        let notifier = evt.try_clone().map_err(VfioPciError::EventFd)?;
+        self.error_evt = Some(evt);
+
+        Ok(notifier)
+    }
+
+    /// Consume a pending error notification, returning the error status the
+    /// device reports in its AER capability. The status registers are left
+    /// untouched, for the guest to find and clear them.
+    pub fn handle_error_notification(&self) -> Option<AerStatus> {
+        match (&self.error_evt, self.aer_cap) {
+            (Some(evt), Some(cap)) => {
+                consume_error_notification(evt, &self.vfio_pci_configuration, cap)
+            }
+            _ => None,
+        }
+    }
+
+    fn reset(&self, sysfs_path: &Path) -> Result<()> {
+        // Safe as the device fd is valid, and the ioctl takes no argument.
+        let ret = unsafe { libc::ioctl(self.device.as_raw_fd(), VFIO_DEVICE_RESET as _) };
+        if ret == 0 {
+            return Ok(());
+        }
+
+        let e = io::Error::last_os_error();
+        debug!(
+            "VFIO_DEVICE_RESET failed ({}), resetting {} through sysfs",
+            e,
+            sysfs_path.display()
+        );
+        std::fs::write(sysfs_path.join("reset"), "1").map_err(VfioPciError::ResetDevice)
+    }
 }
 
 impl Drop for VfioPciDevice {
     fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+
         self.unmap_mmio_regions();
 
         if let Some(msix) = &self.interrupt.msix {
@@ -658,6 +768,9 @@ impl BusDevice for VfioPciDevice {
     }
 }
 
+// _IO(VFIO_TYPE, VFIO_BASE + 11), failing if the device has no function level
+// reset.
+const VFIO_DEVICE_RESET: u64 = 0x3b6f;
 // First BAR offset in the PCI config space.
 const PCI_CONFIG_BAR_OFFSET: u32 = 0x10;
 // Capability register offset in the PCI config space.
@@ -681,119 +794,275 @@ const PCI_ROM_EXP_BAR_INDEX: usize = 12;
 // PCI interrupt pin and line register index
 const PCI_INTX_REG_INDEX: usize = 15;
 
-impl PciDevice for VfioPciDevice {
-    fn allocate_bars(
-        &mut self,
-        allocator: &mut SystemAllocator,
-    ) -> std::result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError>
-    {
-        let mut ranges = Vec::new();
-        let mut bar_id = VFIO_PCI_BAR0_REGION_INDEX as u32;
-
-        // Going through all regular regions to compute the BAR size.
-        // We're not saving the BAR address to restore it, because we
-        // are going to allocate a guest address for each BAR and write
-        // that new address back.
-        while bar_id < VFIO_PCI_CONFIG_REGION_INDEX {
-            let mut lsb_size: u32 = 0xffff_ffff;
-            let mut msb_size = 0;
-            let mut region_size: u64;
-            let bar_addr: GuestAddress;
-
-            // Read the BAR size (Starts by all 1s to the BAR)
-            let bar_offset = if bar_id == VFIO_PCI_ROM_REGION_INDEX {
-                (PCI_ROM_EXP_BAR_INDEX * 4) as u32
-            } else {
-                PCI_CONFIG_BAR_OFFSET + bar_id * 4
-            };
+// Prefetchable memory BAR flag.
+const PCI_CONFIG_MEMORY_BAR_PREFETCHABLE: u32 = 0x8;
+// First extended capability offset in the PCI Express config space.
+const PCI_CONFIG_EXTENDED_CAPABILITY_OFFSET: u32 = 0x100;
+// Resizable BAR extended capability ID.
+const PCI_EXT_CAP_ID_REBAR: u32 = 0x15;
+// Advanced Error Reporting extended capability ID.
+const PCI_EXT_CAP_ID_AER: u32 = 0x1;
+// Error status and severity registers, relative to the AER capability.
+const PCI_ERR_UNCOR_STATUS: u32 = 0x4;
+const PCI_ERR_UNCOR_SEVER: u32 = 0xc;
+const PCI_ERR_COR_STATUS: u32 = 0x10;
+
+/// Size from which the 64 bits prefetchable BARs are placed in the high
+/// MMIO window of the allocator, away from the other BARs.
+pub const VFIO_HUGE_BAR_SIZE: u64 = 1 << 32;
+
+// Accesses to the config space the BARs and capabilities probing relies on.
+trait PciConfigAccess {
+    fn read_config_dword(&self, offset: u32) -> u32;
+    fn write_config_dword(&self, buf: u32, offset: u32);
+}
+
+impl PciConfigAccess for VfioPciConfig {
+    fn read_config_dword(&self, offset: u32) -> u32 {
+        let mut data: [u8; 4] = [0, 0, 0, 0];
+        self.device
+            .region_read(VFIO_PCI_CONFIG_REGION_INDEX, data.as_mut(), offset.into());
+
+        u32::from_le_bytes(data)
+    }
+
+    fn write_config_dword(&self, buf: u32, offset: u32) {
+        let data: [u8; 4] = buf.to_le_bytes();
+        self.device
+            .region_write(VFIO_PCI_CONFIG_REGION_INDEX, &data, offset.into())
+    }
+}
+
+// BAR of the device, as found by probing its size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ProbedBar {
+    region_index: u32,
+    region_type: PciBarRegionType,
+    size: u64,
+    prefetchable: bool,
+    // Flags from the lower bits of the register.
+    flags: u32,
+}
+
+impl ProbedBar {
+    fn is_huge(&self) -> bool {
+        self.region_type == PciBarRegionType::Memory64BitRegion
+            && self.prefetchable
+            && self.size >= VFIO_HUGE_BAR_SIZE
+    }
+}
+
+// Going through all regular regions to compute the BAR size.
+// We're not saving the BAR address to restore it, because we
+// are going to allocate a guest address for each BAR and write
+// that new address back.
+fn probe_bars(config: &dyn PciConfigAccess) -> Vec<ProbedBar> {
+    let mut bars = Vec::new();
+    let mut bar_id = VFIO_PCI_BAR0_REGION_INDEX as u32;
+
+    while bar_id < VFIO_PCI_CONFIG_REGION_INDEX {
+        let mut lsb_size: u32 = 0xffff_ffff;
+        let mut msb_size = 0;
+        let mut region_size: u64;
+
+        // Read the BAR size (Starts by all 1s to the BAR)
+        let bar_offset = if bar_id == VFIO_PCI_ROM_REGION_INDEX {
+            (PCI_ROM_EXP_BAR_INDEX * 4) as u32
+        } else {
+            PCI_CONFIG_BAR_OFFSET + bar_id * 4
+        };
+
+        config.write_config_dword(lsb_size, bar_offset);
+        lsb_size = config.read_config_dword(bar_offset);
 
-            self.vfio_pci_configuration
-                .write_config_dword(lsb_size, bar_offset);
-            lsb_size = self.vfio_pci_configuration.read_config_dword(bar_offset);
+        // We've just read the BAR size back. Or at least its LSB.
+        let lsb_flag = lsb_size & PCI_CONFIG_MEMORY_BAR_FLAG_MASK;
 
-            // We've just read the BAR size back. Or at least its LSB.
-            let lsb_flag = lsb_size & PCI_CONFIG_MEMORY_BAR_FLAG_MASK;
+        if lsb_size == 0 {
+            bar_id += 1;
+            continue;
+        }
 
-            if lsb_size == 0 {
-                bar_id += 1;
-                continue;
+        // Is this an IO BAR?
+        let io_bar = if bar_id != VFIO_PCI_ROM_REGION_INDEX {
+            match lsb_flag & PCI_CONFIG_IO_BAR {
+                PCI_CONFIG_IO_BAR => true,
+                _ => false,
             }
+        } else {
+            false
+        };
 
-            // Is this an IO BAR?
-            let io_bar = if bar_id != VFIO_PCI_ROM_REGION_INDEX {
-                match lsb_flag & PCI_CONFIG_IO_BAR {
-                    PCI_CONFIG_IO_BAR => true,
-                    _ => false,
-                }
-            } else {
-                false
-            };
+        // Is this a 64-bit BAR?
+        let is_64bit_bar = if bar_id != VFIO_PCI_ROM_REGION_INDEX && !io_bar {
+            match lsb_flag & PCI_CONFIG_MEMORY_BAR_64BIT {
+                PCI_CONFIG_MEMORY_BAR_64BIT => true,
+                _ => false,
+            }
+        } else {
+            false
+        };
 
-            // Is this a 64-bit BAR?
-            let is_64bit_bar = if bar_id != VFIO_PCI_ROM_REGION_INDEX {
-                match lsb_flag & PCI_CONFIG_MEMORY_BAR_64BIT {
-                    PCI_CONFIG_MEMORY_BAR_64BIT => true,
-                    _ => false,
-                }
-            } else {
-                false
-            };
+        // By default, the region type is 32 bits memory BAR.
+        let mut region_type = PciBarRegionType::Memory32BitRegion;
 
-            // By default, the region type is 32 bits memory BAR.
-            let mut region_type = PciBarRegionType::Memory32BitRegion;
+        if io_bar {
+            // IO BAR
+            region_type = PciBarRegionType::IORegion;
 
-            if io_bar {
-                #[cfg(target_arch = "x86_64")]
-                {
-                    // IO BAR
-                    region_type = PciBarRegionType::IORegion;
-
-                    // Clear first bit.
-                    lsb_size &= 0xffff_fffc;
-
-                    // Find the first bit that's set to 1.
-                    let first_bit = lsb_size.trailing_zeros();
-                    region_size = 2u64.pow(first_bit);
-                    // We need to allocate a guest PIO address range for that BAR.
-                    // The address needs to be 4 bytes aligned.
-                    bar_addr = allocator
-                        .allocate_io_addresses(None, region_size, Some(0x4))
-                        .ok_or_else(|| PciDeviceError::IoAllocationFailed(region_size))?;
-                }
-                #[cfg(target_arch = "aarch64")]
-                unimplemented!()
-            } else {
-                if is_64bit_bar {
-                    // 64 bits Memory BAR
-                    region_type = PciBarRegionType::Memory64BitRegion;
+            // Clear first bit.
+            lsb_size &= 0xffff_fffc;
 
-                    msb_size = 0xffff_ffff;
-                    let msb_bar_offset: u32 = PCI_CONFIG_BAR_OFFSET + (bar_id + 1) * 4;
+            // Find the first bit that's set to 1.
+            let first_bit = lsb_size.trailing_zeros();
+            region_size = 2u64.pow(first_bit);
+        } else {
+            if is_64bit_bar {
+                // 64 bits Memory BAR
+                region_type = PciBarRegionType::Memory64BitRegion;
 
-                    self.vfio_pci_configuration
-                        .write_config_dword(msb_size, msb_bar_offset);
+                msb_size = 0xffff_ffff;
+                let msb_bar_offset: u32 = PCI_CONFIG_BAR_OFFSET + (bar_id + 1) * 4;
 
-                    msb_size = self
-                        .vfio_pci_configuration
-                        .read_config_dword(msb_bar_offset);
-                }
+                config.write_config_dword(msb_size, msb_bar_offset);
+                msb_size = config.read_config_dword(msb_bar_offset);
+            }
+
+            // Clear the first four bytes from our LSB.
+            lsb_size &= 0xffff_fff0;
 
-                // Clear the first four bytes from our LSB.
-                lsb_size &= 0xffff_fff0;
+            region_size = u64::from(msb_size);
+            region_size <<= 32;
+            region_size |= u64::from(lsb_size);
 
-                region_size = u64::from(msb_size);
-                region_size <<= 32;
-                region_size |= u64::from(lsb_size);
+            // Find the first that's set to 1.
+            let first_bit = region_size.trailing_zeros();
+            region_size = 2u64.pow(first_bit);
+        }
+
+        bars.push(ProbedBar {
+            region_index: bar_id,
+            region_type,
+            size: region_size,
+            prefetchable: !io_bar
+                && bar_id != VFIO_PCI_ROM_REGION_INDEX
+                && lsb_flag & PCI_CONFIG_MEMORY_BAR_PREFETCHABLE != 0,
+            flags: lsb_flag,
+        });
 
-                // Find the first that's set to 1.
-                let first_bit = region_size.trailing_zeros();
-                region_size = 2u64.pow(first_bit);
+        bar_id += 1;
+        if is_64bit_bar {
+            bar_id += 1;
+        }
+    }
+
+    bars
+}
+
+// Offset of the extended capability `id` in the config space.
+fn find_ext_capability(config: &dyn PciConfigAccess, id: u32) -> Option<u32> {
+    let mut cap_offset = PCI_CONFIG_EXTENDED_CAPABILITY_OFFSET;
+
+    // Bounding the walk protects us from capabilities linked in a loop.
+    for _ in 0..(0x1000 - PCI_CONFIG_EXTENDED_CAPABILITY_OFFSET) / 4 {
+        let header = config.read_config_dword(cap_offset);
+        if header == 0 || header == 0xffff_ffff {
+            break;
+        }
+
+        if header & 0xffff == id {
+            return Some(cap_offset);
+        }
+
+        cap_offset = (header >> 20) & 0xffc;
+        if cap_offset < PCI_CONFIG_EXTENDED_CAPABILITY_OFFSET {
+            break;
+        }
+    }
+
+    None
+}
+
+// Range of config registers holding the Resizable BAR capability, if the
+// device has one. The guest can look at it, but not resize the BARs as
+// they're allocated once and for all from their size at creation time.
+fn find_rebar_capability(config: &dyn PciConfigAccess) -> Option<(usize, usize)> {
+    let cap_offset = find_ext_capability(config, PCI_EXT_CAP_ID_REBAR)?;
+
+    // The control register of the first BAR tells how many BARs are
+    // resizable, each of them coming with a capability and a control
+    // register.
+    let num_bars = (config.read_config_dword(cap_offset + 8) >> 5) & 0x7;
+    let cap_size = 4 + 8 * num_bars;
+    Some((
+        (cap_offset / 4) as usize,
+        ((cap_offset + cap_size) / 4) as usize,
+    ))
+}
+
+/// Errors reported by a device through its Advanced Error Reporting
+/// capability, as bitmasks of the PCI Express error status registers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AerStatus {
+    /// Correctable errors.
+    pub correctable: u32,
+    /// Uncorrectable errors the device deems non fatal.
+    pub nonfatal: u32,
+    /// Uncorrectable errors the device deems fatal.
+    pub fatal: u32,
+}
+
+impl AerStatus {
+    pub fn is_empty(&self) -> bool {
+        self.correctable == 0 && self.nonfatal == 0 && self.fatal == 0
+    }
+}
+
+fn read_aer_status(config: &dyn PciConfigAccess, cap: u32) -> AerStatus {
+    let uncorrectable = config.read_config_dword(cap + PCI_ERR_UNCOR_STATUS);
+    let severity = config.read_config_dword(cap + PCI_ERR_UNCOR_SEVER);
+
+    AerStatus {
+        correctable: config.read_config_dword(cap + PCI_ERR_COR_STATUS),
+        nonfatal: uncorrectable & !severity,
+        fatal: uncorrectable & severity,
+    }
+}
+
+fn consume_error_notification(
+    evt: &EventFd,
+    config: &dyn PciConfigAccess,
+    cap: u32,
+) -> Option<AerStatus> {
+    // Nothing to read means there's no pending notification.
+    evt.read().ok()?;
+    Some(read_aer_status(config, cap))
+}
+
+impl PciDevice for VfioPciDevice {
+    fn allocate_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+    ) -> std::result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError>
+    {
+        let mut ranges = Vec::new();
 
-                // We need to allocate a guest MMIO address range for that BAR.
-                // In case the BAR is mappable directly, this means it might be
-                // set as KVM user memory region, which expects to deal with 4K
-                // pages. Therefore, the aligment has to be set accordingly.
-                let bar_alignment = if (bar_id == VFIO_PCI_ROM_REGION_INDEX)
+        for bar in probe_bars(&self.vfio_pci_configuration) {
+            let bar_id = bar.region_index;
+            let region_size = bar.size;
+            let region_type = bar.region_type;
+
+            // We need to allocate a guest address range for that BAR,
+            // naturally aligned on its size as the guest expects. In case
+            // the BAR is mappable directly, this means it might be set as
+            // KVM user memory region, which expects to deal with 4K pages.
+            // Therefore, the aligment has to be at least that.
+            let bar_alignment = cmp::max(
+                region_size,
+                if region_type == PciBarRegionType::IORegion {
+                    // Default 4 bytes alignment
+                    0x4
+                } else if (bar_id == VFIO_PCI_ROM_REGION_INDEX)
                     || (self.device.get_region_flags(bar_id) & VFIO_REGION_INFO_FLAG_MMAP != 0)
                 {
                     // 4K alignment
@@ -801,17 +1070,26 @@ impl PciDevice for VfioPciDevice {
                 } else {
                     // Default 16 bytes alignment
                     0x10
-                };
-                if is_64bit_bar {
-                    bar_addr = allocator
-                        .allocate_mmio_addresses(None, region_size, Some(bar_alignment))
-                        .ok_or_else(|| PciDeviceError::IoAllocationFailed(region_size))?;
-                } else {
-                    bar_addr = allocator
-                        .allocate_mmio_hole_addresses(None, region_size, Some(bar_alignment))
-                        .ok_or_else(|| PciDeviceError::IoAllocationFailed(region_size))?;
-                }
-            }
+                },
+            );
+
+            let bar_addr = match region_type {
+                #[cfg(target_arch = "x86_64")]
+                PciBarRegionType::IORegion => allocator
+                    .allocate_io_addresses(None, region_size, Some(bar_alignment))
+                    .ok_or_else(|| PciDeviceError::IoAllocationFailed(region_size))?,
+                #[cfg(target_arch = "aarch64")]
+                PciBarRegionType::IORegion => unimplemented!(),
+                PciBarRegionType::Memory64BitRegion if bar.is_huge() => allocator
+                    .allocate_high_mmio_addresses(region_size, bar_alignment)
+                    .ok_or_else(|| PciDeviceError::IoAllocationFailed(region_size))?,
+                PciBarRegionType::Memory64BitRegion => allocator
+                    .allocate_mmio_addresses(None, region_size, Some(bar_alignment))
+                    .ok_or_else(|| PciDeviceError::IoAllocationFailed(region_size))?,
+                PciBarRegionType::Memory32BitRegion => allocator
+                    .allocate_mmio_hole_addresses(None, region_size, Some(bar_alignment))
+                    .ok_or_else(|| PciDeviceError::IoAllocationFailed(region_size))?,
+            };
 
             let reg_idx = if bar_id == VFIO_PCI_ROM_REGION_INDEX {
                 PCI_ROM_EXP_BAR_INDEX
@@ -820,15 +1098,18 @@ impl PciDevice for VfioPciDevice {
             };
 
             // We can now build our BAR configuration block.
-            let config = PciBarConfiguration::default()
+            let mut config = PciBarConfiguration::default()
                 .set_register_index(reg_idx)
                 .set_address(bar_addr.raw_value())
                 .set_size(region_size)
                 .set_region_type(region_type);
+            if bar.prefetchable {
+                config = config.set_prefetchable(PciBarPrefetchable::Prefetchable);
+            }
 
             if bar_id == VFIO_PCI_ROM_REGION_INDEX {
                 self.configuration
-                    .add_pci_rom_bar(&config, lsb_flag & 0x1)
+                    .add_pci_rom_bar(&config, bar.flags & 0x1)
                     .map_err(|e| PciDeviceError::IoRegistrationFailed(bar_addr.raw_value(), e))?;
             } else {
                 self.configuration
@@ -842,15 +1123,11 @@ impl PciDevice for VfioPciDevice {
                 length: region_size,
                 type_: region_type,
                 index: bar_id as u32,
+                huge: bar.is_huge(),
                 mem_slot: None,
                 host_addr: None,
                 mmap_size: None,
             });
-
-            bar_id += 1;
-            if is_64bit_bar {
-                bar_id += 1;
-            }
         }
 
         if self
@@ -879,6 +1156,9 @@ impl PciDevice for VfioPciDevice {
                 PciBarRegionType::Memory32BitRegion => {
                     allocator.free_mmio_hole_addresses(region.start, region.length);
                 }
+                PciBarRegionType::Memory64BitRegion if region.huge => {
+                    allocator.free_high_mmio_addresses(region.start, region.length);
+                }
                 PciBarRegionType::Memory64BitRegion => {
                     allocator.free_mmio_addresses(region.start, region.length);
                 }
@@ -902,6 +1182,15 @@ impl PciDevice for VfioPciDevice {
                 .write_config_register(reg_idx, offset, data);
         }
 
+        // Resizing the BARs would make them overflow the ranges we allocated
+        // for them, hence the Resizable BAR capability being read-only.
+        if let Some((start, end)) = self.rebar_registers {
+            if reg_idx >= start && reg_idx < end {
+                debug!("Ignoring write to the Resizable BAR capability");
+                return;
+            }
+        }
+
         let reg = (reg_idx * PCI_CONFIG_REGISTER_SIZE) as u64;
 
         // If the MSI or MSI-X capabilities are accessed, we need to
@@ -1022,6 +1311,7 @@ impl PciDevice for VfioPciDevice {
                             0,
                             host_addr as u64,
                             false,
+                            false,
                         );
 
                         self.vm
@@ -1035,6 +1325,7 @@ impl PciDevice for VfioPciDevice {
                             mmap_size as u64,
                             host_addr as u64,
                             false,
+                            false,
                         );
 
                         self.vm
@@ -1052,3 +1343,130 @@ impl PciDevice for VfioPciDevice {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    // Config space of a device, where writes only change the writable bits
+    // of each register, as the BARs do to report their size.
+    struct TestConfig {
+        registers: RefCell<Vec<u32>>,
+        writable: Vec<u32>,
+    }
+
+    impl TestConfig {
+        fn new() -> Self {
+            TestConfig {
+                registers: RefCell::new(vec![0; 1024]),
+                writable: vec![0; 1024],
+            }
+        }
+
+        fn set_bar(&mut self, reg_idx: usize, size: u64, flags: u32) {
+            let mask = !(size - 1);
+            self.registers.borrow_mut()[reg_idx] = flags;
+            self.writable[reg_idx] = mask as u32 & 0xffff_fff0;
+            if flags & PCI_CONFIG_MEMORY_BAR_64BIT != 0 {
+                self.writable[reg_idx + 1] = (mask >> 32) as u32;
+            }
+        }
+
+        fn set_register(&mut self, offset: u32, value: u32) {
+            self.registers.borrow_mut()[(offset / 4) as usize] = value;
+        }
+    }
+
+    impl PciConfigAccess for TestConfig {
+        fn read_config_dword(&self, offset: u32) -> u32 {
+            self.registers.borrow()[(offset / 4) as usize]
+        }
+
+        fn write_config_dword(&self, buf: u32, offset: u32) {
+            let reg_idx = (offset / 4) as usize;
+            let mut registers = self.registers.borrow_mut();
+            registers[reg_idx] =
+                (registers[reg_idx] & !self.writable[reg_idx]) | (buf & self.writable[reg_idx]);
+        }
+    }
+
+    #[test]
+    fn test_probe_bars() {
+        let mut config = TestConfig::new();
+        // An 8GiB prefetchable 64 bits BAR, too big for the 32 bits LSB
+        // to hold any bit of its size, followed by a 32 bits BAR.
+        config.set_bar(
+            PCI_CONFIG_BAR0_INDEX,
+            8 << 30,
+            PCI_CONFIG_MEMORY_BAR_64BIT | PCI_CONFIG_MEMORY_BAR_PREFETCHABLE,
+        );
+        config.set_bar(PCI_CONFIG_BAR0_INDEX + 2, 16 << 20, 0);
+
+        let bars = probe_bars(&config);
+        assert_eq!(
+            bars,
+            vec![
+                ProbedBar {
+                    region_index: 0,
+                    region_type: PciBarRegionType::Memory64BitRegion,
+                    size: 8 << 30,
+                    prefetchable: true,
+                    flags: PCI_CONFIG_MEMORY_BAR_64BIT | PCI_CONFIG_MEMORY_BAR_PREFETCHABLE,
+                },
+                ProbedBar {
+                    region_index: 2,
+                    region_type: PciBarRegionType::Memory32BitRegion,
+                    size: 16 << 20,
+                    prefetchable: false,
+                    flags: 0,
+                },
+            ]
+        );
+        assert!(bars[0].is_huge());
+        assert!(!bars[1].is_huge());
+    }
+
+    #[test]
+    fn test_find_rebar_capability() {
+        let mut config = TestConfig::new();
+        assert_eq!(find_rebar_capability(&config), None);
+
+        // An AER capability, followed by a Resizable BAR one with two BARs.
+        config.set_register(0x100, (0x140 << 20) | (1 << 16) | 0x1);
+        config.set_register(0x140, (1 << 16) | PCI_EXT_CAP_ID_REBAR);
+        config.set_register(0x148, 2 << 5);
+        assert_eq!(find_rebar_capability(&config), Some((0x50, 0x55)));
+        assert_eq!(
+            find_ext_capability(&config, PCI_EXT_CAP_ID_AER),
+            Some(0x100)
+        );
+    }
+
+    #[test]
+    fn test_error_notification() {
+        let mut config = TestConfig::new();
+        config.set_register(0x100, PCI_EXT_CAP_ID_AER);
+        let cap = find_ext_capability(&config, PCI_EXT_CAP_ID_AER).unwrap();
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+
+        // Nothing gets reported until the eventfd is signaled.
+        assert_eq!(consume_error_notification(&evt, &config, cap), None);
+
+        // A correctable receiver error, along with an unsupported request
+        // and a malformed TLP, only the latter being fatal.
+        config.set_register(cap + PCI_ERR_COR_STATUS, 0x1);
+        config.set_register(cap + PCI_ERR_UNCOR_STATUS, (1 << 20) | (1 << 18));
+        config.set_register(cap + PCI_ERR_UNCOR_SEVER, (1 << 18) | (1 << 4));
+        evt.write(1).unwrap();
+        assert_eq!(
+            consume_error_notification(&evt, &config, cap),
+            Some(AerStatus {
+                correctable: 0x1,
+                nonfatal: 1 << 20,
+                fatal: 1 << 18,
+            })
+        );
+        assert_eq!(consume_error_notification(&evt, &config, cap), None);
+    }
+}
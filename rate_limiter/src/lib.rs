@@ -0,0 +1,461 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Token bucket based rate limiting for virtio device datapaths.
+//!
+//! A `TokenBucket` holds up to `size` tokens and is refilled with `size`
+//! tokens every `refill_time` milliseconds, proportionally to the time
+//! elapsed since the last update. A `RateLimiter` wraps a bandwidth bucket,
+//! counting bytes, and an operations bucket, counting requests, together
+//! with a `TimerFd`, so that a device epoll loop can stop processing once
+//! either budget is exhausted and resume when the timer fires.
+
+#[macro_use]
+extern crate serde_derive;
+
+use std::fmt::{self, Display};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use std::time::{Duration, Instant};
+use vmm_sys_util::timerfd::TimerFd;
+
+const NANOSEC_IN_ONE_MILLISEC: u64 = 1_000_000;
+
+// Shortest period the limiter timer can be armed for, which prevents from
+// waking up the device thread too often when only a few tokens are missing.
+const MIN_TIMER_DURATION_MS: u64 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed creating the timer file descriptor.
+    TimerFdCreate(vmm_sys_util::errno::Error),
+    /// Failed arming the timer file descriptor.
+    TimerFdArm(vmm_sys_util::errno::Error),
+    /// Failed reading from the timer file descriptor.
+    TimerFdWait(vmm_sys_util::errno::Error),
+    /// The rate limiter is configured with a zero size or refill time.
+    InvalidBucket,
+    /// The rate limiter is configured without any bucket.
+    NoBucket,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            TimerFdCreate(e) => write!(f, "failed creating rate limiter timer: {}", e),
+            TimerFdArm(e) => write!(f, "failed arming rate limiter timer: {}", e),
+            TimerFdWait(e) => write!(f, "failed reading rate limiter timer: {}", e),
+            InvalidBucket => write!(f, "rate limiter size and refill time must be non zero"),
+            NoBucket => write!(f, "rate limiter needs a bandwidth or an operations bucket"),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Size and refill time of a token bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TokenBucketConfig {
+    /// Number of tokens the bucket holds.
+    pub size: u64,
+    /// Time in milliseconds needed to refill a completely empty bucket.
+    pub refill_time: u64,
+}
+
+/// Limits of a device datapath, in bytes and in requests, each of them
+/// being enforced independently of the other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct RateLimiterConfig {
+    #[serde(default)]
+    pub bandwidth: Option<TokenBucketConfig>,
+    #[serde(default)]
+    pub ops: Option<TokenBucketConfig>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    // Maximum number of tokens the bucket can hold.
+    size: u64,
+    // Time in milliseconds needed to refill a completely empty bucket.
+    refill_time: u64,
+    // Number of tokens currently available.
+    budget: u64,
+    // Last time the budget was refilled.
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket holding `size` tokens, refilled with `size`
+    /// tokens every `refill_time` milliseconds.
+    pub fn new(size: u64, refill_time: u64) -> Option<Self> {
+        if size == 0 || refill_time == 0 {
+            return None;
+        }
+
+        Some(TokenBucket {
+            size,
+            refill_time,
+            budget: size,
+            last_update: Instant::now(),
+        })
+    }
+
+    fn refill_time_ns(&self) -> u128 {
+        u128::from(self.refill_time) * u128::from(NANOSEC_IN_ONE_MILLISEC)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed_ns = now.saturating_duration_since(self.last_update).as_nanos();
+        let new_tokens = elapsed_ns * u128::from(self.size) / self.refill_time_ns();
+        if new_tokens == 0 {
+            return;
+        }
+
+        let budget = u128::from(self.budget) + new_tokens;
+        if budget >= u128::from(self.size) {
+            self.budget = self.size;
+            self.last_update = now;
+        } else {
+            self.budget = budget as u64;
+            // Only account for the time matching the tokens added to the
+            // budget, so that fractions of tokens are not lost.
+            let consumed_ns = new_tokens * self.refill_time_ns() / u128::from(self.size);
+            self.last_update += Duration::from_nanos(consumed_ns as u64);
+        }
+    }
+
+    // Whether the bucket holds `tokens` at `now`, or is full if it can't
+    // hold that many.
+    fn has_at(&mut self, tokens: u64, now: Instant) -> bool {
+        self.refill(now);
+        self.budget >= std::cmp::min(tokens, self.size)
+    }
+
+    fn reduce_at(&mut self, tokens: u64, now: Instant) -> u64 {
+        self.refill(now);
+        let granted = std::cmp::min(tokens, self.budget);
+        self.budget -= granted;
+        granted
+    }
+
+    /// Take up to `tokens` from the bucket, returning how many of them
+    /// were actually granted.
+    pub fn reduce(&mut self, tokens: u64) -> u64 {
+        self.reduce_at(tokens, Instant::now())
+    }
+
+    /// Time needed for the bucket to hold `tokens`, capped to the bucket
+    /// size since it can never hold more than that.
+    pub fn time_to_refill(&self, tokens: u64) -> Duration {
+        let tokens = std::cmp::min(tokens, self.size);
+        if tokens <= self.budget {
+            return Duration::from_nanos(0);
+        }
+
+        let missing = u128::from(tokens - self.budget);
+        let ns =
+            (missing * self.refill_time_ns() + u128::from(self.size) - 1) / u128::from(self.size);
+        Duration::from_nanos(ns as u64)
+    }
+
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.size
+    }
+}
+
+pub struct RateLimiter {
+    bandwidth: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+    timer_fd: TimerFd,
+    // Set when the budget is exhausted and the timer has been armed.
+    timer_active: bool,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter allowing `size` bytes over `refill_time`
+    /// milliseconds.
+    pub fn new(size: u64, refill_time: u64) -> Result<Self> {
+        let bandwidth = TokenBucket::new(size, refill_time).ok_or(Error::InvalidBucket)?;
+        Self::with_buckets(Some(bandwidth), None)
+    }
+
+    /// Create a rate limiter enforcing the bandwidth and operations limits
+    /// of `config`.
+    pub fn from_config(config: &RateLimiterConfig) -> Result<Self> {
+        let bucket = |c: &TokenBucketConfig| {
+            TokenBucket::new(c.size, c.refill_time).ok_or(Error::InvalidBucket)
+        };
+        let bandwidth = config.bandwidth.as_ref().map(bucket).transpose()?;
+        let ops = config.ops.as_ref().map(bucket).transpose()?;
+
+        Self::with_buckets(bandwidth, ops)
+    }
+
+    fn with_buckets(bandwidth: Option<TokenBucket>, ops: Option<TokenBucket>) -> Result<Self> {
+        if bandwidth.is_none() && ops.is_none() {
+            return Err(Error::NoBucket);
+        }
+        let timer_fd = TimerFd::new().map_err(Error::TimerFdCreate)?;
+
+        Ok(RateLimiter {
+            bandwidth,
+            ops,
+            timer_fd,
+            timer_active: false,
+        })
+    }
+
+    fn arm_timer(&mut self, timeout: Duration) -> Result<()> {
+        let timeout = std::cmp::max(timeout, Duration::from_millis(MIN_TIMER_DURATION_MS));
+        self.timer_fd
+            .reset(timeout, None)
+            .map_err(Error::TimerFdArm)?;
+        self.timer_active = true;
+        Ok(())
+    }
+
+    /// Consume up to `tokens` bytes from the bandwidth budget and return the
+    /// amount which has been granted. When nothing can be granted, the timer
+    /// is armed so that the caller is notified through the file descriptor
+    /// once enough tokens are available again.
+    pub fn consume(&mut self, tokens: u64) -> Result<u64> {
+        if self.timer_active {
+            return Ok(0);
+        }
+
+        let bandwidth = match self.bandwidth.as_mut() {
+            Some(bandwidth) => bandwidth,
+            None => return Ok(tokens),
+        };
+        let granted = bandwidth.reduce(tokens);
+        if granted == 0 && tokens > 0 {
+            let timeout = bandwidth.time_to_refill(tokens);
+            self.arm_timer(timeout)?;
+        }
+
+        Ok(granted)
+    }
+
+    /// Consume a request of `bytes` bytes, taking them from the bandwidth
+    /// budget and the request itself from the operations budget. Returns
+    /// whether the request can go through, nothing being consumed unless
+    /// both budgets allow it. A request larger than the bandwidth bucket
+    /// goes through once the bucket is full, emptying it. When the request
+    /// is refused the timer is armed, so that the caller is notified through
+    /// the file descriptor once both budgets allow it again.
+    pub fn consume_request(&mut self, bytes: u64) -> Result<bool> {
+        self.consume_request_at(bytes, Instant::now())
+    }
+
+    fn consume_request_at(&mut self, bytes: u64, now: Instant) -> Result<bool> {
+        if self.timer_active {
+            return Ok(false);
+        }
+
+        // Wait for the bucket which takes the longest to refill.
+        let mut timeout = None;
+        if let Some(bandwidth) = self.bandwidth.as_mut() {
+            if !bandwidth.has_at(bytes, now) {
+                timeout = Some(bandwidth.time_to_refill(bytes));
+            }
+        }
+        if let Some(ops) = self.ops.as_mut() {
+            if !ops.has_at(1, now) {
+                timeout = std::cmp::max(timeout, Some(ops.time_to_refill(1)));
+            }
+        }
+        if let Some(timeout) = timeout {
+            self.arm_timer(timeout)?;
+            return Ok(false);
+        }
+
+        if let Some(bandwidth) = self.bandwidth.as_mut() {
+            bandwidth.reduce_at(bytes, now);
+        }
+        if let Some(ops) = self.ops.as_mut() {
+            ops.reduce_at(1, now);
+        }
+
+        Ok(true)
+    }
+
+    /// Returns true if the budget has been exhausted and the caller should
+    /// wait for the timer to expire before processing more requests.
+    pub fn is_blocked(&self) -> bool {
+        self.timer_active
+    }
+
+    /// Must be called when the timer file descriptor is readable, it
+    /// unblocks the rate limiter.
+    pub fn event_handler(&mut self) -> Result<()> {
+        self.timer_fd.wait().map_err(Error::TimerFdWait)?;
+        self.timer_active = false;
+        Ok(())
+    }
+}
+
+impl AsRawFd for RateLimiter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer_fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_invalid() {
+        assert!(TokenBucket::new(0, 1000).is_none());
+        assert!(TokenBucket::new(1000, 0).is_none());
+        assert!(RateLimiter::new(0, 1000).is_err());
+        assert!(RateLimiter::from_config(&RateLimiterConfig::default()).is_err());
+        assert!(RateLimiter::from_config(&RateLimiterConfig {
+            bandwidth: None,
+            ops: Some(TokenBucketConfig {
+                size: 10,
+                refill_time: 0,
+            }),
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_token_bucket_refill() {
+        let mut bucket = TokenBucket::new(1000, 1000).unwrap();
+        let start = bucket.last_update;
+
+        // A full bucket grants a burst up to its size.
+        assert_eq!(bucket.reduce_at(4096, start), 1000);
+        assert_eq!(bucket.reduce_at(1, start), 0);
+        assert_eq!(bucket.time_to_refill(4096), Duration::from_secs(1));
+        assert_eq!(bucket.time_to_refill(100), Duration::from_millis(100));
+
+        // Tokens come back proportionally to the elapsed time.
+        let now = start + Duration::from_millis(250);
+        assert_eq!(bucket.reduce_at(4096, now), 250);
+
+        let now = now + Duration::from_millis(1);
+        assert_eq!(bucket.reduce_at(4096, now), 1);
+
+        // The budget never goes above the bucket size.
+        let now = now + Duration::from_secs(10);
+        assert_eq!(bucket.reduce_at(4096, now), 1000);
+    }
+
+    #[test]
+    fn test_token_bucket_pacing() {
+        // Drain a 1000 tokens per second bucket by small steps, and make
+        // sure the total delivered over 5 seconds matches the rate.
+        let mut bucket = TokenBucket::new(1000, 1000).unwrap();
+        let start = bucket.last_update;
+        let mut delivered = 0;
+        for ms in 0..5000 {
+            delivered += bucket.reduce_at(64, start + Duration::from_micros(ms * 1000 + 300));
+        }
+        // Initial burst plus 5 seconds worth of refill.
+        assert!(delivered <= 6000);
+        assert!(delivered >= 5990);
+    }
+
+    #[test]
+    fn test_rate_limiter_timer() {
+        let mut limiter = RateLimiter::new(10, 100).unwrap();
+        assert!(!limiter.is_blocked());
+        assert_eq!(limiter.consume(10).unwrap(), 10);
+
+        // The budget is exhausted, the limiter gets blocked and nothing is
+        // granted until the timer fires.
+        let start = Instant::now();
+        assert_eq!(limiter.consume(5).unwrap(), 0);
+        assert!(limiter.is_blocked());
+        assert_eq!(limiter.consume(5).unwrap(), 0);
+
+        limiter.event_handler().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(45));
+        assert!(!limiter.is_blocked());
+        assert_eq!(limiter.consume(5).unwrap(), 5);
+    }
+
+    fn new_limiter(bandwidth: (u64, u64), ops: (u64, u64)) -> RateLimiter {
+        RateLimiter::from_config(&RateLimiterConfig {
+            bandwidth: Some(TokenBucketConfig {
+                size: bandwidth.0,
+                refill_time: bandwidth.1,
+            }),
+            ops: Some(TokenBucketConfig {
+                size: ops.0,
+                refill_time: ops.1,
+            }),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_rate_limiter_bandwidth_saturation() {
+        let mut limiter = new_limiter((1000, 1000), (100, 1000));
+        let start = limiter.bandwidth.as_ref().unwrap().last_update;
+
+        for _ in 0..4 {
+            assert!(limiter.consume_request_at(250, start).unwrap());
+        }
+
+        // The bandwidth is exhausted while there are operations left, which
+        // are not consumed by the refused request.
+        assert!(!limiter.consume_request_at(250, start).unwrap());
+        assert!(limiter.is_blocked());
+        assert_eq!(limiter.ops.as_ref().unwrap().budget(), 96);
+
+        // As if the timer had fired once the bytes came back.
+        limiter.timer_active = false;
+        let now = start + Duration::from_millis(250);
+        assert!(limiter.consume_request_at(250, now).unwrap());
+        assert!(!limiter.consume_request_at(1, now).unwrap());
+
+        // A request larger than the bucket waits for it to be full.
+        limiter.timer_active = false;
+        let now = now + Duration::from_millis(500);
+        assert!(!limiter.consume_request_at(4096, now).unwrap());
+        limiter.timer_active = false;
+        let now = now + Duration::from_millis(500);
+        assert!(limiter.consume_request_at(4096, now).unwrap());
+        assert_eq!(limiter.bandwidth.as_ref().unwrap().budget(), 0);
+    }
+
+    #[test]
+    fn test_rate_limiter_ops_saturation() {
+        let mut limiter = new_limiter((1 << 20, 1000), (10, 1000));
+        let start = limiter.ops.as_ref().unwrap().last_update;
+
+        for _ in 0..10 {
+            assert!(limiter.consume_request_at(100, start).unwrap());
+        }
+
+        // The operations are exhausted while there is bandwidth left, which
+        // is not consumed by the refused request.
+        assert!(!limiter.consume_request_at(100, start).unwrap());
+        assert!(limiter.is_blocked());
+        assert_eq!(
+            limiter.bandwidth.as_ref().unwrap().budget(),
+            (1 << 20) - 1000
+        );
+
+        // Even an empty request needs an operation.
+        limiter.timer_active = false;
+        assert!(!limiter.consume_request_at(0, start).unwrap());
+
+        // As if the timer had fired once an operation came back.
+        limiter.timer_active = false;
+        let now = start + Duration::from_millis(100);
+        assert!(limiter.consume_request_at(100, now).unwrap());
+        assert!(!limiter.consume_request_at(100, now).unwrap());
+    }
+}
@@ -12,7 +12,10 @@ use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::fmt;
 use std::io::{Read, Write};
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::process;
+use std::thread;
+use std::time::Duration;
 
 #[derive(Debug)]
 enum Error {
@@ -24,12 +27,26 @@ enum Error {
     InvalidCPUCount(std::num::ParseIntError),
     InvalidMemorySize(std::num::ParseIntError),
     InvalidBalloonSize(std::num::ParseIntError),
+    InvalidLinkState(String),
+    InvalidQueuePairs(std::num::ParseIntError),
+    InvalidMac(serde_json::Error),
+    InvalidFlowRules(serde_json::Error),
+    InvalidEjectTimeout(std::num::ParseIntError),
+    InvalidCompressionLevel(std::num::ParseIntError),
+    InvalidSnapshotThreads(std::num::ParseIntError),
+    InvalidMigrationTimeout(std::num::ParseIntError),
+    InvalidSampleDuration(std::num::ParseIntError),
+    InvalidBandwidthLimit(std::num::ParseIntError),
+    InvalidThrottle(std::num::ParseIntError),
+    InvalidResponse(serde_json::Error),
+    MigrationFailed(Option<String>),
     AddDeviceConfig(vmm::config::Error),
     AddDiskConfig(vmm::config::Error),
     AddFsConfig(vmm::config::Error),
     AddPmemConfig(vmm::config::Error),
     AddNetConfig(vmm::config::Error),
     AddVsockConfig(vmm::config::Error),
+    AddConsolePortConfig(vmm::config::Error),
     Restore(vmm::config::Error),
 }
 
@@ -51,12 +68,27 @@ impl fmt::Display for Error {
             InvalidCPUCount(e) => write!(f, "Error parsing CPU count: {}", e),
             InvalidMemorySize(e) => write!(f, "Error parsing memory size: {}", e),
             InvalidBalloonSize(e) => write!(f, "Error parsing balloon size: {}", e),
+            InvalidLinkState(s) => write!(f, "Invalid link state (expected up or down): {}", s),
+            InvalidQueuePairs(e) => write!(f, "Error parsing queue pairs count: {}", e),
+            InvalidMac(e) => write!(f, "Error parsing MAC address: {}", e),
+            InvalidFlowRules(e) => write!(f, "Error parsing flow rules: {}", e),
+            InvalidEjectTimeout(e) => write!(f, "Error parsing eject timeout: {}", e),
+            InvalidCompressionLevel(e) => write!(f, "Error parsing compression level: {}", e),
+            InvalidSnapshotThreads(e) => write!(f, "Error parsing snapshot threads count: {}", e),
+            InvalidMigrationTimeout(e) => write!(f, "Error parsing migration timeout: {}", e),
+            InvalidSampleDuration(e) => write!(f, "Error parsing sample duration: {}", e),
+            InvalidBandwidthLimit(e) => write!(f, "Error parsing bandwidth limit: {}", e),
+            InvalidThrottle(e) => write!(f, "Error parsing throttle percentage: {}", e),
+            InvalidResponse(e) => write!(f, "Error parsing the server response: {}", e),
+            MigrationFailed(Some(e)) => write!(f, "Migration failed: {}", e),
+            MigrationFailed(None) => write!(f, "Migration failed"),
             AddDeviceConfig(e) => write!(f, "Error parsing device syntax: {}", e),
             AddDiskConfig(e) => write!(f, "Error parsing disk syntax: {}", e),
             AddFsConfig(e) => write!(f, "Error parsing filesystem syntax: {}", e),
             AddPmemConfig(e) => write!(f, "Error parsing persistent memory syntax: {}", e),
             AddNetConfig(e) => write!(f, "Error parsing network syntax: {}", e),
             AddVsockConfig(e) => write!(f, "Error parsing vsock syntax: {}", e),
+            AddConsolePortConfig(e) => write!(f, "Error parsing console port syntax: {}", e),
             Restore(e) => write!(f, "Error parsing restore syntax: {}", e),
         }
     }
@@ -164,12 +196,12 @@ fn parse_http_response(socket: &mut UnixStream) -> Result<Option<String>, Error>
     }
 }
 
-fn simple_api_command(
+fn api_command_response(
     socket: &mut UnixStream,
     method: &str,
     c: &str,
     request_body: Option<&str>,
-) -> Result<(), Error> {
+) -> Result<Option<String>, Error> {
     socket
         .write_all(
             format!(
@@ -196,12 +228,102 @@ fn simple_api_command(
 
     socket.flush().map_err(Error::Socket)?;
 
-    if let Some(body) = parse_http_response(socket)? {
+    parse_http_response(socket)
+}
+
+fn simple_api_command(
+    socket: &mut UnixStream,
+    method: &str,
+    c: &str,
+    request_body: Option<&str>,
+) -> Result<(), Error> {
+    if let Some(body) = api_command_response(socket, method, c, request_body)? {
         println!("{}", body);
     }
     Ok(())
 }
 
+fn mib(bytes: u64) -> f64 {
+    bytes as f64 / f64::from(1 << 20)
+}
+
+fn migration_status(
+    socket: &mut UnixStream,
+) -> Result<vmm::migration_progress::MigrationStatus, Error> {
+    let body = api_command_response(socket, "GET", "migration-status", None)?;
+    serde_json::from_str(body.as_deref().unwrap_or_default()).map_err(Error::InvalidResponse)
+}
+
+fn migration_status_api_command(socket: &mut UnixStream) -> Result<(), Error> {
+    let status = migration_status(socket)?;
+
+    println!("Phase: {}", status.phase);
+    println!("Elapsed: {:.1} s", status.elapsed_ms as f64 / 1000.0);
+    println!(
+        "Transferred: {:.1} MiB ({:.1} MiB/s)",
+        mib(status.bytes_transferred),
+        mib(status.throughput)
+    );
+    println!("Pages remaining: {}", status.pages_remaining);
+    println!("Dirty rate: {} pages/s", status.dirty_rate);
+    println!("Iterations: {}", status.iterations);
+    if status.throttle > 0 {
+        println!("Throttle: {}%", status.throttle);
+    }
+    if let Some(error) = status.error {
+        println!("Error: {}", error);
+    }
+    Ok(())
+}
+
+// The migrations going on once the request is answered, wait for them to
+// be over, as reported by the VMM.
+fn wait_for_migration(api_socket: &str) -> Result<(), Error> {
+    use vmm::migration_progress::MigrationPhase;
+
+    loop {
+        let mut socket = UnixStream::connect(api_socket).map_err(Error::Socket)?;
+        let status = migration_status(&mut socket)?;
+        match status.phase {
+            MigrationPhase::Completed => return Ok(()),
+            MigrationPhase::Failed | MigrationPhase::Aborted => {
+                return Err(Error::MigrationFailed(status.error))
+            }
+            _ => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+fn dirty_rate_api_command(socket: &mut UnixStream, sample_ms: Option<&str>) -> Result<(), Error> {
+    let dirty_rate_data = vmm::api::VmDirtyRateData {
+        sample_ms: match sample_ms {
+            Some(sample_ms) => sample_ms.parse().map_err(Error::InvalidSampleDuration)?,
+            None => vmm::api::DEFAULT_DIRTY_RATE_SAMPLE_MS,
+        },
+    };
+
+    let body = api_command_response(
+        socket,
+        "PUT",
+        "dirty-rate",
+        Some(&serde_json::to_string(&dirty_rate_data).unwrap()),
+    )?;
+    let dirty_rate: vmm::migration_progress::DirtyRate =
+        serde_json::from_str(body.as_deref().unwrap_or_default())
+            .map_err(Error::InvalidResponse)?;
+
+    println!(
+        "Dirty rate: {} pages/s ({:.1} MiB/s)",
+        dirty_rate.dirty_rate,
+        mib(dirty_rate.dirty_rate * vmm::memory_manager::DIRTY_LOG_PAGE_SIZE)
+    );
+    println!(
+        "Dirty pages: {} in {} ms",
+        dirty_rate.dirty_pages, dirty_rate.sample_ms
+    );
+    Ok(())
+}
+
 fn resize_api_command(
     socket: &mut UnixStream,
     cpus: Option<&str>,
@@ -240,6 +362,20 @@ fn resize_api_command(
     )
 }
 
+fn resize_zone_api_command(socket: &mut UnixStream, id: &str, size: &str) -> Result<(), Error> {
+    let resize_zone = vmm::api::VmResizeZoneData {
+        id: id.to_owned(),
+        desired_ram: size.parse().map_err(Error::InvalidMemorySize)?,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "resize-zone",
+        Some(&serde_json::to_string(&resize_zone).unwrap()),
+    )
+}
+
 fn add_device_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
     let device_config = vmm::config::DeviceConfig::parse(config).map_err(Error::AddDeviceConfig)?;
 
@@ -251,8 +387,20 @@ fn add_device_api_command(socket: &mut UnixStream, config: &str) -> Result<(), E
     )
 }
 
-fn remove_device_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Error> {
-    let remove_device_data = vmm::api::VmRemoveDeviceData { id: id.to_owned() };
+fn remove_device_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    timeout_ms: Option<&str>,
+) -> Result<(), Error> {
+    let timeout_ms: Option<u64> = if let Some(timeout_ms) = timeout_ms {
+        Some(timeout_ms.parse().map_err(Error::InvalidEjectTimeout)?)
+    } else {
+        None
+    };
+    let remove_device_data = vmm::api::VmRemoveDeviceData {
+        id: id.to_owned(),
+        timeout_ms,
+    };
 
     simple_api_command(
         socket,
@@ -262,6 +410,103 @@ fn remove_device_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Er
     )
 }
 
+fn net_link_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    state: Option<&str>,
+) -> Result<(), Error> {
+    let link_up = match state {
+        Some("up") => Some(true),
+        Some("down") => Some(false),
+        Some(s) => return Err(Error::InvalidLinkState(s.to_owned())),
+        None => None,
+    };
+    let net_link_data = vmm::api::VmNetLinkData {
+        id: id.to_owned(),
+        link_up,
+    };
+
+    simple_api_command(
+        socket,
+        if link_up.is_some() { "PUT" } else { "GET" },
+        "net-link",
+        Some(&serde_json::to_string(&net_link_data).unwrap()),
+    )
+}
+
+fn net_mac_api_command(socket: &mut UnixStream, id: &str, mac: Option<&str>) -> Result<(), Error> {
+    let mac = if let Some(mac) = mac {
+        Some(serde_json::from_value(mac.into()).map_err(Error::InvalidMac)?)
+    } else {
+        None
+    };
+    let net_mac_data = vmm::api::VmNetMacData {
+        id: id.to_owned(),
+        mac,
+    };
+
+    simple_api_command(
+        socket,
+        if net_mac_data.mac.is_some() {
+            "PUT"
+        } else {
+            "GET"
+        },
+        "net-mac",
+        Some(&serde_json::to_string(&net_mac_data).unwrap()),
+    )
+}
+
+fn net_queues_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    queue_pairs: Option<&str>,
+) -> Result<(), Error> {
+    let queue_pairs: Option<u16> = if let Some(queue_pairs) = queue_pairs {
+        Some(queue_pairs.parse().map_err(Error::InvalidQueuePairs)?)
+    } else {
+        None
+    };
+    let net_queues_data = vmm::api::VmNetQueuesData {
+        id: id.to_owned(),
+        queue_pairs,
+    };
+
+    simple_api_command(
+        socket,
+        if queue_pairs.is_some() { "PUT" } else { "GET" },
+        "net-queues",
+        Some(&serde_json::to_string(&net_queues_data).unwrap()),
+    )
+}
+
+fn net_flow_rules_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    rules: Option<&str>,
+) -> Result<(), Error> {
+    let rules = if let Some(rules) = rules {
+        Some(serde_json::from_str(rules).map_err(Error::InvalidFlowRules)?)
+    } else {
+        None
+    };
+    let flow_rules_data = vmm::api::VmNetFlowRulesData {
+        id: id.to_owned(),
+        rules,
+    };
+
+    simple_api_command(
+        socket,
+        if flow_rules_data.rules.is_some() {
+            "PUT"
+        } else {
+            "GET"
+        },
+        "net-flow-rules",
+        Some(&serde_json::to_string(&flow_rules_data).unwrap()),
+    )
+}
+
 fn add_disk_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
     let disk_config = vmm::config::DiskConfig::parse(config).map_err(Error::AddDiskConfig)?;
 
@@ -317,9 +562,51 @@ fn add_vsock_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Er
     )
 }
 
-fn snapshot_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+fn add_console_port_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+    let port_config =
+        vmm::config::ConsolePortConfig::parse(config).map_err(Error::AddConsolePortConfig)?;
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "add-console-port",
+        Some(&serde_json::to_string(&port_config).unwrap()),
+    )
+}
+
+fn snapshot_api_command(
+    socket: &mut UnixStream,
+    url: &str,
+    incremental: bool,
+    compression: Option<&str>,
+    compression_level: Option<&str>,
+    threads: Option<&str>,
+) -> Result<(), Error> {
+    let compression_level: Option<i32> = if let Some(compression_level) = compression_level {
+        Some(
+            compression_level
+                .parse()
+                .map_err(Error::InvalidCompressionLevel)?,
+        )
+    } else {
+        None
+    };
+    let threads: Option<usize> = if let Some(threads) = threads {
+        Some(threads.parse().map_err(Error::InvalidSnapshotThreads)?)
+    } else {
+        None
+    };
     let snapshot_config = vmm::api::VmSnapshotConfig {
         destination_url: String::from(url),
+        mode: if incremental {
+            vmm::api::SnapshotMode::Incremental
+        } else {
+            vmm::api::SnapshotMode::Full
+        },
+        // The only compression accepted on the command line is zstd.
+        compression: compression.map(|_| vmm::api::SnapshotCompression::Zstd),
+        compression_level,
+        threads,
     };
 
     simple_api_command(
@@ -330,6 +617,50 @@ fn snapshot_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error>
     )
 }
 
+fn snapshot_consolidate_api_command(
+    socket: &mut UnixStream,
+    source_url: &str,
+    destination_url: &str,
+) -> Result<(), Error> {
+    let consolidate_config = vmm::api::VmSnapshotConsolidateConfig {
+        source_url: String::from(source_url),
+        destination_url: String::from(destination_url),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "snapshot-consolidate",
+        Some(&serde_json::to_string(&consolidate_config).unwrap()),
+    )
+}
+
+fn screenshot_api_command(socket: &mut UnixStream, path: &str) -> Result<(), Error> {
+    let screenshot_config = vmm::api::VmScreenshotConfig {
+        destination_path: PathBuf::from(path),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "screenshot",
+        Some(&serde_json::to_string(&screenshot_config).unwrap()),
+    )
+}
+
+fn memory_fds_api_command(socket: &mut UnixStream, path: &str) -> Result<(), Error> {
+    let memory_fds_config = vmm::api::VmMemoryFdsConfig {
+        destination_socket: PathBuf::from(path),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "memory-fds",
+        Some(&serde_json::to_string(&memory_fds_config).unwrap()),
+    )
+}
+
 fn restore_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
     let restore_config = vmm::config::RestoreConfig::parse(config).map_err(Error::Restore)?;
 
@@ -341,13 +672,97 @@ fn restore_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Erro
     )
 }
 
+// TLS parameters of the migration subcommands, TLS being disabled unless
+// one of them is given.
+fn migration_tls_config(matches: &ArgMatches) -> Option<vmm::api::MigrationTlsConfig> {
+    let tls_config = vmm::api::MigrationTlsConfig {
+        cert: matches.value_of("tls_cert").map(PathBuf::from),
+        key: matches.value_of("tls_key").map(PathBuf::from),
+        ca: matches.value_of("tls_ca").map(PathBuf::from),
+        server_name: matches.value_of("tls_server_name").map(|s| s.to_owned()),
+    };
+
+    if tls_config == vmm::api::MigrationTlsConfig::default() {
+        None
+    } else {
+        Some(tls_config)
+    }
+}
+
+fn migration_timeout(timeout: Option<&str>) -> Result<u64, Error> {
+    match timeout {
+        Some(timeout) => timeout.parse().map_err(Error::InvalidMigrationTimeout),
+        None => Ok(vmm::api::DEFAULT_MIGRATION_TIMEOUT),
+    }
+}
+
+fn send_migration_api_command(
+    socket: &mut UnixStream,
+    api_socket: &str,
+    matches: &ArgMatches,
+) -> Result<(), Error> {
+    let send_migration_data = vmm::api::VmSendMigrationData {
+        destination_url: matches
+            .value_of("send_migration_config")
+            .unwrap()
+            .to_owned(),
+        postcopy: matches.is_present("postcopy"),
+        tls: migration_tls_config(matches),
+        timeout: migration_timeout(matches.value_of("timeout"))?,
+        bandwidth_limit: matches
+            .value_of("bandwidth_limit")
+            .map(|limit| limit.parse().map_err(Error::InvalidBandwidthLimit))
+            .transpose()?,
+        auto_converge: matches.is_present("auto_converge"),
+        max_throttle: match matches.value_of("max_throttle") {
+            Some(max_throttle) => max_throttle.parse().map_err(Error::InvalidThrottle)?,
+            None => vmm::cpu::MAX_THROTTLE,
+        },
+        local: matches.is_present("local"),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "send-migration",
+        Some(&serde_json::to_string(&send_migration_data).unwrap()),
+    )?;
+    wait_for_migration(api_socket)
+}
+
+fn receive_migration_api_command(
+    socket: &mut UnixStream,
+    api_socket: &str,
+    matches: &ArgMatches,
+) -> Result<(), Error> {
+    let receive_migration_data = vmm::api::VmReceiveMigrationData {
+        receiver_url: matches
+            .value_of("receive_migration_config")
+            .unwrap()
+            .to_owned(),
+        tls: migration_tls_config(matches),
+        timeout: migration_timeout(matches.value_of("timeout"))?,
+        local: matches.is_present("local"),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "receive-migration",
+        Some(&serde_json::to_string(&receive_migration_data).unwrap()),
+    )?;
+    wait_for_migration(api_socket)
+}
+
 fn do_command(matches: &ArgMatches) -> Result<(), Error> {
-    let mut socket =
-        UnixStream::connect(matches.value_of("api-socket").unwrap()).map_err(Error::Socket)?;
+    let api_socket = matches.value_of("api-socket").unwrap();
+    let mut socket = UnixStream::connect(api_socket).map_err(Error::Socket)?;
 
     match matches.subcommand_name() {
         Some("info") => simple_api_command(&mut socket, "GET", "info", None),
         Some("counters") => simple_api_command(&mut socket, "GET", "counters", None),
+        Some("balloon-stats") => simple_api_command(&mut socket, "GET", "balloon-stats", None),
+        Some("vsock-info") => simple_api_command(&mut socket, "GET", "vsock-info", None),
         Some("resize") => resize_api_command(
             &mut socket,
             matches
@@ -363,6 +778,19 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("balloon"),
         ),
+        Some("resize-zone") => resize_zone_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("resize-zone")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("resize-zone")
+                .unwrap()
+                .value_of("size")
+                .unwrap(),
+        ),
         Some("add-device") => add_device_api_command(
             &mut socket,
             matches
@@ -378,6 +806,58 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("id")
                 .unwrap(),
+            matches
+                .subcommand_matches("remove-device")
+                .unwrap()
+                .value_of("timeout"),
+        ),
+        Some("net-link") => net_link_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("net-link")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("net-link")
+                .unwrap()
+                .value_of("state"),
+        ),
+        Some("net-mac") => net_mac_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("net-mac")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("net-mac")
+                .unwrap()
+                .value_of("mac"),
+        ),
+        Some("net-queues") => net_queues_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("net-queues")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("net-queues")
+                .unwrap()
+                .value_of("queue_pairs"),
+        ),
+        Some("net-flow-rules") => net_flow_rules_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("net-flow-rules")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("net-flow-rules")
+                .unwrap()
+                .value_of("rules"),
         ),
         Some("add-disk") => add_disk_api_command(
             &mut socket,
@@ -419,6 +899,14 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .value_of("vsock_config")
                 .unwrap(),
         ),
+        Some("add-console-port") => add_console_port_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("add-console-port")
+                .unwrap()
+                .value_of("console_port_config")
+                .unwrap(),
+        ),
         Some("snapshot") => snapshot_api_command(
             &mut socket,
             matches
@@ -426,6 +914,51 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("snapshot_config")
                 .unwrap(),
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .is_present("incremental"),
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .value_of("compression"),
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .value_of("compression_level"),
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .value_of("threads"),
+        ),
+        Some("snapshot-consolidate") => snapshot_consolidate_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("snapshot-consolidate")
+                .unwrap()
+                .value_of("source_url")
+                .unwrap(),
+            matches
+                .subcommand_matches("snapshot-consolidate")
+                .unwrap()
+                .value_of("destination_url")
+                .unwrap(),
+        ),
+        Some("screenshot") => screenshot_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("screenshot")
+                .unwrap()
+                .value_of("screenshot_path")
+                .unwrap(),
+        ),
+        Some("memory-fds") => memory_fds_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("memory-fds")
+                .unwrap()
+                .value_of("memory_fds_socket")
+                .unwrap(),
         ),
         Some("restore") => restore_api_command(
             &mut socket,
@@ -435,11 +968,55 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .value_of("restore_config")
                 .unwrap(),
         ),
+        Some("send-migration") => send_migration_api_command(
+            &mut socket,
+            api_socket,
+            matches.subcommand_matches("send-migration").unwrap(),
+        ),
+        Some("receive-migration") => receive_migration_api_command(
+            &mut socket,
+            api_socket,
+            matches.subcommand_matches("receive-migration").unwrap(),
+        ),
+        Some("migration-status") => migration_status_api_command(&mut socket),
+        Some("dirty-rate") => dirty_rate_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("dirty-rate")
+                .unwrap()
+                .value_of("sample_ms"),
+        ),
         Some(c) => simple_api_command(&mut socket, "PUT", c, None),
         None => unreachable!(),
     }
 }
 
+// Options shared by both ends of a migration.
+fn migration_args(cert_help: &'static str, ca_help: &'static str) -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("tls_cert")
+            .long("tls-cert")
+            .help(cert_help)
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("tls_key")
+            .long("tls-key")
+            .help("Private key of the TLS certificate")
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("tls_ca")
+            .long("tls-ca")
+            .help(ca_help)
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("timeout")
+            .long("timeout")
+            .help("Seconds without any progress after which the migration fails")
+            .takes_value(true)
+            .number_of_values(1),
+    ]
+}
+
 fn main() {
     let app = App::new("ch-remote")
         .author(crate_authors!())
@@ -453,6 +1030,15 @@ fn main() {
                 .number_of_values(1)
                 .required(true),
         )
+        .subcommand(
+            SubCommand::with_name("add-console-port")
+                .about("Add virtio-console port")
+                .arg(
+                    Arg::with_name("console_port_config")
+                        .index(1)
+                        .help(vmm::config::ConsolePortConfig::SYNTAX),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("add-device")
                 .about("Add VFIO device")
@@ -510,12 +1096,94 @@ fn main() {
         .subcommand(
             SubCommand::with_name("remove-device")
                 .about("Remove VFIO device")
-                .arg(Arg::with_name("id").index(1).help("<device_id>")),
+                .arg(Arg::with_name("id").index(1).help("<device_id>"))
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .takes_value(true)
+                        .help("Milliseconds to wait for the guest to eject the device, not waiting by default"),
+                ),
         )
         .subcommand(SubCommand::with_name("info").about("Info on the VM"))
         .subcommand(SubCommand::with_name("counters").about("Counters from the VM"))
+        .subcommand(
+            SubCommand::with_name("balloon-stats").about("Memory statistics from the balloon"),
+        )
+        .subcommand(
+            SubCommand::with_name("vsock-info").about("Connections tracked by the vsock device"),
+        )
+        .subcommand(
+            SubCommand::with_name("net-link")
+                .about("Get or set the link state of a network device")
+                .arg(Arg::with_name("id").index(1).help("<device_id>"))
+                .arg(Arg::with_name("state").index(2).help("up|down")),
+        )
+        .subcommand(
+            SubCommand::with_name("net-mac")
+                .about("Get or set the MAC address of a network device")
+                .arg(Arg::with_name("id").index(1).help("<device_id>"))
+                .arg(Arg::with_name("mac").index(2).help("<mac_address>")),
+        )
+        .subcommand(
+            SubCommand::with_name("net-queues")
+                .about("Get or set the queue pairs in use by a network device")
+                .arg(Arg::with_name("id").index(1).help("<device_id>"))
+                .arg(
+                    Arg::with_name("queue_pairs")
+                        .index(2)
+                        .help("<queue_pairs_count>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("net-flow-rules")
+                .about("Get or replace the flow steering rules of a network device")
+                .arg(Arg::with_name("id").index(1).help("<device_id>"))
+                .arg(Arg::with_name("rules").index(2).help("<rules_json_array>")),
+        )
+        .subcommand(
+            SubCommand::with_name("memory-fds")
+                .about("Send the memfd file descriptors of the memory zones")
+                .arg(
+                    Arg::with_name("memory_fds_socket")
+                        .index(1)
+                        .help("<destination_socket>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migration-status").about("Progress of the migration of the VM"),
+        )
+        .subcommand(SubCommand::with_name("migration-abort").about("Abort the migration of the VM"))
+        .subcommand(
+            SubCommand::with_name("dirty-rate")
+                .about("Measure the rate the guest dirties its memory at")
+                .arg(
+                    Arg::with_name("sample_ms")
+                        .long("sample-ms")
+                        .help("Milliseconds the dirty pages are logged for")
+                        .takes_value(true)
+                        .number_of_values(1),
+                ),
+        )
         .subcommand(SubCommand::with_name("pause").about("Pause the VM"))
         .subcommand(SubCommand::with_name("reboot").about("Reboot the VM"))
+        .subcommand(
+            SubCommand::with_name("receive-migration")
+                .about("Receive a VM migration")
+                .arg(
+                    Arg::with_name("receive_migration_config")
+                        .index(1)
+                        .help("<receiver_url>"),
+                )
+                .arg(
+                    Arg::with_name("local")
+                        .long("local")
+                        .help("Take the memory and devices over from a source on the same host"),
+                )
+                .args(&migration_args(
+                    "Certificate presented to the source, enabling TLS",
+                    "CA the source certificate must be signed by",
+                )),
+        )
         .subcommand(
             SubCommand::with_name("resize")
                 .about("Resize the VM")
@@ -541,7 +1209,85 @@ fn main() {
                         .number_of_values(1),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("resize-zone")
+                .about("Resize a memory zone")
+                .arg(
+                    Arg::with_name("id")
+                        .long("id")
+                        .help("Memory zone identifier")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("size")
+                        .long("size")
+                        .help("New memory zone size (in bytes)")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .required(true),
+                ),
+        )
         .subcommand(SubCommand::with_name("resume").about("Resume the VM"))
+        .subcommand(
+            SubCommand::with_name("screenshot")
+                .about("Save the VM display as a PNG image")
+                .arg(
+                    Arg::with_name("screenshot_path")
+                        .index(1)
+                        .help("<destination_path>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("send-migration")
+                .about("Initiate a VM migration")
+                .arg(
+                    Arg::with_name("send_migration_config")
+                        .index(1)
+                        .help("<destination_url>"),
+                )
+                .arg(
+                    Arg::with_name("postcopy")
+                        .long("postcopy")
+                        .help("Resume the VM on the destination before all its memory is sent"),
+                )
+                .arg(
+                    Arg::with_name("local")
+                        .long("local")
+                        .help("Hand the memory and devices over to a destination on the same host"),
+                )
+                .arg(
+                    Arg::with_name("bandwidth_limit")
+                        .long("bandwidth-limit")
+                        .help("Bytes per second the migration is limited to")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("auto_converge")
+                        .long("auto-converge")
+                        .help("Throttle the vCPUs while the pre-copy rounds don't converge"),
+                )
+                .arg(
+                    Arg::with_name("max_throttle")
+                        .long("max-throttle")
+                        .help("Maximum percentage of the time the vCPUs are throttled for")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .args(&migration_args(
+                    "Certificate presented to the destination",
+                    "CA the destination certificate must be signed by, enabling TLS",
+                ))
+                .arg(
+                    Arg::with_name("tls_server_name")
+                        .long("tls-server-name")
+                        .help("Name the destination certificate is checked against")
+                        .takes_value(true)
+                        .number_of_values(1),
+                ),
+        )
         .subcommand(SubCommand::with_name("shutdown").about("Shutdown the VM"))
         .subcommand(
             SubCommand::with_name("snapshot")
@@ -550,6 +1296,41 @@ fn main() {
                     Arg::with_name("snapshot_config")
                         .index(1)
                         .help("<destination_url>"),
+                )
+                .arg(
+                    Arg::with_name("incremental").long("incremental").help(
+                        "Only save the memory changed since the previous incremental snapshot",
+                    ),
+                )
+                .arg(
+                    Arg::with_name("compression")
+                        .long("compression")
+                        .takes_value(true)
+                        .possible_values(&["zstd"])
+                        .help("Compress the memory saved"),
+                )
+                .arg(
+                    Arg::with_name("compression_level")
+                        .long("compression-level")
+                        .takes_value(true)
+                        .requires("compression")
+                        .help("Level of the compression, from 1 to 22"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .takes_value(true)
+                        .help("Number of threads saving the memory"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot-consolidate")
+                .about("Merge an incremental snapshot chain into a whole snapshot")
+                .arg(Arg::with_name("source_url").index(1).help("<source_url>"))
+                .arg(
+                    Arg::with_name("destination_url")
+                        .index(2)
+                        .help("<destination_url>"),
                 ),
         )
         .subcommand(
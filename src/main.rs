@@ -97,7 +97,10 @@ fn create_app<'a, 'b>(
                 .long("cpus")
                 .help(
                     "boot=<boot_vcpus>,max=<max_vcpus>,\
-                    topology=<threads_per_core>:<cores_per_die>:<dies_per_package>:<packages>",
+                    topology=<threads_per_core>:<cores_per_die>:<dies_per_package>:<packages>,\
+                    affinity=<vcpu>@<host_cpu>[-<host_cpu>]:...,strict_affinity=on|off,\
+                    model=<cpu_model>,features=<+|-><cpu_feature>:...,\
+                    base_frequency=<mhz>,max_frequency=<mhz>,pstates=on|off,nested=on|off",
                 )
                 .default_value(&default_vcpus)
                 .group("vm-config"),
@@ -108,12 +111,23 @@ fn create_app<'a, 'b>(
                 .help(
                     "Memory parameters \
                      \"size=<guest_memory_size>,mergeable=on|off,shared=on|off,hugepages=on|off,\
-                     hotplug_method=acpi|virtio-mem,\
-                     hotplug_size=<hotpluggable_memory_size>\"",
+                     prefault=on|off,thp=always|never|madvise,hotplug_method=acpi|virtio-mem,\
+                     hotplug_size=<hotpluggable_memory_size>,balloon=on|off,\
+                     deflate_on_oom=on|off,autodeflate=on|off,free_page_reporting=on|off,\
+                     stats_polling_interval=<balloon_stats_polling_interval_in_seconds>,\
+                     dirty_ring=on|off\"",
                 )
                 .default_value(&default_memory)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("memory-zone")
+                .long("memory-zone")
+                .help(config::MemoryZoneConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("kernel")
                 .long("kernel")
@@ -155,7 +169,7 @@ fn create_app<'a, 'b>(
             Arg::with_name("rng")
                 .long("rng")
                 .help(
-                    "Random number generator parameters \"src=<entropy_source_path>,iommu=on|off\"",
+                    "Random number generator parameters \"src=<entropy_source_path>,iommu=on|off,max_bytes=<bytes_per_period>,period_ms=<refill_period_ms>\"",
                 )
                 .default_value(&default_rng)
                 .group("vm-config"),
@@ -179,7 +193,10 @@ fn create_app<'a, 'b>(
         .arg(
             Arg::with_name("serial")
                 .long("serial")
-                .help("Control serial port: off|null|tty|file=/path/to/a/file")
+                .help(
+                    "Control serial port: \
+                    off|null|pty|tty|file=/path/to/a/file|socket=/path/to/a/socket|tcp=<host:port>",
+                )
                 .default_value("null")
                 .group("vm-config"),
         )
@@ -187,8 +204,13 @@ fn create_app<'a, 'b>(
             Arg::with_name("console")
                 .long("console")
                 .help(
-                    "Control (virtio) console: \"off|null|tty|file=/path/to/a/file,iommu=on|off\"",
+                    "Control (virtio) console: \"off|null|pty|tty|file=/path/to/a/file|\
+                    socket=/path/to/a/socket|tcp=<host:port>,iommu=on|off\", \
+                    followed by any additional port: \
+                    \"name=<port_name>,pty|socket=<socket_path>|file=<file_path>\"",
                 )
+                .takes_value(true)
+                .min_values(1)
                 .default_value("tty")
                 .group("vm-config"),
         )
@@ -208,6 +230,54 @@ fn create_app<'a, 'b>(
                 .number_of_values(1)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("watchdog")
+                .long("watchdog")
+                .help(config::WatchdogConfig::SYNTAX)
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("sound")
+                .long("sound")
+                .help(config::SoundConfig::SYNTAX)
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("gpu")
+                .long("gpu")
+                .help(config::GpuConfig::SYNTAX)
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("vdpa")
+                .long("vdpa")
+                .help(config::VdpaConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("numa")
+                .long("numa")
+                .help(config::NumaConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("platform")
+                .long("platform")
+                .help(config::PlatformConfig::SYNTAX)
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -223,6 +293,14 @@ fn create_app<'a, 'b>(
                 .min_values(1)
                 .group("logging"),
         )
+        .arg(
+            Arg::with_name("event-monitor")
+                .long("event-monitor")
+                .help(vmm::event_monitor::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
         .arg(
             Arg::with_name("api-socket")
                 .long("api-socket")
@@ -274,6 +352,20 @@ fn create_app<'a, 'b>(
                 .min_values(1)
                 .group("vm-config"),
         );
+        app = app.arg(
+            Arg::with_name("gdb")
+                .long("gdb")
+                .help(config::GdbConfig::SYNTAX)
+                .takes_value(true)
+                .group("vm-config"),
+        );
+        app = app.arg(
+            Arg::with_name("pvpanic")
+                .long("pvpanic")
+                .help(config::PvpanicConfig::SYNTAX)
+                .takes_value(true)
+                .group("vm-config"),
+        );
     }
 
     app
@@ -435,6 +527,13 @@ fn main() {
     .map(|()| log::set_max_level(log_level))
     .expect("Expected to be able to setup logger");
 
+    if let Some(monitor) = cmd_arguments.value_of("event-monitor") {
+        if let Err(e) = vmm::event_monitor::set_monitor(monitor) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+
     if let Some(backend_command) = cmd_arguments.value_of("net-backend") {
         start_net_backend(backend_command);
     } else if let Some(backend_command) = cmd_arguments.value_of("block-backend") {
@@ -450,7 +549,7 @@ extern crate credibility;
 
 #[cfg(test)]
 mod unit_tests {
-    use crate::config::HotplugMethod;
+    use crate::config::{HotplugMethod, ThpMode};
     use crate::{create_app, prepare_default_values};
     use std::path::PathBuf;
     use vmm::config::{
@@ -511,6 +610,14 @@ mod unit_tests {
                     boot_vcpus: 1,
                     max_vcpus: 1,
                     topology: None,
+                    affinity: None,
+                    strict_affinity: false,
+                    model: None,
+                    features: None,
+                    base_frequency: None,
+                    max_frequency: None,
+                    pstates: false,
+                    nested: false,
                 },
                 memory: MemoryConfig {
                     size: 536_870_912,
@@ -520,8 +627,16 @@ mod unit_tests {
                     hotplug_size: None,
                     shared: false,
                     hugepages: false,
+                    prefault: false,
+                    thp: ThpMode::Madvise,
                     balloon: false,
                     balloon_size: 0,
+                    deflate_on_oom: false,
+                    autodeflate: false,
+                    free_page_reporting: false,
+                    stats_polling_interval: 0,
+                    dirty_ring: true,
+                    zones: None,
                 },
                 kernel: Some(KernelConfig {
                     path: PathBuf::from("/path/to/kernel"),
@@ -535,6 +650,9 @@ mod unit_tests {
                 rng: RngConfig {
                     src: PathBuf::from("/dev/urandom"),
                     iommu: false,
+                    max_bytes: None,
+                    period_ms: 1000,
+                    pci_segment: 0,
                 },
                 fs: None,
                 pmem: None,
@@ -542,17 +660,32 @@ mod unit_tests {
                     file: None,
                     mode: ConsoleOutputMode::Null,
                     iommu: false,
+                    socket: None,
+                    tcp: None,
                 },
                 console: ConsoleConfig {
                     file: None,
                     mode: ConsoleOutputMode::Tty,
                     iommu: false,
+                    socket: None,
+                    tcp: None,
                 },
+                console_ports: None,
                 devices: None,
                 vsock: None,
+                watchdog: None,
+                sound: None,
+                gpu: None,
+                vdpa: None,
+                numa: None,
                 iommu: false,
+                platform: None,
                 #[cfg(target_arch = "x86_64")]
                 sgx_epc: None,
+                #[cfg(target_arch = "x86_64")]
+                gdb: None,
+                #[cfg(target_arch = "x86_64")]
+                pvpanic: None,
             };
 
             aver_eq!(tb, expected_vm_config, result_vm_config);
@@ -683,6 +816,18 @@ mod unit_tests {
                 }"#,
                 true,
             ),
+            (
+                vec![
+                    "cloud-hypervisor", "--kernel", "/path/to/kernel",
+                    "--memory", "size=1G",
+                    "--memory-zone", "id=mem0,hotplug_size=1G,host_numa_node=0,guest_numa_node=1",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "memory": {"size": 1073741824, "zones": [{"id": "mem0", "hotplug_size": 1073741824, "host_numa_node": 0, "guest_numa_node": 1}]}
+                }"#,
+                true,
+            ),
         ]
         .iter()
         .for_each(|(cli, openapi, equal)| {
@@ -1001,20 +1146,36 @@ mod unit_tests {
 
     #[test]
     fn test_valid_vm_config_rng() {
-        vec![(
-            vec![
-                "cloud-hypervisor",
-                "--kernel",
-                "/path/to/kernel",
-                "--rng",
-                "src=/path/to/entropy/source",
-            ],
-            r#"{
-                "kernel": {"path": "/path/to/kernel"},
-                "rng": {"src": "/path/to/entropy/source"}
-            }"#,
-            true,
-        )]
+        vec![
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--rng",
+                    "src=/path/to/entropy/source",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "rng": {"src": "/path/to/entropy/source"}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--rng",
+                    "src=/path/to/entropy/source,max_bytes=1K,period_ms=100",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "rng": {"src": "/path/to/entropy/source", "max_bytes": 1024, "period_ms": 100}
+                }"#,
+                true,
+            ),
+        ]
         .iter()
         .for_each(|(cli, openapi, equal)| {
             compare_vm_config_cli_vs_json(cli, openapi, *equal);
@@ -1351,6 +1512,26 @@ mod unit_tests {
                 }"#,
                 true,
             ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--console",
+                    "name=org.test.agent,socket=/tmp/agent.sock",
+                    "null",
+                    "name=org.test.debug,pty",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "console": {"mode": "Null"},
+                    "console_ports": [
+                        {"name": "org.test.agent", "mode": "Socket", "path": "/tmp/agent.sock"},
+                        {"name": "org.test.debug", "mode": "Pty"}
+                    ]
+                }"#,
+                true,
+            ),
         ]
         .iter()
         .for_each(|(cli, openapi, equal)| {
@@ -1545,6 +1545,22 @@ mod tests {
                 "bar"
             );
 
+            // The DAX window can't be migrated, so the VM must refuse to
+            // be snapshotted.
+            if dax {
+                let snapshot_dir = temp_snapshot_dir_path(&guest.tmp_dir);
+                aver!(tb, remote_command(&api_socket, "pause", None));
+                aver!(
+                    tb,
+                    !remote_command(
+                        &api_socket,
+                        "snapshot",
+                        Some(format!("file://{}", snapshot_dir).as_str()),
+                    )
+                );
+                aver!(tb, remote_command(&api_socket, "resume", None));
+            }
+
             // ACPI is not built with mmio, hence we can't test the resize
             // feature for mmio.
             #[cfg(not(feature = "mmio"))]
@@ -2413,7 +2429,7 @@ mod tests {
                         )
                         .as_str(),
                         format!(
-                            "path={},readonly=on,direct=on,num_queues=4",
+                            "path={},readonly=on,direct=on,num_queues=4,serial=ch-test-serial",
                             blk_file_path.to_str().unwrap()
                         )
                         .as_str(),
@@ -2459,6 +2475,29 @@ mod tests {
                         .unwrap_or_default(),
                     4
                 );
+
+                // Check the serial is reported to the guest, which lets udev
+                // create the by-id link.
+                aver_eq!(
+                    tb,
+                    guest
+                        .ssh_command("ls /dev/disk/by-id/ | grep -c virtio-ch-test-serial")
+                        .unwrap_or_default()
+                        .trim()
+                        .parse::<u32>()
+                        .unwrap_or_default(),
+                    1
+                );
+
+                // Disks without an explicit serial report their identifier.
+                aver_eq!(
+                    tb,
+                    guest
+                        .ssh_command("cat /sys/block/vda/serial")
+                        .unwrap_or_default()
+                        .trim(),
+                    "_disk0"
+                );
                 let _ = cloud_child.kill();
                 let _ = cloud_child.wait();
 
@@ -2527,6 +2566,60 @@ mod tests {
             test_vhost_user_net(None, 2, None, true, true)
         }
 
+        #[test]
+        fn test_vhost_user_net_reconnect() {
+            test_block!(tb, "", {
+                let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+                let guest = Guest::new(&mut focal);
+                let kernel_path = direct_kernel_boot_path().unwrap();
+
+                let (mut daemon_child, vunet_socket_path) =
+                    prepare_vhost_user_net_daemon(&guest.tmp_dir, &guest.network.host_ip, None, 2);
+
+                let mut cloud_child = GuestCommand::new(&guest)
+                    .args(&["--cpus", "boot=1"])
+                    .args(&["--memory", "size=512M,shared=on"])
+                    .args(&["--kernel", kernel_path.to_str().unwrap()])
+                    .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+                    .default_disks()
+                    .args(&[
+                        "--net",
+                        format!(
+                            "vhost_user=true,mac={},socket={},num_queues=2,queue_size=1024,reconnect_backoff_ms=500",
+                            guest.network.guest_mac, vunet_socket_path
+                        )
+                        .as_str(),
+                    ])
+                    .spawn()
+                    .unwrap();
+
+                thread::sleep(std::time::Duration::new(20, 0));
+
+                // The ssh connection goes through the vhost-user-net device.
+                aver_eq!(tb, guest.get_cpu_count().unwrap_or_default(), 1);
+
+                // Kill the backend and start a new one on the same socket.
+                let _ = daemon_child.kill();
+                let _ = daemon_child.wait();
+                let (mut daemon_child, _) =
+                    prepare_vhost_user_net_daemon(&guest.tmp_dir, &guest.network.host_ip, None, 2);
+
+                // The VMM is still running, and the guest can be reached again
+                // through the new backend.
+                aver!(tb, cloud_child.try_wait().unwrap().is_none());
+                aver_eq!(tb, guest.get_cpu_count().unwrap_or_default(), 1);
+
+                let _ = cloud_child.kill();
+                let _ = cloud_child.wait();
+
+                thread::sleep(std::time::Duration::new(5, 0));
+                let _ = daemon_child.kill();
+                let _ = daemon_child.wait();
+
+                Ok(())
+            });
+        }
+
         #[test]
         #[cfg(target_arch = "x86_64")]
         fn test_vhost_user_blk_default() {
@@ -3141,6 +3234,50 @@ mod tests {
             });
         }
 
+        #[cfg_attr(not(feature = "mmio"), test)]
+        fn test_console_pty() {
+            test_block!(tb, "", {
+                let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+                let guest = Guest::new(&mut focal);
+
+                let api_socket = temp_api_path(&guest.tmp_dir);
+                let mut child = GuestCommand::new(&guest)
+                    .args(&["--api-socket", &api_socket])
+                    .args(&["--cpus", "boot=1"])
+                    .args(&["--memory", "size=512M"])
+                    .args(&["--kernel", guest.fw_path.as_str()])
+                    .default_disks()
+                    .default_net()
+                    .args(&["--serial", "pty"])
+                    .args(&["--console", "pty"])
+                    .spawn()
+                    .unwrap();
+
+                thread::sleep(std::time::Duration::new(20, 0));
+
+                // The allocated pseudo terminals are reported by vm.info
+                let (cmd_success, cmd_output) = remote_command_w_output(&api_socket, "info", None);
+                aver!(tb, cmd_success);
+                let info: serde_json::Value = serde_json::from_slice(&cmd_output).unwrap();
+                for device in &["serial", "console"] {
+                    let config = &info["config"][device];
+                    aver_eq!(tb, config["mode"], "Pty");
+                    let path = config["file"].as_str().unwrap_or_default();
+                    aver!(tb, path.starts_with("/dev/pts/"));
+                    aver!(tb, std::path::Path::new(path).exists());
+                }
+
+                guest.ssh_command("sudo shutdown -h now")?;
+
+                // Check that the cloud-hypervisor binary actually terminated
+                if let Ok(status) = child.wait() {
+                    aver_eq!(tb, status.success(), true);
+                }
+
+                Ok(())
+            });
+        }
+
         #[cfg_attr(not(feature = "mmio"), test)]
         // The VFIO integration test starts cloud-hypervisor guest with 3 TAP
         // backed networking interfaces, bound through a simple bridge on the host.
@@ -3184,7 +3321,7 @@ mod tests {
                     prepare_virtiofsd(&guest.tmp_dir, vfio_path.to_str().unwrap(), "none");
 
                 let mut child = GuestCommand::new(&guest)
-                    .args(&["--cpus", "boot=4"])
+                    .args(&["--cpus", "boot=4,nested=on"])
                     .args(&["--memory", "size=2G,hugepages=on,shared=on"])
                     .args(&["--kernel", kernel_path.to_str().unwrap()])
                     .default_disks()
@@ -3372,6 +3509,182 @@ mod tests {
             });
         }
 
+        #[cfg_attr(not(feature = "mmio"), test)]
+        #[cfg(target_arch = "x86_64")]
+        // The L1 guest is exposed the virtualization extensions, and boots an
+        // L2 guest through cloud-hypervisor, the binary and the kernel being
+        // shared through virtio-fs. The L2 guest has no disk, so its kernel
+        // only boots up to the point it looks for a root filesystem.
+        fn test_nested_virtualization() {
+            test_block!(tb, "", {
+                let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+                let guest = Guest::new(&mut focal);
+
+                let mut kernel_path = dirs::home_dir().unwrap();
+                kernel_path.push("workloads");
+                kernel_path.push("bzImage");
+
+                let nested_path = guest.tmp_dir.path().join("nested");
+                fs::create_dir_all(&nested_path).unwrap();
+                rate_limited_copy(
+                    clh_command("cloud-hypervisor"),
+                    nested_path.join("cloud-hypervisor"),
+                )
+                .expect("copying of cloud-hypervisor failed");
+                rate_limited_copy(
+                    direct_kernel_boot_path().unwrap(),
+                    nested_path.join("vmlinux"),
+                )
+                .expect("copying of the L2 kernel failed");
+
+                let (mut daemon_child, virtiofsd_socket_path) =
+                    prepare_virtiofsd(&guest.tmp_dir, nested_path.to_str().unwrap(), "none");
+
+                let mut child = GuestCommand::new(&guest)
+                    .args(&["--cpus", "boot=2,nested=on"])
+                    .args(&["--memory", "size=1G,shared=on"])
+                    .args(&["--kernel", kernel_path.to_str().unwrap()])
+                    .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+                    .default_disks()
+                    .default_net()
+                    .args(&[
+                        "--fs",
+                        format!(
+                            "tag=myfs,socket={},num_queues=1,queue_size=1024",
+                            virtiofsd_socket_path
+                        )
+                        .as_str(),
+                    ])
+                    .spawn()
+                    .unwrap();
+
+                thread::sleep(std::time::Duration::new(20, 0));
+
+                aver!(
+                    tb,
+                    guest
+                        .ssh_command("grep -c -E 'vmx|svm' /proc/cpuinfo")
+                        .unwrap_or_default()
+                        .trim()
+                        .parse::<u32>()
+                        .unwrap_or_default()
+                        > 0
+                );
+                aver_eq!(
+                    tb,
+                    guest
+                        .ssh_command(
+                            "mkdir -p nested && \
+                             sudo mount -t virtiofs myfs nested/ && \
+                             test -c /dev/kvm && \
+                             echo ok"
+                        )
+                        .unwrap_or_default()
+                        .trim(),
+                    "ok"
+                );
+
+                // The L2 kernel hangs once it found no root filesystem.
+                aver!(
+                    tb,
+                    guest
+                        .ssh_command(
+                            "sudo timeout 10 nested/cloud-hypervisor \
+                             --kernel nested/vmlinux \
+                             --cmdline \"console=ttyS0 panic=0\" \
+                             --cpus boot=1 --memory size=256M \
+                             --serial tty --console off | grep -c \"Linux version\""
+                        )
+                        .unwrap_or_default()
+                        .trim()
+                        .parse::<u32>()
+                        .unwrap_or_default()
+                        > 0
+                );
+
+                let _ = child.kill();
+                let _ = daemon_child.kill();
+                let _ = child.wait();
+                let _ = daemon_child.wait();
+
+                Ok(())
+            });
+        }
+
+        #[cfg_attr(not(feature = "mmio"), test)]
+        #[cfg(target_arch = "x86_64")]
+        // The guest reports a panic by writing to the pvpanic port through
+        // /dev/port, rather than by crashing its kernel. The VM gets paused,
+        // and the panic reported to the event monitor and by vm.info until
+        // the VM is resumed.
+        fn test_pvpanic() {
+            test_block!(tb, "", {
+                let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+                let guest = Guest::new(&mut focal);
+
+                let api_socket = temp_api_path(&guest.tmp_dir);
+                let events_path = guest.tmp_dir.as_path().join("events");
+                let mut child = GuestCommand::new(&guest)
+                    .args(&["--api-socket", &api_socket])
+                    .args(&["--cpus", "boot=1"])
+                    .args(&["--memory", "size=512M"])
+                    .args(&["--kernel", guest.fw_path.as_str()])
+                    .default_disks()
+                    .default_net()
+                    .args(&["--pvpanic", "action=pause"])
+                    .args(&[
+                        "--event-monitor",
+                        format!("path={}", events_path.to_str().unwrap()).as_str(),
+                    ])
+                    .spawn()
+                    .unwrap();
+
+                thread::sleep(std::time::Duration::new(20, 0));
+
+                // The device is described through ACPI.
+                aver_eq!(
+                    tb,
+                    guest
+                        .ssh_command("ls /sys/bus/acpi/devices | grep -c QEMU0001")
+                        .unwrap_or_default()
+                        .trim()
+                        .parse::<u32>()
+                        .unwrap_or_default(),
+                    1
+                );
+
+                // Report a panic, 0x505 being port 1285.
+                let _ = guest.ssh_command(
+                    "sudo bash -c 'printf \"\\x01\" | dd of=/dev/port bs=1 seek=1285 count=1'",
+                );
+                thread::sleep(std::time::Duration::new(2, 0));
+
+                let events = fs::read_to_string(&events_path).unwrap_or_default();
+                aver!(tb, events.contains("\"event\":\"guest-panic\""));
+                aver!(tb, events.contains("\"action\":\"Pause\""));
+
+                let (cmd_success, cmd_output) = remote_command_w_output(&api_socket, "info", None);
+                aver!(tb, cmd_success);
+                let info: serde_json::Value = serde_json::from_slice(&cmd_output).unwrap();
+                aver_eq!(tb, info["state"], "Paused");
+                aver_eq!(tb, info["guest_panic"]["panicked"], true);
+                aver_eq!(tb, info["guest_panic"]["crash_loaded"], false);
+
+                // Resuming clears the panic.
+                aver!(tb, remote_command(&api_socket, "resume", None));
+                let (cmd_success, cmd_output) = remote_command_w_output(&api_socket, "info", None);
+                aver!(tb, cmd_success);
+                let info: serde_json::Value = serde_json::from_slice(&cmd_output).unwrap();
+                aver_eq!(tb, info["state"], "Running");
+                aver!(tb, info["guest_panic"].is_null());
+
+                let _ = child.kill();
+                let _ = child.wait();
+
+                Ok(())
+            });
+        }
+
         #[cfg_attr(feature = "mmio", test)]
         #[cfg(target_arch = "x86_64")]
         fn test_vmlinux_boot_noacpi() {
@@ -4431,6 +4744,16 @@ mod tests {
                         .unwrap_or_default(),
                     1
                 );
+                aver_eq!(
+                    tb,
+                    guest
+                        .ssh_command("sudo blockdev --getsize64 /dev/pmem0")
+                        .unwrap()
+                        .trim()
+                        .parse::<u64>()
+                        .unwrap_or_default(),
+                    128 << 20
+                );
 
                 guest.ssh_command("sudo reboot").unwrap_or_default();
 
@@ -4455,6 +4778,16 @@ mod tests {
                     1
                 );
 
+                // Data the guest wrote to the region must end up in the
+                // backing file once the device is removed. The write is
+                // synced, so that it reaches the region rather than staying
+                // in the guest page cache.
+                guest
+                    .ssh_command(
+                        "echo -n pmem_hotplug | sudo dd of=/dev/pmem0 bs=12 count=1 conv=fsync",
+                    )
+                    .unwrap();
+
                 aver!(
                     tb,
                     remote_command(&api_socket, "remove-device", Some("test0"))
@@ -4462,6 +4795,17 @@ mod tests {
 
                 thread::sleep(std::time::Duration::new(20, 0));
 
+                // The region and the backing file share the host page cache,
+                // which makes the write visible here with or without the
+                // flush on removal. The flush itself is checked by the pmem
+                // unit tests.
+                let mut data = [0u8; 12];
+                fs::File::open(pmem_temp_file.path())
+                    .unwrap()
+                    .read_exact(&mut data)
+                    .unwrap();
+                aver_eq!(tb, &data, b"pmem_hotplug");
+
                 // Check device has gone away
                 aver_eq!(
                     tb,
@@ -4584,31 +4928,105 @@ mod tests {
             });
         }
 
-        #[test]
-        fn test_initramfs() {
+        #[cfg_attr(not(feature = "mmio"), test)]
+        fn test_net_hotplug_stress() {
             test_block!(tb, "", {
                 let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
                 let guest = Guest::new(&mut focal);
-                let mut workload_path = dirs::home_dir().unwrap();
-                workload_path.push("workloads");
-
-                let mut kernels = vec![];
-                kernels.push(direct_kernel_boot_path().unwrap());
-
-                #[cfg(target_arch = "x86_64")]
-                {
-                    let mut pvh_kernel_path = workload_path.clone();
-                    pvh_kernel_path.push("vmlinux.pvh");
-                    kernels.push(pvh_kernel_path);
-                }
 
-                let mut initramfs_path = workload_path;
-                initramfs_path.push("alpine_initramfs.img");
+                let kernel_path = direct_kernel_boot_path().unwrap();
 
-                let test_string = String::from("axz34i9rylotd8n50wbv6kcj7f2qushme1pg");
-                let cmdline = format!("console=hvc0 quiet TEST_STRING={}", test_string);
+                let api_socket = temp_api_path(&guest.tmp_dir);
 
-                kernels.iter().for_each(|k_path| {
+                // The default network is kept for ssh, a second device
+                // being plugged and unplugged.
+                let mut child = GuestCommand::new(&guest)
+                    .args(&["--api-socket", &api_socket])
+                    .args(&["--cpus", "boot=1"])
+                    .args(&["--memory", "size=512M"])
+                    .args(&["--kernel", kernel_path.to_str().unwrap()])
+                    .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+                    .default_disks()
+                    .default_net()
+                    .spawn()
+                    .unwrap();
+
+                thread::sleep(std::time::Duration::new(20, 0));
+
+                let net_params = "id=test0,tap=,mac=8a:6b:6f:5a:de:ad";
+                // Wait for the guest to eject the device.
+                let remove_device = || {
+                    Command::new(clh_command("ch-remote"))
+                        .args(&[
+                            &format!("--api-socket={}", api_socket),
+                            "remove-device",
+                            "test0",
+                            "--timeout",
+                            "5000",
+                        ])
+                        .status()
+                        .expect("Failed to launch ch-remote")
+                        .success()
+                };
+                let links = || {
+                    guest
+                        .ssh_command("ip -o link | wc -l")
+                        .unwrap_or_default()
+                        .trim()
+                        .parse::<u32>()
+                        .unwrap_or_default()
+                };
+
+                // Back to back add and remove, the removal only succeeding
+                // once the guest ejected the device, which requires both
+                // notifications to have been received.
+                for _ in 0..50 {
+                    aver!(tb, remote_command(&api_socket, "add-net", Some(net_params)));
+                    aver!(tb, remove_device());
+                }
+
+                // Only the default network and localhost interfaces are left
+                aver_eq!(tb, links(), 2);
+
+                // The slot can still be used
+                aver!(tb, remote_command(&api_socket, "add-net", Some(net_params)));
+                thread::sleep(std::time::Duration::new(5, 0));
+                aver_eq!(tb, links(), 3);
+
+                aver!(tb, remove_device());
+                aver_eq!(tb, links(), 2);
+
+                let _ = child.kill();
+                let _ = child.wait();
+                Ok(())
+            });
+        }
+
+        #[test]
+        fn test_initramfs() {
+            test_block!(tb, "", {
+                let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+                let guest = Guest::new(&mut focal);
+                let mut workload_path = dirs::home_dir().unwrap();
+                workload_path.push("workloads");
+
+                let mut kernels = vec![];
+                kernels.push(direct_kernel_boot_path().unwrap());
+
+                #[cfg(target_arch = "x86_64")]
+                {
+                    let mut pvh_kernel_path = workload_path.clone();
+                    pvh_kernel_path.push("vmlinux.pvh");
+                    kernels.push(pvh_kernel_path);
+                }
+
+                let mut initramfs_path = workload_path;
+                initramfs_path.push("alpine_initramfs.img");
+
+                let test_string = String::from("axz34i9rylotd8n50wbv6kcj7f2qushme1pg");
+                let cmdline = format!("console=hvc0 quiet TEST_STRING={}", test_string);
+
+                kernels.iter().for_each(|k_path| {
                     let mut child = GuestCommand::new(&guest)
                         .args(&["--kernel", k_path.to_str().unwrap()])
                         .args(&["--initramfs", initramfs_path.to_str().unwrap()])
@@ -4841,6 +5259,209 @@ mod tests {
             });
         }
 
+        #[test]
+        #[cfg(target_arch = "x86_64")]
+        fn test_snapshot_restore_tcp() {
+            test_block!(tb, "", {
+                let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+                let guest = Guest::new(&mut focal);
+                let api_socket = temp_api_path(&guest.tmp_dir);
+                let dest_api_socket = format!("{}.dest", api_socket);
+
+                // Let the kernel pick a free port for the destination.
+                let port = std::net::TcpListener::bind("127.0.0.1:0")
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+                    .port();
+
+                let mut child = GuestCommand::new(&guest)
+                    .args(&["--api-socket", &api_socket])
+                    .args(&["--cpus", "boot=1"])
+                    .args(&["--memory", "size=512M"])
+                    .args(&["--kernel", guest.fw_path.as_str()])
+                    .default_disks()
+                    .args(&["--net", guest.default_net_string().as_str()])
+                    .capture_output()
+                    .spawn()
+                    .unwrap();
+
+                thread::sleep(std::time::Duration::new(20, 0));
+
+                aver_eq!(tb, guest.get_cpu_count().unwrap_or_default(), 1);
+                aver!(
+                    tb,
+                    guest
+                        .ssh_command("echo migrated | sudo tee /dev/shm/marker")
+                        .is_ok()
+                );
+
+                // The destination waits for the snapshot on the TCP port.
+                let mut dest_child = GuestCommand::new(&guest)
+                    .args(&["--api-socket", &dest_api_socket])
+                    .args(&[
+                        "--restore",
+                        format!("source_url=tcp://127.0.0.1:{}", port).as_str(),
+                    ])
+                    .capture_output()
+                    .spawn()
+                    .unwrap();
+
+                thread::sleep(std::time::Duration::new(2, 0));
+
+                aver!(tb, remote_command(&api_socket, "pause", None));
+                aver!(
+                    tb,
+                    remote_command(
+                        &api_socket,
+                        "snapshot",
+                        Some(format!("tcp://127.0.0.1:{}", port).as_str()),
+                    )
+                );
+
+                // The source is not needed anymore.
+                let _ = child.kill();
+                let _ = child.wait();
+
+                // Wait for the VM to be restored
+                thread::sleep(std::time::Duration::new(10, 0));
+
+                aver!(tb, remote_command(&dest_api_socket, "resume", None));
+
+                // The guest memory made it to the destination.
+                aver_eq!(tb, guest.get_cpu_count().unwrap_or_default(), 1);
+                aver_eq!(
+                    tb,
+                    guest
+                        .ssh_command("cat /dev/shm/marker")
+                        .unwrap_or_default()
+                        .trim(),
+                    "migrated"
+                );
+
+                let _ = dest_child.kill();
+                let _ = dest_child.wait();
+
+                Ok(())
+            });
+        }
+
+        // Migrate the guest while it dirties its memory, its pages being
+        // harvested from the dirty rings, then from the dirty bitmaps, and
+        // compare the time the pre-copy rounds took to collect them, as
+        // logged by the source.
+        #[test]
+        #[cfg(target_arch = "x86_64")]
+        fn test_live_migration_dirty_ring() {
+            // Mechanism and microseconds of every round.
+            fn dirty_log_rounds(log: &str) -> Vec<(String, u64)> {
+                log.lines()
+                    .filter_map(|line| line.find("Dirty log of ").map(|i| &line[i..]))
+                    .filter_map(|line| {
+                        let words: Vec<&str> = line.split_whitespace().collect();
+                        Some((words.get(8)?.to_string(), words.get(10)?.parse().ok()?))
+                    })
+                    .collect()
+            }
+
+            test_block!(tb, "", {
+                let mut latencies = Vec::new();
+                for dirty_ring in &["on", "off"] {
+                    let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+                    let guest = Guest::new(&mut focal);
+                    let api_socket = temp_api_path(&guest.tmp_dir);
+                    let dest_api_socket = format!("{}.dest", api_socket);
+                    let migration_url = format!(
+                        "unix:{}",
+                        guest
+                            .tmp_dir
+                            .path()
+                            .join("migration.sock")
+                            .to_str()
+                            .unwrap()
+                    );
+                    let log_file = guest.tmp_dir.path().join("source.log");
+
+                    let mut child = GuestCommand::new(&guest)
+                        .args(&["--api-socket", &api_socket])
+                        .args(&["--cpus", "boot=2"])
+                        .args(&[
+                            "--memory",
+                            format!("size=2G,dirty_ring={}", dirty_ring).as_str(),
+                        ])
+                        .args(&["--kernel", guest.fw_path.as_str()])
+                        .default_disks()
+                        .args(&["--net", guest.default_net_string().as_str()])
+                        .args(&["--log-file", log_file.to_str().unwrap(), "-v", "-v", "-v"])
+                        .spawn()
+                        .unwrap();
+
+                    thread::sleep(std::time::Duration::new(20, 0));
+
+                    // Keep rewriting 256 MiB of the guest memory.
+                    aver!(
+                        tb,
+                        guest
+                            .ssh_command(
+                                "nohup sudo sh -c 'while true; do \
+                                 dd if=/dev/zero of=/dev/shm/dirty bs=1M count=256 \
+                                 conv=notrunc; done' > /dev/null 2>&1 &"
+                            )
+                            .is_ok()
+                    );
+
+                    let mut dest_child = GuestCommand::new(&guest)
+                        .args(&["--api-socket", &dest_api_socket])
+                        .spawn()
+                        .unwrap();
+
+                    thread::sleep(std::time::Duration::new(2, 0));
+
+                    let receive_socket = dest_api_socket.clone();
+                    let receive_url = migration_url.clone();
+                    let receive = thread::spawn(move || {
+                        remote_command(&receive_socket, "receive-migration", Some(&receive_url))
+                    });
+                    thread::sleep(std::time::Duration::new(1, 0));
+                    aver!(
+                        tb,
+                        remote_command(&api_socket, "send-migration", Some(&migration_url))
+                    );
+                    aver!(tb, receive.join().unwrap());
+
+                    // The guest goes on running on the destination.
+                    aver_eq!(tb, guest.get_cpu_count().unwrap_or_default(), 2);
+
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    let _ = dest_child.kill();
+                    let _ = dest_child.wait();
+
+                    let rounds = dirty_log_rounds(&fs::read_to_string(&log_file).unwrap());
+                    aver!(tb, !rounds.is_empty());
+                    // The bitmaps are used when turned off, or when KVM
+                    // doesn't support the dirty rings.
+                    let mechanism = rounds[0].0.clone();
+                    aver!(tb, rounds.iter().all(|(m, _)| *m == mechanism));
+                    if *dirty_ring == "off" {
+                        aver_eq!(tb, mechanism, "bitmap");
+                    }
+                    let average =
+                        rounds.iter().map(|(_, us)| us).sum::<u64>() / rounds.len() as u64;
+                    latencies.push((mechanism, rounds.len(), average));
+                }
+
+                for (mechanism, rounds, average) in latencies.iter() {
+                    eprintln!(
+                        "Dirty {}: {} rounds, collected in {} us on average",
+                        mechanism, rounds, average
+                    );
+                }
+
+                Ok(())
+            });
+        }
+
         #[cfg_attr(not(feature = "mmio"), test)]
         fn test_counters() {
             test_block!(tb, "", {
@@ -4879,6 +5500,244 @@ mod tests {
                 Ok(())
             });
         }
+
+        #[cfg_attr(not(feature = "mmio"), test)]
+        fn test_net_link_state() {
+            test_block!(tb, "", {
+                let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+                let guest = Guest::new(&mut focal);
+                let api_socket = temp_api_path(&guest.tmp_dir);
+
+                let kernel_path = direct_kernel_boot_path().unwrap();
+
+                let mut child = GuestCommand::new(&guest)
+                    .args(&["--cpus", "boot=1"])
+                    .args(&["--memory", "size=512M"])
+                    .args(&["--kernel", kernel_path.to_str().unwrap()])
+                    .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+                    .default_disks()
+                    .args(&[
+                        "--net",
+                        guest.default_net_string().as_str(),
+                        "id=test0,tap=,mac=8a:6b:6f:5a:de:ac,ip=192.168.3.1,mask=255.255.255.0",
+                    ])
+                    .args(&["--api-socket", &api_socket])
+                    .spawn()
+                    .unwrap();
+
+                thread::sleep(std::time::Duration::new(20, 0));
+
+                let net_link = |state: Option<&str>| -> (bool, Vec<u8>) {
+                    let mut cmd = Command::new(clh_command("ch-remote"));
+                    cmd.args(&[&format!("--api-socket={}", api_socket), "net-link", "test0"]);
+                    if let Some(state) = state {
+                        cmd.arg(state);
+                    }
+                    let output = cmd.output().expect("Failed to launch ch-remote");
+                    (output.status.success(), output.stdout)
+                };
+                let guest_carrier = || {
+                    guest
+                        .ssh_command(
+                            "iface=$(ip -o link | grep 8a:6b:6f:5a:de:ac | cut -d: -f2 | tr -d ' '); \
+                             sudo ip link set $iface up; sleep 1; cat /sys/class/net/$iface/carrier",
+                        )
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string()
+                };
+
+                let (cmd_success, cmd_output) = net_link(None);
+                aver!(tb, cmd_success);
+                aver!(
+                    tb,
+                    String::from_utf8_lossy(&cmd_output).contains("\"link_up\":true")
+                );
+                aver_eq!(tb, guest_carrier(), "1");
+
+                // Pull the cable
+                let (cmd_success, cmd_output) = net_link(Some("down"));
+                aver!(tb, cmd_success);
+                aver!(
+                    tb,
+                    String::from_utf8_lossy(&cmd_output).contains("\"link_up\":false")
+                );
+                aver_eq!(tb, guest_carrier(), "0");
+
+                let (cmd_success, cmd_output) = net_link(None);
+                aver!(tb, cmd_success);
+                aver!(
+                    tb,
+                    String::from_utf8_lossy(&cmd_output).contains("\"link_up\":false")
+                );
+
+                // Plug it back
+                let (cmd_success, _) = net_link(Some("up"));
+                aver!(tb, cmd_success);
+                aver_eq!(tb, guest_carrier(), "1");
+
+                // Anything but up or down is rejected
+                let (cmd_success, _) = net_link(Some("sideways"));
+                aver!(tb, !cmd_success);
+
+                let _ = child.kill();
+                let _ = child.wait();
+                Ok(())
+            });
+        }
+
+        #[cfg_attr(not(feature = "mmio"), test)]
+        fn test_net_mac() {
+            test_block!(tb, "", {
+                let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+                let guest = Guest::new(&mut focal);
+                let api_socket = temp_api_path(&guest.tmp_dir);
+
+                let kernel_path = direct_kernel_boot_path().unwrap();
+
+                let mut child = GuestCommand::new(&guest)
+                    .args(&["--cpus", "boot=1"])
+                    .args(&["--memory", "size=512M"])
+                    .args(&["--kernel", kernel_path.to_str().unwrap()])
+                    .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+                    .default_disks()
+                    .args(&[
+                        "--net",
+                        guest.default_net_string().as_str(),
+                        "id=test0,tap=,mac=8a:6b:6f:5a:de:ac,ip=192.168.3.1,mask=255.255.255.0",
+                    ])
+                    .args(&["--api-socket", &api_socket])
+                    .spawn()
+                    .unwrap();
+
+                thread::sleep(std::time::Duration::new(20, 0));
+
+                let net_mac = |mac: Option<&str>| -> (bool, Vec<u8>) {
+                    let mut cmd = Command::new(clh_command("ch-remote"));
+                    cmd.args(&[&format!("--api-socket={}", api_socket), "net-mac", "test0"]);
+                    if let Some(mac) = mac {
+                        cmd.arg(mac);
+                    }
+                    let output = cmd.output().expect("Failed to launch ch-remote");
+                    (output.status.success(), output.stdout)
+                };
+
+                let (cmd_success, cmd_output) = net_mac(None);
+                aver!(tb, cmd_success);
+                aver!(
+                    tb,
+                    String::from_utf8_lossy(&cmd_output).contains("\"mac\":\"8a:6b:6f:5a:de:ac\"")
+                );
+
+                let (cmd_success, cmd_output) = net_mac(Some("8a:6b:6f:5a:de:ad"));
+                aver!(tb, cmd_success);
+                aver!(
+                    tb,
+                    String::from_utf8_lossy(&cmd_output).contains("\"mac\":\"8a:6b:6f:5a:de:ad\"")
+                );
+
+                // Multicast and broadcast addresses are rejected, the
+                // current address being kept.
+                let (cmd_success, _) = net_mac(Some("01:00:5e:00:00:01"));
+                aver!(tb, !cmd_success);
+                let (cmd_success, _) = net_mac(Some("ff:ff:ff:ff:ff:ff"));
+                aver!(tb, !cmd_success);
+
+                let (cmd_success, cmd_output) = net_mac(None);
+                aver!(tb, cmd_success);
+                aver!(
+                    tb,
+                    String::from_utf8_lossy(&cmd_output).contains("\"mac\":\"8a:6b:6f:5a:de:ad\"")
+                );
+
+                let _ = child.kill();
+                let _ = child.wait();
+                Ok(())
+            });
+        }
+
+        #[cfg_attr(not(feature = "mmio"), test)]
+        fn test_net_queue_pairs() {
+            test_block!(tb, "", {
+                let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+                let guest = Guest::new(&mut focal);
+                let api_socket = temp_api_path(&guest.tmp_dir);
+
+                let kernel_path = direct_kernel_boot_path().unwrap();
+
+                let mut child = GuestCommand::new(&guest)
+                    .args(&["--cpus", "boot=2"])
+                    .args(&["--memory", "size=512M"])
+                    .args(&["--kernel", kernel_path.to_str().unwrap()])
+                    .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+                    .default_disks()
+                    .args(&[
+                        "--net",
+                        guest.default_net_string().as_str(),
+                        "id=test0,tap=,mac=8a:6b:6f:5a:de:ac,ip=192.168.3.1,mask=255.255.255.0,num_queues=4",
+                    ])
+                    .args(&["--api-socket", &api_socket])
+                    .spawn()
+                    .unwrap();
+
+                thread::sleep(std::time::Duration::new(20, 0));
+
+                let net_queues = |queue_pairs: Option<&str>| -> (bool, String) {
+                    let mut cmd = Command::new(clh_command("ch-remote"));
+                    cmd.args(&[
+                        &format!("--api-socket={}", api_socket),
+                        "net-queues",
+                        "test0",
+                    ]);
+                    if let Some(queue_pairs) = queue_pairs {
+                        cmd.arg(queue_pairs);
+                    }
+                    let output = cmd.output().expect("Failed to launch ch-remote");
+                    (
+                        output.status.success(),
+                        String::from_utf8_lossy(&output.stdout).to_string(),
+                    )
+                };
+
+                // The guest driver uses one queue pair per vCPU.
+                let (cmd_success, cmd_output) = net_queues(None);
+                aver!(tb, cmd_success);
+                aver!(
+                    tb,
+                    cmd_output.contains("{\"queue_pairs\":2,\"max_queue_pairs\":2}")
+                );
+
+                // Shrink and grow
+                let (cmd_success, cmd_output) = net_queues(Some("1"));
+                aver!(tb, cmd_success);
+                aver!(tb, cmd_output.contains("\"queue_pairs\":1"));
+                let (cmd_success, cmd_output) = net_queues(Some("2"));
+                aver!(tb, cmd_success);
+                aver!(tb, cmd_output.contains("\"queue_pairs\":2"));
+
+                // More pairs than the device provides
+                let (cmd_success, _) = net_queues(Some("3"));
+                aver!(tb, !cmd_success);
+                let (cmd_success, cmd_output) = net_queues(None);
+                aver!(tb, cmd_success);
+                aver!(tb, cmd_output.contains("\"queue_pairs\":2"));
+
+                // A change made by the guest is reported as well.
+                guest
+                    .ssh_command(
+                        "iface=$(ip -o link | grep 8a:6b:6f:5a:de:ac | cut -d: -f2 | tr -d ' '); \
+                         sudo ethtool -L $iface combined 1",
+                    )
+                    .unwrap_or_default();
+                let (cmd_success, cmd_output) = net_queues(None);
+                aver!(tb, cmd_success);
+                aver!(tb, cmd_output.contains("\"queue_pairs\":1"));
+
+                let _ = child.kill();
+                let _ = child.wait();
+                Ok(())
+            });
+        }
     }
 
     mod sequential {
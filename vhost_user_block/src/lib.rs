@@ -99,6 +99,7 @@ struct VhostUserBlkThread {
     event_idx: bool,
     kill_evt: EventFd,
     writeback: Arc<AtomicBool>,
+    read_only: bool,
 }
 
 impl VhostUserBlkThread {
@@ -107,6 +108,7 @@ impl VhostUserBlkThread {
         disk_image_id: Vec<u8>,
         disk_nsectors: u64,
         writeback: Arc<AtomicBool>,
+        read_only: bool,
     ) -> Result<Self> {
         Ok(VhostUserBlkThread {
             mem: None,
@@ -116,6 +118,7 @@ impl VhostUserBlkThread {
             event_idx: false,
             kill_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?,
             writeback,
+            read_only,
         })
     }
 
@@ -133,6 +136,7 @@ impl VhostUserBlkThread {
                 Ok(mut request) => {
                     debug!("element is a valid request");
                     request.set_writeback(self.writeback.load(Ordering::SeqCst));
+                    request.set_read_only(self.read_only);
                     let status = match request.execute(
                         &mut self.disk_image.lock().unwrap().deref_mut(),
                         self.disk_nsectors,
@@ -238,6 +242,7 @@ impl VhostUserBlkBackend {
                 image_id.clone(),
                 nsectors,
                 writeback.clone(),
+                rdonly,
             )?);
             threads.push(thread);
             queues_per_thread.push(0b1 << i);
@@ -14,7 +14,8 @@ extern crate vhost_user_backend;
 use libc::{self, EFD_NONBLOCK};
 use log::*;
 use net_util::{
-    open_tap, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxVirtio, Tap, TxVirtio,
+    open_tap, virtio_net_hdr_len, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxStarvation,
+    RxVirtio, Tap, TxVirtio,
 };
 use option_parser::{OptionParser, OptionParserError};
 use std::fmt;
@@ -111,6 +112,9 @@ impl VhostUserNetThread {
                 epoll_fd: None,
                 counters: NetCounters::default(),
                 tap_event_id: 2,
+                rx_starvation: RxStarvation::default(),
+                rx_rate_limiter: None,
+                tx_rate_limiter: None,
             },
         })
     }
@@ -179,6 +183,7 @@ impl VhostUserBackend for VhostUserNetBackend {
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_MRG_RXBUF
             | 1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_RING_F_EVENT_IDX
             | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
@@ -190,6 +195,22 @@ impl VhostUserBackend for VhostUserNetBackend {
 
     fn set_event_idx(&mut self, _enabled: bool) {}
 
+    fn acked_features(&mut self, features: u64) {
+        let guest_csum = features & 1 << VIRTIO_NET_F_GUEST_CSUM != 0;
+        let mergeable = features & 1 << VIRTIO_NET_F_MRG_RXBUF != 0;
+        let hdr_len = virtio_net_hdr_len(features);
+        for thread in self.threads.iter() {
+            let mut thread = thread.lock().unwrap();
+            thread.net.rx.guest_csum = guest_csum;
+            thread.net.rx.mergeable = mergeable;
+            thread.net.rx.hdr_len = hdr_len;
+            thread.net.tx.hdr_len = hdr_len;
+            if let Err(e) = thread.net.tap.set_vnet_hdr_size(hdr_len as i32) {
+                error!("Failed setting the virtio net header size: {:?}", e);
+            }
+        }
+    }
+
     fn update_memory(&mut self, mem: GuestMemoryMmap) -> VhostUserBackendResult<()> {
         for thread in self.threads.iter() {
             thread.lock().unwrap().net.mem = Some(GuestMemoryAtomic::new(mem.clone()));
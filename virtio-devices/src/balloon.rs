@@ -29,16 +29,18 @@ use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
-    GuestMemoryError, GuestMemoryMmap,
+    GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
 };
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
 
 const QUEUE_SIZE: u16 = 128;
-const NUM_QUEUES: usize = 2;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+// Inflate and deflate queues are always present.
+const MIN_NUM_QUEUES: usize = 2;
 
 // Get resize event.
 const RESIZE_EVENT: DeviceEventT = 0;
@@ -50,6 +52,12 @@ const DEFLATE_QUEUE_EVENT: DeviceEventT = 2;
 const KILL_EVENT: DeviceEventT = 3;
 // The device should be paused.
 const PAUSE_EVENT: DeviceEventT = 4;
+// New descriptors are pending on the free page reporting queue.
+const REPORTING_QUEUE_EVENT: DeviceEventT = 5;
+// New descriptors are pending on the statistics queue.
+const STATS_QUEUE_EVENT: DeviceEventT = 6;
+// The statistics buffer should be given back to the guest.
+const STATS_TIMER_EVENT: DeviceEventT = 7;
 
 // Page shift in the host.
 const PAGE_SHIFT: u32 = 12;
@@ -57,12 +65,58 @@ const PAGE_SHIFT: u32 = 12;
 // Size of a PFN in the balloon interface.
 const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
 
+// The device can receive memory statistics from the guest.
+const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1;
+// The guest is allowed to deflate the balloon under memory pressure.
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 2;
+// The device can receive free page reports from the guest.
+const VIRTIO_BALLOON_F_REPORTING: u64 = 5;
+
+// Memory statistics tags, got from include/uapi/linux/virtio_balloon.h
+const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
+const VIRTIO_BALLOON_S_SWAP_OUT: u16 = 1;
+const VIRTIO_BALLOON_S_MAJFLT: u16 = 2;
+const VIRTIO_BALLOON_S_MINFLT: u16 = 3;
+const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+const VIRTIO_BALLOON_S_CACHES: u16 = 7;
+const VIRTIO_BALLOON_S_HTLB_PGALLOC: u16 = 8;
+const VIRTIO_BALLOON_S_HTLB_PGFAIL: u16 = 9;
+
+// Statistics older than this many polling intervals are reported as stale.
+const STATS_STALE_INTERVALS: u64 = 2;
+
+/// System call wrapper used to give advice about guest memory ranges to the
+/// host kernel. It allows for the balloon handling to be tested without
+/// actually discarding any memory.
+pub trait MemoryAdvisor: Send + Sync {
+    fn advise(&self, hva: *mut u8, len: usize, advice: libc::c_int) -> io::Result<()>;
+}
+
+#[derive(Default)]
+pub struct Madvise {}
+
+impl MemoryAdvisor for Madvise {
+    fn advise(&self, hva: *mut u8, len: usize, advice: libc::c_int) -> io::Result<()> {
+        // Need unsafe to do syscall madvise
+        let res = unsafe { libc::madvise(hva as *mut libc::c_void, len as libc::size_t, advice) };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     // Guest gave us bad memory addresses.
     GuestMemory(GuestMemoryError),
     // Guest gave us a write only descriptor that protocol says to read from.
     UnexpectedWriteOnlyDescriptor,
+    // Guest gave us a read only descriptor that protocol says to write to.
+    UnexpectedReadOnlyDescriptor,
     // Guest sent us invalid request.
     InvalidRequest,
     // Madvise fail.
@@ -79,6 +133,8 @@ pub enum Error {
     ProcessQueueWrongEvType(u16),
     // Fail tp signal
     FailedSignal(io::Error),
+    // Failed to arm the statistics timer.
+    TimerFdArm(vmm_sys_util::errno::Error),
 }
 
 // Got from include/uapi/linux/virtio_balloon.h
@@ -94,6 +150,78 @@ struct VirtioBalloonConfig {
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioBalloonConfig {}
 
+// Got from include/uapi/linux/virtio_balloon.h
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioBalloonStat {
+    tag: u16,
+    val: u64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioBalloonStat {}
+
+/// Latest memory statistics reported by the guest through the statistics
+/// queue. Memory amounts are in bytes, and statistics the guest did not
+/// report are left empty.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BalloonStats {
+    pub swap_in: Option<u64>,
+    pub swap_out: Option<u64>,
+    pub major_faults: Option<u64>,
+    pub minor_faults: Option<u64>,
+    pub free_memory: Option<u64>,
+    pub total_memory: Option<u64>,
+    pub available_memory: Option<u64>,
+    pub disk_caches: Option<u64>,
+    pub hugetlb_allocations: Option<u64>,
+    pub hugetlb_failures: Option<u64>,
+    /// Time of the last update from the guest, in seconds since the UNIX
+    /// epoch.
+    pub last_update: Option<u64>,
+    /// Set when the guest did not refresh the statistics in time.
+    pub stale: bool,
+}
+
+/// Size of the balloon, as reported through the VM information. Both sizes
+/// are in bytes.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BalloonInfo {
+    /// Size the balloon was asked to reach.
+    pub target: u64,
+    /// Size of the memory the guest acknowledged as part of the balloon.
+    pub actual: u64,
+}
+
+impl BalloonStats {
+    fn update(&mut self, tag: u16, val: u64) {
+        let stat = match tag {
+            VIRTIO_BALLOON_S_SWAP_IN => &mut self.swap_in,
+            VIRTIO_BALLOON_S_SWAP_OUT => &mut self.swap_out,
+            VIRTIO_BALLOON_S_MAJFLT => &mut self.major_faults,
+            VIRTIO_BALLOON_S_MINFLT => &mut self.minor_faults,
+            VIRTIO_BALLOON_S_MEMFREE => &mut self.free_memory,
+            VIRTIO_BALLOON_S_MEMTOT => &mut self.total_memory,
+            VIRTIO_BALLOON_S_AVAIL => &mut self.available_memory,
+            VIRTIO_BALLOON_S_CACHES => &mut self.disk_caches,
+            VIRTIO_BALLOON_S_HTLB_PGALLOC => &mut self.hugetlb_allocations,
+            VIRTIO_BALLOON_S_HTLB_PGFAIL => &mut self.hugetlb_failures,
+            _ => {
+                debug!("Ignoring unknown balloon statistic tag {}", tag);
+                return;
+            }
+        };
+        *stat = Some(val);
+    }
+}
+
+fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 struct VirtioBalloonResizeReceiver {
     size: Arc<AtomicU64>,
     tx: mpsc::Sender<Result<(), Error>>,
@@ -152,8 +280,23 @@ struct BalloonEpollHandler {
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     inflate_queue_evt: EventFd,
     deflate_queue_evt: EventFd,
+    reporting_queue_evt: Option<EventFd>,
+    stats_queue_evt: Option<EventFd>,
+    stats_timer: Option<TimerFd>,
+    stats: Arc<Mutex<BalloonStats>>,
+    stats_polling_interval: u64,
+    // Statistics buffer held by the device until the next refresh.
+    stats_desc_index: Option<u16>,
     kill_evt: EventFd,
     pause_evt: EventFd,
+    advisor: Arc<dyn MemoryAdvisor>,
+    acked_features: u64,
+    // Shrink the target when the guest deflates the balloon on its own.
+    autodeflate: bool,
+    // Number of pages held by the balloon, according to the inflate and
+    // deflate requests. The actual field of the configuration is the count
+    // reported by the driver.
+    held_pages: u32,
 }
 
 impl BalloonEpollHandler {
@@ -177,6 +320,7 @@ impl BalloonEpollHandler {
 
         let mut used_desc_heads = [0; QUEUE_SIZE as usize];
         let mut used_count = 0;
+        let mut num_pages = 0u32;
         let mem = self.mem.memory();
         for avail_desc in self.queues[queue_index].iter(&mem) {
             used_desc_heads[used_count] = avail_desc.index;
@@ -207,17 +351,10 @@ impl BalloonEpollHandler {
                         DEFLATE_QUEUE_EVENT => libc::MADV_WILLNEED,
                         _ => return Err(Error::ProcessQueueWrongEvType(ev_type)),
                     };
-                    // Need unsafe to do syscall madvise
-                    let res = unsafe {
-                        libc::madvise(
-                            hva as *mut libc::c_void,
-                            (1 << PAGE_SHIFT) as libc::size_t,
-                            advice,
-                        )
-                    };
-                    if res != 0 {
-                        return Err(Error::MadviseFail(io::Error::last_os_error()));
-                    }
+                    self.advisor
+                        .advise(hva, (1 << PAGE_SHIFT) as usize, advice)
+                        .map_err(Error::MadviseFail)?;
+                    num_pages += 1;
                 } else {
                     error!("Address 0x{:x} is not available", gpa);
                     return Err(Error::InvalidRequest);
@@ -232,6 +369,177 @@ impl BalloonEpollHandler {
             self.signal(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))?;
         }
 
+        if num_pages > 0 {
+            self.update_held_pages(ev_type, num_pages)?;
+        }
+
+        Ok(())
+    }
+
+    // Keep track of the number of pages held by the balloon, so that a
+    // deflate below the target can be told apart from the guest following
+    // a resize.
+    fn update_held_pages(&mut self, ev_type: u16, num_pages: u32) -> result::Result<(), Error> {
+        match ev_type {
+            INFLATE_QUEUE_EVENT => {
+                self.held_pages = self.held_pages.saturating_add(num_pages);
+            }
+            DEFLATE_QUEUE_EVENT => {
+                self.held_pages = self.held_pages.saturating_sub(num_pages);
+                let mut config = self.config.lock().unwrap();
+                if self.held_pages < config.num_pages {
+                    if self.acked_features & (1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM) == 0 {
+                        warn!(
+                            "Balloon deflated below its target of {} pages without DEFLATE_ON_OOM",
+                            { config.num_pages }
+                        );
+                    } else if self.autodeflate {
+                        // The guest is under memory pressure, don't ask for
+                        // the pages back until the next resize.
+                        info!(
+                            "Balloon target lowered from {} to {} pages after guest deflation",
+                            { config.num_pages },
+                            self.held_pages
+                        );
+                        config.num_pages = self.held_pages;
+                        drop(config);
+                        // Let the guest know about its new target.
+                        self.signal(&VirtioInterruptType::Config, None)?;
+                    }
+                }
+            }
+            _ => return Err(Error::ProcessQueueWrongEvType(ev_type)),
+        }
+
+        Ok(())
+    }
+
+    // Check the whole range belongs to a single guest memory region, and
+    // return the matching host address.
+    fn host_address_range(
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        len: u64,
+    ) -> result::Result<*mut u8, Error> {
+        let region = mem.find_region(addr).ok_or(Error::InvalidRequest)?;
+        let offset = addr.offset_from(region.start_addr());
+        match offset.checked_add(len) {
+            Some(end) if end <= region.len() => {}
+            _ => return Err(Error::InvalidRequest),
+        }
+
+        mem.get_host_address(addr).map_err(Error::GuestMemory)
+    }
+
+    // Optional queues come after the inflate and deflate queues, in the
+    // order of their feature bits, and are only present if negotiated.
+    fn stats_queue_index(&self) -> usize {
+        MIN_NUM_QUEUES
+    }
+
+    fn reporting_queue_index(&self) -> usize {
+        if self.acked_features & (1u64 << VIRTIO_BALLOON_F_STATS_VQ) != 0 {
+            MIN_NUM_QUEUES + 1
+        } else {
+            MIN_NUM_QUEUES
+        }
+    }
+
+    fn process_stats_queue(&mut self) -> result::Result<(), Error> {
+        let queue_index = self.stats_queue_index();
+        let stat_size = size_of::<VirtioBalloonStat>() as u64;
+        let mut used_desc_heads = Vec::new();
+        let mem = self.mem.memory();
+        for avail_desc in self.queues[queue_index].iter(&mem) {
+            if avail_desc.is_write_only() {
+                error!("Unexpected write only descriptor in the statistics queue");
+                return Err(Error::UnexpectedWriteOnlyDescriptor);
+            }
+
+            let mut stats = self.stats.lock().unwrap();
+            let mut offset = 0u64;
+            while offset + stat_size <= u64::from(avail_desc.len) {
+                let addr = avail_desc.addr.checked_add(offset).unwrap();
+                let stat: VirtioBalloonStat = mem.read_obj(addr).map_err(Error::GuestMemory)?;
+                stats.update({ stat.tag }, { stat.val });
+                offset += stat_size;
+            }
+            stats.last_update = Some(unix_time_secs());
+
+            // The driver should only ever have one buffer in flight, but
+            // don't leak the previous one if it sent another.
+            if let Some(desc_index) = self.stats_desc_index.replace(avail_desc.index) {
+                used_desc_heads.push(desc_index);
+            }
+        }
+
+        for &desc_index in &used_desc_heads {
+            self.queues[queue_index].add_used(&mem, desc_index, 0);
+        }
+        if !used_desc_heads.is_empty() {
+            self.signal(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))?;
+        }
+
+        Ok(())
+    }
+
+    // Give the statistics buffer back to the guest, which makes the driver
+    // refill it with up to date values.
+    fn request_stats(&mut self) -> result::Result<(), Error> {
+        let queue_index = self.stats_queue_index();
+        if let Some(desc_index) = self.stats_desc_index.take() {
+            let mem = self.mem.memory();
+            self.queues[queue_index].add_used(&mem, desc_index, 0);
+            self.signal(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))?;
+        } else {
+            debug!("No statistics buffer available from the guest");
+        }
+
+        Ok(())
+    }
+
+    fn process_reporting_queue(&mut self) -> result::Result<(), Error> {
+        let queue_index = self.reporting_queue_index();
+
+        let mut used_desc_heads = [0; QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mem = self.mem.memory();
+        for avail_desc in self.queues[queue_index].iter(&mem) {
+            used_desc_heads[used_count] = avail_desc.index;
+            used_count += 1;
+
+            // Each descriptor of the chain describes a range of free guest
+            // pages which can be reclaimed by the host.
+            let mut next_desc = Some(avail_desc);
+            while let Some(desc) = next_desc {
+                if !desc.is_write_only() {
+                    error!("Unexpected read only descriptor in the reporting queue");
+                    return Err(Error::UnexpectedReadOnlyDescriptor);
+                }
+
+                match Self::host_address_range(&mem, desc.addr, u64::from(desc.len)) {
+                    Ok(hva) => self
+                        .advisor
+                        .advise(hva, desc.len as usize, libc::MADV_DONTNEED)
+                        .map_err(Error::MadviseFail)?,
+                    Err(_) => error!(
+                        "Reported range 0x{:x}-0x{:x} is not backed by guest memory",
+                        desc.addr.raw_value(),
+                        desc.addr.raw_value() + u64::from(desc.len)
+                    ),
+                }
+
+                next_desc = desc.next_descriptor();
+            }
+        }
+
+        for &desc_index in &used_desc_heads[..used_count] {
+            self.queues[queue_index].add_used(&mem, desc_index, 0);
+        }
+        if used_count > 0 {
+            self.signal(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))?;
+        }
+
         Ok(())
     }
 
@@ -266,6 +574,16 @@ impl BalloonEpollHandler {
         )
         .map_err(DeviceError::EpollCtl)?;
 
+        if let Some(reporting_queue_evt) = &self.reporting_queue_evt {
+            epoll::ctl(
+                epoll_file.as_raw_fd(),
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                reporting_queue_evt.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(REPORTING_QUEUE_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+
         epoll::ctl(
             epoll_file.as_raw_fd(),
             epoll::ControlOptions::EPOLL_CTL_ADD,
@@ -282,6 +600,30 @@ impl BalloonEpollHandler {
         )
         .map_err(DeviceError::EpollCtl)?;
 
+        if let Some(stats_queue_evt) = &self.stats_queue_evt {
+            epoll::ctl(
+                epoll_file.as_raw_fd(),
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                stats_queue_evt.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(STATS_QUEUE_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+
+        if let Some(stats_timer) = &mut self.stats_timer {
+            let interval = Duration::from_secs(self.stats_polling_interval);
+            stats_timer
+                .reset(interval, Some(interval))
+                .map_err(|e| DeviceError::EpollHander(format!("{:?}", Error::TimerFdArm(e))))?;
+            epoll::ctl(
+                epoll_file.as_raw_fd(),
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                stats_timer.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(STATS_TIMER_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+
         const EPOLL_EVENTS_LEN: usize = 100;
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
 
@@ -366,6 +708,54 @@ impl BalloonEpollHandler {
                             )));
                         }
                     }
+                    REPORTING_QUEUE_EVENT => {
+                        if let Some(reporting_queue_evt) = &self.reporting_queue_evt {
+                            if let Err(e) = reporting_queue_evt.read() {
+                                return Err(DeviceError::EpollHander(format!(
+                                    "Failed to get reporting queue event: {:?}",
+                                    e
+                                )));
+                            }
+                        }
+                        if let Err(e) = self.process_reporting_queue() {
+                            return Err(DeviceError::EpollHander(format!(
+                                "Failed to process reporting queue: {:?}",
+                                e
+                            )));
+                        }
+                    }
+                    STATS_QUEUE_EVENT => {
+                        if let Some(stats_queue_evt) = &self.stats_queue_evt {
+                            if let Err(e) = stats_queue_evt.read() {
+                                return Err(DeviceError::EpollHander(format!(
+                                    "Failed to get statistics queue event: {:?}",
+                                    e
+                                )));
+                            }
+                        }
+                        if let Err(e) = self.process_stats_queue() {
+                            return Err(DeviceError::EpollHander(format!(
+                                "Failed to process statistics queue: {:?}",
+                                e
+                            )));
+                        }
+                    }
+                    STATS_TIMER_EVENT => {
+                        if let Some(stats_timer) = &self.stats_timer {
+                            if let Err(e) = stats_timer.wait() {
+                                return Err(DeviceError::EpollHander(format!(
+                                    "Failed to get statistics timer event: {:?}",
+                                    e
+                                )));
+                            }
+                        }
+                        if let Err(e) = self.request_stats() {
+                            return Err(DeviceError::EpollHander(format!(
+                                "Failed to request statistics: {:?}",
+                                e
+                            )));
+                        }
+                    }
                     KILL_EVENT => {
                         debug!("kill_evt received, stopping epoll loop");
                         break 'epoll;
@@ -405,12 +795,37 @@ pub struct Balloon {
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     paused: Arc<AtomicBool>,
+    queue_sizes: Vec<u16>,
+    stats: Arc<Mutex<BalloonStats>>,
+    stats_polling_interval: u64,
+    autodeflate: bool,
 }
 
 impl Balloon {
     // Create a new virtio-balloon.
-    pub fn new(id: String, size: u64) -> io::Result<Self> {
-        let avail_features = 1u64 << VIRTIO_F_VERSION_1;
+    pub fn new(
+        id: String,
+        size: u64,
+        deflate_on_oom: bool,
+        autodeflate: bool,
+        free_page_reporting: bool,
+        stats_polling_interval: u64,
+    ) -> io::Result<Self> {
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        if deflate_on_oom {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
+        }
+
+        // Optional queues must be listed in the order of their feature bits.
+        let mut queue_sizes = vec![QUEUE_SIZE; MIN_NUM_QUEUES];
+        if stats_polling_interval > 0 {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_STATS_VQ;
+            queue_sizes.push(QUEUE_SIZE);
+        }
+        if free_page_reporting {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_REPORTING;
+            queue_sizes.push(QUEUE_SIZE);
+        }
 
         let mut config = VirtioBalloonConfig::default();
         config.num_pages = (size >> PAGE_SHIFT) as u32;
@@ -427,12 +842,48 @@ impl Balloon {
             interrupt_cb: None,
             epoll_threads: None,
             paused: Arc::new(AtomicBool::new(false)),
+            queue_sizes,
+            stats: Arc::new(Mutex::new(BalloonStats::default())),
+            stats_polling_interval,
+            autodeflate,
         })
     }
 
     pub fn resize(&self, size: u64) -> Result<(), Error> {
         self.resize.work(size)
     }
+
+    // Get the size of the memory actually held by the balloon.
+    pub fn get_actual(&self) -> u64 {
+        (self.config.lock().unwrap().actual as u64) << PAGE_SHIFT
+    }
+
+    // Get the size of the memory the balloon is expected to hold.
+    pub fn get_target(&self) -> u64 {
+        (self.config.lock().unwrap().num_pages as u64) << PAGE_SHIFT
+    }
+
+    // Get both the target and the actual size of the balloon, as they were
+    // at the same point in time.
+    pub fn info(&self) -> BalloonInfo {
+        let config = self.config.lock().unwrap();
+        BalloonInfo {
+            target: (config.num_pages as u64) << PAGE_SHIFT,
+            actual: (config.actual as u64) << PAGE_SHIFT,
+        }
+    }
+
+    // Get the latest memory statistics reported by the guest, flagged as
+    // stale if the guest stopped refreshing them.
+    pub fn get_stats(&self) -> BalloonStats {
+        let mut stats = self.stats.lock().unwrap().clone();
+        let max_age = self.stats_polling_interval * STATS_STALE_INTERVALS;
+        stats.stale = match stats.last_update {
+            Some(last_update) => unix_time_secs().saturating_sub(last_update) > max_age,
+            None => true,
+        };
+        stats
+    }
 }
 
 impl Drop for Balloon {
@@ -450,7 +901,7 @@ impl VirtioDevice for Balloon {
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        self.queue_sizes.as_slice()
     }
 
     fn features(&self) -> u64 {
@@ -474,6 +925,23 @@ impl VirtioDevice for Balloon {
         self.read_config_from_slice(self.config.lock().unwrap().as_slice(), offset, data);
     }
 
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Only the actual number of pages can be written by the driver.
+        let actual_offset = size_of::<u32>() as u64;
+        if offset != actual_offset || data.len() != size_of::<u32>() {
+            warn!(
+                "Invalid balloon config write: offset 0x{:x}, length {}",
+                offset,
+                data.len()
+            );
+            return;
+        }
+
+        let mut actual = [0u8; 4];
+        actual.copy_from_slice(data);
+        self.config.lock().unwrap().actual = u32::from_le_bytes(actual);
+    }
+
     fn activate(
         &mut self,
         mem: GuestMemoryAtomic<GuestMemoryMmap>,
@@ -481,10 +949,10 @@ impl VirtioDevice for Balloon {
         queues: Vec<Queue>,
         mut queue_evts: Vec<EventFd>,
     ) -> ActivateResult {
-        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+        if queues.len() != self.queue_sizes.len() || queue_evts.len() != self.queue_sizes.len() {
             error!(
                 "Cannot perform activate. Expected {} queue(s), got {}",
-                NUM_QUEUES,
+                self.queue_sizes.len(),
                 queues.len()
             );
             return Err(ActivateError::BadActivate);
@@ -519,6 +987,29 @@ impl VirtioDevice for Balloon {
         }
         self.queue_evts = Some(tmp_queue_evts);
 
+        let inflate_queue_evt = queue_evts.remove(0);
+        let deflate_queue_evt = queue_evts.remove(0);
+        // The statistics queue is only used if the feature has been
+        // negotiated with the driver.
+        let stats_vq = self.acked_features & (1u64 << VIRTIO_BALLOON_F_STATS_VQ) != 0;
+        let (stats_queue_evt, stats_timer) = if stats_vq {
+            let stats_timer = TimerFd::new().map_err(|e| {
+                error!("failed creating statistics TimerFd: {}", e);
+                ActivateError::BadActivate
+            })?;
+            (Some(queue_evts.remove(0)), Some(stats_timer))
+        } else {
+            (None, None)
+        };
+        // The reporting queue is only used if the feature has been
+        // negotiated with the driver.
+        let reporting = self.acked_features & (1u64 << VIRTIO_BALLOON_F_REPORTING) != 0;
+        let reporting_queue_evt = if reporting {
+            Some(queue_evts.remove(0))
+        } else {
+            None
+        };
+
         let mut handler = BalloonEpollHandler {
             config: self.config.clone(),
             resize_receiver: self.resize.get_receiver().map_err(|e| {
@@ -528,10 +1019,22 @@ impl VirtioDevice for Balloon {
             queues,
             mem,
             interrupt_cb,
-            inflate_queue_evt: queue_evts.remove(0),
-            deflate_queue_evt: queue_evts.remove(0),
+            inflate_queue_evt,
+            deflate_queue_evt,
+            reporting_queue_evt,
+            stats_queue_evt,
+            stats_timer,
+            stats: self.stats.clone(),
+            stats_polling_interval: self.stats_polling_interval,
+            stats_desc_index: None,
             kill_evt,
             pause_evt,
+            advisor: Arc::new(Madvise::default()),
+            acked_features: self.acked_features,
+            autodeflate: self.autodeflate,
+            // Start from the count of the driver, in case the balloon is
+            // already inflated.
+            held_pages: self.config.lock().unwrap().actual,
         };
 
         let paused = self.paused.clone();
@@ -576,3 +1079,343 @@ impl Snapshottable for Balloon {
 }
 impl Transportable for Balloon {}
 impl Migratable for Balloon {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::NoopVirtioInterrupt;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const MEM_SIZE: usize = 0x100_0000;
+
+    #[derive(Default)]
+    struct TestAdvisor {
+        calls: Mutex<Vec<(u64, usize, libc::c_int)>>,
+    }
+
+    impl MemoryAdvisor for TestAdvisor {
+        fn advise(&self, hva: *mut u8, len: usize, advice: libc::c_int) -> io::Result<()> {
+            self.calls.lock().unwrap().push((hva as u64, len, advice));
+            Ok(())
+        }
+    }
+
+    // Interrupt counting the configuration changes notified to the guest.
+    #[derive(Default)]
+    struct ConfigVirtioInterrupt {
+        count: AtomicU64,
+    }
+
+    impl VirtioInterrupt for ConfigVirtioInterrupt {
+        fn trigger(
+            &self,
+            int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> std::result::Result<(), std::io::Error> {
+            if let VirtioInterruptType::Config = int_type {
+                self.count.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(())
+        }
+    }
+
+    fn create_handler(
+        mem: &GuestMemoryMmap,
+        queues: Vec<Queue>,
+        advisor: Arc<TestAdvisor>,
+        config: Arc<Mutex<VirtioBalloonConfig>>,
+        acked_features: u64,
+    ) -> BalloonEpollHandler {
+        BalloonEpollHandler {
+            config,
+            resize_receiver: VirtioBalloonResize::new().unwrap().get_receiver().unwrap(),
+            queues,
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            inflate_queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            deflate_queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            reporting_queue_evt: Some(EventFd::new(EFD_NONBLOCK).unwrap()),
+            stats_queue_evt: Some(EventFd::new(EFD_NONBLOCK).unwrap()),
+            stats_timer: None,
+            stats: Arc::new(Mutex::new(BalloonStats::default())),
+            stats_polling_interval: 10,
+            stats_desc_index: None,
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            advisor,
+            acked_features,
+            autodeflate: false,
+            held_pages: 0,
+        }
+    }
+
+    #[test]
+    fn test_balloon_free_page_reporting() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_inflateq = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let guest_deflateq = GuestQ::new(GuestAddress(0x2_0000), &mem, 16);
+        let guest_reportingq = GuestQ::new(GuestAddress(0x3_0000), &mem, 16);
+
+        // Report a 2MiB range of free pages backed by guest memory, followed
+        // by a range which is partially outside of it.
+        guest_reportingq.dtable[0].set(
+            0x40_0000,
+            0x20_0000,
+            VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT,
+            1,
+        );
+        guest_reportingq.dtable[1].set((MEM_SIZE - 0x1000) as u64, 0x2000, VIRTQ_DESC_F_WRITE, 0);
+        guest_reportingq.avail.ring[0].set(0);
+        guest_reportingq.avail.idx.set(1);
+
+        let queues = vec![
+            guest_inflateq.create_queue(),
+            guest_deflateq.create_queue(),
+            guest_reportingq.create_queue(),
+        ];
+        let advisor = Arc::new(TestAdvisor::default());
+        let mut handler = create_handler(
+            &mem,
+            queues,
+            advisor.clone(),
+            Arc::new(Mutex::new(VirtioBalloonConfig::default())),
+            1u64 << VIRTIO_BALLOON_F_REPORTING,
+        );
+
+        handler.process_reporting_queue().unwrap();
+
+        // Only the valid range must have been given back to the host.
+        let hva = mem.get_host_address(GuestAddress(0x40_0000)).unwrap() as u64;
+        assert_eq!(
+            *advisor.calls.lock().unwrap(),
+            vec![(hva, 0x20_0000, libc::MADV_DONTNEED)]
+        );
+
+        // The descriptor chain must have been returned to the guest.
+        assert_eq!(guest_reportingq.used.idx.get(), 1);
+        assert_eq!(guest_reportingq.used.ring[0].get().id, 0);
+        assert_eq!(guest_reportingq.used.ring[0].get().len, 0);
+    }
+
+    #[test]
+    fn test_balloon_deflate_on_oom() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_inflateq = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let guest_deflateq = GuestQ::new(GuestAddress(0x2_0000), &mem, 16);
+
+        // The balloon has reached its target of 4 pages.
+        let config = Arc::new(Mutex::new(VirtioBalloonConfig {
+            num_pages: 4,
+            actual: 4,
+        }));
+
+        // Under memory pressure, the guest takes back 2 pages from the
+        // balloon even though the target has not changed.
+        let pfns_addr = GuestAddress(0x4_0000);
+        mem.write_obj(0x40u32, pfns_addr).unwrap();
+        mem.write_obj(0x41u32, pfns_addr.unchecked_add(4)).unwrap();
+        guest_deflateq.dtable[0].set(pfns_addr.raw_value(), 8, 0, 0);
+        guest_deflateq.avail.ring[0].set(0);
+        guest_deflateq.avail.idx.set(1);
+
+        let queues = vec![guest_inflateq.create_queue(), guest_deflateq.create_queue()];
+        let advisor = Arc::new(TestAdvisor::default());
+        let interrupt = Arc::new(ConfigVirtioInterrupt::default());
+        let mut handler = create_handler(
+            &mem,
+            queues,
+            advisor.clone(),
+            config.clone(),
+            1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM,
+        );
+        handler.interrupt_cb = interrupt.clone();
+        handler.held_pages = 4;
+
+        handler.process_queue(DEFLATE_QUEUE_EVENT).unwrap();
+
+        assert_eq!(advisor.calls.lock().unwrap().len(), 2);
+        assert_eq!(guest_deflateq.used.idx.get(), 1);
+        assert_eq!(guest_deflateq.used.ring[0].get().id, 0);
+
+        // The device accounts for the deflate, while the target is left
+        // untouched and the actual size is left for the driver to update.
+        assert_eq!(handler.held_pages, 2);
+        assert_eq!(interrupt.count.load(Ordering::SeqCst), 0);
+        let config = config.lock().unwrap();
+        assert_eq!({ config.actual }, 4);
+        assert_eq!({ config.num_pages }, 4);
+    }
+
+    #[test]
+    fn test_balloon_autodeflate() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_inflateq = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let guest_deflateq = GuestQ::new(GuestAddress(0x2_0000), &mem, 16);
+
+        let config = Arc::new(Mutex::new(VirtioBalloonConfig {
+            num_pages: 4,
+            actual: 4,
+        }));
+
+        // The guest returns a page from the balloon without any resize
+        // request from the host.
+        let pfns_addr = GuestAddress(0x4_0000);
+        mem.write_obj(0x40u32, pfns_addr).unwrap();
+        guest_deflateq.dtable[0].set(pfns_addr.raw_value(), 4, 0, 0);
+        guest_deflateq.avail.ring[0].set(0);
+        guest_deflateq.avail.idx.set(1);
+
+        let queues = vec![guest_inflateq.create_queue(), guest_deflateq.create_queue()];
+        let interrupt = Arc::new(ConfigVirtioInterrupt::default());
+        let mut handler = create_handler(
+            &mem,
+            queues,
+            Arc::new(TestAdvisor::default()),
+            config.clone(),
+            1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM,
+        );
+        handler.interrupt_cb = interrupt.clone();
+        handler.autodeflate = true;
+        handler.held_pages = 4;
+
+        handler.process_queue(DEFLATE_QUEUE_EVENT).unwrap();
+
+        // The target follows the pages the device still holds, so that the
+        // balloon doesn't get inflated again behind the guest's back, and
+        // the guest is told about it.
+        assert_eq!(handler.held_pages, 3);
+        assert_eq!(interrupt.count.load(Ordering::SeqCst), 1);
+        {
+            let config = config.lock().unwrap();
+            assert_eq!({ config.actual }, 4);
+            assert_eq!({ config.num_pages }, 3);
+        }
+
+        // Deflating down to a lower target is the guest following a resize,
+        // which leaves the target alone.
+        config.lock().unwrap().num_pages = 1;
+        mem.write_obj(0x41u32, pfns_addr).unwrap();
+        guest_deflateq.avail.ring[1].set(0);
+        guest_deflateq.avail.idx.set(2);
+
+        handler.process_queue(DEFLATE_QUEUE_EVENT).unwrap();
+
+        assert_eq!(handler.held_pages, 2);
+        assert_eq!(interrupt.count.load(Ordering::SeqCst), 1);
+        assert_eq!({ config.lock().unwrap().num_pages }, 1);
+    }
+
+    #[test]
+    fn test_balloon_info() {
+        let mut balloon = Balloon::new(
+            "balloon".to_string(),
+            4 << PAGE_SHIFT,
+            false,
+            false,
+            false,
+            0,
+        )
+        .unwrap();
+        assert_eq!(
+            balloon.info(),
+            BalloonInfo {
+                target: 4 << PAGE_SHIFT,
+                actual: 0,
+            }
+        );
+
+        // The guest inflates the balloon by 2 pages.
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_inflateq = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let guest_deflateq = GuestQ::new(GuestAddress(0x2_0000), &mem, 16);
+        let pfns_addr = GuestAddress(0x4_0000);
+        mem.write_obj(0x40u32, pfns_addr).unwrap();
+        mem.write_obj(0x41u32, pfns_addr.unchecked_add(4)).unwrap();
+        guest_inflateq.dtable[0].set(pfns_addr.raw_value(), 8, 0, 0);
+        guest_inflateq.avail.ring[0].set(0);
+        guest_inflateq.avail.idx.set(1);
+
+        let queues = vec![guest_inflateq.create_queue(), guest_deflateq.create_queue()];
+        let mut handler = create_handler(
+            &mem,
+            queues,
+            Arc::new(TestAdvisor::default()),
+            balloon.config.clone(),
+            0,
+        );
+        handler.process_queue(INFLATE_QUEUE_EVENT).unwrap();
+        assert_eq!(balloon.info().actual, 2 << PAGE_SHIFT);
+
+        // Then acknowledges the whole target through the config space.
+        balloon.write_config(size_of::<u32>() as u64, &4u32.to_le_bytes());
+        let info = serde_json::to_value(balloon.info()).unwrap();
+        assert_eq!(info["target"], 4u64 << PAGE_SHIFT);
+        assert_eq!(info["actual"], 4u64 << PAGE_SHIFT);
+    }
+
+    #[test]
+    fn test_balloon_stats() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_inflateq = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let guest_deflateq = GuestQ::new(GuestAddress(0x2_0000), &mem, 16);
+        let guest_statsq = GuestQ::new(GuestAddress(0x3_0000), &mem, 16);
+        let guest_reportingq = GuestQ::new(GuestAddress(0x4_0000), &mem, 16);
+
+        // The guest reports its free and total memory, along with a tag
+        // the device doesn't know about.
+        let stats_addr = GuestAddress(0x5_0000);
+        let reported = [
+            VirtioBalloonStat {
+                tag: VIRTIO_BALLOON_S_MEMFREE,
+                val: 0x1000_0000,
+            },
+            VirtioBalloonStat {
+                tag: VIRTIO_BALLOON_S_MEMTOT,
+                val: 0x4000_0000,
+            },
+            VirtioBalloonStat { tag: 42, val: 1 },
+        ];
+        for (i, stat) in reported.iter().enumerate() {
+            mem.write_obj(*stat, stats_addr.unchecked_add(i as u64 * 10))
+                .unwrap();
+        }
+        guest_statsq.dtable[0].set(stats_addr.raw_value(), 30, 0, 0);
+        guest_statsq.avail.ring[0].set(0);
+        guest_statsq.avail.idx.set(1);
+
+        let queues = vec![
+            guest_inflateq.create_queue(),
+            guest_deflateq.create_queue(),
+            guest_statsq.create_queue(),
+            guest_reportingq.create_queue(),
+        ];
+        let mut handler = create_handler(
+            &mem,
+            queues,
+            Arc::new(TestAdvisor::default()),
+            Arc::new(Mutex::new(VirtioBalloonConfig::default())),
+            (1u64 << VIRTIO_BALLOON_F_STATS_VQ) | (1u64 << VIRTIO_BALLOON_F_REPORTING),
+        );
+        assert_eq!(handler.stats_queue_index(), 2);
+        assert_eq!(handler.reporting_queue_index(), 3);
+
+        handler.process_stats_queue().unwrap();
+
+        let stats = handler.stats.lock().unwrap().clone();
+        assert_eq!(stats.free_memory, Some(0x1000_0000));
+        assert_eq!(stats.total_memory, Some(0x4000_0000));
+        assert_eq!(stats.available_memory, None);
+        assert!(stats.last_update.is_some());
+
+        // The buffer is held by the device until the next refresh.
+        assert_eq!(guest_statsq.used.idx.get(), 0);
+        handler.request_stats().unwrap();
+        assert_eq!(guest_statsq.used.idx.get(), 1);
+        assert_eq!(guest_statsq.used.ring[0].get().id, 0);
+
+        // Nothing to give back until the guest provides a new buffer.
+        handler.request_stats().unwrap();
+        assert_eq!(guest_statsq.used.idx.get(), 1);
+    }
+}
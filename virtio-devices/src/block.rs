@@ -8,15 +8,20 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
+use super::coalescing::InterruptCoalescer;
+use super::seccomp_filters::{get_seccomp_filter, Thread};
 use super::Error as DeviceError;
 use super::{
     ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
-    VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
+    ReadyBarrier, ReadyNotifier, VirtioDevice, VirtioDeviceType, VirtioInterruptType,
+    EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IN_ORDER,
 };
 use crate::VirtioInterrupt;
 use anyhow::anyhow;
-use block_util::{build_disk_image_id, Request, RequestType, VirtioBlockConfig};
+use block_util::{build_disk_image_id, build_serial, Request, RequestType, VirtioBlockConfig};
 use libc::EFD_NONBLOCK;
+use rate_limiter::{RateLimiter, RateLimiterConfig};
+use seccomp::{SeccompAction, SeccompFilter};
 use std::collections::HashMap;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::num::Wrapping;
@@ -25,10 +30,14 @@ use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 use virtio_bindings::bindings::virtio_blk::*;
-use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use virtio_bindings::bindings::virtio_ring::{
+    VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
+};
 use vm_memory::{
     ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError,
     GuestMemoryMmap,
@@ -44,6 +53,13 @@ pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
 
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// The rate limiter budget has been replenished.
+const RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// The completion coalescing delay has elapsed.
+const COALESCING_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+
+// How long shutting the device down waits for the disk image to be flushed.
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Debug)]
 pub enum Error {
@@ -65,6 +81,17 @@ pub enum Error {
     InvalidOffset,
 }
 
+/// Interrupt moderation of each queue of a virtio-blk device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlockCoalescing {
+    /// Number of completed requests after which the guest gets notified
+    /// right away, 0 meaning no limit.
+    pub max_requests: u32,
+    /// Longest delay, in microseconds, between a request being completed
+    /// and the guest being notified about it. Coalescing is disabled when 0.
+    pub max_usecs: u64,
+}
+
 pub trait DiskFile: Read + Seek + Write + Clone {}
 impl<D: Read + Seek + Write + Clone> DiskFile for D {}
 
@@ -87,11 +114,25 @@ struct BlockEpollHandler<T: DiskFile> {
     pause_evt: EventFd,
     event_idx: bool,
     writeback: Arc<AtomicBool>,
+    read_only: bool,
     counters: BlockCounters,
     queue_evt: EventFd,
+    rate_limiter: Option<RateLimiter>,
+    busy_poll: Option<Duration>,
+    coalescer: InterruptCoalescer,
+    // Whether a flush was completed since the queue was last processed.
+    flush_completed: bool,
 }
 
 impl<T: DiskFile> BlockEpollHandler<T> {
+    // Requests are executed one at a time, in the order the driver made them
+    // available, and none completes before its execution is over. Because of
+    // this, a flush is only executed, and completed, once all the writes
+    // which preceded it have reached the disk image, and it then waits for
+    // them to be durable, which is the barrier guest filesystems rely on.
+    //
+    // A request the rate limiter refuses is left in the queue, along with
+    // the ones following it, until the rate limiter timer expires.
     fn process_queue(&mut self) -> bool {
         let queue = &mut self.queue;
 
@@ -103,11 +144,27 @@ impl<T: DiskFile> BlockEpollHandler<T> {
         let mut read_ops = Wrapping(0);
         let mut write_ops = Wrapping(0);
 
-        for avail_desc in queue.iter(&mem) {
+        while let Some(avail_desc) = queue.iter(&mem).next() {
             let len;
             match Request::parse(&avail_desc, &mem) {
                 Ok(mut request) => {
+                    if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+                        let bytes = match request.request_type {
+                            RequestType::In | RequestType::Out => request.data_len as u64,
+                            _ => 0,
+                        };
+                        let allowed = rate_limiter.consume_request(bytes).unwrap_or_else(|e| {
+                            error!("Failed to consume rate limiter budget: {}", e);
+                            false
+                        });
+                        if !allowed {
+                            queue.go_to_previous_position();
+                            break;
+                        }
+                    }
+
                     request.set_writeback(self.writeback.load(Ordering::SeqCst));
+                    request.set_read_only(self.read_only);
 
                     let mut disk_image_locked = self.disk_image.lock().unwrap();
                     let mut disk_image = disk_image_locked.deref_mut();
@@ -141,6 +198,9 @@ impl<T: DiskFile> BlockEpollHandler<T> {
                     // We use unwrap because the request parsing process already checked that the
                     // status_addr was valid.
                     mem.write_obj(status, request.status_addr).unwrap();
+                    if request.request_type == RequestType::Flush {
+                        self.flush_completed = true;
+                    }
                 }
                 Err(e) => {
                     error!("Failed to parse available descriptor chain: {:?}", e);
@@ -151,8 +211,10 @@ impl<T: DiskFile> BlockEpollHandler<T> {
             used_count += 1;
         }
 
-        for &(desc_index, len) in used_desc_heads.iter() {
-            queue.add_used(&mem, desc_index, len);
+        // The requests complete in order, which lets the used ring be
+        // written in a single batch when VIRTIO_F_IN_ORDER was negotiated.
+        if !used_desc_heads.is_empty() {
+            queue.add_used_batch(&mem, &used_desc_heads);
         }
 
         self.counters
@@ -181,6 +243,71 @@ impl<T: DiskFile> BlockEpollHandler<T> {
             })
     }
 
+    // Decide whether to signal the queue after requests have been completed
+    // since `next_used`, `notify` telling whether the driver asked to be
+    // notified about them. The guest waits on the flushes to make progress,
+    // so their completion is never delayed, along with the ones before.
+    fn used_descs_added(
+        &mut self,
+        next_used: Wrapping<u16>,
+        notify: bool,
+    ) -> result::Result<bool, DeviceError> {
+        let used = (self.queue.next_used - next_used).0;
+        let signal = self
+            .coalescer
+            .completed(used, notify)
+            .map_err(|e| DeviceError::EpollHander(format!("{:?}", e)))?;
+        if std::mem::replace(&mut self.flush_completed, false) {
+            return Ok(self.coalescer.flush() || signal);
+        }
+
+        Ok(signal)
+    }
+
+    // Return true if some requests have been completed.
+    fn process_queue_and_signal(&mut self) -> result::Result<bool, DeviceError> {
+        let mut processed = false;
+        loop {
+            let next_used = self.queue.next_used;
+            if !self.process_queue() {
+                break;
+            }
+            processed = true;
+
+            let notify = if self.event_idx {
+                self.queue.update_avail_event(&self.mem.memory());
+                self.queue
+                    .needs_notification(&self.mem.memory(), self.queue.next_used)
+            } else {
+                true
+            };
+            if self.used_descs_added(next_used, notify)? {
+                self.signal_used_queue()?;
+            }
+
+            // vm-virtio's Queue implementation only checks avail_index
+            // once, so to properly support EVENT_IDX we need to keep
+            // calling process_queue() until it stops finding new
+            // requests on the queue.
+            if !self.event_idx {
+                break;
+            }
+        }
+
+        Ok(processed)
+    }
+
+    fn handle_coalescing_event(&mut self) -> result::Result<(), DeviceError> {
+        if self
+            .coalescer
+            .timer_expired()
+            .map_err(|e| DeviceError::EpollHander(format!("{:?}", e)))?
+        {
+            self.signal_used_queue()?;
+        }
+        Ok(())
+    }
+
     #[allow(dead_code)]
     fn update_disk_image(
         &mut self,
@@ -196,9 +323,23 @@ impl<T: DiskFile> BlockEpollHandler<T> {
         Ok(())
     }
 
-    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), EpollHelperError> {
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        ready: ReadyNotifier,
+    ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        if let Some(rate_limiter) = &self.rate_limiter {
+            helper.add_event(rate_limiter.as_raw_fd(), RATE_LIMITER_EVENT)?;
+        }
+        if let Some(fd) = self.coalescer.timer_fd() {
+            helper.add_event(fd, COALESCING_EVENT)?;
+        }
+        if let Some(busy_poll) = self.busy_poll {
+            helper.set_busy_poll(busy_poll);
+        }
+        ready.notify();
         helper.run(paused, self)?;
 
         Ok(())
@@ -212,34 +353,31 @@ impl<T: DiskFile> EpollHelperHandler for BlockEpollHandler<T> {
                 if let Err(e) = self.queue_evt.read() {
                     error!("Failed to get queue event: {:?}", e);
                     return true;
-                } else if self.event_idx {
-                    // vm-virtio's Queue implementation only checks avail_index
-                    // once, so to properly support EVENT_IDX we need to keep
-                    // calling process_queue() until it stops finding new
-                    // requests on the queue.
-                    loop {
-                        if self.process_queue() {
-                            self.queue.update_avail_event(&self.mem.memory());
-
-                            if self
-                                .queue
-                                .needs_notification(&self.mem.memory(), self.queue.next_used)
-                            {
-                                if let Err(e) = self.signal_used_queue() {
-                                    error!("Failed to signal used queue: {:?}", e);
-                                    return true;
-                                }
-                            }
-                        } else {
-                            break;
-                        }
-                    }
-                } else if self.process_queue() {
-                    if let Err(e) = self.signal_used_queue() {
-                        error!("Failed to signal used queue: {:?}", e);
+                } else if let Err(e) = self.process_queue_and_signal() {
+                    error!("Failed to signal used queue: {:?}", e);
+                    return true;
+                }
+            }
+            RATE_LIMITER_EVENT => {
+                if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+                    if let Err(e) = rate_limiter.event_handler() {
+                        error!("Failed to process rate limiter event: {}", e);
                         return true;
                     }
                 }
+
+                // Budget has been replenished, process the requests which
+                // have been deferred.
+                if let Err(e) = self.process_queue_and_signal() {
+                    error!("Failed to signal used queue: {:?}", e);
+                    return true;
+                }
+            }
+            COALESCING_EVENT => {
+                if let Err(e) = self.handle_coalescing_event() {
+                    error!("Failed to signal used queue: {:?}", e);
+                    return true;
+                }
             }
             _ => {
                 error!("Unexpected event: {}", event);
@@ -248,6 +386,19 @@ impl<T: DiskFile> EpollHelperHandler for BlockEpollHandler<T> {
         }
         false
     }
+
+    fn poll(&mut self, _helper: &mut EpollHelper) -> bool {
+        // The requests the driver makes available while being polled are
+        // picked up straight from the queue, which doesn't need to be
+        // notified of them.
+        match self.process_queue_and_signal() {
+            Ok(processed) => processed,
+            Err(e) => {
+                error!("Failed to signal used queue: {:?}", e);
+                false
+            }
+        }
+    }
 }
 
 /// Virtio device for exposing block level read/write operations on a host file.
@@ -257,6 +408,7 @@ pub struct Block<T: DiskFile> {
     disk_image: Arc<Mutex<T>>,
     disk_path: PathBuf,
     disk_nsectors: u64,
+    serial: Vec<u8>,
     avail_features: u64,
     acked_features: u64,
     config: VirtioBlockConfig,
@@ -268,12 +420,17 @@ pub struct Block<T: DiskFile> {
     queue_size: Vec<u16>,
     writeback: Arc<AtomicBool>,
     counters: BlockCounters,
+    seccomp_action: SeccompAction,
+    rate_limiter_config: Option<RateLimiterConfig>,
+    busy_poll: Option<Duration>,
+    coalescing: BlockCoalescing,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct BlockState {
     pub disk_path: PathBuf,
     pub disk_nsectors: u64,
+    pub serial: Vec<u8>,
     pub avail_features: u64,
     pub acked_features: u64,
     pub config: VirtioBlockConfig,
@@ -282,7 +439,15 @@ pub struct BlockState {
 impl<T: DiskFile> Block<T> {
     /// Create a new virtio block device that operates on the given file.
     ///
-    /// The given file must be seekable and sizable.
+    /// The given file must be seekable and sizable. The `serial` is reported
+    /// to the guest through VIRTIO_BLK_T_GET_ID, and it defaults to an
+    /// identifier built from the disk image metadata. Each queue enforces
+    /// the limits of `rate_limiter_config` on its own. With `busy_poll`, the
+    /// thread of each queue keeps polling it for the given duration once
+    /// some requests have been completed, before waiting for the driver to
+    /// notify it again. The interrupts signalling the completed requests are
+    /// delayed according to `coalescing`, except for the flushes.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         mut disk_image: T,
@@ -291,6 +456,11 @@ impl<T: DiskFile> Block<T> {
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
+        serial: Option<String>,
+        rate_limiter_config: Option<RateLimiterConfig>,
+        busy_poll: Option<Duration>,
+        coalescing: BlockCoalescing,
+        seccomp_action: SeccompAction,
     ) -> io::Result<Block<T>> {
         let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
         if disk_size % SECTOR_SIZE != 0 {
@@ -304,7 +474,9 @@ impl<T: DiskFile> Block<T> {
         let mut avail_features = (1u64 << VIRTIO_F_VERSION_1)
             | (1u64 << VIRTIO_BLK_F_FLUSH)
             | (1u64 << VIRTIO_RING_F_EVENT_IDX)
-            | (1u64 << VIRTIO_BLK_F_CONFIG_WCE);
+            | (1u64 << VIRTIO_RING_F_INDIRECT_DESC)
+            | (1u64 << VIRTIO_BLK_F_CONFIG_WCE)
+            | (1u64 << VIRTIO_F_IN_ORDER);
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -326,12 +498,17 @@ impl<T: DiskFile> Block<T> {
             config.num_queues = num_queues as u16;
         }
 
+        let serial = serial
+            .map(|s| build_serial(&s))
+            .unwrap_or_else(|| build_disk_image_id(&disk_path));
+
         Ok(Block {
             id,
             kill_evt: None,
             disk_image: Arc::new(Mutex::new(disk_image)),
             disk_path,
             disk_nsectors,
+            serial,
             avail_features,
             acked_features: 0u64,
             config,
@@ -343,6 +520,10 @@ impl<T: DiskFile> Block<T> {
             queue_size: vec![queue_size; num_queues],
             writeback: Arc::new(AtomicBool::new(true)),
             counters: BlockCounters::default(),
+            seccomp_action,
+            rate_limiter_config,
+            busy_poll,
+            coalescing,
         })
     }
 
@@ -350,6 +531,7 @@ impl<T: DiskFile> Block<T> {
         BlockState {
             disk_path: self.disk_path.clone(),
             disk_nsectors: self.disk_nsectors,
+            serial: self.serial.clone(),
             avail_features: self.avail_features,
             acked_features: self.acked_features,
             config: self.config,
@@ -359,6 +541,7 @@ impl<T: DiskFile> Block<T> {
     fn set_state(&mut self, state: &BlockState) -> io::Result<()> {
         self.disk_path = state.disk_path.clone();
         self.disk_nsectors = state.disk_nsectors;
+        self.serial = state.serial.clone();
         self.avail_features = state.avail_features;
         self.acked_features = state.acked_features;
         self.config = state.config;
@@ -388,6 +571,36 @@ impl<T: DiskFile> Block<T> {
     }
 }
 
+// Wait for the epoll threads to be over, along with the requests they
+// were executing, then flush the disk image, giving up after `timeout`.
+fn flush_disk_image<T: 'static + DiskFile + Send>(
+    epoll_threads: Vec<thread::JoinHandle<result::Result<(), EpollHelperError>>>,
+    disk_image: Arc<Mutex<T>>,
+    timeout: Duration,
+) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("virtio_blk_flush".to_string())
+        .spawn(move || {
+            for thread in epoll_threads {
+                if let Ok(Err(e)) = thread.join() {
+                    error!("Error running the virtio-blk epoll thread: {:?}", e);
+                }
+            }
+            // Nobody waits for the result anymore if it timed out.
+            let _ = sender.send(disk_image.lock().unwrap().flush());
+        })?;
+
+    receiver.recv_timeout(timeout).map_err(|e| match e {
+        RecvTimeoutError::Timeout => {
+            io::Error::new(io::ErrorKind::TimedOut, "Timed out flushing the disk image")
+        }
+        RecvTimeoutError::Disconnected => {
+            io::Error::new(io::ErrorKind::Other, "The disk image flush thread panicked")
+        }
+    })?
+}
+
 impl<T: DiskFile> Drop for Block<T> {
     fn drop(&mut self) {
         if let Some(kill_evt) = self.kill_evt.take() {
@@ -478,7 +691,7 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
             })?;
         self.pause_evt = Some(self_pause_evt);
 
-        let disk_image_id = build_disk_image_id(&self.disk_path);
+        let disk_image_id = self.serial.clone();
 
         let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
         for queue_evt in queue_evts.iter() {
@@ -504,11 +717,29 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
 
         let event_idx = self.acked_features & 1u64 << VIRTIO_RING_F_EVENT_IDX
             == 1u64 << VIRTIO_RING_F_EVENT_IDX;
+        let in_order = self.acked_features & 1u64 << VIRTIO_F_IN_ORDER != 0;
         self.update_writeback();
 
+        // Waited for by the end of the activation, so that none of the first
+        // notifications from the driver gets in before the thread of its
+        // queue listens to it.
+        let ready = ReadyBarrier::new();
         let mut epoll_threads = Vec::new();
         for _ in 0..self.queue_size.len() {
             let queue_evt = queue_evts.remove(0);
+            let rate_limiter = match &self.rate_limiter_config {
+                Some(config) => Some(RateLimiter::from_config(config).map_err(|e| {
+                    error!("failed creating rate limiter: {}", e);
+                    ActivateError::BadActivate
+                })?),
+                None => None,
+            };
+            let coalescer =
+                InterruptCoalescer::new(self.coalescing.max_requests, self.coalescing.max_usecs)
+                    .map_err(|e| {
+                        error!("failed creating interrupt coalescing timer: {:?}", e);
+                        ActivateError::BadActivate
+                    })?;
             let mut handler = BlockEpollHandler {
                 queue: queues.remove(0),
                 mem: mem.clone(),
@@ -520,16 +751,31 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
                 pause_evt: pause_evt.try_clone().unwrap(),
                 event_idx,
                 writeback: self.writeback.clone(),
+                read_only: self.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0,
                 counters: self.counters.clone(),
                 queue_evt,
+                rate_limiter,
+                busy_poll: self.busy_poll,
+                coalescer,
+                flush_completed: false,
             };
 
             handler.queue.set_event_idx(event_idx);
+            handler.queue.set_in_order(in_order);
 
             let paused = self.paused.clone();
+            let thread_ready = ready.notifier();
+            // Retrieve seccomp filter for virtio_blk thread
+            let virtio_blk_seccomp_filter =
+                get_seccomp_filter(&self.seccomp_action, Thread::VirtioBlock)
+                    .map_err(ActivateError::CreateSeccompFilter)?;
             thread::Builder::new()
                 .name("virtio_blk".to_string())
-                .spawn(move || handler.run(paused))
+                .spawn(move || {
+                    SeccompFilter::apply(virtio_blk_seccomp_filter)
+                        .map_err(EpollHelperError::ApplySeccompFilter)?;
+                    handler.run(paused, thread_ready)
+                })
                 .map(|thread| epoll_threads.push(thread))
                 .map_err(|e| {
                     error!("failed to clone the virtio-blk epoll thread: {}", e);
@@ -543,6 +789,11 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
 
         self.epoll_threads = Some(epoll_threads);
 
+        if !ready.wait() {
+            error!("virtio-blk threads exited before listening to their queues");
+            return Err(ActivateError::BadActivate);
+        }
+
         Ok(())
     }
 
@@ -564,6 +815,41 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
         ))
     }
 
+    // Make the writes the guest completed durable, as the device is about
+    // to be unplugged or the VM to be shut down. The epoll threads are told
+    // to exit once done with the requests they're executing.
+    fn shutdown(&mut self) {
+        if self.pause_evt.take().is_some() {
+            if let Err(e) = self.resume() {
+                error!("Failed resuming the virtio-blk epoll threads: {:?}", e);
+            }
+        }
+
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        let epoll_threads = self.epoll_threads.take().unwrap_or_default();
+        if self.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0 {
+            return;
+        }
+        if let Err(e) = flush_disk_image(
+            epoll_threads,
+            self.disk_image.clone(),
+            SHUTDOWN_FLUSH_TIMEOUT,
+        ) {
+            error!("Failed flushing disk image {:?}: {}", self.disk_path, e);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0 {
+            return Ok(());
+        }
+        self.disk_image.lock().unwrap().flush()
+    }
+
     fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
         let mut counters = HashMap::new();
 
@@ -631,3 +917,572 @@ impl<T: 'static + DiskFile + Send> Snapshottable for Block<T> {
 }
 impl<T: 'static + DiskFile + Send> Transportable for Block<T> {}
 impl<T: 'static + DiskFile + Send> Migratable for Block<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::NoopVirtioInterrupt;
+    use std::time::Instant;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const MEM_SIZE: usize = 0x10_0000;
+    const DISK_NSECTORS: u64 = 16;
+    // From include/uapi/linux/virtio_blk.h
+    const VIRTIO_BLK_T_DISCARD: u32 = 11;
+
+    // Counts the interrupts raised.
+    #[derive(Default)]
+    struct CountingVirtioInterrupt {
+        count: AtomicU64,
+    }
+
+    impl CountingVirtioInterrupt {
+        fn count(&self) -> u64 {
+            self.count.load(Ordering::SeqCst)
+        }
+    }
+
+    impl VirtioInterrupt for CountingVirtioInterrupt {
+        fn trigger(
+            &self,
+            _int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> std::result::Result<(), std::io::Error> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum DiskOp {
+        Write(usize),
+        Flush,
+    }
+
+    // Disk image recording the operations, in the order they're performed.
+    #[derive(Clone, Default)]
+    struct RecordingDisk {
+        position: u64,
+        ops: Arc<Mutex<Vec<DiskOp>>>,
+    }
+
+    impl Read for RecordingDisk {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            for b in buf.iter_mut() {
+                *b = 0;
+            }
+            self.position += buf.len() as u64;
+            Ok(buf.len())
+        }
+    }
+
+    impl Write for RecordingDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.ops.lock().unwrap().push(DiskOp::Write(buf.len()));
+            self.position += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.ops.lock().unwrap().push(DiskOp::Flush);
+            Ok(())
+        }
+    }
+
+    impl Seek for RecordingDisk {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.position = match pos {
+                SeekFrom::Start(offset) => offset,
+                SeekFrom::End(offset) => ((DISK_NSECTORS * SECTOR_SIZE) as i64 + offset) as u64,
+                SeekFrom::Current(offset) => (self.position as i64 + offset) as u64,
+            };
+            Ok(self.position)
+        }
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct RequestHeader {
+        request_type: u32,
+        reserved: u32,
+        sector: u64,
+    }
+
+    unsafe impl ByteValued for RequestHeader {}
+
+    // Queue a request made of its header, its optional data and its status,
+    // using three descriptors per request.
+    fn queue_request(
+        mem: &GuestMemoryMmap,
+        guest_q: &GuestQ,
+        index: u16,
+        request_type: u32,
+        sector: u64,
+        data_len: u32,
+    ) -> GuestAddress {
+        let desc = index * 3;
+        let addr = 0x4_0000 + u64::from(index) * 0x1000;
+        let header = RequestHeader {
+            request_type,
+            reserved: 0,
+            sector,
+        };
+        mem.write_obj(header, GuestAddress(addr)).unwrap();
+
+        let status_addr = addr + 0x800;
+        if data_len > 0 {
+            guest_q.dtable[desc as usize].set(addr, 16, VIRTQ_DESC_F_NEXT, desc + 1);
+            guest_q.dtable[desc as usize + 1].set(
+                addr + 0x200,
+                data_len,
+                VIRTQ_DESC_F_NEXT,
+                desc + 2,
+            );
+            guest_q.dtable[desc as usize + 2].set(status_addr, 1, VIRTQ_DESC_F_WRITE, 0);
+        } else {
+            guest_q.dtable[desc as usize].set(addr, 16, VIRTQ_DESC_F_NEXT, desc + 1);
+            guest_q.dtable[desc as usize + 1].set(status_addr, 1, VIRTQ_DESC_F_WRITE, 0);
+        }
+        // Poison the status to make sure it gets written.
+        mem.write_obj(0xffu8, GuestAddress(status_addr)).unwrap();
+        guest_q.avail.ring[index as usize].set(desc);
+
+        GuestAddress(status_addr)
+    }
+
+    fn epoll_handler(
+        mem: &GuestMemoryMmap,
+        guest_q: &GuestQ,
+        disk: RecordingDisk,
+        read_only: bool,
+    ) -> BlockEpollHandler<RecordingDisk> {
+        BlockEpollHandler {
+            queue: guest_q.create_queue(),
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            disk_image: Arc::new(Mutex::new(disk)),
+            disk_nsectors: DISK_NSECTORS,
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            disk_image_id: vec![0; VIRTIO_BLK_ID_BYTES as usize],
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            event_idx: false,
+            writeback: Arc::new(AtomicBool::new(true)),
+            read_only,
+            counters: BlockCounters::default(),
+            queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            rate_limiter: None,
+            busy_poll: None,
+            coalescer: InterruptCoalescer::new(0, 0).unwrap(),
+            flush_completed: false,
+        }
+    }
+
+    #[test]
+    fn test_block_flush_after_writes() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_q = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let disk = RecordingDisk::default();
+        let ops = disk.ops.clone();
+        let mut handler = epoll_handler(&mem, &guest_q, disk, false);
+
+        // Two writes and a flush, then another write and a flush, all made
+        // available at once.
+        let requests = [
+            (VIRTIO_BLK_T_OUT, 0, 512),
+            (VIRTIO_BLK_T_OUT, 4, 1024),
+            (VIRTIO_BLK_T_FLUSH, 0, 0),
+            (VIRTIO_BLK_T_OUT, 8, 512),
+            (VIRTIO_BLK_T_FLUSH, 0, 0),
+        ];
+        let statuses: Vec<GuestAddress> = requests
+            .iter()
+            .enumerate()
+            .map(|(i, (request_type, sector, len))| {
+                queue_request(&mem, &guest_q, i as u16, *request_type, *sector, *len)
+            })
+            .collect();
+        guest_q.avail.idx.set(requests.len() as u16);
+        assert!(handler.process_queue());
+
+        // Each flush has only been performed once the writes made available
+        // before it reached the disk image, and not the ones after it.
+        let ops = ops.lock().unwrap();
+        assert_eq!(ops.last(), Some(&DiskOp::Flush));
+        let mut written = vec![0];
+        for op in ops[..ops.len() - 1].iter() {
+            match op {
+                DiskOp::Write(len) => *written.last_mut().unwrap() += *len,
+                DiskOp::Flush => written.push(0),
+            }
+        }
+        assert_eq!(written, vec![1536, 512]);
+
+        // Each flush is completed after the writes made available before it.
+        assert_eq!(guest_q.used.idx.get(), requests.len() as u16);
+        let used: Vec<u32> = (0..requests.len())
+            .map(|i| guest_q.used.ring[i].get().id)
+            .collect();
+        let used_position = |request: usize| {
+            used.iter()
+                .position(|id| *id == request as u32 * 3)
+                .unwrap()
+        };
+        for (flush, (request_type, _, _)) in requests.iter().enumerate() {
+            if *request_type != VIRTIO_BLK_T_FLUSH {
+                continue;
+            }
+            for write in 0..flush {
+                assert!(used_position(write) < used_position(flush));
+            }
+        }
+        for status in statuses.iter() {
+            assert_eq!(mem.read_obj::<u8>(*status).unwrap(), VIRTIO_BLK_S_OK as u8);
+        }
+    }
+
+    #[test]
+    fn test_block_read_only() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_q = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let disk = RecordingDisk::default();
+        let ops = disk.ops.clone();
+        let mut handler = epoll_handler(&mem, &guest_q, disk, true);
+
+        // A write and a discard fail, while a flush succeeds.
+        let statuses = [
+            queue_request(&mem, &guest_q, 0, VIRTIO_BLK_T_OUT, 0, 512),
+            queue_request(&mem, &guest_q, 1, VIRTIO_BLK_T_DISCARD, 0, 16),
+            queue_request(&mem, &guest_q, 2, VIRTIO_BLK_T_FLUSH, 0, 0),
+        ];
+        guest_q.avail.idx.set(3);
+        assert!(handler.process_queue());
+
+        assert_eq!(guest_q.used.idx.get(), 3);
+        let expected = [VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK];
+        for (status, expected) in statuses.iter().zip(expected.iter()) {
+            assert_eq!(mem.read_obj::<u8>(*status).unwrap(), *expected as u8);
+        }
+
+        // The disk image has been left untouched, not even flushed.
+        assert!(ops.lock().unwrap().is_empty());
+    }
+
+    fn rate_limited_handler(
+        mem: &GuestMemoryMmap,
+        guest_q: &GuestQ,
+        disk: RecordingDisk,
+        bandwidth: u64,
+        ops: u64,
+    ) -> BlockEpollHandler<RecordingDisk> {
+        let bucket = |size| {
+            Some(rate_limiter::TokenBucketConfig {
+                size,
+                refill_time: 60_000,
+            })
+        };
+        let mut handler = epoll_handler(mem, guest_q, disk, false);
+        handler.rate_limiter = Some(
+            RateLimiter::from_config(&RateLimiterConfig {
+                bandwidth: bucket(bandwidth),
+                ops: bucket(ops),
+            })
+            .unwrap(),
+        );
+        handler
+    }
+
+    #[test]
+    fn test_block_rate_limiter_bandwidth() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_q = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let disk = RecordingDisk::default();
+        let ops = disk.ops.clone();
+        let mut handler = rate_limited_handler(&mem, &guest_q, disk, 1024, 100);
+
+        // The third write exceeds the bandwidth, and waits in the queue
+        // along with the flush following it.
+        let statuses = [
+            queue_request(&mem, &guest_q, 0, VIRTIO_BLK_T_OUT, 0, 512),
+            queue_request(&mem, &guest_q, 1, VIRTIO_BLK_T_OUT, 1, 512),
+            queue_request(&mem, &guest_q, 2, VIRTIO_BLK_T_OUT, 2, 512),
+            queue_request(&mem, &guest_q, 3, VIRTIO_BLK_T_FLUSH, 0, 0),
+        ];
+        guest_q.avail.idx.set(4);
+        assert!(handler.process_queue());
+
+        assert_eq!(guest_q.used.idx.get(), 2);
+        assert_eq!(handler.queue.next_avail.0, 2);
+        assert!(handler.rate_limiter.as_ref().unwrap().is_blocked());
+        assert_eq!(mem.read_obj::<u8>(statuses[2]).unwrap(), 0xff);
+        assert_eq!(
+            *ops.lock().unwrap(),
+            vec![DiskOp::Write(512), DiskOp::Write(512)]
+        );
+
+        // Nothing goes through until the rate limiter timer expires.
+        assert!(!handler.process_queue());
+        assert_eq!(guest_q.used.idx.get(), 2);
+    }
+
+    #[test]
+    fn test_block_rate_limiter_ops() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_q = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let disk = RecordingDisk::default();
+        let ops = disk.ops.clone();
+        let mut handler = rate_limited_handler(&mem, &guest_q, disk, 1 << 20, 2);
+
+        // Requests carrying no data still count as operations.
+        let statuses = [
+            queue_request(&mem, &guest_q, 0, VIRTIO_BLK_T_FLUSH, 0, 0),
+            queue_request(&mem, &guest_q, 1, VIRTIO_BLK_T_OUT, 0, 512),
+            queue_request(&mem, &guest_q, 2, VIRTIO_BLK_T_FLUSH, 0, 0),
+        ];
+        guest_q.avail.idx.set(3);
+        assert!(handler.process_queue());
+
+        assert_eq!(guest_q.used.idx.get(), 2);
+        assert!(handler.rate_limiter.as_ref().unwrap().is_blocked());
+        assert_eq!(mem.read_obj::<u8>(statuses[2]).unwrap(), 0xff);
+        assert_eq!(
+            *ops.lock().unwrap(),
+            vec![DiskOp::Flush, DiskOp::Write(512)]
+        );
+    }
+
+    fn coalescing_handler(
+        mem: &GuestMemoryMmap,
+        guest_q: &GuestQ,
+        max_requests: u32,
+        max_usecs: u64,
+    ) -> (
+        BlockEpollHandler<RecordingDisk>,
+        Arc<CountingVirtioInterrupt>,
+    ) {
+        let interrupts = Arc::new(CountingVirtioInterrupt::default());
+        let mut handler = epoll_handler(mem, guest_q, RecordingDisk::default(), false);
+        handler.interrupt_cb = interrupts.clone();
+        handler.coalescer = InterruptCoalescer::new(max_requests, max_usecs).unwrap();
+        (handler, interrupts)
+    }
+
+    #[test]
+    fn test_block_coalescing_burst() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_q = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let (mut handler, interrupts) = coalescing_handler(&mem, &guest_q, 4, 20_000);
+
+        // The writes completed one by one get a single interrupt, once
+        // enough of them piled up.
+        for i in 0..4 {
+            queue_request(&mem, &guest_q, i, VIRTIO_BLK_T_OUT, u64::from(i), 512);
+            guest_q.avail.idx.set(i + 1);
+            assert!(handler.process_queue_and_signal().unwrap());
+            assert_eq!(guest_q.used.idx.get(), i + 1);
+            assert_eq!(interrupts.count(), if i < 3 { 0 } else { 1 });
+        }
+
+        // The next one waits for the delay to elapse.
+        queue_request(&mem, &guest_q, 4, VIRTIO_BLK_T_OUT, 4, 512);
+        guest_q.avail.idx.set(5);
+        assert!(handler.process_queue_and_signal().unwrap());
+        assert_eq!(interrupts.count(), 1);
+        handler.handle_coalescing_event().unwrap();
+        assert_eq!(interrupts.count(), 2);
+    }
+
+    #[test]
+    fn test_block_coalescing_flush() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_q = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let (mut handler, interrupts) = coalescing_handler(&mem, &guest_q, 0, 1_000_000);
+
+        // A write is delayed, until the flush following it is signalled
+        // right away.
+        queue_request(&mem, &guest_q, 0, VIRTIO_BLK_T_OUT, 0, 512);
+        guest_q.avail.idx.set(1);
+        assert!(handler.process_queue_and_signal().unwrap());
+        assert_eq!(interrupts.count(), 0);
+        queue_request(&mem, &guest_q, 1, VIRTIO_BLK_T_FLUSH, 0, 0);
+        guest_q.avail.idx.set(2);
+        assert!(handler.process_queue_and_signal().unwrap());
+        assert_eq!(interrupts.count(), 1);
+
+        // Likewise when completed along with the writes preceding it.
+        queue_request(&mem, &guest_q, 2, VIRTIO_BLK_T_OUT, 0, 512);
+        queue_request(&mem, &guest_q, 3, VIRTIO_BLK_T_FLUSH, 0, 0);
+        guest_q.avail.idx.set(4);
+        assert!(handler.process_queue_and_signal().unwrap());
+        assert_eq!(guest_q.used.idx.get(), 4);
+        assert_eq!(interrupts.count(), 2);
+    }
+
+    // Median latency of the flushes completed by a handler running in its
+    // own thread, each of them being made available and notified as a
+    // driver would, once the handler had the time to go back waiting.
+    fn completion_latency(busy_poll: Option<Duration>) -> Duration {
+        const REQUESTS: u16 = 64;
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_q = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let mut handler = epoll_handler(&mem, &guest_q, RecordingDisk::default(), false);
+        handler.busy_poll = busy_poll;
+        let queue_evt = handler.queue_evt.try_clone().unwrap();
+        let kill_evt = handler.kill_evt.try_clone().unwrap();
+        let ready = ReadyBarrier::new();
+        let thread_ready = ready.notifier();
+        let thread =
+            thread::spawn(move || handler.run(Arc::new(AtomicBool::new(false)), thread_ready));
+        assert!(ready.wait());
+
+        // The same descriptors are made available over and over.
+        queue_request(&mem, &guest_q, 0, VIRTIO_BLK_T_FLUSH, 0, 0);
+        let mut latencies = Vec::new();
+        for i in 0..REQUESTS {
+            thread::sleep(Duration::from_millis(1));
+
+            let start = Instant::now();
+            guest_q.avail.ring[usize::from(i % 16)].set(0);
+            guest_q.avail.idx.set(i + 1);
+            queue_evt.write(1).unwrap();
+            while guest_q.used.idx.get() != i + 1 {
+                assert!(start.elapsed() < Duration::from_secs(5));
+                thread::yield_now();
+            }
+            latencies.push(start.elapsed());
+        }
+
+        kill_evt.write(1).unwrap();
+        thread.join().unwrap().unwrap();
+        latencies.sort();
+        latencies[latencies.len() / 2]
+    }
+
+    #[test]
+    fn test_block_shutdown_flush() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_q = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let disk = RecordingDisk::default();
+        let ops = disk.ops.clone();
+        let mut block = Block::new(
+            "_disk0".to_string(),
+            disk,
+            PathBuf::from("/dev/null"),
+            false,
+            false,
+            1,
+            16,
+            Some("disk0".to_string()),
+            None,
+            None,
+            BlockCoalescing::default(),
+            SeccompAction::Allow,
+        )
+        .unwrap();
+        let queue_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        block
+            .activate(
+                GuestMemoryAtomic::new(mem.clone()),
+                Arc::new(NoopVirtioInterrupt {}),
+                vec![guest_q.create_queue()],
+                vec![queue_evt.try_clone().unwrap()],
+            )
+            .unwrap();
+
+        // The writes complete without being flushed.
+        queue_request(&mem, &guest_q, 0, VIRTIO_BLK_T_OUT, 0, 512);
+        queue_request(&mem, &guest_q, 1, VIRTIO_BLK_T_OUT, 4, 1024);
+        guest_q.avail.idx.set(2);
+        queue_evt.write(1).unwrap();
+        let start = Instant::now();
+        while guest_q.used.idx.get() != 2 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::yield_now();
+        }
+
+        // Shutting the device down flushes the disk image, once the epoll
+        // thread is over.
+        block.shutdown();
+        assert!(block.epoll_threads.is_none());
+        assert_eq!(
+            *ops.lock().unwrap(),
+            vec![DiskOp::Write(512), DiskOp::Write(1024), DiskOp::Flush]
+        );
+        drop(block);
+        assert_eq!(ops.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_block_shutdown_flush_timeout() {
+        let disk_image = Arc::new(Mutex::new(RecordingDisk::default()));
+        let ops = disk_image.lock().unwrap().ops.clone();
+
+        // A request still executing holds the disk image.
+        let executing = disk_image.lock().unwrap();
+        let e = flush_disk_image(Vec::new(), disk_image.clone(), Duration::from_millis(100))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(ops.lock().unwrap().is_empty());
+        drop(executing);
+
+        flush_disk_image(Vec::new(), disk_image, Duration::from_secs(5)).unwrap();
+        // The first flush went on in the background.
+        let start = Instant::now();
+        while ops.lock().unwrap().len() != 2 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_block_flush() {
+        for readonly in [false, true].iter() {
+            let disk = RecordingDisk::default();
+            let ops = disk.ops.clone();
+            let mut block = Block::new(
+                "_disk0".to_string(),
+                disk,
+                PathBuf::from("/dev/null"),
+                *readonly,
+                false,
+                1,
+                16,
+                Some("disk0".to_string()),
+                None,
+                None,
+                BlockCoalescing::default(),
+                SeccompAction::Allow,
+            )
+            .unwrap();
+
+            // Nothing is written to a read-only disk image.
+            block.flush().unwrap();
+            let expected = if *readonly {
+                Vec::new()
+            } else {
+                vec![DiskOp::Flush]
+            };
+            assert_eq!(*ops.lock().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_block_busy_poll_latency() {
+        // The polling thread would compete with the test for a single CPU.
+        // Safe because sysconf() doesn't access any memory.
+        if unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } < 2 {
+            return;
+        }
+
+        // The polling period outlasts the pauses between the requests, so
+        // that they are all picked up without waking the thread up.
+        let blocking = completion_latency(None);
+        let polling = completion_latency(Some(Duration::from_secs(1)));
+        assert!(
+            polling < blocking,
+            "Busy polling latency {:?}, blocking latency {:?}",
+            polling,
+            blocking
+        );
+    }
+}
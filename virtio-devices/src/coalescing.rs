@@ -0,0 +1,165 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Interrupt coalescing: rather than being notified about every descriptor
+//! a queue has used, the guest gets a single interrupt for the ones used
+//! within a delay, or as soon as enough of them piled up.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+use vmm_sys_util::errno::Result;
+use vmm_sys_util::timerfd::TimerFd;
+
+// Decides when a queue raises its used ring interrupt, merging the
+// notifications of the descriptors used within the coalescing delay.
+pub(crate) struct InterruptCoalescer {
+    max_completions: u32,
+    max_usecs: u64,
+    timer: Option<TimerFd>,
+    // The timer is never rearmed before it expires, so that reading it when
+    // its event is reported can't block.
+    timer_armed: bool,
+    pending: u32,
+    notify: bool,
+}
+
+impl InterruptCoalescer {
+    // Coalescing is disabled when `max_usecs` is 0, and `max_completions`
+    // being 0 means no limit on the descriptors a single interrupt covers.
+    pub(crate) fn new(max_completions: u32, max_usecs: u64) -> Result<Self> {
+        let timer = if max_usecs > 0 {
+            Some(TimerFd::new()?)
+        } else {
+            None
+        };
+
+        Ok(InterruptCoalescer {
+            max_completions,
+            max_usecs,
+            timer,
+            timer_armed: false,
+            pending: 0,
+            notify: false,
+        })
+    }
+
+    pub(crate) fn timer_fd(&self) -> Option<RawFd> {
+        self.timer.as_ref().map(|timer| timer.as_raw_fd())
+    }
+
+    // Whether some descriptors are waiting for the delay to elapse.
+    pub(crate) fn is_pending(&self) -> bool {
+        self.timer_armed
+    }
+
+    // Account for `used` descriptors added to the used ring, `notify` telling
+    // whether the guest asked to be notified about them. Returns whether the
+    // interrupt must be raised now.
+    pub(crate) fn completed(&mut self, used: u16, notify: bool) -> Result<bool> {
+        let timer = match &self.timer {
+            Some(timer) => timer,
+            None => return Ok(notify),
+        };
+
+        self.pending += u32::from(used);
+        self.notify |= notify;
+
+        if self.max_completions != 0 && self.pending >= self.max_completions {
+            return Ok(self.flush());
+        }
+
+        // A timer still running from a previous batch expires earlier than
+        // the delay, which keeps the latency bounded as well.
+        if self.notify && !self.timer_armed {
+            timer.reset(Duration::from_micros(self.max_usecs), None)?;
+            self.timer_armed = true;
+        }
+
+        Ok(false)
+    }
+
+    // The coalescing delay has elapsed. Returns whether the interrupt must be
+    // raised.
+    pub(crate) fn timer_expired(&mut self) -> Result<bool> {
+        if let Some(timer) = &self.timer {
+            timer.wait()?;
+        }
+        self.timer_armed = false;
+
+        Ok(self.flush())
+    }
+
+    // Stop delaying the descriptors used so far, the interrupt covering them
+    // being raised right away. Returns whether it must be, the guest having
+    // asked to be notified about some of them. The timer is left running, its
+    // expiry finding nothing to signal.
+    pub(crate) fn flush(&mut self) -> bool {
+        self.pending = 0;
+        std::mem::replace(&mut self.notify, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_coalescing_burst() {
+        // Without coalescing, the guest is notified as it asks.
+        let mut coalescer = InterruptCoalescer::new(0, 0).unwrap();
+        assert!(coalescer.timer_fd().is_none());
+        assert!(coalescer.completed(1, true).unwrap());
+        assert!(!coalescer.completed(1, false).unwrap());
+
+        let mut coalescer = InterruptCoalescer::new(8, 1_000_000).unwrap();
+
+        // A burst gets a single interrupt once enough descriptors are used.
+        for _ in 0..7 {
+            assert!(!coalescer.completed(1, true).unwrap());
+        }
+        assert!(coalescer.completed(1, true).unwrap());
+        assert!(!coalescer.completed(4, true).unwrap());
+        assert!(coalescer.completed(4, true).unwrap());
+
+        // Descriptors the guest doesn't want to hear about don't trigger
+        // any interrupt, even when the limit is reached.
+        assert!(!coalescer.completed(8, false).unwrap());
+    }
+
+    #[test]
+    fn test_coalescing_idle() {
+        let max_usecs = 20_000;
+        let mut coalescer = InterruptCoalescer::new(64, max_usecs).unwrap();
+
+        // A single descriptor is signalled once the delay has elapsed.
+        let start = Instant::now();
+        assert!(!coalescer.completed(1, true).unwrap());
+        assert!(coalescer.timer_expired().unwrap());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_micros(max_usecs));
+        assert!(elapsed < Duration::from_micros(max_usecs) + Duration::from_millis(500));
+
+        // Descriptors used while the timer runs are not delayed any longer.
+        let start = Instant::now();
+        assert!(!coalescer.completed(1, true).unwrap());
+        thread::sleep(Duration::from_micros(max_usecs / 2));
+        assert!(!coalescer.completed(1, true).unwrap());
+        assert!(coalescer.timer_expired().unwrap());
+        let elapsed = start.elapsed();
+        assert!(elapsed < Duration::from_micros(max_usecs) + Duration::from_millis(500));
+
+        // Nothing is signalled when the guest doesn't want to hear about the
+        // descriptors, and the timer doesn't even get armed.
+        assert!(!coalescer.completed(1, false).unwrap());
+        assert!(!coalescer.is_pending());
+
+        // Flushing signals what's pending at once, the timer then expiring
+        // without anything left to signal.
+        assert!(!coalescer.completed(1, true).unwrap());
+        assert!(coalescer.flush());
+        assert!(!coalescer.timer_expired().unwrap());
+    }
+}
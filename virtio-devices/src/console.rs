@@ -11,10 +11,11 @@ use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use std::cmp;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::File;
 use std::io;
 use std::io::Write;
+use std::mem::size_of;
 use std::ops::DerefMut;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::result;
@@ -29,9 +30,16 @@ use vm_migration::{
 use vmm_sys_util::eventfd::EventFd;
 
 const QUEUE_SIZE: u16 = 256;
-const NUM_QUEUES: usize = 2;
+/// Maximum number of ports the driver can use when multiport is negotiated.
+pub const VIRTIO_CONSOLE_MAX_PORTS: u32 = 4;
+// Receive and transmit queues for each port, plus the control queues.
+const NUM_QUEUES: usize = 2 * (VIRTIO_CONSOLE_MAX_PORTS as usize + 1);
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
 
+// The control queues come right after the queues of the first port.
+const CONTROL_RX_QUEUE: usize = 2;
+const CONTROL_TX_QUEUE: usize = 3;
+
 // New descriptors are pending on the virtio queue.
 const INPUT_QUEUE_EVENT: DeviceEventT = 0;
 const OUTPUT_QUEUE_EVENT: DeviceEventT = 1;
@@ -43,9 +51,26 @@ const KILL_EVENT: DeviceEventT = 3;
 const CONFIG_EVENT: DeviceEventT = 4;
 // The device should be paused.
 const PAUSE_EVENT: DeviceEventT = 5;
+// Some control messages from the VMM are ready to be sent to the driver.
+const CONTROL_EVENT: DeviceEventT = 6;
+// New descriptors are pending on the control queues or on the queues of the
+// additional ports. The queue index is added to this base.
+const QUEUE_EVENT_BASE: DeviceEventT = 7;
 
 //Console size feature bit
 const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
+//Multiple ports feature bit
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1;
+
+// Control message events, got from include/uapi/linux/virtio_console.h
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
 
 #[derive(Copy, Clone, Debug, Default, Deserialize)]
 #[repr(C, packed)]
@@ -83,34 +108,189 @@ impl Serialize for VirtioConsoleConfig {
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioConsoleConfig {}
 
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioConsoleControl {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioConsoleControl {}
+
+// Payload of the VIRTIO_CONSOLE_RESIZE control message, laid out the way
+// the Linux driver reads it.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioConsoleResize {
+    rows: u16,
+    cols: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioConsoleResize {}
+
+fn control_message(id: u32, event: u16, value: u16) -> Vec<u8> {
+    VirtioConsoleControl { id, event, value }
+        .as_slice()
+        .to_vec()
+}
+
+// The port name follows the control header, the driver exposes it through
+// /dev/virtio-ports/<name>.
+fn name_message(id: u32, name: &str) -> Vec<u8> {
+    let mut msg = control_message(id, VIRTIO_CONSOLE_PORT_NAME, 1);
+    msg.extend_from_slice(name.as_bytes());
+    msg
+}
+
+fn resize_message(id: u32, cols: u16, rows: u16) -> Vec<u8> {
+    let mut msg = control_message(id, VIRTIO_CONSOLE_RESIZE, 0);
+    msg.extend_from_slice(VirtioConsoleResize { rows, cols }.as_slice());
+    msg
+}
+
+// Receive queue of a port, followed by its transmit queue.
+fn port_rx_queue(port: u32) -> usize {
+    if port == 0 {
+        0
+    } else {
+        2 * (port as usize + 1)
+    }
+}
+
+struct ConsolePort {
+    name: Option<String>,
+    in_buffer: Arc<Mutex<VecDeque<u8>>>,
+    out: Arc<Mutex<Box<dyn io::Write + Send + Sync + 'static>>>,
+    cols: u16,
+    rows: u16,
+    // The driver has set the port up.
+    ready: bool,
+    // The port has been opened from the guest.
+    guest_connected: bool,
+}
+
+// Ports of the device, shared between the device, its epoll thread and the
+// console inputs.
+#[derive(Default)]
+struct ConsolePorts {
+    ports: BTreeMap<u32, ConsolePort>,
+    // The driver negotiated multiport and is ready for control messages.
+    driver_ready: bool,
+    // Control messages waiting for the driver to provide some buffers.
+    control_out: VecDeque<Vec<u8>>,
+}
+
+impl ConsolePorts {
+    fn reset(&mut self) {
+        self.driver_ready = false;
+        self.control_out.clear();
+        for port in self.ports.values_mut() {
+            port.ready = false;
+            port.guest_connected = false;
+        }
+    }
+
+    fn handle_control_message(&mut self, ctrl: VirtioConsoleControl) {
+        let id = ctrl.id;
+        let event = ctrl.event;
+        let value = ctrl.value;
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY => {
+                if value != 1 {
+                    error!("Console driver failed to initialize");
+                    return;
+                }
+                self.driver_ready = true;
+                let ids: Vec<u32> = self.ports.keys().cloned().collect();
+                for id in ids {
+                    self.control_out
+                        .push_back(control_message(id, VIRTIO_CONSOLE_DEVICE_ADD, 0));
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY => {
+                let port = match self.ports.get_mut(&id) {
+                    Some(port) => port,
+                    None => {
+                        warn!("Console driver set up unknown port {}", id);
+                        return;
+                    }
+                };
+                if value != 1 {
+                    error!("Console driver failed to set up port {}", id);
+                    return;
+                }
+                port.ready = true;
+                let (cols, rows) = (port.cols, port.rows);
+                let name = port.name.clone();
+
+                // The first port is always used as the console.
+                if id == 0 {
+                    self.control_out
+                        .push_back(control_message(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1));
+                    self.control_out.push_back(resize_message(id, cols, rows));
+                }
+                if let Some(name) = name {
+                    self.control_out.push_back(name_message(id, &name));
+                }
+                // The host side of the ports is always connected.
+                self.control_out
+                    .push_back(control_message(id, VIRTIO_CONSOLE_PORT_OPEN, 1));
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                if let Some(port) = self.ports.get_mut(&id) {
+                    port.guest_connected = value == 1;
+                    debug!("Console port {} guest connected: {}", id, value == 1);
+                } else {
+                    warn!("Console driver opened unknown port {}", id);
+                }
+            }
+            event => warn!("Unexpected console control event {} for port {}", event, id),
+        }
+    }
+}
+
 struct ConsoleEpollHandler {
     queues: Vec<Queue>,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
-    in_buffer: Arc<Mutex<VecDeque<u8>>>,
-    out: Arc<Mutex<Box<dyn io::Write + Send + Sync + 'static>>>,
-    input_queue_evt: EventFd,
-    output_queue_evt: EventFd,
+    ports: Arc<Mutex<ConsolePorts>>,
+    queue_evts: Vec<EventFd>,
     input_evt: EventFd,
     config_evt: EventFd,
+    control_evt: EventFd,
     kill_evt: EventFd,
     pause_evt: EventFd,
 }
 
 impl ConsoleEpollHandler {
+    fn queue_event(queue_index: usize) -> DeviceEventT {
+        match queue_index {
+            0 => INPUT_QUEUE_EVENT,
+            1 => OUTPUT_QUEUE_EVENT,
+            i => QUEUE_EVENT_BASE + i as DeviceEventT,
+        }
+    }
+
     /*
      * Each port of virtio console device has one receive
      * queue. One or more empty buffers are placed by the
      * dirver in the receive queue for incoming data. Here,
      * we place the input data to these empty buffers.
      */
-    fn process_input_queue(&mut self) -> bool {
-        let mut in_buffer = self.in_buffer.lock().unwrap();
-        let recv_queue = &mut self.queues[0]; //receiveq
+    fn process_input_queue(&mut self, port: u32) -> bool {
+        let in_buffer = match self.ports.lock().unwrap().ports.get(&port) {
+            Some(port) => port.in_buffer.clone(),
+            None => return false,
+        };
+        let mut in_buffer = in_buffer.lock().unwrap();
+        let recv_queue = &mut self.queues[port_rx_queue(port)]; //receiveq
         let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
         let mut used_count = 0;
 
-        if in_buffer.is_empty() {
+        if in_buffer.is_empty() || !recv_queue.ready {
             return false;
         }
 
@@ -146,15 +326,19 @@ impl ConsoleEpollHandler {
      * we read data from the transmit queue and flush them
      * to the referenced address.
      */
-    fn process_output_queue(&mut self) -> bool {
-        let trans_queue = &mut self.queues[1]; //transmitq
+    fn process_output_queue(&mut self, port: u32) -> bool {
+        let out = match self.ports.lock().unwrap().ports.get(&port) {
+            Some(port) => port.out.clone(),
+            None => return false,
+        };
+        let trans_queue = &mut self.queues[port_rx_queue(port) + 1]; //transmitq
         let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
         let mut used_count = 0;
 
         let mem = self.mem.memory();
         for avail_desc in trans_queue.iter(&mem) {
             let len;
-            let mut out = self.out.lock().unwrap();
+            let mut out = out.lock().unwrap();
             let _ = mem.write_to(
                 avail_desc.addr,
                 &mut out.deref_mut(),
@@ -173,15 +357,125 @@ impl ConsoleEpollHandler {
         used_count > 0
     }
 
-    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+    // Handle the control messages sent by the driver.
+    fn process_control_queue(&mut self) -> bool {
+        let mut ports = self.ports.lock().unwrap();
+        let ctrl_queue = &mut self.queues[CONTROL_TX_QUEUE];
+        let mut used_desc_heads = [0; QUEUE_SIZE as usize];
+        let mut used_count = 0;
+
+        let mem = self.mem.memory();
+        for avail_desc in ctrl_queue.iter(&mem) {
+            used_desc_heads[used_count] = avail_desc.index;
+            used_count += 1;
+
+            if (avail_desc.len as usize) < size_of::<VirtioConsoleControl>() {
+                error!("Console control message too short: {}", avail_desc.len);
+                continue;
+            }
+            match mem.read_obj::<VirtioConsoleControl>(avail_desc.addr) {
+                Ok(ctrl) => ports.handle_control_message(ctrl),
+                Err(e) => error!("Failed to read console control message: {:?}", e),
+            }
+        }
+
+        for &desc_index in &used_desc_heads[..used_count] {
+            ctrl_queue.add_used(&mem, desc_index, 0);
+        }
+        used_count > 0
+    }
+
+    // Send the pending control messages to the driver.
+    fn process_control_rx_queue(&mut self) -> bool {
+        let mut ports = self.ports.lock().unwrap();
+        let ctrl_queue = &mut self.queues[CONTROL_RX_QUEUE];
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+
+        if ports.control_out.is_empty() || !ctrl_queue.ready {
+            return false;
+        }
+
+        let mem = self.mem.memory();
+        for avail_desc in ctrl_queue.iter(&mem) {
+            let msg = match ports.control_out.pop_front() {
+                Some(msg) => msg,
+                None => {
+                    ctrl_queue.go_to_previous_position();
+                    break;
+                }
+            };
+            if (avail_desc.len as usize) < msg.len() {
+                error!("Console control buffer too small: {}", avail_desc.len);
+                ports.control_out.push_front(msg);
+                ctrl_queue.go_to_previous_position();
+                break;
+            }
+            if let Err(e) = mem.write_slice(&msg, avail_desc.addr) {
+                error!("Failed to write console control message: {:?}", e);
+                ports.control_out.push_front(msg);
+                ctrl_queue.go_to_previous_position();
+                break;
+            }
+
+            used_desc_heads[used_count] = (avail_desc.index, msg.len() as u32);
+            used_count += 1;
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            ctrl_queue.add_used(&mem, desc_index, len);
+        }
+        used_count > 0
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
         self.interrupt_cb
-            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[0]))
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
             .map_err(|e| {
                 error!("Failed to signal used queue: {:?}", e);
                 DeviceError::FailedSignalingUsedQueue(e)
             })
     }
 
+    fn process_control(&mut self) -> result::Result<(), DeviceError> {
+        if self.process_control_queue() {
+            self.signal_used_queue(CONTROL_TX_QUEUE)?;
+        }
+        if self.process_control_rx_queue() {
+            self.signal_used_queue(CONTROL_RX_QUEUE)?;
+        }
+
+        Ok(())
+    }
+
+    fn process_input(&mut self) -> result::Result<(), DeviceError> {
+        let ports: Vec<u32> = self.ports.lock().unwrap().ports.keys().cloned().collect();
+        for port in ports {
+            if self.process_input_queue(port) {
+                self.signal_used_queue(port_rx_queue(port))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_queue_event(&mut self, queue_index: usize) -> result::Result<(), DeviceError> {
+        match queue_index {
+            CONTROL_RX_QUEUE | CONTROL_TX_QUEUE => self.process_control(),
+            i => {
+                let port = (i / 2 - 1) as u32;
+                if i % 2 == 0 {
+                    if self.process_input_queue(port) {
+                        self.signal_used_queue(i)?;
+                    }
+                } else {
+                    self.process_output_queue(port);
+                }
+                Ok(())
+            }
+        }
+    }
+
     fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), DeviceError> {
         // Create the epoll file descriptor
         let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
@@ -189,32 +483,34 @@ impl ConsoleEpollHandler {
         let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
 
         // Add events
+        for (i, queue_evt) in self.queue_evts.iter().enumerate() {
+            epoll::ctl(
+                epoll_file.as_raw_fd(),
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                queue_evt.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(Self::queue_event(i))),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
         epoll::ctl(
             epoll_file.as_raw_fd(),
             epoll::ControlOptions::EPOLL_CTL_ADD,
-            self.input_queue_evt.as_raw_fd(),
-            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(INPUT_QUEUE_EVENT)),
-        )
-        .map_err(DeviceError::EpollCtl)?;
-        epoll::ctl(
-            epoll_file.as_raw_fd(),
-            epoll::ControlOptions::EPOLL_CTL_ADD,
-            self.output_queue_evt.as_raw_fd(),
-            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(OUTPUT_QUEUE_EVENT)),
+            self.input_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(INPUT_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
         epoll::ctl(
             epoll_file.as_raw_fd(),
             epoll::ControlOptions::EPOLL_CTL_ADD,
-            self.input_evt.as_raw_fd(),
-            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(INPUT_EVENT)),
+            self.config_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(CONFIG_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
         epoll::ctl(
             epoll_file.as_raw_fd(),
             epoll::ControlOptions::EPOLL_CTL_ADD,
-            self.config_evt.as_raw_fd(),
-            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(CONFIG_EVENT)),
+            self.control_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(CONTROL_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
 
@@ -267,33 +563,31 @@ impl ConsoleEpollHandler {
 
                 match ev_type {
                     INPUT_QUEUE_EVENT => {
-                        if let Err(e) = self.input_queue_evt.read() {
+                        if let Err(e) = self.queue_evts[0].read() {
                             error!("Failed to get queue event: {:?}", e);
                             break 'epoll;
-                        } else if self.process_input_queue() {
-                            if let Err(e) = self.signal_used_queue() {
+                        } else if self.process_input_queue(0) {
+                            if let Err(e) = self.signal_used_queue(0) {
                                 error!("Failed to signal used queue: {:?}", e);
                                 break 'epoll;
                             }
                         }
                     }
                     OUTPUT_QUEUE_EVENT => {
-                        if let Err(e) = self.output_queue_evt.read() {
+                        if let Err(e) = self.queue_evts[1].read() {
                             error!("Failed to get queue event: {:?}", e);
                             break 'epoll;
                         } else {
-                            self.process_output_queue();
+                            self.process_output_queue(0);
                         }
                     }
                     INPUT_EVENT => {
                         if let Err(e) = self.input_evt.read() {
                             error!("Failed to get input event: {:?}", e);
                             break 'epoll;
-                        } else if self.process_input_queue() {
-                            if let Err(e) = self.signal_used_queue() {
-                                error!("Failed to signal used queue: {:?}", e);
-                                break 'epoll;
-                            }
+                        } else if let Err(e) = self.process_input() {
+                            error!("Failed to signal used queue: {:?}", e);
+                            break 'epoll;
                         }
                     }
                     CONFIG_EVENT => {
@@ -307,6 +601,15 @@ impl ConsoleEpollHandler {
                             error!("Failed to signal console driver: {:?}", e);
                         }
                     }
+                    CONTROL_EVENT => {
+                        if let Err(e) = self.control_evt.read() {
+                            error!("Failed to get control event: {:?}", e);
+                            break 'epoll;
+                        } else if let Err(e) = self.process_control() {
+                            error!("Failed to signal used queue: {:?}", e);
+                            break 'epoll;
+                        }
+                    }
 
                     KILL_EVENT => {
                         debug!("KILL_EVENT received, stopping epoll loop");
@@ -326,6 +629,18 @@ impl ConsoleEpollHandler {
                         // and every thread related to this virtio device.
                         let _ = self.pause_evt.read();
                     }
+                    ev if ev >= QUEUE_EVENT_BASE
+                        && ((ev - QUEUE_EVENT_BASE) as usize) < self.queue_evts.len() =>
+                    {
+                        let queue_index = (ev - QUEUE_EVENT_BASE) as usize;
+                        if let Err(e) = self.queue_evts[queue_index].read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        } else if let Err(e) = self.handle_queue_event(queue_index) {
+                            error!("Failed to signal used queue: {:?}", e);
+                            break 'epoll;
+                        }
+                    }
                     _ => {
                         error!("Unknown event for virtio-console");
                     }
@@ -339,11 +654,14 @@ impl ConsoleEpollHandler {
 
 /// Input device.
 pub struct ConsoleInput {
+    port: u32,
     input_evt: EventFd,
     config_evt: EventFd,
+    control_evt: EventFd,
     in_buffer: Arc<Mutex<VecDeque<u8>>>,
     config: Arc<Mutex<VirtioConsoleConfig>>,
-    acked_features: AtomicU64,
+    ports: Arc<Mutex<ConsolePorts>>,
+    acked_features: Arc<AtomicU64>,
 }
 
 impl ConsoleInput {
@@ -354,16 +672,43 @@ impl ConsoleInput {
     }
 
     pub fn update_console_size(&self, cols: u16, rows: u16) {
-        if self
-            .acked_features
-            .fetch_and(1u64 << VIRTIO_CONSOLE_F_SIZE, Ordering::SeqCst)
-            != 0
-        {
+        let acked_features = self.acked_features.load(Ordering::SeqCst);
+
+        let mut ports = self.ports.lock().unwrap();
+        let ready = match ports.ports.get_mut(&self.port) {
+            Some(port) => {
+                port.cols = cols;
+                port.rows = rows;
+                port.ready
+            }
+            None => return,
+        };
+
+        // The configuration space always reflects the size of the console,
+        // even when the driver doesn't read it.
+        if self.port == 0 {
             self.config.lock().unwrap().update_console_size(cols, rows);
+        }
+
+        if acked_features & (1u64 << VIRTIO_CONSOLE_F_MULTIPORT) != 0 {
+            // The driver only relies on control messages to know about the
+            // size of each port.
+            if ready {
+                ports
+                    .control_out
+                    .push_back(resize_message(self.port, cols, rows));
+                let _ = self.control_evt.write(1);
+            }
+        } else if self.port == 0 && acked_features & (1u64 << VIRTIO_CONSOLE_F_SIZE) != 0 {
             //Send the interrupt to the driver
             let _ = self.config_evt.write(1);
         }
     }
+
+    /// Identifier of the port this input is bound to.
+    pub fn port(&self) -> u32 {
+        self.port
+    }
 }
 
 impl VirtioConsoleConfig {
@@ -371,7 +716,7 @@ impl VirtioConsoleConfig {
         VirtioConsoleConfig {
             cols,
             rows,
-            max_nr_ports: 1u32,
+            max_nr_ports: VIRTIO_CONSOLE_MAX_PORTS,
             emerg_wr: 0u32,
         }
     }
@@ -391,7 +736,7 @@ pub struct Console {
     acked_features: u64,
     config: Arc<Mutex<VirtioConsoleConfig>>,
     input: Arc<ConsoleInput>,
-    out: Arc<Mutex<Box<dyn io::Write + Send + Sync + 'static>>>,
+    ports: Arc<Mutex<ConsolePorts>>,
     queue_evts: Option<Vec<EventFd>>,
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
@@ -415,7 +760,9 @@ impl Console {
         rows: u16,
         iommu: bool,
     ) -> io::Result<(Console, Arc<ConsoleInput>)> {
-        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_CONSOLE_F_SIZE;
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1
+            | 1u64 << VIRTIO_CONSOLE_F_SIZE
+            | 1u64 << VIRTIO_CONSOLE_F_MULTIPORT;
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -423,13 +770,35 @@ impl Console {
 
         let input_evt = EventFd::new(EFD_NONBLOCK).unwrap();
         let config_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let control_evt = EventFd::new(EFD_NONBLOCK).unwrap();
         let console_config = Arc::new(Mutex::new(VirtioConsoleConfig::new(cols, rows)));
+        let in_buffer = Arc::new(Mutex::new(VecDeque::new()));
+
+        // The first port always exists, and is used as the console.
+        let mut ports = ConsolePorts::default();
+        ports.ports.insert(
+            0,
+            ConsolePort {
+                name: None,
+                in_buffer: in_buffer.clone(),
+                out: Arc::new(Mutex::new(out)),
+                cols,
+                rows,
+                ready: false,
+                guest_connected: false,
+            },
+        );
+        let ports = Arc::new(Mutex::new(ports));
+
         let console_input = Arc::new(ConsoleInput {
+            port: 0,
             input_evt,
             config_evt,
-            in_buffer: Arc::new(Mutex::new(VecDeque::new())),
+            control_evt,
+            in_buffer,
             config: console_config.clone(),
-            acked_features: AtomicU64::new(0),
+            ports: ports.clone(),
+            acked_features: Arc::new(AtomicU64::new(0)),
         });
 
         Ok((
@@ -441,7 +810,7 @@ impl Console {
                 acked_features: 0u64,
                 config: console_config,
                 input: console_input.clone(),
-                out: Arc::new(Mutex::new(out)),
+                ports,
                 queue_evts: None,
                 interrupt_cb: None,
                 epoll_threads: None,
@@ -451,6 +820,94 @@ impl Console {
         ))
     }
 
+    /// Add a port to the device, writing the guest output to `out`. The
+    /// returned input is used to inject data into the port. Additional
+    /// ports are only usable if the driver negotiated multiport support.
+    /// A named port shows up as /dev/virtio-ports/<name> in the guest.
+    pub fn add_port(
+        &self,
+        name: Option<String>,
+        out: Box<dyn io::Write + Send + Sync + 'static>,
+    ) -> io::Result<Arc<ConsoleInput>> {
+        let mut ports = self.ports.lock().unwrap();
+        if let Some(name) = &name {
+            if ports.ports.values().any(|p| p.name.as_ref() == Some(name)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("Console port {} already exists", name),
+                ));
+            }
+        }
+        let id = (1..VIRTIO_CONSOLE_MAX_PORTS)
+            .find(|id| !ports.ports.contains_key(id))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No console port available"))?;
+
+        let in_buffer = Arc::new(Mutex::new(VecDeque::new()));
+        let (cols, rows) = {
+            let config = self.config.lock().unwrap();
+            (config.cols, config.rows)
+        };
+        ports.ports.insert(
+            id,
+            ConsolePort {
+                name,
+                in_buffer: in_buffer.clone(),
+                out: Arc::new(Mutex::new(out)),
+                cols,
+                rows,
+                ready: false,
+                guest_connected: false,
+            },
+        );
+
+        // Let the driver know about the new port if it already went through
+        // the initial port discovery.
+        if ports.driver_ready {
+            ports
+                .control_out
+                .push_back(control_message(id, VIRTIO_CONSOLE_DEVICE_ADD, 0));
+            let _ = self.input.control_evt.write(1);
+        }
+
+        Ok(Arc::new(ConsoleInput {
+            port: id,
+            input_evt: self.input.input_evt.try_clone()?,
+            config_evt: self.input.config_evt.try_clone()?,
+            control_evt: self.input.control_evt.try_clone()?,
+            in_buffer,
+            config: self.config.clone(),
+            ports: self.ports.clone(),
+            acked_features: self.input.acked_features.clone(),
+        }))
+    }
+
+    /// Remove a port previously added with `add_port()`.
+    pub fn remove_port(&self, id: u32) -> io::Result<()> {
+        if id == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The console port can't be removed",
+            ));
+        }
+
+        let mut ports = self.ports.lock().unwrap();
+        if ports.ports.remove(&id).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Unknown console port {}", id),
+            ));
+        }
+
+        if ports.driver_ready {
+            ports
+                .control_out
+                .push_back(control_message(id, VIRTIO_CONSOLE_DEVICE_REMOVE, 0));
+            let _ = self.input.control_evt.write(1);
+        }
+
+        Ok(())
+    }
+
     fn state(&self) -> ConsoleState {
         ConsoleState {
             avail_features: self.avail_features,
@@ -514,7 +971,7 @@ impl VirtioDevice for Console {
         mem: GuestMemoryAtomic<GuestMemoryMmap>,
         interrupt_cb: Arc<dyn VirtioInterrupt>,
         queues: Vec<Queue>,
-        mut queue_evts: Vec<EventFd>,
+        queue_evts: Vec<EventFd>,
     ) -> ActivateResult {
         if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
             error!(
@@ -561,6 +1018,9 @@ impl VirtioDevice for Console {
             .acked_features
             .store(self.acked_features, Ordering::Relaxed);
 
+        // The driver goes through the port discovery again.
+        self.ports.lock().unwrap().reset();
+
         if (self.acked_features & (1u64 << VIRTIO_CONSOLE_F_SIZE)) != 0 {
             if let Err(e) = interrupt_cb.trigger(&VirtioInterruptType::Config, None) {
                 error!("Failed to signal console driver: {:?}", e);
@@ -571,12 +1031,11 @@ impl VirtioDevice for Console {
             queues,
             mem,
             interrupt_cb,
-            in_buffer: self.input.in_buffer.clone(),
-            out: self.out.clone(),
-            input_queue_evt: queue_evts.remove(0),
-            output_queue_evt: queue_evts.remove(0),
+            ports: self.ports.clone(),
+            queue_evts,
             input_evt: self.input.input_evt.try_clone().unwrap(),
             config_evt: self.input.config_evt.try_clone().unwrap(),
+            control_evt: self.input.control_evt.try_clone().unwrap(),
             kill_evt,
             pause_evt,
         };
@@ -659,3 +1118,316 @@ impl Snapshottable for Console {
 }
 impl Transportable for Console {}
 impl Migratable for Console {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::NoopVirtioInterrupt;
+    use vm_memory::{Address, GuestAddress};
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::VIRTQ_DESC_F_WRITE;
+
+    const MEM_SIZE: usize = 0x10_0000;
+    const CONTROL_MSG_SIZE: u32 = 12;
+
+    #[derive(Clone, Default)]
+    struct TestOutput {
+        data: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl io::Write for TestOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn create_handler(
+        console: &Console,
+        mem: &GuestMemoryMmap,
+        queues: Vec<Queue>,
+    ) -> ConsoleEpollHandler {
+        ConsoleEpollHandler {
+            queues,
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            ports: console.ports.clone(),
+            queue_evts: Vec::new(),
+            input_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            config_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            control_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+        }
+    }
+
+    // Have the driver send some control messages to the device.
+    fn send_control(
+        mem: &GuestMemoryMmap,
+        queue: &GuestQ,
+        addr: GuestAddress,
+        msgs: &[(u32, u16, u16)],
+    ) {
+        let avail_idx = queue.avail.idx.get();
+        for (i, &(id, event, value)) in msgs.iter().enumerate() {
+            let slot = avail_idx as usize + i;
+            let msg_addr = addr.unchecked_add(slot as u64 * 8);
+            mem.write_obj(VirtioConsoleControl { id, event, value }, msg_addr)
+                .unwrap();
+            queue.dtable[slot].set(msg_addr.raw_value(), 8, 0, 0);
+            queue.avail.ring[slot].set(slot as u16);
+        }
+        queue.avail.idx.set(avail_idx + msgs.len() as u16);
+    }
+
+    // Collect the control messages the device sent to the driver, along
+    // with the resize payload if any.
+    fn received_control(
+        mem: &GuestMemoryMmap,
+        queue: &GuestQ,
+        addr: GuestAddress,
+        from: u16,
+    ) -> Vec<(u32, u16, u16, Option<(u16, u16)>)> {
+        (from..queue.used.idx.get())
+            .map(|i| {
+                let used = queue.used.ring[i as usize].get();
+                let msg_addr = addr.unchecked_add(u64::from(used.id * CONTROL_MSG_SIZE));
+                let ctrl: VirtioConsoleControl = mem.read_obj(msg_addr).unwrap();
+                let size = if ctrl.event == VIRTIO_CONSOLE_RESIZE {
+                    let resize: VirtioConsoleResize =
+                        mem.read_obj(msg_addr.unchecked_add(8)).unwrap();
+                    Some((resize.cols, resize.rows))
+                } else {
+                    None
+                };
+                (ctrl.id, ctrl.event, ctrl.value, size)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_console_multiport_resize() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_ctrl_rxq = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let guest_ctrl_txq = GuestQ::new(GuestAddress(0x2_0000), &mem, 16);
+        let guest_port1_txq = GuestQ::new(GuestAddress(0x3_0000), &mem, 16);
+        let ctrl_rx_addr = GuestAddress(0x4_0000);
+        let ctrl_tx_addr = GuestAddress(0x5_0000);
+
+        let (console, _) =
+            Console::new(String::from("console"), Box::new(io::sink()), 80, 25, false).unwrap();
+        let output = TestOutput::default();
+        let port1 = console.add_port(None, Box::new(output.clone())).unwrap();
+        assert_eq!(port1.port(), 1);
+        port1
+            .acked_features
+            .store(1u64 << VIRTIO_CONSOLE_F_MULTIPORT, Ordering::SeqCst);
+
+        // Buffers for the device to send control messages.
+        for i in 0..16u16 {
+            let addr = ctrl_rx_addr.unchecked_add(u64::from(u32::from(i) * CONTROL_MSG_SIZE));
+            guest_ctrl_rxq.dtable[i as usize].set(
+                addr.raw_value(),
+                CONTROL_MSG_SIZE,
+                VIRTQ_DESC_F_WRITE,
+                0,
+            );
+            guest_ctrl_rxq.avail.ring[i as usize].set(i);
+        }
+        guest_ctrl_rxq.avail.idx.set(16);
+
+        let mut queues: Vec<Queue> = (0..NUM_QUEUES).map(|_| Queue::new(16)).collect();
+        queues[CONTROL_RX_QUEUE] = guest_ctrl_rxq.create_queue();
+        queues[CONTROL_TX_QUEUE] = guest_ctrl_txq.create_queue();
+        queues[port_rx_queue(1) + 1] = guest_port1_txq.create_queue();
+        let mut handler = create_handler(&console, &mem, queues);
+
+        // The driver is ready, both ports must be announced.
+        send_control(
+            &mem,
+            &guest_ctrl_txq,
+            ctrl_tx_addr,
+            &[(0, VIRTIO_CONSOLE_DEVICE_READY, 1)],
+        );
+        handler.process_control().unwrap();
+        assert_eq!(
+            received_control(&mem, &guest_ctrl_rxq, ctrl_rx_addr, 0),
+            vec![
+                (0, VIRTIO_CONSOLE_DEVICE_ADD, 0, None),
+                (1, VIRTIO_CONSOLE_DEVICE_ADD, 0, None),
+            ]
+        );
+
+        // Both ports are set up and the guest opens the second one.
+        send_control(
+            &mem,
+            &guest_ctrl_txq,
+            ctrl_tx_addr,
+            &[
+                (0, VIRTIO_CONSOLE_PORT_READY, 1),
+                (1, VIRTIO_CONSOLE_PORT_READY, 1),
+                (1, VIRTIO_CONSOLE_PORT_OPEN, 1),
+            ],
+        );
+        handler.process_control().unwrap();
+        assert_eq!(guest_ctrl_txq.used.idx.get(), 4);
+        assert_eq!(
+            received_control(&mem, &guest_ctrl_rxq, ctrl_rx_addr, 2),
+            vec![
+                (0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, None),
+                (0, VIRTIO_CONSOLE_RESIZE, 0, Some((80, 25))),
+                (0, VIRTIO_CONSOLE_PORT_OPEN, 1, None),
+                (1, VIRTIO_CONSOLE_PORT_OPEN, 1, None),
+            ]
+        );
+        assert!(console.ports.lock().unwrap().ports[&1].guest_connected);
+        assert!(!console.ports.lock().unwrap().ports[&0].guest_connected);
+
+        // Resizing the second port only notifies the driver about it.
+        port1.update_console_size(120, 40);
+        handler.process_control().unwrap();
+        assert_eq!(
+            received_control(&mem, &guest_ctrl_rxq, ctrl_rx_addr, 6),
+            vec![(1, VIRTIO_CONSOLE_RESIZE, 0, Some((120, 40)))]
+        );
+
+        // Each port has its own output.
+        let data_addr = GuestAddress(0x6_0000);
+        mem.write_slice(b"port1", data_addr).unwrap();
+        guest_port1_txq.dtable[0].set(data_addr.raw_value(), 5, 0, 0);
+        guest_port1_txq.avail.ring[0].set(0);
+        guest_port1_txq.avail.idx.set(1);
+        handler.handle_queue_event(port_rx_queue(1) + 1).unwrap();
+        assert_eq!(*output.data.lock().unwrap(), b"port1".to_vec());
+
+        // Removing the port is reported to the driver.
+        console.remove_port(1).unwrap();
+        assert!(console.remove_port(0).is_err());
+        handler.process_control().unwrap();
+        assert_eq!(
+            received_control(&mem, &guest_ctrl_rxq, ctrl_rx_addr, 7),
+            vec![(1, VIRTIO_CONSOLE_DEVICE_REMOVE, 0, None)]
+        );
+    }
+
+    #[test]
+    fn test_console_config_resize() {
+        let (mut console, input) =
+            Console::new(String::from("console"), Box::new(io::sink()), 80, 25, false).unwrap();
+        assert_ne!(console.features() & (1u64 << VIRTIO_CONSOLE_F_SIZE), 0);
+        console.ack_features(1u64 << VIRTIO_CONSOLE_F_SIZE);
+        input
+            .acked_features
+            .store(console.acked_features, Ordering::SeqCst);
+
+        let read_size = |console: &Console| {
+            let mut data = [0u8; 4];
+            console.read_config(0, &mut data);
+            (
+                u16::from_le_bytes([data[0], data[1]]),
+                u16::from_le_bytes([data[2], data[3]]),
+            )
+        };
+        assert_eq!(read_size(&console), (80, 25));
+        assert!(input.config_evt.read().is_err());
+
+        // The new size lands in the configuration space, and the driver
+        // gets a configuration change interrupt.
+        input.update_console_size(132, 43);
+        assert_eq!(read_size(&console), (132, 43));
+        assert_eq!(input.config_evt.read().unwrap(), 1);
+
+        // With multiport, the size goes through a control message instead.
+        input.acked_features.store(
+            1u64 << VIRTIO_CONSOLE_F_SIZE | 1u64 << VIRTIO_CONSOLE_F_MULTIPORT,
+            Ordering::SeqCst,
+        );
+        input.update_console_size(100, 30);
+        assert_eq!(read_size(&console), (100, 30));
+        assert!(input.config_evt.read().is_err());
+    }
+
+    #[test]
+    fn test_console_port_name() {
+        const NAME_MSG_SIZE: u32 = 64;
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_ctrl_rxq = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let guest_ctrl_txq = GuestQ::new(GuestAddress(0x2_0000), &mem, 16);
+        let ctrl_rx_addr = GuestAddress(0x4_0000);
+        let ctrl_tx_addr = GuestAddress(0x5_0000);
+
+        let (console, _) =
+            Console::new(String::from("console"), Box::new(io::sink()), 80, 25, false).unwrap();
+        let port1 = console
+            .add_port(Some(String::from("org.test.0")), Box::new(io::sink()))
+            .unwrap();
+        assert_eq!(
+            console
+                .add_port(Some(String::from("org.test.0")), Box::new(io::sink()))
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        port1
+            .acked_features
+            .store(1u64 << VIRTIO_CONSOLE_F_MULTIPORT, Ordering::SeqCst);
+
+        for i in 0..16u16 {
+            let addr = ctrl_rx_addr.unchecked_add(u64::from(u32::from(i) * NAME_MSG_SIZE));
+            guest_ctrl_rxq.dtable[i as usize].set(
+                addr.raw_value(),
+                NAME_MSG_SIZE,
+                VIRTQ_DESC_F_WRITE,
+                0,
+            );
+            guest_ctrl_rxq.avail.ring[i as usize].set(i);
+        }
+        guest_ctrl_rxq.avail.idx.set(16);
+
+        let mut queues: Vec<Queue> = (0..NUM_QUEUES).map(|_| Queue::new(16)).collect();
+        queues[CONTROL_RX_QUEUE] = guest_ctrl_rxq.create_queue();
+        queues[CONTROL_TX_QUEUE] = guest_ctrl_txq.create_queue();
+        let mut handler = create_handler(&console, &mem, queues);
+
+        send_control(
+            &mem,
+            &guest_ctrl_txq,
+            ctrl_tx_addr,
+            &[
+                (0, VIRTIO_CONSOLE_DEVICE_READY, 1),
+                (1, VIRTIO_CONSOLE_PORT_READY, 1),
+            ],
+        );
+        handler.process_control().unwrap();
+
+        // The name is sent once the port is set up, before opening it.
+        assert_eq!(guest_ctrl_rxq.used.idx.get(), 4);
+        let used = guest_ctrl_rxq.used.ring[2].get();
+        assert_eq!(used.len, 8 + 10);
+        let msg_addr = ctrl_rx_addr.unchecked_add(u64::from(used.id * NAME_MSG_SIZE));
+        let ctrl: VirtioConsoleControl = mem.read_obj(msg_addr).unwrap();
+        assert_eq!(
+            (ctrl.id, ctrl.event, ctrl.value),
+            (1, VIRTIO_CONSOLE_PORT_NAME, 1)
+        );
+        let mut name = [0u8; 10];
+        mem.read_slice(&mut name, msg_addr.unchecked_add(8))
+            .unwrap();
+        assert_eq!(&name, b"org.test.0");
+
+        let used = guest_ctrl_rxq.used.ring[3].get();
+        let ctrl: VirtioConsoleControl = mem
+            .read_obj(ctrl_rx_addr.unchecked_add(u64::from(used.id * NAME_MSG_SIZE)))
+            .unwrap();
+        assert_eq!(
+            (ctrl.id, ctrl.event, ctrl.value),
+            (1, VIRTIO_CONSOLE_PORT_OPEN, 1)
+        );
+    }
+}
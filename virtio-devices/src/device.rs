@@ -6,7 +6,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use crate::{ActivateResult, Error, Queue};
+use crate::{ActivateResult, Error, Idle, Queue};
 use std::collections::HashMap;
 use std::io::Write;
 use std::num::Wrapping;
@@ -137,6 +137,12 @@ pub trait VirtioDevice: Send {
     /// after a shutdown() can lead to unpredictable results.
     fn shutdown(&mut self) {}
 
+    /// Write back whatever the device cached of its backend, the device
+    /// being paused, for another process to take the backend over.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
     fn update_memory(&mut self, _mem: &GuestMemoryMmap) -> std::result::Result<(), Error> {
         Ok(())
     }
@@ -151,6 +157,10 @@ pub trait VirtioDevice: Send {
         None
     }
 
+    /// Set the callback notified each time the device goes idle, which
+    /// takes effect from the next activation on.
+    fn set_idle_callback(&mut self, _callback: Arc<dyn Idle>) {}
+
     /// Helper to allow common implementation of read_config
     fn read_config_from_slice(&self, config: &[u8], offset: u64, mut data: &mut [u8]) {
         let config_len = config.len() as u64;
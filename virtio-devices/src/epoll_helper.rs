@@ -11,13 +11,16 @@
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use vmm_sys_util::eventfd::EventFd;
 
 pub struct EpollHelper {
     pause_evt: EventFd,
     epoll_file: File,
+    idle_tracker: Option<IdleTracker>,
+    busy_poll: Option<Duration>,
 }
 
 #[derive(Debug)]
@@ -25,6 +28,7 @@ pub enum EpollHelperError {
     CreateFd(std::io::Error),
     Ctl(std::io::Error),
     Wait(std::io::Error),
+    ApplySeccompFilter(seccomp::Error),
 }
 
 pub const EPOLL_HELPER_EVENT_PAUSE: u16 = 0;
@@ -34,6 +38,125 @@ pub const EPOLL_HELPER_EVENT_LAST: u16 = 15;
 pub trait EpollHelperHandler {
     // Return true if execution of the loop should be stopped
     fn handle_event(&mut self, helper: &mut EpollHelper, event: u16) -> bool;
+
+    // Return true if requests taken from the queues are still being
+    // processed once the events have been handled, in which case the thread
+    // isn't idle until an upcoming event completes them.
+    fn has_pending(&self) -> bool {
+        false
+    }
+
+    // Look for some work without waiting for any event, which is only done
+    // while busy polling. Return true if some was found.
+    fn poll(&mut self, _helper: &mut EpollHelper) -> bool {
+        false
+    }
+}
+
+/// Notified when a device goes idle: none of its queues has any request in
+/// flight, and all of its threads are waiting for events.
+pub trait Idle: Send + Sync {
+    fn idle(&self);
+}
+
+/// Shared between the threads of a device, to notify the device once all of
+/// them are done with the requests they were working on.
+#[derive(Clone)]
+pub struct IdleTracker {
+    busy_threads: Arc<Mutex<usize>>,
+    callback: Arc<dyn Idle>,
+}
+
+impl IdleTracker {
+    pub fn new(callback: Arc<dyn Idle>) -> Self {
+        IdleTracker {
+            busy_threads: Arc::new(Mutex::new(0)),
+            callback,
+        }
+    }
+
+    /// The calling thread starts working on some requests.
+    pub fn busy(&self) {
+        *self.busy_threads.lock().unwrap() += 1;
+    }
+
+    /// The calling thread is done with the requests it was working on, the
+    /// device going idle if no other thread is busy.
+    pub fn done(&self) {
+        let idle = {
+            let mut busy_threads = self.busy_threads.lock().unwrap();
+            *busy_threads -= 1;
+            *busy_threads == 0
+        };
+        if idle {
+            self.callback.idle();
+        }
+    }
+}
+
+#[derive(Default)]
+struct ReadyState {
+    // Notifiers neither notified nor dropped yet.
+    pending: usize,
+    // Whether a notifier was dropped without being notified.
+    failed: bool,
+}
+
+/// Lets `activate()` wait for the threads of a device to listen to their
+/// events. Without it, the driver may notify a queue right after setting
+/// DRIVER_OK, before the thread of the queue listens to it.
+#[derive(Default)]
+pub struct ReadyBarrier {
+    state: Arc<(Mutex<ReadyState>, Condvar)>,
+}
+
+impl ReadyBarrier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Notifier to hand over to one more thread to wait for.
+    pub fn notifier(&self) -> ReadyNotifier {
+        self.state.0.lock().unwrap().pending += 1;
+        ReadyNotifier {
+            state: self.state.clone(),
+            notified: false,
+        }
+    }
+
+    /// Wait for all the notifiers to be notified, or dropped, as they are
+    /// when their thread exits early. Returns false in the latter case.
+    pub fn wait(self) -> bool {
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while state.pending > 0 {
+            state = cvar.wait(state).unwrap();
+        }
+        !state.failed
+    }
+}
+
+/// Notified by a device thread once it listens to all its events, which is
+/// also the case of a paused thread about to wait for being resumed.
+pub struct ReadyNotifier {
+    state: Arc<(Mutex<ReadyState>, Condvar)>,
+    notified: bool,
+}
+
+impl ReadyNotifier {
+    pub fn notify(mut self) {
+        self.notified = true;
+    }
+}
+
+impl Drop for ReadyNotifier {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        state.pending -= 1;
+        state.failed |= !self.notified;
+        cvar.notify_all();
+    }
 }
 
 impl EpollHelper {
@@ -49,6 +172,8 @@ impl EpollHelper {
         let mut helper = Self {
             pause_evt: pause_evt.try_clone().unwrap(),
             epoll_file,
+            idle_tracker: None,
+            busy_poll: None,
         };
 
         helper.add_event(kill_evt.as_raw_fd(), EPOLL_HELPER_EVENT_KILL)?;
@@ -66,6 +191,18 @@ impl EpollHelper {
         .map_err(EpollHelperError::Ctl)
     }
 
+    /// Report to `idle_tracker` when the thread is busy handling events.
+    pub fn set_idle_tracker(&mut self, idle_tracker: IdleTracker) {
+        self.idle_tracker = Some(idle_tracker);
+    }
+
+    /// Rather than waiting for the next event once some work has been done,
+    /// keep polling the handler for `duration`, which saves the latency of
+    /// being woken up at the expense of the CPU time spent spinning.
+    pub fn set_busy_poll(&mut self, duration: Duration) {
+        self.busy_poll = Some(duration);
+    }
+
     pub fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -82,8 +219,14 @@ impl EpollHelper {
             thread::park();
         }
 
+        let mut busy = false;
+        // While busy polling, the events are only checked for, without
+        // waiting, until this deadline.
+        let mut poll_deadline: Option<Instant> = None;
+        let epoll_fd = self.epoll_file.as_raw_fd();
         loop {
-            let num_events = match epoll::wait(self.epoll_file.as_raw_fd(), -1, &mut events[..]) {
+            let timeout = if poll_deadline.is_some() { 0 } else { -1 };
+            let num_events = match epoll::wait(epoll_fd, timeout, &mut events[..]) {
                 Ok(res) => res,
                 Err(e) => {
                     if e.kind() == std::io::ErrorKind::Interrupted {
@@ -100,6 +243,7 @@ impl EpollHelper {
                 }
             };
 
+            let mut handled = false;
             for event in events.iter().take(num_events) {
                 let ev_type = event.data as u16;
 
@@ -123,12 +267,39 @@ impl EpollHelper {
                         let _ = self.pause_evt.read();
                     }
                     id => {
+                        handled = true;
                         if handler.handle_event(self, id) {
                             return Ok(());
                         }
                     }
                 }
             }
+
+            if let Some(busy_poll) = self.busy_poll {
+                if handler.poll(self) {
+                    handled = true;
+                }
+                if handled {
+                    poll_deadline = Some(Instant::now() + busy_poll);
+                } else if poll_deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                    // Nothing showed up for the whole polling period, wait
+                    // for the next event. The handler was just polled and
+                    // the driver notifications still go through the events
+                    // while polling, hence nothing can be missed.
+                    poll_deadline = None;
+                }
+            }
+
+            if let Some(idle_tracker) = &self.idle_tracker {
+                if handled && !busy {
+                    idle_tracker.busy();
+                    busy = true;
+                }
+                if busy && !handler.has_pending() {
+                    idle_tracker.done();
+                    busy = false;
+                }
+            }
         }
     }
 }
@@ -138,3 +309,162 @@ impl AsRawFd for EpollHelper {
         self.epoll_file.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc::{channel, Sender};
+    use std::time::Duration;
+
+    const QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+    const COMPLETION_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+
+    // Handler completing the requests taken from its queue only once told
+    // so, as devices relying on asynchronous IO do.
+    struct TestHandler {
+        queue_evt: EventFd,
+        completion_evt: EventFd,
+        in_flight: u64,
+        taken: Sender<u64>,
+    }
+
+    impl EpollHelperHandler for TestHandler {
+        fn handle_event(&mut self, _helper: &mut EpollHelper, event: u16) -> bool {
+            match event {
+                QUEUE_EVENT => {
+                    let requests = self.queue_evt.read().unwrap();
+                    self.in_flight += requests;
+                    self.taken.send(requests).unwrap();
+                }
+                COMPLETION_EVENT => self.in_flight -= self.completion_evt.read().unwrap(),
+                _ => return true,
+            }
+            false
+        }
+
+        fn has_pending(&self) -> bool {
+            self.in_flight > 0
+        }
+    }
+
+    struct TestIdle {
+        count: AtomicUsize,
+        notify: Mutex<Sender<()>>,
+    }
+
+    impl Idle for TestIdle {
+        fn idle(&self) {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            self.notify.lock().unwrap().send(()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_idle_callback() {
+        let kill_evt = EventFd::new(0).unwrap();
+        let pause_evt = EventFd::new(0).unwrap();
+        let queue_evt = EventFd::new(0).unwrap();
+        let completion_evt = EventFd::new(0).unwrap();
+        let (taken_tx, taken_rx) = channel();
+        let (idle_tx, idle_rx) = channel();
+        let idle = Arc::new(TestIdle {
+            count: AtomicUsize::new(0),
+            notify: Mutex::new(idle_tx),
+        });
+
+        let mut helper = EpollHelper::new(&kill_evt, &pause_evt).unwrap();
+        helper
+            .add_event(queue_evt.as_raw_fd(), QUEUE_EVENT)
+            .unwrap();
+        helper
+            .add_event(completion_evt.as_raw_fd(), COMPLETION_EVENT)
+            .unwrap();
+        helper.set_idle_tracker(IdleTracker::new(idle.clone()));
+        let mut handler = TestHandler {
+            queue_evt: queue_evt.try_clone().unwrap(),
+            completion_evt: completion_evt.try_clone().unwrap(),
+            in_flight: 0,
+            taken: taken_tx,
+        };
+
+        let thread =
+            thread::spawn(move || helper.run(Arc::new(AtomicBool::new(false)), &mut handler));
+
+        // The device isn't idle as long as some requests are in flight.
+        queue_evt.write(3).unwrap();
+        let mut taken = 0;
+        while taken < 3 {
+            taken += taken_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(idle.count.load(Ordering::SeqCst), 0);
+
+        completion_evt.write(2).unwrap();
+        completion_evt.write(1).unwrap();
+        idle_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        kill_evt.write(1).unwrap();
+        thread.join().unwrap().unwrap();
+        assert_eq!(idle.count.load(Ordering::SeqCst), 1);
+    }
+
+    // The driver notifies the queue as soon as the device is activated,
+    // which the thread must not miss however late it got to listen to it.
+    #[test]
+    fn test_ready_barrier_kick() {
+        for i in 0..100 {
+            let kill_evt = EventFd::new(0).unwrap();
+            let pause_evt = EventFd::new(0).unwrap();
+            let queue_evt = EventFd::new(0).unwrap();
+            let (taken_tx, taken_rx) = channel();
+            let mut handler = TestHandler {
+                queue_evt: queue_evt.try_clone().unwrap(),
+                completion_evt: EventFd::new(0).unwrap(),
+                in_flight: 0,
+                taken: taken_tx,
+            };
+
+            let ready = ReadyBarrier::new();
+            let notifier = ready.notifier();
+            let thread_kill_evt = kill_evt.try_clone().unwrap();
+            let thread_pause_evt = pause_evt.try_clone().unwrap();
+            let thread = thread::spawn(move || {
+                // Late to listen, every other time.
+                if i % 2 == 0 {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                let mut helper = EpollHelper::new(&thread_kill_evt, &thread_pause_evt)?;
+                helper.add_event(handler.queue_evt.as_raw_fd(), QUEUE_EVENT)?;
+                notifier.notify();
+                helper.run(Arc::new(AtomicBool::new(false)), &mut handler)
+            });
+
+            assert!(ready.wait());
+            queue_evt.write(1).unwrap();
+            assert_eq!(taken_rx.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+
+            kill_evt.write(1).unwrap();
+            thread.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_ready_barrier_thread_exited() {
+        let ready = ReadyBarrier::new();
+        let notified = ready.notifier();
+        let dropped = ready.notifier();
+        let threads = vec![
+            thread::spawn(move || notified.notify()),
+            thread::spawn(move || drop(dropped)),
+        ];
+        assert!(!ready.wait());
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let ready = ReadyBarrier::new();
+        let notifier = ready.notifier();
+        notifier.notify();
+        assert!(ready.wait());
+    }
+}
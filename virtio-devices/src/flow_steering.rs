@@ -0,0 +1,457 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Steering of the frames received from a tap to the RX queues of the guest.
+//!
+//! Each queue pair reads its own queue of a multiqueue tap, the kernel
+//! picking the queue a frame goes to. By default it hashes the flow, which
+//! spreads the flows across the queues without any control over where a
+//! given flow lands. The flow rules send the TCP or UDP frames matching their
+//! ports to a specific queue instead.
+//!
+//! The rules are compiled into an eBPF program attached to the tap through
+//! `TUNSETSTEERINGEBPF`, the kernel picking the queue whose index the program
+//! returns. The rules are tried in order, and the frames matching none of
+//! them fall back to the hash of their flow as computed by the host, or to
+//! the hash of their ports if it hasn't computed any. Fragments other than
+//! the first one of an IPv4 datagram carry no ports and always fall back,
+//! and so do the IPv6 frames with extension headers.
+
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::FromRawFd;
+
+const ETH_HLEN: i32 = 14;
+const ETH_P_IP: i32 = 0x0800;
+const ETH_P_IPV6: i32 = 0x86dd;
+const IPPROTO_TCP: i32 = 6;
+const IPPROTO_UDP: i32 = 17;
+const IPV6_HLEN: i32 = 40;
+// Offset of the hash field of struct __sk_buff.
+const SKB_HASH_OFFSET: i16 = 68;
+
+// From include/uapi/linux/bpf.h
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_PROG_TYPE_SOCKET_FILTER: u32 = 1;
+
+// Instruction classes, sizes, modes and operations of eBPF, from
+// include/uapi/linux/bpf_common.h and include/uapi/linux/bpf.h
+const BPF_LD: u8 = 0x00;
+const BPF_LDX: u8 = 0x01;
+const BPF_JMP: u8 = 0x05;
+const BPF_ALU64: u8 = 0x07;
+const BPF_W: u8 = 0x00;
+const BPF_H: u8 = 0x08;
+const BPF_B: u8 = 0x10;
+const BPF_ABS: u8 = 0x20;
+const BPF_IND: u8 = 0x40;
+const BPF_MEM: u8 = 0x60;
+const BPF_K: u8 = 0x00;
+const BPF_X: u8 = 0x08;
+const BPF_AND: u8 = 0x50;
+const BPF_LSH: u8 = 0x60;
+const BPF_XOR: u8 = 0xa0;
+const BPF_MOV: u8 = 0xb0;
+const BPF_JA: u8 = 0x00;
+const BPF_JEQ: u8 = 0x10;
+const BPF_JNE: u8 = 0x50;
+const BPF_EXIT: u8 = 0x90;
+
+// Registers holding the context, and everything that must survive the
+// packet loads, which clobber the registers 1 to 5.
+const R0: u8 = 0;
+const R1: u8 = 1;
+const R_CTX: u8 = 6;
+const R_PROTOCOL: u8 = 7;
+const R_DST_PORT: u8 = 8;
+const R_SRC_PORT: u8 = 9;
+// Offset of the transport header from the network header, until the
+// destination port is loaded over it.
+const R_L4_OFFSET: u8 = R_DST_PORT;
+
+/// Transport protocol of the frames a flow rule applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowProtocol {
+    Tcp,
+    Udp,
+}
+
+impl FlowProtocol {
+    fn ip_protocol(self) -> i32 {
+        match self {
+            FlowProtocol::Tcp => IPPROTO_TCP,
+            FlowProtocol::Udp => IPPROTO_UDP,
+        }
+    }
+}
+
+/// Rule steering the frames of the matching flows to an RX queue.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct FlowRule {
+    pub protocol: FlowProtocol,
+    /// Source port of the frames, any port matching if unset.
+    #[serde(default)]
+    pub src_port: Option<u16>,
+    /// Destination port of the frames, any port matching if unset.
+    #[serde(default)]
+    pub dst_port: Option<u16>,
+    /// Index of the queue pair the frames are received through.
+    pub queue: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct BpfInsn {
+    code: u8,
+    // Destination register in the low nibble, source in the high one.
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+// The leading fields of union bpf_attr used by BPF_PROG_LOAD.
+#[repr(C)]
+#[derive(Default)]
+struct BpfProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+}
+
+#[derive(Default)]
+struct Program {
+    insns: Vec<BpfInsn>,
+}
+
+impl Program {
+    fn push(&mut self, code: u8, dst: u8, src: u8, off: i16, imm: i32) -> usize {
+        self.insns.push(BpfInsn {
+            code,
+            regs: dst | src << 4,
+            off,
+            imm,
+        });
+        self.insns.len() - 1
+    }
+
+    fn mov_reg(&mut self, dst: u8, src: u8) {
+        self.push(BPF_ALU64 | BPF_MOV | BPF_X, dst, src, 0, 0);
+    }
+
+    fn mov_imm(&mut self, dst: u8, imm: i32) {
+        self.push(BPF_ALU64 | BPF_MOV | BPF_K, dst, 0, 0, imm);
+    }
+
+    // Load a byte or a big endian half word of the frame into R0.
+    fn load_abs(&mut self, size: u8, offset: i32) {
+        self.push(BPF_LD | BPF_ABS | size, 0, 0, 0, offset);
+    }
+
+    fn load_ind(&mut self, size: u8, src: u8, offset: i32) {
+        self.push(BPF_LD | BPF_IND | size, 0, src, 0, offset);
+    }
+
+    // Conditional jump, to be pointed at its target once emitted.
+    fn jump_imm(&mut self, op: u8, dst: u8, imm: i32) -> usize {
+        self.push(BPF_JMP | op | BPF_K, dst, 0, 0, imm)
+    }
+
+    // Point the jumps at the next instruction emitted.
+    fn land(&mut self, jumps: &[usize]) {
+        let target = self.insns.len();
+        for jump in jumps {
+            self.insns[*jump].off = (target - jump - 1) as i16;
+        }
+    }
+
+    // Check the protocol of the transport header, jumping away unless it's
+    // TCP or UDP.
+    fn transport_protocol(&mut self, offset: i32) -> usize {
+        self.load_abs(BPF_B, offset);
+        self.mov_reg(R_PROTOCOL, R0);
+        let tcp = self.jump_imm(BPF_JEQ, R_PROTOCOL, IPPROTO_TCP);
+        let other = self.jump_imm(BPF_JNE, R_PROTOCOL, IPPROTO_UDP);
+        self.land(&[tcp]);
+        other
+    }
+}
+
+fn steering_program(rules: &[FlowRule]) -> Vec<BpfInsn> {
+    let mut p = Program::default();
+    let mut fallback = Vec::new();
+
+    p.mov_reg(R_CTX, R1);
+    p.mov_imm(R_DST_PORT, 0);
+    p.mov_imm(R_SRC_PORT, 0);
+    p.load_abs(BPF_H, ETH_HLEN - 2);
+    let ipv6 = p.jump_imm(BPF_JEQ, R0, ETH_P_IPV6);
+    fallback.push(p.jump_imm(BPF_JNE, R0, ETH_P_IP));
+
+    // IPv4, whose header length is given in words by the low nibble of its
+    // first byte.
+    fallback.push(p.transport_protocol(ETH_HLEN + 9));
+    p.load_abs(BPF_H, ETH_HLEN + 6);
+    p.push(BPF_ALU64 | BPF_AND | BPF_K, R0, 0, 0, 0x1fff);
+    fallback.push(p.jump_imm(BPF_JNE, R0, 0));
+    p.load_abs(BPF_B, ETH_HLEN);
+    p.push(BPF_ALU64 | BPF_AND | BPF_K, R0, 0, 0, 0xf);
+    p.push(BPF_ALU64 | BPF_LSH | BPF_K, R0, 0, 0, 2);
+    p.mov_reg(R_L4_OFFSET, R0);
+    let ports = p.push(BPF_JMP | BPF_JA, 0, 0, 0, 0);
+
+    p.land(&[ipv6]);
+    fallback.push(p.transport_protocol(ETH_HLEN + 6));
+    p.mov_imm(R_L4_OFFSET, IPV6_HLEN);
+
+    p.land(&[ports]);
+    p.load_ind(BPF_H, R_L4_OFFSET, ETH_HLEN);
+    p.mov_reg(R_SRC_PORT, R0);
+    p.load_ind(BPF_H, R_L4_OFFSET, ETH_HLEN + 2);
+    p.mov_reg(R_DST_PORT, R0);
+
+    for rule in rules {
+        let mut next = vec![p.jump_imm(BPF_JNE, R_PROTOCOL, rule.protocol.ip_protocol())];
+        if let Some(port) = rule.src_port {
+            next.push(p.jump_imm(BPF_JNE, R_SRC_PORT, i32::from(port)));
+        }
+        if let Some(port) = rule.dst_port {
+            next.push(p.jump_imm(BPF_JNE, R_DST_PORT, i32::from(port)));
+        }
+        p.mov_imm(R0, i32::from(rule.queue));
+        p.push(BPF_JMP | BPF_EXIT, 0, 0, 0, 0);
+        p.land(&next);
+    }
+
+    p.land(&fallback);
+    p.push(BPF_LDX | BPF_MEM | BPF_W, R0, R_CTX, SKB_HASH_OFFSET, 0);
+    let hashed = p.jump_imm(BPF_JNE, R0, 0);
+    p.mov_reg(R0, R_SRC_PORT);
+    p.push(BPF_ALU64 | BPF_XOR | BPF_X, R0, R_DST_PORT, 0, 0);
+    p.land(&[hashed]);
+    p.push(BPF_JMP | BPF_EXIT, 0, 0, 0, 0);
+
+    p.insns
+}
+
+/// Compile the flow rules into a steering program for the tap.
+pub fn load_steering_program(rules: &[FlowRule]) -> io::Result<File> {
+    let insns = steering_program(rules);
+    let license = b"Apache-2.0\0";
+    let attr = BpfProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_SOCKET_FILTER,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        ..Default::default()
+    };
+
+    // Safe because the kernel only reads the attributes, and the program
+    // and license they point to, which all outlive the call. The return
+    // value is checked.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_PROG_LOAD,
+            &attr as *const BpfProgLoadAttr,
+            mem::size_of::<BpfProgLoadAttr>(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because the file descriptor was just returned by the kernel, and
+    // isn't owned by anything else.
+    Ok(unsafe { File::from_raw_fd(ret as i32) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SKB_HASH: u32 = 0x1234_5678;
+
+    // Run the program the way the kernel does for a socket filter, a load
+    // out of the frame ending it with 0.
+    fn run(insns: &[BpfInsn], frame: &[u8], hash: u32) -> u64 {
+        let mut regs = [0u64; 11];
+        let mut pc = 0;
+
+        let load = |offset: u64, size: u8| -> Option<u64> {
+            let len = match size {
+                BPF_B => 1,
+                BPF_H => 2,
+                _ => 4,
+            };
+            let offset = offset as usize;
+            let bytes = frame.get(offset..offset + len)?;
+            Some(bytes.iter().fold(0, |v, b| v << 8 | u64::from(*b)))
+        };
+
+        loop {
+            let insn = insns[pc];
+            let dst = (insn.regs & 0xf) as usize;
+            let src = (insn.regs >> 4) as usize;
+            let imm = insn.imm as i64 as u64;
+            pc += 1;
+
+            match insn.code & 0x07 {
+                BPF_LD => {
+                    let offset = if insn.code & 0xe0 == BPF_IND {
+                        regs[src].wrapping_add(imm)
+                    } else {
+                        imm
+                    };
+                    match load(offset, insn.code & 0x18) {
+                        Some(v) => regs[0] = v,
+                        None => return 0,
+                    }
+                }
+                BPF_LDX => {
+                    assert_eq!(src, R_CTX as usize);
+                    assert_eq!(insn.off, SKB_HASH_OFFSET);
+                    regs[dst] = u64::from(hash);
+                }
+                BPF_ALU64 => {
+                    let operand = if insn.code & BPF_X != 0 {
+                        regs[src]
+                    } else {
+                        imm
+                    };
+                    regs[dst] = match insn.code & 0xf0 {
+                        BPF_AND => regs[dst] & operand,
+                        BPF_LSH => regs[dst] << operand,
+                        BPF_XOR => regs[dst] ^ operand,
+                        BPF_MOV => operand,
+                        op => panic!("Unexpected ALU operation {:#x}", op),
+                    };
+                }
+                BPF_JMP => {
+                    let taken = match insn.code & 0xf0 {
+                        BPF_JA => true,
+                        BPF_JEQ => regs[dst] == imm,
+                        BPF_JNE => regs[dst] != imm,
+                        BPF_EXIT => return regs[0],
+                        op => panic!("Unexpected jump operation {:#x}", op),
+                    };
+                    if taken {
+                        pc = (pc as i64 + i64::from(insn.off)) as usize;
+                    }
+                }
+                class => panic!("Unexpected instruction class {:#x}", class),
+            }
+        }
+    }
+
+    fn ipv4_frame(protocol: u8, src_port: u16, dst_port: u16) -> Vec<u8> {
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        // Version 4 and a header of 5 words, without any option.
+        let mut header = [0u8; 20];
+        header[0] = 0x45;
+        header[9] = protocol;
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&src_port.to_be_bytes());
+        frame.extend_from_slice(&dst_port.to_be_bytes());
+        frame.extend_from_slice(&[0u8; 16]);
+        frame
+    }
+
+    fn ipv6_frame(next_header: u8, src_port: u16, dst_port: u16) -> Vec<u8> {
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&[0x86, 0xdd]);
+        let mut header = [0u8; 40];
+        header[0] = 0x60;
+        header[6] = next_header;
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&src_port.to_be_bytes());
+        frame.extend_from_slice(&dst_port.to_be_bytes());
+        frame.extend_from_slice(&[0u8; 16]);
+        frame
+    }
+
+    fn rules() -> Vec<FlowRule> {
+        vec![
+            FlowRule {
+                protocol: FlowProtocol::Tcp,
+                src_port: None,
+                dst_port: Some(80),
+                queue: 2,
+            },
+            FlowRule {
+                protocol: FlowProtocol::Udp,
+                src_port: Some(5000),
+                dst_port: Some(6000),
+                queue: 3,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_flow_steering_match() {
+        let program = steering_program(&rules());
+
+        assert_eq!(run(&program, &ipv4_frame(6, 40000, 80), SKB_HASH), 2);
+        assert_eq!(run(&program, &ipv6_frame(6, 40001, 80), SKB_HASH), 2);
+        assert_eq!(run(&program, &ipv4_frame(17, 5000, 6000), SKB_HASH), 3);
+        assert_eq!(run(&program, &ipv6_frame(17, 5000, 6000), 0), 3);
+
+        // IPv4 options move the transport header.
+        let mut frame = ipv4_frame(6, 40000, 8080);
+        frame[14] = 0x46;
+        for _ in 0..4 {
+            frame.insert(34, 0);
+        }
+        frame[40..42].copy_from_slice(&80u16.to_be_bytes());
+        assert_eq!(run(&program, &frame, SKB_HASH), 2);
+    }
+
+    #[test]
+    fn test_flow_steering_fallthrough() {
+        let program = steering_program(&rules());
+        let hash = u64::from(SKB_HASH);
+
+        // Right ports, wrong protocol.
+        assert_eq!(run(&program, &ipv4_frame(17, 40000, 80), SKB_HASH), hash);
+        // Only one of the ports matching.
+        assert_eq!(run(&program, &ipv4_frame(17, 5000, 6001), SKB_HASH), hash);
+        // Neither TCP nor UDP.
+        assert_eq!(run(&program, &ipv4_frame(1, 0, 80), SKB_HASH), hash);
+        assert_eq!(run(&program, &ipv6_frame(0, 0, 80), SKB_HASH), hash);
+
+        // Fragments past the first one have no ports.
+        let mut frame = ipv4_frame(6, 40000, 80);
+        frame[20] = 0x00;
+        frame[21] = 0xb9;
+        assert_eq!(run(&program, &frame, SKB_HASH), hash);
+
+        // Not IP at all.
+        let mut frame = ipv4_frame(6, 40000, 80);
+        frame[12] = 0x08;
+        frame[13] = 0x06;
+        assert_eq!(run(&program, &frame, SKB_HASH), hash);
+
+        // Without any hash from the host, the ports get hashed.
+        assert_eq!(
+            run(&program, &ipv4_frame(6, 40000, 8080), 0),
+            u64::from(40000u16 ^ 8080)
+        );
+
+        // Truncated frames go to the first queue.
+        assert_eq!(run(&program, &ipv4_frame(6, 40000, 80)[..20], SKB_HASH), 0);
+
+        // Without any rule, everything falls through.
+        let program = steering_program(&[]);
+        assert_eq!(run(&program, &ipv4_frame(6, 40000, 80), SKB_HASH), hash);
+    }
+}
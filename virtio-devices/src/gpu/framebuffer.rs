@@ -0,0 +1,380 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Host copy of what the guest displays on the scanout.
+//!
+//! The pixels are stored as packed RGB, whatever the format of the guest
+//! resources, and can be saved as a PNG image. The image data is stored
+//! uncompressed, which avoids depending on a deflate implementation at the
+//! cost of larger files.
+//!
+//! The framebuffer can also be mirrored to a host file, such as one of
+//! `/dev/shm`, for another process to display it. The file starts with a
+//! header of little-endian 32 bits words: the `CHFB` magic, the version of
+//! the layout, the width, height and stride of the image, whether the guest
+//! displays anything, and a sequence number incremented after each update,
+//! followed by a reserved word. The packed RGB pixels come next.
+
+use libc::{MAP_SHARED, PROT_READ, PROT_WRITE};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use vm_memory::guest_memory::FileOffset;
+use vm_memory::{MmapRegion, VolatileMemory};
+
+/// Size of the header of a framebuffer mirrored to a file, the pixels
+/// following.
+pub const EXPORT_HEADER_SIZE: usize = 32;
+const EXPORT_MAGIC: &[u8; 4] = b"CHFB";
+const EXPORT_VERSION: u32 = 1;
+const EXPORT_SEQUENCE_OFFSET: usize = 24;
+
+fn export_error<E: fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{:?}", e))
+}
+
+// Host file the framebuffer is mirrored to, mapped shared.
+#[derive(Debug)]
+struct Export {
+    file: File,
+    mmap: Option<MmapRegion>,
+    sequence: u32,
+}
+
+impl Export {
+    // Size the file for a black `width` x `height` image, and map it again.
+    fn reset(&mut self, width: u32, height: u32, enabled: bool) -> io::Result<()> {
+        let stride = width as usize * 3;
+        let size = EXPORT_HEADER_SIZE + stride * height as usize;
+
+        self.mmap = None;
+        self.file.set_len(0)?;
+        self.file.set_len(size as u64)?;
+        let mmap = MmapRegion::build(
+            Some(FileOffset::new(self.file.try_clone()?, 0)),
+            size,
+            PROT_READ | PROT_WRITE,
+            MAP_SHARED,
+        )
+        .map_err(export_error)?;
+
+        let mut header = Vec::with_capacity(EXPORT_HEADER_SIZE);
+        header.extend_from_slice(EXPORT_MAGIC);
+        for word in [EXPORT_VERSION, width, height, stride as u32, enabled as u32].iter() {
+            header.extend_from_slice(&word.to_le_bytes());
+        }
+        mmap.get_slice(0, header.len())
+            .map_err(export_error)?
+            .copy_from(&header);
+        self.mmap = Some(mmap);
+
+        self.bump_sequence()
+    }
+
+    // Copy `data`, the pixels starting at `offset`, then tell they changed.
+    fn update(&mut self, offset: usize, data: &[u8]) -> io::Result<()> {
+        if let Some(mmap) = &self.mmap {
+            mmap.get_slice(EXPORT_HEADER_SIZE + offset, data.len())
+                .map_err(export_error)?
+                .copy_from(data);
+        }
+
+        self.bump_sequence()
+    }
+
+    fn bump_sequence(&mut self) -> io::Result<()> {
+        self.sequence = self.sequence.wrapping_add(1);
+        if let Some(mmap) = &self.mmap {
+            mmap.get_slice(EXPORT_SEQUENCE_OFFSET, 4)
+                .map_err(export_error)?
+                .copy_from(&self.sequence.to_le_bytes());
+        }
+
+        Ok(())
+    }
+}
+
+/// Content of the display, updated whenever the guest flushes the resource
+/// attached to the scanout.
+#[derive(Debug, Default)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    enabled: bool,
+    data: Vec<u8>,
+    export: Option<Export>,
+}
+
+impl Framebuffer {
+    /// Create a disabled, black, framebuffer of the given size.
+    pub fn new(width: u32, height: u32) -> Self {
+        Framebuffer {
+            width,
+            height,
+            enabled: false,
+            data: vec![0; width as usize * height as usize * 3],
+            export: None,
+        }
+    }
+
+    /// Mirror the framebuffer to `file`, which is resized whenever the
+    /// size of the displayed resource changes.
+    pub fn export(&mut self, file: File) -> io::Result<()> {
+        let mut export = Export {
+            file,
+            mmap: None,
+            sequence: 0,
+        };
+        export.reset(self.width, self.height, self.enabled)?;
+        export.update(0, &self.data)?;
+        self.export = Some(export);
+
+        Ok(())
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Whether the guest has attached a resource to the scanout.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Red, green and blue components of the pixel at (`x`, `y`).
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        let offset = (y as usize * self.width as usize + x as usize) * 3;
+        [
+            self.data[offset],
+            self.data[offset + 1],
+            self.data[offset + 2],
+        ]
+    }
+
+    // Start displaying a new resource, or nothing, with a black screen.
+    pub(crate) fn reset(&mut self, width: u32, height: u32, enabled: bool) {
+        self.width = width;
+        self.height = height;
+        self.enabled = enabled;
+        self.data = vec![0; width as usize * height as usize * 3];
+
+        if let Some(export) = &mut self.export {
+            if let Err(e) = export.reset(width, height, enabled) {
+                error!("Could not mirror the framebuffer anymore: {}", e);
+                self.export = None;
+            }
+        }
+    }
+
+    // Copy a `width` x `height` area of 32 bits pixels starting at
+    // `src_offset` in `src` to (`x`, `y`). `rgb` gives the offsets of the
+    // red, green and blue bytes within a source pixel.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn update(
+        &mut self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        src: &[u8],
+        src_offset: usize,
+        src_stride: usize,
+        rgb: [usize; 3],
+    ) {
+        for row in 0..height as usize {
+            let src_row = src_offset + row * src_stride;
+            let dst_row = ((y as usize + row) * self.width as usize + x as usize) * 3;
+            for col in 0..width as usize {
+                let src = &src[src_row + col * 4..src_row + col * 4 + 4];
+                let dst = dst_row + col * 3;
+                self.data[dst] = src[rgb[0]];
+                self.data[dst + 1] = src[rgb[1]];
+                self.data[dst + 2] = src[rgb[2]];
+            }
+        }
+
+        // The updated rows are mirrored as a whole.
+        if let Some(export) = &mut self.export {
+            let stride = self.width as usize * 3;
+            let start = y as usize * stride;
+            let end = start + height as usize * stride;
+            if let Err(e) = export.update(start, &self.data[start..end]) {
+                error!("Could not mirror the framebuffer anymore: {}", e);
+                self.export = None;
+            }
+        }
+    }
+
+    /// Write the framebuffer content as a PNG image.
+    pub fn write_png<W: Write>(&self, mut out: W) -> io::Result<()> {
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&self.width.to_be_bytes());
+        ihdr.extend_from_slice(&self.height.to_be_bytes());
+        // 8 bits per component RGB, default compression, filtering and no
+        // interlacing.
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_chunk(&mut out, b"IHDR", &ihdr)?;
+
+        // Each scanline starts with its filter type, none here.
+        let stride = self.width as usize * 3;
+        let mut scanlines = Vec::with_capacity((stride + 1) * self.height as usize);
+        for row in self.data.chunks(stride) {
+            scanlines.push(0);
+            scanlines.extend_from_slice(row);
+        }
+        write_chunk(&mut out, b"IDAT", &zlib_stored(&scanlines))?;
+
+        write_chunk(&mut out, b"IEND", &[])
+    }
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+
+    let crc = crc32(crc32(0xffff_ffff, kind), data) ^ 0xffff_ffff;
+    out.write_all(&crc.to_be_bytes())
+}
+
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+// Wrap `data` in a zlib stream made of stored deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 0xffff;
+
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_BLOCK * 5 + 11);
+    // Deflate with a 32KiB window, no preset dictionary.
+    out.extend_from_slice(&[0x78, 0x01]);
+
+    let mut blocks = data.chunks(MAX_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + u32::from(*byte)) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom};
+
+    fn read_export(mut file: &File) -> Vec<u8> {
+        let mut content = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut content).unwrap();
+        content
+    }
+
+    fn header_word(content: &[u8], index: usize) -> u32 {
+        let mut word = [0u8; 4];
+        word.copy_from_slice(&content[index * 4..index * 4 + 4]);
+        u32::from_le_bytes(word)
+    }
+
+    #[test]
+    fn test_framebuffer_export() {
+        let file = tempfile::tempfile().unwrap();
+        let mut fb = Framebuffer::new(2, 2);
+        fb.export(file.try_clone().unwrap()).unwrap();
+
+        let content = read_export(&file);
+        assert_eq!(content.len(), EXPORT_HEADER_SIZE + 12);
+        assert_eq!(&content[..4], EXPORT_MAGIC);
+        assert_eq!(header_word(&content, 1), EXPORT_VERSION);
+        assert_eq!(header_word(&content, 2), 2);
+        assert_eq!(header_word(&content, 3), 2);
+        assert_eq!(header_word(&content, 4), 6);
+        assert_eq!(header_word(&content, 5), 0);
+        let sequence = header_word(&content, 6);
+        assert!(content[EXPORT_HEADER_SIZE..].iter().all(|b| *b == 0));
+
+        // Only the second row is updated.
+        let src = [0x10, 0x20, 0x30, 0, 0x40, 0x50, 0x60, 0];
+        fb.update(0, 1, 2, 1, &src, 0, 8, [0, 1, 2]);
+        let content = read_export(&file);
+        assert!(header_word(&content, 6) > sequence);
+        assert_eq!(
+            &content[EXPORT_HEADER_SIZE..],
+            &[0, 0, 0, 0, 0, 0, 0x10, 0x20, 0x30, 0x40, 0x50, 0x60]
+        );
+
+        // A resource of another size resizes the mirror, black again.
+        fb.reset(1, 3, true);
+        let content = read_export(&file);
+        assert_eq!(content.len(), EXPORT_HEADER_SIZE + 9);
+        assert_eq!(header_word(&content, 2), 1);
+        assert_eq!(header_word(&content, 3), 3);
+        assert_eq!(header_word(&content, 4), 3);
+        assert_eq!(header_word(&content, 5), 1);
+        assert!(content[EXPORT_HEADER_SIZE..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_framebuffer_png() {
+        let mut fb = Framebuffer::new(2, 1);
+        // One BGRX pixel then one RGBX pixel.
+        let src = [0x30, 0x20, 0x10, 0, 0x40, 0x50, 0x60, 0];
+        fb.update(0, 0, 1, 1, &src, 0, 8, [2, 1, 0]);
+        fb.update(1, 0, 1, 1, &src, 4, 8, [0, 1, 2]);
+        assert_eq!(fb.pixel(0, 0), [0x10, 0x20, 0x30]);
+        assert_eq!(fb.pixel(1, 0), [0x40, 0x50, 0x60]);
+
+        let mut png = Vec::new();
+        fb.write_png(&mut png).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        // IHDR, with the CRC of a 2x1 RGB image.
+        assert_eq!(&png[8..16], b"\x00\x00\x00\x0dIHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 2, 0, 0, 0, 1]);
+        assert_eq!(&png[24..29], &[8, 2, 0, 0, 0]);
+        assert_eq!(crc32(0xffff_ffff, b"IEND") ^ 0xffff_ffff, 0xae42_6082);
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+
+        // Single stored block holding the filter byte and both pixels.
+        let idat = &png[33..];
+        assert_eq!(&idat[..8], b"\x00\x00\x00\x12IDAT");
+        assert_eq!(
+            &idat[8..26],
+            &[
+                0x78, 0x01, 1, 7, 0, 0xf8, 0xff, 0, 0x10, 0x20, 0x30, 0x40, 0x50, 0x60, 0x03, 0x87,
+                0x01, 0x51
+            ]
+        );
+    }
+}
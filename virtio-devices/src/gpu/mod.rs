@@ -0,0 +1,1332 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Virtio GPU device.
+//!
+//! Only the 2D commands are supported, without any 3D acceleration. The
+//! device exposes a single scanout, and the resource attached to it is
+//! copied into a `Framebuffer` each time the guest flushes it, from where
+//! it can be saved as an image or mirrored to a host file. Cursor updates are acknowledged but not
+//! displayed.
+
+mod framebuffer;
+
+pub use self::framebuffer::Framebuffer;
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DescriptorChain, EpollHelper, EpollHelperError,
+    EpollHelperHandler, Queue, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST,
+    VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
+};
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
+use libc::EFD_NONBLOCK;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vm_memory::{
+    ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError,
+    GuestMemoryMmap,
+};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 256;
+const NUM_QUEUES: usize = 2;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+
+const CONTROL_QUEUE: usize = 0;
+const CURSOR_QUEUE: usize = 1;
+
+// New descriptors are pending on the control queue.
+const CONTROL_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// New descriptors are pending on the cursor queue.
+const CURSOR_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+
+// 2D commands carried by the control queue.
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const VIRTIO_GPU_CMD_RESOURCE_UNREF: u32 = 0x0102;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+
+// Responses to the commands.
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+const VIRTIO_GPU_RESP_ERR_UNSPEC: u32 = 0x1200;
+const VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+const VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+const VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+
+// The driver wants to be notified when the command completes.
+const VIRTIO_GPU_FLAG_FENCE: u32 = 1;
+
+const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+
+// Resource formats, all of them 32 bits per pixel.
+const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 1;
+const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
+const VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM: u32 = 3;
+const VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM: u32 = 4;
+const VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM: u32 = 67;
+const VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM: u32 = 68;
+const VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM: u32 = 121;
+const VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM: u32 = 134;
+
+const BYTES_PER_PIXEL: u64 = 4;
+
+// Upper bound on the host memory used by the resources of a driver.
+const MAX_HOST_MEMORY: u64 = 256 << 20;
+
+// Upper bound on the amount of data read from a single descriptor chain,
+// leaving room for the backing entries of a large resource.
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Guest gave us a readable descriptor following a writable one.
+    UnexpectedReadableDescriptor,
+    /// Guest gave us more data than we can handle at once.
+    RequestTooLarge,
+    /// Guest gave us too small a writable buffer for the response.
+    ResponseTooLarge,
+    /// Failed accessing the guest memory.
+    GuestMemory(GuestMemoryError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            UnexpectedReadableDescriptor => write!(f, "unexpected readable descriptor"),
+            RequestTooLarge => write!(f, "request too large"),
+            ResponseTooLarge => write!(f, "not enough room for the response"),
+            GuestMemory(e) => write!(f, "failed accessing guest memory: {}", e),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuConfig {
+    events_read: u32,
+    events_clear: u32,
+    num_scanouts: u32,
+    reserved: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuConfig {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuCtrlHdr {
+    type_: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    padding: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuCtrlHdr {}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+struct VirtioGpuRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuRect {}
+
+impl VirtioGpuRect {
+    // Whether the rectangle lies within a `width` x `height` area.
+    fn fits(&self, width: u32, height: u32) -> bool {
+        u64::from(self.x) + u64::from(self.width) <= u64::from(width)
+            && u64::from(self.y) + u64::from(self.height) <= u64::from(height)
+    }
+
+    fn intersection(&self, other: &VirtioGpuRect) -> Option<VirtioGpuRect> {
+        let x = cmp::max(self.x, other.x);
+        let y = cmp::max(self.y, other.y);
+        let right = cmp::min(self.x + self.width, other.x + other.width);
+        let bottom = cmp::min(self.y + self.height, other.y + other.height);
+        if right <= x || bottom <= y {
+            return None;
+        }
+
+        Some(VirtioGpuRect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuDisplayOne {
+    r: VirtioGpuRect,
+    enabled: u32,
+    flags: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuDisplayOne {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuRespDisplayInfo {
+    hdr: VirtioGpuCtrlHdr,
+    pmodes: [VirtioGpuDisplayOne; VIRTIO_GPU_MAX_SCANOUTS],
+}
+
+unsafe impl ByteValued for VirtioGpuRespDisplayInfo {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuResourceCreate2d {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuResourceCreate2d {}
+
+// Layout shared by the commands only carrying a resource id.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuResourceCmd {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    padding: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuResourceCmd {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuSetScanout {
+    hdr: VirtioGpuCtrlHdr,
+    r: VirtioGpuRect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuSetScanout {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuResourceFlush {
+    hdr: VirtioGpuCtrlHdr,
+    r: VirtioGpuRect,
+    resource_id: u32,
+    padding: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuResourceFlush {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuTransferToHost2d {
+    hdr: VirtioGpuCtrlHdr,
+    r: VirtioGpuRect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuTransferToHost2d {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuResourceAttachBacking {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    nr_entries: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuResourceAttachBacking {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuMemEntry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuMemEntry {}
+
+fn read_obj<T: ByteValued>(data: &[u8]) -> Option<T> {
+    if data.len() < size_of::<T>() {
+        return None;
+    }
+
+    let mut obj = T::default();
+    obj.as_mut_slice().copy_from_slice(&data[..size_of::<T>()]);
+    Some(obj)
+}
+
+// Offsets of the red, green and blue bytes within a pixel of `format`.
+fn format_rgb_offsets(format: u32) -> Option<[usize; 3]> {
+    match format {
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM => Some([2, 1, 0]),
+        VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM => Some([1, 2, 3]),
+        VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM | VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM => Some([0, 1, 2]),
+        VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM | VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM => Some([3, 2, 1]),
+        _ => None,
+    }
+}
+
+// Content of a descriptor chain, made of the command provided by the driver
+// followed by the room it left for the response.
+struct Buffers {
+    readable: Vec<u8>,
+    writable: Vec<(GuestAddress, u32)>,
+}
+
+impl Buffers {
+    fn parse(mem: &GuestMemoryMmap, head: &DescriptorChain) -> result::Result<Buffers, Error> {
+        let mut readable = Vec::new();
+        let mut writable = Vec::new();
+
+        let mut next = Some(head.clone());
+        while let Some(desc) = next {
+            if desc.is_write_only() {
+                writable.push((desc.addr, desc.len));
+            } else {
+                if !writable.is_empty() {
+                    return Err(Error::UnexpectedReadableDescriptor);
+                }
+                if readable.len() + desc.len as usize > MAX_REQUEST_SIZE {
+                    return Err(Error::RequestTooLarge);
+                }
+
+                let offset = readable.len();
+                readable.resize(offset + desc.len as usize, 0);
+                mem.read_slice(&mut readable[offset..], desc.addr)
+                    .map_err(Error::GuestMemory)?;
+            }
+            next = desc.next_descriptor();
+        }
+
+        Ok(Buffers { readable, writable })
+    }
+
+    // Write `data` at the start of the writable part of the chain.
+    fn write(&self, mem: &GuestMemoryMmap, mut data: &[u8]) -> result::Result<(), Error> {
+        let writable_len: usize = self.writable.iter().map(|(_, len)| *len as usize).sum();
+        if data.len() > writable_len {
+            return Err(Error::ResponseTooLarge);
+        }
+
+        for &(addr, len) in self.writable.iter() {
+            if data.is_empty() {
+                break;
+            }
+
+            let count = cmp::min(len as usize, data.len());
+            mem.write_slice(&data[..count], addr)
+                .map_err(Error::GuestMemory)?;
+            data = &data[count..];
+        }
+
+        Ok(())
+    }
+}
+
+// Host side of a 2D resource created by the guest.
+struct Resource {
+    width: u32,
+    height: u32,
+    rgb: [usize; 3],
+    data: Vec<u8>,
+    backing: Vec<VirtioGpuMemEntry>,
+}
+
+impl Resource {
+    fn stride(&self) -> usize {
+        self.width as usize * BYTES_PER_PIXEL as usize
+    }
+
+    // Fill `buf` with the content of the guest backing at `offset`.
+    fn read_backing(
+        &self,
+        mem: &GuestMemoryMmap,
+        mut offset: u64,
+        mut buf: &mut [u8],
+    ) -> result::Result<(), GuestMemoryError> {
+        for entry in self.backing.iter() {
+            let len = u64::from(entry.length);
+            if offset >= len {
+                offset -= len;
+                continue;
+            }
+
+            let count = cmp::min(len - offset, buf.len() as u64) as usize;
+            mem.read_slice(
+                &mut buf[..count],
+                GuestAddress(entry.addr).unchecked_add(offset),
+            )?;
+            buf = &mut buf[count..];
+            offset = 0;
+
+            if buf.is_empty() {
+                return Ok(());
+            }
+        }
+
+        if buf.is_empty() {
+            Ok(())
+        } else {
+            Err(GuestMemoryError::InvalidBackendAddress)
+        }
+    }
+}
+
+// Resource attached to the scanout, and the area of it being displayed.
+struct Scanout {
+    resource_id: u32,
+    r: VirtioGpuRect,
+}
+
+struct GpuState {
+    width: u32,
+    height: u32,
+    resources: BTreeMap<u32, Resource>,
+    host_memory: u64,
+    scanout: Option<Scanout>,
+    framebuffer: Arc<Mutex<Framebuffer>>,
+}
+
+impl GpuState {
+    fn new(width: u32, height: u32, framebuffer: Arc<Mutex<Framebuffer>>) -> Self {
+        GpuState {
+            width,
+            height,
+            resources: BTreeMap::new(),
+            host_memory: 0,
+            scanout: None,
+            framebuffer,
+        }
+    }
+
+    fn display_info(&self) -> VirtioGpuRespDisplayInfo {
+        let mut info = VirtioGpuRespDisplayInfo {
+            hdr: VirtioGpuCtrlHdr {
+                type_: VIRTIO_GPU_RESP_OK_DISPLAY_INFO,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        info.pmodes[0] = VirtioGpuDisplayOne {
+            r: VirtioGpuRect {
+                x: 0,
+                y: 0,
+                width: self.width,
+                height: self.height,
+            },
+            enabled: 1,
+            flags: 0,
+        };
+
+        info
+    }
+
+    fn create_2d(&mut self, cmd: &VirtioGpuResourceCreate2d) -> u32 {
+        if cmd.resource_id == 0 || self.resources.contains_key(&cmd.resource_id) {
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        }
+        let rgb = match format_rgb_offsets(cmd.format) {
+            Some(rgb) => rgb,
+            None => {
+                let format = cmd.format;
+                warn!("Unsupported virtio-gpu resource format {}", format);
+                return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+            }
+        };
+
+        let size = u64::from(cmd.width) * u64::from(cmd.height) * BYTES_PER_PIXEL;
+        if size == 0 {
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+        }
+        if self.host_memory + size > MAX_HOST_MEMORY {
+            return VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY;
+        }
+
+        self.host_memory += size;
+        self.resources.insert(
+            cmd.resource_id,
+            Resource {
+                width: cmd.width,
+                height: cmd.height,
+                rgb,
+                data: vec![0; size as usize],
+                backing: Vec::new(),
+            },
+        );
+
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn unref(&mut self, resource_id: u32) -> u32 {
+        let resource = match self.resources.remove(&resource_id) {
+            Some(resource) => resource,
+            None => return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID,
+        };
+        self.host_memory -= resource.data.len() as u64;
+
+        if self.scanout.as_ref().map(|s| s.resource_id) == Some(resource_id) {
+            self.disable_scanout();
+        }
+
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn disable_scanout(&mut self) {
+        self.scanout = None;
+        self.framebuffer
+            .lock()
+            .unwrap()
+            .reset(self.width, self.height, false);
+    }
+
+    // Copy the part of the resource within `r` which is being displayed
+    // to the framebuffer.
+    fn update_framebuffer(&self, resource_id: u32, r: &VirtioGpuRect) {
+        let scanout = match &self.scanout {
+            Some(scanout) if scanout.resource_id == resource_id => scanout,
+            _ => return,
+        };
+        let r = match r.intersection(&scanout.r) {
+            Some(r) => r,
+            None => return,
+        };
+        // The scanout resource always exists.
+        let resource = &self.resources[&resource_id];
+
+        let src_offset = r.y as usize * resource.stride() + r.x as usize * 4;
+        self.framebuffer.lock().unwrap().update(
+            r.x - scanout.r.x,
+            r.y - scanout.r.y,
+            r.width,
+            r.height,
+            &resource.data,
+            src_offset,
+            resource.stride(),
+            resource.rgb,
+        );
+    }
+
+    fn set_scanout(&mut self, cmd: &VirtioGpuSetScanout) -> u32 {
+        if cmd.scanout_id != 0 {
+            return VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID;
+        }
+        if cmd.resource_id == 0 {
+            self.disable_scanout();
+            return VIRTIO_GPU_RESP_OK_NODATA;
+        }
+
+        let resource = match self.resources.get(&cmd.resource_id) {
+            Some(resource) => resource,
+            None => return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID,
+        };
+        if cmd.r.width == 0 || cmd.r.height == 0 || !cmd.r.fits(resource.width, resource.height) {
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+        }
+
+        self.framebuffer
+            .lock()
+            .unwrap()
+            .reset(cmd.r.width, cmd.r.height, true);
+        self.scanout = Some(Scanout {
+            resource_id: cmd.resource_id,
+            r: cmd.r,
+        });
+        self.update_framebuffer(cmd.resource_id, &cmd.r);
+
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn flush(&mut self, cmd: &VirtioGpuResourceFlush) -> u32 {
+        let resource = match self.resources.get(&cmd.resource_id) {
+            Some(resource) => resource,
+            None => return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID,
+        };
+        if !cmd.r.fits(resource.width, resource.height) {
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+        }
+
+        self.update_framebuffer(cmd.resource_id, &cmd.r);
+
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn transfer_to_host_2d(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        cmd: &VirtioGpuTransferToHost2d,
+    ) -> u32 {
+        let resource = match self.resources.get_mut(&cmd.resource_id) {
+            Some(resource) => resource,
+            None => return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID,
+        };
+        if resource.backing.is_empty() {
+            return VIRTIO_GPU_RESP_ERR_UNSPEC;
+        }
+        if !cmd.r.fits(resource.width, resource.height) {
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+        }
+
+        // Both the backing and the resource are laid out with the stride of
+        // the resource, the offset locating the first pixel of the
+        // rectangle in the backing.
+        let stride = resource.stride();
+        let row_len = cmd.r.width as usize * BYTES_PER_PIXEL as usize;
+        let mut data = std::mem::take(&mut resource.data);
+        let mut result = Ok(());
+        for row in 0..cmd.r.height as usize {
+            let src = cmd.offset.saturating_add((stride * row) as u64);
+            let dst = (cmd.r.y as usize + row) * stride + cmd.r.x as usize * 4;
+            result = resource.read_backing(mem, src, &mut data[dst..dst + row_len]);
+            if result.is_err() {
+                break;
+            }
+        }
+        resource.data = data;
+
+        match result {
+            Ok(()) => VIRTIO_GPU_RESP_OK_NODATA,
+            Err(e) => {
+                error!("Failed to transfer virtio-gpu resource: {}", e);
+                VIRTIO_GPU_RESP_ERR_UNSPEC
+            }
+        }
+    }
+
+    fn attach_backing(&mut self, request: &[u8]) -> u32 {
+        let cmd: VirtioGpuResourceAttachBacking = match read_obj(request) {
+            Some(cmd) => cmd,
+            None => return VIRTIO_GPU_RESP_ERR_UNSPEC,
+        };
+        let resource = match self.resources.get_mut(&cmd.resource_id) {
+            Some(resource) => resource,
+            None => return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID,
+        };
+        if !resource.backing.is_empty() {
+            return VIRTIO_GPU_RESP_ERR_UNSPEC;
+        }
+
+        // The entries follow the command.
+        let entries = &request[size_of::<VirtioGpuResourceAttachBacking>()..];
+        let nr_entries = cmd.nr_entries as usize;
+        if nr_entries == 0 || entries.len() < nr_entries * size_of::<VirtioGpuMemEntry>() {
+            return VIRTIO_GPU_RESP_ERR_UNSPEC;
+        }
+
+        resource.backing = entries
+            .chunks_exact(size_of::<VirtioGpuMemEntry>())
+            .take(nr_entries)
+            .filter_map(read_obj)
+            .collect();
+
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn detach_backing(&mut self, resource_id: u32) -> u32 {
+        match self.resources.get_mut(&resource_id) {
+            Some(resource) => {
+                resource.backing.clear();
+                VIRTIO_GPU_RESP_OK_NODATA
+            }
+            None => VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID,
+        }
+    }
+}
+
+struct GpuEpollHandler {
+    queues: Vec<Queue>,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evts: Vec<EventFd>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    state: GpuState,
+}
+
+impl GpuEpollHandler {
+    // Run the command in `request`, returning the response type or, for
+    // the display info, the whole response.
+    fn handle_command(&mut self, mem: &GuestMemoryMmap, type_: u32, request: &[u8]) -> Vec<u8> {
+        macro_rules! cmd {
+            ($t:ty) => {
+                match read_obj::<$t>(request) {
+                    Some(cmd) => cmd,
+                    None => return response_hdr(VIRTIO_GPU_RESP_ERR_UNSPEC),
+                }
+            };
+        }
+
+        let resp = match type_ {
+            VIRTIO_GPU_CMD_GET_DISPLAY_INFO => {
+                return self.state.display_info().as_slice().to_vec();
+            }
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D => {
+                self.state.create_2d(&cmd!(VirtioGpuResourceCreate2d))
+            }
+            VIRTIO_GPU_CMD_RESOURCE_UNREF => {
+                self.state.unref(cmd!(VirtioGpuResourceCmd).resource_id)
+            }
+            VIRTIO_GPU_CMD_SET_SCANOUT => self.state.set_scanout(&cmd!(VirtioGpuSetScanout)),
+            VIRTIO_GPU_CMD_RESOURCE_FLUSH => self.state.flush(&cmd!(VirtioGpuResourceFlush)),
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D => self
+                .state
+                .transfer_to_host_2d(mem, &cmd!(VirtioGpuTransferToHost2d)),
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => self.state.attach_backing(request),
+            VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING => self
+                .state
+                .detach_backing(cmd!(VirtioGpuResourceCmd).resource_id),
+            _ => {
+                warn!("Unsupported virtio-gpu command 0x{:x}", type_);
+                VIRTIO_GPU_RESP_ERR_UNSPEC
+            }
+        };
+
+        response_hdr(resp)
+    }
+
+    fn handle_request(&mut self, mem: &GuestMemoryMmap, request: &[u8]) -> Vec<u8> {
+        let hdr: VirtioGpuCtrlHdr = match read_obj(request) {
+            Some(hdr) => hdr,
+            None => return response_hdr(VIRTIO_GPU_RESP_ERR_UNSPEC),
+        };
+
+        let mut response = self.handle_command(mem, hdr.type_, request);
+
+        // Commands complete synchronously, so fenced ones can simply report
+        // their fence right away.
+        if hdr.flags & VIRTIO_GPU_FLAG_FENCE != 0 {
+            // The response always starts with its header.
+            let mut resp_hdr: VirtioGpuCtrlHdr = read_obj(&response).unwrap();
+            resp_hdr.flags |= VIRTIO_GPU_FLAG_FENCE;
+            resp_hdr.fence_id = hdr.fence_id;
+            resp_hdr.ctx_id = hdr.ctx_id;
+            response[..size_of::<VirtioGpuCtrlHdr>()].copy_from_slice(resp_hdr.as_slice());
+        }
+
+        response
+    }
+
+    fn process_control_queue(&mut self) -> bool {
+        let mem = self.mem.memory();
+
+        let mut requests = Vec::new();
+        for avail_desc in self.queues[CONTROL_QUEUE].iter(&mem) {
+            requests.push((avail_desc.index, Buffers::parse(&mem, &avail_desc)));
+        }
+
+        for &(desc_index, ref buffers) in requests.iter() {
+            let mut len = 0;
+            match buffers {
+                Ok(buffers) => {
+                    let response = self.handle_request(&mem, &buffers.readable);
+                    match buffers.write(&mem, &response) {
+                        Ok(()) => len = response.len() as u32,
+                        Err(e) => error!("Failed to write virtio-gpu response: {}", e),
+                    }
+                }
+                Err(e) => error!("Failed to parse virtio-gpu command: {}", e),
+            }
+            self.queues[CONTROL_QUEUE].add_used(&mem, desc_index, len);
+        }
+
+        !requests.is_empty()
+    }
+
+    fn process_cursor_queue(&mut self) -> bool {
+        let mem = self.mem.memory();
+
+        // There is no cursor to update, the commands are simply returned.
+        let mut used_desc_heads = Vec::new();
+        for avail_desc in self.queues[CURSOR_QUEUE].iter(&mem) {
+            used_desc_heads.push(avail_desc.index);
+        }
+
+        for &desc_index in used_desc_heads.iter() {
+            self.queues[CURSOR_QUEUE].add_used(&mem, desc_index, 0);
+        }
+
+        !used_desc_heads.is_empty()
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(
+            self.queue_evts[CONTROL_QUEUE].as_raw_fd(),
+            CONTROL_QUEUE_EVENT,
+        )?;
+        helper.add_event(
+            self.queue_evts[CURSOR_QUEUE].as_raw_fd(),
+            CURSOR_QUEUE_EVENT,
+        )?;
+        helper.run(paused, self)?;
+
+        Ok(())
+    }
+}
+
+fn response_hdr(type_: u32) -> Vec<u8> {
+    VirtioGpuCtrlHdr {
+        type_,
+        ..Default::default()
+    }
+    .as_slice()
+    .to_vec()
+}
+
+impl EpollHelperHandler for GpuEpollHandler {
+    fn handle_event(&mut self, _helper: &mut EpollHelper, event: u16) -> bool {
+        let queue_index = match event {
+            CONTROL_QUEUE_EVENT => CONTROL_QUEUE,
+            CURSOR_QUEUE_EVENT => CURSOR_QUEUE,
+            _ => {
+                error!("Unexpected event: {}", event);
+                return true;
+            }
+        };
+
+        if let Err(e) = self.queue_evts[queue_index].read() {
+            error!("Failed to get queue event: {:?}", e);
+            return true;
+        }
+
+        let processed = if queue_index == CONTROL_QUEUE {
+            self.process_control_queue()
+        } else {
+            self.process_cursor_queue()
+        };
+
+        if processed {
+            if let Err(e) = self.signal_used_queue(queue_index) {
+                error!("Failed to signal used queue: {:?}", e);
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+/// Virtio device providing a 2D display to the guest.
+pub struct Gpu {
+    id: String,
+    kill_evt: Option<EventFd>,
+    pause_evt: Option<EventFd>,
+    avail_features: u64,
+    acked_features: u64,
+    config: VirtioGpuConfig,
+    width: u32,
+    height: u32,
+    framebuffer: Arc<Mutex<Framebuffer>>,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), EpollHelperError>>>>,
+    paused: Arc<AtomicBool>,
+}
+
+impl Gpu {
+    /// Create a new virtio GPU device, whose single scanout is advertised
+    /// to the guest with the given size.
+    pub fn new(id: String, width: u32, height: u32, iommu: bool) -> Gpu {
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+        if iommu {
+            avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+        }
+
+        let config = VirtioGpuConfig {
+            num_scanouts: 1,
+            ..Default::default()
+        };
+
+        Gpu {
+            id,
+            kill_evt: None,
+            pause_evt: None,
+            avail_features,
+            acked_features: 0u64,
+            config,
+            width,
+            height,
+            framebuffer: Arc::new(Mutex::new(Framebuffer::new(width, height))),
+            queue_evts: None,
+            interrupt_cb: None,
+            epoll_threads: None,
+            paused: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Framebuffer the scanout is displayed to.
+    pub fn framebuffer(&self) -> Arc<Mutex<Framebuffer>> {
+        self.framebuffer.clone()
+    }
+}
+
+impl Drop for Gpu {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Gpu {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_GPU as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(self.config.as_slice(), offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Only events_clear is writable, and no event is ever raised.
+        if offset != 4 || data.len() != 4 {
+            warn!(
+                "Invalid virtio-gpu config write: offset 0x{:x}, length {}",
+                offset,
+                data.len()
+            );
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating kill EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.kill_evt = Some(self_kill_evt);
+
+        let (self_pause_evt, pause_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating pause EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.pause_evt = Some(self_pause_evt);
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the thread.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        // A driver being activated starts without any resource, and with
+        // nothing displayed.
+        self.framebuffer
+            .lock()
+            .unwrap()
+            .reset(self.width, self.height, false);
+
+        let mut handler = GpuEpollHandler {
+            queues,
+            mem,
+            interrupt_cb,
+            queue_evts,
+            kill_evt,
+            pause_evt,
+            state: GpuState::new(self.width, self.height, self.framebuffer.clone()),
+        };
+
+        let paused = self.paused.clone();
+        let mut epoll_threads = Vec::new();
+        thread::Builder::new()
+            .name("virtio_gpu".to_string())
+            .spawn(move || handler.run(paused))
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(|e| {
+                error!("failed to clone the virtio-gpu epoll thread: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.epoll_threads = Some(epoll_threads);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // We first must resume the virtio thread if it was paused.
+        if self.pause_evt.take().is_some() {
+            self.resume().ok()?;
+        }
+
+        // Then kill it.
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+}
+
+virtio_pausable!(Gpu);
+
+impl Snapshottable for Gpu {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        // The resources only live in the host memory of the device thread.
+        Err(MigratableError::Snapshot(anyhow!(
+            "Cannot snapshot virtio-gpu device {}",
+            self.id
+        )))
+    }
+
+    fn restore(&mut self, _snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        Err(MigratableError::Restore(anyhow!(
+            "Cannot restore virtio-gpu device {}",
+            self.id
+        )))
+    }
+}
+
+impl Transportable for Gpu {}
+impl Migratable for Gpu {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::NoopVirtioInterrupt;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const MEM_SIZE: usize = 0x10_0000;
+    const REQUEST_ADDR: u64 = 0x2_0000;
+    const RESPONSE_ADDR: u64 = 0x3_0000;
+    const BACKING_ADDR: u64 = 0x8_0000;
+
+    fn create_handler(mem: &GuestMemoryMmap, control: &GuestQ, cursor: &GuestQ) -> GpuEpollHandler {
+        GpuEpollHandler {
+            queues: vec![control.create_queue(), cursor.create_queue()],
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            queue_evts: (0..NUM_QUEUES)
+                .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+                .collect(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            state: GpuState::new(4, 2, Arc::new(Mutex::new(Framebuffer::new(4, 2)))),
+        }
+    }
+
+    // Make the `index`th command available on the control queue and return
+    // the response.
+    fn control_request(
+        mem: &GuestMemoryMmap,
+        control: &GuestQ,
+        handler: &mut GpuEpollHandler,
+        index: u16,
+        request: &[u8],
+    ) -> Vec<u8> {
+        let request_addr = REQUEST_ADDR + u64::from(index) * 0x100;
+        let response_addr = RESPONSE_ADDR + u64::from(index) * 0x1000;
+        mem.write_slice(request, GuestAddress(request_addr))
+            .unwrap();
+
+        let desc = index * 2;
+        control.dtable[desc as usize].set(
+            request_addr,
+            request.len() as u32,
+            VIRTQ_DESC_F_NEXT,
+            desc + 1,
+        );
+        control.dtable[desc as usize + 1].set(
+            response_addr,
+            size_of::<VirtioGpuRespDisplayInfo>() as u32,
+            VIRTQ_DESC_F_WRITE,
+            0,
+        );
+        control.avail.ring[index as usize].set(desc);
+        control.avail.idx.set(index + 1);
+
+        assert!(handler.process_control_queue());
+        assert_eq!(control.used.idx.get(), index + 1);
+
+        let len = control.used.ring[index as usize].get().len;
+        let mut response = vec![0u8; len as usize];
+        mem.read_slice(&mut response, GuestAddress(response_addr))
+            .unwrap();
+        response
+    }
+
+    fn hdr(type_: u32) -> VirtioGpuCtrlHdr {
+        VirtioGpuCtrlHdr {
+            type_,
+            ..Default::default()
+        }
+    }
+
+    fn response_type(response: &[u8]) -> u32 {
+        read_obj::<VirtioGpuCtrlHdr>(response).unwrap().type_
+    }
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> VirtioGpuRect {
+        VirtioGpuRect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn test_gpu_display_info() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let control = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let cursor = GuestQ::new(GuestAddress(0x1_4000), &mem, 16);
+        let mut handler = create_handler(&mem, &control, &cursor);
+
+        let mut request = hdr(VIRTIO_GPU_CMD_GET_DISPLAY_INFO);
+        request.flags = VIRTIO_GPU_FLAG_FENCE;
+        request.fence_id = 42;
+        let response = control_request(&mem, &control, &mut handler, 0, request.as_slice());
+        assert_eq!(response.len(), size_of::<VirtioGpuRespDisplayInfo>());
+
+        let info: VirtioGpuRespDisplayInfo = read_obj(&response).unwrap();
+        let (type_, flags, fence_id) = (info.hdr.type_, info.hdr.flags, info.hdr.fence_id);
+        assert_eq!(type_, VIRTIO_GPU_RESP_OK_DISPLAY_INFO);
+        assert_eq!(flags, VIRTIO_GPU_FLAG_FENCE);
+        assert_eq!(fence_id, 42);
+        let (r, enabled) = (info.pmodes[0].r, info.pmodes[0].enabled);
+        assert_eq!(r, rect(0, 0, 4, 2));
+        assert_eq!(enabled, 1);
+        let enabled = info.pmodes[1].enabled;
+        assert_eq!(enabled, 0);
+
+        let response = control_request(&mem, &control, &mut handler, 1, hdr(0x42).as_slice());
+        assert_eq!(response_type(&response), VIRTIO_GPU_RESP_ERR_UNSPEC);
+    }
+
+    #[test]
+    fn test_gpu_scanout_update() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let control = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        let cursor = GuestQ::new(GuestAddress(0x1_4000), &mem, 16);
+        let mut handler = create_handler(&mem, &control, &cursor);
+        let framebuffer = handler.state.framebuffer.clone();
+
+        let mut index = 0;
+        let mut request = |handler: &mut GpuEpollHandler, request: &[u8]| {
+            let response = control_request(&mem, &control, handler, index, request);
+            index += 1;
+            response_type(&response)
+        };
+
+        let create = VirtioGpuResourceCreate2d {
+            hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D),
+            resource_id: 1,
+            format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+            width: 4,
+            height: 2,
+        };
+        assert_eq!(
+            request(&mut handler, create.as_slice()),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        // Resource ids can't be reused.
+        assert_eq!(
+            request(&mut handler, create.as_slice()),
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+
+        // Backing split in two entries, the second one holding the second
+        // row of pixels.
+        let mut attach = VirtioGpuResourceAttachBacking {
+            hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING),
+            resource_id: 1,
+            nr_entries: 2,
+        }
+        .as_slice()
+        .to_vec();
+        for (i, addr) in [BACKING_ADDR, BACKING_ADDR + 0x1000].iter().enumerate() {
+            let pixels: Vec<u8> = (0..4u8).flat_map(|x| vec![x, i as u8, 0x80, 0]).collect();
+            mem.write_slice(&pixels, GuestAddress(*addr)).unwrap();
+            let entry = VirtioGpuMemEntry {
+                addr: *addr,
+                length: 16,
+                padding: 0,
+            };
+            attach.extend_from_slice(entry.as_slice());
+        }
+        assert_eq!(request(&mut handler, &attach), VIRTIO_GPU_RESP_OK_NODATA);
+
+        let transfer = VirtioGpuTransferToHost2d {
+            hdr: hdr(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
+            r: rect(0, 0, 4, 2),
+            offset: 0,
+            resource_id: 1,
+            padding: 0,
+        };
+        assert_eq!(
+            request(&mut handler, transfer.as_slice()),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        // Out of bounds rectangles are refused.
+        let set_scanout = |r, resource_id| VirtioGpuSetScanout {
+            hdr: hdr(VIRTIO_GPU_CMD_SET_SCANOUT),
+            r,
+            scanout_id: 0,
+            resource_id,
+        };
+        assert_eq!(
+            request(&mut handler, set_scanout(rect(1, 0, 4, 2), 1).as_slice()),
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+        assert_eq!(
+            request(&mut handler, set_scanout(rect(0, 0, 4, 2), 2).as_slice()),
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+        assert!(!framebuffer.lock().unwrap().enabled());
+
+        // Displaying the right half of the resource.
+        assert_eq!(
+            request(&mut handler, set_scanout(rect(2, 0, 2, 2), 1).as_slice()),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        {
+            let fb = framebuffer.lock().unwrap();
+            assert!(fb.enabled());
+            assert_eq!((fb.width(), fb.height()), (2, 2));
+            assert_eq!(fb.pixel(0, 0), [0x80, 0, 2]);
+            assert_eq!(fb.pixel(1, 1), [0x80, 1, 3]);
+        }
+
+        // Update the last pixel and flush it only.
+        mem.write_slice(&[0xff, 0xff, 0xff, 0], GuestAddress(BACKING_ADDR + 0x100c))
+            .unwrap();
+        let transfer = VirtioGpuTransferToHost2d {
+            r: rect(3, 1, 1, 1),
+            offset: 28,
+            ..transfer
+        };
+        assert_eq!(
+            request(&mut handler, transfer.as_slice()),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert_eq!(framebuffer.lock().unwrap().pixel(1, 1), [0x80, 1, 3]);
+        let flush = VirtioGpuResourceFlush {
+            hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_FLUSH),
+            r: rect(0, 0, 4, 2),
+            resource_id: 1,
+            padding: 0,
+        };
+        assert_eq!(
+            request(&mut handler, flush.as_slice()),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert_eq!(framebuffer.lock().unwrap().pixel(1, 1), [0xff, 0xff, 0xff]);
+        assert_eq!(framebuffer.lock().unwrap().pixel(0, 1), [0x80, 1, 2]);
+
+        // Releasing the resource disables the scanout.
+        let unref = VirtioGpuResourceCmd {
+            hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_UNREF),
+            resource_id: 1,
+            padding: 0,
+        };
+        assert_eq!(
+            request(&mut handler, unref.as_slice()),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert!(!framebuffer.lock().unwrap().enabled());
+        assert_eq!(handler.state.host_memory, 0);
+    }
+
+    #[test]
+    fn test_gpu_snapshot_refused() {
+        let gpu = Gpu::new("_gpu".to_string(), 1280, 800, false);
+        assert!(gpu.snapshot().is_err());
+    }
+}
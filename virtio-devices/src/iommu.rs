@@ -51,15 +51,16 @@ const KILL_EVENT: DeviceEventT = 2;
 const PAUSE_EVENT: DeviceEventT = 3;
 
 /// PROBE properties size.
-/// This is the minimal size to provide at least one RESV_MEM property.
-/// Because virtio-iommu expects one MSI reserved region, we must provide it,
-/// otherwise the driver in the guest will define a predefined one between
-/// 0x8000000 and 0x80FFFFF, which is only relevant for ARM architecture, but
-/// will conflict with x86.
-const PROBE_PROP_SIZE: u32 =
-    (size_of::<VirtioIommuProbeProperty>() + size_of::<VirtioIommuProbeResvMem>()) as u32;
-const MSI_IOVA_START: u64 = 0xfee0_0000;
-const MSI_IOVA_END: u64 = 0xfeef_ffff;
+/// Because virtio-iommu expects one MSI reserved region, we must always
+/// provide it, otherwise the driver in the guest will define a predefined one
+/// between 0x8000000 and 0x80FFFFF, which is only relevant for ARM
+/// architecture, but will conflict with x86. The remaining space holds the
+/// reserved regions of the endpoint, the unused part being filled with zeros,
+/// which the driver reads as a VIRTIO_IOMMU_PROBE_T_NONE property ending the
+/// list.
+const PROBE_PROP_SIZE: u32 = 0x200;
+const RESV_MEM_PROP_SIZE: usize =
+    size_of::<VirtioIommuProbeProperty>() + size_of::<VirtioIommuProbeResvMem>();
 
 /// Virtio IOMMU features
 #[allow(unused)]
@@ -68,7 +69,6 @@ const VIRTIO_IOMMU_F_INPUT_RANGE: u32 = 0;
 const VIRTIO_IOMMU_F_DOMAIN_BITS: u32 = 1;
 #[allow(unused)]
 const VIRTIO_IOMMU_F_MAP_UNMAP: u32 = 2;
-#[allow(unused)]
 const VIRTIO_IOMMU_F_BYPASS: u32 = 3;
 const VIRTIO_IOMMU_F_PROBE: u32 = 4;
 #[allow(unused)]
@@ -276,7 +276,6 @@ struct VirtioIommuProbeProperty {
 unsafe impl ByteValued for VirtioIommuProbeProperty {}
 
 /// Virtio IOMMU request PROBE property RESV_MEM subtypes
-#[allow(unused)]
 const VIRTIO_IOMMU_RESV_MEM_T_RESERVED: u8 = 0;
 const VIRTIO_IOMMU_RESV_MEM_T_MSI: u8 = 1;
 
@@ -291,6 +290,70 @@ struct VirtioIommuProbeResvMem {
 
 unsafe impl ByteValued for VirtioIommuProbeResvMem {}
 
+/// Range of I/O virtual addresses, both ends included, which an endpoint
+/// can't use for its DMA, reported to the guest through the PROBE request.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ReservedRegion {
+    pub start: u64,
+    pub end: u64,
+}
+
+// Reserved regions of all the endpoints.
+#[derive(Clone, Default)]
+struct ReservedRegions {
+    // MSI doorbell, reserved on every endpoint.
+    msi: ReservedRegion,
+    // Regions registered by the devices behind each endpoint.
+    endpoints: BTreeMap<u32, Vec<ReservedRegion>>,
+}
+
+impl ReservedRegions {
+    // Encode the PROBE properties of `endpoint`, with one RESV_MEM property
+    // per reserved region, the MSI doorbell first. The properties always fill
+    // PROBE_PROP_SIZE bytes.
+    fn probe_properties(&self, endpoint: u32) -> Vec<u8> {
+        let mut props = Vec::with_capacity(PROBE_PROP_SIZE as usize);
+        let regions = self
+            .endpoints
+            .get(&endpoint)
+            .map_or(&[][..], |r| r.as_slice());
+        let max_regions = PROBE_PROP_SIZE as usize / RESV_MEM_PROP_SIZE;
+
+        let resv_mems = std::iter::once((VIRTIO_IOMMU_RESV_MEM_T_MSI, &self.msi)).chain(
+            regions
+                .iter()
+                .map(|region| (VIRTIO_IOMMU_RESV_MEM_T_RESERVED, region)),
+        );
+        if regions.len() >= max_regions {
+            warn!(
+                "Only reporting {} reserved regions out of {} for endpoint {}",
+                max_regions - 1,
+                regions.len(),
+                endpoint
+            );
+        }
+
+        for (subtype, region) in resv_mems.take(max_regions) {
+            let prop = VirtioIommuProbeProperty {
+                type_: VIRTIO_IOMMU_PROBE_T_RESV_MEM,
+                length: size_of::<VirtioIommuProbeResvMem>() as u16,
+            };
+            props.extend_from_slice(prop.as_slice());
+
+            let resv_mem = VirtioIommuProbeResvMem {
+                subtype,
+                start: region.start,
+                end: region.end,
+                ..Default::default()
+            };
+            props.extend_from_slice(resv_mem.as_slice());
+        }
+
+        props.resize(PROBE_PROP_SIZE as usize, 0);
+        props
+    }
+}
+
 /// Virtio IOMMU fault flags
 #[allow(unused)]
 const VIRTIO_IOMMU_FAULT_F_READ: u32 = 1;
@@ -393,6 +456,7 @@ impl Request {
         mapping: &Arc<IommuMapping>,
         ext_mapping: &BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
         ext_domain_mapping: &mut BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
+        reserved_regions: &ReservedRegions,
     ) -> result::Result<usize, Error> {
         // The head contains the request type which MUST be readable.
         if avail_desc.is_write_only() {
@@ -434,19 +498,24 @@ impl Request {
                 // Add endpoint associated with specific domain
                 mapping.endpoints.write().unwrap().insert(endpoint, domain);
 
+                // Add new domain with no mapping if the entry didn't exist yet
+                let mut mappings = mapping.mappings.write().unwrap();
+                let domain_mappings = mappings.entry(domain).or_insert_with(BTreeMap::new);
+
                 // If the endpoint is part of the list of devices with an
                 // external mapping, insert a new entry for the corresponding
-                // domain, with the same reference to the trait.
+                // domain, with the same reference to the trait. The device
+                // starts without any mapping, so the ones the domain already
+                // has must be replayed for it to see the same address space
+                // as the other endpoints.
                 if let Some(map) = ext_mapping.get(&endpoint) {
+                    for (iova, m) in domain_mappings.iter() {
+                        map.map(*iova, m.gpa, m.size)
+                            .map_err(Error::ExternalMapping)?;
+                    }
                     ext_domain_mapping.insert(domain, map.clone());
                 }
 
-                // Add new domain with no mapping if the entry didn't exist yet
-                let mut mappings = mapping.mappings.write().unwrap();
-                if !mappings.contains_key(&domain) {
-                    mappings.insert(domain, BTreeMap::new());
-                }
-
                 0
             }
             VIRTIO_IOMMU_T_DETACH => {
@@ -465,8 +534,14 @@ impl Request {
 
                 // If the endpoint is part of the list of devices with an
                 // external mapping, remove the entry for the corresponding
-                // domain.
-                if ext_mapping.contains_key(&endpoint) {
+                // domain, and tear down the mappings the device was given so
+                // that it can't reach the guest memory anymore.
+                if let Some(map) = ext_mapping.get(&endpoint) {
+                    if let Some(domain_mappings) = mapping.mappings.read().unwrap().get(&domain) {
+                        for (iova, m) in domain_mappings.iter() {
+                            map.unmap(*iova, m.size).map_err(Error::ExternalUnmapping)?;
+                        }
+                    }
                     ext_domain_mapping.remove(&domain);
                 }
 
@@ -550,19 +625,7 @@ impl Request {
                     .map_err(Error::GuestMemory)?;
                 debug!("Probe request {:?}", req);
 
-                let probe_prop = VirtioIommuProbeProperty {
-                    type_: VIRTIO_IOMMU_PROBE_T_RESV_MEM,
-                    length: size_of::<VirtioIommuProbeResvMem>() as u16,
-                };
-                reply.extend_from_slice(probe_prop.as_slice());
-
-                let resv_mem = VirtioIommuProbeResvMem {
-                    subtype: VIRTIO_IOMMU_RESV_MEM_T_MSI,
-                    start: MSI_IOVA_START,
-                    end: MSI_IOVA_END,
-                    ..Default::default()
-                };
-                reply.extend_from_slice(resv_mem.as_slice());
+                reply.extend_from_slice(&reserved_regions.probe_properties(req.endpoint));
 
                 PROBE_PROP_SIZE
             }
@@ -605,6 +668,7 @@ struct IommuEpollHandler {
     mapping: Arc<IommuMapping>,
     ext_mapping: BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
     ext_domain_mapping: BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
+    reserved_regions: ReservedRegions,
 }
 
 impl IommuEpollHandler {
@@ -619,6 +683,7 @@ impl IommuEpollHandler {
                 &self.mapping,
                 &self.ext_mapping,
                 &mut self.ext_domain_mapping,
+                &self.reserved_regions,
             ) {
                 Ok(len) => len as u32,
                 Err(e) => {
@@ -784,24 +849,40 @@ pub struct IommuMapping {
     endpoints: Arc<RwLock<BTreeMap<u32, u32>>>,
     // List of mappings per domain.
     mappings: Arc<RwLock<BTreeMap<u32, BTreeMap<u64, Mapping>>>>,
+    // Whether endpoints not attached to any domain can access the guest
+    // memory directly. This is always the case until the driver completes
+    // the features negotiation, and remains true afterwards only if the
+    // driver acknowledged VIRTIO_IOMMU_F_BYPASS.
+    bypass: AtomicBool,
 }
 
 impl DmaRemapping for IommuMapping {
     fn translate(&self, id: u32, addr: u64) -> std::result::Result<u64, std::io::Error> {
         debug!("Translate addr 0x{:x}", addr);
-        if let Some(domain) = self.endpoints.read().unwrap().get(&id) {
-            if let Some(mapping) = self.mappings.read().unwrap().get(domain) {
-                let range_start = if VIRTIO_IOMMU_PAGE_SIZE_MASK > addr {
-                    0
-                } else {
-                    addr - VIRTIO_IOMMU_PAGE_SIZE_MASK
-                };
-                for (&key, &value) in mapping.range((Included(&range_start), Included(&addr))) {
-                    if addr >= key && addr < key + value.size {
-                        let new_addr = addr - key + value.gpa;
-                        debug!("Into new_addr 0x{:x}", new_addr);
-                        return Ok(new_addr);
-                    }
+        let endpoints = self.endpoints.read().unwrap();
+        let domain = if let Some(domain) = endpoints.get(&id) {
+            domain
+        } else if self.bypass.load(Ordering::Acquire) {
+            debug!("Bypass addr 0x{:x}", addr);
+            return Ok(addr);
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("endpoint {} is not attached to any domain", id),
+            ));
+        };
+
+        if let Some(mapping) = self.mappings.read().unwrap().get(domain) {
+            let range_start = if VIRTIO_IOMMU_PAGE_SIZE_MASK > addr {
+                0
+            } else {
+                addr - VIRTIO_IOMMU_PAGE_SIZE_MASK
+            };
+            for (&key, &value) in mapping.range((Included(&range_start), Included(&addr))) {
+                if addr >= key && addr < key + value.size {
+                    let new_addr = addr - key + value.gpa;
+                    debug!("Into new_addr 0x{:x}", new_addr);
+                    return Ok(new_addr);
                 }
             }
         }
@@ -821,6 +902,7 @@ pub struct Iommu {
     config_topo_pci_ranges: Vec<VirtioIommuTopoPciRange>,
     mapping: Arc<IommuMapping>,
     ext_mapping: BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
+    reserved_regions: ReservedRegions,
     queue_evts: Option<Vec<EventFd>>,
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
@@ -836,7 +918,9 @@ struct IommuState {
 }
 
 impl Iommu {
-    pub fn new(id: String) -> io::Result<(Self, Arc<IommuMapping>)> {
+    /// Create a virtio-iommu device. `msi_region` is where the guest MSI
+    /// messages are written to, which gets reserved on every endpoint.
+    pub fn new(id: String, msi_region: ReservedRegion) -> io::Result<(Self, Arc<IommuMapping>)> {
         let config = VirtioIommuConfig {
             page_size_mask: VIRTIO_IOMMU_PAGE_SIZE_MASK,
             probe_size: PROBE_PROP_SIZE,
@@ -846,6 +930,7 @@ impl Iommu {
         let mapping = Arc::new(IommuMapping {
             endpoints: Arc::new(RwLock::new(BTreeMap::new())),
             mappings: Arc::new(RwLock::new(BTreeMap::new())),
+            bypass: AtomicBool::new(true),
         });
 
         Ok((
@@ -855,12 +940,17 @@ impl Iommu {
                 pause_evt: None,
                 avail_features: 1u64 << VIRTIO_F_VERSION_1
                     | 1u64 << VIRTIO_IOMMU_F_MAP_UNMAP
-                    | 1u64 << VIRTIO_IOMMU_F_PROBE,
+                    | 1u64 << VIRTIO_IOMMU_F_PROBE
+                    | 1u64 << VIRTIO_IOMMU_F_BYPASS,
                 acked_features: 0u64,
                 config,
                 config_topo_pci_ranges: Vec::new(),
                 mapping: mapping.clone(),
                 ext_mapping: BTreeMap::new(),
+                reserved_regions: ReservedRegions {
+                    msi: msi_region,
+                    endpoints: BTreeMap::new(),
+                },
                 queue_evts: None,
                 interrupt_cb: None,
                 epoll_threads: None,
@@ -884,6 +974,7 @@ impl Iommu {
         self.acked_features = state.acked_features;
         *(self.mapping.endpoints.write().unwrap()) = state.endpoints.clone();
         *(self.mapping.mappings.write().unwrap()) = state.mappings.clone();
+        self.update_bypass();
 
         Ok(())
     }
@@ -927,9 +1018,27 @@ impl Iommu {
         self.config.topo_config.offset = size_of::<VirtioIommuConfig>() as u16;
     }
 
+    // Unattached endpoints only keep bypassing the translation once the
+    // driver has negotiated VIRTIO_IOMMU_F_BYPASS.
+    fn update_bypass(&self) {
+        let bypass = self.acked_features & (1u64 << VIRTIO_IOMMU_F_BYPASS) != 0;
+        self.mapping.bypass.store(bypass, Ordering::Release);
+    }
+
     pub fn add_external_mapping(&mut self, device_id: u32, mapping: Arc<dyn ExternalDmaMapping>) {
         self.ext_mapping.insert(device_id, mapping);
     }
+
+    /// Register the ranges of I/O virtual addresses the device behind the
+    /// endpoint `device_id` can't use, which get reported to the guest when
+    /// it probes the endpoint.
+    pub fn add_reserved_regions(&mut self, device_id: u32, regions: Vec<ReservedRegion>) {
+        self.reserved_regions
+            .endpoints
+            .entry(device_id)
+            .or_insert_with(Vec::new)
+            .extend(regions);
+    }
 }
 
 impl Drop for Iommu {
@@ -1024,6 +1133,8 @@ impl VirtioDevice for Iommu {
         }
         self.queue_evts = Some(tmp_queue_evts);
 
+        self.update_bypass();
+
         let mut handler = IommuEpollHandler {
             queues,
             mem,
@@ -1034,6 +1145,7 @@ impl VirtioDevice for Iommu {
             mapping: self.mapping.clone(),
             ext_mapping: self.ext_mapping.clone(),
             ext_domain_mapping: BTreeMap::new(),
+            reserved_regions: self.reserved_regions.clone(),
         };
 
         let paused = self.paused.clone();
@@ -1063,6 +1175,10 @@ impl VirtioDevice for Iommu {
             let _ = kill_evt.write(1);
         }
 
+        // Back to the default behavior until the features are negotiated
+        // again.
+        self.mapping.bypass.store(true, Ordering::Release);
+
         // Return the interrupt and queue EventFDs
         Some((
             self.interrupt_cb.take().unwrap(),
@@ -1114,3 +1230,416 @@ impl Snapshottable for Iommu {
 }
 impl Transportable for Iommu {}
 impl Migratable for Iommu {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const MEM_SIZE: usize = 0x10_0000;
+    const MSI_REGION: ReservedRegion = ReservedRegion {
+        start: 0xfee0_0000,
+        end: 0xfeef_ffff,
+    };
+
+    fn create_mapping() -> Arc<IommuMapping> {
+        let (_, mapping) = Iommu::new("iommu0".to_string(), MSI_REGION).unwrap();
+        mapping
+    }
+
+    // Decode the RESV_MEM properties, checking they're followed by zeros.
+    fn decode_resv_mems(props: &[u8]) -> Vec<(u8, u64, u64)> {
+        let mut resv_mems = Vec::new();
+        let mut offset = 0;
+        while offset + size_of::<VirtioIommuProbeProperty>() <= props.len() {
+            let prop = VirtioIommuProbeProperty::from_slice(
+                &props[offset..offset + size_of::<VirtioIommuProbeProperty>()],
+            )
+            .unwrap();
+            offset += size_of::<VirtioIommuProbeProperty>();
+
+            let (type_, length) = (prop.type_, prop.length);
+            match type_ & VIRTIO_IOMMU_PROBE_T_MASK {
+                VIRTIO_IOMMU_PROBE_T_NONE => {
+                    assert_eq!(length, 0);
+                    break;
+                }
+                VIRTIO_IOMMU_PROBE_T_RESV_MEM => {
+                    assert_eq!(length as usize, size_of::<VirtioIommuProbeResvMem>());
+                    let resv = VirtioIommuProbeResvMem::from_slice(
+                        &props[offset..offset + length as usize],
+                    )
+                    .unwrap();
+                    resv_mems.push((resv.subtype, resv.start, resv.end));
+                }
+                t => panic!("unexpected probe property {}", t),
+            }
+            offset += length as usize;
+        }
+        assert!(props[offset..].iter().all(|b| *b == 0));
+
+        resv_mems
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum DmaOp {
+        Map(u64, u64, u64),
+        Unmap(u64, u64),
+    }
+
+    // Stands for the VFIO container of a device, recording the DMA mapping
+    // updates it would go through.
+    #[derive(Default)]
+    struct RecordingDmaMapping {
+        ops: Mutex<Vec<DmaOp>>,
+    }
+
+    impl ExternalDmaMapping for RecordingDmaMapping {
+        fn map(&self, iova: u64, gpa: u64, size: u64) -> std::result::Result<(), io::Error> {
+            self.ops.lock().unwrap().push(DmaOp::Map(iova, gpa, size));
+            Ok(())
+        }
+
+        fn unmap(&self, iova: u64, size: u64) -> std::result::Result<(), io::Error> {
+            self.ops.lock().unwrap().push(DmaOp::Unmap(iova, size));
+            Ok(())
+        }
+    }
+
+    // Have the device parse a single request of the given type.
+    fn parse_request<T: ByteValued>(
+        mem: &GuestMemoryMmap,
+        type_: u8,
+        req: T,
+        mapping: &Arc<IommuMapping>,
+        ext_mapping: &BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
+        ext_domain_mapping: &mut BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
+    ) -> result::Result<usize, Error> {
+        let guest_requestq = GuestQ::new(GuestAddress(0x1_0000), mem, 16);
+
+        let req_addr = GuestAddress(0x2_0000);
+        let reply_addr = GuestAddress(0x3_0000);
+        let req_len = size_of::<VirtioIommuReqHead>() + size_of::<T>();
+
+        mem.write_obj(
+            VirtioIommuReqHead {
+                type_,
+                ..Default::default()
+            },
+            req_addr,
+        )
+        .unwrap();
+        mem.write_obj(
+            req,
+            req_addr.unchecked_add(size_of::<VirtioIommuReqHead>() as u64),
+        )
+        .unwrap();
+
+        guest_requestq.dtable[0].set(req_addr.raw_value(), req_len as u32, VIRTQ_DESC_F_NEXT, 1);
+        guest_requestq.dtable[1].set(
+            reply_addr.raw_value(),
+            size_of::<VirtioIommuReqTail>() as u32,
+            VIRTQ_DESC_F_WRITE,
+            0,
+        );
+        guest_requestq.avail.ring[0].set(0);
+        guest_requestq.avail.idx.set(1);
+
+        let mut queue = guest_requestq.create_queue();
+        let avail_desc = queue.iter(mem).next().unwrap();
+        Request::parse(
+            &avail_desc,
+            mem,
+            mapping,
+            ext_mapping,
+            ext_domain_mapping,
+            &ReservedRegions::default(),
+        )
+    }
+
+    #[test]
+    fn test_iommu_probe_resv_mem() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_requestq = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+
+        let req_addr = GuestAddress(0x2_0000);
+        let reply_addr = GuestAddress(0x3_0000);
+        let req_len = size_of::<VirtioIommuReqHead>() + size_of::<VirtioIommuReqProbe>();
+        let reply_len = PROBE_PROP_SIZE as usize + size_of::<VirtioIommuReqTail>();
+
+        mem.write_obj(
+            VirtioIommuReqHead {
+                type_: VIRTIO_IOMMU_T_PROBE,
+                ..Default::default()
+            },
+            req_addr,
+        )
+        .unwrap();
+        mem.write_obj(
+            VirtioIommuReqProbe {
+                endpoint: 3,
+                ..Default::default()
+            },
+            req_addr.unchecked_add(size_of::<VirtioIommuReqHead>() as u64),
+        )
+        .unwrap();
+        // Make sure the device writes the whole reply.
+        mem.write_slice(&vec![0xff; reply_len], reply_addr).unwrap();
+
+        guest_requestq.dtable[0].set(req_addr.raw_value(), req_len as u32, VIRTQ_DESC_F_NEXT, 1);
+        guest_requestq.dtable[1].set(
+            reply_addr.raw_value(),
+            reply_len as u32,
+            VIRTQ_DESC_F_WRITE,
+            0,
+        );
+        guest_requestq.avail.ring[0].set(0);
+        guest_requestq.avail.idx.set(1);
+
+        let mut queue = guest_requestq.create_queue();
+        let avail_desc = queue.iter(&mem).next().unwrap();
+        let mut reserved_regions = ReservedRegions {
+            msi: MSI_REGION,
+            endpoints: BTreeMap::new(),
+        };
+        reserved_regions.endpoints.insert(
+            3,
+            vec![ReservedRegion {
+                start: 0x1000,
+                end: 0x1fff,
+            }],
+        );
+        let len = Request::parse(
+            &avail_desc,
+            &mem,
+            &create_mapping(),
+            &BTreeMap::new(),
+            &mut BTreeMap::new(),
+            &reserved_regions,
+        )
+        .unwrap();
+        assert_eq!(len, reply_len);
+
+        let mut props = vec![0u8; PROBE_PROP_SIZE as usize];
+        mem.read_slice(&mut props, reply_addr).unwrap();
+        assert_eq!(
+            decode_resv_mems(&props),
+            vec![
+                (VIRTIO_IOMMU_RESV_MEM_T_MSI, 0xfee0_0000, 0xfeef_ffff),
+                (VIRTIO_IOMMU_RESV_MEM_T_RESERVED, 0x1000, 0x1fff),
+            ]
+        );
+
+        // The status follows the properties, at the offset given by the
+        // probe_size field of the configuration.
+        let tail: VirtioIommuReqTail = mem
+            .read_obj(reply_addr.unchecked_add(u64::from(PROBE_PROP_SIZE)))
+            .unwrap();
+        assert_eq!(tail.status, VIRTIO_IOMMU_S_OK);
+    }
+
+    #[test]
+    fn test_iommu_probe_properties() {
+        let mut reserved_regions = ReservedRegions {
+            msi: MSI_REGION,
+            endpoints: BTreeMap::new(),
+        };
+
+        // Endpoints without any reserved region only get the MSI doorbell,
+        // encoded as little endian fields.
+        let props = reserved_regions.probe_properties(8);
+        assert_eq!(props.len(), PROBE_PROP_SIZE as usize);
+        assert_eq!(
+            &props[..RESV_MEM_PROP_SIZE],
+            &[
+                1, 0, 20, 0, // RESV_MEM property, 20 bytes long
+                1, 0, 0, 0, // MSI subtype
+                0, 0, 0xe0, 0xfe, 0, 0, 0, 0, // start
+                0xff, 0xff, 0xef, 0xfe, 0, 0, 0, 0, // end
+            ][..]
+        );
+        assert!(props[RESV_MEM_PROP_SIZE..].iter().all(|b| *b == 0));
+
+        // Regions get appended after the MSI one, in registration order.
+        let regions: Vec<ReservedRegion> = (0..3)
+            .map(|i| ReservedRegion {
+                start: i << 20,
+                end: (i << 20) | 0xfff,
+            })
+            .collect();
+        reserved_regions.endpoints.insert(8, regions);
+        let resv_mems = decode_resv_mems(&reserved_regions.probe_properties(8));
+        assert_eq!(resv_mems.len(), 4);
+        assert_eq!(resv_mems[0].0, VIRTIO_IOMMU_RESV_MEM_T_MSI);
+        assert_eq!(
+            resv_mems[3],
+            (VIRTIO_IOMMU_RESV_MEM_T_RESERVED, 0x20_0000, 0x20_0fff)
+        );
+        // Other endpoints are not affected.
+        assert_eq!(
+            decode_resv_mems(&reserved_regions.probe_properties(3)).len(),
+            1
+        );
+
+        // Regions which don't fit are left out.
+        let regions: Vec<ReservedRegion> = (0..32)
+            .map(|i| ReservedRegion {
+                start: i << 20,
+                end: (i << 20) | 0xfff,
+            })
+            .collect();
+        reserved_regions.endpoints.insert(8, regions);
+        let props = reserved_regions.probe_properties(8);
+        assert_eq!(props.len(), PROBE_PROP_SIZE as usize);
+        assert_eq!(
+            decode_resv_mems(&props).len(),
+            PROBE_PROP_SIZE as usize / RESV_MEM_PROP_SIZE
+        );
+    }
+
+    #[test]
+    fn test_iommu_bypass() {
+        let mapping = create_mapping();
+
+        // Until the features are negotiated, unattached endpoints bypass
+        // the translation.
+        assert_eq!(mapping.translate(3, 0x1000).unwrap(), 0x1000);
+
+        // Without VIRTIO_IOMMU_F_BYPASS, accesses from unattached endpoints
+        // are denied.
+        mapping.bypass.store(false, Ordering::Release);
+        assert!(mapping.translate(3, 0x1000).is_err());
+
+        // Attached endpoints are always translated through their domain.
+        mapping.endpoints.write().unwrap().insert(3, 1);
+        let mut domain_mappings = BTreeMap::new();
+        domain_mappings.insert(
+            0x1000,
+            Mapping {
+                gpa: 0x8000,
+                size: 0x1000,
+            },
+        );
+        mapping.mappings.write().unwrap().insert(1, domain_mappings);
+        assert_eq!(mapping.translate(3, 0x1800).unwrap(), 0x8800);
+        assert!(mapping.translate(4, 0x1800).is_err());
+    }
+
+    #[test]
+    fn test_iommu_external_mapping() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let mapping = create_mapping();
+        let vfio = Arc::new(RecordingDmaMapping::default());
+        let mut ext_mapping: BTreeMap<u32, Arc<dyn ExternalDmaMapping>> = BTreeMap::new();
+        ext_mapping.insert(8, vfio.clone());
+        let mut ext_domain_mapping = BTreeMap::new();
+
+        // A paravirtualized endpoint populates the domain first.
+        parse_request(
+            &mem,
+            VIRTIO_IOMMU_T_ATTACH,
+            VirtioIommuReqAttach {
+                domain: 1,
+                endpoint: 3,
+                ..Default::default()
+            },
+            &mapping,
+            &ext_mapping,
+            &mut ext_domain_mapping,
+        )
+        .unwrap();
+        parse_request(
+            &mem,
+            VIRTIO_IOMMU_T_MAP,
+            VirtioIommuReqMap {
+                domain: 1,
+                virt_start: 0x1000,
+                virt_end: 0x1fff,
+                phys_start: 0x8000,
+                ..Default::default()
+            },
+            &mapping,
+            &ext_mapping,
+            &mut ext_domain_mapping,
+        )
+        .unwrap();
+        assert!(vfio.ops.lock().unwrap().is_empty());
+
+        // Attaching the VFIO endpoint replays the existing mapping.
+        parse_request(
+            &mem,
+            VIRTIO_IOMMU_T_ATTACH,
+            VirtioIommuReqAttach {
+                domain: 1,
+                endpoint: 8,
+                ..Default::default()
+            },
+            &mapping,
+            &ext_mapping,
+            &mut ext_domain_mapping,
+        )
+        .unwrap();
+        assert_eq!(
+            *vfio.ops.lock().unwrap(),
+            vec![DmaOp::Map(0x1000, 0x8000, 0x1000)]
+        );
+
+        // Further updates of the domain go through the container.
+        parse_request(
+            &mem,
+            VIRTIO_IOMMU_T_MAP,
+            VirtioIommuReqMap {
+                domain: 1,
+                virt_start: 0x4000,
+                virt_end: 0x5fff,
+                phys_start: 0x2_0000,
+                ..Default::default()
+            },
+            &mapping,
+            &ext_mapping,
+            &mut ext_domain_mapping,
+        )
+        .unwrap();
+        parse_request(
+            &mem,
+            VIRTIO_IOMMU_T_UNMAP,
+            VirtioIommuReqUnmap {
+                domain: 1,
+                virt_start: 0x1000,
+                virt_end: 0x1fff,
+                ..Default::default()
+            },
+            &mapping,
+            &ext_mapping,
+            &mut ext_domain_mapping,
+        )
+        .unwrap();
+
+        // Detaching it tears down whatever is left.
+        parse_request(
+            &mem,
+            VIRTIO_IOMMU_T_DETACH,
+            VirtioIommuReqDetach {
+                domain: 1,
+                endpoint: 8,
+                ..Default::default()
+            },
+            &mapping,
+            &ext_mapping,
+            &mut ext_domain_mapping,
+        )
+        .unwrap();
+        assert!(ext_domain_mapping.is_empty());
+        assert_eq!(
+            *vfio.ops.lock().unwrap(),
+            vec![
+                DmaOp::Map(0x1000, 0x8000, 0x1000),
+                DmaOp::Map(0x4000, 0x2_0000, 0x2000),
+                DmaOp::Unmap(0x1000, 0x1000),
+                DmaOp::Unmap(0x4000, 0x2000),
+            ]
+        );
+    }
+}
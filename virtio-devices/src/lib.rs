@@ -16,6 +16,7 @@ extern crate epoll;
 extern crate log;
 #[cfg(feature = "pci_support")]
 extern crate pci;
+extern crate seccomp;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -24,6 +25,8 @@ extern crate vhost_rs;
 extern crate virtio_bindings;
 extern crate vm_device;
 extern crate vm_memory;
+#[macro_use]
+extern crate vmm_sys_util;
 
 use std::io;
 
@@ -31,30 +34,44 @@ use std::io;
 mod device;
 pub mod balloon;
 pub mod block;
+mod coalescing;
 mod console;
 pub mod epoll_helper;
+mod flow_steering;
+pub mod gpu;
 mod iommu;
 pub mod mem;
 pub mod net;
 pub mod net_util;
 mod pmem;
 mod rng;
+pub mod seccomp_filters;
+pub mod sound;
+#[cfg(test)]
+mod testing;
+pub mod trace;
 pub mod transport;
+pub mod vdpa;
 pub mod vhost_user;
 pub mod vsock;
+pub mod watchdog;
 
 pub use self::balloon::*;
 pub use self::block::*;
 pub use self::console::*;
 pub use self::device::*;
 pub use self::epoll_helper::*;
+pub use self::flow_steering::*;
+pub use self::gpu::*;
 pub use self::iommu::*;
 pub use self::mem::*;
 pub use self::net::*;
 pub use self::net_util::*;
 pub use self::pmem::*;
 pub use self::rng::*;
+pub use self::sound::*;
 pub use self::vsock::*;
+pub use self::watchdog::*;
 use vm_virtio::{queue::*, VirtioDeviceType};
 
 const DEVICE_INIT: u32 = 0x00;
@@ -93,6 +110,10 @@ pub enum ActivateError {
     VhostUserBlkSetup(vhost_user::Error),
     /// Failed to reset vhost-user daemon.
     VhostUserReset(vhost_user::Error),
+    /// Failed to setup the vDPA device.
+    VdpaSetup(vdpa::Error),
+    /// Cannot create seccomp filter
+    CreateSeccompFilter(seccomp::SeccompError),
 }
 
 pub type ActivateResult = std::result::Result<(), ActivateError>;
@@ -117,9 +138,11 @@ pub enum Error {
     EpollWait(io::Error),
     FailedSignalingDriver(io::Error),
     VhostUserUpdateMemory(vhost_user::Error),
+    VdpaUpdateMemory(vdpa::Error),
     EventfdError(io::Error),
     SetShmRegionsNotSupported,
     EpollHander(String),
     NoMemoryConfigured,
     NetQueuePair(::net_util::NetQueuePairError),
+    ApplySeccompFilter(seccomp::Error),
 }
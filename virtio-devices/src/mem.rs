@@ -18,6 +18,7 @@ use super::{
     VirtioDeviceType, VIRTIO_F_VERSION_1,
 };
 use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use std::cmp;
 use std::fs::File;
@@ -33,7 +34,10 @@ use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
     GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
 };
-use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
+    Transportable,
+};
 use vmm_sys_util::eventfd::EventFd;
 
 const QUEUE_SIZE: u16 = 128;
@@ -44,6 +48,13 @@ const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 pub const VIRTIO_MEM_DEFAULT_BLOCK_SIZE: u64 = 512 * 4096;
 const VIRTIO_MEM_USABLE_EXTENT: u64 = 256 * 1024 * 1024;
 
+// The node_id field of the configuration is valid, it contains the ACPI
+// proximity domain the memory belongs to.
+const VIRTIO_MEM_F_ACPI_PXM: u8 = 0;
+
+// Magic number identifying files from hugetlbfs, from include/uapi/linux/magic.h
+const HUGETLBFS_MAGIC: u64 = 0x9584_58f6;
+
 // Request processed successfully, applicable for
 // - VIRTIO_MEM_REQ_PLUG
 // - VIRTIO_MEM_REQ_UNPLUG
@@ -270,7 +281,7 @@ impl Resize {
 struct MemEpollHandler {
     host_addr: u64,
     host_fd: Option<RawFd>,
-    mem_state: Vec<bool>,
+    mem_state: Arc<Mutex<Vec<bool>>>,
     config: Arc<Mutex<VirtioMemConfig>>,
     resize: Resize,
     queue: Queue,
@@ -300,7 +311,7 @@ impl MemEpollHandler {
         }
 
         // reasonable size
-        if addr + size <= addr || size == 0 {
+        if size == 0 || addr.checked_add(size).is_none() {
             return false;
         }
 
@@ -361,7 +372,23 @@ impl MemEpollHandler {
             return VIRTIO_MEM_RESP_ERROR;
         }
 
-        if !r.plug {
+        if r.plug {
+            // Regions backed by hugetlbfs are mapped with MAP_NORESERVE,
+            // meaning the guest would get a SIGBUS on access if no hugepage
+            // is left on the host. Allocate the blocks upfront so that the
+            // request can be rejected instead.
+            if let Some(fd) = r.host_fd {
+                if MemEpollHandler::is_hugetlbfs(fd) {
+                    let res = unsafe {
+                        libc::fallocate64(fd, 0, offset as libc::off64_t, r.size as libc::off64_t)
+                    };
+                    if res != 0 {
+                        error!("fallocate64 get error {}", io::Error::last_os_error());
+                        return VIRTIO_MEM_RESP_NACK;
+                    }
+                }
+            }
+        } else {
             if let Some(fd) = r.host_fd {
                 let res = unsafe {
                     libc::fallocate64(
@@ -394,6 +421,14 @@ impl MemEpollHandler {
         VIRTIO_MEM_RESP_ACK
     }
 
+    fn is_hugetlbfs(fd: RawFd) -> bool {
+        let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+        // Safe because the file descriptor is valid and the kernel only
+        // writes into the structure we provide.
+        let res = unsafe { libc::fstatfs(fd, &mut stat) };
+        res == 0 && stat.f_type as u64 == HUGETLBFS_MAGIC
+    }
+
     fn virtio_mem_unplug_all(
         config: VirtioMemConfig,
         mem_state: &mut Vec<bool>,
@@ -430,11 +465,9 @@ impl MemEpollHandler {
         mem_state: &mut Vec<bool>,
     ) -> (u16, u16) {
         let size: u64 = nb_blocks as u64 * config.block_size as u64;
-        let resp_type = if MemEpollHandler::virtio_mem_valid_range(&config, addr, size) {
-            VIRTIO_MEM_RESP_ACK
-        } else {
-            VIRTIO_MEM_RESP_ERROR
-        };
+        if !MemEpollHandler::virtio_mem_valid_range(&config, addr, size) {
+            return (VIRTIO_MEM_RESP_ERROR, 0u16);
+        }
 
         let offset = addr - config.addr;
         let bit_index = (offset / config.block_size as u64) as usize;
@@ -449,7 +482,65 @@ impl MemEpollHandler {
                 VIRTIO_MEM_STATE_MIXED
             };
 
-        (resp_type, resp_state)
+        (VIRTIO_MEM_RESP_ACK, resp_state)
+    }
+
+    // Run a request against the device configuration and the blocks bitmap,
+    // returning the response type and the blocks state.
+    fn virtio_mem_process_request(
+        req: &VirtioMemReq,
+        config: &mut VirtioMemConfig,
+        mem_state: &mut Vec<bool>,
+        host_addr: u64,
+        host_fd: Option<RawFd>,
+    ) -> (u16, u16) {
+        match req.req_type {
+            VIRTIO_MEM_REQ_PLUG | VIRTIO_MEM_REQ_UNPLUG => {
+                let plug = req.req_type == VIRTIO_MEM_REQ_PLUG;
+                let size: u64 = req.nb_blocks as u64 * config.block_size as u64;
+                let resp_type =
+                    MemEpollHandler::virtio_mem_state_change_request(StateChangeRequest {
+                        config: *config,
+                        addr: req.addr,
+                        size,
+                        nb_blocks: req.nb_blocks,
+                        mem_state,
+                        host_addr,
+                        host_fd,
+                        plug,
+                    });
+                if resp_type == VIRTIO_MEM_RESP_ACK {
+                    if plug {
+                        config.plugged_size += size;
+                    } else {
+                        config.plugged_size -= size;
+                    }
+                }
+                (resp_type, 0u16)
+            }
+            VIRTIO_MEM_REQ_UNPLUG_ALL => {
+                let resp_type =
+                    MemEpollHandler::virtio_mem_unplug_all(*config, mem_state, host_addr, host_fd);
+                if resp_type == VIRTIO_MEM_RESP_ACK {
+                    config.plugged_size = 0;
+                    config.usable_region_size = cmp::min(
+                        config.region_size,
+                        config.requested_size + VIRTIO_MEM_USABLE_EXTENT,
+                    );
+                }
+                (resp_type, 0u16)
+            }
+            VIRTIO_MEM_REQ_STATE => MemEpollHandler::virtio_mem_state_request(
+                *config,
+                req.addr,
+                req.nb_blocks,
+                mem_state,
+            ),
+            _ => {
+                error!("VirtioMemReq unknown request type {:?}", req.req_type);
+                (VIRTIO_MEM_RESP_ERROR, 0u16)
+            }
+        }
     }
 
     fn virtio_mem_send_response(
@@ -482,6 +573,7 @@ impl MemEpollHandler {
     fn process_queue(&mut self) -> bool {
         let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
         let mut used_count = 0;
+        let mut config_changed = false;
         let mem = self.mem.memory();
         for avail_desc in self.queue.iter(&mem) {
             let len = match Request::parse(&avail_desc, &mem) {
@@ -491,95 +583,24 @@ impl MemEpollHandler {
                 }
                 Ok(r) => {
                     let mut config = self.config.lock().unwrap();
-                    match r.req.req_type {
-                        VIRTIO_MEM_REQ_PLUG => {
-                            let size: u64 = r.req.nb_blocks as u64 * config.block_size as u64;
-                            let resp_type = MemEpollHandler::virtio_mem_state_change_request(
-                                StateChangeRequest {
-                                    config: *config,
-                                    addr: r.req.addr,
-                                    size,
-                                    nb_blocks: r.req.nb_blocks,
-                                    mem_state: &mut self.mem_state,
-                                    host_addr: self.host_addr,
-                                    host_fd: self.host_fd,
-                                    plug: true,
-                                },
-                            );
-                            if resp_type == VIRTIO_MEM_RESP_ACK {
-                                config.plugged_size += size;
-                            }
-                            MemEpollHandler::virtio_mem_send_response(
-                                &mem,
-                                resp_type,
-                                0u16,
-                                r.status_addr,
-                            )
-                        }
-                        VIRTIO_MEM_REQ_UNPLUG => {
-                            let size: u64 = r.req.nb_blocks as u64 * config.block_size as u64;
-                            let resp_type = MemEpollHandler::virtio_mem_state_change_request(
-                                StateChangeRequest {
-                                    config: *config,
-                                    addr: r.req.addr,
-                                    size,
-                                    nb_blocks: r.req.nb_blocks,
-                                    mem_state: &mut self.mem_state,
-                                    host_addr: self.host_addr,
-                                    host_fd: self.host_fd,
-                                    plug: false,
-                                },
-                            );
-                            if resp_type == VIRTIO_MEM_RESP_ACK {
-                                config.plugged_size -= size;
-                            }
-                            MemEpollHandler::virtio_mem_send_response(
-                                &mem,
-                                resp_type,
-                                0u16,
-                                r.status_addr,
-                            )
-                        }
-                        VIRTIO_MEM_REQ_UNPLUG_ALL => {
-                            let resp_type = MemEpollHandler::virtio_mem_unplug_all(
-                                *config,
-                                &mut self.mem_state,
-                                self.host_addr,
-                                self.host_fd,
-                            );
-                            if resp_type == VIRTIO_MEM_RESP_ACK {
-                                config.plugged_size = 0;
-                                config.usable_region_size = cmp::min(
-                                    config.region_size,
-                                    config.requested_size + VIRTIO_MEM_USABLE_EXTENT,
-                                );
-                            }
-                            MemEpollHandler::virtio_mem_send_response(
-                                &mem,
-                                resp_type,
-                                0u16,
-                                r.status_addr,
-                            )
-                        }
-                        VIRTIO_MEM_REQ_STATE => {
-                            let (resp_type, resp_state) = MemEpollHandler::virtio_mem_state_request(
-                                *config,
-                                r.req.addr,
-                                r.req.nb_blocks,
-                                &mut self.mem_state,
-                            );
-                            MemEpollHandler::virtio_mem_send_response(
-                                &mem,
-                                resp_type,
-                                resp_state,
-                                r.status_addr,
-                            )
-                        }
-                        _ => {
-                            error!("VirtioMemReq unknown request type {:?}", r.req.req_type);
-                            0
-                        }
+                    let mut mem_state = self.mem_state.lock().unwrap();
+                    let plugged_size = config.plugged_size;
+                    let (resp_type, resp_state) = MemEpollHandler::virtio_mem_process_request(
+                        &r.req,
+                        &mut config,
+                        &mut mem_state,
+                        self.host_addr,
+                        self.host_fd,
+                    );
+                    if config.plugged_size != plugged_size {
+                        config_changed = true;
                     }
+                    MemEpollHandler::virtio_mem_send_response(
+                        &mem,
+                        resp_type,
+                        resp_state,
+                        r.status_addr,
+                    )
                 }
             };
             used_desc_heads[used_count] = (avail_desc.index, len);
@@ -589,6 +610,12 @@ impl MemEpollHandler {
         for &(desc_index, len) in &used_desc_heads[..used_count] {
             self.queue.add_used(&mem, desc_index, len);
         }
+
+        // Let the guest know about the new plugged size.
+        if config_changed {
+            let _ = self.signal(&VirtioInterruptType::Config);
+        }
+
         used_count > 0
     }
 
@@ -777,35 +804,74 @@ pub struct Mem {
     host_addr: u64,
     host_fd: Option<RawFd>,
     config: Arc<Mutex<VirtioMemConfig>>,
+    blocks_state: Arc<Mutex<Vec<bool>>>,
     queue_evts: Option<Vec<EventFd>>,
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     paused: Arc<AtomicBool>,
 }
 
+#[derive(Serialize, Deserialize)]
+struct MemState {
+    avail_features: u64,
+    acked_features: u64,
+    requested_size: u64,
+    plugged_size: u64,
+    usable_region_size: u64,
+    blocks_state: Vec<bool>,
+}
+
 impl Mem {
     // Create a new virtio-mem device.
-    pub fn new(id: String, region: &Arc<GuestRegionMmap>, resize: Resize) -> io::Result<Mem> {
+    // The device exposes the memory region with the given block size, and
+    // asks the guest to plug initial_size bytes of it. When a NUMA node is
+    // provided, the guest is told which ACPI proximity domain the memory
+    // belongs to.
+    pub fn new(
+        id: String,
+        region: &Arc<GuestRegionMmap>,
+        resize: Resize,
+        block_size: u64,
+        numa_node_id: Option<u16>,
+        initial_size: u64,
+    ) -> io::Result<Mem> {
         let region_len = region.len();
 
-        if region_len != region_len / VIRTIO_MEM_DEFAULT_BLOCK_SIZE * VIRTIO_MEM_DEFAULT_BLOCK_SIZE
-        {
+        if !block_size.is_power_of_two() || block_size < VIRTIO_MEM_DEFAULT_BLOCK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Virtio-mem block size {} is invalid", block_size),
+            ));
+        }
+
+        if region_len % block_size != 0 || region.start_addr().raw_value() % block_size != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Virtio-mem size is not aligned with {}", block_size),
+            ));
+        }
+
+        if initial_size % block_size != 0 || initial_size > region_len {
             return Err(io::Error::new(
                 io::ErrorKind::Other,
                 format!(
-                    "Virtio-mem size is not aligned with {}",
-                    VIRTIO_MEM_DEFAULT_BLOCK_SIZE
+                    "Virtio-mem initial size {} is invalid for region size {}",
+                    initial_size, region_len
                 ),
             ));
         }
 
-        // Fixme: Not support VIRTIO_MEM_F_ACPI_PXM
-        let avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
 
         let mut config = VirtioMemConfig::default();
-        config.block_size = VIRTIO_MEM_DEFAULT_BLOCK_SIZE;
+        if let Some(node_id) = numa_node_id {
+            avail_features |= 1u64 << VIRTIO_MEM_F_ACPI_PXM;
+            config.node_id = node_id;
+        }
+        config.block_size = block_size;
         config.addr = region.start_addr().raw_value();
         config.region_size = region.len();
+        config.requested_size = initial_size;
         config.usable_region_size = cmp::min(
             config.region_size,
             config.requested_size + VIRTIO_MEM_USABLE_EXTENT,
@@ -827,12 +893,49 @@ impl Mem {
             host_addr: region.as_ptr() as u64,
             host_fd,
             config: Arc::new(Mutex::new(config)),
+            blocks_state: Arc::new(Mutex::new(vec![false; (region_len / block_size) as usize])),
             queue_evts: None,
             interrupt_cb: None,
             epoll_threads: None,
             paused: Arc::new(AtomicBool::new(false)),
         })
     }
+
+    fn state(&self) -> MemState {
+        let config = self.config.lock().unwrap();
+        MemState {
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+            requested_size: config.requested_size,
+            plugged_size: config.plugged_size,
+            usable_region_size: config.usable_region_size,
+            blocks_state: self.blocks_state.lock().unwrap().clone(),
+        }
+    }
+
+    fn set_state(&mut self, state: &MemState) -> io::Result<()> {
+        let mut config = self.config.lock().unwrap();
+        let mut blocks_state = self.blocks_state.lock().unwrap();
+        if state.blocks_state.len() != blocks_state.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Virtio-mem restored {} blocks, expected {}",
+                    state.blocks_state.len(),
+                    blocks_state.len()
+                ),
+            ));
+        }
+
+        self.avail_features = state.avail_features;
+        self.acked_features = state.acked_features;
+        config.requested_size = state.requested_size;
+        config.plugged_size = state.plugged_size;
+        config.usable_region_size = state.usable_region_size;
+        blocks_state.copy_from_slice(&state.blocks_state);
+
+        Ok(())
+    }
 }
 
 impl Drop for Mem {
@@ -919,11 +1022,10 @@ impl VirtioDevice for Mem {
         }
         self.queue_evts = Some(tmp_queue_evts);
 
-        let config = self.config.lock().unwrap();
         let mut handler = MemEpollHandler {
             host_addr: self.host_addr,
             host_fd: self.host_fd,
-            mem_state: vec![false; config.region_size as usize / config.block_size as usize],
+            mem_state: self.blocks_state.clone(),
             config: self.config.clone(),
             resize: self.resize.try_clone().map_err(|e| {
                 error!("failed to clone resize EventFd: {:?}", e);
@@ -976,6 +1078,307 @@ impl Snapshottable for Mem {
     fn id(&self) -> String {
         self.id.clone()
     }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        let snapshot =
+            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
+
+        let mut mem_snapshot = Snapshot::new(self.id.as_str());
+        mem_snapshot.add_data_section(SnapshotDataSection {
+            id: format!("{}-section", self.id),
+            snapshot,
+        });
+
+        Ok(mem_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        if let Some(mem_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            let mem_state = match serde_json::from_slice(&mem_section.snapshot) {
+                Ok(state) => state,
+                Err(error) => {
+                    return Err(MigratableError::Restore(anyhow!(
+                        "Could not deserialize virtio-mem {}",
+                        error
+                    )))
+                }
+            };
+
+            return self.set_state(&mem_state).map_err(|e| {
+                MigratableError::Restore(anyhow!("Could not restore virtio-mem state {:?}", e))
+            });
+        }
+
+        Err(MigratableError::Restore(anyhow!(
+            "Could not find virtio-mem snapshot section"
+        )))
+    }
 }
 impl Transportable for Mem {}
 impl Migratable for Mem {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::MmapRegion;
+
+    const BLOCK_SIZE: u64 = VIRTIO_MEM_DEFAULT_BLOCK_SIZE;
+
+    fn create_mem(region_size: u64, initial_size: u64) -> Mem {
+        let region = Arc::new(
+            GuestRegionMmap::new(
+                MmapRegion::new(region_size as usize).unwrap(),
+                GuestAddress(1 << 32),
+            )
+            .unwrap(),
+        );
+        Mem::new(
+            "_mem0".to_string(),
+            &region,
+            Resize::new().unwrap(),
+            BLOCK_SIZE,
+            Some(1),
+            initial_size,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_mem_config() {
+        let mem = create_mem(16 * BLOCK_SIZE, 4 * BLOCK_SIZE);
+        assert_ne!(mem.features() & (1u64 << VIRTIO_MEM_F_ACPI_PXM), 0);
+
+        let config = *mem.config.lock().unwrap();
+        let (block_size, node_id, requested_size) =
+            (config.block_size, config.node_id, config.requested_size);
+        assert_eq!(block_size, BLOCK_SIZE);
+        assert_eq!(node_id, 1);
+        assert_eq!(requested_size, 4 * BLOCK_SIZE);
+        assert_eq!(mem.blocks_state.lock().unwrap().len(), 16);
+
+        // The initial size can't exceed the region.
+        let region = Arc::new(
+            GuestRegionMmap::new(
+                MmapRegion::new(BLOCK_SIZE as usize).unwrap(),
+                GuestAddress(1 << 32),
+            )
+            .unwrap(),
+        );
+        assert!(Mem::new(
+            "_mem1".to_string(),
+            &region,
+            Resize::new().unwrap(),
+            BLOCK_SIZE,
+            None,
+            2 * BLOCK_SIZE,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_mem_snapshot_restore() {
+        let mem = create_mem(16 * BLOCK_SIZE, 4 * BLOCK_SIZE);
+        {
+            let mut config = mem.config.lock().unwrap();
+            config.plugged_size = 3 * BLOCK_SIZE;
+            let mut blocks_state = mem.blocks_state.lock().unwrap();
+            blocks_state[0] = true;
+            blocks_state[5] = true;
+            blocks_state[15] = true;
+        }
+
+        let snapshot = mem.snapshot().unwrap();
+
+        let mut restored = create_mem(16 * BLOCK_SIZE, 0);
+        restored.restore(snapshot).unwrap();
+        assert_eq!(
+            *restored.blocks_state.lock().unwrap(),
+            *mem.blocks_state.lock().unwrap()
+        );
+        let config = *restored.config.lock().unwrap();
+        let (plugged_size, requested_size) = (config.plugged_size, config.requested_size);
+        assert_eq!(plugged_size, 3 * BLOCK_SIZE);
+        assert_eq!(requested_size, 4 * BLOCK_SIZE);
+
+        // The snapshot can't be applied to a device of a different size.
+        let mut other = create_mem(8 * BLOCK_SIZE, 0);
+        assert!(other.restore(mem.snapshot().unwrap()).is_err());
+    }
+
+    fn process_request(
+        mem: &Mem,
+        config: &mut VirtioMemConfig,
+        mem_state: &mut Vec<bool>,
+        req_type: u16,
+        addr: u64,
+        nb_blocks: u16,
+    ) -> (u16, u16) {
+        let req = VirtioMemReq {
+            req_type,
+            addr,
+            nb_blocks,
+            ..Default::default()
+        };
+        MemEpollHandler::virtio_mem_process_request(
+            &req,
+            config,
+            mem_state,
+            mem.host_addr,
+            mem.host_fd,
+        )
+    }
+
+    #[test]
+    fn test_mem_plug_unplug() {
+        let mem = create_mem(8 * BLOCK_SIZE, 4 * BLOCK_SIZE);
+        let mut config = *mem.config.lock().unwrap();
+        let mut state = mem.blocks_state.lock().unwrap().clone();
+        let base = config.addr;
+
+        let (resp, _) = process_request(
+            &mem,
+            &mut config,
+            &mut state,
+            VIRTIO_MEM_REQ_PLUG,
+            base + BLOCK_SIZE,
+            2,
+        );
+        assert_eq!(resp, VIRTIO_MEM_RESP_ACK);
+        let plugged_size = config.plugged_size;
+        assert_eq!(plugged_size, 2 * BLOCK_SIZE);
+        assert_eq!(
+            state,
+            vec![false, true, true, false, false, false, false, false]
+        );
+
+        // Unaligned address and blocks already plugged are rejected.
+        let (resp, _) = process_request(
+            &mem,
+            &mut config,
+            &mut state,
+            VIRTIO_MEM_REQ_PLUG,
+            base + 0x1000,
+            1,
+        );
+        assert_eq!(resp, VIRTIO_MEM_RESP_ERROR);
+        let (resp, _) = process_request(
+            &mem,
+            &mut config,
+            &mut state,
+            VIRTIO_MEM_REQ_PLUG,
+            base + 2 * BLOCK_SIZE,
+            1,
+        );
+        assert_eq!(resp, VIRTIO_MEM_RESP_ERROR);
+
+        // Plugging more than the requested size is denied.
+        let (resp, _) = process_request(
+            &mem,
+            &mut config,
+            &mut state,
+            VIRTIO_MEM_REQ_PLUG,
+            base + 4 * BLOCK_SIZE,
+            3,
+        );
+        assert_eq!(resp, VIRTIO_MEM_RESP_NACK);
+        let (resp, _) = process_request(
+            &mem,
+            &mut config,
+            &mut state,
+            VIRTIO_MEM_REQ_PLUG,
+            base + 4 * BLOCK_SIZE,
+            2,
+        );
+        assert_eq!(resp, VIRTIO_MEM_RESP_ACK);
+        let plugged_size = config.plugged_size;
+        assert_eq!(plugged_size, 4 * BLOCK_SIZE);
+
+        assert_eq!(
+            process_request(&mem, &mut config, &mut state, VIRTIO_MEM_REQ_STATE, base, 3),
+            (VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_STATE_MIXED)
+        );
+        assert_eq!(
+            process_request(
+                &mem,
+                &mut config,
+                &mut state,
+                VIRTIO_MEM_REQ_STATE,
+                base + BLOCK_SIZE,
+                2
+            ),
+            (VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_STATE_PLUGGED)
+        );
+        assert_eq!(
+            process_request(
+                &mem,
+                &mut config,
+                &mut state,
+                VIRTIO_MEM_REQ_STATE,
+                base + 6 * BLOCK_SIZE,
+                2
+            ),
+            (VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_STATE_UNPLUGGED)
+        );
+        let (resp, _) = process_request(
+            &mem,
+            &mut config,
+            &mut state,
+            VIRTIO_MEM_REQ_STATE,
+            base + 7 * BLOCK_SIZE,
+            2,
+        );
+        assert_eq!(resp, VIRTIO_MEM_RESP_ERROR);
+
+        let (resp, _) = process_request(
+            &mem,
+            &mut config,
+            &mut state,
+            VIRTIO_MEM_REQ_UNPLUG,
+            base + BLOCK_SIZE,
+            1,
+        );
+        assert_eq!(resp, VIRTIO_MEM_RESP_ACK);
+        let plugged_size = config.plugged_size;
+        assert_eq!(plugged_size, 3 * BLOCK_SIZE);
+        assert_eq!(
+            state,
+            vec![false, false, true, false, true, true, false, false]
+        );
+
+        // Blocks can't be unplugged twice, nor plugged outside the region.
+        let (resp, _) = process_request(
+            &mem,
+            &mut config,
+            &mut state,
+            VIRTIO_MEM_REQ_UNPLUG,
+            base + BLOCK_SIZE,
+            1,
+        );
+        assert_eq!(resp, VIRTIO_MEM_RESP_ERROR);
+        let (resp, _) = process_request(
+            &mem,
+            &mut config,
+            &mut state,
+            VIRTIO_MEM_REQ_PLUG,
+            base + 8 * BLOCK_SIZE,
+            1,
+        );
+        assert_eq!(resp, VIRTIO_MEM_RESP_ERROR);
+
+        let (resp, _) = process_request(
+            &mem,
+            &mut config,
+            &mut state,
+            VIRTIO_MEM_REQ_UNPLUG_ALL,
+            0,
+            0,
+        );
+        assert_eq!(resp, VIRTIO_MEM_RESP_ACK);
+        let plugged_size = config.plugged_size;
+        assert_eq!(plugged_size, 0);
+        assert!(state.iter().all(|plugged| !plugged));
+
+        let (resp, _) = process_request(&mem, &mut config, &mut state, 42, base, 1);
+        assert_eq!(resp, VIRTIO_MEM_RESP_ERROR);
+    }
+}
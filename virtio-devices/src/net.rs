@@ -5,27 +5,34 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use super::coalescing::InterruptCoalescer;
 use super::net_util::{
     build_net_config_space, build_net_config_space_with_mq, CtrlVirtio, NetCtrlEpollHandler,
     VirtioNetConfig,
 };
+use super::seccomp_filters::{get_seccomp_filter, Thread};
 use super::Error as DeviceError;
+use super::{load_steering_program, FlowRule};
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
-    VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Idle,
+    IdleTracker, Queue, ReadyBarrier, ReadyNotifier, VirtioDevice, VirtioDeviceType,
+    VirtioInterruptType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IN_ORDER,
 };
-use crate::VirtioInterrupt;
+use crate::{trace, VirtioInterrupt};
 use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use net_util::{
-    open_tap, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxVirtio, Tap, TxVirtio,
+    open_tap, virtio_net_hdr_len, LatencyHistogram, MacAddr, NetCounters, NetQueuePair,
+    OpenTapError, RxStarvation, RxStarvationPolicy, RxVirtio, Tap, TxVirtio,
 };
+use rate_limiter::{RateLimiter, RateLimiterConfig};
+use seccomp::{SeccompAction, SeccompFilter};
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
 use std::num::Wrapping;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::vec::Vec;
@@ -44,15 +51,53 @@ pub const RX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 pub const TX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // A frame is available for reading from the tap device to receive in the guest.
 pub const RX_TAP_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// The RX queue coalescing delay has elapsed.
+pub const RX_COALESCING_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+// The TX queue coalescing delay has elapsed.
+pub const TX_COALESCING_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
+// The RX queue has been starved for long enough to start dropping frames.
+pub const RX_STARVATION_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 6;
+// The RX rate limiter budget has been replenished.
+pub const RX_RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 7;
+// The TX rate limiter budget has been replenished.
+pub const TX_RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 8;
+
+// The device is a standby for a primary device with the same MAC address.
+const VIRTIO_NET_F_STANDBY: u32 = 62;
 
 #[derive(Debug)]
 pub enum Error {
     /// Failed to open taps.
     OpenTap(OpenTapError),
+    /// Multiqueue has not been negotiated with the guest.
+    MqNotNegotiated,
+    /// Number of queue pairs not supported by the device.
+    InvalidQueuePairs(u16),
+    /// Flow rule targeting a queue pair not in use.
+    InactiveFlowRuleQueue(u16),
+    /// Queue pair still targeted by a flow rule.
+    FlowRuleQueueInUse(u16),
+    /// Failed to load the flow steering program.
+    LoadSteeringProgram(std::io::Error),
+    /// Failed to attach the flow steering program to the tap.
+    SetSteeringProgram(net_util::TapError),
+    /// MAC address which can't be assigned to the device.
+    InvalidMac(MacAddr),
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Interrupt moderation of each RX and TX queue of a virtio-net device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NetCoalescing {
+    /// Number of used descriptors after which the guest gets notified right
+    /// away, 0 meaning no limit.
+    pub max_packets: u32,
+    /// Longest delay, in microseconds, between a descriptor being used and
+    /// the guest being notified about it. Coalescing is disabled when 0.
+    pub max_usecs: u64,
+}
+
 struct NetEpollHandler {
     net: NetQueuePair,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
@@ -60,11 +105,14 @@ struct NetEpollHandler {
     pause_evt: EventFd,
     queue_pair: Vec<Queue>,
     queue_evt_pair: Vec<EventFd>,
+    // RX then TX queue interrupt coalescing.
+    coalescers: Vec<InterruptCoalescer>,
     // Always generate interrupts until the driver has signalled to the device.
     // This mitigates a problem with interrupts from tap events being "lost" upon
     // a restore as the vCPU thread isn't ready to handle the interrupt. This causes
     // issues when combined with VIRTIO_RING_F_EVENT_IDX interrupt suppression.
     driver_awake: bool,
+    idle_tracker: Option<IdleTracker>,
 }
 
 impl NetEpollHandler {
@@ -77,18 +125,35 @@ impl NetEpollHandler {
             })
     }
 
+    // Decide whether to signal the queue `index` after descriptors have been
+    // added to its used ring since `next_used`, `notify` telling whether the
+    // guest asked to be notified about them.
+    fn used_descs_added(
+        &mut self,
+        index: usize,
+        next_used: Wrapping<u16>,
+        notify: bool,
+    ) -> result::Result<bool, DeviceError> {
+        let used = (self.queue_pair[index].next_used - next_used).0;
+        let signal = self.coalescers[index]
+            .completed(used, notify)
+            .map_err(|e| DeviceError::EpollHander(format!("{:?}", e)))?;
+
+        Ok(signal || !self.driver_awake)
+    }
+
     fn handle_rx_event(&mut self) -> result::Result<(), DeviceError> {
         let queue_evt = &self.queue_evt_pair[0];
         if let Err(e) = queue_evt.read() {
             error!("Failed to get rx queue event: {:?}", e);
         }
 
-        if self
+        let next_used = self.queue_pair[0].next_used;
+        let notify = self
             .net
             .resume_rx(&mut self.queue_pair[0])
-            .map_err(DeviceError::NetQueuePair)?
-            || !self.driver_awake
-        {
+            .map_err(DeviceError::NetQueuePair)?;
+        if self.used_descs_added(0, next_used, notify)? {
             self.signal_used_queue(&self.queue_pair[0])?;
             info!("Signalling RX queue");
         } else {
@@ -103,12 +168,12 @@ impl NetEpollHandler {
         if let Err(e) = queue_evt.read() {
             error!("Failed to get tx queue event: {:?}", e);
         }
-        if self
+        let next_used = self.queue_pair[1].next_used;
+        let notify = self
             .net
             .process_tx(&mut self.queue_pair[1])
-            .map_err(DeviceError::NetQueuePair)?
-            || !self.driver_awake
-        {
+            .map_err(DeviceError::NetQueuePair)?;
+        if self.used_descs_added(1, next_used, notify)? {
             self.signal_used_queue(&self.queue_pair[1])?;
             info!("Signalling TX queue");
         } else {
@@ -118,12 +183,12 @@ impl NetEpollHandler {
     }
 
     fn handle_rx_tap_event(&mut self) -> result::Result<(), DeviceError> {
-        if self
+        let next_used = self.queue_pair[0].next_used;
+        let notify = self
             .net
             .process_rx_tap(&mut self.queue_pair[0])
-            .map_err(DeviceError::NetQueuePair)?
-            || !self.driver_awake
-        {
+            .map_err(DeviceError::NetQueuePair)?;
+        if self.used_descs_added(0, next_used, notify)? {
             self.signal_used_queue(&self.queue_pair[0])?;
             info!("Signalling RX queue");
         } else {
@@ -132,10 +197,75 @@ impl NetEpollHandler {
         Ok(())
     }
 
-    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), EpollHelperError> {
+    fn handle_rx_starvation_event(&mut self) -> result::Result<(), DeviceError> {
+        let next_used = self.queue_pair[0].next_used;
+        let notify = self
+            .net
+            .rx_starvation_timeout(&mut self.queue_pair[0])
+            .map_err(DeviceError::NetQueuePair)?;
+        if self.used_descs_added(0, next_used, notify)? {
+            self.signal_used_queue(&self.queue_pair[0])?;
+        }
+        Ok(())
+    }
+
+    fn handle_rx_rate_limiter_event(&mut self) -> result::Result<(), DeviceError> {
+        let next_used = self.queue_pair[0].next_used;
+        let notify = self
+            .net
+            .rx_rate_limiter_event(&mut self.queue_pair[0])
+            .map_err(DeviceError::NetQueuePair)?;
+        if self.used_descs_added(0, next_used, notify)? {
+            self.signal_used_queue(&self.queue_pair[0])?;
+        }
+        Ok(())
+    }
+
+    fn handle_tx_rate_limiter_event(&mut self) -> result::Result<(), DeviceError> {
+        let next_used = self.queue_pair[1].next_used;
+        let notify = self
+            .net
+            .tx_rate_limiter_event(&mut self.queue_pair[1])
+            .map_err(DeviceError::NetQueuePair)?;
+        if self.used_descs_added(1, next_used, notify)? {
+            self.signal_used_queue(&self.queue_pair[1])?;
+        }
+        Ok(())
+    }
+
+    fn handle_coalescing_event(&mut self, index: usize) -> result::Result<(), DeviceError> {
+        if self.coalescers[index]
+            .timer_expired()
+            .map_err(|e| DeviceError::EpollHander(format!("{:?}", e)))?
+        {
+            self.signal_used_queue(&self.queue_pair[index])?;
+        }
+        Ok(())
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        ready: ReadyNotifier,
+    ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evt_pair[0].as_raw_fd(), RX_QUEUE_EVENT)?;
         helper.add_event(self.queue_evt_pair[1].as_raw_fd(), TX_QUEUE_EVENT)?;
+        if let Some(fd) = self.coalescers[0].timer_fd() {
+            helper.add_event(fd, RX_COALESCING_EVENT)?;
+        }
+        if let Some(fd) = self.coalescers[1].timer_fd() {
+            helper.add_event(fd, TX_COALESCING_EVENT)?;
+        }
+        if let Some(fd) = self.net.rx_starvation.timer_fd() {
+            helper.add_event(fd, RX_STARVATION_EVENT)?;
+        }
+        if let Some(rate_limiter) = &self.net.rx_rate_limiter {
+            helper.add_event(rate_limiter.as_raw_fd(), RX_RATE_LIMITER_EVENT)?;
+        }
+        if let Some(rate_limiter) = &self.net.tx_rate_limiter {
+            helper.add_event(rate_limiter.as_raw_fd(), TX_RATE_LIMITER_EVENT)?;
+        }
 
         // If there are some already available descriptors on the RX queue,
         // then we can start the thread while listening onto the TAP.
@@ -151,6 +281,11 @@ impl NetEpollHandler {
         // The NetQueuePair needs the epoll fd.
         self.net.epoll_fd = Some(helper.as_raw_fd());
 
+        if let Some(idle_tracker) = self.idle_tracker.clone() {
+            helper.set_idle_tracker(idle_tracker);
+        }
+
+        ready.notify();
         helper.run(paused, self)?;
 
         Ok(())
@@ -180,6 +315,36 @@ impl EpollHelperHandler for NetEpollHandler {
                     return true;
                 }
             }
+            RX_COALESCING_EVENT => {
+                if let Err(e) = self.handle_coalescing_event(0) {
+                    error!("Error signalling RX queue: {:?}", e);
+                    return true;
+                }
+            }
+            TX_COALESCING_EVENT => {
+                if let Err(e) = self.handle_coalescing_event(1) {
+                    error!("Error signalling TX queue: {:?}", e);
+                    return true;
+                }
+            }
+            RX_STARVATION_EVENT => {
+                if let Err(e) = self.handle_rx_starvation_event() {
+                    error!("Error dropping starved RX frames: {:?}", e);
+                    return true;
+                }
+            }
+            RX_RATE_LIMITER_EVENT => {
+                if let Err(e) = self.handle_rx_rate_limiter_event() {
+                    error!("Error processing RX rate limiter event: {:?}", e);
+                    return true;
+                }
+            }
+            TX_RATE_LIMITER_EVENT => {
+                if let Err(e) = self.handle_tx_rate_limiter_event() {
+                    error!("Error processing TX rate limiter event: {:?}", e);
+                    return true;
+                }
+            }
             _ => {
                 error!("Unknown event: {}", event);
                 return true;
@@ -187,6 +352,14 @@ impl EpollHelperHandler for NetEpollHandler {
         }
         false
     }
+
+    // The descriptors waiting for their interrupt to be coalesced are
+    // considered in flight.
+    fn has_pending(&self) -> bool {
+        self.coalescers
+            .iter()
+            .any(|coalescer| coalescer.is_pending())
+    }
 }
 
 pub struct Net {
@@ -196,6 +369,10 @@ pub struct Net {
     taps: Option<Vec<Tap>>,
     avail_features: u64,
     acked_features: u64,
+    // What the features offered to the driver are computed from.
+    iommu: bool,
+    failover_standby: bool,
+    config_features: u64,
     config: VirtioNetConfig,
     queue_evts: Option<Vec<EventFd>>,
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
@@ -204,6 +381,46 @@ pub struct Net {
     paused: Arc<AtomicBool>,
     queue_size: Vec<u16>,
     counters: NetCounters,
+    seccomp_action: SeccompAction,
+    queue_pairs: Arc<AtomicU16>,
+    // Queue pairs the driver had in use when the device was snapshotted,
+    // kept in use once it gets activated again.
+    restored_queue_pairs: Option<u16>,
+    coalescing: NetCoalescing,
+    rx_starvation: RxStarvationPolicy,
+    rate_limiter_config: Option<RateLimiterConfig>,
+    idle_callback: Option<Arc<dyn Idle>>,
+    flow_rules: Vec<FlowRule>,
+    rx_latency: Option<Arc<LatencyHistogram>>,
+}
+
+/// Queue pairs of a multiqueue virtio-net device.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NetQueuesState {
+    /// Number of queue pairs currently in use.
+    pub queue_pairs: u16,
+    /// Number of queue pairs advertised to the guest.
+    pub max_queue_pairs: u16,
+}
+
+/// Link state of a virtio-net device, as reported to the guest through the
+/// status field of the configuration space.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NetLinkState {
+    pub link_up: bool,
+    /// Features negotiated with the guest driver. The link state is only
+    /// visible from the guest if `VIRTIO_NET_F_STATUS` is part of them.
+    pub acked_features: u64,
+}
+
+/// MAC address of a virtio-net device, as reported to the guest through the
+/// configuration space.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NetMacState {
+    pub mac: MacAddr,
+    /// Features negotiated with the guest driver. The MAC address is only
+    /// used by the guest if `VIRTIO_NET_F_MAC` is part of them.
+    pub acked_features: u64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -212,10 +429,18 @@ pub struct NetState {
     pub acked_features: u64,
     pub config: VirtioNetConfig,
     pub queue_size: Vec<u16>,
+    /// Number of queue pairs the driver set through the control queue.
+    #[serde(default)]
+    pub queue_pairs: Option<u16>,
+    #[serde(default)]
+    pub flow_rules: Vec<FlowRule>,
 }
 
 impl Net {
-    /// Create a new virtio network device with the given TAP interface.
+    /// Create a new virtio network device with the given TAP interface. The
+    /// RX and TX queues of each queue pair enforce the limits of
+    /// `rate_limiter_config` on their own.
+    #[allow(clippy::too_many_arguments)]
     pub fn new_with_tap(
         id: String,
         taps: Vec<Tap>,
@@ -223,37 +448,33 @@ impl Net {
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
+        coalescing: NetCoalescing,
+        rx_starvation: RxStarvationPolicy,
+        rate_limiter_config: Option<RateLimiterConfig>,
+        seccomp_action: SeccompAction,
     ) -> Result<Self> {
-        let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
-            | 1 << VIRTIO_NET_F_CSUM
-            | 1 << VIRTIO_NET_F_GUEST_TSO4
-            | 1 << VIRTIO_NET_F_GUEST_UFO
-            | 1 << VIRTIO_NET_F_HOST_TSO4
-            | 1 << VIRTIO_NET_F_HOST_UFO
-            | 1 << VIRTIO_RING_F_EVENT_IDX
-            | 1 << VIRTIO_F_VERSION_1;
-
-        if iommu {
-            avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
-        }
-
-        avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ;
         let queue_num = num_queues + 1;
 
-        let mut config = VirtioNetConfig::default();
-        if let Some(mac) = guest_mac {
-            build_net_config_space(&mut config, mac, num_queues, &mut avail_features);
+        let mut config = VirtioNetConfig {
+            status: VIRTIO_NET_S_LINK_UP as u16,
+            ..Default::default()
+        };
+        let config_features = if let Some(mac) = guest_mac {
+            build_net_config_space(&mut config, mac, num_queues)
         } else {
-            build_net_config_space_with_mq(&mut config, num_queues, &mut avail_features);
-        }
+            build_net_config_space_with_mq(&mut config, num_queues)
+        };
 
-        Ok(Net {
+        let mut net = Net {
             id,
             kill_evt: None,
             pause_evt: None,
             taps: Some(taps),
-            avail_features,
+            avail_features: 0,
             acked_features: 0u64,
+            iommu,
+            failover_standby: false,
+            config_features,
             config,
             queue_evts: None,
             interrupt_cb: None,
@@ -262,7 +483,19 @@ impl Net {
             paused: Arc::new(AtomicBool::new(false)),
             queue_size: vec![queue_size; queue_num],
             counters: NetCounters::default(),
-        })
+            seccomp_action,
+            queue_pairs: Arc::new(AtomicU16::new(1)),
+            restored_queue_pairs: None,
+            coalescing,
+            rx_starvation,
+            rate_limiter_config,
+            idle_callback: None,
+            flow_rules: Vec::new(),
+            rx_latency: None,
+        };
+        net.avail_features = net.device_features();
+
+        Ok(net)
     }
 
     /// Create a new virtio network device with the given IP address and
@@ -278,11 +511,188 @@ impl Net {
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
+        coalescing: NetCoalescing,
+        rx_starvation: RxStarvationPolicy,
+        rate_limiter_config: Option<RateLimiterConfig>,
+        seccomp_action: SeccompAction,
     ) -> Result<Self> {
         let taps = open_tap(if_name, ip_addr, netmask, host_mac, num_queues / 2)
             .map_err(Error::OpenTap)?;
 
-        Self::new_with_tap(id, taps, guest_mac, iommu, num_queues, queue_size)
+        Self::new_with_tap(
+            id,
+            taps,
+            guest_mac,
+            iommu,
+            num_queues,
+            queue_size,
+            coalescing,
+            rx_starvation,
+            rate_limiter_config,
+            seccomp_action,
+        )
+    }
+
+    // Features offered to the driver, computed from scratch for every
+    // driver loaded to be offered the same ones, whatever the previous one
+    // negotiated.
+    fn device_features(&self) -> u64 {
+        let mut features = 1 << VIRTIO_NET_F_GUEST_CSUM
+            | 1 << VIRTIO_NET_F_CSUM
+            | 1 << VIRTIO_NET_F_GUEST_TSO4
+            | 1 << VIRTIO_NET_F_GUEST_UFO
+            | 1 << VIRTIO_NET_F_HOST_TSO4
+            | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_MRG_RXBUF
+            | 1 << VIRTIO_NET_F_STATUS
+            | 1 << VIRTIO_NET_F_CTRL_VQ
+            | 1 << VIRTIO_RING_F_EVENT_IDX
+            | 1 << VIRTIO_F_VERSION_1
+            | 1 << VIRTIO_F_IN_ORDER;
+
+        if self.iommu {
+            features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+        }
+        if self.failover_standby {
+            features |= 1 << VIRTIO_NET_F_STANDBY;
+        }
+
+        features | self.config_features
+    }
+
+    pub fn link_state(&self) -> NetLinkState {
+        NetLinkState {
+            link_up: self.config.status & VIRTIO_NET_S_LINK_UP as u16 != 0,
+            acked_features: self.acked_features,
+        }
+    }
+
+    /// Report the link as up or down to the guest, which is notified of
+    /// the change through a configuration interrupt.
+    pub fn set_link_up(&mut self, link_up: bool) -> NetLinkState {
+        if link_up {
+            self.config.status |= VIRTIO_NET_S_LINK_UP as u16;
+        } else {
+            self.config.status &= !(VIRTIO_NET_S_LINK_UP as u16);
+        }
+
+        if self.acked_features & (1 << VIRTIO_NET_F_STATUS) != 0 {
+            if let Some(interrupt_cb) = &self.interrupt_cb {
+                if let Err(e) = interrupt_cb.trigger(&VirtioInterruptType::Config, None) {
+                    error!("Failed to signal link state change: {:?}", e);
+                }
+            }
+        }
+
+        self.link_state()
+    }
+
+    pub fn mac_state(&self) -> NetMacState {
+        NetMacState {
+            mac: MacAddr::from_bytes_unchecked(&self.config.mac),
+            acked_features: self.acked_features,
+        }
+    }
+
+    /// Change the MAC address of the device, the same way the guest does
+    /// through `VIRTIO_NET_CTRL_MAC_ADDR_SET`, and notify the guest through
+    /// a configuration interrupt.
+    pub fn set_mac(&mut self, mac: MacAddr) -> Result<NetMacState> {
+        if mac.is_multicast() {
+            return Err(Error::InvalidMac(mac));
+        }
+
+        self.config.mac.copy_from_slice(mac.get_bytes());
+
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            if let Err(e) = interrupt_cb.trigger(&VirtioInterruptType::Config, None) {
+                error!("Failed to signal MAC address change: {:?}", e);
+            }
+        }
+
+        Ok(self.mac_state())
+    }
+
+    pub fn queues_state(&self) -> NetQueuesState {
+        NetQueuesState {
+            queue_pairs: self.queue_pairs.load(Ordering::SeqCst),
+            max_queue_pairs: self.config.max_virtqueue_pairs,
+        }
+    }
+
+    /// Change the number of queue pairs in use, the same way the guest
+    /// does through `VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET`, and notify the
+    /// guest through a configuration interrupt.
+    pub fn set_queue_pairs(&mut self, queue_pairs: u16) -> Result<NetQueuesState> {
+        if self.acked_features & (1 << VIRTIO_NET_F_MQ) == 0 {
+            return Err(Error::MqNotNegotiated);
+        }
+
+        if queue_pairs < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN as u16
+            || queue_pairs > self.config.max_virtqueue_pairs
+        {
+            return Err(Error::InvalidQueuePairs(queue_pairs));
+        }
+
+        if let Some(rule) = self.flow_rules.iter().find(|r| r.queue >= queue_pairs) {
+            return Err(Error::FlowRuleQueueInUse(rule.queue));
+        }
+
+        self.queue_pairs.store(queue_pairs, Ordering::SeqCst);
+
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            if let Err(e) = interrupt_cb.trigger(&VirtioInterruptType::Config, None) {
+                error!("Failed to signal queue pairs change: {:?}", e);
+            }
+        }
+
+        Ok(self.queues_state())
+    }
+
+    pub fn flow_rules(&self) -> Vec<FlowRule> {
+        self.flow_rules.clone()
+    }
+
+    /// Replace the rules steering the received flows to specific queue
+    /// pairs, the frames matching none of them being spread across the
+    /// queue pairs by the kernel. Only the queue pairs in use can be
+    /// targeted.
+    pub fn set_flow_rules(&mut self, rules: Vec<FlowRule>) -> Result<Vec<FlowRule>> {
+        let queue_pairs = self.queue_pairs.load(Ordering::SeqCst);
+        if let Some(rule) = rules.iter().find(|r| r.queue >= queue_pairs) {
+            return Err(Error::InactiveFlowRuleQueue(rule.queue));
+        }
+
+        // The program is attached to the tap itself, and applies to all of
+        // its queues.
+        if let Some(tap) = self.taps.as_ref().and_then(|taps| taps.first()) {
+            if rules.is_empty() {
+                tap.set_steering_ebpf(-1)
+                    .map_err(Error::SetSteeringProgram)?;
+            } else {
+                let program = load_steering_program(&rules).map_err(Error::LoadSteeringProgram)?;
+                tap.set_steering_ebpf(program.as_raw_fd())
+                    .map_err(Error::SetSteeringProgram)?;
+            }
+        }
+
+        self.flow_rules = rules;
+
+        Ok(self.flow_rules())
+    }
+
+    /// Offer the device as the failover standby of the primary device
+    /// sharing its MAC address, the guest sending the traffic through the
+    /// primary device whenever its link is up.
+    pub fn set_failover_standby(&mut self) {
+        self.failover_standby = true;
+        self.avail_features = self.device_features();
+    }
+
+    /// Timestamp the frames read from the TAP device, reporting the time
+    /// they took to reach the RX queue through the rx_latency_* counters.
+    pub fn enable_rx_timestamps(&mut self) {
+        self.rx_latency = Some(Arc::new(LatencyHistogram::new()));
     }
 
     fn state(&self) -> NetState {
@@ -291,6 +701,8 @@ impl Net {
             acked_features: self.acked_features,
             config: self.config,
             queue_size: self.queue_size.clone(),
+            queue_pairs: Some(self.queue_pairs.load(Ordering::SeqCst)),
+            flow_rules: self.flow_rules.clone(),
         }
     }
 
@@ -300,8 +712,28 @@ impl Net {
         self.config = state.config;
         self.queue_size = state.queue_size.clone();
 
+        if let Some(queue_pairs) = state.queue_pairs {
+            self.queue_pairs.store(queue_pairs, Ordering::SeqCst);
+        }
+        self.restored_queue_pairs = state.queue_pairs;
+
+        // The steering program is attached to the TAP interface again, in
+        // case it isn't the one the rules were set on.
+        if !state.flow_rules.is_empty() {
+            self.set_flow_rules(state.flow_rules.clone())?;
+        }
+
         Ok(())
     }
+
+    /// File descriptors of the TAP interface, one per queue pair.
+    pub fn tap_fds(&self) -> Vec<RawFd> {
+        self.taps
+            .iter()
+            .flatten()
+            .map(|tap| tap.as_raw_fd())
+            .collect()
+    }
 }
 
 impl Drop for Net {
@@ -390,6 +822,21 @@ impl VirtioDevice for Net {
             }
             self.queue_evts = Some(tmp_queue_evts);
 
+            // Until told otherwise, the driver only uses the first pair,
+            // unless the device was restored.
+            self.queue_pairs.store(
+                self.restored_queue_pairs.take().unwrap_or(1),
+                Ordering::SeqCst,
+            );
+
+            // Shared by the threads of the device, including the control
+            // queue one, for the device to be idle only once all are.
+            let idle_tracker = self.idle_callback.clone().map(IdleTracker::new);
+            // Waited for by the end of the activation, so that none of the
+            // first notifications from the driver gets in before the thread
+            // of its queue listens to it.
+            let ready = ReadyBarrier::new();
+
             let queue_num = queues.len();
             if (self.acked_features & 1 << VIRTIO_NET_F_CTRL_VQ) != 0 && queue_num % 2 != 0 {
                 let cvq_queue = queues.remove(queue_num - 1);
@@ -399,14 +846,25 @@ impl VirtioDevice for Net {
                     mem: mem.clone(),
                     kill_evt: kill_evt.try_clone().unwrap(),
                     pause_evt: pause_evt.try_clone().unwrap(),
-                    ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt),
+                    ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt, self.queue_pairs.clone()),
                     epoll_fd: 0,
+                    activate_span: trace::current(),
+                    idle_tracker: idle_tracker.clone(),
                 };
 
                 let paused = self.paused.clone();
+                let ctrl_ready = ready.notifier();
+                // Retrieve seccomp filter for virtio_net_ctl thread
+                let virtio_net_ctl_seccomp_filter =
+                    get_seccomp_filter(&self.seccomp_action, Thread::VirtioNetCtl)
+                        .map_err(ActivateError::CreateSeccompFilter)?;
                 thread::Builder::new()
                     .name("virtio_net".to_string())
-                    .spawn(move || ctrl_handler.run_ctrl(paused))
+                    .spawn(move || {
+                        SeccompFilter::apply(virtio_net_ctl_seccomp_filter)
+                            .map_err(DeviceError::ApplySeccompFilter)?;
+                        ctrl_handler.run_ctrl(paused, ctrl_ready)
+                    })
                     .map(|thread| self.ctrl_queue_epoll_thread = Some(thread))
                     .map_err(|e| {
                         error!("failed to clone queue EventFd: {}", e);
@@ -415,11 +873,28 @@ impl VirtioDevice for Net {
             }
 
             let event_idx = self.acked_features & 1 << VIRTIO_RING_F_EVENT_IDX != 0;
+            let in_order = self.acked_features & 1 << VIRTIO_F_IN_ORDER != 0;
+            let guest_csum = self.acked_features & 1 << VIRTIO_NET_F_GUEST_CSUM != 0;
+            let mergeable = self.acked_features & 1 << VIRTIO_NET_F_MRG_RXBUF != 0;
+            // The TAP devices must exchange the frames with the header the
+            // driver expects, which is shorter for legacy drivers.
+            let hdr_len = virtio_net_hdr_len(self.acked_features);
+            for tap in taps.iter() {
+                tap.set_vnet_hdr_size(hdr_len as i32).map_err(|e| {
+                    error!("failed setting the virtio net header size: {:?}", e);
+                    ActivateError::BadActivate
+                })?;
+            }
 
             let mut epoll_threads = Vec::new();
             for _ in 0..taps.len() {
-                let rx = RxVirtio::new();
-                let tx = TxVirtio::new();
+                let mut rx = RxVirtio::new();
+                rx.guest_csum = guest_csum;
+                rx.mergeable = mergeable;
+                rx.hdr_len = hdr_len;
+                rx.latency = self.rx_latency.clone();
+                let mut tx = TxVirtio::new();
+                tx.hdr_len = hdr_len;
                 let rx_tap_listening = false;
 
                 let mut queue_pair = Vec::new();
@@ -427,11 +902,44 @@ impl VirtioDevice for Net {
                 queue_pair.push(queues.remove(0));
                 queue_pair[0].set_event_idx(event_idx);
                 queue_pair[1].set_event_idx(event_idx);
+                queue_pair[0].set_in_order(in_order);
+                queue_pair[1].set_in_order(in_order);
 
                 let mut queue_evt_pair = Vec::new();
                 queue_evt_pair.push(queue_evts.remove(0));
                 queue_evt_pair.push(queue_evts.remove(0));
 
+                let mut coalescers = Vec::new();
+                for _ in 0..2 {
+                    coalescers.push(
+                        InterruptCoalescer::new(
+                            self.coalescing.max_packets,
+                            self.coalescing.max_usecs,
+                        )
+                        .map_err(|e| {
+                            error!("failed creating interrupt coalescing timer: {:?}", e);
+                            ActivateError::BadActivate
+                        })?,
+                    );
+                }
+
+                let rx_starvation = RxStarvation::new(self.rx_starvation).map_err(|e| {
+                    error!("failed creating RX starvation timer: {:?}", e);
+                    ActivateError::BadActivate
+                })?;
+
+                let rate_limiter_config = self.rate_limiter_config;
+                let new_rate_limiter = || {
+                    rate_limiter_config
+                        .as_ref()
+                        .map(RateLimiter::from_config)
+                        .transpose()
+                        .map_err(|e| {
+                            error!("failed creating rate limiter: {}", e);
+                            ActivateError::BadActivate
+                        })
+                };
+
                 let mut handler = NetEpollHandler {
                     net: NetQueuePair {
                         mem: Some(mem.clone()),
@@ -442,19 +950,33 @@ impl VirtioDevice for Net {
                         rx_tap_listening,
                         counters: self.counters.clone(),
                         tap_event_id: RX_TAP_EVENT,
+                        rx_starvation,
+                        rx_rate_limiter: new_rate_limiter()?,
+                        tx_rate_limiter: new_rate_limiter()?,
                     },
                     queue_pair,
                     queue_evt_pair,
+                    coalescers,
                     interrupt_cb: interrupt_cb.clone(),
                     kill_evt: kill_evt.try_clone().unwrap(),
                     pause_evt: pause_evt.try_clone().unwrap(),
                     driver_awake: false,
+                    idle_tracker: idle_tracker.clone(),
                 };
 
                 let paused = self.paused.clone();
+                let thread_ready = ready.notifier();
+                // Retrieve seccomp filter for virtio_net thread
+                let virtio_net_seccomp_filter =
+                    get_seccomp_filter(&self.seccomp_action, Thread::VirtioNet)
+                        .map_err(ActivateError::CreateSeccompFilter)?;
                 thread::Builder::new()
                     .name("virtio_net".to_string())
-                    .spawn(move || handler.run(paused))
+                    .spawn(move || {
+                        SeccompFilter::apply(virtio_net_seccomp_filter)
+                            .map_err(EpollHelperError::ApplySeccompFilter)?;
+                        handler.run(paused, thread_ready)
+                    })
                     .map(|thread| epoll_threads.push(thread))
                     .map_err(|e| {
                         error!("failed to clone queue EventFd: {}", e);
@@ -464,6 +986,11 @@ impl VirtioDevice for Net {
 
             self.epoll_threads = Some(epoll_threads);
 
+            if !ready.wait() {
+                error!("virtio-net threads exited before listening to their queues");
+                return Err(ActivateError::BadActivate);
+            }
+
             return Ok(());
         }
         Err(ActivateError::BadActivate)
@@ -480,6 +1007,10 @@ impl VirtioDevice for Net {
             let _ = kill_evt.write(1);
         }
 
+        // The driver negotiates the features again, as when it is reloaded.
+        self.avail_features = self.device_features();
+        self.acked_features = 0;
+
         // Return the interrupt and queue EventFDs
         Some((
             self.interrupt_cb.take().unwrap(),
@@ -487,6 +1018,10 @@ impl VirtioDevice for Net {
         ))
     }
 
+    fn set_idle_callback(&mut self, callback: Arc<dyn Idle>) {
+        self.idle_callback = Some(callback);
+    }
+
     fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
         let mut counters = HashMap::new();
 
@@ -498,6 +1033,10 @@ impl VirtioDevice for Net {
             "rx_frames",
             Wrapping(self.counters.rx_frames.load(Ordering::Acquire)),
         );
+        counters.insert(
+            "rx_dropped",
+            Wrapping(self.counters.rx_dropped.load(Ordering::Acquire)),
+        );
         counters.insert(
             "tx_bytes",
             Wrapping(self.counters.tx_bytes.load(Ordering::Acquire)),
@@ -507,6 +1046,14 @@ impl VirtioDevice for Net {
             Wrapping(self.counters.tx_frames.load(Ordering::Acquire)),
         );
 
+        if let Some(rx_latency) = &self.rx_latency {
+            let summary = rx_latency.summary();
+            counters.insert("rx_latency_min_ns", Wrapping(summary.min_ns));
+            counters.insert("rx_latency_p50_ns", Wrapping(summary.p50_ns));
+            counters.insert("rx_latency_p99_ns", Wrapping(summary.p99_ns));
+            counters.insert("rx_latency_max_ns", Wrapping(summary.max_ns));
+        }
+
         Some(counters)
     }
 }
@@ -554,3 +1101,286 @@ impl Snapshottable for Net {
 }
 impl Transportable for Net {}
 impl Migratable for Net {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::NoopVirtioInterrupt;
+    use crate::FlowProtocol;
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_net_link_state() {
+        let mut net = Net::new_with_tap(
+            String::from("net0"),
+            Vec::new(),
+            None,
+            false,
+            2,
+            256,
+            NetCoalescing::default(),
+            RxStarvationPolicy::default(),
+            None,
+            SeccompAction::Allow,
+        )
+        .unwrap();
+        assert_ne!(net.features() & (1 << VIRTIO_NET_F_STATUS), 0);
+        assert!(net.link_state().link_up);
+
+        net.ack_features(1 << VIRTIO_NET_F_STATUS);
+        let state = net.set_link_up(false);
+        assert!(!state.link_up);
+        assert_eq!(state.acked_features, 1 << VIRTIO_NET_F_STATUS);
+
+        // The status field follows the MAC address in the config space.
+        let mut status = [0xffu8; 2];
+        net.read_config(6, &mut status);
+        assert_eq!(status, [0, 0]);
+
+        assert!(net.set_link_up(true).link_up);
+        net.read_config(6, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP as u16);
+    }
+
+    #[test]
+    fn test_net_mac() {
+        let mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let mut net = Net::new_with_tap(
+            String::from("net0"),
+            Vec::new(),
+            Some(mac),
+            false,
+            2,
+            256,
+            NetCoalescing::default(),
+            RxStarvationPolicy::default(),
+            None,
+            SeccompAction::Allow,
+        )
+        .unwrap();
+        assert_ne!(net.features() & (1 << VIRTIO_NET_F_MAC), 0);
+        assert_eq!(net.mac_state().mac, mac);
+
+        net.ack_features(1 << VIRTIO_NET_F_MAC);
+        let mac = MacAddr::parse_str("2e:00:00:00:00:01").unwrap();
+        let state = net.set_mac(mac).unwrap();
+        assert_eq!(state.mac, mac);
+        assert_eq!(state.acked_features, 1 << VIRTIO_NET_F_MAC);
+
+        // The MAC address starts the config space.
+        let mut config_mac = [0u8; 6];
+        net.read_config(0, &mut config_mac);
+        assert_eq!(&config_mac, mac.get_bytes());
+
+        // Group addresses are refused, the current one being kept.
+        for group in &["01:00:5e:00:00:01", "ff:ff:ff:ff:ff:ff"] {
+            let group = MacAddr::parse_str(group).unwrap();
+            assert!(net.set_mac(group).is_err());
+        }
+        net.read_config(0, &mut config_mac);
+        assert_eq!(&config_mac, mac.get_bytes());
+    }
+
+    #[test]
+    fn test_net_features_renegotiation() {
+        let mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let mut net = Net::new_with_tap(
+            String::from("net0"),
+            Vec::new(),
+            Some(mac),
+            false,
+            4,
+            256,
+            NetCoalescing::default(),
+            RxStarvationPolicy::default(),
+            None,
+            SeccompAction::Allow,
+        )
+        .unwrap();
+        let features = net.features();
+        assert_ne!(features & (1 << VIRTIO_NET_F_MAC), 0);
+        assert_ne!(features & (1 << VIRTIO_NET_F_MQ), 0);
+
+        let mem = GuestMemoryAtomic::new(
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap(),
+        );
+        // The first driver negotiates multiqueue and the MAC address, the
+        // reloaded one neither of them.
+        let negotiated = [
+            1 << VIRTIO_NET_F_MQ | 1 << VIRTIO_NET_F_MAC | 1 << VIRTIO_NET_F_STATUS,
+            1 << VIRTIO_NET_F_STATUS,
+            1 << VIRTIO_NET_F_MAC,
+        ];
+        for acked in negotiated.iter() {
+            assert_eq!(net.features(), features);
+            net.ack_features(*acked);
+            let queues = (0..5).map(|_| Queue::new(256)).collect();
+            let queue_evts = (0..5)
+                .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+                .collect();
+            net.activate(
+                mem.clone(),
+                Arc::new(NoopVirtioInterrupt {}),
+                queues,
+                queue_evts,
+            )
+            .unwrap();
+            assert_eq!(net.acked_features, *acked);
+            assert_eq!(net.features(), features);
+
+            let (_, queue_evts) = net.reset().unwrap();
+            assert_eq!(queue_evts.len(), 5);
+            assert_eq!(net.acked_features, 0);
+        }
+        assert_eq!(net.features(), features);
+    }
+
+    #[test]
+    fn test_net_failover_standby() {
+        let mut net = Net::new_with_tap(
+            String::from("net0"),
+            Vec::new(),
+            None,
+            false,
+            2,
+            256,
+            NetCoalescing::default(),
+            RxStarvationPolicy::default(),
+            None,
+            SeccompAction::Allow,
+        )
+        .unwrap();
+        assert_eq!(net.features() & (1 << VIRTIO_NET_F_STANDBY), 0);
+
+        net.set_failover_standby();
+        net.ack_features(1 << VIRTIO_NET_F_STANDBY);
+        assert_ne!(net.acked_features & (1 << VIRTIO_NET_F_STANDBY), 0);
+    }
+
+    #[test]
+    fn test_net_queue_pairs() {
+        let mut net = Net::new_with_tap(
+            String::from("net0"),
+            Vec::new(),
+            None,
+            false,
+            8,
+            256,
+            NetCoalescing::default(),
+            RxStarvationPolicy::default(),
+            None,
+            SeccompAction::Allow,
+        )
+        .unwrap();
+        assert_eq!(
+            net.queues_state(),
+            NetQueuesState {
+                queue_pairs: 1,
+                max_queue_pairs: 4,
+            }
+        );
+
+        // Nothing can be changed before the guest negotiates multiqueue.
+        assert!(net.set_queue_pairs(2).is_err());
+        net.ack_features(1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ);
+
+        assert_eq!(net.set_queue_pairs(4).unwrap().queue_pairs, 4);
+        assert_eq!(net.set_queue_pairs(2).unwrap().queue_pairs, 2);
+        assert!(net.set_queue_pairs(0).is_err());
+        assert!(net.set_queue_pairs(5).is_err());
+        assert_eq!(net.queues_state().queue_pairs, 2);
+    }
+
+    #[test]
+    fn test_net_flow_rules() {
+        let mut net = Net::new_with_tap(
+            String::from("net0"),
+            Vec::new(),
+            None,
+            false,
+            8,
+            256,
+            NetCoalescing::default(),
+            RxStarvationPolicy::default(),
+            None,
+            SeccompAction::Allow,
+        )
+        .unwrap();
+        let rule = FlowRule {
+            protocol: FlowProtocol::Tcp,
+            src_port: None,
+            dst_port: Some(80),
+            queue: 2,
+        };
+
+        // Only the first queue pair is in use until the guest asks for more.
+        assert!(net.set_flow_rules(vec![rule.clone()]).is_err());
+        net.ack_features(1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ);
+        net.set_queue_pairs(4).unwrap();
+
+        assert_eq!(
+            net.set_flow_rules(vec![rule.clone()]).unwrap(),
+            vec![rule.clone()]
+        );
+        let inactive = FlowRule { queue: 4, ..rule };
+        assert!(net.set_flow_rules(vec![inactive]).is_err());
+        assert_eq!(net.flow_rules().len(), 1);
+
+        // The queue pairs targeted by a rule stay in use.
+        assert!(net.set_queue_pairs(2).is_err());
+        assert!(net.set_flow_rules(Vec::new()).unwrap().is_empty());
+        assert_eq!(net.set_queue_pairs(2).unwrap().queue_pairs, 2);
+    }
+
+    #[test]
+    fn test_net_state_queue_pairs() {
+        let new_net = || {
+            Net::new_with_tap(
+                String::from("net0"),
+                Vec::new(),
+                None,
+                false,
+                8,
+                256,
+                NetCoalescing::default(),
+                RxStarvationPolicy::default(),
+                None,
+                SeccompAction::Allow,
+            )
+            .unwrap()
+        };
+        let rule = FlowRule {
+            protocol: FlowProtocol::Udp,
+            src_port: Some(53),
+            dst_port: None,
+            queue: 3,
+        };
+
+        let mut net = new_net();
+        net.ack_features(1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ);
+        net.set_queue_pairs(4).unwrap();
+        net.set_flow_rules(vec![rule.clone()]).unwrap();
+        let state = net.state();
+
+        // The queue pairs set by the driver are still in use once the
+        // restored device gets activated.
+        let mut restored = new_net();
+        restored.set_state(&state).unwrap();
+        assert_eq!(restored.queues_state().queue_pairs, 4);
+        assert_eq!(restored.restored_queue_pairs, Some(4));
+        assert_eq!(restored.flow_rules(), vec![rule]);
+
+        // The snapshots taken before the queue pairs were saved restore
+        // the device with just the first one.
+        let mut value = serde_json::to_value(&state).unwrap();
+        let fields = value.as_object_mut().unwrap();
+        fields.remove("queue_pairs");
+        fields.remove("flow_rules");
+        let state: NetState = serde_json::from_value(value).unwrap();
+        let mut restored = new_net();
+        restored.set_state(&state).unwrap();
+        assert_eq!(restored.queues_state().queue_pairs, 1);
+        assert_eq!(restored.restored_queue_pairs, None);
+        assert!(restored.flow_rules().is_empty());
+    }
+}
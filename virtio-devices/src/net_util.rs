@@ -2,13 +2,14 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
+use super::trace::{Span, SpanId};
 use super::Error as DeviceError;
-use super::{DescriptorChain, DeviceEventT, Queue};
+use super::{DescriptorChain, DeviceEventT, IdleTracker, Queue, ReadyNotifier};
 use net_util::{register_listener, MacAddr};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 use std::sync::Arc;
 use std::thread;
 use virtio_bindings::bindings::virtio_net::*;
@@ -97,6 +98,8 @@ pub enum Error {
 pub struct CtrlVirtio {
     pub queue_evt: EventFd,
     pub queue: Queue,
+    /// Number of queue pairs in use, as last set by the guest.
+    pub queue_pairs: Arc<AtomicU16>,
 }
 
 impl std::clone::Clone for CtrlVirtio {
@@ -104,13 +107,18 @@ impl std::clone::Clone for CtrlVirtio {
         CtrlVirtio {
             queue_evt: self.queue_evt.try_clone().unwrap(),
             queue: self.queue.clone(),
+            queue_pairs: self.queue_pairs.clone(),
         }
     }
 }
 
 impl CtrlVirtio {
-    pub fn new(queue: Queue, queue_evt: EventFd) -> Self {
-        CtrlVirtio { queue_evt, queue }
+    pub fn new(queue: Queue, queue_evt: EventFd, queue_pairs: Arc<AtomicU16>) -> Self {
+        CtrlVirtio {
+            queue_evt,
+            queue,
+            queue_pairs,
+        }
     }
 
     fn process_mq(&self, mem: &GuestMemoryMmap, avail_desc: DescriptorChain) -> Result<()> {
@@ -134,6 +142,7 @@ impl CtrlVirtio {
         };
         mem.write_obj::<u8>(0, status_desc.addr)
             .map_err(Error::GuestMemory)?;
+        self.queue_pairs.store(queue_pairs, Ordering::SeqCst);
 
         Ok(())
     }
@@ -179,10 +188,19 @@ pub struct NetCtrlEpollHandler {
     pub pause_evt: EventFd,
     pub ctrl_q: CtrlVirtio,
     pub epoll_fd: RawFd,
+    // Span of the activation which started the control thread
+    pub activate_span: Option<SpanId>,
+    pub idle_tracker: Option<IdleTracker>,
 }
 
 impl NetCtrlEpollHandler {
-    pub fn run_ctrl(&mut self, paused: Arc<AtomicBool>) -> std::result::Result<(), DeviceError> {
+    pub fn run_ctrl(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        ready: ReadyNotifier,
+    ) -> std::result::Result<(), DeviceError> {
+        let startup_span = Span::child_of(self.activate_span, "net_ctrl_startup", String::new);
+
         // Create the epoll file descriptor
         self.epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
         // Use 'File' to enforce closing on 'epoll_fd'
@@ -211,6 +229,8 @@ impl NetCtrlEpollHandler {
         .unwrap();
 
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); CTRL_EVENT_COUNT];
+        drop(startup_span);
+        ready.notify();
 
         // Before jumping into the epoll loop, check if the device is expected
         // to be in a paused state. This is helpful for the restore code path
@@ -240,9 +260,16 @@ impl NetCtrlEpollHandler {
                         if let Err(e) = self.ctrl_q.queue_evt.read() {
                             error!("failed to get ctl queue event: {:?}", e);
                         }
+                        // Control requests are completed right away.
+                        if let Some(idle_tracker) = &self.idle_tracker {
+                            idle_tracker.busy();
+                        }
                         if let Err(e) = self.ctrl_q.process_cvq(&mem) {
                             error!("failed to process ctrl queue: {:?}", e);
                         }
+                        if let Some(idle_tracker) = &self.idle_tracker {
+                            idle_tracker.done();
+                        }
                     }
                     KILL_EVENT => {
                         break 'epoll;
@@ -272,28 +299,28 @@ impl NetCtrlEpollHandler {
     }
 }
 
+/// Fill `config` with the MAC address and the queue pairs of the device,
+/// returning the features telling the driver about them.
 pub fn build_net_config_space(
-    mut config: &mut VirtioNetConfig,
+    config: &mut VirtioNetConfig,
     mac: MacAddr,
     num_queues: usize,
-    mut avail_features: &mut u64,
-) {
+) -> u64 {
     config.mac.copy_from_slice(mac.get_bytes());
-    *avail_features |= 1 << VIRTIO_NET_F_MAC;
 
-    build_net_config_space_with_mq(&mut config, num_queues, &mut avail_features);
+    1 << VIRTIO_NET_F_MAC | build_net_config_space_with_mq(config, num_queues)
 }
 
-pub fn build_net_config_space_with_mq(
-    config: &mut VirtioNetConfig,
-    num_queues: usize,
-    avail_features: &mut u64,
-) {
+/// Fill `config` with the queue pairs of the device, returning the
+/// features telling the driver about them.
+pub fn build_net_config_space_with_mq(config: &mut VirtioNetConfig, num_queues: usize) -> u64 {
     let num_queue_pairs = (num_queues / 2) as u16;
     if (num_queue_pairs >= VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN as u16)
         && (num_queue_pairs <= VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX as u16)
     {
         config.max_virtqueue_pairs = num_queue_pairs;
-        *avail_features |= 1 << VIRTIO_NET_F_MQ;
+        1 << VIRTIO_NET_F_MQ
+    } else {
+        0
     }
 }
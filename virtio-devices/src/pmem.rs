@@ -363,7 +363,7 @@ pub struct Pmem {
 
     // Hold ownership of the memory that is allocated for the device
     // which will be automatically dropped when the device is dropped
-    _region: MmapRegion,
+    region: MmapRegion,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -379,12 +379,12 @@ impl Pmem {
         disk: File,
         addr: GuestAddress,
         mapping: UserspaceMapping,
-        _region: MmapRegion,
+        region: MmapRegion,
         iommu: bool,
     ) -> io::Result<Pmem> {
         let config = VirtioPmemConfig {
             start: addr.raw_value().to_le(),
-            size: (_region.size() as u64).to_le(),
+            size: (region.size() as u64).to_le(),
         };
 
         let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
@@ -406,7 +406,7 @@ impl Pmem {
             epoll_threads: None,
             paused: Arc::new(AtomicBool::new(false)),
             mapping,
-            _region,
+            region,
         })
     }
 
@@ -567,6 +567,26 @@ impl VirtioDevice for Pmem {
     fn userspace_mappings(&self) -> Vec<UserspaceMapping> {
         vec![self.mapping.clone()]
     }
+
+    // Write back to the backing file what the guest stored in the region
+    // and didn't flush yet, as the device is about to be unplugged or the
+    // VM to be shut down.
+    fn shutdown(&mut self) {
+        // SAFETY: the region stays mapped for as long as the device exists.
+        let ret = unsafe {
+            libc::msync(
+                self.region.as_ptr() as *mut libc::c_void,
+                self.region.size(),
+                libc::MS_SYNC,
+            )
+        };
+        if ret != 0 {
+            error!(
+                "Failed flushing pmem region: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
 }
 
 virtio_pausable!(Pmem);
@@ -613,3 +633,76 @@ impl Snapshottable for Pmem {
 
 impl Transportable for Pmem {}
 impl Migratable for Pmem {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use vm_memory::FileOffset;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const REGION_SIZE: usize = 0x20_0000;
+
+    // Size of the dirty pages of the mapping starting at addr, as accounted
+    // in /proc/self/smaps, in KiB.
+    fn dirty_size(addr: u64) -> u64 {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let header = format!("{:x}-", addr);
+        smaps
+            .lines()
+            .skip_while(|line| !line.starts_with(&header))
+            .skip(1)
+            .take_while(|line| line.split_whitespace().next().unwrap().ends_with(':'))
+            .filter(|line| line.starts_with("Shared_Dirty:") || line.starts_with("Private_Dirty:"))
+            .map(|line| {
+                line.split_whitespace()
+                    .nth(1)
+                    .unwrap()
+                    .parse::<u64>()
+                    .unwrap()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_pmem_shutdown_flush() {
+        // The page cache of a tmpfs file never gets written back, so the
+        // backing file must sit on a disk.
+        let file = TempFile::new_in(Path::new("/var/tmp")).unwrap();
+        let file = file.as_file().try_clone().unwrap();
+        file.set_len(REGION_SIZE as u64).unwrap();
+        let region = MmapRegion::build(
+            Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+            REGION_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_NORESERVE | libc::MAP_SHARED,
+        )
+        .unwrap();
+        let host_addr = region.as_ptr() as u64;
+        // SAFETY: the region is mapped for its whole size.
+        unsafe { std::ptr::write_bytes(region.as_ptr(), 0xa5, REGION_SIZE) };
+
+        let mapping = UserspaceMapping {
+            host_addr,
+            mem_slot: 0,
+            addr: GuestAddress(0),
+            len: REGION_SIZE as u64,
+            mergeable: false,
+        };
+        let mut pmem = Pmem::new(
+            "pmem0".to_string(),
+            file,
+            GuestAddress(0),
+            mapping,
+            region,
+            false,
+        )
+        .unwrap();
+
+        // What was stored in the region has yet to be written back, until
+        // the device gets shut down.
+        assert!(dirty_size(host_addr) > 0);
+        pmem.shutdown();
+        assert_eq!(dirty_size(host_addr), 0);
+    }
+}
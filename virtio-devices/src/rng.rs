@@ -10,11 +10,14 @@ use super::{
 use crate::{VirtioInterrupt, VirtioInterruptType};
 use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
+use rate_limiter::RateLimiter;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io;
+use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::result;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread;
 use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
@@ -34,6 +37,13 @@ const QUEUE_AVAIL_EVENT: DeviceEventT = 0;
 const KILL_EVENT: DeviceEventT = 1;
 // The device should be paused.
 const PAUSE_EVENT: DeviceEventT = 2;
+// The rate limiter budget has been replenished.
+const RATE_LIMITER_EVENT: DeviceEventT = 3;
+
+#[derive(Clone, Default)]
+pub struct RngCounters {
+    bytes_served: Arc<AtomicU64>,
+}
 
 struct RngEpollHandler {
     queues: Vec<Queue>,
@@ -43,6 +53,8 @@ struct RngEpollHandler {
     queue_evt: EventFd,
     kill_evt: EventFd,
     pause_evt: EventFd,
+    rate_limiter: Option<RateLimiter>,
+    counters: RngCounters,
 }
 
 impl RngEpollHandler {
@@ -52,24 +64,49 @@ impl RngEpollHandler {
         let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
         let mut used_count = 0;
         let mem = self.mem.memory();
-        for avail_desc in queue.iter(&mem) {
+        // The descriptors are taken one at a time, so that the queue can be
+        // moved back to the one the rate limiter refused.
+        while let Some(avail_desc) = queue.iter(&mem).next() {
             let mut len = 0;
 
             // Drivers can only read from the random device.
             if avail_desc.is_write_only() {
+                let mut read_len = avail_desc.len;
+
+                // Only fill what the rate limiter allows. If the budget is
+                // exhausted, or can't be accounted for, the descriptor is
+                // left in the queue until the rate limiter timer expires or
+                // the driver notifies the queue again.
+                if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+                    let granted = match rate_limiter.consume(u64::from(avail_desc.len)) {
+                        Ok(granted) => granted,
+                        Err(e) => {
+                            error!("Failed to consume rate limiter budget: {}", e);
+                            queue.go_to_previous_position();
+                            break;
+                        }
+                    };
+
+                    if rate_limiter.is_blocked() {
+                        queue.go_to_previous_position();
+                        break;
+                    }
+                    read_len = granted as u32;
+                }
+
                 // Fill the read with data from the random device on the host.
-                if mem
-                    .read_from(
-                        avail_desc.addr,
-                        &mut self.random_file,
-                        avail_desc.len as usize,
-                    )
-                    .is_ok()
+                // A hardware generator can provide less than requested.
+                if let Ok(count) =
+                    mem.read_from(avail_desc.addr, &mut self.random_file, read_len as usize)
                 {
-                    len = avail_desc.len;
+                    len = count as u32;
                 }
             }
 
+            self.counters
+                .bytes_served
+                .fetch_add(u64::from(len), Ordering::AcqRel);
+
             used_desc_heads[used_count] = (avail_desc.index, len);
             used_count += 1;
         }
@@ -117,6 +154,15 @@ impl RngEpollHandler {
             epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
+        if let Some(rate_limiter) = &self.rate_limiter {
+            epoll::ctl(
+                epoll_file.as_raw_fd(),
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                rate_limiter.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(RATE_LIMITER_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
 
         const EPOLL_EVENTS_LEN: usize = 100;
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
@@ -162,6 +208,23 @@ impl RngEpollHandler {
                             }
                         }
                     }
+                    RATE_LIMITER_EVENT => {
+                        if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+                            if let Err(e) = rate_limiter.event_handler() {
+                                error!("Failed to process rate limiter event: {}", e);
+                                break 'epoll;
+                            }
+                        }
+
+                        // Budget has been replenished, process descriptors
+                        // which have been deferred.
+                        if self.process_queue() {
+                            if let Err(e) = self.signal_used_queue() {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
                     KILL_EVENT => {
                         debug!("KILL_EVENT received, stopping epoll loop");
                         break 'epoll;
@@ -203,6 +266,9 @@ pub struct Rng {
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     paused: Arc<AtomicBool>,
+    max_bytes: Option<u64>,
+    period_ms: u64,
+    counters: RngCounters,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -213,8 +279,16 @@ pub struct RngState {
 }
 
 impl Rng {
-    /// Create a new virtio rng device that gets random data from /dev/urandom.
-    pub fn new(id: String, path: &str, iommu: bool) -> io::Result<Rng> {
+    /// Create a new virtio rng device that gets random data from the file
+    /// at `path`. The amount of random bytes provided to the guest can be
+    /// limited to `max_bytes` every `period_ms` milliseconds.
+    pub fn new(
+        id: String,
+        path: &str,
+        iommu: bool,
+        max_bytes: Option<u64>,
+        period_ms: u64,
+    ) -> io::Result<Rng> {
         let random_file = File::open(path)?;
         let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
 
@@ -233,6 +307,9 @@ impl Rng {
             interrupt_cb: None,
             epoll_threads: None,
             paused: Arc::new(AtomicBool::new(false)),
+            max_bytes,
+            period_ms,
+            counters: RngCounters::default(),
         })
     }
 
@@ -340,6 +417,16 @@ impl VirtioDevice for Rng {
                 error!("failed cloning rng source: {}", e);
                 ActivateError::BadActivate
             })?;
+            let rate_limiter = match self.max_bytes {
+                Some(max_bytes) => {
+                    Some(RateLimiter::new(max_bytes, self.period_ms).map_err(|e| {
+                        error!("failed creating rate limiter: {}", e);
+                        ActivateError::BadActivate
+                    })?)
+                }
+                None => None,
+            };
+
             let mut handler = RngEpollHandler {
                 queues,
                 mem,
@@ -348,6 +435,8 @@ impl VirtioDevice for Rng {
                 queue_evt: queue_evts.remove(0),
                 kill_evt,
                 pause_evt,
+                rate_limiter,
+                counters: self.counters.clone(),
             };
 
             let paused = self.paused.clone();
@@ -386,6 +475,17 @@ impl VirtioDevice for Rng {
             self.queue_evts.take().unwrap(),
         ))
     }
+
+    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
+        let mut counters = HashMap::new();
+
+        counters.insert(
+            "bytes_served",
+            Wrapping(self.counters.bytes_served.load(Ordering::Acquire)),
+        );
+
+        Some(counters)
+    }
 }
 
 virtio_pausable!(Rng);
@@ -432,3 +532,111 @@ impl Snapshottable for Rng {
 
 impl Transportable for Rng {}
 impl Migratable for Rng {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::NoopVirtioInterrupt;
+    use std::time::{Duration, Instant};
+    use vm_memory::GuestAddress;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::VIRTQ_DESC_F_WRITE;
+
+    #[test]
+    fn test_rng_burst_is_paced() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_q = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        for i in 0..16u16 {
+            guest_q.dtable[i as usize].set(
+                0x2_0000 + u64::from(i) * 0x100,
+                16,
+                VIRTQ_DESC_F_WRITE,
+                0,
+            );
+            guest_q.avail.ring[i as usize].set(i);
+        }
+        guest_q.avail.idx.set(16);
+
+        let start = Instant::now();
+        let mut handler = RngEpollHandler {
+            queues: vec![guest_q.create_queue()],
+            mem: GuestMemoryAtomic::new(mem),
+            random_file: File::open("/dev/urandom").unwrap(),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            // 32 bytes every 100 ms, that is two requests.
+            rate_limiter: Some(RateLimiter::new(32, 100).unwrap()),
+            counters: RngCounters::default(),
+        };
+
+        // The burst only gets what the full bucket holds right away.
+        assert!(handler.process_queue());
+        assert_eq!(guest_q.used.idx.get(), 2);
+
+        // The rest of it is served as the budget gets replenished.
+        let mut served = 32;
+        while served < 128 {
+            handler
+                .rate_limiter
+                .as_mut()
+                .unwrap()
+                .event_handler()
+                .unwrap();
+            let used = guest_q.used.idx.get();
+            handler.process_queue();
+            for i in used..guest_q.used.idx.get() {
+                served += guest_q.used.ring[i as usize].get().len;
+            }
+        }
+
+        // The 96 bytes beyond the initial budget take 300 ms at the
+        // configured rate.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(300));
+        assert!(elapsed < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_rng_rate_limited_requests_are_deferred() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_q = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        for i in 0..3u16 {
+            guest_q.dtable[i as usize].set(
+                0x2_0000 + u64::from(i) * 0x100,
+                16,
+                VIRTQ_DESC_F_WRITE,
+                0,
+            );
+            guest_q.avail.ring[i as usize].set(i);
+        }
+        guest_q.avail.idx.set(3);
+
+        let counters = RngCounters::default();
+        let mut handler = RngEpollHandler {
+            queues: vec![guest_q.create_queue()],
+            mem: GuestMemoryAtomic::new(mem),
+            random_file: File::open("/dev/urandom").unwrap(),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            // Room for two requests, the refill is far beyond the test.
+            rate_limiter: Some(RateLimiter::new(32, 60_000).unwrap()),
+            counters: counters.clone(),
+        };
+
+        // The third request must wait for the budget to be replenished
+        // instead of being completed without any data.
+        assert!(handler.process_queue());
+        assert_eq!(guest_q.used.idx.get(), 2);
+        for i in 0..2 {
+            assert_eq!(guest_q.used.ring[i].get().len, 16);
+        }
+        assert!(handler.rate_limiter.as_ref().unwrap().is_blocked());
+        assert!(!handler.process_queue());
+        assert_eq!(guest_q.used.idx.get(), 2);
+        assert_eq!(counters.bytes_served.load(Ordering::Acquire), 32);
+    }
+}
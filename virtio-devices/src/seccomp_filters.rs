@@ -0,0 +1,149 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Seccomp filters applied by the virtio device threads on top of the one
+//! they inherit from the VMM thread, each thread only being allowed the
+//! system calls its role requires.
+
+use seccomp::{
+    allow_syscall, BpfProgram, Error, SeccompAction, SeccompError, SeccompFilter, SeccompRule,
+};
+use std::convert::TryInto;
+
+pub enum Thread {
+    VirtioBlock,
+    VirtioNet,
+    VirtioNetCtl,
+}
+
+// System calls needed by any device thread to wait for its events, to
+// allocate memory, to log and to exit.
+fn thread_common_rules() -> Vec<(i64, Vec<SeccompRule>)> {
+    vec![
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_epoll_create1),
+        allow_syscall(libc::SYS_epoll_ctl),
+        #[cfg(target_arch = "aarch64")]
+        allow_syscall(libc::SYS_epoll_pwait),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_epoll_wait),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_read),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_write),
+    ]
+}
+
+fn virtio_block_thread_filter(action: SeccompAction) -> Result<SeccompFilter, Error> {
+    let mut rules = thread_common_rules();
+    rules.extend(vec![
+        allow_syscall(libc::SYS_fallocate),
+        allow_syscall(libc::SYS_fdatasync),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_fstat),
+        allow_syscall(libc::SYS_fsync),
+        allow_syscall(libc::SYS_ftruncate),
+        allow_syscall(libc::SYS_lseek),
+        allow_syscall(libc::SYS_pread64),
+        allow_syscall(libc::SYS_preadv),
+        allow_syscall(libc::SYS_pwrite64),
+        allow_syscall(libc::SYS_pwritev),
+        allow_syscall(libc::SYS_readv),
+        allow_syscall(libc::SYS_timerfd_settime),
+        allow_syscall(libc::SYS_writev),
+    ]);
+
+    Ok(SeccompFilter::new(rules.into_iter().collect(), action)?)
+}
+
+fn virtio_net_thread_filter(action: SeccompAction) -> Result<SeccompFilter, Error> {
+    let mut rules = thread_common_rules();
+    rules.extend(vec![
+        allow_syscall(libc::SYS_readv),
+        allow_syscall(libc::SYS_timerfd_settime),
+        allow_syscall(libc::SYS_writev),
+    ]);
+
+    Ok(SeccompFilter::new(rules.into_iter().collect(), action)?)
+}
+
+fn virtio_net_ctl_thread_filter(action: SeccompAction) -> Result<SeccompFilter, Error> {
+    Ok(SeccompFilter::new(
+        thread_common_rules().into_iter().collect(),
+        action,
+    )?)
+}
+
+/// Generate the BPF program of a device thread. The system calls which
+/// are not allowed trigger `seccomp_action`, and no filtering happens at
+/// all if that action is `SeccompAction::Allow`.
+pub fn get_seccomp_filter(
+    seccomp_action: &SeccompAction,
+    thread_type: Thread,
+) -> Result<BpfProgram, SeccompError> {
+    if *seccomp_action == SeccompAction::Allow {
+        return Ok(vec![]);
+    }
+
+    let action = seccomp_action.clone();
+    let filter = match thread_type {
+        Thread::VirtioBlock => virtio_block_thread_filter(action),
+        Thread::VirtioNet => virtio_net_thread_filter(action),
+        Thread::VirtioNetCtl => virtio_net_ctl_thread_filter(action),
+    };
+    filter
+        .and_then(|filter| filter.try_into())
+        .map_err(SeccompError::SeccompFilter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use vmm_sys_util::eventfd::EventFd;
+
+    #[test]
+    fn test_virtio_net_ctl_thread_filter() {
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let thread_evt = evt.try_clone().unwrap();
+
+        // Failing the forbidden system calls rather than killing the
+        // thread keeps the test process alive. The filter only applies to
+        // the thread installing it.
+        let filter = get_seccomp_filter(
+            &SeccompAction::Errno(libc::EPERM as u32),
+            Thread::VirtioNetCtl,
+        )
+        .unwrap();
+        let (write_ok, socket_ret, socket_errno) = thread::spawn(move || {
+            SeccompFilter::apply(filter).unwrap();
+
+            let write_ok = thread_evt.write(1).is_ok();
+            // The control thread has no business opening sockets.
+            let socket_ret = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+            let socket_errno = std::io::Error::last_os_error().raw_os_error();
+            (write_ok, socket_ret, socket_errno)
+        })
+        .join()
+        .unwrap();
+
+        assert!(write_ok);
+        assert_eq!(socket_ret, -1);
+        assert_eq!(socket_errno, Some(libc::EPERM));
+        assert_eq!(evt.read().unwrap(), 1);
+
+        // Nothing gets filtered when the action allows everything.
+        assert!(
+            get_seccomp_filter(&SeccompAction::Allow, Thread::VirtioNetCtl)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Host side of the virtio-snd PCM streams.
+//!
+//! Samples can be dropped, written to a raw file, or played through an ALSA
+//! device when built with the `alsa` feature. Backends block until they can
+//! take more samples, making the stream progress in real time from the guest
+//! point of view.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "alsa")]
+pub use self::alsa::AlsaBackend;
+
+/// Sample formats supported by the device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PcmFormat {
+    U8,
+    S16,
+    S32,
+}
+
+impl PcmFormat {
+    pub fn sample_bytes(self) -> u32 {
+        match self {
+            PcmFormat::U8 => 1,
+            PcmFormat::S16 => 2,
+            PcmFormat::S32 => 4,
+        }
+    }
+}
+
+/// Parameters of a PCM stream, as set by the guest driver.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PcmParams {
+    pub buffer_bytes: u32,
+    pub period_bytes: u32,
+    pub channels: u8,
+    pub format: PcmFormat,
+    pub rate: u32,
+}
+
+impl PcmParams {
+    pub fn frame_bytes(&self) -> u32 {
+        self.format.sample_bytes() * u32::from(self.channels)
+    }
+
+    pub fn byte_rate(&self) -> u64 {
+        u64::from(self.frame_bytes()) * u64::from(self.rate)
+    }
+}
+
+/// Host audio output a playback stream is played to.
+pub trait PcmBackend: Send {
+    /// Get ready to play samples with the given parameters.
+    fn prepare(&mut self, params: &PcmParams) -> io::Result<()>;
+    /// Start playing.
+    fn start(&mut self) -> io::Result<()>;
+    /// Play `data`, blocking until the host can take more samples.
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+    /// Stop playing, dropping the samples which haven't been played yet.
+    fn stop(&mut self) -> io::Result<()>;
+    /// Give back the host resources taken by `prepare()`.
+    fn release(&mut self);
+}
+
+// Consume samples at the stream rate for backends which would otherwise
+// take them as fast as they are provided.
+#[derive(Default)]
+struct Pacer {
+    byte_rate: u64,
+    start: Option<Instant>,
+    bytes: u64,
+}
+
+impl Pacer {
+    fn prepare(&mut self, params: &PcmParams) {
+        self.byte_rate = params.byte_rate();
+        self.start = None;
+    }
+
+    fn start(&mut self) {
+        self.start = Some(Instant::now());
+        self.bytes = 0;
+    }
+
+    fn stop(&mut self) {
+        self.start = None;
+    }
+
+    fn consume(&mut self, len: usize) {
+        if let Some(start) = self.start {
+            if self.byte_rate == 0 {
+                return;
+            }
+
+            self.bytes += len as u64;
+            let deadline = start + Duration::from_micros(self.bytes * 1_000_000 / self.byte_rate);
+            let now = Instant::now();
+            if deadline > now {
+                thread::sleep(deadline - now);
+            }
+        }
+    }
+}
+
+/// Drop all samples.
+#[derive(Default)]
+pub struct NullBackend {
+    pacer: Pacer,
+}
+
+impl NullBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PcmBackend for NullBackend {
+    fn prepare(&mut self, params: &PcmParams) -> io::Result<()> {
+        self.pacer.prepare(params);
+        Ok(())
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        self.pacer.start();
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.pacer.consume(data.len());
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.pacer.stop();
+        Ok(())
+    }
+
+    fn release(&mut self) {}
+}
+
+/// Write raw samples to a file, one stream after the other.
+pub struct FileBackend {
+    file: File,
+    pacer: Pacer,
+}
+
+impl FileBackend {
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(FileBackend {
+            file: File::create(path)?,
+            pacer: Pacer::default(),
+        })
+    }
+}
+
+impl PcmBackend for FileBackend {
+    fn prepare(&mut self, params: &PcmParams) -> io::Result<()> {
+        self.pacer.prepare(params);
+        Ok(())
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        self.pacer.start();
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.pacer.consume(data.len());
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.pacer.stop();
+        self.file.flush()
+    }
+
+    fn release(&mut self) {}
+}
+
+#[cfg(feature = "alsa")]
+mod alsa {
+    use super::{PcmBackend, PcmFormat, PcmParams};
+    use std::ffi::CString;
+    use std::io;
+    use std::os::raw::{c_char, c_int, c_long, c_uint, c_ulong, c_void};
+    use std::ptr;
+
+    #[allow(non_camel_case_types)]
+    enum snd_pcm_t {}
+
+    const SND_PCM_STREAM_PLAYBACK: c_int = 0;
+    const SND_PCM_ACCESS_RW_INTERLEAVED: c_int = 3;
+    const SND_PCM_FORMAT_U8: c_int = 1;
+    const SND_PCM_FORMAT_S16_LE: c_int = 2;
+    const SND_PCM_FORMAT_S32_LE: c_int = 10;
+
+    #[link(name = "asound")]
+    extern "C" {
+        fn snd_pcm_open(
+            pcm: *mut *mut snd_pcm_t,
+            name: *const c_char,
+            stream: c_int,
+            mode: c_int,
+        ) -> c_int;
+        fn snd_pcm_close(pcm: *mut snd_pcm_t) -> c_int;
+        fn snd_pcm_set_params(
+            pcm: *mut snd_pcm_t,
+            format: c_int,
+            access: c_int,
+            channels: c_uint,
+            rate: c_uint,
+            soft_resample: c_int,
+            latency: c_uint,
+        ) -> c_int;
+        fn snd_pcm_prepare(pcm: *mut snd_pcm_t) -> c_int;
+        fn snd_pcm_drop(pcm: *mut snd_pcm_t) -> c_int;
+        fn snd_pcm_writei(pcm: *mut snd_pcm_t, buffer: *const c_void, size: c_ulong) -> c_long;
+        fn snd_pcm_recover(pcm: *mut snd_pcm_t, err: c_int, silent: c_int) -> c_int;
+    }
+
+    fn check(ret: c_int) -> io::Result<()> {
+        if ret < 0 {
+            Err(io::Error::from_raw_os_error(-ret))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Play samples through an ALSA PCM device.
+    pub struct AlsaBackend {
+        device: CString,
+        pcm: *mut snd_pcm_t,
+        frame_bytes: usize,
+    }
+
+    // The PCM handle is only ever used by the thread owning the backend.
+    unsafe impl Send for AlsaBackend {}
+
+    impl AlsaBackend {
+        /// Create a backend playing to the ALSA PCM `device`, which is
+        /// opened once to make sure it exists.
+        pub fn new(device: &str) -> io::Result<Self> {
+            let mut backend = AlsaBackend {
+                device: CString::new(device)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+                pcm: ptr::null_mut(),
+                frame_bytes: 0,
+            };
+            backend.open()?;
+            backend.release();
+
+            Ok(backend)
+        }
+
+        fn open(&mut self) -> io::Result<()> {
+            let mut pcm = ptr::null_mut();
+            check(unsafe {
+                snd_pcm_open(&mut pcm, self.device.as_ptr(), SND_PCM_STREAM_PLAYBACK, 0)
+            })?;
+            self.pcm = pcm;
+
+            Ok(())
+        }
+    }
+
+    impl PcmBackend for AlsaBackend {
+        fn prepare(&mut self, params: &PcmParams) -> io::Result<()> {
+            self.release();
+            self.open()?;
+
+            let format = match params.format {
+                PcmFormat::U8 => SND_PCM_FORMAT_U8,
+                PcmFormat::S16 => SND_PCM_FORMAT_S16_LE,
+                PcmFormat::S32 => SND_PCM_FORMAT_S32_LE,
+            };
+            // Let ALSA buffer as much as the guest driver does.
+            let latency_us = u64::from(params.buffer_bytes) * 1_000_000 / params.byte_rate();
+            self.frame_bytes = params.frame_bytes() as usize;
+
+            check(unsafe {
+                snd_pcm_set_params(
+                    self.pcm,
+                    format,
+                    SND_PCM_ACCESS_RW_INTERLEAVED,
+                    c_uint::from(params.channels),
+                    params.rate,
+                    1,
+                    latency_us as c_uint,
+                )
+            })
+        }
+
+        fn start(&mut self) -> io::Result<()> {
+            // Playback starts with the first samples being written.
+            Ok(())
+        }
+
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            let mut frames = data.chunks_exact(self.frame_bytes).len();
+            let mut offset = 0;
+            while frames > 0 {
+                let ret = unsafe {
+                    snd_pcm_writei(
+                        self.pcm,
+                        data[offset..].as_ptr() as *const c_void,
+                        frames as c_ulong,
+                    )
+                };
+                if ret < 0 {
+                    // Recover from underruns, the guest simply didn't
+                    // provide samples fast enough.
+                    check(unsafe { snd_pcm_recover(self.pcm, ret as c_int, 1) })?;
+                    continue;
+                }
+
+                frames -= ret as usize;
+                offset += ret as usize * self.frame_bytes;
+            }
+
+            Ok(())
+        }
+
+        fn stop(&mut self) -> io::Result<()> {
+            check(unsafe { snd_pcm_drop(self.pcm) })?;
+            check(unsafe { snd_pcm_prepare(self.pcm) })
+        }
+
+        fn release(&mut self) {
+            if !self.pcm.is_null() {
+                unsafe { snd_pcm_close(self.pcm) };
+                self.pcm = ptr::null_mut();
+            }
+        }
+    }
+
+    impl Drop for AlsaBackend {
+        fn drop(&mut self) {
+            self.release();
+        }
+    }
+}
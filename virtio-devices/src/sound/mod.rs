@@ -0,0 +1,1635 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Virtio sound device.
+//!
+//! The device exposes a single jack and a single stereo playback stream,
+//! whose samples are handed to a `PcmBackend`. Requests on the control
+//! queue move the stream through the states defined by the specification,
+//! and PCM buffers made available on the tx queue are played while the
+//! stream is running. Each buffer is returned once played, and an event is
+//! sent whenever a period elapsed. Capture isn't supported yet, so buffers
+//! made available on the rx queue are returned right away with an error
+//! status.
+
+mod backend;
+
+pub use self::backend::*;
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DescriptorChain, EpollHelper, EpollHelperError,
+    EpollHelperHandler, Queue, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST,
+    VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
+};
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
+use libc::EFD_NONBLOCK;
+use std::cmp;
+use std::fmt::{self, Display};
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vm_memory::{
+    ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError,
+    GuestMemoryMmap,
+};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 256;
+const NUM_QUEUES: usize = 4;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+
+const CONTROL_QUEUE: usize = 0;
+const EVENT_QUEUE: usize = 1;
+const TX_QUEUE: usize = 2;
+const RX_QUEUE: usize = 3;
+
+// New descriptors are pending on the control queue.
+const CONTROL_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// New descriptors are pending on the event queue.
+const EVENT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// New descriptors are pending on the tx queue.
+const TX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// New descriptors are pending on the rx queue.
+const RX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+
+// Requests carried by the control queue.
+const VIRTIO_SND_R_JACK_INFO: u32 = 1;
+const VIRTIO_SND_R_JACK_REMAP: u32 = 2;
+const VIRTIO_SND_R_PCM_INFO: u32 = 0x0100;
+const VIRTIO_SND_R_PCM_SET_PARAMS: u32 = 0x0101;
+const VIRTIO_SND_R_PCM_PREPARE: u32 = 0x0102;
+const VIRTIO_SND_R_PCM_RELEASE: u32 = 0x0103;
+const VIRTIO_SND_R_PCM_START: u32 = 0x0104;
+const VIRTIO_SND_R_PCM_STOP: u32 = 0x0105;
+const VIRTIO_SND_R_CHMAP_INFO: u32 = 0x0200;
+
+// Events notified through the event queue.
+const VIRTIO_SND_EVT_PCM_PERIOD_ELAPSED: u32 = 0x1100;
+const VIRTIO_SND_EVT_PCM_XRUN: u32 = 0x1101;
+
+// Status of the requests and of the PCM buffers.
+const VIRTIO_SND_S_OK: u32 = 0x8000;
+const VIRTIO_SND_S_BAD_MSG: u32 = 0x8001;
+const VIRTIO_SND_S_NOT_SUPP: u32 = 0x8002;
+const VIRTIO_SND_S_IO_ERR: u32 = 0x8003;
+
+// Data flow directions.
+const VIRTIO_SND_D_OUTPUT: u8 = 0;
+
+// PCM sample formats.
+const VIRTIO_SND_PCM_FMT_U8: u8 = 4;
+const VIRTIO_SND_PCM_FMT_S16: u8 = 5;
+const VIRTIO_SND_PCM_FMT_S32: u8 = 17;
+
+// PCM sample formats supported by the stream, along with their code.
+const PCM_FORMATS: &[(u8, PcmFormat)] = &[
+    (VIRTIO_SND_PCM_FMT_U8, PcmFormat::U8),
+    (VIRTIO_SND_PCM_FMT_S16, PcmFormat::S16),
+    (VIRTIO_SND_PCM_FMT_S32, PcmFormat::S32),
+];
+
+// PCM frame rates, indexed by their code from the specification.
+const PCM_RATES: &[(u8, u32)] = &[
+    (1, 8000),
+    (2, 11025),
+    (3, 16000),
+    (4, 22050),
+    (5, 32000),
+    (6, 44100),
+    (7, 48000),
+];
+
+// Channel positions.
+const VIRTIO_SND_CHMAP_FL: u8 = 3;
+const VIRTIO_SND_CHMAP_FR: u8 = 4;
+const VIRTIO_SND_CHMAP_MAX_SIZE: usize = 18;
+
+// Function node shared by all the entities, as they are part of the same
+// HDA codec function group.
+const HDA_FN_NID: u32 = 0;
+
+const PCM_CHANNELS_MIN: u8 = 1;
+const PCM_CHANNELS_MAX: u8 = 2;
+
+// Upper bound on the amount of data read from a single descriptor chain,
+// which is way more than a PCM period should ever be.
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Guest gave us a readable descriptor following a writable one.
+    UnexpectedReadableDescriptor,
+    /// Guest gave us more data than we can handle at once.
+    RequestTooLarge,
+    /// Guest gave us too small a writable buffer for the response.
+    ResponseTooLarge,
+    /// Failed accessing the guest memory.
+    GuestMemory(GuestMemoryError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            UnexpectedReadableDescriptor => write!(f, "unexpected readable descriptor"),
+            RequestTooLarge => write!(f, "request too large"),
+            ResponseTooLarge => write!(f, "not enough room for the response"),
+            GuestMemory(e) => write!(f, "failed accessing guest memory: {}", e),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioSoundConfig {
+    jacks: u32,
+    streams: u32,
+    chmaps: u32,
+}
+
+unsafe impl ByteValued for VirtioSoundConfig {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioSoundHdr {
+    code: u32,
+}
+
+unsafe impl ByteValued for VirtioSoundHdr {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioSoundQueryInfo {
+    hdr: VirtioSoundHdr,
+    start_id: u32,
+    count: u32,
+    size: u32,
+}
+
+unsafe impl ByteValued for VirtioSoundQueryInfo {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioSoundInfo {
+    hda_fn_nid: u32,
+}
+
+unsafe impl ByteValued for VirtioSoundInfo {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioSoundJackInfo {
+    hdr: VirtioSoundInfo,
+    features: u32,
+    hda_reg_defconf: u32,
+    hda_reg_caps: u32,
+    connected: u8,
+    padding: [u8; 7],
+}
+
+unsafe impl ByteValued for VirtioSoundJackInfo {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioSoundPcmInfo {
+    hdr: VirtioSoundInfo,
+    features: u32,
+    formats: u64,
+    rates: u64,
+    direction: u8,
+    channels_min: u8,
+    channels_max: u8,
+    padding: [u8; 5],
+}
+
+unsafe impl ByteValued for VirtioSoundPcmInfo {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioSoundChmapInfo {
+    hdr: VirtioSoundInfo,
+    direction: u8,
+    channels: u8,
+    positions: [u8; VIRTIO_SND_CHMAP_MAX_SIZE],
+}
+
+unsafe impl ByteValued for VirtioSoundChmapInfo {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioSoundPcmHdr {
+    hdr: VirtioSoundHdr,
+    stream_id: u32,
+}
+
+unsafe impl ByteValued for VirtioSoundPcmHdr {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioSoundPcmSetParams {
+    hdr: VirtioSoundPcmHdr,
+    buffer_bytes: u32,
+    period_bytes: u32,
+    features: u32,
+    channels: u8,
+    format: u8,
+    rate: u8,
+    padding: u8,
+}
+
+unsafe impl ByteValued for VirtioSoundPcmSetParams {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioSoundPcmXfer {
+    stream_id: u32,
+}
+
+unsafe impl ByteValued for VirtioSoundPcmXfer {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioSoundPcmStatus {
+    status: u32,
+    latency_bytes: u32,
+}
+
+unsafe impl ByteValued for VirtioSoundPcmStatus {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioSoundEvent {
+    hdr: VirtioSoundHdr,
+    data: u32,
+}
+
+unsafe impl ByteValued for VirtioSoundEvent {}
+
+fn jack_infos() -> Vec<VirtioSoundJackInfo> {
+    vec![VirtioSoundJackInfo {
+        hdr: VirtioSoundInfo {
+            hda_fn_nid: HDA_FN_NID,
+        },
+        connected: 1,
+        ..Default::default()
+    }]
+}
+
+fn pcm_infos() -> Vec<VirtioSoundPcmInfo> {
+    let formats = PCM_FORMATS
+        .iter()
+        .fold(0u64, |formats, (code, _)| formats | (1u64 << code));
+    let rates = PCM_RATES
+        .iter()
+        .fold(0u64, |rates, (code, _)| rates | (1u64 << code));
+
+    vec![VirtioSoundPcmInfo {
+        hdr: VirtioSoundInfo {
+            hda_fn_nid: HDA_FN_NID,
+        },
+        formats,
+        rates,
+        direction: VIRTIO_SND_D_OUTPUT,
+        channels_min: PCM_CHANNELS_MIN,
+        channels_max: PCM_CHANNELS_MAX,
+        ..Default::default()
+    }]
+}
+
+fn chmap_infos() -> Vec<VirtioSoundChmapInfo> {
+    let mut positions = [0u8; VIRTIO_SND_CHMAP_MAX_SIZE];
+    positions[0] = VIRTIO_SND_CHMAP_FL;
+    positions[1] = VIRTIO_SND_CHMAP_FR;
+
+    vec![VirtioSoundChmapInfo {
+        hdr: VirtioSoundInfo {
+            hda_fn_nid: HDA_FN_NID,
+        },
+        direction: VIRTIO_SND_D_OUTPUT,
+        channels: 2,
+        positions,
+    }]
+}
+
+fn read_obj<T: ByteValued>(data: &[u8]) -> Option<T> {
+    if data.len() < size_of::<T>() {
+        return None;
+    }
+
+    let mut obj = T::default();
+    obj.as_mut_slice().copy_from_slice(&data[..size_of::<T>()]);
+    Some(obj)
+}
+
+fn status(status: u32) -> Vec<u8> {
+    VirtioSoundHdr { code: status }.as_slice().to_vec()
+}
+
+// Content of a descriptor chain, made of the data provided by the driver
+// followed by the room it left for the device to write into.
+struct Buffers {
+    readable: Vec<u8>,
+    writable: Vec<(GuestAddress, u32)>,
+}
+
+impl Buffers {
+    fn parse(mem: &GuestMemoryMmap, head: &DescriptorChain) -> result::Result<Buffers, Error> {
+        let mut readable = Vec::new();
+        let mut writable = Vec::new();
+
+        let mut next = Some(head.clone());
+        while let Some(desc) = next {
+            if desc.is_write_only() {
+                writable.push((desc.addr, desc.len));
+            } else {
+                if !writable.is_empty() {
+                    return Err(Error::UnexpectedReadableDescriptor);
+                }
+                if readable.len() + desc.len as usize > MAX_REQUEST_SIZE {
+                    return Err(Error::RequestTooLarge);
+                }
+
+                let offset = readable.len();
+                readable.resize(offset + desc.len as usize, 0);
+                mem.read_slice(&mut readable[offset..], desc.addr)
+                    .map_err(Error::GuestMemory)?;
+            }
+            next = desc.next_descriptor();
+        }
+
+        Ok(Buffers { readable, writable })
+    }
+
+    fn writable_len(&self) -> usize {
+        self.writable.iter().map(|(_, len)| *len as usize).sum()
+    }
+
+    // Write `data` at `offset` into the writable part of the chain.
+    fn write_at(
+        &self,
+        mem: &GuestMemoryMmap,
+        mut offset: usize,
+        mut data: &[u8],
+    ) -> result::Result<(), Error> {
+        if offset + data.len() > self.writable_len() {
+            return Err(Error::ResponseTooLarge);
+        }
+
+        for &(addr, len) in self.writable.iter() {
+            let len = len as usize;
+            if offset >= len {
+                offset -= len;
+                continue;
+            }
+
+            let count = cmp::min(len - offset, data.len());
+            mem.write_slice(&data[..count], addr.unchecked_add(offset as u64))
+                .map_err(Error::GuestMemory)?;
+            data = &data[count..];
+            offset = 0;
+
+            if data.is_empty() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Reply to a request for the information about `infos`.
+fn query_info<T: ByteValued>(request: &[u8], infos: &[T]) -> Vec<u8> {
+    let query: VirtioSoundQueryInfo = match read_obj(request) {
+        Some(query) => query,
+        None => return status(VIRTIO_SND_S_BAD_MSG),
+    };
+
+    let start = query.start_id as usize;
+    let count = query.count as usize;
+    if query.size as usize != size_of::<T>() || start + count > infos.len() {
+        return status(VIRTIO_SND_S_BAD_MSG);
+    }
+
+    let mut response = status(VIRTIO_SND_S_OK);
+    for info in infos[start..start + count].iter() {
+        response.extend_from_slice(info.as_slice());
+    }
+
+    response
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StreamState {
+    Idle,
+    ParamsSet,
+    Prepared,
+    Started,
+    Stopped,
+    Released,
+}
+
+// Amount of data played since the stream got prepared, telling when a
+// period elapses.
+#[derive(Default)]
+struct PcmPosition {
+    period_bytes: u64,
+    played: u64,
+}
+
+impl PcmPosition {
+    fn reset(&mut self, period_bytes: u32) {
+        self.period_bytes = u64::from(period_bytes);
+        self.played = 0;
+    }
+
+    // Account for `len` more bytes being played, returning the number of
+    // periods which elapsed meanwhile.
+    fn advance(&mut self, len: usize) -> u64 {
+        if self.period_bytes == 0 {
+            return 0;
+        }
+
+        let periods = self.played / self.period_bytes;
+        self.played += len as u64;
+        self.played / self.period_bytes - periods
+    }
+}
+
+struct PcmStream {
+    state: StreamState,
+    params: Option<PcmParams>,
+    position: PcmPosition,
+    backend: Box<dyn PcmBackend>,
+}
+
+impl PcmStream {
+    fn new(backend: Box<dyn PcmBackend>) -> Self {
+        PcmStream {
+            state: StreamState::Idle,
+            params: None,
+            position: PcmPosition::default(),
+            backend,
+        }
+    }
+
+    fn reset(&mut self) {
+        if self.state != StreamState::Idle {
+            self.backend.release();
+        }
+        self.state = StreamState::Idle;
+        self.params = None;
+    }
+
+    fn set_params(&mut self, request: &VirtioSoundPcmSetParams) -> u32 {
+        match self.state {
+            StreamState::Idle
+            | StreamState::ParamsSet
+            | StreamState::Prepared
+            | StreamState::Released => {}
+            _ => return VIRTIO_SND_S_BAD_MSG,
+        }
+
+        let format = match PCM_FORMATS.iter().find(|(code, _)| *code == request.format) {
+            Some((_, format)) => *format,
+            None => return VIRTIO_SND_S_NOT_SUPP,
+        };
+        let rate = match PCM_RATES.iter().find(|(code, _)| *code == request.rate) {
+            Some((_, rate)) => *rate,
+            None => return VIRTIO_SND_S_NOT_SUPP,
+        };
+        if request.features != 0
+            || request.channels < PCM_CHANNELS_MIN
+            || request.channels > PCM_CHANNELS_MAX
+        {
+            return VIRTIO_SND_S_NOT_SUPP;
+        }
+        let params = PcmParams {
+            buffer_bytes: request.buffer_bytes,
+            period_bytes: request.period_bytes,
+            channels: request.channels,
+            format,
+            rate,
+        };
+        // The periods are made of whole frames, and fit in the buffer.
+        let frame_bytes = params.frame_bytes();
+        if params.period_bytes == 0
+            || params.period_bytes > params.buffer_bytes
+            || params.period_bytes % frame_bytes != 0
+            || params.buffer_bytes % frame_bytes != 0
+        {
+            return VIRTIO_SND_S_BAD_MSG;
+        }
+
+        if self.state == StreamState::Prepared {
+            self.backend.release();
+        }
+        self.params = Some(params);
+        self.state = StreamState::ParamsSet;
+
+        VIRTIO_SND_S_OK
+    }
+
+    fn command(&mut self, code: u32) -> u32 {
+        let result = match (code, self.state) {
+            (VIRTIO_SND_R_PCM_PREPARE, StreamState::ParamsSet)
+            | (VIRTIO_SND_R_PCM_PREPARE, StreamState::Prepared)
+            | (VIRTIO_SND_R_PCM_PREPARE, StreamState::Released) => {
+                // Parameters are always set before getting here.
+                let params = self.params.unwrap();
+                self.position.reset(params.period_bytes);
+                self.backend.prepare(&params).map(|_| StreamState::Prepared)
+            }
+            (VIRTIO_SND_R_PCM_START, StreamState::Prepared)
+            | (VIRTIO_SND_R_PCM_START, StreamState::Stopped) => {
+                self.backend.start().map(|_| StreamState::Started)
+            }
+            (VIRTIO_SND_R_PCM_STOP, StreamState::Started) => {
+                self.backend.stop().map(|_| StreamState::Stopped)
+            }
+            (VIRTIO_SND_R_PCM_RELEASE, StreamState::Prepared)
+            | (VIRTIO_SND_R_PCM_RELEASE, StreamState::Stopped) => {
+                self.backend.release();
+                Ok(StreamState::Released)
+            }
+            (_, state) => {
+                warn!(
+                    "Invalid request 0x{:x} for PCM stream in state {:?}",
+                    code, state
+                );
+                return VIRTIO_SND_S_BAD_MSG;
+            }
+        };
+
+        match result {
+            Ok(state) => {
+                self.state = state;
+                VIRTIO_SND_S_OK
+            }
+            Err(e) => {
+                error!("Failed to process PCM request 0x{:x}: {}", code, e);
+                VIRTIO_SND_S_IO_ERR
+            }
+        }
+    }
+}
+
+struct SoundEpollHandler {
+    queues: Vec<Queue>,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evts: Vec<EventFd>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    streams: Arc<Mutex<Vec<PcmStream>>>,
+}
+
+impl SoundEpollHandler {
+    fn handle_pcm_request(&mut self, code: u32, request: &[u8]) -> Vec<u8> {
+        let pcm_hdr: VirtioSoundPcmHdr = match read_obj(request) {
+            Some(pcm_hdr) => pcm_hdr,
+            None => return status(VIRTIO_SND_S_BAD_MSG),
+        };
+
+        let mut streams = self.streams.lock().unwrap();
+        let stream = match streams.get_mut(pcm_hdr.stream_id as usize) {
+            Some(stream) => stream,
+            None => return status(VIRTIO_SND_S_BAD_MSG),
+        };
+
+        if code == VIRTIO_SND_R_PCM_SET_PARAMS {
+            return match read_obj(request) {
+                Some(set_params) => status(stream.set_params(&set_params)),
+                None => status(VIRTIO_SND_S_BAD_MSG),
+            };
+        }
+
+        status(stream.command(code))
+    }
+
+    fn handle_control_request(&mut self, request: &[u8]) -> Vec<u8> {
+        let hdr: VirtioSoundHdr = match read_obj(request) {
+            Some(hdr) => hdr,
+            None => return status(VIRTIO_SND_S_BAD_MSG),
+        };
+
+        match hdr.code {
+            VIRTIO_SND_R_JACK_INFO => query_info(request, &jack_infos()),
+            VIRTIO_SND_R_PCM_INFO => query_info(request, &pcm_infos()),
+            VIRTIO_SND_R_CHMAP_INFO => query_info(request, &chmap_infos()),
+            VIRTIO_SND_R_PCM_SET_PARAMS
+            | VIRTIO_SND_R_PCM_PREPARE
+            | VIRTIO_SND_R_PCM_RELEASE
+            | VIRTIO_SND_R_PCM_START
+            | VIRTIO_SND_R_PCM_STOP => self.handle_pcm_request(hdr.code, request),
+            VIRTIO_SND_R_JACK_REMAP => status(VIRTIO_SND_S_NOT_SUPP),
+            code => {
+                warn!("Unknown virtio-snd request 0x{:x}", code);
+                status(VIRTIO_SND_S_NOT_SUPP)
+            }
+        }
+    }
+
+    fn process_control_queue(&mut self) -> bool {
+        let mem = self.mem.memory();
+
+        let mut requests = Vec::new();
+        for avail_desc in self.queues[CONTROL_QUEUE].iter(&mem) {
+            requests.push((avail_desc.index, Buffers::parse(&mem, &avail_desc)));
+        }
+
+        for &(desc_index, ref buffers) in requests.iter() {
+            let mut len = 0;
+            match buffers {
+                Ok(buffers) => {
+                    let response = self.handle_control_request(&buffers.readable);
+                    match buffers.write_at(&mem, 0, &response) {
+                        Ok(()) => len = response.len() as u32,
+                        Err(e) => error!("Failed to write virtio-snd response: {}", e),
+                    }
+                }
+                Err(e) => error!("Failed to parse virtio-snd request: {}", e),
+            }
+            self.queues[CONTROL_QUEUE].add_used(&mem, desc_index, len);
+        }
+
+        !requests.is_empty()
+    }
+
+    // Complete a PCM buffer by writing its status at the end of the
+    // writable part of the chain.
+    fn complete_pcm_buffer(
+        &mut self,
+        queue_index: usize,
+        desc_index: u16,
+        buffers: &Buffers,
+        status: u32,
+    ) {
+        let mem = self.mem.memory();
+        let pcm_status = VirtioSoundPcmStatus {
+            status,
+            latency_bytes: 0,
+        };
+
+        let mut len = 0;
+        match buffers
+            .writable_len()
+            .checked_sub(size_of::<VirtioSoundPcmStatus>())
+            .ok_or(Error::ResponseTooLarge)
+            .and_then(|offset| buffers.write_at(&mem, offset, pcm_status.as_slice()))
+        {
+            Ok(()) => len = size_of::<VirtioSoundPcmStatus>() as u32,
+            Err(e) => error!("Failed to write virtio-snd PCM status: {}", e),
+        }
+        self.queues[queue_index].add_used(&mem, desc_index, len);
+    }
+
+    // Play the next PCM buffer, returning false if there's no buffer the
+    // stream can take for now.
+    fn process_tx_buffer(&mut self) -> bool {
+        let mem = self.mem.memory();
+        let avail_desc = match self.queues[TX_QUEUE].iter(&mem).next() {
+            Some(avail_desc) => avail_desc,
+            None => return false,
+        };
+        let desc_index = avail_desc.index;
+
+        let buffers = match Buffers::parse(&mem, &avail_desc) {
+            Ok(buffers) => buffers,
+            Err(e) => {
+                error!("Failed to parse virtio-snd PCM buffer: {}", e);
+                self.queues[TX_QUEUE].add_used(&mem, desc_index, 0);
+                return true;
+            }
+        };
+
+        let xfer: VirtioSoundPcmXfer = match read_obj(&buffers.readable) {
+            Some(xfer) => xfer,
+            None => {
+                self.complete_pcm_buffer(TX_QUEUE, desc_index, &buffers, VIRTIO_SND_S_BAD_MSG);
+                return true;
+            }
+        };
+        let stream_id = xfer.stream_id;
+
+        let mut periods = 0;
+        let mut streams = self.streams.lock().unwrap();
+        let status = match streams.get_mut(stream_id as usize) {
+            Some(stream) => match stream.state {
+                StreamState::Started => {
+                    let data = &buffers.readable[size_of::<VirtioSoundPcmXfer>()..];
+                    // Parameters are always set before getting here.
+                    let params = stream.params.unwrap();
+                    if data.len() % params.frame_bytes() as usize != 0
+                        || data.len() > params.buffer_bytes as usize
+                    {
+                        VIRTIO_SND_S_BAD_MSG
+                    } else {
+                        match stream.backend.write(data) {
+                            Ok(()) => {
+                                periods = stream.position.advance(data.len());
+                                VIRTIO_SND_S_OK
+                            }
+                            Err(e) => {
+                                error!("Failed to play PCM buffer: {}", e);
+                                VIRTIO_SND_S_IO_ERR
+                            }
+                        }
+                    }
+                }
+                StreamState::Prepared | StreamState::Stopped => {
+                    // The buffer gets played once the stream is started,
+                    // or returned once it is released.
+                    self.queues[TX_QUEUE].go_to_previous_position();
+                    return false;
+                }
+                // The remaining buffers are given back to the driver once
+                // the stream is released.
+                StreamState::Released => VIRTIO_SND_S_OK,
+                StreamState::Idle | StreamState::ParamsSet => VIRTIO_SND_S_BAD_MSG,
+            },
+            None => VIRTIO_SND_S_BAD_MSG,
+        };
+        drop(streams);
+
+        if status == VIRTIO_SND_S_IO_ERR {
+            self.send_event(VIRTIO_SND_EVT_PCM_XRUN, stream_id);
+        } else if periods > 0 {
+            self.send_event(VIRTIO_SND_EVT_PCM_PERIOD_ELAPSED, stream_id);
+        }
+        self.complete_pcm_buffer(TX_QUEUE, desc_index, &buffers, status);
+
+        true
+    }
+
+    fn process_tx_queue(&mut self) -> result::Result<(), DeviceError> {
+        while self.process_tx_buffer() {
+            // Let the driver know about each buffer as soon as it's been
+            // played, as it uses them to track the stream position.
+            self.signal_used_queue(TX_QUEUE)?;
+
+            // Playing blocks for a while, take the requests which came in
+            // meanwhile into account, as they could stop the stream.
+            if self.process_control_queue() {
+                self.signal_used_queue(CONTROL_QUEUE)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn process_rx_queue(&mut self) -> bool {
+        let mem = self.mem.memory();
+
+        let mut requests = Vec::new();
+        for avail_desc in self.queues[RX_QUEUE].iter(&mem) {
+            requests.push((avail_desc.index, Buffers::parse(&mem, &avail_desc)));
+        }
+
+        for (desc_index, buffers) in requests.iter() {
+            match buffers {
+                // There is no input stream.
+                Ok(buffers) => {
+                    self.complete_pcm_buffer(RX_QUEUE, *desc_index, buffers, VIRTIO_SND_S_BAD_MSG)
+                }
+                Err(e) => {
+                    error!("Failed to parse virtio-snd PCM buffer: {}", e);
+                    self.queues[RX_QUEUE].add_used(&mem, *desc_index, 0);
+                }
+            }
+        }
+
+        !requests.is_empty()
+    }
+
+    fn send_event(&mut self, code: u32, data: u32) {
+        let mem = self.mem.memory();
+        let avail_desc = match self.queues[EVENT_QUEUE].iter(&mem).next() {
+            Some(avail_desc) => avail_desc,
+            None => {
+                // Drivers don't have to care about the elapsed periods, as
+                // the PCM buffers tell them about the stream position.
+                if code != VIRTIO_SND_EVT_PCM_PERIOD_ELAPSED {
+                    warn!("No buffer available for virtio-snd event 0x{:x}", code);
+                }
+                return;
+            }
+        };
+
+        let event = VirtioSoundEvent {
+            hdr: VirtioSoundHdr { code },
+            data,
+        };
+        let mut len = 0;
+        if avail_desc.is_write_only() && avail_desc.len as usize >= size_of::<VirtioSoundEvent>() {
+            match mem.write_obj(event, avail_desc.addr) {
+                Ok(()) => len = size_of::<VirtioSoundEvent>() as u32,
+                Err(e) => error!("Failed to write virtio-snd event: {}", e),
+            }
+        }
+        self.queues[EVENT_QUEUE].add_used(&mem, avail_desc.index, len);
+
+        if let Err(e) = self.signal_used_queue(EVENT_QUEUE) {
+            error!("Failed to signal virtio-snd event: {:?}", e);
+        }
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(
+            self.queue_evts[CONTROL_QUEUE].as_raw_fd(),
+            CONTROL_QUEUE_EVENT,
+        )?;
+        helper.add_event(self.queue_evts[EVENT_QUEUE].as_raw_fd(), EVENT_QUEUE_EVENT)?;
+        helper.add_event(self.queue_evts[TX_QUEUE].as_raw_fd(), TX_QUEUE_EVENT)?;
+        helper.add_event(self.queue_evts[RX_QUEUE].as_raw_fd(), RX_QUEUE_EVENT)?;
+        helper.run(paused, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for SoundEpollHandler {
+    fn handle_event(&mut self, _helper: &mut EpollHelper, event: u16) -> bool {
+        let queue_index = match event {
+            CONTROL_QUEUE_EVENT => CONTROL_QUEUE,
+            EVENT_QUEUE_EVENT => EVENT_QUEUE,
+            TX_QUEUE_EVENT => TX_QUEUE,
+            RX_QUEUE_EVENT => RX_QUEUE,
+            _ => {
+                error!("Unexpected event: {}", event);
+                return true;
+            }
+        };
+
+        if let Err(e) = self.queue_evts[queue_index].read() {
+            error!("Failed to get queue event: {:?}", e);
+            return true;
+        }
+
+        let result = match queue_index {
+            CONTROL_QUEUE => {
+                // Starting or releasing the stream lets the pending PCM
+                // buffers be processed.
+                if self.process_control_queue() {
+                    self.signal_used_queue(CONTROL_QUEUE)
+                        .and_then(|_| self.process_tx_queue())
+                } else {
+                    Ok(())
+                }
+            }
+            // Event buffers are only used when something happens.
+            EVENT_QUEUE => Ok(()),
+            TX_QUEUE => self.process_tx_queue(),
+            _ => {
+                if self.process_rx_queue() {
+                    self.signal_used_queue(RX_QUEUE)
+                } else {
+                    Ok(())
+                }
+            }
+        };
+
+        if let Err(e) = result {
+            error!("Failed to process virtio-snd queue: {:?}", e);
+            return true;
+        }
+
+        false
+    }
+}
+
+/// Virtio device providing audio playback to the guest.
+pub struct Sound {
+    id: String,
+    kill_evt: Option<EventFd>,
+    pause_evt: Option<EventFd>,
+    avail_features: u64,
+    acked_features: u64,
+    config: VirtioSoundConfig,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), EpollHelperError>>>>,
+    paused: Arc<AtomicBool>,
+    streams: Arc<Mutex<Vec<PcmStream>>>,
+}
+
+impl Sound {
+    /// Create a new virtio sound device, whose playback stream is played
+    /// through `backend`.
+    pub fn new(id: String, backend: Box<dyn PcmBackend>, iommu: bool) -> Sound {
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+        if iommu {
+            avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+        }
+
+        let config = VirtioSoundConfig {
+            jacks: jack_infos().len() as u32,
+            streams: pcm_infos().len() as u32,
+            chmaps: chmap_infos().len() as u32,
+        };
+
+        Sound {
+            id,
+            kill_evt: None,
+            pause_evt: None,
+            avail_features,
+            acked_features: 0u64,
+            config,
+            queue_evts: None,
+            interrupt_cb: None,
+            epoll_threads: None,
+            paused: Arc::new(AtomicBool::new(false)),
+            streams: Arc::new(Mutex::new(vec![PcmStream::new(backend)])),
+        }
+    }
+}
+
+impl Drop for Sound {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Sound {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_SOUND as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(self.config.as_slice(), offset, data);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating kill EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.kill_evt = Some(self_kill_evt);
+
+        let (self_pause_evt, pause_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating pause EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.pause_evt = Some(self_pause_evt);
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the thread.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        // A driver being activated starts from idle streams.
+        for stream in self.streams.lock().unwrap().iter_mut() {
+            stream.reset();
+        }
+
+        let mut handler = SoundEpollHandler {
+            queues,
+            mem,
+            interrupt_cb,
+            queue_evts,
+            kill_evt,
+            pause_evt,
+            streams: self.streams.clone(),
+        };
+
+        let paused = self.paused.clone();
+        let mut epoll_threads = Vec::new();
+        thread::Builder::new()
+            .name("virtio_sound".to_string())
+            .spawn(move || handler.run(paused))
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(|e| {
+                error!("failed to clone the virtio-sound epoll thread: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.epoll_threads = Some(epoll_threads);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // We first must resume the virtio thread if it was paused.
+        if self.pause_evt.take().is_some() {
+            self.resume().ok()?;
+        }
+
+        // Then kill it.
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+}
+
+virtio_pausable!(Sound);
+
+impl Snapshottable for Sound {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        // The state of the streams lives partly in the host audio backend,
+        // and the PCM buffers owned by the device would be lost.
+        Err(MigratableError::Snapshot(anyhow!(
+            "Cannot snapshot virtio-sound device {}",
+            self.id
+        )))
+    }
+
+    fn restore(&mut self, _snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        Err(MigratableError::Restore(anyhow!(
+            "Cannot restore virtio-sound device {}",
+            self.id
+        )))
+    }
+}
+
+impl Transportable for Sound {}
+impl Migratable for Sound {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::NoopVirtioInterrupt;
+    use std::fs;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use vmm_sys_util::tempfile::TempFile;
+
+    const MEM_SIZE: usize = 0x10_0000;
+    const REQUEST_ADDR: u64 = 0x2_0000;
+    const RESPONSE_ADDR: u64 = 0x3_0000;
+
+    struct Queues<'a> {
+        control: GuestQ<'a>,
+        event: GuestQ<'a>,
+        tx: GuestQ<'a>,
+        rx: GuestQ<'a>,
+    }
+
+    fn create_queues(mem: &GuestMemoryMmap) -> Queues {
+        Queues {
+            control: GuestQ::new(GuestAddress(0x1_0000), mem, 16),
+            event: GuestQ::new(GuestAddress(0x1_4000), mem, 16),
+            tx: GuestQ::new(GuestAddress(0x1_8000), mem, 16),
+            rx: GuestQ::new(GuestAddress(0x1_c000), mem, 16),
+        }
+    }
+
+    fn create_handler(
+        mem: &GuestMemoryMmap,
+        queues: &Queues,
+        backend: Box<dyn PcmBackend>,
+    ) -> SoundEpollHandler {
+        SoundEpollHandler {
+            queues: vec![
+                queues.control.create_queue(),
+                queues.event.create_queue(),
+                queues.tx.create_queue(),
+                queues.rx.create_queue(),
+            ],
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            queue_evts: (0..NUM_QUEUES)
+                .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+                .collect(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            streams: Arc::new(Mutex::new(vec![PcmStream::new(backend)])),
+        }
+    }
+
+    // Make the `index`th request available on the control queue, with room
+    // for a `response_len` bytes response, and return the response.
+    fn control_request(
+        mem: &GuestMemoryMmap,
+        queues: &Queues,
+        handler: &mut SoundEpollHandler,
+        index: u16,
+        request: &[u8],
+        response_len: u32,
+    ) -> Vec<u8> {
+        let request_addr = REQUEST_ADDR + u64::from(index) * 0x100;
+        let response_addr = RESPONSE_ADDR + u64::from(index) * 0x100;
+        mem.write_slice(request, GuestAddress(request_addr))
+            .unwrap();
+
+        let desc = index * 2;
+        queues.control.dtable[desc as usize].set(
+            request_addr,
+            request.len() as u32,
+            VIRTQ_DESC_F_NEXT,
+            desc + 1,
+        );
+        queues.control.dtable[desc as usize + 1].set(
+            response_addr,
+            response_len,
+            VIRTQ_DESC_F_WRITE,
+            0,
+        );
+        queues.control.avail.ring[index as usize].set(desc);
+        queues.control.avail.idx.set(index + 1);
+
+        assert!(handler.process_control_queue());
+        assert_eq!(queues.control.used.idx.get(), index + 1);
+
+        let len = queues.control.used.ring[index as usize].get().len;
+        let mut response = vec![0u8; len as usize];
+        mem.read_slice(&mut response, GuestAddress(response_addr))
+            .unwrap();
+        response
+    }
+
+    fn pcm_request(code: u32) -> Vec<u8> {
+        VirtioSoundPcmHdr {
+            hdr: VirtioSoundHdr { code },
+            stream_id: 0,
+        }
+        .as_slice()
+        .to_vec()
+    }
+
+    fn set_params_request(channels: u8, format: u8, rate: u8) -> Vec<u8> {
+        VirtioSoundPcmSetParams {
+            hdr: VirtioSoundPcmHdr {
+                hdr: VirtioSoundHdr {
+                    code: VIRTIO_SND_R_PCM_SET_PARAMS,
+                },
+                stream_id: 0,
+            },
+            buffer_bytes: 64,
+            period_bytes: 16,
+            channels,
+            format,
+            rate,
+            ..Default::default()
+        }
+        .as_slice()
+        .to_vec()
+    }
+
+    fn status_code(response: &[u8]) -> u32 {
+        read_obj::<VirtioSoundHdr>(response).unwrap().code
+    }
+
+    fn stream_state(handler: &SoundEpollHandler) -> StreamState {
+        handler.streams.lock().unwrap()[0].state
+    }
+
+    #[test]
+    fn test_sound_info_queries() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let queues = create_queues(&mem);
+        let mut handler = create_handler(&mem, &queues, Box::new(NullBackend::new()));
+
+        let query = |code, count, size| {
+            VirtioSoundQueryInfo {
+                hdr: VirtioSoundHdr { code },
+                start_id: 0,
+                count,
+                size,
+            }
+            .as_slice()
+            .to_vec()
+        };
+
+        let pcm_info_size = size_of::<VirtioSoundPcmInfo>() as u32;
+        let response = control_request(
+            &mem,
+            &queues,
+            &mut handler,
+            0,
+            &query(VIRTIO_SND_R_PCM_INFO, 1, pcm_info_size),
+            4 + pcm_info_size,
+        );
+        assert_eq!(status_code(&response), VIRTIO_SND_S_OK);
+        let info: VirtioSoundPcmInfo = read_obj(&response[4..]).unwrap();
+        let direction = info.direction;
+        let channels_max = info.channels_max;
+        let formats = info.formats;
+        assert_eq!(direction, VIRTIO_SND_D_OUTPUT);
+        assert_eq!(channels_max, 2);
+        assert_ne!(formats & (1 << VIRTIO_SND_PCM_FMT_S16), 0);
+
+        let chmap_info_size = size_of::<VirtioSoundChmapInfo>() as u32;
+        let response = control_request(
+            &mem,
+            &queues,
+            &mut handler,
+            1,
+            &query(VIRTIO_SND_R_CHMAP_INFO, 1, chmap_info_size),
+            4 + chmap_info_size,
+        );
+        assert_eq!(status_code(&response), VIRTIO_SND_S_OK);
+        let info: VirtioSoundChmapInfo = read_obj(&response[4..]).unwrap();
+        let positions = info.positions;
+        assert_eq!(positions[..2], [VIRTIO_SND_CHMAP_FL, VIRTIO_SND_CHMAP_FR]);
+
+        let jack_info_size = size_of::<VirtioSoundJackInfo>() as u32;
+        let response = control_request(
+            &mem,
+            &queues,
+            &mut handler,
+            2,
+            &query(VIRTIO_SND_R_JACK_INFO, 1, jack_info_size),
+            4 + jack_info_size,
+        );
+        assert_eq!(status_code(&response), VIRTIO_SND_S_OK);
+        let info: VirtioSoundJackInfo = read_obj(&response[4..]).unwrap();
+        assert_eq!(info.connected, 1);
+
+        // Asking for more entities than there are, or with a wrong
+        // structure size, is invalid.
+        let response = control_request(
+            &mem,
+            &queues,
+            &mut handler,
+            3,
+            &query(VIRTIO_SND_R_PCM_INFO, 2, pcm_info_size),
+            4 + 2 * pcm_info_size,
+        );
+        assert_eq!(response.len(), 4);
+        assert_eq!(status_code(&response), VIRTIO_SND_S_BAD_MSG);
+        let response = control_request(
+            &mem,
+            &queues,
+            &mut handler,
+            4,
+            &query(VIRTIO_SND_R_JACK_INFO, 1, jack_info_size + 1),
+            4 + jack_info_size,
+        );
+        assert_eq!(status_code(&response), VIRTIO_SND_S_BAD_MSG);
+    }
+
+    #[test]
+    fn test_sound_stream_state_machine() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let queues = create_queues(&mem);
+        let mut handler = create_handler(&mem, &queues, Box::new(NullBackend::new()));
+
+        let mut index = 0;
+        let mut request = |handler: &mut SoundEpollHandler, request: Vec<u8>| {
+            let response = control_request(&mem, &queues, handler, index, &request, 4);
+            index += 1;
+            status_code(&response)
+        };
+
+        // Nothing can happen before the parameters are set.
+        assert_eq!(
+            request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_PREPARE)),
+            VIRTIO_SND_S_BAD_MSG
+        );
+        assert_eq!(
+            request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_START)),
+            VIRTIO_SND_S_BAD_MSG
+        );
+        assert_eq!(stream_state(&handler), StreamState::Idle);
+
+        // Unsupported parameters are refused.
+        assert_eq!(
+            request(
+                &mut handler,
+                set_params_request(6, VIRTIO_SND_PCM_FMT_S16, 7)
+            ),
+            VIRTIO_SND_S_NOT_SUPP
+        );
+        assert_eq!(
+            request(&mut handler, set_params_request(2, 0, 7)),
+            VIRTIO_SND_S_NOT_SUPP
+        );
+        assert_eq!(
+            request(
+                &mut handler,
+                set_params_request(2, VIRTIO_SND_PCM_FMT_S16, 0)
+            ),
+            VIRTIO_SND_S_NOT_SUPP
+        );
+        assert_eq!(stream_state(&handler), StreamState::Idle);
+
+        assert_eq!(
+            request(
+                &mut handler,
+                set_params_request(2, VIRTIO_SND_PCM_FMT_S16, 7)
+            ),
+            VIRTIO_SND_S_OK
+        );
+        assert_eq!(stream_state(&handler), StreamState::ParamsSet);
+        assert_eq!(
+            handler.streams.lock().unwrap()[0].params.unwrap().rate,
+            48000
+        );
+
+        assert_eq!(
+            request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_STOP)),
+            VIRTIO_SND_S_BAD_MSG
+        );
+        assert_eq!(
+            request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_PREPARE)),
+            VIRTIO_SND_S_OK
+        );
+        assert_eq!(
+            request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_START)),
+            VIRTIO_SND_S_OK
+        );
+        assert_eq!(stream_state(&handler), StreamState::Started);
+
+        // The parameters can't change while the stream is running.
+        assert_eq!(
+            request(
+                &mut handler,
+                set_params_request(2, VIRTIO_SND_PCM_FMT_S16, 7)
+            ),
+            VIRTIO_SND_S_BAD_MSG
+        );
+        assert_eq!(
+            request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_RELEASE)),
+            VIRTIO_SND_S_BAD_MSG
+        );
+
+        assert_eq!(
+            request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_STOP)),
+            VIRTIO_SND_S_OK
+        );
+        assert_eq!(
+            request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_RELEASE)),
+            VIRTIO_SND_S_OK
+        );
+        assert_eq!(stream_state(&handler), StreamState::Released);
+
+        // A released stream can be prepared again with its parameters.
+        assert_eq!(
+            request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_PREPARE)),
+            VIRTIO_SND_S_OK
+        );
+        assert_eq!(stream_state(&handler), StreamState::Prepared);
+
+        // Requests for a stream which doesn't exist are invalid.
+        let mut bad_stream = pcm_request(VIRTIO_SND_R_PCM_START);
+        bad_stream[4] = 1;
+        assert_eq!(request(&mut handler, bad_stream), VIRTIO_SND_S_BAD_MSG);
+    }
+
+    #[test]
+    fn test_sound_playback() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let queues = create_queues(&mem);
+        let file = TempFile::new().unwrap();
+        let backend = FileBackend::new(file.as_path()).unwrap();
+        let mut handler = create_handler(&mem, &queues, Box::new(backend));
+
+        // Two PCM buffers, each made of the transfer header, the samples
+        // and the room for the status.
+        for i in 0..2u16 {
+            let addr = 0x4_0000 + u64::from(i) * 0x1000;
+            let xfer = VirtioSoundPcmXfer { stream_id: 0 };
+            mem.write_obj(xfer, GuestAddress(addr)).unwrap();
+            mem.write_slice(&[i as u8 + 1; 16], GuestAddress(addr + 0x100))
+                .unwrap();
+
+            let desc = i * 3;
+            queues.tx.dtable[desc as usize].set(addr, 4, VIRTQ_DESC_F_NEXT, desc + 1);
+            queues.tx.dtable[desc as usize + 1].set(addr + 0x100, 16, VIRTQ_DESC_F_NEXT, desc + 2);
+            queues.tx.dtable[desc as usize + 2].set(addr + 0x200, 8, VIRTQ_DESC_F_WRITE, 0);
+            queues.tx.avail.ring[i as usize].set(desc);
+        }
+        queues.tx.avail.idx.set(2);
+
+        let mut index = 0;
+        let mut request = |handler: &mut SoundEpollHandler, request: Vec<u8>| {
+            let response = control_request(&mem, &queues, handler, index, &request, 4);
+            index += 1;
+            assert_eq!(status_code(&response), VIRTIO_SND_S_OK);
+        };
+        request(
+            &mut handler,
+            set_params_request(2, VIRTIO_SND_PCM_FMT_S16, 7),
+        );
+        request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_PREPARE));
+
+        // The buffers are held until the stream is started.
+        handler.process_tx_queue().unwrap();
+        assert_eq!(queues.tx.used.idx.get(), 0);
+
+        request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_START));
+        handler.process_tx_queue().unwrap();
+        assert_eq!(queues.tx.used.idx.get(), 2);
+        for i in 0..2u64 {
+            assert_eq!(queues.tx.used.ring[i as usize].get().len, 8);
+            let pcm_status: VirtioSoundPcmStatus =
+                mem.read_obj(GuestAddress(0x4_0200 + i * 0x1000)).unwrap();
+            let status = pcm_status.status;
+            assert_eq!(status, VIRTIO_SND_S_OK);
+        }
+
+        request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_STOP));
+
+        let mut expected = vec![1u8; 16];
+        expected.extend_from_slice(&[2u8; 16]);
+        assert_eq!(fs::read(file.as_path()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_sound_pcm_position() {
+        let mut position = PcmPosition::default();
+        assert_eq!(position.advance(16), 0);
+
+        position.reset(16);
+        assert_eq!(position.advance(8), 0);
+        assert_eq!(position.advance(12), 1);
+        assert_eq!(position.advance(12), 1);
+        assert_eq!(position.advance(40), 2);
+        assert_eq!(position.played, 72);
+
+        position.reset(16);
+        assert_eq!(position.played, 0);
+        assert_eq!(position.advance(15), 0);
+    }
+
+    #[test]
+    fn test_sound_pcm_accounting() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let queues = create_queues(&mem);
+        let file = TempFile::new().unwrap();
+        let backend = FileBackend::new(file.as_path()).unwrap();
+        let mut handler = create_handler(&mem, &queues, Box::new(backend));
+
+        let mut index = 0;
+        let mut request = |handler: &mut SoundEpollHandler, request: Vec<u8>| {
+            let response = control_request(&mem, &queues, handler, index, &request, 4);
+            index += 1;
+            status_code(&response)
+        };
+
+        // Periods must hold whole frames, which are 4 bytes long here.
+        let mut bad_period = set_params_request(2, VIRTIO_SND_PCM_FMT_S16, 7);
+        bad_period[12..16].copy_from_slice(&6u32.to_le_bytes());
+        assert_eq!(request(&mut handler, bad_period), VIRTIO_SND_S_BAD_MSG);
+
+        // Periods of 16 bytes, in a buffer of 64 bytes.
+        assert_eq!(
+            request(
+                &mut handler,
+                set_params_request(2, VIRTIO_SND_PCM_FMT_S16, 7)
+            ),
+            VIRTIO_SND_S_OK
+        );
+        assert_eq!(
+            request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_PREPARE)),
+            VIRTIO_SND_S_OK
+        );
+        assert_eq!(
+            request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_START)),
+            VIRTIO_SND_S_OK
+        );
+
+        // Room for two events.
+        for i in 0..2u16 {
+            queues.event.dtable[i as usize].set(
+                0x5_0000 + u64::from(i) * 0x100,
+                8,
+                VIRTQ_DESC_F_WRITE,
+                0,
+            );
+            queues.event.avail.ring[i as usize].set(i);
+        }
+        queues.event.avail.idx.set(2);
+
+        // Half a period, then the end of the first period, and then a buffer
+        // which isn't made of whole frames.
+        let sizes = [8u32, 12, 6];
+        for (i, size) in sizes.iter().enumerate() {
+            let addr = 0x4_0000 + i as u64 * 0x1000;
+            mem.write_obj(VirtioSoundPcmXfer { stream_id: 0 }, GuestAddress(addr))
+                .unwrap();
+            mem.write_slice(
+                &vec![i as u8 + 1; *size as usize],
+                GuestAddress(addr + 0x100),
+            )
+            .unwrap();
+
+            let desc = i as u16 * 3;
+            queues.tx.dtable[desc as usize].set(addr, 4, VIRTQ_DESC_F_NEXT, desc + 1);
+            queues.tx.dtable[desc as usize + 1].set(
+                addr + 0x100,
+                *size,
+                VIRTQ_DESC_F_NEXT,
+                desc + 2,
+            );
+            queues.tx.dtable[desc as usize + 2].set(addr + 0x200, 8, VIRTQ_DESC_F_WRITE, 0);
+            queues.tx.avail.ring[i].set(desc);
+        }
+        queues.tx.avail.idx.set(sizes.len() as u16);
+
+        handler.process_tx_queue().unwrap();
+        assert_eq!(queues.tx.used.idx.get(), 3);
+        let statuses: Vec<u32> = (0..3u64)
+            .map(|i| {
+                let pcm_status: VirtioSoundPcmStatus =
+                    mem.read_obj(GuestAddress(0x4_0200 + i * 0x1000)).unwrap();
+                pcm_status.status
+            })
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![VIRTIO_SND_S_OK, VIRTIO_SND_S_OK, VIRTIO_SND_S_BAD_MSG]
+        );
+
+        // A single period elapsed.
+        assert_eq!(queues.event.used.idx.get(), 1);
+        let event: VirtioSoundEvent = mem.read_obj(GuestAddress(0x5_0000)).unwrap();
+        let (code, data) = (event.hdr.code, event.data);
+        assert_eq!((code, data), (VIRTIO_SND_EVT_PCM_PERIOD_ELAPSED, 0));
+
+        request(&mut handler, pcm_request(VIRTIO_SND_R_PCM_STOP));
+
+        let mut expected = vec![1u8; 8];
+        expected.extend_from_slice(&[2u8; 12]);
+        assert_eq!(fs::read(file.as_path()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_sound_snapshot_refused() {
+        let sound = Sound::new("_sound".to_string(), Box::new(NullBackend::new()), false);
+        assert!(sound.snapshot().is_err());
+    }
+}
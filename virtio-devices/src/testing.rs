@@ -0,0 +1,22 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers shared by the unit tests of the virtio devices.
+
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use vm_virtio::queue::Queue;
+
+/// Interrupt handed to the devices under test, its notifications being
+/// dropped.
+pub struct NoopVirtioInterrupt {}
+
+impl VirtioInterrupt for NoopVirtioInterrupt {
+    fn trigger(
+        &self,
+        _int_type: &VirtioInterruptType,
+        _queue: Option<&Queue>,
+    ) -> std::result::Result<(), std::io::Error> {
+        Ok(())
+    }
+}
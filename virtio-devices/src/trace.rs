@@ -0,0 +1,242 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Spans measuring how long the virtio devices take to get ready.
+//!
+//! A span is reported once it ends, along with its duration, through the
+//! `log` crate at the debug level and with the `virtio_devices::trace`
+//! target. Spans are nested in the innermost one entered by the same thread,
+//! or explicitly in a parent span when started from another thread. Nothing
+//! gets measured nor formatted when the debug level is disabled.
+
+use crate::{ActivateResult, Queue, VirtioDevice, VirtioInterrupt};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use vm_memory::{GuestMemoryAtomic, GuestMemoryMmap};
+use vm_virtio::VirtioDeviceType;
+use vmm_sys_util::eventfd::EventFd;
+
+const TARGET: &str = "virtio_devices::trace";
+
+static NEXT_SPAN_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    // Spans entered by the current thread, innermost last.
+    static ENTERED: RefCell<Vec<u64>> = RefCell::new(Vec::new());
+}
+
+/// Identifier of a span, for nesting in it spans started from other threads.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpanId(u64);
+
+struct SpanInner {
+    id: u64,
+    parent: Option<u64>,
+    name: &'static str,
+    fields: String,
+    start: Instant,
+}
+
+/// A span, lasting until it gets dropped.
+pub struct Span {
+    inner: Option<SpanInner>,
+}
+
+impl Span {
+    /// Enter a span nested in the innermost one entered by this thread.
+    /// `fields` describes the span, and is only called if it gets reported.
+    pub fn enter<F: FnOnce() -> String>(name: &'static str, fields: F) -> Span {
+        Span::child_of(current(), name, fields)
+    }
+
+    /// Enter a span nested in `parent`.
+    pub fn child_of<F: FnOnce() -> String>(
+        parent: Option<SpanId>,
+        name: &'static str,
+        fields: F,
+    ) -> Span {
+        if !log_enabled!(target: TARGET, log::Level::Debug) {
+            return Span { inner: None };
+        }
+
+        let id = NEXT_SPAN_ID.fetch_add(1, Ordering::Relaxed);
+        ENTERED.with(|entered| entered.borrow_mut().push(id));
+
+        Span {
+            inner: Some(SpanInner {
+                id,
+                parent: parent.map(|p| p.0),
+                name,
+                fields: fields(),
+                start: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn id(&self) -> Option<SpanId> {
+        self.inner.as_ref().map(|inner| SpanId(inner.id))
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            ENTERED.with(|entered| entered.borrow_mut().retain(|id| *id != inner.id));
+
+            let parent = inner
+                .parent
+                .map_or_else(|| "none".to_string(), |p| p.to_string());
+            debug!(
+                target: TARGET,
+                "span={} id={} parent={} {} duration_us={}",
+                inner.name,
+                inner.id,
+                parent,
+                inner.fields,
+                inner.start.elapsed().as_micros()
+            );
+        }
+    }
+}
+
+/// Innermost span entered by the current thread.
+pub fn current() -> Option<SpanId> {
+    ENTERED.with(|entered| entered.borrow().last().copied().map(SpanId))
+}
+
+/// Activate `device` within a span recording its type and number of queues.
+pub fn activate_device(
+    device: &mut dyn VirtioDevice,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queues: Vec<Queue>,
+    queue_evts: Vec<EventFd>,
+) -> ActivateResult {
+    let _span = Span::enter("activate", || {
+        format!(
+            "device={} queues={}",
+            VirtioDeviceType::from(device.device_type()),
+            queues.len()
+        )
+    });
+    device.activate(mem, interrupt_cb, queues, queue_evts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::NoopVirtioInterrupt;
+    use log::{Log, Metadata, Record};
+    use std::sync::Once;
+    use vm_memory::GuestAddress;
+
+    thread_local! {
+        // Spans reported by the current thread, as each test runs in its own.
+        static RECORDS: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    }
+
+    struct TestSubscriber;
+
+    impl Log for TestSubscriber {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            if record.target() == TARGET {
+                RECORDS.with(|records| records.borrow_mut().push(format!("{}", record.args())));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static SUBSCRIBER: TestSubscriber = TestSubscriber;
+    static INIT: Once = Once::new();
+
+    fn set_subscriber() {
+        INIT.call_once(|| {
+            log::set_logger(&SUBSCRIBER).unwrap();
+            log::set_max_level(log::LevelFilter::Debug);
+        });
+    }
+
+    // Device starting a worker span on activation, as the devices spawning
+    // threads do.
+    struct TestDevice {
+        worker_parent: Option<SpanId>,
+    }
+
+    impl VirtioDevice for TestDevice {
+        fn device_type(&self) -> u32 {
+            VirtioDeviceType::TYPE_NET as u32
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            &[16, 16]
+        }
+
+        fn activate(
+            &mut self,
+            _mem: GuestMemoryAtomic<GuestMemoryMmap>,
+            _interrupt_cb: Arc<dyn VirtioInterrupt>,
+            _queues: Vec<Queue>,
+            _queue_evts: Vec<EventFd>,
+        ) -> ActivateResult {
+            self.worker_parent = current();
+            let _span = Span::child_of(self.worker_parent, "worker", String::new);
+            Ok(())
+        }
+    }
+
+    fn field<'a>(record: &'a str, key: &str) -> &'a str {
+        record
+            .split(' ')
+            .filter_map(|field| {
+                let mut kv = field.splitn(2, '=');
+                Some((kv.next()?, kv.next()?))
+            })
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_activate_span() {
+        set_subscriber();
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1_0000)]).unwrap();
+        let mut device = TestDevice {
+            worker_parent: None,
+        };
+        activate_device(
+            &mut device,
+            GuestMemoryAtomic::new(mem),
+            Arc::new(NoopVirtioInterrupt {}),
+            vec![Queue::new(16), Queue::new(16)],
+            vec![EventFd::new(0).unwrap(), EventFd::new(0).unwrap()],
+        )
+        .unwrap();
+        assert!(current().is_none());
+
+        let records = RECORDS.with(|records| records.borrow_mut().split_off(0));
+        assert_eq!(records.len(), 2);
+        // The worker span ends first, nested in the activation one.
+        let (worker, activate) = (&records[0], &records[1]);
+        assert_eq!(field(worker, "span"), "worker");
+        assert_eq!(field(activate, "span"), "activate");
+        assert_eq!(field(activate, "parent"), "none");
+        assert_eq!(field(worker, "parent"), field(activate, "id"));
+        assert_eq!(
+            device.worker_parent.map(|p| p.0.to_string()).as_deref(),
+            Some(field(activate, "id"))
+        );
+        assert_eq!(field(activate, "device"), "net");
+        assert_eq!(field(activate, "queues"), "2");
+        field(activate, "duration_us").parse::<u128>().unwrap();
+    }
+}
@@ -4,8 +4,8 @@
 
 use crate::transport::{VirtioTransport, NOTIFY_REG_OFFSET};
 use crate::{
-    Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER,
-    DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK, DEVICE_INIT,
+    trace, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, DEVICE_ACKNOWLEDGE,
+    DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK, DEVICE_INIT,
     INTERRUPT_STATUS_CONFIG_CHANGED, INTERRUPT_STATUS_USED_RING,
 };
 use anyhow::anyhow;
@@ -22,7 +22,7 @@ use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
     Transportable,
 };
-use vm_virtio::queue;
+use vm_virtio::{queue, DirtyLog};
 use vmm_sys_util::{errno::Result, eventfd::EventFd};
 
 const VENDOR_ID: u32 = 0;
@@ -120,6 +120,7 @@ impl MmioDevice {
         id: String,
         mem: GuestMemoryAtomic<GuestMemoryMmap>,
         device: Arc<Mutex<dyn VirtioDevice>>,
+        dirty_log: Option<Arc<DirtyLog>>,
     ) -> Result<MmioDevice> {
         let device_clone = device.clone();
         let locked_device = device_clone.lock().unwrap();
@@ -130,7 +131,11 @@ impl MmioDevice {
         let queues = locked_device
             .queue_max_sizes()
             .iter()
-            .map(|&s| Queue::new(s))
+            .map(|&s| {
+                let mut queue = Queue::new(s);
+                queue.dirty_log = dirty_log.clone();
+                queue
+            })
             .collect();
         Ok(MmioDevice {
             id,
@@ -220,7 +225,13 @@ impl MmioDevice {
 
     fn are_queues_valid(&self) -> bool {
         if let Some(mem) = self.mem.as_ref() {
-            self.queues.iter().all(|q| q.is_valid(&mem.memory()))
+            // Depending on the negotiated features, the driver might not use
+            // all the queues exposed by the device. Only the queues enabled
+            // by the driver are expected to be valid.
+            self.queues
+                .iter()
+                .filter(|q| q.ready)
+                .all(|q| q.is_valid(&mem.memory()))
         } else {
             false
         }
@@ -410,16 +421,14 @@ impl BusDevice for MmioDevice {
             if let Some(interrupt_cb) = self.interrupt_cb.take() {
                 if self.mem.is_some() {
                     let mem = self.mem.as_ref().unwrap().clone();
-                    self.device
-                        .lock()
-                        .unwrap()
-                        .activate(
-                            mem,
-                            interrupt_cb,
-                            self.queues.clone(),
-                            self.queue_evts.split_off(0),
-                        )
-                        .expect("Failed to activate device");
+                    trace::activate_device(
+                        &mut *self.device.lock().unwrap(),
+                        mem,
+                        interrupt_cb,
+                        self.queues.clone(),
+                        self.queue_evts.split_off(0),
+                    )
+                    .expect("Failed to activate device");
                     self.device_activated = true;
                 }
             }
@@ -505,21 +514,19 @@ impl Snapshottable for MmioDevice {
                 if let Some(interrupt_cb) = self.interrupt_cb.take() {
                     if self.mem.is_some() {
                         let mem = self.mem.as_ref().unwrap().clone();
-                        self.device
-                            .lock()
-                            .unwrap()
-                            .activate(
-                                mem,
-                                interrupt_cb,
-                                self.queues.clone(),
-                                self.queue_evts.split_off(0),
-                            )
-                            .map_err(|e| {
-                                MigratableError::Restore(anyhow!(
-                                    "Failed activating the device: {:?}",
-                                    e
-                                ))
-                            })?;
+                        trace::activate_device(
+                            &mut *self.device.lock().unwrap(),
+                            mem,
+                            interrupt_cb,
+                            self.queues.clone(),
+                            self.queue_evts.split_off(0),
+                        )
+                        .map_err(|e| {
+                            MigratableError::Restore(anyhow!(
+                                "Failed activating the device: {:?}",
+                                e
+                            ))
+                        })?;
                     }
                 }
             }
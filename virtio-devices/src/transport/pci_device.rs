@@ -16,7 +16,7 @@ extern crate vmm_sys_util;
 use super::VirtioPciCommonConfig;
 use crate::transport::VirtioTransport;
 use crate::{
-    Queue, VirtioDevice, VirtioDeviceType, VirtioInterrupt, VirtioInterruptType,
+    trace, Queue, VirtioDevice, VirtioDeviceType, VirtioInterrupt, VirtioInterruptType,
     DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK,
     DEVICE_INIT, VIRTIO_MSI_NO_VECTOR,
 };
@@ -47,7 +47,7 @@ use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
     Transportable,
 };
-use vm_virtio::{queue, VirtioIommuRemapping};
+use vm_virtio::{queue, DirtyLog, VirtioIommuRemapping};
 use vmm_sys_util::{errno::Result, eventfd::EventFd};
 
 #[derive(Debug)]
@@ -335,6 +335,7 @@ impl VirtioPciDevice {
         device: Arc<Mutex<dyn VirtioDevice>>,
         msix_num: u16,
         iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
+        dirty_log: Option<Arc<DirtyLog>>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
         pci_device_bdf: u32,
     ) -> Result<Self> {
@@ -350,6 +351,7 @@ impl VirtioPciDevice {
             .map(|&s| {
                 let mut queue = Queue::new(s);
                 queue.iommu_mapping_cb = iommu_mapping_cb.clone();
+                queue.dirty_log = dirty_log.clone();
                 queue
             })
             .collect();
@@ -508,7 +510,13 @@ impl VirtioPciDevice {
 
     fn are_queues_valid(&self) -> bool {
         if let Some(mem) = self.memory.as_ref() {
-            self.queues.iter().all(|q| q.is_valid(&mem.memory()))
+            // Depending on the negotiated features, the driver might not use
+            // all the queues exposed by the device. Only the queues enabled
+            // by the driver are expected to be valid.
+            self.queues
+                .iter()
+                .filter(|q| q.ready)
+                .all(|q| q.is_valid(&mem.memory()))
         } else {
             false
         }
@@ -976,14 +984,14 @@ impl PciDevice for VirtioPciDevice {
                 if self.memory.is_some() {
                     let mem = self.memory.as_ref().unwrap().clone();
                     let mut device = self.device.lock().unwrap();
-                    device
-                        .activate(
-                            mem,
-                            virtio_interrupt,
-                            self.queues.clone(),
-                            self.queue_evts.split_off(0),
-                        )
-                        .expect("Failed to activate device");
+                    trace::activate_device(
+                        &mut *device,
+                        mem,
+                        virtio_interrupt,
+                        self.queues.clone(),
+                        self.queue_evts.split_off(0),
+                    )
+                    .expect("Failed to activate device");
                     self.device_activated = true;
                 }
             }
@@ -1117,19 +1125,19 @@ impl Snapshottable for VirtioPciDevice {
                     if self.memory.is_some() {
                         let mem = self.memory.as_ref().unwrap().clone();
                         let mut device = self.device.lock().unwrap();
-                        device
-                            .activate(
-                                mem,
-                                virtio_interrupt,
-                                self.queues.clone(),
-                                self.queue_evts.split_off(0),
-                            )
-                            .map_err(|e| {
-                                MigratableError::Restore(anyhow!(
-                                    "Failed activating the device: {:?}",
-                                    e
-                                ))
-                            })?;
+                        trace::activate_device(
+                            &mut *device,
+                            mem,
+                            virtio_interrupt,
+                            self.queues.clone(),
+                            self.queue_evts.split_off(0),
+                        )
+                        .map_err(|e| {
+                            MigratableError::Restore(anyhow!(
+                                "Failed activating the device: {:?}",
+                                e
+                            ))
+                        })?;
                     }
                 }
             }
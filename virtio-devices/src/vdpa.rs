@@ -0,0 +1,894 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! vDPA device.
+//!
+//! The guest virtqueues are handed over to a vhost-vdpa character device
+//! (e.g. `/dev/vhost-vdpa-0`), through which the hardware processes them
+//! directly. The VMM only proxies the configuration space, the features and
+//! the device status, and maps the guest memory into the device IOTLB, the
+//! guest physical addresses being used as IOVAs.
+
+use super::{
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
+    VirtioDevice, VirtioDeviceType, VirtioInterrupt, VirtioInterruptType, DEVICE_ACKNOWLEDGE,
+    DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FEATURES_OK, EPOLL_HELPER_EVENT_LAST,
+};
+use anyhow::anyhow;
+use libc::EFD_NONBLOCK;
+use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use vm_memory::{
+    Address, ByteValued, GuestAddressSpace, GuestMemory, GuestMemoryAtomic, GuestMemoryMmap,
+    GuestMemoryRegion,
+};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ptr, ioctl_with_mut_ref, ioctl_with_ref};
+
+// Ioctls from the vhost and vhost-vdpa kernel interfaces, see
+// include/uapi/linux/vhost.h.
+const VHOST_VIRTIO: u32 = 0xAF;
+
+ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_io_nr!(VHOST_SET_OWNER, VHOST_VIRTIO, 0x01);
+ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST_VIRTIO, 0x10, VhostVringState);
+ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST_VIRTIO, 0x11, VhostVringAddr);
+ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST_VIRTIO, 0x12, VhostVringState);
+ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST_VIRTIO, 0x20, VhostVringFile);
+ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST_VIRTIO, 0x21, VhostVringFile);
+ioctl_iow_nr!(VHOST_SET_BACKEND_FEATURES, VHOST_VIRTIO, 0x25, u64);
+ioctl_ior_nr!(VHOST_GET_BACKEND_FEATURES, VHOST_VIRTIO, 0x26, u64);
+ioctl_ior_nr!(VHOST_VDPA_GET_DEVICE_ID, VHOST_VIRTIO, 0x70, u32);
+ioctl_iow_nr!(VHOST_VDPA_SET_STATUS, VHOST_VIRTIO, 0x72, u8);
+ioctl_ior_nr!(VHOST_VDPA_GET_CONFIG, VHOST_VIRTIO, 0x73, VhostVdpaConfig);
+ioctl_iow_nr!(VHOST_VDPA_SET_CONFIG, VHOST_VIRTIO, 0x74, VhostVdpaConfig);
+ioctl_iow_nr!(
+    VHOST_VDPA_SET_VRING_ENABLE,
+    VHOST_VIRTIO,
+    0x75,
+    VhostVringState
+);
+ioctl_ior_nr!(VHOST_VDPA_GET_VRING_NUM, VHOST_VIRTIO, 0x76, u16);
+
+// The IOTLB is updated through vhost_msg_v2 messages written to the device.
+const VHOST_BACKEND_F_IOTLB_MSG_V2: u64 = 0x1;
+const VHOST_IOTLB_MSG_V2: u32 = 0x2;
+const VHOST_IOTLB_UPDATE: u8 = 2;
+const VHOST_IOTLB_INVALIDATE: u8 = 3;
+const VHOST_ACCESS_RW: u8 = 3;
+
+// The call EventFd of a queue has been written to by the device.
+const CALL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+
+// The structures below are only read by the kernel.
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostVringState {
+    index: u32,
+    num: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostVringFile {
+    index: u32,
+    fd: i32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostVringAddr {
+    index: u32,
+    flags: u32,
+    desc_user_addr: u64,
+    used_user_addr: u64,
+    avail_user_addr: u64,
+    log_guest_addr: u64,
+}
+
+// Header of the configuration space accesses, followed by the data.
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostVdpaConfig {
+    off: u32,
+    len: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostIotlbMsg {
+    iova: u64,
+    size: u64,
+    uaddr: u64,
+    perm: u8,
+    msg_type: u8,
+    padding: [u8; 6],
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostMsgV2 {
+    msg_type: u32,
+    reserved: u32,
+    iotlb: VhostIotlbMsg,
+    // The message is a union, padded to 64 bytes.
+    padding: [u8; 32],
+}
+
+unsafe impl ByteValued for VhostIotlbMsg {}
+unsafe impl ByteValued for VhostMsgV2 {}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to open the vhost-vdpa device.
+    Open(io::Error),
+    /// Failed to set the VMM as the device owner.
+    SetOwner(io::Error),
+    /// Failed to get the device type.
+    GetDeviceId(io::Error),
+    /// Failed to get the device features.
+    GetFeatures(io::Error),
+    /// Failed to set the device features.
+    SetFeatures(io::Error),
+    /// Failed to get the vhost backend features.
+    GetBackendFeatures(io::Error),
+    /// Failed to set the vhost backend features.
+    SetBackendFeatures(io::Error),
+    /// The device doesn't support IOTLB v2 messages.
+    NoIotlbMsgV2,
+    /// Failed to set the device status.
+    SetStatus(io::Error),
+    /// Failed to read from the device configuration space.
+    GetConfig(io::Error),
+    /// Failed to write to the device configuration space.
+    SetConfig(io::Error),
+    /// Failed to get the maximum queue size.
+    GetVringNum(io::Error),
+    /// Failed to set the size of a queue.
+    SetVringNum(io::Error),
+    /// Failed to set the addresses of a queue.
+    SetVringAddr(io::Error),
+    /// Failed to set the first available descriptor of a queue.
+    SetVringBase(io::Error),
+    /// Failed to set the EventFd notifying a queue.
+    SetVringKick(io::Error),
+    /// Failed to set the EventFd the device notifies a queue with.
+    SetVringCall(io::Error),
+    /// Failed to enable or disable a queue.
+    SetVringEnable(io::Error),
+    /// Failed to create the EventFd the device notifies a queue with.
+    CreateCallEventFd(io::Error),
+    /// Failed to map guest memory into the device IOTLB.
+    DmaMap(io::Error),
+    /// Failed to unmap guest memory from the device IOTLB.
+    DmaUnmap(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Open(e) => write!(f, "failed opening vhost-vdpa device: {}", e),
+            SetOwner(e) => write!(f, "failed setting vhost-vdpa device owner: {}", e),
+            GetDeviceId(e) => write!(f, "failed getting vDPA device type: {}", e),
+            GetFeatures(e) => write!(f, "failed getting vDPA device features: {}", e),
+            SetFeatures(e) => write!(f, "failed setting vDPA device features: {}", e),
+            GetBackendFeatures(e) => write!(f, "failed getting vhost backend features: {}", e),
+            SetBackendFeatures(e) => write!(f, "failed setting vhost backend features: {}", e),
+            NoIotlbMsgV2 => write!(f, "vhost-vdpa device doesn't support IOTLB v2 messages"),
+            SetStatus(e) => write!(f, "failed setting vDPA device status: {}", e),
+            GetConfig(e) => write!(f, "failed reading vDPA configuration space: {}", e),
+            SetConfig(e) => write!(f, "failed writing vDPA configuration space: {}", e),
+            GetVringNum(e) => write!(f, "failed getting vDPA maximum queue size: {}", e),
+            SetVringNum(e) => write!(f, "failed setting vDPA queue size: {}", e),
+            SetVringAddr(e) => write!(f, "failed setting vDPA queue addresses: {}", e),
+            SetVringBase(e) => write!(f, "failed setting vDPA queue base: {}", e),
+            SetVringKick(e) => write!(f, "failed setting vDPA queue kick EventFd: {}", e),
+            SetVringCall(e) => write!(f, "failed setting vDPA queue call EventFd: {}", e),
+            SetVringEnable(e) => write!(f, "failed enabling vDPA queue: {}", e),
+            CreateCallEventFd(e) => write!(f, "failed creating vDPA call EventFd: {}", e),
+            DmaMap(e) => write!(f, "failed mapping guest memory for vDPA: {}", e),
+            DmaUnmap(e) => write!(f, "failed unmapping guest memory for vDPA: {}", e),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// Check the return value of a vhost ioctl.
+fn ioctl_result(ret: i32) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Handle on a vhost-vdpa character device.
+pub struct VhostVdpa {
+    file: File,
+}
+
+impl VhostVdpa {
+    /// Open the vhost-vdpa device at `path` and take ownership of it.
+    pub fn new(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(Error::Open)?;
+        let vhost = VhostVdpa { file };
+
+        // SAFETY: the ioctl doesn't take any argument.
+        ioctl_result(unsafe { ioctl(&vhost.file, VHOST_SET_OWNER()) }).map_err(Error::SetOwner)?;
+
+        Ok(vhost)
+    }
+
+    pub fn get_device_id(&self) -> Result<u32> {
+        let mut device_id = 0u32;
+        // SAFETY: the kernel only writes a u32 to the reference we pass.
+        ioctl_result(unsafe {
+            ioctl_with_mut_ref(&self.file, VHOST_VDPA_GET_DEVICE_ID(), &mut device_id)
+        })
+        .map_err(Error::GetDeviceId)?;
+        Ok(device_id)
+    }
+
+    pub fn get_features(&self) -> Result<u64> {
+        let mut features = 0u64;
+        // SAFETY: the kernel only writes a u64 to the reference we pass.
+        ioctl_result(unsafe {
+            ioctl_with_mut_ref(&self.file, VHOST_GET_FEATURES(), &mut features)
+        })
+        .map_err(Error::GetFeatures)?;
+        Ok(features)
+    }
+
+    pub fn set_features(&self, features: u64) -> Result<()> {
+        // SAFETY: the kernel only reads a u64 from the reference we pass.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_FEATURES(), &features) })
+            .map_err(Error::SetFeatures)
+    }
+
+    pub fn get_backend_features(&self) -> Result<u64> {
+        let mut features = 0u64;
+        // SAFETY: the kernel only writes a u64 to the reference we pass.
+        ioctl_result(unsafe {
+            ioctl_with_mut_ref(&self.file, VHOST_GET_BACKEND_FEATURES(), &mut features)
+        })
+        .map_err(Error::GetBackendFeatures)?;
+        Ok(features)
+    }
+
+    pub fn set_backend_features(&self, features: u64) -> Result<()> {
+        // SAFETY: the kernel only reads a u64 from the reference we pass.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_BACKEND_FEATURES(), &features) })
+            .map_err(Error::SetBackendFeatures)
+    }
+
+    pub fn set_status(&self, status: u8) -> Result<()> {
+        // SAFETY: the kernel only reads a u8 from the reference we pass.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_VDPA_SET_STATUS(), &status) })
+            .map_err(Error::SetStatus)
+    }
+
+    pub fn get_config(&self, offset: u32, data: &mut [u8]) -> Result<()> {
+        let header = VhostVdpaConfig {
+            off: offset,
+            len: data.len() as u32,
+        };
+        let mut config = Self::config_buffer(header);
+        // SAFETY: the buffer holds the header followed by the `len` bytes
+        // the kernel writes.
+        ioctl_result(unsafe {
+            ioctl_with_mut_ptr(&self.file, VHOST_VDPA_GET_CONFIG(), config.as_mut_ptr())
+        })
+        .map_err(Error::GetConfig)?;
+        data.copy_from_slice(&config[std::mem::size_of::<VhostVdpaConfig>()..]);
+        Ok(())
+    }
+
+    pub fn set_config(&self, offset: u32, data: &[u8]) -> Result<()> {
+        let header = VhostVdpaConfig {
+            off: offset,
+            len: data.len() as u32,
+        };
+        let mut config = Self::config_buffer(header);
+        config[std::mem::size_of::<VhostVdpaConfig>()..].copy_from_slice(data);
+        // SAFETY: the buffer holds the header followed by the `len` bytes
+        // the kernel reads.
+        ioctl_result(unsafe {
+            ioctl_with_mut_ptr(&self.file, VHOST_VDPA_SET_CONFIG(), config.as_mut_ptr())
+        })
+        .map_err(Error::SetConfig)
+    }
+
+    // Buffer made of `header`, followed by room for the data it describes.
+    fn config_buffer(header: VhostVdpaConfig) -> Vec<u8> {
+        let mut config = Vec::with_capacity(std::mem::size_of::<VhostVdpaConfig>());
+        config.extend_from_slice(&header.off.to_ne_bytes());
+        config.extend_from_slice(&header.len.to_ne_bytes());
+        config.resize(config.len() + header.len as usize, 0);
+        config
+    }
+
+    pub fn get_vring_num(&self) -> Result<u16> {
+        let mut num = 0u16;
+        // SAFETY: the kernel only writes a u16 to the reference we pass.
+        ioctl_result(unsafe {
+            ioctl_with_mut_ref(&self.file, VHOST_VDPA_GET_VRING_NUM(), &mut num)
+        })
+        .map_err(Error::GetVringNum)?;
+        Ok(num)
+    }
+
+    pub fn set_vring_num(&self, index: usize, num: u16) -> Result<()> {
+        let state = VhostVringState {
+            index: index as u32,
+            num: u32::from(num),
+        };
+        // SAFETY: the kernel only reads the structure we pass.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_NUM(), &state) })
+            .map_err(Error::SetVringNum)
+    }
+
+    /// Set the IOVAs of the descriptor table, the used and the available
+    /// rings of a queue.
+    pub fn set_vring_addr(&self, index: usize, desc: u64, used: u64, avail: u64) -> Result<()> {
+        let addr = VhostVringAddr {
+            index: index as u32,
+            flags: 0,
+            desc_user_addr: desc,
+            used_user_addr: used,
+            avail_user_addr: avail,
+            log_guest_addr: 0,
+        };
+        // SAFETY: the kernel only reads the structure we pass.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_ADDR(), &addr) })
+            .map_err(Error::SetVringAddr)
+    }
+
+    pub fn set_vring_base(&self, index: usize, base: u16) -> Result<()> {
+        let state = VhostVringState {
+            index: index as u32,
+            num: u32::from(base),
+        };
+        // SAFETY: the kernel only reads the structure we pass.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_BASE(), &state) })
+            .map_err(Error::SetVringBase)
+    }
+
+    pub fn set_vring_kick(&self, index: usize, fd: &EventFd) -> Result<()> {
+        let file = VhostVringFile {
+            index: index as u32,
+            fd: fd.as_raw_fd(),
+        };
+        // SAFETY: the kernel only reads the structure we pass.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_KICK(), &file) })
+            .map_err(Error::SetVringKick)
+    }
+
+    pub fn set_vring_call(&self, index: usize, fd: &EventFd) -> Result<()> {
+        let file = VhostVringFile {
+            index: index as u32,
+            fd: fd.as_raw_fd(),
+        };
+        // SAFETY: the kernel only reads the structure we pass.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_CALL(), &file) })
+            .map_err(Error::SetVringCall)
+    }
+
+    pub fn set_vring_enable(&self, index: usize, enable: bool) -> Result<()> {
+        let state = VhostVringState {
+            index: index as u32,
+            num: enable as u32,
+        };
+        // SAFETY: the kernel only reads the structure we pass.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_VDPA_SET_VRING_ENABLE(), &state) })
+            .map_err(Error::SetVringEnable)
+    }
+
+    fn iotlb_msg(&self, iotlb: VhostIotlbMsg) -> io::Result<()> {
+        let msg = VhostMsgV2 {
+            msg_type: VHOST_IOTLB_MSG_V2,
+            iotlb,
+            ..Default::default()
+        };
+        (&self.file).write_all(msg.as_slice())
+    }
+
+    /// Let the device access `size` bytes of memory at `uaddr` through
+    /// `iova`.
+    pub fn dma_map(&self, iova: u64, size: u64, uaddr: u64) -> Result<()> {
+        self.iotlb_msg(VhostIotlbMsg {
+            iova,
+            size,
+            uaddr,
+            perm: VHOST_ACCESS_RW,
+            msg_type: VHOST_IOTLB_UPDATE,
+            ..Default::default()
+        })
+        .map_err(Error::DmaMap)
+    }
+
+    pub fn dma_unmap(&self, iova: u64, size: u64) -> Result<()> {
+        self.iotlb_msg(VhostIotlbMsg {
+            iova,
+            size,
+            msg_type: VHOST_IOTLB_INVALIDATE,
+            ..Default::default()
+        })
+        .map_err(Error::DmaUnmap)
+    }
+}
+
+// Forward the notifications of the device for the queues which interrupt
+// can't be triggered directly through an EventFd.
+struct VdpaEpollHandler {
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    call_evts: Vec<(EventFd, Queue)>,
+}
+
+impl VdpaEpollHandler {
+    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        for (index, (call_evt, _)) in self.call_evts.iter().enumerate() {
+            helper.add_event(call_evt.as_raw_fd(), CALL_EVENT + index as u16)?;
+        }
+        helper.run(paused, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for VdpaEpollHandler {
+    fn handle_event(&mut self, _helper: &mut EpollHelper, event: u16) -> bool {
+        let (call_evt, queue) = match event
+            .checked_sub(CALL_EVENT)
+            .and_then(|index| self.call_evts.get(index as usize))
+        {
+            Some(call_evt) => call_evt,
+            None => {
+                error!("Unexpected event: {}", event);
+                return true;
+            }
+        };
+
+        if let Err(e) = call_evt.read() {
+            error!("Failed to get call event: {:?}", e);
+            return true;
+        }
+        if let Err(e) = self
+            .interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(queue))
+        {
+            error!("Failed to signal used queue: {:?}", e);
+            return true;
+        }
+
+        false
+    }
+}
+
+/// Virtio device backed by a vDPA device, the guest virtqueues being
+/// processed by the hardware.
+pub struct Vdpa {
+    id: String,
+    vhost: VhostVdpa,
+    device_type: u32,
+    avail_features: u64,
+    acked_features: u64,
+    queue_sizes: Vec<u16>,
+    // Guest memory regions mapped into the device IOTLB.
+    mapped_regions: Vec<(u64, u64)>,
+    enabled_queues: usize,
+    kill_evt: Option<EventFd>,
+    pause_evt: Option<EventFd>,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), EpollHelperError>>>>,
+    paused: Arc<AtomicBool>,
+}
+
+impl Vdpa {
+    /// Create a new vDPA device from the vhost-vdpa device at `path`,
+    /// exposing `num_queues` queues to the guest.
+    pub fn new(id: String, path: &Path, num_queues: usize) -> Result<Vdpa> {
+        let vhost = VhostVdpa::new(path)?;
+
+        let device_type = vhost.get_device_id()?;
+        let avail_features = vhost.get_features()?;
+        let backend_features = vhost.get_backend_features()?;
+        if backend_features & VHOST_BACKEND_F_IOTLB_MSG_V2 == 0 {
+            return Err(Error::NoIotlbMsgV2);
+        }
+        vhost.set_backend_features(VHOST_BACKEND_F_IOTLB_MSG_V2)?;
+        let queue_size = vhost.get_vring_num()?;
+
+        info!(
+            "vDPA {} device {:?}, {} queues of {} descriptors",
+            VirtioDeviceType::from(device_type),
+            path,
+            num_queues,
+            queue_size
+        );
+
+        Ok(Vdpa {
+            id,
+            vhost,
+            device_type,
+            avail_features,
+            acked_features: 0u64,
+            queue_sizes: vec![queue_size; num_queues],
+            mapped_regions: Vec::new(),
+            enabled_queues: 0,
+            kill_evt: None,
+            pause_evt: None,
+            queue_evts: None,
+            interrupt_cb: None,
+            epoll_threads: None,
+            paused: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    // Map the guest memory regions which aren't yet into the device IOTLB.
+    fn map_memory(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
+        let mut regions = Vec::new();
+        mem.with_regions_mut(|_, region| -> Result<()> {
+            regions.push((
+                region.start_addr().raw_value(),
+                region.len() as u64,
+                region.as_ptr() as u64,
+            ));
+            Ok(())
+        })?;
+
+        for (iova, size, uaddr) in regions {
+            if self.mapped_regions.iter().any(|(start, _)| *start == iova) {
+                continue;
+            }
+
+            self.vhost.dma_map(iova, size, uaddr)?;
+            self.mapped_regions.push((iova, size));
+        }
+
+        Ok(())
+    }
+
+    fn unmap_memory(&mut self) -> Result<()> {
+        while let Some((iova, size)) = self.mapped_regions.pop() {
+            self.vhost.dma_unmap(iova, size)?;
+        }
+
+        Ok(())
+    }
+
+    fn setup_queues(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        interrupt_cb: &Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: &[EventFd],
+    ) -> Result<Vec<(EventFd, Queue)>> {
+        self.vhost.set_features(self.acked_features)?;
+        self.vhost
+            .set_status((DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK) as u8)?;
+        self.map_memory(mem)?;
+
+        let mut call_evts = Vec::new();
+        for (index, queue) in queues.into_iter().enumerate() {
+            self.vhost.set_vring_num(index, queue.actual_size())?;
+            self.vhost.set_vring_addr(
+                index,
+                queue.desc_table.raw_value(),
+                queue.used_ring.raw_value(),
+                queue.avail_ring.raw_value(),
+            )?;
+            self.vhost.set_vring_base(index, 0)?;
+            self.vhost.set_vring_kick(index, &queue_evts[index])?;
+
+            // The device can trigger the interrupt directly if the transport
+            // provides an EventFd for it.
+            if let Some(notifier) = interrupt_cb.notifier(&VirtioInterruptType::Queue, Some(&queue))
+            {
+                self.vhost.set_vring_call(index, notifier)?;
+            } else {
+                let call_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::CreateCallEventFd)?;
+                self.vhost.set_vring_call(index, &call_evt)?;
+                call_evts.push((call_evt, queue));
+            }
+
+            self.vhost.set_vring_enable(index, true)?;
+            self.enabled_queues = index + 1;
+        }
+
+        self.vhost.set_status(
+            (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK | DEVICE_DRIVER_OK) as u8,
+        )?;
+
+        Ok(call_evts)
+    }
+
+    fn enable_queues(&self, enable: bool) -> Result<()> {
+        for index in 0..self.enabled_queues {
+            self.vhost.set_vring_enable(index, enable)?;
+        }
+
+        Ok(())
+    }
+
+    // Stop the device and revoke its access to the guest memory.
+    fn reset_device(&mut self) -> Result<()> {
+        self.enabled_queues = 0;
+        self.vhost.set_status(0)?;
+        self.unmap_memory()
+    }
+}
+
+impl Drop for Vdpa {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Vdpa {
+    fn device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        if let Err(e) = self.vhost.get_config(offset as u32, data) {
+            error!("Failed reading vDPA configuration space: {}", e);
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if let Err(e) = self.vhost.set_config(offset as u32, data) {
+            error!("Failed writing vDPA configuration space: {}", e);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != self.queue_sizes.len() || queue_evts.len() != self.queue_sizes.len() {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                self.queue_sizes.len(),
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating kill EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.kill_evt = Some(self_kill_evt);
+
+        let (self_pause_evt, pause_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating pause EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.pause_evt = Some(self_pause_evt);
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let call_evts = self
+            .setup_queues(&mem.memory(), &interrupt_cb, queues, &queue_evts)
+            .map_err(ActivateError::VdpaSetup)?;
+
+        // Save the queue EventFDs as we need to return them on reset, the
+        // device keeping references to them.
+        self.queue_evts = Some(queue_evts);
+
+        let mut handler = VdpaEpollHandler {
+            interrupt_cb,
+            kill_evt,
+            pause_evt,
+            call_evts,
+        };
+
+        let paused = self.paused.clone();
+        let mut epoll_threads = Vec::new();
+        thread::Builder::new()
+            .name("vdpa".to_string())
+            .spawn(move || handler.run(paused))
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(|e| {
+                error!("failed to clone the vDPA epoll thread: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.epoll_threads = Some(epoll_threads);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // We first must resume the virtio thread if it was paused.
+        if self.pause_evt.take().is_some() {
+            self.resume().ok()?;
+        }
+
+        if let Err(e) = self.reset_device() {
+            error!("Failed to reset vDPA device: {}", e);
+            return None;
+        }
+
+        // Then kill the thread.
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+
+    fn shutdown(&mut self) {
+        if let Err(e) = self.reset_device() {
+            error!("Failed to reset vDPA device: {}", e);
+        }
+    }
+
+    fn update_memory(&mut self, mem: &GuestMemoryMmap) -> std::result::Result<(), crate::Error> {
+        // Nothing is mapped until the device gets activated.
+        if self.enabled_queues == 0 {
+            return Ok(());
+        }
+
+        self.map_memory(mem).map_err(crate::Error::VdpaUpdateMemory)
+    }
+}
+
+virtio_pausable_trait!(Vdpa);
+
+impl Pausable for Vdpa {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        // Stop the hardware from processing the queues.
+        self.enable_queues(false)
+            .map_err(|e| MigratableError::Pause(anyhow!("{}", e)))?;
+
+        self.virtio_pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.enable_queues(true)
+            .map_err(|e| MigratableError::Resume(anyhow!("{}", e)))?;
+
+        self.virtio_resume()
+    }
+}
+
+impl Snapshottable for Vdpa {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        // The state of the queues lives in the hardware.
+        Err(MigratableError::Snapshot(anyhow!(
+            "Cannot snapshot vDPA device {}",
+            self.id
+        )))
+    }
+
+    fn restore(&mut self, _snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        Err(MigratableError::Restore(anyhow!(
+            "Cannot restore vDPA device {}",
+            self.id
+        )))
+    }
+}
+
+impl Transportable for Vdpa {}
+impl Migratable for Vdpa {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vdpa_uapi_layout() {
+        // Sizes the kernel encodes in the ioctl numbers and expects for the
+        // IOTLB messages.
+        assert_eq!(std::mem::size_of::<VhostVringState>(), 8);
+        assert_eq!(std::mem::size_of::<VhostVringFile>(), 8);
+        assert_eq!(std::mem::size_of::<VhostVringAddr>(), 40);
+        assert_eq!(std::mem::size_of::<VhostVdpaConfig>(), 8);
+        assert_eq!(std::mem::size_of::<VhostIotlbMsg>(), 32);
+        assert_eq!(std::mem::size_of::<VhostMsgV2>(), 72);
+
+        assert_eq!(VHOST_SET_OWNER(), 0xaf01);
+        assert_eq!(VHOST_VDPA_GET_DEVICE_ID(), 0x8004_af70);
+        assert_eq!(VHOST_VDPA_SET_STATUS(), 0x4001_af72);
+        assert_eq!(VHOST_VDPA_SET_VRING_ENABLE(), 0x4008_af75);
+        assert_eq!(VHOST_SET_VRING_ADDR(), 0x4028_af11);
+
+        let buffer = VhostVdpa::config_buffer(VhostVdpaConfig { off: 4, len: 6 });
+        assert_eq!(buffer.len(), 14);
+        assert_eq!(&buffer[..8], &[4, 0, 0, 0, 6, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_vdpa_iotlb_msg() {
+        let msg = VhostMsgV2 {
+            msg_type: VHOST_IOTLB_MSG_V2,
+            iotlb: VhostIotlbMsg {
+                iova: 0x1000,
+                size: 0x2000,
+                uaddr: 0x7f00_0000_0000,
+                perm: VHOST_ACCESS_RW,
+                msg_type: VHOST_IOTLB_UPDATE,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let bytes = msg.as_slice();
+        assert_eq!(&bytes[..4], &2u32.to_ne_bytes());
+        assert_eq!(&bytes[8..16], &0x1000u64.to_ne_bytes());
+        assert_eq!(&bytes[16..24], &0x2000u64.to_ne_bytes());
+        assert_eq!(&bytes[24..32], &0x7f00_0000_0000u64.to_ne_bytes());
+        assert_eq!(bytes[32], VHOST_ACCESS_RW);
+        assert_eq!(bytes[33], VHOST_IOTLB_UPDATE);
+    }
+}
@@ -269,6 +269,7 @@ impl VirtioDevice for Blk {
                 pause_evt: pause_evt.try_clone().unwrap(),
                 vu_interrupt_list: interrupt_list_sub,
                 slave_req_handler: None,
+                reconnect: None,
             });
 
             let paused = self.paused.clone();
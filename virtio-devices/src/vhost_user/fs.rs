@@ -9,9 +9,12 @@ use crate::{
     ActivateError, ActivateResult, Queue, UserspaceMapping, VirtioDevice, VirtioDeviceType,
     VirtioInterrupt, VirtioSharedMemoryList, VIRTIO_F_VERSION_1,
 };
+use anyhow::anyhow;
 use libc::{self, c_void, off64_t, pread64, pwrite64, EFD_NONBLOCK};
+use std::collections::BTreeMap;
+use std::fs::File;
 use std::io;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
@@ -26,18 +29,86 @@ use vhost_rs::vhost_user::{
 use vhost_rs::VhostBackend;
 use vm_memory::{
     Address, ByteValued, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
-    GuestMemoryMmap, MmapRegion,
+    GuestMemoryMmap, GuestMemoryRegion, MmapRegion,
 };
-use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 
 const NUM_QUEUE_OFFSET: usize = 1;
 
+// Maximum number of file mappings living in the DAX window at the same time.
+// Each of them takes at least one VMA from the VMM process, and going over
+// the vm.max_map_count limit would make any further mmap() fail.
+const MAX_DAX_MAPPINGS: usize = 16384;
+
+// Keep track of the file ranges mapped into the DAX window, so that the
+// oldest ones can be evicted when the window is full.
+struct DaxMappings {
+    // Length and setup sequence number of each mapping, indexed by its
+    // offset in the window.
+    mappings: BTreeMap<u64, (u64, u64)>,
+    max_mappings: usize,
+    seq: u64,
+}
+
+impl DaxMappings {
+    fn new(max_mappings: usize) -> Self {
+        DaxMappings {
+            mappings: BTreeMap::new(),
+            max_mappings,
+            seq: 0,
+        }
+    }
+
+    // Forget about the given range, trimming the mappings which are only
+    // partially covered by it.
+    fn remove(&mut self, offset: u64, len: u64) {
+        let end = offset + len;
+        let overlapping: Vec<(u64, (u64, u64))> = self
+            .mappings
+            .range(..end)
+            .filter(|&(&start, &(mlen, _))| start + mlen > offset)
+            .map(|(&start, &mapping)| (start, mapping))
+            .collect();
+
+        for (start, (mlen, seq)) in overlapping {
+            self.mappings.remove(&start);
+            if start < offset {
+                self.mappings.insert(start, (offset - start, seq));
+            }
+            if start + mlen > end {
+                self.mappings.insert(end, (start + mlen - end, seq));
+            }
+        }
+    }
+
+    // Record a new mapping, which must not overlap any existing one. The
+    // least recently set up mappings are evicted to make room for it, and
+    // returned so that the caller can unmap them.
+    fn insert(&mut self, offset: u64, len: u64) -> Vec<(u64, u64)> {
+        let mut evicted = Vec::new();
+        while !self.mappings.is_empty() && self.mappings.len() >= self.max_mappings {
+            let (&start, &(mlen, _)) = self
+                .mappings
+                .iter()
+                .min_by_key(|&(_, &(_, seq))| seq)
+                .unwrap();
+            self.mappings.remove(&start);
+            evicted.push((start, mlen));
+        }
+
+        self.seq += 1;
+        self.mappings.insert(offset, (len, self.seq));
+        evicted
+    }
+}
+
 struct SlaveReqHandler {
     cache_offset: GuestAddress,
     cache_size: u64,
     mmap_cache_addr: u64,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    mappings: DaxMappings,
 }
 
 impl SlaveReqHandler {
@@ -50,6 +121,51 @@ impl SlaveReqHandler {
 
         !(offset >= self.cache_size || end > self.cache_size)
     }
+
+    // Translate the guest physical range targeted by an I/O request into
+    // the host address it is mapped at. The range must fit entirely either
+    // in the DAX window or in a single guest RAM region.
+    fn io_host_addr(&self, mem: &GuestMemoryMmap, gpa: u64, len: u64) -> Option<u64> {
+        let end = gpa.checked_add(len)?;
+        let cache_start = self.cache_offset.raw_value();
+        let cache_end = cache_start + self.cache_size;
+        if gpa >= cache_start && gpa < cache_end {
+            if end > cache_end {
+                return None;
+            }
+            return Some(self.mmap_cache_addr + gpa - cache_start);
+        }
+
+        let region = mem.find_region(GuestAddress(gpa))?;
+        let region_end = region.start_addr().raw_value().checked_add(region.len())?;
+        if end > region_end {
+            return None;
+        }
+
+        mem.get_host_address(GuestAddress(gpa))
+            .ok()
+            .map(|addr| addr as u64)
+    }
+
+    // Replace a range of the window with an inaccessible anonymous mapping.
+    fn unmap_range(&self, offset: u64, len: u64) -> io::Result<()> {
+        let addr = self.mmap_cache_addr + offset;
+        let ret = unsafe {
+            libc::mmap(
+                addr as *mut libc::c_void,
+                len as usize,
+                libc::PROT_NONE,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_FIXED,
+                -1,
+                0 as libc::off_t,
+            )
+        };
+        if ret == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
 }
 
 impl VhostUserMasterReqHandler for SlaveReqHandler {
@@ -89,6 +205,18 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {
                 return Err(io::Error::last_os_error());
             }
 
+            // The new mapping replaced whatever was previously mapped in
+            // this range.
+            self.mappings.remove(offset, len);
+            for (offset, len) in self.mappings.insert(offset, len) {
+                warn!(
+                    "DAX window full, evicting mapping 0x{:x}-0x{:x}",
+                    offset,
+                    offset + len
+                );
+                self.unmap_range(offset, len)?;
+            }
+
             let ret = unsafe { libc::close(fd) };
             if ret == -1 {
                 return Err(io::Error::last_os_error());
@@ -120,20 +248,8 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {
                 return Err(io::Error::from_raw_os_error(libc::EINVAL));
             }
 
-            let addr = self.mmap_cache_addr + offset;
-            let ret = unsafe {
-                libc::mmap(
-                    addr as *mut libc::c_void,
-                    len as usize,
-                    libc::PROT_NONE,
-                    libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_FIXED,
-                    -1,
-                    0 as libc::off_t,
-                )
-            };
-            if ret == libc::MAP_FAILED {
-                return Err(io::Error::last_os_error());
-            }
+            self.unmap_range(offset, len)?;
+            self.mappings.remove(offset, len);
         }
 
         Ok(0)
@@ -169,6 +285,11 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {
     fn fs_slave_io(&mut self, fs: &VhostUserFSSlaveMsg, fd: RawFd) -> HandlerResult<u64> {
         debug!("fs_slave_io");
 
+        // Take ownership of the file descriptor so that it gets closed
+        // whatever the outcome of the request is.
+        let file = unsafe { File::from_raw_fd(fd) };
+        let mem = self.mem.memory();
+
         let mut done: u64 = 0;
         for i in 0..VHOST_USER_FS_SLAVE_ENTRIES {
             // Ignore if the length is 0.
@@ -179,44 +300,38 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {
             let mut foffset = fs.fd_offset[i];
             let mut len = fs.len[i] as usize;
             let gpa = fs.cache_offset[i];
-            let cache_end = self.cache_offset.raw_value() + self.cache_size;
-            let efault = libc::EFAULT;
-
-            let mut ptr = if gpa >= self.cache_offset.raw_value() && gpa < cache_end {
-                let offset = gpa
-                    .checked_sub(self.cache_offset.raw_value())
-                    .ok_or_else(|| io::Error::from_raw_os_error(efault))?;
-                let end = gpa
-                    .checked_add(fs.len[i])
-                    .ok_or_else(|| io::Error::from_raw_os_error(efault))?;
-
-                if end >= cache_end {
-                    return Err(io::Error::from_raw_os_error(efault));
-                }
-
-                self.mmap_cache_addr + offset
-            } else {
-                self.mem
-                    .memory()
-                    .get_host_address(GuestAddress(gpa))
-                    .map_err(|e| {
-                        error!(
-                            "Failed to find RAM region associated with guest physical address 0x{:x}: {:?}",
-                            gpa, e
-                        );
-                        io::Error::from_raw_os_error(efault)
-                    })? as u64
-            };
+            let mut ptr = self.io_host_addr(&mem, gpa, fs.len[i]).ok_or_else(|| {
+                error!(
+                    "Invalid I/O range 0x{:x}-0x{:x}",
+                    gpa,
+                    gpa.wrapping_add(fs.len[i])
+                );
+                io::Error::from_raw_os_error(libc::EFAULT)
+            })?;
 
             while len > 0 {
                 let ret = if (fs.flags[i] & VhostUserFSSlaveMsgFlags::MAP_W)
                     == VhostUserFSSlaveMsgFlags::MAP_W
                 {
                     debug!("write: foffset={}, len={}", foffset, len);
-                    unsafe { pwrite64(fd, ptr as *const c_void, len as usize, foffset as off64_t) }
+                    unsafe {
+                        pwrite64(
+                            file.as_raw_fd(),
+                            ptr as *const c_void,
+                            len as usize,
+                            foffset as off64_t,
+                        )
+                    }
                 } else {
                     debug!("read: foffset={}, len={}", foffset, len);
-                    unsafe { pread64(fd, ptr as *mut c_void, len as usize, foffset as off64_t) }
+                    unsafe {
+                        pread64(
+                            file.as_raw_fd(),
+                            ptr as *mut c_void,
+                            len as usize,
+                            foffset as off64_t,
+                        )
+                    }
                 };
 
                 if ret < 0 {
@@ -224,11 +339,8 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {
                 }
 
                 if ret == 0 {
-                    // EOF
-                    return Err(io::Error::new(
-                        io::ErrorKind::UnexpectedEof,
-                        "failed to access whole buffer",
-                    ));
+                    // EOF, let the backend know how much could be read.
+                    return Ok(done);
                 }
                 len -= ret as usize;
                 foffset += ret as u64;
@@ -237,11 +349,6 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {
             }
         }
 
-        let ret = unsafe { libc::close(fd) };
-        if ret == -1 {
-            return Err(io::Error::last_os_error());
-        }
-
         Ok(done)
     }
 }
@@ -468,26 +575,27 @@ impl VirtioDevice for Fs {
 
         // Initialize slave communication.
         let slave_req_handler = if self.slave_req_support {
-            if let Some(cache) = self.cache.as_ref() {
-                let vu_master_req_handler = Arc::new(Mutex::new(SlaveReqHandler {
-                    cache_offset: cache.0.addr,
-                    cache_size: cache.0.len,
-                    mmap_cache_addr: cache.0.host_addr,
-                    mem,
-                }));
-
-                let req_handler = MasterReqHandler::new(vu_master_req_handler).map_err(|e| {
-                    ActivateError::VhostUserSetup(Error::MasterReqHandlerCreation(e))
-                })?;
-                self.vu
-                    .set_slave_request_fd(req_handler.get_tx_raw_fd())
-                    .map_err(|e| {
-                        ActivateError::VhostUserSetup(Error::VhostUserSetSlaveRequestFd(e))
-                    })?;
-                Some(req_handler)
-            } else {
-                None
-            }
+            // Without any DAX window, the backend can still rely on the slave
+            // channel for FS_IO requests targeting guest RAM, while any
+            // mapping request will be rejected.
+            let (cache_offset, cache_size, mmap_cache_addr) = match self.cache.as_ref() {
+                Some(cache) => (cache.0.addr, cache.0.len, cache.0.host_addr),
+                None => (GuestAddress(0), 0, 0),
+            };
+            let vu_master_req_handler = Arc::new(Mutex::new(SlaveReqHandler {
+                cache_offset,
+                cache_size,
+                mmap_cache_addr,
+                mem,
+                mappings: DaxMappings::new(MAX_DAX_MAPPINGS),
+            }));
+
+            let req_handler = MasterReqHandler::new(vu_master_req_handler)
+                .map_err(|e| ActivateError::VhostUserSetup(Error::MasterReqHandlerCreation(e)))?;
+            self.vu
+                .set_slave_request_fd(req_handler.get_tx_raw_fd())
+                .map_err(|e| ActivateError::VhostUserSetup(Error::VhostUserSetSlaveRequestFd(e)))?;
+            Some(req_handler)
         } else {
             None
         };
@@ -498,6 +606,7 @@ impl VirtioDevice for Fs {
             kill_evt,
             pause_evt,
             slave_req_handler,
+            reconnect: None,
         });
 
         let paused = self.paused.clone();
@@ -588,6 +697,240 @@ impl Snapshottable for Fs {
     fn id(&self) -> String {
         self.id.clone()
     }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        // The DAX window is filled with host files mapped on behalf of the
+        // backend, which can't be recreated on the destination. Refuse
+        // rather than silently dropping the guest mappings.
+        if self.cache.is_some() {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "Cannot snapshot virtio-fs device {} with DAX enabled",
+                self.id
+            )));
+        }
+
+        Ok(Snapshot::new(&self.id))
+    }
 }
 impl Transportable for Fs {}
 impl Migratable for Fs {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+    use vm_memory::Bytes;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const PAGE_SIZE: u64 = 0x1000;
+    const CACHE_SIZE: u64 = 4 * PAGE_SIZE;
+
+    fn create_handler(max_mappings: usize) -> (SlaveReqHandler, MmapRegion) {
+        let cache = MmapRegion::new(CACHE_SIZE as usize).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), PAGE_SIZE as usize)]).unwrap();
+        let handler = SlaveReqHandler {
+            cache_offset: GuestAddress(0x1_0000_0000),
+            cache_size: CACHE_SIZE,
+            mmap_cache_addr: cache.as_ptr() as u64,
+            mem: GuestMemoryAtomic::new(mem),
+            mappings: DaxMappings::new(max_mappings),
+        };
+
+        (handler, cache)
+    }
+
+    fn map_msg(moffset: u64, len: u64) -> VhostUserFSSlaveMsg {
+        let mut msg = VhostUserFSSlaveMsg::default();
+        msg.cache_offset[0] = moffset;
+        msg.len[0] = len;
+        msg.flags[0] = VhostUserFSSlaveMsgFlags::MAP_R;
+        msg
+    }
+
+    fn test_file() -> TempFile {
+        let file = TempFile::new().unwrap();
+        file.as_file()
+            .write_all(&[0xa5u8; (CACHE_SIZE as usize)])
+            .unwrap();
+        file
+    }
+
+    fn map(handler: &mut SlaveReqHandler, file: &TempFile, moffset: u64, len: u64) -> bool {
+        // The handler takes ownership of the file descriptor.
+        let fd = unsafe { libc::dup(file.as_file().as_raw_fd()) };
+        handler.fs_slave_map(&map_msg(moffset, len), fd).is_ok()
+    }
+
+    fn io_msg(gpa: u64, foffset: u64, len: u64, write: bool) -> VhostUserFSSlaveMsg {
+        let mut msg = VhostUserFSSlaveMsg::default();
+        msg.cache_offset[0] = gpa;
+        msg.fd_offset[0] = foffset;
+        msg.len[0] = len;
+        msg.flags[0] = if write {
+            VhostUserFSSlaveMsgFlags::MAP_W
+        } else {
+            VhostUserFSSlaveMsgFlags::MAP_R
+        };
+        msg
+    }
+
+    fn io(handler: &mut SlaveReqHandler, file: &TempFile, msg: VhostUserFSSlaveMsg) -> Option<u64> {
+        // The handler takes ownership of the file descriptor.
+        let fd = unsafe { libc::dup(file.as_file().as_raw_fd()) };
+        handler.fs_slave_io(&msg, fd).ok()
+    }
+
+    fn mapped_ranges(handler: &SlaveReqHandler) -> Vec<(u64, u64)> {
+        handler
+            .mappings
+            .mappings
+            .iter()
+            .map(|(&offset, &(len, _))| (offset, len))
+            .collect()
+    }
+
+    #[test]
+    fn test_dax_setup_remove_mapping() {
+        let (mut handler, cache) = create_handler(MAX_DAX_MAPPINGS);
+        let file = test_file();
+
+        assert!(map(&mut handler, &file, PAGE_SIZE, 2 * PAGE_SIZE));
+        assert_eq!(mapped_ranges(&handler), vec![(PAGE_SIZE, 2 * PAGE_SIZE)]);
+        // The file content is directly accessible through the window.
+        let data = unsafe { *cache.as_ptr().add(PAGE_SIZE as usize) };
+        assert_eq!(data, 0xa5);
+
+        // Ranges going beyond the window are rejected.
+        assert!(!map(&mut handler, &file, 3 * PAGE_SIZE, 2 * PAGE_SIZE));
+        assert!(!map(&mut handler, &file, CACHE_SIZE, PAGE_SIZE));
+
+        // Removing part of a mapping keeps track of what's left.
+        handler
+            .fs_slave_unmap(&map_msg(2 * PAGE_SIZE, PAGE_SIZE))
+            .unwrap();
+        assert_eq!(mapped_ranges(&handler), vec![(PAGE_SIZE, PAGE_SIZE)]);
+
+        // Mapping over an existing range replaces it.
+        assert!(map(&mut handler, &file, 0, 2 * PAGE_SIZE));
+        assert_eq!(mapped_ranges(&handler), vec![(0, 2 * PAGE_SIZE)]);
+
+        // The whole window can be unmapped at once.
+        handler
+            .fs_slave_unmap(&map_msg(0, 0xffff_ffff_ffff_ffff))
+            .unwrap();
+        assert!(mapped_ranges(&handler).is_empty());
+    }
+
+    #[test]
+    fn test_dax_window_full_eviction() {
+        let (mut handler, _cache) = create_handler(2);
+        let file = test_file();
+
+        assert!(map(&mut handler, &file, 0, PAGE_SIZE));
+        assert!(map(&mut handler, &file, PAGE_SIZE, PAGE_SIZE));
+        assert!(map(&mut handler, &file, 2 * PAGE_SIZE, PAGE_SIZE));
+
+        // The oldest mapping has been evicted to make room for the new one.
+        assert_eq!(
+            mapped_ranges(&handler),
+            vec![(PAGE_SIZE, PAGE_SIZE), (2 * PAGE_SIZE, PAGE_SIZE)]
+        );
+
+        // Remapping a range makes it the most recent one.
+        assert!(map(&mut handler, &file, PAGE_SIZE, PAGE_SIZE));
+        assert!(map(&mut handler, &file, 3 * PAGE_SIZE, PAGE_SIZE));
+        assert_eq!(
+            mapped_ranges(&handler),
+            vec![(PAGE_SIZE, PAGE_SIZE), (3 * PAGE_SIZE, PAGE_SIZE)]
+        );
+    }
+
+    #[test]
+    fn test_fs_io() {
+        let (mut handler, cache) = create_handler(MAX_DAX_MAPPINGS);
+        let file = test_file();
+        let mem = handler.mem.memory();
+        let window = handler.cache_offset.raw_value();
+
+        // Reading from the file into guest RAM.
+        assert_eq!(
+            io(&mut handler, &file, io_msg(0x100, 0, 0x100, false)),
+            Some(0x100)
+        );
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x1ff)).unwrap(), 0xa5);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x200)).unwrap(), 0);
+
+        // Reading from the file into the DAX window, up to its very end.
+        assert_eq!(
+            io(
+                &mut handler,
+                &file,
+                io_msg(window + 3 * PAGE_SIZE, 0, PAGE_SIZE, false)
+            ),
+            Some(PAGE_SIZE)
+        );
+        let data = unsafe { *cache.as_ptr().add((CACHE_SIZE - 1) as usize) };
+        assert_eq!(data, 0xa5);
+
+        // Writing guest RAM to the file.
+        mem.write_obj(0x5au8, GuestAddress(0)).unwrap();
+        assert_eq!(io(&mut handler, &file, io_msg(0, 0, 1, true)), Some(1));
+        let mut buf = [0u8; 2];
+        file.as_file().read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [0x5a, 0xa5]);
+
+        // Reads stop at the end of the file.
+        assert_eq!(
+            io(
+                &mut handler,
+                &file,
+                io_msg(0, CACHE_SIZE - 0x10, 0x100, false)
+            ),
+            Some(0x10)
+        );
+
+        // Ranges crossing the end of the window or of guest RAM, or not
+        // backed by anything, are rejected.
+        assert_eq!(
+            io(
+                &mut handler,
+                &file,
+                io_msg(window + 3 * PAGE_SIZE, 0, 2 * PAGE_SIZE, false)
+            ),
+            None
+        );
+        assert_eq!(
+            io(
+                &mut handler,
+                &file,
+                io_msg(PAGE_SIZE / 2, 0, PAGE_SIZE, false)
+            ),
+            None
+        );
+        assert_eq!(
+            io(&mut handler, &file, io_msg(2 * PAGE_SIZE, 0, 1, false)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_fs_io_without_dax_window() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), PAGE_SIZE as usize)]).unwrap();
+        let mut handler = SlaveReqHandler {
+            cache_offset: GuestAddress(0),
+            cache_size: 0,
+            mmap_cache_addr: 0,
+            mem: GuestMemoryAtomic::new(mem),
+            mappings: DaxMappings::new(MAX_DAX_MAPPINGS),
+        };
+        let file = test_file();
+
+        // I/O to guest RAM keeps working while mappings are refused.
+        assert_eq!(
+            io(&mut handler, &file, io_msg(0, 0, PAGE_SIZE, false)),
+            Some(PAGE_SIZE)
+        );
+        assert!(!map(&mut handler, &file, 0, PAGE_SIZE));
+    }
+}
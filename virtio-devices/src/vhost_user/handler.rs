@@ -8,6 +8,7 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use super::super::{Queue, VirtioInterruptType};
+use super::vu_common_ctrl::VhostUserReconnect;
 use super::{Error, Result};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -27,12 +28,14 @@ use vhost_rs::vhost_user::{MasterReqHandler, VhostUserMasterReqHandler};
 /// * `interrupt_cb` interrupt for virtqueue change.
 /// * `kill_evt` - EventFd used to kill the vhost-user device.
 /// * `vu_interrupt_list` - virtqueue and EventFd to signal when buffer used.
+/// * `reconnect` - state to replay when the backend connection is lost.
 pub struct VhostUserEpollConfig<S: VhostUserMasterReqHandler> {
     pub interrupt_cb: Arc<dyn VirtioInterrupt>,
     pub kill_evt: EventFd,
     pub pause_evt: EventFd,
     pub vu_interrupt_list: Vec<(Option<EventFd>, Queue)>,
     pub slave_req_handler: Option<MasterReqHandler<S>>,
+    pub reconnect: Option<VhostUserReconnect>,
 }
 
 pub struct VhostUserEpollHandler<S: VhostUserMasterReqHandler> {
@@ -101,7 +104,7 @@ impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
         let mut index = pause_evt_index;
 
         let slave_evt_index = if let Some(self_req_handler) = &self.vu_epoll_cfg.slave_req_handler {
-            index = pause_evt_index + 1;
+            index += 1;
             epoll::ctl(
                 epoll_file.as_raw_fd(),
                 epoll::ControlOptions::EPOLL_CTL_ADD,
@@ -115,6 +118,21 @@ impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
             None
         };
 
+        let reconnect_evt_index = if let Some(reconnect) = &self.vu_epoll_cfg.reconnect {
+            index += 1;
+            epoll::ctl(
+                epoll_file.as_raw_fd(),
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                reconnect.vu.lock().unwrap().as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLRDHUP, index as u64),
+            )
+            .map_err(Error::EpollCtl)?;
+
+            Some(index)
+        } else {
+            None
+        };
+
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); index + 1];
 
         // Before jumping into the epoll loop, check if the device is expected
@@ -180,11 +198,48 @@ impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
                         if let Some(slave_req_handler) =
                             self.vu_epoll_cfg.slave_req_handler.as_mut()
                         {
-                            slave_req_handler
-                                .handle_request()
-                                .map_err(Error::VhostUserSlaveRequest)?;
+                            match slave_req_handler.handle_request() {
+                                Ok(_) => {}
+                                // The backend has already been told about the
+                                // failure, and a single invalid request should
+                                // not take the whole device down.
+                                Err(vhost_rs::vhost_user::Error::ReqHandlerError(e)) => {
+                                    error!("Failed handling slave request: {:?}", e);
+                                }
+                                Err(e) => return Err(Error::VhostUserSlaveRequest(e)),
+                            }
                         }
                     }
+                    x if reconnect_evt_index == Some(x) => {
+                        let reconnect = self.vu_epoll_cfg.reconnect.as_ref().unwrap();
+                        warn!(
+                            "Lost connection with vhost-user backend {}, reconnecting",
+                            reconnect.socket
+                        );
+
+                        // The socket is about to be replaced, stop watching it.
+                        epoll::ctl(
+                            epoll_file.as_raw_fd(),
+                            epoll::ControlOptions::EPOLL_CTL_DEL,
+                            reconnect.vu.lock().unwrap().as_raw_fd(),
+                            epoll::Event::new(epoll::Events::empty(), 0),
+                        )
+                        .map_err(Error::EpollCtl)?;
+
+                        if let Err(e) = reconnect.reconnect() {
+                            error!("Failed reconnecting to vhost-user backend: {:?}", e);
+                            continue;
+                        }
+
+                        epoll::ctl(
+                            epoll_file.as_raw_fd(),
+                            epoll::ControlOptions::EPOLL_CTL_ADD,
+                            reconnect.vu.lock().unwrap().as_raw_fd(),
+                            epoll::Event::new(epoll::Events::EPOLLRDHUP, x as u64),
+                        )
+                        .map_err(Error::EpollCtl)?;
+                        info!("Reconnected to vhost-user backend {}", reconnect.socket);
+                    }
                     _ => {
                         error!("Unknown event for vhost-user");
                     }
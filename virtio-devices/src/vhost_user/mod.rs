@@ -20,7 +20,7 @@ pub mod vu_common_ctrl;
 pub use self::blk::Blk;
 pub use self::fs::*;
 pub use self::net::Net;
-pub use self::vu_common_ctrl::VhostUserConfig;
+pub use self::vu_common_ctrl::{VhostUserConfig, VhostUserReconnectConfig};
 
 #[derive(Debug)]
 pub enum Error {
@@ -96,6 +96,8 @@ pub enum Error {
     VhostUserSetSlaveRequestFd(vhost_rs::Error),
     /// Invalid used address.
     UsedAddress,
+    /// Failed reading the ring index to resume a vring from.
+    VringBase(vm_virtio::queue::Error),
     /// Invalid features provided from vhost-user backend
     InvalidFeatures,
 }
@@ -4,19 +4,23 @@
 use super::super::net_util::{
     build_net_config_space, CtrlVirtio, NetCtrlEpollHandler, VirtioNetConfig,
 };
+use super::super::seccomp_filters::{get_seccomp_filter, Thread};
 use super::super::Error as CtrlError;
-use super::super::{ActivateError, ActivateResult, Queue, VirtioDevice, VirtioDeviceType};
+use super::super::{
+    ActivateError, ActivateResult, Queue, ReadyBarrier, VirtioDevice, VirtioDeviceType,
+};
 use super::handler::*;
 use super::vu_common_ctrl::*;
 use super::Error as DeviceError;
 use super::{Error, Result};
-use crate::VirtioInterrupt;
+use crate::{trace, VirtioInterrupt, VirtioInterruptType};
 use libc::EFD_NONBLOCK;
 use net_util::MacAddr;
+use seccomp::{SeccompAction, SeccompFilter};
 use std::os::unix::io::AsRawFd;
 use std::result;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::vec::Vec;
 use vhost_rs::vhost_user::message::{VhostUserProtocolFeatures, VhostUserVirtioFeatures};
@@ -35,7 +39,11 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {}
 
 pub struct Net {
     id: String,
-    vhost_user_net: Master,
+    vhost_user_net: Arc<Mutex<Master>>,
+    socket: String,
+    num_queues: usize,
+    protocol_features: VhostUserProtocolFeatures,
+    reconnect: VhostUserReconnectConfig,
     kill_evt: Option<EventFd>,
     pause_evt: Option<EventFd>,
     avail_features: u64,
@@ -48,12 +56,18 @@ pub struct Net {
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     ctrl_queue_epoll_thread: Option<thread::JoinHandle<result::Result<(), CtrlError>>>,
     paused: Arc<AtomicBool>,
+    seccomp_action: SeccompAction,
 }
 
 impl Net {
     /// Create a new vhost-user-net device
-    /// Create a new vhost-user-net device
-    pub fn new(id: String, mac_addr: MacAddr, vu_cfg: VhostUserConfig) -> Result<Net> {
+    pub fn new(
+        id: String,
+        mac_addr: MacAddr,
+        vu_cfg: VhostUserConfig,
+        reconnect: VhostUserReconnectConfig,
+        seccomp_action: SeccompAction,
+    ) -> Result<Net> {
         let mut vhost_user_net = Master::connect(&vu_cfg.socket, vu_cfg.num_queues as u64)
             .map_err(Error::VhostUserCreateMaster)?;
 
@@ -101,10 +115,11 @@ impl Net {
             return Err(Error::VhostUserProtocolNotSupport);
         }
 
+        let protocol_features = protocol_features & VhostUserProtocolFeatures::MQ;
         let max_queue_number =
             if protocol_features.bits() & VhostUserProtocolFeatures::MQ.bits() != 0 {
                 vhost_user_net
-                    .set_protocol_features(protocol_features & VhostUserProtocolFeatures::MQ)
+                    .set_protocol_features(protocol_features)
                     .map_err(Error::VhostUserSetProtocolFeatures)?;
                 match vhost_user_net.get_queue_num() {
                     Ok(qn) => qn,
@@ -123,12 +138,7 @@ impl Net {
         let queue_num = vu_cfg.num_queues + 1;
 
         let mut config = VirtioNetConfig::default();
-        build_net_config_space(
-            &mut config,
-            mac_addr,
-            vu_cfg.num_queues,
-            &mut avail_features,
-        );
+        avail_features |= build_net_config_space(&mut config, mac_addr, vu_cfg.num_queues);
 
         // Send set_vring_base here, since it could tell backends, like OVS + DPDK,
         // how many virt queues to be handled, which backend required to know at early stage.
@@ -140,7 +150,11 @@ impl Net {
 
         Ok(Net {
             id,
-            vhost_user_net,
+            vhost_user_net: Arc::new(Mutex::new(vhost_user_net)),
+            socket: vu_cfg.socket,
+            num_queues: vu_cfg.num_queues,
+            protocol_features,
+            reconnect,
             kill_evt: None,
             pause_evt: None,
             avail_features,
@@ -153,6 +167,7 @@ impl Net {
             epoll_threads: None,
             ctrl_queue_epoll_thread: None,
             paused: Arc::new(AtomicBool::new(false)),
+            seccomp_action,
         })
     }
 }
@@ -254,23 +269,49 @@ impl VirtioDevice for Net {
                 mem: mem.clone(),
                 kill_evt: kill_evt.try_clone().unwrap(),
                 pause_evt: pause_evt.try_clone().unwrap(),
-                ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt),
+                ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt, Arc::new(AtomicU16::new(1))),
                 epoll_fd: 0,
+                activate_span: trace::current(),
+                idle_tracker: None,
             };
 
             let paused = self.paused.clone();
+            let ready = ReadyBarrier::new();
+            let ctrl_ready = ready.notifier();
+            // Retrieve seccomp filter for virtio_net_ctl thread
+            let virtio_net_ctl_seccomp_filter =
+                get_seccomp_filter(&self.seccomp_action, Thread::VirtioNetCtl)
+                    .map_err(ActivateError::CreateSeccompFilter)?;
             thread::Builder::new()
                 .name("virtio_net".to_string())
-                .spawn(move || ctrl_handler.run_ctrl(paused))
+                .spawn(move || {
+                    SeccompFilter::apply(virtio_net_ctl_seccomp_filter)
+                        .map_err(CtrlError::ApplySeccompFilter)?;
+                    ctrl_handler.run_ctrl(paused, ctrl_ready)
+                })
                 .map(|thread| self.ctrl_queue_epoll_thread = Some(thread))
                 .map_err(|e| {
                     error!("failed to clone queue EventFd: {}", e);
                     ActivateError::BadActivate
                 })?;
+
+            // The other queues are handled by the backend.
+            if !ready.wait() {
+                error!("virtio-net control thread exited before listening to its queue");
+                return Err(ActivateError::BadActivate);
+            }
+        }
+
+        let mut kick_evts = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            kick_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
         }
 
         let mut vu_interrupt_list = setup_vhost_user(
-            &mut self.vhost_user_net,
+            &mut self.vhost_user_net.lock().unwrap(),
             &mem.memory(),
             queues,
             queue_evts,
@@ -279,6 +320,39 @@ impl VirtioDevice for Net {
         )
         .map_err(ActivateError::VhostUserNetSetup)?;
 
+        // Only the thread handling the first queue pair watches the
+        // connection with the backend.
+        let mut vu_reconnect = if self.reconnect.retries > 0 {
+            let mut call_evts = Vec::new();
+            for (eventfd, queue) in vu_interrupt_list.iter() {
+                let eventfd = match eventfd {
+                    Some(eventfd) => eventfd,
+                    None => interrupt_cb
+                        .notifier(&VirtioInterruptType::Queue, Some(queue))
+                        .ok_or(ActivateError::BadActivate)?,
+                };
+                call_evts.push(eventfd.try_clone().map_err(|e| {
+                    error!("failed to clone call EventFd: {}", e);
+                    ActivateError::BadActivate
+                })?);
+            }
+
+            Some(VhostUserReconnect {
+                vu: self.vhost_user_net.clone(),
+                socket: self.socket.clone(),
+                num_queues: self.num_queues,
+                acked_features: self.acked_features & self.backend_features,
+                protocol_features: self.protocol_features,
+                mem: mem.clone(),
+                queues: vu_interrupt_list.iter().map(|(_, q)| q.clone()).collect(),
+                call_evts,
+                kick_evts,
+                config: self.reconnect,
+            })
+        } else {
+            None
+        };
+
         let mut epoll_threads = Vec::new();
         for _ in 0..vu_interrupt_list.len() / 2 {
             let mut interrupt_list_sub: Vec<(Option<EventFd>, Queue)> = Vec::with_capacity(2);
@@ -291,6 +365,7 @@ impl VirtioDevice for Net {
                 pause_evt: pause_evt.try_clone().unwrap(),
                 vu_interrupt_list: interrupt_list_sub,
                 slave_req_handler: None,
+                reconnect: vu_reconnect.take(),
             });
 
             let paused = self.paused.clone();
@@ -315,7 +390,10 @@ impl VirtioDevice for Net {
             self.resume().ok()?;
         }
 
-        if let Err(e) = reset_vhost_user(&mut self.vhost_user_net, self.queue_sizes.len()) {
+        if let Err(e) = reset_vhost_user(
+            &mut self.vhost_user_net.lock().unwrap(),
+            self.queue_sizes.len(),
+        ) {
             error!("Failed to reset vhost-user daemon: {:?}", e);
             return None;
         }
@@ -333,11 +411,12 @@ impl VirtioDevice for Net {
     }
 
     fn shutdown(&mut self) {
-        let _ = unsafe { libc::close(self.vhost_user_net.as_raw_fd()) };
+        let _ = unsafe { libc::close(self.vhost_user_net.lock().unwrap().as_raw_fd()) };
     }
 
     fn update_memory(&mut self, mem: &GuestMemoryMmap) -> std::result::Result<(), crate::Error> {
-        update_mem_table(&mut self.vhost_user_net, mem).map_err(crate::Error::VhostUserUpdateMemory)
+        update_mem_table(&mut self.vhost_user_net.lock().unwrap(), mem)
+            .map_err(crate::Error::VhostUserUpdateMemory)
     }
 }
 
@@ -5,16 +5,26 @@ use super::super::{Descriptor, Queue};
 use super::{Error, Result};
 use crate::{VirtioInterrupt, VirtioInterruptType};
 use libc::EFD_NONBLOCK;
+use std::cmp;
 use std::convert::TryInto;
 use std::os::unix::io::AsRawFd;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use std::vec::Vec;
 use vfio_ioctls::get_host_address_range;
+use vhost_rs::vhost_user::message::{VhostUserProtocolFeatures, VhostUserVirtioFeatures};
 use vhost_rs::vhost_user::{Master, VhostUserMaster};
 use vhost_rs::{VhostBackend, VhostUserMemoryRegionInfo, VringConfigData};
-use vm_memory::{Address, Error as MmapError, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_memory::{
+    Address, Error as MmapError, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
+    GuestMemoryMmap, GuestMemoryRegion,
+};
 use vmm_sys_util::eventfd::EventFd;
 
+// Longest delay between two attempts at reconnecting to a backend.
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone)]
 pub struct VhostUserConfig {
     pub socket: String,
@@ -22,6 +32,16 @@ pub struct VhostUserConfig {
     pub queue_size: u16,
 }
 
+/// Number of attempts made at reconnecting to a vhost-user backend after
+/// the connection with it has been lost, and the delay before the first
+/// one. The delay doubles after each failed attempt, and no reconnection
+/// is attempted at all if `retries` is 0.
+#[derive(Debug, Clone, Copy)]
+pub struct VhostUserReconnectConfig {
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
 pub fn update_mem_table(vu: &mut Master, mem: &GuestMemoryMmap) -> Result<()> {
     let mut regions: Vec<VhostUserMemoryRegionInfo> = Vec::new();
     mem.with_regions_mut(|_, region| {
@@ -63,59 +83,83 @@ pub fn setup_vhost_user_vring(
     let mut vu_interrupt_list = Vec::new();
 
     for (queue_index, queue) in queues.into_iter().enumerate() {
-        let actual_size: usize = queue.actual_size().try_into().unwrap();
-
-        vu.set_vring_num(queue_index, queue.actual_size())
-            .map_err(Error::VhostUserSetVringNum)?;
-
-        let config_data = VringConfigData {
-            queue_max_size: queue.get_max_size(),
-            queue_size: queue.actual_size(),
-            flags: 0u32,
-            desc_table_addr: get_host_address_range(
-                mem,
-                queue.desc_table,
-                actual_size * std::mem::size_of::<Descriptor>(),
-            )
-            .ok_or_else(|| Error::DescriptorTableAddress)? as u64,
-            // The used ring is {flags: u16; idx: u16; virtq_used_elem [{id: u16, len: u16}; actual_size]},
-            // i.e. 4 + (4 + 4) * actual_size.
-            used_ring_addr: get_host_address_range(mem, queue.used_ring, 4 + actual_size * 8)
-                .ok_or_else(|| Error::UsedAddress)? as u64,
-            // The used ring is {flags: u16; idx: u16; elem [u16; actual_size]},
-            // i.e. 4 + (2) * actual_size.
-            avail_ring_addr: get_host_address_range(mem, queue.avail_ring, 4 + actual_size * 2)
-                .ok_or_else(|| Error::AvailAddress)? as u64,
-            log_addr: None,
-        };
-
-        vu.set_vring_addr(queue_index, &config_data)
-            .map_err(Error::VhostUserSetVringAddr)?;
-        vu.set_vring_base(queue_index, 0u16)
-            .map_err(Error::VhostUserSetVringBase)?;
-
         if let Some(eventfd) = virtio_interrupt.notifier(&VirtioInterruptType::Queue, Some(&queue))
         {
-            vu.set_vring_call(queue_index, &eventfd)
-                .map_err(Error::VhostUserSetVringCall)?;
+            setup_vring(
+                vu,
+                mem,
+                queue_index,
+                &queue,
+                0,
+                eventfd,
+                &queue_evts[queue_index],
+            )?;
             vu_interrupt_list.push((None, queue));
         } else {
             let eventfd = EventFd::new(EFD_NONBLOCK).map_err(Error::VhostIrqCreate)?;
-            vu.set_vring_call(queue_index, &eventfd)
-                .map_err(Error::VhostUserSetVringCall)?;
+            setup_vring(
+                vu,
+                mem,
+                queue_index,
+                &queue,
+                0,
+                &eventfd,
+                &queue_evts[queue_index],
+            )?;
             vu_interrupt_list.push((Some(eventfd), queue));
         }
-
-        vu.set_vring_kick(queue_index, &queue_evts[queue_index])
-            .map_err(Error::VhostUserSetVringKick)?;
-
-        vu.set_vring_enable(queue_index, true)
-            .map_err(Error::VhostUserSetVringEnable)?;
     }
 
     Ok(vu_interrupt_list)
 }
 
+fn setup_vring(
+    vu: &mut Master,
+    mem: &GuestMemoryMmap,
+    queue_index: usize,
+    queue: &Queue,
+    base: u16,
+    call_evt: &EventFd,
+    kick_evt: &EventFd,
+) -> Result<()> {
+    let actual_size: usize = queue.actual_size().try_into().unwrap();
+
+    vu.set_vring_num(queue_index, queue.actual_size())
+        .map_err(Error::VhostUserSetVringNum)?;
+
+    let config_data = VringConfigData {
+        queue_max_size: queue.get_max_size(),
+        queue_size: queue.actual_size(),
+        flags: 0u32,
+        desc_table_addr: get_host_address_range(
+            mem,
+            queue.desc_table,
+            actual_size * std::mem::size_of::<Descriptor>(),
+        )
+        .ok_or_else(|| Error::DescriptorTableAddress)? as u64,
+        // The used ring is {flags: u16; idx: u16; virtq_used_elem [{id: u16, len: u16}; actual_size]},
+        // i.e. 4 + (4 + 4) * actual_size.
+        used_ring_addr: get_host_address_range(mem, queue.used_ring, 4 + actual_size * 8)
+            .ok_or_else(|| Error::UsedAddress)? as u64,
+        // The used ring is {flags: u16; idx: u16; elem [u16; actual_size]},
+        // i.e. 4 + (2) * actual_size.
+        avail_ring_addr: get_host_address_range(mem, queue.avail_ring, 4 + actual_size * 2)
+            .ok_or_else(|| Error::AvailAddress)? as u64,
+        log_addr: None,
+    };
+
+    vu.set_vring_addr(queue_index, &config_data)
+        .map_err(Error::VhostUserSetVringAddr)?;
+    vu.set_vring_base(queue_index, base)
+        .map_err(Error::VhostUserSetVringBase)?;
+    vu.set_vring_call(queue_index, call_evt)
+        .map_err(Error::VhostUserSetVringCall)?;
+    vu.set_vring_kick(queue_index, kick_evt)
+        .map_err(Error::VhostUserSetVringKick)?;
+    vu.set_vring_enable(queue_index, true)
+        .map_err(Error::VhostUserSetVringEnable)
+}
+
 pub fn setup_vhost_user(
     vu: &mut Master,
     mem: &GuestMemoryMmap,
@@ -145,3 +189,107 @@ pub fn reset_vhost_user(vu: &mut Master, num_queues: usize) -> Result<()> {
     // Reset the owner.
     vu.reset_owner().map_err(Error::VhostUserResetOwner)
 }
+
+/// Everything a vhost-user device has set up on its backend, which needs
+/// to be replayed after reconnecting to it.
+pub struct VhostUserReconnect {
+    pub vu: Arc<Mutex<Master>>,
+    pub socket: String,
+    pub num_queues: usize,
+    pub acked_features: u64,
+    pub protocol_features: VhostUserProtocolFeatures,
+    pub mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    pub queues: Vec<Queue>,
+    pub call_evts: Vec<EventFd>,
+    pub kick_evts: Vec<EventFd>,
+    pub config: VhostUserReconnectConfig,
+}
+
+impl VhostUserReconnect {
+    /// Connect to the backend again, retrying until it succeeds or until the
+    /// number of retries is exhausted. On success, the new connection
+    /// replaces the one shared with the device.
+    pub fn reconnect(&self) -> Result<()> {
+        let mut backoff = self.config.backoff;
+        let mut attempt = 1;
+        let vu = loop {
+            // Give the backend some time to come back.
+            thread::sleep(backoff);
+
+            match self.replay() {
+                Ok(vu) => break vu,
+                Err(e) if attempt < self.config.retries => {
+                    warn!(
+                        "Failed reconnecting to vhost-user backend {} (attempt {}/{}): {:?}",
+                        self.socket, attempt, self.config.retries, e
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+
+            attempt += 1;
+            backoff = cmp::min(backoff * 2, MAX_RECONNECT_BACKOFF);
+        };
+        *self.vu.lock().unwrap() = vu;
+
+        // Let the backend process whatever the guest made available while
+        // it was away.
+        for kick_evt in self.kick_evts.iter() {
+            if let Err(e) = kick_evt.write(1) {
+                error!("Failed kicking vhost-user backend: {:?}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn replay(&self) -> Result<Master> {
+        let mut vu = Master::connect(&self.socket, self.num_queues as u64)
+            .map_err(Error::VhostUserCreateMaster)?;
+        vu.set_owner().map_err(Error::VhostUserSetOwner)?;
+
+        // The guest driver can't be asked to negotiate its features again,
+        // the new backend must support all of them.
+        let backend_features = vu.get_features().map_err(Error::VhostUserGetFeatures)?;
+        if backend_features & self.acked_features != self.acked_features {
+            return Err(Error::InvalidFeatures);
+        }
+        vu.set_features(self.acked_features)
+            .map_err(Error::VhostUserSetFeatures)?;
+
+        if self.acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0 {
+            let backend_protocol_features = vu
+                .get_protocol_features()
+                .map_err(Error::VhostUserGetProtocolFeatures)?;
+            if !backend_protocol_features.contains(self.protocol_features) {
+                return Err(Error::InvalidFeatures);
+            }
+            if !self.protocol_features.is_empty() {
+                vu.set_protocol_features(self.protocol_features)
+                    .map_err(Error::VhostUserSetProtocolFeatures)?;
+            }
+        }
+
+        let mem = self.mem.memory();
+        update_mem_table(&mut vu, &mem)?;
+
+        for (queue_index, queue) in self.queues.iter().enumerate() {
+            // There is no way to know which buffers the previous backend was
+            // still processing, so resume from the last one it returned.
+            let base = queue
+                .used_index_from_memory(&mem)
+                .map_err(Error::VringBase)?;
+            setup_vring(
+                &mut vu,
+                &mem,
+                queue_index,
+                queue,
+                base,
+                &self.call_evts[queue_index],
+                &self.kick_evts[queue_index],
+            )?;
+        }
+
+        Ok(vu)
+    }
+}
@@ -78,8 +78,27 @@
 //             information must be included in any packet);
 //          2. The receiver can be proactive, and send VSOCK_OP_CREDIT_UPDATE packet, whenever
 //             it thinks its peer's information is out of date.
-//          Our implementation uses the proactive approach.
+//          Our implementation uses the proactive approach: once the peer's view of our free
+//          buffer space drops below `CONN_CREDIT_UPDATE_THRESHOLD`, a credit update is sent
+//          as soon as enough buffer space has been freed up (or the buffer has been drained),
+//          rather than waiting for the peer to run out of credit. Credit requests coming from
+//          the peer are answered all the same.
 //
+// 4. SEQPACKET connections
+//    When `hdr.type` is VSOCK_TYPE_SEQPACKET, the connection preserves message boundaries.
+//    A message (or record) can span several VSOCK_OP_RW packets, and its last packet carries
+//    the VSOCK_FLAGS_SEQ_EOM flag (plus VSOCK_FLAGS_SEQ_EOR, if it was sent with MSG_EOR).
+//    Since the host side is a plain stream, records are framed on it with a 4 bytes, little
+//    endian, header: bit 31 is set for records ending with MSG_EOR, and the remaining bits
+//    hold the record length. The record data immediately follows its header.
+//    - Guest records are gathered until their last fragment comes in, and are only then
+//      written to the host stream, in one go.
+//    - Host records are split into as many packets as needed, the last one being flagged
+//      with VSOCK_FLAGS_SEQ_EOM. A host record larger than the peer buffer could never be
+//      received, so it resets the connection.
+//    The framing headers are not accounted for in the flow control counters.
+//
+use std::collections::VecDeque;
 use std::io::{ErrorKind, Read, Write};
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -87,7 +106,10 @@ use std::time::{Duration, Instant};
 
 use super::super::defs::uapi;
 use super::super::packet::VsockPacket;
-use super::super::{Result as VsockResult, VsockChannel, VsockEpollListener, VsockError};
+use super::super::{
+    Result as VsockResult, VsockChannel, VsockConnectionDirection, VsockConnectionInfo,
+    VsockEpollListener, VsockError,
+};
 use super::defs;
 use super::txbuf::TxBuf;
 use super::{ConnState, Error, PendingRx, PendingRxSet, Result};
@@ -113,6 +135,9 @@ pub struct VsockConnection<S: Read + Write + AsRawFd> {
     /// Total number of bytes that have been successfully written to `self.stream`, either
     /// directly, or flushed from `self.tx_buf`.
     fwd_cnt: Wrapping<u32>,
+    /// Total number of bytes that have been received from the peer (guest), i.e. the amount of
+    /// our buffer space the peer has used up so far.
+    tx_cnt: Wrapping<u32>,
     /// The amount of buffer space that the peer (guest) has allocated for this connection.
     peer_buf_alloc: u32,
     /// The total number of bytes that the peer has forwarded away.
@@ -128,6 +153,33 @@ pub struct VsockConnection<S: Read + Write + AsRawFd> {
     /// Instant when this connection should be scheduled for immediate termination, due to some
     /// timeout condition having been fulfilled.
     expiry: Option<Instant>,
+    /// Instant of the last packet exchanged with the peer, or event on the host stream. This
+    /// is used to find out which connections have been idle for the longest.
+    last_activity: Instant,
+    /// Which end initiated this connection.
+    direction: VsockConnectionDirection,
+    /// Total number of data bytes received from the peer (guest). Unlike `tx_cnt`, this
+    /// counter doesn't wrap, and is only used for reporting.
+    bytes_in: u64,
+    /// Total number of data bytes sent to the peer (guest).
+    bytes_out: u64,
+    /// Number of data packets dropped because the TX buffer was full.
+    dropped_pkts: u64,
+    /// The socket type, either VSOCK_TYPE_STREAM or VSOCK_TYPE_SEQPACKET.
+    type_: u16,
+    /// SEQPACKET only: the data of the record being received from the peer (guest), until
+    /// its last fragment comes in.
+    tx_record: Vec<u8>,
+    /// SEQPACKET only: the framed records that have been sent to the host stream, but not yet
+    /// entirely flushed out of the TX buffer, as the number of (header, data) bytes left.
+    tx_frames: VecDeque<(usize, usize)>,
+    /// SEQPACKET only: the header of the next record to be read from the host stream.
+    rx_hdr: [u8; defs::SEQPACKET_HDR_SIZE],
+    /// SEQPACKET only: the number of header bytes that have been read so far.
+    rx_hdr_len: usize,
+    /// SEQPACKET only: the record being read from the host stream, as the number of bytes
+    /// left to read, and whether it ends with MSG_EOR.
+    rx_record: Option<(usize, bool)>,
 }
 
 impl<S> VsockChannel for VsockConnection<S>
@@ -152,122 +204,14 @@ where
     ///    it is missing the data buffer.
     ///
     fn recv_pkt(&mut self, pkt: &mut VsockPacket) -> VsockResult<()> {
-        // Perform some generic initialization that is the same for any packet operation (e.g.
-        // source, destination, credit, etc).
-        self.init_pkt(pkt);
-
-        // If forceful termination is pending, there's no point in checking for anything else.
-        // It's dead, Jim.
-        if self.pending_rx.remove(PendingRx::Rst) {
-            pkt.set_op(uapi::VSOCK_OP_RST);
-            return Ok(());
-        }
-
-        // Next up: if we're due a connection confirmation, that's all we need to know to fill
-        // in this packet.
-        if self.pending_rx.remove(PendingRx::Response) {
-            self.state = ConnState::Established;
-            pkt.set_op(uapi::VSOCK_OP_RESPONSE);
-            return Ok(());
-        }
-
-        // Same thing goes for locally-initiated connections that need to yield a connection
-        // request.
-        if self.pending_rx.remove(PendingRx::Request) {
-            self.expiry =
-                Some(Instant::now() + Duration::from_millis(defs::CONN_REQUEST_TIMEOUT_MS));
-            pkt.set_op(uapi::VSOCK_OP_REQUEST);
-            return Ok(());
-        }
-
-        if self.pending_rx.remove(PendingRx::Rw) {
-            // We're due to produce a data packet, by reading the data from the host-side
-            // Unix socket.
-
-            match self.state {
-                // A data packet is only valid for established connections, and connections for
-                // which our peer has initiated a graceful shutdown, but can still receive data.
-                ConnState::Established | ConnState::PeerClosed(false, _) => (),
-                _ => {
-                    // Any other connection state is invalid at this point, and we need to kill it
-                    // with fire.
-                    pkt.set_op(uapi::VSOCK_OP_RST);
-                    return Ok(());
-                }
-            }
-
-            // Oh wait, before we start bringing in the big data, can our peer handle receiving so
-            // much bytey goodness?
-            if self.need_credit_update_from_peer() {
-                self.last_fwd_cnt_to_peer = self.fwd_cnt;
-                pkt.set_op(uapi::VSOCK_OP_CREDIT_REQUEST);
-                return Ok(());
-            }
-
-            let buf = pkt.buf_mut().ok_or(VsockError::PktBufMissing)?;
+        self.fill_rx_pkt(pkt)?;
+        self.last_activity = Instant::now();
 
-            // The maximum amount of data we can read in is limited by both the RX buffer size and
-            // the peer available buffer space.
-            let max_len = std::cmp::min(buf.len(), self.peer_avail_credit());
+        // Every packet carries our credit information, so whatever we've just yielded, the
+        // peer is now up to date on our buffer space situation.
+        self.last_fwd_cnt_to_peer = self.fwd_cnt;
 
-            // Read data from the stream straight to the RX buffer, for maximum throughput.
-            match self.stream.read(&mut buf[..max_len]) {
-                Ok(read_cnt) => {
-                    if read_cnt == 0 {
-                        // A 0-length read means the host stream was closed down. In that case,
-                        // we'll ask our peer to shut down the connection. We can neither send nor
-                        // receive any more data.
-                        self.state = ConnState::LocalClosed;
-                        self.expiry = Some(
-                            Instant::now() + Duration::from_millis(defs::CONN_SHUTDOWN_TIMEOUT_MS),
-                        );
-                        pkt.set_op(uapi::VSOCK_OP_SHUTDOWN)
-                            .set_flag(uapi::VSOCK_FLAGS_SHUTDOWN_RCV)
-                            .set_flag(uapi::VSOCK_FLAGS_SHUTDOWN_SEND);
-                    } else {
-                        // On a successful data read, we fill in the packet with the RW op, and
-                        // length of the read data.
-                        pkt.set_op(uapi::VSOCK_OP_RW).set_len(read_cnt as u32);
-                    }
-                    self.rx_cnt += Wrapping(pkt.len());
-                    self.last_fwd_cnt_to_peer = self.fwd_cnt;
-                    return Ok(());
-                }
-                Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                    // This shouldn't actually happen (receiving EWOULDBLOCK after EPOLLIN), but
-                    // apparently it does, so we need to handle it greacefully.
-                    warn!(
-                        "vsock: unexpected EWOULDBLOCK while reading from backing stream: \
-                         lp={}, pp={}, err={:?}",
-                        self.local_port, self.peer_port, err
-                    );
-                }
-                Err(err) => {
-                    // We are not expecting any other errors when reading from the underlying
-                    // stream. If any show up, we'll immediately kill this connection.
-                    error!(
-                        "vsock: error reading from backing stream: lp={}, pp={}, err={:?}",
-                        self.local_port, self.peer_port, err
-                    );
-                    pkt.set_op(uapi::VSOCK_OP_RST);
-                    self.last_fwd_cnt_to_peer = self.fwd_cnt;
-                    return Ok(());
-                }
-            };
-        }
-
-        // A credit update is basically a no-op, so we should only waste a perfectly fine RX
-        // buffer on it if we really have nothing else to say, hence we check for this RX
-        // indication last.
-        if self.pending_rx.remove(PendingRx::CreditUpdate) && !self.has_pending_rx() {
-            pkt.set_op(uapi::VSOCK_OP_CREDIT_UPDATE);
-            self.last_fwd_cnt_to_peer = self.fwd_cnt;
-            return Ok(());
-        }
-
-        // We've already checked for all conditions that would have produced a packet, so
-        // if we got to here, we don't know how to yield one.
-        Err(VsockError::NoData)
+        Ok(())
     }
 
     /// Deliver a guest-generated packet to this connection.
@@ -279,10 +223,24 @@ where
     /// always `Ok(())`: the packet has been consumed;
     ///
     fn send_pkt(&mut self, pkt: &VsockPacket) -> VsockResult<()> {
+        self.last_activity = Instant::now();
+
         // Update the peer credit information.
         self.peer_buf_alloc = pkt.buf_alloc();
         self.peer_fwd_cnt = Wrapping(pkt.fwd_cnt());
 
+        // A connection can't switch socket types halfway through.
+        if pkt.type_() != self.type_ {
+            warn!(
+                "vsock: unexpected packet type {} (lp={}, pp={})",
+                pkt.type_(),
+                self.local_port,
+                self.peer_port
+            );
+            self.kill();
+            return Ok(());
+        }
+
         match self.state {
             // Most frequent case: this is an established connection that needs to forward some
             // data to the host stream. Also works for a connection that has begun shutting
@@ -290,7 +248,12 @@ where
             ConnState::Established | ConnState::PeerClosed(_, false)
                 if pkt.op() == uapi::VSOCK_OP_RW =>
             {
-                if pkt.buf().is_none() {
+                let seqpacket = self.type_ == uapi::VSOCK_TYPE_SEQPACKET;
+
+                // An empty SEQPACKET data packet is still meaningful when it ends a record.
+                if pkt.buf().is_none()
+                    && !(seqpacket && pkt.flags() & uapi::VSOCK_FLAGS_SEQ_EOM != 0)
+                {
                     info!(
                         "vsock: dropping empty data packet from guest (lp={}, pp={}",
                         self.local_port, self.peer_port
@@ -298,9 +261,21 @@ where
                     return Ok(());
                 }
 
-                // Unwrapping here is safe, since we just checked `pkt.buf()` above.
-                let buf_slice = &pkt.buf().unwrap()[..(pkt.len() as usize)];
-                if let Err(err) = self.send_bytes(buf_slice) {
+                let buf_slice = match pkt.buf() {
+                    Some(buf) => &buf[..(pkt.len() as usize)],
+                    None => &[],
+                };
+                self.tx_cnt += Wrapping(buf_slice.len() as u32);
+                self.bytes_in += buf_slice.len() as u64;
+                let res = if seqpacket {
+                    self.send_record_bytes(buf_slice, pkt.flags())
+                } else {
+                    self.send_bytes(buf_slice)
+                };
+                if let Err(err) = res {
+                    if let Error::TxBufFull = err {
+                        self.dropped_pkts += 1;
+                    }
                     // If we can't write to the host stream, that's an unrecoverable error, so
                     // we'll terminate this connection.
                     warn!(
@@ -433,6 +408,8 @@ where
     /// Notify the connection about an event (or set of events) that it was interested in.
     ///
     fn notify(&mut self, evset: epoll::Events) {
+        self.last_activity = Instant::now();
+
         if evset.contains(epoll::Events::EPOLLIN) {
             // Data can be read from the host stream. Setting a Rw pending indication, so that
             // the muxer will know to call `recv_pkt()` later.
@@ -463,7 +440,7 @@ where
                     };
                     0
                 });
-            self.fwd_cnt += Wrapping(flushed as u32);
+            self.account_written(flushed);
 
             // If this connection was shutting down, but is waiting to drain the TX buffer
             // before forceful termination, the wait might be over.
@@ -491,6 +468,7 @@ where
         local_port: u32,
         peer_port: u32,
         peer_buf_alloc: u32,
+        type_: u16,
     ) -> Self {
         Self {
             local_cid,
@@ -501,12 +479,24 @@ where
             state: ConnState::PeerInit,
             tx_buf: TxBuf::new(),
             fwd_cnt: Wrapping(0),
+            tx_cnt: Wrapping(0),
             peer_buf_alloc,
             peer_fwd_cnt: Wrapping(0),
             rx_cnt: Wrapping(0),
             last_fwd_cnt_to_peer: Wrapping(0),
             pending_rx: PendingRxSet::from(PendingRx::Response),
             expiry: None,
+            last_activity: Instant::now(),
+            direction: VsockConnectionDirection::GuestInitiated,
+            bytes_in: 0,
+            bytes_out: 0,
+            dropped_pkts: 0,
+            type_,
+            tx_record: Vec::new(),
+            tx_frames: VecDeque::new(),
+            rx_hdr: [0u8; defs::SEQPACKET_HDR_SIZE],
+            rx_hdr_len: 0,
+            rx_record: None,
         }
     }
 
@@ -518,6 +508,7 @@ where
         peer_cid: u64,
         local_port: u32,
         peer_port: u32,
+        type_: u16,
     ) -> Self {
         Self {
             local_cid,
@@ -528,12 +519,24 @@ where
             state: ConnState::LocalInit,
             tx_buf: TxBuf::new(),
             fwd_cnt: Wrapping(0),
+            tx_cnt: Wrapping(0),
             peer_buf_alloc: 0,
             peer_fwd_cnt: Wrapping(0),
             rx_cnt: Wrapping(0),
             last_fwd_cnt_to_peer: Wrapping(0),
             pending_rx: PendingRxSet::from(PendingRx::Request),
             expiry: None,
+            last_activity: Instant::now(),
+            direction: VsockConnectionDirection::HostInitiated,
+            bytes_in: 0,
+            bytes_out: 0,
+            dropped_pkts: 0,
+            type_,
+            tx_record: Vec::new(),
+            tx_frames: VecDeque::new(),
+            rx_hdr: [0u8; defs::SEQPACKET_HDR_SIZE],
+            rx_hdr_len: 0,
+            rx_record: None,
         }
     }
 
@@ -563,6 +566,12 @@ where
         self.expiry
     }
 
+    /// Get the instant this connection was last active at.
+    ///
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
+
     /// Schedule the connection to be forcefully terminated ASAP (i.e. the next time the
     /// connection is asked to yield a packet, via `recv_pkt()`).
     ///
@@ -577,6 +586,32 @@ where
         self.state
     }
 
+    /// Get the socket type of this connection.
+    ///
+    pub fn type_(&self) -> u16 {
+        self.type_
+    }
+
+    /// Get the number of data packets dropped by this connection, due to its TX buffer
+    /// being full.
+    ///
+    pub fn dropped_pkts(&self) -> u64 {
+        self.dropped_pkts
+    }
+
+    /// Describe this connection, for reporting purposes.
+    ///
+    pub fn info(&self) -> VsockConnectionInfo {
+        VsockConnectionInfo {
+            local_port: self.local_port,
+            peer_port: self.peer_port,
+            direction: self.direction,
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            state: format!("{:?}", self.state),
+        }
+    }
+
     /// Send some raw, untracked, data straight to the underlying connected stream.
     /// Returns: number of bytes written, or the error describing the write failure.
     ///
@@ -588,6 +623,135 @@ where
         self.stream.write(buf).map_err(Error::StreamWrite)
     }
 
+    /// Fill in a packet for the peer (guest), according to the pending RX indications.
+    ///
+    /// See `recv_pkt()` for the possible return values.
+    ///
+    fn fill_rx_pkt(&mut self, pkt: &mut VsockPacket) -> VsockResult<()> {
+        // Perform some generic initialization that is the same for any packet operation (e.g.
+        // source, destination, credit, etc).
+        self.init_pkt(pkt);
+
+        // If forceful termination is pending, there's no point in checking for anything else.
+        // It's dead, Jim.
+        if self.pending_rx.remove(PendingRx::Rst) {
+            pkt.set_op(uapi::VSOCK_OP_RST);
+            return Ok(());
+        }
+
+        // Next up: if we're due a connection confirmation, that's all we need to know to fill
+        // in this packet.
+        if self.pending_rx.remove(PendingRx::Response) {
+            self.state = ConnState::Established;
+            pkt.set_op(uapi::VSOCK_OP_RESPONSE);
+            return Ok(());
+        }
+
+        // Same thing goes for locally-initiated connections that need to yield a connection
+        // request.
+        if self.pending_rx.remove(PendingRx::Request) {
+            self.expiry =
+                Some(Instant::now() + Duration::from_millis(defs::CONN_REQUEST_TIMEOUT_MS));
+            pkt.set_op(uapi::VSOCK_OP_REQUEST);
+            return Ok(());
+        }
+
+        if self.pending_rx.remove(PendingRx::Rw) {
+            // We're due to produce a data packet, by reading the data from the host-side
+            // Unix socket.
+
+            match self.state {
+                // A data packet is only valid for established connections, and connections for
+                // which our peer has initiated a graceful shutdown, but can still receive data.
+                ConnState::Established | ConnState::PeerClosed(false, _) => (),
+                _ => {
+                    // Any other connection state is invalid at this point, and we need to kill it
+                    // with fire.
+                    pkt.set_op(uapi::VSOCK_OP_RST);
+                    return Ok(());
+                }
+            }
+
+            // Oh wait, before we start bringing in the big data, can our peer handle receiving so
+            // much bytey goodness?
+            if self.need_credit_update_from_peer() {
+                pkt.set_op(uapi::VSOCK_OP_CREDIT_REQUEST);
+                return Ok(());
+            }
+
+            let buf = pkt.buf_mut().ok_or(VsockError::PktBufMissing)?;
+
+            // The maximum amount of data we can read in is limited by both the RX buffer size and
+            // the peer available buffer space.
+            let max_len = std::cmp::min(buf.len(), self.peer_avail_credit());
+
+            // Read data from the stream straight to the RX buffer, for maximum throughput.
+            let read_res = if self.type_ == uapi::VSOCK_TYPE_SEQPACKET {
+                self.read_record_bytes(&mut buf[..max_len])
+            } else {
+                self.stream
+                    .read(&mut buf[..max_len])
+                    .map(|read_cnt| (read_cnt, 0))
+            };
+            match read_res {
+                Ok((read_cnt, flags)) => {
+                    if read_cnt == 0 && flags == 0 {
+                        // A 0-length read means the host stream was closed down. In that case,
+                        // we'll ask our peer to shut down the connection. We can neither send nor
+                        // receive any more data.
+                        self.state = ConnState::LocalClosed;
+                        self.expiry = Some(
+                            Instant::now() + Duration::from_millis(defs::CONN_SHUTDOWN_TIMEOUT_MS),
+                        );
+                        pkt.set_op(uapi::VSOCK_OP_SHUTDOWN)
+                            .set_flag(uapi::VSOCK_FLAGS_SHUTDOWN_RCV)
+                            .set_flag(uapi::VSOCK_FLAGS_SHUTDOWN_SEND);
+                    } else {
+                        // On a successful data read, we fill in the packet with the RW op, and
+                        // length of the read data.
+                        pkt.set_op(uapi::VSOCK_OP_RW)
+                            .set_len(read_cnt as u32)
+                            .set_flags(flags);
+                        self.bytes_out += read_cnt as u64;
+                    }
+                    self.rx_cnt += Wrapping(pkt.len());
+                    return Ok(());
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    // This shouldn't actually happen (receiving EWOULDBLOCK after EPOLLIN), but
+                    // apparently it does, so we need to handle it greacefully.
+                    warn!(
+                        "vsock: unexpected EWOULDBLOCK while reading from backing stream: \
+                         lp={}, pp={}, err={:?}",
+                        self.local_port, self.peer_port, err
+                    );
+                }
+                Err(err) => {
+                    // We are not expecting any other errors when reading from the underlying
+                    // stream. If any show up, we'll immediately kill this connection.
+                    error!(
+                        "vsock: error reading from backing stream: lp={}, pp={}, err={:?}",
+                        self.local_port, self.peer_port, err
+                    );
+                    pkt.set_op(uapi::VSOCK_OP_RST);
+                    return Ok(());
+                }
+            };
+        }
+
+        // A credit update is basically a no-op, so we should only waste a perfectly fine RX
+        // buffer on it if we really have nothing else to say, hence we check for this RX
+        // indication last.
+        if self.pending_rx.remove(PendingRx::CreditUpdate) && !self.has_pending_rx() {
+            pkt.set_op(uapi::VSOCK_OP_CREDIT_UPDATE);
+            return Ok(());
+        }
+
+        // We've already checked for all conditions that would have produced a packet, so
+        // if we got to here, we don't know how to yield one.
+        Err(VsockError::NoData)
+    }
+
     /// Send some raw data (a byte-slice) to the host stream.
     ///
     /// Raw data can either be sent straight to the host stream, or to our TX buffer, if the
@@ -617,7 +781,7 @@ where
             }
         };
         // Move the "forwarded bytes" counter ahead by how much we were able to send out.
-        self.fwd_cnt += Wrapping(written as u32);
+        self.account_written(written);
 
         // If we couldn't write the whole slice, we'll need to push the remaining data to our
         // buffer.
@@ -628,12 +792,139 @@ where
         Ok(())
     }
 
+    /// Absorb a fragment of a SEQPACKET record sent by the peer (guest).
+    ///
+    /// Fragments are gathered until the last one (flagged with VSOCK_FLAGS_SEQ_EOM) comes in.
+    /// The whole record is then framed, and sent to the host stream, so that the host end never
+    /// gets to see a partial record.
+    ///
+    fn send_record_bytes(&mut self, buf: &[u8], flags: u32) -> Result<()> {
+        let record_len = self.tx_record.len() + buf.len();
+        if record_len > defs::SEQPACKET_MAX_RECORD_SIZE {
+            return Err(Error::RecordTooLarge(record_len));
+        }
+        self.tx_record.extend_from_slice(buf);
+
+        if flags & uapi::VSOCK_FLAGS_SEQ_EOM == 0 {
+            return Ok(());
+        }
+
+        let mut hdr = record_len as u32;
+        if flags & uapi::VSOCK_FLAGS_SEQ_EOR != 0 {
+            hdr |= defs::SEQPACKET_HDR_EOR;
+        }
+        let mut frame = Vec::with_capacity(defs::SEQPACKET_HDR_SIZE + record_len);
+        frame.extend_from_slice(&hdr.to_le_bytes());
+        frame.append(&mut self.tx_record);
+
+        self.tx_frames
+            .push_back((defs::SEQPACKET_HDR_SIZE, record_len));
+        self.send_bytes(&frame)
+    }
+
+    /// Read the next fragment of a SEQPACKET record from the host stream.
+    ///
+    /// The record header is read first, if no record is in progress. The last fragment of a
+    /// record gets flagged with VSOCK_FLAGS_SEQ_EOM (and VSOCK_FLAGS_SEQ_EOR, if the header
+    /// says so). Returns the number of bytes read along with the packet flags, `(0, 0)`
+    /// meaning the host stream was closed.
+    ///
+    fn read_record_bytes(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, u32)> {
+        if self.rx_record.is_none() {
+            while self.rx_hdr_len < defs::SEQPACKET_HDR_SIZE {
+                let read_cnt = self.stream.read(&mut self.rx_hdr[self.rx_hdr_len..])?;
+                if read_cnt == 0 {
+                    return Ok((0, 0));
+                }
+                self.rx_hdr_len += read_cnt;
+            }
+            self.rx_hdr_len = 0;
+
+            let hdr = u32::from_le_bytes(self.rx_hdr);
+            let len = (hdr & defs::SEQPACKET_HDR_LEN_MASK) as usize;
+            // The peer only hands out whole records, so one that doesn't fit in its buffer
+            // would never make it through.
+            if len > self.peer_buf_alloc as usize {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("record too large: {}", len),
+                ));
+            }
+            self.rx_record = Some((len, hdr & defs::SEQPACKET_HDR_EOR != 0));
+        }
+
+        // Unwrapping here is safe, since we just made sure a record is in progress.
+        let (len, eor) = self.rx_record.unwrap();
+        let read_cnt = if len == 0 {
+            0
+        } else {
+            let max_len = std::cmp::min(buf.len(), len);
+            match self.stream.read(&mut buf[..max_len])? {
+                0 => return Ok((0, 0)),
+                read_cnt => read_cnt,
+            }
+        };
+
+        if read_cnt < len {
+            self.rx_record = Some((len - read_cnt, eor));
+            return Ok((read_cnt, 0));
+        }
+
+        self.rx_record = None;
+        let mut flags = uapi::VSOCK_FLAGS_SEQ_EOM;
+        if eor {
+            flags |= uapi::VSOCK_FLAGS_SEQ_EOR;
+        }
+        Ok((read_cnt, flags))
+    }
+
+    /// Move the "forwarded bytes" counter ahead, after `written` bytes made it to the host
+    /// stream.
+    ///
+    /// SEQPACKET record headers are not part of the data the peer has sent us, so they are
+    /// left out.
+    ///
+    fn account_written(&mut self, mut written: usize) {
+        if self.type_ != uapi::VSOCK_TYPE_SEQPACKET {
+            self.fwd_cnt += Wrapping(written as u32);
+            return;
+        }
+
+        while written > 0 {
+            let frame = match self.tx_frames.front_mut() {
+                Some(frame) => frame,
+                None => break,
+            };
+            let hdr_len = std::cmp::min(frame.0, written);
+            frame.0 -= hdr_len;
+            written -= hdr_len;
+            let data_len = std::cmp::min(frame.1, written);
+            frame.1 -= data_len;
+            written -= data_len;
+            self.fwd_cnt += Wrapping(data_len as u32);
+            if *frame == (0, 0) {
+                self.tx_frames.pop_front();
+            }
+        }
+    }
+
     /// Check if the credit information the peer has last received from us is outdated.
     ///
+    /// That is the case when the peer believes it is (almost) out of credit, while we have
+    /// since freed up either a meaningful amount of buffer space, or all of it.
+    ///
     fn peer_needs_credit_update(&self) -> bool {
-        let peer_seen_free_buf =
-            Wrapping(defs::CONN_TX_BUF_SIZE) - (self.fwd_cnt - self.last_fwd_cnt_to_peer);
-        peer_seen_free_buf < Wrapping(defs::CONN_CREDIT_UPDATE_THRESHOLD)
+        // Buffer space freed up since the peer last heard from us.
+        let freed = (self.fwd_cnt - self.last_fwd_cnt_to_peer).0;
+        if freed == 0 {
+            return false;
+        }
+
+        // Buffer space the peer thinks it has used up, i.e. everything it has sent us since
+        // our last update.
+        let peer_seen_used_buf = (self.tx_cnt - self.last_fwd_cnt_to_peer).0;
+        peer_seen_used_buf > defs::CONN_TX_BUF_SIZE - defs::CONN_CREDIT_UPDATE_THRESHOLD
+            && (freed >= defs::CONN_CREDIT_UPDATE_THRESHOLD || self.fwd_cnt == self.tx_cnt)
     }
 
     /// Check if we need to ask the peer for a credit update before sending any more data its
@@ -664,7 +955,7 @@ where
             .set_dst_cid(self.peer_cid)
             .set_src_port(self.local_port)
             .set_dst_port(self.peer_port)
-            .set_type(uapi::VSOCK_TYPE_STREAM)
+            .set_type(self.type_)
             .set_buf_alloc(defs::CONN_TX_BUF_SIZE)
             .set_fwd_cnt(self.fwd_cnt.0)
     }
@@ -703,6 +994,8 @@ mod tests {
         read_state: StreamState,
         write_buf: Vec<u8>,
         write_state: StreamState,
+        // Number of bytes the stream accepts before blocking, if limited.
+        write_budget: Option<usize>,
     }
     impl TestStream {
         fn new() -> Self {
@@ -712,6 +1005,7 @@ mod tests {
                 write_state: StreamState::Ready,
                 read_buf: Vec::new(),
                 write_buf: Vec::new(),
+                write_budget: None,
             }
         }
         fn new_with_read_buf(buf: &[u8]) -> Self {
@@ -753,8 +1047,17 @@ mod tests {
                 StreamState::Closed => Err(IoError::new(ErrorKind::BrokenPipe, "EPIPE")),
                 StreamState::Error(kind) => Err(IoError::new(kind, "whatevs")),
                 StreamState::Ready => {
-                    self.write_buf.extend_from_slice(data);
-                    Ok(data.len())
+                    let len = match self.write_budget {
+                        Some(0) => return Err(IoError::new(ErrorKind::WouldBlock, "EAGAIN")),
+                        Some(ref mut budget) => {
+                            let len = std::cmp::min(*budget, data.len());
+                            *budget -= len;
+                            len
+                        }
+                        None => data.len(),
+                    };
+                    self.write_buf.extend_from_slice(&data[..len]);
+                    Ok(len)
                 }
                 StreamState::WouldBlock => Err(IoError::new(ErrorKind::WouldBlock, "EAGAIN")),
             }
@@ -779,7 +1082,7 @@ mod tests {
         }
     }
 
-    fn init_pkt(pkt: &mut VsockPacket, op: u16, len: u32) -> &mut VsockPacket {
+    fn init_pkt(pkt: &mut VsockPacket, type_: u16, op: u16, len: u32) -> &mut VsockPacket {
         for b in pkt.hdr_mut() {
             *b = 0;
         }
@@ -787,7 +1090,7 @@ mod tests {
             .set_dst_cid(LOCAL_CID)
             .set_src_port(PEER_PORT)
             .set_dst_port(LOCAL_PORT)
-            .set_type(uapi::VSOCK_TYPE_STREAM)
+            .set_type(type_)
             .set_buf_alloc(PEER_BUF_ALLOC)
             .set_op(op)
             .set_len(len)
@@ -814,6 +1117,14 @@ mod tests {
         }
 
         fn new(conn_state: ConnState) -> Self {
+            Self::new_with_type(conn_state, uapi::VSOCK_TYPE_STREAM)
+        }
+
+        fn new_seqpacket_established() -> Self {
+            Self::new_with_type(ConnState::Established, uapi::VSOCK_TYPE_SEQPACKET)
+        }
+
+        fn new_with_type(conn_state: ConnState, type_: u16) -> Self {
             let vsock_test_ctx = TestContext::new();
             let mut handler_ctx = vsock_test_ctx.create_epoll_handler_context();
             let stream = TestStream::new();
@@ -832,9 +1143,10 @@ mod tests {
                     LOCAL_PORT,
                     PEER_PORT,
                     PEER_BUF_ALLOC,
+                    type_,
                 ),
                 ConnState::LocalInit => VsockConnection::<TestStream>::new_local_init(
-                    stream, LOCAL_CID, PEER_CID, LOCAL_PORT, PEER_PORT,
+                    stream, LOCAL_CID, PEER_CID, LOCAL_PORT, PEER_PORT, type_,
                 ),
                 ConnState::Established => {
                     let mut conn = VsockConnection::<TestStream>::new_peer_init(
@@ -844,6 +1156,7 @@ mod tests {
                         LOCAL_PORT,
                         PEER_PORT,
                         PEER_BUF_ALLOC,
+                        type_,
                     );
                     assert!(conn.has_pending_rx());
                     conn.recv_pkt(&mut pkt).unwrap();
@@ -889,7 +1202,7 @@ mod tests {
         }
 
         fn init_pkt(&mut self, op: u16, len: u32) -> &mut VsockPacket {
-            init_pkt(&mut self.pkt, op, len)
+            init_pkt(&mut self.pkt, self.conn.type_, op, len)
         }
 
         fn init_data_pkt(&mut self, data: &[u8]) -> &VsockPacket {
@@ -1111,12 +1424,13 @@ mod tests {
         // Force a stale state, where the peer hasn't been updated on our credit situation.
         ctx.conn.last_fwd_cnt_to_peer = Wrapping(0);
 
-        // Since a credit update token is sent when the fwd_cnt value exceeds
-        // CONN_TX_BUF_SIZE - CONN_CREDIT_UPDATE_THRESHOLD, we initialize
-        // fwd_cnt at 6 bytes below the threshold.
+        // Since a credit update token is sent when the data received since the last update
+        // exceeds CONN_TX_BUF_SIZE - CONN_CREDIT_UPDATE_THRESHOLD, we initialize both tx_cnt
+        // and fwd_cnt (all the data got forwarded) at 6 bytes below the threshold.
         let initial_fwd_cnt =
             csm_defs::CONN_TX_BUF_SIZE as u32 - csm_defs::CONN_CREDIT_UPDATE_THRESHOLD as u32 - 6;
         ctx.conn.fwd_cnt = Wrapping(initial_fwd_cnt);
+        ctx.conn.tx_cnt = Wrapping(initial_fwd_cnt);
 
         // Use a 4-byte packet for triggering the credit update threshold.
         let data = &[1, 2, 3, 4];
@@ -1138,6 +1452,55 @@ mod tests {
         assert_eq!(ctx.conn.fwd_cnt, ctx.conn.last_fwd_cnt_to_peer);
     }
 
+    #[test]
+    fn test_credit_update_slow_stream() {
+        // Push a large amount of data through a host stream that only drains a few KiB at a
+        // time. The peer is only sending data it has credit for, based on what it has learnt
+        // from the packets it received, so the transfer can only go through if the connection
+        // proactively lets it know about the buffer space freed up along the way.
+        const TOTAL_LEN: usize = 1024 * 1024;
+        const DRAIN_LEN: usize = 8 * 1024;
+
+        let mut ctx = CsmTestContext::new_established();
+        let mut stream = TestStream::new();
+        stream.write_budget = Some(0);
+        ctx.set_stream(stream);
+
+        let data = vec![0xa5u8; ctx.pkt.buf().unwrap().len()];
+        let mut peer_buf_alloc = ctx.pkt.buf_alloc();
+        let mut peer_fwd_cnt = Wrapping(ctx.pkt.fwd_cnt());
+        let mut sent_cnt = Wrapping(0u32);
+
+        while (sent_cnt.0 as usize) < TOTAL_LEN {
+            let credit = (Wrapping(peer_buf_alloc) - (sent_cnt - peer_fwd_cnt)).0 as usize;
+            if credit >= data.len() {
+                ctx.init_data_pkt(&data);
+                ctx.send();
+                assert_eq!(ctx.conn.state, ConnState::Established);
+                sent_cnt += Wrapping(data.len() as u32);
+                continue;
+            }
+
+            // The peer is out of credit, so let the host stream drain some data. Once it did,
+            // the connection must not keep the peer waiting for a credit update.
+            assert!(!ctx.conn.tx_buf.is_empty());
+            ctx.conn.stream.write_budget = Some(DRAIN_LEN);
+            ctx.notify_epollout();
+            assert!(ctx.conn.has_pending_rx());
+            ctx.recv();
+            assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_CREDIT_UPDATE);
+            assert_eq!(ctx.pkt.fwd_cnt(), ctx.conn.fwd_cnt.0);
+            peer_buf_alloc = ctx.pkt.buf_alloc();
+            peer_fwd_cnt = Wrapping(ctx.pkt.fwd_cnt());
+        }
+
+        ctx.conn.stream.write_budget = None;
+        ctx.notify_epollout();
+        assert!(ctx.conn.tx_buf.is_empty());
+        assert_eq!(ctx.conn.stream.write_buf.len(), TOTAL_LEN);
+        assert_eq!(ctx.conn.fwd_cnt, ctx.conn.tx_cnt);
+    }
+
     #[test]
     fn test_tx_buffering() {
         // Test case:
@@ -1246,4 +1609,157 @@ mod tests {
         ctx.recv();
         assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
     }
+
+    #[test]
+    fn test_seqpacket_tx_record() {
+        let mut ctx = CsmTestContext::new_seqpacket_established();
+        let frag_len = ctx.pkt.buf().unwrap().len();
+
+        // Send a record that spans a couple of packets. Nothing should make it to the host
+        // stream until the last fragment comes in.
+        let record: Vec<u8> = (0..(2 * frag_len + 100)).map(|i| i as u8).collect();
+        for frag in record.chunks(frag_len) {
+            ctx.init_data_pkt(frag);
+            if frag.len() < frag_len {
+                ctx.pkt
+                    .set_flag(uapi::VSOCK_FLAGS_SEQ_EOM)
+                    .set_flag(uapi::VSOCK_FLAGS_SEQ_EOR);
+            } else {
+                assert!(ctx.conn.stream.write_buf.is_empty());
+            }
+            ctx.send();
+        }
+        assert_eq!(ctx.conn.state, ConnState::Established);
+
+        // The host stream should now hold the framed record, and only the record data should
+        // have been accounted for as forwarded.
+        let hdr = record.len() as u32 | csm_defs::SEQPACKET_HDR_EOR;
+        assert_eq!(
+            ctx.conn.stream.write_buf[..csm_defs::SEQPACKET_HDR_SIZE],
+            hdr.to_le_bytes()
+        );
+        assert_eq!(
+            ctx.conn.stream.write_buf[csm_defs::SEQPACKET_HDR_SIZE..],
+            record[..]
+        );
+        assert_eq!(ctx.conn.fwd_cnt().0 as usize, record.len());
+
+        // An empty record is still a record.
+        ctx.init_pkt(uapi::VSOCK_OP_RW, 0)
+            .set_flag(uapi::VSOCK_FLAGS_SEQ_EOM);
+        ctx.send();
+        assert_eq!(
+            ctx.conn.stream.write_buf[(csm_defs::SEQPACKET_HDR_SIZE + record.len())..],
+            0u32.to_le_bytes()
+        );
+        assert_eq!(ctx.conn.fwd_cnt().0 as usize, record.len());
+    }
+
+    #[test]
+    fn test_seqpacket_tx_buffering() {
+        let mut ctx = CsmTestContext::new_seqpacket_established();
+
+        // Only let the header and part of the record through.
+        let mut stream = TestStream::new();
+        stream.write_budget = Some(csm_defs::SEQPACKET_HDR_SIZE + 2);
+        ctx.set_stream(stream);
+
+        let data = &[1, 2, 3, 4, 5, 6];
+        ctx.init_data_pkt(data);
+        ctx.pkt.set_flag(uapi::VSOCK_FLAGS_SEQ_EOM);
+        ctx.send();
+        assert_eq!(ctx.conn.fwd_cnt().0, 2);
+        assert_eq!(ctx.conn.tx_buf.len(), data.len() - 2);
+
+        // Flushing the TX buffer should account for the rest of the record data.
+        ctx.conn.stream.write_budget = None;
+        ctx.notify_epollout();
+        assert!(ctx.conn.tx_buf.is_empty());
+        assert!(ctx.conn.tx_frames.is_empty());
+        assert_eq!(ctx.conn.fwd_cnt().0 as usize, data.len());
+        assert_eq!(
+            ctx.conn.stream.write_buf[csm_defs::SEQPACKET_HDR_SIZE..],
+            data[..]
+        );
+    }
+
+    #[test]
+    fn test_seqpacket_tx_record_too_large() {
+        let mut ctx = CsmTestContext::new_seqpacket_established();
+        let data = vec![0u8; ctx.pkt.buf().unwrap().len()];
+
+        // Keep on sending fragments of a never ending record.
+        for _i in 0..(csm_defs::SEQPACKET_MAX_RECORD_SIZE / data.len()) {
+            ctx.init_data_pkt(data.as_slice());
+            ctx.send();
+        }
+        assert_eq!(ctx.conn.state, ConnState::Established);
+        ctx.init_data_pkt(data.as_slice());
+        ctx.send();
+
+        assert_eq!(ctx.conn.state, ConnState::Killed);
+        assert!(ctx.conn.stream.write_buf.is_empty());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+    }
+
+    #[test]
+    fn test_seqpacket_rx_record() {
+        let mut ctx = CsmTestContext::new_seqpacket_established();
+        let frag_len = ctx.pkt.buf().unwrap().len();
+
+        // Queue up a record larger than a single RX buffer, followed by a small one.
+        let record: Vec<u8> = (0..(2 * frag_len + 100)).map(|i| i as u8).collect();
+        let mut host_data = (record.len() as u32).to_le_bytes().to_vec();
+        host_data.extend_from_slice(&record);
+        host_data.extend_from_slice(&(3 | csm_defs::SEQPACKET_HDR_EOR).to_le_bytes());
+        host_data.extend_from_slice(&[7, 8, 9]);
+        ctx.set_stream(TestStream::new_with_read_buf(&host_data));
+
+        // The first record should come through in fragments, with only the last one flagged as
+        // the end of the message.
+        let mut received = Vec::new();
+        for i in 0..3 {
+            ctx.notify_epollin();
+            ctx.recv();
+            assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+            assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+            let flags = if i == 2 { uapi::VSOCK_FLAGS_SEQ_EOM } else { 0 };
+            assert_eq!(ctx.pkt.flags(), flags);
+            received.extend_from_slice(&ctx.pkt.buf().unwrap()[..ctx.pkt.len() as usize]);
+        }
+        assert_eq!(received, record);
+
+        // The second record fits in a single packet, and ends with MSG_EOR.
+        ctx.notify_epollin();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(
+            ctx.pkt.flags(),
+            uapi::VSOCK_FLAGS_SEQ_EOM | uapi::VSOCK_FLAGS_SEQ_EOR
+        );
+        assert_eq!(ctx.pkt.buf().unwrap()[..ctx.pkt.len() as usize], [7, 8, 9]);
+    }
+
+    #[test]
+    fn test_seqpacket_rx_record_too_large() {
+        let mut ctx = CsmTestContext::new_seqpacket_established();
+        let host_data = (PEER_BUF_ALLOC + 1).to_le_bytes();
+        ctx.set_stream(TestStream::new_with_read_buf(&host_data));
+
+        // The peer would never be able to take in this record, so the connection gets reset.
+        ctx.notify_epollin();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+    }
+
+    #[test]
+    fn test_seqpacket_type_mismatch() {
+        let mut ctx = CsmTestContext::new_seqpacket_established();
+        init_pkt(&mut ctx.pkt, uapi::VSOCK_TYPE_STREAM, uapi::VSOCK_OP_RW, 0);
+        ctx.send();
+        assert_eq!(ctx.conn.state, ConnState::Killed);
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+    }
 }
@@ -22,6 +22,19 @@ pub mod defs {
 
     /// Connection graceful shutdown timeout, in millis.
     pub const CONN_SHUTDOWN_TIMEOUT_MS: u64 = 2000;
+
+    /// Size of the header framing each SEQPACKET record on the host stream.
+    pub const SEQPACKET_HDR_SIZE: usize = 4;
+
+    /// SEQPACKET record header bit, set when the record ends with MSG_EOR.
+    pub const SEQPACKET_HDR_EOR: u32 = 1 << 31;
+
+    /// SEQPACKET record header mask, selecting the record length.
+    pub const SEQPACKET_HDR_LEN_MASK: u32 = !SEQPACKET_HDR_EOR;
+
+    /// Largest SEQPACKET record that can be sent from the guest, so that the framed record
+    /// always fits in the connection TX buffer.
+    pub const SEQPACKET_MAX_RECORD_SIZE: usize = CONN_TX_BUF_SIZE as usize - SEQPACKET_HDR_SIZE;
 }
 
 #[derive(Debug)]
@@ -32,6 +45,8 @@ pub enum Error {
     TxBufFlush(std::io::Error),
     /// An I/O error occurred, when attempting to write data to the host-side stream.
     StreamWrite(std::io::Error),
+    /// The guest sent a SEQPACKET record larger than `SEQPACKET_MAX_RECORD_SIZE`.
+    RecordTooLarge(usize),
 }
 
 type Result<T> = std::result::Result<T, Error>;
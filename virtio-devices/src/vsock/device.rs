@@ -8,7 +8,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
-use super::{VsockBackend, VsockPacket};
+use super::{VsockBackend, VsockInfo, VsockPacket};
 use crate::Error as DeviceError;
 use crate::VirtioInterrupt;
 use crate::{
@@ -53,6 +53,8 @@ use vm_migration::{
 use vmm_sys_util::eventfd::EventFd;
 
 const QUEUE_SIZE: u16 = 256;
+// Feature bit for SOCK_SEQPACKET support
+const VIRTIO_VSOCK_F_SEQPACKET: u64 = 1;
 const NUM_QUEUES: usize = 3;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
 
@@ -426,7 +428,9 @@ where
         backend: B,
         iommu: bool,
     ) -> io::Result<Vsock<B>> {
-        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_IN_ORDER;
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1
+            | 1u64 << VIRTIO_F_IN_ORDER
+            | 1u64 << VIRTIO_VSOCK_F_SEQPACKET;
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -448,6 +452,11 @@ where
         })
     }
 
+    /// Report the connections currently tracked by the backend.
+    pub fn info(&self) -> VsockInfo {
+        self.backend.read().unwrap().info()
+    }
+
     fn state(&self) -> VsockState {
         VsockState {
             avail_features: self.avail_features,
@@ -665,15 +674,18 @@ impl<B> Migratable for Vsock<B> where B: VsockBackend + Sync + 'static {}
 
 #[cfg(test)]
 mod tests {
-    use super::super::tests::{NoopVirtioInterrupt, TestContext};
+    use super::super::tests::TestContext;
     use super::super::*;
     use super::*;
+    use crate::testing::NoopVirtioInterrupt;
     use crate::vsock::device::{BACKEND_EVENT, EVT_QUEUE_EVENT, RX_QUEUE_EVENT, TX_QUEUE_EVENT};
 
     #[test]
     fn test_virtio_device() {
         let mut ctx = TestContext::new();
-        let avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_IN_ORDER;
+        let avail_features = 1u64 << VIRTIO_F_VERSION_1
+            | 1u64 << VIRTIO_F_IN_ORDER
+            | 1u64 << VIRTIO_VSOCK_F_SEQPACKET;
         let device_features = avail_features;
         let driver_features: u64 = avail_features | 1 | (1 << 32);
         let device_pages = [
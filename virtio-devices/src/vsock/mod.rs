@@ -52,12 +52,18 @@ mod defs {
         pub const VSOCK_FLAGS_SHUTDOWN_RCV: u32 = 1;
         /// Valid with a VSOCK_OP_SHUTDOWN packet: the packet sender will send no more data.
         pub const VSOCK_FLAGS_SHUTDOWN_SEND: u32 = 2;
+        /// Valid with a SEQPACKET VSOCK_OP_RW packet: this is the last packet of a message.
+        pub const VSOCK_FLAGS_SEQ_EOM: u32 = 1;
+        /// Valid with a SEQPACKET VSOCK_OP_RW packet: the message was sent with MSG_EOR.
+        pub const VSOCK_FLAGS_SEQ_EOR: u32 = 2;
 
         /// Vsock packet type.
         /// Defined in `/include/uapi/linux/virtio_vsock.h`.
         ///
-        /// Stream / connection-oriented packet (the only currently valid type).
+        /// Stream / connection-oriented packet.
         pub const VSOCK_TYPE_STREAM: u16 = 1;
+        /// Sequential packet / connection-oriented packet, preserving message boundaries.
+        pub const VSOCK_TYPE_SEQPACKET: u16 = 2;
 
         pub const VSOCK_HOST_CID: u64 = 2;
     }
@@ -153,14 +159,47 @@ pub trait VsockChannel {
 /// sendable through a mpsc channel (the latter due to how `vmm::EpollContext` works).
 /// Currently, the only implementation we have is `crate::virtio::unix::muxer::VsockMuxer`, which
 /// translates guest-side vsock connections to host-side Unix domain socket connections.
-pub trait VsockBackend: VsockChannel + VsockEpollListener + Send {}
+pub trait VsockBackend: VsockChannel + VsockEpollListener + Send {
+    /// Report the connections currently tracked by the backend.
+    fn info(&self) -> VsockInfo;
+}
+
+/// The end that initiated a vsock connection.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum VsockConnectionDirection {
+    HostInitiated,
+    GuestInitiated,
+}
+
+/// Description of a single vsock connection, as reported through the API.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VsockConnectionInfo {
+    /// Host-side port.
+    pub local_port: u32,
+    /// Guest-side port.
+    pub peer_port: u32,
+    pub direction: VsockConnectionDirection,
+    /// Number of data bytes received from the guest.
+    pub bytes_in: u64,
+    /// Number of data bytes sent to the guest.
+    pub bytes_out: u64,
+    /// Connection state machine state.
+    pub state: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct VsockInfo {
+    pub connections: Vec<VsockConnectionInfo>,
+    /// Number of data packets dropped because a connection TX buffer was full.
+    pub dropped_packets: u64,
+}
 
 #[cfg(test)]
 mod tests {
     use super::device::{VsockEpollHandler, RX_QUEUE_EVENT, TX_QUEUE_EVENT};
     use super::packet::VSOCK_PKT_HDR_SIZE;
     use super::*;
-    use crate::device::{VirtioInterrupt, VirtioInterruptType};
+    use crate::testing::NoopVirtioInterrupt;
     use libc::EFD_NONBLOCK;
     use std::os::unix::io::AsRawFd;
     use std::path::PathBuf;
@@ -168,22 +207,9 @@ mod tests {
     use std::sync::{Arc, RwLock};
     use vm_memory::{GuestAddress, GuestMemoryAtomic, GuestMemoryMmap};
     use vm_virtio::queue::testing::VirtQueue as GuestQ;
-    use vm_virtio::queue::Queue;
     use vm_virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
     use vmm_sys_util::eventfd::EventFd;
 
-    pub struct NoopVirtioInterrupt {}
-
-    impl VirtioInterrupt for NoopVirtioInterrupt {
-        fn trigger(
-            &self,
-            _int_type: &VirtioInterruptType,
-            _queue: Option<&Queue>,
-        ) -> std::result::Result<(), std::io::Error> {
-            Ok(())
-        }
-    }
-
     pub struct TestBackend {
         pub evfd: EventFd,
         pub rx_err: Option<VsockError>,
@@ -249,7 +275,11 @@ mod tests {
             self.evset = Some(evset);
         }
     }
-    impl VsockBackend for TestBackend {}
+    impl VsockBackend for TestBackend {
+        fn info(&self) -> VsockInfo {
+            VsockInfo::default()
+        }
+    }
 
     pub struct TestContext {
         pub cid: u64,
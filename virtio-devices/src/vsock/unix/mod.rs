@@ -16,9 +16,6 @@ pub use muxer::VsockMuxer as VsockUnixBackend;
 pub use Error as VsockUnixError;
 
 mod defs {
-    /// Maximum number of established connections that we can handle.
-    pub const MAX_CONNECTIONS: usize = 1023;
-
     /// Size of the muxer RX packet queue.
     pub const MUXER_RXQ_SIZE: usize = 256;
 
@@ -50,6 +47,8 @@ pub enum Error {
     UnixRead(std::io::Error),
     /// Muxer connection limit reached.
     TooManyConnections,
+    /// The muxer connection limit must be non zero.
+    InvalidMaxConnections,
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -11,7 +11,8 @@
 /// 1. Vsock connection multiplexer:
 ///    It's the muxer's job to create, manage, and terminate `VsockConnection` objects. The
 ///    muxer also routes packets to their owning connections. It does so via a connection
-///    `HashMap`, keyed by what is basically a (host_port, guest_port) tuple.
+///    `HashMap`, keyed by what is basically a (host_cid, host_port, guest_cid, guest_port)
+///    tuple.
 ///    Vsock packet traffic needs to be inspected, in order to detect connection request
 ///    packets (leading to the creation of a new connection), and connection reset packets
 ///    (leading to the termination of an existing connection). All other packets, though, must
@@ -23,7 +24,10 @@
 ///    2. Data is available for reading from a newly-accepted host-initiated connection (i.e.
 ///       the host is ready to issue a vsock connection request, informing us of the
 ///       destination port to which it wants to connect);
-///    3. Some event was triggered for a connected Unix socket, that belongs to a
+///    3. A new host-initiated connection is ready to be accepted from one of the per-port
+///       listening host Unix sockets ("<host socket path>_<port>"), meaning the host wants to
+///       connect to that port on the guest side, without issuing any connection request;
+///    4. Some event was triggered for a connected Unix socket, that belongs to a
 ///       `VsockConnection`.
 ///    The muxer gets notified about all of these events, because, as a `VsockEpollListener`
 ///    implementor, it gets to register a nested epoll FD into the main VMM epolling loop. All
@@ -31,17 +35,29 @@
 ///    To route all these events to their handlers, the muxer uses another `HashMap` object,
 ///    mapping `RawFd`s to `EpollListener`s.
 ///
+/// The number of connections the muxer keeps track of is bounded, since each of them holds a
+/// host socket and buffers. Once the limit is reached, new connections are refused (with an
+/// RST, for guest-initiated ones), unless an idle timeout has been set, and the least recently
+/// active connection has been idle for longer than that. That connection then gets evicted, to
+/// make room for the new one.
+///
+/// Since the per-port host Unix sockets are the same ones guest-initiated connections are
+/// forwarded to, a guest connection to one of the ports the muxer listens for is refused.
+/// The per-port sockets, as well as the main host socket, are removed from the file system
+/// when the muxer is dropped.
+///
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{self, Read};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
 
 use super::super::csm::ConnState;
 use super::super::defs::uapi;
 use super::super::packet::VsockPacket;
 use super::super::{
-    Result as VsockResult, VsockBackend, VsockChannel, VsockEpollListener, VsockError,
+    Result as VsockResult, VsockBackend, VsockChannel, VsockEpollListener, VsockError, VsockInfo,
 };
 use super::defs;
 use super::muxer_killq::MuxerKillQ;
@@ -50,22 +66,42 @@ use super::MuxerConnection;
 use super::{Error, Result};
 
 /// A unique identifier of a `MuxerConnection` object. Connections are stored in a hash map,
-/// keyed by a `ConnMapKey` object.
+/// keyed by a `ConnMapKey` object. The key covers both endpoints (CID and port), so that a
+/// packet is only ever routed to (and accounted against) the connection it belongs to.
 ///
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct ConnMapKey {
+    local_cid: u64,
     local_port: u32,
+    peer_cid: u64,
     peer_port: u32,
 }
 
+impl ConnMapKey {
+    /// Build the key of the connection a guest-generated packet is addressed to.
+    ///
+    fn from_peer_pkt(pkt: &VsockPacket) -> Self {
+        ConnMapKey {
+            local_cid: pkt.dst_cid(),
+            local_port: pkt.dst_port(),
+            peer_cid: pkt.src_cid(),
+            peer_port: pkt.src_port(),
+        }
+    }
+}
+
 /// A muxer RX queue item.
 ///
 #[derive(Clone, Copy, Debug)]
 pub enum MuxerRx {
     /// The packet must be fetched from the connection identified by `ConnMapKey`.
     ConnRx(ConnMapKey),
-    /// The muxer must produce an RST packet.
-    RstPkt { local_port: u32, peer_port: u32 },
+    /// The muxer must produce an RST packet, of the given socket type.
+    RstPkt {
+        local_port: u32,
+        peer_port: u32,
+        type_: u16,
+    },
 }
 
 /// An epoll listener, registered under the muxer's nested epoll FD.
@@ -80,6 +116,8 @@ enum EpollListener {
     },
     /// A listener interested in new host-initiated connections.
     HostSock,
+    /// A listener interested in new host-initiated connections, to the given guest port.
+    PortSock(u32),
     /// A listener interested in reading host "connect <port>" commands from a freshly
     /// connected host socket.
     LocalStream(UnixStream),
@@ -115,6 +153,16 @@ pub struct VsockMuxer {
     local_port_set: HashSet<u32>,
     /// The last used host-side port.
     local_port_last: u32,
+    /// Maximum number of connections the muxer keeps track of.
+    max_connections: usize,
+    /// How long a connection must have been idle for, before it can be evicted to make room
+    /// for a new one. No connection gets evicted if not set.
+    idle_timeout: Option<Duration>,
+    /// Number of data packets dropped by connections which have since been removed.
+    dropped_pkts: u64,
+    /// The per-port Unix sockets, through which host-initiated connections to a specific
+    /// guest port are accepted, keyed by that port.
+    port_socks: HashMap<u32, UnixListener>,
 }
 
 impl VsockChannel for VsockMuxer {
@@ -140,6 +188,7 @@ impl VsockChannel for VsockMuxer {
                 MuxerRx::RstPkt {
                     local_port,
                     peer_port,
+                    type_,
                 } => {
                     pkt.set_op(uapi::VSOCK_OP_RST)
                         .set_src_cid(uapi::VSOCK_HOST_CID)
@@ -147,7 +196,7 @@ impl VsockChannel for VsockMuxer {
                         .set_src_port(local_port)
                         .set_dst_port(peer_port)
                         .set_len(0)
-                        .set_type(uapi::VSOCK_TYPE_STREAM)
+                        .set_type(type_)
                         .set_flags(0)
                         .set_buf_alloc(0)
                         .set_fwd_cnt(0);
@@ -177,7 +226,9 @@ impl VsockChannel for VsockMuxer {
                 //
                 if pkt.op() == uapi::VSOCK_OP_RST {
                     self.remove_connection(ConnMapKey {
+                        local_cid: pkt.src_cid(),
                         local_port: pkt.src_port(),
+                        peer_cid: pkt.dst_cid(),
                         peer_port: pkt.dst_port(),
                     });
                 }
@@ -200,10 +251,7 @@ impl VsockChannel for VsockMuxer {
     /// returned to the guest vsock driver.
     ///
     fn send_pkt(&mut self, pkt: &VsockPacket) -> VsockResult<()> {
-        let conn_key = ConnMapKey {
-            local_port: pkt.dst_port(),
-            peer_port: pkt.src_port(),
-        };
+        let conn_key = ConnMapKey::from_peer_pkt(pkt);
 
         debug!(
             "vsock: muxer.send[rxq.len={}]: {:?}",
@@ -211,10 +259,11 @@ impl VsockChannel for VsockMuxer {
             pkt.hdr()
         );
 
-        // If this packet has an unsupported type (!=stream), we must send back an RST.
+        // If this packet has an unsupported type (neither stream nor seqpacket), we must send
+        // back an RST.
         //
-        if pkt.type_() != uapi::VSOCK_TYPE_STREAM {
-            self.enq_rst(pkt.dst_port(), pkt.src_port());
+        if pkt.type_() != uapi::VSOCK_TYPE_STREAM && pkt.type_() != uapi::VSOCK_TYPE_SEQPACKET {
+            self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_());
             return Ok(());
         }
 
@@ -228,16 +277,26 @@ impl VsockChannel for VsockMuxer {
             return Ok(());
         }
 
+        // Likewise, a packet claiming to originate from any other CID than our guest's can't
+        // belong to any of our connections.
+        if pkt.src_cid() != self.cid {
+            info!(
+                "vsock: dropping guest packet from unknown CID: {:?}",
+                pkt.hdr()
+            );
+            return Ok(());
+        }
+
         if !self.conn_map.contains_key(&conn_key) {
             // This packet can't be routed to any active connection (based on its src and dst
-            // ports).  The only orphan / unroutable packets we know how to handle are
+            // addresses).  The only orphan / unroutable packets we know how to handle are
             // connection requests.
             if pkt.op() == uapi::VSOCK_OP_REQUEST {
                 // Oh, this is a connection request!
                 self.handle_peer_request_pkt(&pkt);
             } else {
                 // Send back an RST, to let the drive know we weren't expecting this packet.
-                self.enq_rst(pkt.dst_port(), pkt.src_port());
+                self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_());
             }
             return Ok(());
         }
@@ -325,12 +384,37 @@ impl VsockEpollListener for VsockMuxer {
     }
 }
 
-impl VsockBackend for VsockMuxer {}
+impl VsockBackend for VsockMuxer {
+    fn info(&self) -> VsockInfo {
+        let mut connections: Vec<_> = self.conn_map.values().map(|conn| conn.info()).collect();
+        connections.sort_by_key(|info| (info.local_port, info.peer_port));
+
+        VsockInfo {
+            connections,
+            dropped_packets: self.dropped_pkts
+                + self
+                    .conn_map
+                    .values()
+                    .map(|conn| conn.dropped_pkts())
+                    .sum::<u64>(),
+        }
+    }
+}
 
 impl VsockMuxer {
     /// Muxer constructor.
     ///
-    pub fn new(cid: u64, host_sock_path: String) -> Result<Self> {
+    pub fn new(
+        cid: u64,
+        host_sock_path: String,
+        max_connections: usize,
+        idle_timeout: Option<Duration>,
+        listen_ports: &[u32],
+    ) -> Result<Self> {
+        if max_connections == 0 {
+            return Err(Error::InvalidMaxConnections);
+        }
+
         // Create the nested epoll FD. This FD will be added to the VMM `EpollContext`, at
         // device activation time.
         let epoll_fd = epoll::create(true).map_err(Error::EpollFdCreate)?;
@@ -349,14 +433,29 @@ impl VsockMuxer {
             host_sock_path,
             epoll_file,
             rxq: MuxerRxQ::new(),
-            conn_map: HashMap::with_capacity(defs::MAX_CONNECTIONS),
-            listener_map: HashMap::with_capacity(defs::MAX_CONNECTIONS + 1),
+            conn_map: HashMap::with_capacity(max_connections),
+            listener_map: HashMap::with_capacity(max_connections + listen_ports.len() + 1),
             killq: MuxerKillQ::new(),
             local_port_last: (1u32 << 30) - 1,
-            local_port_set: HashSet::with_capacity(defs::MAX_CONNECTIONS),
+            local_port_set: HashSet::with_capacity(max_connections),
+            max_connections,
+            idle_timeout,
+            dropped_pkts: 0,
+            port_socks: HashMap::with_capacity(listen_ports.len()),
         };
 
         muxer.add_listener(muxer.host_sock.as_raw_fd(), EpollListener::HostSock)?;
+
+        // Open/bind/listen on the per-port host Unix sockets, so that the host can connect to
+        // these guest ports straight away.
+        for port in listen_ports {
+            let sock = UnixListener::bind(format!("{}_{}", muxer.host_sock_path, port))
+                .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
+                .map_err(Error::UnixBind)?;
+            muxer.add_listener(sock.as_raw_fd(), EpollListener::PortSock(*port))?;
+            muxer.port_socks.insert(*port, sock);
+        }
+
         Ok(muxer)
     }
 
@@ -387,7 +486,7 @@ impl VsockMuxer {
             // A new host-initiated connection is ready to be accepted.
             //
             Some(EpollListener::HostSock) => {
-                if self.conn_map.len() == defs::MAX_CONNECTIONS {
+                if self.conn_map.len() >= self.max_connections && !self.evict_idle_connection() {
                     // If we're already maxed-out on connections, we'll just accept and
                     // immediately discard this potentially new one.
                     warn!("vsock: connection limit reached; refusing new host connection");
@@ -415,16 +514,64 @@ impl VsockMuxer {
                     });
             }
 
+            // A new host-initiated connection to a specific guest port is ready to be accepted.
+            // Since the port is already known, the connection can be forwarded to the guest
+            // right away.
+            //
+            Some(EpollListener::PortSock(port)) => {
+                let peer_port = *port;
+                if self.conn_map.len() >= self.max_connections && !self.evict_idle_connection() {
+                    warn!("vsock: connection limit reached; refusing new host connection");
+                    self.port_socks[&peer_port].accept().map(|_| 0).unwrap_or(0);
+                    return;
+                }
+                self.port_socks[&peer_port]
+                    .accept()
+                    .map_err(Error::UnixAccept)
+                    .and_then(|(stream, _)| {
+                        stream
+                            .set_nonblocking(true)
+                            .map(|_| stream)
+                            .map_err(Error::UnixAccept)
+                    })
+                    .and_then(|stream| {
+                        let local_port = self.allocate_local_port();
+                        self.add_connection(
+                            ConnMapKey {
+                                local_cid: uapi::VSOCK_HOST_CID,
+                                local_port,
+                                peer_cid: self.cid,
+                                peer_port,
+                            },
+                            MuxerConnection::new_local_init(
+                                stream,
+                                uapi::VSOCK_HOST_CID,
+                                self.cid,
+                                local_port,
+                                peer_port,
+                                uapi::VSOCK_TYPE_STREAM,
+                            ),
+                        )
+                    })
+                    .unwrap_or_else(|err| {
+                        warn!("vsock: unable to accept local connection: {:?}", err);
+                    });
+            }
+
             // Data is ready to be read from a host-initiated connection. That would be the
             // "connect" command that we're expecting.
             Some(EpollListener::LocalStream(_)) => {
                 if let Some(EpollListener::LocalStream(mut stream)) = self.remove_listener(fd) {
-                    Self::read_local_stream_port(&mut stream)
-                        .and_then(|peer_port| Ok((self.allocate_local_port(), peer_port)))
-                        .and_then(|(local_port, peer_port)| {
+                    Self::read_local_stream_request(&mut stream)
+                        .and_then(|(peer_port, type_)| {
+                            Ok((self.allocate_local_port(), peer_port, type_))
+                        })
+                        .and_then(|(local_port, peer_port, type_)| {
                             self.add_connection(
                                 ConnMapKey {
+                                    local_cid: uapi::VSOCK_HOST_CID,
                                     local_port,
+                                    peer_cid: self.cid,
                                     peer_port,
                                 },
                                 MuxerConnection::new_local_init(
@@ -433,6 +580,7 @@ impl VsockMuxer {
                                     self.cid,
                                     local_port,
                                     peer_port,
+                                    type_,
                                 ),
                             )
                         })
@@ -448,9 +596,11 @@ impl VsockMuxer {
         }
     }
 
-    /// Parse a host "connect" command, and extract the destination vsock port.
+    /// Parse a host "connect" command, and extract the destination vsock port, along with the
+    /// socket type. The command is either `connect <port>` for a stream connection, or
+    /// `connect <port> seqpacket` for a seqpacket one.
     ///
-    fn read_local_stream_port(stream: &mut UnixStream) -> Result<u32> {
+    fn read_local_stream_request(stream: &mut UnixStream) -> Result<(u32, u16)> {
         let mut buf = [0u8; 32];
 
         // This is the minimum number of bytes that we should be able to read, when parsing a
@@ -489,6 +639,13 @@ impl VsockMuxer {
             })
             .and_then(|_| word_iter.next().ok_or(Error::InvalidPortRequest))
             .and_then(|word| word.parse::<u32>().map_err(Error::ParseInteger))
+            .and_then(|port| match word_iter.next() {
+                None => Ok((port, uapi::VSOCK_TYPE_STREAM)),
+                Some(word) if word.to_lowercase() == "seqpacket" => {
+                    Ok((port, uapi::VSOCK_TYPE_SEQPACKET))
+                }
+                Some(_) => Err(Error::InvalidPortRequest),
+            })
             .map_err(|e| Error::ReadStreamPort(Box::new(e)))
     }
 
@@ -502,10 +659,10 @@ impl VsockMuxer {
         //   termination.
         self.sweep_killq();
 
-        if self.conn_map.len() >= defs::MAX_CONNECTIONS {
+        if self.conn_map.len() >= self.max_connections && !self.evict_idle_connection() {
             info!(
                 "vsock: muxer connection limit reached ({})",
-                defs::MAX_CONNECTIONS
+                self.max_connections
             );
             return Err(Error::TooManyConnections);
         }
@@ -534,10 +691,47 @@ impl VsockMuxer {
     fn remove_connection(&mut self, key: ConnMapKey) {
         if let Some(conn) = self.conn_map.remove(&key) {
             self.remove_listener(conn.get_polled_fd());
+            self.dropped_pkts += conn.dropped_pkts();
         }
         self.free_local_port(key.local_port);
     }
 
+    /// Evict the least recently active connection, provided it has been idle for longer than
+    /// `self.idle_timeout`.
+    ///
+    /// The connection is removed right away, dropping its host stream and TX buffer, and our
+    /// peer is sent an RST about it. Any RX or kill queue entry still referring to it becomes
+    /// stale, which is fine since both queues look connections up by key, and skip the ones
+    /// that are gone.
+    ///
+    /// Returns `true` if a connection got evicted.
+    ///
+    fn evict_idle_connection(&mut self) -> bool {
+        let idle_timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return false,
+        };
+
+        let lru = self
+            .conn_map
+            .iter()
+            .map(|(key, conn)| (*key, conn.type_(), conn.last_activity()))
+            .min_by_key(|(_, _, last_activity)| *last_activity);
+
+        match lru {
+            Some((key, type_, last_activity)) if last_activity.elapsed() >= idle_timeout => {
+                info!(
+                    "vsock: evicting idle connection (lp={}, pp={})",
+                    key.local_port, key.peer_port
+                );
+                self.remove_connection(key);
+                self.enq_rst(key.local_port, key.peer_port, type_);
+                true
+            }
+            _ => false,
+        }
+    }
+
     /// Schedule a connection for immediate termination.
     /// I.e. as soon as we can also let our peer know we're dropping the connection, by sending
     /// it an RST packet.
@@ -636,6 +830,16 @@ impl VsockMuxer {
     /// RST packet will be scheduled for delivery to the guest.
     ///
     fn handle_peer_request_pkt(&mut self, pkt: &VsockPacket) {
+        // The socket for this port is our own, so there's nothing listening on the host side.
+        if self.port_socks.contains_key(&pkt.dst_port()) {
+            info!(
+                "vsock: refusing guest connection to host listening port {}",
+                pkt.dst_port()
+            );
+            self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_());
+            return;
+        }
+
         let port_path = format!("{}_{}", self.host_sock_path, pkt.dst_port());
 
         UnixStream::connect(port_path)
@@ -643,10 +847,7 @@ impl VsockMuxer {
             .map_err(Error::UnixConnect)
             .and_then(|stream| {
                 self.add_connection(
-                    ConnMapKey {
-                        local_port: pkt.dst_port(),
-                        peer_port: pkt.src_port(),
-                    },
+                    ConnMapKey::from_peer_pkt(pkt),
                     MuxerConnection::new_peer_init(
                         stream,
                         uapi::VSOCK_HOST_CID,
@@ -654,10 +855,11 @@ impl VsockMuxer {
                         pkt.dst_port(),
                         pkt.src_port(),
                         pkt.buf_alloc(),
+                        pkt.type_(),
                     ),
                 )
             })
-            .unwrap_or_else(|_| self.enq_rst(pkt.dst_port(), pkt.src_port()));
+            .unwrap_or_else(|_| self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_()));
     }
 
     /// Perform an action that might mutate a connection's state.
@@ -797,10 +999,11 @@ impl VsockMuxer {
     /// handle them. We do, however, log a warning, since not being able to enqueue an RST
     /// packet means we have to drop it, which is not normal operation.
     ///
-    fn enq_rst(&mut self, local_port: u32, peer_port: u32) {
+    fn enq_rst(&mut self, local_port: u32, peer_port: u32, type_: u16) {
         let pushed = self.rxq.push(MuxerRx::RstPkt {
             local_port,
             peer_port,
+            type_,
         });
         if !pushed {
             warn!(
@@ -811,6 +1014,17 @@ impl VsockMuxer {
     }
 }
 
+impl Drop for VsockMuxer {
+    fn drop(&mut self) {
+        // Clean up the host Unix sockets, so that they can be bound again (e.g. after a
+        // reboot).
+        for port in self.port_socks.keys() {
+            let _ = std::fs::remove_file(format!("{}_{}", self.host_sock_path, port));
+        }
+        let _ = std::fs::remove_file(&self.host_sock_path);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::{Read, Write};
@@ -820,10 +1034,12 @@ mod tests {
 
     use super::super::super::csm::defs as csm_defs;
     use super::super::super::tests::TestContext as VsockTestContext;
+    use super::super::super::VsockConnectionDirection;
     use super::*;
 
     const PEER_CID: u64 = 3;
     const PEER_BUF_ALLOC: u32 = 64 * 1024;
+    const MAX_CONNECTIONS: usize = 1023;
 
     struct MuxerTestContext {
         _vsock_test_ctx: VsockTestContext,
@@ -831,14 +1047,29 @@ mod tests {
         muxer: VsockMuxer,
     }
 
-    impl Drop for MuxerTestContext {
-        fn drop(&mut self) {
-            std::fs::remove_file(self.muxer.host_sock_path.as_str()).unwrap();
-        }
-    }
-
     impl MuxerTestContext {
         fn new(name: &str) -> Self {
+            Self::new_with_limits(name, MAX_CONNECTIONS, None)
+        }
+
+        fn new_with_limits(
+            name: &str,
+            max_connections: usize,
+            idle_timeout: Option<Duration>,
+        ) -> Self {
+            Self::create(name, max_connections, idle_timeout, &[])
+        }
+
+        fn new_with_listen_ports(name: &str, listen_ports: &[u32]) -> Self {
+            Self::create(name, MAX_CONNECTIONS, None, listen_ports)
+        }
+
+        fn create(
+            name: &str,
+            max_connections: usize,
+            idle_timeout: Option<Duration>,
+            listen_ports: &[u32],
+        ) -> Self {
             let vsock_test_ctx = VsockTestContext::new();
             let mut handler_ctx = vsock_test_ctx.create_epoll_handler_context();
             let pkt = VsockPacket::from_rx_virtq_head(
@@ -849,7 +1080,14 @@ mod tests {
             )
             .unwrap();
             let uds_path = format!("test_vsock_{}.sock", name);
-            let muxer = VsockMuxer::new(PEER_CID, uds_path).unwrap();
+            let muxer = VsockMuxer::new(
+                PEER_CID,
+                uds_path,
+                max_connections,
+                idle_timeout,
+                listen_ports,
+            )
+            .unwrap();
 
             Self {
                 _vsock_test_ctx: vsock_test_ctx,
@@ -915,6 +1153,10 @@ mod tests {
         }
 
         fn local_connect(&mut self, peer_port: u32) -> (UnixStream, u32) {
+            self.local_connect_with_type(peer_port, uapi::VSOCK_TYPE_STREAM)
+        }
+
+        fn local_connect_with_type(&mut self, peer_port: u32, type_: u16) -> (UnixStream, u32) {
             let (init_local_lsn_count, init_conn_lsn_count) = self.count_epoll_listeners();
 
             let mut stream = UnixStream::connect(self.muxer.host_sock_path.clone()).unwrap();
@@ -928,7 +1170,11 @@ mod tests {
             let (local_lsn_count, _) = self.count_epoll_listeners();
             assert_eq!(local_lsn_count, init_local_lsn_count + 1);
 
-            let buf = format!("CONNECT {}\n", peer_port);
+            let buf = if type_ == uapi::VSOCK_TYPE_SEQPACKET {
+                format!("CONNECT {} SEQPACKET\n", peer_port)
+            } else {
+                format!("CONNECT {}\n", peer_port)
+            };
             stream.write_all(buf.as_bytes()).unwrap();
             // The muxer would now get notified that data is available for reading from the locally
             // initiated connection.
@@ -944,7 +1190,9 @@ mod tests {
             // local port should also have been allocated for the new LocalInit connection.
             let local_port = self.muxer.local_port_last;
             let key = ConnMapKey {
+                local_cid: uapi::VSOCK_HOST_CID,
                 local_port,
+                peer_cid: PEER_CID,
                 peer_port,
             };
             assert!(self.muxer.conn_map.contains_key(&key));
@@ -954,10 +1202,12 @@ mod tests {
             assert!(self.muxer.has_pending_rx());
             self.recv();
             assert_eq!(self.pkt.op(), uapi::VSOCK_OP_REQUEST);
+            assert_eq!(self.pkt.type_(), type_);
             assert_eq!(self.pkt.dst_port(), peer_port);
             assert_eq!(self.pkt.src_port(), local_port);
 
-            self.init_pkt(local_port, peer_port, uapi::VSOCK_OP_RESPONSE);
+            self.init_pkt(local_port, peer_port, uapi::VSOCK_OP_RESPONSE)
+                .set_type(type_);
             self.send();
 
             let mut buf = vec![0u8; 32];
@@ -1005,7 +1255,7 @@ mod tests {
     fn test_bad_peer_pkt() {
         const LOCAL_PORT: u32 = 1026;
         const PEER_PORT: u32 = 1025;
-        const SOCK_DGRAM: u16 = 2;
+        const SOCK_DGRAM: u16 = 3;
 
         let mut ctx = MuxerTestContext::new("bad_peer_pkt");
         ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST)
@@ -1013,7 +1263,7 @@ mod tests {
         ctx.send();
 
         // The guest sent a SOCK_DGRAM packet. Per the vsock spec, we need to reply with an RST
-        // packet, since vsock only supports stream sockets.
+        // packet, since we only support stream and seqpacket sockets.
         assert!(ctx.muxer.has_pending_rx());
         ctx.recv();
         assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
@@ -1081,7 +1331,9 @@ mod tests {
         assert_eq!(ctx.pkt.src_port(), LOCAL_PORT);
         assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
         let key = ConnMapKey {
+            local_cid: uapi::VSOCK_HOST_CID,
             local_port: LOCAL_PORT,
+            peer_cid: PEER_CID,
             peer_port: PEER_PORT,
         };
         assert!(ctx.muxer.conn_map.contains_key(&key));
@@ -1112,6 +1364,15 @@ mod tests {
         assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
 
         assert!(!ctx.muxer.has_pending_rx());
+
+        // Data claiming to come from another CID must neither reach the host stream, nor be
+        // accounted against the connection.
+        let fwd_cnt = ctx.muxer.conn_map.get(&key).unwrap().fwd_cnt();
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &data)
+            .set_src_cid(PEER_CID + 1);
+        ctx.send();
+        assert_eq!(ctx.muxer.conn_map.get(&key).unwrap().fwd_cnt(), fwd_cnt);
+        assert!(!ctx.muxer.has_pending_rx());
     }
 
     #[test]
@@ -1140,6 +1401,172 @@ mod tests {
         assert_eq!(ctx.pkt.src_port(), local_port);
         assert_eq!(ctx.pkt.dst_port(), peer_port);
         assert_eq!(ctx.pkt.buf().unwrap()[..data.len()], data);
+
+        let info = ctx.muxer.info();
+        assert_eq!(info.dropped_packets, 0);
+        assert_eq!(info.connections.len(), 1);
+        let conn = &info.connections[0];
+        assert_eq!(conn.local_port, local_port);
+        assert_eq!(conn.peer_port, peer_port);
+        assert_eq!(conn.direction, VsockConnectionDirection::HostInitiated);
+        assert_eq!(conn.bytes_in, 4);
+        assert_eq!(conn.bytes_out, 4);
+        assert_eq!(conn.state, "Established");
+    }
+
+    #[test]
+    fn test_seqpacket_peer_connection() {
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+
+        let mut ctx = MuxerTestContext::new("seqpacket_peer_connection");
+
+        // A refused seqpacket connection must be reset with a seqpacket RST, or the guest
+        // wouldn't be able to match it with its socket.
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST)
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+
+        let mut listener = ctx.create_local_listener(LOCAL_PORT);
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST)
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET);
+        ctx.send();
+        let mut stream = listener.accept();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+
+        // Test guest -> host record flow, with the record split across two packets.
+        let data = [1u8, 2, 3, 4, 5, 6];
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &data[..4])
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET);
+        ctx.send();
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &data[4..])
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET)
+            .set_flag(uapi::VSOCK_FLAGS_SEQ_EOM);
+        ctx.send();
+        let mut buf = vec![0u8; csm_defs::SEQPACKET_HDR_SIZE + data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+        assert_eq!(
+            buf[..csm_defs::SEQPACKET_HDR_SIZE],
+            (data.len() as u32).to_le_bytes()
+        );
+        assert_eq!(buf[csm_defs::SEQPACKET_HDR_SIZE..], data);
+
+        // Test host -> guest record flow.
+        let mut buf = (2 | csm_defs::SEQPACKET_HDR_EOR).to_le_bytes().to_vec();
+        buf.extend_from_slice(&[7, 8]);
+        stream.write_all(&buf).unwrap();
+        ctx.notify_muxer();
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+        assert_eq!(
+            ctx.pkt.flags(),
+            uapi::VSOCK_FLAGS_SEQ_EOM | uapi::VSOCK_FLAGS_SEQ_EOR
+        );
+        assert_eq!(ctx.pkt.buf().unwrap()[..ctx.pkt.len() as usize], [7, 8]);
+
+        // A stream packet doesn't belong to this connection, which gets reset.
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &data);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+        assert!(ctx.muxer.conn_map.is_empty());
+    }
+
+    #[test]
+    fn test_seqpacket_local_connection() {
+        let mut ctx = MuxerTestContext::new("seqpacket_local_connection");
+        let peer_port = 1025;
+        let (mut stream, local_port) =
+            ctx.local_connect_with_type(peer_port, uapi::VSOCK_TYPE_SEQPACKET);
+
+        let data = [1u8, 2, 3];
+        ctx.init_data_pkt(local_port, peer_port, &data)
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET)
+            .set_flag(uapi::VSOCK_FLAGS_SEQ_EOM);
+        ctx.send();
+        let mut buf = vec![0u8; csm_defs::SEQPACKET_HDR_SIZE + data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+        assert_eq!(buf[csm_defs::SEQPACKET_HDR_SIZE..], data);
+    }
+
+    #[test]
+    fn test_listen_ports() {
+        const LISTEN_PORTS: [u32; 2] = [1025, 1026];
+        const PEER_PORT: u32 = 1030;
+
+        let mut ctx = MuxerTestContext::new_with_listen_ports("listen_ports", &LISTEN_PORTS);
+        let path = |port| format!("{}_{}", ctx.muxer.host_sock_path, port);
+        let paths: Vec<String> = LISTEN_PORTS.iter().map(path).collect();
+        let host_sock_path = ctx.muxer.host_sock_path.clone();
+        for path in paths.iter() {
+            assert!(Path::new(path).exists());
+        }
+        let (local_lsn_count, conn_lsn_count) = ctx.count_epoll_listeners();
+
+        // Connecting to a per-port socket should get a connection request to that port sent
+        // to the guest straight away, without the need for a "connect" command.
+        let mut stream = UnixStream::connect(&paths[1]).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        ctx.notify_muxer();
+        assert_eq!(
+            ctx.count_epoll_listeners(),
+            (local_lsn_count, conn_lsn_count + 1)
+        );
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_REQUEST);
+        assert_eq!(ctx.pkt.dst_port(), LISTEN_PORTS[1]);
+        let local_port = ctx.pkt.src_port();
+
+        ctx.init_pkt(local_port, LISTEN_PORTS[1], uapi::VSOCK_OP_RESPONSE);
+        ctx.send();
+        let mut buf = vec![0u8; 32];
+        let len = stream.read(&mut buf[..]).unwrap();
+        assert_eq!(&buf[..len], format!("OK {}\n", local_port).as_bytes());
+
+        // Test guest -> host data flow.
+        let data = [1, 2, 3, 4];
+        ctx.init_data_pkt(local_port, LISTEN_PORTS[1], &data);
+        ctx.send();
+        let mut buf = vec![0u8; data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+        assert_eq!(buf.as_slice(), &data);
+
+        // The guest can't connect to a port the muxer is listening on, since there wouldn't be
+        // anyone on the host side to answer.
+        ctx.init_pkt(LISTEN_PORTS[0], PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), LISTEN_PORTS[0]);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+
+        // A guest refusing the connection should get the host stream closed.
+        let mut stream = UnixStream::connect(&paths[0]).unwrap();
+        ctx.notify_muxer();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_REQUEST);
+        assert_eq!(ctx.pkt.dst_port(), LISTEN_PORTS[0]);
+        let local_port = ctx.pkt.src_port();
+        ctx.init_pkt(local_port, LISTEN_PORTS[0], uapi::VSOCK_OP_RST);
+        ctx.send();
+        assert_eq!(ctx.muxer.conn_map.len(), 1);
+        assert_eq!(stream.read(&mut buf[..]).unwrap(), 0);
+
+        // All the host sockets should be gone once the muxer is.
+        drop(ctx);
+        assert!(!Path::new(&host_sock_path).exists());
+        for path in paths.iter() {
+            assert!(!Path::new(path).exists());
+        }
     }
 
     #[test]
@@ -1168,7 +1595,9 @@ mod tests {
         ctx.init_pkt(local_port, peer_port, uapi::VSOCK_OP_RST);
         ctx.send();
         let key = ConnMapKey {
+            local_cid: uapi::VSOCK_HOST_CID,
             local_port,
+            peer_cid: PEER_CID,
             peer_port,
         };
         assert!(!ctx.muxer.conn_map.contains_key(&key));
@@ -1192,7 +1621,9 @@ mod tests {
         assert_eq!(ctx.pkt.src_port(), local_port);
         assert_eq!(ctx.pkt.dst_port(), peer_port);
         let key = ConnMapKey {
+            local_cid: uapi::VSOCK_HOST_CID,
             local_port,
+            peer_cid: PEER_CID,
             peer_port,
         };
         assert!(ctx.muxer.conn_map.contains_key(&key));
@@ -1210,7 +1641,9 @@ mod tests {
         assert_eq!(ctx.pkt.src_port(), local_port);
         assert_eq!(ctx.pkt.dst_port(), peer_port);
         let key = ConnMapKey {
+            local_cid: uapi::VSOCK_HOST_CID,
             local_port,
+            peer_cid: PEER_CID,
             peer_port,
         };
         assert!(!ctx.muxer.conn_map.contains_key(&key));
@@ -1357,6 +1790,106 @@ mod tests {
         assert!(!ctx.muxer.has_pending_rx());
     }
 
+    #[test]
+    fn test_connection_limit() {
+        let mut ctx = MuxerTestContext::new_with_limits("connection_limit", 2, None);
+        let local_port = 1026;
+        let peer_port_first = 1025;
+        let mut listener = ctx.create_local_listener(local_port);
+        let mut streams: Vec<UnixStream> = Vec::new();
+
+        for peer_port in peer_port_first..peer_port_first + 2 {
+            ctx.init_pkt(local_port, peer_port, uapi::VSOCK_OP_REQUEST);
+            ctx.send();
+            streams.push(listener.accept());
+            ctx.recv();
+            assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        }
+        assert_eq!(ctx.muxer.conn_map.len(), 2);
+
+        // A connection request beyond the limit should be refused with an RST.
+        let peer_port = peer_port_first + 2;
+        ctx.init_pkt(local_port, peer_port, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), local_port);
+        assert_eq!(ctx.pkt.dst_port(), peer_port);
+        assert_eq!(ctx.muxer.conn_map.len(), 2);
+        assert!(!ctx.muxer.has_pending_rx());
+
+        // Once a connection is gone, there should be room for a new one.
+        ctx.init_pkt(local_port, peer_port_first, uapi::VSOCK_OP_RST);
+        ctx.send();
+        assert_eq!(ctx.muxer.conn_map.len(), 1);
+        ctx.init_pkt(local_port, peer_port, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        assert_eq!(ctx.pkt.dst_port(), peer_port);
+        assert_eq!(ctx.muxer.conn_map.len(), 2);
+    }
+
+    #[test]
+    fn test_idle_connection_eviction() {
+        const IDLE_TIMEOUT_MS: u64 = 50;
+
+        let mut ctx = MuxerTestContext::new_with_limits(
+            "idle_connection_eviction",
+            2,
+            Some(Duration::from_millis(IDLE_TIMEOUT_MS)),
+        );
+        let local_port = 1026;
+        let mut listener = ctx.create_local_listener(local_port);
+        let mut streams: Vec<UnixStream> = Vec::new();
+        let key = |peer_port| ConnMapKey {
+            local_cid: uapi::VSOCK_HOST_CID,
+            local_port,
+            peer_cid: PEER_CID,
+            peer_port,
+        };
+
+        for peer_port in 1025..1027 {
+            ctx.init_pkt(local_port, peer_port, uapi::VSOCK_OP_REQUEST);
+            ctx.send();
+            streams.push(listener.accept());
+            ctx.recv();
+            assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        }
+
+        // Connections that haven't been idle for long enough must not be evicted.
+        ctx.init_pkt(local_port, 1027, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.dst_port(), 1027);
+        assert!(ctx.muxer.conn_map.contains_key(&key(1025)));
+        assert!(ctx.muxer.conn_map.contains_key(&key(1026)));
+
+        std::thread::sleep(Duration::from_millis(IDLE_TIMEOUT_MS));
+
+        // Both connections are now idle, but some activity on the first one should make the
+        // second one the least recently active, and therefore the one to evict.
+        ctx.init_pkt(local_port, 1025, uapi::VSOCK_OP_CREDIT_UPDATE);
+        ctx.send();
+        ctx.init_pkt(local_port, 1027, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), local_port);
+        assert_eq!(ctx.pkt.dst_port(), 1026);
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        assert_eq!(ctx.pkt.dst_port(), 1027);
+        assert!(ctx.muxer.conn_map.contains_key(&key(1025)));
+        assert!(!ctx.muxer.conn_map.contains_key(&key(1026)));
+        assert!(ctx.muxer.conn_map.contains_key(&key(1027)));
+
+        // The evicted connection's host stream should have been closed.
+        let mut buf = vec![0u8; 16];
+        assert_eq!(streams[1].read(buf.as_mut_slice()).unwrap(), 0);
+    }
+
     #[test]
     fn test_regression_handshake() {
         // Address one of the issues found while fixing the following issue:
@@ -1370,7 +1903,9 @@ mod tests {
 
         // Get the connection from the connection map.
         let key = ConnMapKey {
+            local_cid: uapi::VSOCK_HOST_CID,
             local_port,
+            peer_cid: PEER_CID,
             peer_port,
         };
         let conn = ctx.muxer.conn_map.get_mut(&key).unwrap();
@@ -1396,7 +1931,9 @@ mod tests {
 
         // Get the connection from the connection map.
         let key = ConnMapKey {
+            local_cid: uapi::VSOCK_HOST_CID,
             local_port,
+            peer_cid: PEER_CID,
             peer_port,
         };
         let conn = ctx.muxer.conn_map.get_mut(&key).unwrap();
@@ -0,0 +1,650 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Virtio watchdog device.
+//!
+//! The guest pings the watchdog by making descriptors available on its
+//! single queue. The first ping arms a timer, and every following ping
+//! pushes the expiry back by the configured timeout. If the timer expires,
+//! the host is notified through an `EventFd` so that it can take the
+//! configured action (pause, reset or shut the VM down, or nothing).
+//!
+//! The timer doesn't run while the device is paused: an expiry happening
+//! meanwhile is deferred, the guest being given a full timeout period once
+//! the device is resumed.
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
+    VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM,
+    VIRTIO_F_VERSION_1,
+};
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
+use libc::EFD_NONBLOCK;
+use std::fmt::{self, Display};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
+    Transportable,
+};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
+
+const QUEUE_SIZE: u16 = 8;
+const NUM_QUEUES: usize = 1;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+
+// New descriptors are pending on the virtio queue.
+const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// The watchdog timer has expired.
+const TIMER_EXPIRED_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed arming the watchdog timer.
+    TimerFdArm(vmm_sys_util::errno::Error),
+    /// Failed reading from the watchdog timer.
+    TimerFdWait(vmm_sys_util::errno::Error),
+    /// Failed notifying the host about the watchdog expiry.
+    ExpiredEventWrite(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            TimerFdArm(e) => write!(f, "failed arming watchdog timer: {}", e),
+            TimerFdWait(e) => write!(f, "failed reading watchdog timer: {}", e),
+            ExpiredEventWrite(e) => write!(f, "failed notifying watchdog expiry: {}", e),
+        }
+    }
+}
+
+struct WatchdogEpollHandler {
+    queues: Vec<Queue>,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evt: EventFd,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    timer: TimerFd,
+    timeout: Duration,
+    last_ping_time: Arc<Mutex<Option<Instant>>>,
+    expired_evt: EventFd,
+}
+
+impl WatchdogEpollHandler {
+    // Every descriptor made available by the guest counts as a ping.
+    fn process_queue(&mut self) -> result::Result<bool, Error> {
+        let queue = &mut self.queues[0];
+
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mem = self.mem.memory();
+        for avail_desc in queue.iter(&mem) {
+            let mut len = 0;
+
+            // Acknowledge the ping if the driver gave us some room for it.
+            if avail_desc.is_write_only() && mem.write_obj(1u8, avail_desc.addr).is_ok() {
+                len = 1;
+            }
+
+            used_desc_heads[used_count] = (avail_desc.index, len);
+            used_count += 1;
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            queue.add_used(&mem, desc_index, len);
+        }
+
+        if used_count > 0 {
+            self.ping()?;
+        }
+
+        Ok(used_count > 0)
+    }
+
+    fn ping(&mut self) -> result::Result<(), Error> {
+        let mut last_ping_time = self.last_ping_time.lock().unwrap();
+        if last_ping_time.is_none() {
+            info!(
+                "Watchdog armed, timeout set to {} seconds",
+                self.timeout.as_secs()
+            );
+        }
+        *last_ping_time = Some(Instant::now());
+
+        self.timer
+            .reset(self.timeout, None)
+            .map_err(Error::TimerFdArm)
+    }
+
+    fn timer_expired(&mut self) -> result::Result<(), Error> {
+        self.timer.wait().map_err(Error::TimerFdWait)?;
+
+        let mut last_ping_time = self.last_ping_time.lock().unwrap();
+        if let Some(ping_time) = *last_ping_time {
+            let elapsed = ping_time.elapsed();
+
+            // The last ping time can be moved forward without the timer
+            // being rearmed, which is what happens when the device is
+            // resumed. Only wait for the remaining time in this case.
+            if elapsed < self.timeout {
+                return self
+                    .timer
+                    .reset(self.timeout - elapsed, None)
+                    .map_err(Error::TimerFdArm);
+            }
+
+            error!(
+                "Watchdog expired, no ping received for {} seconds",
+                elapsed.as_secs()
+            );
+
+            // Don't fire again until the guest pings the watchdog.
+            *last_ping_time = None;
+            self.expired_evt
+                .write(1)
+                .map_err(Error::ExpiredEventWrite)?;
+        }
+
+        Ok(())
+    }
+
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[0]))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        helper.add_event(self.timer.as_raw_fd(), TIMER_EXPIRED_EVENT)?;
+        helper.run(paused, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for WatchdogEpollHandler {
+    fn handle_event(&mut self, _helper: &mut EpollHelper, event: u16) -> bool {
+        match event {
+            QUEUE_AVAIL_EVENT => {
+                if let Err(e) = self.queue_evt.read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                }
+
+                match self.process_queue() {
+                    Ok(true) => {
+                        if let Err(e) = self.signal_used_queue() {
+                            error!("Failed to signal used queue: {:?}", e);
+                            return true;
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!("Failed to process watchdog ping: {}", e);
+                        return true;
+                    }
+                }
+            }
+            TIMER_EXPIRED_EVENT => {
+                if let Err(e) = self.timer_expired() {
+                    error!("Failed to process watchdog expiry: {}", e);
+                    return true;
+                }
+            }
+            _ => {
+                error!("Unexpected event: {}", event);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Virtio device letting the host detect an unresponsive guest.
+pub struct Watchdog {
+    id: String,
+    kill_evt: Option<EventFd>,
+    pause_evt: Option<EventFd>,
+    avail_features: u64,
+    acked_features: u64,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), EpollHelperError>>>>,
+    paused: Arc<AtomicBool>,
+    timeout: Duration,
+    last_ping_time: Arc<Mutex<Option<Instant>>>,
+    expired_evt: EventFd,
+}
+
+/// Watchdog status, as reported through the VM information.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WatchdogInfo {
+    /// Time of the last ping, in milliseconds since the UNIX epoch, unset
+    /// while the watchdog isn't armed.
+    pub last_ping_time: Option<u64>,
+    /// Time left, in milliseconds, before the watchdog expires, unset while
+    /// the watchdog isn't armed.
+    pub remaining_time: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WatchdogState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+}
+
+impl Watchdog {
+    /// Create a new virtio watchdog device, writing to `expired_evt` when
+    /// the guest stops pinging it for longer than `timeout`.
+    pub fn new(
+        id: String,
+        timeout: Duration,
+        expired_evt: EventFd,
+        iommu: bool,
+    ) -> io::Result<Watchdog> {
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+        if iommu {
+            avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+        }
+
+        Ok(Watchdog {
+            id,
+            kill_evt: None,
+            pause_evt: None,
+            avail_features,
+            acked_features: 0u64,
+            queue_evts: None,
+            interrupt_cb: None,
+            epoll_threads: None,
+            paused: Arc::new(AtomicBool::new(false)),
+            timeout,
+            last_ping_time: Arc::new(Mutex::new(None)),
+            expired_evt,
+        })
+    }
+
+    /// Report when the guest last pinged the watchdog, and how long it has
+    /// left before the watchdog expires.
+    pub fn info(&self) -> WatchdogInfo {
+        let ping_time = match *self.last_ping_time.lock().unwrap() {
+            Some(ping_time) => ping_time,
+            None => return WatchdogInfo::default(),
+        };
+
+        let elapsed = ping_time.elapsed();
+        let remaining = if self.paused.load(Ordering::SeqCst) {
+            // The guest gets a full timeout period once resumed.
+            self.timeout
+        } else {
+            self.timeout
+                .checked_sub(elapsed)
+                .unwrap_or_else(|| Duration::from_secs(0))
+        };
+        let last_ping_time = SystemTime::now()
+            .checked_sub(elapsed)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|t| t.as_millis() as u64);
+
+        WatchdogInfo {
+            last_ping_time,
+            remaining_time: Some(remaining.as_millis() as u64),
+        }
+    }
+
+    fn state(&self) -> WatchdogState {
+        WatchdogState {
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+        }
+    }
+
+    fn set_state(&mut self, state: &WatchdogState) -> io::Result<()> {
+        self.avail_features = state.avail_features;
+        self.acked_features = state.acked_features;
+
+        Ok(())
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Watchdog {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_WATCHDOG as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating kill EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.kill_evt = Some(self_kill_evt);
+
+        let (self_pause_evt, pause_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating pause EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.pause_evt = Some(self_pause_evt);
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the thread.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        let timer = TimerFd::new().map_err(|e| {
+            error!("failed creating watchdog TimerFd: {}", e);
+            ActivateError::BadActivate
+        })?;
+        let expired_evt = self.expired_evt.try_clone().map_err(|e| {
+            error!("failed cloning watchdog expiry EventFd: {}", e);
+            ActivateError::BadActivate
+        })?;
+
+        // A driver being activated starts from a disarmed watchdog.
+        *self.last_ping_time.lock().unwrap() = None;
+
+        let mut handler = WatchdogEpollHandler {
+            queues,
+            mem,
+            interrupt_cb,
+            queue_evt: queue_evts.remove(0),
+            kill_evt,
+            pause_evt,
+            timer,
+            timeout: self.timeout,
+            last_ping_time: self.last_ping_time.clone(),
+            expired_evt,
+        };
+
+        let paused = self.paused.clone();
+        let mut epoll_threads = Vec::new();
+        thread::Builder::new()
+            .name("virtio_watchdog".to_string())
+            .spawn(move || handler.run(paused))
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(|e| {
+                error!("failed to clone the virtio-watchdog epoll thread: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.epoll_threads = Some(epoll_threads);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // We first must resume the virtio thread if it was paused.
+        if self.pause_evt.take().is_some() {
+            self.resume().ok()?;
+        }
+
+        // Then kill it.
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+}
+
+virtio_pausable_trait!(Watchdog);
+
+impl Pausable for Watchdog {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.virtio_pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        // The guest could not ping the watchdog while being paused, so give
+        // it a full timeout period from now on.
+        if let Some(ping_time) = self.last_ping_time.lock().unwrap().as_mut() {
+            *ping_time = Instant::now();
+        }
+
+        self.virtio_resume()
+    }
+}
+
+impl Snapshottable for Watchdog {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        let snapshot =
+            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
+
+        let mut watchdog_snapshot = Snapshot::new(self.id.as_str());
+        watchdog_snapshot.add_data_section(SnapshotDataSection {
+            id: format!("{}-section", self.id),
+            snapshot,
+        });
+
+        Ok(watchdog_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        if let Some(watchdog_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id))
+        {
+            let watchdog_state = match serde_json::from_slice(&watchdog_section.snapshot) {
+                Ok(state) => state,
+                Err(error) => {
+                    return Err(MigratableError::Restore(anyhow!(
+                        "Could not deserialize watchdog {}",
+                        error
+                    )))
+                }
+            };
+
+            return self.set_state(&watchdog_state).map_err(|e| {
+                MigratableError::Restore(anyhow!("Could not restore watchdog state {:?}", e))
+            });
+        }
+
+        Err(MigratableError::Restore(anyhow!(
+            "Could not find watchdog snapshot section"
+        )))
+    }
+}
+
+impl Transportable for Watchdog {}
+impl Migratable for Watchdog {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::NoopVirtioInterrupt;
+    use vm_memory::GuestAddress;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::VIRTQ_DESC_F_WRITE;
+
+    const MEM_SIZE: usize = 0x10_0000;
+
+    fn create_handler(
+        mem: &GuestMemoryMmap,
+        queues: Vec<Queue>,
+        timeout: Duration,
+    ) -> WatchdogEpollHandler {
+        WatchdogEpollHandler {
+            queues,
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            timer: TimerFd::new().unwrap(),
+            timeout,
+            last_ping_time: Arc::new(Mutex::new(None)),
+            expired_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_watchdog_expiry() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_q = GuestQ::new(GuestAddress(0x1_0000), &mem, QUEUE_SIZE);
+        guest_q.dtable[0].set(0x2_0000, 1, VIRTQ_DESC_F_WRITE, 0);
+        guest_q.dtable[1].set(0x2_0001, 1, VIRTQ_DESC_F_WRITE, 0);
+
+        let timeout = Duration::from_millis(50);
+        let mut handler = create_handler(&mem, vec![guest_q.create_queue()], timeout);
+
+        // Nothing happens before the first ping.
+        assert!(!handler.process_queue().unwrap());
+        assert!(handler.last_ping_time.lock().unwrap().is_none());
+
+        // The first ping arms the watchdog and gets acknowledged.
+        let start = Instant::now();
+        guest_q.avail.ring[0].set(0);
+        guest_q.avail.idx.set(1);
+        assert!(handler.process_queue().unwrap());
+        assert_eq!(guest_q.used.idx.get(), 1);
+        assert_eq!(guest_q.used.ring[0].get().len, 1);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x2_0000)).unwrap(), 1);
+
+        // Ping again before the timeout, which pushes the expiry back.
+        thread::sleep(Duration::from_millis(30));
+        let last_ping = Instant::now();
+        guest_q.avail.ring[1].set(1);
+        guest_q.avail.idx.set(2);
+        assert!(handler.process_queue().unwrap());
+        assert!(handler.expired_evt.read().is_err());
+
+        // Then stop pinging, the expiry must be reported once the timeout
+        // has elapsed since the last ping.
+        handler.timer_expired().unwrap();
+        assert!(last_ping.elapsed() >= timeout);
+        assert!(start.elapsed() >= Duration::from_millis(80));
+        assert_eq!(handler.expired_evt.read().unwrap(), 1);
+
+        // The watchdog is disarmed until the guest pings it again.
+        assert!(handler.last_ping_time.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_watchdog_expiry_while_paused() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_q = GuestQ::new(GuestAddress(0x1_0000), &mem, QUEUE_SIZE);
+        guest_q.dtable[0].set(0x2_0000, 1, VIRTQ_DESC_F_WRITE, 0);
+
+        let timeout = Duration::from_millis(50);
+        let mut watchdog = Watchdog::new(
+            "watchdog".to_string(),
+            timeout,
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            false,
+        )
+        .unwrap();
+        let mut handler = create_handler(&mem, vec![guest_q.create_queue()], timeout);
+        handler.last_ping_time = watchdog.last_ping_time.clone();
+        assert_eq!(watchdog.info(), WatchdogInfo::default());
+
+        guest_q.avail.ring[0].set(0);
+        guest_q.avail.idx.set(1);
+        assert!(handler.process_queue().unwrap());
+        let info = watchdog.info();
+        assert!(info.last_ping_time.is_some());
+        assert!(info.remaining_time.unwrap() <= 50);
+
+        // The timeout elapses while the VM is paused, and the guest gets a
+        // full timeout period again once resumed.
+        watchdog.pause().unwrap();
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(watchdog.info().remaining_time, Some(50));
+        watchdog.resume().unwrap();
+        let resume_time = Instant::now();
+
+        // The pending timer expiry only rearms the timer.
+        handler.timer_expired().unwrap();
+        assert!(handler.expired_evt.read().is_err());
+        assert!(handler.last_ping_time.lock().unwrap().is_some());
+
+        // Then the watchdog fires if the guest still doesn't ping it.
+        handler.timer_expired().unwrap();
+        assert!(resume_time.elapsed() >= timeout);
+        assert_eq!(handler.expired_evt.read().unwrap(), 1);
+        assert_eq!(watchdog.info(), WatchdogInfo::default());
+    }
+}
@@ -201,6 +201,11 @@ impl AddressAllocator {
             }
         }
     }
+
+    /// Whether `address` is part of the managed range.
+    pub fn contains(&self, address: GuestAddress) -> bool {
+        address >= self.base && address <= self.end
+    }
 }
 
 #[cfg(test)]
@@ -220,6 +225,15 @@ mod tests {
         assert_eq!(AddressAllocator::new(GuestAddress(0x1000), 0), None);
     }
 
+    #[test]
+    fn contains_range() {
+        let pool = AddressAllocator::new(GuestAddress(0x1000), 0x1000).unwrap();
+        assert!(!pool.contains(GuestAddress(0xfff)));
+        assert!(pool.contains(GuestAddress(0x1000)));
+        assert!(pool.contains(GuestAddress(0x1fff)));
+        assert!(!pool.contains(GuestAddress(0x2000)));
+    }
+
     #[test]
     fn allocate_fails_alignment_zero() {
         let mut pool = AddressAllocator::new(GuestAddress(0x1000), 0x10000).unwrap();
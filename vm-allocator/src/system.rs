@@ -55,6 +55,7 @@ pub struct SystemAllocator {
     io_address_space: AddressAllocator,
     mmio_address_space: AddressAllocator,
     mmio_hole_address_space: AddressAllocator,
+    high_mmio_address_space: Option<AddressAllocator>,
     gsi_allocator: GsiAllocator,
 }
 
@@ -84,6 +85,7 @@ impl SystemAllocator {
             io_address_space: AddressAllocator::new(io_base, io_size)?,
             mmio_address_space: AddressAllocator::new(mmio_base, mmio_size)?,
             mmio_hole_address_space: AddressAllocator::new(mmio_hole_base, mmio_hole_size)?,
+            high_mmio_address_space: None,
             #[cfg(target_arch = "x86_64")]
             gsi_allocator: GsiAllocator::new(apics),
             #[cfg(target_arch = "aarch64")]
@@ -141,6 +143,36 @@ impl SystemAllocator {
         )
     }
 
+    /// Sets aside `size` bytes of MMIO address space, aligned on `align_size`,
+    /// for the huge BARs allocated through `allocate_high_mmio_addresses()`.
+    pub fn reserve_high_mmio_window(
+        &mut self,
+        size: GuestUsize,
+        align_size: GuestUsize,
+    ) -> Option<GuestAddress> {
+        let base = self
+            .mmio_address_space
+            .allocate(None, size, Some(align_size))?;
+        self.high_mmio_address_space = Some(AddressAllocator::new(base, size)?);
+        Some(base)
+    }
+
+    /// Reserves a section of `size` bytes of the high MMIO window, or of the
+    /// MMIO address space if there is no window or not enough room left in it.
+    pub fn allocate_high_mmio_addresses(
+        &mut self,
+        size: GuestUsize,
+        align_size: GuestUsize,
+    ) -> Option<GuestAddress> {
+        self.high_mmio_address_space
+            .as_mut()
+            .and_then(|window| window.allocate(None, size, Some(align_size)))
+            .or_else(|| {
+                self.mmio_address_space
+                    .allocate(None, size, Some(align_size))
+            })
+    }
+
     #[cfg(target_arch = "x86_64")]
     /// Free an IO address range.
     /// We can only free a range if it matches exactly an already allocated range.
@@ -159,4 +191,13 @@ impl SystemAllocator {
     pub fn free_mmio_hole_addresses(&mut self, address: GuestAddress, size: GuestUsize) {
         self.mmio_hole_address_space.free(address, size)
     }
+
+    /// Free an address range allocated through `allocate_high_mmio_addresses()`.
+    /// We can only free a range if it matches exactly an already allocated range.
+    pub fn free_high_mmio_addresses(&mut self, address: GuestAddress, size: GuestUsize) {
+        match self.high_mmio_address_space.as_mut() {
+            Some(window) if window.contains(address) => window.free(address, size),
+            _ => self.mmio_address_space.free(address, size),
+        }
+    }
 }
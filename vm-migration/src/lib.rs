@@ -8,8 +8,11 @@ extern crate thiserror;
 #[macro_use]
 extern crate serde_derive;
 
+use crate::protocol::MemoryRangeTable;
 use thiserror::Error;
 
+pub mod protocol;
+
 #[derive(Error, Debug)]
 pub enum MigratableError {
     #[error("Failed to pause migratable component: {0}")]
@@ -29,6 +32,15 @@ pub enum MigratableError {
 
     #[error("Failed to receive migratable component snapshot: {0}")]
     MigrateReceive(#[source] anyhow::Error),
+
+    #[error("Failed to start dirty pages logging: {0}")]
+    StartDirtyLog(#[source] anyhow::Error),
+
+    #[error("Failed to stop dirty pages logging: {0}")]
+    StopDirtyLog(#[source] anyhow::Error),
+
+    #[error("Failed to retrieve dirty pages: {0}")]
+    DirtyLog(#[source] anyhow::Error),
 }
 
 /// A Pausable component can be paused and resumed.
@@ -159,4 +171,24 @@ pub trait Transportable: Pausable + Snapshottable {
 /// and Snapshottable.
 /// Moreover a migratable component can be transported to a remote or local
 /// destination and thus must be Transportable.
-pub trait Migratable: Send + Pausable + Snapshottable + Transportable {}
+///
+/// Components owning guest memory can also track the pages written by the
+/// guest, so that a live migration only has to re-send what changed since
+/// the previous pre-copy round.
+pub trait Migratable: Send + Pausable + Snapshottable + Transportable {
+    /// Start tracking the guest memory pages being dirtied.
+    fn start_dirty_log(&mut self) -> std::result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    /// Stop tracking the guest memory pages being dirtied.
+    fn stop_dirty_log(&mut self) -> std::result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    /// Retrieve the guest memory ranges dirtied since the last call, or
+    /// since the dirty log was started.
+    fn dirty_log(&mut self) -> std::result::Result<MemoryRangeTable, MigratableError> {
+        Ok(MemoryRangeTable::default())
+    }
+}
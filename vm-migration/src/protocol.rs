@@ -0,0 +1,516 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::MigratableError;
+use anyhow::anyhow;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// Codec applied to the guest pages sent through the migration stream.
+///
+/// Both ends agree on the codec through `request_compression()` and
+/// `accept_compression()` before any memory is sent.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+impl Compression {
+    fn to_raw(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+
+    fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseCompressionError {
+    InvalidValue(String),
+}
+
+impl FromStr for Compression {
+    type Err = ParseCompressionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(ParseCompressionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// Ask the destination to use `compression` for the memory pages, returning
+/// the codec both ends agreed on. The destination falls back to no
+/// compression if it doesn't support the requested codec.
+pub fn request_compression<S: Read + Write>(
+    stream: &mut S,
+    compression: Compression,
+) -> Result<Compression, MigratableError> {
+    stream
+        .write_all(&compression.to_raw().to_le_bytes())
+        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+    let raw = read_u32(stream)?;
+    match Compression::from_raw(raw) {
+        Some(agreed) if agreed == compression || agreed == Compression::None => Ok(agreed),
+        _ => Err(MigratableError::MigrateReceive(anyhow!(
+            "Unexpected compression {} agreed by the destination",
+            raw
+        ))),
+    }
+}
+
+/// Answer the compression request from the source, agreeing on the
+/// requested codec if it is part of `supported`.
+pub fn accept_compression<S: Read + Write>(
+    stream: &mut S,
+    supported: &[Compression],
+) -> Result<Compression, MigratableError> {
+    let agreed = Compression::from_raw(read_u32(stream)?)
+        .filter(|c| supported.contains(c))
+        .unwrap_or(Compression::None);
+
+    stream
+        .write_all(&agreed.to_raw().to_le_bytes())
+        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+    Ok(agreed)
+}
+
+/// Write a chunk of guest memory to the migration stream.
+///
+/// The chunk is preceded by the length of its payload, as a little endian
+/// u32. A payload shorter than the chunk is compressed, otherwise the chunk
+/// is sent raw, which is the case for all chunks when compression is off
+/// and for the ones that don't compress well.
+pub fn write_chunk(
+    fd: &mut dyn Write,
+    data: &[u8],
+    compression: Compression,
+) -> Result<(), MigratableError> {
+    let compressed = match compression {
+        Compression::None => None,
+        Compression::Lz4 => Some(lz4_flex::compress(data)).filter(|c| c.len() < data.len()),
+    };
+    let payload = compressed.as_deref().unwrap_or(data);
+
+    fd.write_all(&(payload.len() as u32).to_le_bytes())
+        .and_then(|_| fd.write_all(payload))
+        .map_err(|e| MigratableError::MigrateSend(e.into()))
+}
+
+/// Read a chunk of guest memory written by `write_chunk()` into `data`,
+/// whose length must match the length of the chunk that was sent.
+pub fn read_chunk(
+    fd: &mut dyn Read,
+    data: &mut [u8],
+    compression: Compression,
+) -> Result<(), MigratableError> {
+    let len = read_u32(fd)? as usize;
+    if len == data.len() {
+        return fd
+            .read_exact(data)
+            .map_err(|e| MigratableError::MigrateReceive(e.into()));
+    }
+    if len > data.len() || compression == Compression::None {
+        return Err(MigratableError::MigrateReceive(anyhow!(
+            "Invalid chunk payload length {} for {} bytes",
+            len,
+            data.len()
+        )));
+    }
+
+    let mut payload = vec![0u8; len];
+    fd.read_exact(&mut payload)
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+    let decompressed = lz4_flex::decompress(&payload, data.len()).map_err(|e| {
+        MigratableError::MigrateReceive(anyhow!("Failed to decompress chunk: {:?}", e))
+    })?;
+    if decompressed.len() != data.len() {
+        return Err(MigratableError::MigrateReceive(anyhow!(
+            "Decompressed chunk is {} bytes instead of {}",
+            decompressed.len(),
+            data.len()
+        )));
+    }
+    data.copy_from_slice(&decompressed);
+
+    Ok(())
+}
+
+/// A contiguous range of guest physical memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct MemoryRange {
+    /// Guest physical address of the start of the range.
+    pub gpa: u64,
+    /// Length of the range in bytes.
+    pub length: u64,
+}
+
+/// A list of guest memory ranges, used to describe which parts of the
+/// guest memory are being sent through the migration stream.
+///
+/// On the stream a table is encoded as the number of ranges followed by
+/// each range's guest physical address and length, all of them as little
+/// endian u64 values.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct MemoryRangeTable {
+    data: Vec<MemoryRange>,
+}
+
+impl MemoryRangeTable {
+    /// Build a table from a dirty pages bitmap, such as the one returned by
+    /// `KVM_GET_DIRTY_LOG`, where bit N of the bitmap tracks the page
+    /// starting at `start_addr + N * page_size`. Adjacent dirty pages are
+    /// merged into a single range.
+    pub fn from_bitmap(bitmap: Vec<u64>, start_addr: u64, page_size: u64) -> Self {
+        let mut table = MemoryRangeTable::default();
+        let mut entry: Option<MemoryRange> = None;
+        for (i, block) in bitmap.iter().enumerate() {
+            for j in 0..64 {
+                let is_page_dirty = ((block >> j) & 1u64) != 0u64;
+                let page_offset = ((i * 64) + j) as u64 * page_size;
+                if is_page_dirty {
+                    if let Some(entry) = &mut entry {
+                        entry.length += page_size;
+                    } else {
+                        entry = Some(MemoryRange {
+                            gpa: start_addr + page_offset,
+                            length: page_size,
+                        });
+                    }
+                } else if let Some(entry) = entry.take() {
+                    table.push(entry);
+                }
+            }
+        }
+        if let Some(entry) = entry.take() {
+            table.push(entry);
+        }
+
+        table
+    }
+
+    /// The ranges of the table.
+    pub fn regions(&self) -> &[MemoryRange] {
+        &self.data
+    }
+
+    /// Append a range to the table.
+    pub fn push(&mut self, range: MemoryRange) {
+        self.data.push(range)
+    }
+
+    /// Append all the ranges of `table` to this table.
+    pub fn extend(&mut self, table: Self) {
+        self.data.extend(table.data)
+    }
+
+    /// Whether the table describes no memory at all.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Amount of guest memory, in bytes, covered by the table.
+    pub fn effective_size(&self) -> u64 {
+        self.data.iter().map(|r| r.length).sum()
+    }
+
+    /// Read a table from the migration stream.
+    pub fn read_from(fd: &mut dyn Read) -> Result<MemoryRangeTable, MigratableError> {
+        let count = read_u64(fd)?;
+        let mut table = MemoryRangeTable::default();
+        for _ in 0..count {
+            let gpa = read_u64(fd)?;
+            let length = read_u64(fd)?;
+            table.push(MemoryRange { gpa, length });
+        }
+
+        Ok(table)
+    }
+
+    /// Write the table to the migration stream.
+    pub fn write_to(&self, fd: &mut dyn Write) -> Result<(), MigratableError> {
+        let mut buf = Vec::with_capacity((1 + 2 * self.data.len()) * 8);
+        buf.extend_from_slice(&(self.data.len() as u64).to_le_bytes());
+        for range in self.data.iter() {
+            buf.extend_from_slice(&range.gpa.to_le_bytes());
+            buf.extend_from_slice(&range.length.to_le_bytes());
+        }
+
+        fd.write_all(&buf)
+            .map_err(|e| MigratableError::MigrateSend(e.into()))
+    }
+}
+
+/// Message sent by the destination of a post-copy migration, while the
+/// guest runs there and the memory it lacks is still on the source.
+///
+/// The source sends the pages as tables, each one followed by the memory it
+/// describes as written by `write_chunk()`, or raw when compression is off.
+/// A table can describe any pages, in any order, the ones being requested
+/// by the destination preempting the background copy of the others. An
+/// empty table tells all the pages have been sent.
+///
+/// On the stream a message is encoded as its kind, as a little endian u32,
+/// followed by its payload.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PostcopyRequest {
+    /// The guest faulted on a range of pages which were not received yet,
+    /// to be sent before any other.
+    Pages(MemoryRange),
+    /// All the pages were received, the source has nothing left to do.
+    Complete,
+}
+
+impl PostcopyRequest {
+    const PAGES: u32 = 1;
+    const COMPLETE: u32 = 2;
+
+    /// Read a request from the migration stream.
+    pub fn read_from(fd: &mut dyn Read) -> Result<PostcopyRequest, MigratableError> {
+        match read_u32(fd)? {
+            Self::PAGES => {
+                let gpa = read_u64(fd)?;
+                let length = read_u64(fd)?;
+                Ok(PostcopyRequest::Pages(MemoryRange { gpa, length }))
+            }
+            Self::COMPLETE => Ok(PostcopyRequest::Complete),
+            kind => Err(MigratableError::MigrateReceive(anyhow!(
+                "Unknown post-copy request {}",
+                kind
+            ))),
+        }
+    }
+
+    /// Write the request to the migration stream.
+    pub fn write_to(&self, fd: &mut dyn Write) -> Result<(), MigratableError> {
+        let mut buf = Vec::with_capacity(20);
+        match self {
+            PostcopyRequest::Pages(range) => {
+                buf.extend_from_slice(&Self::PAGES.to_le_bytes());
+                buf.extend_from_slice(&range.gpa.to_le_bytes());
+                buf.extend_from_slice(&range.length.to_le_bytes());
+            }
+            PostcopyRequest::Complete => buf.extend_from_slice(&Self::COMPLETE.to_le_bytes()),
+        }
+
+        fd.write_all(&buf)
+            .map_err(|e| MigratableError::MigrateSend(e.into()))
+    }
+}
+
+fn read_u64(fd: &mut dyn Read) -> Result<u64, MigratableError> {
+    let mut buf = [0u8; 8];
+    fd.read_exact(&mut buf)
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_u32(fd: &mut dyn Read) -> Result<u32, MigratableError> {
+    let mut buf = [0u8; 4];
+    fd.read_exact(&mut buf)
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    const PAGE_SIZE: u64 = 0x1000;
+
+    #[test]
+    fn test_from_bitmap() {
+        // No dirty page.
+        let table = MemoryRangeTable::from_bitmap(vec![0, 0], 0x10_0000, PAGE_SIZE);
+        assert!(table.is_empty());
+        assert_eq!(table.effective_size(), 0);
+
+        // Pages 0, 2-3 and 63-65 are dirty, the last range spanning across
+        // two bitmap blocks.
+        let bitmap = vec![0b1101 | (1 << 63), 0b11];
+        let table = MemoryRangeTable::from_bitmap(bitmap, 0x10_0000, PAGE_SIZE);
+        assert_eq!(
+            table.regions(),
+            &[
+                MemoryRange {
+                    gpa: 0x10_0000,
+                    length: PAGE_SIZE,
+                },
+                MemoryRange {
+                    gpa: 0x10_0000 + 2 * PAGE_SIZE,
+                    length: 2 * PAGE_SIZE,
+                },
+                MemoryRange {
+                    gpa: 0x10_0000 + 63 * PAGE_SIZE,
+                    length: 3 * PAGE_SIZE,
+                },
+            ]
+        );
+        assert_eq!(table.effective_size(), 6 * PAGE_SIZE);
+
+        // All pages dirty.
+        let table = MemoryRangeTable::from_bitmap(vec![u64::MAX; 2], 0, PAGE_SIZE);
+        assert_eq!(
+            table.regions(),
+            &[MemoryRange {
+                gpa: 0,
+                length: 128 * PAGE_SIZE,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_read_write() {
+        let mut table = MemoryRangeTable::default();
+        table.push(MemoryRange {
+            gpa: 0x1000,
+            length: 0x2000,
+        });
+        table.push(MemoryRange {
+            gpa: 0x10_0000,
+            length: 0x1000,
+        });
+
+        let mut stream = Vec::new();
+        table.write_to(&mut stream).unwrap();
+        assert_eq!(stream.len(), 5 * 8);
+
+        let read_table = MemoryRangeTable::read_from(&mut stream.as_slice()).unwrap();
+        assert_eq!(read_table, table);
+
+        // Truncated stream.
+        assert!(MemoryRangeTable::read_from(&mut &stream[..20]).is_err());
+    }
+
+    #[test]
+    fn test_postcopy_request() {
+        let requests = [
+            PostcopyRequest::Pages(MemoryRange {
+                gpa: 0x10_0000,
+                length: PAGE_SIZE,
+            }),
+            PostcopyRequest::Complete,
+        ];
+
+        let mut stream = Vec::new();
+        for request in requests.iter() {
+            request.write_to(&mut stream).unwrap();
+        }
+        assert_eq!(stream.len(), 20 + 4);
+
+        let mut reader = stream.as_slice();
+        for request in requests.iter() {
+            assert_eq!(PostcopyRequest::read_from(&mut reader).unwrap(), *request);
+        }
+        assert!(PostcopyRequest::read_from(&mut reader).is_err());
+
+        // Unknown and truncated requests.
+        assert!(PostcopyRequest::read_from(&mut &[3u8, 0, 0, 0][..]).is_err());
+        assert!(PostcopyRequest::read_from(&mut &stream[..12]).is_err());
+    }
+
+    // Bytes from a xorshift generator, which LZ4 can't do anything with.
+    fn incompressible_page() -> Vec<u8> {
+        let mut state: u32 = 0x1234_5678;
+        (0..PAGE_SIZE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunk_compression() {
+        let compressible: Vec<u8> = (0..PAGE_SIZE).map(|i| (i % 16) as u8).collect();
+        let incompressible = incompressible_page();
+        let raw_len = 4 + PAGE_SIZE as usize;
+
+        // Compressed page.
+        let mut stream = Vec::new();
+        write_chunk(&mut stream, &compressible, Compression::Lz4).unwrap();
+        assert!(stream.len() < raw_len);
+        let mut page = vec![0u8; PAGE_SIZE as usize];
+        read_chunk(&mut stream.as_slice(), &mut page, Compression::Lz4).unwrap();
+        assert_eq!(page, compressible);
+
+        // A compressed page can't be read without the codec.
+        assert!(read_chunk(&mut stream.as_slice(), &mut page, Compression::None).is_err());
+
+        // Incompressible page, sent raw.
+        let mut stream = Vec::new();
+        write_chunk(&mut stream, &incompressible, Compression::Lz4).unwrap();
+        assert_eq!(stream.len(), raw_len);
+        assert_eq!(&stream[4..], incompressible.as_slice());
+        read_chunk(&mut stream.as_slice(), &mut page, Compression::Lz4).unwrap();
+        assert_eq!(page, incompressible);
+
+        // No compression.
+        let mut stream = Vec::new();
+        write_chunk(&mut stream, &compressible, Compression::None).unwrap();
+        assert_eq!(stream.len(), raw_len);
+        read_chunk(&mut stream.as_slice(), &mut page, Compression::None).unwrap();
+        assert_eq!(page, compressible);
+
+        // Truncated and corrupted streams.
+        let mut stream = Vec::new();
+        write_chunk(&mut stream, &compressible, Compression::Lz4).unwrap();
+        let len = stream.len();
+        assert!(read_chunk(&mut &stream[..len - 1], &mut page, Compression::Lz4).is_err());
+        for b in stream[4..].iter_mut() {
+            *b = 0xff;
+        }
+        assert!(read_chunk(&mut stream.as_slice(), &mut page, Compression::Lz4).is_err());
+    }
+
+    #[test]
+    fn test_compression_negotiation() {
+        fn negotiate(requested: Compression, supported: &'static [Compression]) -> Compression {
+            let (mut src, mut dst) = UnixStream::pair().unwrap();
+            let dst_thread =
+                thread::spawn(move || accept_compression(&mut dst, supported).unwrap());
+            let agreed = request_compression(&mut src, requested).unwrap();
+            assert_eq!(dst_thread.join().unwrap(), agreed);
+            agreed
+        }
+
+        assert_eq!(
+            negotiate(Compression::Lz4, &[Compression::Lz4]),
+            Compression::Lz4
+        );
+        assert_eq!(negotiate(Compression::Lz4, &[]), Compression::None);
+        assert_eq!(
+            negotiate(Compression::None, &[Compression::Lz4]),
+            Compression::None
+        );
+
+        assert_eq!("lz4".parse::<Compression>().unwrap(), Compression::Lz4);
+        assert_eq!("none".parse::<Compression>().unwrap(), Compression::None);
+        assert!("zstd".parse::<Compression>().is_err());
+    }
+}
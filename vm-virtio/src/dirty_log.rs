@@ -0,0 +1,96 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use vm_memory::{Address, GuestAddress};
+
+/// Size of the pages logged by a `DirtyLog`, the same as the hypervisor's
+/// dirty log.
+pub const DIRTY_LOG_PAGE_SIZE: u64 = 4096;
+
+/// The guest pages written by the VMM on behalf of the devices, which the
+/// hypervisor doesn't see, logged while a live migration is in progress.
+///
+/// The pages are logged between `start()` and `stop()` only, logging them
+/// otherwise costing a single atomic load.
+#[derive(Default)]
+pub struct DirtyLog {
+    enabled: AtomicBool,
+    pages: Mutex<BTreeSet<u64>>,
+}
+
+impl DirtyLog {
+    /// Start logging the pages, dropping whatever was logged before.
+    pub fn start(&self) {
+        let mut pages = self.pages.lock().unwrap();
+        pages.clear();
+        self.enabled.store(true, Ordering::Release);
+    }
+
+    /// Stop logging the pages, dropping the ones not taken yet.
+    pub fn stop(&self) {
+        let mut pages = self.pages.lock().unwrap();
+        self.enabled.store(false, Ordering::Release);
+        pages.clear();
+    }
+
+    /// Whether the pages are being logged.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Log the pages covering the `len` bytes at `addr` as dirty.
+    pub fn mark(&self, addr: GuestAddress, len: u64) {
+        if len == 0 || !self.is_enabled() {
+            return;
+        }
+
+        let first = addr.raw_value() / DIRTY_LOG_PAGE_SIZE;
+        let last = addr.raw_value().saturating_add(len - 1) / DIRTY_LOG_PAGE_SIZE;
+        let mut pages = self.pages.lock().unwrap();
+        // The flag may have been cleared while waiting for the lock.
+        if self.is_enabled() {
+            pages.extend(first..=last);
+        }
+    }
+
+    /// Take the page frame numbers logged since the previous call, in
+    /// ascending order.
+    pub fn take(&self) -> Vec<u64> {
+        let mut pages = self.pages.lock().unwrap();
+        std::mem::take(&mut *pages).into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_log() {
+        let log = DirtyLog::default();
+
+        // Nothing is logged until started.
+        log.mark(GuestAddress(0x1000), 0x10);
+        log.start();
+        assert!(log.take().is_empty());
+
+        log.mark(GuestAddress(0x3000), 0);
+        log.mark(GuestAddress(0x5ff0), 0x20);
+        log.mark(GuestAddress(0x1000), 0x1000);
+        log.mark(GuestAddress(0x1fff), 1);
+        assert_eq!(log.take(), vec![1, 5, 6]);
+        assert!(log.take().is_empty());
+
+        // What wasn't taken is dropped once stopped.
+        log.mark(GuestAddress(0x2000), 0x10);
+        log.stop();
+        log.mark(GuestAddress(0x4000), 0x10);
+        assert!(!log.is_enabled());
+        assert!(log.take().is_empty());
+    }
+}
@@ -17,7 +17,9 @@ extern crate serde_derive;
 
 use std::fmt;
 
+pub mod dirty_log;
 pub mod queue;
+pub use dirty_log::*;
 pub use queue::*;
 
 pub type VirtioIommuRemapping =
@@ -40,8 +42,10 @@ pub enum VirtioDeviceType {
     TYPE_VSOCK = 19,
     TYPE_IOMMU = 23,
     TYPE_MEM = 24,
+    TYPE_SOUND = 25,
     TYPE_FS = 26,
     TYPE_PMEM = 27,
+    TYPE_WATCHDOG = 35, // Temporary until an official number gets allocated
     TYPE_UNKNOWN = 0xFF,
 }
 
@@ -59,8 +63,10 @@ impl From<u32> for VirtioDeviceType {
             19 => VirtioDeviceType::TYPE_VSOCK,
             23 => VirtioDeviceType::TYPE_IOMMU,
             24 => VirtioDeviceType::TYPE_MEM,
+            25 => VirtioDeviceType::TYPE_SOUND,
             26 => VirtioDeviceType::TYPE_FS,
             27 => VirtioDeviceType::TYPE_PMEM,
+            35 => VirtioDeviceType::TYPE_WATCHDOG,
             _ => VirtioDeviceType::TYPE_UNKNOWN,
         }
     }
@@ -83,8 +89,10 @@ impl fmt::Display for VirtioDeviceType {
             VirtioDeviceType::TYPE_VSOCK => "vsock",
             VirtioDeviceType::TYPE_IOMMU => "iommu",
             VirtioDeviceType::TYPE_MEM => "mem",
+            VirtioDeviceType::TYPE_SOUND => "sound",
             VirtioDeviceType::TYPE_FS => "fs",
             VirtioDeviceType::TYPE_PMEM => "pmem",
+            VirtioDeviceType::TYPE_WATCHDOG => "watchdog",
             VirtioDeviceType::TYPE_UNKNOWN => "UNKNOWN",
         };
         write!(f, "{}", output)
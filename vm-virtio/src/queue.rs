@@ -8,7 +8,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use crate::VirtioIommuRemapping;
+use crate::{DirtyLog, VirtioIommuRemapping};
 use std::cmp::min;
 use std::convert::TryInto;
 use std::fmt::{self, Display};
@@ -98,6 +98,25 @@ pub struct Descriptor {
 
 unsafe impl ByteValued for Descriptor {}
 
+// Translate a descriptor address from the I/O virtual address space to the
+// guest physical address space, when the device is behind the virtual IOMMU.
+fn translate_desc_addr(
+    iommu_mapping_cb: &Option<Arc<VirtioIommuRemapping>>,
+    addr: u64,
+) -> Option<u64> {
+    if let Some(iommu_mapping_cb) = iommu_mapping_cb {
+        match (iommu_mapping_cb)(addr) {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                error!("Failed to translate descriptor address 0x{:x}: {}", addr, e);
+                None
+            }
+        }
+    } else {
+        Some(addr)
+    }
+}
+
 /// A virtio descriptor head, not tied to a GuestMemoryMmap.
 pub struct DescriptorHead {
     desc_table: GuestAddress,
@@ -163,11 +182,7 @@ impl<'a> DescriptorChain<'a> {
         };
 
         // Translate address if necessary
-        let desc_addr = if let Some(iommu_mapping_cb) = &iommu_mapping_cb {
-            (iommu_mapping_cb)(desc.addr).unwrap()
-        } else {
-            desc.addr
-        };
+        let desc_addr = translate_desc_addr(&iommu_mapping_cb, desc.addr)?;
 
         let chain = DescriptorChain {
             mem,
@@ -189,14 +204,28 @@ impl<'a> DescriptorChain<'a> {
         }
     }
 
+    /// Returns the first descriptor of the indirect table this descriptor
+    /// points to.
+    ///
+    /// The address of this descriptor has already been translated, which
+    /// means it can be used as is to locate the indirect table. The buffers
+    /// from the indirect table are translated the same way the ones from the
+    /// descriptor table are.
     pub fn new_from_indirect(&self) -> Result<DescriptorChain, Error> {
         if !self.is_indirect() {
             return Err(Error::InvalidIndirectDescriptor);
         }
 
+        let table_size: u16 = (self.len / 16)
+            .try_into()
+            .map_err(|_| Error::InvalidIndirectDescriptor)?;
+        if table_size == 0 || self.len % 16 != 0 {
+            return Err(Error::InvalidIndirectDescriptor);
+        }
+
         let desc_head = self.addr;
         self.mem
-            .checked_offset(desc_head, 16)
+            .checked_offset(desc_head, self.len as usize)
             .ok_or(Error::GuestMemoryError)?;
 
         // These reads can't fail unless Guest memory is hopelessly broken.
@@ -206,27 +235,20 @@ impl<'a> DescriptorChain<'a> {
         };
 
         // Translate address if necessary
-        let (desc_addr, iommu_mapping_cb) =
-            if let Some(iommu_mapping_cb) = self.iommu_mapping_cb.clone() {
-                (
-                    (iommu_mapping_cb)(desc.addr).unwrap(),
-                    Some(iommu_mapping_cb),
-                )
-            } else {
-                (desc.addr, None)
-            };
+        let desc_addr =
+            translate_desc_addr(&self.iommu_mapping_cb, desc.addr).ok_or(Error::InvalidChain)?;
 
         let chain = DescriptorChain {
             mem: self.mem,
             desc_table: self.addr,
-            table_size: (self.len / 16).try_into().unwrap(),
-            ttl: (self.len / 16).try_into().unwrap(),
+            table_size,
+            ttl: table_size,
             index: 0,
             addr: GuestAddress(desc_addr),
             len: desc.len,
             flags: desc.flags,
             next: desc.next,
-            iommu_mapping_cb,
+            iommu_mapping_cb: self.iommu_mapping_cb.clone(),
         };
 
         if !chain.is_valid() {
@@ -424,11 +446,19 @@ pub struct Queue {
     #[serde(skip)]
     pub iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
 
+    /// Log of the guest pages written through the used descriptors
+    #[serde(skip)]
+    pub dirty_log: Option<Arc<DirtyLog>>,
+
     /// VIRTIO_F_RING_EVENT_IDX negotiated
     event_idx: bool,
 
     /// The last used value when using EVENT_IDX
     signalled_used: Option<Wrapping<u16>>,
+
+    /// VIRTIO_F_IN_ORDER negotiated
+    #[serde(default)]
+    in_order: bool,
 }
 
 impl Queue {
@@ -445,8 +475,10 @@ impl Queue {
             next_avail: Wrapping(0),
             next_used: Wrapping(0),
             iommu_mapping_cb: None,
+            dirty_log: None,
             event_idx: false,
             signalled_used: None,
+            in_order: false,
         }
     }
 
@@ -597,6 +629,9 @@ impl Queue {
         match mem.checked_offset(self.used_ring, (4 + self.actual_size() * 8) as usize) {
             Some(a) => {
                 mem.write_obj(last_index, a).unwrap();
+                if let Some(dirty_log) = &self.dirty_log {
+                    dirty_log.mark(a, 2);
+                }
             }
             None => warn!("Can't update avail_event"),
         }
@@ -653,9 +688,93 @@ impl Queue {
         mem.write_obj(self.next_used.0 as u16, used_ring.unchecked_add(2))
             .unwrap();
 
+        self.log_used(mem, std::iter::once(desc_index));
+
+        Some(self.next_used.0)
+    }
+
+    /// Puts several descriptor heads into the used ring at once, the guest
+    /// only seeing them once they're all there. Returns the new used index,
+    /// or None if any of the heads is out of bounds, in which case nothing
+    /// is added.
+    ///
+    /// The heads must be in the order the driver made them available when
+    /// VIRTIO_F_IN_ORDER was negotiated: only the last one is written then,
+    /// in its own slot of the used ring, the others being implied by it.
+    pub fn add_used_batch(&mut self, mem: &GuestMemoryMmap, elems: &[(u16, u32)]) -> Option<u16> {
+        if let Some((desc_index, _)) = elems.iter().find(|(i, _)| *i >= self.actual_size()) {
+            error!(
+                "attempted to add out of bounds descriptor to used ring: {}",
+                desc_index
+            );
+            return None;
+        }
+
+        let used_ring = self.used_ring;
+        let written = if self.in_order && !elems.is_empty() {
+            self.next_used += Wrapping(elems.len() as u16 - 1);
+            &elems[elems.len() - 1..]
+        } else {
+            elems
+        };
+        for (desc_index, len) in written {
+            let next_used = u64::from(self.next_used.0 % self.actual_size());
+            let used_elem = used_ring.unchecked_add(4 + next_used * 8);
+
+            // These writes can't fail as we are guaranteed to be within the descriptor ring.
+            mem.write_obj(u32::from(*desc_index), used_elem).unwrap();
+            mem.write_obj(*len, used_elem.unchecked_add(4)).unwrap();
+
+            self.next_used += Wrapping(1);
+        }
+
+        // This fence ensures all descriptor writes are visible before the index update is.
+        fence(Ordering::Release);
+
+        mem.write_obj(self.next_used.0 as u16, used_ring.unchecked_add(2))
+            .unwrap();
+
+        self.log_used(mem, elems.iter().map(|(desc_index, _)| *desc_index));
+
         Some(self.next_used.0)
     }
 
+    // Log the used ring and the buffers the device may have written into,
+    // the writable descriptors of the chains just used, when the guest
+    // memory is being migrated. The whole buffers are logged, whatever
+    // the length reported to the driver.
+    fn log_used<I: IntoIterator<Item = u16>>(&self, mem: &GuestMemoryMmap, heads: I) {
+        let dirty_log = match &self.dirty_log {
+            Some(dirty_log) if dirty_log.is_enabled() => dirty_log,
+            _ => return,
+        };
+
+        dirty_log.mark(self.used_ring, 4 + u64::from(self.actual_size()) * 8);
+        for head in heads {
+            let chain = match DescriptorChain::checked_new(
+                mem,
+                self.desc_table,
+                self.actual_size(),
+                head,
+                self.iommu_mapping_cb.clone(),
+            ) {
+                Some(chain) => chain,
+                None => continue,
+            };
+            for desc in chain {
+                if desc.is_indirect() {
+                    if let Ok(table) = desc.new_from_indirect() {
+                        for desc in table.into_iter().writable() {
+                            dirty_log.mark(desc.addr, u64::from(desc.len));
+                        }
+                    }
+                } else if desc.is_write_only() {
+                    dirty_log.mark(desc.addr, u64::from(desc.len));
+                }
+            }
+        }
+    }
+
     /// Goes back one position in the available descriptor chain offered by the driver.
     /// Rust does not support bidirectional iterators. This is the only way to revert the effect
     /// of an iterator increment on the queue.
@@ -692,6 +811,10 @@ impl Queue {
         self.event_idx = enabled;
     }
 
+    pub fn set_in_order(&mut self, enabled: bool) {
+        self.in_order = enabled;
+    }
+
     pub fn needs_notification(&mut self, mem: &GuestMemoryMmap, used_idx: Wrapping<u16>) -> bool {
         if !self.event_idx {
             return true;
@@ -1061,6 +1184,56 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn test_new_from_indirect_translated() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // Synthetic I/O virtual address space, offset by 0x8000 from the
+        // guest physical one, with nothing mapped above 0x4000.
+        let translate = |iova: u64| {
+            if iova < 0x4000 {
+                Ok(iova + 0x8000)
+            } else {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "unmapped address",
+                ))
+            }
+        };
+        let iommu_mapping_cb = Some(Arc::new(Box::new(translate) as VirtioIommuRemapping));
+
+        // The indirect table lives at IOVA 0x1000, hence GPA 0x9000.
+        vq.dtable[0].set(0x1000, 0x20, VIRTQ_DESC_F_INDIRECT, 0);
+        let c =
+            DescriptorChain::checked_new(m, vq.start(), 16, 0, iommu_mapping_cb.clone()).unwrap();
+        assert!(c.is_indirect());
+        assert_eq!(c.addr, GuestAddress(0x9000));
+
+        // Only the translated location holds the indirect table.
+        let desc = VirtqDesc::new(GuestAddress(0x9000), m);
+        desc.set(0x2000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        let desc = VirtqDesc::new(GuestAddress(0x9010), m);
+        desc.set(0x3000, 0x10, VIRTQ_DESC_F_WRITE, 0);
+
+        let i = c.new_from_indirect().unwrap();
+        assert_eq!(i.addr, GuestAddress(0xa000));
+        assert_eq!(i.len, 0x100);
+        let i = i.next_descriptor().unwrap();
+        assert_eq!(i.addr, GuestAddress(0xb000));
+        assert!(i.is_write_only());
+        assert!(i.next_descriptor().is_none());
+
+        // A buffer which can't be translated invalidates the chain instead
+        // of being accessed at its untranslated address.
+        let desc = VirtqDesc::new(GuestAddress(0x9000), m);
+        desc.set(0x5000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        assert!(c.new_from_indirect().is_err());
+
+        vq.dtable[0].set(0x6000, 0x20, VIRTQ_DESC_F_INDIRECT, 0);
+        assert!(DescriptorChain::checked_new(m, vq.start(), 16, 0, iommu_mapping_cb).is_none());
+    }
+
     #[test]
     fn test_queue_and_iterator() {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
@@ -1185,4 +1358,125 @@ pub mod tests {
         assert_eq!(x.id, 1);
         assert_eq!(x.len, 0x1000);
     }
+
+    #[test]
+    fn test_add_used_dirty_log() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // A readable buffer followed by a writable one across two pages.
+        vq.dtable[0].set(0x2000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x3800, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        // The same through an indirect table.
+        vq.dtable[2].set(0x6000, 32, VIRTQ_DESC_F_INDIRECT, 0);
+        m.write_obj(
+            Descriptor {
+                addr: 0x8000,
+                len: 0x10,
+                flags: VIRTQ_DESC_F_NEXT,
+                next: 1,
+            },
+            GuestAddress(0x6000),
+        )
+        .unwrap();
+        m.write_obj(
+            Descriptor {
+                addr: 0x9000,
+                len: 0x10,
+                flags: VIRTQ_DESC_F_WRITE,
+                next: 0,
+            },
+            GuestAddress(0x6010),
+        )
+        .unwrap();
+
+        let dirty_log = Arc::new(DirtyLog::default());
+        let mut q = vq.create_queue();
+        q.dirty_log = Some(dirty_log.clone());
+
+        // Nothing is logged unless the log was started.
+        q.add_used(m, 0, 0x10);
+        assert!(dirty_log.take().is_empty());
+
+        // The used ring is logged along with the writable buffers only.
+        dirty_log.start();
+        q.add_used(m, 0, 0x10);
+        assert_eq!(dirty_log.take(), vec![0, 3, 4]);
+        q.add_used_batch(m, &[(2, 0x10)]);
+        assert_eq!(dirty_log.take(), vec![0, 9]);
+        q.update_avail_event(m);
+        assert_eq!(dirty_log.take(), vec![0]);
+    }
+
+    #[test]
+    fn test_add_used_batch() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue();
+
+        // Nothing gets added if any index is too large.
+        assert_eq!(q.add_used_batch(m, &[(1, 0x1000), (16, 0x1000)]), None);
+        assert_eq!(vq.used.idx.get(), 0);
+
+        assert_eq!(q.add_used_batch(m, &[(3, 0x1000), (5, 0x200)]), Some(2));
+        assert_eq!(vq.used.idx.get(), 2);
+        let x = vq.used.ring[0].get();
+        assert_eq!((x.id, x.len), (3, 0x1000));
+        let x = vq.used.ring[1].get();
+        assert_eq!((x.id, x.len), (5, 0x200));
+    }
+
+    #[test]
+    fn test_add_used_batch_in_order() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let batch = [(3, 0x1000), (4, 0x1000), (5, 0x200)];
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let unused = VirtqUsedElem {
+            id: 0xff,
+            len: 0xff,
+        };
+        for i in 0..3 {
+            vq.used.ring[i].set(unused);
+        }
+
+        // Every head is written without VIRTIO_F_IN_ORDER.
+        let mut q = vq.create_queue();
+        assert_eq!(q.add_used_batch(m, &batch), Some(3));
+        assert_eq!(vq.used.idx.get(), 3);
+        for (i, (id, len)) in batch.iter().enumerate() {
+            let x = vq.used.ring[i].get();
+            assert_eq!((x.id, x.len), (u32::from(*id), *len));
+        }
+
+        // Only the last one with it, the used index still accounting for
+        // all of them.
+        for i in 0..3 {
+            vq.used.ring[i].set(unused);
+        }
+        let mut q = vq.create_queue();
+        q.set_in_order(true);
+        assert_eq!(q.add_used_batch(m, &batch), Some(3));
+        assert_eq!(vq.used.idx.get(), 3);
+        for i in 0..2 {
+            let x = vq.used.ring[i].get();
+            assert_eq!((x.id, x.len), (0xff, 0xff));
+        }
+        let x = vq.used.ring[2].get();
+        assert_eq!((x.id, x.len), (5, 0x200));
+
+        // Across the end of the ring.
+        q.next_used = Wrapping(15);
+        vq.used.ring[15].set(unused);
+        assert_eq!(q.add_used_batch(m, &batch), Some(18));
+        assert_eq!(vq.used.ring[15].get().id, 0xff);
+        let x = vq.used.ring[1].get();
+        assert_eq!((x.id, x.len), (5, 0x200));
+
+        // A single head is written as is.
+        assert_eq!(q.add_used_batch(m, &[(7, 0x10)]), Some(19));
+        let x = vq.used.ring[2].get();
+        assert_eq!((x.id, x.len), (7, 0x10));
+        assert_eq!(q.add_used_batch(m, &[]), Some(19));
+    }
 }
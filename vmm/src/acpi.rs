@@ -7,7 +7,7 @@ use acpi_tables::{
     rsdp::RSDP,
     sdt::{GenericAddress, SDT},
 };
-use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
 use vm_memory::{Address, ByteValued, Bytes};
 
@@ -16,7 +16,7 @@ use std::sync::{Arc, Mutex};
 use crate::cpu::CpuManager;
 use crate::device_manager::DeviceManager;
 use crate::memory_manager::MemoryManager;
-use arch::layout;
+use arch::{layout, numa_distance, numa_node_of_cpu, NumaNodes};
 
 #[repr(packed)]
 #[derive(Default)]
@@ -28,6 +28,163 @@ struct PCIRangeEntry {
     _reserved: u32,
 }
 
+#[repr(packed)]
+#[derive(Default)]
+struct MemoryAffinity {
+    pub r#type: u8,
+    pub length: u8,
+    pub proximity_domain: u32,
+    _reserved1: u16,
+    pub base_addr_lo: u32,
+    pub base_addr_hi: u32,
+    pub length_lo: u32,
+    pub length_hi: u32,
+    _reserved2: u32,
+    pub flags: u32,
+    _reserved3: u64,
+}
+
+#[repr(packed)]
+#[derive(Default)]
+struct ProcessorLocalX2ApicAffinity {
+    pub r#type: u8,
+    pub length: u8,
+    _reserved1: u16,
+    pub proximity_domain: u32,
+    pub x2apic_id: u32,
+    pub flags: u32,
+    pub clock_domain: u32,
+    _reserved2: u32,
+}
+
+const MEMORY_AFFINITY_ENABLED: u32 = 1 << 0;
+const MEMORY_AFFINITY_HOTPLUGGABLE: u32 = 1 << 1;
+const PROCESSOR_AFFINITY_ENABLED: u32 = 1 << 0;
+
+impl MemoryAffinity {
+    fn new(base_addr: u64, size: u64, proximity_domain: u32, flags: u32) -> Self {
+        MemoryAffinity {
+            r#type: 1,
+            length: 40,
+            proximity_domain,
+            base_addr_lo: base_addr as u32,
+            base_addr_hi: (base_addr >> 32) as u32,
+            length_lo: size as u32,
+            length_hi: (size >> 32) as u32,
+            flags,
+            ..Default::default()
+        }
+    }
+}
+
+// The SRAT is only needed when some guest NUMA nodes are described, or some
+// memory zones are bound to one. The boot RAM belongs to the node 0, and so
+// do the vCPUs not assigned to any node. A node may end up without any
+// memory, or without any vCPU, the guest handling both cases.
+fn create_srat_table(
+    guest_mem: &GuestMemoryMmap,
+    cpu_manager: &Arc<Mutex<CpuManager>>,
+    memory_manager: &Arc<Mutex<MemoryManager>>,
+    numa_nodes: &NumaNodes,
+) -> Option<SDT> {
+    let memory_manager = memory_manager.lock().unwrap();
+    if numa_nodes.is_empty()
+        && memory_manager
+            .virtiomem_zones
+            .values()
+            .all(|zone| zone.guest_numa_node().is_none())
+    {
+        return None;
+    }
+
+    let cpu_manager = cpu_manager.lock().unwrap();
+    let cpus: Vec<(u32, u32)> = (0..cpu_manager.max_vcpus())
+        .map(|cpu| {
+            (
+                u32::from(cpu_manager.apic_id(cpu)),
+                numa_node_of_cpu(numa_nodes, cpu),
+            )
+        })
+        .collect();
+
+    let mut memory = Vec::new();
+    let _: Result<(), ()> = guest_mem.with_regions_mut(|_, region| {
+        memory.push(MemoryAffinity::new(
+            region.start_addr().raw_value(),
+            region.len(),
+            0,
+            MEMORY_AFFINITY_ENABLED,
+        ));
+        Ok(())
+    });
+
+    for zone in memory_manager.virtiomem_zones.values() {
+        memory.push(MemoryAffinity::new(
+            zone.region().start_addr().raw_value(),
+            zone.region().len(),
+            zone.guest_numa_node().unwrap_or(0),
+            MEMORY_AFFINITY_ENABLED | MEMORY_AFFINITY_HOTPLUGGABLE,
+        ));
+    }
+
+    Some(srat_table(&cpus, memory))
+}
+
+// SRAT assigning the vCPUs, given as (x2APIC id, proximity domain) pairs, and
+// the memory ranges to their node.
+fn srat_table(cpus: &[(u32, u32)], memory: Vec<MemoryAffinity>) -> SDT {
+    let mut srat = SDT::new(*b"SRAT", 36, 3, *b"CLOUDH", *b"CHSRAT  ", 1);
+    // Reserved fields, the first one must be set to 1 for compatibility.
+    srat.append(1u32);
+    srat.append(0u64);
+
+    for (x2apic_id, proximity_domain) in cpus {
+        srat.append(ProcessorLocalX2ApicAffinity {
+            r#type: 2,
+            length: 24,
+            proximity_domain: *proximity_domain,
+            x2apic_id: *x2apic_id,
+            flags: PROCESSOR_AFFINITY_ENABLED,
+            ..Default::default()
+        });
+    }
+
+    for affinity in memory {
+        srat.append(affinity);
+    }
+
+    srat.update_checksum();
+
+    srat
+}
+
+// The SLIT is only needed when there are several guest NUMA nodes, the
+// distances not given being the default remote one. The matrix covers every
+// identifier up to the highest one, as the localities are indexed by
+// proximity domain.
+fn create_slit_table(numa_nodes: &NumaNodes) -> Option<SDT> {
+    if numa_nodes.len() < 2 {
+        return None;
+    }
+
+    let localities = numa_nodes.keys().max().map_or(0, |id| id + 1);
+
+    let mut slit = SDT::new(*b"SLIT", 36, 1, *b"CLOUDH", *b"CHSLIT  ", 1);
+    slit.append(u64::from(localities));
+
+    let mut matrix = Vec::new();
+    for from in 0..localities {
+        for to in 0..localities {
+            matrix.push(numa_distance(numa_nodes, from, to));
+        }
+    }
+    slit.append_slice(&matrix);
+
+    slit.update_checksum();
+
+    Some(slit)
+}
+
 pub fn create_dsdt_table(
     device_manager: &Arc<Mutex<DeviceManager>>,
     cpu_manager: &Arc<Mutex<CpuManager>>,
@@ -48,6 +205,7 @@ pub fn create_acpi_tables(
     device_manager: &Arc<Mutex<DeviceManager>>,
     cpu_manager: &Arc<Mutex<CpuManager>>,
     memory_manager: &Arc<Mutex<MemoryManager>>,
+    numa_nodes: &NumaNodes,
 ) -> GuestAddress {
     // RSDP is at the EBDA
     let rsdp_offset = layout::RSDP_POINTER;
@@ -110,14 +268,19 @@ pub fn create_acpi_tables(
     // MCFG reserved 8 bytes
     mcfg.append(0u64);
 
-    // 32-bit PCI enhanced configuration mechanism
-    mcfg.append(PCIRangeEntry {
-        base_address: layout::PCI_MMCONFIG_START.0,
-        segment: 0,
-        start: 0,
-        end: 0xff,
-        ..Default::default()
-    });
+    // 32-bit PCI enhanced configuration mechanism, for the single bus of
+    // each PCI segment
+    let num_pci_segments = device_manager.lock().unwrap().num_pci_segments();
+    for segment in 0..num_pci_segments {
+        mcfg.append(PCIRangeEntry {
+            base_address: layout::PCI_MMCONFIG_START.0
+                + u64::from(segment) * layout::PCI_MMIO_CONFIG_SIZE_PER_SEGMENT,
+            segment,
+            start: 0,
+            end: 0,
+            ..Default::default()
+        });
+    }
 
     let mcfg_offset = madt_offset.checked_add(madt.len() as u64).unwrap();
     guest_mem
@@ -125,6 +288,31 @@ pub fn create_acpi_tables(
         .expect("Error writing MCFG table");
     tables.push(mcfg_offset.0);
 
+    // SRAT
+    let (last_offset, last_len) =
+        if let Some(srat) = create_srat_table(guest_mem, cpu_manager, memory_manager, numa_nodes) {
+            let srat_offset = mcfg_offset.checked_add(mcfg.len() as u64).unwrap();
+            guest_mem
+                .write_slice(srat.as_slice(), srat_offset)
+                .expect("Error writing SRAT table");
+            tables.push(srat_offset.0);
+            (srat_offset, srat.len())
+        } else {
+            (mcfg_offset, mcfg.len())
+        };
+
+    // SLIT
+    let (last_offset, last_len) = if let Some(slit) = create_slit_table(numa_nodes) {
+        let slit_offset = last_offset.checked_add(last_len as u64).unwrap();
+        guest_mem
+            .write_slice(slit.as_slice(), slit_offset)
+            .expect("Error writing SLIT table");
+        tables.push(slit_offset.0);
+        (slit_offset, slit.len())
+    } else {
+        (last_offset, last_len)
+    };
+
     // XSDT
     let mut xsdt = SDT::new(*b"XSDT", 36, 1, *b"CLOUDH", *b"CHXSDT  ", 1);
     for table in tables {
@@ -132,7 +320,7 @@ pub fn create_acpi_tables(
     }
     xsdt.update_checksum();
 
-    let xsdt_offset = mcfg_offset.checked_add(mcfg.len() as u64).unwrap();
+    let xsdt_offset = last_offset.checked_add(last_len as u64).unwrap();
     guest_mem
         .write_slice(xsdt.as_slice(), xsdt_offset)
         .expect("Error writing XSDT table");
@@ -145,3 +333,129 @@ pub fn create_acpi_tables(
 
     rsdp_offset
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arch::NumaNode;
+    use std::collections::BTreeMap;
+
+    fn read_u32(table: &SDT, offset: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&table.as_slice()[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn read_u64(table: &SDT, offset: usize) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&table.as_slice()[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn checksum(table: &SDT) -> u8 {
+        table
+            .as_slice()
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b))
+    }
+
+    // Two nodes of two vCPUs each, the node 1 holding a hotpluggable zone,
+    // and the distance between them being 30.
+    fn two_nodes() -> NumaNodes {
+        let mut numa_nodes = BTreeMap::new();
+        let mut distances = BTreeMap::new();
+        distances.insert(1, 30);
+        numa_nodes.insert(
+            0,
+            NumaNode {
+                cpus: vec![0, 1],
+                distances,
+            },
+        );
+        let mut distances = BTreeMap::new();
+        distances.insert(0, 30);
+        numa_nodes.insert(
+            1,
+            NumaNode {
+                cpus: vec![2, 3],
+                distances,
+            },
+        );
+        numa_nodes
+    }
+
+    #[test]
+    fn test_srat_two_nodes() {
+        let numa_nodes = two_nodes();
+        let cpus: Vec<(u32, u32)> = (0..4u8)
+            .map(|cpu| (u32::from(cpu), numa_node_of_cpu(&numa_nodes, cpu)))
+            .collect();
+        let memory = vec![
+            MemoryAffinity::new(0, 0x8000_0000, 0, MEMORY_AFFINITY_ENABLED),
+            MemoryAffinity::new(
+                0x1_0000_0000,
+                0x4000_0000,
+                1,
+                MEMORY_AFFINITY_ENABLED | MEMORY_AFFINITY_HOTPLUGGABLE,
+            ),
+        ];
+        let srat = srat_table(&cpus, memory);
+
+        assert_eq!(&srat.as_slice()[..4], b"SRAT");
+        assert_eq!(srat.len(), 48 + 4 * 24 + 2 * 40);
+        assert_eq!(read_u32(&srat, 4) as usize, srat.len());
+        assert_eq!(checksum(&srat), 0);
+
+        // The vCPUs, type 2, along with their proximity domain.
+        for (cpu, expected) in [0, 0, 1, 1].iter().enumerate() {
+            let entry = 48 + cpu * 24;
+            assert_eq!(srat.as_slice()[entry..entry + 2], [2, 24]);
+            assert_eq!(read_u32(&srat, entry + 4), *expected);
+            assert_eq!(read_u32(&srat, entry + 8), cpu as u32);
+            assert_eq!(read_u32(&srat, entry + 12), PROCESSOR_AFFINITY_ENABLED);
+        }
+
+        // The memory ranges, type 1.
+        let expected = [
+            (0, 0, 0x8000_0000, MEMORY_AFFINITY_ENABLED),
+            (
+                1,
+                0x1_0000_0000,
+                0x4000_0000,
+                MEMORY_AFFINITY_ENABLED | MEMORY_AFFINITY_HOTPLUGGABLE,
+            ),
+        ];
+        for (i, (node, base, size, flags)) in expected.iter().enumerate() {
+            let entry = 48 + 4 * 24 + i * 40;
+            assert_eq!(srat.as_slice()[entry..entry + 2], [1, 40]);
+            assert_eq!(read_u32(&srat, entry + 2), *node);
+            assert_eq!(read_u64(&srat, entry + 8), *base);
+            assert_eq!(read_u64(&srat, entry + 16), *size);
+            assert_eq!(read_u32(&srat, entry + 28), *flags);
+        }
+    }
+
+    #[test]
+    fn test_slit_two_nodes() {
+        // A single node doesn't need any SLIT.
+        let mut numa_nodes = two_nodes();
+        numa_nodes.remove(&1);
+        assert!(create_slit_table(&numa_nodes).is_none());
+
+        let slit = create_slit_table(&two_nodes()).unwrap();
+        assert_eq!(&slit.as_slice()[..4], b"SLIT");
+        assert_eq!(slit.len(), 36 + 8 + 4);
+        assert_eq!(read_u32(&slit, 4) as usize, slit.len());
+        assert_eq!(checksum(&slit), 0);
+        assert_eq!(read_u64(&slit, 36), 2);
+        assert_eq!(slit.as_slice()[44..], [10, 30, 30, 10]);
+
+        // Without any distance, the nodes are the default distance apart.
+        let mut numa_nodes = two_nodes();
+        for node in numa_nodes.values_mut() {
+            node.distances.clear();
+        }
+        let slit = create_slit_table(&numa_nodes).unwrap();
+        assert_eq!(slit.as_slice()[44..], [10, 20, 20, 10]);
+    }
+}
@@ -3,8 +3,12 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::api::http_endpoint::{VmActionHandler, VmCreate, VmInfo, VmmPing, VmmShutdown};
+use crate::api::http_endpoint::{
+    VmActionHandler, VmCreate, VmInfo, VmMigrationAbort, VmMigrationStatus, VmmPing, VmmShutdown,
+};
 use crate::api::{ApiError, ApiRequest, VmAction};
+use crate::config::ValidationError;
+use crate::migration_progress::AbortError;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::{Error, Result};
 use micro_http::{Body, HttpServer, MediaType, Method, Request, Response, StatusCode, Version};
@@ -26,6 +30,9 @@ pub enum HttpError {
     /// Attempt to access unsupported HTTP method
     BadRequest,
 
+    /// The provided configuration is invalid
+    InvalidConfig(ValidationError),
+
     /// Undefined endpoints
     NotFound,
 
@@ -59,15 +66,39 @@ pub enum HttpError {
     /// Could not snapshot a VM
     VmSnapshot(ApiError),
 
+    /// Could not consolidate an incremental snapshot chain
+    VmSnapshotConsolidate(ApiError),
+
+    /// Could not save the display of a VM
+    VmScreenshot(ApiError),
+
+    /// Could not send the memfd file descriptors of a VM
+    VmMemoryFds(ApiError),
+
     /// Could not restore a VM
     VmRestore(ApiError),
 
+    /// Could not send a VM through a live migration
+    VmSendMigration(ApiError),
+
+    /// Could not receive a VM through a live migration
+    VmReceiveMigration(ApiError),
+
+    /// Could not abort a live migration
+    VmMigrationAbort(AbortError),
+
+    /// Could not measure the dirty rate of a VM
+    VmDirtyRate(ApiError),
+
     /// Could not act on a VM
     VmAction(ApiError),
 
     /// Could not resize a VM
     VmResize(ApiError),
 
+    /// Could not resize a memory zone
+    VmResizeZone(ApiError),
+
     /// Could not add a device to a VM
     VmAddDevice(ApiError),
 
@@ -95,8 +126,29 @@ pub enum HttpError {
     /// Could not add a vsock device to a VM
     VmAddVsock(ApiError),
 
+    /// Could not add a console port to a VM
+    VmAddConsolePort(ApiError),
+
     /// Could not get counters from VM
     VmCounters(ApiError),
+
+    /// Could not get balloon statistics from VM
+    VmBalloonStats(ApiError),
+
+    /// Could not get vsock information from VM
+    VmVsockInfo(ApiError),
+
+    /// Could not access the link state of a network device
+    VmNetLink(ApiError),
+
+    /// Could not access the MAC address of a network device
+    VmNetMac(ApiError),
+
+    /// Could not access the queue pairs of a network device
+    VmNetQueues(ApiError),
+
+    /// Could not access the flow rules of a network device
+    VmNetFlowRules(ApiError),
 }
 
 impl From<serde_json::Error> for HttpError {
@@ -114,6 +166,17 @@ pub fn error_response(error: HttpError, status: StatusCode) -> Response {
     response
 }
 
+// Status of the response to a request that failed with `error`, the
+// requests the VMM can't make sense of being answered 400.
+fn error_status(error: &HttpError) -> StatusCode {
+    match error {
+        HttpError::BadRequest
+        | HttpError::SerdeJsonDeserialize(_)
+        | HttpError::InvalidConfig(_) => StatusCode::BadRequest,
+        _ => StatusCode::InternalServerError,
+    }
+}
+
 /// An HTTP endpoint handler interface
 pub trait EndpointHandler: Sync + Send {
     /// Handles an HTTP request.
@@ -143,11 +206,10 @@ pub trait EndpointHandler: Sync + Send {
                     Response::new(Version::Http11, StatusCode::NoContent)
                 }
             }
-            Err(e @ HttpError::BadRequest) => error_response(e, StatusCode::BadRequest),
-            Err(e @ HttpError::SerdeJsonDeserialize(_)) => {
-                error_response(e, StatusCode::BadRequest)
+            Err(e) => {
+                let status = error_status(&e);
+                error_response(e, status)
             }
-            Err(e) => error_response(e, StatusCode::InternalServerError),
         }
     }
 
@@ -189,25 +251,41 @@ lazy_static! {
             routes: HashMap::new(),
         };
 
+        r.routes.insert(endpoint!("/vm.add-console-port"), Box::new(VmActionHandler::new(VmAction::AddConsolePort(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-device"), Box::new(VmActionHandler::new(VmAction::AddDevice(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-disk"), Box::new(VmActionHandler::new(VmAction::AddDisk(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-fs"), Box::new(VmActionHandler::new(VmAction::AddFs(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-net"), Box::new(VmActionHandler::new(VmAction::AddNet(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-pmem"), Box::new(VmActionHandler::new(VmAction::AddPmem(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-vsock"), Box::new(VmActionHandler::new(VmAction::AddVsock(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.balloon-stats"), Box::new(VmActionHandler::new(VmAction::BalloonStats)));
         r.routes.insert(endpoint!("/vm.boot"), Box::new(VmActionHandler::new(VmAction::Boot)));
         r.routes.insert(endpoint!("/vm.counters"), Box::new(VmActionHandler::new(VmAction::Counters)));
         r.routes.insert(endpoint!("/vm.create"), Box::new(VmCreate {}));
         r.routes.insert(endpoint!("/vm.delete"), Box::new(VmActionHandler::new(VmAction::Delete)));
+        r.routes.insert(endpoint!("/vm.dirty-rate"), Box::new(VmActionHandler::new(VmAction::DirtyRate(Arc::default()))));
         r.routes.insert(endpoint!("/vm.info"), Box::new(VmInfo {}));
+        r.routes.insert(endpoint!("/vm.memory-fds"), Box::new(VmActionHandler::new(VmAction::MemoryFds(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.migration-abort"), Box::new(VmMigrationAbort {}));
+        r.routes.insert(endpoint!("/vm.migration-status"), Box::new(VmMigrationStatus {}));
+        r.routes.insert(endpoint!("/vm.net-flow-rules"), Box::new(VmActionHandler::new(VmAction::NetFlowRules(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.net-link"), Box::new(VmActionHandler::new(VmAction::NetLink(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.net-mac"), Box::new(VmActionHandler::new(VmAction::NetMac(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.net-queues"), Box::new(VmActionHandler::new(VmAction::NetQueues(Arc::default()))));
         r.routes.insert(endpoint!("/vm.pause"), Box::new(VmActionHandler::new(VmAction::Pause)));
         r.routes.insert(endpoint!("/vm.reboot"), Box::new(VmActionHandler::new(VmAction::Reboot)));
+        r.routes.insert(endpoint!("/vm.receive-migration"), Box::new(VmActionHandler::new(VmAction::ReceiveMigration(Arc::default()))));
         r.routes.insert(endpoint!("/vm.remove-device"), Box::new(VmActionHandler::new(VmAction::RemoveDevice(Arc::default()))));
         r.routes.insert(endpoint!("/vm.resize"), Box::new(VmActionHandler::new(VmAction::Resize(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.resize-zone"), Box::new(VmActionHandler::new(VmAction::ResizeZone(Arc::default()))));
         r.routes.insert(endpoint!("/vm.restore"), Box::new(VmActionHandler::new(VmAction::Restore(Arc::default()))));
         r.routes.insert(endpoint!("/vm.resume"), Box::new(VmActionHandler::new(VmAction::Resume)));
+        r.routes.insert(endpoint!("/vm.screenshot"), Box::new(VmActionHandler::new(VmAction::Screenshot(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.send-migration"), Box::new(VmActionHandler::new(VmAction::SendMigration(Arc::default()))));
         r.routes.insert(endpoint!("/vm.shutdown"), Box::new(VmActionHandler::new(VmAction::Shutdown)));
         r.routes.insert(endpoint!("/vm.snapshot"), Box::new(VmActionHandler::new(VmAction::Snapshot(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.snapshot-consolidate"), Box::new(VmActionHandler::new(VmAction::SnapshotConsolidate(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.vsock-info"), Box::new(VmActionHandler::new(VmAction::VsockInfo)));
         r.routes.insert(endpoint!("/vmm.ping"), Box::new(VmmPing {}));
         r.routes.insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
 
@@ -283,3 +361,62 @@ pub fn start_http_thread(
         })
         .map_err(Error::HttpThreadSpawn)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DiskConfig, PmemConfig};
+    use std::sync::mpsc::channel;
+
+    // Error of the PUT request of `body` to `path`, which must fail before
+    // reaching the VMM.
+    fn put_error(path: &str, body: String) -> HttpError {
+        let (api_sender, _api_receiver) = channel();
+        let api_notifier = EventFd::new(0).unwrap();
+        match HTTP_ROUTES.routes[&endpoint!(path)].put_handler(
+            api_notifier,
+            api_sender,
+            &Some(Body::new(body)),
+        ) {
+            Ok(_) => panic!("PUT {} succeeded", path),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn test_invalid_config_bad_request() {
+        let disk = DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            readonly: true,
+            ..Default::default()
+        };
+        let e = put_error("/vm.add-disk", serde_json::to_string(&disk).unwrap());
+        match &e {
+            HttpError::InvalidConfig(ValidationError::DiskAccessModeWithSocket) => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert_eq!(error_status(&e), StatusCode::BadRequest);
+
+        let pmem = PmemConfig {
+            file: PathBuf::from("/path/to/pmem"),
+            readonly: true,
+            discard_writes: true,
+            ..Default::default()
+        };
+        let e = put_error("/vm.add-pmem", serde_json::to_string(&pmem).unwrap());
+        match &e {
+            HttpError::InvalidConfig(ValidationError::PmemReadonlyDiscardWrites) => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert_eq!(error_status(&e), StatusCode::BadRequest);
+
+        // Unlike the failures of the VMM.
+        assert_eq!(
+            error_status(&HttpError::VmAddDisk(ApiError::ResponseRecv(
+                std::sync::mpsc::RecvError
+            ))),
+            StatusCode::InternalServerError
+        );
+    }
+}
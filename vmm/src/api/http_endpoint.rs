@@ -5,11 +5,17 @@
 
 use crate::api::http::{error_response, EndpointHandler, HttpError};
 use crate::api::{
-    vm_add_device, vm_add_disk, vm_add_fs, vm_add_net, vm_add_pmem, vm_add_vsock, vm_boot,
-    vm_counters, vm_create, vm_delete, vm_info, vm_pause, vm_reboot, vm_remove_device, vm_resize,
-    vm_restore, vm_resume, vm_shutdown, vm_snapshot, vmm_ping, vmm_shutdown, ApiRequest, VmAction,
-    VmConfig,
+    vm_add_console_port, vm_add_device, vm_add_disk, vm_add_fs, vm_add_net, vm_add_pmem,
+    vm_add_vsock, vm_balloon_stats, vm_boot, vm_counters, vm_create, vm_delete, vm_dirty_rate,
+    vm_info, vm_memory_fds, vm_net_flow_rules, vm_net_link, vm_net_mac, vm_net_queues, vm_pause,
+    vm_reboot, vm_receive_migration, vm_remove_device, vm_resize, vm_resize_zone, vm_restore,
+    vm_resume, vm_screenshot, vm_send_migration, vm_shutdown, vm_snapshot, vm_snapshot_consolidate,
+    vm_vsock_info, vmm_ping, vmm_shutdown, ApiRequest, VmAction, VmConfig, VmDirtyRateData,
+    VmNetFlowRulesData, VmNetLinkData, VmNetMacData, VmNetQueuesData, VmReceiveMigrationData,
+    VmSendMigrationData, VmSnapshotConfig, MAX_DIRTY_RATE_SAMPLE_MS,
 };
+use crate::config::{ConsolePortConfig, DiskConfig, PmemConfig, VsockConfig};
+use crate::migration_progress::migration_progress;
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
@@ -83,68 +89,165 @@ impl EndpointHandler for VmActionHandler {
                 )
                 .map_err(HttpError::VmAddDevice),
 
-                AddDisk(_) => vm_add_disk(
+                AddDisk(_) => {
+                    let disk_cfg: DiskConfig = serde_json::from_slice(body.raw())?;
+                    disk_cfg.validate().map_err(HttpError::InvalidConfig)?;
+                    vm_add_disk(api_notifier, api_sender, Arc::new(disk_cfg))
+                        .map_err(HttpError::VmAddDisk)
+                }
+
+                AddFs(_) => vm_add_fs(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 )
-                .map_err(HttpError::VmAddDisk),
+                .map_err(HttpError::VmAddFs),
 
-                AddFs(_) => vm_add_fs(
+                AddPmem(_) => {
+                    let pmem_cfg: PmemConfig = serde_json::from_slice(body.raw())?;
+                    pmem_cfg.validate().map_err(HttpError::InvalidConfig)?;
+                    vm_add_pmem(api_notifier, api_sender, Arc::new(pmem_cfg))
+                        .map_err(HttpError::VmAddPmem)
+                }
+
+                AddNet(_) => vm_add_net(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 )
-                .map_err(HttpError::VmAddFs),
+                .map_err(HttpError::VmAddNet),
 
-                AddPmem(_) => vm_add_pmem(
+                AddVsock(_) => {
+                    let vsock_cfg: VsockConfig = serde_json::from_slice(body.raw())?;
+                    vsock_cfg.validate().map_err(HttpError::InvalidConfig)?;
+                    vm_add_vsock(api_notifier, api_sender, Arc::new(vsock_cfg))
+                        .map_err(HttpError::VmAddVsock)
+                }
+
+                AddConsolePort(_) => {
+                    let port_cfg: ConsolePortConfig = serde_json::from_slice(body.raw())?;
+                    port_cfg.validate().map_err(HttpError::InvalidConfig)?;
+                    vm_add_console_port(api_notifier, api_sender, Arc::new(port_cfg))
+                        .map_err(HttpError::VmAddConsolePort)
+                }
+
+                RemoveDevice(_) => vm_remove_device(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 )
-                .map_err(HttpError::VmAddPmem),
+                .map_err(HttpError::VmRemoveDevice),
 
-                AddNet(_) => vm_add_net(
+                Resize(_) => vm_resize(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 )
-                .map_err(HttpError::VmAddNet),
+                .map_err(HttpError::VmResize),
 
-                AddVsock(_) => vm_add_vsock(
+                ResizeZone(_) => vm_resize_zone(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 )
-                .map_err(HttpError::VmAddVsock),
+                .map_err(HttpError::VmResizeZone),
 
-                RemoveDevice(_) => vm_remove_device(
+                Restore(_) => vm_restore(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 )
-                .map_err(HttpError::VmRemoveDevice),
+                .map_err(HttpError::VmRestore),
 
-                Resize(_) => vm_resize(
+                Snapshot(_) => {
+                    let snapshot_cfg: VmSnapshotConfig = serde_json::from_slice(body.raw())?;
+                    snapshot_cfg.validate().map_err(HttpError::InvalidConfig)?;
+                    vm_snapshot(api_notifier, api_sender, Arc::new(snapshot_cfg))
+                        .map_err(HttpError::VmSnapshot)
+                }
+
+                SnapshotConsolidate(_) => vm_snapshot_consolidate(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 )
-                .map_err(HttpError::VmResize),
+                .map_err(HttpError::VmSnapshotConsolidate),
 
-                Restore(_) => vm_restore(
+                Screenshot(_) => vm_screenshot(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 )
-                .map_err(HttpError::VmRestore),
+                .map_err(HttpError::VmScreenshot),
 
-                Snapshot(_) => vm_snapshot(
+                MemoryFds(_) => vm_memory_fds(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 )
-                .map_err(HttpError::VmSnapshot),
+                .map_err(HttpError::VmMemoryFds),
+
+                SendMigration(_) => {
+                    let send_data: VmSendMigrationData = serde_json::from_slice(body.raw())?;
+                    send_data.validate().map_err(HttpError::InvalidConfig)?;
+                    vm_send_migration(api_notifier, api_sender, Arc::new(send_data))
+                        .map_err(HttpError::VmSendMigration)
+                }
+
+                ReceiveMigration(_) => {
+                    let receive_data: VmReceiveMigrationData = serde_json::from_slice(body.raw())?;
+                    receive_data.validate().map_err(HttpError::InvalidConfig)?;
+                    vm_receive_migration(api_notifier, api_sender, Arc::new(receive_data))
+                        .map_err(HttpError::VmReceiveMigration)
+                }
+
+                DirtyRate(_) => {
+                    let dirty_rate_data: VmDirtyRateData = serde_json::from_slice(body.raw())?;
+                    if dirty_rate_data.sample_ms == 0
+                        || dirty_rate_data.sample_ms > MAX_DIRTY_RATE_SAMPLE_MS
+                    {
+                        return Err(HttpError::BadRequest);
+                    }
+                    vm_dirty_rate(api_notifier, api_sender, Arc::new(dirty_rate_data))
+                        .map_err(HttpError::VmDirtyRate)
+                }
+
+                NetLink(_) => {
+                    let net_link_data: VmNetLinkData = serde_json::from_slice(body.raw())?;
+                    if net_link_data.link_up.is_none() {
+                        return Err(HttpError::BadRequest);
+                    }
+                    vm_net_link(api_notifier, api_sender, Arc::new(net_link_data))
+                        .map_err(HttpError::VmNetLink)
+                }
+
+                NetMac(_) => {
+                    let net_mac_data: VmNetMacData = serde_json::from_slice(body.raw())?;
+                    // Group addresses can't be assigned to the device.
+                    if net_mac_data.mac.map_or(true, |mac| mac.is_multicast()) {
+                        return Err(HttpError::BadRequest);
+                    }
+                    vm_net_mac(api_notifier, api_sender, Arc::new(net_mac_data))
+                        .map_err(HttpError::VmNetMac)
+                }
+
+                NetQueues(_) => {
+                    let net_queues_data: VmNetQueuesData = serde_json::from_slice(body.raw())?;
+                    if net_queues_data.queue_pairs.is_none() {
+                        return Err(HttpError::BadRequest);
+                    }
+                    vm_net_queues(api_notifier, api_sender, Arc::new(net_queues_data))
+                        .map_err(HttpError::VmNetQueues)
+                }
+
+                NetFlowRules(_) => {
+                    let flow_rules_data: VmNetFlowRulesData = serde_json::from_slice(body.raw())?;
+                    if flow_rules_data.rules.is_none() {
+                        return Err(HttpError::BadRequest);
+                    }
+                    vm_net_flow_rules(api_notifier, api_sender, Arc::new(flow_rules_data))
+                        .map_err(HttpError::VmNetFlowRules)
+                }
 
                 _ => Err(HttpError::BadRequest),
             }
@@ -156,6 +259,8 @@ impl EndpointHandler for VmActionHandler {
                 Reboot => vm_reboot(api_notifier, api_sender).map_err(HttpError::VmReboot),
                 Pause => vm_pause(api_notifier, api_sender).map_err(HttpError::VmPause),
                 Resume => vm_resume(api_notifier, api_sender).map_err(HttpError::VmResume),
+                DirtyRate(_) => vm_dirty_rate(api_notifier, api_sender, Arc::default())
+                    .map_err(HttpError::VmDirtyRate),
                 _ => Err(HttpError::BadRequest),
             }
         }
@@ -165,11 +270,56 @@ impl EndpointHandler for VmActionHandler {
         &self,
         api_notifier: EventFd,
         api_sender: Sender<ApiRequest>,
-        _body: &Option<Body>,
+        body: &Option<Body>,
     ) -> std::result::Result<Option<Body>, HttpError> {
         use VmAction::*;
         match self.action {
+            NetLink(_) => {
+                let body = body.as_ref().ok_or(HttpError::BadRequest)?;
+                let net_link_data: VmNetLinkData = serde_json::from_slice(body.raw())?;
+                // A query never changes the link state.
+                let net_link_data = VmNetLinkData {
+                    link_up: None,
+                    ..net_link_data
+                };
+                vm_net_link(api_notifier, api_sender, Arc::new(net_link_data))
+                    .map_err(HttpError::VmNetLink)
+            }
+            NetMac(_) => {
+                let body = body.as_ref().ok_or(HttpError::BadRequest)?;
+                let net_mac_data: VmNetMacData = serde_json::from_slice(body.raw())?;
+                let net_mac_data = VmNetMacData {
+                    mac: None,
+                    ..net_mac_data
+                };
+                vm_net_mac(api_notifier, api_sender, Arc::new(net_mac_data))
+                    .map_err(HttpError::VmNetMac)
+            }
+            NetQueues(_) => {
+                let body = body.as_ref().ok_or(HttpError::BadRequest)?;
+                let net_queues_data: VmNetQueuesData = serde_json::from_slice(body.raw())?;
+                let net_queues_data = VmNetQueuesData {
+                    queue_pairs: None,
+                    ..net_queues_data
+                };
+                vm_net_queues(api_notifier, api_sender, Arc::new(net_queues_data))
+                    .map_err(HttpError::VmNetQueues)
+            }
+            NetFlowRules(_) => {
+                let body = body.as_ref().ok_or(HttpError::BadRequest)?;
+                let flow_rules_data: VmNetFlowRulesData = serde_json::from_slice(body.raw())?;
+                let flow_rules_data = VmNetFlowRulesData {
+                    rules: None,
+                    ..flow_rules_data
+                };
+                vm_net_flow_rules(api_notifier, api_sender, Arc::new(flow_rules_data))
+                    .map_err(HttpError::VmNetFlowRules)
+            }
             Counters => vm_counters(api_notifier, api_sender).map_err(HttpError::VmCounters),
+            BalloonStats => {
+                vm_balloon_stats(api_notifier, api_sender).map_err(HttpError::VmBalloonStats)
+            }
+            VsockInfo => vm_vsock_info(api_notifier, api_sender).map_err(HttpError::VmVsockInfo),
             _ => Err(HttpError::BadRequest),
         }
     }
@@ -201,6 +351,52 @@ impl EndpointHandler for VmInfo {
     }
 }
 
+// /api/v1/vm.migration-abort handler, answered by the HTTP thread itself
+// for the VMM thread is busy migrating the VM.
+pub struct VmMigrationAbort {}
+
+impl EndpointHandler for VmMigrationAbort {
+    fn handle_request(
+        &self,
+        req: &Request,
+        _api_notifier: EventFd,
+        _api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => match migration_progress().abort() {
+                Ok(()) => Response::new(Version::Http11, StatusCode::NoContent),
+                Err(e) => error_response(HttpError::VmMigrationAbort(e), StatusCode::BadRequest),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.migration-status handler, answered by the HTTP thread itself
+// for the VMM thread is busy migrating the VM.
+pub struct VmMigrationStatus {}
+
+impl EndpointHandler for VmMigrationStatus {
+    fn handle_request(
+        &self,
+        req: &Request,
+        _api_notifier: EventFd,
+        _api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => {
+                let mut response = Response::new(Version::Http11, StatusCode::OK);
+                let status_serialized =
+                    serde_json::to_string(&migration_progress().status()).unwrap();
+
+                response.set_body(Body::new(status_serialized));
+                response
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
 // /api/v1/vmm.info handler
 pub struct VmmPing {}
 
@@ -37,11 +37,17 @@ pub mod http;
 pub mod http_endpoint;
 
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, VmConfig, VsockConfig,
+    ConsolePortConfig, CpuTopology, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig,
+    RestoreConfig, ValidationError, VmConfig, VsockConfig,
 };
+use crate::cpu::MAX_THROTTLE;
+use crate::memory_manager::MemoryZoneHints;
+use crate::migration::{TCP_URL_PREFIX, UNIX_URL_PREFIX};
 use crate::vm::{Error as VmError, VmState};
 use micro_http::Body;
+use net_util::MacAddr;
 use std::io;
+use std::path::PathBuf;
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
 use vmm_sys_util::eventfd::EventFd;
@@ -100,15 +106,54 @@ pub enum ApiError {
     /// The VM could not be snapshotted.
     VmSnapshot(VmError),
 
+    /// The incremental snapshot chain could not be consolidated.
+    VmSnapshotConsolidate(VmError),
+
+    /// The VM display could not be saved.
+    VmScreenshot(VmError),
+
+    /// The memfd file descriptors could not be sent.
+    VmMemoryFds(VmError),
+
     /// The VM could not restored.
     VmRestore(VmError),
 
+    /// The VM could not be sent through a live migration.
+    VmSendMigration(VmError),
+
+    /// The VM could not be received through a live migration.
+    VmReceiveMigration(VmError),
+
+    /// The rate the guest dirties its memory at could not be measured.
+    VmDirtyRate(VmError),
+
     /// The VMM could not shutdown.
     VmmShutdown(VmError),
 
     /// The VM could not be resized
     VmResize(VmError),
 
+    /// The memory zone could not be resized.
+    VmResizeZone(VmError),
+
+    /// The balloon statistics could not be retrieved.
+    VmBalloonStats(VmError),
+
+    /// The vsock information could not be retrieved.
+    VmVsockInfo(VmError),
+
+    /// The network link state could not be accessed.
+    VmNetLink(VmError),
+
+    /// The network MAC address could not be accessed.
+    VmNetMac(VmError),
+
+    /// The network queue pairs could not be accessed.
+    VmNetQueues(VmError),
+
+    /// The network flow rules could not be accessed.
+    VmNetFlowRules(VmError),
+
     /// The device could not be added to the VM.
     VmAddDevice(VmError),
 
@@ -135,6 +180,9 @@ pub enum ApiError {
 
     /// The vsock device could not be added to the VM.
     VmAddVsock(VmError),
+
+    /// The console port could not be added to the VM.
+    VmAddConsolePort(VmError),
 }
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
@@ -142,6 +190,11 @@ pub type ApiResult<T> = std::result::Result<T, ApiError>;
 pub struct VmInfo {
     pub config: Arc<Mutex<VmConfig>>,
     pub state: VmState,
+    pub watchdog: Option<virtio_devices::WatchdogInfo>,
+    pub guest_panic: Option<devices::legacy::PvpanicInfo>,
+    pub balloon: Option<virtio_devices::BalloonInfo>,
+    pub cpu_topology: CpuTopology,
+    pub memory_zones: Vec<MemoryZoneHints>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -156,15 +209,338 @@ pub struct VmResizeData {
     pub desired_ram_w_balloon: Option<u64>,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmResizeZoneData {
+    pub id: String,
+    pub desired_ram: u64,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default)]
 pub struct VmRemoveDeviceData {
     pub id: String,
+    /// Milliseconds to wait for the guest to eject the device, not waiting
+    /// at all when missing or zero
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmNetLinkData {
+    /// Identifier of the virtio-net device
+    pub id: String,
+    /// New link state, the current one is left untouched if not set
+    #[serde(default)]
+    pub link_up: Option<bool>,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmNetMacData {
+    /// Identifier of the virtio-net device
+    pub id: String,
+    /// New MAC address, the current one is left untouched if not set
+    #[serde(default)]
+    pub mac: Option<MacAddr>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmNetQueuesData {
+    /// Identifier of the virtio-net device
+    pub id: String,
+    /// New number of queue pairs, the current one is left untouched if not
+    /// set
+    #[serde(default)]
+    pub queue_pairs: Option<u16>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmNetFlowRulesData {
+    /// Identifier of the virtio-net device
+    pub id: String,
+    /// New flow rules, replacing all the current ones, which are left
+    /// untouched if not set
+    #[serde(default)]
+    pub rules: Option<Vec<virtio_devices::FlowRule>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotMode {
+    /// The snapshot holds all the guest memory.
+    Full,
+    /// The snapshot only holds the guest memory which changed since the
+    /// previous incremental snapshot of the VM.
+    Incremental,
+}
+
+impl Default for SnapshotMode {
+    fn default() -> Self {
+        SnapshotMode::Full
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotCompression {
+    Zstd,
+}
+
+pub const DEFAULT_SNAPSHOT_COMPRESSION_LEVEL: i32 = 3;
+pub const MAX_SNAPSHOT_COMPRESSION_LEVEL: i32 = 22;
+
 #[derive(Clone, Deserialize, Serialize, Default)]
 pub struct VmSnapshotConfig {
     /// The snapshot destination URL
     pub destination_url: String,
+    #[serde(default)]
+    pub mode: SnapshotMode,
+    /// The compression of the guest memory, left uncompressed if unset
+    #[serde(default)]
+    pub compression: Option<SnapshotCompression>,
+    #[serde(default)]
+    pub compression_level: Option<i32>,
+    /// The number of threads writing the guest memory
+    #[serde(default)]
+    pub threads: Option<usize>,
+}
+
+impl VmSnapshotConfig {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if let Some(level) = self.compression_level {
+            if self.compression.is_none() {
+                return Err(ValidationError::SnapshotCompressionLevelWithoutCompression);
+            }
+            if level < 1 || level > MAX_SNAPSHOT_COMPRESSION_LEVEL {
+                return Err(ValidationError::InvalidSnapshotCompressionLevel(level));
+            }
+        }
+        if self.threads == Some(0) {
+            return Err(ValidationError::InvalidSnapshotThreads);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmSnapshotConsolidateConfig {
+    /// The URL of the last snapshot of the chain
+    pub source_url: String,
+    /// The URL the whole snapshot is written to
+    pub destination_url: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmScreenshotConfig {
+    /// The path the PNG image is written to
+    pub destination_path: PathBuf,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmMemoryFdsConfig {
+    /// The UNIX socket the memfd file descriptors are sent to
+    pub destination_socket: PathBuf,
+}
+
+pub const DEFAULT_MIGRATION_TIMEOUT: u64 = 60;
+
+fn default_migration_timeout() -> u64 {
+    DEFAULT_MIGRATION_TIMEOUT
+}
+
+/// TLS parameters of a live migration over TCP. The destination needs a
+/// certificate and its key, the source the CA which signed it. Given a CA,
+/// the destination requires the source to present a certificate it signed.
+#[derive(Clone, Debug, Deserialize, Serialize, Default, PartialEq)]
+pub struct MigrationTlsConfig {
+    #[serde(default)]
+    pub cert: Option<PathBuf>,
+    #[serde(default)]
+    pub key: Option<PathBuf>,
+    #[serde(default)]
+    pub ca: Option<PathBuf>,
+    /// The name the destination certificate is checked against, the host
+    /// of the destination URL by default
+    #[serde(default)]
+    pub server_name: Option<String>,
+}
+
+impl MigrationTlsConfig {
+    fn validate(&self, url: &str) -> Result<(), ValidationError> {
+        if !url.starts_with(TCP_URL_PREFIX) {
+            return Err(ValidationError::MigrationTlsWithoutTcp);
+        }
+        if self.cert.is_some() != self.key.is_some() {
+            return Err(ValidationError::MigrationTlsCertWithoutKey);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmSendMigrationData {
+    /// The URL the destination listens on, as unix:<path> or
+    /// tcp://<host>:<port>
+    pub destination_url: String,
+    /// Resume the guest on the destination before all its memory is sent
+    #[serde(default)]
+    pub postcopy: bool,
+    #[serde(default)]
+    pub tls: Option<MigrationTlsConfig>,
+    /// Seconds after which the migration fails if the destination doesn't
+    /// make any progress
+    #[serde(default = "default_migration_timeout")]
+    pub timeout: u64,
+    /// Bytes per second the migration is limited to
+    #[serde(default)]
+    pub bandwidth_limit: Option<u64>,
+    /// Throttle the vCPUs while the pre-copy rounds don't converge
+    #[serde(default)]
+    pub auto_converge: bool,
+    /// Maximum percentage of the time the vCPUs are kept out of the guest
+    /// for with auto-converge
+    #[serde(default = "default_max_throttle")]
+    pub max_throttle: u8,
+    /// Hand the VM over to a VMM process on the same host, along with the
+    /// file descriptors of its memory and devices, rather than copying its
+    /// memory
+    #[serde(default)]
+    pub local: bool,
+}
+
+fn default_max_throttle() -> u8 {
+    MAX_THROTTLE
+}
+
+impl Default for VmSendMigrationData {
+    fn default() -> Self {
+        VmSendMigrationData {
+            destination_url: String::new(),
+            postcopy: false,
+            tls: None,
+            timeout: DEFAULT_MIGRATION_TIMEOUT,
+            bandwidth_limit: None,
+            auto_converge: false,
+            max_throttle: MAX_THROTTLE,
+            local: false,
+        }
+    }
+}
+
+impl VmSendMigrationData {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if let Some(tls) = &self.tls {
+            tls.validate(&self.destination_url)?;
+            if tls.ca.is_none() {
+                return Err(ValidationError::MigrationTlsCaMissing);
+            }
+            // The pages requested during the post-copy phase are read from
+            // another thread than the one sending them.
+            if self.postcopy {
+                return Err(ValidationError::MigrationTlsWithPostcopy);
+            }
+        }
+        if self.timeout == 0 {
+            return Err(ValidationError::InvalidMigrationTimeout);
+        }
+        if self.bandwidth_limit == Some(0) {
+            return Err(ValidationError::InvalidMigrationBandwidth);
+        }
+        if self.max_throttle == 0 || self.max_throttle > MAX_THROTTLE {
+            return Err(ValidationError::InvalidMigrationThrottle(self.max_throttle));
+        }
+        // The post-copy migrations don't go through pre-copy rounds.
+        if self.auto_converge && self.postcopy {
+            return Err(ValidationError::MigrationAutoConvergeWithPostcopy);
+        }
+        if self.local {
+            validate_local_migration(&self.destination_url)?;
+            // The memory isn't copied at all.
+            if self.postcopy || self.auto_converge {
+                return Err(ValidationError::LocalMigrationWithMemoryCopy);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmReceiveMigrationData {
+    /// The URL to listen on for the source, as unix:<path> or
+    /// tcp://[<host>]:<port>
+    pub receiver_url: String,
+    #[serde(default)]
+    pub tls: Option<MigrationTlsConfig>,
+    /// Seconds after which the migration fails if the source doesn't make
+    /// any progress once connected
+    #[serde(default = "default_migration_timeout")]
+    pub timeout: u64,
+    /// Take the VM over from a VMM process on the same host, along with
+    /// the file descriptors of its memory and devices
+    #[serde(default)]
+    pub local: bool,
+}
+
+impl Default for VmReceiveMigrationData {
+    fn default() -> Self {
+        VmReceiveMigrationData {
+            receiver_url: String::new(),
+            tls: None,
+            timeout: DEFAULT_MIGRATION_TIMEOUT,
+            local: false,
+        }
+    }
+}
+
+impl VmReceiveMigrationData {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if let Some(tls) = &self.tls {
+            tls.validate(&self.receiver_url)?;
+            if tls.cert.is_none() {
+                return Err(ValidationError::MigrationTlsCertMissing);
+            }
+        }
+        if self.timeout == 0 {
+            return Err(ValidationError::InvalidMigrationTimeout);
+        }
+        if self.local {
+            validate_local_migration(&self.receiver_url)?;
+        }
+
+        Ok(())
+    }
+}
+
+// File descriptors can only be handed over through a UNIX socket.
+fn validate_local_migration(url: &str) -> Result<(), ValidationError> {
+    if !url.starts_with(UNIX_URL_PREFIX) {
+        return Err(ValidationError::LocalMigrationWithoutUnix);
+    }
+
+    Ok(())
+}
+
+pub const DEFAULT_DIRTY_RATE_SAMPLE_MS: u64 = 1000;
+pub const MAX_DIRTY_RATE_SAMPLE_MS: u64 = 60_000;
+
+fn default_dirty_rate_sample_ms() -> u64 {
+    DEFAULT_DIRTY_RATE_SAMPLE_MS
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmDirtyRateData {
+    /// How long the pages the guest dirties are logged for, in milliseconds
+    #[serde(default = "default_dirty_rate_sample_ms")]
+    pub sample_ms: u64,
+}
+
+impl Default for VmDirtyRateData {
+    fn default() -> Self {
+        VmDirtyRateData {
+            sample_ms: DEFAULT_DIRTY_RATE_SAMPLE_MS,
+        }
+    }
 }
 
 pub enum ApiResponsePayload {
@@ -218,6 +594,24 @@ pub enum ApiRequest {
     /// Get counters for a VM.
     VmCounters(Sender<ApiResponse>),
 
+    /// Get the memory statistics reported through the balloon.
+    VmBalloonStats(Sender<ApiResponse>),
+
+    /// Get the connections tracked by the vsock device.
+    VmVsockInfo(Sender<ApiResponse>),
+
+    /// Get or set the link state of a network device.
+    VmNetLink(Arc<VmNetLinkData>, Sender<ApiResponse>),
+
+    /// Get or set the MAC address of a network device.
+    VmNetMac(Arc<VmNetMacData>, Sender<ApiResponse>),
+
+    /// Get or set the number of queue pairs of a network device.
+    VmNetQueues(Arc<VmNetQueuesData>, Sender<ApiResponse>),
+
+    /// Get or set the flow steering rules of a network device.
+    VmNetFlowRules(Arc<VmNetFlowRulesData>, Sender<ApiResponse>),
+
     /// Shut the previously booted virtual machine down.
     /// If the VM was not previously booted or created, the VMM API server
     /// will send a VmShutdown error back.
@@ -236,6 +630,9 @@ pub enum ApiRequest {
     /// Resize the VM.
     VmResize(Arc<VmResizeData>, Sender<ApiResponse>),
 
+    /// Resize a memory zone.
+    VmResizeZone(Arc<VmResizeZoneData>, Sender<ApiResponse>),
+
     /// Add a device to the VM.
     VmAddDevice(Arc<DeviceConfig>, Sender<ApiResponse>),
 
@@ -257,11 +654,32 @@ pub enum ApiRequest {
     /// Add a vsock device to the VM.
     VmAddVsock(Arc<VsockConfig>, Sender<ApiResponse>),
 
+    /// Add a console port to the VM.
+    VmAddConsolePort(Arc<ConsolePortConfig>, Sender<ApiResponse>),
+
     /// Take a VM snapshot
     VmSnapshot(Arc<VmSnapshotConfig>, Sender<ApiResponse>),
 
+    /// Merge an incremental snapshot chain into a whole snapshot
+    VmSnapshotConsolidate(Arc<VmSnapshotConsolidateConfig>, Sender<ApiResponse>),
+
+    /// Save the content of the VM display as a PNG image
+    VmScreenshot(Arc<VmScreenshotConfig>, Sender<ApiResponse>),
+
+    /// Send the file descriptors of the memfd memory zones
+    VmMemoryFds(Arc<VmMemoryFdsConfig>, Sender<ApiResponse>),
+
     /// Restore from a VM snapshot
     VmRestore(Arc<RestoreConfig>, Sender<ApiResponse>),
+
+    /// Live migrate the VM to another VMM
+    VmSendMigration(Arc<VmSendMigrationData>, Sender<ApiResponse>),
+
+    /// Receive a VM live migrated from another VMM
+    VmReceiveMigration(Arc<VmReceiveMigrationData>, Sender<ApiResponse>),
+
+    /// Measure the rate the guest dirties its memory at
+    VmDirtyRate(Arc<VmDirtyRateData>, Sender<ApiResponse>),
 }
 
 pub fn vm_create(
@@ -307,6 +725,24 @@ pub enum VmAction {
     /// Return VM counters
     Counters,
 
+    /// Return balloon statistics
+    BalloonStats,
+
+    /// Return vsock connections
+    VsockInfo,
+
+    /// Get or set network link state
+    NetLink(Arc<VmNetLinkData>),
+
+    /// Get or set network MAC address
+    NetMac(Arc<VmNetMacData>),
+
+    /// Get or set network queue pairs
+    NetQueues(Arc<VmNetQueuesData>),
+
+    /// Get or set network flow rules
+    NetFlowRules(Arc<VmNetFlowRulesData>),
+
     /// Add VFIO device
     AddDevice(Arc<DeviceConfig>),
 
@@ -325,17 +761,41 @@ pub enum VmAction {
     /// Add vsock
     AddVsock(Arc<VsockConfig>),
 
+    /// Add console port
+    AddConsolePort(Arc<ConsolePortConfig>),
+
     /// Remove VFIO device
     RemoveDevice(Arc<VmRemoveDeviceData>),
 
     /// Resize VM
     Resize(Arc<VmResizeData>),
 
+    /// Resize memory zone
+    ResizeZone(Arc<VmResizeZoneData>),
+
     /// Restore VM
     Restore(Arc<RestoreConfig>),
 
     /// Snapshot VM
     Snapshot(Arc<VmSnapshotConfig>),
+
+    /// Consolidate an incremental snapshot chain
+    SnapshotConsolidate(Arc<VmSnapshotConsolidateConfig>),
+
+    /// Save the VM display
+    Screenshot(Arc<VmScreenshotConfig>),
+
+    /// Send the memfd file descriptors
+    MemoryFds(Arc<VmMemoryFdsConfig>),
+
+    /// Send a live migration
+    SendMigration(Arc<VmSendMigrationData>),
+
+    /// Receive a live migration
+    ReceiveMigration(Arc<VmReceiveMigrationData>),
+
+    /// Measure the guest dirty rate
+    DirtyRate(Arc<VmDirtyRateData>),
 }
 
 fn vm_action(
@@ -354,16 +814,30 @@ fn vm_action(
         Pause => ApiRequest::VmPause(response_sender),
         Resume => ApiRequest::VmResume(response_sender),
         Counters => ApiRequest::VmCounters(response_sender),
+        BalloonStats => ApiRequest::VmBalloonStats(response_sender),
+        VsockInfo => ApiRequest::VmVsockInfo(response_sender),
+        NetLink(v) => ApiRequest::VmNetLink(v, response_sender),
+        NetMac(v) => ApiRequest::VmNetMac(v, response_sender),
+        NetQueues(v) => ApiRequest::VmNetQueues(v, response_sender),
+        NetFlowRules(v) => ApiRequest::VmNetFlowRules(v, response_sender),
         AddDevice(v) => ApiRequest::VmAddDevice(v, response_sender),
         AddDisk(v) => ApiRequest::VmAddDisk(v, response_sender),
         AddFs(v) => ApiRequest::VmAddFs(v, response_sender),
         AddPmem(v) => ApiRequest::VmAddPmem(v, response_sender),
         AddNet(v) => ApiRequest::VmAddNet(v, response_sender),
         AddVsock(v) => ApiRequest::VmAddVsock(v, response_sender),
+        AddConsolePort(v) => ApiRequest::VmAddConsolePort(v, response_sender),
         RemoveDevice(v) => ApiRequest::VmRemoveDevice(v, response_sender),
         Resize(v) => ApiRequest::VmResize(v, response_sender),
+        ResizeZone(v) => ApiRequest::VmResizeZone(v, response_sender),
         Restore(v) => ApiRequest::VmRestore(v, response_sender),
         Snapshot(v) => ApiRequest::VmSnapshot(v, response_sender),
+        SnapshotConsolidate(v) => ApiRequest::VmSnapshotConsolidate(v, response_sender),
+        Screenshot(v) => ApiRequest::VmScreenshot(v, response_sender),
+        MemoryFds(v) => ApiRequest::VmMemoryFds(v, response_sender),
+        SendMigration(v) => ApiRequest::VmSendMigration(v, response_sender),
+        ReceiveMigration(v) => ApiRequest::VmReceiveMigration(v, response_sender),
+        DirtyRate(v) => ApiRequest::VmDirtyRate(v, response_sender),
     };
 
     // Send the VM request.
@@ -407,6 +881,49 @@ pub fn vm_counters(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResul
     vm_action(api_evt, api_sender, VmAction::Counters)
 }
 
+pub fn vm_balloon_stats(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::BalloonStats)
+}
+
+pub fn vm_vsock_info(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::VsockInfo)
+}
+
+pub fn vm_net_link(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmNetLinkData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::NetLink(data))
+}
+
+pub fn vm_net_mac(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmNetMacData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::NetMac(data))
+}
+
+pub fn vm_net_queues(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmNetQueuesData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::NetQueues(data))
+}
+
+pub fn vm_net_flow_rules(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmNetFlowRulesData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::NetFlowRules(data))
+}
+
 pub fn vm_snapshot(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -415,6 +932,54 @@ pub fn vm_snapshot(
     vm_action(api_evt, api_sender, VmAction::Snapshot(data))
 }
 
+pub fn vm_snapshot_consolidate(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSnapshotConsolidateConfig>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SnapshotConsolidate(data))
+}
+
+pub fn vm_screenshot(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmScreenshotConfig>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::Screenshot(data))
+}
+
+pub fn vm_memory_fds(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmMemoryFdsConfig>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::MemoryFds(data))
+}
+
+pub fn vm_send_migration(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSendMigrationData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SendMigration(data))
+}
+
+pub fn vm_receive_migration(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmReceiveMigrationData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::ReceiveMigration(data))
+}
+
+pub fn vm_dirty_rate(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmDirtyRateData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::DirtyRate(data))
+}
+
 pub fn vm_restore(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -478,6 +1043,14 @@ pub fn vm_resize(
     vm_action(api_evt, api_sender, VmAction::Resize(data))
 }
 
+pub fn vm_resize_zone(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmResizeZoneData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::ResizeZone(data))
+}
+
 pub fn vm_add_device(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -533,3 +1106,11 @@ pub fn vm_add_vsock(
 ) -> ApiResult<Option<Body>> {
     vm_action(api_evt, api_sender, VmAction::AddVsock(data))
 }
+
+pub fn vm_add_console_port(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<ConsolePortConfig>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::AddConsolePort(data))
+}
@@ -3,23 +3,40 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crate::cpu::MAX_THROTTLE;
 use clap::ArgMatches;
 use net_util::MacAddr;
 use option_parser::{ByteSized, OptionParser, OptionParserError, Toggle};
+use rate_limiter::{RateLimiterConfig, TokenBucketConfig};
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::From;
 use std::fmt;
 use std::net::Ipv4Addr;
 use std::path::PathBuf;
 use std::result;
 use std::str::FromStr;
+use std::time::Duration;
 
 pub const DEFAULT_VCPUS: u8 = 1;
 pub const DEFAULT_MEMORY_MB: u64 = 512;
 pub const DEFAULT_RNG_SOURCE: &str = "/dev/urandom";
+pub const DEFAULT_RNG_PERIOD_MS: u64 = 1000;
 pub const DEFAULT_NUM_QUEUES_VUNET: usize = 2;
 pub const DEFAULT_QUEUE_SIZE_VUNET: u16 = 256;
+pub const DEFAULT_RECONNECT_RETRIES_VUNET: u32 = 10;
+pub const DEFAULT_RECONNECT_BACKOFF_MS_VUNET: u64 = 100;
+pub const DEFAULT_RX_STARVATION_TIMEOUT_MS: u64 = 100;
 pub const DEFAULT_NUM_QUEUES_VUBLK: usize = 1;
 pub const DEFAULT_QUEUE_SIZE_VUBLK: u16 = 128;
+pub const DEFAULT_DISK_STRIPE_SIZE: u64 = 64 << 10;
+pub const DEFAULT_DISK_BUSY_POLL_US: u64 = 50;
+pub const DEFAULT_RATE_LIMITER_REFILL_TIME_MS: u64 = 1000;
+pub const DEFAULT_VSOCK_MAX_CONNECTIONS: usize = 1023;
+pub const DEFAULT_WATCHDOG_TIMEOUT: u64 = 30;
+pub const DEFAULT_MEMORY_ZONE_BLOCK_SIZE: u64 = virtio_devices::VIRTIO_MEM_DEFAULT_BLOCK_SIZE;
+
+// Maximum length of a disk serial, as reported through VIRTIO_BLK_T_GET_ID.
+pub const DISK_SERIAL_MAX_LEN: usize = 20;
 
 /// Errors associated with VM configuration parameters.
 #[derive(Debug)]
@@ -38,10 +55,20 @@ pub enum Error {
     ParseVsockCidMissing,
     /// Missing restore source_url parameter.
     ParseRestoreSourceUrlMissing,
+    /// Missing vDPA device path parameter.
+    ParseVdpaPathMissing,
+    /// Missing guest NUMA node identifier parameter.
+    ParseNumaIdMissing,
     /// Error parsing CPU options
     ParseCpus(OptionParserError),
     /// Error parsing memory options
     ParseMemory(OptionParserError),
+    /// Error parsing memory zone options
+    ParseMemoryZone(OptionParserError),
+    /// Missing memory zone identifier
+    ParseMemoryZoneIdMissing,
+    /// Missing memory zone hotplug size
+    ParseMemoryZoneHotplugSizeMissing,
     /// Error parsing disk options
     ParseDisk(OptionParserError),
     /// Error parsing network options
@@ -56,17 +83,41 @@ pub enum Error {
     ParseConsole(OptionParserError),
     /// No mode given for console
     ParseConsoleInvalidModeGiven,
+    /// Failed parsing console port
+    ParseConsolePort(OptionParserError),
+    /// Missing name from console port
+    ParseConsolePortNameMissing,
+    /// No mode given for console port
+    ParseConsolePortInvalidModeGiven,
     /// Failed parsing device parameters
     ParseDevice(OptionParserError),
     /// Missing path from device,
     ParseDevicePathMissing,
     /// Failed to parse vsock parameters
     ParseVsock(OptionParserError),
+    /// Failed to parse watchdog parameters
+    ParseWatchdog(OptionParserError),
+    /// Failed to parse sound parameters
+    ParseSound(OptionParserError),
+    /// Failed to parse GPU parameters
+    ParseGpu(OptionParserError),
+    /// Failed to parse vDPA device parameters
+    ParseVdpa(OptionParserError),
+    /// Failed to parse guest NUMA node parameters
+    ParseNuma(OptionParserError),
     /// Failed to parse restore parameters
     ParseRestore(OptionParserError),
+    /// Failed to parse platform parameters
+    ParsePlatform(OptionParserError),
     /// Failed to parse SGX EPC parameters
     #[cfg(target_arch = "x86_64")]
     ParseSgxEpc(OptionParserError),
+    /// Failed to parse GDB stub parameters
+    #[cfg(target_arch = "x86_64")]
+    ParseGdb(OptionParserError),
+    /// Failed to parse pvpanic parameters
+    #[cfg(target_arch = "x86_64")]
+    ParsePvpanic(OptionParserError),
     /// Failed to validate configuration
     Validation(ValidationError),
 }
@@ -79,20 +130,205 @@ pub enum ValidationError {
     KernelMissing,
     /// Missing file value for console
     ConsoleFileMissing,
+    /// Missing socket path for console
+    ConsoleSocketMissing,
+    /// Missing TCP address for console
+    ConsoleTcpMissing,
+    /// Missing file or socket path for console port
+    ConsolePortPathMissing(String),
+    /// Console port name can't be used as a device name
+    ConsolePortInvalidName(String),
+    /// Two console ports share the same name
+    ConsolePortDuplicateName(String),
+    /// More console ports than the virtio-console device supports
+    ConsolePortsTooMany,
+    /// Console ports require the virtio-console device
+    ConsolePortsWithoutConsole,
     /// Max is less than boot
     CpusMaxLowerThanBoot,
     /// Both socket and path specified
     DiskSocketAndPath,
     /// Using vhost user requires shared memory
     VhostUserRequiresSharedMemory,
+    /// Network coalescing packet limit given without any delay
+    NetCoalescingWithoutDelay,
+    /// Network RX starvation blocking without any timeout
+    NetRxStarvationWithoutTimeout,
+    /// Network failover onto a device which doesn't exist
+    NetFailoverUnknownDevice(String),
+    /// Network failover onto a device not sharing the MAC address
+    NetFailoverMacMismatch(String),
+    /// Network failover from a vhost-user device
+    NetFailoverVhostUser,
     /// Trying to use IOMMU without PCI
     IommuUnsupported,
     /// Trying to use VFIO without PCI
     VfioUnsupported,
+    /// Trying to use NVMe without PCI
+    NvmeUnsupported,
     /// CPU topology count doesn't match max
     CpuTopologyCount,
     /// One part of the CPU topology was zero
     CpuTopologyZeroPart,
+    /// Boot vCPUs don't fill whole dies of the CPU topology
+    CpuTopologyBootVcpus,
+    /// CPU affinity given for a vCPU beyond max
+    CpuAffinityInvalidVcpu(u8),
+    /// CPU affinity given twice for the same vCPU
+    CpuAffinityDuplicateVcpu(u8),
+    /// CPU affinity without any host CPU
+    CpuAffinityEmpty(u8),
+    /// Strict CPU affinity without a topology or an affinity
+    StrictAffinityRequiresTopology,
+    /// Invalid CPU model or features
+    CpuFeatures(String),
+    /// Invalid CPU frequency
+    CpuFrequency(String),
+    /// Nested virtualization not supported on AArch64
+    NestedNotSupported,
+    /// RNG rate limiting budget can't be zero
+    RngMaxBytesZero,
+    /// RNG rate limiting period can't be zero
+    RngPeriodZero,
+    /// Disk serial is longer than what virtio-blk can report
+    DiskSerialTooLong,
+    /// Readonly or direct used with an external vhost-user socket
+    DiskAccessModeWithSocket,
+    /// NVMe emulation can't be used with vhost-user
+    DiskNvmeWithVhostUser,
+    /// NVMe controller can't be placed behind the virtual IOMMU
+    DiskNvmeWithIommu,
+    /// NVMe controller only sits on the first PCI segment
+    DiskNvmeOnSegment(u16),
+    /// Disk striping only applies to the disk images opened by the VMM
+    DiskStripeWithVhostUser,
+    /// Disk striping can't be used with NVMe emulation
+    DiskStripeWithNvme,
+    /// Disk stripe size is zero or not a multiple of the sector size
+    DiskInvalidStripeSize(u64),
+    /// Rate limiting can't be enforced by vhost-user backends
+    RateLimiterWithVhostUser,
+    /// Rate limiting can't be used with NVMe emulation
+    DiskRateLimiterWithNvme,
+    /// Rate limiter bucket with a zero size or refill time
+    InvalidRateLimiter,
+    /// Busy polling only applies to the queues handled by the VMM
+    DiskBusyPollWithVhostUser,
+    /// Busy polling can't be used with NVMe emulation
+    DiskBusyPollWithNvme,
+    /// Busy polling for zero microseconds
+    DiskInvalidBusyPoll,
+    /// Completion coalescing only applies to the queues handled by the VMM
+    DiskCoalescingWithVhostUser,
+    /// Completion coalescing can't be used with NVMe emulation
+    DiskCoalescingWithNvme,
+    /// Disk coalescing request limit given without any delay
+    DiskCoalescingWithoutDelay,
+    /// Both readonly and discard_writes specified for pmem
+    PmemReadonlyDiscardWrites,
+    /// Free page reporting requires the balloon
+    FreePageReportingRequiresBalloon,
+    /// Deflate on OOM requires the balloon
+    DeflateOnOomRequiresBalloon,
+    /// Balloon statistics polling requires the balloon
+    StatsPollingRequiresBalloon,
+    /// Balloon autodeflate requires deflate on OOM
+    AutodeflateRequiresDeflateOnOom,
+    /// Vsock connection limit can't be zero
+    VsockMaxConnectionsZero,
+    /// Vsock CID is reserved or out of range
+    VsockInvalidCid(u64),
+    /// Vsock CID is already used by another VM on the host
+    VsockCidInUse(u64),
+    /// Vsock listening port is given more than once
+    VsockDuplicateListenPort(u32),
+    /// Watchdog timeout can't be zero
+    WatchdogTimeoutZero,
+    /// Sound file backend used without a path
+    SoundFileMissing,
+    /// GPU scanout size out of bounds
+    GpuInvalidSize(u32, u32),
+    /// vDPA device needs at least one queue
+    VdpaNumQueuesZero,
+    /// Two guest NUMA nodes share the same identifier
+    NumaDuplicateId(u32),
+    /// Guest NUMA nodes given without the node 0
+    NumaNodeZeroMissing,
+    /// Guest NUMA node referenced without being defined
+    NumaUnknownNode(u32),
+    /// Guest NUMA node without any vCPU nor memory
+    NumaNodeEmpty(u32),
+    /// vCPU beyond max assigned to a guest NUMA node
+    NumaInvalidVcpu(u8),
+    /// vCPU assigned to more than one guest NUMA node
+    NumaDuplicateVcpu(u8),
+    /// Distance between two guest NUMA nodes is out of range or given twice
+    NumaInvalidDistance(u32, u32),
+    /// Distance between two guest NUMA nodes is missing
+    NumaDistancesIncomplete(u32, u32),
+    /// Distances between two guest NUMA nodes differ in each direction
+    NumaDistancesAsymmetric(u32, u32),
+    /// GDB stub needs exactly one of a socket path or a TCP address
+    #[cfg(target_arch = "x86_64")]
+    GdbEndpoint,
+    /// Two memory zones share the same identifier
+    MemoryZoneDuplicateId,
+    /// Memory zones can only be hotplugged through virtio-mem
+    MemoryZoneHotplugMethod,
+    /// Memory zone block size is not a power of two or is too small
+    MemoryZoneInvalidBlockSize,
+    /// Memory zone block size is not a multiple of the hugepage size
+    MemoryZoneBlockSizeHugepages,
+    /// Memory zone sizes are not multiples of the block size
+    MemoryZoneUnalignedSize,
+    /// Memory zone initially hotplugged size exceeds the hotplug size
+    MemoryZoneHotpluggedSizeTooLarge,
+    /// Memory zone backed by a memfd without being shared
+    MemoryZoneMemfdNotShared,
+    /// Hugepage size is neither 2MiB nor 1GiB
+    InvalidHugepageSize,
+    /// Hugepage size specified without hugepages
+    HugepageSizeWithoutHugepages,
+    /// Transparent huge pages policy specified along with hugepages
+    ThpWithHugepages,
+    /// Number of PCI segments is zero or too large
+    InvalidNumPciSegments(u16),
+    /// Device placed on a PCI segment which doesn't exist
+    InvalidPciSegment(u16),
+    /// Device placed behind the IOMMU on a PCI segment other than the first one
+    IommuNotSupportedOnSegment(u16),
+    /// VLAN of a passed through VF is out of range
+    DeviceInvalidVlan(u16),
+    /// MAC address or VLAN given for a device which isn't an SR-IOV VF
+    DeviceNotVirtualFunction(PathBuf),
+    /// Snapshot compression level specified without any compression
+    SnapshotCompressionLevelWithoutCompression,
+    /// Snapshot compression level is out of range
+    InvalidSnapshotCompressionLevel(i32),
+    /// Snapshot written by no thread
+    InvalidSnapshotThreads,
+    /// TLS requested for a migration which isn't over TCP
+    MigrationTlsWithoutTcp,
+    /// TLS requested for a post-copy migration
+    MigrationTlsWithPostcopy,
+    /// Migration TLS certificate given without its key, or conversely
+    MigrationTlsCertWithoutKey,
+    /// Migration TLS source missing the CA of the destination
+    MigrationTlsCaMissing,
+    /// Migration TLS destination missing its certificate
+    MigrationTlsCertMissing,
+    /// Migration timing out immediately
+    InvalidMigrationTimeout,
+    /// Migration limited to a zero bandwidth
+    InvalidMigrationBandwidth,
+    /// Migration throttling the vCPUs out of range
+    InvalidMigrationThrottle(u8),
+    /// Auto-converge requested for a post-copy migration
+    MigrationAutoConvergeWithPostcopy,
+    /// Local migration requested over another transport than a UNIX socket
+    LocalMigrationWithoutUnix,
+    /// Post-copy or auto-converge requested for a local migration
+    LocalMigrationWithMemoryCopy,
 }
 
 type ValidationResult<T> = std::result::Result<T, ValidationError>;
@@ -104,18 +340,301 @@ impl fmt::Display for ValidationError {
             DoubleTtyMode => write!(f, "Console mode tty specified for both serial and console"),
             KernelMissing => write!(f, "No kernel specified"),
             ConsoleFileMissing => write!(f, "Path missing when using file console mode"),
+            ConsoleSocketMissing => write!(f, "Path missing when using socket console mode"),
+            ConsoleTcpMissing => write!(f, "Address missing when using tcp console mode"),
+            ConsolePortPathMissing(name) => write!(f, "Path missing for console port {}", name),
+            ConsolePortInvalidName(name) => write!(f, "Invalid console port name {:?}", name),
+            ConsolePortDuplicateName(name) => {
+                write!(f, "Console port name {} is used more than once", name)
+            }
+            ConsolePortsTooMany => write!(
+                f,
+                "Too many console ports, at most {} can be added",
+                virtio_devices::VIRTIO_CONSOLE_MAX_PORTS - 1
+            ),
+            ConsolePortsWithoutConsole => {
+                write!(f, "Console ports can't be used with the console off")
+            }
             CpusMaxLowerThanBoot => write!(f, "Max CPUs greater than boot CPUs"),
             DiskSocketAndPath => write!(f, "Disk path and vhost socket both provided"),
             VhostUserRequiresSharedMemory => {
                 write!(f, "Using vhost-user requires using shared memory")
             }
+            NetCoalescingWithoutDelay => write!(
+                f,
+                "Network coalescing requires coalesce_max_usecs when coalesce_max_packets is set"
+            ),
+            NetRxStarvationWithoutTimeout => write!(
+                f,
+                "Network rx_starvation=block requires a non zero rx_starvation_timeout_ms"
+            ),
+            NetFailoverUnknownDevice(id) => {
+                write!(
+                    f,
+                    "Network failover device {} is not a passthrough device",
+                    id
+                )
+            }
+            NetFailoverMacMismatch(id) => write!(
+                f,
+                "Network failover device {} must be given the MAC address of the network device",
+                id
+            ),
+            NetFailoverVhostUser => {
+                write!(f, "Network failover is not supported with vhost_user=true")
+            }
             IommuUnsupported => write!(f, "Using an IOMMU without PCI support is unsupported"),
             VfioUnsupported => write!(f, "Using VFIO without PCI support is unsupported"),
+            NvmeUnsupported => write!(f, "Using NVMe without PCI support is unsupported"),
             CpuTopologyZeroPart => write!(f, "No part of the CPU topology can be zero"),
             CpuTopologyCount => write!(
                 f,
                 "Product of CPU topology parts does not match maximum vCPUs"
             ),
+            CpuTopologyBootVcpus => write!(
+                f,
+                "Boot vCPUs must be a multiple of threads_per_core * cores_per_die"
+            ),
+            CpuAffinityInvalidVcpu(v) => {
+                write!(f, "CPU affinity given for vCPU {} beyond maximum vCPUs", v)
+            }
+            CpuAffinityDuplicateVcpu(v) => {
+                write!(f, "CPU affinity given more than once for vCPU {}", v)
+            }
+            CpuAffinityEmpty(v) => write!(f, "CPU affinity of vCPU {} has no host CPU", v),
+            StrictAffinityRequiresTopology => write!(
+                f,
+                "Strict CPU affinity requires both a CPU topology and an affinity"
+            ),
+            CpuFeatures(s) => write!(f, "Invalid CPU features: {}", s),
+            CpuFrequency(s) => write!(f, "Invalid CPU frequency: {}", s),
+            NestedNotSupported => write!(f, "Nested virtualization not supported on AArch64"),
+            RngMaxBytesZero => write!(f, "RNG max_bytes can't be zero"),
+            RngPeriodZero => write!(f, "RNG period_ms can't be zero"),
+            DiskSerialTooLong => write!(
+                f,
+                "Disk serial can't be longer than {} bytes",
+                DISK_SERIAL_MAX_LEN
+            ),
+            DiskAccessModeWithSocket => write!(
+                f,
+                "Disk readonly and direct can't be enforced with a vhost-user socket"
+            ),
+            DiskNvmeWithVhostUser => write!(f, "Disk nvme and vhost_user are mutually exclusive"),
+            DiskNvmeWithIommu => write!(f, "Disk nvme can't be placed behind the IOMMU"),
+            DiskNvmeOnSegment(segment) => write!(
+                f,
+                "Disk nvme can't be placed on PCI segment {}, the controller is on segment 0",
+                segment
+            ),
+            DiskStripeWithVhostUser => {
+                write!(f, "Disk stripe_paths and vhost_user are mutually exclusive")
+            }
+            DiskStripeWithNvme => write!(f, "Disk stripe_paths and nvme are mutually exclusive"),
+            DiskInvalidStripeSize(size) => write!(
+                f,
+                "Disk stripe size {} is invalid, it must be a non zero multiple of {}",
+                size,
+                virtio_devices::block::SECTOR_SIZE
+            ),
+            RateLimiterWithVhostUser => {
+                write!(f, "Rate limiting can't be used with vhost_user=true")
+            }
+            DiskRateLimiterWithNvme => write!(f, "Disk rate limiting can't be used with nvme"),
+            InvalidRateLimiter => write!(f, "Rate limiter sizes and refill times must be non zero"),
+            DiskBusyPollWithVhostUser => {
+                write!(f, "Disk busy_poll and vhost_user are mutually exclusive")
+            }
+            DiskBusyPollWithNvme => write!(f, "Disk busy_poll and nvme are mutually exclusive"),
+            DiskInvalidBusyPoll => write!(f, "Disk busy_poll_us must be non zero"),
+            DiskCoalescingWithVhostUser => {
+                write!(f, "Disk coalescing and vhost_user are mutually exclusive")
+            }
+            DiskCoalescingWithNvme => write!(f, "Disk coalescing and nvme are mutually exclusive"),
+            DiskCoalescingWithoutDelay => write!(
+                f,
+                "Disk coalescing requires coalesce_max_usecs when coalesce_max_requests is set"
+            ),
+            PmemReadonlyDiscardWrites => {
+                write!(f, "Pmem readonly and discard_writes are mutually exclusive")
+            }
+            FreePageReportingRequiresBalloon => {
+                write!(f, "Free page reporting requires the balloon to be enabled")
+            }
+            DeflateOnOomRequiresBalloon => {
+                write!(f, "Deflate on OOM requires the balloon to be enabled")
+            }
+            StatsPollingRequiresBalloon => write!(
+                f,
+                "Balloon statistics polling requires the balloon to be enabled"
+            ),
+            AutodeflateRequiresDeflateOnOom => write!(
+                f,
+                "Balloon autodeflate requires deflate on OOM to be enabled"
+            ),
+            VsockMaxConnectionsZero => write!(f, "Vsock connection limit can't be zero"),
+            VsockInvalidCid(cid) => write!(
+                f,
+                "Vsock CID {} is invalid, it must be at least 3 and fit in 32 bits",
+                cid
+            ),
+            VsockCidInUse(cid) => write!(f, "Vsock CID {} is already in use on the host", cid),
+            VsockDuplicateListenPort(port) => {
+                write!(f, "Vsock listening port {} is given more than once", port)
+            }
+            WatchdogTimeoutZero => write!(f, "Watchdog timeout can't be zero"),
+            SoundFileMissing => write!(f, "Path missing when using the sound file backend"),
+            GpuInvalidSize(width, height) => write!(
+                f,
+                "GPU scanout size {}x{} is invalid, both dimensions must be between 1 and {}",
+                width,
+                height,
+                GpuConfig::MAX_SIZE
+            ),
+            VdpaNumQueuesZero => write!(f, "vDPA device needs at least one queue"),
+            NumaDuplicateId(id) => write!(f, "Guest NUMA node {} is defined twice", id),
+            NumaNodeZeroMissing => write!(
+                f,
+                "Guest NUMA node 0 must be defined, it holds the boot memory"
+            ),
+            NumaUnknownNode(id) => write!(f, "Guest NUMA node {} is not defined", id),
+            NumaNodeEmpty(id) => write!(f, "Guest NUMA node {} has neither vCPU nor memory", id),
+            NumaInvalidVcpu(v) => write!(
+                f,
+                "vCPU {} beyond maximum vCPUs assigned to a guest NUMA node",
+                v
+            ),
+            NumaDuplicateVcpu(v) => {
+                write!(f, "vCPU {} assigned to more than one guest NUMA node", v)
+            }
+            NumaInvalidDistance(from, to) => write!(
+                f,
+                "Invalid distance from guest NUMA node {} to {}, it must be 10 from a node \
+                to itself and above 10 otherwise, and be given once",
+                from, to
+            ),
+            NumaDistancesIncomplete(from, to) => write!(
+                f,
+                "Distance from guest NUMA node {} to {} is missing",
+                from, to
+            ),
+            NumaDistancesAsymmetric(from, to) => write!(
+                f,
+                "Distances between guest NUMA nodes {} and {} differ, unless asymmetric=on",
+                from, to
+            ),
+            #[cfg(target_arch = "x86_64")]
+            GdbEndpoint => write!(
+                f,
+                "GDB stub requires either a socket path or a TCP address, but not both"
+            ),
+            MemoryZoneDuplicateId => write!(f, "Memory zone identifiers must be unique"),
+            MemoryZoneHotplugMethod => {
+                write!(f, "Memory zones require hotplug_method=virtio-mem")
+            }
+            MemoryZoneInvalidBlockSize => write!(
+                f,
+                "Memory zone block size must be a power of two of at least {} bytes",
+                DEFAULT_MEMORY_ZONE_BLOCK_SIZE
+            ),
+            MemoryZoneBlockSizeHugepages => write!(
+                f,
+                "Memory zone block size must be a multiple of the hugepage size"
+            ),
+            MemoryZoneUnalignedSize => write!(
+                f,
+                "Memory zone hotplug sizes must be multiples of the block size"
+            ),
+            MemoryZoneHotpluggedSizeTooLarge => write!(
+                f,
+                "Memory zone hotplugged size can't exceed its hotplug size"
+            ),
+            MemoryZoneMemfdNotShared => {
+                write!(f, "Memory zones with backing=memfd require shared=on")
+            }
+            InvalidHugepageSize => write!(f, "Hugepage size must be either 2M or 1G"),
+            HugepageSizeWithoutHugepages => {
+                write!(f, "Hugepage size can only be used along with hugepages=on")
+            }
+            ThpWithHugepages => write!(
+                f,
+                "Transparent huge pages policy can't be set along with hugepages=on"
+            ),
+            InvalidNumPciSegments(n) => write!(
+                f,
+                "Number of PCI segments {} is invalid, it must be between 1 and {}",
+                n, MAX_NUM_PCI_SEGMENTS
+            ),
+            InvalidPciSegment(segment) => {
+                write!(
+                    f,
+                    "Device placed on PCI segment {} which doesn't exist",
+                    segment
+                )
+            }
+            IommuNotSupportedOnSegment(segment) => write!(
+                f,
+                "Device on PCI segment {} can't be placed behind the IOMMU",
+                segment
+            ),
+            DeviceInvalidVlan(vlan) => {
+                write!(
+                    f,
+                    "Device VLAN {} is invalid, it must be at most 4094",
+                    vlan
+                )
+            }
+            DeviceNotVirtualFunction(path) => write!(
+                f,
+                "MAC address and VLAN require {} to be an SR-IOV virtual function",
+                path.display()
+            ),
+            SnapshotCompressionLevelWithoutCompression => write!(
+                f,
+                "Snapshot compression level specified without any compression"
+            ),
+            InvalidSnapshotCompressionLevel(level) => write!(
+                f,
+                "Snapshot compression level {} is invalid, it must be between 1 and 22",
+                level
+            ),
+            InvalidSnapshotThreads => write!(f, "Snapshot must be written by at least one thread"),
+            MigrationTlsWithoutTcp => write!(f, "Migration TLS is only supported over TCP"),
+            MigrationTlsWithPostcopy => {
+                write!(f, "Migration TLS is not supported with post-copy")
+            }
+            MigrationTlsCertWithoutKey => write!(
+                f,
+                "Migration TLS certificate and key must be specified together"
+            ),
+            MigrationTlsCaMissing => write!(
+                f,
+                "Migration TLS requires the CA of the destination certificate on the source"
+            ),
+            MigrationTlsCertMissing => write!(
+                f,
+                "Migration TLS requires a certificate and its key on the destination"
+            ),
+            InvalidMigrationTimeout => write!(f, "Migration timeout must be at least one second"),
+            InvalidMigrationBandwidth => write!(
+                f,
+                "Migration bandwidth limit must be at least one byte per second"
+            ),
+            InvalidMigrationThrottle(percent) => write!(
+                f,
+                "Migration maximum throttle {}% is invalid, it must be between 1 and {}%",
+                percent, MAX_THROTTLE
+            ),
+            MigrationAutoConvergeWithPostcopy => {
+                write!(f, "Migration auto-converge is not supported with post-copy")
+            }
+            LocalMigrationWithoutUnix => {
+                write!(f, "Local migration is only supported over a UNIX socket")
+            }
+            LocalMigrationWithMemoryCopy => write!(
+                f,
+                "Local migration supports neither post-copy nor auto-converge"
+            ),
         }
     }
 }
@@ -128,6 +647,11 @@ impl fmt::Display for Error {
             ParseConsoleInvalidModeGiven => {
                 write!(f, "Error parsing --console: invalid console mode given")
             }
+            ParseConsolePort(o) => write!(f, "Error parsing --console port: {}", o),
+            ParseConsolePortNameMissing => write!(f, "Error parsing --console port: name missing"),
+            ParseConsolePortInvalidModeGiven => {
+                write!(f, "Error parsing --console port: invalid port mode given")
+            }
             ParseCpus(o) => write!(f, "Error parsing --cpus: {}", o),
 
             ParseDevice(o) => write!(f, "Error parsing --device: {}", o),
@@ -143,13 +667,30 @@ impl fmt::Display for Error {
             ParseVsock(o) => write!(f, "Error parsing --vsock: {}", o),
             ParseVsockCidMissing => write!(f, "Error parsing --vsock: cid missing"),
             ParseVsockSockMissing => write!(f, "Error parsing --vsock: socket missing"),
+            ParseWatchdog(o) => write!(f, "Error parsing --watchdog: {}", o),
+            ParseSound(o) => write!(f, "Error parsing --sound: {}", o),
+            ParseGpu(o) => write!(f, "Error parsing --gpu: {}", o),
+            ParseVdpa(o) => write!(f, "Error parsing --vdpa: {}", o),
+            ParseVdpaPathMissing => write!(f, "Error parsing --vdpa: path missing"),
+            ParseNuma(o) => write!(f, "Error parsing --numa: {}", o),
+            ParseNumaIdMissing => write!(f, "Error parsing --numa: guest_numa_id missing"),
             ParseMemory(o) => write!(f, "Error parsing --memory: {}", o),
+            ParseMemoryZone(o) => write!(f, "Error parsing --memory-zone: {}", o),
+            ParseMemoryZoneIdMissing => write!(f, "Error parsing --memory-zone: id missing"),
+            ParseMemoryZoneHotplugSizeMissing => {
+                write!(f, "Error parsing --memory-zone: hotplug_size missing")
+            }
             ParseNetwork(o) => write!(f, "Error parsing --net: {}", o),
             ParseDisk(o) => write!(f, "Error parsing --disk: {}", o),
             ParseRNG(o) => write!(f, "Error parsing --rng: {}", o),
             ParseRestore(o) => write!(f, "Error parsing --restore: {}", o),
+            ParsePlatform(o) => write!(f, "Error parsing --platform: {}", o),
             #[cfg(target_arch = "x86_64")]
             ParseSgxEpc(o) => write!(f, "Error parsing --sgx-epc: {}", o),
+            #[cfg(target_arch = "x86_64")]
+            ParseGdb(o) => write!(f, "Error parsing --gdb: {}", o),
+            #[cfg(target_arch = "x86_64")]
+            ParsePvpanic(o) => write!(f, "Error parsing --pvpanic: {}", o),
             ParseRestoreSourceUrlMissing => {
                 write!(f, "Error parsing --restore: source_url missing")
             }
@@ -163,6 +704,7 @@ pub type Result<T> = result::Result<T, Error>;
 pub struct VmParams<'a> {
     pub cpus: &'a str,
     pub memory: &'a str,
+    pub memory_zones: Option<Vec<&'a str>>,
     pub kernel: Option<&'a str>,
     pub initramfs: Option<&'a str>,
     pub cmdline: Option<&'a str>,
@@ -173,10 +715,21 @@ pub struct VmParams<'a> {
     pub pmem: Option<Vec<&'a str>>,
     pub serial: &'a str,
     pub console: &'a str,
+    pub console_ports: Option<Vec<&'a str>>,
     pub devices: Option<Vec<&'a str>>,
     pub vsock: Option<&'a str>,
+    pub watchdog: Option<&'a str>,
+    pub sound: Option<&'a str>,
+    pub gpu: Option<&'a str>,
+    pub vdpa: Option<Vec<&'a str>>,
+    pub numa: Option<Vec<&'a str>>,
+    pub platform: Option<&'a str>,
     #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<&'a str>>,
+    #[cfg(target_arch = "x86_64")]
+    pub gdb: Option<&'a str>,
+    #[cfg(target_arch = "x86_64")]
+    pub pvpanic: Option<&'a str>,
 }
 
 impl<'a> VmParams<'a> {
@@ -184,6 +737,7 @@ impl<'a> VmParams<'a> {
         // These .unwrap()s cannot fail as there is a default value defined
         let cpus = args.value_of("cpus").unwrap();
         let memory = args.value_of("memory").unwrap();
+        let memory_zones: Option<Vec<&str>> = args.values_of("memory-zone").map(|x| x.collect());
         let rng = args.value_of("rng").unwrap();
         let serial = args.value_of("serial").unwrap();
 
@@ -193,17 +747,43 @@ impl<'a> VmParams<'a> {
 
         let disks: Option<Vec<&str>> = args.values_of("disk").map(|x| x.collect());
         let net: Option<Vec<&str>> = args.values_of("net").map(|x| x.collect());
-        let console = args.value_of("console").unwrap();
+        // Values naming a port describe additional console ports, the
+        // last remaining one being the console itself.
+        let mut console = "tty";
+        let mut console_ports: Vec<&str> = Vec::new();
+        for value in args.values_of("console").unwrap() {
+            if ConsolePortConfig::is_port(value) {
+                console_ports.push(value);
+            } else {
+                console = value;
+            }
+        }
+        let console_ports = if console_ports.is_empty() {
+            None
+        } else {
+            Some(console_ports)
+        };
         let fs: Option<Vec<&str>> = args.values_of("fs").map(|x| x.collect());
         let pmem: Option<Vec<&str>> = args.values_of("pmem").map(|x| x.collect());
         let devices: Option<Vec<&str>> = args.values_of("device").map(|x| x.collect());
         let vsock: Option<&str> = args.value_of("vsock");
+        let watchdog: Option<&str> = args.value_of("watchdog");
+        let sound: Option<&str> = args.value_of("sound");
+        let gpu: Option<&str> = args.value_of("gpu");
+        let vdpa: Option<Vec<&str>> = args.values_of("vdpa").map(|x| x.collect());
+        let numa: Option<Vec<&str>> = args.values_of("numa").map(|x| x.collect());
+        let platform: Option<&str> = args.value_of("platform");
         #[cfg(target_arch = "x86_64")]
         let sgx_epc: Option<Vec<&str>> = args.values_of("sgx-epc").map(|x| x.collect());
+        #[cfg(target_arch = "x86_64")]
+        let gdb: Option<&str> = args.value_of("gdb");
+        #[cfg(target_arch = "x86_64")]
+        let pvpanic: Option<&str> = args.value_of("pvpanic");
 
         VmParams {
             cpus,
             memory,
+            memory_zones,
             kernel,
             initramfs,
             cmdline,
@@ -214,10 +794,21 @@ impl<'a> VmParams<'a> {
             pmem,
             serial,
             console,
+            console_ports,
             devices,
             vsock,
+            watchdog,
+            sound,
+            gpu,
+            vdpa,
+            numa,
+            platform,
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
+            #[cfg(target_arch = "x86_64")]
+            gdb,
+            #[cfg(target_arch = "x86_64")]
+            pvpanic,
         }
     }
 }
@@ -251,6 +842,71 @@ impl FromStr for HotplugMethod {
     }
 }
 
+/// Backing of the memory of a zone. `Memfd` memory is shared through a
+/// memfd whose size is sealed, so that other processes can be handed its
+/// file descriptor.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum MemoryBacking {
+    Anonymous,
+    Memfd,
+}
+
+impl Default for MemoryBacking {
+    fn default() -> Self {
+        MemoryBacking::Anonymous
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseMemoryBackingError {
+    InvalidValue(String),
+}
+
+impl FromStr for MemoryBacking {
+    type Err = ParseMemoryBackingError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "anonymous" => Ok(MemoryBacking::Anonymous),
+            "memfd" => Ok(MemoryBacking::Memfd),
+            _ => Err(ParseMemoryBackingError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// Transparent huge pages policy of the guest memory. With `madvise`, the
+/// host policy applies, nothing being advised about the pages.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ThpMode {
+    Always,
+    Never,
+    Madvise,
+}
+
+impl Default for ThpMode {
+    fn default() -> Self {
+        ThpMode::Madvise
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseThpModeError {
+    InvalidValue(String),
+}
+
+impl FromStr for ThpMode {
+    type Err = ParseThpModeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(ThpMode::Always),
+            "never" => Ok(ThpMode::Never),
+            "madvise" => Ok(ThpMode::Madvise),
+            _ => Err(ParseThpModeError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
 pub enum CpuTopologyParseError {
     InvalidValue(String),
 }
@@ -292,18 +948,133 @@ impl FromStr for CpuTopology {
     }
 }
 
+impl CpuTopology {
+    /// Topology of `max_vcpus` single threaded cores, on a single die and
+    /// package, which is what the guest sees without any explicit topology.
+    pub fn flat(max_vcpus: u8) -> Self {
+        CpuTopology {
+            threads_per_core: 1,
+            cores_per_die: max_vcpus,
+            dies_per_package: 1,
+            packages: 1,
+        }
+    }
+}
+
+/// Host CPUs a vCPU thread is allowed to run on.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CpuAffinity {
+    pub vcpu: u8,
+    pub host_cpus: Vec<usize>,
+}
+
+pub enum CpuAffinityParseError {
+    InvalidValue(String),
+}
+
+pub enum CpuFeatureParseError {
+    InvalidValue(String),
+}
+
+// List of CPU features, separated by ':', each of them prefixed with '-' to
+// hide it or '+' to expose it, as in "-avx512f:-waitpkg".
+struct CpuFeatureList(Vec<String>);
+
+impl FromStr for CpuFeatureList {
+    type Err = CpuFeatureParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut list = Vec::new();
+
+        for feature in s.split(':') {
+            if feature.len() < 2 || !(feature.starts_with('-') || feature.starts_with('+')) {
+                return Err(CpuFeatureParseError::InvalidValue(s.to_owned()));
+            }
+            list.push(feature.to_owned());
+        }
+
+        Ok(CpuFeatureList(list))
+    }
+}
+
+// List of vCPU affinities, separated by ':', each of them being the vCPU
+// index followed by '@' and either a host CPU or a range of host CPUs, as
+// in "0@4:1@5:2@6-7".
+struct CpuAffinityList(Vec<CpuAffinity>);
+
+impl FromStr for CpuAffinityList {
+    type Err = CpuAffinityParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || CpuAffinityParseError::InvalidValue(s.to_owned());
+        let mut list = Vec::new();
+
+        for affinity in s.split(':') {
+            let mut parts = affinity.splitn(2, '@');
+            let vcpu = parts
+                .next()
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(invalid)?;
+            let host_cpus = parts.next().ok_or_else(invalid)?;
+            let mut range = host_cpus.splitn(2, '-');
+            let first: usize = range
+                .next()
+                .and_then(|c| c.parse().ok())
+                .ok_or_else(invalid)?;
+            let last: usize = match range.next() {
+                Some(c) => c.parse().map_err(|_| invalid())?,
+                None => first,
+            };
+
+            list.push(CpuAffinity {
+                vcpu,
+                host_cpus: (first..=last).collect(),
+            });
+        }
+
+        Ok(CpuAffinityList(list))
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct CpusConfig {
     pub boot_vcpus: u8,
     pub max_vcpus: u8,
     #[serde(default)]
     pub topology: Option<CpuTopology>,
+    #[serde(default)]
+    pub affinity: Option<Vec<CpuAffinity>>,
+    #[serde(default)]
+    pub strict_affinity: bool,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub features: Option<Vec<String>>,
+    #[serde(default)]
+    pub base_frequency: Option<u16>,
+    #[serde(default)]
+    pub max_frequency: Option<u16>,
+    #[serde(default)]
+    pub pstates: bool,
+    #[serde(default)]
+    pub nested: bool,
 }
 
 impl CpusConfig {
     pub fn parse(cpus: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("boot").add("max").add("topology");
+        parser
+            .add("boot")
+            .add("max")
+            .add("topology")
+            .add("affinity")
+            .add("strict_affinity")
+            .add("model")
+            .add("features")
+            .add("base_frequency")
+            .add("max_frequency")
+            .add("pstates")
+            .add("nested");
         parser.parse(cpus).map_err(Error::ParseCpus)?;
 
         let boot_vcpus: u8 = parser
@@ -315,13 +1086,60 @@ impl CpusConfig {
             .map_err(Error::ParseCpus)?
             .unwrap_or(boot_vcpus);
         let topology = parser.convert("topology").map_err(Error::ParseCpus)?;
+        let affinity = parser
+            .convert::<CpuAffinityList>("affinity")
+            .map_err(Error::ParseCpus)?
+            .map(|l| l.0);
+        let strict_affinity = parser
+            .convert::<Toggle>("strict_affinity")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let model = parser.get("model");
+        let features = parser
+            .convert::<CpuFeatureList>("features")
+            .map_err(Error::ParseCpus)?
+            .map(|l| l.0);
+        let base_frequency = parser.convert("base_frequency").map_err(Error::ParseCpus)?;
+        let max_frequency = parser.convert("max_frequency").map_err(Error::ParseCpus)?;
+        let pstates = parser
+            .convert::<Toggle>("pstates")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let nested = parser
+            .convert::<Toggle>("nested")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(Toggle(false))
+            .0;
 
         Ok(CpusConfig {
             boot_vcpus,
             max_vcpus,
             topology,
+            affinity,
+            strict_affinity,
+            model,
+            features,
+            base_frequency,
+            max_frequency,
+            pstates,
+            nested,
         })
     }
+
+    /// The base and maximum frequencies reported to the guest, in MHz.
+    pub fn frequency(&self) -> Option<(u16, u16)> {
+        self.base_frequency
+            .map(|base| (base, self.max_frequency.unwrap_or(base)))
+    }
+
+    /// The topology exposed to the guest.
+    pub fn effective_topology(&self) -> CpuTopology {
+        self.topology
+            .clone()
+            .unwrap_or_else(|| CpuTopology::flat(self.max_vcpus))
+    }
 }
 
 impl Default for CpusConfig {
@@ -330,6 +1148,14 @@ impl Default for CpusConfig {
             boot_vcpus: DEFAULT_VCPUS,
             max_vcpus: DEFAULT_VCPUS,
             topology: None,
+            affinity: None,
+            strict_affinity: false,
+            model: None,
+            features: None,
+            base_frequency: None,
+            max_frequency: None,
+            pstates: false,
+            nested: false,
         }
     }
 }
@@ -350,9 +1176,29 @@ pub struct MemoryConfig {
     #[serde(default)]
     pub hugepages: bool,
     #[serde(default)]
+    pub prefault: bool,
+    #[serde(default)]
+    pub thp: ThpMode,
+    #[serde(default)]
     pub balloon: bool,
     #[serde(default)]
     pub balloon_size: u64,
+    #[serde(default)]
+    pub deflate_on_oom: bool,
+    #[serde(default)]
+    pub autodeflate: bool,
+    #[serde(default)]
+    pub free_page_reporting: bool,
+    #[serde(default)]
+    pub stats_polling_interval: u64,
+    #[serde(default = "default_memoryconfig_dirty_ring")]
+    pub dirty_ring: bool,
+    #[serde(default)]
+    pub zones: Option<Vec<MemoryZoneConfig>>,
+}
+
+fn default_memoryconfig_dirty_ring() -> bool {
+    true
 }
 
 impl MemoryConfig {
@@ -366,7 +1212,14 @@ impl MemoryConfig {
             .add("hotplug_size")
             .add("shared")
             .add("hugepages")
-            .add("balloon");
+            .add("prefault")
+            .add("thp")
+            .add("balloon")
+            .add("deflate_on_oom")
+            .add("autodeflate")
+            .add("free_page_reporting")
+            .add("stats_polling_interval")
+            .add("dirty_ring");
         parser.parse(memory).map_err(Error::ParseMemory)?;
 
         let size = parser
@@ -398,11 +1251,44 @@ impl MemoryConfig {
             .map_err(Error::ParseMemory)?
             .unwrap_or(Toggle(false))
             .0;
+        let prefault = parser
+            .convert::<Toggle>("prefault")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let thp = parser
+            .convert("thp")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or_default();
         let balloon = parser
             .convert::<Toggle>("balloon")
             .map_err(Error::ParseMemory)?
             .unwrap_or(Toggle(false))
             .0;
+        let deflate_on_oom = parser
+            .convert::<Toggle>("deflate_on_oom")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let autodeflate = parser
+            .convert::<Toggle>("autodeflate")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let free_page_reporting = parser
+            .convert::<Toggle>("free_page_reporting")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let stats_polling_interval = parser
+            .convert("stats_polling_interval")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(0);
+        let dirty_ring = parser
+            .convert::<Toggle>("dirty_ring")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(true))
+            .0;
 
         Ok(MemoryConfig {
             size,
@@ -412,8 +1298,16 @@ impl MemoryConfig {
             hotplug_size,
             shared,
             hugepages,
+            prefault,
+            thp,
             balloon,
             balloon_size: 0,
+            deflate_on_oom,
+            autodeflate,
+            free_page_reporting,
+            stats_polling_interval,
+            dirty_ring,
+            zones: None,
         })
     }
 }
@@ -428,18 +1322,237 @@ impl Default for MemoryConfig {
             hotplug_size: None,
             shared: false,
             hugepages: false,
+            prefault: false,
+            thp: ThpMode::Madvise,
             balloon: false,
             balloon_size: 0,
+            deflate_on_oom: false,
+            autodeflate: false,
+            free_page_reporting: false,
+            stats_polling_interval: 0,
+            dirty_ring: true,
+            zones: None,
         }
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct KernelConfig {
-    pub path: PathBuf,
+pub struct MemoryZoneConfig {
+    pub id: String,
+    pub hotplug_size: u64,
+    #[serde(default)]
+    pub hotplugged_size: Option<u64>,
+    #[serde(default = "default_memoryzoneconfig_hotplug_method")]
+    pub hotplug_method: HotplugMethod,
+    #[serde(default)]
+    pub host_numa_node: Option<u32>,
+    #[serde(default)]
+    pub guest_numa_node: Option<u32>,
+    #[serde(default = "default_memoryzoneconfig_block_size")]
+    pub block_size: u64,
+    #[serde(default)]
+    pub shared: bool,
+    #[serde(default)]
+    pub hugepages: bool,
+    #[serde(default)]
+    pub hugepage_size: Option<u64>,
+    #[serde(default)]
+    pub prefault: bool,
+    #[serde(default)]
+    pub mergeable: bool,
+    #[serde(default)]
+    pub thp: ThpMode,
+    #[serde(default)]
+    pub backing: MemoryBacking,
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+fn default_memoryzoneconfig_hotplug_method() -> HotplugMethod {
+    HotplugMethod::VirtioMem
+}
+
+fn default_memoryzoneconfig_block_size() -> u64 {
+    DEFAULT_MEMORY_ZONE_BLOCK_SIZE
+}
+
+impl Default for MemoryZoneConfig {
+    fn default() -> Self {
+        MemoryZoneConfig {
+            id: String::new(),
+            hotplug_size: 0,
+            hotplugged_size: None,
+            hotplug_method: default_memoryzoneconfig_hotplug_method(),
+            host_numa_node: None,
+            guest_numa_node: None,
+            block_size: default_memoryzoneconfig_block_size(),
+            shared: false,
+            hugepages: false,
+            hugepage_size: None,
+            prefault: false,
+            mergeable: false,
+            thp: ThpMode::Madvise,
+            backing: MemoryBacking::Anonymous,
+        }
+    }
+}
+
+impl MemoryZoneConfig {
+    pub const SYNTAX: &'static str = "Memory zone parameters \
+        \"id=<zone_identifier>,hotplug_method=virtio-mem,\
+        hotplug_size=<hotpluggable_memory_size>,hotplugged_size=<hotplugged_memory_size>,\
+        host_numa_node=<host_node_id>,guest_numa_node=<guest_node_id>,\
+        block_size=<virtio_mem_block_size>,shared=on|off,hugepages=on|off,\
+        hugepage_size=2M|1G,prefault=on|off,mergeable=on|off,\
+        thp=always|never|madvise,backing=anonymous|memfd\"";
+    pub fn parse(memory_zone: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("id")
+            .add("hotplug_size")
+            .add("hotplugged_size")
+            .add("hotplug_method")
+            .add("host_numa_node")
+            .add("guest_numa_node")
+            .add("block_size")
+            .add("shared")
+            .add("hugepages")
+            .add("hugepage_size")
+            .add("prefault")
+            .add("mergeable")
+            .add("thp")
+            .add("backing");
+        parser.parse(memory_zone).map_err(Error::ParseMemoryZone)?;
+
+        let id = parser.get("id").ok_or(Error::ParseMemoryZoneIdMissing)?;
+        let hotplug_size = parser
+            .convert::<ByteSized>("hotplug_size")
+            .map_err(Error::ParseMemoryZone)?
+            .ok_or(Error::ParseMemoryZoneHotplugSizeMissing)?
+            .0;
+        let hotplugged_size = parser
+            .convert::<ByteSized>("hotplugged_size")
+            .map_err(Error::ParseMemoryZone)?
+            .map(|v| v.0);
+        let hotplug_method = parser
+            .convert("hotplug_method")
+            .map_err(Error::ParseMemoryZone)?
+            .unwrap_or_else(default_memoryzoneconfig_hotplug_method);
+        let host_numa_node = parser
+            .convert("host_numa_node")
+            .map_err(Error::ParseMemoryZone)?;
+        let guest_numa_node = parser
+            .convert("guest_numa_node")
+            .map_err(Error::ParseMemoryZone)?;
+        let block_size = parser
+            .convert::<ByteSized>("block_size")
+            .map_err(Error::ParseMemoryZone)?
+            .unwrap_or(ByteSized(DEFAULT_MEMORY_ZONE_BLOCK_SIZE))
+            .0;
+        let shared = parser
+            .convert::<Toggle>("shared")
+            .map_err(Error::ParseMemoryZone)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let hugepages = parser
+            .convert::<Toggle>("hugepages")
+            .map_err(Error::ParseMemoryZone)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let hugepage_size = parser
+            .convert::<ByteSized>("hugepage_size")
+            .map_err(Error::ParseMemoryZone)?
+            .map(|v| v.0);
+        let prefault = parser
+            .convert::<Toggle>("prefault")
+            .map_err(Error::ParseMemoryZone)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let mergeable = parser
+            .convert::<Toggle>("mergeable")
+            .map_err(Error::ParseMemoryZone)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let thp = parser
+            .convert("thp")
+            .map_err(Error::ParseMemoryZone)?
+            .unwrap_or_default();
+        let backing = parser
+            .convert("backing")
+            .map_err(Error::ParseMemoryZone)?
+            .unwrap_or_default();
+
+        Ok(MemoryZoneConfig {
+            id,
+            hotplug_size,
+            hotplugged_size,
+            hotplug_method,
+            host_numa_node,
+            guest_numa_node,
+            block_size,
+            shared,
+            hugepages,
+            hugepage_size,
+            prefault,
+            mergeable,
+            thp,
+            backing,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.hotplug_method != HotplugMethod::VirtioMem {
+            return Err(ValidationError::MemoryZoneHotplugMethod);
+        }
+
+        if !self.block_size.is_power_of_two() || self.block_size < DEFAULT_MEMORY_ZONE_BLOCK_SIZE {
+            return Err(ValidationError::MemoryZoneInvalidBlockSize);
+        }
+
+        if let Some(hugepage_size) = self.hugepage_size {
+            if !self.hugepages {
+                return Err(ValidationError::HugepageSizeWithoutHugepages);
+            }
+            if hugepage_size != 2 << 20 && hugepage_size != 1 << 30 {
+                return Err(ValidationError::InvalidHugepageSize);
+            }
+        }
+
+        // Each block must be backed by a whole number of hugepages, as a
+        // hugepage can't be partially given back to the host.
+        if self.hugepages && self.block_size % self.hugepage_size.unwrap_or(2 << 20) != 0 {
+            return Err(ValidationError::MemoryZoneBlockSizeHugepages);
+        }
+
+        if self.backing == MemoryBacking::Memfd && !self.shared {
+            return Err(ValidationError::MemoryZoneMemfdNotShared);
+        }
+
+        // Pages from hugetlbfs aren't subject to transparent huge pages.
+        if self.hugepages && self.thp != ThpMode::Madvise {
+            return Err(ValidationError::ThpWithHugepages);
+        }
+
+        let hotplugged_size = self.hotplugged_size.unwrap_or(0);
+        if self.hotplug_size == 0
+            || self.hotplug_size % self.block_size != 0
+            || hotplugged_size % self.block_size != 0
+        {
+            return Err(ValidationError::MemoryZoneUnalignedSize);
+        }
+
+        if hotplugged_size > self.hotplug_size {
+            return Err(ValidationError::MemoryZoneHotpluggedSizeTooLarge);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct KernelConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct InitramfsConfig {
     pub path: PathBuf,
 }
@@ -479,6 +1592,26 @@ pub struct DiskConfig {
     pub poll_queue: bool,
     #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub serial: Option<String>,
+    #[serde(default)]
+    pub nvme: bool,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub stripe_paths: Vec<PathBuf>,
+    #[serde(default = "default_diskconfig_stripe_size")]
+    pub stripe_size: u64,
+    #[serde(default)]
+    pub rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub busy_poll: bool,
+    #[serde(default = "default_diskconfig_busy_poll_us")]
+    pub busy_poll_us: u64,
+    #[serde(default)]
+    pub coalesce_max_requests: u32,
+    #[serde(default)]
+    pub coalesce_max_usecs: u64,
 }
 
 fn default_diskconfig_num_queues() -> usize {
@@ -493,6 +1626,14 @@ fn default_diskconfig_poll_queue() -> bool {
     true
 }
 
+fn default_diskconfig_stripe_size() -> u64 {
+    DEFAULT_DISK_STRIPE_SIZE
+}
+
+fn default_diskconfig_busy_poll_us() -> u64 {
+    DEFAULT_DISK_BUSY_POLL_US
+}
+
 impl Default for DiskConfig {
     fn default() -> Self {
         Self {
@@ -506,15 +1647,90 @@ impl Default for DiskConfig {
             vhost_socket: None,
             poll_queue: default_diskconfig_poll_queue(),
             id: None,
+            serial: None,
+            nvme: false,
+            pci_segment: 0,
+            stripe_paths: Vec::new(),
+            stripe_size: default_diskconfig_stripe_size(),
+            rate_limiter_config: None,
+            busy_poll: false,
+            busy_poll_us: default_diskconfig_busy_poll_us(),
+            coalesce_max_requests: 0,
+            coalesce_max_usecs: 0,
+        }
+    }
+}
+
+struct PathList(Vec<PathBuf>);
+
+impl FromStr for PathList {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(PathList(s.split(':').map(PathBuf::from).collect()))
+    }
+}
+
+// Rate limiter described by the bw_size/bw_refill_time and the
+// ops_size/ops_refill_time options of a disk or a network device, each
+// bucket being refilled every second unless specified otherwise.
+fn parse_rate_limiter(
+    parser: &OptionParser,
+) -> std::result::Result<Option<RateLimiterConfig>, OptionParserError> {
+    let refill_time = |option: &str| {
+        parser
+            .convert(option)
+            .map(|t| t.unwrap_or(DEFAULT_RATE_LIMITER_REFILL_TIME_MS))
+    };
+    let bandwidth = match parser.convert::<ByteSized>("bw_size")? {
+        Some(size) => Some(TokenBucketConfig {
+            size: size.0,
+            refill_time: refill_time("bw_refill_time")?,
+        }),
+        None => None,
+    };
+    let ops = match parser.convert("ops_size")? {
+        Some(size) => Some(TokenBucketConfig {
+            size,
+            refill_time: refill_time("ops_refill_time")?,
+        }),
+        None => None,
+    };
+
+    if bandwidth.is_none() && parser.is_set("bw_refill_time") {
+        warn!("bw_refill_time has no effect without bw_size");
+    }
+    if ops.is_none() && parser.is_set("ops_refill_time") {
+        warn!("ops_refill_time has no effect without ops_size");
+    }
+
+    if bandwidth.is_none() && ops.is_none() {
+        return Ok(None);
+    }
+
+    Ok(Some(RateLimiterConfig { bandwidth, ops }))
+}
+
+fn validate_rate_limiter(config: &RateLimiterConfig) -> ValidationResult<()> {
+    for bucket in config.bandwidth.iter().chain(config.ops.iter()) {
+        if bucket.size == 0 || bucket.refill_time == 0 {
+            return Err(ValidationError::InvalidRateLimiter);
         }
     }
+
+    Ok(())
 }
 
 impl DiskConfig {
     pub const SYNTAX: &'static str = "Disk parameters \
          \"path=<disk_image_path>,readonly=on|off,iommu=on|off,num_queues=<number_of_queues>,\
          queue_size=<size_of_each_queue>,vhost_user=<vhost_user_enable>,\
-         socket=<vhost_user_socket_path>, default true>,id=<device_id>\"";
+         socket=<vhost_user_socket_path>, default true>,id=<device_id>,serial=<serial_number>,\
+         nvme=on|off,pci_segment=<segment_id>,stripe_paths=<image_path>:<image_path>:...,\
+         stripe_size=<stripe_chunk_size>,bw_size=<bytes>,bw_refill_time=<ms>,\
+         ops_size=<io_ops>,ops_refill_time=<ms>,busy_poll=on|off,busy_poll_us=<us>,\
+         coalesce_max_requests=<completed_requests_per_interrupt>,\
+         coalesce_max_usecs=<max_interrupt_delay_us>\"";
 
     pub fn parse(disk: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -528,7 +1744,20 @@ impl DiskConfig {
             .add("vhost_user")
             .add("socket")
             .add("poll_queue")
-            .add("id");
+            .add("id")
+            .add("serial")
+            .add("nvme")
+            .add("pci_segment")
+            .add("stripe_paths")
+            .add("stripe_size")
+            .add("bw_size")
+            .add("bw_refill_time")
+            .add("ops_size")
+            .add("ops_refill_time")
+            .add("busy_poll")
+            .add("busy_poll_us")
+            .add("coalesce_max_requests")
+            .add("coalesce_max_usecs");
         parser.parse(disk).map_err(Error::ParseDisk)?;
 
         let path = parser.get("path").map(PathBuf::from);
@@ -567,10 +1796,51 @@ impl DiskConfig {
             .unwrap_or_else(|| Toggle(default_diskconfig_poll_queue()))
             .0;
         let id = parser.get("id");
+        let serial = parser.get("serial");
+        let nvme = parser
+            .convert::<Toggle>("nvme")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let stripe_paths = parser
+            .convert::<PathList>("stripe_paths")
+            .map_err(Error::ParseDisk)?
+            .map(|paths| paths.0)
+            .unwrap_or_default();
+        let stripe_size = parser
+            .convert::<ByteSized>("stripe_size")
+            .map_err(Error::ParseDisk)?
+            .map(|size| size.0)
+            .unwrap_or_else(default_diskconfig_stripe_size);
+        let rate_limiter_config = parse_rate_limiter(&parser).map_err(Error::ParseDisk)?;
+        let busy_poll = parser
+            .convert::<Toggle>("busy_poll")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let busy_poll_us = parser
+            .convert("busy_poll_us")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_else(default_diskconfig_busy_poll_us);
+        let coalesce_max_requests = parser
+            .convert("coalesce_max_requests")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(0);
+        let coalesce_max_usecs = parser
+            .convert("coalesce_max_usecs")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(0);
 
         if parser.is_set("poll_queue") && !vhost_user {
             warn!("poll_queue parameter currently only has effect when used vhost_user=true");
         }
+        if parser.is_set("busy_poll_us") && !busy_poll {
+            warn!("busy_poll_us has no effect without busy_poll=on");
+        }
 
         Ok(DiskConfig {
             path,
@@ -583,8 +1853,102 @@ impl DiskConfig {
             vhost_user,
             poll_queue,
             id,
+            serial,
+            nvme,
+            pci_segment,
+            stripe_paths,
+            stripe_size,
+            rate_limiter_config,
+            busy_poll,
+            busy_poll_us,
+            coalesce_max_requests,
+            coalesce_max_usecs,
         })
     }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.vhost_socket.as_ref().and(self.path.as_ref()).is_some() {
+            return Err(ValidationError::DiskSocketAndPath);
+        }
+
+        // Access mode can't be enforced when the vhost-user backend is not
+        // spawned by us.
+        if self.vhost_socket.is_some() && (self.readonly || self.direct) {
+            return Err(ValidationError::DiskAccessModeWithSocket);
+        }
+
+        if let Some(serial) = &self.serial {
+            if serial.len() > DISK_SERIAL_MAX_LEN {
+                return Err(ValidationError::DiskSerialTooLong);
+            }
+        }
+
+        if self.nvme {
+            if self.vhost_user {
+                return Err(ValidationError::DiskNvmeWithVhostUser);
+            }
+            if self.iommu {
+                return Err(ValidationError::DiskNvmeWithIommu);
+            }
+        }
+
+        if !self.stripe_paths.is_empty() {
+            if self.vhost_user || self.vhost_socket.is_some() {
+                return Err(ValidationError::DiskStripeWithVhostUser);
+            }
+            if self.nvme {
+                return Err(ValidationError::DiskStripeWithNvme);
+            }
+            if self.stripe_size == 0 || self.stripe_size % virtio_devices::block::SECTOR_SIZE != 0 {
+                return Err(ValidationError::DiskInvalidStripeSize(self.stripe_size));
+            }
+        }
+
+        if let Some(rate_limiter_config) = &self.rate_limiter_config {
+            if self.vhost_user || self.vhost_socket.is_some() {
+                return Err(ValidationError::RateLimiterWithVhostUser);
+            }
+            if self.nvme {
+                return Err(ValidationError::DiskRateLimiterWithNvme);
+            }
+            validate_rate_limiter(rate_limiter_config)?;
+        }
+
+        if self.busy_poll {
+            if self.vhost_user || self.vhost_socket.is_some() {
+                return Err(ValidationError::DiskBusyPollWithVhostUser);
+            }
+            if self.nvme {
+                return Err(ValidationError::DiskBusyPollWithNvme);
+            }
+            if self.busy_poll_us == 0 {
+                return Err(ValidationError::DiskInvalidBusyPoll);
+            }
+        }
+
+        if self.coalesce_max_requests > 0 || self.coalesce_max_usecs > 0 {
+            if self.vhost_user || self.vhost_socket.is_some() {
+                return Err(ValidationError::DiskCoalescingWithVhostUser);
+            }
+            if self.nvme {
+                return Err(ValidationError::DiskCoalescingWithNvme);
+            }
+            if self.coalesce_max_usecs == 0 {
+                return Err(ValidationError::DiskCoalescingWithoutDelay);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How long the queues are busy polled for, if they are.
+    pub fn busy_poll_duration(&self) -> Option<Duration> {
+        if self.busy_poll {
+            Some(Duration::from_micros(self.busy_poll_us))
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -608,8 +1972,28 @@ pub struct NetConfig {
     #[serde(default)]
     pub vhost_user: bool,
     pub vhost_socket: Option<String>,
+    #[serde(default = "default_netconfig_reconnect_retries")]
+    pub reconnect_retries: u32,
+    #[serde(default = "default_netconfig_reconnect_backoff_ms")]
+    pub reconnect_backoff_ms: u64,
+    #[serde(default)]
+    pub coalesce_max_packets: u32,
+    #[serde(default)]
+    pub coalesce_max_usecs: u64,
+    #[serde(default)]
+    pub rx_starvation: RxStarvationMode,
+    #[serde(default = "default_netconfig_rx_starvation_timeout_ms")]
+    pub rx_starvation_timeout_ms: u64,
+    #[serde(default)]
+    pub failover: Option<String>,
     #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub rx_timestamps: bool,
 }
 
 fn default_netconfig_tap() -> Option<String> {
@@ -636,6 +2020,18 @@ fn default_netconfig_queue_size() -> u16 {
     DEFAULT_QUEUE_SIZE_VUNET
 }
 
+fn default_netconfig_reconnect_retries() -> u32 {
+    DEFAULT_RECONNECT_RETRIES_VUNET
+}
+
+fn default_netconfig_reconnect_backoff_ms() -> u64 {
+    DEFAULT_RECONNECT_BACKOFF_MS_VUNET
+}
+
+fn default_netconfig_rx_starvation_timeout_ms() -> u64 {
+    DEFAULT_RX_STARVATION_TIMEOUT_MS
+}
+
 impl Default for NetConfig {
     fn default() -> Self {
         Self {
@@ -649,7 +2045,53 @@ impl Default for NetConfig {
             queue_size: default_netconfig_queue_size(),
             vhost_user: false,
             vhost_socket: None,
+            reconnect_retries: default_netconfig_reconnect_retries(),
+            reconnect_backoff_ms: default_netconfig_reconnect_backoff_ms(),
+            coalesce_max_packets: 0,
+            coalesce_max_usecs: 0,
+            rx_starvation: RxStarvationMode::default(),
+            rx_starvation_timeout_ms: default_netconfig_rx_starvation_timeout_ms(),
+            failover: None,
             id: None,
+            pci_segment: 0,
+            rate_limiter_config: None,
+            rx_timestamps: false,
+        }
+    }
+}
+
+/// What the virtio-net device does with the frames it receives while the
+/// guest doesn't provide any buffer for them.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum RxStarvationMode {
+    /// Stop receiving until the guest provides buffers.
+    Pause,
+    /// Drop the frames.
+    Drop,
+    /// Pause, and drop the frames once rx_starvation_timeout_ms elapsed.
+    Block,
+}
+
+impl Default for RxStarvationMode {
+    fn default() -> Self {
+        RxStarvationMode::Pause
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseRxStarvationModeError {
+    InvalidValue(String),
+}
+
+impl FromStr for RxStarvationMode {
+    type Err = ParseRxStarvationModeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pause" => Ok(RxStarvationMode::Pause),
+            "drop" => Ok(RxStarvationMode::Drop),
+            "block" => Ok(RxStarvationMode::Block),
+            _ => Err(ParseRxStarvationModeError::InvalidValue(s.to_owned())),
         }
     }
 }
@@ -658,7 +2100,15 @@ impl NetConfig {
     pub const SYNTAX: &'static str = "Network parameters \
     \"tap=<if_name>,ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,iommu=on|off,\
     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
-    vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,id=<device_id>\"";
+    vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,\
+    reconnect_retries=<vhost_user_reconnection_attempts>,\
+    reconnect_backoff_ms=<first_vhost_user_reconnection_delay>,\
+    coalesce_max_packets=<used_descriptors_per_interrupt>,\
+    coalesce_max_usecs=<max_interrupt_delay_us>,rx_starvation=pause|drop|block,\
+    rx_starvation_timeout_ms=<block_delay_before_dropping_frames>,\
+    failover=<primary_vf_device_id>,id=<device_id>,pci_segment=<segment_id>,\
+    bw_size=<bytes>,bw_refill_time=<ms>,ops_size=<frames>,ops_refill_time=<ms>,\
+    rx_timestamps=on|off\"";
 
     pub fn parse(net: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -674,7 +2124,20 @@ impl NetConfig {
             .add("num_queues")
             .add("vhost_user")
             .add("socket")
-            .add("id");
+            .add("reconnect_retries")
+            .add("reconnect_backoff_ms")
+            .add("coalesce_max_packets")
+            .add("coalesce_max_usecs")
+            .add("rx_starvation")
+            .add("rx_starvation_timeout_ms")
+            .add("failover")
+            .add("id")
+            .add("pci_segment")
+            .add("bw_size")
+            .add("bw_refill_time")
+            .add("ops_size")
+            .add("ops_refill_time")
+            .add("rx_timestamps");
         parser.parse(net).map_err(Error::ParseNetwork)?;
 
         let tap = parser.get("tap");
@@ -710,7 +2173,66 @@ impl NetConfig {
             .unwrap_or(Toggle(false))
             .0;
         let vhost_socket = parser.get("socket");
+        let reconnect_retries = parser
+            .convert("reconnect_retries")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(default_netconfig_reconnect_retries);
+        let reconnect_backoff_ms = parser
+            .convert("reconnect_backoff_ms")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(default_netconfig_reconnect_backoff_ms);
+        let coalesce_max_packets = parser
+            .convert("coalesce_max_packets")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(0);
+        let coalesce_max_usecs = parser
+            .convert("coalesce_max_usecs")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(0);
+        let rx_starvation = parser
+            .convert("rx_starvation")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_default();
+        let rx_starvation_timeout_ms = parser
+            .convert("rx_starvation_timeout_ms")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(default_netconfig_rx_starvation_timeout_ms);
+        let failover = parser.get("failover");
         let id = parser.get("id");
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_default();
+        let rate_limiter_config = parse_rate_limiter(&parser).map_err(Error::ParseNetwork)?;
+        let rx_timestamps = parser
+            .convert::<Toggle>("rx_timestamps")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        if (parser.is_set("reconnect_retries") || parser.is_set("reconnect_backoff_ms"))
+            && !vhost_user
+        {
+            warn!("reconnect parameters currently only have effect when used vhost_user=true");
+        }
+
+        if (parser.is_set("coalesce_max_packets") || parser.is_set("coalesce_max_usecs"))
+            && vhost_user
+        {
+            warn!("coalescing parameters have no effect when used with vhost_user=true");
+        }
+
+        if parser.is_set("rx_starvation") && vhost_user {
+            warn!("rx_starvation has no effect when used with vhost_user=true");
+        }
+
+        if parser.is_set("rx_starvation_timeout_ms") && rx_starvation != RxStarvationMode::Block {
+            warn!("rx_starvation_timeout_ms only has effect when used with rx_starvation=block");
+        }
+
+        if rx_timestamps && vhost_user {
+            warn!("rx_timestamps has no effect when used with vhost_user=true");
+        }
 
         Ok(NetConfig {
             tap,
@@ -723,7 +2245,17 @@ impl NetConfig {
             queue_size,
             vhost_user,
             vhost_socket,
+            reconnect_retries,
+            reconnect_backoff_ms,
+            coalesce_max_packets,
+            coalesce_max_usecs,
+            rx_starvation,
+            rx_starvation_timeout_ms,
+            failover,
             id,
+            pci_segment,
+            rate_limiter_config,
+            rx_timestamps,
         })
     }
 }
@@ -733,12 +2265,27 @@ pub struct RngConfig {
     pub src: PathBuf,
     #[serde(default)]
     pub iommu: bool,
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default = "default_rngconfig_period_ms")]
+    pub period_ms: u64,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
+fn default_rngconfig_period_ms() -> u64 {
+    DEFAULT_RNG_PERIOD_MS
 }
 
 impl RngConfig {
     pub fn parse(rng: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("src").add("iommu");
+        parser
+            .add("src")
+            .add("iommu")
+            .add("max_bytes")
+            .add("period_ms")
+            .add("pci_segment");
         parser.parse(rng).map_err(Error::ParseRNG)?;
 
         let src = PathBuf::from(
@@ -751,8 +2298,26 @@ impl RngConfig {
             .map_err(Error::ParseRNG)?
             .unwrap_or(Toggle(false))
             .0;
+        let max_bytes = parser
+            .convert::<ByteSized>("max_bytes")
+            .map_err(Error::ParseRNG)?
+            .map(|v| v.0);
+        let period_ms = parser
+            .convert("period_ms")
+            .map_err(Error::ParseRNG)?
+            .unwrap_or(DEFAULT_RNG_PERIOD_MS);
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseRNG)?
+            .unwrap_or_default();
 
-        Ok(RngConfig { src, iommu })
+        Ok(RngConfig {
+            src,
+            iommu,
+            max_bytes,
+            period_ms,
+            pci_segment,
+        })
     }
 }
 
@@ -761,6 +2326,9 @@ impl Default for RngConfig {
         RngConfig {
             src: PathBuf::from(DEFAULT_RNG_SOURCE),
             iommu: false,
+            max_bytes: None,
+            period_ms: DEFAULT_RNG_PERIOD_MS,
+            pci_segment: 0,
         }
     }
 }
@@ -779,6 +2347,8 @@ pub struct FsConfig {
     pub cache_size: u64,
     #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
 }
 
 fn default_fsconfig_num_queues() -> usize {
@@ -807,6 +2377,7 @@ impl Default for FsConfig {
             dax: default_fsconfig_dax(),
             cache_size: default_fsconfig_cache_size(),
             id: None,
+            pci_segment: 0,
         }
     }
 }
@@ -815,7 +2386,7 @@ impl FsConfig {
     pub const SYNTAX: &'static str = "virtio-fs parameters \
     \"tag=<tag_name>,socket=<socket_path>,num_queues=<number_of_queues>,\
     queue_size=<size_of_each_queue>,dax=on|off,cache_size=<DAX cache size: \
-    default 8Gib>,id=<device_id>\"";
+    default 8Gib>,id=<device_id>,pci_segment=<segment_id>\"";
 
     pub fn parse(fs: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -826,7 +2397,8 @@ impl FsConfig {
             .add("queue_size")
             .add("num_queues")
             .add("socket")
-            .add("id");
+            .add("id")
+            .add("pci_segment");
         parser.parse(fs).map_err(Error::ParseFileSystem)?;
 
         let tag = parser.get("tag").ok_or(Error::ParseFsTagMissing)?;
@@ -858,6 +2430,10 @@ impl FsConfig {
             .0;
 
         let id = parser.get("id");
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseFileSystem)?
+            .unwrap_or_default();
 
         Ok(FsConfig {
             tag,
@@ -867,6 +2443,7 @@ impl FsConfig {
             dax,
             cache_size,
             id,
+            pci_segment,
         })
     }
 }
@@ -883,13 +2460,18 @@ pub struct PmemConfig {
     #[serde(default)]
     pub discard_writes: bool,
     #[serde(default)]
+    pub readonly: bool,
+    #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
 }
 
 impl PmemConfig {
     pub const SYNTAX: &'static str = "Persistent memory parameters \
     \"file=<backing_file_path>,size=<persistent_memory_size>,iommu=on|off,\
-    mergeable=on|off,discard_writes=on|off,id=<device_id>\"";
+    mergeable=on|off,discard_writes=on|off,readonly=on|off,id=<device_id>,\
+    pci_segment=<segment_id>\"";
     pub fn parse(pmem: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
         parser
@@ -898,7 +2480,9 @@ impl PmemConfig {
             .add("mergeable")
             .add("iommu")
             .add("discard_writes")
-            .add("id");
+            .add("readonly")
+            .add("id")
+            .add("pci_segment");
         parser.parse(pmem).map_err(Error::ParsePersistentMemory)?;
 
         let file = PathBuf::from(parser.get("file").ok_or(Error::ParsePmemFileMissing)?);
@@ -921,7 +2505,16 @@ impl PmemConfig {
             .map_err(Error::ParsePersistentMemory)?
             .unwrap_or(Toggle(false))
             .0;
+        let readonly = parser
+            .convert::<Toggle>("readonly")
+            .map_err(Error::ParsePersistentMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
         let id = parser.get("id");
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParsePersistentMemory)?
+            .unwrap_or_default();
 
         Ok(PmemConfig {
             file,
@@ -929,17 +2522,32 @@ impl PmemConfig {
             iommu,
             mergeable,
             discard_writes,
+            readonly,
             id,
+            pci_segment,
         })
     }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        // Discarding writes means the guest is allowed to write, which
+        // contradicts a readonly device.
+        if self.readonly && self.discard_writes {
+            return Err(ValidationError::PmemReadonlyDiscardWrites);
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum ConsoleOutputMode {
     Off,
+    Pty,
     Tty,
     File,
     Null,
+    Socket,
+    Tcp,
 }
 
 impl ConsoleOutputMode {
@@ -958,6 +2566,10 @@ pub struct ConsoleConfig {
     pub mode: ConsoleOutputMode,
     #[serde(default)]
     pub iommu: bool,
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+    #[serde(default)]
+    pub tcp: Option<String>,
 }
 
 fn default_consoleconfig_file() -> Option<PathBuf> {
@@ -969,16 +2581,23 @@ impl ConsoleConfig {
         let mut parser = OptionParser::new();
         parser
             .add_valueless("off")
+            .add_valueless("pty")
             .add_valueless("tty")
             .add_valueless("null")
             .add("file")
+            .add("socket")
+            .add("tcp")
             .add("iommu");
         parser.parse(console).map_err(Error::ParseConsole)?;
 
         let mut file: Option<PathBuf> = default_consoleconfig_file();
+        let mut socket: Option<PathBuf> = None;
+        let mut tcp: Option<String> = None;
         let mut mode: ConsoleOutputMode = ConsoleOutputMode::Off;
 
         if parser.is_set("off") {
+        } else if parser.is_set("pty") {
+            mode = ConsoleOutputMode::Pty
         } else if parser.is_set("tty") {
             mode = ConsoleOutputMode::Tty
         } else if parser.is_set("null") {
@@ -989,6 +2608,19 @@ impl ConsoleConfig {
                 Some(PathBuf::from(parser.get("file").ok_or(
                     Error::Validation(ValidationError::ConsoleFileMissing),
                 )?));
+        } else if parser.is_set("socket") {
+            mode = ConsoleOutputMode::Socket;
+            socket =
+                Some(PathBuf::from(parser.get("socket").ok_or(
+                    Error::Validation(ValidationError::ConsoleSocketMissing),
+                )?));
+        } else if parser.is_set("tcp") {
+            mode = ConsoleOutputMode::Tcp;
+            tcp = Some(
+                parser
+                    .get("tcp")
+                    .ok_or(Error::Validation(ValidationError::ConsoleTcpMissing))?,
+            );
         } else {
             return Err(Error::ParseConsoleInvalidModeGiven);
         }
@@ -998,7 +2630,26 @@ impl ConsoleConfig {
             .unwrap_or(Toggle(false))
             .0;
 
-        Ok(Self { mode, file, iommu })
+        Ok(Self {
+            mode,
+            file,
+            iommu,
+            socket,
+            tcp,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        match self.mode {
+            ConsoleOutputMode::File if self.file.is_none() => {
+                Err(ValidationError::ConsoleFileMissing)
+            }
+            ConsoleOutputMode::Socket if self.socket.is_none() => {
+                Err(ValidationError::ConsoleSocketMissing)
+            }
+            ConsoleOutputMode::Tcp if self.tcp.is_none() => Err(ValidationError::ConsoleTcpMissing),
+            _ => Ok(()),
+        }
     }
 
     pub fn default_serial() -> Self {
@@ -1006,6 +2657,8 @@ impl ConsoleConfig {
             file: None,
             mode: ConsoleOutputMode::Null,
             iommu: false,
+            socket: None,
+            tcp: None,
         }
     }
 
@@ -1014,7 +2667,81 @@ impl ConsoleConfig {
             file: None,
             mode: ConsoleOutputMode::Tty,
             iommu: false,
+            socket: None,
+            tcp: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ConsolePortMode {
+    Pty,
+    Socket,
+    File,
+}
+
+impl Default for ConsolePortMode {
+    fn default() -> Self {
+        ConsolePortMode::Pty
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct ConsolePortConfig {
+    pub name: String,
+    pub mode: ConsolePortMode,
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+impl ConsolePortConfig {
+    pub const SYNTAX: &'static str = "Additional console port parameters \
+        \"name=<port_name>,pty|socket=<socket_path>|file=<file_path>\"";
+
+    /// Whether a `--console` value describes an additional port rather
+    /// than the console itself.
+    pub fn is_port(console: &str) -> bool {
+        console.split(',').any(|option| option.starts_with("name="))
+    }
+
+    pub fn parse(console_port: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("name")
+            .add_valueless("pty")
+            .add("socket")
+            .add("file");
+        parser
+            .parse(console_port)
+            .map_err(Error::ParseConsolePort)?;
+
+        let name = parser
+            .get("name")
+            .ok_or(Error::ParseConsolePortNameMissing)?;
+        let (mode, path) = if parser.is_set("pty") {
+            (ConsolePortMode::Pty, None)
+        } else if let Some(socket) = parser.get("socket") {
+            (ConsolePortMode::Socket, Some(PathBuf::from(socket)))
+        } else if let Some(file) = parser.get("file") {
+            (ConsolePortMode::File, Some(PathBuf::from(file)))
+        } else {
+            return Err(Error::ParseConsolePortInvalidModeGiven);
+        };
+
+        Ok(ConsolePortConfig { name, mode, path })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        // The guest exposes the port as /dev/virtio-ports/<name>.
+        if self.name.is_empty() || self.name.contains('/') {
+            return Err(ValidationError::ConsolePortInvalidName(self.name.clone()));
+        }
+
+        if self.mode != ConsolePortMode::Pty && self.path.is_none() {
+            return Err(ValidationError::ConsolePortPathMissing(self.name.clone()));
         }
+
+        Ok(())
     }
 }
 
@@ -1025,14 +2752,27 @@ pub struct DeviceConfig {
     pub iommu: bool,
     #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub mac: Option<MacAddr>,
+    #[serde(default)]
+    pub vlan: Option<u16>,
 }
 
 impl DeviceConfig {
-    pub const SYNTAX: &'static str =
-        "Direct device assignment parameters \"path=<device_path>,iommu=on|off,id=<device_id>\"";
+    pub const SYNTAX: &'static str = "Direct device assignment parameters \
+        \"path=<device_path>,iommu=on|off,id=<device_id>,pci_segment=<segment_id>,\
+        mac=<sriov_vf_mac>,vlan=<sriov_vf_vlan>\"";
     pub fn parse(device: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("path").add("id").add("iommu");
+        parser
+            .add("path")
+            .add("id")
+            .add("iommu")
+            .add("pci_segment")
+            .add("mac")
+            .add("vlan");
         parser.parse(device).map_err(Error::ParseDevice)?;
 
         let path = parser
@@ -1045,11 +2785,40 @@ impl DeviceConfig {
             .unwrap_or(Toggle(false))
             .0;
         let id = parser.get("id");
-        Ok(DeviceConfig { path, iommu, id })
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseDevice)?
+            .unwrap_or_default();
+        let mac = parser.convert("mac").map_err(Error::ParseDevice)?;
+        let vlan = parser.convert("vlan").map_err(Error::ParseDevice)?;
+        Ok(DeviceConfig {
+            path,
+            iommu,
+            id,
+            pci_segment,
+            mac,
+            vlan,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if let Some(vlan) = self.vlan {
+            if vlan >= 4095 {
+                return Err(ValidationError::DeviceInvalidVlan(vlan));
+            }
+        }
+
+        // The MAC address and VLAN can only be programmed on an SR-IOV VF,
+        // through its physical function.
+        if (self.mac.is_some() || self.vlan.is_some()) && !self.path.join("physfn").exists() {
+            return Err(ValidationError::DeviceNotVirtualFunction(self.path.clone()));
+        }
+
+        Ok(())
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct VsockConfig {
     pub cid: u64,
     pub socket: PathBuf,
@@ -1057,14 +2826,64 @@ pub struct VsockConfig {
     pub iommu: bool,
     #[serde(default)]
     pub id: Option<String>,
+    #[serde(default = "default_vsockconfig_max_connections")]
+    pub max_connections: usize,
+    #[serde(default)]
+    pub idle_timeout: u64,
+    #[serde(default)]
+    pub listen_ports: Vec<u32>,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
+fn default_vsockconfig_max_connections() -> usize {
+    DEFAULT_VSOCK_MAX_CONNECTIONS
+}
+
+impl Default for VsockConfig {
+    fn default() -> Self {
+        Self {
+            cid: 0,
+            socket: PathBuf::new(),
+            iommu: false,
+            id: None,
+            max_connections: default_vsockconfig_max_connections(),
+            idle_timeout: 0,
+            listen_ports: Vec::new(),
+            pci_segment: 0,
+        }
+    }
+}
+
+struct PortList(Vec<u32>);
+
+impl FromStr for PortList {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.split(':')
+            .map(|port| port.parse())
+            .collect::<std::result::Result<Vec<u32>, _>>()
+            .map(PortList)
+    }
 }
 
 impl VsockConfig {
     pub const SYNTAX: &'static str = "Virtio VSOCK parameters \
-        \"cid=<context_id>,socket=<socket_path>,iommu=on|off,id=<device_id>\"";
+        \"cid=<context_id>,socket=<socket_path>,iommu=on|off,id=<device_id>,\
+        max_connections=<max_connections>,idle_timeout=<idle_timeout_in_seconds>,\
+        listen_ports=<port>:<port>:...,pci_segment=<segment_id>\"";
     pub fn parse(vsock: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("socket").add("cid").add("iommu").add("id");
+        parser
+            .add("socket")
+            .add("cid")
+            .add("iommu")
+            .add("id")
+            .add("max_connections")
+            .add("idle_timeout")
+            .add("listen_ports")
+            .add("pci_segment");
         parser.parse(vsock).map_err(Error::ParseVsock)?;
 
         let socket = parser
@@ -1081,158 +2900,1014 @@ impl VsockConfig {
             .map_err(Error::ParseVsock)?
             .ok_or(Error::ParseVsockCidMissing)?;
         let id = parser.get("id");
+        let max_connections = parser
+            .convert("max_connections")
+            .map_err(Error::ParseVsock)?
+            .unwrap_or_else(default_vsockconfig_max_connections);
+        let idle_timeout = parser
+            .convert("idle_timeout")
+            .map_err(Error::ParseVsock)?
+            .unwrap_or(0);
+        let listen_ports = parser
+            .convert::<PortList>("listen_ports")
+            .map_err(Error::ParseVsock)?
+            .map(|ports| ports.0)
+            .unwrap_or_default();
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseVsock)?
+            .unwrap_or_default();
 
         Ok(VsockConfig {
             cid,
             socket,
             iommu,
             id,
+            max_connections,
+            idle_timeout,
+            listen_ports,
+            pci_segment,
         })
     }
-}
 
-#[cfg(target_arch = "x86_64")]
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
-pub struct SgxEpcConfig {
-    #[serde(default)]
-    pub size: u64,
-    #[serde(default)]
-    pub prefault: bool,
-}
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.max_connections == 0 {
+            return Err(ValidationError::VsockMaxConnectionsZero);
+        }
 
-#[cfg(target_arch = "x86_64")]
-impl SgxEpcConfig {
-    pub const SYNTAX: &'static str = "SGX EPC parameters \
-        \"size=<epc_section_size>,prefault=on|off\"";
-    pub fn parse(sgx_epc: &str) -> Result<Self> {
-        let mut parser = OptionParser::new();
-        parser.add("size").add("prefault");
-        parser.parse(sgx_epc).map_err(Error::ParseSgxEpc)?;
+        for (index, port) in self.listen_ports.iter().enumerate() {
+            if self.listen_ports[..index].contains(port) {
+                return Err(ValidationError::VsockDuplicateListenPort(*port));
+            }
+        }
 
-        let size = parser
-            .convert::<ByteSized>("size")
-            .map_err(Error::ParseSgxEpc)?
-            .unwrap_or(ByteSized(0))
-            .0;
-        let prefault = parser
-            .convert::<Toggle>("prefault")
-            .map_err(Error::ParseSgxEpc)?
-            .unwrap_or(Toggle(false))
-            .0;
+        // CIDs 0 to 2 are reserved (hypervisor, local and host), and
+        // 0xffff_ffff is VMADDR_CID_ANY.
+        if self.cid < 3 || self.cid >= u64::from(std::u32::MAX) {
+            return Err(ValidationError::VsockInvalidCid(self.cid));
+        }
 
-        Ok(SgxEpcConfig { size, prefault })
+        if vsock_cid_in_use(self.cid) {
+            return Err(ValidationError::VsockCidInUse(self.cid));
+        }
+
+        Ok(())
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
-pub struct RestoreConfig {
-    pub source_url: PathBuf,
-    #[serde(default)]
-    pub prefault: bool,
+// _IOW(VHOST_VIRTIO, 0x60, __u64)
+const VHOST_VSOCK_SET_GUEST_CID: u64 = 0x4008_af60;
+
+// The unix backend doesn't register the CID anywhere on the host, which
+// means nothing prevents two VMs from being given the same one. The best
+// we can do is to check against the CIDs claimed through vhost-vsock, by
+// trying to claim the CID ourselves. It gets released as soon as the file
+// is closed. If vhost-vsock is not available, there is nothing to check
+// against.
+fn vsock_cid_in_use(cid: u64) -> bool {
+    let file = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/vhost-vsock")
+    {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+
+    // SAFETY: the ioctl only reads the CID from the pointer we pass, and
+    // the file descriptor is valid for the duration of the call.
+    let ret = unsafe {
+        libc::ioctl(
+            std::os::unix::io::AsRawFd::as_raw_fd(&file),
+            VHOST_VSOCK_SET_GUEST_CID as _,
+            &cid,
+        )
+    };
+
+    ret < 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::EADDRINUSE)
 }
 
-impl RestoreConfig {
-    pub const SYNTAX: &'static str = "Restore from a VM snapshot. \
-        \nRestore parameters \"source_url=<source_url>,prefault=on|off\" \
-        \n`source_url` should be a valid URL (e.g file:///foo/bar or tcp://192.168.1.10/foo) \
-        \n`prefault` brings memory pages in when enabled (disabled by default)";
-    pub fn parse(restore: &str) -> Result<Self> {
-        let mut parser = OptionParser::new();
-        parser.add("source_url").add("prefault");
-        parser.parse(restore).map_err(Error::ParseRestore)?;
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum WatchdogAction {
+    #[serde(alias = "Log")]
+    None,
+    Pause,
+    Reset,
+    Shutdown,
+}
 
-        let source_url = parser
-            .get("source_url")
-            .map(PathBuf::from)
-            .ok_or(Error::ParseRestoreSourceUrlMissing)?;
-        let prefault = parser
-            .convert::<Toggle>("prefault")
-            .map_err(Error::ParseRestore)?
-            .unwrap_or(Toggle(false))
-            .0;
+impl Default for WatchdogAction {
+    fn default() -> Self {
+        WatchdogAction::Reset
+    }
+}
 
-        Ok(RestoreConfig {
-            source_url,
-            prefault,
-        })
+#[derive(Debug)]
+pub enum ParseWatchdogActionError {
+    InvalidValue(String),
+}
+
+impl FromStr for WatchdogAction {
+    type Err = ParseWatchdogActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            // "log" is kept for compatibility, the expiry being always logged.
+            "none" | "log" => Ok(WatchdogAction::None),
+            "pause" => Ok(WatchdogAction::Pause),
+            "reset" => Ok(WatchdogAction::Reset),
+            "shutdown" => Ok(WatchdogAction::Shutdown),
+            _ => Err(ParseWatchdogActionError::InvalidValue(s.to_owned())),
+        }
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct VmConfig {
-    #[serde(default)]
-    pub cpus: CpusConfig,
-    #[serde(default)]
-    pub memory: MemoryConfig,
-    pub kernel: Option<KernelConfig>,
-    #[serde(default)]
-    pub initramfs: Option<InitramfsConfig>,
+pub struct WatchdogConfig {
+    #[serde(default = "default_watchdogconfig_timeout")]
+    pub timeout: u64,
     #[serde(default)]
-    pub cmdline: CmdlineConfig,
-    pub disks: Option<Vec<DiskConfig>>,
-    pub net: Option<Vec<NetConfig>>,
-    #[serde(default)]
-    pub rng: RngConfig,
-    pub fs: Option<Vec<FsConfig>>,
-    pub pmem: Option<Vec<PmemConfig>>,
-    #[serde(default = "ConsoleConfig::default_serial")]
-    pub serial: ConsoleConfig,
-    #[serde(default = "ConsoleConfig::default_console")]
-    pub console: ConsoleConfig,
-    pub devices: Option<Vec<DeviceConfig>>,
-    pub vsock: Option<VsockConfig>,
+    pub action: WatchdogAction,
     #[serde(default)]
     pub iommu: bool,
-    #[cfg(target_arch = "x86_64")]
-    pub sgx_epc: Option<Vec<SgxEpcConfig>>,
+    #[serde(default)]
+    pub pci_segment: u16,
 }
 
-impl VmConfig {
-    pub fn validate(&self) -> ValidationResult<()> {
-        self.kernel.as_ref().ok_or(ValidationError::KernelMissing)?;
+fn default_watchdogconfig_timeout() -> u64 {
+    DEFAULT_WATCHDOG_TIMEOUT
+}
 
-        if self.console.mode == ConsoleOutputMode::Tty && self.serial.mode == ConsoleOutputMode::Tty
-        {
-            return Err(ValidationError::DoubleTtyMode);
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            timeout: default_watchdogconfig_timeout(),
+            action: WatchdogAction::default(),
+            iommu: false,
+            pci_segment: 0,
         }
+    }
+}
 
-        if self.console.mode == ConsoleOutputMode::File && self.console.file.is_none() {
-            return Err(ValidationError::ConsoleFileMissing);
-        }
+impl WatchdogConfig {
+    pub const SYNTAX: &'static str = "Virtio watchdog parameters \
+        \"timeout=<timeout_in_seconds>,action=reset|shutdown|pause|none,iommu=on|off,\
+        pci_segment=<segment_id>\"";
+    pub fn parse(watchdog: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("timeout")
+            .add("action")
+            .add("iommu")
+            .add("pci_segment");
+        parser.parse(watchdog).map_err(Error::ParseWatchdog)?;
+
+        let timeout = parser
+            .convert("timeout")
+            .map_err(Error::ParseWatchdog)?
+            .unwrap_or_else(default_watchdogconfig_timeout);
+        let action = parser
+            .convert("action")
+            .map_err(Error::ParseWatchdog)?
+            .unwrap_or_default();
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseWatchdog)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseWatchdog)?
+            .unwrap_or_default();
 
-        if self.serial.mode == ConsoleOutputMode::File && self.serial.file.is_none() {
-            return Err(ValidationError::ConsoleFileMissing);
-        }
+        Ok(WatchdogConfig {
+            timeout,
+            action,
+            iommu,
+            pci_segment,
+        })
+    }
 
-        if self.cpus.max_vcpus < self.cpus.boot_vcpus {
-            return Err(ValidationError::CpusMaxLowerThanBoot);
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.timeout == 0 {
+            return Err(ValidationError::WatchdogTimeoutZero);
         }
 
-        if self.memory.file.is_some() {
-            error!("Use of backing file ('--memory file=') is deprecated. Use the 'shared' and 'hugepages' controls.");
-        }
+        Ok(())
+    }
+}
 
-        if let Some(disks) = &self.disks {
-            for disk in disks {
-                if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some() {
-                    return Err(ValidationError::DiskSocketAndPath);
-                }
-                if disk.vhost_user && !self.memory.shared {
-                    return Err(ValidationError::VhostUserRequiresSharedMemory);
-                }
-            }
-        }
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum SoundBackend {
+    Null,
+    Alsa,
+    File,
+}
 
-        if let Some(nets) = &self.net {
-            for net in nets {
-                if net.vhost_user && !self.memory.shared {
-                    return Err(ValidationError::VhostUserRequiresSharedMemory);
+impl Default for SoundBackend {
+    fn default() -> Self {
+        SoundBackend::Null
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseSoundBackendError {
+    InvalidValue(String),
+}
+
+impl FromStr for SoundBackend {
+    type Err = ParseSoundBackendError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "null" => Ok(SoundBackend::Null),
+            "alsa" => Ok(SoundBackend::Alsa),
+            "file" => Ok(SoundBackend::File),
+            _ => Err(ParseSoundBackendError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SoundConfig {
+    #[serde(default)]
+    pub backend: SoundBackend,
+    #[serde(default = "default_soundconfig_device")]
+    pub device: String,
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
+fn default_soundconfig_device() -> String {
+    String::from("default")
+}
+
+impl Default for SoundConfig {
+    fn default() -> Self {
+        Self {
+            backend: SoundBackend::default(),
+            device: default_soundconfig_device(),
+            path: None,
+            iommu: false,
+            pci_segment: 0,
+        }
+    }
+}
+
+impl SoundConfig {
+    pub const SYNTAX: &'static str = "Virtio sound parameters \
+        \"backend=null|alsa|file,device=<alsa_pcm_device>,path=<raw_samples_file>,iommu=on|off,\
+        pci_segment=<segment_id>\"";
+    pub fn parse(sound: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("backend")
+            .add("device")
+            .add("path")
+            .add("iommu")
+            .add("pci_segment");
+        parser.parse(sound).map_err(Error::ParseSound)?;
+
+        let backend = parser
+            .convert("backend")
+            .map_err(Error::ParseSound)?
+            .unwrap_or_default();
+        let device = parser
+            .get("device")
+            .unwrap_or_else(default_soundconfig_device);
+        let path = parser.get("path").map(PathBuf::from);
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseSound)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseSound)?
+            .unwrap_or_default();
+
+        Ok(SoundConfig {
+            backend,
+            device,
+            path,
+            iommu,
+            pci_segment,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.backend == SoundBackend::File && self.path.is_none() {
+            return Err(ValidationError::SoundFileMissing);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct GpuConfig {
+    #[serde(default = "default_gpuconfig_width")]
+    pub width: u32,
+    #[serde(default = "default_gpuconfig_height")]
+    pub height: u32,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub shm: Option<PathBuf>,
+}
+
+fn default_gpuconfig_width() -> u32 {
+    1280
+}
+
+fn default_gpuconfig_height() -> u32 {
+    800
+}
+
+impl Default for GpuConfig {
+    fn default() -> Self {
+        Self {
+            width: default_gpuconfig_width(),
+            height: default_gpuconfig_height(),
+            iommu: false,
+            pci_segment: 0,
+            shm: None,
+        }
+    }
+}
+
+impl GpuConfig {
+    /// Largest width and height of the scanout.
+    pub const MAX_SIZE: u32 = 8192;
+
+    pub const SYNTAX: &'static str = "Virtio GPU parameters \
+        \"width=<scanout_width>,height=<scanout_height>,iommu=on|off,pci_segment=<segment_id>,\
+        shm=<framebuffer_mirror_path>\"";
+    pub fn parse(gpu: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("width")
+            .add("height")
+            .add("iommu")
+            .add("pci_segment")
+            .add("shm");
+        parser.parse(gpu).map_err(Error::ParseGpu)?;
+
+        let width = parser
+            .convert("width")
+            .map_err(Error::ParseGpu)?
+            .unwrap_or_else(default_gpuconfig_width);
+        let height = parser
+            .convert("height")
+            .map_err(Error::ParseGpu)?
+            .unwrap_or_else(default_gpuconfig_height);
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseGpu)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseGpu)?
+            .unwrap_or_default();
+        let shm = parser.get("shm").map(PathBuf::from);
+
+        Ok(GpuConfig {
+            width,
+            height,
+            iommu,
+            pci_segment,
+            shm,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.width == 0
+            || self.height == 0
+            || self.width > Self::MAX_SIZE
+            || self.height > Self::MAX_SIZE
+        {
+            return Err(ValidationError::GpuInvalidSize(self.width, self.height));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct VdpaConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_vdpaconfig_num_queues")]
+    pub num_queues: usize,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
+fn default_vdpaconfig_num_queues() -> usize {
+    1
+}
+
+impl VdpaConfig {
+    pub const SYNTAX: &'static str = "vDPA device parameters \
+    \"path=<device_path>,num_queues=<number_of_queues>,id=<device_id>,\
+    pci_segment=<segment_id>\"";
+
+    pub fn parse(vdpa: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("path")
+            .add("num_queues")
+            .add("id")
+            .add("pci_segment");
+        parser.parse(vdpa).map_err(Error::ParseVdpa)?;
+
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseVdpaPathMissing)?;
+        let num_queues = parser
+            .convert("num_queues")
+            .map_err(Error::ParseVdpa)?
+            .unwrap_or_else(default_vdpaconfig_num_queues);
+        let id = parser.get("id");
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseVdpa)?
+            .unwrap_or_default();
+
+        Ok(VdpaConfig {
+            path,
+            num_queues,
+            id,
+            pci_segment,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.num_queues == 0 {
+            return Err(ValidationError::VdpaNumQueuesZero);
+        }
+
+        Ok(())
+    }
+}
+
+pub enum NumaParseError {
+    InvalidValue(String),
+}
+
+// List of vCPUs, separated by ':', each of them being either a vCPU index
+// or a range of vCPUs, as in "0-3:8".
+struct VcpuList(Vec<u8>);
+
+impl FromStr for VcpuList {
+    type Err = NumaParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || NumaParseError::InvalidValue(s.to_owned());
+        let mut list = Vec::new();
+
+        for vcpus in s.split(':') {
+            let mut range = vcpus.splitn(2, '-');
+            let first: u8 = range
+                .next()
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(invalid)?;
+            let last: u8 = match range.next() {
+                Some(v) => v.parse().map_err(|_| invalid())?,
+                None => first,
+            };
+            if last < first {
+                return Err(invalid());
+            }
+
+            list.extend(first..=last);
+        }
+
+        Ok(VcpuList(list))
+    }
+}
+
+// List of distances to other NUMA nodes, separated by ':', each of them
+// being the node identifier followed by '@' and the distance, as in
+// "1@20:2@30".
+struct NumaDistanceList(Vec<NumaDistance>);
+
+impl FromStr for NumaDistanceList {
+    type Err = NumaParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || NumaParseError::InvalidValue(s.to_owned());
+        let mut list = Vec::new();
+
+        for distance in s.split(':') {
+            let mut parts = distance.splitn(2, '@');
+            let destination = parts
+                .next()
+                .and_then(|d| d.parse().ok())
+                .ok_or_else(invalid)?;
+            let distance = parts
+                .next()
+                .and_then(|d| d.parse().ok())
+                .ok_or_else(invalid)?;
+
+            list.push(NumaDistance {
+                destination,
+                distance,
+            });
+        }
+
+        Ok(NumaDistanceList(list))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct NumaDistance {
+    pub destination: u32,
+    pub distance: u8,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct NumaConfig {
+    pub guest_numa_id: u32,
+    #[serde(default)]
+    pub cpus: Option<Vec<u8>>,
+    #[serde(default)]
+    pub distances: Option<Vec<NumaDistance>>,
+    #[serde(default)]
+    pub asymmetric: bool,
+}
+
+impl NumaConfig {
+    pub const SYNTAX: &'static str = "Guest NUMA node parameters \
+    \"guest_numa_id=<node_id>,cpus=<vcpu>:<first_vcpu>-<last_vcpu>:...,\
+    distances=<node_id>@<distance>:...,asymmetric=on|off\"";
+
+    pub fn parse(numa: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("guest_numa_id")
+            .add("cpus")
+            .add("distances")
+            .add("asymmetric");
+        parser.parse(numa).map_err(Error::ParseNuma)?;
+
+        let guest_numa_id = parser
+            .convert("guest_numa_id")
+            .map_err(Error::ParseNuma)?
+            .ok_or(Error::ParseNumaIdMissing)?;
+        let cpus = parser
+            .convert::<VcpuList>("cpus")
+            .map_err(Error::ParseNuma)?
+            .map(|l| l.0);
+        let distances = parser
+            .convert::<NumaDistanceList>("distances")
+            .map_err(Error::ParseNuma)?
+            .map(|l| l.0);
+        let asymmetric = parser
+            .convert::<Toggle>("asymmetric")
+            .map_err(Error::ParseNuma)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        Ok(NumaConfig {
+            guest_numa_id,
+            cpus,
+            distances,
+            asymmetric,
+        })
+    }
+
+    /// The distance from this node to `destination`, if given.
+    pub fn distance(&self, destination: u32) -> Option<u8> {
+        self.distances
+            .iter()
+            .flatten()
+            .find(|d| d.destination == destination)
+            .map(|d| d.distance)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct SgxEpcConfig {
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub prefault: bool,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl SgxEpcConfig {
+    pub const SYNTAX: &'static str = "SGX EPC parameters \
+        \"size=<epc_section_size>,prefault=on|off\"";
+    pub fn parse(sgx_epc: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("size").add("prefault");
+        parser.parse(sgx_epc).map_err(Error::ParseSgxEpc)?;
+
+        let size = parser
+            .convert::<ByteSized>("size")
+            .map_err(Error::ParseSgxEpc)?
+            .unwrap_or(ByteSized(0))
+            .0;
+        let prefault = parser
+            .convert::<Toggle>("prefault")
+            .map_err(Error::ParseSgxEpc)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        Ok(SgxEpcConfig { size, prefault })
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct GdbConfig {
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+    #[serde(default)]
+    pub tcp: Option<String>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl GdbConfig {
+    pub const SYNTAX: &'static str = "GDB stub parameters \
+        \"socket=<socket_path>|tcp=<host:port>\"";
+    pub fn parse(gdb: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("socket").add("tcp");
+        parser.parse(gdb).map_err(Error::ParseGdb)?;
+
+        let socket = parser.get("socket").map(PathBuf::from);
+        let tcp = parser.get("tcp");
+
+        Ok(GdbConfig { socket, tcp })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.socket.is_some() == self.tcp.is_some() {
+            return Err(ValidationError::GdbEndpoint);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum PvpanicAction {
+    None,
+    Pause,
+    Shutdown,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Default for PvpanicAction {
+    fn default() -> Self {
+        PvpanicAction::None
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug)]
+pub enum ParsePvpanicActionError {
+    InvalidValue(String),
+}
+
+#[cfg(target_arch = "x86_64")]
+impl FromStr for PvpanicAction {
+    type Err = ParsePvpanicActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(PvpanicAction::None),
+            "pause" => Ok(PvpanicAction::Pause),
+            "shutdown" => Ok(PvpanicAction::Shutdown),
+            _ => Err(ParsePvpanicActionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct PvpanicConfig {
+    #[serde(default)]
+    pub action: PvpanicAction,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl PvpanicConfig {
+    pub const SYNTAX: &'static str = "Guest panic notification device parameters \
+        \"action=pause|shutdown|none\"";
+    pub fn parse(pvpanic: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("action");
+        parser.parse(pvpanic).map_err(Error::ParsePvpanic)?;
+
+        let action = parser
+            .convert("action")
+            .map_err(Error::ParsePvpanic)?
+            .unwrap_or_default();
+
+        Ok(PvpanicConfig { action })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct RestoreConfig {
+    pub source_url: PathBuf,
+    #[serde(default)]
+    pub prefault: bool,
+}
+
+impl RestoreConfig {
+    pub const SYNTAX: &'static str = "Restore from a VM snapshot. \
+        \nRestore parameters \"source_url=<source_url>,prefault=on|off\" \
+        \n`source_url` should be a valid URL (e.g file:///foo/bar or tcp://192.168.1.10/foo) \
+        \n`prefault` brings memory pages in when enabled (disabled by default)";
+    pub fn parse(restore: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("source_url").add("prefault");
+        parser.parse(restore).map_err(Error::ParseRestore)?;
+
+        let source_url = parser
+            .get("source_url")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseRestoreSourceUrlMissing)?;
+        let prefault = parser
+            .convert::<Toggle>("prefault")
+            .map_err(Error::ParseRestore)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        Ok(RestoreConfig {
+            source_url,
+            prefault,
+        })
+    }
+}
+
+/// Largest number of PCI segments a VM can be given.
+pub const MAX_NUM_PCI_SEGMENTS: u16 = 16;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PlatformConfig {
+    #[serde(default = "default_platformconfig_num_pci_segments")]
+    pub num_pci_segments: u16,
+}
+
+fn default_platformconfig_num_pci_segments() -> u16 {
+    1
+}
+
+impl Default for PlatformConfig {
+    fn default() -> Self {
+        Self {
+            num_pci_segments: default_platformconfig_num_pci_segments(),
+        }
+    }
+}
+
+impl PlatformConfig {
+    pub const SYNTAX: &'static str = "Platform parameters \
+        \"num_pci_segments=<num_pci_segments>\"";
+    pub fn parse(platform: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("num_pci_segments");
+        parser.parse(platform).map_err(Error::ParsePlatform)?;
+
+        let num_pci_segments = parser
+            .convert("num_pci_segments")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or_else(default_platformconfig_num_pci_segments);
+
+        Ok(PlatformConfig { num_pci_segments })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        // Additional segments are only described to the guest through ACPI,
+        // the device tree describing a single one.
+        let max = if cfg!(all(
+            feature = "pci_support",
+            feature = "acpi",
+            target_arch = "x86_64"
+        )) {
+            MAX_NUM_PCI_SEGMENTS
+        } else {
+            1
+        };
+        if self.num_pci_segments == 0 || self.num_pci_segments > max {
+            return Err(ValidationError::InvalidNumPciSegments(
+                self.num_pci_segments,
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct VmConfig {
+    #[serde(default)]
+    pub cpus: CpusConfig,
+    #[serde(default)]
+    pub memory: MemoryConfig,
+    pub kernel: Option<KernelConfig>,
+    #[serde(default)]
+    pub initramfs: Option<InitramfsConfig>,
+    #[serde(default)]
+    pub cmdline: CmdlineConfig,
+    pub disks: Option<Vec<DiskConfig>>,
+    pub net: Option<Vec<NetConfig>>,
+    #[serde(default)]
+    pub rng: RngConfig,
+    pub fs: Option<Vec<FsConfig>>,
+    pub pmem: Option<Vec<PmemConfig>>,
+    #[serde(default = "ConsoleConfig::default_serial")]
+    pub serial: ConsoleConfig,
+    #[serde(default = "ConsoleConfig::default_console")]
+    pub console: ConsoleConfig,
+    #[serde(default)]
+    pub console_ports: Option<Vec<ConsolePortConfig>>,
+    pub devices: Option<Vec<DeviceConfig>>,
+    pub vsock: Option<VsockConfig>,
+    #[serde(default)]
+    pub watchdog: Option<WatchdogConfig>,
+    #[serde(default)]
+    pub sound: Option<SoundConfig>,
+    #[serde(default)]
+    pub gpu: Option<GpuConfig>,
+    pub vdpa: Option<Vec<VdpaConfig>>,
+    #[serde(default)]
+    pub numa: Option<Vec<NumaConfig>>,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub platform: Option<PlatformConfig>,
+    #[cfg(target_arch = "x86_64")]
+    pub sgx_epc: Option<Vec<SgxEpcConfig>>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub gdb: Option<GdbConfig>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub pvpanic: Option<PvpanicConfig>,
+}
+
+impl VmConfig {
+    pub fn num_pci_segments(&self) -> u16 {
+        self.platform
+            .as_ref()
+            .map_or(1, |platform| platform.num_pci_segments)
+    }
+
+    /// Validate a device placement against the PCI segments of the VM.
+    pub fn validate_pci_segment(&self, pci_segment: u16, iommu: bool) -> ValidationResult<()> {
+        if pci_segment >= self.num_pci_segments() {
+            return Err(ValidationError::InvalidPciSegment(pci_segment));
+        }
+
+        // The virtual IOMMU sits on the first segment, and only manages
+        // the endpoints from there.
+        if iommu && pci_segment != 0 {
+            return Err(ValidationError::IommuNotSupportedOnSegment(pci_segment));
+        }
+
+        Ok(())
+    }
+
+    /// Validate the additional console ports, either from the initial
+    /// configuration or once a port has been hot-added.
+    pub fn validate_console_ports(&self) -> ValidationResult<()> {
+        if let Some(ports) = &self.console_ports {
+            if self.console.mode == ConsoleOutputMode::Off {
+                return Err(ValidationError::ConsolePortsWithoutConsole);
+            }
+            // The first port of the device is the console itself.
+            if ports.len() >= virtio_devices::VIRTIO_CONSOLE_MAX_PORTS as usize {
+                return Err(ValidationError::ConsolePortsTooMany);
+            }
+            for (index, port) in ports.iter().enumerate() {
+                port.validate()?;
+                if ports[..index].iter().any(|p| p.name == port.name) {
+                    return Err(ValidationError::ConsolePortDuplicateName(port.name.clone()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        self.kernel.as_ref().ok_or(ValidationError::KernelMissing)?;
+
+        if self.console.mode == ConsoleOutputMode::Tty && self.serial.mode == ConsoleOutputMode::Tty
+        {
+            return Err(ValidationError::DoubleTtyMode);
+        }
+
+        self.console.validate()?;
+        self.serial.validate()?;
+
+        self.validate_console_ports()?;
+
+        if self.cpus.max_vcpus < self.cpus.boot_vcpus {
+            return Err(ValidationError::CpusMaxLowerThanBoot);
+        }
+
+        if self.memory.hugepages && self.memory.thp != ThpMode::Madvise {
+            return Err(ValidationError::ThpWithHugepages);
+        }
+
+        if self.memory.file.is_some() {
+            error!("Use of backing file ('--memory file=') is deprecated. Use the 'shared' and 'hugepages' controls.");
+        }
+
+        if let Some(zones) = &self.memory.zones {
+            for (index, zone) in zones.iter().enumerate() {
+                zone.validate()?;
+                if zones[..index].iter().any(|z| z.id == zone.id) {
+                    return Err(ValidationError::MemoryZoneDuplicateId);
+                }
+            }
+        }
+
+        // vhost-user backends need access to the whole guest memory,
+        // including the memory zones.
+        let shared_memory =
+            self.memory.shared && self.memory.zones.iter().flatten().all(|zone| zone.shared);
+
+        if let Some(disks) = &self.disks {
+            for disk in disks {
+                disk.validate()?;
+                if disk.vhost_user && !shared_memory {
+                    return Err(ValidationError::VhostUserRequiresSharedMemory);
+                }
+            }
+        }
+
+        if let Some(pmems) = &self.pmem {
+            for pmem in pmems {
+                pmem.validate()?;
+            }
+        }
+
+        if let Some(devices) = &self.devices {
+            for device in devices {
+                device.validate()?;
+            }
+        }
+
+        if let Some(nets) = &self.net {
+            for net in nets {
+                if net.vhost_user && !shared_memory {
+                    return Err(ValidationError::VhostUserRequiresSharedMemory);
+                }
+                if net.coalesce_max_packets > 0 && net.coalesce_max_usecs == 0 {
+                    return Err(ValidationError::NetCoalescingWithoutDelay);
+                }
+                if net.rx_starvation == RxStarvationMode::Block && net.rx_starvation_timeout_ms == 0
+                {
+                    return Err(ValidationError::NetRxStarvationWithoutTimeout);
+                }
+                if let Some(rate_limiter_config) = &net.rate_limiter_config {
+                    if net.vhost_user {
+                        return Err(ValidationError::RateLimiterWithVhostUser);
+                    }
+                    validate_rate_limiter(rate_limiter_config)?;
+                }
+                if let Some(failover) = &net.failover {
+                    if net.vhost_user {
+                        return Err(ValidationError::NetFailoverVhostUser);
+                    }
+                    // The guest pairs the devices sharing the same MAC
+                    // address, which is programmed on the VF.
+                    let device = self
+                        .devices
+                        .iter()
+                        .flatten()
+                        .find(|d| d.id.as_ref() == Some(failover))
+                        .ok_or_else(|| {
+                            ValidationError::NetFailoverUnknownDevice(failover.clone())
+                        })?;
+                    if device.mac != Some(net.mac) {
+                        return Err(ValidationError::NetFailoverMacMismatch(failover.clone()));
+                    }
                 }
             }
         }
 
         if let Some(fses) = &self.fs {
-            if !fses.is_empty() && !self.memory.shared {
+            if !fses.is_empty() && !shared_memory {
                 return Err(ValidationError::VhostUserRequiresSharedMemory);
             }
         }
@@ -1244,6 +3919,9 @@ impl VmConfig {
             if self.devices.is_some() {
                 return Err(ValidationError::VfioUnsupported);
             }
+            if self.disks.iter().flatten().any(|disk| disk.nvme) {
+                return Err(ValidationError::NvmeUnsupported);
+            }
         }
 
         if let Some(t) = &self.cpus.topology {
@@ -1259,6 +3937,264 @@ impl VmConfig {
             if total != self.cpus.max_vcpus {
                 return Err(ValidationError::CpuTopologyCount);
             }
+
+            if self.cpus.boot_vcpus % (t.threads_per_core * t.cores_per_die) != 0 {
+                return Err(ValidationError::CpuTopologyBootVcpus);
+            }
+        }
+
+        if let Some(affinity) = &self.cpus.affinity {
+            let mut vcpus = BTreeSet::new();
+            for a in affinity.iter() {
+                if a.vcpu >= self.cpus.max_vcpus {
+                    return Err(ValidationError::CpuAffinityInvalidVcpu(a.vcpu));
+                }
+                if !vcpus.insert(a.vcpu) {
+                    return Err(ValidationError::CpuAffinityDuplicateVcpu(a.vcpu));
+                }
+                if a.host_cpus.is_empty() {
+                    return Err(ValidationError::CpuAffinityEmpty(a.vcpu));
+                }
+            }
+        }
+
+        if self.cpus.strict_affinity
+            && (self.cpus.topology.is_none() || self.cpus.affinity.is_none())
+        {
+            return Err(ValidationError::StrictAffinityRequiresTopology);
+        }
+
+        if self.cpus.model.is_some() || self.cpus.features.is_some() {
+            #[cfg(target_arch = "x86_64")]
+            arch::x86_64::cpu_features::masked_features(
+                self.cpus.model.as_deref(),
+                self.cpus.features.as_deref().unwrap_or(&[]),
+            )
+            .map_err(|e| ValidationError::CpuFeatures(e.to_string()))?;
+            #[cfg(target_arch = "aarch64")]
+            return Err(ValidationError::CpuFeatures(
+                "not supported on AArch64".to_owned(),
+            ));
+        }
+
+        if self.cpus.base_frequency.is_none()
+            && (self.cpus.max_frequency.is_some() || self.cpus.pstates)
+        {
+            return Err(ValidationError::CpuFrequency(
+                "max_frequency and pstates require a base_frequency".to_owned(),
+            ));
+        }
+        if let Some((base, max)) = self.cpus.frequency() {
+            if base == 0 {
+                return Err(ValidationError::CpuFrequency(
+                    "base_frequency can't be zero".to_owned(),
+                ));
+            }
+            if max < base {
+                return Err(ValidationError::CpuFrequency(format!(
+                    "max_frequency {} MHz below base_frequency {} MHz",
+                    max, base
+                )));
+            }
+            #[cfg(target_arch = "aarch64")]
+            return Err(ValidationError::CpuFrequency(
+                "not supported on AArch64".to_owned(),
+            ));
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        if self.cpus.nested {
+            return Err(ValidationError::NestedNotSupported);
+        }
+
+        if self.memory.free_page_reporting && !self.memory.balloon {
+            return Err(ValidationError::FreePageReportingRequiresBalloon);
+        }
+
+        if self.memory.deflate_on_oom && !self.memory.balloon {
+            return Err(ValidationError::DeflateOnOomRequiresBalloon);
+        }
+
+        if self.memory.autodeflate && !self.memory.deflate_on_oom {
+            return Err(ValidationError::AutodeflateRequiresDeflateOnOom);
+        }
+
+        if self.memory.stats_polling_interval > 0 && !self.memory.balloon {
+            return Err(ValidationError::StatsPollingRequiresBalloon);
+        }
+
+        if self.rng.max_bytes == Some(0) {
+            return Err(ValidationError::RngMaxBytesZero);
+        }
+
+        if self.rng.period_ms == 0 {
+            return Err(ValidationError::RngPeriodZero);
+        }
+
+        if let Some(vsock) = &self.vsock {
+            vsock.validate()?;
+        }
+
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.validate()?;
+        }
+
+        if let Some(sound) = &self.sound {
+            sound.validate()?;
+        }
+
+        if let Some(gpu) = &self.gpu {
+            gpu.validate()?;
+        }
+
+        if let Some(vdpa_list) = &self.vdpa {
+            for vdpa in vdpa_list.iter() {
+                vdpa.validate()?;
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(gdb) = &self.gdb {
+                gdb.validate()?;
+            }
+        }
+
+        if let Some(platform) = &self.platform {
+            platform.validate()?;
+        }
+
+        self.validate_pci_segments()?;
+        self.validate_numa()?;
+
+        Ok(())
+    }
+
+    fn validate_numa(&self) -> ValidationResult<()> {
+        let numa = match &self.numa {
+            Some(numa) => numa,
+            None => return Ok(()),
+        };
+
+        let mut nodes = BTreeMap::new();
+        for node in numa.iter() {
+            if nodes.insert(node.guest_numa_id, node).is_some() {
+                return Err(ValidationError::NumaDuplicateId(node.guest_numa_id));
+            }
+        }
+        // The boot memory, and the vCPUs not assigned to any node, belong
+        // to the node 0.
+        if !nodes.contains_key(&0) {
+            return Err(ValidationError::NumaNodeZeroMissing);
+        }
+
+        let mut vcpus = BTreeSet::new();
+        for vcpu in numa.iter().flat_map(|n| n.cpus.iter().flatten()) {
+            if *vcpu >= self.cpus.max_vcpus {
+                return Err(ValidationError::NumaInvalidVcpu(*vcpu));
+            }
+            if !vcpus.insert(*vcpu) {
+                return Err(ValidationError::NumaDuplicateVcpu(*vcpu));
+            }
+        }
+
+        let memory_nodes: BTreeSet<u32> = self
+            .memory
+            .zones
+            .iter()
+            .flatten()
+            .filter_map(|zone| zone.guest_numa_node)
+            .collect();
+        if let Some(id) = memory_nodes.iter().find(|id| !nodes.contains_key(id)) {
+            return Err(ValidationError::NumaUnknownNode(*id));
+        }
+        // The guest ignores the nodes it finds nothing in.
+        for node in numa.iter() {
+            let id = node.guest_numa_id;
+            if id != 0
+                && node.cpus.as_ref().map_or(true, |cpus| cpus.is_empty())
+                && !memory_nodes.contains(&id)
+            {
+                return Err(ValidationError::NumaNodeEmpty(id));
+            }
+        }
+
+        if numa.iter().all(|node| node.distances.is_none()) {
+            return Ok(());
+        }
+
+        for node in numa.iter() {
+            let from = node.guest_numa_id;
+            let mut destinations = BTreeSet::new();
+            for d in node.distances.iter().flatten() {
+                if !nodes.contains_key(&d.destination) {
+                    return Err(ValidationError::NumaUnknownNode(d.destination));
+                }
+                let valid = if d.destination == from {
+                    d.distance == 10
+                } else {
+                    d.distance > 10
+                };
+                if !valid || !destinations.insert(d.destination) {
+                    return Err(ValidationError::NumaInvalidDistance(from, d.destination));
+                }
+            }
+        }
+
+        // Once some distances are given, the whole matrix must be.
+        for a in numa.iter() {
+            for b in numa.iter().filter(|b| b.guest_numa_id != a.guest_numa_id) {
+                let (from, to) = (a.guest_numa_id, b.guest_numa_id);
+                let there = a
+                    .distance(to)
+                    .ok_or(ValidationError::NumaDistancesIncomplete(from, to))?;
+                let back = b
+                    .distance(from)
+                    .ok_or(ValidationError::NumaDistancesIncomplete(to, from))?;
+                if there != back && !a.asymmetric && !b.asymmetric {
+                    return Err(ValidationError::NumaDistancesAsymmetric(from, to));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_pci_segments(&self) -> ValidationResult<()> {
+        for disk in self.disks.iter().flatten() {
+            self.validate_pci_segment(disk.pci_segment, disk.iommu)?;
+            // All the NVMe disks are namespaces of a single controller.
+            if disk.nvme && disk.pci_segment != 0 {
+                return Err(ValidationError::DiskNvmeOnSegment(disk.pci_segment));
+            }
+        }
+        for net in self.net.iter().flatten() {
+            self.validate_pci_segment(net.pci_segment, net.iommu)?;
+        }
+        self.validate_pci_segment(self.rng.pci_segment, self.rng.iommu)?;
+        for fs in self.fs.iter().flatten() {
+            self.validate_pci_segment(fs.pci_segment, false)?;
+        }
+        for pmem in self.pmem.iter().flatten() {
+            self.validate_pci_segment(pmem.pci_segment, pmem.iommu)?;
+        }
+        for device in self.devices.iter().flatten() {
+            self.validate_pci_segment(device.pci_segment, device.iommu)?;
+        }
+        if let Some(vsock) = &self.vsock {
+            self.validate_pci_segment(vsock.pci_segment, vsock.iommu)?;
+        }
+        if let Some(watchdog) = &self.watchdog {
+            self.validate_pci_segment(watchdog.pci_segment, watchdog.iommu)?;
+        }
+        if let Some(sound) = &self.sound {
+            self.validate_pci_segment(sound.pci_segment, sound.iommu)?;
+        }
+        if let Some(gpu) = &self.gpu {
+            self.validate_pci_segment(gpu.pci_segment, gpu.iommu)?;
+        }
+        for vdpa in self.vdpa.iter().flatten() {
+            self.validate_pci_segment(vdpa.pci_segment, false)?;
         }
 
         Ok(())
@@ -1326,6 +4262,15 @@ impl VmConfig {
         }
         let serial = ConsoleConfig::parse(vm_params.serial)?;
 
+        let mut console_ports: Option<Vec<ConsolePortConfig>> = None;
+        if let Some(console_port_list) = &vm_params.console_ports {
+            let mut console_port_config_list = Vec::new();
+            for item in console_port_list.iter() {
+                console_port_config_list.push(ConsolePortConfig::parse(item)?);
+            }
+            console_ports = Some(console_port_config_list);
+        }
+
         let mut devices: Option<Vec<DeviceConfig>> = None;
         if let Some(device_list) = &vm_params.devices {
             let mut device_config_list = Vec::new();
@@ -1336,18 +4281,68 @@ impl VmConfig {
                 }
                 device_config_list.push(device_config);
             }
-            devices = Some(device_config_list);
+            devices = Some(device_config_list);
+        }
+
+        let mut vsock: Option<VsockConfig> = None;
+        if let Some(vs) = &vm_params.vsock {
+            let vsock_config = VsockConfig::parse(vs)?;
+            if vsock_config.iommu {
+                iommu = true;
+            }
+            vsock = Some(vsock_config);
+        }
+
+        let mut watchdog: Option<WatchdogConfig> = None;
+        if let Some(wd) = &vm_params.watchdog {
+            let watchdog_config = WatchdogConfig::parse(wd)?;
+            if watchdog_config.iommu {
+                iommu = true;
+            }
+            watchdog = Some(watchdog_config);
+        }
+
+        let mut sound: Option<SoundConfig> = None;
+        if let Some(snd) = &vm_params.sound {
+            let sound_config = SoundConfig::parse(snd)?;
+            if sound_config.iommu {
+                iommu = true;
+            }
+            sound = Some(sound_config);
+        }
+
+        let mut gpu: Option<GpuConfig> = None;
+        if let Some(g) = &vm_params.gpu {
+            let gpu_config = GpuConfig::parse(g)?;
+            if gpu_config.iommu {
+                iommu = true;
+            }
+            gpu = Some(gpu_config);
+        }
+
+        let mut vdpa: Option<Vec<VdpaConfig>> = None;
+        if let Some(vdpa_list) = &vm_params.vdpa {
+            let mut vdpa_config_list = Vec::new();
+            for item in vdpa_list.iter() {
+                vdpa_config_list.push(VdpaConfig::parse(item)?);
+            }
+            vdpa = Some(vdpa_config_list);
         }
 
-        let mut vsock: Option<VsockConfig> = None;
-        if let Some(vs) = &vm_params.vsock {
-            let vsock_config = VsockConfig::parse(vs)?;
-            if vsock_config.iommu {
-                iommu = true;
+        let mut numa: Option<Vec<NumaConfig>> = None;
+        if let Some(numa_list) = &vm_params.numa {
+            let mut numa_config_list = Vec::new();
+            for item in numa_list.iter() {
+                numa_config_list.push(NumaConfig::parse(item)?);
             }
-            vsock = Some(vsock_config);
+            numa = Some(numa_config_list);
         }
 
+        let platform = match vm_params.platform {
+            Some(platform) => Some(PlatformConfig::parse(platform)?),
+            None => None,
+        };
+
         #[cfg(target_arch = "x86_64")]
         let mut sgx_epc: Option<Vec<SgxEpcConfig>> = None;
         #[cfg(target_arch = "x86_64")]
@@ -1362,6 +4357,18 @@ impl VmConfig {
             }
         }
 
+        #[cfg(target_arch = "x86_64")]
+        let gdb = match vm_params.gdb {
+            Some(gdb) => Some(GdbConfig::parse(gdb)?),
+            None => None,
+        };
+
+        #[cfg(target_arch = "x86_64")]
+        let pvpanic = match vm_params.pvpanic {
+            Some(pvpanic) => Some(PvpanicConfig::parse(pvpanic)?),
+            None => None,
+        };
+
         let mut kernel: Option<KernelConfig> = None;
         if let Some(k) = vm_params.kernel {
             kernel = Some(KernelConfig {
@@ -1376,9 +4383,18 @@ impl VmConfig {
             });
         }
 
+        let mut memory = MemoryConfig::parse(vm_params.memory)?;
+        if let Some(memory_zone_list) = &vm_params.memory_zones {
+            let mut memory_zone_config_list = Vec::new();
+            for item in memory_zone_list.iter() {
+                memory_zone_config_list.push(MemoryZoneConfig::parse(item)?);
+            }
+            memory.zones = Some(memory_zone_config_list);
+        }
+
         let config = VmConfig {
             cpus: CpusConfig::parse(vm_params.cpus)?,
-            memory: MemoryConfig::parse(vm_params.memory)?,
+            memory,
             kernel,
             initramfs,
             cmdline: CmdlineConfig::parse(vm_params.cmdline)?,
@@ -1389,11 +4405,22 @@ impl VmConfig {
             pmem,
             serial,
             console,
+            console_ports,
             devices,
             vsock,
+            watchdog,
+            sound,
+            gpu,
+            vdpa,
+            numa,
             iommu,
+            platform,
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
+            #[cfg(target_arch = "x86_64")]
+            gdb,
+            #[cfg(target_arch = "x86_64")]
+            pvpanic,
         };
         config.validate().map_err(Error::Validation)?;
         Ok(config)
@@ -1438,7 +4465,8 @@ mod tests {
             CpusConfig {
                 boot_vcpus: 1,
                 max_vcpus: 1,
-                topology: None
+                topology: None,
+                ..Default::default()
             }
         );
         assert_eq!(
@@ -1446,7 +4474,8 @@ mod tests {
             CpusConfig {
                 boot_vcpus: 1,
                 max_vcpus: 2,
-                topology: None
+                topology: None,
+                ..Default::default()
             }
         );
         assert_eq!(
@@ -1459,12 +4488,79 @@ mod tests {
                     cores_per_die: 2,
                     dies_per_package: 1,
                     packages: 2
-                })
+                }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=4,topology=2:2:1:1,affinity=0@4:1@5:2@6-7,strict_affinity=on")?,
+            CpusConfig {
+                boot_vcpus: 4,
+                max_vcpus: 4,
+                topology: Some(CpuTopology {
+                    threads_per_core: 2,
+                    cores_per_die: 2,
+                    dies_per_package: 1,
+                    packages: 1
+                }),
+                affinity: Some(vec![
+                    CpuAffinity {
+                        vcpu: 0,
+                        host_cpus: vec![4]
+                    },
+                    CpuAffinity {
+                        vcpu: 1,
+                        host_cpus: vec![5]
+                    },
+                    CpuAffinity {
+                        vcpu: 2,
+                        host_cpus: vec![6, 7]
+                    }
+                ]),
+                strict_affinity: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=2,model=haswell,features=+rdseed:-avx2")?,
+            CpusConfig {
+                boot_vcpus: 2,
+                max_vcpus: 2,
+                model: Some("haswell".to_owned()),
+                features: Some(vec!["+rdseed".to_owned(), "-avx2".to_owned()]),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            CpusConfig::parse("boot=2,base_frequency=2400,max_frequency=3200,pstates=on")?,
+            CpusConfig {
+                boot_vcpus: 2,
+                max_vcpus: 2,
+                base_frequency: Some(2400),
+                max_frequency: Some(3200),
+                pstates: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=2,nested=on")?,
+            CpusConfig {
+                boot_vcpus: 2,
+                max_vcpus: 2,
+                nested: true,
+                ..Default::default()
             }
         );
 
         assert!(CpusConfig::parse("boot=8,topology=2:2:1").is_err());
         assert!(CpusConfig::parse("boot=8,topology=2:2:1:x").is_err());
+        assert!(CpusConfig::parse("boot=2,affinity=0").is_err());
+        assert!(CpusConfig::parse("boot=2,affinity=0@x").is_err());
+        assert!(CpusConfig::parse("boot=2,affinity=0@1-").is_err());
+        assert!(CpusConfig::parse("boot=2,features=avx2").is_err());
+        assert!(CpusConfig::parse("boot=2,features=-avx2:").is_err());
+        assert!(CpusConfig::parse("boot=2,base_frequency=2.4GHz").is_err());
 
         Ok(())
     }
@@ -1526,9 +4622,197 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            MemoryConfig::parse("size=1G,hugepages=on,prefault=on")?,
+            MemoryConfig {
+                size: 1 << 30,
+                hugepages: true,
+                prefault: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("mergeable=on,thp=never")?,
+            MemoryConfig {
+                mergeable: true,
+                thp: ThpMode::Never,
+                ..Default::default()
+            }
+        );
+        assert!(MemoryConfig::parse("thp=sometimes").is_err());
+        assert_eq!(
+            MemoryConfig::parse("balloon=on,free_page_reporting=on")?,
+            MemoryConfig {
+                balloon: true,
+                free_page_reporting: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("balloon=on,deflate_on_oom=on")?,
+            MemoryConfig {
+                balloon: true,
+                deflate_on_oom: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("balloon=on,deflate_on_oom=on,autodeflate=on")?,
+            MemoryConfig {
+                balloon: true,
+                deflate_on_oom: true,
+                autodeflate: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("balloon=on,stats_polling_interval=5")?,
+            MemoryConfig {
+                balloon: true,
+                stats_polling_interval: 5,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("dirty_ring=off")?,
+            MemoryConfig {
+                dirty_ring: false,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_zone_parsing() -> Result<()> {
+        // Missing id or hotplug size
+        assert!(MemoryZoneConfig::parse("").is_err());
+        assert!(MemoryZoneConfig::parse("id=zone0").is_err());
+        assert!(MemoryZoneConfig::parse("hotplug_size=1G").is_err());
+
+        assert_eq!(
+            MemoryZoneConfig::parse("id=zone0,hotplug_size=1G")?,
+            MemoryZoneConfig {
+                id: "zone0".to_owned(),
+                hotplug_size: 1 << 30,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryZoneConfig::parse(
+                "id=zone1,hotplug_method=virtio-mem,hotplug_size=8G,hotplugged_size=2G,\
+                 host_numa_node=1,guest_numa_node=1,block_size=1G,shared=on,hugepages=on,\
+                 hugepage_size=1G,prefault=on,mergeable=on"
+            )?,
+            MemoryZoneConfig {
+                id: "zone1".to_owned(),
+                hotplug_size: 8 << 30,
+                hotplugged_size: Some(2 << 30),
+                hotplug_method: HotplugMethod::VirtioMem,
+                host_numa_node: Some(1),
+                guest_numa_node: Some(1),
+                block_size: 1 << 30,
+                shared: true,
+                hugepages: true,
+                hugepage_size: Some(1 << 30),
+                prefault: true,
+                mergeable: true,
+                thp: ThpMode::Madvise,
+                backing: MemoryBacking::Anonymous,
+            }
+        );
+        assert_eq!(
+            MemoryZoneConfig::parse("id=zone3,hotplug_size=1G,shared=on,backing=memfd")?,
+            MemoryZoneConfig {
+                id: "zone3".to_owned(),
+                hotplug_size: 1 << 30,
+                shared: true,
+                backing: MemoryBacking::Memfd,
+                ..Default::default()
+            }
+        );
+        assert!(MemoryZoneConfig::parse("id=zone4,hotplug_size=1G,backing=file").is_err());
+        assert_eq!(
+            MemoryZoneConfig::parse("id=zone2,hotplug_size=1G,thp=always")?,
+            MemoryZoneConfig {
+                id: "zone2".to_owned(),
+                hotplug_size: 1 << 30,
+                thp: ThpMode::Always,
+                ..Default::default()
+            }
+        );
+
         Ok(())
     }
 
+    #[test]
+    fn test_memory_zone_validation() {
+        let valid_zone = MemoryZoneConfig {
+            id: "zone0".to_owned(),
+            hotplug_size: 1 << 30,
+            ..Default::default()
+        };
+        assert!(valid_zone.validate().is_ok());
+
+        let mut invalid_zone = valid_zone.clone();
+        invalid_zone.hotplug_method = HotplugMethod::Acpi;
+        assert!(invalid_zone.validate().is_err());
+
+        // Block size must be a power of two, at least 2MiB
+        let mut invalid_zone = valid_zone.clone();
+        invalid_zone.block_size = 3 << 20;
+        assert!(invalid_zone.validate().is_err());
+        let mut invalid_zone = valid_zone.clone();
+        invalid_zone.block_size = 1 << 20;
+        assert!(invalid_zone.validate().is_err());
+
+        // Sizes must be aligned on the block size
+        let mut invalid_zone = valid_zone.clone();
+        invalid_zone.hotplug_size = 3 << 20;
+        assert!(invalid_zone.validate().is_err());
+        let mut invalid_zone = valid_zone.clone();
+        invalid_zone.hotplugged_size = Some(3 << 20);
+        assert!(invalid_zone.validate().is_err());
+        let mut invalid_zone = valid_zone.clone();
+        invalid_zone.hotplugged_size = Some(2 << 30);
+        assert!(invalid_zone.validate().is_err());
+
+        // The block size must match the hugepage settings
+        let mut invalid_zone = valid_zone.clone();
+        invalid_zone.hugepage_size = Some(2 << 20);
+        assert!(invalid_zone.validate().is_err());
+        let mut invalid_zone = valid_zone.clone();
+        invalid_zone.hugepages = true;
+        invalid_zone.hugepage_size = Some(4 << 20);
+        assert!(invalid_zone.validate().is_err());
+        let mut invalid_zone = valid_zone.clone();
+        invalid_zone.hugepages = true;
+        invalid_zone.hugepage_size = Some(1 << 30);
+        assert!(invalid_zone.validate().is_err());
+
+        let mut still_valid_zone = valid_zone.clone();
+        still_valid_zone.hugepages = true;
+        assert!(still_valid_zone.validate().is_ok());
+        still_valid_zone.hugepage_size = Some(1 << 30);
+        still_valid_zone.block_size = 1 << 30;
+        assert!(still_valid_zone.validate().is_ok());
+
+        // Hugepages leave no room for transparent huge pages
+        let mut invalid_zone = valid_zone.clone();
+        invalid_zone.hugepages = true;
+        invalid_zone.thp = ThpMode::Never;
+        assert!(invalid_zone.validate().is_err());
+
+        // A memfd has to be shared to be of any use
+        let mut invalid_zone = valid_zone.clone();
+        invalid_zone.backing = MemoryBacking::Memfd;
+        assert!(invalid_zone.validate().is_err());
+        let mut still_valid_zone = valid_zone.clone();
+        still_valid_zone.backing = MemoryBacking::Memfd;
+        still_valid_zone.shared = true;
+        assert!(still_valid_zone.validate().is_ok());
+    }
+
     #[test]
     fn test_disk_parsing() -> Result<()> {
         assert_eq!(
@@ -1603,6 +4887,63 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,stripe_paths=/path/to_file1:/path/to_file2")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                stripe_paths: vec![
+                    PathBuf::from("/path/to_file1"),
+                    PathBuf::from("/path/to_file2")
+                ],
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,stripe_paths=/path/to_file1,stripe_size=1M")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                stripe_paths: vec![PathBuf::from("/path/to_file1")],
+                stripe_size: 1 << 20,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,bw_size=10M,ops_size=1000,ops_refill_time=100")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                rate_limiter_config: Some(RateLimiterConfig {
+                    bandwidth: Some(TokenBucketConfig {
+                        size: 10 << 20,
+                        refill_time: 1000,
+                    }),
+                    ops: Some(TokenBucketConfig {
+                        size: 1000,
+                        refill_time: 100,
+                    }),
+                }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,busy_poll=on,busy_poll_us=20")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                busy_poll: true,
+                busy_poll_us: 20,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse(
+                "path=/path/to_file,coalesce_max_requests=16,coalesce_max_usecs=100"
+            )?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                coalesce_max_requests: 16,
+                coalesce_max_usecs: 100,
+                ..Default::default()
+            }
+        );
         assert_eq!(
             DiskConfig::parse("path=/path/to_file,poll_queue=false")?,
             DiskConfig {
@@ -1619,67 +4960,206 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,serial=mydisk-serial")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                serial: Some("mydisk-serial".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,nvme=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                nvme: true,
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_validation() {
+        let valid_disk = DiskConfig {
+            path: Some(PathBuf::from("/path/to_file")),
+            ..Default::default()
+        };
+        assert!(valid_disk.validate().is_ok());
+
+        let mut invalid_disk = valid_disk.clone();
+        invalid_disk.vhost_socket = Some("/path/to/sock".to_owned());
+        assert!(invalid_disk.validate().is_err());
+
+        // The access mode is enforced on the images opened by the VMM, and
+        // on the ones of the vhost-user backends it spawns, but not by an
+        // external vhost-user backend
+        let mut still_valid_disk = valid_disk.clone();
+        still_valid_disk.readonly = true;
+        still_valid_disk.direct = true;
+        assert!(still_valid_disk.validate().is_ok());
+        still_valid_disk.vhost_user = true;
+        assert!(still_valid_disk.validate().is_ok());
+
+        let socket_disk = DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            ..Default::default()
+        };
+        assert!(socket_disk.validate().is_ok());
+        let mut invalid_disk = socket_disk.clone();
+        invalid_disk.readonly = true;
+        assert!(invalid_disk.validate().is_err());
+        let mut invalid_disk = socket_disk.clone();
+        invalid_disk.direct = true;
+        assert!(invalid_disk.validate().is_err());
+
+        let mut invalid_disk = valid_disk.clone();
+        invalid_disk.serial = Some("x".repeat(DISK_SERIAL_MAX_LEN + 1));
+        assert!(invalid_disk.validate().is_err());
+        let mut still_valid_disk = valid_disk;
+        still_valid_disk.serial = Some("x".repeat(DISK_SERIAL_MAX_LEN));
+        assert!(still_valid_disk.validate().is_ok());
+    }
+
+    #[test]
+    fn test_net_parsing() -> Result<()> {
+        // mac address is random
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,id=mynet0")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                id: Some("mynet0".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,tap=tap0,ip=192.168.100.1,mask=255.255.255.128"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                tap: Some("tap0".to_owned()),
+                ip: "192.168.100.1".parse().unwrap(),
+                mask: "255.255.255.128".parse().unwrap(),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,vhost_user=true,socket=/tmp/sock"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                vhost_user: true,
+                vhost_socket: Some("/tmp/sock".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "vhost_user=true,socket=/tmp/sock,reconnect_retries=0,reconnect_backoff_ms=500"
+            )?,
+            NetConfig {
+                vhost_user: true,
+                vhost_socket: Some("/tmp/sock".to_owned()),
+                reconnect_retries: 0,
+                reconnect_backoff_ms: 500,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,num_queues=4,queue_size=1024,iommu=on")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                num_queues: 4,
+                queue_size: 1024,
+                iommu: true,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,coalesce_max_packets=32,coalesce_max_usecs=50"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                coalesce_max_packets: 32,
+                coalesce_max_usecs: 50,
+                ..Default::default()
+            }
+        );
 
-        Ok(())
-    }
-
-    #[test]
-    fn test_net_parsing() -> Result<()> {
-        // mac address is random
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef")?,
+            NetConfig::parse("mac=de:ad:be:ef:12:34,rx_starvation=drop")?,
             NetConfig {
                 mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                rx_starvation: RxStarvationMode::Drop,
                 ..Default::default()
             }
         );
 
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,id=mynet0")?,
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,rx_starvation=block,rx_starvation_timeout_ms=20"
+            )?,
             NetConfig {
                 mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                id: Some("mynet0".to_owned()),
+                rx_starvation: RxStarvationMode::Block,
+                rx_starvation_timeout_ms: 20,
                 ..Default::default()
             }
         );
+        assert!(NetConfig::parse("rx_starvation=wait").is_err());
 
         assert_eq!(
-            NetConfig::parse(
-                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,tap=tap0,ip=192.168.100.1,mask=255.255.255.128"
-            )?,
+            NetConfig::parse("mac=de:ad:be:ef:12:34,ops_size=100")?,
             NetConfig {
                 mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                tap: Some("tap0".to_owned()),
-                ip: "192.168.100.1".parse().unwrap(),
-                mask: "255.255.255.128".parse().unwrap(),
+                rate_limiter_config: Some(RateLimiterConfig {
+                    bandwidth: None,
+                    ops: Some(TokenBucketConfig {
+                        size: 100,
+                        refill_time: 1000,
+                    }),
+                }),
                 ..Default::default()
             }
         );
 
         assert_eq!(
-            NetConfig::parse(
-                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,vhost_user=true,socket=/tmp/sock"
-            )?,
+            NetConfig::parse("mac=de:ad:be:ef:12:34,failover=vf0")?,
             NetConfig {
                 mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                vhost_user: true,
-                vhost_socket: Some("/tmp/sock".to_owned()),
+                failover: Some("vf0".to_owned()),
                 ..Default::default()
             }
         );
 
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,num_queues=4,queue_size=1024,iommu=on")?,
+            NetConfig::parse("mac=de:ad:be:ef:12:34,rx_timestamps=on")?,
             NetConfig {
                 mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                num_queues: 4,
-                queue_size: 1024,
-                iommu: true,
+                rx_timestamps: true,
                 ..Default::default()
             }
         );
@@ -1702,6 +5182,7 @@ mod tests {
             RngConfig {
                 src: PathBuf::from("/dev/random"),
                 iommu: true,
+                ..Default::default()
             }
         );
         assert_eq!(
@@ -1711,6 +5192,24 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            RngConfig::parse("max_bytes=4K")?,
+            RngConfig {
+                max_bytes: Some(4096),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RngConfig::parse("src=/dev/hwrng,max_bytes=1K,period_ms=100")?,
+            RngConfig {
+                src: PathBuf::from("/dev/hwrng"),
+                max_bytes: Some(1024),
+                period_ms: 100,
+                ..Default::default()
+            }
+        );
+        assert!(RngConfig::parse("max_bytes=foo").is_err());
+        assert!(RngConfig::parse("period_ms=foo").is_err());
         Ok(())
     }
 
@@ -1794,6 +5293,15 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            PmemConfig::parse("file=/tmp/pmem,size=128M,readonly=on")?,
+            PmemConfig {
+                file: PathBuf::from("/tmp/pmem"),
+                size: Some(128 << 20),
+                readonly: true,
+                ..Default::default()
+            }
+        );
         assert_eq!(
             PmemConfig::parse("file=/tmp/pmem,size=128M,iommu=on,mergeable=on,discard_writes=on")?,
             PmemConfig {
@@ -1809,6 +5317,29 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_pmem_validation() {
+        let valid_pmem = PmemConfig {
+            file: PathBuf::from("/tmp/pmem"),
+            size: Some(128 << 20),
+            ..Default::default()
+        };
+        assert!(valid_pmem.validate().is_ok());
+
+        let mut still_valid_pmem = valid_pmem.clone();
+        still_valid_pmem.readonly = true;
+        assert!(still_valid_pmem.validate().is_ok());
+        let mut still_valid_pmem = valid_pmem.clone();
+        still_valid_pmem.discard_writes = true;
+        assert!(still_valid_pmem.validate().is_ok());
+
+        // Discarding the writes lets the guest write to the device
+        let mut invalid_pmem = valid_pmem;
+        invalid_pmem.readonly = true;
+        invalid_pmem.discard_writes = true;
+        assert!(invalid_pmem.validate().is_err());
+    }
+
     #[test]
     fn test_console_parsing() -> Result<()> {
         assert!(ConsoleConfig::parse("").is_err());
@@ -1819,6 +5350,8 @@ mod tests {
                 mode: ConsoleOutputMode::Off,
                 iommu: false,
                 file: None,
+                socket: None,
+                tcp: None,
             }
         );
         assert_eq!(
@@ -1827,6 +5360,8 @@ mod tests {
                 mode: ConsoleOutputMode::Tty,
                 iommu: false,
                 file: None,
+                socket: None,
+                tcp: None,
             }
         );
         assert_eq!(
@@ -1835,6 +5370,8 @@ mod tests {
                 mode: ConsoleOutputMode::Null,
                 iommu: false,
                 file: None,
+                socket: None,
+                tcp: None,
             }
         );
         assert_eq!(
@@ -1842,7 +5379,9 @@ mod tests {
             ConsoleConfig {
                 mode: ConsoleOutputMode::File,
                 iommu: false,
-                file: Some(PathBuf::from("/tmp/console"))
+                file: Some(PathBuf::from("/tmp/console")),
+                socket: None,
+                tcp: None,
             }
         );
         assert_eq!(
@@ -1851,6 +5390,8 @@ mod tests {
                 mode: ConsoleOutputMode::Null,
                 iommu: true,
                 file: None,
+                socket: None,
+                tcp: None,
             }
         );
         assert_eq!(
@@ -1858,7 +5399,74 @@ mod tests {
             ConsoleConfig {
                 mode: ConsoleOutputMode::File,
                 iommu: true,
-                file: Some(PathBuf::from("/tmp/console"))
+                file: Some(PathBuf::from("/tmp/console")),
+                socket: None,
+                tcp: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("socket=/tmp/serial.sock")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Socket,
+                iommu: false,
+                file: None,
+                socket: Some(PathBuf::from("/tmp/serial.sock")),
+                tcp: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("tcp=127.0.0.1:4444")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Tcp,
+                iommu: false,
+                file: None,
+                socket: None,
+                tcp: Some(String::from("127.0.0.1:4444")),
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("pty")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Pty,
+                iommu: false,
+                file: None,
+                socket: None,
+                tcp: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_port_parsing() -> Result<()> {
+        assert!(ConsolePortConfig::parse("").is_err());
+        assert!(ConsolePortConfig::parse("pty").is_err());
+        assert!(ConsolePortConfig::parse("name=org.test.0").is_err());
+        assert!(ConsolePortConfig::is_port("name=org.test.0,pty"));
+        assert!(ConsolePortConfig::is_port("pty,name=org.test.0"));
+        assert!(!ConsolePortConfig::is_port("file=/tmp/name=console"));
+        assert_eq!(
+            ConsolePortConfig::parse("name=org.test.0,pty")?,
+            ConsolePortConfig {
+                name: String::from("org.test.0"),
+                mode: ConsolePortMode::Pty,
+                path: None,
+            }
+        );
+        assert_eq!(
+            ConsolePortConfig::parse("name=org.test.0,socket=/tmp/agent.sock")?,
+            ConsolePortConfig {
+                name: String::from("org.test.0"),
+                mode: ConsolePortMode::Socket,
+                path: Some(PathBuf::from("/tmp/agent.sock")),
+            }
+        );
+        assert_eq!(
+            ConsolePortConfig::parse("file=/tmp/port,name=org.test.0")?,
+            ConsolePortConfig {
+                name: String::from("org.test.0"),
+                mode: ConsolePortMode::File,
+                path: Some(PathBuf::from("/tmp/port")),
             }
         );
         Ok(())
@@ -1873,7 +5481,10 @@ mod tests {
             DeviceConfig {
                 path: PathBuf::from("/path/to/device"),
                 id: None,
-                iommu: false
+                iommu: false,
+                pci_segment: 0,
+                mac: None,
+                vlan: None,
             }
         );
 
@@ -1882,7 +5493,10 @@ mod tests {
             DeviceConfig {
                 path: PathBuf::from("/path/to/device"),
                 id: None,
-                iommu: true
+                iommu: true,
+                pci_segment: 0,
+                mac: None,
+                vlan: None,
             }
         );
 
@@ -1891,7 +5505,22 @@ mod tests {
             DeviceConfig {
                 path: PathBuf::from("/path/to/device"),
                 id: Some("mydevice0".to_owned()),
-                iommu: true
+                iommu: true,
+                pci_segment: 0,
+                mac: None,
+                vlan: None,
+            }
+        );
+
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,mac=12:34:56:78:90:ab,vlan=42")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                id: None,
+                iommu: false,
+                pci_segment: 0,
+                mac: Some(MacAddr::parse_str("12:34:56:78:90:ab").unwrap()),
+                vlan: Some(42),
             }
         );
 
@@ -1909,6 +5538,10 @@ mod tests {
                 socket: PathBuf::from("/tmp/sock"),
                 iommu: false,
                 id: None,
+                max_connections: DEFAULT_VSOCK_MAX_CONNECTIONS,
+                idle_timeout: 0,
+                listen_ports: Vec::new(),
+                pci_segment: 0,
             }
         );
         assert_eq!(
@@ -1918,8 +5551,237 @@ mod tests {
                 socket: PathBuf::from("/tmp/sock"),
                 iommu: true,
                 id: None,
+                max_connections: DEFAULT_VSOCK_MAX_CONNECTIONS,
+                idle_timeout: 0,
+                listen_ports: Vec::new(),
+                pci_segment: 0,
+            }
+        );
+        assert_eq!(
+            VsockConfig::parse("socket=/tmp/sock,cid=1,max_connections=16,idle_timeout=30")?,
+            VsockConfig {
+                cid: 1,
+                socket: PathBuf::from("/tmp/sock"),
+                iommu: false,
+                id: None,
+                max_connections: 16,
+                idle_timeout: 30,
+                listen_ports: Vec::new(),
+                pci_segment: 0,
+            }
+        );
+        assert_eq!(
+            VsockConfig::parse("socket=/tmp/sock,cid=1,listen_ports=1234:5678")?,
+            VsockConfig {
+                cid: 1,
+                socket: PathBuf::from("/tmp/sock"),
+                listen_ports: vec![1234, 5678],
+                ..Default::default()
+            }
+        );
+        assert!(VsockConfig::parse("socket=/tmp/sock,cid=1,listen_ports=1234:abc").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_watchdog_parsing() -> Result<()> {
+        assert_eq!(WatchdogConfig::parse("")?, WatchdogConfig::default());
+        assert_eq!(
+            WatchdogConfig::parse("timeout=10,action=pause")?,
+            WatchdogConfig {
+                timeout: 10,
+                action: WatchdogAction::Pause,
+                iommu: false,
+                pci_segment: 0,
+            }
+        );
+        assert_eq!(
+            WatchdogConfig::parse("action=log,iommu=on")?,
+            WatchdogConfig {
+                timeout: DEFAULT_WATCHDOG_TIMEOUT,
+                action: WatchdogAction::None,
+                iommu: true,
+                pci_segment: 0,
+            }
+        );
+        assert_eq!(
+            WatchdogConfig::parse("action=shutdown")?.action,
+            WatchdogAction::Shutdown
+        );
+        assert_eq!(
+            WatchdogConfig::parse("action=none")?.action,
+            WatchdogAction::None
+        );
+        assert!(WatchdogConfig::parse("action=reboot").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sound_parsing() -> Result<()> {
+        assert_eq!(SoundConfig::parse("")?, SoundConfig::default());
+        assert_eq!(
+            SoundConfig::parse("backend=alsa,device=hw:0")?,
+            SoundConfig {
+                backend: SoundBackend::Alsa,
+                device: String::from("hw:0"),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            SoundConfig::parse("backend=file,path=/tmp/sound.raw,iommu=on")?,
+            SoundConfig {
+                backend: SoundBackend::File,
+                path: Some(PathBuf::from("/tmp/sound.raw")),
+                iommu: true,
+                ..Default::default()
+            }
+        );
+        assert!(SoundConfig::parse("backend=pulse").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gpu_parsing() -> Result<()> {
+        assert_eq!(GpuConfig::parse("")?, GpuConfig::default());
+        assert_eq!(
+            GpuConfig::parse("width=1920,height=1080")?,
+            GpuConfig {
+                width: 1920,
+                height: 1080,
+                iommu: false,
+                pci_segment: 0,
+                shm: None,
+            }
+        );
+        assert_eq!(
+            GpuConfig::parse("height=600,iommu=on")?,
+            GpuConfig {
+                width: 1280,
+                height: 600,
+                iommu: true,
+                pci_segment: 0,
+                shm: None,
+            }
+        );
+        assert_eq!(
+            GpuConfig::parse("shm=/dev/shm/framebuffer")?,
+            GpuConfig {
+                shm: Some(PathBuf::from("/dev/shm/framebuffer")),
+                ..Default::default()
+            }
+        );
+        assert!(GpuConfig::parse("width=wide").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_platform_parsing() -> Result<()> {
+        assert_eq!(PlatformConfig::parse("")?, PlatformConfig::default());
+        assert_eq!(
+            PlatformConfig::parse("num_pci_segments=4")?,
+            PlatformConfig {
+                num_pci_segments: 4,
+            }
+        );
+        assert!(PlatformConfig::parse("num_pci_segments=many").is_err());
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,pci_segment=2")?.pci_segment,
+            2
+        );
+        assert!(NetConfig::parse("pci_segment=-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_vdpa_parsing() -> Result<()> {
+        assert!(VdpaConfig::parse("").is_err());
+        assert!(VdpaConfig::parse("num_queues=2").is_err());
+        assert_eq!(
+            VdpaConfig::parse("path=/dev/vhost-vdpa-0")?,
+            VdpaConfig {
+                path: PathBuf::from("/dev/vhost-vdpa-0"),
+                num_queues: 1,
+                id: None,
+                pci_segment: 0,
+            }
+        );
+        assert_eq!(
+            VdpaConfig::parse("path=/dev/vhost-vdpa-1,num_queues=2,id=mydpa0")?,
+            VdpaConfig {
+                path: PathBuf::from("/dev/vhost-vdpa-1"),
+                num_queues: 2,
+                id: Some("mydpa0".to_owned()),
+                pci_segment: 0,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_numa_parsing() -> Result<()> {
+        assert!(NumaConfig::parse("").is_err());
+        assert!(NumaConfig::parse("cpus=0").is_err());
+        assert_eq!(
+            NumaConfig::parse("guest_numa_id=1")?,
+            NumaConfig {
+                guest_numa_id: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            NumaConfig::parse("guest_numa_id=0,cpus=0-2:5,distances=1@20:2@30,asymmetric=on")?,
+            NumaConfig {
+                guest_numa_id: 0,
+                cpus: Some(vec![0, 1, 2, 5]),
+                distances: Some(vec![
+                    NumaDistance {
+                        destination: 1,
+                        distance: 20,
+                    },
+                    NumaDistance {
+                        destination: 2,
+                        distance: 30,
+                    },
+                ]),
+                asymmetric: true,
             }
         );
+        assert!(NumaConfig::parse("guest_numa_id=0,cpus=2-1").is_err());
+        assert!(NumaConfig::parse("guest_numa_id=0,distances=1").is_err());
+        assert!(NumaConfig::parse("guest_numa_id=0,distances=1@300").is_err());
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_gdb_parsing() -> Result<()> {
+        let gdb = GdbConfig::parse("socket=/tmp/gdb.sock")?;
+        assert_eq!(gdb.socket, Some(PathBuf::from("/tmp/gdb.sock")));
+        assert!(gdb.validate().is_ok());
+        let gdb = GdbConfig::parse("tcp=127.0.0.1:1234")?;
+        assert_eq!(gdb.tcp, Some(String::from("127.0.0.1:1234")));
+        assert!(gdb.validate().is_ok());
+        assert!(GdbConfig::parse("")?.validate().is_err());
+        assert!(GdbConfig::parse("socket=/tmp/gdb.sock,tcp=127.0.0.1:1234")?
+            .validate()
+            .is_err());
+        assert!(GdbConfig::parse("port=1234").is_err());
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_pvpanic_parsing() -> Result<()> {
+        assert_eq!(PvpanicConfig::parse("")?.action, PvpanicAction::None);
+        assert_eq!(
+            PvpanicConfig::parse("action=pause")?.action,
+            PvpanicAction::Pause
+        );
+        assert_eq!(
+            PvpanicConfig::parse("action=shutdown")?.action,
+            PvpanicAction::Shutdown
+        );
+        assert!(PvpanicConfig::parse("action=reset").is_err());
         Ok(())
     }
 
@@ -1930,6 +5792,14 @@ mod tests {
                 boot_vcpus: 1,
                 max_vcpus: 1,
                 topology: None,
+                affinity: None,
+                strict_affinity: false,
+                model: None,
+                features: None,
+                base_frequency: None,
+                max_frequency: None,
+                pstates: false,
+                nested: false,
             },
             memory: MemoryConfig {
                 size: 536_870_912,
@@ -1939,8 +5809,16 @@ mod tests {
                 hotplug_size: None,
                 shared: false,
                 hugepages: false,
+                prefault: false,
+                thp: ThpMode::Madvise,
                 balloon: false,
                 balloon_size: 0,
+                deflate_on_oom: false,
+                autodeflate: false,
+                free_page_reporting: false,
+                stats_polling_interval: 0,
+                dirty_ring: true,
+                zones: None,
             },
             kernel: Some(KernelConfig {
                 path: PathBuf::from("/path/to/kernel"),
@@ -1954,6 +5832,9 @@ mod tests {
             rng: RngConfig {
                 src: PathBuf::from("/dev/urandom"),
                 iommu: false,
+                max_bytes: None,
+                period_ms: 1000,
+                pci_segment: 0,
             },
             fs: None,
             pmem: None,
@@ -1961,28 +5842,178 @@ mod tests {
                 file: None,
                 mode: ConsoleOutputMode::Null,
                 iommu: false,
+                socket: None,
+                tcp: None,
             },
             console: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Tty,
                 iommu: false,
+                socket: None,
+                tcp: None,
             },
+            console_ports: None,
             devices: None,
             vsock: None,
+            watchdog: None,
+            sound: None,
+            gpu: None,
+            vdpa: None,
+            numa: None,
             iommu: false,
+            platform: None,
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
+            #[cfg(target_arch = "x86_64")]
+            gdb: None,
+            #[cfg(target_arch = "x86_64")]
+            pvpanic: None,
+        };
+
+        assert!(valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.serial.mode = ConsoleOutputMode::Tty;
+        invalid_config.console.mode = ConsoleOutputMode::Tty;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.serial.mode = ConsoleOutputMode::Socket;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console.mode = ConsoleOutputMode::Tcp;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.kernel = None;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.rng.max_bytes = Some(0);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.rng.period_ms = 0;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.free_page_reporting = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.hugepages = true;
+        invalid_config.memory.thp = ThpMode::Always;
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.balloon = true;
+        still_valid_config.memory.free_page_reporting = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.deflate_on_oom = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.balloon = true;
+        invalid_config.memory.autodeflate = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.balloon = true;
+        still_valid_config.memory.deflate_on_oom = true;
+        still_valid_config.memory.autodeflate = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.stats_polling_interval = 5;
+        assert!(invalid_config.validate().is_err());
+
+        let zone = MemoryZoneConfig {
+            id: "zone0".to_owned(),
+            hotplug_size: 1 << 30,
+            ..Default::default()
         };
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.zones = Some(vec![
+            zone.clone(),
+            MemoryZoneConfig {
+                id: "zone1".to_owned(),
+                ..zone.clone()
+            },
+        ]);
+        assert!(still_valid_config.validate().is_ok());
 
-        assert!(valid_config.validate().is_ok());
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.zones = Some(vec![zone.clone(), zone.clone()]);
+        assert!(invalid_config.validate().is_err());
 
+        // vhost-user requires the memory zones to be shared too
         let mut invalid_config = valid_config.clone();
-        invalid_config.serial.mode = ConsoleOutputMode::Tty;
-        invalid_config.console.mode = ConsoleOutputMode::Tty;
+        invalid_config.memory.shared = true;
+        invalid_config.memory.zones = Some(vec![zone]);
+        invalid_config.fs = Some(vec![FsConfig::default()]);
         assert!(invalid_config.validate().is_err());
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.kernel = None;
+        invalid_config.vsock = Some(VsockConfig {
+            cid: 3,
+            max_connections: 0,
+            ..Default::default()
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.vsock = Some(VsockConfig {
+            cid: 3,
+            listen_ports: vec![1234, 1234],
+            ..Default::default()
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.vsock = Some(VsockConfig {
+            cid: 2,
+            ..Default::default()
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.vsock = Some(VsockConfig {
+            cid: u64::from(std::u32::MAX),
+            ..Default::default()
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.watchdog = Some(WatchdogConfig {
+            timeout: 0,
+            ..Default::default()
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.sound = Some(SoundConfig {
+            backend: SoundBackend::File,
+            ..Default::default()
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.gpu = Some(GpuConfig {
+            width: 0,
+            ..Default::default()
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.vdpa = Some(vec![VdpaConfig {
+            path: PathBuf::from("/dev/vhost-vdpa-0"),
+            num_queues: 0,
+            id: None,
+            pci_segment: 0,
+        }]);
         assert!(invalid_config.validate().is_err());
 
         let mut invalid_config = valid_config.clone();
@@ -2006,6 +6037,80 @@ mod tests {
         });
         assert!(invalid_config.validate().is_err());
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.cpus.max_vcpus = 16;
+        still_valid_config.cpus.boot_vcpus = 8;
+        still_valid_config.cpus.topology = Some(CpuTopology {
+            threads_per_core: 2,
+            cores_per_die: 4,
+            dies_per_package: 1,
+            packages: 2,
+        });
+        assert!(still_valid_config.validate().is_ok());
+
+        // Only whole dies can be booted.
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.cpus.boot_vcpus = 6;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.cpus.strict_affinity = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.cpus.pstates = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.cpus.base_frequency = Some(3200);
+        invalid_config.cpus.max_frequency = Some(2400);
+        assert!(invalid_config.validate().is_err());
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut valid_config = still_valid_config.clone();
+            valid_config.cpus.base_frequency = Some(2400);
+            valid_config.cpus.pstates = true;
+            assert!(valid_config.validate().is_ok());
+            assert_eq!(valid_config.cpus.frequency(), Some((2400, 2400)));
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut valid_config = still_valid_config.clone();
+            valid_config.cpus.model = Some("skylake-server".to_owned());
+            valid_config.cpus.features = Some(vec!["-avx512f".to_owned()]);
+            assert!(valid_config.validate().is_ok());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.cpus.model = Some("pentium".to_owned());
+            assert!(invalid_config.validate().is_err());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.cpus.features = Some(vec!["-hypervisor".to_owned()]);
+            assert!(invalid_config.validate().is_err());
+        }
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.cpus.affinity = Some(vec![CpuAffinity {
+            vcpu: 16,
+            host_cpus: vec![0],
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.cpus.affinity = Some(vec![
+            CpuAffinity {
+                vcpu: 1,
+                host_cpus: vec![0],
+            },
+            CpuAffinity {
+                vcpu: 1,
+                host_cpus: vec![1],
+            },
+        ]);
+        assert!(invalid_config.validate().is_err());
+
         let mut invalid_config = valid_config.clone();
         invalid_config.disks = Some(vec![DiskConfig {
             vhost_socket: Some("/path/to/sock".to_owned()),
@@ -2029,6 +6134,139 @@ mod tests {
         still_valid_config.memory.shared = true;
         assert!(still_valid_config.validate().is_ok());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            serial: Some("a-serial-longer-than-20-bytes".to_owned()),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            stripe_paths: vec![PathBuf::from("/path/to/image1")],
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].stripe_size = 1000;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].nvme = true;
+        assert!(invalid_config.validate().is_err());
+
+        let rate_limiter_config = Some(RateLimiterConfig {
+            bandwidth: None,
+            ops: Some(TokenBucketConfig {
+                size: 100,
+                refill_time: 1000,
+            }),
+        });
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            rate_limiter_config,
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].nvme = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0]
+            .rate_limiter_config
+            .as_mut()
+            .unwrap()
+            .ops
+            .as_mut()
+            .unwrap()
+            .refill_time = 0;
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            busy_poll: true,
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].busy_poll_us = 0;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].nvme = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            coalesce_max_requests: 16,
+            coalesce_max_usecs: 100,
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].coalesce_max_usecs = 0;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].nvme = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            vhost_user: true,
+            rate_limiter_config,
+            ..Default::default()
+        }]);
+        invalid_config.memory.shared = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            nvme: true,
+            ..Default::default()
+        }]);
+        invalid_config.memory.shared = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            iommu: true,
+            nvme: true,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            readonly: true,
+            ..Default::default()
+        }]);
+        invalid_config.memory.shared = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.pmem = Some(vec![PmemConfig {
+            file: PathBuf::from("/path/to/pmem"),
+            readonly: true,
+            discard_writes: true,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
         let mut invalid_config = valid_config.clone();
         invalid_config.net = Some(vec![NetConfig {
             vhost_user: true,
@@ -2044,6 +6282,29 @@ mod tests {
         still_valid_config.memory.shared = true;
         assert!(still_valid_config.validate().is_ok());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            coalesce_max_packets: 32,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            rx_starvation: RxStarvationMode::Block,
+            rx_starvation_timeout_ms: 0,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        // The failover primary must be one of the passthrough devices.
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            failover: Some("vf0".to_owned()),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
         let mut invalid_config = valid_config.clone();
         invalid_config.fs = Some(vec![FsConfig {
             ..Default::default()
@@ -2057,6 +6318,184 @@ mod tests {
         still_valid_config.memory.shared = true;
         assert!(still_valid_config.validate().is_ok());
 
+        let port = ConsolePortConfig {
+            name: String::from("org.test.0"),
+            mode: ConsolePortMode::Pty,
+            path: None,
+        };
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.console_ports = Some(vec![port.clone()]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console.mode = ConsoleOutputMode::Off;
+        invalid_config.console_ports = Some(vec![port.clone()]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console_ports = Some(vec![port.clone(), port.clone()]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console_ports = Some(
+            (0..virtio_devices::VIRTIO_CONSOLE_MAX_PORTS)
+                .map(|i| ConsolePortConfig {
+                    name: format!("org.test.{}", i),
+                    ..port.clone()
+                })
+                .collect(),
+        );
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console_ports = Some(vec![ConsolePortConfig {
+            name: String::from("org/test"),
+            ..port.clone()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console_ports = Some(vec![ConsolePortConfig {
+            mode: ConsolePortMode::Socket,
+            ..port
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            pci_segment: 1,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            num_pci_segments: 0,
+        });
+        assert!(invalid_config.validate().is_err());
+
+        #[cfg(all(feature = "pci_support", feature = "acpi", target_arch = "x86_64"))]
+        {
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.platform = Some(PlatformConfig {
+                num_pci_segments: 2,
+            });
+            still_valid_config.disks = Some(vec![DiskConfig {
+                path: Some(PathBuf::from("/path/to/image")),
+                pci_segment: 1,
+                ..Default::default()
+            }]);
+            assert!(still_valid_config.validate().is_ok());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.disks.as_mut().unwrap()[0].iommu = true;
+            assert!(invalid_config.validate().is_err());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.disks.as_mut().unwrap()[0].nvme = true;
+            assert!(invalid_config.validate().is_err());
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.platform = Some(PlatformConfig {
+                num_pci_segments: MAX_NUM_PCI_SEGMENTS + 1,
+            });
+            assert!(invalid_config.validate().is_err());
+        }
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.devices = Some(vec![DeviceConfig {
+            path: PathBuf::from("/path/to/device"),
+            iommu: false,
+            id: None,
+            pci_segment: 0,
+            mac: None,
+            vlan: Some(4095),
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        // Only an SR-IOV VF can be given a MAC address.
+        let mut invalid_config = valid_config.clone();
+        invalid_config.devices = Some(vec![DeviceConfig {
+            path: PathBuf::from("/path/to/device"),
+            iommu: false,
+            id: None,
+            pci_segment: 0,
+            mac: Some(MacAddr::parse_str("12:34:56:78:90:ab").unwrap()),
+            vlan: None,
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut numa_config = valid_config.clone();
+        numa_config.cpus.boot_vcpus = 4;
+        numa_config.cpus.max_vcpus = 4;
+        numa_config.numa = Some(vec![
+            NumaConfig::parse("guest_numa_id=0,cpus=0-1,distances=1@20:2@30")?,
+            NumaConfig::parse("guest_numa_id=1,cpus=2-3,distances=0@20:2@25")?,
+            NumaConfig::parse("guest_numa_id=2,distances=0@30:1@25")?,
+        ]);
+        numa_config.memory.zones = Some(vec![MemoryZoneConfig::parse(
+            "id=mem0,hotplug_size=1G,guest_numa_node=2",
+        )?]);
+        assert!(numa_config.validate().is_ok());
+
+        // Memory-less and CPU-less nodes are fine, empty ones aren't.
+        let mut invalid_config = numa_config.clone();
+        invalid_config.memory.zones = None;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = numa_config.clone();
+        invalid_config.numa.as_mut().unwrap().remove(0);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = numa_config.clone();
+        invalid_config.numa.as_mut().unwrap()[2].cpus = Some(vec![1]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = numa_config.clone();
+        invalid_config.numa.as_mut().unwrap()[2].cpus = Some(vec![4]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = numa_config.clone();
+        invalid_config.memory.zones.as_mut().unwrap()[0].guest_numa_node = Some(3);
+        assert!(invalid_config.validate().is_err());
+
+        // The distance matrix must be complete and symmetric.
+        let mut invalid_config = numa_config.clone();
+        invalid_config.numa.as_mut().unwrap()[2].distances = Some(vec![NumaDistance {
+            destination: 0,
+            distance: 30,
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = numa_config.clone();
+        invalid_config.numa.as_mut().unwrap()[1].distances = None;
+        assert!(invalid_config.validate().is_err());
+
+        let mut asymmetric_config = numa_config.clone();
+        asymmetric_config.numa.as_mut().unwrap()[1] =
+            NumaConfig::parse("guest_numa_id=1,cpus=2-3,distances=0@40:2@25")?;
+        assert!(asymmetric_config.validate().is_err());
+        asymmetric_config.numa.as_mut().unwrap()[1].asymmetric = true;
+        assert!(asymmetric_config.validate().is_ok());
+
+        let mut invalid_config = numa_config.clone();
+        invalid_config.numa.as_mut().unwrap()[0] =
+            NumaConfig::parse("guest_numa_id=0,cpus=0-1,distances=0@20:1@20:2@30")?;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = numa_config.clone();
+        invalid_config.numa.as_mut().unwrap()[0] =
+            NumaConfig::parse("guest_numa_id=0,cpus=0-1,distances=1@10:2@30")?;
+        assert!(invalid_config.validate().is_err());
+
+        // Without any distance, the guest picks its own defaults.
+        let mut valid_numa_config = numa_config;
+        for node in valid_numa_config.numa.as_mut().unwrap().iter_mut() {
+            node.distances = None;
+        }
+        assert!(valid_numa_config.validate().is_ok());
+
         Ok(())
     }
 }
@@ -0,0 +1,509 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Host endpoints of the additional virtio-console ports.
+//!
+//! Each port configured through `--console name=<name>,...` gets its own
+//! port on the virtio-console device, and its host side is either a file
+//! collecting the guest output, a pseudo terminal or a UNIX socket. For
+//! the last two, a dedicated thread relays the host input to the guest.
+//! The thread also propagates the size changes of a pseudo terminal to
+//! the guest, as the kernel doesn't notify the master side about them.
+//!
+//! The serial port and the virtio-console itself can be attached to a
+//! pseudo terminal as well, through a `ConsolePty`.
+
+use crate::config::{ConsolePortConfig, ConsolePortMode};
+use libc::EFD_NONBLOCK;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{result, thread};
+use virtio_devices::ConsoleInput;
+use vmm_sys_util::eventfd::EventFd;
+
+const KILL_EVENT: u64 = 0;
+const INPUT_EVENT: u64 = 1;
+const ACCEPT_EVENT: u64 = 2;
+
+// How often the size of a pseudo terminal port is checked, in milliseconds.
+const PTY_SIZE_POLL_INTERVAL: i32 = 250;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot create the port output file
+    CreateFile(io::Error),
+    /// Cannot allocate the port pseudo terminal
+    CreatePty(io::Error),
+    /// Cannot bind the port socket
+    BindSocket(io::Error),
+    /// Cannot add the port to the virtio-console device
+    AddPort(io::Error),
+    /// Cannot create the port kill EventFd
+    EventFd(io::Error),
+    /// Cannot spawn the port thread
+    SpawnThread(io::Error),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// Output of a pseudo terminal port. Nothing prevents the guest from
+// writing while no one reads the other side, in which case the output is
+// dropped rather than stalling the device.
+struct PtyOutput(File);
+
+impl Write for PtyOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.write(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+            r => r,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// Output of a socket port, sent to the connected client if any and
+// dropped otherwise.
+struct SocketOutput(Arc<Mutex<Option<UnixStream>>>);
+
+impl Write for SocketOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut client = self.0.lock().unwrap();
+        if let Some(stream) = client.as_mut() {
+            if let Err(e) = stream.write_all(buf) {
+                debug!("Console port client went away: {}", e);
+                *client = None;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum PortInput {
+    Pty {
+        master: File,
+        // Kept open so that the master doesn't hang up while no one has
+        // the pseudo terminal opened.
+        _slave: File,
+    },
+    Socket {
+        listener: UnixListener,
+        client: Arc<Mutex<Option<UnixStream>>>,
+    },
+}
+
+impl PortInput {
+    fn run(self, kill_evt: &EventFd, input: &ConsoleInput) -> io::Result<()> {
+        let epoll_fd = epoll::create(true)?;
+        // Safe because the fd was just created and is owned by this file
+        // from now on, which closes it on drop.
+        let _epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+
+        epoll_add(epoll_fd, kill_evt.as_raw_fd(), KILL_EVENT)?;
+        match &self {
+            PortInput::Pty { master, .. } => epoll_add(epoll_fd, master.as_raw_fd(), INPUT_EVENT)?,
+            PortInput::Socket { listener, .. } => {
+                epoll_add(epoll_fd, listener.as_raw_fd(), ACCEPT_EVENT)?
+            }
+        }
+
+        // Connected client of a socket port, reading its input.
+        let mut stream: Option<UnixStream> = None;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 3];
+        let mut buf = [0u8; 256];
+        // Last size of a pseudo terminal port reported to the guest.
+        let mut pty_size = None;
+        let timeout = match &self {
+            PortInput::Pty { .. } => PTY_SIZE_POLL_INTERVAL,
+            PortInput::Socket { .. } => -1,
+        };
+
+        loop {
+            if let PortInput::Pty { master, .. } = &self {
+                let size = window_size(master.as_raw_fd())?;
+                if pty_size != Some(size) {
+                    input.update_console_size(size.0, size.1);
+                    pty_size = Some(size);
+                }
+            }
+
+            let num_events = match epoll::wait(epoll_fd, timeout, &mut events[..]) {
+                Ok(num_events) => num_events,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for event in events.iter().take(num_events) {
+                let ev_type = event.data;
+                match ev_type {
+                    KILL_EVENT => return Ok(()),
+                    ACCEPT_EVENT => {
+                        if let PortInput::Socket { listener, client } = &self {
+                            let (new_stream, _) = listener.accept()?;
+                            // Only one client at a time, the newest one wins.
+                            if let Some(old) = stream.take() {
+                                epoll_del(epoll_fd, old.as_raw_fd())?;
+                            }
+                            *client.lock().unwrap() = Some(new_stream.try_clone()?);
+                            epoll_add(epoll_fd, new_stream.as_raw_fd(), INPUT_EVENT)?;
+                            stream = Some(new_stream);
+                        }
+                    }
+                    INPUT_EVENT => {
+                        let count = match &self {
+                            PortInput::Pty { master, .. } => (&*master).read(&mut buf),
+                            PortInput::Socket { .. } => match stream.as_mut() {
+                                Some(stream) => stream.read(&mut buf),
+                                None => continue,
+                            },
+                        };
+                        match count {
+                            Ok(count) if count > 0 => input.queue_input_bytes(&buf[..count]),
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                            // The client hung up.
+                            _ => {
+                                if let PortInput::Socket { client, .. } = &self {
+                                    if let Some(old) = stream.take() {
+                                        epoll_del(epoll_fd, old.as_raw_fd())?;
+                                    }
+                                    *client.lock().unwrap() = None;
+                                }
+                            }
+                        }
+                    }
+                    _ => error!("Unknown console port event {}", ev_type),
+                }
+            }
+        }
+    }
+}
+
+fn epoll_add(epoll_fd: RawFd, fd: RawFd, data: u64) -> io::Result<()> {
+    epoll::ctl(
+        epoll_fd,
+        epoll::ControlOptions::EPOLL_CTL_ADD,
+        fd,
+        epoll::Event::new(epoll::Events::EPOLLIN, data),
+    )
+}
+
+fn epoll_del(epoll_fd: RawFd, fd: RawFd) -> io::Result<()> {
+    epoll::ctl(
+        epoll_fd,
+        epoll::ControlOptions::EPOLL_CTL_DEL,
+        fd,
+        epoll::Event::new(epoll::Events::empty(), 0),
+    )
+}
+
+// Columns and rows of the terminal behind fd.
+fn window_size(fd: RawFd) -> io::Result<(u16, u16)> {
+    let mut ws = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // Safe because the kernel only writes a winsize structure to the
+    // pointer.
+    let ret = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((ws.ws_col, ws.ws_row))
+}
+
+// Allocate a pseudo terminal, returning its master and slave sides along
+// with the path the slave can be opened from.
+fn create_pty() -> io::Result<(File, File, PathBuf)> {
+    let master = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+        .open("/dev/ptmx")?;
+
+    let unlock: libc::c_int = 0;
+    // Safe because master is a pseudo terminal master and the kernel only
+    // reads an integer from the pointer.
+    let ret = unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSPTLCK as _, &unlock) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut pty_num: libc::c_uint = 0;
+    // Safe because master is a pseudo terminal master and the kernel only
+    // writes an integer to the pointer.
+    let ret = unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCGPTN as _, &mut pty_num) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let path = PathBuf::from(format!("/dev/pts/{}", pty_num));
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(&path)?;
+    // Safe because termios is only used once filled by tcgetattr(), and
+    // the kernel only reads and writes a termios structure.
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(slave.as_raw_fd(), &mut termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok((master, slave, path))
+}
+
+/// Host endpoint of a console port. The port thread, if any, is stopped
+/// when the endpoint is dropped.
+pub struct ConsolePortEndpoint {
+    kill_evt: EventFd,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ConsolePortEndpoint {
+    /// Create the host side of the port described by `config` and add the
+    /// port to `console`. The path of an allocated pseudo terminal is
+    /// stored back into `config`.
+    pub fn new(console: &virtio_devices::Console, config: &mut ConsolePortConfig) -> Result<Self> {
+        let (out, port_input): (Box<dyn Write + Send + Sync>, Option<PortInput>) = match config.mode
+        {
+            ConsolePortMode::File => {
+                let file =
+                    File::create(config.path.as_ref().unwrap()).map_err(Error::CreateFile)?;
+                (Box::new(file), None)
+            }
+            ConsolePortMode::Pty => {
+                let (master, slave, path) = create_pty().map_err(Error::CreatePty)?;
+                info!("Console port {} available at {:?}", config.name, path);
+                config.path = Some(path);
+                (
+                    Box::new(PtyOutput(master.try_clone().map_err(Error::CreatePty)?)),
+                    Some(PortInput::Pty {
+                        master,
+                        _slave: slave,
+                    }),
+                )
+            }
+            ConsolePortMode::Socket => {
+                let listener =
+                    UnixListener::bind(config.path.as_ref().unwrap()).map_err(Error::BindSocket)?;
+                let client = Arc::new(Mutex::new(None));
+                (
+                    Box::new(SocketOutput(client.clone())),
+                    Some(PortInput::Socket { listener, client }),
+                )
+            }
+        };
+
+        let input = console
+            .add_port(Some(config.name.clone()), out)
+            .map_err(Error::AddPort)?;
+
+        let kill_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let thread = if let Some(port_input) = port_input {
+            let thread_kill_evt = kill_evt.try_clone().map_err(Error::EventFd)?;
+            Some(
+                thread::Builder::new()
+                    .name(String::from("console_port"))
+                    .spawn(move || {
+                        if let Err(e) = port_input.run(&thread_kill_evt, &input) {
+                            error!("Error running console port thread: {}", e);
+                        }
+                    })
+                    .map_err(Error::SpawnThread)?,
+            )
+        } else {
+            None
+        };
+
+        Ok(ConsolePortEndpoint { kill_evt, thread })
+    }
+}
+
+impl Drop for ConsolePortEndpoint {
+    fn drop(&mut self) {
+        let _ = self.kill_evt.write(1);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Relay the input written to the slave side of a pseudo terminal.
+fn run_pty_input(
+    master: File,
+    kill_evt: EventFd,
+    mut input: Box<dyn FnMut(&[u8]) + Send>,
+) -> io::Result<()> {
+    let epoll_fd = epoll::create(true)?;
+    // Safe because the fd was just created and is owned by this file from
+    // now on, which closes it on drop.
+    let _epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+
+    epoll_add(epoll_fd, kill_evt.as_raw_fd(), KILL_EVENT)?;
+    epoll_add(epoll_fd, master.as_raw_fd(), INPUT_EVENT)?;
+
+    let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 2];
+    let mut buf = [0u8; 256];
+    loop {
+        let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+            Ok(num_events) => num_events,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        for event in events.iter().take(num_events) {
+            match event.data {
+                KILL_EVENT => return Ok(()),
+                INPUT_EVENT => match (&master).read(&mut buf) {
+                    Ok(count) if count > 0 => input(&buf[..count]),
+                    Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(e),
+                    _ => {}
+                },
+                ev_type => error!("Unknown console pty event {}", ev_type),
+            }
+        }
+    }
+}
+
+/// Pseudo terminal the serial port or the virtio-console is attached to.
+/// The input thread is stopped when the pseudo terminal is dropped.
+pub struct ConsolePty {
+    master: File,
+    // Kept open so that the master doesn't hang up while no one has the
+    // pseudo terminal opened.
+    _slave: File,
+    path: PathBuf,
+    kill_evt: EventFd,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ConsolePty {
+    pub fn new() -> Result<Self> {
+        let (master, slave, path) = create_pty().map_err(Error::CreatePty)?;
+        let kill_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+
+        Ok(ConsolePty {
+            master,
+            _slave: slave,
+            path,
+            kill_evt,
+            thread: None,
+        })
+    }
+
+    /// Path of the slave side, which users open to attach to the console.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writer for the guest output, to be handed to the device.
+    pub fn writer(&self) -> Result<Box<dyn Write + Send + Sync>> {
+        Ok(Box::new(PtyOutput(
+            self.master.try_clone().map_err(Error::CreatePty)?,
+        )))
+    }
+
+    /// Start relaying the pseudo terminal input to `input`.
+    pub fn start(&mut self, input: Box<dyn FnMut(&[u8]) + Send>) -> Result<()> {
+        if self.thread.is_some() {
+            return Ok(());
+        }
+        let master = self.master.try_clone().map_err(Error::CreatePty)?;
+        let kill_evt = self.kill_evt.try_clone().map_err(Error::EventFd)?;
+
+        self.thread = Some(
+            thread::Builder::new()
+                .name(String::from("console_pty"))
+                .spawn(move || {
+                    if let Err(e) = run_pty_input(master, kill_evt, input) {
+                        error!("Error running console pty thread: {}", e);
+                    }
+                })
+                .map_err(Error::SpawnThread)?,
+        );
+
+        Ok(())
+    }
+}
+
+impl Drop for ConsolePty {
+    fn drop(&mut self) {
+        let _ = self.kill_evt.write(1);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_console_pty() {
+        let mut pty = ConsolePty::new().unwrap();
+        assert!(pty.path().starts_with("/dev/pts"));
+
+        let (sender, receiver) = channel();
+        pty.start(Box::new(move |data: &[u8]| {
+            sender.send(data.to_vec()).unwrap();
+        }))
+        .unwrap();
+
+        let mut user = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(pty.path())
+            .unwrap();
+        user.write_all(b"ls\n").unwrap();
+        assert_eq!(receiver.recv().unwrap(), b"ls\n");
+
+        pty.writer().unwrap().write_all(b"guest").unwrap();
+        let mut buf = [0u8; 5];
+        user.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"guest");
+    }
+
+    #[test]
+    fn test_pty_window_size() {
+        let (master, slave, _) = create_pty().unwrap();
+
+        let ws = libc::winsize {
+            ws_row: 48,
+            ws_col: 160,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // Safe because slave is a terminal and the kernel only reads a
+        // winsize structure from the pointer.
+        let ret = unsafe { libc::ioctl(slave.as_raw_fd(), libc::TIOCSWINSZ, &ws) };
+        assert_eq!(ret, 0);
+
+        // Resizing the slave side is visible from the master side, which
+        // is what the port thread relies on.
+        assert_eq!(window_size(master.as_raw_fd()).unwrap(), (160, 48));
+    }
+}
@@ -0,0 +1,481 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Socket backend for the serial port and the virtio-console.
+//!
+//! The guest output is sent to the client connected to a UNIX or TCP
+//! listening socket, one client at a time, while the client input is
+//! injected into the guest. A new client replaces the current one, which
+//! lets external consoles reconnect at any time.
+//!
+//! Writing the guest output never blocks, as it can happen from the vCPU
+//! thread emulating the UART. While no client is connected, or when the
+//! client doesn't read fast enough, the output is buffered up to a fixed
+//! size, the oldest bytes being dropped first.
+
+use libc::EFD_NONBLOCK;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::{result, thread};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Amount of guest output kept while no client reads it.
+pub const DEFAULT_BUFFER_SIZE: usize = 64 << 10;
+
+const KILL_EVENT: u64 = 0;
+const ACCEPT_EVENT: u64 = 1;
+const CLIENT_EVENT: u64 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot bind the listening socket
+    Bind(io::Error),
+    /// Cannot create the kill EventFd
+    EventFd(io::Error),
+    /// Cannot spawn the socket thread
+    SpawnThread(io::Error),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Address the backend listens on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+pub(crate) enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    pub(crate) fn bind(endpoint: &Endpoint) -> io::Result<Self> {
+        Ok(match endpoint {
+            Endpoint::Unix(path) => Listener::Unix(UnixListener::bind(path)?),
+            Endpoint::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr)?),
+        })
+    }
+
+    pub(crate) fn accept(&self) -> io::Result<Stream> {
+        Ok(match self {
+            Listener::Unix(listener) => Stream::Unix(listener.accept()?.0),
+            Listener::Tcp(listener) => Stream::Tcp(listener.accept()?.0),
+        })
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Unix(listener) => listener.as_raw_fd(),
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+pub(crate) enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Stream::Unix(stream) => Stream::Unix(stream.try_clone()?),
+            Stream::Tcp(stream) => Stream::Tcp(stream.try_clone()?),
+        })
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Unix(stream) => stream.as_raw_fd(),
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+// State shared between the guest output writer and the socket thread.
+struct Output {
+    client: Option<Stream>,
+    buffer: VecDeque<u8>,
+    buffer_size: usize,
+}
+
+impl Output {
+    fn push(&mut self, data: &[u8]) {
+        let data = &data[data.len().saturating_sub(self.buffer_size)..];
+        let excess = (self.buffer.len() + data.len()).saturating_sub(self.buffer_size);
+        self.buffer.drain(..excess);
+        self.buffer.extend(data);
+    }
+
+    // Send as much of the buffered output as the client accepts without
+    // blocking. The output stays buffered if the client went away.
+    fn flush(&mut self) {
+        while !self.buffer.is_empty() {
+            let client = match self.client.as_mut() {
+                Some(client) => client,
+                None => return,
+            };
+            match client.write(self.buffer.as_slices().0) {
+                Ok(0) => self.client = None,
+                Ok(count) => {
+                    self.buffer.drain(..count);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!("Console socket client went away: {}", e);
+                    self.client = None;
+                }
+            }
+        }
+    }
+}
+
+/// Guest output writer of a `ConsoleSocket`.
+pub struct ConsoleSocketWriter(Arc<Mutex<Output>>);
+
+impl Write for ConsoleSocketWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut output = self.0.lock().unwrap();
+        output.push(buf);
+        output.flush();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Listening socket the serial port or the virtio-console is attached to.
+/// The socket thread is stopped, and the UNIX socket removed, when the
+/// backend is dropped.
+pub struct ConsoleSocket {
+    endpoint: Endpoint,
+    output: Arc<Mutex<Output>>,
+    listener: Option<Listener>,
+    kill_evt: EventFd,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ConsoleSocket {
+    pub fn new(endpoint: Endpoint, buffer_size: usize) -> Result<Self> {
+        let listener = Listener::bind(&endpoint).map_err(Error::Bind)?;
+        let kill_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+
+        Ok(ConsoleSocket {
+            endpoint,
+            output: Arc::new(Mutex::new(Output {
+                client: None,
+                buffer: VecDeque::new(),
+                buffer_size,
+            })),
+            listener: Some(listener),
+            kill_evt,
+            thread: None,
+        })
+    }
+
+    /// Writer for the guest output, to be handed to the device.
+    pub fn writer(&self) -> ConsoleSocketWriter {
+        ConsoleSocketWriter(self.output.clone())
+    }
+
+    /// Start accepting clients, passing their input to `input`.
+    pub fn start(&mut self, input: Box<dyn FnMut(&[u8]) + Send>) -> Result<()> {
+        let listener = match self.listener.take() {
+            Some(listener) => listener,
+            None => return Ok(()),
+        };
+        let output = self.output.clone();
+        let kill_evt = self.kill_evt.try_clone().map_err(Error::EventFd)?;
+
+        self.thread = Some(
+            thread::Builder::new()
+                .name(String::from("console_socket"))
+                .spawn(move || {
+                    if let Err(e) = run(listener, output, kill_evt, input) {
+                        error!("Error running console socket thread: {}", e);
+                    }
+                })
+                .map_err(Error::SpawnThread)?,
+        );
+
+        Ok(())
+    }
+}
+
+impl Drop for ConsoleSocket {
+    fn drop(&mut self) {
+        let _ = self.kill_evt.write(1);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        if let Endpoint::Unix(path) = &self.endpoint {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn epoll_ctl(
+    epoll_fd: RawFd,
+    op: epoll::ControlOptions,
+    fd: RawFd,
+    events: epoll::Events,
+    data: u64,
+) -> io::Result<()> {
+    epoll::ctl(epoll_fd, op, fd, epoll::Event::new(events, data))
+}
+
+fn run(
+    listener: Listener,
+    output: Arc<Mutex<Output>>,
+    kill_evt: EventFd,
+    mut input: Box<dyn FnMut(&[u8]) + Send>,
+) -> io::Result<()> {
+    let epoll_fd = epoll::create(true)?;
+    // Safe because the fd was just created and is owned by this file from
+    // now on, which closes it on drop.
+    let _epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+
+    let add = epoll::ControlOptions::EPOLL_CTL_ADD;
+    epoll_ctl(
+        epoll_fd,
+        add,
+        kill_evt.as_raw_fd(),
+        epoll::Events::EPOLLIN,
+        KILL_EVENT,
+    )?;
+    epoll_ctl(
+        epoll_fd,
+        add,
+        listener.as_raw_fd(),
+        epoll::Events::EPOLLIN,
+        ACCEPT_EVENT,
+    )?;
+
+    // The client is read from this thread, through its own handle.
+    let mut reader: Option<Stream> = None;
+    let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 3];
+    let mut buf = [0u8; 256];
+
+    loop {
+        let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+            Ok(num_events) => num_events,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        for event in events.iter().take(num_events) {
+            let evset = epoll::Events::from_bits_truncate(event.events);
+            let ev_type = event.data;
+            match ev_type {
+                KILL_EVENT => return Ok(()),
+                ACCEPT_EVENT => {
+                    let stream = listener.accept()?;
+                    stream.set_nonblocking(true)?;
+                    if let Some(old) = reader.take() {
+                        epoll::ctl(
+                            epoll_fd,
+                            epoll::ControlOptions::EPOLL_CTL_DEL,
+                            old.as_raw_fd(),
+                            epoll::Event::new(epoll::Events::empty(), 0),
+                        )?;
+                    }
+                    // Edge triggered, so that the buffered output is only
+                    // flushed once the client can take more of it.
+                    epoll_ctl(
+                        epoll_fd,
+                        add,
+                        stream.as_raw_fd(),
+                        epoll::Events::EPOLLIN | epoll::Events::EPOLLOUT | epoll::Events::EPOLLET,
+                        CLIENT_EVENT,
+                    )?;
+                    let mut output = output.lock().unwrap();
+                    output.client = Some(stream.try_clone()?);
+                    output.flush();
+                    reader = Some(stream);
+                }
+                CLIENT_EVENT => {
+                    if evset.contains(epoll::Events::EPOLLOUT) {
+                        output.lock().unwrap().flush();
+                    }
+                    if !evset.intersects(
+                        epoll::Events::EPOLLIN | epoll::Events::EPOLLHUP | epoll::Events::EPOLLERR,
+                    ) {
+                        continue;
+                    }
+
+                    let mut hung_up = false;
+                    if let Some(stream) = reader.as_mut() {
+                        loop {
+                            match stream.read(&mut buf) {
+                                Ok(0) => {
+                                    hung_up = true;
+                                    break;
+                                }
+                                Ok(count) => input(&buf[..count]),
+                                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                                Err(_) => {
+                                    hung_up = true;
+                                    break;
+                                }
+                            }
+                        }
+                    }
+
+                    if hung_up {
+                        if let Some(old) = reader.take() {
+                            epoll::ctl(
+                                epoll_fd,
+                                epoll::ControlOptions::EPOLL_CTL_DEL,
+                                old.as_raw_fd(),
+                                epoll::Event::new(epoll::Events::empty(), 0),
+                            )?;
+                        }
+                        output.lock().unwrap().client = None;
+                    }
+                }
+                _ => error!("Unknown console socket event {}", ev_type),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn connect(path: &PathBuf) -> UnixStream {
+        let stream = UnixStream::connect(path).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+
+    fn read_exact(stream: &mut UnixStream, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        stream.read_exact(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_console_socket_reconnect() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("console.sock");
+        let (input_tx, input_rx) = std::sync::mpsc::channel();
+
+        let mut socket = ConsoleSocket::new(Endpoint::Unix(path.clone()), 16).unwrap();
+        let mut writer = socket.writer();
+        socket
+            .start(Box::new(move |data: &[u8]| {
+                input_tx.send(data.to_vec()).unwrap()
+            }))
+            .unwrap();
+
+        // Output is kept until a client connects.
+        writer.write_all(b"early boot").unwrap();
+        let mut client = connect(&path);
+        assert_eq!(read_exact(&mut client, 10), b"early boot");
+
+        writer.write_all(b"login:").unwrap();
+        assert_eq!(read_exact(&mut client, 6), b"login:");
+
+        client.write_all(b"root\n").unwrap();
+        assert_eq!(
+            input_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            b"root\n"
+        );
+
+        // Nothing is lost while the client is away.
+        drop(client);
+        writer.write_all(b"while away").unwrap();
+        let mut client = connect(&path);
+        assert_eq!(read_exact(&mut client, 10), b"while away");
+
+        // A new client replaces the current one, once its input shows it
+        // has been accepted.
+        let mut new_client = connect(&path);
+        new_client.write_all(b"\n").unwrap();
+        assert_eq!(
+            input_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            b"\n"
+        );
+        writer.write_all(b"new").unwrap();
+        assert_eq!(read_exact(&mut new_client, 3), b"new");
+        drop(client);
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_console_socket_buffer_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("console.sock");
+
+        let mut socket = ConsoleSocket::new(Endpoint::Unix(path.clone()), 8).unwrap();
+        let mut writer = socket.writer();
+        socket.start(Box::new(|_: &[u8]| {})).unwrap();
+
+        // Only the most recent output fits in the buffer.
+        writer.write_all(b"0123").unwrap();
+        writer.write_all(b"456789").unwrap();
+        let mut client = connect(&path);
+        assert_eq!(read_exact(&mut client, 8), b"23456789");
+
+        drop(client);
+        writer.write_all(b"a much longer line").unwrap();
+        let mut client = connect(&path);
+        assert_eq!(read_exact(&mut client, 8), b"ger line");
+    }
+}
@@ -12,8 +12,8 @@
 //
 
 #[cfg(target_arch = "x86_64")]
-use crate::config::CpuTopology;
 use crate::config::CpusConfig;
+use crate::config::{CpuAffinity, CpuTopology};
 use crate::device_manager::DeviceManager;
 use crate::memory_manager::MemoryManager;
 use crate::CPU_MANAGER_SNAPSHOT_ID;
@@ -23,22 +23,30 @@ use anyhow::anyhow;
 #[cfg(feature = "acpi")]
 use arch::layout;
 #[cfg(target_arch = "x86_64")]
+use arch::x86_64::cpu_features::{self, CpuFeature};
+#[cfg(target_arch = "x86_64")]
 use arch::x86_64::SgxEpcSection;
 use arch::EntryPoint;
 #[cfg(target_arch = "x86_64")]
 use arch::{CpuidPatch, CpuidReg};
 use devices::{interrupt_controller::InterruptController, BusDevice};
 #[cfg(target_arch = "x86_64")]
+use hypervisor::arch::x86::{HwBreakpoint, NestedStateFormat};
+#[cfg(target_arch = "x86_64")]
+use hypervisor::x86_64::{SpecialRegisters, StandardRegisters};
+#[cfg(target_arch = "x86_64")]
 use hypervisor::CpuId;
 use hypervisor::{CpuState, VmExit};
 
 use libc::{c_void, siginfo_t};
 
 #[cfg(target_arch = "x86_64")]
+use std::collections::BTreeMap;
 use std::fmt;
 use std::os::unix::thread::JoinHandleExt;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
+use std::time::{Duration, Instant};
 use std::{cmp, io, result, thread};
 #[cfg(target_arch = "x86_64")]
 use vm_memory::GuestAddress;
@@ -55,6 +63,10 @@ use vmm_sys_util::signal::{register_signal_handler, SIGRTMIN};
 const TSC_DEADLINE_TIMER_ECX_BIT: u8 = 24; // tsc deadline timer ecx bit.
 #[cfg(target_arch = "x86_64")]
 const HYPERVISOR_ECX_BIT: u8 = 31; // Hypervisor ecx bit.
+#[cfg(target_arch = "x86_64")]
+const VMX_ECX_BIT: u8 = 5; // Intel VMX ecx bit.
+#[cfg(target_arch = "x86_64")]
+const SVM_ECX_BIT: u8 = 2; // AMD SVM ecx bit, in the extended leaf.
 
 // Debug I/O port
 #[cfg(target_arch = "x86_64")]
@@ -62,6 +74,14 @@ const DEBUG_IOPORT: u16 = 0x80;
 #[cfg(target_arch = "x86_64")]
 const DEBUG_IOPORT_PREFIX: &str = "Debug I/O port";
 
+// Time the throttled vCPUs run for before sleeping, also the period they
+// are kicked out of the guest at.
+const THROTTLE_TIMESLICE: Duration = Duration::from_millis(10);
+
+/// Maximum percentage of the time the vCPUs can be kept out of the guest
+/// for.
+pub const MAX_THROTTLE: u8 = 99;
+
 #[cfg(target_arch = "x86_64")]
 /// Debug I/O port, see:
 /// https://www.intel.com/content/www/us/en/support/articles/000005500/boards-and-kits.html
@@ -193,9 +213,174 @@ pub enum Error {
 
     /// Error resuming vCPU on shutdown
     ResumeOnShutdown(MigratableError),
+
+    #[cfg(target_arch = "x86_64")]
+    /// Failed to set the vCPU guest debug state.
+    VcpuSetGuestDebug(anyhow::Error),
+
+    #[cfg(target_arch = "x86_64")]
+    /// Invalid vCPU index.
+    InvalidVcpu(u8),
+
+    #[cfg(target_arch = "x86_64")]
+    /// Failed to map the dirty ring of a vCPU plugged back.
+    VcpuAddDirtyRing(anyhow::Error),
+
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot create or clone an EventFd.
+    EventFd(io::Error),
+
+    #[cfg(target_arch = "aarch64")]
+    /// Changing the number of vCPUs of a running VM is not supported.
+    HotplugNotSupported,
+
+    /// Cannot read the topology of a host CPU.
+    HostCpuTopology(usize, io::Error),
+
+    /// Threads of a guest core pinned onto host CPUs which aren't siblings.
+    StrictAffinity(u8, usize),
+
+    #[cfg(target_arch = "x86_64")]
+    /// Invalid CPU model or features.
+    CpuFeatures(arch::x86_64::cpu_features::Error),
+
+    #[cfg(target_arch = "x86_64")]
+    /// CPU features exposed by the VM this one is restored from which the
+    /// host doesn't support.
+    MissingCpuFeatures(Vec<String>),
+
+    #[cfg(target_arch = "x86_64")]
+    /// The host can't run nested guests, or save their state.
+    NestedNotSupported,
 }
 pub type Result<T> = result::Result<T, Error>;
 
+/// Feature registers of a CPUID leaf exposed to the guest, as saved along
+/// with the VM.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct CpuidFeatureLeaf {
+    pub function: u32,
+    pub index: u32,
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl From<&CpuidFeatureLeaf> for hypervisor::CpuIdEntry {
+    fn from(leaf: &CpuidFeatureLeaf) -> Self {
+        hypervisor::CpuIdEntry {
+            function: leaf.function,
+            index: leaf.index,
+            eax: leaf.eax,
+            ebx: leaf.ebx,
+            ecx: leaf.ecx,
+            edx: leaf.edx,
+            ..Default::default()
+        }
+    }
+}
+
+// Host CPUs listed in the cpulist format of sysfs, such as "0-3,8".
+fn parse_cpu_list(list: &str) -> io::Result<Vec<usize>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, list.to_owned());
+    let mut cpus = Vec::new();
+
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let first: usize = bounds
+            .next()
+            .and_then(|c| c.parse().ok())
+            .ok_or_else(invalid)?;
+        let last: usize = match bounds.next() {
+            Some(c) => c.parse().map_err(|_| invalid())?,
+            None => first,
+        };
+        cpus.extend(first..=last);
+    }
+
+    Ok(cpus)
+}
+
+fn host_thread_siblings(cpu: usize) -> io::Result<Vec<usize>> {
+    let path = format!(
+        "/sys/devices/system/cpu/cpu{}/topology/thread_siblings_list",
+        cpu
+    );
+    parse_cpu_list(&std::fs::read_to_string(path)?)
+}
+
+// Check that the threads of each guest core are pinned onto host CPUs which
+// are thread siblings of each other, so that the guest scheduler assumptions
+// about which vCPUs share a core hold on the host.
+fn check_strict_affinity<F>(
+    topology: &CpuTopology,
+    affinity: &[CpuAffinity],
+    thread_siblings: F,
+) -> Result<()>
+where
+    F: Fn(usize) -> io::Result<Vec<usize>>,
+{
+    let mut cores: BTreeMap<u8, Vec<usize>> = BTreeMap::new();
+    for a in affinity.iter() {
+        cores
+            .entry(a.vcpu / topology.threads_per_core)
+            .or_insert_with(Vec::new)
+            .extend(a.host_cpus.iter());
+    }
+
+    for host_cpus in cores.values() {
+        let siblings =
+            thread_siblings(host_cpus[0]).map_err(|e| Error::HostCpuTopology(host_cpus[0], e))?;
+        if let Some(host_cpu) = host_cpus.iter().find(|c| !siblings.contains(c)) {
+            let vcpu = affinity
+                .iter()
+                .find(|a| a.host_cpus.contains(host_cpu))
+                .map(|a| a.vcpu)
+                .unwrap();
+            return Err(Error::StrictAffinity(vcpu, *host_cpu));
+        }
+    }
+
+    Ok(())
+}
+
+// The guest boots from a device tree, which can't tell it about the vCPUs
+// coming and going, as opposed to ACPI. Resizing is only accepted when it
+// leaves the number of vCPUs unchanged.
+#[cfg(target_arch = "aarch64")]
+fn check_resize(desired_vcpus: u8, present_vcpus: u8) -> Result<()> {
+    if desired_vcpus != present_vcpus {
+        return Err(Error::HotplugNotSupported);
+    }
+
+    Ok(())
+}
+
+// Restrict the calling thread to run on `host_cpus`.
+fn set_thread_affinity(host_cpus: &[usize]) -> io::Result<()> {
+    // Safe because cpu_set_t is a plain bitmap.
+    let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in host_cpus.iter() {
+        if *cpu >= libc::CPU_SETSIZE as usize {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        // Safe because the CPU was checked to fit in the bitmap.
+        unsafe { libc::CPU_SET(*cpu, &mut cpuset) };
+    }
+
+    // Safe because the kernel only reads a cpu_set_t from the pointer.
+    let ret =
+        unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &cpuset) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
 #[cfg(feature = "acpi")]
 #[repr(packed)]
 struct LocalAPIC {
@@ -228,11 +413,26 @@ struct InterruptSourceOverride {
     pub flags: u16,
 }
 
+/// What the vCPU thread should do after a call to `Vcpu::run()`.
+#[derive(Debug, PartialEq)]
+pub enum VcpuRunState {
+    /// Keep running the vCPU.
+    Continue,
+    /// The guest asked for a reset, or triple-faulted.
+    Reset,
+    #[cfg(target_arch = "x86_64")]
+    /// The vCPU hit a hardware breakpoint, whose index is given, or
+    /// completed a single step.
+    Debug(Option<usize>),
+}
+
 /// A wrapper around creating and using a kvm-based VCPU.
 pub struct Vcpu {
     // The hypervisor abstracted CPU.
     vcpu: Arc<dyn hypervisor::Vcpu>,
     id: u8,
+    #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
+    apic_id: u8,
     #[cfg(target_arch = "x86_64")]
     io_bus: Arc<devices::Bus>,
     mmio_bus: Arc<devices::Bus>,
@@ -251,9 +451,12 @@ impl Vcpu {
     /// # Arguments
     ///
     /// * `id` - Represents the CPU number between [0, max vcpus).
+    /// * `apic_id` - The id the vCPU is created with in the hypervisor. On x86_64
+    ///               this is the APIC id derived from the CPU topology.
     /// * `vm` - The virtual machine this vcpu will get attached to.
     pub fn new(
         id: u8,
+        apic_id: u8,
         vm: &Arc<dyn hypervisor::Vm>,
         #[cfg(target_arch = "x86_64")] io_bus: Arc<devices::Bus>,
         mmio_bus: Arc<devices::Bus>,
@@ -261,12 +464,13 @@ impl Vcpu {
         creation_ts: std::time::Instant,
     ) -> Result<Arc<Mutex<Self>>> {
         let vcpu = vm
-            .create_vcpu(id)
+            .create_vcpu(apic_id)
             .map_err(|e| Error::VcpuCreate(e.into()))?;
         // Initially the cpuid per vCPU is the one supported by this VM.
         Ok(Arc::new(Mutex::new(Vcpu {
             vcpu,
             id,
+            apic_id,
             #[cfg(target_arch = "x86_64")]
             io_bus,
             mmio_bus,
@@ -300,8 +504,14 @@ impl Vcpu {
         }
 
         #[cfg(target_arch = "x86_64")]
-        arch::configure_vcpu(&self.vcpu, self.id, kernel_entry_point, vm_memory, cpuid)
-            .map_err(Error::VcpuConfiguration)?;
+        arch::configure_vcpu(
+            &self.vcpu,
+            self.apic_id,
+            kernel_entry_point,
+            vm_memory,
+            cpuid,
+        )
+        .map_err(Error::VcpuConfiguration)?;
 
         Ok(())
     }
@@ -316,13 +526,13 @@ impl Vcpu {
     ///
     /// Note that the state of the VCPU and associated VM must be setup first for this to do
     /// anything useful.
-    pub fn run(&self) -> Result<bool> {
+    pub fn run(&self) -> Result<VcpuRunState> {
         match self.vcpu.run() {
             Ok(run) => match run {
                 #[cfg(target_arch = "x86_64")]
                 VmExit::IoIn(addr, data) => {
                     self.io_bus.read(u64::from(addr), data);
-                    Ok(true)
+                    Ok(VcpuRunState::Continue)
                 }
                 #[cfg(target_arch = "x86_64")]
                 VmExit::IoOut(addr, data) => {
@@ -330,15 +540,15 @@ impl Vcpu {
                         self.log_debug_ioport(data[0]);
                     }
                     self.io_bus.write(u64::from(addr), data);
-                    Ok(true)
+                    Ok(VcpuRunState::Continue)
                 }
                 VmExit::MmioRead(addr, data) => {
                     self.mmio_bus.read(addr as u64, data);
-                    Ok(true)
+                    Ok(VcpuRunState::Continue)
                 }
                 VmExit::MmioWrite(addr, data) => {
                     self.mmio_bus.write(addr as u64, data);
-                    Ok(true)
+                    Ok(VcpuRunState::Continue)
                 }
                 #[cfg(target_arch = "x86_64")]
                 VmExit::IoapicEoi(vector) => {
@@ -348,11 +558,13 @@ impl Vcpu {
                             .unwrap()
                             .end_of_interrupt(vector);
                     }
-                    Ok(true)
+                    Ok(VcpuRunState::Continue)
                 }
+                #[cfg(target_arch = "x86_64")]
+                VmExit::Debug(breakpoint) => Ok(VcpuRunState::Debug(breakpoint)),
 
-                VmExit::Ignore => Ok(true),
-                VmExit::Reset => Ok(false),
+                VmExit::Ignore => Ok(VcpuRunState::Continue),
+                VmExit::Reset => Ok(VcpuRunState::Reset),
             },
 
             Err(e) => Err(Error::VcpuRun(e.into())),
@@ -451,15 +663,22 @@ pub struct CpuManager {
     vm_memory: GuestMemoryAtomic<GuestMemoryMmap>,
     #[cfg(target_arch = "x86_64")]
     cpuid: CpuId,
+    #[cfg(target_arch = "x86_64")]
+    nested_state_format: Option<NestedStateFormat>,
     #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
     vm: Arc<dyn hypervisor::Vm>,
     vcpus_kill_signalled: Arc<AtomicBool>,
     vcpus_pause_signalled: Arc<AtomicBool>,
     #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
     reset_evt: EventFd,
+    #[cfg(target_arch = "x86_64")]
+    debug_evt: EventFd,
     vcpu_states: Vec<VcpuState>,
     selected_cpu: u8,
     vcpus: Vec<Arc<Mutex<Vcpu>>>,
+    // Percentage of the time the vCPUs are kept out of the guest for.
+    vcpus_throttle: Arc<AtomicU8>,
+    throttle_kicker: Option<ThrottleKicker>,
 }
 
 const CPU_ENABLE_FLAG: usize = 0;
@@ -538,6 +757,12 @@ struct VcpuState {
     handle: Option<thread::JoinHandle<()>>,
     kill: Arc<AtomicBool>,
     vcpu_run_interrupted: Arc<AtomicBool>,
+    // Set by the vCPU thread when it stops on a breakpoint or a single step.
+    #[cfg(target_arch = "x86_64")]
+    debug_stopped: Arc<AtomicBool>,
+    // The hardware breakpoint it stopped on, if any.
+    #[cfg(target_arch = "x86_64")]
+    debug_breakpoint: Arc<Mutex<Option<usize>>>,
 }
 
 impl VcpuState {
@@ -577,6 +802,32 @@ impl VcpuState {
     }
 }
 
+// Thread kicking the vCPUs out of the guest at every time slice while they
+// are throttled, for them to notice they have to sleep.
+struct ThrottleKicker {
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+// Time a vCPU throttled by `percent` sleeps for after each time slice it
+// ran for.
+fn throttle_sleep(percent: u8) -> Duration {
+    let percent = u32::from(cmp::min(percent, MAX_THROTTLE));
+    THROTTLE_TIMESLICE * percent / (100 - percent)
+}
+
+// Sleep for `duration` by time slices, waking up early once `interrupted`.
+fn throttle_vcpu<F: Fn() -> bool>(duration: Duration, interrupted: F) {
+    let wake = Instant::now() + duration;
+    loop {
+        let remaining = wake.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) || interrupted() {
+            break;
+        }
+        thread::sleep(cmp::min(remaining, THROTTLE_TIMESLICE));
+    }
+}
+
 impl CpuManager {
     #[allow(unused_variables)]
     pub fn new(
@@ -599,7 +850,32 @@ impl CpuManager {
                 None
             };
         #[cfg(target_arch = "x86_64")]
-        let cpuid = CpuManager::patch_cpuid(hypervisor, &config.topology, sgx_epc_sections)?;
+        let masked_features = cpu_features::masked_features(
+            config.model.as_deref(),
+            config.features.as_deref().unwrap_or(&[]),
+        )
+        .map_err(Error::CpuFeatures)?;
+        #[cfg(target_arch = "x86_64")]
+        let nested_state_format = if config.nested {
+            hypervisor.nested_state_format()
+        } else {
+            None
+        };
+        #[cfg(target_arch = "x86_64")]
+        let cpuid = CpuManager::patch_cpuid(
+            hypervisor,
+            &config.effective_topology(),
+            sgx_epc_sections,
+            &masked_features,
+            config.frequency(),
+            config.nested,
+        )?;
+
+        if config.strict_affinity {
+            if let (Some(topology), Some(affinity)) = (&config.topology, &config.affinity) {
+                check_strict_affinity(topology, affinity, host_thread_siblings)?;
+            }
+        }
 
         let device_manager = device_manager.lock().unwrap();
         let cpu_manager = Arc::new(Mutex::new(CpuManager {
@@ -611,13 +887,19 @@ impl CpuManager {
             vm_memory: guest_memory,
             #[cfg(target_arch = "x86_64")]
             cpuid,
+            #[cfg(target_arch = "x86_64")]
+            nested_state_format,
             vm,
             vcpus_kill_signalled: Arc::new(AtomicBool::new(false)),
             vcpus_pause_signalled: Arc::new(AtomicBool::new(false)),
             vcpu_states,
             reset_evt,
+            #[cfg(target_arch = "x86_64")]
+            debug_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
             selected_cpu: 0,
             vcpus: Vec::with_capacity(usize::from(config.max_vcpus)),
+            vcpus_throttle: Arc::new(AtomicU8::new(0)),
+            throttle_kicker: None,
         }));
 
         #[cfg(target_arch = "x86_64")]
@@ -642,8 +924,11 @@ impl CpuManager {
     #[cfg(target_arch = "x86_64")]
     fn patch_cpuid(
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
-        topology: &Option<CpuTopology>,
+        topology: &CpuTopology,
         sgx_epc_sections: Option<Vec<SgxEpcSection>>,
+        masked_features: &[&CpuFeature],
+        frequency: Option<(u16, u16)>,
+        nested: bool,
     ) -> Result<CpuId> {
         let mut cpuid_patches = Vec::new();
 
@@ -675,20 +960,41 @@ impl CpuManager {
             .map_err(|e| Error::PatchCpuId(e.into()))?;
 
         CpuidPatch::patch_cpuid(&mut cpuid, cpuid_patches);
+        cpu_features::mask_cpuid(&mut cpuid, masked_features);
 
-        if let Some(t) = topology {
-            arch::x86_64::update_cpuid_topology(
-                &mut cpuid,
-                t.threads_per_core,
-                t.cores_per_die,
-                t.dies_per_package,
-            );
+        // The virtualization extensions are only exposed on demand, as the
+        // state of the nested guests is part of the snapshots from then on.
+        if nested {
+            if hypervisor.nested_state_format().is_none() {
+                return Err(Error::NestedNotSupported);
+            }
+        } else {
+            for entry in cpuid.as_mut_slice().iter_mut() {
+                match entry.function {
+                    1 => entry.ecx &= !(1 << VMX_ECX_BIT),
+                    0x8000_0001 => entry.ecx &= !(1 << SVM_ECX_BIT),
+                    _ => {}
+                }
+            }
         }
 
+        // Always override the topology, as the one of the host would leak
+        // otherwise.
+        arch::x86_64::update_cpuid_topology(
+            &mut cpuid,
+            topology.threads_per_core,
+            topology.cores_per_die,
+            topology.dies_per_package,
+        );
+
         if let Some(sgx_epc_sections) = sgx_epc_sections {
             arch::x86_64::update_cpuid_sgx(&mut cpuid, sgx_epc_sections).unwrap();
         }
 
+        if let Some((base_mhz, max_mhz)) = frequency {
+            arch::x86_64::update_cpuid_frequency(&mut cpuid, base_mhz, max_mhz);
+        }
+
         Ok(cpuid)
     }
 
@@ -708,8 +1014,14 @@ impl CpuManager {
 
         let creation_ts = std::time::Instant::now();
 
+        #[cfg(target_arch = "x86_64")]
+        let apic_id = self.apic_id(cpu_id);
+        #[cfg(target_arch = "aarch64")]
+        let apic_id = cpu_id;
+
         let vcpu = Vcpu::new(
             cpu_id,
+            apic_id,
             &self.vm,
             #[cfg(target_arch = "x86_64")]
             self.io_bus.clone(),
@@ -722,8 +1034,14 @@ impl CpuManager {
             #[cfg(target_arch = "x86_64")]
             {
                 let mut cpuid = self.cpuid.clone();
-                CpuidPatch::set_cpuid_reg(&mut cpuid, 0xb, None, CpuidReg::EDX, u32::from(cpu_id));
-                CpuidPatch::set_cpuid_reg(&mut cpuid, 0x1f, None, CpuidReg::EDX, u32::from(cpu_id));
+                CpuidPatch::set_cpuid_reg(&mut cpuid, 0xb, None, CpuidReg::EDX, u32::from(apic_id));
+                CpuidPatch::set_cpuid_reg(
+                    &mut cpuid,
+                    0x1f,
+                    None,
+                    CpuidReg::EDX,
+                    u32::from(apic_id),
+                );
 
                 vcpu.lock()
                     .unwrap()
@@ -790,10 +1108,26 @@ impl CpuManager {
         let vcpu_kill_signalled = self.vcpus_kill_signalled.clone();
         let vcpu_pause_signalled = self.vcpus_pause_signalled.clone();
 
+        let vcpu_throttle = self.vcpus_throttle.clone();
+
         let vcpu_kill = self.vcpu_states[usize::from(cpu_id)].kill.clone();
         let vcpu_run_interrupted = self.vcpu_states[usize::from(cpu_id)]
             .vcpu_run_interrupted
             .clone();
+        #[cfg(target_arch = "x86_64")]
+        let debug_evt = self.debug_evt.try_clone().unwrap();
+        #[cfg(target_arch = "x86_64")]
+        let vcpu_debug_stopped = self.vcpu_states[usize::from(cpu_id)].debug_stopped.clone();
+        #[cfg(target_arch = "x86_64")]
+        let vcpu_debug_breakpoint = self.vcpu_states[usize::from(cpu_id)]
+            .debug_breakpoint
+            .clone();
+        let host_cpus = self.config.affinity.as_ref().and_then(|affinity| {
+            affinity
+                .iter()
+                .find(|a| a.vcpu == cpu_id)
+                .map(|a| a.host_cpus.clone())
+        });
 
         info!("Starting vCPU: cpu_id = {}", cpu_id);
 
@@ -806,9 +1140,16 @@ impl CpuManager {
                     register_signal_handler(SIGRTMIN(), handle_signal)
                         .expect("Failed to register vcpu signal handler");
 
+                    if let Some(host_cpus) = host_cpus {
+                        if let Err(e) = set_thread_affinity(&host_cpus) {
+                            error!("Failed to set the affinity of vCPU {}: {}", cpu_id, e);
+                        }
+                    }
+
                     // Block until all CPUs are ready.
                     vcpu_thread_barrier.wait();
 
+                    let mut timeslice_start = Instant::now();
                     loop {
                         // If we are being told to pause, we park the thread
                         // until the pause boolean is toggled.
@@ -833,18 +1174,27 @@ impl CpuManager {
                             break;
                         }
 
-                        // vcpu.run() returns false on a triple-fault so trigger a reset
+                        // vcpu.run() returns Reset on a triple-fault so trigger a reset
                         match vcpu.lock().unwrap().run() {
                             Err(e) => {
                                 error!("VCPU generated error: {:?}", e);
                                 break;
                             }
-                            Ok(true) => {}
-                            Ok(false) => {
+                            Ok(VcpuRunState::Continue) => {}
+                            Ok(VcpuRunState::Reset) => {
                                 vcpu_run_interrupted.store(true, Ordering::SeqCst);
                                 reset_evt.write(1).unwrap();
                                 break;
                             }
+                            #[cfg(target_arch = "x86_64")]
+                            Ok(VcpuRunState::Debug(breakpoint)) => {
+                                // Park ourselves and let the debugger stop
+                                // the other vCPUs.
+                                *vcpu_debug_breakpoint.lock().unwrap() = breakpoint;
+                                vcpu_debug_stopped.store(true, Ordering::SeqCst);
+                                vcpu_pause_signalled.store(true, Ordering::SeqCst);
+                                debug_evt.write(1).unwrap();
+                            }
                         }
 
                         // We've been told to terminate
@@ -854,6 +1204,19 @@ impl CpuManager {
                             vcpu_run_interrupted.store(true, Ordering::SeqCst);
                             break;
                         }
+
+                        // Stay out of the guest for part of the time while
+                        // throttled, still noticing whenever we are told to
+                        // pause or terminate.
+                        let throttle = vcpu_throttle.load(Ordering::SeqCst);
+                        if throttle > 0 && timeslice_start.elapsed() >= THROTTLE_TIMESLICE {
+                            throttle_vcpu(throttle_sleep(throttle), || {
+                                vcpu_pause_signalled.load(Ordering::SeqCst)
+                                    || vcpu_kill_signalled.load(Ordering::SeqCst)
+                                    || vcpu_kill.load(Ordering::SeqCst)
+                            });
+                            timeslice_start = Instant::now();
+                        }
                     }
                 })
                 .map_err(Error::VcpuSpawn)?,
@@ -887,12 +1250,20 @@ impl CpuManager {
         // This reuses any inactive vCPUs as well as any that were newly created
         for cpu_id in self.present_vcpus()..desired_vcpus {
             let vcpu = Arc::clone(&self.vcpus[cpu_id as usize]);
+            // A vCPU plugged back logs its pages in its dirty ring again.
+            #[cfg(target_arch = "x86_64")]
+            vcpu.lock()
+                .unwrap()
+                .vcpu
+                .add_dirty_ring()
+                .map_err(|e| Error::VcpuAddDirtyRing(e.into()))?;
             self.start_vcpu(vcpu, vcpu_thread_barrier.clone(), inserting)?;
         }
 
         // Unblock all CPU threads.
         vcpu_thread_barrier.wait();
-        Ok(())
+
+        self.restart_throttle_kicker()
     }
 
     fn mark_vcpus_for_removal(&mut self, desired_vcpus: u8) -> Result<()> {
@@ -905,15 +1276,101 @@ impl CpuManager {
 
     fn remove_vcpu(&mut self, cpu_id: u8) -> Result<()> {
         info!("Removing vCPU: cpu_id = {}", cpu_id);
+        // The vCPU thread must not be kicked once it has exited.
+        self.stop_throttle_kicker()?;
         let mut state = &mut self.vcpu_states[usize::from(cpu_id)];
         state.kill.store(true, Ordering::SeqCst);
         state.signal_thread();
         state.join_thread()?;
         state.handle = None;
 
+        // The pages the vCPU dirtied are kept, but its ring isn't harvested
+        // until it is plugged back.
+        #[cfg(target_arch = "x86_64")]
+        self.vcpus[usize::from(cpu_id)]
+            .lock()
+            .unwrap()
+            .vcpu
+            .remove_dirty_ring();
+
         // Once the thread has exited, clear the "kill" so that it can reused
         state.kill.store(false, Ordering::SeqCst);
 
+        self.restart_throttle_kicker()
+    }
+
+    /// Keep the vCPUs out of the guest for `percent` of the time, up to
+    /// `MAX_THROTTLE`, slowing the guest down, as when it dirties its memory
+    /// faster than a live migration sends it. The vCPUs run freely again
+    /// once the throttle is set back to 0.
+    pub fn set_throttle(&mut self, percent: u8) -> Result<()> {
+        let percent = cmp::min(percent, MAX_THROTTLE);
+        info!("Throttling the vCPUs by {}%", percent);
+        self.vcpus_throttle.store(percent, Ordering::SeqCst);
+        if percent == 0 {
+            self.stop_throttle_kicker()
+        } else if self.throttle_kicker.is_none() {
+            self.start_throttle_kicker()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn throttle(&self) -> u8 {
+        self.vcpus_throttle.load(Ordering::SeqCst)
+    }
+
+    fn start_throttle_kicker(&mut self) -> Result<()> {
+        let threads: Vec<libc::pthread_t> = self
+            .vcpu_states
+            .iter()
+            .filter_map(|state| state.handle.as_ref())
+            .map(|handle| handle.as_pthread_t() as _)
+            .collect();
+        let stop = Arc::new(AtomicBool::new(false));
+        let kicker_stop = stop.clone();
+        let vcpus_pause_signalled = self.vcpus_pause_signalled.clone();
+
+        let handle = thread::Builder::new()
+            .name("vcpu_throttle".to_string())
+            .spawn(move || loop {
+                thread::sleep(THROTTLE_TIMESLICE);
+                if kicker_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                // The paused vCPUs are parked, out of the guest already.
+                if vcpus_pause_signalled.load(Ordering::SeqCst) {
+                    continue;
+                }
+                for thread in threads.iter() {
+                    unsafe {
+                        libc::pthread_kill(*thread, SIGRTMIN());
+                    }
+                }
+            })
+            .map_err(Error::VcpuSpawn)?;
+        self.throttle_kicker = Some(ThrottleKicker { stop, handle });
+
+        Ok(())
+    }
+
+    fn stop_throttle_kicker(&mut self) -> Result<()> {
+        if let Some(kicker) = self.throttle_kicker.take() {
+            kicker.stop.store(true, Ordering::SeqCst);
+            kicker.handle.join().map_err(Error::ThreadCleanup)?;
+        }
+
+        Ok(())
+    }
+
+    // Kick the vCPU threads running from now on, some having been started
+    // or stopped.
+    fn restart_throttle_kicker(&mut self) -> Result<()> {
+        self.stop_throttle_kicker()?;
+        if self.throttle() > 0 {
+            self.start_throttle_kicker()?;
+        }
+
         Ok(())
     }
 
@@ -927,6 +1384,13 @@ impl CpuManager {
     }
 
     pub fn resize(&mut self, desired_vcpus: u8) -> Result<bool> {
+        if desired_vcpus > self.config.max_vcpus {
+            return Err(Error::DesiredVCPUCountExceedsMax);
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        check_resize(desired_vcpus, self.present_vcpus())?;
+
         match desired_vcpus.cmp(&self.present_vcpus()) {
             cmp::Ordering::Greater => {
                 self.create_vcpus(desired_vcpus, None)?;
@@ -939,6 +1403,9 @@ impl CpuManager {
     }
 
     pub fn shutdown(&mut self) -> Result<()> {
+        // The vCPU threads must not be kicked once they have exited.
+        self.stop_throttle_kicker()?;
+
         // Tell the vCPUs to stop themselves next time they go through the loop
         self.vcpus_kill_signalled.store(true, Ordering::SeqCst);
 
@@ -973,6 +1440,208 @@ impl CpuManager {
         self.config.max_vcpus
     }
 
+    /// The topology exposed to the guest.
+    pub fn topology(&self) -> CpuTopology {
+        self.config.effective_topology()
+    }
+
+    /// The known CPU features exposed to the guest.
+    #[cfg(target_arch = "x86_64")]
+    pub fn cpu_features(&self) -> Vec<String> {
+        cpu_features::enabled_features(&self.cpuid)
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    /// Format of the state of the nested guests saved along with the vCPUs,
+    /// if the guest is exposed the virtualization extensions.
+    #[cfg(target_arch = "x86_64")]
+    pub fn nested_state_format(&self) -> Option<NestedStateFormat> {
+        self.nested_state_format
+    }
+
+    /// The CPUID leaves holding the feature bits exposed to the guest.
+    #[cfg(target_arch = "x86_64")]
+    pub fn cpuid_feature_leaves(&self) -> Vec<CpuidFeatureLeaf> {
+        cpu_features::feature_leaves(&self.cpuid)
+            .iter()
+            .map(|e| CpuidFeatureLeaf {
+                function: e.function,
+                index: e.index,
+                eax: e.eax,
+                ebx: e.ebx,
+                ecx: e.ecx,
+                edx: e.edx,
+            })
+            .collect()
+    }
+
+    /// Expose the same CPU features as the VM this one is restored from,
+    /// which exposed the feature `leaves`, or the known `features` for the
+    /// snapshots not recording them. This must happen before the vCPUs get
+    /// created, and fails if the host doesn't support all of them.
+    #[cfg(target_arch = "x86_64")]
+    pub fn restrict_cpu_features(
+        &mut self,
+        features: &[String],
+        leaves: Option<&[CpuidFeatureLeaf]>,
+    ) -> Result<()> {
+        if let Some(leaves) = leaves {
+            let leaves: Vec<hypervisor::CpuIdEntry> = leaves.iter().map(|l| l.into()).collect();
+            let missing = cpu_features::missing_features(&leaves, &self.cpuid);
+            if !missing.is_empty() {
+                error!(
+                    "The host lacks CPU features exposed to the restored guest: {}",
+                    missing.join(", ")
+                );
+                return Err(Error::MissingCpuFeatures(missing));
+            }
+            cpu_features::restrict_cpuid(&mut self.cpuid, &leaves);
+            return Ok(());
+        }
+
+        let enabled = cpu_features::enabled_features(&self.cpuid);
+
+        let mut missing = Vec::new();
+        for feature in features {
+            if cpu_features::find_feature(feature).is_none() {
+                warn!("Unknown CPU feature {} can't be checked", feature);
+            } else if !enabled.contains(&feature.as_str()) {
+                missing.push(feature.clone());
+            }
+        }
+        if !missing.is_empty() {
+            return Err(Error::MissingCpuFeatures(missing));
+        }
+
+        let extra: Vec<&CpuFeature> = enabled
+            .into_iter()
+            .filter(|name| !features.iter().any(|f| f == name))
+            .filter_map(cpu_features::find_feature)
+            .collect();
+        cpu_features::mask_cpuid(&mut self.cpuid, &extra);
+
+        Ok(())
+    }
+
+    pub fn get_vcpu_topology(&self) -> Option<(u8, u8, u8)> {
+        self.config
+            .topology
+            .as_ref()
+            .map(|t| (t.threads_per_core, t.cores_per_die, t.dies_per_package))
+    }
+
+    // The APIC id of a vCPU, laid out according to the CPU topology so that
+    // it matches the x2APIC id reported through CPUID.
+    #[cfg(target_arch = "x86_64")]
+    pub fn apic_id(&self, cpu_id: u8) -> u8 {
+        arch::x86_64::get_x2apic_id(u32::from(cpu_id), self.get_vcpu_topology()) as u8
+    }
+
+    /// A copy of the EventFd written to whenever a vCPU stops on a
+    /// breakpoint or a single step.
+    #[cfg(target_arch = "x86_64")]
+    pub fn debug_evt(&self) -> Result<EventFd> {
+        self.debug_evt.try_clone().map_err(Error::EventFd)
+    }
+
+    /// Stop all the vCPUs on behalf of the debugger. As opposed to
+    /// `Pausable::pause()` the vCPU state isn't saved, so that the debugger
+    /// can change it before resuming.
+    #[cfg(target_arch = "x86_64")]
+    pub fn debug_pause(&self) {
+        self.vcpus_pause_signalled.store(true, Ordering::SeqCst);
+        for state in self.vcpu_states.iter() {
+            state.signal_thread();
+        }
+    }
+
+    /// Resume the vCPUs stopped by `debug_pause()`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn debug_resume(&self) {
+        for state in self.vcpu_states.iter() {
+            state.debug_stopped.store(false, Ordering::SeqCst);
+        }
+        self.vcpus_pause_signalled.store(false, Ordering::SeqCst);
+        for state in self.vcpu_states.iter() {
+            state.unpark_thread();
+        }
+    }
+
+    /// The vCPUs which stopped on a breakpoint or a single step since
+    /// the last `debug_resume()`, along with the index of the hardware
+    /// breakpoint each of them hit, if any.
+    #[cfg(target_arch = "x86_64")]
+    pub fn debug_stopped_vcpus(&self) -> Vec<(u8, Option<usize>)> {
+        self.vcpu_states
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| state.debug_stopped.load(Ordering::SeqCst))
+            .map(|(cpu_id, state)| (cpu_id as u8, *state.debug_breakpoint.lock().unwrap()))
+            .collect()
+    }
+
+    /// The number of vCPUs the debugger can inspect.
+    #[cfg(target_arch = "x86_64")]
+    pub fn debug_vcpu_count(&self) -> u8 {
+        self.vcpus.len() as u8
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn debug_vcpu(&self, cpu_id: u8) -> Result<&Arc<Mutex<Vcpu>>> {
+        self.vcpus
+            .get(usize::from(cpu_id))
+            .ok_or(Error::InvalidVcpu(cpu_id))
+    }
+
+    /// Read the registers of a vCPU stopped by `debug_pause()`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn debug_get_regs(&self, cpu_id: u8) -> Result<(StandardRegisters, SpecialRegisters)> {
+        let vcpu = self.debug_vcpu(cpu_id)?.lock().unwrap();
+        let regs = vcpu
+            .vcpu
+            .get_regs()
+            .map_err(|e| Error::VcpuGetRegs(e.into()))?;
+        let sregs = vcpu
+            .vcpu
+            .get_sregs()
+            .map_err(|e| Error::VcpuGetSregs(e.into()))?;
+
+        Ok((regs, sregs))
+    }
+
+    /// Write the general purpose registers of a vCPU stopped by
+    /// `debug_pause()`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn debug_set_regs(&self, cpu_id: u8, regs: &StandardRegisters) -> Result<()> {
+        self.debug_vcpu(cpu_id)?
+            .lock()
+            .unwrap()
+            .vcpu
+            .set_regs(regs)
+            .map_err(|e| Error::VcpuSetRegs(e.into()))
+    }
+
+    /// Program the hardware breakpoints and watchpoints of all the vCPUs,
+    /// only `step_vcpu` being single-stepped if any.
+    #[cfg(target_arch = "x86_64")]
+    pub fn debug_set_breakpoints(
+        &self,
+        breakpoints: &[HwBreakpoint],
+        step_vcpu: Option<u8>,
+    ) -> Result<()> {
+        for (cpu_id, vcpu) in self.vcpus.iter().enumerate() {
+            vcpu.lock()
+                .unwrap()
+                .vcpu
+                .set_guest_debug(breakpoints, step_vcpu == Some(cpu_id as u8))
+                .map_err(|e| Error::VcpuSetGuestDebug(e.into()))?;
+        }
+
+        Ok(())
+    }
+
     fn present_vcpus(&self) -> u8 {
         self.vcpu_states
             .iter()
@@ -1002,7 +1671,7 @@ impl CpuManager {
                 r#type: 0,
                 length: 8,
                 processor_id: cpu,
-                apic_id: cpu,
+                apic_id: self.apic_id(cpu),
                 flags: if cpu < self.config.boot_vcpus {
                     1 << MADT_CPU_ENABLE_FLAG
                 } else {
@@ -1037,6 +1706,56 @@ impl CpuManager {
 #[cfg(feature = "acpi")]
 struct CPU {
     cpu_id: u8,
+    apic_id: u8,
+    pstates: Option<PStates>,
+}
+
+// Performance states of a vCPU, from its maximum frequency down to its base
+// one, in MHz. They are only informative, the guest finding no way to switch
+// between them, as the control and status registers are functional fixed
+// hardware ones while the Enhanced SpeedStep feature isn't exposed.
+#[cfg(feature = "acpi")]
+#[derive(Clone, Copy)]
+struct PStates {
+    base_mhz: u16,
+    max_mhz: u16,
+}
+
+#[cfg(feature = "acpi")]
+impl Aml for PStates {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut frequencies = vec![u32::from(self.max_mhz)];
+        if self.base_mhz < self.max_mhz {
+            frequencies.push(u32::from(self.base_mhz));
+        }
+        // Frequency, power (mW), transition and bus master latencies (us),
+        // control and status values, for each state.
+        let states: Vec<[u32; 6]> = frequencies
+            .iter()
+            .map(|mhz| [*mhz, 0, 10, 10, mhz / 100, mhz / 100])
+            .collect();
+        let states: Vec<aml::Package> = states
+            .iter()
+            .map(|state| aml::Package::new(state.iter().map(|v| v as &dyn Aml).collect()))
+            .collect();
+
+        let register = aml::ResourceTemplate::new(vec![&aml::Register::new(0x7f, 0, 0, 0, 0)]);
+        let mut bytes = aml::Name::new(
+            "_PCT".into(),
+            &aml::Package::new(vec![&register, &register]),
+        )
+        .to_aml_bytes();
+        bytes.extend_from_slice(
+            &aml::Name::new(
+                "_PSS".into(),
+                &aml::Package::new(states.iter().map(|s| s as &dyn Aml).collect()),
+            )
+            .to_aml_bytes(),
+        );
+        // All the states are available.
+        bytes.extend_from_slice(&aml::Name::new("_PPC".into(), &aml::ZERO).to_aml_bytes());
+        bytes
+    }
 }
 
 #[cfg(feature = "acpi")]
@@ -1049,7 +1768,7 @@ impl Aml for CPU {
             r#type: 0,
             length: 8,
             processor_id: self.cpu_id,
-            apic_id: self.cpu_id,
+            apic_id: self.apic_id,
             flags: 1 << MADT_CPU_ENABLE_FLAG,
         };
 
@@ -1057,48 +1776,49 @@ impl Aml for CPU {
         mat_data.resize(std::mem::size_of_val(&lapic), 0);
         unsafe { *(mat_data.as_mut_ptr() as *mut LocalAPIC) = lapic };
 
-        aml::Device::new(
-            format!("C{:03}", self.cpu_id).as_str().into(),
-            vec![
-                &aml::Name::new("_HID".into(), &"ACPI0007"),
-                &aml::Name::new("_UID".into(), &self.cpu_id),
-                /*
-                _STA return value:
-                Bit [0] – Set if the device is present.
-                Bit [1] – Set if the device is enabled and decoding its resources.
-                Bit [2] – Set if the device should be shown in the UI.
-                Bit [3] – Set if the device is functioning properly (cleared if device failed its diagnostics).
-                Bit [4] – Set if the battery is present.
-                Bits [31:5] – Reserved (must be cleared).
-                */
-                &aml::Method::new(
-                    "_STA".into(),
-                    0,
-                    false,
-                    // Call into CSTA method which will interrogate device
-                    vec![&aml::Return::new(&aml::MethodCall::new(
-                        "CSTA".into(),
-                        vec![&self.cpu_id],
-                    ))],
-                ),
-                // The Linux kernel expects every CPU device to have a _MAT entry
-                // containing the LAPIC for this processor with the enabled bit set
-                // even it if is disabled in the MADT (non-boot CPU)
-                &aml::Name::new("_MAT".into(), &aml::Buffer::new(mat_data)),
-                // Trigger CPU ejection
-                &aml::Method::new(
-                    "_EJ0".into(),
-                    1,
-                    false,
-                    // Call into CEJ0 method which will actually eject device
-                    vec![&aml::Return::new(&aml::MethodCall::new(
-                        "CEJ0".into(),
-                        vec![&self.cpu_id],
-                    ))],
-                ),
-            ],
-        )
-        .to_aml_bytes()
+        let hid = aml::Name::new("_HID".into(), &"ACPI0007");
+        let uid = aml::Name::new("_UID".into(), &self.cpu_id);
+        /*
+        _STA return value:
+        Bit [0] – Set if the device is present.
+        Bit [1] – Set if the device is enabled and decoding its resources.
+        Bit [2] – Set if the device should be shown in the UI.
+        Bit [3] – Set if the device is functioning properly (cleared if device failed its diagnostics).
+        Bit [4] – Set if the battery is present.
+        Bits [31:5] – Reserved (must be cleared).
+        */
+        let sta = aml::Method::new(
+            "_STA".into(),
+            0,
+            false,
+            // Call into CSTA method which will interrogate device
+            vec![&aml::Return::new(&aml::MethodCall::new(
+                "CSTA".into(),
+                vec![&self.cpu_id],
+            ))],
+        );
+        // The Linux kernel expects every CPU device to have a _MAT entry
+        // containing the LAPIC for this processor with the enabled bit set
+        // even it if is disabled in the MADT (non-boot CPU)
+        let mat = aml::Name::new("_MAT".into(), &aml::Buffer::new(mat_data));
+        // Trigger CPU ejection
+        let ej0 = aml::Method::new(
+            "_EJ0".into(),
+            1,
+            false,
+            // Call into CEJ0 method which will actually eject device
+            vec![&aml::Return::new(&aml::MethodCall::new(
+                "CEJ0".into(),
+                vec![&self.cpu_id],
+            ))],
+        );
+
+        let mut children: Vec<&dyn Aml> = vec![&hid, &uid, &sta, &mat, &ej0];
+        if let Some(pstates) = &self.pstates {
+            children.push(pstates);
+        }
+
+        aml::Device::new(format!("C{:03}", self.cpu_id).as_str().into(), children).to_aml_bytes()
     }
 }
 
@@ -1305,7 +2025,15 @@ impl Aml for CpuManager {
 
         let mut cpu_devices = Vec::new();
         for cpu_id in 0..self.config.max_vcpus {
-            let cpu_device = CPU { cpu_id };
+            let cpu_device = CPU {
+                cpu_id,
+                apic_id: self.apic_id(cpu_id),
+                pstates: self
+                    .config
+                    .frequency()
+                    .filter(|_| self.config.pstates)
+                    .map(|(base_mhz, max_mhz)| PStates { base_mhz, max_mhz }),
+            };
 
             cpu_devices.push(cpu_device);
         }
@@ -1415,6 +2143,66 @@ mod tests {
     use arch::x86_64::BootProtocol;
     use hypervisor::x86_64::{FpuState, LapicState, SpecialRegisters, StandardRegisters};
 
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("3\n").unwrap(), vec![3]);
+        assert_eq!(parse_cpu_list("0-2,8").unwrap(), vec![0, 1, 2, 8]);
+        assert!(parse_cpu_list("0-x").is_err());
+    }
+
+    #[test]
+    fn test_throttle() {
+        // Running half of the time, then a single time slice out of 100.
+        assert_eq!(throttle_sleep(50), THROTTLE_TIMESLICE);
+        assert_eq!(throttle_sleep(99), THROTTLE_TIMESLICE * 99);
+        assert_eq!(throttle_sleep(100), throttle_sleep(MAX_THROTTLE));
+        assert_eq!(throttle_sleep(0), Duration::from_secs(0));
+
+        // The sleep ends as soon as the vCPU gets interrupted.
+        let start = Instant::now();
+        throttle_vcpu(Duration::from_secs(10), || {
+            start.elapsed() >= THROTTLE_TIMESLICE * 2
+        });
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_check_strict_affinity() {
+        // Host with 4 cores of 2 threads, the siblings being n and n + 4.
+        let siblings = |cpu: usize| -> io::Result<Vec<usize>> { Ok(vec![cpu % 4, cpu % 4 + 4]) };
+        let topology = CpuTopology {
+            threads_per_core: 2,
+            cores_per_die: 2,
+            dies_per_package: 1,
+            packages: 1,
+        };
+        let affinity = |pairs: &[(u8, usize)]| {
+            pairs
+                .iter()
+                .map(|(vcpu, host_cpu)| CpuAffinity {
+                    vcpu: *vcpu,
+                    host_cpus: vec![*host_cpu],
+                })
+                .collect::<Vec<CpuAffinity>>()
+        };
+
+        assert!(check_strict_affinity(
+            &topology,
+            &affinity(&[(0, 0), (1, 4), (2, 1), (3, 5)]),
+            siblings
+        )
+        .is_ok());
+        // vCPUs 2 and 3 share a guest core but not a host one.
+        match check_strict_affinity(
+            &topology,
+            &affinity(&[(0, 0), (1, 4), (2, 1), (3, 2)]),
+            siblings,
+        ) {
+            Err(Error::StrictAffinity(3, 2)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
     #[test]
     fn test_setlint() {
         let hv = hypervisor::new().unwrap();
@@ -1540,11 +2328,23 @@ mod tests {
 #[cfg(target_arch = "aarch64")]
 #[cfg(test)]
 mod tests {
+    use super::{check_resize, Error};
     use arch::aarch64::layout;
     use arch::aarch64::regs::*;
     use hypervisor::kvm::kvm_bindings;
     use vm_memory::{GuestAddress, GuestMemoryMmap};
 
+    #[test]
+    fn test_resize_not_supported() {
+        assert!(check_resize(2, 2).is_ok());
+        for desired_vcpus in [1, 3].iter() {
+            match check_resize(*desired_vcpus, 2) {
+                Err(Error::HotplugNotSupported) => {}
+                r => panic!("Unexpected resize result {:?}", r),
+            }
+        }
+    }
+
     #[test]
     fn test_setup_regs() {
         let hv = hypervisor::new().unwrap();
@@ -9,13 +9,28 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
-use crate::config::ConsoleOutputMode;
 #[cfg(feature = "pci_support")]
 use crate::config::DeviceConfig;
-use crate::config::{DiskConfig, FsConfig, NetConfig, PmemConfig, VmConfig, VsockConfig};
+use crate::config::{ConsoleConfig, ConsoleOutputMode, ConsolePortConfig};
+use crate::config::{
+    DiskConfig, FsConfig, NetConfig, PmemConfig, RxStarvationMode, VdpaConfig, VmConfig,
+    VsockConfig,
+};
+use crate::config::{SoundBackend, SoundConfig};
+use crate::console_port::{ConsolePortEndpoint, ConsolePty, Error as ConsolePortError};
+use crate::console_socket::{
+    ConsoleSocket, Endpoint as ConsoleSocketEndpoint, Error as ConsoleSocketError,
+    DEFAULT_BUFFER_SIZE as CONSOLE_SOCKET_BUFFER_SIZE,
+};
 use crate::device_tree::{DeviceNode, DeviceTree};
+#[cfg(all(feature = "pci_support", feature = "kvm"))]
+use crate::failover::{Error as FailoverError, FailoverPair};
 use crate::interrupt::{kvm::KvmMsiInterruptManager, LegacyUserspaceInterruptManager};
 use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
+#[cfg(all(feature = "pci_support", feature = "kvm"))]
+use crate::sriov::VirtualFunction;
+#[cfg(all(feature = "pci_support", feature = "kvm"))]
+use crate::vfio_error::ErrorMonitor;
 #[cfg(feature = "pci_support")]
 use crate::PciDeviceInfo;
 use crate::{device_node, DEVICE_MANAGER_SNAPSHOT_ID};
@@ -30,6 +45,7 @@ use arch::layout;
 use arch::layout::{APIC_START, IOAPIC_SIZE, IOAPIC_START};
 #[cfg(target_arch = "aarch64")]
 use arch::DeviceType;
+use block_util::StripedDisk;
 #[cfg(target_arch = "aarch64")]
 use devices::gic;
 #[cfg(target_arch = "x86_64")]
@@ -44,31 +60,38 @@ use hypervisor::kvm_ioctls::*;
 use hypervisor::vm::DataMatch;
 use libc::TIOCGWINSZ;
 use libc::{MAP_NORESERVE, MAP_PRIVATE, MAP_SHARED, O_TMPFILE, PROT_READ, PROT_WRITE};
+use net_util::{MacAddr, RxStarvationPolicy, Tap};
 #[cfg(feature = "pci_support")]
 use pci::{
-    DeviceRelocation, PciBarRegionType, PciBus, PciConfigIo, PciConfigMmio, PciDevice, PciRoot,
-    VfioPciDevice,
+    DeviceRelocation, NvmeDisk, NvmeNamespace, NvmePciDevice, PciBarRegionType, PciBus,
+    PciConfigIo, PciConfigMmio, PciDevice, PciRoot, VfioPciDevice, NVME_MAX_IO_QUEUES,
 };
 use qcow::{self, ImageType, QcowFile};
+use seccomp::SeccompAction;
 #[cfg(feature = "pci_support")]
 use std::any::Any;
 use std::collections::HashMap;
+#[cfg(feature = "pci_support")]
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::{File, OpenOptions};
 use std::io::{self, sink, stdout, Seek, SeekFrom};
 use std::num::Wrapping;
 use std::os::unix::fs::OpenOptionsExt;
 #[cfg(feature = "pci_support")]
 use std::os::unix::io::FromRawFd;
-use std::path::PathBuf;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tempfile::NamedTempFile;
 #[cfg(feature = "pci_support")]
 use vfio_ioctls::{VfioContainer, VfioDevice, VfioDmaMapping};
 #[cfg(feature = "pci_support")]
 use virtio_devices::transport::VirtioPciDevice;
 use virtio_devices::transport::VirtioTransport;
-use virtio_devices::vhost_user::VhostUserConfig;
+use virtio_devices::vhost_user::{VhostUserConfig, VhostUserReconnectConfig};
+use virtio_devices::DiskFile;
 #[cfg(feature = "pci_support")]
 use virtio_devices::{DmaRemapping, IommuMapping};
 use virtio_devices::{VirtioSharedMemory, VirtioSharedMemoryList};
@@ -111,15 +134,25 @@ const NET_DEVICE_NAME_PREFIX: &str = "_net";
 const PMEM_DEVICE_NAME_PREFIX: &str = "_pmem";
 const RNG_DEVICE_NAME: &str = "_rng";
 const VSOCK_DEVICE_NAME_PREFIX: &str = "_vsock";
+const WATCHDOG_DEVICE_NAME: &str = "_watchdog";
+const SOUND_DEVICE_NAME: &str = "_sound";
+const GPU_DEVICE_NAME: &str = "_gpu";
+const VDPA_DEVICE_NAME_PREFIX: &str = "_vdpa";
 
 #[cfg(feature = "pci_support")]
 const IOMMU_DEVICE_NAME: &str = "_iommu";
+#[cfg(feature = "pci_support")]
+const NVME_DEVICE_NAME: &str = "_nvme0";
 
 #[cfg(feature = "mmio_support")]
 const VIRTIO_MMIO_DEVICE_NAME_PREFIX: &str = "_virtio-mmio";
 #[cfg(feature = "pci_support")]
 const VIRTIO_PCI_DEVICE_NAME_PREFIX: &str = "_virtio-pci";
 
+// I/O port of the pvpanic device, as expected by the guests.
+#[cfg(target_arch = "x86_64")]
+const PVPANIC_PORT: u64 = 0x505;
+
 /// Errors associated with device manager
 #[derive(Debug)]
 pub enum DeviceManagerError {
@@ -135,12 +168,42 @@ pub enum DeviceManagerError {
     /// Cannot create virtio-blk device
     CreateVirtioBlock(io::Error),
 
+    /// Cannot stripe the disk images
+    StripedDiskCreate(io::Error),
+
     /// Cannot create virtio-net device
     CreateVirtioNet(virtio_devices::net::Error),
 
     /// Cannot create virtio-console device
     CreateVirtioConsole(io::Error),
 
+    /// Cannot create virtio-console port
+    CreateConsolePort(ConsolePortError),
+
+    /// Cannot create the serial or virtio-console socket backend
+    CreateConsoleSocket(ConsoleSocketError),
+
+    /// Cannot allocate the serial port pseudo terminal
+    CreateSerialPty(ConsolePortError),
+
+    /// Cannot allocate the virtio-console pseudo terminal
+    CreateConsolePty(ConsolePortError),
+
+    /// No virtio-console device to add ports to
+    NoVirtioConsole,
+
+    /// No virtio-net device matches the given identifier
+    NoVirtioNet(String),
+
+    /// Cannot change the number of virtio-net queue pairs
+    SetNetQueuePairs(virtio_devices::net::Error),
+
+    /// Cannot change the MAC address of a virtio-net device
+    SetNetMac(virtio_devices::net::Error),
+
+    /// Cannot set the flow rules of a virtio-net device
+    SetNetFlowRules(virtio_devices::net::Error),
+
     /// Cannot create virtio-rng device
     CreateVirtioRng(io::Error),
 
@@ -150,6 +213,9 @@ pub enum DeviceManagerError {
     /// Virtio-fs device was created without a socket.
     NoVirtioFsSock,
 
+    /// Cannot create vDPA device
+    CreateVdpa(virtio_devices::vdpa::Error),
+
     /// Cannot create vhost-user-blk device
     CreateVhostUserBlk(virtio_devices::vhost_user::Error),
 
@@ -168,9 +234,24 @@ pub enum DeviceManagerError {
     /// Cannot create virtio-iommu device
     CreateVirtioIommu(io::Error),
 
+    /// Cannot mirror the virtio-gpu framebuffer to a host file
+    ExportGpuFramebuffer(io::Error),
+
     /// Cannot create virtio-balloon device
     CreateVirtioBalloon(io::Error),
 
+    /// Cannot create virtio-watchdog device
+    CreateVirtioWatchdog(io::Error),
+
+    /// Cannot create virtio-sound backend
+    CreateVirtioSoundBackend(io::Error),
+
+    /// Sound ALSA backend requested without ALSA support
+    SoundAlsaNotSupported,
+
+    /// No sound file path was specified when one was expected
+    NoSoundPath,
+
     /// Failed parsing disk image format
     DetectImageType(qcow::Error),
 
@@ -180,6 +261,12 @@ pub enum DeviceManagerError {
     /// Cannot open tap interface
     OpenTap(net_util::TapError),
 
+    /// Cannot take over a tap interface handed over by another process
+    TakeOverTap(net_util::TapError),
+
+    /// Cannot flush a virtio device
+    FlushDevice(String, io::Error),
+
     /// Cannot allocate IRQ.
     AllocateIrq,
 
@@ -237,6 +324,18 @@ pub enum DeviceManagerError {
     #[cfg(feature = "pci_support")]
     VfioMapRegion(pci::VfioPciError),
 
+    /// Failed to program the MAC address or VLAN of an SR-IOV VF.
+    #[cfg(all(feature = "pci_support", feature = "kvm"))]
+    SriovVfConfigure(crate::sriov::Error),
+
+    /// Failed to start reporting the errors of a VFIO device.
+    #[cfg(all(feature = "pci_support", feature = "kvm"))]
+    VfioErrorMonitor(io::Error),
+
+    /// Failed to move the traffic between the devices of a failover pair.
+    #[cfg(all(feature = "pci_support", feature = "kvm"))]
+    Failover(String, FailoverError),
+
     /// Failed to create the passthrough device.
     CreatePassthroughDevice(anyhow::Error),
 
@@ -282,6 +381,14 @@ pub enum DeviceManagerError {
     /// Missing PCI bus.
     NoPciBus,
 
+    /// Unknown PCI segment.
+    #[cfg(feature = "pci_support")]
+    InvalidPciSegment(u16),
+
+    /// Failed to allocate the address ranges of a PCI segment.
+    #[cfg(feature = "pci_support")]
+    PciSegmentAllocation(u16),
+
     /// Could not find an available device name.
     NoAvailableDeviceName,
 
@@ -350,6 +457,21 @@ pub enum DeviceManagerError {
     /// Trying to use a size that is not multiple of 2MiB
     PmemSizeNotAligned,
 
+    /// Trying to use direct I/O on a disk whose size is not a multiple of
+    /// the sector size
+    DirectDiskSizeNotAligned,
+
+    /// Cannot create a NVMe namespace
+    #[cfg(feature = "pci_support")]
+    CreateNvmeNamespace(io::Error),
+
+    /// Cannot create the NVMe controller
+    #[cfg(feature = "pci_support")]
+    CreateNvme(io::Error),
+
+    /// NVMe disks can't be hotplugged
+    NvmeHotplugNotSupported,
+
     /// Could not find the node in the device tree.
     MissingNode,
 
@@ -379,6 +501,123 @@ pub type DeviceManagerResult<T> = result::Result<T, DeviceManagerError>;
 
 type VirtioDeviceArc = Arc<Mutex<dyn virtio_devices::VirtioDevice>>;
 
+// PCI hotplug event reported to the guest through the PCIU and PCID fields.
+#[cfg(feature = "pci_support")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PciSlotEvent {
+    Insert,
+    Eject,
+}
+
+// Size of the IO port window given to each PCI segment but the first one.
+#[cfg(all(feature = "pci_support", target_arch = "x86_64"))]
+const PCI_SEGMENT_IO_SIZE: GuestUsize = 0x400;
+
+// A PCI segment, made of a single bus, with its own configuration space and
+// its own share of the 32 bits and 64 bits device areas.
+#[cfg(feature = "pci_support")]
+struct PciSegment {
+    id: u16,
+    pci_bus: Arc<Mutex<PciBus>>,
+    // Allocator of the BARs of the devices on the segment. The first segment
+    // relies on the allocator of the VM, as the devices found outside of the
+    // PCI topology do.
+    allocator: Arc<Mutex<SystemAllocator>>,
+    mmio_config_address: u64,
+    start_of_mem32_area: u64,
+    end_of_mem32_area: u64,
+    start_of_mem64_area: u64,
+    end_of_mem64_area: u64,
+    #[cfg(target_arch = "x86_64")]
+    start_of_io_area: u16,
+    #[cfg(target_arch = "x86_64")]
+    end_of_io_area: u16,
+    // Hotplug events not consumed by the guest yet, for each PCI slot.
+    pci_slot_events: BTreeMap<u8, VecDeque<PciSlotEvent>>,
+}
+
+// PCI b/d/f of a device on the bus 0 of a segment. The 3 first bits are
+// dedicated to the PCI function, always 0 as we don't do multifunction.
+#[cfg(feature = "pci_support")]
+fn pci_bdf(pci_segment_id: u16, device_id: u32) -> u32 {
+    (u32::from(pci_segment_id) << 16) | (device_id << 3)
+}
+
+// Host IOVA ranges reserved for the IOMMU group of a VFIO device, as exposed
+// by sysfs through lines of "<start> <end> <type>" hexadecimal addresses.
+#[cfg(all(feature = "pci_support", feature = "kvm"))]
+fn vfio_reserved_regions(device_path: &std::path::Path) -> Vec<virtio_devices::ReservedRegion> {
+    let path = device_path.join("iommu_group/reserved_regions");
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            warn!("Failed to read {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+
+    let parse = |addr: &str| u64::from_str_radix(addr.trim_start_matches("0x"), 16).ok();
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next().and_then(parse), fields.next().and_then(parse)) {
+                (Some(start), Some(end)) => Some(virtio_devices::ReservedRegion { start, end }),
+                _ => {
+                    warn!("Invalid reserved region \"{}\" in {}", line, path.display());
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+// Sizes of the BARs of a VFIO device meant for the high MMIO window, from the
+// lines of "<start> <end> <flags>" hexadecimal values sysfs exposes for each
+// resource of the device, the BARs coming first.
+#[cfg(all(feature = "pci_support", feature = "kvm"))]
+fn vfio_huge_bar_sizes(device_path: &std::path::Path) -> Vec<u64> {
+    const IORESOURCE_PREFETCH: u64 = 0x2000;
+    const IORESOURCE_MEM_64: u64 = 0x0010_0000;
+
+    let path = device_path.join("resource");
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            warn!("Failed to read {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+
+    let parse = |value: &str| u64::from_str_radix(value.trim_start_matches("0x"), 16).ok();
+    content
+        .lines()
+        .take(6)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let start = fields.next().and_then(parse)?;
+            let end = fields.next().and_then(parse)?;
+            let flags = fields.next().and_then(parse)?;
+            let huge_flags = IORESOURCE_PREFETCH | IORESOURCE_MEM_64;
+            if end <= start || flags & huge_flags != huge_flags {
+                return None;
+            }
+            Some(end - start + 1)
+        })
+        .filter(|size| *size >= pci::VFIO_HUGE_BAR_SIZE)
+        .collect()
+}
+
+// With O_DIRECT, every access must be aligned on the logical block size,
+// which can't be guaranteed if the image size is not a multiple of the
+// sector size.
+fn check_direct_image_size(image_size: u64) -> DeviceManagerResult<()> {
+    if image_size % virtio_devices::block::SECTOR_SIZE != 0 {
+        return Err(DeviceManagerError::DirectDiskSizeNotAligned);
+    }
+    Ok(())
+}
+
 pub fn get_win_size() -> (u16, u16) {
     #[repr(C)]
     #[derive(Default)]
@@ -612,6 +851,7 @@ impl DeviceRelocation for AddressManager {
                             0,
                             shm_regions.host_addr,
                             false,
+                            false,
                         );
 
                         self.vm.set_user_memory_region(mem_region).map_err(|e| {
@@ -628,6 +868,7 @@ impl DeviceRelocation for AddressManager {
                             shm_regions.len,
                             shm_regions.host_addr,
                             false,
+                            false,
                         );
 
                         self.vm.set_user_memory_region(mem_region).map_err(|e| {
@@ -720,7 +961,7 @@ pub struct DeviceManager {
     memory_manager: Arc<Mutex<MemoryManager>>,
 
     // The virtio devices on the system
-    virtio_devices: Vec<(VirtioDeviceArc, bool, String)>,
+    virtio_devices: Vec<(VirtioDeviceArc, bool, String, u16)>,
 
     // List of bus devices
     // Let the DeviceManager keep strong references to the BusDevice devices.
@@ -731,15 +972,22 @@ pub struct DeviceManager {
     // The path to the VMM for self spawning
     vmm_path: PathBuf,
 
+    // Action taken by the device threads on a forbidden system call
+    seccomp_action: SeccompAction,
+
     // Backends that have been spawned
     vhost_user_backends: Vec<ActivatedBackend>,
 
     // Counter to keep track of the consumed device IDs.
     device_id_cnt: Wrapping<usize>,
 
-    // Keep a reference to the PCI bus
+    // PCI segments, the first one being the default segment of the devices
+    #[cfg(feature = "pci_support")]
+    pci_segments: Vec<PciSegment>,
+
+    // PCI segment selected by the guest through the PSEG field
     #[cfg(feature = "pci_support")]
-    pci_bus: Option<Arc<Mutex<PciBus>>>,
+    selected_pci_segment: u16,
 
     #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
     // MSI Interrupt Manager
@@ -753,13 +1001,41 @@ pub struct DeviceManager {
     #[cfg(feature = "pci_support")]
     iommu_device: Option<Arc<Mutex<virtio_devices::Iommu>>>,
 
-    // Bitmap of PCI devices to hotplug.
-    #[cfg(feature = "pci_support")]
-    pci_devices_up: u32,
+    // Virtio console device, kept around for adding ports
+    console_device: Option<Arc<Mutex<virtio_devices::Console>>>,
 
-    // Bitmap of PCI devices to hotunplug.
-    #[cfg(feature = "pci_support")]
-    pci_devices_down: u32,
+    // Host endpoints of the additional console ports
+    console_ports: Vec<ConsolePortEndpoint>,
+
+    // Socket backends of the serial port and the virtio-console
+    console_sockets: Vec<ConsoleSocket>,
+
+    // Pseudo terminals of the serial port and the virtio-console
+    console_ptys: Vec<ConsolePty>,
+
+    // Virtio net devices, kept around for controlling their link state
+    net_devices: HashMap<String, Arc<Mutex<virtio_devices::Net>>>,
+
+    // Disk images of the virtio-blk devices, kept around for handing them
+    // over to another process
+    disk_images: HashMap<String, qcow::RawFile>,
+
+    // Tap interfaces and disk images handed over by another process, taken
+    // by the devices created from then on
+    handed_over_taps: HashMap<String, Vec<Tap>>,
+    handed_over_disks: HashMap<String, File>,
+
+    // Virtio vsock device, kept around for reporting connections
+    vsock_device: Option<Arc<Mutex<virtio_devices::Vsock<virtio_devices::VsockUnixBackend>>>>,
+
+    // Content of the virtio-gpu scanout, kept around for screenshots
+    gpu_framebuffer: Option<Arc<Mutex<virtio_devices::Framebuffer>>>,
+
+    // Virtio watchdog device, kept around for reporting its status
+    watchdog_device: Option<Arc<Mutex<virtio_devices::Watchdog>>>,
+
+    // pvpanic device, kept around for reporting the guest panics
+    pvpanic_device: Option<Arc<Mutex<devices::legacy::Pvpanic>>>,
 
     // Hashmap of device's name to their corresponding PCI b/d/f.
     #[cfg(feature = "pci_support")]
@@ -769,6 +1045,25 @@ pub struct DeviceManager {
     #[cfg(feature = "pci_support")]
     pci_devices: HashMap<u32, Arc<dyn Any + Send + Sync>>,
 
+    // Hashmap of PCI b/d/f to the SR-IOV VFs whose settings were programmed,
+    // these being restored once the VF is removed.
+    #[cfg(all(feature = "pci_support", feature = "kvm"))]
+    sriov_vfs: HashMap<u32, VirtualFunction>,
+
+    // Hashmap of PCI b/d/f to the sysfs path of the VFIO devices, through
+    // which they get reset once removed.
+    #[cfg(all(feature = "pci_support", feature = "kvm"))]
+    vfio_device_paths: HashMap<u32, PathBuf>,
+
+    // Hashmap of PCI b/d/f to the threads reporting the errors of the VFIO
+    // devices with an AER capability.
+    #[cfg(all(feature = "pci_support", feature = "kvm"))]
+    vfio_error_monitors: HashMap<u32, ErrorMonitor>,
+
+    // virtio-net devices acting as the failover standby of a VF.
+    #[cfg(all(feature = "pci_support", feature = "kvm"))]
+    failover_pairs: Vec<FailoverPair>,
+
     // Tree of devices, representing the dependencies between devices.
     // Useful for introspection, snapshot and restore.
     device_tree: Arc<Mutex<DeviceTree>>,
@@ -781,18 +1076,29 @@ pub struct DeviceManager {
     #[cfg(target_arch = "x86_64")]
     reset_evt: EventFd,
 
+    // Watchdog expiry event
+    watchdog_evt: EventFd,
+
+    // Guest panic event
+    #[cfg(target_arch = "x86_64")]
+    panic_evt: EventFd,
+
     #[cfg(target_arch = "aarch64")]
     id_to_dev_info: HashMap<(DeviceType, String), MMIODeviceInfo>,
 }
 
 impl DeviceManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         vm: Arc<dyn hypervisor::Vm>,
         config: Arc<Mutex<VmConfig>>,
         memory_manager: Arc<Mutex<MemoryManager>>,
         _exit_evt: &EventFd,
         #[cfg_attr(target_arch = "aarch64", allow(unused_variables))] reset_evt: &EventFd,
+        watchdog_evt: &EventFd,
+        #[cfg_attr(target_arch = "aarch64", allow(unused_variables))] panic_evt: &EventFd,
         vmm_path: PathBuf,
+        seccomp_action: SeccompAction,
     ) -> DeviceManagerResult<Arc<Mutex<Self>>> {
         let device_tree = Arc::new(Mutex::new(DeviceTree::new()));
 
@@ -830,28 +1136,52 @@ impl DeviceManager {
             virtio_devices: Vec::new(),
             bus_devices: Vec::new(),
             vmm_path,
+            seccomp_action,
             vhost_user_backends: Vec::new(),
             device_id_cnt: Wrapping(0),
             #[cfg(feature = "pci_support")]
-            pci_bus: None,
+            pci_segments: Vec::new(),
+            #[cfg(feature = "pci_support")]
+            selected_pci_segment: 0,
             msi_interrupt_manager,
             #[cfg(feature = "pci_support")]
             passthrough_device: None,
             #[cfg(feature = "pci_support")]
             iommu_device: None,
-            #[cfg(feature = "pci_support")]
-            pci_devices_up: 0,
-            #[cfg(feature = "pci_support")]
-            pci_devices_down: 0,
+            console_device: None,
+            console_ports: Vec::new(),
+            console_sockets: Vec::new(),
+            console_ptys: Vec::new(),
+            net_devices: HashMap::new(),
+            disk_images: HashMap::new(),
+            handed_over_taps: HashMap::new(),
+            handed_over_disks: HashMap::new(),
+            vsock_device: None,
+            gpu_framebuffer: None,
+            watchdog_device: None,
+            pvpanic_device: None,
             #[cfg(feature = "pci_support")]
             pci_id_list: HashMap::new(),
             #[cfg(feature = "pci_support")]
             pci_devices: HashMap::new(),
+            #[cfg(all(feature = "pci_support", feature = "kvm"))]
+            sriov_vfs: HashMap::new(),
+            #[cfg(all(feature = "pci_support", feature = "kvm"))]
+            vfio_device_paths: HashMap::new(),
+            #[cfg(all(feature = "pci_support", feature = "kvm"))]
+            vfio_error_monitors: HashMap::new(),
+            #[cfg(all(feature = "pci_support", feature = "kvm"))]
+            failover_pairs: Vec::new(),
             device_tree,
             #[cfg(feature = "acpi")]
             exit_evt: _exit_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
             #[cfg(target_arch = "x86_64")]
             reset_evt: reset_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
+            watchdog_evt: watchdog_evt
+                .try_clone()
+                .map_err(DeviceManagerError::EventFd)?,
+            #[cfg(target_arch = "x86_64")]
+            panic_evt: panic_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
             #[cfg(target_arch = "aarch64")]
             id_to_dev_info: HashMap::new(),
         };
@@ -880,7 +1210,10 @@ impl DeviceManager {
     }
 
     pub fn create_devices(&mut self) -> DeviceManagerResult<()> {
-        let mut virtio_devices: Vec<(VirtioDeviceArc, bool, String)> = Vec::new();
+        let mut virtio_devices: Vec<(VirtioDeviceArc, bool, String, u16)> = Vec::new();
+
+        #[cfg(feature = "pci_support")]
+        self.create_pci_segments()?;
 
         let interrupt_controller = self.add_interrupt_controller()?;
 
@@ -969,24 +1302,260 @@ impl DeviceManager {
         &self.id_to_dev_info
     }
 
+    // Guest physical range MSIs are written to, which the virtual IOMMU
+    // reports as reserved to every endpoint.
+    #[cfg(feature = "pci_support")]
+    fn msi_reserved_region(&self) -> virtio_devices::ReservedRegion {
+        #[cfg(target_arch = "x86_64")]
+        let (start, end) = (APIC_START.0, 0xfeef_ffff);
+        #[cfg(target_arch = "aarch64")]
+        let (start, end) = arch::aarch64::gic::kvm::its_doorbell_range(u64::from(
+            self.config.lock().unwrap().cpus.boot_vcpus,
+        ));
+
+        virtio_devices::ReservedRegion { start, end }
+    }
+
+    // Sets aside a window at the top of the device area, big enough for the
+    // huge BARs of the VFIO devices of the default segment, returning where
+    // it starts.
+    #[cfg(all(feature = "pci_support", feature = "kvm"))]
+    fn reserve_high_mmio_window(&mut self) -> DeviceManagerResult<Option<u64>> {
+        let sizes: Vec<u64> = match &self.config.lock().unwrap().devices {
+            Some(devices) => devices
+                .iter()
+                .filter(|device_cfg| device_cfg.pci_segment == 0)
+                .flat_map(|device_cfg| vfio_huge_bar_sizes(&device_cfg.path))
+                .collect(),
+            None => Vec::new(),
+        };
+        let alignment = match sizes.iter().max() {
+            Some(alignment) => *alignment,
+            None => return Ok(None),
+        };
+
+        // The BARs being naturally aligned powers of two, rounding the window
+        // up to the biggest one leaves room for all of them.
+        let size = (sizes.iter().sum::<u64>() + alignment - 1) / alignment * alignment;
+        let base = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .reserve_high_mmio_window(size, alignment)
+            .map_err(DeviceManagerError::MemoryManager)?;
+        info!(
+            "High MMIO window for the huge BARs: 0x{:x}-0x{:x}",
+            base.0,
+            base.0 + size - 1
+        );
+
+        Ok(Some(base.0))
+    }
+
+    #[cfg(feature = "pci_support")]
+    fn create_pci_segments(&mut self) -> DeviceManagerResult<()> {
+        let num_pci_segments = self.config.lock().unwrap().num_pci_segments();
+        let (start_of_device_area, end_of_device_area) = {
+            let memory_manager = self.memory_manager.lock().unwrap();
+            (
+                memory_manager.start_of_device_area().0,
+                memory_manager.end_of_device_area().0,
+            )
+        };
+        // The high MMIO window belongs to the default segment, and the other
+        // segments share what's below it. As the default segment spans up to
+        // the end of the device area, its _CRS covers the window too.
+        #[cfg(feature = "kvm")]
+        let end_of_shared_area = match self.reserve_high_mmio_window()? {
+            Some(start_of_window) => start_of_window - 1,
+            None => end_of_device_area,
+        };
+        #[cfg(not(feature = "kvm"))]
+        let end_of_shared_area = end_of_device_area;
+
+        // Each segment gets an equal share of the 32 bits and 64 bits device
+        // areas. The default segment takes the last share, up to the end of
+        // the areas, as this is where the allocations made for the VM itself
+        // already landed.
+        let start_of_mem32_devices = arch::layout::MEM_32BIT_DEVICES_START.0;
+        let end_of_mem32_devices =
+            start_of_mem32_devices + arch::layout::MEM_32BIT_DEVICES_SIZE - 1;
+        let mem32_size =
+            (arch::layout::MEM_32BIT_DEVICES_SIZE / u64::from(num_pci_segments)) & !((1 << 20) - 1);
+        let mem64_size = ((end_of_shared_area - start_of_device_area + 1)
+            / u64::from(num_pci_segments))
+            & !((1 << 32) - 1);
+        if num_pci_segments > 1 && mem64_size == 0 {
+            return Err(DeviceManagerError::PciSegmentAllocation(1));
+        }
+
+        for id in 0..num_pci_segments {
+            let index = u64::from(if id == 0 {
+                num_pci_segments - 1
+            } else {
+                id - 1
+            });
+            let start_of_mem32_area = start_of_mem32_devices + index * mem32_size;
+            let start_of_mem64_area = start_of_device_area + index * mem64_size;
+            // The default segment gets whatever is left once the other
+            // segments got their share.
+            #[cfg(target_arch = "x86_64")]
+            let mut io_area = (0xd00, 0xffff);
+
+            let (allocator, address_manager) = if id == 0 {
+                (
+                    Arc::clone(&self.address_manager.allocator),
+                    Arc::clone(&self.address_manager),
+                )
+            } else {
+                // Reserve the ranges of the segment from the allocator of
+                // the VM, for them to be allocated by the segment only.
+                let mut vm_allocator = self.address_manager.allocator.lock().unwrap();
+                vm_allocator
+                    .allocate_mmio_hole_addresses(
+                        Some(GuestAddress(start_of_mem32_area)),
+                        mem32_size,
+                        None,
+                    )
+                    .ok_or(DeviceManagerError::PciSegmentAllocation(id))?;
+                vm_allocator
+                    .allocate_mmio_addresses(
+                        Some(GuestAddress(start_of_mem64_area)),
+                        mem64_size,
+                        None,
+                    )
+                    .ok_or(DeviceManagerError::PciSegmentAllocation(id))?;
+                #[cfg(target_arch = "x86_64")]
+                let start_of_io_area = vm_allocator
+                    .allocate_io_addresses(None, PCI_SEGMENT_IO_SIZE, Some(PCI_SEGMENT_IO_SIZE))
+                    .ok_or(DeviceManagerError::PciSegmentAllocation(id))?;
+                #[cfg(target_arch = "x86_64")]
+                {
+                    io_area = (
+                        start_of_io_area.0 as u16,
+                        (start_of_io_area.0 + PCI_SEGMENT_IO_SIZE - 1) as u16,
+                    );
+                }
+
+                let allocator = Arc::new(Mutex::new(
+                    SystemAllocator::new(
+                        #[cfg(target_arch = "x86_64")]
+                        start_of_io_area,
+                        #[cfg(target_arch = "x86_64")]
+                        PCI_SEGMENT_IO_SIZE,
+                        GuestAddress(start_of_mem64_area),
+                        mem64_size,
+                        GuestAddress(start_of_mem32_area),
+                        mem32_size,
+                        #[cfg(target_arch = "x86_64")]
+                        Vec::new(),
+                    )
+                    .ok_or(DeviceManagerError::PciSegmentAllocation(id))?,
+                ));
+
+                // Moving the BARs of the devices of the segment must go
+                // through the allocator of the segment.
+                let address_manager = Arc::new(AddressManager {
+                    allocator: Arc::clone(&allocator),
+                    #[cfg(target_arch = "x86_64")]
+                    io_bus: Arc::clone(&self.address_manager.io_bus),
+                    mmio_bus: Arc::clone(&self.address_manager.mmio_bus),
+                    vm: self.address_manager.vm.clone(),
+                    device_tree: Arc::clone(&self.device_tree),
+                });
+
+                (allocator, address_manager)
+            };
+
+            let pci_bus = PciBus::new(
+                PciRoot::new(None),
+                address_manager as Arc<dyn DeviceRelocation>,
+            );
+
+            self.pci_segments.push(PciSegment {
+                id,
+                pci_bus: Arc::new(Mutex::new(pci_bus)),
+                allocator,
+                mmio_config_address: arch::layout::PCI_MMCONFIG_START.0
+                    + u64::from(id) * arch::layout::PCI_MMIO_CONFIG_SIZE_PER_SEGMENT,
+                start_of_mem32_area,
+                end_of_mem32_area: if id == 0 {
+                    end_of_mem32_devices
+                } else {
+                    start_of_mem32_area + mem32_size - 1
+                },
+                start_of_mem64_area,
+                end_of_mem64_area: if id == 0 {
+                    end_of_device_area
+                } else {
+                    start_of_mem64_area + mem64_size - 1
+                },
+                #[cfg(target_arch = "x86_64")]
+                start_of_io_area: io_area.0,
+                #[cfg(target_arch = "x86_64")]
+                end_of_io_area: io_area.1,
+                pci_slot_events: BTreeMap::new(),
+            });
+        }
+
+        // The IO ports of the other segments are allocated from the top.
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(start_of_io_area) = self
+                .pci_segments
+                .iter()
+                .skip(1)
+                .map(|pci_segment| pci_segment.start_of_io_area)
+                .min()
+            {
+                self.pci_segments[0].end_of_io_area = start_of_io_area - 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Allocator of the guest ranges exposed through the BARs of a device
+    // placed on the given PCI segment.
+    #[cfg_attr(not(feature = "pci_support"), allow(unused_variables))]
+    fn device_allocator(
+        &self,
+        pci_segment_id: u16,
+    ) -> DeviceManagerResult<Arc<Mutex<SystemAllocator>>> {
+        #[cfg(feature = "pci_support")]
+        {
+            if !self.pci_segments.is_empty() {
+                return Ok(Arc::clone(&self.pci_segment(pci_segment_id)?.allocator));
+            }
+        }
+
+        Ok(Arc::clone(&self.address_manager.allocator))
+    }
+
+    #[cfg(feature = "pci_support")]
+    fn pci_segment(&self, id: u16) -> DeviceManagerResult<&PciSegment> {
+        if self.pci_segments.is_empty() {
+            return Err(DeviceManagerError::NoPciBus);
+        }
+
+        self.pci_segments
+            .get(id as usize)
+            .ok_or(DeviceManagerError::InvalidPciSegment(id))
+    }
+
     #[allow(unused_variables)]
     fn add_pci_devices(
         &mut self,
-        virtio_devices: Vec<(VirtioDeviceArc, bool, String)>,
+        virtio_devices: Vec<(VirtioDeviceArc, bool, String, u16)>,
     ) -> DeviceManagerResult<()> {
         #[cfg(feature = "pci_support")]
         {
-            let pci_root = PciRoot::new(None);
-            let mut pci_bus = PciBus::new(
-                pci_root,
-                Arc::clone(&self.address_manager) as Arc<dyn DeviceRelocation>,
-            );
-
             let iommu_id = String::from(IOMMU_DEVICE_NAME);
 
             let (iommu_device, iommu_mapping) = if self.config.lock().unwrap().iommu {
-                let (device, mapping) = virtio_devices::Iommu::new(iommu_id.clone())
-                    .map_err(DeviceManagerError::CreateVirtioIommu)?;
+                let (device, mapping) =
+                    virtio_devices::Iommu::new(iommu_id.clone(), self.msi_reserved_region())
+                        .map_err(DeviceManagerError::CreateVirtioIommu)?;
                 let device = Arc::new(Mutex::new(device));
                 self.iommu_device = Some(Arc::clone(&device));
 
@@ -1007,7 +1576,7 @@ impl DeviceManager {
 
             let mut iommu_attached_devices = Vec::new();
 
-            for (device, iommu_attached, id) in virtio_devices {
+            for (device, iommu_attached, id, pci_segment_id) in virtio_devices {
                 let mapping: &Option<Arc<IommuMapping>> = if iommu_attached {
                     &iommu_mapping
                 } else {
@@ -1016,7 +1585,7 @@ impl DeviceManager {
 
                 let dev_id = self.add_virtio_pci_device(
                     device,
-                    &mut pci_bus,
+                    pci_segment_id,
                     mapping,
                     &interrupt_manager,
                     id,
@@ -1027,8 +1596,9 @@ impl DeviceManager {
                 }
             }
 
-            let mut vfio_iommu_device_ids =
-                self.add_vfio_devices(&mut pci_bus, &interrupt_manager)?;
+            let mut vfio_iommu_device_ids = self.add_vfio_devices(&interrupt_manager)?;
+
+            self.add_nvme_device(&interrupt_manager)?;
 
             iommu_attached_devices.append(&mut vfio_iommu_device_ids);
 
@@ -1041,17 +1611,13 @@ impl DeviceManager {
                 // Because we determined the virtio-iommu b/d/f, we have to
                 // add the device to the PCI topology now. Otherwise, the
                 // b/d/f won't match the virtio-iommu device as expected.
-                self.add_virtio_pci_device(
-                    iommu_device,
-                    &mut pci_bus,
-                    &None,
-                    &interrupt_manager,
-                    iommu_id,
-                )?;
+                self.add_virtio_pci_device(iommu_device, 0, &None, &interrupt_manager, iommu_id)?;
             }
 
-            let pci_bus = Arc::new(Mutex::new(pci_bus));
-            let pci_config_io = Arc::new(Mutex::new(PciConfigIo::new(Arc::clone(&pci_bus))));
+            // Only the default segment can be reached through the legacy
+            // configuration IO ports.
+            let pci_bus = Arc::clone(&self.pci_segment(0)?.pci_bus);
+            let pci_config_io = Arc::new(Mutex::new(PciConfigIo::new(pci_bus)));
             self.bus_devices
                 .push(Arc::clone(&pci_config_io) as Arc<Mutex<dyn BusDevice>>);
             #[cfg(target_arch = "x86_64")]
@@ -1059,19 +1625,22 @@ impl DeviceManager {
                 .io_bus
                 .insert(pci_config_io, 0xcf8, 0x8)
                 .map_err(DeviceManagerError::BusError)?;
-            let pci_config_mmio = Arc::new(Mutex::new(PciConfigMmio::new(Arc::clone(&pci_bus))));
-            self.bus_devices
-                .push(Arc::clone(&pci_config_mmio) as Arc<Mutex<dyn BusDevice>>);
-            self.address_manager
-                .mmio_bus
-                .insert(
-                    pci_config_mmio,
-                    arch::layout::PCI_MMCONFIG_START.0,
-                    arch::layout::PCI_MMCONFIG_SIZE,
-                )
-                .map_err(DeviceManagerError::BusError)?;
 
-            self.pci_bus = Some(pci_bus);
+            for pci_segment in self.pci_segments.iter() {
+                let pci_config_mmio = Arc::new(Mutex::new(PciConfigMmio::new(Arc::clone(
+                    &pci_segment.pci_bus,
+                ))));
+                self.bus_devices
+                    .push(Arc::clone(&pci_config_mmio) as Arc<Mutex<dyn BusDevice>>);
+                self.address_manager
+                    .mmio_bus
+                    .insert(
+                        pci_config_mmio,
+                        pci_segment.mmio_config_address,
+                        arch::layout::PCI_MMIO_CONFIG_SIZE_PER_SEGMENT,
+                    )
+                    .map_err(DeviceManagerError::BusError)?;
+            }
         }
 
         Ok(())
@@ -1080,12 +1649,12 @@ impl DeviceManager {
     #[allow(unused_variables, unused_mut)]
     fn add_mmio_devices(
         &mut self,
-        virtio_devices: Vec<(VirtioDeviceArc, bool, String)>,
+        virtio_devices: Vec<(VirtioDeviceArc, bool, String, u16)>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
     ) -> DeviceManagerResult<()> {
         #[cfg(feature = "mmio_support")]
         {
-            for (device, _, id) in virtio_devices {
+            for (device, _, id, _) in virtio_devices {
                 self.add_virtio_mmio_device(id, device, interrupt_manager)?;
             }
         }
@@ -1299,6 +1868,24 @@ impl DeviceManager {
                 .map_err(DeviceManagerError::BusError)?;
         }
 
+        if self.config.lock().unwrap().pvpanic.is_some() {
+            let pvpanic = Arc::new(Mutex::new(devices::legacy::Pvpanic::new(
+                self.panic_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+            )));
+
+            self.bus_devices
+                .push(Arc::clone(&pvpanic) as Arc<Mutex<dyn BusDevice>>);
+
+            self.address_manager
+                .io_bus
+                .insert(pvpanic.clone(), PVPANIC_PORT, 0x1)
+                .map_err(DeviceManagerError::BusError)?;
+
+            self.pvpanic_device = Some(pvpanic);
+        }
+
         Ok(())
     }
 
@@ -1456,18 +2043,53 @@ impl DeviceManager {
         Ok(serial)
     }
 
+    fn create_console_socket(
+        console_config: &ConsoleConfig,
+    ) -> DeviceManagerResult<Option<ConsoleSocket>> {
+        let endpoint = match console_config.mode {
+            ConsoleOutputMode::Socket => {
+                ConsoleSocketEndpoint::Unix(console_config.socket.clone().unwrap())
+            }
+            ConsoleOutputMode::Tcp => {
+                ConsoleSocketEndpoint::Tcp(console_config.tcp.clone().unwrap())
+            }
+            _ => return Ok(None),
+        };
+
+        ConsoleSocket::new(endpoint, CONSOLE_SOCKET_BUFFER_SIZE)
+            .map(Some)
+            .map_err(DeviceManagerError::CreateConsoleSocket)
+    }
+
     fn add_console_device(
         &mut self,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
-        virtio_devices: &mut Vec<(VirtioDeviceArc, bool, String)>,
+        virtio_devices: &mut Vec<(VirtioDeviceArc, bool, String, u16)>,
     ) -> DeviceManagerResult<Arc<Console>> {
         let serial_config = self.config.lock().unwrap().serial.clone();
+        let serial_socket = Self::create_console_socket(&serial_config)?;
+        let serial_pty = if serial_config.mode == ConsoleOutputMode::Pty {
+            let pty = ConsolePty::new().map_err(DeviceManagerError::CreateSerialPty)?;
+            info!("Serial port available at {:?}", pty.path());
+            // Report the allocated path through the VM information.
+            self.config.lock().unwrap().serial.file = Some(pty.path().to_path_buf());
+            Some(pty)
+        } else {
+            None
+        };
         let serial_writer: Option<Box<dyn io::Write + Send>> = match serial_config.mode {
             ConsoleOutputMode::File => Some(Box::new(
                 File::create(serial_config.file.as_ref().unwrap())
                     .map_err(DeviceManagerError::SerialOutputFileOpen)?,
             )),
+            ConsoleOutputMode::Pty => match &serial_pty {
+                Some(pty) => Some(pty.writer().map_err(DeviceManagerError::CreateSerialPty)?),
+                None => None,
+            },
             ConsoleOutputMode::Tty => Some(Box::new(stdout())),
+            ConsoleOutputMode::Socket | ConsoleOutputMode::Tcp => serial_socket
+                .as_ref()
+                .map(|s| Box::new(s.writer()) as Box<dyn io::Write + Send>),
             ConsoleOutputMode::Off | ConsoleOutputMode::Null => None,
         };
         let serial = if serial_config.mode != ConsoleOutputMode::Off {
@@ -1475,16 +2097,52 @@ impl DeviceManager {
         } else {
             None
         };
+        if let (Some(mut socket), Some(serial)) = (serial_socket, serial.clone()) {
+            socket
+                .start(Box::new(move |data: &[u8]| {
+                    if let Err(e) = serial.lock().unwrap().queue_input_bytes(data) {
+                        error!("Failed to queue serial socket input: {:?}", e);
+                    }
+                }))
+                .map_err(DeviceManagerError::CreateConsoleSocket)?;
+            self.console_sockets.push(socket);
+        }
+        if let (Some(mut pty), Some(serial)) = (serial_pty, serial.clone()) {
+            pty.start(Box::new(move |data: &[u8]| {
+                if let Err(e) = serial.lock().unwrap().queue_input_bytes(data) {
+                    error!("Failed to queue serial pty input: {:?}", e);
+                }
+            }))
+            .map_err(DeviceManagerError::CreateSerialPty)?;
+            self.console_ptys.push(pty);
+        }
 
         // Create serial and virtio-console
         let console_config = self.config.lock().unwrap().console.clone();
+        let console_socket = Self::create_console_socket(&console_config)?;
+        let console_pty = if console_config.mode == ConsoleOutputMode::Pty {
+            let pty = ConsolePty::new().map_err(DeviceManagerError::CreateConsolePty)?;
+            info!("Virtio console available at {:?}", pty.path());
+            // Report the allocated path through the VM information.
+            self.config.lock().unwrap().console.file = Some(pty.path().to_path_buf());
+            Some(pty)
+        } else {
+            None
+        };
         let console_writer: Option<Box<dyn io::Write + Send + Sync>> = match console_config.mode {
             ConsoleOutputMode::File => Some(Box::new(
                 File::create(console_config.file.as_ref().unwrap())
                     .map_err(DeviceManagerError::ConsoleOutputFileOpen)?,
             )),
+            ConsoleOutputMode::Pty => match &console_pty {
+                Some(pty) => Some(pty.writer().map_err(DeviceManagerError::CreateConsolePty)?),
+                None => None,
+            },
             ConsoleOutputMode::Tty => Some(Box::new(stdout())),
             ConsoleOutputMode::Null => Some(Box::new(sink())),
+            ConsoleOutputMode::Socket | ConsoleOutputMode::Tcp => console_socket
+                .as_ref()
+                .map(|s| Box::new(s.writer()) as Box<dyn io::Write + Send + Sync>),
             ConsoleOutputMode::Off => None,
         };
         let (col, row) = get_win_size();
@@ -1499,6 +2157,7 @@ impl DeviceManager {
                 Arc::clone(&virtio_console_device) as VirtioDeviceArc,
                 console_config.iommu,
                 id.clone(),
+                0,
             ));
 
             // Fill the device tree with a new node. In case of restore, we
@@ -1509,6 +2168,39 @@ impl DeviceManager {
                 .unwrap()
                 .insert(id.clone(), device_node!(id, virtio_console_device));
 
+            // The additional ports are set up before the driver starts, so
+            // that it discovers them right away.
+            let mut console_ports = self.config.lock().unwrap().console_ports.clone();
+            if let Some(console_ports) = &mut console_ports {
+                for port_cfg in console_ports.iter_mut() {
+                    let endpoint =
+                        ConsolePortEndpoint::new(&virtio_console_device.lock().unwrap(), port_cfg)
+                            .map_err(DeviceManagerError::CreateConsolePort)?;
+                    self.console_ports.push(endpoint);
+                }
+            }
+            self.config.lock().unwrap().console_ports = console_ports;
+            self.console_device = Some(virtio_console_device);
+
+            if let Some(mut socket) = console_socket {
+                let console_input = console_input.clone();
+                socket
+                    .start(Box::new(move |data: &[u8]| {
+                        console_input.queue_input_bytes(data)
+                    }))
+                    .map_err(DeviceManagerError::CreateConsoleSocket)?;
+                self.console_sockets.push(socket);
+            }
+
+            if let Some(mut pty) = console_pty {
+                let console_input = console_input.clone();
+                pty.start(Box::new(move |data: &[u8]| {
+                    console_input.queue_input_bytes(data)
+                }))
+                .map_err(DeviceManagerError::CreateConsolePty)?;
+                self.console_ptys.push(pty);
+            }
+
             Some(console_input)
         } else {
             None
@@ -1522,8 +2214,10 @@ impl DeviceManager {
         }))
     }
 
-    fn make_virtio_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
-        let mut devices: Vec<(VirtioDeviceArc, bool, String)> = Vec::new();
+    fn make_virtio_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String, u16)>> {
+        let mut devices: Vec<(VirtioDeviceArc, bool, String, u16)> = Vec::new();
 
         // Create "standard" virtio devices (net/block/rng)
         devices.append(&mut self.make_virtio_block_devices()?);
@@ -1544,6 +2238,18 @@ impl DeviceManager {
         // Add virtio-balloon if required
         devices.append(&mut self.make_virtio_balloon_devices()?);
 
+        // Add virtio-watchdog if required
+        devices.append(&mut self.make_virtio_watchdog_devices()?);
+
+        // Add virtio-sound if required
+        devices.append(&mut self.make_virtio_sound_devices()?);
+
+        // Add virtio-gpu if required
+        devices.append(&mut self.make_virtio_gpu_devices()?);
+
+        // Add vDPA devices if required
+        devices.append(&mut self.make_vdpa_devices()?);
+
         Ok(devices)
     }
 
@@ -1556,7 +2262,7 @@ impl DeviceManager {
             .args(&[
                 "--block-backend",
                 &format!(
-                    "path={},socket={},num_queues={},queue_size={}",
+                    "path={},socket={},num_queues={},queue_size={},readonly={},direct={}",
                     disk_cfg
                         .path
                         .as_ref()
@@ -1565,7 +2271,9 @@ impl DeviceManager {
                         .unwrap(),
                     &socket,
                     disk_cfg.num_queues,
-                    disk_cfg.queue_size
+                    disk_cfg.queue_size,
+                    disk_cfg.readonly,
+                    disk_cfg.direct
                 ),
             ])
             .spawn()
@@ -1580,12 +2288,44 @@ impl DeviceManager {
         Ok(socket)
     }
 
-    fn make_virtio_block_device(
-        &mut self,
-        disk_cfg: &mut DiskConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
-        let id = if let Some(id) = &disk_cfg.id {
-            id.clone()
+    fn open_disk_image(&self, disk_cfg: &DiskConfig) -> DeviceManagerResult<qcow::RawFile> {
+        self.open_disk_file(
+            disk_cfg,
+            disk_cfg
+                .path
+                .as_ref()
+                .ok_or(DeviceManagerError::NoDiskPath)?,
+        )
+    }
+
+    // Open a disk image at `path` with the access mode of `disk_cfg`.
+    fn open_disk_file(
+        &self,
+        disk_cfg: &DiskConfig,
+        path: &Path,
+    ) -> DeviceManagerResult<qcow::RawFile> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        options.write(!disk_cfg.readonly);
+        if disk_cfg.direct {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        // Open block device path
+        let image: File = options.open(path).map_err(DeviceManagerError::Disk)?;
+
+        if disk_cfg.direct {
+            check_direct_image_size(image.metadata().map_err(DeviceManagerError::Disk)?.len())?;
+        }
+
+        Ok(qcow::RawFile::new(image, disk_cfg.direct))
+    }
+
+    fn make_virtio_block_device(
+        &mut self,
+        disk_cfg: &mut DiskConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String, u16)> {
+        let id = if let Some(id) = &disk_cfg.id {
+            id.clone()
         } else {
             let id = self.next_device_name(DISK_DEVICE_NAME_PREFIX)?;
             disk_cfg.id = Some(id.clone());
@@ -1620,100 +2360,106 @@ impl DeviceManager {
                 Arc::clone(&vhost_user_block_device) as VirtioDeviceArc,
                 false,
                 id,
+                disk_cfg.pci_segment,
             ))
         } else {
-            let mut options = OpenOptions::new();
-            options.read(true);
-            options.write(!disk_cfg.readonly);
-            if disk_cfg.direct {
-                options.custom_flags(libc::O_DIRECT);
-            }
-            // Open block device path
-            let image: File = options
-                .open(
-                    disk_cfg
-                        .path
-                        .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .clone(),
-                )
-                .map_err(DeviceManagerError::Disk)?;
+            let mut raw_img = match self.handed_over_disks.remove(&id) {
+                Some(file) => qcow::RawFile::new(file, disk_cfg.direct),
+                None => self.open_disk_image(disk_cfg)?,
+            };
+            if disk_cfg.stripe_paths.is_empty() {
+                self.disk_images.insert(
+                    id.clone(),
+                    raw_img.try_clone().map_err(DeviceManagerError::Disk)?,
+                );
+            }
+
+            // Unless specified, the serial reported to the guest is derived
+            // from the device identifier.
+            let serial = disk_cfg.serial.clone().unwrap_or_else(|| id.clone());
 
-            let mut raw_img = qcow::RawFile::new(image, disk_cfg.direct);
+            // Striped disks are only made of raw images.
+            if !disk_cfg.stripe_paths.is_empty() {
+                let mut backends = vec![raw_img];
+                for path in disk_cfg.stripe_paths.iter() {
+                    backends.push(self.open_disk_file(disk_cfg, path)?);
+                }
+                let striped_img = StripedDisk::new(backends, disk_cfg.stripe_size)
+                    .map_err(DeviceManagerError::StripedDiskCreate)?;
+                return self.make_virtio_block(id, striped_img, disk_cfg, serial);
+            }
 
             let image_type = qcow::detect_image_type(&mut raw_img)
                 .map_err(DeviceManagerError::DetectImageType)?;
             match image_type {
-                ImageType::Raw => {
-                    let dev = virtio_devices::Block::new(
-                        id.clone(),
-                        raw_img,
-                        disk_cfg
-                            .path
-                            .as_ref()
-                            .ok_or(DeviceManagerError::NoDiskPath)?
-                            .clone(),
-                        disk_cfg.readonly,
-                        disk_cfg.iommu,
-                        disk_cfg.num_queues,
-                        disk_cfg.queue_size,
-                    )
-                    .map_err(DeviceManagerError::CreateVirtioBlock)?;
-
-                    let block = Arc::new(Mutex::new(dev));
-
-                    // Fill the device tree with a new node. In case of restore, we
-                    // know there is nothing to do, so we can simply override the
-                    // existing entry.
-                    self.device_tree
-                        .lock()
-                        .unwrap()
-                        .insert(id.clone(), device_node!(id, block));
-
-                    Ok((Arc::clone(&block) as VirtioDeviceArc, disk_cfg.iommu, id))
-                }
+                ImageType::Raw => self.make_virtio_block(id, raw_img, disk_cfg, serial),
                 ImageType::Qcow2 => {
                     let qcow_img =
                         QcowFile::from(raw_img).map_err(DeviceManagerError::QcowDeviceCreate)?;
-                    let dev = virtio_devices::Block::new(
-                        id.clone(),
-                        qcow_img,
-                        disk_cfg
-                            .path
-                            .as_ref()
-                            .ok_or(DeviceManagerError::NoDiskPath)?
-                            .clone(),
-                        disk_cfg.readonly,
-                        disk_cfg.iommu,
-                        disk_cfg.num_queues,
-                        disk_cfg.queue_size,
-                    )
-                    .map_err(DeviceManagerError::CreateVirtioBlock)?;
-
-                    let block = Arc::new(Mutex::new(dev));
-
-                    // Fill the device tree with a new node. In case of restore, we
-                    // know there is nothing to do, so we can simply override the
-                    // existing entry.
-                    self.device_tree
-                        .lock()
-                        .unwrap()
-                        .insert(id.clone(), device_node!(id, block));
-
-                    Ok((Arc::clone(&block) as VirtioDeviceArc, disk_cfg.iommu, id))
+                    self.make_virtio_block(id, qcow_img, disk_cfg, serial)
                 }
             }
         }
     }
 
+    fn make_virtio_block<T: 'static + DiskFile + Send>(
+        &mut self,
+        id: String,
+        disk_image: T,
+        disk_cfg: &DiskConfig,
+        serial: String,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String, u16)> {
+        let dev = virtio_devices::Block::new(
+            id.clone(),
+            disk_image,
+            disk_cfg
+                .path
+                .as_ref()
+                .ok_or(DeviceManagerError::NoDiskPath)?
+                .clone(),
+            disk_cfg.readonly,
+            disk_cfg.iommu,
+            disk_cfg.num_queues,
+            disk_cfg.queue_size,
+            Some(serial),
+            disk_cfg.rate_limiter_config,
+            disk_cfg.busy_poll_duration(),
+            virtio_devices::BlockCoalescing {
+                max_requests: disk_cfg.coalesce_max_requests,
+                max_usecs: disk_cfg.coalesce_max_usecs,
+            },
+            self.seccomp_action.clone(),
+        )
+        .map_err(DeviceManagerError::CreateVirtioBlock)?;
+
+        let block = Arc::new(Mutex::new(dev));
+
+        // Fill the device tree with a new node. In case of restore, we
+        // know there is nothing to do, so we can simply override the
+        // existing entry.
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(id.clone(), device_node!(id, block));
+
+        Ok((
+            Arc::clone(&block) as VirtioDeviceArc,
+            disk_cfg.iommu,
+            id,
+            disk_cfg.pci_segment,
+        ))
+    }
+
     fn make_virtio_block_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String, u16)>> {
         let mut devices = Vec::new();
 
         let mut block_devices = self.config.lock().unwrap().disks.clone();
         if let Some(disk_list_cfg) = &mut block_devices {
-            for disk_cfg in disk_list_cfg.iter_mut() {
+            // NVMe disks are exposed as namespaces of the emulated NVMe
+            // controller, which is created along with the PCI devices.
+            for disk_cfg in disk_list_cfg.iter_mut().filter(|d| !d.nvme) {
                 devices.push(self.make_virtio_block_device(disk_cfg)?);
             }
         }
@@ -1759,7 +2505,7 @@ impl DeviceManager {
     fn make_virtio_net_device(
         &mut self,
         net_cfg: &mut NetConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String, u16)> {
         let id = if let Some(id) = &net_cfg.id {
             id.clone()
         } else {
@@ -1780,8 +2526,17 @@ impl DeviceManager {
                 queue_size: net_cfg.queue_size,
             };
             let vhost_user_net_device = Arc::new(Mutex::new(
-                virtio_devices::vhost_user::Net::new(id.clone(), net_cfg.mac, vu_cfg)
-                    .map_err(DeviceManagerError::CreateVhostUserNet)?,
+                virtio_devices::vhost_user::Net::new(
+                    id.clone(),
+                    net_cfg.mac,
+                    vu_cfg,
+                    VhostUserReconnectConfig {
+                        retries: net_cfg.reconnect_retries,
+                        backoff: Duration::from_millis(net_cfg.reconnect_backoff_ms),
+                    },
+                    self.seccomp_action.clone(),
+                )
+                .map_err(DeviceManagerError::CreateVhostUserNet)?,
             ));
 
             // Fill the device tree with a new node. In case of restore, we
@@ -1796,9 +2551,37 @@ impl DeviceManager {
                 Arc::clone(&vhost_user_net_device) as VirtioDeviceArc,
                 net_cfg.iommu,
                 id,
+                net_cfg.pci_segment,
             ))
         } else {
-            let virtio_net_device = if let Some(ref tap_if_name) = net_cfg.tap {
+            let coalescing = virtio_devices::NetCoalescing {
+                max_packets: net_cfg.coalesce_max_packets,
+                max_usecs: net_cfg.coalesce_max_usecs,
+            };
+            let rx_starvation = match net_cfg.rx_starvation {
+                RxStarvationMode::Pause => RxStarvationPolicy::Pause,
+                RxStarvationMode::Drop => RxStarvationPolicy::Drop,
+                RxStarvationMode::Block => RxStarvationPolicy::BlockWithTimeout(
+                    Duration::from_millis(net_cfg.rx_starvation_timeout_ms),
+                ),
+            };
+            let virtio_net_device = if let Some(taps) = self.handed_over_taps.remove(&id) {
+                Arc::new(Mutex::new(
+                    virtio_devices::Net::new_with_tap(
+                        id.clone(),
+                        taps,
+                        Some(net_cfg.mac),
+                        net_cfg.iommu,
+                        net_cfg.num_queues,
+                        net_cfg.queue_size,
+                        coalescing,
+                        rx_starvation,
+                        net_cfg.rate_limiter_config,
+                        self.seccomp_action.clone(),
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?,
+                ))
+            } else if let Some(ref tap_if_name) = net_cfg.tap {
                 Arc::new(Mutex::new(
                     virtio_devices::Net::new(
                         id.clone(),
@@ -1810,6 +2593,10 @@ impl DeviceManager {
                         net_cfg.iommu,
                         net_cfg.num_queues,
                         net_cfg.queue_size,
+                        coalescing,
+                        rx_starvation,
+                        net_cfg.rate_limiter_config,
+                        self.seccomp_action.clone(),
                     )
                     .map_err(DeviceManagerError::CreateVirtioNet)?,
                 ))
@@ -1825,6 +2612,10 @@ impl DeviceManager {
                         net_cfg.iommu,
                         net_cfg.num_queues,
                         net_cfg.queue_size,
+                        coalescing,
+                        rx_starvation,
+                        net_cfg.rate_limiter_config,
+                        self.seccomp_action.clone(),
                     )
                     .map_err(DeviceManagerError::CreateVirtioNet)?,
                 ))
@@ -1838,10 +2629,26 @@ impl DeviceManager {
                 .unwrap()
                 .insert(id.clone(), device_node!(id, virtio_net_device));
 
+            if net_cfg.failover.is_some() {
+                virtio_net_device.lock().unwrap().set_failover_standby();
+            }
+            if net_cfg.rx_timestamps {
+                virtio_net_device.lock().unwrap().enable_rx_timestamps();
+            }
+            #[cfg(all(feature = "pci_support", feature = "kvm"))]
+            if let Some(primary) = &net_cfg.failover {
+                self.failover_pairs
+                    .push(FailoverPair::new(id.clone(), primary.clone()));
+            }
+
+            self.net_devices
+                .insert(id.clone(), Arc::clone(&virtio_net_device));
+
             Ok((
                 Arc::clone(&virtio_net_device) as VirtioDeviceArc,
                 net_cfg.iommu,
                 id,
+                net_cfg.pci_segment,
             ))
         }
     }
@@ -1849,7 +2656,7 @@ impl DeviceManager {
     /// Add virto-net and vhost-user-net devices
     fn make_virtio_net_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String, u16)>> {
         let mut devices = Vec::new();
         let mut net_devices = self.config.lock().unwrap().net.clone();
         if let Some(net_list_cfg) = &mut net_devices {
@@ -1864,7 +2671,7 @@ impl DeviceManager {
 
     fn make_virtio_rng_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String, u16)>> {
         let mut devices = Vec::new();
 
         // Add virtio-rng if required
@@ -1873,13 +2680,20 @@ impl DeviceManager {
             let id = String::from(RNG_DEVICE_NAME);
 
             let virtio_rng_device = Arc::new(Mutex::new(
-                virtio_devices::Rng::new(id.clone(), rng_path, rng_config.iommu)
-                    .map_err(DeviceManagerError::CreateVirtioRng)?,
+                virtio_devices::Rng::new(
+                    id.clone(),
+                    rng_path,
+                    rng_config.iommu,
+                    rng_config.max_bytes,
+                    rng_config.period_ms,
+                )
+                .map_err(DeviceManagerError::CreateVirtioRng)?,
             ));
             devices.push((
                 Arc::clone(&virtio_rng_device) as VirtioDeviceArc,
                 rng_config.iommu,
                 id.clone(),
+                rng_config.pci_segment,
             ));
 
             // Fill the device tree with a new node. In case of restore, we
@@ -1897,7 +2711,7 @@ impl DeviceManager {
     fn make_virtio_fs_device(
         &mut self,
         fs_cfg: &mut FsConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String, u16)> {
         let id = if let Some(id) = &fs_cfg.id {
             id.clone()
         } else {
@@ -1943,8 +2757,7 @@ impl DeviceManager {
                 let (cache_base, cache_size) = if let Some((base, size)) = cache_range {
                     // The memory needs to be 2MiB aligned in order to support
                     // hugepages.
-                    self.address_manager
-                        .allocator
+                    self.device_allocator(fs_cfg.pci_segment)?
                         .lock()
                         .unwrap()
                         .allocate_mmio_addresses(
@@ -1960,8 +2773,7 @@ impl DeviceManager {
                     // The memory needs to be 2MiB aligned in order to support
                     // hugepages.
                     let base = self
-                        .address_manager
-                        .allocator
+                        .device_allocator(fs_cfg.pci_segment)?
                         .lock()
                         .unwrap()
                         .allocate_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
@@ -2028,7 +2840,12 @@ impl DeviceManager {
             node.migratable = Some(Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn Migratable>>);
             self.device_tree.lock().unwrap().insert(id.clone(), node);
 
-            Ok((Arc::clone(&virtio_fs_device) as VirtioDeviceArc, false, id))
+            Ok((
+                Arc::clone(&virtio_fs_device) as VirtioDeviceArc,
+                false,
+                id,
+                fs_cfg.pci_segment,
+            ))
         } else {
             Err(DeviceManagerError::NoVirtioFsSock)
         }
@@ -2036,7 +2853,7 @@ impl DeviceManager {
 
     fn make_virtio_fs_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String, u16)>> {
         let mut devices = Vec::new();
 
         let mut fs_devices = self.config.lock().unwrap().fs.clone();
@@ -2053,7 +2870,7 @@ impl DeviceManager {
     fn make_virtio_pmem_device(
         &mut self,
         pmem_cfg: &mut PmemConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String, u16)> {
         let id = if let Some(id) = &pmem_cfg.id {
             id.clone()
         } else {
@@ -2103,9 +2920,13 @@ impl DeviceManager {
             (0, false)
         };
 
+        // The guest must not be able to modify the backing file, either
+        // because writes are discarded or because the device is readonly.
+        let readonly = pmem_cfg.readonly || pmem_cfg.discard_writes;
+
         let mut file = OpenOptions::new()
             .read(true)
-            .write(!pmem_cfg.discard_writes)
+            .write(!readonly)
             .custom_flags(custom_flags)
             .open(&pmem_cfg.file)
             .map_err(DeviceManagerError::PmemFileOpen)?;
@@ -2128,8 +2949,7 @@ impl DeviceManager {
         let (region_base, region_size) = if let Some((base, size)) = region_range {
             // The memory needs to be 2MiB aligned in order to support
             // hugepages.
-            self.address_manager
-                .allocator
+            self.device_allocator(pmem_cfg.pci_segment)?
                 .lock()
                 .unwrap()
                 .allocate_mmio_addresses(
@@ -2144,8 +2964,7 @@ impl DeviceManager {
             // The memory needs to be 2MiB aligned in order to support
             // hugepages.
             let base = self
-                .address_manager
-                .allocator
+                .device_allocator(pmem_cfg.pci_segment)?
                 .lock()
                 .unwrap()
                 .allocate_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
@@ -2158,7 +2977,7 @@ impl DeviceManager {
         let mmap_region = MmapRegion::build(
             Some(FileOffset::new(cloned_file, 0)),
             region_size as usize,
-            if pmem_cfg.discard_writes {
+            if readonly {
                 PROT_READ
             } else {
                 PROT_READ | PROT_WRITE
@@ -2182,7 +3001,7 @@ impl DeviceManager {
                 region_size,
                 host_addr,
                 pmem_cfg.mergeable,
-                pmem_cfg.discard_writes,
+                readonly,
             )
             .map_err(DeviceManagerError::MemoryManager)?;
 
@@ -2219,12 +3038,13 @@ impl DeviceManager {
             Arc::clone(&virtio_pmem_device) as VirtioDeviceArc,
             pmem_cfg.iommu,
             id,
+            pmem_cfg.pci_segment,
         ))
     }
 
     fn make_virtio_pmem_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String, u16)>> {
         let mut devices = Vec::new();
         // Add virtio-pmem if required
         let mut pmem_devices = self.config.lock().unwrap().pmem.clone();
@@ -2241,7 +3061,7 @@ impl DeviceManager {
     fn make_virtio_vsock_device(
         &mut self,
         vsock_cfg: &mut VsockConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String, u16)> {
         let id = if let Some(id) = &vsock_cfg.id {
             id.clone()
         } else {
@@ -2254,9 +3074,19 @@ impl DeviceManager {
             .socket
             .to_str()
             .ok_or(DeviceManagerError::CreateVsockConvertPath)?;
-        let backend =
-            virtio_devices::vsock::VsockUnixBackend::new(vsock_cfg.cid, socket_path.to_string())
-                .map_err(DeviceManagerError::CreateVsockBackend)?;
+        let idle_timeout = if vsock_cfg.idle_timeout > 0 {
+            Some(Duration::from_secs(vsock_cfg.idle_timeout))
+        } else {
+            None
+        };
+        let backend = virtio_devices::vsock::VsockUnixBackend::new(
+            vsock_cfg.cid,
+            socket_path.to_string(),
+            vsock_cfg.max_connections,
+            idle_timeout,
+            &vsock_cfg.listen_ports,
+        )
+        .map_err(DeviceManagerError::CreateVsockBackend)?;
 
         let vsock_device = Arc::new(Mutex::new(
             virtio_devices::Vsock::new(
@@ -2277,16 +3107,19 @@ impl DeviceManager {
             .unwrap()
             .insert(id.clone(), device_node!(id, vsock_device));
 
+        self.vsock_device = Some(Arc::clone(&vsock_device));
+
         Ok((
             Arc::clone(&vsock_device) as VirtioDeviceArc,
             vsock_cfg.iommu,
             id,
+            vsock_cfg.pci_segment,
         ))
     }
 
     fn make_virtio_vsock_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String, u16)>> {
         let mut devices = Vec::new();
 
         let mut vsock = self.config.lock().unwrap().vsock.clone();
@@ -2300,21 +3133,31 @@ impl DeviceManager {
 
     fn make_virtio_mem_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String, u16)>> {
         let mut devices = Vec::new();
 
         let mm = self.memory_manager.clone();
         let mm = mm.lock().unwrap();
-        if let (Some(region), Some(resize)) = (&mm.virtiomem_region, &mm.virtiomem_resize) {
-            let id = String::from(MEM_DEVICE_NAME);
-
+        let zones = mm
+            .virtiomem_zone
+            .iter()
+            .map(|zone| (String::from(MEM_DEVICE_NAME), zone))
+            .chain(
+                mm.virtiomem_zones
+                    .iter()
+                    .map(|(zone_id, zone)| (format!("{}_{}", MEM_DEVICE_NAME, zone_id), zone)),
+            );
+        for (id, zone) in zones {
             let virtio_mem_device = Arc::new(Mutex::new(
                 virtio_devices::Mem::new(
                     id.clone(),
-                    &region,
-                    resize
+                    zone.region(),
+                    zone.resize_handler()
                         .try_clone()
                         .map_err(DeviceManagerError::TryCloneVirtioMemResize)?,
+                    zone.block_size(),
+                    zone.guest_numa_node().map(|node| node as u16),
+                    zone.hotplugged_size(),
                 )
                 .map_err(DeviceManagerError::CreateVirtioMem)?,
             ));
@@ -2323,6 +3166,7 @@ impl DeviceManager {
                 Arc::clone(&virtio_mem_device) as VirtioDeviceArc,
                 false,
                 id.clone(),
+                0,
             ));
 
             // Fill the device tree with a new node. In case of restore, we
@@ -2339,7 +3183,7 @@ impl DeviceManager {
 
     fn make_virtio_balloon_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String, u16)>> {
         let mut devices = Vec::new();
 
         if self.config.lock().unwrap().memory.balloon {
@@ -2349,6 +3193,10 @@ impl DeviceManager {
                 virtio_devices::Balloon::new(
                     id.clone(),
                     self.config.lock().unwrap().memory.balloon_size,
+                    self.config.lock().unwrap().memory.deflate_on_oom,
+                    self.config.lock().unwrap().memory.autodeflate,
+                    self.config.lock().unwrap().memory.free_page_reporting,
+                    self.config.lock().unwrap().memory.stats_polling_interval,
                 )
                 .map_err(DeviceManagerError::CreateVirtioBalloon)?,
             ));
@@ -2362,6 +3210,7 @@ impl DeviceManager {
                 Arc::clone(&virtio_balloon_device) as VirtioDeviceArc,
                 false,
                 id.clone(),
+                0,
             ));
 
             self.device_tree
@@ -2373,6 +3222,197 @@ impl DeviceManager {
         Ok(devices)
     }
 
+    fn make_virtio_watchdog_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String, u16)>> {
+        let mut devices = Vec::new();
+
+        let watchdog_config = self.config.lock().unwrap().watchdog.clone();
+        if let Some(watchdog_cfg) = watchdog_config {
+            let id = String::from(WATCHDOG_DEVICE_NAME);
+
+            let virtio_watchdog_device = Arc::new(Mutex::new(
+                virtio_devices::Watchdog::new(
+                    id.clone(),
+                    Duration::from_secs(watchdog_cfg.timeout),
+                    self.watchdog_evt
+                        .try_clone()
+                        .map_err(DeviceManagerError::EventFd)?,
+                    watchdog_cfg.iommu,
+                )
+                .map_err(DeviceManagerError::CreateVirtioWatchdog)?,
+            ));
+
+            devices.push((
+                Arc::clone(&virtio_watchdog_device) as VirtioDeviceArc,
+                watchdog_cfg.iommu,
+                id.clone(),
+                watchdog_cfg.pci_segment,
+            ));
+
+            self.device_tree
+                .lock()
+                .unwrap()
+                .insert(id.clone(), device_node!(id, virtio_watchdog_device));
+
+            self.watchdog_device = Some(virtio_watchdog_device);
+        }
+
+        Ok(devices)
+    }
+
+    fn make_sound_backend(
+        sound_cfg: &SoundConfig,
+    ) -> DeviceManagerResult<Box<dyn virtio_devices::PcmBackend>> {
+        let backend: Box<dyn virtio_devices::PcmBackend> = match sound_cfg.backend {
+            SoundBackend::Null => Box::new(virtio_devices::NullBackend::new()),
+            SoundBackend::File => Box::new(
+                virtio_devices::FileBackend::new(
+                    sound_cfg
+                        .path
+                        .as_ref()
+                        .ok_or(DeviceManagerError::NoSoundPath)?,
+                )
+                .map_err(DeviceManagerError::CreateVirtioSoundBackend)?,
+            ),
+            #[cfg(feature = "alsa")]
+            SoundBackend::Alsa => Box::new(
+                virtio_devices::AlsaBackend::new(&sound_cfg.device)
+                    .map_err(DeviceManagerError::CreateVirtioSoundBackend)?,
+            ),
+            #[cfg(not(feature = "alsa"))]
+            SoundBackend::Alsa => return Err(DeviceManagerError::SoundAlsaNotSupported),
+        };
+
+        Ok(backend)
+    }
+
+    fn make_virtio_sound_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String, u16)>> {
+        let mut devices = Vec::new();
+
+        let sound_config = self.config.lock().unwrap().sound.clone();
+        if let Some(sound_cfg) = sound_config {
+            let id = String::from(SOUND_DEVICE_NAME);
+
+            let virtio_sound_device = Arc::new(Mutex::new(virtio_devices::Sound::new(
+                id.clone(),
+                Self::make_sound_backend(&sound_cfg)?,
+                sound_cfg.iommu,
+            )));
+
+            devices.push((
+                Arc::clone(&virtio_sound_device) as VirtioDeviceArc,
+                sound_cfg.iommu,
+                id.clone(),
+                sound_cfg.pci_segment,
+            ));
+
+            self.device_tree
+                .lock()
+                .unwrap()
+                .insert(id.clone(), device_node!(id, virtio_sound_device));
+        }
+
+        Ok(devices)
+    }
+
+    fn make_virtio_gpu_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String, u16)>> {
+        let mut devices = Vec::new();
+
+        let gpu_config = self.config.lock().unwrap().gpu.clone();
+        if let Some(gpu_cfg) = gpu_config {
+            let id = String::from(GPU_DEVICE_NAME);
+
+            let virtio_gpu_device = Arc::new(Mutex::new(virtio_devices::Gpu::new(
+                id.clone(),
+                gpu_cfg.width,
+                gpu_cfg.height,
+                gpu_cfg.iommu,
+            )));
+            let framebuffer = virtio_gpu_device.lock().unwrap().framebuffer();
+            if let Some(path) = &gpu_cfg.shm {
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .open(path)
+                    .map_err(DeviceManagerError::ExportGpuFramebuffer)?;
+                framebuffer
+                    .lock()
+                    .unwrap()
+                    .export(file)
+                    .map_err(DeviceManagerError::ExportGpuFramebuffer)?;
+            }
+            self.gpu_framebuffer = Some(framebuffer);
+
+            devices.push((
+                Arc::clone(&virtio_gpu_device) as VirtioDeviceArc,
+                gpu_cfg.iommu,
+                id.clone(),
+                gpu_cfg.pci_segment,
+            ));
+
+            self.device_tree
+                .lock()
+                .unwrap()
+                .insert(id.clone(), device_node!(id, virtio_gpu_device));
+        }
+
+        Ok(devices)
+    }
+
+    fn make_vdpa_device(
+        &mut self,
+        vdpa_cfg: &mut VdpaConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String, u16)> {
+        let id = if let Some(id) = &vdpa_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(VDPA_DEVICE_NAME_PREFIX)?;
+            vdpa_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Creating vDPA device: {:?}", vdpa_cfg);
+
+        let vdpa_device = Arc::new(Mutex::new(
+            virtio_devices::vdpa::Vdpa::new(id.clone(), &vdpa_cfg.path, vdpa_cfg.num_queues)
+                .map_err(DeviceManagerError::CreateVdpa)?,
+        ));
+
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(id.clone(), device_node!(id, vdpa_device));
+
+        Ok((
+            Arc::clone(&vdpa_device) as VirtioDeviceArc,
+            false,
+            id,
+            vdpa_cfg.pci_segment,
+        ))
+    }
+
+    fn make_vdpa_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String, u16)>> {
+        let mut devices = Vec::new();
+
+        let mut vdpa_devices = self.config.lock().unwrap().vdpa.clone();
+        if let Some(vdpa_list_cfg) = &mut vdpa_devices {
+            for vdpa_cfg in vdpa_list_cfg.iter_mut() {
+                devices.push(self.make_vdpa_device(vdpa_cfg)?);
+            }
+        }
+        self.config.lock().unwrap().vdpa = vdpa_devices;
+
+        Ok(devices)
+    }
+
     #[cfg(not(feature = "pci_support"))]
     fn next_device_name(&mut self, prefix: &str) -> DeviceManagerResult<String> {
         // Generate the temporary name.
@@ -2408,12 +3448,11 @@ impl DeviceManager {
     #[cfg(feature = "pci_support")]
     fn add_passthrough_device(
         &mut self,
-        pci: &mut PciBus,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
         device_cfg: &mut DeviceConfig,
     ) -> DeviceManagerResult<(u32, String)> {
         #[cfg(feature = "kvm")]
-        return self.add_vfio_device(pci, interrupt_manager, device_cfg);
+        return self.add_vfio_device(interrupt_manager, device_cfg);
 
         #[cfg(not(feature = "kvm"))]
         Err(DeviceManagerError::NoDevicePassthroughSupport)
@@ -2422,24 +3461,38 @@ impl DeviceManager {
     #[cfg(all(feature = "pci_support", feature = "kvm"))]
     fn add_vfio_device(
         &mut self,
-        pci: &mut PciBus,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
         device_cfg: &mut DeviceConfig,
     ) -> DeviceManagerResult<(u32, String)> {
+        let pci_segment = self.pci_segment(device_cfg.pci_segment)?;
+        let pci_bus = Arc::clone(&pci_segment.pci_bus);
+        let allocator = Arc::clone(&pci_segment.allocator);
+        let mut pci = pci_bus.lock().unwrap();
+
         let passthrough_device = self
             .passthrough_device
             .as_ref()
             .ok_or(DeviceManagerError::NoDevicePassthroughSupport)?;
 
-        // We need to shift the device id since the 3 first bits
-        // are dedicated to the PCI function, and we know we don't
-        // do multifunction. Also, because we only support one PCI
-        // bus, the bus 0, we don't need to add anything to the
-        // global device ID.
-        let pci_device_bdf = pci
-            .next_device_id()
-            .map_err(DeviceManagerError::NextPciDeviceId)?
-            << 3;
+        // Program the VF before anything else, so that a physical function
+        // refusing the settings doesn't leave the device half plugged. The
+        // original settings get restored if anything below fails.
+        let sriov_vf = if device_cfg.mac.is_some() || device_cfg.vlan.is_some() {
+            Some(
+                VirtualFunction::configure(&device_cfg.path, device_cfg.mac, device_cfg.vlan)
+                    .map_err(DeviceManagerError::SriovVfConfigure)?,
+            )
+        } else {
+            None
+        };
+
+        // Each segment has a single bus, the bus 0, which leaves only the
+        // segment and the device id to encode in the b/d/f.
+        let pci_device_bdf = pci_bdf(
+            device_cfg.pci_segment,
+            pci.next_device_id()
+                .map_err(DeviceManagerError::NextPciDeviceId)?,
+        );
 
         let memory = self.memory_manager.lock().unwrap().guest_memory();
         // SAFETY the raw fd conversion here is safe because:
@@ -2468,10 +3521,11 @@ impl DeviceManager {
                     Arc::new(memory),
                 ));
 
-                iommu
-                    .lock()
-                    .unwrap()
-                    .add_external_mapping(pci_device_bdf, vfio_mapping);
+                let mut iommu = iommu.lock().unwrap();
+                iommu.add_external_mapping(pci_device_bdf, vfio_mapping);
+                // The guest must not use the IOVA ranges the host IOMMU
+                // reserves for this device.
+                iommu.add_reserved_regions(pci_device_bdf, vfio_reserved_regions(&device_cfg.path));
             }
         }
 
@@ -2485,7 +3539,7 @@ impl DeviceManager {
         .map_err(DeviceManagerError::VfioPciCreate)?;
 
         let bars = vfio_pci_device
-            .allocate_bars(&mut self.address_manager.allocator.lock().unwrap())
+            .allocate_bars(&mut allocator.lock().unwrap())
             .map_err(DeviceManagerError::AllocateBars)?;
 
         vfio_pci_device
@@ -2494,6 +3548,16 @@ impl DeviceManager {
             })
             .map_err(DeviceManagerError::VfioMapRegion)?;
 
+        // The device remains usable without its errors being reported.
+        let error_evt = if vfio_pci_device.has_aer() {
+            vfio_pci_device
+                .enable_error_notification()
+                .map_err(|e| warn!("No error reporting for VFIO device: {}", e))
+                .ok()
+        } else {
+            None
+        };
+
         let vfio_pci_device = Arc::new(Mutex::new(vfio_pci_device));
 
         pci.add_device(pci_device_bdf, vfio_pci_device.clone())
@@ -2507,7 +3571,7 @@ impl DeviceManager {
             .push(Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn BusDevice>>);
 
         pci.register_mapping(
-            vfio_pci_device,
+            Arc::clone(&vfio_pci_device),
             #[cfg(target_arch = "x86_64")]
             self.address_manager.io_bus.as_ref(),
             self.address_manager.mmio_bus.as_ref(),
@@ -2527,58 +3591,186 @@ impl DeviceManager {
             id
         };
         self.pci_id_list.insert(vfio_name.clone(), pci_device_bdf);
+        if let Some(sriov_vf) = sriov_vf {
+            self.sriov_vfs.insert(pci_device_bdf, sriov_vf);
+        }
+        self.vfio_device_paths
+            .insert(pci_device_bdf, device_cfg.path.clone());
+
+        if let Some(error_evt) = error_evt {
+            let monitor = ErrorMonitor::start(vfio_name.clone(), vfio_pci_device, error_evt)
+                .map_err(DeviceManagerError::VfioErrorMonitor)?;
+            self.vfio_error_monitors.insert(pci_device_bdf, monitor);
+        }
 
         Ok((pci_device_bdf, vfio_name))
     }
 
     #[cfg(feature = "pci_support")]
-    fn add_vfio_devices(
+    fn make_nvme_namespace(
         &mut self,
-        pci: &mut PciBus,
-        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
-    ) -> DeviceManagerResult<Vec<u32>> {
-        let mut iommu_attached_device_ids = Vec::new();
-        let mut devices = self.config.lock().unwrap().devices.clone();
-
-        if let Some(device_list_cfg) = &mut devices {
-            if self.passthrough_device.is_none() {
-                // Create the passthrough device.
-                self.passthrough_device = Some(
-                    self.address_manager
-                        .vm
-                        .create_passthrough_device()
-                        .map_err(|e| DeviceManagerError::CreatePassthroughDevice(e.into()))?,
-                );
-            }
-
-            for device_cfg in device_list_cfg.iter_mut() {
-                let (device_id, _) =
-                    self.add_passthrough_device(pci, interrupt_manager, device_cfg)?;
-                if device_cfg.iommu && self.iommu_device.is_some() {
-                    iommu_attached_device_ids.push(device_id);
-                }
-            }
+        disk_cfg: &mut DiskConfig,
+    ) -> DeviceManagerResult<NvmeNamespace> {
+        if disk_cfg.id.is_none() {
+            disk_cfg.id = Some(self.next_device_name(DISK_DEVICE_NAME_PREFIX)?);
         }
 
-        // Update the list of devices
-        self.config.lock().unwrap().devices = devices;
+        let mut raw_img = self.open_disk_image(disk_cfg)?;
+        let image_type =
+            qcow::detect_image_type(&mut raw_img).map_err(DeviceManagerError::DetectImageType)?;
+        let disk: Box<dyn NvmeDisk> = match image_type {
+            ImageType::Raw => Box::new(raw_img),
+            ImageType::Qcow2 => {
+                Box::new(QcowFile::from(raw_img).map_err(DeviceManagerError::QcowDeviceCreate)?)
+            }
+        };
 
-        Ok(iommu_attached_device_ids)
+        NvmeNamespace::new(disk, disk_cfg.readonly).map_err(DeviceManagerError::CreateNvmeNamespace)
     }
 
     #[cfg(feature = "pci_support")]
-    fn add_virtio_pci_device(
+    fn add_nvme_device(
         &mut self,
-        virtio_device: VirtioDeviceArc,
-        pci: &mut PciBus,
-        iommu_mapping: &Option<Arc<IommuMapping>>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
-        virtio_device_id: String,
-    ) -> DeviceManagerResult<u32> {
-        let id = format!("{}-{}", VIRTIO_PCI_DEVICE_NAME_PREFIX, virtio_device_id);
+    ) -> DeviceManagerResult<()> {
+        let mut namespaces = Vec::new();
+        let mut queue_size = None;
 
-        // Add the new virtio-pci node to the device tree.
-        let mut node = device_node!(id);
+        // Each disk with nvme enabled becomes a namespace of a single NVMe
+        // controller, following the order of the disks.
+        let mut block_devices = self.config.lock().unwrap().disks.clone();
+        if let Some(disk_list_cfg) = &mut block_devices {
+            for disk_cfg in disk_list_cfg.iter_mut().filter(|d| d.nvme) {
+                namespaces.push(self.make_nvme_namespace(disk_cfg)?);
+                // The controller queues are sized after the first disk.
+                queue_size.get_or_insert(disk_cfg.queue_size);
+            }
+        }
+        self.config.lock().unwrap().disks = block_devices;
+
+        let queue_size = match queue_size {
+            Some(queue_size) => queue_size,
+            None => return Ok(()),
+        };
+
+        // The controller always sits on the default PCI segment.
+        let pci_segment = self.pci_segment(0)?;
+        let pci_bus = Arc::clone(&pci_segment.pci_bus);
+        let allocator = Arc::clone(&pci_segment.allocator);
+        let mut pci = pci_bus.lock().unwrap();
+
+        let id = String::from(NVME_DEVICE_NAME);
+        let pci_device_bdf = pci_bdf(
+            0,
+            pci.next_device_id()
+                .map_err(DeviceManagerError::NextPciDeviceId)?,
+        );
+
+        let mut nvme_device = NvmePciDevice::new(
+            id.clone(),
+            self.memory_manager.lock().unwrap().guest_memory(),
+            namespaces,
+            std::cmp::min(
+                self.config.lock().unwrap().cpus.boot_vcpus as u16,
+                NVME_MAX_IO_QUEUES,
+            ),
+            queue_size,
+            interrupt_manager,
+            pci_device_bdf,
+        )
+        .map_err(DeviceManagerError::CreateNvme)?;
+
+        let bars = nvme_device
+            .allocate_bars(&mut allocator.lock().unwrap())
+            .map_err(DeviceManagerError::AllocateBars)?;
+
+        let nvme_device = Arc::new(Mutex::new(nvme_device));
+
+        pci.add_device(pci_device_bdf, nvme_device.clone())
+            .map_err(DeviceManagerError::AddPciDevice)?;
+
+        self.pci_devices.insert(
+            pci_device_bdf,
+            Arc::clone(&nvme_device) as Arc<dyn Any + Send + Sync>,
+        );
+        self.bus_devices
+            .push(Arc::clone(&nvme_device) as Arc<Mutex<dyn BusDevice>>);
+
+        pci.register_mapping(
+            nvme_device.clone(),
+            #[cfg(target_arch = "x86_64")]
+            self.address_manager.io_bus.as_ref(),
+            self.address_manager.mmio_bus.as_ref(),
+            bars.clone(),
+        )
+        .map_err(DeviceManagerError::AddPciDevice)?;
+
+        // The controller is part of the device tree so that pausing the VM
+        // reaches it, and so that a snapshot request gets refused.
+        let mut node = device_node!(id, nvme_device);
+        for pci_bar in bars.iter() {
+            node.resources.push(Resource::MmioAddressRange {
+                base: pci_bar.0.raw_value(),
+                size: pci_bar.1 as u64,
+            });
+        }
+        node.pci_bdf = Some(pci_device_bdf);
+        self.device_tree.lock().unwrap().insert(id, node);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "pci_support")]
+    fn add_vfio_devices(
+        &mut self,
+        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+    ) -> DeviceManagerResult<Vec<u32>> {
+        let mut iommu_attached_device_ids = Vec::new();
+        let mut devices = self.config.lock().unwrap().devices.clone();
+
+        if let Some(device_list_cfg) = &mut devices {
+            if self.passthrough_device.is_none() {
+                // Create the passthrough device.
+                self.passthrough_device = Some(
+                    self.address_manager
+                        .vm
+                        .create_passthrough_device()
+                        .map_err(|e| DeviceManagerError::CreatePassthroughDevice(e.into()))?,
+                );
+            }
+
+            for device_cfg in device_list_cfg.iter_mut() {
+                let (device_id, _) = self.add_passthrough_device(interrupt_manager, device_cfg)?;
+                if device_cfg.iommu && self.iommu_device.is_some() {
+                    iommu_attached_device_ids.push(device_id);
+                }
+            }
+        }
+
+        // Update the list of devices
+        self.config.lock().unwrap().devices = devices;
+
+        Ok(iommu_attached_device_ids)
+    }
+
+    #[cfg(feature = "pci_support")]
+    fn add_virtio_pci_device(
+        &mut self,
+        virtio_device: VirtioDeviceArc,
+        pci_segment_id: u16,
+        iommu_mapping: &Option<Arc<IommuMapping>>,
+        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        virtio_device_id: String,
+    ) -> DeviceManagerResult<u32> {
+        let id = format!("{}-{}", VIRTIO_PCI_DEVICE_NAME_PREFIX, virtio_device_id);
+
+        let pci_segment = self.pci_segment(pci_segment_id)?;
+        let pci_bus = Arc::clone(&pci_segment.pci_bus);
+        let allocator = Arc::clone(&pci_segment.allocator);
+        let mut pci = pci_bus.lock().unwrap();
+
+        // Add the new virtio-pci node to the device tree.
+        let mut node = device_node!(id);
         node.children = vec![virtio_device_id.clone()];
 
         // Look for the id in the device tree. If it can be found, that means
@@ -2590,7 +3782,7 @@ impl DeviceManager {
                     .pci_bdf
                     .ok_or(DeviceManagerError::MissingDeviceNodePciBdf)?;
 
-                pci.get_device_id((pci_device_bdf >> 3) as usize)
+                pci.get_device_id(((pci_device_bdf >> 3) & 0x1f) as usize)
                     .map_err(DeviceManagerError::GetPciDeviceId)?;
 
                 if node.resources.is_empty() {
@@ -2609,14 +3801,13 @@ impl DeviceManager {
 
                 (pci_device_bdf, config_bar_addr)
             } else {
-                // We need to shift the device id since the 3 first bits are dedicated
-                // to the PCI function, and we know we don't do multifunction.
-                // Also, because we only support one PCI bus, the bus 0, we don't need
-                // to add anything to the global device ID.
-                let pci_device_bdf = pci
-                    .next_device_id()
-                    .map_err(DeviceManagerError::NextPciDeviceId)?
-                    << 3;
+                // Each segment has a single bus, the bus 0, which leaves only
+                // the segment and the device id to encode in the b/d/f.
+                let pci_device_bdf = pci_bdf(
+                    pci_segment_id,
+                    pci.next_device_id()
+                        .map_err(DeviceManagerError::NextPciDeviceId)?,
+                );
 
                 (pci_device_bdf, None)
             };
@@ -2655,12 +3846,14 @@ impl DeviceManager {
             };
 
         let memory = self.memory_manager.lock().unwrap().guest_memory();
+        let dirty_log = self.memory_manager.lock().unwrap().device_dirty_log();
         let mut virtio_pci_device = VirtioPciDevice::new(
             id.clone(),
             memory,
             virtio_device,
             msix_num,
             iommu_mapping_cb,
+            Some(dirty_log),
             interrupt_manager,
             pci_device_bdf,
         )
@@ -2672,7 +3865,6 @@ impl DeviceManager {
             virtio_pci_device.set_config_bar_addr(addr);
         }
 
-        let allocator = self.address_manager.allocator.clone();
         let mut allocator = allocator.lock().unwrap();
         let bars = virtio_pci_device
             .allocate_bars(&mut allocator)
@@ -2832,9 +4024,14 @@ impl DeviceManager {
         }
 
         let memory = self.memory_manager.lock().unwrap().guest_memory();
-        let mut mmio_device =
-            virtio_devices::transport::MmioDevice::new(id.clone(), memory, virtio_device)
-                .map_err(DeviceManagerError::VirtioDevice)?;
+        let dirty_log = self.memory_manager.lock().unwrap().device_dirty_log();
+        let mut mmio_device = virtio_devices::transport::MmioDevice::new(
+            id.clone(),
+            memory,
+            virtio_device,
+            Some(dirty_log),
+        )
+        .map_err(DeviceManagerError::VirtioDevice)?;
 
         for (i, (event, addr)) in mmio_device.ioeventfds(mmio_base).iter().enumerate() {
             let io_addr = IoEventAddress::Mmio(*addr);
@@ -2905,13 +4102,159 @@ impl DeviceManager {
         &self.console
     }
 
+    pub fn gpu_framebuffer(&self) -> Option<&Arc<Mutex<virtio_devices::Framebuffer>>> {
+        self.gpu_framebuffer.as_ref()
+    }
+
+    pub fn watchdog_info(&self) -> Option<virtio_devices::WatchdogInfo> {
+        self.watchdog_device
+            .as_ref()
+            .map(|watchdog| watchdog.lock().unwrap().info())
+    }
+
+    pub fn pvpanic_info(&self) -> Option<devices::legacy::PvpanicInfo> {
+        self.pvpanic_device
+            .as_ref()
+            .and_then(|pvpanic| pvpanic.lock().unwrap().info())
+    }
+
+    pub fn clear_pvpanic(&self) {
+        if let Some(pvpanic) = &self.pvpanic_device {
+            pvpanic.lock().unwrap().clear();
+        }
+    }
+
+    pub fn vsock_info(&self) -> Option<virtio_devices::VsockInfo> {
+        self.vsock_device
+            .as_ref()
+            .map(|vsock| vsock.lock().unwrap().info())
+    }
+
+    pub fn net_link_state(&self, id: &str) -> DeviceManagerResult<virtio_devices::NetLinkState> {
+        self.net_devices
+            .get(id)
+            .map(|net| net.lock().unwrap().link_state())
+            .ok_or_else(|| DeviceManagerError::NoVirtioNet(id.to_owned()))
+    }
+
+    pub fn set_net_link_up(
+        &self,
+        id: &str,
+        link_up: bool,
+    ) -> DeviceManagerResult<virtio_devices::NetLinkState> {
+        self.net_devices
+            .get(id)
+            .map(|net| net.lock().unwrap().set_link_up(link_up))
+            .ok_or_else(|| DeviceManagerError::NoVirtioNet(id.to_owned()))
+    }
+
+    pub fn net_mac_state(&self, id: &str) -> DeviceManagerResult<virtio_devices::NetMacState> {
+        self.net_devices
+            .get(id)
+            .map(|net| net.lock().unwrap().mac_state())
+            .ok_or_else(|| DeviceManagerError::NoVirtioNet(id.to_owned()))
+    }
+
+    pub fn set_net_mac(
+        &self,
+        id: &str,
+        mac: MacAddr,
+    ) -> DeviceManagerResult<virtio_devices::NetMacState> {
+        self.net_devices
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::NoVirtioNet(id.to_owned()))?
+            .lock()
+            .unwrap()
+            .set_mac(mac)
+            .map_err(DeviceManagerError::SetNetMac)
+    }
+
+    /// Move the traffic of the failover pairs onto their virtio-net device,
+    /// or back onto their VF.
+    #[cfg(all(feature = "pci_support", feature = "kvm"))]
+    pub fn failover(&mut self, standby: bool) -> DeviceManagerResult<()> {
+        for pair in self.failover_pairs.iter_mut() {
+            let net = self.net_devices.get(&pair.standby);
+            let vf = self
+                .pci_id_list
+                .get(&pair.primary)
+                .and_then(|bdf| self.sriov_vfs.get_mut(bdf));
+            let (net, vf) = match (net, vf) {
+                (Some(net), Some(vf)) => (net, vf),
+                _ => {
+                    // The VF was unplugged, leaving the standby device alone.
+                    continue;
+                }
+            };
+
+            let mut net = net.lock().unwrap();
+            if standby {
+                pair.switch_to_standby(vf, &mut *net)
+            } else {
+                pair.switch_to_primary(vf)
+            }
+            .map_err(|e| DeviceManagerError::Failover(pair.standby.clone(), e))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn net_queues_state(
+        &self,
+        id: &str,
+    ) -> DeviceManagerResult<virtio_devices::NetQueuesState> {
+        self.net_devices
+            .get(id)
+            .map(|net| net.lock().unwrap().queues_state())
+            .ok_or_else(|| DeviceManagerError::NoVirtioNet(id.to_owned()))
+    }
+
+    pub fn set_net_queue_pairs(
+        &self,
+        id: &str,
+        queue_pairs: u16,
+    ) -> DeviceManagerResult<virtio_devices::NetQueuesState> {
+        self.net_devices
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::NoVirtioNet(id.to_owned()))?
+            .lock()
+            .unwrap()
+            .set_queue_pairs(queue_pairs)
+            .map_err(DeviceManagerError::SetNetQueuePairs)
+    }
+
+    pub fn net_flow_rules(&self, id: &str) -> DeviceManagerResult<Vec<virtio_devices::FlowRule>> {
+        self.net_devices
+            .get(id)
+            .map(|net| net.lock().unwrap().flow_rules())
+            .ok_or_else(|| DeviceManagerError::NoVirtioNet(id.to_owned()))
+    }
+
+    pub fn set_net_flow_rules(
+        &self,
+        id: &str,
+        rules: Vec<virtio_devices::FlowRule>,
+    ) -> DeviceManagerResult<Vec<virtio_devices::FlowRule>> {
+        self.net_devices
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::NoVirtioNet(id.to_owned()))?
+            .lock()
+            .unwrap()
+            .set_flow_rules(rules)
+            .map_err(DeviceManagerError::SetNetFlowRules)
+    }
+
+    pub fn num_pci_segments(&self) -> u16 {
+        self.config.lock().unwrap().num_pci_segments()
+    }
+
     pub fn cmdline_additions(&self) -> &[String] {
         self.cmdline_additions.as_slice()
     }
 
     pub fn update_memory(&self, _new_region: &Arc<GuestRegionMmap>) -> DeviceManagerResult<()> {
         let memory = self.memory_manager.lock().unwrap().guest_memory();
-        for (virtio_device, _, _) in self.virtio_devices.iter() {
+        for (virtio_device, _, _, _) in self.virtio_devices.iter() {
             virtio_device
                 .lock()
                 .unwrap()
@@ -2960,14 +4303,18 @@ impl DeviceManager {
         &mut self,
         device_cfg: &mut DeviceConfig,
     ) -> DeviceManagerResult<PciDeviceInfo> {
-        let pci = if let Some(pci_bus) = &self.pci_bus {
-            Arc::clone(&pci_bus)
-        } else {
-            return Err(DeviceManagerError::NoPciBus);
-        };
+        self.pci_segment(device_cfg.pci_segment)?;
 
         let interrupt_manager = Arc::clone(&self.msi_interrupt_manager);
 
+        // The endpoints of the vIOMMU are fixed at boot time. Attaching the
+        // device to it would leave it without any DMA mapping, hence it is
+        // given the identity mapping of the guest RAM instead.
+        if device_cfg.iommu {
+            warn!("Placing device behind vIOMMU is not available for hotplugged devices");
+            device_cfg.iommu = false;
+        }
+
         if self.passthrough_device.is_none() {
             // If the passthrough device has not been created yet, it is created
             // here and stored in the DeviceManager structure for future needs.
@@ -2980,10 +4327,9 @@ impl DeviceManager {
         }
 
         let (device_id, device_name) =
-            self.add_passthrough_device(&mut pci.lock().unwrap(), &interrupt_manager, device_cfg)?;
+            self.add_passthrough_device(&interrupt_manager, device_cfg)?;
 
-        // Update the PCIU bitmap
-        self.pci_devices_up |= 1 << (device_id >> 3);
+        self.queue_pci_slot_event(device_id, PciSlotEvent::Insert);
 
         Ok(PciDeviceInfo {
             id: device_name,
@@ -3020,16 +4366,8 @@ impl DeviceManager {
                 return Err(DeviceManagerError::UnknownPciBdf(*pci_device_bdf));
             }
 
-            // Update the PCID bitmap
-            self.pci_devices_down |= 1 << (*pci_device_bdf >> 3);
-
-            // Remove the device from the device tree along with its parent.
-            let mut device_tree = self.device_tree.lock().unwrap();
-            if let Some(node) = device_tree.remove(&id) {
-                if let Some(parent) = &node.parent {
-                    device_tree.remove(parent);
-                }
-            }
+            // The device stays attached until the guest ejects it.
+            self.queue_pci_slot_event(*pci_device_bdf, PciSlotEvent::Eject);
 
             Ok(())
         } else {
@@ -3037,21 +4375,135 @@ impl DeviceManager {
         }
     }
 
+    /// Withdraw the removal of a device the guest didn't eject in time, if
+    /// the guest didn't get the eject request yet.
     #[cfg(feature = "pci_support")]
-    pub fn eject_device(&mut self, device_id: u8) -> DeviceManagerResult<()> {
-        // Retrieve the PCI bus.
-        let pci = if let Some(pci_bus) = &self.pci_bus {
-            Arc::clone(&pci_bus)
-        } else {
-            return Err(DeviceManagerError::NoPciBus);
+    pub fn cancel_device_removal(&mut self, id: &str) {
+        let pci_device_bdf = match self.pci_id_list.get(id) {
+            Some(pci_device_bdf) => *pci_device_bdf,
+            None => return,
+        };
+        let pci_segment_id = (pci_device_bdf >> 16) as usize;
+        let device_id = ((pci_device_bdf >> 3) & 0x1f) as u8;
+        if let Some(pci_segment) = self.pci_segments.get_mut(pci_segment_id) {
+            if let Some(events) = pci_segment.pci_slot_events.get_mut(&device_id) {
+                if events.back() == Some(&PciSlotEvent::Eject) {
+                    events.pop_back();
+                }
+                if events.is_empty() {
+                    pci_segment.pci_slot_events.remove(&device_id);
+                }
+            }
+        }
+    }
+
+    // Queue a hotplug event for the guest, unless the same one is already
+    // the last one pending on the slot.
+    #[cfg(feature = "pci_support")]
+    fn queue_pci_slot_event(&mut self, pci_device_bdf: u32, event: PciSlotEvent) {
+        let pci_segment_id = (pci_device_bdf >> 16) as usize;
+        let device_id = ((pci_device_bdf >> 3) & 0x1f) as u8;
+        if let Some(pci_segment) = self.pci_segments.get_mut(pci_segment_id) {
+            let events = pci_segment
+                .pci_slot_events
+                .entry(device_id)
+                .or_insert_with(VecDeque::new);
+            if events.back() != Some(&event) {
+                events.push_back(event);
+            }
+        }
+    }
+
+    // Bitmap of the slots of the selected segment whose oldest pending event
+    // is `event`, these events being consumed. A slot with an insertion
+    // followed by an eject reports both through PCIU and then PCID, in the
+    // order they happened.
+    #[cfg(feature = "pci_support")]
+    fn consume_pci_slot_events(&mut self, event: PciSlotEvent) -> u32 {
+        let pci_segment = match self
+            .pci_segments
+            .get_mut(self.selected_pci_segment as usize)
+        {
+            Some(pci_segment) => pci_segment,
+            None => return 0,
         };
 
+        let mut bitmap = 0;
+        for (device_id, events) in pci_segment.pci_slot_events.iter_mut() {
+            if events.front() == Some(&event) {
+                events.pop_front();
+                bitmap |= 1 << device_id;
+            }
+        }
+        pci_segment
+            .pci_slot_events
+            .retain(|_, events| !events.is_empty());
+
+        bitmap
+    }
+
+    /// Whether the device is still plugged, which after its removal was
+    /// requested lasts until the guest ejects it.
+    #[cfg(feature = "pci_support")]
+    pub fn is_device_present(&self, id: &str) -> bool {
+        self.pci_id_list.contains_key(id)
+    }
+
+    /// Raise the hotplug notification again if the guest didn't consume it.
+    pub fn retrigger_hotplug(&self) -> DeviceManagerResult<()> {
+        #[cfg(feature = "acpi")]
+        return self
+            .ged_notification_device
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .retrigger()
+            .map_err(DeviceManagerError::HotPlugNotification);
+        #[cfg(not(feature = "acpi"))]
+        return Ok(());
+    }
+
+    #[cfg(feature = "pci_support")]
+    pub fn eject_device(&mut self, pci_segment_id: u16, device_id: u8) -> DeviceManagerResult<()> {
+        // Retrieve the PCI bus of the segment.
+        let pci_segment = self.pci_segment(pci_segment_id)?;
+        let pci = Arc::clone(&pci_segment.pci_bus);
+        let allocator = Arc::clone(&pci_segment.allocator);
+
         // Convert the device ID into the corresponding b/d/f.
-        let pci_device_bdf = (device_id as u32) << 3;
+        let pci_device_bdf = pci_bdf(pci_segment_id, device_id as u32);
 
         // Find the device name corresponding to the PCI b/d/f while removing
         // the device entry.
+        let id = self
+            .pci_id_list
+            .iter()
+            .find(|(_, bdf)| **bdf == pci_device_bdf)
+            .map(|(id, _)| id.clone());
         self.pci_id_list.retain(|_, bdf| *bdf != pci_device_bdf);
+        if let Some(id) = &id {
+            self.net_devices.remove(id);
+            self.disk_images.remove(id);
+
+            // Remove the device from the device tree along with its parent.
+            let mut device_tree = self.device_tree.lock().unwrap();
+            if let Some(node) = device_tree.remove(id) {
+                if let Some(parent) = &node.parent {
+                    device_tree.remove(parent);
+                }
+            }
+        }
+
+        // Whatever the guest didn't consume yet is about a device which is
+        // now gone.
+        self.pci_segments[pci_segment_id as usize]
+            .pci_slot_events
+            .remove(&device_id);
+
+        // Restore the original settings of a programmed SR-IOV VF.
+        #[cfg(feature = "kvm")]
+        self.sriov_vfs.remove(&pci_device_bdf);
 
         // Give the PCI device ID back to the PCI bus.
         pci.lock()
@@ -3063,6 +4515,21 @@ impl DeviceManager {
             let (pci_device, bus_device, virtio_device) = if let Ok(vfio_pci_device) =
                 any_device.clone().downcast::<Mutex<VfioPciDevice>>()
             {
+                // Stop reporting the errors of the device, the monitor
+                // holding a reference onto it.
+                #[cfg(feature = "kvm")]
+                self.vfio_error_monitors.remove(&pci_device_bdf);
+
+                // Leave the device in a clean state for whoever gets it next.
+                // The guest ejected it already, so the removal goes on even
+                // if the device couldn't be cleaned up entirely.
+                #[cfg(feature = "kvm")]
+                if let Some(sysfs_path) = self.vfio_device_paths.remove(&pci_device_bdf) {
+                    if let Err(e) = vfio_pci_device.lock().unwrap().release(&sysfs_path) {
+                        error!("Failed to release VFIO device {:?}: {}", id, e);
+                    }
+                }
+
                 (
                     Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn PciDevice>>,
                     Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn BusDevice>>,
@@ -3091,7 +4558,7 @@ impl DeviceManager {
             pci_device
                 .lock()
                 .unwrap()
-                .free_bars(&mut self.address_manager.allocator.lock().unwrap())
+                .free_bars(&mut allocator.lock().unwrap())
                 .map_err(DeviceManagerError::FreePciBars)?;
 
             // Remove the device from the PCI bus
@@ -3134,8 +4601,14 @@ impl DeviceManager {
 
                 virtio_device.lock().unwrap().shutdown();
 
+                if virtio_device.lock().unwrap().device_type()
+                    == VirtioDeviceType::TYPE_VSOCK as u32
+                {
+                    self.vsock_device = None;
+                }
+
                 self.virtio_devices
-                    .retain(|(d, _, _)| !Arc::ptr_eq(d, &virtio_device));
+                    .retain(|(d, _, _, _)| !Arc::ptr_eq(d, &virtio_device));
             }
 
             // At this point, the device has been removed from all the list and
@@ -3155,16 +4628,13 @@ impl DeviceManager {
         device: VirtioDeviceArc,
         iommu_attached: bool,
         id: String,
+        pci_segment_id: u16,
     ) -> DeviceManagerResult<PciDeviceInfo> {
         if iommu_attached {
             warn!("Placing device behind vIOMMU is not available for hotplugged devices");
         }
 
-        let pci = if let Some(pci_bus) = &self.pci_bus {
-            Arc::clone(&pci_bus)
-        } else {
-            return Err(DeviceManagerError::NoPciBus);
-        };
+        self.pci_segment(pci_segment_id)?;
 
         let interrupt_manager = Arc::clone(&self.msi_interrupt_manager);
 
@@ -3172,50 +4642,70 @@ impl DeviceManager {
         // as the list is used to notify virtio devices about memory updates
         // for instance.
         self.virtio_devices
-            .push((device.clone(), iommu_attached, id.clone()));
+            .push((device.clone(), iommu_attached, id.clone(), pci_segment_id));
 
         let device_id = self.add_virtio_pci_device(
             device,
-            &mut pci.lock().unwrap(),
+            pci_segment_id,
             &None,
             &interrupt_manager,
             id.clone(),
         )?;
 
-        // Update the PCIU bitmap
-        self.pci_devices_up |= 1 << (device_id >> 3);
+        self.queue_pci_slot_event(device_id, PciSlotEvent::Insert);
 
         Ok(PciDeviceInfo { id, bdf: device_id })
     }
 
     #[cfg(feature = "pci_support")]
     pub fn add_disk(&mut self, disk_cfg: &mut DiskConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_block_device(disk_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        if disk_cfg.nvme {
+            return Err(DeviceManagerError::NvmeHotplugNotSupported);
+        }
+
+        let (device, iommu_attached, id, pci_segment_id) =
+            self.make_virtio_block_device(disk_cfg)?;
+        self.hotplug_virtio_pci_device(device, iommu_attached, id, pci_segment_id)
     }
 
     #[cfg(feature = "pci_support")]
     pub fn add_fs(&mut self, fs_cfg: &mut FsConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_fs_device(fs_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        let (device, iommu_attached, id, pci_segment_id) = self.make_virtio_fs_device(fs_cfg)?;
+        self.hotplug_virtio_pci_device(device, iommu_attached, id, pci_segment_id)
     }
 
     #[cfg(feature = "pci_support")]
     pub fn add_pmem(&mut self, pmem_cfg: &mut PmemConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_pmem_device(pmem_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        let (device, iommu_attached, id, pci_segment_id) =
+            self.make_virtio_pmem_device(pmem_cfg)?;
+        self.hotplug_virtio_pci_device(device, iommu_attached, id, pci_segment_id)
     }
 
     #[cfg(feature = "pci_support")]
     pub fn add_net(&mut self, net_cfg: &mut NetConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_net_device(net_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        let (device, iommu_attached, id, pci_segment_id) = self.make_virtio_net_device(net_cfg)?;
+        self.hotplug_virtio_pci_device(device, iommu_attached, id, pci_segment_id)
     }
 
     #[cfg(feature = "pci_support")]
     pub fn add_vsock(&mut self, vsock_cfg: &mut VsockConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_vsock_device(vsock_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        let (device, iommu_attached, id, pci_segment_id) =
+            self.make_virtio_vsock_device(vsock_cfg)?;
+        self.hotplug_virtio_pci_device(device, iommu_attached, id, pci_segment_id)
+    }
+
+    pub fn add_console_port(
+        &mut self,
+        port_cfg: &mut ConsolePortConfig,
+    ) -> DeviceManagerResult<()> {
+        let console_device = self
+            .console_device
+            .as_ref()
+            .ok_or(DeviceManagerError::NoVirtioConsole)?;
+        let endpoint = ConsolePortEndpoint::new(&console_device.lock().unwrap(), port_cfg)
+            .map_err(DeviceManagerError::CreateConsolePort)?;
+        self.console_ports.push(endpoint);
+        Ok(())
     }
 
     pub fn counters(&self) -> HashMap<String, HashMap<&'static str, Wrapping<u64>>> {
@@ -3230,6 +4720,66 @@ impl DeviceManager {
 
         counters
     }
+
+    /// Hand the tap interfaces and disk images over to the devices created
+    /// from then on, the ones of another process being taken over.
+    pub fn take_over(
+        &mut self,
+        taps: Vec<(String, Vec<File>)>,
+        disks: Vec<(String, File)>,
+    ) -> DeviceManagerResult<()> {
+        for (id, files) in taps {
+            let taps = files
+                .into_iter()
+                .map(|file| Tap::from_tap_fd(file.into_raw_fd()))
+                .collect::<Result<Vec<Tap>, _>>()
+                .map_err(DeviceManagerError::TakeOverTap)?;
+            self.handed_over_taps.insert(id, taps);
+        }
+        self.handed_over_disks.extend(disks);
+
+        Ok(())
+    }
+
+    /// File descriptors of the tap interfaces, by net device, and of the
+    /// disk images, by block device, for another process to take them over.
+    pub fn handoff_fds(&self) -> (Vec<(String, Vec<RawFd>)>, Vec<(String, RawFd)>) {
+        let taps = self
+            .net_devices
+            .iter()
+            .map(|(id, net)| (id.clone(), net.lock().unwrap().tap_fds()))
+            .collect();
+        let disks = self
+            .disk_images
+            .iter()
+            .map(|(id, disk)| (id.clone(), disk.as_raw_fd()))
+            .collect();
+
+        (taps, disks)
+    }
+
+    /// Write back what the virtio devices cached of their backend, the VM
+    /// being paused.
+    pub fn flush_devices(&self) -> DeviceManagerResult<()> {
+        for (device, _, id, _) in self.virtio_devices.iter() {
+            device
+                .lock()
+                .unwrap()
+                .flush()
+                .map_err(|e| DeviceManagerError::FlushDevice(id.clone(), e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Shut the virtio devices down, for them to be done with what the guest
+    /// asked for, such as the block devices flushing their disk image. This
+    /// is done once, the devices being out of the list from then on.
+    pub fn shutdown(&mut self) {
+        for (device, _, _, _) in self.virtio_devices.drain(..) {
+            device.lock().unwrap().shutdown();
+        }
+    }
 }
 
 #[cfg(feature = "acpi")]
@@ -3253,7 +4803,7 @@ impl Aml for PciDevSlot {
                     true,
                     vec![&aml::MethodCall::new(
                         "\\_SB_.PHPR.PCEJ".into(),
-                        vec![&aml::Path::new("_SUN")],
+                        vec![&aml::Path::new("_SUN"), &aml::Path::new("_SEG")],
                     )],
                 ),
             ],
@@ -3285,7 +4835,9 @@ impl Aml for PciDevSlotNotify {
 }
 
 #[cfg(feature = "acpi")]
-struct PciDevSlotMethods {}
+struct PciDevSlotMethods {
+    pci_segment_id: u16,
+}
 
 #[cfg(feature = "acpi")]
 impl Aml for PciDevSlotMethods {
@@ -3309,6 +4861,13 @@ impl Aml for PciDevSlotMethods {
                 0,
                 true,
                 vec![
+                    // The hotplug controller reports the events of the
+                    // selected segment.
+                    &aml::Acquire::new("\\_SB_.PHPR.BLCK".into(), 0xffff),
+                    &aml::Store::new(
+                        &aml::Path::new("\\_SB_.PHPR.PSEG"),
+                        &(self.pci_segment_id as usize),
+                    ),
                     &aml::MethodCall::new(
                         "DVNT".into(),
                         vec![&aml::Path::new("\\_SB_.PHPR.PCIU"), &aml::ONE],
@@ -3317,6 +4876,7 @@ impl Aml for PciDevSlotMethods {
                         "DVNT".into(),
                         vec![&aml::Path::new("\\_SB_.PHPR.PCID"), &3usize],
                     ),
+                    &aml::Release::new("\\_SB_.PHPR.BLCK".into()),
                 ],
             )
             .to_aml_bytes(),
@@ -3325,10 +4885,108 @@ impl Aml for PciDevSlotMethods {
     }
 }
 
+#[cfg(all(feature = "acpi", feature = "pci_support"))]
+impl Aml for PciSegment {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let id = self.id as usize;
+
+        let mut pci_dsdt_inner_data: Vec<&dyn aml::Aml> = Vec::new();
+        let hid = aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0A08"));
+        pci_dsdt_inner_data.push(&hid);
+        let cid = aml::Name::new("_CID".into(), &aml::EISAName::new("PNP0A03"));
+        pci_dsdt_inner_data.push(&cid);
+        let adr = aml::Name::new("_ADR".into(), &aml::ZERO);
+        pci_dsdt_inner_data.push(&adr);
+        let seg = aml::Name::new("_SEG".into(), &id);
+        pci_dsdt_inner_data.push(&seg);
+        let uid = aml::Name::new("_UID".into(), &id);
+        pci_dsdt_inner_data.push(&uid);
+        let supp = aml::Name::new("SUPP".into(), &aml::ZERO);
+        pci_dsdt_inner_data.push(&supp);
+
+        let bus_number = aml::AddressSpace::new_bus_number(0x0u16, 0x0u16);
+        #[cfg(target_arch = "x86_64")]
+        let pci_config_io = aml::IO::new(0xcf8, 0xcf8, 1, 0x8);
+        #[cfg(target_arch = "x86_64")]
+        let legacy_io = aml::AddressSpace::new_io(0x0u16, 0xcf7u16);
+        #[cfg(target_arch = "x86_64")]
+        let io = aml::AddressSpace::new_io(self.start_of_io_area, self.end_of_io_area);
+        let mem32 = aml::AddressSpace::new_memory(
+            aml::AddressSpaceCachable::NotCacheable,
+            true,
+            self.start_of_mem32_area as u32,
+            self.end_of_mem32_area as u32,
+        );
+        let mem64 = aml::AddressSpace::new_memory(
+            aml::AddressSpaceCachable::NotCacheable,
+            true,
+            self.start_of_mem64_area,
+            self.end_of_mem64_area,
+        );
+        let mut crs_data: Vec<&dyn aml::Aml> = vec![&bus_number];
+        #[cfg(target_arch = "x86_64")]
+        {
+            // Only the default segment is reachable through the legacy
+            // configuration IO ports.
+            if self.id == 0 {
+                crs_data.push(&pci_config_io);
+                crs_data.push(&legacy_io);
+            }
+            crs_data.push(&io);
+        }
+        crs_data.push(&mem32);
+        crs_data.push(&mem64);
+        let crs = aml::Name::new("_CRS".into(), &aml::ResourceTemplate::new(crs_data));
+        pci_dsdt_inner_data.push(&crs);
+
+        let mut pci_devices = Vec::new();
+        for device_id in 0..32 {
+            let pci_device = PciDevSlot { device_id };
+            pci_devices.push(pci_device);
+        }
+        for pci_device in pci_devices.iter() {
+            pci_dsdt_inner_data.push(pci_device);
+        }
+
+        let pci_device_methods = PciDevSlotMethods {
+            pci_segment_id: self.id,
+        };
+        pci_dsdt_inner_data.push(&pci_device_methods);
+
+        aml::Device::new(
+            format!("_SB_.PCI{:X}", self.id).as_str().into(),
+            pci_dsdt_inner_data,
+        )
+        .to_aml_bytes()
+    }
+}
+
 #[cfg(feature = "acpi")]
 impl Aml for DeviceManager {
     fn to_aml_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
+
+        // Notify each PCI segment about the hotplug events it may have.
+        #[cfg(feature = "pci_support")]
+        let pci_scan_calls: Vec<aml::MethodCall> = self
+            .pci_segments
+            .iter()
+            .map(|pci_segment| {
+                aml::MethodCall::new(
+                    format!("\\_SB_.PCI{:X}.PCNT", pci_segment.id)
+                        .as_str()
+                        .into(),
+                    vec![],
+                )
+            })
+            .collect();
+        #[cfg(not(feature = "pci_support"))]
+        let pci_scan_calls: Vec<aml::MethodCall> = Vec::new();
+        let pci_scan_refs: Vec<&dyn aml::Aml> = pci_scan_calls
+            .iter()
+            .map(|call| call as &dyn aml::Aml)
+            .collect();
+
         // PCI hotplug controller
         bytes.extend_from_slice(
             &aml::Device::new(
@@ -3354,15 +5012,18 @@ impl Aml for DeviceManager {
                             aml::FieldEntry::Named(*b"PCIU", 32),
                             aml::FieldEntry::Named(*b"PCID", 32),
                             aml::FieldEntry::Named(*b"B0EJ", 32),
+                            aml::FieldEntry::Named(*b"PSEG", 32),
                         ],
                     ),
                     &aml::Method::new(
                         "PCEJ".into(),
-                        1,
+                        2,
                         true,
                         vec![
                             // Take lock defined above
                             &aml::Acquire::new("BLCK".into(), 0xffff),
+                            // Select the PCI segment (in second argument)
+                            &aml::Store::new(&aml::Path::new("PSEG"), &aml::Arg(1)),
                             // Write PCI bus number (in first argument) to I/O port via field
                             &aml::ShiftLeft::new(&aml::Path::new("B0EJ"), &aml::ONE, &aml::Arg(0)),
                             // Release lock
@@ -3371,65 +5032,12 @@ impl Aml for DeviceManager {
                             &aml::Return::new(&aml::ZERO),
                         ],
                     ),
+                    &aml::Method::new("PSCN".into(), 0, true, pci_scan_refs),
                 ],
             )
             .to_aml_bytes(),
         );
 
-        let start_of_device_area = self.memory_manager.lock().unwrap().start_of_device_area().0;
-        let end_of_device_area = self.memory_manager.lock().unwrap().end_of_device_area().0;
-
-        let mut pci_dsdt_inner_data: Vec<&dyn aml::Aml> = Vec::new();
-        let hid = aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0A08"));
-        pci_dsdt_inner_data.push(&hid);
-        let cid = aml::Name::new("_CID".into(), &aml::EISAName::new("PNP0A03"));
-        pci_dsdt_inner_data.push(&cid);
-        let adr = aml::Name::new("_ADR".into(), &aml::ZERO);
-        pci_dsdt_inner_data.push(&adr);
-        let seg = aml::Name::new("_SEG".into(), &aml::ZERO);
-        pci_dsdt_inner_data.push(&seg);
-        let uid = aml::Name::new("_UID".into(), &aml::ZERO);
-        pci_dsdt_inner_data.push(&uid);
-        let supp = aml::Name::new("SUPP".into(), &aml::ZERO);
-        pci_dsdt_inner_data.push(&supp);
-        let crs = aml::Name::new(
-            "_CRS".into(),
-            &aml::ResourceTemplate::new(vec![
-                &aml::AddressSpace::new_bus_number(0x0u16, 0xffu16),
-                &aml::IO::new(0xcf8, 0xcf8, 1, 0x8),
-                &aml::AddressSpace::new_io(0x0u16, 0xcf7u16),
-                &aml::AddressSpace::new_io(0xd00u16, 0xffffu16),
-                &aml::AddressSpace::new_memory(
-                    aml::AddressSpaceCachable::NotCacheable,
-                    true,
-                    layout::MEM_32BIT_DEVICES_START.0 as u32,
-                    (layout::MEM_32BIT_DEVICES_START.0 + layout::MEM_32BIT_DEVICES_SIZE - 1) as u32,
-                ),
-                &aml::AddressSpace::new_memory(
-                    aml::AddressSpaceCachable::NotCacheable,
-                    true,
-                    start_of_device_area,
-                    end_of_device_area,
-                ),
-            ]),
-        );
-        pci_dsdt_inner_data.push(&crs);
-
-        let mut pci_devices = Vec::new();
-        for device_id in 0..32 {
-            let pci_device = PciDevSlot { device_id };
-            pci_devices.push(pci_device);
-        }
-        for pci_device in pci_devices.iter() {
-            pci_dsdt_inner_data.push(pci_device);
-        }
-
-        let pci_device_methods = PciDevSlotMethods {};
-        pci_dsdt_inner_data.push(&pci_device_methods);
-
-        let pci_dsdt_data =
-            aml::Device::new("_SB_.PCI0".into(), pci_dsdt_inner_data).to_aml_bytes();
-
         let mbrd_dsdt_data = aml::Device::new(
             "_SB_.MBRD".into(),
             vec![
@@ -3474,11 +5082,36 @@ impl Aml for DeviceManager {
             .unwrap()
             .to_aml_bytes();
 
-        bytes.extend_from_slice(pci_dsdt_data.as_slice());
+        #[cfg(feature = "pci_support")]
+        for pci_segment in self.pci_segments.iter() {
+            bytes.extend_from_slice(pci_segment.to_aml_bytes().as_slice());
+        }
         bytes.extend_from_slice(mbrd_dsdt_data.as_slice());
         if self.config.lock().unwrap().serial.mode != ConsoleOutputMode::Off {
             bytes.extend_from_slice(com1_dsdt_data.as_slice());
         }
+        // Lets the pvpanic driver of the guest bind to the device.
+        #[cfg(target_arch = "x86_64")]
+        if self.pvpanic_device.is_some() {
+            bytes.extend_from_slice(
+                &aml::Device::new(
+                    "_SB_.PEVT".into(),
+                    vec![
+                        &aml::Name::new("_HID".into(), &"QEMU0001"),
+                        &aml::Name::new(
+                            "_CRS".into(),
+                            &aml::ResourceTemplate::new(vec![&aml::IO::new(
+                                PVPANIC_PORT as u16,
+                                PVPANIC_PORT as u16,
+                                1,
+                                1,
+                            )]),
+                        ),
+                    ],
+                )
+                .to_aml_bytes(),
+            );
+        }
         bytes.extend_from_slice(s5_sleep_data.as_slice());
         bytes.extend_from_slice(ged_data.as_slice());
         bytes
@@ -3597,6 +5230,8 @@ const PCIU_FIELD_OFFSET: u64 = 0;
 const PCID_FIELD_OFFSET: u64 = 4;
 #[cfg(feature = "pci_support")]
 const B0EJ_FIELD_OFFSET: u64 = 8;
+#[cfg(feature = "pci_support")]
+const PSEG_FIELD_OFFSET: u64 = 12;
 
 #[cfg(feature = "pci_support")]
 const PCIU_FIELD_SIZE: usize = 4;
@@ -3604,6 +5239,8 @@ const PCIU_FIELD_SIZE: usize = 4;
 const PCID_FIELD_SIZE: usize = 4;
 #[cfg(feature = "pci_support")]
 const B0EJ_FIELD_SIZE: usize = 4;
+#[cfg(feature = "pci_support")]
+const PSEG_FIELD_SIZE: usize = 4;
 
 impl BusDevice for DeviceManager {
     fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
@@ -3611,15 +5248,17 @@ impl BusDevice for DeviceManager {
         match offset {
             PCIU_FIELD_OFFSET => {
                 assert!(data.len() == PCIU_FIELD_SIZE);
-                data.copy_from_slice(&self.pci_devices_up.to_le_bytes());
-                // Clear the PCIU bitmap
-                self.pci_devices_up = 0;
+                let devices_up = self.consume_pci_slot_events(PciSlotEvent::Insert);
+                data.copy_from_slice(&devices_up.to_le_bytes());
             }
             PCID_FIELD_OFFSET => {
                 assert!(data.len() == PCID_FIELD_SIZE);
-                data.copy_from_slice(&self.pci_devices_down.to_le_bytes());
-                // Clear the PCID bitmap
-                self.pci_devices_down = 0;
+                let devices_down = self.consume_pci_slot_events(PciSlotEvent::Eject);
+                data.copy_from_slice(&devices_down.to_le_bytes());
+            }
+            PSEG_FIELD_OFFSET => {
+                assert!(data.len() == PSEG_FIELD_SIZE);
+                data.copy_from_slice(&u32::from(self.selected_pci_segment).to_le_bytes());
             }
             _ => error!(
                 "Accessing unknown location at base 0x{:x}, offset 0x{:x}",
@@ -3645,12 +5284,29 @@ impl BusDevice for DeviceManager {
                 for device_id in 0..32 {
                     let mask = 1u32 << device_id;
                     if (device_bitmap & mask) == mask {
-                        if let Err(e) = self.eject_device(device_id as u8) {
+                        if let Err(e) =
+                            self.eject_device(self.selected_pci_segment, device_id as u8)
+                        {
                             error!("Failed ejecting device {}: {:?}", device_id, e);
                         }
                     }
                 }
             }
+            PSEG_FIELD_OFFSET => {
+                assert!(data.len() == PSEG_FIELD_SIZE);
+                let mut data_array: [u8; 4] = [0, 0, 0, 0];
+                data_array.copy_from_slice(&data[..]);
+                let selected_pci_segment = u32::from_le_bytes(data_array);
+                if selected_pci_segment >= self.pci_segments.len() as u32 {
+                    error!(
+                        "Segment selection out of range: {} >= {}",
+                        selected_pci_segment,
+                        self.pci_segments.len()
+                    );
+                    return;
+                }
+                self.selected_pci_segment = selected_pci_segment as u16;
+            }
             _ => error!(
                 "Accessing unknown location at base 0x{:x}, offset 0x{:x}",
                 base, offset
@@ -3666,8 +5322,20 @@ impl BusDevice for DeviceManager {
 
 impl Drop for DeviceManager {
     fn drop(&mut self) {
-        for (device, _, _) in self.virtio_devices.drain(..) {
-            device.lock().unwrap().shutdown();
-        }
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_direct_image_size() {
+        assert!(check_direct_image_size(0).is_ok());
+        assert!(check_direct_image_size(512).is_ok());
+        assert!(check_direct_image_size(8 << 30).is_ok());
+        assert!(check_direct_image_size(1000).is_err());
+        assert!(check_direct_image_size((1 << 20) + 1).is_err());
     }
 }
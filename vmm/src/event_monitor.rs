@@ -0,0 +1,112 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Reporting of VMM events to an external supervisor.
+//!
+//! Events are written to the file or file descriptor given through
+//! `--event-monitor`, as one JSON object per line, so that they can be
+//! processed as soon as they happen.
+
+use option_parser::{OptionParser, OptionParserError};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::FromRawFd;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    static ref MONITOR: Mutex<Option<File>> = Mutex::new(None);
+}
+
+pub const SYNTAX: &str = "File to report events to, one JSON object per line \
+    \"path=<events_file>|fd=<file_descriptor>\"";
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to parse the event monitor parameters.
+    Parse(OptionParserError),
+    /// Neither a path nor a file descriptor was given.
+    MissingTarget,
+    /// Failed to open the events file.
+    Open(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Parse(e) => write!(f, "Error parsing --event-monitor: {}", e),
+            MissingTarget => write!(f, "Error parsing --event-monitor: path or fd required"),
+            Open(e) => write!(f, "Error opening the event monitor file: {}", e),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    timestamp: u64,
+    source: &'a str,
+    event: &'a str,
+    properties: &'a HashMap<&'a str, String>,
+}
+
+/// Set where events get reported, from the `--event-monitor` parameters.
+pub fn set_monitor(monitor: &str) -> Result<(), Error> {
+    let mut parser = OptionParser::new();
+    parser.add("path").add("fd");
+    parser.parse(monitor).map_err(Error::Parse)?;
+
+    let file = if let Some(path) = parser.get("path") {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::Open)?
+    } else if let Some(fd) = parser.convert::<i32>("fd").map_err(Error::Parse)? {
+        // SAFETY: the file descriptor was handed over to us for this sole
+        // purpose.
+        unsafe { File::from_raw_fd(fd) }
+    } else {
+        return Err(Error::MissingTarget);
+    };
+
+    *MONITOR.lock().unwrap() = Some(file);
+
+    Ok(())
+}
+
+/// Report `event` happening on `source`, nothing being done if no event
+/// monitor was set.
+pub fn event_log(source: &str, event: &str, properties: &HashMap<&str, String>) {
+    let mut monitor = MONITOR.lock().unwrap();
+    let file = match monitor.as_mut() {
+        Some(file) => file,
+        None => return,
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_millis() as u64)
+        .unwrap_or_default();
+    let event = Event {
+        timestamp,
+        source,
+        event,
+        properties,
+    };
+
+    let result = serde_json::to_vec(&event)
+        .map_err(io::Error::from)
+        .and_then(|mut line| {
+            line.push(b'\n');
+            file.write_all(&line)
+        });
+    if let Err(e) = result {
+        warn!("Failed to report {} event {}: {}", source, event.event, e);
+    }
+}
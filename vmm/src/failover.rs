@@ -0,0 +1,200 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Failover of a passthrough SR-IOV VF onto a virtio-net standby device.
+//!
+//! The guest pairs the virtio-net device offering `VIRTIO_NET_F_STANDBY` with
+//! the VF sharing its MAC address, and sends the traffic through the VF as
+//! long as its link is up. The VF can't be migrated, so the traffic is moved
+//! onto the virtio-net device before the VM gets paused, which is where a
+//! snapshot or a migration starts, by bringing the link of the VF down. It
+//! moves back onto the VF once the VM resumes, either on the source if the
+//! migration didn't happen or on the destination.
+
+use crate::sriov::VirtualFunction;
+use anyhow::anyhow;
+use std::fmt;
+
+/// Link of one of the devices of a failover pair.
+pub trait FailoverLink {
+    fn set_link_up(&mut self, link_up: bool) -> anyhow::Result<()>;
+}
+
+/// Device of a failover pair carrying the traffic of the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailoverState {
+    Primary,
+    Standby,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The link of the primary device couldn't be changed.
+    PrimaryLink(anyhow::Error),
+    /// The link of the standby device couldn't be changed.
+    StandbyLink(anyhow::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            PrimaryLink(e) => write!(f, "Failed to set the link of the primary device: {}", e),
+            StandbyLink(e) => write!(f, "Failed to set the link of the standby device: {}", e),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A virtio-net device, identified by `standby`, paired with the VF
+/// identified by `primary`.
+pub struct FailoverPair {
+    pub standby: String,
+    pub primary: String,
+    state: FailoverState,
+}
+
+impl FailoverPair {
+    pub fn new(standby: String, primary: String) -> Self {
+        FailoverPair {
+            standby,
+            primary,
+            state: FailoverState::Primary,
+        }
+    }
+
+    pub fn state(&self) -> FailoverState {
+        self.state
+    }
+
+    /// Move the traffic onto the standby device. Its link comes up before
+    /// the one of the primary device goes down, so that the guest always
+    /// has a link to send the traffic through.
+    pub fn switch_to_standby(
+        &mut self,
+        primary: &mut dyn FailoverLink,
+        standby: &mut dyn FailoverLink,
+    ) -> Result<()> {
+        if self.state == FailoverState::Standby {
+            return Ok(());
+        }
+
+        standby.set_link_up(true).map_err(Error::StandbyLink)?;
+        primary.set_link_up(false).map_err(Error::PrimaryLink)?;
+        self.state = FailoverState::Standby;
+
+        Ok(())
+    }
+
+    /// Move the traffic back onto the primary device, the standby one
+    /// staying up for the next failover.
+    pub fn switch_to_primary(&mut self, primary: &mut dyn FailoverLink) -> Result<()> {
+        if self.state == FailoverState::Primary {
+            return Ok(());
+        }
+
+        primary.set_link_up(true).map_err(Error::PrimaryLink)?;
+        self.state = FailoverState::Primary;
+
+        Ok(())
+    }
+}
+
+impl FailoverLink for virtio_devices::Net {
+    fn set_link_up(&mut self, link_up: bool) -> anyhow::Result<()> {
+        virtio_devices::Net::set_link_up(self, link_up);
+        Ok(())
+    }
+}
+
+impl FailoverLink for VirtualFunction {
+    fn set_link_up(&mut self, link_up: bool) -> anyhow::Result<()> {
+        VirtualFunction::set_link_up(self, link_up).map_err(|e| anyhow!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Link recording its changes in a log shared with the other link.
+    struct TestLink {
+        name: &'static str,
+        log: Rc<RefCell<Vec<String>>>,
+        fail: bool,
+    }
+
+    impl FailoverLink for TestLink {
+        fn set_link_up(&mut self, link_up: bool) -> anyhow::Result<()> {
+            if self.fail {
+                return Err(anyhow!("refused"));
+            }
+            let state = if link_up { "up" } else { "down" };
+            self.log
+                .borrow_mut()
+                .push(format!("{} {}", self.name, state));
+            Ok(())
+        }
+    }
+
+    fn links() -> (TestLink, TestLink, Rc<RefCell<Vec<String>>>) {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let link = |name| TestLink {
+            name,
+            log: log.clone(),
+            fail: false,
+        };
+        (link("vf"), link("virtio"), log.clone())
+    }
+
+    #[test]
+    fn test_failover_handoff() {
+        let (mut vf, mut virtio, log) = links();
+        let mut pair = FailoverPair::new("net0".to_owned(), "vf0".to_owned());
+        assert_eq!(pair.state(), FailoverState::Primary);
+
+        // The VM gets paused for a migration, and resumed on the destination.
+        pair.switch_to_standby(&mut vf, &mut virtio).unwrap();
+        assert_eq!(pair.state(), FailoverState::Standby);
+        pair.switch_to_standby(&mut vf, &mut virtio).unwrap();
+        pair.switch_to_primary(&mut vf).unwrap();
+        assert_eq!(pair.state(), FailoverState::Primary);
+        pair.switch_to_primary(&mut vf).unwrap();
+
+        assert_eq!(
+            *log.borrow(),
+            vec!["virtio up", "vf down", "vf up"]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>()
+        );
+    }
+
+    #[test]
+    fn test_failover_handoff_error() {
+        let (mut vf, mut virtio, log) = links();
+        let mut pair = FailoverPair::new("net0".to_owned(), "vf0".to_owned());
+
+        // The VF link is left untouched if the standby one can't come up.
+        virtio.fail = true;
+        match pair.switch_to_standby(&mut vf, &mut virtio) {
+            Err(Error::StandbyLink(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(pair.state(), FailoverState::Primary);
+        assert!(log.borrow().is_empty());
+
+        // The traffic stays on the standby device until the VF is back.
+        virtio.fail = false;
+        pair.switch_to_standby(&mut vf, &mut virtio).unwrap();
+        vf.fail = true;
+        assert!(pair.switch_to_primary(&mut vf).is_err());
+        assert_eq!(pair.state(), FailoverState::Standby);
+    }
+}
@@ -0,0 +1,1289 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! GDB remote serial protocol stub.
+//!
+//! A single debugger at a time connects to a UNIX or TCP listening socket.
+//! The whole VM is stopped when it attaches, when it interrupts the guest,
+//! and whenever any vCPU hits a breakpoint or completes a single step.
+//! Breakpoints and watchpoints are implemented through the x86 debug
+//! registers, which limits them to 4 altogether, whether the debugger asks
+//! for software or hardware breakpoints. Guest memory is accessed through
+//! the page tables of the vCPU currently selected by the debugger.
+
+use crate::console_socket::{Endpoint, Listener, Stream};
+use crate::cpu::CpuManager;
+use anyhow::anyhow;
+use hypervisor::arch::x86::{HwBreakpoint, HwBreakpointKind};
+use hypervisor::x86_64::{SpecialRegisters, StandardRegisters};
+use libc::EFD_NONBLOCK;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::{result, thread};
+use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Number of hardware breakpoints and watchpoints the debug registers can
+/// hold.
+pub const MAX_BREAKPOINTS: usize = 4;
+
+// Largest packet we accept, advertised to the debugger.
+const PACKET_SIZE: usize = 4096;
+
+const KILL_EVENT: u64 = 0;
+const ACCEPT_EVENT: u64 = 1;
+const CLIENT_EVENT: u64 = 2;
+const DEBUG_EVENT: u64 = 3;
+
+// Signals reported in the stop replies.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot bind the listening socket
+    Bind(io::Error),
+    /// Cannot create the kill EventFd
+    EventFd(io::Error),
+    /// Cannot spawn the GDB stub thread
+    SpawnThread(io::Error),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// What the packet parser got out of the bytes sent by the debugger.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// A complete packet, without its framing and checksum.
+    Packet(Vec<u8>),
+    /// A packet whose checksum doesn't match, the debugger will send it
+    /// again once told so.
+    BadChecksum,
+    /// The debugger wants the guest to stop.
+    Interrupt,
+    /// The last reply was received fine.
+    Ack,
+    /// The last reply must be sent again.
+    Nack,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ParserState {
+    Idle,
+    Data,
+    Checksum,
+    ChecksumLow(u8),
+}
+
+/// Split the stream of bytes sent by the debugger into packets
+/// `$<data>#<checksum>`, acknowledgements and interrupts.
+pub struct PacketParser {
+    state: ParserState,
+    data: Vec<u8>,
+}
+
+impl Default for PacketParser {
+    fn default() -> Self {
+        PacketParser {
+            state: ParserState::Idle,
+            data: Vec::new(),
+        }
+    }
+}
+
+impl PacketParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed one byte to the parser, returning an event once it completes
+    /// one.
+    pub fn feed(&mut self, byte: u8) -> Option<Event> {
+        match self.state {
+            ParserState::Idle => match byte {
+                b'$' => {
+                    self.data.clear();
+                    self.state = ParserState::Data;
+                    None
+                }
+                b'+' => Some(Event::Ack),
+                b'-' => Some(Event::Nack),
+                0x03 => Some(Event::Interrupt),
+                // Anything else between packets is noise.
+                _ => None,
+            },
+            ParserState::Data => {
+                match byte {
+                    b'#' => self.state = ParserState::Checksum,
+                    // A new packet starts, dropping the incomplete one.
+                    b'$' => self.data.clear(),
+                    _ => {
+                        if self.data.len() < PACKET_SIZE {
+                            self.data.push(byte);
+                        }
+                    }
+                }
+                None
+            }
+            ParserState::Checksum => match hex_digit(byte) {
+                Some(high) => {
+                    self.state = ParserState::ChecksumLow(high);
+                    None
+                }
+                None => {
+                    self.state = ParserState::Idle;
+                    Some(Event::BadChecksum)
+                }
+            },
+            ParserState::ChecksumLow(high) => {
+                self.state = ParserState::Idle;
+                match hex_digit(byte) {
+                    Some(low) if ((high << 4) | low) == checksum(&self.data) => {
+                        Some(Event::Packet(std::mem::take(&mut self.data)))
+                    }
+                    _ => Some(Event::BadChecksum),
+                }
+            }
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Frame `data` as a packet, escaping the bytes having a special meaning.
+pub fn encode_packet(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for byte in data {
+        match byte {
+            b'$' | b'#' | b'}' | b'*' => {
+                escaped.push(b'}');
+                escaped.push(byte ^ 0x20);
+            }
+            _ => escaped.push(*byte),
+        }
+    }
+
+    let mut packet = Vec::with_capacity(escaped.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(&escaped);
+    packet.extend_from_slice(format!("#{:02x}", checksum(&escaped)).as_bytes());
+    packet
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() % 2 != 0 {
+        return None;
+    }
+
+    data.chunks(2)
+        .map(|pair| Some((hex_digit(pair[0])? << 4) | hex_digit(pair[1])?))
+        .collect()
+}
+
+fn parse_hex(data: &[u8]) -> Option<u64> {
+    u64::from_str_radix(std::str::from_utf8(data).ok()?, 16).ok()
+}
+
+// "<addr>,<length>" as used by the memory and breakpoint packets.
+fn parse_range(data: &[u8]) -> Option<(u64, u64)> {
+    let mut fields = data.splitn(2, |b| *b == b',');
+    let addr = parse_hex(fields.next()?)?;
+    let len = parse_hex(fields.next()?)?;
+    Some((addr, len))
+}
+
+/// The registers exchanged through the `g` and `G` packets, in the order
+/// of the GDB amd64 register layout. The floating point and vector
+/// registers are left out, the debugger treating them as unavailable.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CoreRegs {
+    /// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp and r8 to r15.
+    pub gprs: [u64; 16],
+    pub rip: u64,
+    pub eflags: u32,
+    /// cs, ss, ds, es, fs and gs.
+    pub segments: [u32; 6],
+}
+
+impl CoreRegs {
+    const SIZE: usize = 16 * 8 + 8 + 4 + 6 * 4;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::SIZE);
+        for gpr in self.gprs.iter() {
+            data.extend_from_slice(&gpr.to_le_bytes());
+        }
+        data.extend_from_slice(&self.rip.to_le_bytes());
+        data.extend_from_slice(&self.eflags.to_le_bytes());
+        for segment in self.segments.iter() {
+            data.extend_from_slice(&segment.to_le_bytes());
+        }
+        data
+    }
+
+    // The debugger sends all the registers it knows about, anything past
+    // the ones we handle is ignored.
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+
+        let u64_at = |offset: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&data[offset..offset + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let u32_at = |offset: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&data[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
+        };
+
+        let mut regs = CoreRegs::default();
+        for (i, gpr) in regs.gprs.iter_mut().enumerate() {
+            *gpr = u64_at(i * 8);
+        }
+        regs.rip = u64_at(128);
+        regs.eflags = u32_at(136);
+        for (i, segment) in regs.segments.iter_mut().enumerate() {
+            *segment = u32_at(140 + i * 4);
+        }
+
+        Some(regs)
+    }
+}
+
+/// The VM as seen by the stub. vCPUs are designated by their index.
+pub trait GdbTarget {
+    fn vcpu_count(&self) -> usize;
+    fn read_regs(&self, vcpu: usize) -> anyhow::Result<CoreRegs>;
+    fn write_regs(&self, vcpu: usize, regs: &CoreRegs) -> anyhow::Result<()>;
+    /// Read guest memory at a virtual address of `vcpu`.
+    fn read_mem(&self, vcpu: usize, addr: u64, data: &mut [u8]) -> anyhow::Result<()>;
+    /// Write guest memory at a virtual address of `vcpu`.
+    fn write_mem(&self, vcpu: usize, addr: u64, data: &[u8]) -> anyhow::Result<()>;
+    /// Run all the vCPUs with the given breakpoints, `step` being the vCPU
+    /// to single-step if any.
+    fn resume(&mut self, breakpoints: &[HwBreakpoint], step: Option<usize>) -> anyhow::Result<()>;
+    /// Stop all the vCPUs, returning the one which hit a breakpoint or
+    /// completed a single step, if any, along with the index of the
+    /// breakpoint it hit.
+    fn pause(&mut self) -> anyhow::Result<Option<(usize, Option<usize>)>>;
+}
+
+/// How the stub handled a packet.
+#[derive(Debug, PartialEq)]
+pub enum Response {
+    /// Send this reply to the debugger.
+    Reply(Vec<u8>),
+    /// The guest runs again, the stop reply is sent once it stops.
+    Resumed,
+    /// The debugger is gone, after sending it this reply if any. The guest
+    /// runs freely again.
+    Detached(Option<Vec<u8>>),
+}
+
+fn reply(data: &str) -> Response {
+    Response::Reply(data.as_bytes().to_vec())
+}
+
+fn error_reply(errno: i32) -> Response {
+    reply(&format!("E{:02x}", errno))
+}
+
+// Breakpoint or watchpoint inserted by the debugger, along with the type
+// it asked for, which tells read watchpoints from access ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Breakpoint {
+    kind: u8,
+    hw: HwBreakpoint,
+}
+
+impl Breakpoint {
+    // The type and "<addr>,<kind>" of a Z or z packet, the kind being the
+    // length of a watchpoint. Software breakpoints are handled as hardware
+    // ones, and read watchpoints as access ones, which x86 lacks.
+    fn parse(kind: u8, data: &[u8]) -> Option<Self> {
+        let (addr, len) = parse_range(data)?;
+        let (kind, hw_kind) = match kind {
+            b'0' | b'1' => {
+                return Some(Breakpoint {
+                    kind: b'1',
+                    hw: HwBreakpoint {
+                        addr,
+                        len: 1,
+                        kind: HwBreakpointKind::Execute,
+                    },
+                })
+            }
+            b'2' => (kind, HwBreakpointKind::Write),
+            b'3' | b'4' => (kind, HwBreakpointKind::Access),
+            _ => return None,
+        };
+        match len {
+            1 | 2 | 4 | 8 if addr % len == 0 => Some(Breakpoint {
+                kind,
+                hw: HwBreakpoint {
+                    addr,
+                    len,
+                    kind: hw_kind,
+                },
+            }),
+            _ => None,
+        }
+    }
+
+    // Stop reply field of a watchpoint hit.
+    fn stop_reason(&self) -> Option<String> {
+        let reason = match self.kind {
+            b'2' => "watch",
+            b'3' => "rwatch",
+            b'4' => "awatch",
+            _ => return None,
+        };
+        Some(format!("{}:{:x};", reason, self.hw.addr))
+    }
+}
+
+/// The protocol state machine, handling the packets sent by the debugger
+/// on a `GdbTarget`.
+pub struct GdbStub<T: GdbTarget> {
+    target: T,
+    breakpoints: Vec<Breakpoint>,
+    // vCPU the register and memory accesses, and the single steps, apply to.
+    current: usize,
+    running: bool,
+    last_signal: u8,
+    // Breakpoint the guest last stopped on.
+    last_hit: Option<Breakpoint>,
+}
+
+impl<T: GdbTarget> GdbStub<T> {
+    pub fn new(target: T) -> Self {
+        GdbStub {
+            target,
+            breakpoints: Vec::new(),
+            current: 0,
+            running: true,
+            last_signal: SIGTRAP,
+            last_hit: None,
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    /// Stop the guest, if running, returning the stop reply to send.
+    /// `interrupted` tells whether the debugger asked for it, otherwise a
+    /// vCPU is expected to have hit a breakpoint.
+    pub fn stop(&mut self, interrupted: bool) -> anyhow::Result<Option<Vec<u8>>> {
+        if !self.running {
+            return Ok(None);
+        }
+
+        let stopped = self.target.pause()?;
+        self.running = false;
+        self.last_hit = None;
+        if let Some((vcpu, hit)) = stopped {
+            self.current = vcpu;
+            self.last_hit = hit.and_then(|i| self.breakpoints.get(i).cloned());
+        }
+        self.last_signal = if interrupted || stopped.is_none() {
+            SIGINT
+        } else {
+            SIGTRAP
+        };
+
+        Ok(Some(self.stop_reply()))
+    }
+
+    /// Remove all the breakpoints and let the guest run, as the debugger
+    /// went away.
+    pub fn detach(&mut self) -> anyhow::Result<()> {
+        self.breakpoints.clear();
+        if !self.running {
+            self.target.resume(&[], None)?;
+            self.running = true;
+        }
+
+        Ok(())
+    }
+
+    fn stop_reply(&self) -> Vec<u8> {
+        let mut reply = format!("T{:02x}thread:{:x};", self.last_signal, self.current + 1);
+        if let Some(reason) = self.last_hit.as_ref().and_then(Breakpoint::stop_reason) {
+            reply.push_str(&reason);
+        }
+        reply.into_bytes()
+    }
+
+    // Thread ids start at 1, 0 and -1 meaning any and all threads.
+    fn parse_thread(&self, data: &[u8]) -> Option<Option<usize>> {
+        if data == b"-1" || data == b"0" {
+            return Some(None);
+        }
+        let id = parse_hex(data)? as usize;
+        if id == 0 || id > self.target.vcpu_count() {
+            return None;
+        }
+        Some(Some(id - 1))
+    }
+
+    fn resume(&mut self, data: &[u8], step: bool) -> Response {
+        // An address to resume at can follow the command.
+        if !data.is_empty() {
+            let addr = match parse_hex(data) {
+                Some(addr) => addr,
+                None => return error_reply(libc::EINVAL),
+            };
+            let regs = self.target.read_regs(self.current).and_then(|mut regs| {
+                regs.rip = addr;
+                self.target.write_regs(self.current, &regs)
+            });
+            if regs.is_err() {
+                return error_reply(libc::EIO);
+            }
+        }
+
+        let step = if step { Some(self.current) } else { None };
+        let breakpoints: Vec<HwBreakpoint> = self.breakpoints.iter().map(|bp| bp.hw).collect();
+        match self.target.resume(&breakpoints, step) {
+            Ok(()) => {
+                self.running = true;
+                Response::Resumed
+            }
+            Err(e) => {
+                error!("Cannot resume the guest: {}", e);
+                error_reply(libc::EIO)
+            }
+        }
+    }
+
+    fn breakpoint(&mut self, data: &[u8], insert: bool) -> Response {
+        if data.len() < 2 || !(b'0'..=b'4').contains(&data[0]) || data[1] != b',' {
+            return reply("");
+        }
+        // Watchpoints must be naturally aligned with a length the debug
+        // registers support.
+        let breakpoint = match Breakpoint::parse(data[0], &data[2..]) {
+            Some(breakpoint) => breakpoint,
+            None => return error_reply(libc::EINVAL),
+        };
+
+        if insert {
+            if !self.breakpoints.contains(&breakpoint) {
+                if self.breakpoints.len() == MAX_BREAKPOINTS {
+                    return error_reply(libc::ENOSPC);
+                }
+                self.breakpoints.push(breakpoint);
+            }
+        } else {
+            self.breakpoints.retain(|bp| *bp != breakpoint);
+        }
+
+        reply("OK")
+    }
+
+    /// Handle a packet, the guest being stopped.
+    pub fn handle_packet(&mut self, packet: &[u8]) -> Response {
+        if packet.is_empty() {
+            return reply("");
+        }
+        let (command, data) = (packet[0], &packet[1..]);
+
+        match command {
+            b'?' => Response::Reply(self.stop_reply()),
+            b'g' => match self.target.read_regs(self.current) {
+                Ok(regs) => reply(&to_hex(&regs.encode())),
+                Err(_) => error_reply(libc::EIO),
+            },
+            b'G' => match from_hex(data).as_deref().and_then(CoreRegs::decode) {
+                Some(regs) => match self.target.write_regs(self.current, &regs) {
+                    Ok(()) => reply("OK"),
+                    Err(_) => error_reply(libc::EIO),
+                },
+                None => error_reply(libc::EINVAL),
+            },
+            b'm' => match parse_range(data) {
+                Some((addr, len)) => {
+                    let mut buf = vec![0u8; std::cmp::min(len as usize, PACKET_SIZE / 2)];
+                    match self.target.read_mem(self.current, addr, &mut buf) {
+                        Ok(()) => reply(&to_hex(&buf)),
+                        Err(_) => error_reply(libc::EFAULT),
+                    }
+                }
+                None => error_reply(libc::EINVAL),
+            },
+            b'M' => {
+                let mut fields = data.splitn(2, |b| *b == b':');
+                let range = fields.next().and_then(parse_range);
+                let bytes = fields.next().and_then(from_hex);
+                match (range, bytes) {
+                    (Some((addr, len)), Some(bytes)) if len as usize == bytes.len() => {
+                        match self.target.write_mem(self.current, addr, &bytes) {
+                            Ok(()) => reply("OK"),
+                            Err(_) => error_reply(libc::EFAULT),
+                        }
+                    }
+                    _ => error_reply(libc::EINVAL),
+                }
+            }
+            b'Z' => self.breakpoint(data, true),
+            b'z' => self.breakpoint(data, false),
+            b'c' => self.resume(data, false),
+            b's' => self.resume(data, true),
+            b'H' if !data.is_empty() => match self.parse_thread(&data[1..]) {
+                Some(vcpu) => {
+                    if let Some(vcpu) = vcpu {
+                        self.current = vcpu;
+                    }
+                    reply("OK")
+                }
+                None => error_reply(libc::ESRCH),
+            },
+            b'T' => match self.parse_thread(data) {
+                Some(_) => reply("OK"),
+                None => error_reply(libc::ESRCH),
+            },
+            b'D' => Response::Detached(Some(b"OK".to_vec())),
+            b'k' => Response::Detached(None),
+            b'q' => self.query(data),
+            // Leaving vCont unsupported makes the debugger fall back to
+            // the c and s packets.
+            _ => reply(""),
+        }
+    }
+
+    fn query(&self, data: &[u8]) -> Response {
+        if data.starts_with(b"Supported") {
+            reply(&format!("PacketSize={:x}", PACKET_SIZE))
+        } else if data.starts_with(b"Attached") {
+            // Detaching leaves the guest running.
+            reply("1")
+        } else if data == b"C" {
+            reply(&format!("QC{:x}", self.current + 1))
+        } else if data == b"fThreadInfo" {
+            let threads: Vec<String> = (1..=self.target.vcpu_count())
+                .map(|id| format!("{:x}", id))
+                .collect();
+            reply(&format!("m{}", threads.join(",")))
+        } else if data == b"sThreadInfo" {
+            reply("l")
+        } else {
+            reply("")
+        }
+    }
+}
+
+// Page table entry bits.
+const PTE_PRESENT: u64 = 1;
+const PTE_PAGE_SIZE: u64 = 1 << 7;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const CR0_PG: u64 = 1 << 31;
+const EFER_LMA: u64 = 1 << 10;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Translate a guest virtual address through the page tables the vCPU
+/// runs with. Only the identity mapping of a vCPU without paging and the
+/// 4-level paging of long mode are handled.
+pub fn translate_gva(
+    mem: &GuestMemoryMmap,
+    sregs: &SpecialRegisters,
+    gva: u64,
+) -> anyhow::Result<GuestAddress> {
+    if sregs.cr0 & CR0_PG == 0 {
+        return Ok(GuestAddress(gva));
+    }
+    if sregs.efer & EFER_LMA == 0 {
+        return Err(anyhow!("Only long mode paging is supported"));
+    }
+
+    let mut table = sregs.cr3 & PTE_ADDR_MASK;
+    for shift in [39u64, 30, 21, 12].iter() {
+        let index = (gva >> shift) & 0x1ff;
+        let entry: u64 = mem
+            .read_obj(GuestAddress(table + index * 8))
+            .map_err(|e| anyhow!("Cannot read page table entry: {}", e))?;
+        if entry & PTE_PRESENT == 0 {
+            return Err(anyhow!("Address 0x{:x} isn't mapped", gva));
+        }
+
+        // 1GiB and 2MiB pages.
+        let page_mask = (1u64 << shift) - 1;
+        if (*shift == 30 || *shift == 21) && entry & PTE_PAGE_SIZE != 0 {
+            return Ok(GuestAddress(
+                (entry & PTE_ADDR_MASK & !page_mask) | (gva & page_mask),
+            ));
+        }
+        table = entry & PTE_ADDR_MASK;
+    }
+
+    Ok(GuestAddress(table | (gva & (PAGE_SIZE - 1))))
+}
+
+/// The VM driven by the stub, through the `CpuManager`.
+pub struct VmTarget {
+    cpu_manager: Arc<Mutex<CpuManager>>,
+    memory: GuestMemoryAtomic<GuestMemoryMmap>,
+}
+
+impl VmTarget {
+    pub fn new(
+        cpu_manager: Arc<Mutex<CpuManager>>,
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+    ) -> Self {
+        VmTarget {
+            cpu_manager,
+            memory,
+        }
+    }
+
+    fn regs(&self, vcpu: usize) -> anyhow::Result<(StandardRegisters, SpecialRegisters)> {
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .debug_get_regs(vcpu as u8)
+            .map_err(|e| anyhow!("Cannot get the vCPU registers: {:?}", e))
+    }
+
+    // Split the access in chunks not crossing guest pages, as contiguous
+    // virtual pages needn't be contiguous in guest memory.
+    fn for_each_page<F>(&self, vcpu: usize, addr: u64, len: usize, mut f: F) -> anyhow::Result<()>
+    where
+        F: FnMut(&GuestMemoryMmap, GuestAddress, std::ops::Range<usize>) -> anyhow::Result<()>,
+    {
+        let (_, sregs) = self.regs(vcpu)?;
+        let mem = self.memory.memory();
+        let mut offset = 0;
+        while offset < len {
+            let gva = addr + offset as u64;
+            let count = std::cmp::min(len - offset, (PAGE_SIZE - (gva & (PAGE_SIZE - 1))) as usize);
+            let gpa = translate_gva(&mem, &sregs, gva)?;
+            f(&mem, gpa, offset..offset + count)?;
+            offset += count;
+        }
+
+        Ok(())
+    }
+}
+
+impl GdbTarget for VmTarget {
+    fn vcpu_count(&self) -> usize {
+        usize::from(self.cpu_manager.lock().unwrap().debug_vcpu_count())
+    }
+
+    fn read_regs(&self, vcpu: usize) -> anyhow::Result<CoreRegs> {
+        let (regs, sregs) = self.regs(vcpu)?;
+        Ok(CoreRegs {
+            gprs: [
+                regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp,
+                regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+            ],
+            rip: regs.rip,
+            eflags: regs.rflags as u32,
+            segments: [
+                u32::from(sregs.cs.selector),
+                u32::from(sregs.ss.selector),
+                u32::from(sregs.ds.selector),
+                u32::from(sregs.es.selector),
+                u32::from(sregs.fs.selector),
+                u32::from(sregs.gs.selector),
+            ],
+        })
+    }
+
+    // The segment registers can't be changed from their selector alone,
+    // so only the general purpose ones, rip and eflags are written.
+    fn write_regs(&self, vcpu: usize, core: &CoreRegs) -> anyhow::Result<()> {
+        let (mut regs, _) = self.regs(vcpu)?;
+        let g = &core.gprs;
+        regs.rax = g[0];
+        regs.rbx = g[1];
+        regs.rcx = g[2];
+        regs.rdx = g[3];
+        regs.rsi = g[4];
+        regs.rdi = g[5];
+        regs.rbp = g[6];
+        regs.rsp = g[7];
+        regs.r8 = g[8];
+        regs.r9 = g[9];
+        regs.r10 = g[10];
+        regs.r11 = g[11];
+        regs.r12 = g[12];
+        regs.r13 = g[13];
+        regs.r14 = g[14];
+        regs.r15 = g[15];
+        regs.rip = core.rip;
+        regs.rflags = (regs.rflags & !0xffff_ffff) | u64::from(core.eflags);
+
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .debug_set_regs(vcpu as u8, &regs)
+            .map_err(|e| anyhow!("Cannot set the vCPU registers: {:?}", e))
+    }
+
+    fn read_mem(&self, vcpu: usize, addr: u64, data: &mut [u8]) -> anyhow::Result<()> {
+        let len = data.len();
+        self.for_each_page(vcpu, addr, len, |mem, gpa, range| {
+            mem.read_slice(&mut data[range], gpa)
+                .map_err(|e| anyhow!("Cannot read guest memory: {}", e))
+        })
+    }
+
+    fn write_mem(&self, vcpu: usize, addr: u64, data: &[u8]) -> anyhow::Result<()> {
+        self.for_each_page(vcpu, addr, data.len(), |mem, gpa, range| {
+            mem.write_slice(&data[range], gpa)
+                .map_err(|e| anyhow!("Cannot write guest memory: {}", e))
+        })
+    }
+
+    fn resume(&mut self, breakpoints: &[HwBreakpoint], step: Option<usize>) -> anyhow::Result<()> {
+        let cpu_manager = self.cpu_manager.lock().unwrap();
+        cpu_manager
+            .debug_set_breakpoints(breakpoints, step.map(|vcpu| vcpu as u8))
+            .map_err(|e| anyhow!("Cannot set the breakpoints: {:?}", e))?;
+        cpu_manager.debug_resume();
+
+        Ok(())
+    }
+
+    fn pause(&mut self) -> anyhow::Result<Option<(usize, Option<usize>)>> {
+        let cpu_manager = self.cpu_manager.lock().unwrap();
+        cpu_manager.debug_pause();
+
+        Ok(cpu_manager
+            .debug_stopped_vcpus()
+            .first()
+            .map(|(vcpu, hit)| (usize::from(*vcpu), *hit)))
+    }
+}
+
+/// Listening socket the debugger connects to. The stub thread is stopped,
+/// and the UNIX socket removed, when the server is dropped.
+pub struct GdbServer {
+    endpoint: Endpoint,
+    kill_evt: EventFd,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl GdbServer {
+    /// Listen on `endpoint`, `debug_evt` being written to whenever a vCPU
+    /// stops on a breakpoint or a single step.
+    pub fn new<T: GdbTarget + Send + 'static>(
+        endpoint: Endpoint,
+        target: T,
+        debug_evt: EventFd,
+    ) -> Result<Self> {
+        let listener = Listener::bind(&endpoint).map_err(Error::Bind)?;
+        let kill_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let thread_kill_evt = kill_evt.try_clone().map_err(Error::EventFd)?;
+
+        let thread = thread::Builder::new()
+            .name(String::from("gdb"))
+            .spawn(move || {
+                let stub = GdbStub::new(target);
+                if let Err(e) = run(listener, stub, thread_kill_evt, debug_evt) {
+                    error!("Error running GDB stub thread: {}", e);
+                }
+            })
+            .map_err(Error::SpawnThread)?;
+
+        Ok(GdbServer {
+            endpoint,
+            kill_evt,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for GdbServer {
+    fn drop(&mut self) {
+        let _ = self.kill_evt.write(1);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        if let Endpoint::Unix(path) = &self.endpoint {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn epoll_ctl(epoll_fd: RawFd, op: epoll::ControlOptions, fd: RawFd, data: u64) -> io::Result<()> {
+    epoll::ctl(
+        epoll_fd,
+        op,
+        fd,
+        epoll::Event::new(epoll::Events::EPOLLIN, data),
+    )
+}
+
+fn to_io_error(e: anyhow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+// The debugger connection, with the last reply kept around in case the
+// debugger asks for it again.
+struct Client {
+    stream: Stream,
+    parser: PacketParser,
+    last_reply: Vec<u8>,
+}
+
+impl Client {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.last_reply = encode_packet(data);
+        self.stream.write_all(&self.last_reply)
+    }
+}
+
+fn run<T: GdbTarget>(
+    listener: Listener,
+    mut stub: GdbStub<T>,
+    kill_evt: EventFd,
+    debug_evt: EventFd,
+) -> io::Result<()> {
+    let epoll_fd = epoll::create(true)?;
+    // Safe because the fd was just created and is owned by this file from
+    // now on, which closes it on drop.
+    let _epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+
+    let add = epoll::ControlOptions::EPOLL_CTL_ADD;
+    epoll_ctl(epoll_fd, add, kill_evt.as_raw_fd(), KILL_EVENT)?;
+    epoll_ctl(epoll_fd, add, listener.as_raw_fd(), ACCEPT_EVENT)?;
+    epoll_ctl(epoll_fd, add, debug_evt.as_raw_fd(), DEBUG_EVENT)?;
+
+    let mut client: Option<Client> = None;
+    let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 4];
+    let mut buf = [0u8; 1024];
+
+    loop {
+        let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+            Ok(num_events) => num_events,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        for event in events.iter().take(num_events) {
+            let ev_type = event.data;
+            match ev_type {
+                KILL_EVENT => {
+                    stub.detach().map_err(to_io_error)?;
+                    return Ok(());
+                }
+                ACCEPT_EVENT => {
+                    let stream = listener.accept()?;
+                    if client.is_some() {
+                        warn!("Refusing GDB connection, a debugger is already attached");
+                        continue;
+                    }
+                    stream.set_nonblocking(true)?;
+                    epoll_ctl(epoll_fd, add, stream.as_raw_fd(), CLIENT_EVENT)?;
+                    // The debugger expects the guest to be stopped once
+                    // attached, and asks for the stop reason itself.
+                    stub.stop(true).map_err(to_io_error)?;
+                    client = Some(Client {
+                        stream,
+                        parser: PacketParser::new(),
+                        last_reply: Vec::new(),
+                    });
+                }
+                DEBUG_EVENT => {
+                    let _ = debug_evt.read();
+                    if let Some(reply) = stub.stop(false).map_err(to_io_error)? {
+                        match client.as_mut() {
+                            Some(client) => client.send(&reply)?,
+                            // Left over breakpoints from a debugger which
+                            // went away.
+                            None => stub.detach().map_err(to_io_error)?,
+                        }
+                    }
+                }
+                CLIENT_EVENT => {
+                    let hung_up = match client.as_mut() {
+                        Some(client) => handle_client(client, &mut stub, &mut buf)?,
+                        None => false,
+                    };
+                    if hung_up {
+                        if let Some(old) = client.take() {
+                            epoll::ctl(
+                                epoll_fd,
+                                epoll::ControlOptions::EPOLL_CTL_DEL,
+                                old.stream.as_raw_fd(),
+                                epoll::Event::new(epoll::Events::empty(), 0),
+                            )?;
+                        }
+                        stub.detach().map_err(to_io_error)?;
+                    }
+                }
+                _ => error!("Unknown GDB stub event {}", ev_type),
+            }
+        }
+    }
+}
+
+// Process the bytes available from the debugger, returning whether it
+// went away.
+fn handle_client<T: GdbTarget>(
+    client: &mut Client,
+    stub: &mut GdbStub<T>,
+    buf: &mut [u8],
+) -> io::Result<bool> {
+    loop {
+        let count = match client.stream.read(buf) {
+            Ok(0) => return Ok(true),
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return Ok(true),
+        };
+
+        for byte in buf[..count].iter() {
+            match client.parser.feed(*byte) {
+                Some(Event::Packet(packet)) => {
+                    client.stream.write_all(b"+")?;
+                    // Nothing but interrupts is expected while the guest
+                    // runs.
+                    if stub.running() {
+                        continue;
+                    }
+                    match stub.handle_packet(&packet) {
+                        Response::Reply(reply) => client.send(&reply)?,
+                        Response::Resumed => {}
+                        Response::Detached(reply) => {
+                            if let Some(reply) = reply {
+                                client.send(&reply)?;
+                            }
+                            return Ok(true);
+                        }
+                    }
+                }
+                Some(Event::BadChecksum) => client.stream.write_all(b"-")?,
+                Some(Event::Interrupt) => {
+                    if let Some(reply) = stub.stop(true).map_err(to_io_error)? {
+                        client.send(&reply)?;
+                    }
+                }
+                Some(Event::Nack) => {
+                    let last_reply = client.last_reply.clone();
+                    client.stream.write_all(&last_reply)?;
+                }
+                Some(Event::Ack) | None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct MockTarget {
+        regs: Vec<CoreRegs>,
+        mem: Vec<u8>,
+        running: Arc<Mutex<bool>>,
+        breakpoints: Vec<HwBreakpoint>,
+        step: Option<usize>,
+        // vCPU reported as stopped on a breakpoint by the next pause, along
+        // with the breakpoint it hit.
+        hit: Option<(usize, Option<usize>)>,
+    }
+
+    impl MockTarget {
+        fn new(vcpus: usize) -> Self {
+            MockTarget {
+                regs: vec![CoreRegs::default(); vcpus],
+                mem: (0..=255).collect(),
+                running: Arc::new(Mutex::new(true)),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl GdbTarget for MockTarget {
+        fn vcpu_count(&self) -> usize {
+            self.regs.len()
+        }
+
+        fn read_regs(&self, vcpu: usize) -> anyhow::Result<CoreRegs> {
+            Ok(self.regs[vcpu].clone())
+        }
+
+        fn write_regs(&self, _vcpu: usize, _regs: &CoreRegs) -> anyhow::Result<()> {
+            Err(anyhow!("Read-only registers"))
+        }
+
+        fn read_mem(&self, _vcpu: usize, addr: u64, data: &mut [u8]) -> anyhow::Result<()> {
+            let start = addr as usize;
+            let src = self
+                .mem
+                .get(start..start + data.len())
+                .ok_or_else(|| anyhow!("Out of range"))?;
+            data.copy_from_slice(src);
+            Ok(())
+        }
+
+        fn write_mem(&self, _vcpu: usize, _addr: u64, _data: &[u8]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn resume(
+            &mut self,
+            breakpoints: &[HwBreakpoint],
+            step: Option<usize>,
+        ) -> anyhow::Result<()> {
+            self.breakpoints = breakpoints.to_vec();
+            self.step = step;
+            *self.running.lock().unwrap() = true;
+            Ok(())
+        }
+
+        fn pause(&mut self) -> anyhow::Result<Option<(usize, Option<usize>)>> {
+            *self.running.lock().unwrap() = false;
+            Ok(self.hit.take())
+        }
+    }
+
+    fn feed(parser: &mut PacketParser, data: &[u8]) -> Vec<Event> {
+        data.iter().filter_map(|b| parser.feed(*b)).collect()
+    }
+
+    fn reply_str(response: Response) -> String {
+        match response {
+            Response::Reply(data) => String::from_utf8(data).unwrap(),
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_packet_parser() {
+        let mut parser = PacketParser::new();
+
+        assert_eq!(
+            feed(&mut parser, b"+$g#67"),
+            vec![Event::Ack, Event::Packet(b"g".to_vec())]
+        );
+        assert_eq!(
+            feed(&mut parser, b"$Z1,ffffffff81000000,1#cd"),
+            vec![Event::Packet(b"Z1,ffffffff81000000,1".to_vec())]
+        );
+        assert_eq!(
+            feed(&mut parser, b"$c#63"),
+            vec![Event::Packet(b"c".to_vec())]
+        );
+        assert_eq!(feed(&mut parser, b"$c#00"), vec![Event::BadChecksum]);
+        assert_eq!(
+            feed(&mut parser, b"\x03-"),
+            vec![Event::Interrupt, Event::Nack]
+        );
+        // An interrupted packet is dropped for the next one.
+        assert_eq!(
+            feed(&mut parser, b"$gar$s#73"),
+            vec![Event::Packet(b"s".to_vec())]
+        );
+
+        assert_eq!(encode_packet(b"OK"), b"$OK#9a".to_vec());
+        assert_eq!(encode_packet(b"a#b"), b"$a}\x03b#43".to_vec());
+    }
+
+    #[test]
+    fn test_stub_registers() {
+        let mut target = MockTarget::new(2);
+        target.regs[1].gprs[0] = 0x1122_3344_5566_7788;
+        target.regs[1].rip = 0xffff_ffff_8100_0000;
+        target.regs[1].eflags = 0x246;
+        target.regs[1].segments[0] = 0x10;
+        let mut stub = GdbStub::new(target);
+        assert_eq!(stub.stop(true).unwrap(), Some(b"T02thread:1;".to_vec()));
+
+        assert_eq!(reply_str(stub.handle_packet(b"qfThreadInfo")), "m1,2");
+        assert_eq!(reply_str(stub.handle_packet(b"Hg2")), "OK");
+        assert_eq!(reply_str(stub.handle_packet(b"Hg3")), "E03");
+        assert_eq!(reply_str(stub.handle_packet(b"qC")), "QC2");
+
+        let regs = reply_str(stub.handle_packet(b"g"));
+        assert_eq!(regs.len(), CoreRegs::SIZE * 2);
+        assert!(regs.starts_with("8877665544332211"));
+        assert_eq!(&regs[256..272], "00000081ffffffff");
+        assert_eq!(&regs[272..280], "46020000");
+        assert_eq!(&regs[280..288], "10000000");
+        assert_eq!(
+            CoreRegs::decode(&from_hex(regs.as_bytes()).unwrap()).unwrap(),
+            stub.target.regs[1]
+        );
+
+        assert_eq!(reply_str(stub.handle_packet(b"m10,4")), "10111213");
+        assert_eq!(reply_str(stub.handle_packet(b"m1000,4")), "E0e");
+        assert_eq!(reply_str(stub.handle_packet(b"M10,2:abcd")), "OK");
+        assert_eq!(reply_str(stub.handle_packet(b"M10,2:ab")), "E16");
+        assert_eq!(reply_str(stub.handle_packet(b"vCont?")), "");
+    }
+
+    #[test]
+    fn test_stub_breakpoints() {
+        let target = MockTarget::new(2);
+        let running = target.running.clone();
+        let mut stub = GdbStub::new(target);
+        stub.stop(true).unwrap();
+        assert!(!*running.lock().unwrap());
+
+        assert_eq!(reply_str(stub.handle_packet(b"Z0,1000,1")), "OK");
+        assert_eq!(reply_str(stub.handle_packet(b"Z1,2000,1")), "OK");
+        // Adding a breakpoint twice only takes one debug register.
+        assert_eq!(reply_str(stub.handle_packet(b"Z1,2000,1")), "OK");
+        assert_eq!(reply_str(stub.handle_packet(b"Z1,3000,1")), "OK");
+        assert_eq!(reply_str(stub.handle_packet(b"Z1,4000,1")), "OK");
+        assert_eq!(reply_str(stub.handle_packet(b"Z1,5000,1")), "E1c");
+        assert_eq!(reply_str(stub.handle_packet(b"z1,3000,1")), "OK");
+        assert_eq!(reply_str(stub.handle_packet(b"Z5,1000,1")), "");
+
+        // Continuing programs the breakpoints and lets the guest run until
+        // a vCPU hits one.
+        assert_eq!(stub.handle_packet(b"c"), Response::Resumed);
+        assert!(stub.running());
+        assert!(*running.lock().unwrap());
+        let addrs: Vec<u64> = stub.target.breakpoints.iter().map(|bp| bp.addr).collect();
+        assert_eq!(addrs, vec![0x1000, 0x2000, 0x4000]);
+        assert_eq!(stub.target.step, None);
+
+        stub.target.hit = Some((1, Some(0)));
+        assert_eq!(stub.stop(false).unwrap(), Some(b"T05thread:2;".to_vec()));
+        assert!(!*running.lock().unwrap());
+        // Stopping twice, after simultaneous hits, only reports once.
+        assert_eq!(stub.stop(false).unwrap(), None);
+        assert_eq!(reply_str(stub.handle_packet(b"?")), "T05thread:2;");
+
+        // Single-stepping applies to the vCPU which stopped.
+        assert_eq!(stub.handle_packet(b"s"), Response::Resumed);
+        assert_eq!(stub.target.step, Some(1));
+
+        // Detaching removes the breakpoints and leaves the guest running.
+        stub.stop(true).unwrap();
+        assert_eq!(
+            stub.handle_packet(b"D"),
+            Response::Detached(Some(b"OK".to_vec()))
+        );
+        stub.detach().unwrap();
+        assert!(stub.running());
+        assert!(stub.target.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn test_stub_watchpoints() {
+        let mut stub = GdbStub::new(MockTarget::new(1));
+        stub.stop(true).unwrap();
+
+        assert_eq!(reply_str(stub.handle_packet(b"Z2,1000,4")), "OK");
+        assert_eq!(reply_str(stub.handle_packet(b"Z3,2000,8")), "OK");
+        assert_eq!(reply_str(stub.handle_packet(b"Z4,3000,2")), "OK");
+        // Lengths the debug registers don't support, and unaligned ones.
+        assert_eq!(reply_str(stub.handle_packet(b"Z2,4000,3")), "E16");
+        assert_eq!(reply_str(stub.handle_packet(b"Z2,4002,4")), "E16");
+        // Watchpoints and breakpoints share the debug registers.
+        assert_eq!(reply_str(stub.handle_packet(b"Z0,5000,1")), "OK");
+        assert_eq!(reply_str(stub.handle_packet(b"Z1,6000,1")), "E1c");
+        // Removing a watchpoint takes its type and length.
+        assert_eq!(reply_str(stub.handle_packet(b"z2,1000,2")), "OK");
+        assert_eq!(reply_str(stub.handle_packet(b"z4,2000,8")), "OK");
+        assert_eq!(reply_str(stub.handle_packet(b"z2,1000,4")), "OK");
+
+        assert_eq!(stub.handle_packet(b"c"), Response::Resumed);
+        assert_eq!(
+            stub.target.breakpoints,
+            vec![
+                HwBreakpoint {
+                    addr: 0x2000,
+                    len: 8,
+                    kind: HwBreakpointKind::Access,
+                },
+                HwBreakpoint {
+                    addr: 0x3000,
+                    len: 2,
+                    kind: HwBreakpointKind::Access,
+                },
+                HwBreakpoint {
+                    addr: 0x5000,
+                    len: 1,
+                    kind: HwBreakpointKind::Execute,
+                },
+            ]
+        );
+
+        // The stop reply tells which watchpoint triggered.
+        stub.target.hit = Some((0, Some(0)));
+        assert_eq!(
+            stub.stop(false).unwrap(),
+            Some(b"T05thread:1;rwatch:2000;".to_vec())
+        );
+        assert_eq!(stub.handle_packet(b"c"), Response::Resumed);
+        stub.target.hit = Some((0, Some(1)));
+        assert_eq!(
+            stub.stop(false).unwrap(),
+            Some(b"T05thread:1;awatch:3000;".to_vec())
+        );
+        // But not for execution breakpoints and single steps.
+        assert_eq!(stub.handle_packet(b"s"), Response::Resumed);
+        stub.target.hit = Some((0, None));
+        assert_eq!(stub.stop(false).unwrap(), Some(b"T05thread:1;".to_vec()));
+    }
+
+    #[test]
+    fn test_translate_gva() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let mut sregs = SpecialRegisters::default();
+        assert_eq!(
+            translate_gva(&mem, &sregs, 0x1234).unwrap(),
+            GuestAddress(0x1234)
+        );
+
+        // PML4 at 0x1000, PDPT at 0x2000, PD at 0x3000 mapping a 2MiB page
+        // and PT at 0x4000.
+        sregs.cr0 = CR0_PG;
+        sregs.efer = EFER_LMA;
+        sregs.cr3 = 0x1000;
+        mem.write_obj(0x2000u64 | PTE_PRESENT, GuestAddress(0x1000))
+            .unwrap();
+        mem.write_obj(0x3000u64 | PTE_PRESENT, GuestAddress(0x2000))
+            .unwrap();
+        mem.write_obj(0x4000u64 | PTE_PRESENT, GuestAddress(0x3000))
+            .unwrap();
+        mem.write_obj(
+            0x20_0000u64 | PTE_PRESENT | PTE_PAGE_SIZE,
+            GuestAddress(0x3008),
+        )
+        .unwrap();
+        mem.write_obj(0x8000u64 | PTE_PRESENT, GuestAddress(0x4008))
+            .unwrap();
+
+        assert_eq!(
+            translate_gva(&mem, &sregs, 0x1abc).unwrap(),
+            GuestAddress(0x8abc)
+        );
+        assert_eq!(
+            translate_gva(&mem, &sregs, 0x21_2345).unwrap(),
+            GuestAddress(0x21_2345)
+        );
+        assert!(translate_gva(&mem, &sregs, 0x2abc).is_err());
+
+        sregs.efer = 0;
+        assert!(translate_gva(&mem, &sregs, 0x1abc).is_err());
+    }
+}
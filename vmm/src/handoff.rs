@@ -0,0 +1,309 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Local migration, moving a VM to another VMM process of the same host,
+//! such as a newer binary. Rather than being copied, the guest memory, the
+//! tap interfaces and the disk images are handed over as file descriptors
+//! through the UNIX socket of the migration.
+
+use crate::config::{ConsoleOutputMode, ConsolePortMode, VmConfig};
+use crate::migration_stream::MigrationStream;
+use anyhow::anyhow;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::RawFd;
+use vm_migration::MigratableError;
+
+/// Most file descriptors the kernel passes along with a single message.
+pub const MAX_HANDOFF_FDS: usize = 253;
+
+// Handshake ending a local migration: the destination is ready to resume
+// the guest, and the source lets it go, never resuming it from then on.
+const HANDOFF_READY: u8 = 1;
+const HANDOFF_GO: u8 = 2;
+
+// What the file descriptors sent along with it belong to, in their order.
+#[derive(Deserialize, Serialize)]
+struct HandoffManifest {
+    memory_regions: usize,
+    taps: Vec<(String, usize)>,
+    disks: Vec<String>,
+}
+
+/// File descriptors handed over by a local migration: the ones backing the
+/// guest memory regions, in the order of the memory manager snapshot, the
+/// ones of the tap interfaces, by net device, and the disk images, by block
+/// device.
+pub struct HandoffFds<T> {
+    pub memory: Vec<T>,
+    pub taps: Vec<(String, Vec<T>)>,
+    pub disks: Vec<(String, T)>,
+}
+
+impl HandoffFds<RawFd> {
+    /// Send the file descriptors in a single message, along with the
+    /// length of the manifest describing them, the manifest following.
+    pub fn send(&self, stream: &mut MigrationStream) -> Result<(), MigratableError> {
+        let manifest = HandoffManifest {
+            memory_regions: self.memory.len(),
+            taps: self
+                .taps
+                .iter()
+                .map(|(id, fds)| (id.clone(), fds.len()))
+                .collect(),
+            disks: self.disks.iter().map(|(id, _)| id.clone()).collect(),
+        };
+        let fds: Vec<RawFd> = self
+            .memory
+            .iter()
+            .chain(self.taps.iter().flat_map(|(_, fds)| fds.iter()))
+            .chain(self.disks.iter().map(|(_, fd)| fd))
+            .cloned()
+            .collect();
+        if fds.len() > MAX_HANDOFF_FDS {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Too many file descriptors to hand over: {}, at most {}",
+                fds.len(),
+                MAX_HANDOFF_FDS
+            )));
+        }
+
+        let manifest =
+            serde_json::to_vec(&manifest).map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        stream
+            .send_with_fds(&(manifest.len() as u64).to_le_bytes(), &fds)
+            .and_then(|_| stream.write_all(&manifest))
+            .map_err(|e| MigratableError::MigrateSend(e.into()))
+    }
+}
+
+impl HandoffFds<File> {
+    /// Receive the file descriptors sent by `HandoffFds::send()`.
+    pub fn receive(stream: &mut MigrationStream) -> Result<Self, MigratableError> {
+        let mut len = [0u8; 8];
+        let files = stream
+            .recv_with_fds(&mut len, MAX_HANDOFF_FDS)
+            .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+        let mut manifest = vec![0u8; u64::from_le_bytes(len) as usize];
+        stream
+            .read_exact(&mut manifest)
+            .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+        let manifest: HandoffManifest = serde_json::from_slice(&manifest)
+            .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+
+        let expected = manifest.memory_regions
+            + manifest.taps.iter().map(|(_, count)| count).sum::<usize>()
+            + manifest.disks.len();
+        if files.len() != expected {
+            return Err(MigratableError::MigrateReceive(anyhow!(
+                "Received {} file descriptors, expected {}",
+                files.len(),
+                expected
+            )));
+        }
+
+        let mut files = files.into_iter();
+        let memory = files.by_ref().take(manifest.memory_regions).collect();
+        let taps = manifest
+            .taps
+            .into_iter()
+            .map(|(id, count)| (id, files.by_ref().take(count).collect()))
+            .collect();
+        let disks = manifest.disks.into_iter().zip(files).collect();
+
+        Ok(HandoffFds {
+            memory,
+            taps,
+            disks,
+        })
+    }
+}
+
+/// Let the guest go once the destination is ready to resume it. The source
+/// must not resume the guest once this returned successfully.
+pub fn release(stream: &mut MigrationStream) -> Result<(), MigratableError> {
+    let mut ready = [0u8; 1];
+    stream
+        .read_exact(&mut ready)
+        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+    if ready[0] != HANDOFF_READY {
+        return Err(MigratableError::MigrateSend(anyhow!(
+            "Unexpected handoff message {}",
+            ready[0]
+        )));
+    }
+
+    stream
+        .write_all(&[HANDOFF_GO])
+        .map_err(|e| MigratableError::MigrateSend(e.into()))
+}
+
+/// Tell the source the guest is ready to be resumed, and wait for it to let
+/// the guest go.
+pub fn wait_for_release(stream: &mut MigrationStream) -> Result<(), MigratableError> {
+    stream
+        .write_all(&[HANDOFF_READY])
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+
+    let mut go = [0u8; 1];
+    stream
+        .read_exact(&mut go)
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+    if go[0] != HANDOFF_GO {
+        return Err(MigratableError::MigrateReceive(anyhow!(
+            "Unexpected handoff message {}",
+            go[0]
+        )));
+    }
+
+    Ok(())
+}
+
+/// Refuse to hand over a VM relying on what can't be handed over: the
+/// backends of the vhost-user, virtio-fs, VFIO and vDPA devices, and the
+/// sockets the vsock device and the consoles listen on.
+pub fn check_config(config: &VmConfig) -> Result<(), MigratableError> {
+    let unsupported = |device: &str| {
+        Err(MigratableError::MigrateSend(anyhow!(
+            "Local migration doesn't support {}",
+            device
+        )))
+    };
+
+    if let Some(net) = &config.net {
+        if net.iter().any(|net| net.vhost_user) {
+            return unsupported("vhost-user-net");
+        }
+    }
+    if let Some(disks) = &config.disks {
+        if disks.iter().any(|disk| disk.vhost_user) {
+            return unsupported("vhost-user-blk");
+        }
+    }
+    if config.fs.is_some() {
+        return unsupported("virtio-fs");
+    }
+    if config.devices.is_some() {
+        return unsupported("VFIO devices");
+    }
+    if config.vdpa.is_some() {
+        return unsupported("vDPA devices");
+    }
+    if config.vsock.is_some() {
+        return unsupported("virtio-vsock");
+    }
+    for console in [&config.serial, &config.console].iter() {
+        match console.mode {
+            ConsoleOutputMode::Socket | ConsoleOutputMode::Tcp => {
+                return unsupported("a console listening on a socket")
+            }
+            _ => {}
+        }
+    }
+    if let Some(ports) = &config.console_ports {
+        if ports
+            .iter()
+            .any(|port| port.mode == ConsolePortMode::Socket)
+        {
+            return unsupported("a console port listening on a socket");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migration_stream::MigrationListener;
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn stream_pair(dir: &TempDir) -> (MigrationStream, MigrationStream) {
+        let url = format!("unix:{}", dir.path().join("migration.sock").display());
+        let listener = MigrationListener::bind(&url, None, TIMEOUT).unwrap();
+        let source = MigrationStream::connect(&url, None, TIMEOUT).unwrap();
+        (source, listener.accept().unwrap())
+    }
+
+    fn content(mut file: &File) -> String {
+        let mut content = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn test_handoff_fds() {
+        let dir = TempDir::new().unwrap();
+        let (mut source, mut destination) = stream_pair(&dir);
+
+        let files: Vec<File> = ["memory", "tap0", "tap1", "disk"]
+            .iter()
+            .map(|name| {
+                let mut file = tempfile::tempfile().unwrap();
+                file.write_all(name.as_bytes()).unwrap();
+                file
+            })
+            .collect();
+        let fds = HandoffFds {
+            memory: vec![files[0].as_raw_fd()],
+            taps: vec![(
+                "_net0".to_string(),
+                vec![files[1].as_raw_fd(), files[2].as_raw_fd()],
+            )],
+            disks: vec![("_disk0".to_string(), files[3].as_raw_fd())],
+        };
+        fds.send(&mut source).unwrap();
+
+        let fds = HandoffFds::<File>::receive(&mut destination).unwrap();
+        assert_eq!(fds.memory.len(), 1);
+        assert_eq!(content(&fds.memory[0]), "memory");
+        assert_eq!(fds.taps.len(), 1);
+        assert_eq!(fds.taps[0].0, "_net0");
+        assert_eq!(fds.taps[0].1.len(), 2);
+        assert_eq!(content(&fds.taps[0].1[0]), "tap0");
+        assert_eq!(content(&fds.taps[0].1[1]), "tap1");
+        assert_eq!(fds.disks.len(), 1);
+        assert_eq!(fds.disks[0].0, "_disk0");
+        assert_eq!(content(&fds.disks[0].1), "disk");
+
+        // The handshake only goes through in order.
+        let source = std::thread::spawn(move || release(&mut source));
+        wait_for_release(&mut destination).unwrap();
+        source.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_handoff_fds_mismatch() {
+        let dir = TempDir::new().unwrap();
+        let (mut source, mut destination) = stream_pair(&dir);
+
+        // A manifest announcing more file descriptors than were sent.
+        let file = tempfile::tempfile().unwrap();
+        let manifest = serde_json::to_vec(&HandoffManifest {
+            memory_regions: 2,
+            taps: Vec::new(),
+            disks: Vec::new(),
+        })
+        .unwrap();
+        source
+            .send_with_fds(&(manifest.len() as u64).to_le_bytes(), &[file.as_raw_fd()])
+            .unwrap();
+        source.write_all(&manifest).unwrap();
+        assert!(HandoffFds::<File>::receive(&mut destination).is_err());
+
+        // Too many file descriptors for a single message.
+        let fds = HandoffFds {
+            memory: vec![file.as_raw_fd(); MAX_HANDOFF_FDS + 1],
+            taps: Vec::new(),
+            disks: Vec::new(),
+        };
+        assert!(fds.send(&mut source).is_err());
+    }
+}
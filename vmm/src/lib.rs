@@ -17,40 +17,78 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate tempfile;
 extern crate url;
+#[macro_use]
 extern crate vmm_sys_util;
 #[cfg(test)]
 #[macro_use]
 extern crate credibility;
 
-use crate::api::{ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmInfo, VmmPingResponse};
+use crate::api::{
+    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, SnapshotMode, VmInfo,
+    VmReceiveMigrationData, VmSendMigrationData, VmSnapshotConfig, VmSnapshotConsolidateConfig,
+    VmmPingResponse, DEFAULT_SNAPSHOT_COMPRESSION_LEVEL,
+};
+#[cfg(target_arch = "x86_64")]
+use crate::config::PvpanicAction;
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, VmConfig, VsockConfig,
+    ConsolePortConfig, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig,
+    VmConfig, VsockConfig, WatchdogAction,
+};
+use crate::handoff::{self, HandoffFds};
+use crate::migration::{
+    consolidate_vm_snapshot, get_vm_snapshot, read_vm_snapshot, recv_vm_snapshot, PrecopyConfig,
 };
-use crate::migration::{get_vm_snapshot, recv_vm_snapshot};
+use crate::migration_progress::{migration_progress, MigrationPhase, MigrationProgress};
+use crate::migration_stream::{MigrationListener, MigrationStream};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::snapshot_chain::WriteOptions;
 use crate::vm::{Error as VmError, Vm, VmState};
 use libc::EFD_NONBLOCK;
-use seccomp::{SeccompFilter, SeccompLevel};
+use net_util::MacAddr;
+use seccomp::{SeccompAction, SeccompFilter, SeccompLevel};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{Receiver, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{result, thread};
-use vm_migration::{Pausable, Snapshottable, Transportable};
+use vm_migration::protocol::Compression;
+use vm_migration::{MigratableError, Pausable, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 
 pub mod api;
 pub mod config;
+pub mod console_port;
+pub mod console_socket;
 pub mod cpu;
 pub mod device_manager;
 pub mod device_tree;
+pub mod event_monitor;
+#[cfg(all(feature = "pci_support", feature = "kvm"))]
+mod failover;
+#[cfg(target_arch = "x86_64")]
+pub mod gdb;
+mod handoff;
 pub mod interrupt;
 pub mod memory_manager;
 pub mod migration;
+pub mod migration_progress;
+mod migration_stream;
+mod postcopy;
+mod prefault;
 pub mod seccomp_filters;
+mod snapshot_chain;
+#[cfg(all(feature = "pci_support", feature = "kvm"))]
+mod sriov;
+#[cfg(test)]
+mod testing;
+mod userfaultfd;
+#[cfg(all(feature = "pci_support", feature = "kvm"))]
+mod vfio_error;
 pub mod vm;
 
 #[cfg(feature = "acpi")]
@@ -116,6 +154,8 @@ pub enum EpollDispatch {
     Reset,
     Stdin,
     Api,
+    Watchdog,
+    GuestPanic,
 }
 
 pub struct EpollContext {
@@ -208,6 +248,25 @@ impl Serialize for PciDeviceInfo {
     }
 }
 
+// Let external supervisors know, the API response only reaching the client
+// which requested the migration.
+fn migration_failed(role: &str, error: String) {
+    let progress = migration_progress();
+    let mut properties = HashMap::new();
+    properties.insert("role", role.to_owned());
+    if progress.aborted() {
+        info!("Migration aborted on the {}: {}", role, error);
+        progress.fail(error);
+        event_monitor::event_log("migration", "aborted", &properties);
+        return;
+    }
+
+    error!("Migration failed on the {}: {}", role, error);
+    progress.fail(error.clone());
+    properties.insert("error", error);
+    event_monitor::event_log("migration", "failed", &properties);
+}
+
 pub fn start_vmm_thread(
     vmm_version: String,
     http_path: &str,
@@ -222,6 +281,12 @@ pub fn start_vmm_thread(
     // Retrieve seccomp filter
     let vmm_seccomp_filter =
         get_seccomp_filter(seccomp_level, Thread::Vmm).map_err(Error::CreateSeccompFilter)?;
+    // The device threads apply their own filter on top of the VMM one,
+    // killing them through SIGSYS as well.
+    let seccomp_action = match seccomp_level {
+        SeccompLevel::None => SeccompAction::Allow,
+        _ => SeccompAction::Trap,
+    };
 
     // Find the path that the "/proc/<pid>/exe" symlink points to. Must be done before spawning
     // a thread as Rust does not put the child threads in the same thread group which prevents the
@@ -235,7 +300,13 @@ pub fn start_vmm_thread(
             // Apply seccomp filter for VMM thread.
             SeccompFilter::apply(vmm_seccomp_filter).map_err(Error::ApplySeccompFilter)?;
 
-            let mut vmm = Vmm::new(vmm_version.to_string(), api_event, vmm_path, hypervisor)?;
+            let mut vmm = Vmm::new(
+                vmm_version.to_string(),
+                api_event,
+                vmm_path,
+                seccomp_action,
+                hypervisor,
+            )?;
 
             vmm.control_loop(Arc::new(api_receiver))
         })
@@ -251,11 +322,14 @@ pub struct Vmm {
     epoll: EpollContext,
     exit_evt: EventFd,
     reset_evt: EventFd,
+    watchdog_evt: EventFd,
+    panic_evt: EventFd,
     api_evt: EventFd,
     version: String,
     vm: Option<Vm>,
     vm_config: Option<Arc<Mutex<VmConfig>>>,
     vmm_path: PathBuf,
+    seccomp_action: SeccompAction,
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
 }
 
@@ -264,11 +338,14 @@ impl Vmm {
         vmm_version: String,
         api_evt: EventFd,
         vmm_path: PathBuf,
+        seccomp_action: SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
     ) -> Result<Self> {
         let mut epoll = EpollContext::new().map_err(Error::Epoll)?;
         let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let reset_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let watchdog_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let panic_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
 
         if unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0 {
             epoll.add_stdin().map_err(Error::Epoll)?;
@@ -286,15 +363,26 @@ impl Vmm {
             .add_event(&api_evt, EpollDispatch::Api)
             .map_err(Error::Epoll)?;
 
+        epoll
+            .add_event(&watchdog_evt, EpollDispatch::Watchdog)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&panic_evt, EpollDispatch::GuestPanic)
+            .map_err(Error::Epoll)?;
+
         Ok(Vmm {
             epoll,
             exit_evt,
             reset_evt,
+            watchdog_evt,
+            panic_evt,
             api_evt,
             version: vmm_version,
             vm: None,
             vm_config: None,
             vmm_path,
+            seccomp_action,
             hypervisor,
         })
     }
@@ -304,13 +392,21 @@ impl Vmm {
         if self.vm.is_none() {
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
             let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let watchdog_evt = self
+                .watchdog_evt
+                .try_clone()
+                .map_err(VmError::EventFdClone)?;
+            let panic_evt = self.panic_evt.try_clone().map_err(VmError::EventFdClone)?;
 
             if let Some(ref vm_config) = self.vm_config {
                 let vm = Vm::new(
                     Arc::clone(vm_config),
                     exit_evt,
                     reset_evt,
+                    watchdog_evt,
+                    panic_evt,
                     self.vmm_path.clone(),
+                    &self.seccomp_action,
                     self.hypervisor.clone(),
                 )?;
                 self.vm = Some(vm);
@@ -335,25 +431,71 @@ impl Vmm {
 
     fn vm_resume(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            vm.resume().map_err(VmError::Resume)
+            vm.resume().map_err(VmError::Resume)?;
+            // Resuming the guest acknowledges its panic, if any.
+            vm.clear_guest_panic();
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
-    fn vm_snapshot(&mut self, destination_url: &str) -> result::Result<(), VmError> {
+    fn vm_snapshot(&mut self, snapshot_cfg: &VmSnapshotConfig) -> result::Result<(), VmError> {
+        let options = WriteOptions {
+            compression_level: snapshot_cfg.compression.map(|_| {
+                snapshot_cfg
+                    .compression_level
+                    .unwrap_or(DEFAULT_SNAPSHOT_COMPRESSION_LEVEL)
+            }),
+            threads: snapshot_cfg.threads.unwrap_or(1),
+        };
+
         if let Some(ref mut vm) = self.vm {
             vm.snapshot()
                 .map_err(VmError::Snapshot)
                 .and_then(|snapshot| {
-                    vm.send(&snapshot, destination_url)
-                        .map_err(VmError::SnapshotSend)
+                    match snapshot_cfg.mode {
+                        SnapshotMode::Full => {
+                            vm.send_snapshot(&snapshot, &snapshot_cfg.destination_url, &options)
+                        }
+                        SnapshotMode::Incremental => {
+                            vm.send_incremental(&snapshot, &snapshot_cfg.destination_url, &options)
+                        }
+                    }
+                    .map_err(VmError::SnapshotSend)
                 })
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
+    fn vm_snapshot_consolidate(
+        &mut self,
+        consolidate_cfg: &VmSnapshotConsolidateConfig,
+    ) -> result::Result<(), VmError> {
+        consolidate_vm_snapshot(
+            &consolidate_cfg.source_url,
+            &consolidate_cfg.destination_url,
+        )
+        .map_err(VmError::SnapshotConsolidate)
+    }
+
+    fn vm_screenshot(&mut self, destination_path: &Path) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.screenshot(destination_path)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_memory_fds(&mut self, destination_socket: &Path) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.send_memory_fds(destination_socket)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_restore(&mut self, restore_cfg: RestoreConfig) -> result::Result<(), VmError> {
         if self.vm.is_some() || self.vm_config.is_some() {
             return Err(VmError::VmAlreadyCreated);
@@ -366,23 +508,38 @@ impl Vmm {
         // Safe to unwrap as we checked it was Some(&str).
         let source_url = source_url.unwrap();
 
-        let snapshot = recv_vm_snapshot(source_url).map_err(VmError::Restore)?;
+        let (snapshot, memory_stream) = recv_vm_snapshot(source_url).map_err(VmError::Restore)?;
         let vm_snapshot = get_vm_snapshot(&snapshot).map_err(VmError::Restore)?;
 
         self.vm_config = Some(Arc::clone(&vm_snapshot.config));
 
         let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
         let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let watchdog_evt = self
+            .watchdog_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
+        let panic_evt = self.panic_evt.try_clone().map_err(VmError::EventFdClone)?;
 
         let vm = Vm::new_from_snapshot(
             &snapshot,
             exit_evt,
             reset_evt,
+            watchdog_evt,
+            panic_evt,
             self.vmm_path.clone(),
             source_url,
             restore_cfg.prefault,
+            None,
+            &self.seccomp_action,
             self.hypervisor.clone(),
         )?;
+        // The guest memory follows the snapshot when it is received from
+        // a TCP stream.
+        if let Some(mut memory_stream) = memory_stream {
+            vm.receive_memory(&mut memory_stream, &[Compression::None, Compression::Lz4])
+                .map_err(VmError::Restore)?;
+        }
         self.vm = Some(vm);
 
         // Now we can restore the rest of the VM.
@@ -393,6 +550,203 @@ impl Vmm {
         }
     }
 
+    // Connect to the destination, the migration going on once the API
+    // request has been answered.
+    fn vm_connect_migration(
+        &mut self,
+        send_data: &VmSendMigrationData,
+    ) -> result::Result<MigrationStream, VmError> {
+        if self.vm.is_none() {
+            return Err(VmError::VmNotRunning);
+        }
+
+        let progress = migration_progress();
+        progress.start();
+        let mut stream = MigrationStream::connect(
+            &send_data.destination_url,
+            send_data.tls.as_ref(),
+            Duration::from_secs(send_data.timeout),
+        )
+        .map_err(|e| {
+            migration_failed("source", e.to_string());
+            VmError::MigrateSend(e)
+        })?;
+        stream.set_progress(progress).map_err(|e| {
+            migration_failed("source", e.to_string());
+            VmError::MigrateSend(MigratableError::MigrateSend(e.into()))
+        })?;
+        if let Some(bandwidth_limit) = send_data.bandwidth_limit {
+            stream.set_bandwidth_limit(bandwidth_limit).map_err(|e| {
+                migration_failed("source", e.to_string());
+                VmError::MigrateSend(MigratableError::MigrateSend(e.into()))
+            })?;
+        }
+
+        Ok(stream)
+    }
+
+    fn vm_send_migration(&mut self, stream: MigrationStream, send_data: &VmSendMigrationData) {
+        let progress = migration_progress();
+        let precopy = PrecopyConfig {
+            auto_converge: send_data.auto_converge,
+            max_throttle: send_data.max_throttle,
+            ..Default::default()
+        };
+        if let Some(ref mut vm) = self.vm {
+            let was_running = vm.get_state().ok() == Some(VmState::Running);
+            let result = if send_data.local {
+                vm.send_handoff(stream, &progress)
+            } else {
+                vm.send_migration(stream, send_data.postcopy, &precopy, &progress)
+            };
+            if let Err(e) = result {
+                // The guest keeps running on the source, unless it may run
+                // on the destination already.
+                if was_running
+                    && progress.phase() != MigrationPhase::Postcopy
+                    && vm.get_state().ok() == Some(VmState::Paused)
+                {
+                    if let Err(e) = vm.resume() {
+                        error!("Error resuming the VM after the migration: {:?}", e);
+                    }
+                }
+                migration_failed("source", e.to_string());
+                return;
+            }
+        }
+        progress.complete();
+
+        // The guest runs on the destination from now on.
+        if let Err(e) = self.vm_shutdown() {
+            error!("Error shutting down the migrated VM: {:?}", e);
+        }
+    }
+
+    // Listen for the source, the migration going on once the API request
+    // has been answered.
+    fn vm_listen_migration(
+        &mut self,
+        receive_data: &VmReceiveMigrationData,
+    ) -> result::Result<MigrationListener, VmError> {
+        if self.vm.is_some() || self.vm_config.is_some() {
+            return Err(VmError::VmAlreadyCreated);
+        }
+
+        migration_progress().start();
+        MigrationListener::bind(
+            &receive_data.receiver_url,
+            receive_data.tls.as_ref(),
+            Duration::from_secs(receive_data.timeout),
+        )
+        .map_err(|e| {
+            migration_failed("destination", e.to_string());
+            VmError::MigrateReceive(e)
+        })
+    }
+
+    fn vm_receive_migration(
+        &mut self,
+        listener: MigrationListener,
+        receive_data: &VmReceiveMigrationData,
+    ) {
+        let progress = migration_progress();
+        match self.receive_migration(listener, receive_data, &progress) {
+            // The post-copy phase completes once the last pages have been
+            // received in the background.
+            Ok(()) => {
+                if progress.phase() != MigrationPhase::Postcopy {
+                    progress.complete();
+                }
+            }
+            Err(e) => {
+                let error = match &e {
+                    VmError::MigrateReceive(e) => e.to_string(),
+                    e => format!("{:?}", e),
+                };
+                migration_failed("destination", error);
+            }
+        }
+    }
+
+    fn receive_migration(
+        &mut self,
+        listener: MigrationListener,
+        receive_data: &VmReceiveMigrationData,
+        progress: &Arc<MigrationProgress>,
+    ) -> result::Result<(), VmError> {
+        let mut stream = listener.accept().map_err(VmError::MigrateReceive)?;
+        stream
+            .set_progress(progress.clone())
+            .map_err(|e| VmError::MigrateReceive(MigratableError::MigrateReceive(e.into())))?;
+
+        // A local migration hands the file descriptors over first.
+        let handoff = if receive_data.local {
+            Some(HandoffFds::<File>::receive(&mut stream).map_err(VmError::MigrateReceive)?)
+        } else {
+            None
+        };
+
+        // The VM is created from the snapshot sent first, its memory and
+        // state coming next on the same stream.
+        let snapshot = read_vm_snapshot(&mut stream).map_err(VmError::MigrateReceive)?;
+        let vm_snapshot = get_vm_snapshot(&snapshot).map_err(VmError::MigrateReceive)?;
+
+        let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let watchdog_evt = self
+            .watchdog_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
+        let panic_evt = self.panic_evt.try_clone().map_err(VmError::EventFdClone)?;
+
+        let mut vm = Vm::new_from_snapshot(
+            &snapshot,
+            exit_evt,
+            reset_evt,
+            watchdog_evt,
+            panic_evt,
+            self.vmm_path.clone(),
+            &receive_data.receiver_url,
+            false,
+            handoff.as_ref().map(|fds| fds.memory.as_slice()),
+            &self.seccomp_action,
+            self.hypervisor.clone(),
+        )?;
+        if let Some(fds) = handoff {
+            // The snapshot holds the whole state, the devices being created
+            // from the tap interfaces and disk images handed over. The
+            // guest only resumes once the source let it go.
+            vm.take_over_devices(fds.taps, fds.disks)?;
+            vm.restore(snapshot).map_err(VmError::MigrateReceive)?;
+            handoff::wait_for_release(&mut stream).map_err(VmError::MigrateReceive)?;
+        } else {
+            let snapshot = vm
+                .receive_migration(stream, progress)
+                .map_err(VmError::MigrateReceive)?;
+            vm.restore(snapshot).map_err(VmError::MigrateReceive)?;
+        }
+        vm.resume().map_err(VmError::MigrateReceive)?;
+
+        self.vm_config = Some(Arc::clone(&vm_snapshot.config));
+        self.vm = Some(vm);
+
+        Ok(())
+    }
+
+    fn vm_dirty_rate(&mut self, sample_ms: u64) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref vm) = self.vm {
+            let dirty_rate = vm
+                .dirty_rate(Duration::from_millis(sample_ms))
+                .map_err(|e| {
+                    error!("Error when measuring the dirty rate: {:?}", e);
+                    e
+                })?;
+            serde_json::to_vec(&dirty_rate).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_shutdown(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm.take() {
             vm.shutdown()
@@ -418,6 +772,11 @@ impl Vmm {
 
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
             let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let watchdog_evt = self
+                .watchdog_evt
+                .try_clone()
+                .map_err(VmError::EventFdClone)?;
+            let panic_evt = self.panic_evt.try_clone().map_err(VmError::EventFdClone)?;
 
             // The Linux kernel fires off an i8042 reset after doing the ACPI reset so there may be
             // an event sitting in the shared reset_evt. Without doing this we get very early reboots
@@ -429,7 +788,10 @@ impl Vmm {
                 config,
                 exit_evt,
                 reset_evt,
+                watchdog_evt,
+                panic_evt,
                 self.vmm_path.clone(),
+                &self.seccomp_action,
                 self.hypervisor.clone(),
             )?);
         }
@@ -447,14 +809,26 @@ impl Vmm {
     fn vm_info(&self) -> result::Result<VmInfo, VmError> {
         match &self.vm_config {
             Some(config) => {
-                let state = match &self.vm {
-                    Some(vm) => vm.get_state()?,
-                    None => VmState::Created,
+                let (state, watchdog, guest_panic, balloon, memory_zones) = match &self.vm {
+                    Some(vm) => (
+                        vm.get_state()?,
+                        vm.watchdog_info(),
+                        vm.guest_panic_info(),
+                        vm.balloon_info(),
+                        vm.memory_zone_hints(),
+                    ),
+                    None => (VmState::Created, None, None, None, Vec::new()),
                 };
+                let cpu_topology = config.lock().unwrap().cpus.effective_topology();
 
                 Ok(VmInfo {
                     config: Arc::clone(config),
                     state,
+                    watchdog,
+                    guest_panic,
+                    balloon,
+                    cpu_topology,
+                    memory_zones,
                 })
             }
             None => Err(VmError::VmNotCreated),
@@ -504,6 +878,19 @@ impl Vmm {
         }
     }
 
+    fn vm_resize_zone(&mut self, id: String, desired_ram: u64) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.resize_zone(id, desired_ram) {
+                error!("Error when resizing memory zone: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_add_device(&mut self, device_cfg: DeviceConfig) -> result::Result<Vec<u8>, VmError> {
         if let Some(ref mut vm) = self.vm {
             let info = vm.add_device(device_cfg).map_err(|e| {
@@ -516,9 +903,13 @@ impl Vmm {
         }
     }
 
-    fn vm_remove_device(&mut self, id: String) -> result::Result<(), VmError> {
+    fn vm_remove_device(
+        &mut self,
+        id: String,
+        timeout_ms: Option<u64>,
+    ) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            if let Err(e) = vm.remove_device(id) {
+            if let Err(e) = vm.remove_device(id, timeout_ms) {
                 error!("Error when removing new device to the VM: {:?}", e);
                 Err(e)
             } else {
@@ -589,6 +980,21 @@ impl Vmm {
         }
     }
 
+    fn vm_add_console_port(
+        &mut self,
+        port_cfg: ConsolePortConfig,
+    ) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let port_cfg = vm.add_console_port(port_cfg).map_err(|e| {
+                error!("Error when adding new console port to the VM: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&port_cfg).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_counters(&mut self) -> result::Result<Vec<u8>, VmError> {
         if let Some(ref mut vm) = self.vm {
             let info = vm.counters().map_err(|e| {
@@ -601,6 +1007,170 @@ impl Vmm {
         }
     }
 
+    fn vm_balloon_stats(&mut self) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let stats = vm.balloon_stats().map_err(|e| {
+                error!("Error when getting balloon statistics from the VM: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&stats).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_vsock_info(&mut self) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let info = vm.vsock_info().map_err(|e| {
+                error!("Error when getting vsock information from the VM: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&info).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_net_link(&mut self, id: &str, link_up: Option<bool>) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let state = vm.net_link(id, link_up).map_err(|e| {
+                error!("Error when accessing the network link state: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&state).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_net_mac(&mut self, id: &str, mac: Option<MacAddr>) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let state = vm.net_mac(id, mac).map_err(|e| {
+                error!("Error when accessing the network MAC address: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&state).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_net_queues(
+        &mut self,
+        id: &str,
+        queue_pairs: Option<u16>,
+    ) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let state = vm.net_queues(id, queue_pairs).map_err(|e| {
+                error!("Error when accessing the network queue pairs: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&state).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_net_flow_rules(
+        &mut self,
+        id: &str,
+        rules: Option<Vec<virtio_devices::FlowRule>>,
+    ) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let rules = vm.net_flow_rules(id, rules).map_err(|e| {
+                error!("Error when accessing the network flow rules: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&rules).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_watchdog_expired(&mut self) -> result::Result<(), VmError> {
+        let action = match &self.vm {
+            Some(vm) => vm
+                .get_config()
+                .lock()
+                .unwrap()
+                .watchdog
+                .as_ref()
+                .map(|w| w.action),
+            None => None,
+        };
+
+        // Let external supervisors know, whatever the action taken.
+        if let Some(action) = action {
+            let mut properties = HashMap::new();
+            properties.insert("action", format!("{:?}", action));
+            event_monitor::event_log("watchdog", "expired", &properties);
+        }
+
+        match action {
+            Some(WatchdogAction::None) => {
+                warn!("Watchdog expired, no action taken");
+                Ok(())
+            }
+            Some(WatchdogAction::Pause) => {
+                warn!("Watchdog expired, pausing the VM");
+                self.vm_pause()
+            }
+            Some(WatchdogAction::Reset) => {
+                warn!("Watchdog expired, resetting the VM");
+                self.vm_reboot()
+            }
+            Some(WatchdogAction::Shutdown) => {
+                warn!("Watchdog expired, shutting the VM down");
+                // Same as the guest powering off.
+                self.exit_evt.write(1).map_err(VmError::EventFdWrite)
+            }
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn vm_guest_panicked(&mut self) -> result::Result<(), VmError> {
+        let (action, info) = match &self.vm {
+            Some(vm) => (
+                vm.get_config()
+                    .lock()
+                    .unwrap()
+                    .pvpanic
+                    .as_ref()
+                    .map(|p| p.action),
+                vm.guest_panic_info(),
+            ),
+            None => (None, None),
+        };
+
+        // Let external supervisors know, whatever the action taken.
+        if let Some(action) = action {
+            let mut properties = HashMap::new();
+            properties.insert("action", format!("{:?}", action));
+            if let Some(info) = info {
+                properties.insert("crash_loaded", info.crash_loaded.to_string());
+            }
+            event_monitor::event_log("pvpanic", "guest-panic", &properties);
+        }
+
+        match action {
+            Some(PvpanicAction::None) => {
+                warn!("Guest panicked, no action taken");
+                Ok(())
+            }
+            Some(PvpanicAction::Pause) => {
+                warn!("Guest panicked, pausing the VM");
+                self.vm_pause()
+            }
+            Some(PvpanicAction::Shutdown) => {
+                warn!("Guest panicked, shutting the VM down");
+                // Same as the guest powering off.
+                self.exit_evt.write(1).map_err(VmError::EventFdWrite)
+            }
+            None => Ok(()),
+        }
+    }
+
     fn control_loop(&mut self, api_receiver: Arc<Receiver<ApiRequest>>) -> Result<()> {
         const EPOLL_EVENTS_LEN: usize = 100;
 
@@ -642,6 +1212,25 @@ impl Vmm {
                             self.reset_evt.read().map_err(Error::EventFdRead)?;
                             self.vm_reboot().map_err(Error::VmReboot)?;
                         }
+                        EpollDispatch::Watchdog => {
+                            // Consume the event.
+                            self.watchdog_evt.read().map_err(Error::EventFdRead)?;
+
+                            // The guest being unresponsive must not take
+                            // the VMM down, hence errors are only logged.
+                            if let Err(e) = self.vm_watchdog_expired() {
+                                error!("Failed to handle watchdog expiry: {:?}", e);
+                            }
+                        }
+                        EpollDispatch::GuestPanic => {
+                            // Consume the event.
+                            self.panic_evt.read().map_err(Error::EventFdRead)?;
+
+                            #[cfg(target_arch = "x86_64")]
+                            if let Err(e) = self.vm_guest_panicked() {
+                                error!("Failed to handle guest panic: {:?}", e);
+                            }
+                        }
                         EpollDispatch::Stdin => {
                             if let Some(ref vm) = self.vm {
                                 vm.handle_stdin().map_err(Error::Stdin)?;
@@ -738,12 +1327,36 @@ impl Vmm {
                                 }
                                 ApiRequest::VmSnapshot(snapshot_data, sender) => {
                                     let response = self
-                                        .vm_snapshot(&snapshot_data.destination_url)
+                                        .vm_snapshot(&snapshot_data)
                                         .map_err(ApiError::VmSnapshot)
                                         .map(|_| ApiResponsePayload::Empty);
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmSnapshotConsolidate(consolidate_data, sender) => {
+                                    let response = self
+                                        .vm_snapshot_consolidate(&consolidate_data)
+                                        .map_err(ApiError::VmSnapshotConsolidate)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmScreenshot(screenshot_data, sender) => {
+                                    let response = self
+                                        .vm_screenshot(&screenshot_data.destination_path)
+                                        .map_err(ApiError::VmScreenshot)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmMemoryFds(memory_fds_data, sender) => {
+                                    let response = self
+                                        .vm_memory_fds(&memory_fds_data.destination_socket)
+                                        .map_err(ApiError::VmMemoryFds)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmRestore(restore_data, sender) => {
                                     let response = self
                                         .vm_restore(restore_data.as_ref().clone())
@@ -752,6 +1365,43 @@ impl Vmm {
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                // The migrations are answered once started, their
+                                // progress being reported by vm.migration-status
+                                // while the other requests wait for them.
+                                ApiRequest::VmSendMigration(send_data, sender) => {
+                                    match self.vm_connect_migration(&send_data) {
+                                        Ok(stream) => {
+                                            sender
+                                                .send(Ok(ApiResponsePayload::Empty))
+                                                .map_err(Error::ApiResponseSend)?;
+                                            self.vm_send_migration(stream, &send_data);
+                                        }
+                                        Err(e) => sender
+                                            .send(Err(ApiError::VmSendMigration(e)))
+                                            .map_err(Error::ApiResponseSend)?,
+                                    }
+                                }
+                                ApiRequest::VmReceiveMigration(receive_data, sender) => {
+                                    match self.vm_listen_migration(&receive_data) {
+                                        Ok(listener) => {
+                                            sender
+                                                .send(Ok(ApiResponsePayload::Empty))
+                                                .map_err(Error::ApiResponseSend)?;
+                                            self.vm_receive_migration(listener, &receive_data);
+                                        }
+                                        Err(e) => sender
+                                            .send(Err(ApiError::VmReceiveMigration(e)))
+                                            .map_err(Error::ApiResponseSend)?,
+                                    }
+                                }
+                                ApiRequest::VmDirtyRate(dirty_rate_data, sender) => {
+                                    let response = self
+                                        .vm_dirty_rate(dirty_rate_data.sample_ms)
+                                        .map_err(ApiError::VmDirtyRate)
+                                        .map(ApiResponsePayload::VmAction);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmmShutdown(sender) => {
                                     let response = self
                                         .vmm_shutdown()
@@ -773,6 +1423,16 @@ impl Vmm {
                                         .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmResizeZone(resize_zone_data, sender) => {
+                                    let response = self
+                                        .vm_resize_zone(
+                                            resize_zone_data.id.clone(),
+                                            resize_zone_data.desired_ram,
+                                        )
+                                        .map_err(ApiError::VmResizeZone)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmAddDevice(add_device_data, sender) => {
                                     let response = self
                                         .vm_add_device(add_device_data.as_ref().clone())
@@ -782,7 +1442,10 @@ impl Vmm {
                                 }
                                 ApiRequest::VmRemoveDevice(remove_device_data, sender) => {
                                     let response = self
-                                        .vm_remove_device(remove_device_data.id.clone())
+                                        .vm_remove_device(
+                                            remove_device_data.id.clone(),
+                                            remove_device_data.timeout_ms,
+                                        )
                                         .map_err(ApiError::VmRemoveDevice)
                                         .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
@@ -822,6 +1485,13 @@ impl Vmm {
                                         .map(ApiResponsePayload::VmAction);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmAddConsolePort(add_console_port_data, sender) => {
+                                    let response = self
+                                        .vm_add_console_port(add_console_port_data.as_ref().clone())
+                                        .map_err(ApiError::VmAddConsolePort)
+                                        .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmCounters(sender) => {
                                     let response = self
                                         .vm_counters()
@@ -830,6 +1500,60 @@ impl Vmm {
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmBalloonStats(sender) => {
+                                    let response = self
+                                        .vm_balloon_stats()
+                                        .map_err(ApiError::VmBalloonStats)
+                                        .map(ApiResponsePayload::VmAction);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmVsockInfo(sender) => {
+                                    let response = self
+                                        .vm_vsock_info()
+                                        .map_err(ApiError::VmVsockInfo)
+                                        .map(ApiResponsePayload::VmAction);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmNetLink(net_link_data, sender) => {
+                                    let response = self
+                                        .vm_net_link(&net_link_data.id, net_link_data.link_up)
+                                        .map_err(ApiError::VmNetLink)
+                                        .map(ApiResponsePayload::VmAction);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmNetMac(net_mac_data, sender) => {
+                                    let response = self
+                                        .vm_net_mac(&net_mac_data.id, net_mac_data.mac)
+                                        .map_err(ApiError::VmNetMac)
+                                        .map(ApiResponsePayload::VmAction);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmNetQueues(net_queues_data, sender) => {
+                                    let response = self
+                                        .vm_net_queues(
+                                            &net_queues_data.id,
+                                            net_queues_data.queue_pairs,
+                                        )
+                                        .map_err(ApiError::VmNetQueues)
+                                        .map(ApiResponsePayload::VmAction);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmNetFlowRules(flow_rules_data, sender) => {
+                                    let response = self
+                                        .vm_net_flow_rules(
+                                            &flow_rules_data.id,
+                                            flow_rules_data.rules.clone(),
+                                        )
+                                        .map_err(ApiError::VmNetFlowRules)
+                                        .map(ApiResponsePayload::VmAction);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                             }
                         }
                     }
@@ -5,7 +5,12 @@
 extern crate hypervisor;
 #[cfg(target_arch = "x86_64")]
 use crate::config::SgxEpcConfig;
-use crate::config::{HotplugMethod, MemoryConfig};
+use crate::config::{HotplugMethod, MemoryBacking, MemoryConfig, MemoryZoneConfig, ThpMode};
+use crate::postcopy::{self, PostcopyRegion};
+use crate::snapshot_chain::{
+    self, ChunkCompression, MemoryManifest, RegionManifest, RegionToWrite, WriteOptions, CHUNK_SIZE,
+};
+use crate::userfaultfd::Userfaultfd;
 use crate::MEMORY_MANAGER_SNAPSHOT_ID;
 #[cfg(feature = "acpi")]
 use acpi_tables::{aml, aml::Aml};
@@ -19,16 +24,18 @@ use devices::BusDevice;
 
 #[cfg(target_arch = "x86_64")]
 use libc::{MAP_NORESERVE, MAP_POPULATE, MAP_SHARED, PROT_READ, PROT_WRITE};
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::ffi;
 use std::fs::{File, OpenOptions};
-use std::io;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 #[cfg(target_arch = "x86_64")]
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::{FromRawFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use url::Url;
 #[cfg(target_arch = "x86_64")]
 use vm_allocator::GsiApic;
@@ -40,15 +47,27 @@ use vm_memory::{
     GuestRegionMmap, GuestUsize, MemoryRegionAddress, MmapRegion,
 };
 use vm_migration::{
+    protocol::{read_chunk, write_chunk, Compression, MemoryRange, MemoryRangeTable},
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
     Transportable,
 };
+use vm_virtio::DirtyLog;
 
 #[cfg(target_arch = "x86_64")]
 const X86_64_IRQ_BASE: u32 = 5;
 
 const HOTPLUG_COUNT: usize = 8;
 
+// Granularity of the dirty pages bitmap reported by the hypervisor.
+pub const DIRTY_LOG_PAGE_SIZE: u64 = 0x1000;
+
+// Memory policy constants from include/uapi/linux/mempolicy.h
+const MPOL_BIND: u64 = 2;
+const MPOL_MF_STRICT: u64 = 1;
+const MPOL_MF_MOVE: u64 = 1 << 1;
+
+const DEFAULT_HUGEPAGE_SIZE: u64 = 2 << 20;
+
 #[derive(Default)]
 struct HotPlugState {
     base: u64,
@@ -58,24 +77,105 @@ struct HotPlugState {
     removing: bool,
 }
 
+/// Hotpluggable memory handled through a virtio-mem device. The whole region
+/// is allocated at boot time, but it is only inserted into the guest memory
+/// once the device is asked to plug some memory.
+pub struct VirtioMemZone {
+    region: Arc<GuestRegionMmap>,
+    resize: virtio_devices::Resize,
+    block_size: u64,
+    hotplugged_size: u64,
+    guest_numa_node: Option<u32>,
+    region_inserted: bool,
+    backing: MemoryBacking,
+    shared: bool,
+    hugepages: bool,
+}
+
+impl VirtioMemZone {
+    pub fn region(&self) -> &Arc<GuestRegionMmap> {
+        &self.region
+    }
+
+    pub fn resize_handler(&self) -> &virtio_devices::Resize {
+        &self.resize
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn hotplugged_size(&self) -> u64 {
+        self.hotplugged_size
+    }
+
+    pub fn guest_numa_node(&self) -> Option<u32> {
+        self.guest_numa_node
+    }
+}
+
+/// Memory zone backed by a memfd, whose file descriptor can be handed out to
+/// the processes introspecting the guest RAM.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MemfdZone {
+    pub id: String,
+    pub start_addr: u64,
+    pub size: u64,
+}
+
+/// Hints given to the host about the pages of a memory zone, the guest RAM
+/// from `--memory` being the zone without any id. The hints the host refused
+/// are reported as not applied.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MemoryZoneHints {
+    pub id: Option<String>,
+    pub mergeable: bool,
+    pub thp: ThpMode,
+}
+
+// A guest RAM region and the memory slot it is mapped through.
+#[derive(Clone, Copy)]
+struct GuestRamMapping {
+    slot: u32,
+    gpa: u64,
+    size: u64,
+    host_addr: u64,
+}
+
+// Last snapshot of the incremental chain being taken, along with the
+// checksums of all the chunks of the guest memory it restores.
+struct SnapshotChainTip {
+    path: PathBuf,
+    regions: Vec<RegionManifest>,
+}
+
 pub struct MemoryManager {
     guest_memory: GuestMemoryAtomic<GuestMemoryMmap>,
     next_memory_slot: u32,
+    guest_ram_mappings: Vec<GuestRamMapping>,
+    log_dirty_pages: bool,
+    // The vCPUs log the pages they dirty to rings rather than to the
+    // bitmaps of the memory slots.
+    dirty_ring: bool,
+    // The pages written by the VMM on behalf of the virtio devices.
+    device_dirty_log: Arc<DirtyLog>,
     start_of_device_area: GuestAddress,
     end_of_device_area: GuestAddress,
+    high_mmio_window: Option<(GuestAddress, GuestUsize)>,
     pub vm: Arc<dyn hypervisor::Vm>,
     hotplug_slots: Vec<HotPlugState>,
     selected_slot: usize,
     backing_file: Option<PathBuf>,
-    mergeable: bool,
+    hints: Vec<MemoryZoneHints>,
     allocator: Arc<Mutex<SystemAllocator>>,
     hotplug_method: HotplugMethod,
     boot_ram: u64,
     current_ram: u64,
     next_hotplug_slot: usize,
-    pub virtiomem_region: Option<Arc<GuestRegionMmap>>,
-    pub virtiomem_resize: Option<virtio_devices::Resize>,
+    pub virtiomem_zone: Option<VirtioMemZone>,
+    pub virtiomem_zones: BTreeMap<String, VirtioMemZone>,
     snapshot: Mutex<Option<GuestMemoryLoadGuard<GuestMemoryMmap>>>,
+    snapshot_chain: Option<SnapshotChainTip>,
     shared: bool,
     hugepages: bool,
     balloon: Option<Arc<Mutex<virtio_devices::Balloon>>>,
@@ -156,6 +256,28 @@ pub enum Error {
     /// Failed creating a new MmapRegion instance.
     #[cfg(target_arch = "x86_64")]
     NewMmapRegion(vm_memory::mmap::MmapRegionError),
+
+    /// Failed binding a memory zone to its host NUMA node.
+    ApplyNumaPolicy(io::Error),
+
+    /// Unknown virtio-mem memory zone.
+    UnknownMemoryZone,
+
+    /// Failed to reserve the high MMIO window for the huge BARs.
+    HighMmioWindowAllocation,
+
+    /// Failed to prefault the guest RAM.
+    Prefault(io::Error),
+
+    /// Failed to seal the size of a memfd.
+    SealMemfd(io::Error),
+
+    /// The host hugepage pool is too small for a memory zone.
+    InsufficientHugepages {
+        zone: String,
+        hugepage_size: u64,
+        missing: u64,
+    },
 }
 
 const ENABLE_FLAG: usize = 0;
@@ -261,21 +383,59 @@ impl MemoryManager {
             .map(|r| (r.0, r.1))
             .collect();
 
+        let end_of_device_area = GuestAddress(mmio_address_space_size() - 1);
+
+        // The device area starts right after the boot RAM, no matter if some
+        // memory has been hotplugged before a snapshot was taken.
+        let last_ram_region = ram_regions.last().unwrap();
+        let mut start_of_device_area = MemoryManager::start_addr(
+            last_ram_region
+                .0
+                .unchecked_add(last_ram_region.1 as u64 - 1),
+            false,
+        );
+
+        // Memory regions restored from a snapshot which are located above the
+        // ACPI hotpluggable area belong to virtio-mem zones.
+        let virtiomem_area_start = match (config.hotplug_size, &config.hotplug_method) {
+            (Some(size), HotplugMethod::Acpi) => start_of_device_area.unchecked_add(size),
+            _ => start_of_device_area,
+        };
+        let (virtiomem_ext_regions, ext_regions): (Vec<MemoryRegion>, Option<Vec<MemoryRegion>>) =
+            match ext_regions {
+                Some(ext_regions) => {
+                    let (virtiomem_ext_regions, ext_regions) = ext_regions
+                        .into_iter()
+                        .partition(|r| r.start_addr >= virtiomem_area_start);
+                    (virtiomem_ext_regions, Some(ext_regions))
+                }
+                None => (Vec::new(), None),
+            };
+
+        // The memory restored from a snapshot is backed by the snapshot
+        // files rather than by hugepages.
+        if ext_regions.is_none() {
+            MemoryManager::check_hugepages(config)?;
+        }
+
         let mut mem_regions = Vec::new();
         if let Some(ext_regions) = &ext_regions {
             if ram_regions.len() > ext_regions.len() {
                 return Err(Error::InvalidAmountExternalBackingFiles);
             }
 
+            // The snapshot files are mapped copy-on-write, unlike the
+            // shared memory handed over by another process.
             for region in ext_regions.iter() {
                 mem_regions.push(MemoryManager::create_ram_region(
                     &Some(region.backing_file.clone()),
                     region.start_addr,
                     region.size as usize,
-                    true,
+                    !region.shared,
                     prefault,
                     false,
                     false,
+                    None,
                 )?);
             }
         } else {
@@ -288,38 +448,39 @@ impl MemoryManager {
                     prefault,
                     config.shared,
                     config.hugepages,
+                    None,
                 )?);
             }
-        }
-
-        let guest_memory =
-            GuestMemoryMmap::from_arc_regions(mem_regions).map_err(Error::GuestMemory)?;
-
-        let end_of_device_area = GuestAddress(mmio_address_space_size() - 1);
 
-        let mut start_of_device_area = MemoryManager::start_addr(guest_memory.last_addr(), false);
+            // Unlike the regions restored from a snapshot, which get populated
+            // from their backing file through MAP_POPULATE, the boot RAM is
+            // populated on as many threads as there are host CPUs.
+            if config.prefault {
+                crate::prefault::prefault_regions(
+                    &mem_regions,
+                    config.shared || config.file.is_some(),
+                    MemoryManager::worker_threads(),
+                )
+                .map_err(Error::Prefault)?;
+            }
+        }
 
-        let mut virtiomem_region = None;
-        let mut virtiomem_resize = None;
+        let mut virtiomem_zone = None;
         if let Some(size) = config.hotplug_size {
             if config.hotplug_method == HotplugMethod::VirtioMem {
-                // Alignment must be "natural" i.e. same as size of block
-                let start_addr = GuestAddress(
-                    (start_of_device_area.0 + virtio_devices::VIRTIO_MEM_DEFAULT_BLOCK_SIZE - 1)
-                        / virtio_devices::VIRTIO_MEM_DEFAULT_BLOCK_SIZE
-                        * virtio_devices::VIRTIO_MEM_DEFAULT_BLOCK_SIZE,
-                );
-                virtiomem_region = Some(MemoryManager::create_ram_region(
+                let zone = MemoryZoneConfig {
+                    hotplug_size: size,
+                    shared: config.shared,
+                    hugepages: config.hugepages,
+                    ..Default::default()
+                };
+                let (start_addr, zone) = MemoryManager::create_virtio_mem_zone(
                     &config.file,
-                    start_addr,
-                    size as usize,
-                    false,
-                    false,
-                    config.shared,
-                    config.hugepages,
-                )?);
-
-                virtiomem_resize = Some(virtio_devices::Resize::new().map_err(Error::EventFdFail)?);
+                    &zone,
+                    start_of_device_area,
+                    &virtiomem_ext_regions,
+                )?;
+                virtiomem_zone = Some(zone);
 
                 start_of_device_area = start_addr.unchecked_add(size);
             } else {
@@ -327,6 +488,30 @@ impl MemoryManager {
             }
         }
 
+        let mut virtiomem_zones = BTreeMap::new();
+        for zone in config.zones.iter().flatten() {
+            let (start_addr, virtio_mem_zone) = MemoryManager::create_virtio_mem_zone(
+                &None,
+                zone,
+                start_of_device_area,
+                &virtiomem_ext_regions,
+            )?;
+            virtiomem_zones.insert(zone.id.clone(), virtio_mem_zone);
+
+            start_of_device_area = start_addr.unchecked_add(zone.hotplug_size);
+        }
+
+        // Regions restored from a snapshot were part of the guest memory
+        // already, they must be inserted right away.
+        for zone in virtiomem_zone.iter().chain(virtiomem_zones.values()) {
+            if zone.region_inserted {
+                mem_regions.push(zone.region.clone());
+            }
+        }
+
+        let guest_memory =
+            GuestMemoryMmap::from_arc_regions(mem_regions).map_err(Error::GuestMemory)?;
+
         let guest_memory = GuestMemoryAtomic::new(guest_memory);
 
         let mut hotplug_slots = Vec::with_capacity(HOTPLUG_COUNT);
@@ -352,24 +537,50 @@ impl MemoryManager {
             .ok_or(Error::CreateSystemAllocator)?,
         ));
 
+        let hints = std::iter::once(MemoryZoneHints {
+            id: None,
+            mergeable: config.mergeable,
+            thp: config.thp,
+        })
+        .chain(config.zones.iter().flatten().map(|zone| MemoryZoneHints {
+            id: Some(zone.id.clone()),
+            mergeable: zone.mergeable,
+            thp: zone.thp,
+        }))
+        .collect();
+
+        // Must be enabled before the vCPUs are created, the dirty bitmaps
+        // being used otherwise.
+        let dirty_ring = config.dirty_ring
+            && vm.enable_dirty_ring().unwrap_or_else(|e| {
+                warn!("Could not enable the dirty ring: {}", e);
+                false
+            });
+
         let memory_manager = Arc::new(Mutex::new(MemoryManager {
             guest_memory: guest_memory.clone(),
             next_memory_slot: 0,
+            guest_ram_mappings: Vec::new(),
+            log_dirty_pages: false,
+            dirty_ring,
+            device_dirty_log: Arc::new(DirtyLog::default()),
             start_of_device_area,
             end_of_device_area,
+            high_mmio_window: None,
             vm,
             hotplug_slots,
             selected_slot: 0,
             backing_file: config.file.clone(),
-            mergeable: config.mergeable,
+            hints,
             allocator: allocator.clone(),
             hotplug_method: config.hotplug_method.clone(),
             boot_ram: config.size,
             current_ram: config.size,
             next_hotplug_slot: 0,
-            virtiomem_region: virtiomem_region.clone(),
-            virtiomem_resize,
+            virtiomem_zone,
+            virtiomem_zones,
             snapshot: Mutex::new(None),
+            snapshot_chain: None,
             shared: config.shared,
             hugepages: config.hugepages,
             balloon: None,
@@ -378,24 +589,29 @@ impl MemoryManager {
         }));
 
         guest_memory.memory().with_regions(|_, region| {
-            let _ = memory_manager.lock().unwrap().create_userspace_mapping(
-                region.start_addr().raw_value(),
-                region.len() as u64,
-                region.as_ptr() as u64,
-                config.mergeable,
-                false,
-            )?;
-            Ok(())
+            memory_manager
+                .lock()
+                .unwrap()
+                .create_guest_ram_mapping(region)
         })?;
 
-        if let Some(region) = virtiomem_region {
-            memory_manager.lock().unwrap().create_userspace_mapping(
-                region.start_addr().raw_value(),
-                region.len() as u64,
-                region.as_ptr() as u64,
-                config.mergeable,
-                false,
-            )?;
+        let virtiomem_regions: Vec<(Arc<GuestRegionMmap>, bool)> = {
+            let mm = memory_manager.lock().unwrap();
+            mm.virtiomem_zone
+                .iter()
+                .chain(mm.virtiomem_zones.values())
+                .map(|zone| (zone.region.clone(), zone.region_inserted))
+                .collect()
+        };
+        for (region, inserted) in virtiomem_regions {
+            // Inserted regions have already been mapped with the rest of the
+            // guest memory.
+            if !inserted {
+                memory_manager
+                    .lock()
+                    .unwrap()
+                    .create_guest_ram_mapping(&region)?;
+            }
             allocator
                 .lock()
                 .unwrap()
@@ -422,10 +638,6 @@ impl MemoryManager {
         source_url: &str,
         prefault: bool,
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
-        let url = Url::parse(source_url).unwrap();
-        /* url must be valid dir which is verified in recv_vm_snapshot() */
-        let vm_snapshot_path = url.to_file_path().unwrap();
-
         if let Some(mem_section) = snapshot
             .snapshot_data
             .get(&format!("{}-section", MEMORY_MANAGER_SNAPSHOT_ID))
@@ -441,6 +653,24 @@ impl MemoryManager {
                     }
                 };
 
+            // When the snapshot comes from a TCP stream, or from a live
+            // migration, the memory content follows it on the same stream,
+            // and is received once the VM has been created.
+            if source_url.starts_with("tcp://") || source_url.starts_with("unix:") {
+                let memory_manager = MemoryManager::new(vm, config, None, prefault)?;
+                for region in mem_snapshot.memory_regions.iter() {
+                    memory_manager
+                        .lock()
+                        .unwrap()
+                        .insert_virtio_mem_region_at(region.start_addr)?;
+                }
+                return Ok(memory_manager);
+            }
+
+            let url = Url::parse(source_url).unwrap();
+            /* url must be valid dir which is verified in recv_vm_snapshot() */
+            let vm_snapshot_path = url.to_file_path().unwrap();
+
             let mut ext_regions = mem_snapshot.memory_regions;
             for region in ext_regions.iter_mut() {
                 let mut memory_region_path = vm_snapshot_path.clone();
@@ -448,40 +678,112 @@ impl MemoryManager {
                 region.backing_file = memory_region_path;
             }
 
+            // The memory of an incremental snapshot is spread across its
+            // chain, and can't be mapped from its files.
+            let chain =
+                match snapshot_chain::read_manifest(&vm_snapshot_path).map_err(Error::Restore)? {
+                    Some(_) => Some(
+                        snapshot_chain::read_chain(&vm_snapshot_path).map_err(Error::Restore)?,
+                    ),
+                    None => None,
+                };
+
             // In case there was no backing file, we can safely use CoW by
             // mapping the source files provided for restoring. This case
             // allows for a faster VM restoration and does not require us to
-            // fill the memory content, hence we can return right away.
-            if config.file.is_none() {
+            // fill the memory content, hence we can return right away. The
+            // shared and memfd regions must be backed the same way again
+            // though, to be shared with other processes, and so must the
+            // hugepage regions.
+            if config.file.is_none()
+                && chain.is_none()
+                && ext_regions.iter().all(|region| {
+                    region.backing != MemoryBacking::Memfd && !region.shared && !region.hugepages
+                })
+            {
                 return MemoryManager::new(vm, config, Some(ext_regions), prefault);
             };
 
+            // The memory is created as described by the configuration, which
+            // fails if the host lacks hugepages, before any snapshot file is
+            // opened.
             let memory_manager = MemoryManager::new(vm, config, None, false)?;
+
+            // The virtio-mem regions which were plugged when the snapshot was
+            // taken must be inserted again before their content is restored.
+            for region in ext_regions.iter() {
+                memory_manager
+                    .lock()
+                    .unwrap()
+                    .insert_virtio_mem_region_at(region.start_addr)?;
+            }
+
             let guest_memory = memory_manager.lock().unwrap().guest_memory();
 
             // In case the previous config was using a backing file, this means
             // it was MAP_SHARED, therefore we must copy the content into the
             // new regions so that we can still use MAP_SHARED when restoring
             // the VM.
-            guest_memory.memory().with_regions(|index, region| {
+            let mut chain_regions = Vec::new();
+            guest_memory.memory().with_regions_mut(|_, region| {
+                let ext_region = ext_regions
+                    .iter()
+                    .find(|r| r.start_addr == region.start_addr())
+                    .ok_or(Error::InvalidAmountExternalBackingFiles)?;
+                if ext_region.size != region.len() {
+                    return Err(Error::Restore(MigratableError::Restore(anyhow!(
+                        "Memory region at {:#x} is {} bytes long, instead of {} in the snapshot",
+                        region.start_addr().raw_value(),
+                        region.len(),
+                        ext_region.size
+                    ))));
+                }
+
+                // The regions of a snapshot chain are all restored at once.
+                if chain.is_some() {
+                    chain_regions.push((region.start_addr().raw_value(), region.len()));
+                    return Ok(());
+                }
+
                 // Open (read only) the snapshot file for the given region.
                 let mut memory_region_file = OpenOptions::new()
                     .read(true)
-                    .open(&ext_regions[index].backing_file)
+                    .open(&ext_region.backing_file)
                     .map_err(|e| Error::Restore(MigratableError::MigrateReceive(e.into())))?;
 
-                // Fill the region with the file content.
-                region
-                    .read_from(
-                        MemoryRegionAddress(0),
-                        &mut memory_region_file,
-                        region.len().try_into().unwrap(),
-                    )
+                // Fill the region with the file content. The new region is
+                // zeroed already, so the holes of the file are skipped rather
+                // than allocating the memory they cover.
+                let ranges = data_ranges(&memory_region_file, 0, region.len())
                     .map_err(|e| Error::Restore(MigratableError::MigrateReceive(e.into())))?;
+                for (offset, len) in ranges {
+                    memory_region_file
+                        .seek(SeekFrom::Start(offset))
+                        .map_err(|e| Error::Restore(MigratableError::MigrateReceive(e.into())))?;
+                    region
+                        .read_from(
+                            MemoryRegionAddress(offset),
+                            &mut memory_region_file,
+                            len.try_into().unwrap(),
+                        )
+                        .map_err(|e| Error::Restore(MigratableError::MigrateReceive(e.into())))?;
+                }
 
                 Ok(())
             })?;
 
+            // The chunks are read, and decompressed, on as many threads as
+            // there are host CPUs.
+            if let Some(chain) = &chain {
+                snapshot_chain::restore_regions(
+                    chain,
+                    &guest_memory.memory(),
+                    &chain_regions,
+                    MemoryManager::worker_threads(),
+                )
+                .map_err(Error::Restore)?;
+            }
+
             Ok(memory_manager)
         } else {
             Err(Error::Restore(MigratableError::Restore(anyhow!(
@@ -491,6 +793,76 @@ impl MemoryManager {
         }
     }
 
+    /// Create the memory manager of a VM handed over by another VMM process
+    /// on the same host, the guest memory being mapped from the `files` it
+    /// handed over, one per region of the `snapshot`, rather than copied.
+    pub fn new_from_handoff(
+        snapshot: &Snapshot,
+        vm: Arc<dyn hypervisor::Vm>,
+        config: &MemoryConfig,
+        files: &[File],
+    ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
+        let mem_section = snapshot
+            .snapshot_data
+            .get(&format!("{}-section", MEMORY_MANAGER_SNAPSHOT_ID))
+            .ok_or_else(|| {
+                Error::Restore(MigratableError::Restore(anyhow!(
+                    "Could not find {}-section from snapshot",
+                    MEMORY_MANAGER_SNAPSHOT_ID
+                )))
+            })?;
+        let mem_snapshot: MemoryManagerSnapshotData = serde_json::from_slice(&mem_section.snapshot)
+            .map_err(|e| {
+                Error::Restore(MigratableError::Restore(anyhow!(
+                    "Could not deserialize MemoryManager {}",
+                    e
+                )))
+            })?;
+        if mem_snapshot.memory_regions.len() != files.len() {
+            return Err(Error::InvalidAmountExternalBackingFiles);
+        }
+
+        // The files are opened again through procfs, which maps the very
+        // same memory, shared with the other process until it exits.
+        let ext_regions = mem_snapshot
+            .memory_regions
+            .into_iter()
+            .zip(files.iter())
+            .map(|(region, file)| MemoryRegion {
+                backing_file: PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd())),
+                shared: true,
+                ..region
+            })
+            .collect();
+
+        MemoryManager::new(vm, config, Some(ext_regions), false)
+    }
+
+    /// File descriptors backing the regions of the guest memory, in the
+    /// order of the snapshot, for another VMM process to map the same
+    /// memory. Only the shared memory, or the one backed by a file or a
+    /// memfd, can be handed over.
+    pub fn handoff_fds(&self) -> result::Result<Vec<RawFd>, MigratableError> {
+        let mut fds = Vec::new();
+        self.guest_memory
+            .memory()
+            .with_regions_mut(|index, region| {
+                let snapshot_region = self.snapshot_region(index, region);
+                match region.file_offset() {
+                    Some(file_offset) if snapshot_region.shared && file_offset.start() == 0 => {
+                        fds.push(file_offset.file().as_raw_fd());
+                        Ok(())
+                    }
+                    _ => Err(MigratableError::MigrateSend(anyhow!(
+                        "The memory region at {:#x} is private, and can't be handed over",
+                        region.start_addr().raw_value()
+                    ))),
+                }
+            })?;
+
+        Ok(fds)
+    }
+
     fn memfd_create(name: &ffi::CStr, flags: u32) -> Result<RawFd, io::Error> {
         let res = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), flags) };
 
@@ -501,6 +873,7 @@ impl MemoryManager {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn create_ram_region(
         backing_file: &Option<PathBuf>,
         start_addr: GuestAddress,
@@ -509,6 +882,7 @@ impl MemoryManager {
         prefault: bool,
         shared: bool,
         hugepages: bool,
+        hugepage_size: Option<u64>,
     ) -> Result<Arc<GuestRegionMmap>, Error> {
         Ok(Arc::new(match backing_file {
             Some(ref file) => {
@@ -554,7 +928,12 @@ impl MemoryManager {
                 let fd = Self::memfd_create(
                     &ffi::CString::new("ch_ram").unwrap(),
                     if hugepages {
-                        libc::MFD_HUGETLB | libc::MAP_HUGE_2MB as u32
+                        libc::MFD_HUGETLB
+                            | if hugepage_size == Some(1 << 30) {
+                                libc::MAP_HUGE_1GB as u32
+                            } else {
+                                libc::MAP_HUGE_2MB as u32
+                            }
                     } else {
                         0
                     },
@@ -585,6 +964,253 @@ impl MemoryManager {
         }))
     }
 
+    // Allocate a region backed by a memfd whose size is sealed, so that the
+    // processes it gets shared with can't see it shrink under their feet.
+    fn create_memfd_region(
+        start_addr: GuestAddress,
+        size: usize,
+        hugepages: bool,
+        hugepage_size: Option<u64>,
+    ) -> Result<Arc<GuestRegionMmap>, Error> {
+        let mut flags = libc::MFD_ALLOW_SEALING;
+        if hugepages {
+            flags |= libc::MFD_HUGETLB
+                | if hugepage_size == Some(1 << 30) {
+                    libc::MAP_HUGE_1GB as u32
+                } else {
+                    libc::MAP_HUGE_2MB as u32
+                };
+        }
+        let fd = Self::memfd_create(&ffi::CString::new("ch_ram").unwrap(), flags)
+            .map_err(Error::SharedFileCreate)?;
+
+        let f = unsafe { File::from_raw_fd(fd) };
+        f.set_len(size as u64).map_err(Error::SharedFileSetLen)?;
+
+        // Safe because the file descriptor is valid, and we check the return
+        // value.
+        let ret = unsafe {
+            libc::fcntl(
+                f.as_raw_fd(),
+                libc::F_ADD_SEALS,
+                libc::F_SEAL_GROW | libc::F_SEAL_SHRINK,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::SealMemfd(io::Error::last_os_error()));
+        }
+
+        Ok(Arc::new(
+            GuestRegionMmap::new(
+                MmapRegion::build(
+                    Some(FileOffset::new(f, 0)),
+                    size,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_NORESERVE | libc::MAP_SHARED,
+                )
+                .map_err(Error::GuestMemoryRegion)?,
+                start_addr,
+            )
+            .map_err(Error::GuestMemory)?,
+        ))
+    }
+
+    // Allocate the region backing a virtio-mem zone, naturally aligned on
+    // the zone block size right after the given address. If the region was
+    // part of a snapshot, it is backed by the snapshot file instead.
+    fn create_virtio_mem_zone(
+        backing_file: &Option<PathBuf>,
+        zone: &MemoryZoneConfig,
+        start_addr: GuestAddress,
+        ext_regions: &[MemoryRegion],
+    ) -> Result<(GuestAddress, VirtioMemZone), Error> {
+        let block_size = zone.block_size;
+        let start_addr = GuestAddress((start_addr.0 + block_size - 1) / block_size * block_size);
+
+        let ext_region = ext_regions.iter().find(|r| r.start_addr == start_addr);
+        let region = if let Some(ext_region) = ext_region {
+            MemoryManager::create_ram_region(
+                &Some(ext_region.backing_file.clone()),
+                start_addr,
+                zone.hotplug_size as usize,
+                !ext_region.shared,
+                false,
+                false,
+                false,
+                None,
+            )?
+        } else if zone.backing == MemoryBacking::Memfd {
+            MemoryManager::create_memfd_region(
+                start_addr,
+                zone.hotplug_size as usize,
+                zone.hugepages,
+                zone.hugepage_size,
+            )?
+        } else {
+            MemoryManager::create_ram_region(
+                backing_file,
+                start_addr,
+                zone.hotplug_size as usize,
+                false,
+                false,
+                zone.shared,
+                zone.hugepages,
+                zone.hugepage_size,
+            )?
+        };
+
+        if let Some(host_numa_node) = zone.host_numa_node {
+            MemoryManager::mbind(&region, host_numa_node)?;
+        }
+
+        // The whole zone gets populated, including the memory not plugged
+        // yet. This must happen once the region is bound to its host NUMA
+        // node, which is why it can't be mapped with MAP_POPULATE.
+        if zone.prefault && ext_region.is_none() {
+            crate::prefault::prefault_regions(
+                &[region.clone()],
+                zone.shared || zone.backing == MemoryBacking::Memfd,
+                MemoryManager::worker_threads(),
+            )
+            .map_err(Error::Prefault)?;
+        }
+
+        Ok((
+            start_addr,
+            VirtioMemZone {
+                region,
+                resize: virtio_devices::Resize::new().map_err(Error::EventFdFail)?,
+                block_size,
+                hotplugged_size: zone.hotplugged_size.unwrap_or(0),
+                guest_numa_node: zone.guest_numa_node,
+                region_inserted: ext_region.is_some(),
+                backing: zone.backing,
+                // A region restored from a snapshot is a private mapping of
+                // the snapshot file, unless it was handed over.
+                shared: match ext_region {
+                    Some(region) => region.shared,
+                    None => zone.shared || zone.backing == MemoryBacking::Memfd,
+                },
+                hugepages: ext_region.map_or(zone.hugepages, |region| region.hugepages),
+            },
+        ))
+    }
+
+    // The boot RAM and the memory zones are populated, and the snapshots
+    // restored, on as many threads as there are host CPUs.
+    fn worker_threads() -> usize {
+        // Safe because sysconf() doesn't access any memory.
+        let num_threads = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+        std::cmp::max(num_threads, 1) as usize
+    }
+
+    // Number of free hugepages of the given size on the host, or on one of
+    // its NUMA nodes.
+    fn free_hugepages(hugepage_size: u64, host_numa_node: Option<u32>) -> io::Result<u64> {
+        let dir = match host_numa_node {
+            Some(node) => format!("/sys/devices/system/node/node{}/hugepages", node),
+            None => "/sys/kernel/mm/hugepages".to_string(),
+        };
+        let path = format!("{}/hugepages-{}kB/free_hugepages", dir, hugepage_size >> 10);
+        std::fs::read_to_string(path)?
+            .trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // The guest memory is mapped with MAP_NORESERVE, so that running out of
+    // hugepages only shows when the memory gets touched, the VMM dying on a
+    // SIGBUS. Instead, fail before creating the memory if the host pools
+    // don't hold enough free hugepages for the boot RAM, the memory plugged
+    // in the zones at boot, and the whole of the prefaulted zones. The pages
+    // of a zone bound to a host NUMA node come from the pool of that node,
+    // which is part of the pool of the host.
+    fn check_hugepages(config: &MemoryConfig) -> Result<(), Error> {
+        let mut needed: BTreeMap<(u64, Option<u32>), u64> = BTreeMap::new();
+        if config.hugepages && config.file.is_none() {
+            needed.insert(
+                (DEFAULT_HUGEPAGE_SIZE, None),
+                config.size / DEFAULT_HUGEPAGE_SIZE,
+            );
+        }
+
+        for zone in config.zones.iter().flatten().filter(|z| z.hugepages) {
+            let hugepage_size = zone.hugepage_size.unwrap_or(DEFAULT_HUGEPAGE_SIZE);
+            let size = if zone.prefault {
+                zone.hotplug_size
+            } else {
+                zone.hotplugged_size.unwrap_or(0)
+            };
+
+            let mut pools = vec![None];
+            if zone.host_numa_node.is_some() {
+                pools.push(zone.host_numa_node);
+            }
+            for pool in pools {
+                let total = needed.entry((hugepage_size, pool)).or_insert(0);
+                *total += size / hugepage_size;
+
+                let free = match MemoryManager::free_hugepages(hugepage_size, pool) {
+                    Ok(free) => free,
+                    Err(e) => {
+                        warn!(
+                            "Cannot read the free hugepages of {} KiB: {}",
+                            hugepage_size >> 10,
+                            e
+                        );
+                        continue;
+                    }
+                };
+                if *total > free {
+                    let missing = *total - free;
+                    error!(
+                        "Memory zone {} misses {} hugepages of {} KiB{}",
+                        zone.id,
+                        missing,
+                        hugepage_size >> 10,
+                        pool.map_or(String::new(), |node| format!(" on host NUMA node {}", node))
+                    );
+                    return Err(Error::InsufficientHugepages {
+                        zone: zone.id.clone(),
+                        hugepage_size,
+                        missing,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Restrict the allocation of the pages backing the region to the given
+    // host NUMA node. This must happen before the memory is touched.
+    fn mbind(region: &GuestRegionMmap, host_numa_node: u32) -> Result<(), Error> {
+        let node = host_numa_node as usize;
+        let mut nodemask: Vec<u64> = vec![0; node / 64 + 1];
+        nodemask[node / 64] |= 1 << (node % 64);
+        // The kernel expects the number of bits of the mask plus one.
+        let maxnode = nodemask.len() as u64 * 64 + 1;
+
+        // Safe because the region has been successfully mapped and the node
+        // mask outlives the syscall.
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                region.as_ptr() as *mut libc::c_void,
+                region.len(),
+                MPOL_BIND,
+                nodemask.as_ptr(),
+                maxnode,
+                MPOL_MF_STRICT | MPOL_MF_MOVE,
+            )
+        };
+        if res < 0 {
+            return Err(Error::ApplyNumaPolicy(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
     // Update the GuestMemoryMmap with the new range
     fn add_region(&mut self, region: Arc<GuestRegionMmap>) -> Result<(), Error> {
         let guest_memory = self
@@ -650,16 +1276,11 @@ impl MemoryManager {
             false,
             self.shared,
             self.hugepages,
+            None,
         )?;
 
         // Map it into the guest
-        self.create_userspace_mapping(
-            region.start_addr().0,
-            region.len() as u64,
-            region.as_ptr() as u64,
-            self.mergeable,
-            false,
-        )?;
+        self.create_guest_ram_mapping(&region)?;
 
         // Tell the allocator
         self.allocator
@@ -682,6 +1303,29 @@ impl MemoryManager {
         Ok(region)
     }
 
+    pub fn memory_zone_hints(&self) -> Vec<MemoryZoneHints> {
+        self.hints.clone()
+    }
+
+    /// The memory zones backed by a memfd, along with its file descriptor.
+    pub fn memfd_zones(&self) -> Vec<(MemfdZone, RawFd)> {
+        self.virtiomem_zones
+            .iter()
+            .filter(|(_, zone)| zone.backing == MemoryBacking::Memfd)
+            .filter_map(|(id, zone)| {
+                let fd = zone.region.file_offset()?.file().as_raw_fd();
+                Some((
+                    MemfdZone {
+                        id: id.clone(),
+                        start_addr: zone.region.start_addr().raw_value(),
+                        size: zone.region.len(),
+                    },
+                    fd,
+                ))
+            })
+            .collect()
+    }
+
     pub fn set_balloon(&mut self, balloon: Arc<Mutex<virtio_devices::Balloon>>) {
         self.balloon = Some(balloon);
     }
@@ -702,6 +1346,32 @@ impl MemoryManager {
         self.end_of_device_area
     }
 
+    /// Sets aside `size` bytes of the device area, as high as possible and
+    /// aligned on `alignment`, for the huge BARs of the devices. This has to
+    /// happen before the device area gets shared between the PCI segments.
+    pub fn reserve_high_mmio_window(
+        &mut self,
+        size: GuestUsize,
+        alignment: GuestUsize,
+    ) -> Result<GuestAddress, Error> {
+        let base = self
+            .allocator
+            .lock()
+            .unwrap()
+            .reserve_high_mmio_window(size, alignment)
+            .ok_or(Error::HighMmioWindowAllocation)?;
+        if base < self.start_of_device_area {
+            return Err(Error::HighMmioWindowAllocation);
+        }
+
+        self.high_mmio_window = Some((base, size));
+        Ok(base)
+    }
+
+    pub fn high_mmio_window(&self) -> Option<(GuestAddress, GuestUsize)> {
+        self.high_mmio_window
+    }
+
     pub fn allocate_memory_slot(&mut self) -> u32 {
         let slot_id = self.next_memory_slot;
         self.next_memory_slot += 1;
@@ -723,6 +1393,7 @@ impl MemoryManager {
             memory_size,
             userspace_addr,
             readonly,
+            false,
         );
 
         self.vm
@@ -762,6 +1433,301 @@ impl MemoryManager {
         Ok(slot)
     }
 
+    fn madvise(host_addr: u64, size: u64, advice: libc::c_int) -> io::Result<()> {
+        // Safe because the address and size are valid since the mmap
+        // succeeded.
+        let ret =
+            unsafe { libc::madvise(host_addr as *mut libc::c_void, size as libc::size_t, advice) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    // Give the host the hints about the pages of a guest RAM region, the
+    // ones it refuses being dropped from the hints of the zone.
+    fn advise_guest_ram(host_addr: u64, size: u64, hints: &mut MemoryZoneHints) {
+        if hints.mergeable {
+            if let Err(e) = Self::madvise(host_addr, size, libc::MADV_MERGEABLE) {
+                if e.raw_os_error() == Some(libc::EINVAL) {
+                    warn!("kernel not configured with CONFIG_KSM");
+                } else {
+                    warn!("madvise error: {}", e);
+                }
+                warn!("failed to mark pages as mergeable");
+                hints.mergeable = false;
+            }
+        }
+
+        let advice = match hints.thp {
+            ThpMode::Always => libc::MADV_HUGEPAGE,
+            ThpMode::Never => libc::MADV_NOHUGEPAGE,
+            ThpMode::Madvise => return,
+        };
+        if let Err(e) = Self::madvise(host_addr, size, advice) {
+            if e.raw_os_error() == Some(libc::EINVAL) {
+                warn!("kernel not configured with CONFIG_TRANSPARENT_HUGEPAGE");
+            } else {
+                warn!("madvise error: {}", e);
+            }
+            warn!("failed to set the transparent huge pages policy");
+            hints.thp = ThpMode::Madvise;
+        }
+    }
+
+    // Map a guest RAM region, keeping track of the memory slot so that the
+    // pages dirtied by the guest can be retrieved during live migration.
+    fn create_guest_ram_mapping(&mut self, region: &GuestRegionMmap) -> Result<(), Error> {
+        let gpa = region.start_addr().raw_value();
+        let size = region.len() as u64;
+        let host_addr = region.as_ptr() as u64;
+        let slot = self.create_userspace_mapping(gpa, size, host_addr, false, false)?;
+        // The memory hotplugged while the dirty pages are logged is logged
+        // as well.
+        if self.log_dirty_pages {
+            let mem_region = self
+                .vm
+                .make_user_memory_region(slot, gpa, size, host_addr, false, true);
+            self.vm
+                .set_user_memory_region(mem_region)
+                .map_err(Error::SetUserMemoryRegion)?;
+        }
+
+        // The regions not backing a virtio-mem zone are part of the guest
+        // RAM from --memory, including the ones hotplugged through ACPI.
+        let zone = self
+            .virtiomem_zones
+            .iter()
+            .find(|(_, zone)| zone.region.start_addr() == region.start_addr())
+            .map(|(id, _)| id.clone());
+        if let Some(hints) = self.hints.iter_mut().find(|hints| hints.id == zone) {
+            Self::advise_guest_ram(host_addr, size, hints);
+        }
+
+        self.guest_ram_mappings.push(GuestRamMapping {
+            slot,
+            gpa,
+            size,
+            host_addr,
+        });
+
+        Ok(())
+    }
+
+    // Update all the guest RAM memory slots to enable or disable the
+    // logging of the pages written by the guest.
+    fn set_dirty_log(
+        &self,
+        log_dirty_pages: bool,
+    ) -> result::Result<(), hypervisor::HypervisorVmError> {
+        for mapping in self.guest_ram_mappings.iter() {
+            let mem_region = self.vm.make_user_memory_region(
+                mapping.slot,
+                mapping.gpa,
+                mapping.size,
+                mapping.host_addr,
+                false,
+                log_dirty_pages,
+            );
+            self.vm.set_user_memory_region(mem_region)?;
+        }
+
+        Ok(())
+    }
+
+    // Turn the pages harvested from the dirty rings into a bitmap per guest
+    // RAM mapping, as reported by the memory slots otherwise.
+    fn dirty_ring_bitmaps(&self) -> result::Result<Vec<Vec<u64>>, MigratableError> {
+        let dirty = self
+            .vm
+            .harvest_dirty_log()
+            .map_err(|e| MigratableError::DirtyLog(e.into()))?;
+
+        let mut bitmaps = Vec::new();
+        for mapping in self.guest_ram_mappings.iter() {
+            let pages = mapping.size / DIRTY_LOG_PAGE_SIZE;
+            let mut bitmap = vec![0u64; ((pages + 63) / 64) as usize];
+            for (_, offset) in dirty
+                .iter()
+                .filter(|(slot, offset)| *slot == mapping.slot && *offset < pages)
+            {
+                bitmap[(offset / 64) as usize] |= 1 << (offset % 64);
+            }
+            bitmaps.push(bitmap);
+        }
+
+        Ok(bitmaps)
+    }
+
+    /// The log of the guest pages written by the VMM on behalf of the
+    /// virtio devices, which the hypervisor doesn't report. It is turned on
+    /// and off along with the dirty log.
+    pub fn device_dirty_log(&self) -> Arc<DirtyLog> {
+        self.device_dirty_log.clone()
+    }
+
+    /// The table of all the guest RAM, as sent by the first pre-copy round
+    /// of a live migration.
+    pub fn memory_range_table(&self) -> MemoryRangeTable {
+        let mut table = MemoryRangeTable::default();
+        for mapping in self.guest_ram_mappings.iter() {
+            table.push(MemoryRange {
+                gpa: mapping.gpa,
+                length: mapping.size,
+            });
+        }
+
+        table
+    }
+
+    /// Write the guest memory described by `table` to the migration stream,
+    /// the table itself coming first. When compression is enabled, the
+    /// memory is sent one page at a time so that each page can be sent raw
+    /// if it doesn't compress well.
+    pub fn send_memory_regions<W: Write>(
+        &self,
+        table: &MemoryRangeTable,
+        fd: &mut W,
+        compression: Compression,
+    ) -> result::Result<(), MigratableError> {
+        table.write_to(fd)?;
+
+        let guest_memory = self.guest_memory.memory();
+        let mut page = vec![0u8; DIRTY_LOG_PAGE_SIZE as usize];
+        for range in table.regions() {
+            if compression == Compression::None {
+                guest_memory
+                    .write_all_to(GuestAddress(range.gpa), fd, range.length as usize)
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                continue;
+            }
+
+            let mut offset = 0;
+            while offset < range.length {
+                let len = std::cmp::min(DIRTY_LOG_PAGE_SIZE, range.length - offset) as usize;
+                guest_memory
+                    .read_slice(&mut page[..len], GuestAddress(range.gpa + offset))
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                write_chunk(fd, &page[..len], compression)?;
+                offset += len as u64;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read a table and the guest memory it describes from the migration
+    /// stream, as written by `send_memory_regions()`.
+    pub fn receive_memory_regions<R: Read>(
+        &self,
+        fd: &mut R,
+        compression: Compression,
+    ) -> result::Result<MemoryRangeTable, MigratableError> {
+        let table = MemoryRangeTable::read_from(fd)?;
+
+        let guest_memory = self.guest_memory.memory();
+        let mut page = vec![0u8; DIRTY_LOG_PAGE_SIZE as usize];
+        for range in table.regions() {
+            if compression == Compression::None {
+                guest_memory
+                    .read_exact_from(GuestAddress(range.gpa), fd, range.length as usize)
+                    .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+                continue;
+            }
+
+            let mut offset = 0;
+            while offset < range.length {
+                let len = std::cmp::min(DIRTY_LOG_PAGE_SIZE, range.length - offset) as usize;
+                read_chunk(fd, &mut page[..len], compression)?;
+                guest_memory
+                    .write_slice(&page[..len], GuestAddress(range.gpa + offset))
+                    .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+                offset += len as u64;
+            }
+        }
+
+        Ok(table)
+    }
+
+    /// Let the guest run before the `pending` pages of its memory are
+    /// received from the post-copy `reader`, these pages being discarded
+    /// and requested through `writer` as the guest faults on them.
+    pub fn start_postcopy<R, W>(
+        &self,
+        pending: &MemoryRangeTable,
+        reader: R,
+        writer: W,
+        compression: Compression,
+    ) -> result::Result<(), MigratableError>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let uffd = Userfaultfd::new().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Could not create userfaultfd: {}", e))
+        })?;
+
+        let guest_memory = self.guest_memory.memory();
+        let mut regions = Vec::new();
+        guest_memory.with_regions_mut(|index, region| {
+            let gpa = region.start_addr().raw_value();
+            let size = region.len() as u64;
+            let host_addr = region.as_ptr() as u64;
+            let mut discarded = false;
+            for range in pending.regions() {
+                let start = std::cmp::max(range.gpa, gpa);
+                let end = std::cmp::min(range.gpa + range.length, gpa + size);
+                if start >= end {
+                    continue;
+                }
+
+                // Only the private anonymous pages can be discarded, and
+                // be reported missing by the userfaultfd.
+                if !discarded {
+                    let snapshot_region = self.snapshot_region(index, region);
+                    if snapshot_region.backing != MemoryBacking::Anonymous
+                        || snapshot_region.shared
+                        || snapshot_region.hugepages
+                    {
+                        return Err(MigratableError::MigrateReceive(anyhow!(
+                            "Post-copy is only supported for private anonymous memory"
+                        )));
+                    }
+                }
+                Self::madvise(host_addr + start - gpa, end - start, libc::MADV_DONTNEED)
+                    .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+                discarded = true;
+            }
+
+            if discarded {
+                uffd.register(host_addr, size).map_err(|e| {
+                    MigratableError::MigrateReceive(anyhow!(
+                        "Could not register the guest memory with userfaultfd: {}",
+                        e
+                    ))
+                })?;
+                regions.push(PostcopyRegion {
+                    gpa,
+                    size,
+                    host_addr,
+                });
+            }
+
+            Ok(())
+        })?;
+
+        postcopy::start_postcopy_destination(
+            uffd,
+            regions,
+            pending,
+            DIRTY_LOG_PAGE_SIZE,
+            compression,
+            reader,
+            writer,
+        )
+    }
+
     pub fn remove_userspace_mapping(
         &mut self,
         guest_phys_addr: u64,
@@ -776,6 +1742,7 @@ impl MemoryManager {
             0, /* memory_size -- using 0 removes this slot */
             userspace_addr,
             false, /* readonly -- don't care */
+            false, /* log dirty */
         );
 
         self.vm
@@ -816,21 +1783,102 @@ impl MemoryManager {
         Ok(())
     }
 
-    pub fn virtiomem_resize(&mut self, size: u64) -> Result<(), Error> {
-        let region = self.virtiomem_region.take();
-        if let Some(region) = region {
-            self.add_region(region)?;
+    fn virtio_mem_zone_mut(&mut self, id: Option<&str>) -> Result<&mut VirtioMemZone, Error> {
+        match id {
+            Some(id) => self.virtiomem_zones.get_mut(id),
+            None => self.virtiomem_zone.as_mut(),
         }
+        .ok_or(Error::UnknownMemoryZone)
+    }
 
-        if let Some(resize) = &self.virtiomem_resize {
-            resize.work(size).map_err(Error::VirtioMemResizeFail)?;
-        } else {
-            panic!("should not fail here");
+    // Insert the region of a virtio-mem zone into the guest memory. The
+    // region is returned if it was not part of the guest memory yet.
+    fn insert_virtio_mem_region(
+        &mut self,
+        id: Option<&str>,
+    ) -> Result<Option<Arc<GuestRegionMmap>>, Error> {
+        let zone = self.virtio_mem_zone_mut(id)?;
+        if zone.region_inserted {
+            return Ok(None);
+        }
+        zone.region_inserted = true;
+        let region = zone.region.clone();
+
+        self.add_region(region.clone())?;
+
+        Ok(Some(region))
+    }
+
+    fn insert_virtio_mem_region_at(&mut self, start_addr: GuestAddress) -> Result<(), Error> {
+        if let Some(zone) = &self.virtiomem_zone {
+            if zone.region.start_addr() == start_addr {
+                self.insert_virtio_mem_region(None)?;
+                return Ok(());
+            }
+        }
+
+        let id = self
+            .virtiomem_zones
+            .iter()
+            .find(|(_, zone)| zone.region.start_addr() == start_addr)
+            .map(|(id, _)| id.clone());
+        if let Some(id) = id {
+            self.insert_virtio_mem_region(Some(&id))?;
         }
 
         Ok(())
     }
 
+    pub fn virtiomem_resize(&mut self, size: u64) -> Result<Option<Arc<GuestRegionMmap>>, Error> {
+        let region = self.insert_virtio_mem_region(None)?;
+
+        self.virtio_mem_zone_mut(None)?
+            .resize
+            .work(size)
+            .map_err(Error::VirtioMemResizeFail)?;
+
+        Ok(region)
+    }
+
+    /// Resize the virtio-mem device associated with the memory zone `id`.
+    /// The zone region is returned the first time it is added to the guest
+    /// memory.
+    pub fn resize_zone(
+        &mut self,
+        id: &str,
+        size: u64,
+    ) -> Result<Option<Arc<GuestRegionMmap>>, Error> {
+        let region = self.insert_virtio_mem_region(Some(id))?;
+
+        self.virtio_mem_zone_mut(Some(id))?
+            .resize
+            .work(size)
+            .map_err(Error::VirtioMemResizeFail)?;
+
+        Ok(region)
+    }
+
+    /// Insert the regions of the memory zones having some memory plugged
+    /// from boot. This must only be done once the memory map has been
+    /// described to the guest, since this memory is not boot RAM.
+    pub fn insert_hotplugged_zones(&mut self) -> Result<Vec<Arc<GuestRegionMmap>>, Error> {
+        let ids: Vec<String> = self
+            .virtiomem_zones
+            .iter()
+            .filter(|(_, zone)| zone.hotplugged_size > 0)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        let mut regions = Vec::new();
+        for id in ids {
+            if let Some(region) = self.insert_virtio_mem_region(Some(&id))? {
+                regions.push(region);
+            }
+        }
+
+        Ok(regions)
+    }
+
     pub fn balloon_resize(&mut self, expected_ram: u64) -> Result<u64, Error> {
         let mut balloon_size = 0;
         if let Some(balloon) = &self.balloon {
@@ -847,16 +1895,28 @@ impl MemoryManager {
         Ok(balloon_size)
     }
 
+    pub fn balloon_info(&self) -> Option<virtio_devices::BalloonInfo> {
+        self.balloon
+            .as_ref()
+            .map(|balloon| balloon.lock().unwrap().info())
+    }
+
+    pub fn balloon_stats(&self) -> Option<virtio_devices::BalloonStats> {
+        self.balloon
+            .as_ref()
+            .map(|balloon| balloon.lock().unwrap().get_stats())
+    }
+
     /// In case this function resulted in adding a new memory region to the
     /// guest memory, the new region is returned to the caller. The virtio-mem
-    /// use case never adds a new region as the whole hotpluggable memory has
-    /// already been allocated at boot time.
+    /// use case only adds a new region the first time it is resized, as the
+    /// whole hotpluggable memory has already been allocated at boot time.
     pub fn resize(&mut self, desired_ram: u64) -> Result<Option<Arc<GuestRegionMmap>>, Error> {
         let mut region: Option<Arc<GuestRegionMmap>> = None;
         match self.hotplug_method {
             HotplugMethod::VirtioMem => {
                 if desired_ram >= self.boot_ram {
-                    self.virtiomem_resize(desired_ram - self.boot_ram)?;
+                    region = self.virtiomem_resize(desired_ram - self.boot_ram)?;
                     self.current_ram = desired_ram;
                 }
             }
@@ -1339,6 +2399,52 @@ pub struct MemoryRegion {
     #[serde(with = "GuestAddressDef")]
     start_addr: GuestAddress,
     size: GuestUsize,
+    #[serde(default)]
+    backing: MemoryBacking,
+    /// Memory zone the region belongs to.
+    #[serde(default)]
+    zone: Option<String>,
+    #[serde(default)]
+    shared: bool,
+    #[serde(default)]
+    hugepages: bool,
+}
+
+// Ranges of `file` holding data, as (offset, length) relative to `start`,
+// between `start` and `start + len`. The filesystems not tracking holes
+// report the whole file as data.
+fn data_ranges(file: &File, start: u64, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let end = start + len;
+    let mut ranges = Vec::new();
+    let mut offset = start;
+    while offset < end {
+        // Safe because lseek() doesn't access any memory, and we check the
+        // return value.
+        let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            // Nothing but a hole up to the end of the file.
+            if err.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(err);
+        }
+        let data = data as u64;
+        if data >= end {
+            break;
+        }
+        // Safe for the same reasons.
+        let hole = unsafe { libc::lseek(fd, data as libc::off_t, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let hole = std::cmp::min(hole as u64, end);
+        ranges.push((data - start, hole - data));
+        offset = hole;
+    }
+
+    Ok(ranges)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -1346,6 +2452,213 @@ pub struct MemoryManagerSnapshotData {
     memory_regions: Vec<MemoryRegion>,
 }
 
+impl MemoryManager {
+    // Describe a region of the guest memory as saved in a snapshot, the
+    // content of a memory zone being saved in a file named after it.
+    fn snapshot_region(&self, index: usize, region: &GuestRegionMmap) -> MemoryRegion {
+        let start_addr = region.start_addr();
+        let zone = self
+            .virtiomem_zones
+            .iter()
+            .find(|(_, zone)| zone.region.start_addr() == start_addr)
+            .map(|(id, zone)| (Some(id.clone()), zone))
+            .or_else(|| {
+                self.virtiomem_zone
+                    .as_ref()
+                    .filter(|zone| zone.region.start_addr() == start_addr)
+                    .map(|zone| (None, zone))
+            });
+
+        let (backing_file, zone, backing, shared, hugepages) = match zone {
+            Some((Some(id), zone)) => (
+                format!("memory-zone-{}", id),
+                Some(id),
+                zone.backing,
+                zone.shared,
+                zone.hugepages,
+            ),
+            Some((None, zone)) => (
+                format!("memory-region-{}", index),
+                None,
+                zone.backing,
+                zone.shared,
+                zone.hugepages,
+            ),
+            None => (
+                format!("memory-region-{}", index),
+                None,
+                MemoryBacking::default(),
+                self.shared || self.backing_file.is_some(),
+                self.hugepages,
+            ),
+        };
+
+        MemoryRegion {
+            backing_file: PathBuf::from(backing_file),
+            start_addr,
+            size: region.len(),
+            backing,
+            zone,
+            shared,
+            hugepages,
+        }
+    }
+
+    // Write the chunks of `region` which changed since the `previous`
+    // snapshot of the chain to the file at `path`, returning the checksums
+    // of the chunks written and of all of them. Without any previous
+    // snapshot, every chunk is written.
+    // Memory regions of `guest_memory` to be written to a snapshot, the
+    // checksums of their chunks being compared to the ones of the `tip` of
+    // the chain.
+    fn regions_to_write(
+        &self,
+        guest_memory: &GuestMemoryMmap,
+        tip: Option<&SnapshotChainTip>,
+    ) -> result::Result<Vec<RegionToWrite>, MigratableError> {
+        let mut regions = Vec::new();
+        guest_memory.with_regions_mut(|index, region| {
+            let snapshot_region = self.snapshot_region(index, region);
+            let start_addr = region.start_addr().raw_value();
+
+            // The holes of the file backing a shared region are neither
+            // read nor written, reading them would allocate the memory they
+            // cover.
+            let data_ranges = match region.file_offset() {
+                Some(file_offset) if snapshot_region.shared => {
+                    data_ranges(file_offset.file(), file_offset.start(), region.len())
+                        .map_err(|e| MigratableError::MigrateSend(e.into()))?
+                }
+                _ => vec![(0, region.len())],
+            };
+
+            regions.push(RegionToWrite {
+                backing_file: snapshot_region.backing_file,
+                start_addr,
+                size: region.len(),
+                data_ranges,
+                previous: tip.and_then(|tip| {
+                    tip.regions
+                        .iter()
+                        .find(|r| r.start_addr == start_addr && r.size == region.len())
+                        .map(|r| r.chunks.clone())
+                }),
+            });
+
+            Ok(())
+        })?;
+
+        Ok(regions)
+    }
+
+    fn send_chain_snapshot(
+        &mut self,
+        destination: &Path,
+        options: &WriteOptions,
+    ) -> result::Result<(), MigratableError> {
+        let dirty = match self.snapshot_chain {
+            Some(_) => Some(self.dirty_log()?),
+            None => None,
+        };
+
+        let guest_memory = self.guest_memory.memory();
+        let regions = self.regions_to_write(&guest_memory, self.snapshot_chain.as_ref())?;
+        let written = snapshot_chain::write_regions(
+            destination,
+            &guest_memory,
+            regions,
+            dirty.as_ref(),
+            options,
+        )?;
+
+        let mut regions = Vec::with_capacity(written.len());
+        let mut tip_regions = Vec::with_capacity(written.len());
+        for region in written {
+            tip_regions.push(RegionManifest {
+                chunks: region.checksums,
+                extents: Vec::new(),
+                ..region.manifest.clone()
+            });
+            regions.push(region.manifest);
+        }
+
+        snapshot_chain::write_manifest(
+            destination,
+            &MemoryManifest {
+                parent: self.snapshot_chain.as_ref().map(|tip| tip.path.clone()),
+                chunk_size: CHUNK_SIZE,
+                compression: options.compression_level.map(|_| ChunkCompression::Zstd),
+                regions,
+            },
+        )?;
+
+        if self.snapshot_chain.is_none() {
+            self.start_dirty_log()?;
+        }
+        self.snapshot_chain = Some(SnapshotChainTip {
+            path: destination.to_path_buf(),
+            regions: tip_regions,
+        });
+
+        Ok(())
+    }
+
+    /// Write the guest memory, as captured by the last snapshot, to the
+    /// snapshot directory `destination`. The compressed snapshots come with
+    /// a manifest telling where each chunk lies, the uncompressed ones being
+    /// laid out as they always were.
+    pub fn send_full(
+        &self,
+        destination: &Path,
+        options: &WriteOptions,
+    ) -> result::Result<(), MigratableError> {
+        let guest_memory = match &*self.snapshot.lock().unwrap() {
+            Some(guest_memory) => guest_memory.clone(),
+            None => return Ok(()),
+        };
+
+        let regions = self.regions_to_write(&guest_memory, None)?;
+        let written =
+            snapshot_chain::write_regions(destination, &guest_memory, regions, None, options)?;
+
+        if options.compression_level.is_some() {
+            snapshot_chain::write_manifest(
+                destination,
+                &MemoryManifest {
+                    parent: None,
+                    chunk_size: CHUNK_SIZE,
+                    compression: Some(ChunkCompression::Zstd),
+                    regions: written.into_iter().map(|r| r.manifest).collect(),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the guest memory to the snapshot directory `destination`, as
+    /// part of an incremental chain. The first snapshot of the chain holds
+    /// all the guest memory, and the pages dirtied by the guest are logged
+    /// from then on, for the next snapshots to only hold the chunks which
+    /// changed since the previous one.
+    pub fn send_incremental(
+        &mut self,
+        destination: &Path,
+        options: &WriteOptions,
+    ) -> result::Result<(), MigratableError> {
+        let result = self.send_chain_snapshot(destination, options);
+        // The pages dirtied since the previous snapshot are lost along with
+        // the failed one, the next snapshot starts a new chain.
+        if result.is_err() && self.snapshot_chain.take().is_some() && self.log_dirty_pages {
+            if let Err(e) = self.stop_dirty_log() {
+                warn!("Could not stop logging the dirty pages: {}", e);
+            }
+        }
+
+        result
+    }
+}
+
 impl Snapshottable for MemoryManager {
     fn id(&self) -> String {
         MEMORY_MANAGER_SNAPSHOT_ID.to_string()
@@ -1362,11 +2675,7 @@ impl Snapshottable for MemoryManager {
                 return Err(MigratableError::Snapshot(anyhow!("Zero length region")));
             }
 
-            memory_regions.push(MemoryRegion {
-                backing_file: PathBuf::from(format!("memory-region-{}", index)),
-                start_addr: region.start_addr(),
-                size: region.len(),
-            });
+            memory_regions.push(self.snapshot_region(index, region));
 
             Ok(())
         })?;
@@ -1415,30 +2724,7 @@ impl Transportable for MemoryManager {
                         Ok(path)
                     })?;
 
-                if let Some(guest_memory) = &*self.snapshot.lock().unwrap() {
-                    guest_memory.with_regions_mut(|index, region| {
-                        let mut memory_region_path = vm_memory_snapshot_path.clone();
-                        memory_region_path.push(format!("memory-region-{}", index));
-
-                        // Create the snapshot file for the region
-                        let mut memory_region_file = OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .create_new(true)
-                            .open(memory_region_path)
-                            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
-
-                        guest_memory
-                            .write_to(
-                                region.start_addr(),
-                                &mut memory_region_file,
-                                region.len().try_into().unwrap(),
-                            )
-                            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
-
-                        Ok(())
-                    })?;
-                }
+                self.send_full(&vm_memory_snapshot_path, &WriteOptions::default())?;
             }
             _ => {
                 return Err(MigratableError::MigrateSend(anyhow!(
@@ -1450,4 +2736,190 @@ impl Transportable for MemoryManager {
         Ok(())
     }
 }
-impl Migratable for MemoryManager {}
+// Set the bits of the dirty pages bitmap of the guest RAM mapping of `size`
+// bytes at `gpa` for the pages the devices wrote, `pages` being page frame
+// numbers in ascending order.
+fn mark_device_pages(bitmap: &mut [u64], gpa: u64, size: u64, pages: &[u64]) {
+    let first = gpa / DIRTY_LOG_PAGE_SIZE;
+    let end = (gpa + size) / DIRTY_LOG_PAGE_SIZE;
+    let start = match pages.binary_search(&first) {
+        Ok(i) | Err(i) => i,
+    };
+    for page in pages[start..].iter().take_while(|page| **page < end) {
+        let offset = page - first;
+        bitmap[(offset / 64) as usize] |= 1 << (offset % 64);
+    }
+}
+
+impl Migratable for MemoryManager {
+    fn start_dirty_log(&mut self) -> result::Result<(), MigratableError> {
+        // Drop what the rings may still hold from a previous migration.
+        if self.dirty_ring {
+            self.vm
+                .harvest_dirty_log()
+                .map_err(|e| MigratableError::StartDirtyLog(e.into()))?;
+        }
+        self.set_dirty_log(true)
+            .map_err(|e| MigratableError::StartDirtyLog(e.into()))?;
+        self.device_dirty_log.start();
+        self.log_dirty_pages = true;
+        Ok(())
+    }
+
+    fn stop_dirty_log(&mut self) -> result::Result<(), MigratableError> {
+        self.device_dirty_log.stop();
+        self.set_dirty_log(false)
+            .map_err(|e| MigratableError::StopDirtyLog(e.into()))?;
+        self.log_dirty_pages = false;
+        Ok(())
+    }
+
+    // The hypervisor only reports the pages written by the guest vCPUs,
+    // the ones the VMM wrote on behalf of the virtio devices are added
+    // from the device dirty log.
+    fn dirty_log(&mut self) -> result::Result<MemoryRangeTable, MigratableError> {
+        let start = Instant::now();
+        let device_pages = self.device_dirty_log.take();
+        let bitmaps = if self.dirty_ring {
+            self.dirty_ring_bitmaps()?
+        } else {
+            let mut bitmaps = Vec::new();
+            for mapping in self.guest_ram_mappings.iter() {
+                bitmaps.push(
+                    self.vm
+                        .get_dirty_log(mapping.slot, mapping.size)
+                        .map_err(|e| MigratableError::DirtyLog(e.into()))?,
+                );
+            }
+            bitmaps
+        };
+
+        let mut table = MemoryRangeTable::default();
+        for (mapping, mut bitmap) in self.guest_ram_mappings.iter().zip(bitmaps) {
+            mark_device_pages(&mut bitmap, mapping.gpa, mapping.size, &device_pages);
+            table.extend(MemoryRangeTable::from_bitmap(
+                bitmap,
+                mapping.gpa,
+                DIRTY_LOG_PAGE_SIZE,
+            ));
+        }
+        debug!(
+            "Dirty log of {} pages from the dirty {} in {} us",
+            table.effective_size() / DIRTY_LOG_PAGE_SIZE,
+            if self.dirty_ring { "ring" } else { "bitmap" },
+            start.elapsed().as_micros()
+        );
+
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockVm;
+
+    // The guest RAM of 16 MiB all fits in the first memory slot.
+    fn test_memory_manager(vm: &Arc<MockVm>, dirty_ring: bool) -> Arc<Mutex<MemoryManager>> {
+        let config = MemoryConfig {
+            size: 16 << 20,
+            dirty_ring,
+            ..Default::default()
+        };
+        MemoryManager::new(vm.clone(), &config, None, false).unwrap()
+    }
+
+    fn range(gpa: u64, pages: u64) -> MemoryRange {
+        MemoryRange {
+            gpa,
+            length: pages * DIRTY_LOG_PAGE_SIZE,
+        }
+    }
+
+    #[test]
+    fn test_mark_device_pages() {
+        // A mapping of 128 pages from page 0x100, the device pages around
+        // it being left out.
+        let mut bitmap = vec![0u64; 2];
+        let pages = [0x10, 0xff, 0x100, 0x13f, 0x140, 0x17f, 0x180, 0x200];
+        mark_device_pages(&mut bitmap, 0x100_000, 0x80_000, &pages);
+        assert_eq!(bitmap, vec![1 | 1 << 63, 1 | 1 << 63]);
+
+        let table = MemoryRangeTable::from_bitmap(bitmap, 0x100_000, DIRTY_LOG_PAGE_SIZE);
+        assert_eq!(table.effective_size(), 4 * DIRTY_LOG_PAGE_SIZE);
+        assert_eq!(table.regions()[0].gpa, 0x100_000);
+
+        // Nothing to mark.
+        let mut bitmap = vec![0u64; 1];
+        mark_device_pages(&mut bitmap, 0x100_000, 0x40_000, &[]);
+        mark_device_pages(&mut bitmap, 0x100_000, 0x40_000, &[0x140, 0x150]);
+        assert_eq!(bitmap, vec![0]);
+    }
+
+    #[test]
+    fn test_dirty_log_bitmap_fallback() {
+        // Without dirty rings, the bitmaps of the slots are read instead.
+        let vm = Arc::new(MockVm::default());
+        let memory_manager = test_memory_manager(&vm, true);
+        let mut mm = memory_manager.lock().unwrap();
+        assert!(!mm.dirty_ring);
+        mm.start_dirty_log().unwrap();
+        vm.bitmaps.lock().unwrap().insert(0, vec![0b1011]);
+        mm.device_dirty_log().mark(GuestAddress(0x8000), 1);
+        let table = mm.dirty_log().unwrap();
+        assert_eq!(
+            table.regions(),
+            &[range(0, 2), range(0x3000, 1), range(0x8000, 1)]
+        );
+        mm.stop_dirty_log().unwrap();
+        assert_eq!(
+            *vm.calls.lock().unwrap(),
+            vec!["enable_dirty_ring", "get_dirty_log"]
+        );
+
+        // Nor are they used when turned off.
+        let vm = Arc::new(MockVm {
+            dirty_ring: true,
+            ..Default::default()
+        });
+        let memory_manager = test_memory_manager(&vm, false);
+        let mut mm = memory_manager.lock().unwrap();
+        mm.start_dirty_log().unwrap();
+        vm.bitmaps.lock().unwrap().insert(0, vec![1 << 63]);
+        let table = mm.dirty_log().unwrap();
+        assert_eq!(table.regions(), &[range(0x3f000, 1)]);
+        assert_eq!(*vm.calls.lock().unwrap(), vec!["get_dirty_log"]);
+    }
+
+    #[test]
+    fn test_dirty_log_ring() {
+        let vm = Arc::new(MockVm {
+            dirty_ring: true,
+            ..Default::default()
+        });
+        let memory_manager = test_memory_manager(&vm, true);
+        let mut mm = memory_manager.lock().unwrap();
+        assert!(mm.dirty_ring);
+
+        // What the rings logged before the migration is dropped.
+        vm.harvested.lock().unwrap().push((0, 5));
+        mm.start_dirty_log().unwrap();
+
+        // The pages may be harvested more than once, and the ones of
+        // another slot, or past the end of this one, are left out.
+        *vm.harvested.lock().unwrap() = vec![(0, 1), (0, 3), (0, 1), (1, 2), (0, 0x1000)];
+        mm.device_dirty_log().mark(GuestAddress(0x2000), 1);
+        let table = mm.dirty_log().unwrap();
+        assert_eq!(table.regions(), &[range(0x1000, 3)]);
+        assert!(mm.dirty_log().unwrap().regions().is_empty());
+        assert_eq!(
+            *vm.calls.lock().unwrap(),
+            vec![
+                "enable_dirty_ring",
+                "harvest_dirty_log",
+                "harvest_dirty_log",
+                "harvest_dirty_log"
+            ]
+        );
+    }
+}
@@ -2,16 +2,70 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::config::VmConfig;
+use crate::cpu::MAX_THROTTLE;
+use crate::migration_progress::MigrationProgress;
+use crate::snapshot_chain;
 use crate::vm::{VmSnapshot, VM_SNAPSHOT_ID};
 use anyhow::anyhow;
-use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use url::Url;
-use vm_migration::{MigratableError, Snapshot};
+use vm_migration::{protocol::MemoryRangeTable, Migratable, MigratableError, Snapshot};
 
 pub const VM_SNAPSHOT_FILE: &str = "vm.json";
 
+pub const TCP_URL_PREFIX: &str = "tcp://";
+
+pub const UNIX_URL_PREFIX: &str = "unix:";
+
+// Address listened on when a tcp:// source URL doesn't specify one.
+pub const TCP_DEFAULT_LISTEN_HOST: &str = "0.0.0.0";
+
+// Number of pre-copy rounds after which the VM is stopped, even if the
+// amount of memory it dirties did not converge.
+const PRECOPY_MAX_ITERATIONS: usize = 5;
+
+// Amount of dirty memory under which the VM is stopped for the final
+// stop-and-copy round.
+const PRECOPY_DIRTY_THRESHOLD: u64 = 8 << 20;
+
+// Percentage of the time the vCPUs are first kept out of the guest for,
+// once a pre-copy round didn't reduce the amount of dirty memory, and how
+// much it is raised by at every other round which didn't.
+const AUTO_CONVERGE_INITIAL_THROTTLE: u8 = 20;
+const AUTO_CONVERGE_THROTTLE_INCREMENT: u8 = 10;
+
+/// Pre-copy parameters of a live migration.
+pub struct PrecopyConfig {
+    /// Maximum number of dirty pages rounds sent while the VM is running.
+    pub max_iterations: usize,
+    /// Amount of dirty memory, in bytes, small enough for the VM to be
+    /// stopped and the remaining pages sent.
+    pub dirty_threshold: u64,
+    /// Throttle the vCPUs more and more while the rounds don't reduce the
+    /// amount of dirty memory, the rounds then going on past
+    /// `max_iterations` until the throttle reaches `max_throttle`.
+    pub auto_converge: bool,
+    /// Maximum percentage of the time the vCPUs are kept out of the guest
+    /// for.
+    pub max_throttle: u8,
+}
+
+impl Default for PrecopyConfig {
+    fn default() -> Self {
+        PrecopyConfig {
+            max_iterations: PRECOPY_MAX_ITERATIONS,
+            dirty_threshold: PRECOPY_DIRTY_THRESHOLD,
+            auto_converge: false,
+            max_throttle: MAX_THROTTLE,
+        }
+    }
+}
+
 pub fn url_to_path(url: &Url) -> std::result::Result<PathBuf, MigratableError> {
     match url.scheme() {
         "file" => url
@@ -35,7 +89,123 @@ pub fn url_to_path(url: &Url) -> std::result::Result<PathBuf, MigratableError> {
     }
 }
 
-pub fn recv_vm_snapshot(source_url: &str) -> std::result::Result<Snapshot, MigratableError> {
+/// Address of a `tcp://[<host>]:<port>` URL, or `None` if the URL has
+/// another scheme. The host can only be omitted if `default_host` is
+/// provided.
+pub fn tcp_url_address(
+    url: &str,
+    default_host: Option<&str>,
+) -> std::result::Result<Option<String>, MigratableError> {
+    if !url.starts_with(TCP_URL_PREFIX) {
+        return Ok(None);
+    }
+
+    let address = &url[TCP_URL_PREFIX.len()..];
+    let (host, port) = match address.rfind(':') {
+        Some(index) => (&address[..index], &address[index + 1..]),
+        None => {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Missing port in TCP URL: {}",
+                url
+            )))
+        }
+    };
+    port.parse::<u16>().map_err(|e| {
+        MigratableError::MigrateSend(anyhow!("Invalid port in TCP URL {}: {}", url, e))
+    })?;
+    let host = match (host, default_host) {
+        ("", Some(default_host)) => default_host,
+        ("", None) => {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Missing host in TCP URL: {}",
+                url
+            )))
+        }
+        (host, _) => host,
+    };
+
+    Ok(Some(format!("{}:{}", host, port)))
+}
+
+/// Path of the socket of a `unix:<path>` URL.
+pub fn unix_url_path(url: &str) -> std::result::Result<PathBuf, MigratableError> {
+    if !url.starts_with(UNIX_URL_PREFIX) || url.len() == UNIX_URL_PREFIX.len() {
+        return Err(MigratableError::MigrateSend(anyhow!(
+            "Invalid migration URL, expected unix:<path>: {}",
+            url
+        )));
+    }
+
+    Ok(PathBuf::from(&url[UNIX_URL_PREFIX.len()..]))
+}
+
+/// Write `snapshot` to a migration stream, prefixed with its length.
+pub fn send_vm_snapshot<W: Write>(
+    snapshot: &Snapshot,
+    fd: &mut W,
+) -> std::result::Result<(), MigratableError> {
+    let vm_snapshot =
+        serde_json::to_vec(snapshot).map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+    fd.write_all(&(vm_snapshot.len() as u64).to_le_bytes())
+        .and_then(|_| fd.write_all(&vm_snapshot))
+        .map_err(|e| MigratableError::MigrateSend(e.into()))
+}
+
+/// Write `snapshot` to the `vm.json` file of the snapshot directory `dir`.
+pub fn write_vm_snapshot(
+    snapshot: &Snapshot,
+    dir: &Path,
+) -> std::result::Result<(), MigratableError> {
+    // Create the snapshot file
+    let mut vm_snapshot_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(dir.join(VM_SNAPSHOT_FILE))
+        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+    // Serialize and write the snapshot
+    let vm_snapshot =
+        serde_json::to_vec(snapshot).map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+    vm_snapshot_file
+        .write_all(&vm_snapshot)
+        .map_err(|e| MigratableError::MigrateSend(e.into()))
+}
+
+/// Read a snapshot written by `send_vm_snapshot()` from a migration stream.
+pub fn read_vm_snapshot<R: Read>(fd: &mut R) -> std::result::Result<Snapshot, MigratableError> {
+    let mut len = [0u8; 8];
+    fd.read_exact(&mut len)
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+
+    let mut vm_snapshot = vec![0u8; u64::from_le_bytes(len) as usize];
+    fd.read_exact(&mut vm_snapshot)
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+
+    serde_json::from_slice(&vm_snapshot).map_err(|e| MigratableError::MigrateReceive(e.into()))
+}
+
+/// Receive the snapshot found at `source_url`. For a tcp:// URL, this
+/// waits for the source to connect, and the returned stream is where the
+/// guest memory comes from once the snapshot has been read.
+pub fn recv_vm_snapshot(
+    source_url: &str,
+) -> std::result::Result<(Snapshot, Option<TcpStream>), MigratableError> {
+    if let Some(address) = tcp_url_address(source_url, Some(TCP_DEFAULT_LISTEN_HOST))? {
+        let listener =
+            TcpListener::bind(&address).map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+        info!("Waiting for the snapshot on {}", address);
+        let (mut stream, peer) = listener
+            .accept()
+            .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+        info!("Receiving the snapshot from {}", peer);
+
+        let vm_snapshot = read_vm_snapshot(&mut stream)?;
+        return Ok((vm_snapshot, Some(stream)));
+    }
+
     let url = Url::parse(source_url).map_err(|e| {
         MigratableError::MigrateSend(anyhow!("Could not parse destination URL: {}", e))
     })?;
@@ -52,7 +222,7 @@ pub fn recv_vm_snapshot(source_url: &str) -> std::result::Result<Snapshot, Migra
             let vm_snapshot = serde_json::from_reader(vm_snapshot_reader)
                 .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
 
-            Ok(vm_snapshot)
+            Ok((vm_snapshot, None))
         }
         _ => Err(MigratableError::MigrateSend(anyhow!(
             "Unsupported VM transport URL scheme: {}",
@@ -75,3 +245,691 @@ pub fn get_vm_snapshot(snapshot: &Snapshot) -> std::result::Result<VmSnapshot, M
         "Could not find VM config snapshot section"
     )))
 }
+
+/// Merge the incremental snapshot chain ending with the snapshot found at
+/// `source_url` into a whole snapshot, written to `destination_url`.
+pub fn consolidate_vm_snapshot(
+    source_url: &str,
+    destination_url: &str,
+) -> std::result::Result<(), MigratableError> {
+    let parse = |url: &str| {
+        Url::parse(url)
+            .map_err(|e| {
+                MigratableError::MigrateSend(anyhow!("Could not parse URL {}: {}", url, e))
+            })
+            .and_then(|url| url_to_path(&url))
+    };
+
+    snapshot_chain::consolidate(&parse(source_url)?, &parse(destination_url)?)
+}
+
+/// Refuse to live migrate a VM whose guest memory may be written behind
+/// the back of the dirty pages logging, which only covers the vCPUs and
+/// the virtio devices emulated by the VMM: by the vhost-user and vDPA
+/// backends, by the VFIO devices, or by the emulated NVMe controllers.
+pub fn check_config(config: &VmConfig) -> std::result::Result<(), MigratableError> {
+    let unsupported = |device: &str| {
+        Err(MigratableError::MigrateSend(anyhow!(
+            "Live migration doesn't support {}",
+            device
+        )))
+    };
+
+    if let Some(net) = &config.net {
+        if net.iter().any(|net| net.vhost_user) {
+            return unsupported("vhost-user-net");
+        }
+    }
+    if let Some(disks) = &config.disks {
+        if disks.iter().any(|disk| disk.vhost_user) {
+            return unsupported("vhost-user-blk");
+        }
+        if disks.iter().any(|disk| disk.nvme) {
+            return unsupported("NVMe disks");
+        }
+    }
+    if config.fs.iter().flatten().next().is_some() {
+        return unsupported("virtio-fs");
+    }
+    if config.devices.iter().flatten().next().is_some() {
+        return unsupported("VFIO devices, which must be unplugged first");
+    }
+    if config.vdpa.iter().flatten().next().is_some() {
+        return unsupported("vDPA devices");
+    }
+
+    Ok(())
+}
+
+/// Send the guest memory of `migratable` through pre-copy rounds.
+///
+/// The whole `table` is sent first, while the dirty pages logging is
+/// enabled. Then each round only sends what the guest dirtied during the
+/// previous one, until the dirty set gets below the configured threshold
+/// or the maximum number of rounds is reached. At that point `stop` is
+/// called to stop the VM and the remaining dirty pages are sent. With
+/// auto-converge, `throttle` is called with the percentage of the time the
+/// vCPUs must be kept out of the guest for whenever a round didn't reduce
+/// the dirty set by a tenth, and with 0 once the rounds are over, whether
+/// they succeeded or not. The rounds, the rate the guest dirties its memory
+/// at and the throttle are reported through `progress`.
+pub fn send_memory_precopy<M, S, P, T>(
+    migratable: &mut M,
+    table: MemoryRangeTable,
+    config: &PrecopyConfig,
+    progress: &MigrationProgress,
+    send: S,
+    stop: P,
+    mut throttle: T,
+) -> std::result::Result<(), MigratableError>
+where
+    M: Migratable + ?Sized,
+    S: FnMut(&M, &MemoryRangeTable) -> std::result::Result<(), MigratableError>,
+    P: FnOnce() -> std::result::Result<(), MigratableError>,
+    T: FnMut(u8) -> std::result::Result<(), MigratableError>,
+{
+    migratable.start_dirty_log()?;
+    let result = precopy_rounds(
+        migratable,
+        table,
+        config,
+        progress,
+        send,
+        stop,
+        &mut throttle,
+    );
+    let unthrottled = if progress.throttle() > 0 {
+        progress.set_throttle(0);
+        throttle(0)
+    } else {
+        Ok(())
+    };
+    migratable.stop_dirty_log()?;
+
+    result.and(unthrottled)
+}
+
+fn precopy_rounds<M, S, P, T>(
+    migratable: &mut M,
+    table: MemoryRangeTable,
+    config: &PrecopyConfig,
+    progress: &MigrationProgress,
+    mut send: S,
+    stop: P,
+    mut throttle: T,
+) -> std::result::Result<(), MigratableError>
+where
+    M: Migratable + ?Sized,
+    S: FnMut(&M, &MemoryRangeTable) -> std::result::Result<(), MigratableError>,
+    P: FnOnce() -> std::result::Result<(), MigratableError>,
+    T: FnMut(u8) -> std::result::Result<(), MigratableError>,
+{
+    let mut round_start = Instant::now();
+    let mut previous_size = table.effective_size();
+    progress.start_round(previous_size);
+    send(migratable, &table)?;
+
+    let mut iteration = 0;
+    let pending = loop {
+        let dirty = migratable.dirty_log()?;
+        let size = dirty.effective_size();
+        progress.dirtied(size, round_start.elapsed());
+        round_start = Instant::now();
+        iteration += 1;
+        let throttled = progress.throttle();
+        if size <= config.dirty_threshold
+            || (iteration >= config.max_iterations
+                && (!config.auto_converge || throttled >= config.max_throttle))
+        {
+            break dirty;
+        }
+        if config.auto_converge && size * 10 > previous_size * 9 && throttled < config.max_throttle
+        {
+            let percent = if throttled == 0 {
+                AUTO_CONVERGE_INITIAL_THROTTLE
+            } else {
+                throttled.saturating_add(AUTO_CONVERGE_THROTTLE_INCREMENT)
+            };
+            let percent = std::cmp::min(percent, config.max_throttle);
+            info!(
+                "Pre-copy round {}: throttling the guest by {}%",
+                iteration, percent
+            );
+            // Accounted for first, to be removed if the vCPUs got throttled
+            // only partially.
+            progress.set_throttle(percent);
+            throttle(percent)?;
+        }
+        previous_size = size;
+        info!(
+            "Pre-copy round {}: {} bytes dirtied",
+            iteration,
+            dirty.effective_size()
+        );
+        progress.start_round(dirty.effective_size());
+        send(migratable, &dirty)?;
+    };
+
+    stop()?;
+
+    // The pages retrieved last have not been sent yet, and the guest may
+    // have dirtied some more before being stopped.
+    let mut dirty = pending;
+    dirty.extend(migratable.dirty_log()?);
+    progress.start_round(dirty.effective_size());
+    send(migratable, &dirty)
+}
+
+/// Send the guest memory of `migratable` before a post-copy phase.
+///
+/// The whole `table` is sent once, while the dirty pages logging is
+/// enabled, then `stop` is called to stop the VM. The pages the guest
+/// dirtied in the meantime are returned, to be sent once the guest runs on
+/// the destination.
+pub fn send_memory_postcopy<M, S, P>(
+    migratable: &mut M,
+    table: MemoryRangeTable,
+    progress: &MigrationProgress,
+    mut send: S,
+    stop: P,
+) -> std::result::Result<MemoryRangeTable, MigratableError>
+where
+    M: Migratable + ?Sized,
+    S: FnMut(&M, &MemoryRangeTable) -> std::result::Result<(), MigratableError>,
+    P: FnOnce() -> std::result::Result<(), MigratableError>,
+{
+    migratable.start_dirty_log()?;
+    let started = Instant::now();
+    progress.start_round(table.effective_size());
+    let result = send(migratable, &table)
+        .and_then(|_| stop())
+        .and_then(|_| migratable.dirty_log());
+    migratable.stop_dirty_log()?;
+
+    if let Ok(pending) = &result {
+        progress.dirtied(pending.effective_size(), started.elapsed());
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DeviceConfig, DiskConfig, NetConfig};
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+    use vm_migration::protocol::MemoryRange;
+    use vm_migration::{Pausable, Snapshottable, Transportable};
+    use vm_virtio::queue::testing::VirtQueue;
+    use vm_virtio::{DirtyLog, VIRTQ_DESC_F_WRITE};
+
+    const PAGE_SIZE: u64 = 0x1000;
+    const MEM_PAGES: usize = 256;
+
+    // Mimics the dirty pages bitmap KVM maintains for a memory slot: the
+    // pages written while logging is enabled are flagged, and retrieving
+    // the bitmap through KVM_GET_DIRTY_LOG resets it.
+    struct MockDirtyLog {
+        logging: bool,
+        bitmap: Vec<u64>,
+        // Pages written by the guest before each bitmap retrieval.
+        guest_writes: VecDeque<Vec<usize>>,
+        dirty_log_calls: usize,
+        // Pages written by the devices, added to the bitmap as the memory
+        // manager does.
+        device_log: Arc<DirtyLog>,
+    }
+
+    impl MockDirtyLog {
+        fn new(guest_writes: Vec<Vec<usize>>) -> Self {
+            MockDirtyLog {
+                logging: false,
+                bitmap: vec![0; MEM_PAGES / 64],
+                guest_writes: guest_writes.into(),
+                dirty_log_calls: 0,
+                device_log: Arc::new(DirtyLog::default()),
+            }
+        }
+
+        fn write_page(&mut self, page: usize) {
+            if self.logging {
+                self.bitmap[page / 64] |= 1 << (page % 64);
+            }
+        }
+
+        fn full_table() -> MemoryRangeTable {
+            let mut table = MemoryRangeTable::default();
+            table.push(MemoryRange {
+                gpa: 0,
+                length: MEM_PAGES as u64 * PAGE_SIZE,
+            });
+            table
+        }
+    }
+
+    impl Pausable for MockDirtyLog {}
+    impl Snapshottable for MockDirtyLog {}
+    impl Transportable for MockDirtyLog {}
+    impl Migratable for MockDirtyLog {
+        fn start_dirty_log(&mut self) -> std::result::Result<(), MigratableError> {
+            self.logging = true;
+            self.device_log.start();
+            Ok(())
+        }
+
+        fn stop_dirty_log(&mut self) -> std::result::Result<(), MigratableError> {
+            self.logging = false;
+            self.device_log.stop();
+            Ok(())
+        }
+
+        fn dirty_log(&mut self) -> std::result::Result<MemoryRangeTable, MigratableError> {
+            self.dirty_log_calls += 1;
+            if let Some(pages) = self.guest_writes.pop_front() {
+                for page in pages {
+                    self.write_page(page);
+                }
+            }
+            for page in self.device_log.take() {
+                self.write_page(page as usize);
+            }
+
+            let bitmap = std::mem::replace(&mut self.bitmap, vec![0; MEM_PAGES / 64]);
+            Ok(MemoryRangeTable::from_bitmap(bitmap, 0, PAGE_SIZE))
+        }
+    }
+
+    fn pages(ranges: &[(usize, usize)]) -> MemoryRangeTable {
+        let mut table = MemoryRangeTable::default();
+        for (first, count) in ranges {
+            table.push(MemoryRange {
+                gpa: *first as u64 * PAGE_SIZE,
+                length: *count as u64 * PAGE_SIZE,
+            });
+        }
+        table
+    }
+
+    #[test]
+    fn test_precopy_sends_dirty_pages_only() {
+        let mut mock = MockDirtyLog::new(vec![
+            // Round 1, the guest rewrites a large chunk of its memory.
+            (0..32).collect(),
+            // Round 2, a few scattered pages.
+            vec![4, 5, 100],
+            // Round 3, below the threshold.
+            vec![7],
+            // Pages written before the VM got stopped.
+            vec![9, 200],
+        ]);
+        // Written before the dirty log is started, not to be reported.
+        mock.write_page(42);
+
+        let config = PrecopyConfig {
+            max_iterations: 5,
+            dirty_threshold: 2 * PAGE_SIZE,
+            ..Default::default()
+        };
+        let progress = MigrationProgress::default();
+        let stopped = Cell::new(false);
+        let mut sent = Vec::new();
+        send_memory_precopy(
+            &mut mock,
+            MockDirtyLog::full_table(),
+            &config,
+            &progress,
+            |_, table| {
+                sent.push((table.clone(), stopped.get()));
+                Ok(())
+            },
+            || {
+                stopped.set(true);
+                Ok(())
+            },
+            |_| panic!("Throttled without auto-converge"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            sent,
+            vec![
+                (MockDirtyLog::full_table(), false),
+                (pages(&[(0, 32)]), false),
+                (pages(&[(4, 2), (100, 1)]), false),
+                (pages(&[(7, 1), (9, 1), (200, 1)]), true),
+            ]
+        );
+        assert_eq!(mock.dirty_log_calls, 4);
+        assert!(!mock.logging);
+
+        // Nothing was sent through the progress, the last round being
+        // left to copy.
+        let status = progress.status();
+        assert_eq!(status.iterations, 3);
+        assert_eq!(status.pages_remaining, 3);
+    }
+
+    #[test]
+    fn test_precopy_max_iterations() {
+        // The guest keeps dirtying more than the threshold at every round.
+        let mut mock = MockDirtyLog::new(vec![(0..8).collect(); 5]);
+
+        let config = PrecopyConfig {
+            max_iterations: 3,
+            dirty_threshold: PAGE_SIZE,
+            ..Default::default()
+        };
+        let stopped = Cell::new(false);
+        let mut sent = Vec::new();
+        send_memory_precopy(
+            &mut mock,
+            MockDirtyLog::full_table(),
+            &config,
+            &MigrationProgress::default(),
+            |_, table| {
+                sent.push((table.effective_size(), stopped.get()));
+                Ok(())
+            },
+            || {
+                stopped.set(true);
+                Ok(())
+            },
+            |_| panic!("Throttled without auto-converge"),
+        )
+        .unwrap();
+
+        // Full memory, two rounds while running, then the last round
+        // retrieved along with what got dirtied before the VM stopped.
+        assert_eq!(
+            sent,
+            vec![
+                (MEM_PAGES as u64 * PAGE_SIZE, false),
+                (8 * PAGE_SIZE, false),
+                (8 * PAGE_SIZE, false),
+                (16 * PAGE_SIZE, true),
+            ]
+        );
+        assert_eq!(mock.dirty_log_calls, 4);
+        assert!(!mock.logging);
+    }
+
+    #[test]
+    fn test_precopy_error_stops_dirty_log() {
+        let mut mock = MockDirtyLog::new(Vec::new());
+
+        assert!(send_memory_precopy(
+            &mut mock,
+            MockDirtyLog::full_table(),
+            &PrecopyConfig::default(),
+            &MigrationProgress::default(),
+            |_, _| Err(MigratableError::MigrateSend(anyhow!("broken stream"))),
+            || Ok(()),
+            |_| Ok(()),
+        )
+        .is_err());
+        assert!(!mock.logging);
+    }
+
+    #[test]
+    fn test_precopy_auto_converge() {
+        // The guest keeps dirtying the same amount of memory until it gets
+        // throttled enough.
+        let mut guest_writes: Vec<Vec<usize>> = vec![(0..16).collect(); 4];
+        guest_writes.push((0..15).collect());
+        guest_writes.push(vec![3]);
+        guest_writes.push(vec![5]);
+        let mut mock = MockDirtyLog::new(guest_writes);
+
+        let config = PrecopyConfig {
+            max_iterations: 2,
+            dirty_threshold: PAGE_SIZE,
+            auto_converge: true,
+            max_throttle: 45,
+        };
+        let progress = MigrationProgress::default();
+        let mut throttles = Vec::new();
+        let mut sent = Vec::new();
+        send_memory_precopy(
+            &mut mock,
+            MockDirtyLog::full_table(),
+            &config,
+            &progress,
+            |_, table| {
+                sent.push(table.effective_size());
+                Ok(())
+            },
+            || Ok(()),
+            |percent| {
+                throttles.push(percent);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        // The throttle rises until the cap, past the maximum number of
+        // rounds, a round reducing the dirty set by less than a tenth
+        // raising it too. It goes away once the rounds are over.
+        assert_eq!(throttles, vec![20, 30, 40, 45, 0]);
+        assert_eq!(
+            sent,
+            vec![
+                MEM_PAGES as u64 * PAGE_SIZE,
+                16 * PAGE_SIZE,
+                16 * PAGE_SIZE,
+                16 * PAGE_SIZE,
+                16 * PAGE_SIZE,
+                15 * PAGE_SIZE,
+                2 * PAGE_SIZE,
+            ]
+        );
+        assert_eq!(progress.throttle(), 0);
+        assert!(!mock.logging);
+    }
+
+    #[test]
+    fn test_precopy_error_removes_throttle() {
+        // The guest is throttled by the second round, which fails.
+        let mut mock = MockDirtyLog::new(vec![(0..16).collect(); 2]);
+
+        let config = PrecopyConfig {
+            auto_converge: true,
+            dirty_threshold: PAGE_SIZE,
+            ..Default::default()
+        };
+        let progress = MigrationProgress::default();
+        let mut throttles = Vec::new();
+        let mut rounds = 0;
+        assert!(send_memory_precopy(
+            &mut mock,
+            MockDirtyLog::full_table(),
+            &config,
+            &progress,
+            |_, _| {
+                rounds += 1;
+                if rounds == 3 {
+                    Err(MigratableError::MigrateSend(anyhow!("broken stream")))
+                } else {
+                    Ok(())
+                }
+            },
+            || Ok(()),
+            |percent| {
+                throttles.push(percent);
+                Ok(())
+            },
+        )
+        .is_err());
+        assert_eq!(throttles, vec![20, 0]);
+        assert_eq!(progress.throttle(), 0);
+        assert!(!mock.logging);
+    }
+
+    #[test]
+    fn test_postcopy_returns_pending_pages() {
+        let mut mock = MockDirtyLog::new(vec![
+            // Pages written during the bulk copy, and before the VM got
+            // stopped.
+            vec![3, 4, 5, 120],
+        ]);
+
+        let stopped = Cell::new(false);
+        let mut sent = Vec::new();
+        let pending = send_memory_postcopy(
+            &mut mock,
+            MockDirtyLog::full_table(),
+            &MigrationProgress::default(),
+            |_, table| {
+                sent.push((table.clone(), stopped.get()));
+                Ok(())
+            },
+            || {
+                stopped.set(true);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(sent, vec![(MockDirtyLog::full_table(), false)]);
+        assert!(stopped.get());
+        assert_eq!(pending, pages(&[(3, 3), (120, 1)]));
+        assert!(!mock.logging);
+
+        // The dirty log is stopped on errors too.
+        let mut mock = MockDirtyLog::new(Vec::new());
+        assert!(send_memory_postcopy(
+            &mut mock,
+            MockDirtyLog::full_table(),
+            &MigrationProgress::default(),
+            |_, _| Ok(()),
+            || Err(MigratableError::Pause(anyhow!("cannot pause"))),
+        )
+        .is_err());
+        assert!(!mock.logging);
+    }
+
+    #[test]
+    fn test_unix_url_path() {
+        assert_eq!(
+            unix_url_path("unix:/tmp/migration.sock").unwrap(),
+            PathBuf::from("/tmp/migration.sock")
+        );
+        assert!(unix_url_path("unix:").is_err());
+        assert!(unix_url_path("tcp://localhost:6000").is_err());
+        assert!(unix_url_path("/tmp/migration.sock").is_err());
+    }
+
+    #[test]
+    fn test_postcopy_pending_device_pages() {
+        let mem =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_PAGES * PAGE_SIZE as usize)])
+                .unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        vq.dtable[0].set(0x20 * PAGE_SIZE, 0x10, VIRTQ_DESC_F_WRITE, 0);
+        let mut mock = MockDirtyLog::new(Vec::new());
+        let mut queue = vq.create_queue();
+        queue.dirty_log = Some(mock.device_log.clone());
+
+        // A device hands a buffer back while the memory is being sent, which
+        // the hypervisor doesn't see. The buffer and the used ring, which
+        // shares the first page with the rest of the queue, are left to be
+        // sent once the guest runs on the destination.
+        let pending = send_memory_postcopy(
+            &mut mock,
+            MockDirtyLog::full_table(),
+            &MigrationProgress::default(),
+            |_, _| {
+                queue.add_used(&mem, 0, 0x10);
+                Ok(())
+            },
+            || Ok(()),
+        )
+        .unwrap();
+        assert_eq!(pending, pages(&[(0, 1), (0x20, 1)]));
+
+        // Nothing is logged once the migration is over.
+        queue.add_used(&mem, 0, 0x10);
+        assert!(mock.device_log.take().is_empty());
+    }
+
+    #[test]
+    fn test_check_config() {
+        let mut config: VmConfig = serde_json::from_str("{}").unwrap();
+        assert!(check_config(&config).is_ok());
+
+        config.disks = Some(vec![DiskConfig::parse("path=/tmp/disk.img").unwrap()]);
+        assert!(check_config(&config).is_ok());
+        config.disks = Some(vec![
+            DiskConfig::parse("path=/tmp/disk.img,nvme=on").unwrap()
+        ]);
+        assert!(check_config(&config).is_err());
+        config.disks = None;
+
+        config.net = Some(vec![
+            NetConfig::parse("vhost_user=true,socket=/tmp/sock").unwrap()
+        ]);
+        assert!(check_config(&config).is_err());
+        config.net = None;
+
+        // The VFIO devices must have been removed, which leaves an empty
+        // list behind.
+        config.devices = Some(vec![DeviceConfig::parse(
+            "path=/sys/bus/pci/devices/0000:01:00.0/",
+        )
+        .unwrap()]);
+        assert!(check_config(&config).is_err());
+        config.devices = Some(Vec::new());
+        assert!(check_config(&config).is_ok());
+    }
+
+    #[test]
+    fn test_tcp_url_address() {
+        assert_eq!(
+            tcp_url_address("tcp://192.168.1.2:6000", None).unwrap(),
+            Some(String::from("192.168.1.2:6000"))
+        );
+        assert_eq!(
+            tcp_url_address("tcp://:6000", Some("0.0.0.0")).unwrap(),
+            Some(String::from("0.0.0.0:6000"))
+        );
+        assert_eq!(
+            tcp_url_address("tcp://[::1]:6000", Some("0.0.0.0")).unwrap(),
+            Some(String::from("[::1]:6000"))
+        );
+        assert_eq!(tcp_url_address("file:///tmp/snapshot", None).unwrap(), None);
+
+        assert!(tcp_url_address("tcp://:6000", None).is_err());
+        assert!(tcp_url_address("tcp://localhost", None).is_err());
+        assert!(tcp_url_address("tcp://localhost:port", None).is_err());
+        assert!(tcp_url_address("tcp://localhost:65536", None).is_err());
+    }
+
+    #[test]
+    fn test_vm_snapshot_stream() {
+        let mut snapshot = Snapshot::new(VM_SNAPSHOT_ID);
+        snapshot.add_data_section(vm_migration::SnapshotDataSection {
+            id: format!("{}-section", VM_SNAPSHOT_ID),
+            snapshot: vec![0x5a; 64],
+        });
+
+        let mut stream = Vec::new();
+        send_vm_snapshot(&snapshot, &mut stream).unwrap();
+        // Whatever follows the snapshot in the stream is left untouched.
+        stream.extend_from_slice(b"memory");
+
+        let mut reader = &stream[..];
+        let received = read_vm_snapshot(&mut reader).unwrap();
+        assert_eq!(received.id, snapshot.id);
+        assert_eq!(
+            received.snapshot_data[&format!("{}-section", VM_SNAPSHOT_ID)].snapshot,
+            vec![0x5a; 64]
+        );
+        assert_eq!(reader, b"memory");
+
+        // A truncated stream must be reported.
+        let mut reader = &stream[..16];
+        assert!(read_vm_snapshot(&mut reader).is_err());
+    }
+}
@@ -0,0 +1,432 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Progress of the live migration of the VM, updated by the VMM thread
+//! while migrating it, and reported by the HTTP thread through
+//! `vm.migration-status` without waiting for the migration to be over.
+
+use crate::memory_manager::DIRTY_LOG_PAGE_SIZE;
+use crate::migration_stream::MigrationConnection;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref PROGRESS: Arc<MigrationProgress> = Arc::new(MigrationProgress::default());
+}
+
+/// Progress of the last migration of this VMM, either as the source or as
+/// the destination.
+pub fn migration_progress() -> Arc<MigrationProgress> {
+    PROGRESS.clone()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MigrationPhase {
+    /// No migration was started.
+    None,
+    /// The guest memory is being copied.
+    MemoryCopy,
+    /// The VM is paused, the state of its devices and vCPUs being copied.
+    DeviceState,
+    /// The guest runs on the destination, the pages it dirtied before
+    /// being paused on the source being copied.
+    Postcopy,
+    Completed,
+    Failed,
+    /// The migration was aborted through `vm.migration-abort`.
+    Aborted,
+}
+
+const PHASES: [MigrationPhase; 7] = [
+    MigrationPhase::None,
+    MigrationPhase::MemoryCopy,
+    MigrationPhase::DeviceState,
+    MigrationPhase::Postcopy,
+    MigrationPhase::Completed,
+    MigrationPhase::Failed,
+    MigrationPhase::Aborted,
+];
+
+impl Default for MigrationPhase {
+    fn default() -> Self {
+        MigrationPhase::None
+    }
+}
+
+impl fmt::Display for MigrationPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::MigrationPhase::*;
+
+        match self {
+            None => write!(f, "none"),
+            MemoryCopy => write!(f, "memory-copy"),
+            DeviceState => write!(f, "device-state"),
+            Postcopy => write!(f, "postcopy"),
+            Completed => write!(f, "completed"),
+            Failed => write!(f, "failed"),
+            Aborted => write!(f, "aborted"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AbortError {
+    /// No migration is in progress.
+    NotInProgress,
+    /// The guest may run on the destination already.
+    Postcopy,
+}
+
+impl fmt::Display for AbortError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::AbortError::*;
+
+        match self {
+            NotInProgress => write!(f, "No migration is in progress"),
+            Postcopy => write!(f, "The migration can't be aborted during post-copy"),
+        }
+    }
+}
+
+/// Snapshot of the progress of a migration, as reported by
+/// `vm.migration-status`.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct MigrationStatus {
+    pub phase: MigrationPhase,
+    /// Bytes sent or received through the migration stream.
+    pub bytes_transferred: u64,
+    /// Pages of the guest memory left to copy in the current round.
+    pub pages_remaining: u64,
+    /// Pages the guest dirtied per second during the last round.
+    pub dirty_rate: u64,
+    /// Rounds of dirty pages harvested so far.
+    pub iterations: u64,
+    /// Bytes transferred per second since the migration started.
+    pub throughput: u64,
+    /// Percentage of the time the vCPUs are kept out of the guest for, for
+    /// the pre-copy rounds to converge.
+    pub throttle: u8,
+    /// Milliseconds since the migration started, or that it lasted.
+    pub elapsed_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+struct Timing {
+    started: Option<Instant>,
+    lasted: Option<Duration>,
+}
+
+/// Progress of a migration, updated through atomics for the threads moving
+/// the pages not to contend with the ones reporting it.
+#[derive(Default)]
+pub struct MigrationProgress {
+    phase: AtomicU8,
+    bytes_transferred: AtomicU64,
+    // Bytes transferred when the current round started, and the size of
+    // the memory it copies.
+    round_start: AtomicU64,
+    round_bytes: AtomicU64,
+    dirty_rate: AtomicU64,
+    iterations: AtomicU64,
+    throttle: AtomicU8,
+    aborted: AtomicBool,
+    // Locked while changing the phase, for the migration not to be aborted
+    // once in post-copy.
+    connection: Mutex<Option<MigrationConnection>>,
+    timing: Mutex<Timing>,
+    error: Mutex<Option<String>>,
+}
+
+impl MigrationProgress {
+    /// Forget about the previous migration, and start copying the memory.
+    pub fn start(&self) {
+        self.bytes_transferred.store(0, Ordering::Relaxed);
+        self.round_start.store(0, Ordering::Relaxed);
+        self.round_bytes.store(0, Ordering::Relaxed);
+        self.dirty_rate.store(0, Ordering::Relaxed);
+        self.iterations.store(0, Ordering::Relaxed);
+        self.throttle.store(0, Ordering::Relaxed);
+        self.aborted.store(false, Ordering::SeqCst);
+        *self.timing.lock().unwrap() = Timing {
+            started: Some(Instant::now()),
+            lasted: None,
+        };
+        self.error.lock().unwrap().take();
+        self.set_phase(MigrationPhase::MemoryCopy);
+    }
+
+    pub fn set_phase(&self, phase: MigrationPhase) {
+        let _connection = self.connection.lock().unwrap();
+        let index = PHASES.iter().position(|p| *p == phase).unwrap();
+        self.phase.store(index as u8, Ordering::Relaxed);
+    }
+
+    /// Shut `connection` down when the migration gets aborted, right away
+    /// if it was already.
+    pub fn set_connection(&self, connection: MigrationConnection) {
+        let mut current = self.connection.lock().unwrap();
+        if self.aborted() {
+            connection.shutdown();
+        }
+        *current = Some(connection);
+    }
+
+    /// Abort the migration, its connection being shut down for the reads
+    /// and writes of the thread migrating the VM to fail. This is refused
+    /// once the guest may run on the destination.
+    pub fn abort(&self) -> Result<(), AbortError> {
+        let connection = self.connection.lock().unwrap();
+        match self.phase() {
+            MigrationPhase::MemoryCopy | MigrationPhase::DeviceState => {}
+            MigrationPhase::Postcopy => return Err(AbortError::Postcopy),
+            _ => return Err(AbortError::NotInProgress),
+        }
+
+        self.aborted.store(true, Ordering::SeqCst);
+        if let Some(connection) = connection.as_ref() {
+            connection.shutdown();
+        }
+
+        Ok(())
+    }
+
+    pub fn aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+
+    pub fn phase(&self) -> MigrationPhase {
+        PHASES[self.phase.load(Ordering::Relaxed) as usize]
+    }
+
+    /// Account for `bytes` going through the migration stream.
+    pub fn transferred(&self, bytes: usize) {
+        self.bytes_transferred
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Start copying `bytes` of the guest memory.
+    pub fn start_round(&self, bytes: u64) {
+        self.round_start.store(
+            self.bytes_transferred.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.round_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Account for the guest having dirtied `bytes` of its memory over
+    /// `duration`, as harvested from the dirty log.
+    pub fn dirtied(&self, bytes: u64, duration: Duration) {
+        let pages = bytes / DIRTY_LOG_PAGE_SIZE;
+        self.dirty_rate
+            .store(pages_per_second(pages, duration), Ordering::Relaxed);
+        self.iterations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_throttle(&self, percent: u8) {
+        self.throttle.store(percent, Ordering::Relaxed);
+    }
+
+    pub fn throttle(&self) -> u8 {
+        self.throttle.load(Ordering::Relaxed)
+    }
+
+    pub fn complete(&self) {
+        self.round_bytes.store(0, Ordering::Relaxed);
+        self.stop(MigrationPhase::Completed);
+    }
+
+    /// The migration failed with `error`, or got aborted.
+    pub fn fail(&self, error: String) {
+        if self.aborted() {
+            *self.error.lock().unwrap() = Some("Migration aborted".to_owned());
+            self.stop(MigrationPhase::Aborted);
+        } else {
+            *self.error.lock().unwrap() = Some(error);
+            self.stop(MigrationPhase::Failed);
+        }
+    }
+
+    fn stop(&self, phase: MigrationPhase) {
+        {
+            let mut timing = self.timing.lock().unwrap();
+            timing.lasted = timing.started.map(|started| started.elapsed());
+        }
+        self.set_phase(phase);
+        self.connection.lock().unwrap().take();
+    }
+
+    pub fn status(&self) -> MigrationStatus {
+        let elapsed = {
+            let timing = self.timing.lock().unwrap();
+            timing
+                .lasted
+                .or_else(|| timing.started.map(|started| started.elapsed()))
+                .unwrap_or_default()
+        };
+        let bytes_transferred = self.bytes_transferred.load(Ordering::Relaxed);
+        // The pages being sent uncompressed, the bytes transferred since the
+        // round started tell how much of its memory was already copied.
+        let round_copied = bytes_transferred - self.round_start.load(Ordering::Relaxed);
+        let round_remaining = self
+            .round_bytes
+            .load(Ordering::Relaxed)
+            .saturating_sub(round_copied);
+        let elapsed_ms = elapsed.as_millis() as u64;
+
+        MigrationStatus {
+            phase: self.phase(),
+            bytes_transferred,
+            pages_remaining: (round_remaining + DIRTY_LOG_PAGE_SIZE - 1) / DIRTY_LOG_PAGE_SIZE,
+            dirty_rate: self.dirty_rate.load(Ordering::Relaxed),
+            iterations: self.iterations.load(Ordering::Relaxed),
+            throughput: if elapsed_ms == 0 {
+                0
+            } else {
+                bytes_transferred * 1000 / elapsed_ms
+            },
+            throttle: self.throttle(),
+            elapsed_ms,
+            error: self.error.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Rate the guest dirtied its memory at while it was logged, as reported
+/// by `vm.dirty-rate`.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct DirtyRate {
+    /// Pages dirtied while they were logged.
+    pub dirty_pages: u64,
+    /// Milliseconds the pages were logged for.
+    pub sample_ms: u64,
+    /// Pages dirtied per second.
+    pub dirty_rate: u64,
+}
+
+/// Rate of `pages` dirtied over `duration`, rounded down.
+pub fn pages_per_second(pages: u64, duration: Duration) -> u64 {
+    let nanos = duration.as_nanos();
+    if nanos == 0 {
+        pages
+    } else {
+        (u128::from(pages) * 1_000_000_000 / nanos) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migration_progress() {
+        let progress = MigrationProgress::default();
+        assert_eq!(progress.status(), MigrationStatus::default());
+
+        progress.start();
+        assert_eq!(progress.phase(), MigrationPhase::MemoryCopy);
+
+        // A first round copying 4 pages, half of them sent.
+        progress.start_round(4 * DIRTY_LOG_PAGE_SIZE);
+        progress.transferred(2 * DIRTY_LOG_PAGE_SIZE as usize);
+        let status = progress.status();
+        assert_eq!(status.bytes_transferred, 2 * DIRTY_LOG_PAGE_SIZE);
+        assert_eq!(status.pages_remaining, 2);
+        assert_eq!(status.iterations, 0);
+
+        // The guest dirtied 3 pages over half a second, the next round
+        // copying them.
+        progress.transferred(2 * DIRTY_LOG_PAGE_SIZE as usize);
+        progress.dirtied(3 * DIRTY_LOG_PAGE_SIZE, Duration::from_millis(500));
+        progress.start_round(3 * DIRTY_LOG_PAGE_SIZE);
+        progress.transferred(DIRTY_LOG_PAGE_SIZE as usize / 2);
+        let status = progress.status();
+        assert_eq!(status.pages_remaining, 3);
+        assert_eq!(status.dirty_rate, 6);
+        assert_eq!(status.iterations, 1);
+        assert_eq!(status.throttle, 0);
+
+        // The guest got throttled for the rounds to converge.
+        progress.set_throttle(30);
+        assert_eq!(progress.status().throttle, 30);
+        progress.set_throttle(0);
+
+        progress.set_phase(MigrationPhase::DeviceState);
+        assert_eq!(progress.phase(), MigrationPhase::DeviceState);
+        progress.complete();
+        let status = progress.status();
+        assert_eq!(status.phase, MigrationPhase::Completed);
+        assert_eq!(status.pages_remaining, 0);
+        assert_eq!(status.error, None);
+
+        // The elapsed time stops with the migration.
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(progress.status().elapsed_ms, status.elapsed_ms);
+
+        // Another migration starts from scratch.
+        progress.set_throttle(20);
+        progress.start();
+        assert_eq!(progress.throttle(), 0);
+        progress.fail("Connection reset by peer".to_owned());
+        let status = progress.status();
+        assert_eq!(status.phase, MigrationPhase::Failed);
+        assert_eq!(status.bytes_transferred, 0);
+        assert_eq!(status.error.as_deref(), Some("Connection reset by peer"));
+    }
+
+    #[test]
+    fn test_migration_abort() {
+        let progress = MigrationProgress::default();
+        assert_eq!(progress.abort(), Err(AbortError::NotInProgress));
+
+        // Aborted while copying the memory, the error the migration fails
+        // with being the consequence of the abort.
+        for phase in &[MigrationPhase::MemoryCopy, MigrationPhase::DeviceState] {
+            progress.start();
+            progress.set_phase(*phase);
+            progress.abort().unwrap();
+            assert!(progress.aborted());
+            progress.fail("Broken pipe".to_owned());
+            let status = progress.status();
+            assert_eq!(status.phase, MigrationPhase::Aborted);
+            assert_eq!(status.error.as_deref(), Some("Migration aborted"));
+            assert_eq!(progress.abort(), Err(AbortError::NotInProgress));
+        }
+
+        // The guest may run on the destination already.
+        progress.start();
+        assert!(!progress.aborted());
+        progress.set_phase(MigrationPhase::Postcopy);
+        assert_eq!(progress.abort(), Err(AbortError::Postcopy));
+        progress.fail("Broken pipe".to_owned());
+        assert_eq!(progress.phase(), MigrationPhase::Failed);
+    }
+
+    #[test]
+    fn test_migration_status_serialization() {
+        let status = MigrationStatus {
+            phase: MigrationPhase::MemoryCopy,
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&status).unwrap();
+        assert!(json.contains(&format!("\"phase\":\"{}\"", status.phase)));
+        assert!(!json.contains("error"));
+        assert_eq!(
+            serde_json::from_str::<MigrationStatus>(&json).unwrap(),
+            status
+        );
+    }
+
+    #[test]
+    fn test_pages_per_second() {
+        assert_eq!(pages_per_second(100, Duration::from_secs(2)), 50);
+        assert_eq!(pages_per_second(3, Duration::from_millis(10)), 300);
+        assert_eq!(pages_per_second(7, Duration::from_secs(0)), 7);
+    }
+}
@@ -0,0 +1,569 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Post-copy phase of a live migration.
+//!
+//! Once the memory has been copied while the guest kept running on the
+//! source, the guest is paused and the destination only lacks the pages
+//! it dirtied during that copy. Instead of sending them on the source
+//! while the guest is stopped, which never ends on a guest writing faster
+//! than the network goes, the guest is resumed on the destination right
+//! away, these pages being left missing from its memory.
+//!
+//! The destination registers its guest memory with a userfaultfd, through
+//! which it finds out about the missing pages the guest, or the VMM on its
+//! behalf, faults on. These pages are requested from the source, which
+//! sends them before any other, while the others keep being sent in the
+//! background. Each page is copied in place as it comes, waking up the
+//! threads which faulted on it.
+//!
+//! The guest can't go on without the memory left on the source, so losing
+//! the migration stream is fatal on the destination. Rather than letting
+//! the guest hang, the error is reported and the VMM exits.
+
+use crate::event_monitor;
+use crate::migration_progress::migration_progress;
+use crate::userfaultfd::Userfaultfd;
+use anyhow::anyhow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use vm_migration::protocol::{
+    read_chunk, Compression, MemoryRange, MemoryRangeTable, PostcopyRequest,
+};
+use vm_migration::MigratableError;
+use vmm_sys_util::eventfd::EventFd;
+
+// Number of pages sent at once by the background copy, small enough for the
+// requested pages not to wait long behind them.
+const POSTCOPY_BATCH_PAGES: usize = 256;
+
+/// Pages left to send by the source during the post-copy phase.
+pub struct PostcopyPages {
+    page_size: u64,
+    // Address of each page, sorted.
+    pages: Vec<u64>,
+    sent: Vec<bool>,
+    // Pages requested by the destination, not sent yet.
+    requested: VecDeque<usize>,
+    // Next page of the background copy.
+    next: usize,
+    remaining: usize,
+}
+
+impl PostcopyPages {
+    pub fn new(pending: &MemoryRangeTable, page_size: u64) -> Self {
+        let mut pages: Vec<u64> = pending
+            .regions()
+            .iter()
+            .flat_map(|range| {
+                let first = range.gpa / page_size;
+                let last = (range.gpa + range.length + page_size - 1) / page_size;
+                (first..last).map(move |page| page * page_size)
+            })
+            .collect();
+        pages.sort_unstable();
+        pages.dedup();
+
+        PostcopyPages {
+            page_size,
+            sent: vec![false; pages.len()],
+            remaining: pages.len(),
+            pages,
+            requested: VecDeque::new(),
+            next: 0,
+        }
+    }
+
+    /// Send the pages of `range` before any other.
+    pub fn request(&mut self, range: &MemoryRange) {
+        let first = match self
+            .pages
+            .binary_search(&(range.gpa & !(self.page_size - 1)))
+        {
+            Ok(index) | Err(index) => index,
+        };
+        for index in first..self.pages.len() {
+            if self.pages[index] >= range.gpa + range.length {
+                break;
+            }
+            if !self.sent[index] {
+                self.requested.push_back(index);
+            }
+        }
+    }
+
+    /// Whether all the pages have been sent.
+    pub fn is_empty(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Next pages to send, up to `max_pages` of them, the requested pages
+    /// coming first.
+    pub fn next_table(&mut self, max_pages: usize) -> MemoryRangeTable {
+        let mut batch = Vec::new();
+        while let Some(index) = self.requested.pop_front() {
+            if !self.sent[index] && !batch.contains(&index) {
+                batch.push(index);
+                if batch.len() == max_pages {
+                    break;
+                }
+            }
+        }
+        if batch.is_empty() {
+            while self.next < self.pages.len() && batch.len() < max_pages {
+                if !self.sent[self.next] {
+                    batch.push(self.next);
+                }
+                self.next += 1;
+            }
+        }
+
+        let mut table = MemoryRangeTable::default();
+        let mut range: Option<MemoryRange> = None;
+        for index in batch {
+            self.sent[index] = true;
+            self.remaining -= 1;
+
+            let gpa = self.pages[index];
+            match &mut range {
+                Some(r) if r.gpa + r.length == gpa => r.length += self.page_size,
+                _ => {
+                    if let Some(r) = range.take() {
+                        table.push(r);
+                    }
+                    range = Some(MemoryRange {
+                        gpa,
+                        length: self.page_size,
+                    });
+                }
+            }
+        }
+        if let Some(r) = range {
+            table.push(r);
+        }
+
+        table
+    }
+}
+
+#[derive(Default)]
+struct SourceState {
+    complete: bool,
+    error: Option<MigratableError>,
+}
+
+/// Send the `pending` pages of the guest memory through `send`, as the
+/// destination `requests` them or in the background, until it has them
+/// all. An empty table is sent once they have all been sent.
+pub fn send_postcopy_pages<R, S>(
+    pending: &MemoryRangeTable,
+    page_size: u64,
+    mut requests: R,
+    mut send: S,
+) -> Result<(), MigratableError>
+where
+    R: Read + Send + 'static,
+    S: FnMut(&MemoryRangeTable) -> Result<(), MigratableError>,
+{
+    let pages = Arc::new((
+        Mutex::new((
+            PostcopyPages::new(pending, page_size),
+            SourceState::default(),
+        )),
+        Condvar::new(),
+    ));
+
+    let reader_pages = pages.clone();
+    let reader = thread::Builder::new()
+        .name("postcopy_requests".to_string())
+        .spawn(move || loop {
+            let request = PostcopyRequest::read_from(&mut requests);
+            let (lock, cvar) = &*reader_pages;
+            let (pages, state) = &mut *lock.lock().unwrap();
+            match request {
+                Ok(PostcopyRequest::Pages(range)) => pages.request(&range),
+                Ok(PostcopyRequest::Complete) => state.complete = true,
+                Err(e) => state.error = Some(e),
+            }
+            cvar.notify_one();
+            if state.complete || state.error.is_some() {
+                break;
+            }
+        })
+        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+    let lost = |e: MigratableError| {
+        MigratableError::MigrateSend(anyhow!(
+            "Lost the destination during the post-copy phase: {}",
+            e
+        ))
+    };
+
+    let (lock, cvar) = &*pages;
+    loop {
+        let table = {
+            let (pages, state) = &mut *lock.lock().unwrap();
+            if let Some(e) = state.error.take() {
+                return Err(lost(e));
+            }
+            if pages.is_empty() {
+                break;
+            }
+            pages.next_table(POSTCOPY_BATCH_PAGES)
+        };
+        send(&table).map_err(lost)?;
+    }
+    send(&MemoryRangeTable::default()).map_err(lost)?;
+
+    // The destination confirms it received all the pages.
+    let mut guard = lock.lock().unwrap();
+    while !guard.1.complete && guard.1.error.is_none() {
+        guard = cvar.wait(guard).unwrap();
+    }
+    if let Some(e) = guard.1.error.take() {
+        return Err(lost(e));
+    }
+    drop(guard);
+
+    reader
+        .join()
+        .map_err(|_| MigratableError::MigrateSend(anyhow!("Post-copy requests thread panicked")))
+}
+
+/// Guest memory region registered with the userfaultfd of the destination.
+pub struct PostcopyRegion {
+    pub gpa: u64,
+    pub size: u64,
+    pub host_addr: u64,
+}
+
+struct Destination {
+    uffd: Userfaultfd,
+    regions: Vec<PostcopyRegion>,
+    page_size: u64,
+    // Pages not received yet, and the ones requested among them.
+    missing: Mutex<(HashSet<u64>, HashSet<u64>)>,
+}
+
+impl Destination {
+    fn host_addr(&self, gpa: u64) -> Result<u64, MigratableError> {
+        self.regions
+            .iter()
+            .find(|r| gpa >= r.gpa && gpa < r.gpa + r.size)
+            .map(|r| r.host_addr + gpa - r.gpa)
+            .ok_or_else(|| {
+                MigratableError::MigrateReceive(anyhow!(
+                    "Page {:#x} is not part of the guest memory",
+                    gpa
+                ))
+            })
+    }
+
+    fn gpa(&self, host_addr: u64) -> Option<u64> {
+        self.regions
+            .iter()
+            .find(|r| host_addr >= r.host_addr && host_addr < r.host_addr + r.size)
+            .map(|r| r.gpa + host_addr - r.host_addr)
+    }
+
+    // Request the pages the guest faulted on, until `stop` is signaled.
+    fn handle_faults<W: Write>(
+        &self,
+        writer: &Mutex<W>,
+        stop: &EventFd,
+    ) -> Result<(), MigratableError> {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.uffd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stop.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        loop {
+            // Safe because poll() only writes to the array we own, and we
+            // check the return value.
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if ret < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(MigratableError::MigrateReceive(err.into()));
+            }
+            if fds[1].revents != 0 {
+                return Ok(());
+            }
+
+            while let Some(addr) = self
+                .uffd
+                .read_fault()
+                .map_err(|e| MigratableError::MigrateReceive(e.into()))?
+            {
+                let gpa = match self.gpa(addr) {
+                    Some(gpa) => gpa & !(self.page_size - 1),
+                    None => {
+                        return Err(MigratableError::MigrateReceive(anyhow!(
+                            "Fault at {:#x} outside of the guest memory",
+                            addr
+                        )))
+                    }
+                };
+
+                // The page may have been received since the fault, or be on
+                // its way already.
+                {
+                    let (missing, requested) = &mut *self.missing.lock().unwrap();
+                    if !missing.contains(&gpa) || !requested.insert(gpa) {
+                        continue;
+                    }
+                }
+                PostcopyRequest::Pages(MemoryRange {
+                    gpa,
+                    length: self.page_size,
+                })
+                .write_to(&mut *writer.lock().unwrap())?;
+            }
+        }
+    }
+
+    // Copy the pages in place as they come, until the source sent them all.
+    fn receive_pages<R: Read>(
+        &self,
+        reader: &mut R,
+        compression: Compression,
+    ) -> Result<(), MigratableError> {
+        let mut page = vec![0u8; self.page_size as usize];
+        loop {
+            let table = MemoryRangeTable::read_from(reader)?;
+            if table.is_empty() {
+                break;
+            }
+
+            for range in table.regions() {
+                let mut offset = 0;
+                while offset < range.length {
+                    let gpa = range.gpa + offset;
+                    if compression == Compression::None {
+                        reader
+                            .read_exact(&mut page)
+                            .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+                    } else {
+                        read_chunk(reader, &mut page, compression)?;
+                    }
+                    self.uffd
+                        .copy(self.host_addr(gpa)?, &page)
+                        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+                    self.missing.lock().unwrap().0.remove(&gpa);
+                    offset += self.page_size;
+                }
+            }
+        }
+
+        let missing = self.missing.lock().unwrap().0.len();
+        if missing != 0 {
+            return Err(MigratableError::MigrateReceive(anyhow!(
+                "{} pages were never received",
+                missing
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+// The guest can't run without its memory, there's no way back once it ran
+// on the destination.
+fn postcopy_failed(e: MigratableError) -> ! {
+    error!(
+        "Post-copy migration failed, the guest memory left on the source can't be retrieved: {}",
+        e
+    );
+    let mut properties = HashMap::new();
+    properties.insert("error", e.to_string());
+    event_monitor::event_log("migration", "postcopy-failed", &properties);
+
+    std::process::exit(1);
+}
+
+/// Receive the `pending` pages of the guest memory from `reader`, copying
+/// them in place through `uffd`, with which the guest memory `regions` are
+/// registered. The pages the guest faults on are requested through
+/// `writer`. This returns as soon as the threads doing so are started, for
+/// the guest to be resumed.
+pub fn start_postcopy_destination<R, W>(
+    uffd: Userfaultfd,
+    regions: Vec<PostcopyRegion>,
+    pending: &MemoryRangeTable,
+    page_size: u64,
+    compression: Compression,
+    mut reader: R,
+    writer: W,
+) -> Result<(), MigratableError>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let missing = PostcopyPages::new(pending, page_size)
+        .pages
+        .into_iter()
+        .collect();
+    let destination = Arc::new(Destination {
+        uffd,
+        regions,
+        page_size,
+        missing: Mutex::new((missing, HashSet::new())),
+    });
+    let writer = Arc::new(Mutex::new(writer));
+    let stop =
+        EventFd::new(libc::EFD_NONBLOCK).map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+    let fault_stop = stop
+        .try_clone()
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+
+    let fault_destination = destination.clone();
+    let fault_writer = writer.clone();
+    thread::Builder::new()
+        .name("postcopy_faults".to_string())
+        .spawn(move || {
+            if let Err(e) = fault_destination.handle_faults(&*fault_writer, &fault_stop) {
+                postcopy_failed(e);
+            }
+        })
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+
+    thread::Builder::new()
+        .name("postcopy_pages".to_string())
+        .spawn(move || {
+            let result = destination
+                .receive_pages(&mut reader, compression)
+                .and_then(|_| PostcopyRequest::Complete.write_to(&mut *writer.lock().unwrap()));
+            if let Err(e) = result {
+                postcopy_failed(e);
+            }
+            migration_progress().complete();
+
+            // The userfaultfd is closed once both threads are done, which
+            // unregisters the guest memory.
+            if let Err(e) = stop.write(1) {
+                warn!("Could not stop the post-copy faults thread: {}", e);
+            }
+            info!("Post-copy migration completed");
+        })
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const PAGE_SIZE: u64 = 0x1000;
+
+    fn pages(ranges: &[(u64, u64)]) -> MemoryRangeTable {
+        let mut table = MemoryRangeTable::default();
+        for (first, count) in ranges {
+            table.push(MemoryRange {
+                gpa: first * PAGE_SIZE,
+                length: count * PAGE_SIZE,
+            });
+        }
+        table
+    }
+
+    #[test]
+    fn test_postcopy_pages() {
+        let mut pending = PostcopyPages::new(&pages(&[(0, 4), (10, 2), (2, 1)]), PAGE_SIZE);
+        assert_eq!(pending.remaining, 6);
+
+        // The background copy goes through the pages in order.
+        assert_eq!(pending.next_table(3), pages(&[(0, 3)]));
+
+        // The requested pages come first, the ones already sent or not
+        // pending being ignored.
+        pending.request(&MemoryRange {
+            gpa: 11 * PAGE_SIZE,
+            length: PAGE_SIZE,
+        });
+        pending.request(&MemoryRange {
+            gpa: PAGE_SIZE,
+            length: 9 * PAGE_SIZE,
+        });
+        assert_eq!(pending.next_table(8), pages(&[(11, 1), (3, 1)]));
+
+        assert!(!pending.is_empty());
+        assert_eq!(pending.next_table(8), pages(&[(10, 1)]));
+        assert!(pending.is_empty());
+        assert!(pending.next_table(8).is_empty());
+    }
+
+    fn requests(requests: &[PostcopyRequest]) -> Cursor<Vec<u8>> {
+        let mut stream = Vec::new();
+        for request in requests {
+            request.write_to(&mut stream).unwrap();
+        }
+        Cursor::new(stream)
+    }
+
+    #[test]
+    fn test_send_postcopy_pages() {
+        let pending = pages(&[(0, POSTCOPY_BATCH_PAGES as u64 + 8)]);
+        let request = requests(&[
+            PostcopyRequest::Pages(MemoryRange {
+                gpa: 4 * PAGE_SIZE,
+                length: PAGE_SIZE,
+            }),
+            PostcopyRequest::Complete,
+        ]);
+
+        let mut sent = Vec::new();
+        send_postcopy_pages(&pending, PAGE_SIZE, request, |table| {
+            sent.push(table.clone());
+            Ok(())
+        })
+        .unwrap();
+
+        // Every page is sent once, whether requested or not, before the
+        // empty table.
+        assert!(sent.last().unwrap().is_empty());
+        let mut sent_pages: Vec<u64> = sent
+            .iter()
+            .flat_map(|table| table.regions().to_vec())
+            .flat_map(|r| r.gpa / PAGE_SIZE..(r.gpa + r.length) / PAGE_SIZE)
+            .collect();
+        sent_pages.sort_unstable();
+        assert_eq!(
+            sent_pages,
+            (0..POSTCOPY_BATCH_PAGES as u64 + 8).collect::<Vec<u64>>()
+        );
+    }
+
+    #[test]
+    fn test_send_postcopy_pages_lost_destination() {
+        // The destination goes away without confirming it got every page.
+        let pending = pages(&[(0, 8)]);
+        let request = requests(&[PostcopyRequest::Pages(MemoryRange {
+            gpa: 0,
+            length: PAGE_SIZE,
+        })]);
+        let result = send_postcopy_pages(&pending, PAGE_SIZE, request, |_| Ok(()));
+        assert!(format!("{}", result.unwrap_err()).contains("Lost the destination"));
+
+        // The pages can't be sent anymore.
+        let result = send_postcopy_pages(&pending, PAGE_SIZE, requests(&[]), |_| {
+            Err(MigratableError::MigrateSend(anyhow!("broken stream")))
+        });
+        assert!(result.is_err());
+    }
+}
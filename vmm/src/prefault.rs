@@ -0,0 +1,252 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Prefault of the guest RAM at boot time.
+//!
+//! The guest RAM is mapped without being populated, each page being
+//! allocated by the host the first time the guest touches it. Prefaulting
+//! moves the cost of these faults to the boot of the VM, which makes the
+//! memory access latency of the guest predictable. The regions are split in
+//! chunks, populated by a pool of threads.
+//!
+//! The pages are populated through `MADV_POPULATE_WRITE`, or by writing to
+//! each of them on hosts not supporting it. Regions backed by hugetlbfs get
+//! populated one huge page at a time. A huge page can't be allocated once
+//! the hugetlbfs pool is empty, which surfaces as a `SIGBUS` when writing to
+//! it, so without `MADV_POPULATE_WRITE` the pages of shared regions get
+//! allocated through `fallocate()` first. Private regions would get a copy
+//! of the pages allocated that way on the first write, and are written to
+//! directly.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::{cmp, ptr, thread};
+use vm_memory::{GuestMemoryRegion, GuestRegionMmap};
+
+// From include/uapi/asm-generic/mman-common.h, as of Linux 5.14.
+const MADV_POPULATE_WRITE: libc::c_int = 23;
+
+// From include/uapi/linux/magic.h
+const HUGETLBFS_MAGIC: libc::c_long = 0x9584_58f6;
+
+// Granularity of the work handed to the threads, large enough to keep their
+// synchronization out of the way while leaving some room to balance the
+// load between them.
+const CHUNK_SIZE: usize = 64 << 20;
+
+#[derive(Clone, Copy)]
+struct Chunk {
+    addr: usize,
+    len: usize,
+    page_size: usize,
+    // Backing file and offset of the chunk, for the regions to fallocate.
+    fallocate: Option<(libc::c_int, libc::off_t)>,
+}
+
+fn host_page_size() -> usize {
+    // Safe because sysconf() doesn't access any memory.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+// Page size of the region, and whether it's backed by hugetlbfs.
+fn region_page_size(region: &GuestRegionMmap) -> io::Result<(usize, bool)> {
+    if let Some(file_offset) = region.file_offset() {
+        let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+        // Safe because the buffer is large enough for the kernel to fill it,
+        // and we check the return value.
+        let ret = unsafe { libc::fstatfs(file_offset.file().as_raw_fd(), &mut buf) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if buf.f_type as libc::c_long == HUGETLBFS_MAGIC {
+            return Ok((buf.f_bsize as usize, true));
+        }
+    }
+
+    Ok((host_page_size(), false))
+}
+
+fn chunks(regions: &[Arc<GuestRegionMmap>], shared: bool) -> io::Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    for region in regions {
+        let (page_size, hugetlbfs) = region_page_size(region)?;
+        let chunk_size = cmp::max(CHUNK_SIZE / page_size, 1) * page_size;
+        let fallocate = if hugetlbfs && shared {
+            region
+                .file_offset()
+                .map(|f| (f.file().as_raw_fd(), f.start() as libc::off_t))
+        } else {
+            None
+        };
+
+        let len = region.len() as usize;
+        let mut offset = 0;
+        while offset < len {
+            chunks.push(Chunk {
+                addr: region.as_ptr() as usize + offset,
+                len: cmp::min(chunk_size, len - offset),
+                page_size,
+                fallocate: fallocate.map(|(fd, start)| (fd, start + offset as libc::off_t)),
+            });
+            offset += chunk_size;
+        }
+    }
+
+    Ok(chunks)
+}
+
+fn populate(chunk: &Chunk) -> io::Result<()> {
+    // Safe because the chunk is part of a region mapped for the whole
+    // lifetime of the prefault.
+    let ret = unsafe {
+        libc::madvise(
+            chunk.addr as *mut libc::c_void,
+            chunk.len,
+            MADV_POPULATE_WRITE,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EINVAL) {
+        return Err(err);
+    }
+
+    // The host doesn't know about MADV_POPULATE_WRITE.
+    if let Some((fd, offset)) = chunk.fallocate {
+        // Safe because the file descriptor is owned by the region, and we
+        // check the return value.
+        let ret = unsafe { libc::fallocate(fd, 0, offset, chunk.len as libc::off_t) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    for offset in (0..chunk.len).step_by(chunk.page_size) {
+        let addr = (chunk.addr + offset) as *mut u8;
+        // Safe because the address is part of the chunk. The guest isn't
+        // running yet, so writing back what was read can't race with it.
+        unsafe { ptr::write_volatile(addr, ptr::read_volatile(addr)) };
+    }
+
+    Ok(())
+}
+
+/// Populate the pages of `regions`, mapped as shared if `shared` is set,
+/// using up to `num_threads` threads and reporting the progress as they go.
+pub fn prefault_regions(
+    regions: &[Arc<GuestRegionMmap>],
+    shared: bool,
+    num_threads: usize,
+) -> io::Result<()> {
+    let chunks = chunks(regions, shared)?;
+    let total: usize = chunks.iter().map(|c| c.len).sum();
+    let num_threads = cmp::max(cmp::min(num_threads, chunks.len()), 1);
+    let queue = Arc::new(Mutex::new(chunks));
+    let (sender, receiver) = channel();
+
+    info!(
+        "Prefaulting {} MiB of guest memory using {} threads",
+        total >> 20,
+        num_threads
+    );
+
+    let mut threads = Vec::with_capacity(num_threads);
+    for _ in 0..num_threads {
+        let queue = queue.clone();
+        let sender = sender.clone();
+        threads.push(
+            thread::Builder::new()
+                .name("prefault".to_string())
+                .spawn(move || loop {
+                    let chunk = match queue.lock().unwrap().pop() {
+                        Some(chunk) => chunk,
+                        None => break,
+                    };
+                    let result = populate(&chunk).map(|_| chunk.len);
+                    let failed = result.is_err();
+                    if sender.send(result).is_err() || failed {
+                        break;
+                    }
+                })?,
+        );
+    }
+    drop(sender);
+
+    // Report every tenth of the memory being populated. The remaining work
+    // is dropped on the first error.
+    let mut result = Ok(());
+    let mut done = 0;
+    let mut reported = 0;
+    for r in receiver.iter() {
+        match r {
+            Ok(len) => {
+                done += len;
+                let progress = done * 10 / cmp::max(total, 1);
+                if progress > reported {
+                    reported = progress;
+                    info!("Prefaulted {}% of guest memory", progress * 10);
+                }
+            }
+            Err(e) => {
+                queue.lock().unwrap().clear();
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+    }
+
+    for thread in threads {
+        if thread.join().is_err() {
+            error!("Prefault thread panicked");
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::{GuestAddress, MmapRegion};
+
+    // Number of pages of the region resident in memory.
+    fn resident_pages(region: &GuestRegionMmap) -> usize {
+        let page_size = host_page_size();
+        let mut vec = vec![0u8; region.len() as usize / page_size];
+        // Safe because the vector holds a byte per page of the region.
+        let ret = unsafe {
+            libc::mincore(
+                region.as_ptr() as *mut libc::c_void,
+                region.len() as usize,
+                vec.as_mut_ptr(),
+            )
+        };
+        assert_eq!(ret, 0);
+        vec.iter().filter(|v| *v & 1 != 0).count()
+    }
+
+    #[test]
+    fn test_prefault_regions() {
+        let page_size = host_page_size();
+        let num_pages = 2 * CHUNK_SIZE / page_size + 3;
+        let region = Arc::new(
+            GuestRegionMmap::new(
+                MmapRegion::new(num_pages * page_size).unwrap(),
+                GuestAddress(0),
+            )
+            .unwrap(),
+        );
+        assert_eq!(region_page_size(&region).unwrap(), (page_size, false));
+        assert_eq!(chunks(&[region.clone()], false).unwrap().len(), 3);
+        assert_eq!(resident_pages(&region), 0);
+
+        prefault_regions(&[region.clone()], false, 2).unwrap();
+        assert_eq!(resident_pages(&region), num_pages);
+    }
+}
@@ -40,6 +40,8 @@ macro_rules! or {
 const TCGETS: u64 = 0x5401;
 const TCSETS: u64 = 0x5402;
 const TIOCGWINSZ: u64 = 0x5413;
+const TIOCSPTLCK: u64 = 0x4004_5431;
+const TIOCGPTN: u64 = 0x8004_5430;
 const FIOCLEX: u64 = 0x5451;
 const FIONBIO: u64 = 0x5421;
 
@@ -48,6 +50,8 @@ const TUNSETIFF: u64 = 0x4004_54ca;
 const TUNSETOFFLOAD: u64 = 0x4004_54d0;
 const TUNSETVNETHDRSZ: u64 = 0x4004_54d8;
 const TUNGETFEATURES: u64 = 0x8004_54cf;
+const TUNGETIFF: u64 = 0x8004_54d2;
+const TUNSETSTEERINGEBPF: u64 = 0x8004_54e0;
 
 // See include/uapi/linux/sockios.h in the kernel code.
 const SIOCGIFFLAGS: u64 = 0x8913;
@@ -74,6 +78,30 @@ const VFIO_IOMMU_MAP_DMA: u64 = 0x3b71;
 const VFIO_IOMMU_UNMAP_DMA: u64 = 0x3b72;
 const VFIO_DEVICE_IOEVENTFD: u64 = 0x3b74;
 
+// See include/uapi/linux/vhost.h in the kernel code.
+const VHOST_VSOCK_SET_GUEST_CID: u64 = 0x4008_af60;
+const VHOST_GET_FEATURES: u64 = 0x8008_af00;
+const VHOST_SET_FEATURES: u64 = 0x4008_af00;
+const VHOST_SET_OWNER: u64 = 0xaf01;
+const VHOST_SET_VRING_NUM: u64 = 0x4008_af10;
+const VHOST_SET_VRING_ADDR: u64 = 0x4028_af11;
+const VHOST_SET_VRING_BASE: u64 = 0x4008_af12;
+const VHOST_SET_VRING_KICK: u64 = 0x4008_af20;
+const VHOST_SET_VRING_CALL: u64 = 0x4008_af21;
+const VHOST_SET_BACKEND_FEATURES: u64 = 0x4008_af25;
+const VHOST_GET_BACKEND_FEATURES: u64 = 0x8008_af26;
+const VHOST_VDPA_GET_DEVICE_ID: u64 = 0x8004_af70;
+const VHOST_VDPA_SET_STATUS: u64 = 0x4001_af72;
+const VHOST_VDPA_GET_CONFIG: u64 = 0x8008_af73;
+const VHOST_VDPA_SET_CONFIG: u64 = 0x4008_af74;
+const VHOST_VDPA_SET_VRING_ENABLE: u64 = 0x4008_af75;
+const VHOST_VDPA_GET_VRING_NUM: u64 = 0x8002_af76;
+
+// See include/uapi/linux/userfaultfd.h in the kernel code.
+const UFFDIO_REGISTER: u64 = 0xc020_aa00;
+const UFFDIO_COPY: u64 = 0xc028_aa03;
+const UFFDIO_API: u64 = 0xc018_aa3f;
+
 fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
     // See include/uapi/linux/kvm.h in the kernel code.
     const KVM_GET_API_VERSION: u64 = 0xae00;
@@ -137,10 +165,14 @@ fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, TCSETS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TCGETS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TIOCGWINSZ)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TIOCSPTLCK)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TIOCGPTN)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNGETFEATURES)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TUNGETIFF)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETIFF)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETOFFLOAD)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETVNETHDRSZ)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETSTEERINGEBPF)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_GET_API_VERSION)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_CHECK_EXTENSION)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_SET_IOMMU)?],
@@ -161,6 +193,31 @@ fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_IOMMU_MAP_DMA)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_IOMMU_UNMAP_DMA)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_DEVICE_IOEVENTFD)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_VSOCK_SET_GUEST_CID)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_GET_FEATURES)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_FEATURES)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_OWNER)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_NUM)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_ADDR)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_BASE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_KICK)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_CALL)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_BACKEND_FEATURES)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_GET_BACKEND_FEATURES)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_VDPA_GET_DEVICE_ID)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_VDPA_SET_STATUS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_VDPA_GET_CONFIG)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_VDPA_SET_CONFIG)?],
+        and![Cond::new(
+            1,
+            ArgLen::DWORD,
+            Eq,
+            VHOST_VDPA_SET_VRING_ENABLE
+        )?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_VDPA_GET_VRING_NUM)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_REGISTER)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_COPY)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_API)?],
     ])
 }
 
@@ -179,6 +236,7 @@ fn create_vmm_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
     const KVM_SET_CLOCK: u64 = 0x4030_ae7b;
     const KVM_SET_CPUID2: u64 = 0x4008_ae90;
     const KVM_SET_FPU: u64 = 0x41a0_ae8d;
+    const KVM_SET_GUEST_DEBUG: u64 = 0x4048_ae9b;
     const KVM_SET_LAPIC: u64 = 0x4400_ae8f;
     const KVM_SET_MSRS: u64 = 0x4008_ae89;
     const KVM_SET_SREGS: u64 = 0x4138_ae84;
@@ -201,6 +259,7 @@ fn create_vmm_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_CLOCK)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_CPUID2)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_FPU)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_GUEST_DEBUG)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_LAPIC)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_SREGS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_TSS_ADDR,)?],
@@ -243,6 +302,7 @@ pub fn vmm_thread_filter() -> Result<SeccompFilter, Error> {
             #[cfg(target_arch = "x86_64")]
             allow_syscall(libc::SYS_arch_prctl),
             allow_syscall(libc::SYS_bind),
+            allow_syscall(libc::SYS_bpf),
             allow_syscall(libc::SYS_brk),
             allow_syscall(libc::SYS_clock_gettime),
             allow_syscall(libc::SYS_clock_nanosleep),
@@ -265,6 +325,7 @@ pub fn vmm_thread_filter() -> Result<SeccompFilter, Error> {
             #[cfg(target_arch = "x86_64")]
             allow_syscall(libc::SYS_fork),
             allow_syscall(libc::SYS_fstat),
+            allow_syscall(libc::SYS_fstatfs),
             allow_syscall(libc::SYS_fsync),
             #[cfg(target_arch = "x86_64")]
             allow_syscall(libc::SYS_ftruncate),
@@ -275,6 +336,7 @@ pub fn vmm_thread_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_futex),
             allow_syscall(libc::SYS_getpid),
             allow_syscall(libc::SYS_getrandom),
+            allow_syscall(libc::SYS_getsockopt),
             allow_syscall(libc::SYS_gettid),
             allow_syscall(libc::SYS_gettimeofday),
             allow_syscall(libc::SYS_getuid),
@@ -282,6 +344,7 @@ pub fn vmm_thread_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_listen),
             allow_syscall(libc::SYS_lseek),
             allow_syscall(libc::SYS_madvise),
+            allow_syscall(libc::SYS_mbind),
             allow_syscall(libc::SYS_memfd_create),
             allow_syscall(libc::SYS_mmap),
             allow_syscall(libc::SYS_mprotect),
@@ -292,6 +355,9 @@ pub fn vmm_thread_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_open),
             allow_syscall(libc::SYS_openat),
             allow_syscall(libc::SYS_pipe2),
+            #[cfg(target_arch = "x86_64")]
+            allow_syscall(libc::SYS_poll),
+            allow_syscall(libc::SYS_ppoll),
             allow_syscall(libc::SYS_prctl),
             allow_syscall(libc::SYS_pread64),
             allow_syscall(libc::SYS_prlimit64),
@@ -306,16 +372,20 @@ pub fn vmm_thread_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_rt_sigprocmask),
             allow_syscall(libc::SYS_rt_sigreturn),
             allow_syscall(libc::SYS_sched_getaffinity),
+            allow_syscall(libc::SYS_sched_setaffinity),
             allow_syscall(libc::SYS_sendmsg),
             allow_syscall(libc::SYS_sendto),
             allow_syscall(libc::SYS_set_robust_list),
             allow_syscall(libc::SYS_set_tid_address),
+            allow_syscall(libc::SYS_setsockopt),
+            allow_syscall(libc::SYS_shutdown),
             allow_syscall(libc::SYS_sigaltstack),
             allow_syscall_if(
                 libc::SYS_socket,
                 or![
                     and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_UNIX as u64)?],
                     and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET as u64)?],
+                    and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET6 as u64)?],
                 ],
             ),
             allow_syscall(libc::SYS_socketpair),
@@ -323,6 +393,8 @@ pub fn vmm_thread_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_stat),
             allow_syscall(libc::SYS_statx),
             allow_syscall(libc::SYS_tgkill),
+            allow_syscall(libc::SYS_timerfd_create),
+            allow_syscall(libc::SYS_timerfd_settime),
             allow_syscall(libc::SYS_tkill),
             allow_syscall_if(
                 libc::SYS_umask,
@@ -332,6 +404,7 @@ pub fn vmm_thread_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_unlink),
             #[cfg(target_arch = "aarch64")]
             allow_syscall(libc::SYS_unlinkat),
+            allow_syscall(libc::SYS_userfaultfd),
             allow_syscall(libc::SYS_wait4),
             allow_syscall(libc::SYS_write),
         ]
@@ -348,6 +421,7 @@ pub fn api_thread_filter() -> Result<SeccompFilter, Error> {
         vec![
             allow_syscall(libc::SYS_accept4),
             allow_syscall(libc::SYS_bind),
+            allow_syscall(libc::SYS_clock_gettime),
             allow_syscall(libc::SYS_close),
             allow_syscall(libc::SYS_dup),
             allow_syscall(libc::SYS_epoll_create1),
@@ -363,6 +437,7 @@ pub fn api_thread_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_madvise),
             allow_syscall(libc::SYS_munmap),
             allow_syscall(libc::SYS_recvfrom),
+            allow_syscall(libc::SYS_shutdown),
             allow_syscall(libc::SYS_sigaltstack),
             allow_syscall(libc::SYS_socket),
             allow_syscall(libc::SYS_write),
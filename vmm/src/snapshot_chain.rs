@@ -0,0 +1,1028 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Chains of incremental snapshots.
+//!
+//! The guest memory of an incremental snapshot is split in chunks, and only
+//! the chunks which changed since the previous snapshot of the chain are
+//! written, at their offset in the file of their region. A manifest lists
+//! the checksum of each chunk it holds, along with the directory of the
+//! previous snapshot, the chunks it lacks being found by walking the chain
+//! back to its first snapshot, which holds them all. Each chunk is checked
+//! against its checksum when read back, so that a corrupted snapshot of the
+//! chain fails the restore instead of producing a broken guest.
+//!
+//! The chunks can be compressed, in which case they are written one after
+//! the other, the manifest recording where each of them lies in the file.
+//! The chunks being independent from each other, they are read, compressed
+//! and written by a pool of threads, and read back the same way.
+//!
+//! The snapshots without any manifest are whole uncompressed snapshots,
+//! restored as they always were.
+
+use crate::migration::VM_SNAPSHOT_FILE;
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::{cmp, thread};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use vm_migration::{protocol::MemoryRangeTable, MigratableError};
+
+pub const MEMORY_MANIFEST_FILE: &str = "memory-manifest.json";
+
+/// Granularity of the memory written by an incremental snapshot.
+pub const CHUNK_SIZE: u64 = 256 << 10;
+
+/// Compression of the chunks of a snapshot.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkCompression {
+    Zstd,
+}
+
+/// Chunks of a memory region held by a snapshot.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RegionManifest {
+    /// File holding the chunks, each one at its offset in the region unless
+    /// they are compressed.
+    pub backing_file: PathBuf,
+    pub start_addr: u64,
+    pub size: u64,
+    /// Checksum of each chunk of the region, or `None` for the chunks
+    /// which are found in a previous snapshot of the chain.
+    pub chunks: Vec<Option<u32>>,
+    /// Offset and length of each chunk in the file of a compressed
+    /// snapshot. The chunks which didn't compress are stored as they are,
+    /// their length being the one of the chunk.
+    #[serde(default)]
+    pub extents: Vec<Option<(u64, u64)>>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct MemoryManifest {
+    /// Directory of the previous snapshot of the chain.
+    pub parent: Option<PathBuf>,
+    pub chunk_size: u64,
+    #[serde(default)]
+    pub compression: Option<ChunkCompression>,
+    pub regions: Vec<RegionManifest>,
+}
+
+/// How the chunks of the guest memory are written.
+#[derive(Clone, Copy, Debug)]
+pub struct WriteOptions {
+    /// Level of the zstd compression of the chunks, which are left
+    /// uncompressed if unset.
+    pub compression_level: Option<i32>,
+    /// Number of threads the chunks are written by.
+    pub threads: usize,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            compression_level: None,
+            threads: 1,
+        }
+    }
+}
+
+/// Memory region to be written to a snapshot.
+pub struct RegionToWrite {
+    pub backing_file: PathBuf,
+    pub start_addr: u64,
+    pub size: u64,
+    /// Parts of the region holding data, as (offset, length), the other
+    /// parts being neither read nor written.
+    pub data_ranges: Vec<(u64, u64)>,
+    /// Checksum of each chunk in the previous snapshot of the chain. Every
+    /// chunk is written if unset.
+    pub previous: Option<Vec<Option<u32>>>,
+}
+
+/// Memory region written to a snapshot, along with the checksums of all
+/// its chunks, whether they were written or not.
+pub struct WrittenRegion {
+    pub manifest: RegionManifest,
+    pub checksums: Vec<Option<u32>>,
+}
+
+/// Number of chunks of a region of `size` bytes, the last one being
+/// shorter if the size isn't a multiple of the chunk size.
+pub fn num_chunks(size: u64) -> usize {
+    ((size + CHUNK_SIZE - 1) / CHUNK_SIZE) as usize
+}
+
+pub fn checksum(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+// Run `f` on each of the `jobs`, spread across up to `num_threads` threads.
+// The remaining jobs are dropped on the first error.
+fn run_parallel<J, R, F>(
+    mut jobs: Vec<J>,
+    num_threads: usize,
+    error: fn(anyhow::Error) -> MigratableError,
+    f: F,
+) -> Result<Vec<R>, MigratableError>
+where
+    J: Send + 'static,
+    R: Send + 'static,
+    F: Fn(J) -> Result<R, MigratableError> + Send + Sync + 'static,
+{
+    let num_threads = cmp::max(cmp::min(num_threads, jobs.len()), 1);
+    if num_threads == 1 {
+        return jobs.into_iter().map(f).collect();
+    }
+
+    // The jobs are popped from the end of the queue.
+    jobs.reverse();
+    let num_jobs = jobs.len();
+    let queue = Arc::new(Mutex::new(jobs));
+    let f = Arc::new(f);
+    let (sender, receiver) = channel();
+
+    let mut threads = Vec::with_capacity(num_threads);
+    for _ in 0..num_threads {
+        let queue = queue.clone();
+        let sender = sender.clone();
+        let f = f.clone();
+        threads.push(
+            thread::Builder::new()
+                .name("snapshot".to_string())
+                .spawn(move || loop {
+                    let job = match queue.lock().unwrap().pop() {
+                        Some(job) => job,
+                        None => break,
+                    };
+                    let result = f(job);
+                    let failed = result.is_err();
+                    if sender.send(result).is_err() || failed {
+                        break;
+                    }
+                })
+                .map_err(|e| error(e.into()))?,
+        );
+    }
+    drop(sender);
+
+    let mut results = Ok(Vec::with_capacity(num_jobs));
+    for result in receiver.iter() {
+        match (result, &mut results) {
+            (Ok(r), Ok(results)) => results.push(r),
+            (Err(e), Ok(_)) => {
+                queue.lock().unwrap().clear();
+                results = Err(e);
+            }
+            (_, Err(_)) => {}
+        }
+    }
+
+    for thread in threads {
+        if thread.join().is_err() && results.is_ok() {
+            results = Err(error(anyhow!("Snapshot thread panicked")));
+        }
+    }
+
+    results
+}
+
+// Chunk of the guest memory to be written to a snapshot.
+struct ChunkWrite {
+    region: usize,
+    index: usize,
+    // Offset of the chunk in its region.
+    offset: u64,
+    gpa: u64,
+    len: usize,
+    // Parts of the chunk holding data, as offsets in the chunk.
+    parts: Vec<(usize, usize)>,
+    // Whether the chunk is written whatever its checksum.
+    dirty: bool,
+    previous: Option<u32>,
+}
+
+struct ChunkWritten {
+    region: usize,
+    index: usize,
+    checksum: u32,
+    written: bool,
+    extent: Option<(u64, u64)>,
+}
+
+// Snapshot file of a memory region, the compressed chunks being appended
+// to it.
+struct RegionFile {
+    file: File,
+    end: Mutex<u64>,
+}
+
+fn write_chunk(
+    guest_memory: &GuestMemoryMmap,
+    file: &RegionFile,
+    compression_level: Option<i32>,
+    job: ChunkWrite,
+) -> Result<ChunkWritten, MigratableError> {
+    let mut buf = vec![0u8; job.len];
+    for (start, end) in job.parts.iter() {
+        guest_memory
+            .read_slice(
+                &mut buf[*start..*end],
+                GuestAddress(job.gpa + *start as u64),
+            )
+            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+    }
+    let checksum = checksum(&buf);
+
+    // The pages written by the VMM on behalf of the devices are not part of
+    // the dirty log, and only show up through the checksum of their chunk.
+    let mut written = ChunkWritten {
+        region: job.region,
+        index: job.index,
+        checksum,
+        written: false,
+        extent: None,
+    };
+    if !job.dirty && job.previous == Some(checksum) {
+        return Ok(written);
+    }
+
+    match compression_level {
+        Some(level) => {
+            let compressed = zstd::block::compress(&buf, level)
+                .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+            let data = if compressed.len() < buf.len() {
+                &compressed
+            } else {
+                &buf
+            };
+            let offset = {
+                let mut end = file.end.lock().unwrap();
+                let offset = *end;
+                *end += data.len() as u64;
+                offset
+            };
+            file.file
+                .write_all_at(data, offset)
+                .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+            written.extent = Some((offset, data.len() as u64));
+        }
+        None => {
+            for (start, end) in job.parts.iter() {
+                file.file
+                    .write_all_at(&buf[*start..*end], job.offset + *start as u64)
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+            }
+        }
+    }
+    written.written = true;
+
+    Ok(written)
+}
+
+/// Write the chunks of the memory `regions` to the snapshot directory
+/// `dir`, skipping the ones which are neither part of the `dirty` pages nor
+/// changed since the previous snapshot of the chain.
+pub fn write_regions(
+    dir: &Path,
+    guest_memory: &GuestMemoryMmap,
+    regions: Vec<RegionToWrite>,
+    dirty: Option<&MemoryRangeTable>,
+    options: &WriteOptions,
+) -> Result<Vec<WrittenRegion>, MigratableError> {
+    let mut files = Vec::with_capacity(regions.len());
+    let mut jobs = Vec::new();
+    for (r, region) in regions.iter().enumerate() {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dir.join(&region.backing_file))
+            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        if options.compression_level.is_none() {
+            file.set_len(region.size)
+                .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        }
+        files.push(RegionFile {
+            file,
+            end: Mutex::new(0),
+        });
+
+        let start = region.start_addr;
+        let end = start + region.size;
+        let mut dirty_chunks = vec![region.previous.is_none(); num_chunks(region.size)];
+        for range in dirty.iter().flat_map(|dirty| dirty.regions()) {
+            let range_start = cmp::max(range.gpa, start);
+            let range_end = cmp::min(range.gpa + range.length, end);
+            if range_start < range_end {
+                let first = ((range_start - start) / CHUNK_SIZE) as usize;
+                let last = ((range_end - 1 - start) / CHUNK_SIZE) as usize;
+                for chunk in dirty_chunks[first..=last].iter_mut() {
+                    *chunk = true;
+                }
+            }
+        }
+
+        let ranges = &region.data_ranges;
+        let mut next_range = 0;
+        for (index, dirty) in dirty_chunks.into_iter().enumerate() {
+            let offset = index as u64 * CHUNK_SIZE;
+            let len = cmp::min(CHUNK_SIZE, region.size - offset);
+            while next_range < ranges.len() && ranges[next_range].0 + ranges[next_range].1 <= offset
+            {
+                next_range += 1;
+            }
+            let parts = ranges[next_range..]
+                .iter()
+                .take_while(|(data, _)| *data < offset + len)
+                .map(|(data, data_len)| {
+                    (
+                        (cmp::max(*data, offset) - offset) as usize,
+                        (cmp::min(data + data_len, offset + len) - offset) as usize,
+                    )
+                })
+                .collect();
+
+            jobs.push(ChunkWrite {
+                region: r,
+                index,
+                offset,
+                gpa: start + offset,
+                len: len as usize,
+                parts,
+                dirty,
+                previous: region
+                    .previous
+                    .as_ref()
+                    .and_then(|previous| previous.get(index).copied().flatten()),
+            });
+        }
+    }
+
+    let files = Arc::new(files);
+    let guest_memory = guest_memory.clone();
+    let compression_level = options.compression_level;
+    let chunks = run_parallel(
+        jobs,
+        options.threads,
+        MigratableError::MigrateSend,
+        move |job| write_chunk(&guest_memory, &files[job.region], compression_level, job),
+    )?;
+
+    let mut written: Vec<WrittenRegion> = regions
+        .into_iter()
+        .map(|region| {
+            let num_chunks = num_chunks(region.size);
+            WrittenRegion {
+                manifest: RegionManifest {
+                    backing_file: region.backing_file,
+                    start_addr: region.start_addr,
+                    size: region.size,
+                    chunks: vec![None; num_chunks],
+                    extents: match compression_level {
+                        Some(_) => vec![None; num_chunks],
+                        None => Vec::new(),
+                    },
+                },
+                checksums: vec![None; num_chunks],
+            }
+        })
+        .collect();
+    for chunk in chunks {
+        let region = &mut written[chunk.region];
+        region.checksums[chunk.index] = Some(chunk.checksum);
+        if chunk.written {
+            region.manifest.chunks[chunk.index] = Some(chunk.checksum);
+        }
+        if let Some(extent) = chunk.extent {
+            region.manifest.extents[chunk.index] = Some(extent);
+        }
+    }
+
+    Ok(written)
+}
+
+/// Manifest of the snapshot found in `dir`, or `None` if it isn't part of
+/// an incremental chain.
+pub fn read_manifest(dir: &Path) -> Result<Option<MemoryManifest>, MigratableError> {
+    let file = match File::open(dir.join(MEMORY_MANIFEST_FILE)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(MigratableError::MigrateReceive(e.into())),
+    };
+
+    serde_json::from_reader(io::BufReader::new(file))
+        .map(Some)
+        .map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!(
+                "Could not parse the memory manifest of {}: {}",
+                dir.display(),
+                e
+            ))
+        })
+}
+
+pub fn write_manifest(dir: &Path, manifest: &MemoryManifest) -> Result<(), MigratableError> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dir.join(MEMORY_MANIFEST_FILE))
+        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+    serde_json::to_writer(io::BufWriter::new(file), manifest)
+        .map_err(|e| MigratableError::MigrateSend(e.into()))
+}
+
+/// Snapshots of the chain ending with the one found in `dir`, along with
+/// their manifest, the most recent first.
+pub fn read_chain(dir: &Path) -> Result<Vec<(PathBuf, MemoryManifest)>, MigratableError> {
+    let mut chain: Vec<(PathBuf, MemoryManifest)> = Vec::new();
+    let mut next = Some(dir.to_path_buf());
+    while let Some(dir) = next {
+        let manifest = read_manifest(&dir)?.ok_or_else(|| {
+            MigratableError::MigrateReceive(anyhow!(
+                "{} is not part of an incremental snapshot chain",
+                dir.display()
+            ))
+        })?;
+        if manifest.chunk_size != CHUNK_SIZE {
+            return Err(MigratableError::MigrateReceive(anyhow!(
+                "Unsupported chunk size {} in the memory manifest of {}",
+                manifest.chunk_size,
+                dir.display()
+            )));
+        }
+        if chain.iter().any(|(d, _)| *d == dir) {
+            return Err(MigratableError::MigrateReceive(anyhow!(
+                "The snapshot chain loops back to {}",
+                dir.display()
+            )));
+        }
+
+        next = manifest.parent.clone();
+        chain.push((dir, manifest));
+    }
+
+    Ok(chain)
+}
+
+// Files of the snapshots of a chain, each one being opened once.
+#[derive(Default)]
+struct ChainFiles {
+    files: Vec<File>,
+    paths: Vec<PathBuf>,
+    indexes: HashMap<PathBuf, usize>,
+}
+
+impl ChainFiles {
+    fn open(&mut self, path: PathBuf) -> Result<usize, MigratableError> {
+        if let Some(index) = self.indexes.get(&path) {
+            return Ok(*index);
+        }
+
+        let file = File::open(&path).map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+        self.files.push(file);
+        self.paths.push(path.clone());
+        self.indexes.insert(path, self.files.len() - 1);
+
+        Ok(self.files.len() - 1)
+    }
+}
+
+// Chunk of the guest memory to be read from a snapshot of the chain.
+struct ChunkRead {
+    file: usize,
+    index: usize,
+    // Offset of the chunk in its region.
+    offset: u64,
+    gpa: u64,
+    len: usize,
+    // Where the chunk lies in a compressed snapshot.
+    extent: Option<(u64, u64)>,
+    checksum: u32,
+}
+
+// Find the latest version of each chunk of the memory region at
+// `start_addr` through the `chain`.
+fn plan_region(
+    chain: &[(PathBuf, MemoryManifest)],
+    start_addr: u64,
+    size: u64,
+    files: &mut ChainFiles,
+) -> Result<Vec<ChunkRead>, MigratableError> {
+    // The region as described by each snapshot, the ones it wasn't part of,
+    // or with another size, having nothing to offer.
+    let regions: Vec<Option<&RegionManifest>> = chain
+        .iter()
+        .map(|(_, manifest)| {
+            manifest
+                .regions
+                .iter()
+                .find(|r| r.start_addr == start_addr && r.size == size)
+        })
+        .collect();
+
+    let mut chunks = Vec::with_capacity(num_chunks(size));
+    for index in 0..num_chunks(size) {
+        let (snapshot, checksum) = regions
+            .iter()
+            .enumerate()
+            .find_map(|(i, region)| {
+                region.and_then(|r| r.chunks.get(index).copied().flatten().map(|c| (i, c)))
+            })
+            .ok_or_else(|| {
+                MigratableError::MigrateReceive(anyhow!(
+                    "Chunk {} of the memory region at {:#x} is missing from the snapshot chain",
+                    index,
+                    start_addr
+                ))
+            })?;
+        let (dir, manifest) = &chain[snapshot];
+        let region = regions[snapshot].unwrap();
+        let path = dir.join(&region.backing_file);
+        let extent = match manifest.compression {
+            Some(_) => Some(
+                region
+                    .extents
+                    .get(index)
+                    .copied()
+                    .flatten()
+                    .ok_or_else(|| {
+                        MigratableError::MigrateReceive(anyhow!(
+                            "Chunk {} of {} is missing from its manifest",
+                            index,
+                            path.display()
+                        ))
+                    })?,
+            ),
+            None => None,
+        };
+
+        let offset = index as u64 * CHUNK_SIZE;
+        chunks.push(ChunkRead {
+            file: files.open(path)?,
+            index,
+            offset,
+            gpa: start_addr + offset,
+            len: cmp::min(CHUNK_SIZE, size - offset) as usize,
+            extent,
+            checksum,
+        });
+    }
+
+    Ok(chunks)
+}
+
+fn read_chunk(files: &ChainFiles, chunk: &ChunkRead) -> Result<Vec<u8>, MigratableError> {
+    let corrupted = |e: Option<io::Error>| {
+        let path = files.paths[chunk.file].display();
+        MigratableError::MigrateReceive(match e {
+            Some(e) => anyhow!("Chunk {} of {} is corrupted: {}", chunk.index, path, e),
+            None => anyhow!("Chunk {} of {} is corrupted", chunk.index, path),
+        })
+    };
+
+    let (offset, len) = chunk.extent.unwrap_or((chunk.offset, chunk.len as u64));
+    let mut data = vec![0u8; len as usize];
+    files.files[chunk.file]
+        .read_exact_at(&mut data, offset)
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+    if data.len() < chunk.len {
+        data = zstd::block::decompress(&data, chunk.len).map_err(|e| corrupted(Some(e)))?;
+    }
+    if data.len() != chunk.len || checksum(&data) != chunk.checksum {
+        return Err(corrupted(None));
+    }
+
+    Ok(data)
+}
+
+/// Read the latest content of the memory region at `start_addr` through
+/// the `chain`, one chunk at a time, each one being checked against its
+/// checksum before `f` is called with its offset in the region.
+pub fn read_region<F>(
+    chain: &[(PathBuf, MemoryManifest)],
+    start_addr: u64,
+    size: u64,
+    mut f: F,
+) -> Result<(), MigratableError>
+where
+    F: FnMut(u64, &[u8]) -> Result<(), MigratableError>,
+{
+    let mut files = ChainFiles::default();
+    for chunk in plan_region(chain, start_addr, size, &mut files)? {
+        let data = read_chunk(&files, &chunk)?;
+        f(chunk.offset, &data)?;
+    }
+
+    Ok(())
+}
+
+/// Fill the memory `regions`, as (start address, size), with their latest
+/// content found through the `chain`, using up to `num_threads` threads.
+/// The regions must be zeroed already.
+pub fn restore_regions(
+    chain: &[(PathBuf, MemoryManifest)],
+    guest_memory: &GuestMemoryMmap,
+    regions: &[(u64, u64)],
+    num_threads: usize,
+) -> Result<(), MigratableError> {
+    let mut files = ChainFiles::default();
+    let mut chunks = Vec::new();
+    for (start_addr, size) in regions {
+        chunks.extend(plan_region(chain, *start_addr, *size, &mut files)?);
+    }
+
+    let files = Arc::new(files);
+    let guest_memory = guest_memory.clone();
+    run_parallel(
+        chunks,
+        num_threads,
+        MigratableError::MigrateReceive,
+        move |chunk| {
+            let data = read_chunk(&files, &chunk)?;
+            // The chunks holding nothing but zeroes are left untouched.
+            if data.iter().any(|b| *b != 0) {
+                guest_memory
+                    .write_slice(&data, GuestAddress(chunk.gpa))
+                    .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+            }
+            Ok(())
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Merge the chain ending with the snapshot found in `source` into a whole
+/// snapshot written to `destination`, which doesn't depend on any other.
+pub fn consolidate(source: &Path, destination: &Path) -> Result<(), MigratableError> {
+    if !destination.is_dir() {
+        return Err(MigratableError::MigrateSend(anyhow!(
+            "Destination is not a directory"
+        )));
+    }
+    let chain = read_chain(source)?;
+
+    // The state of the VM is the one of the last snapshot.
+    let mut vm_snapshot_file = File::open(source.join(VM_SNAPSHOT_FILE))
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+    let mut consolidated_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(destination.join(VM_SNAPSHOT_FILE))
+        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+    io::copy(&mut vm_snapshot_file, &mut consolidated_file)
+        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+    let mut regions = Vec::new();
+    for region in chain[0].1.regions.iter() {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(destination.join(&region.backing_file))
+            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        file.set_len(region.size)
+            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+        // The chunks holding nothing but zeroes are left as holes.
+        let mut chunks = Vec::with_capacity(region.chunks.len());
+        read_region(&chain, region.start_addr, region.size, |offset, data| {
+            if data.iter().any(|b| *b != 0) {
+                file.write_all_at(data, offset)
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+            }
+            chunks.push(Some(checksum(data)));
+            Ok(())
+        })?;
+
+        regions.push(RegionManifest {
+            chunks,
+            extents: Vec::new(),
+            ..region.clone()
+        });
+    }
+
+    write_manifest(
+        destination,
+        &MemoryManifest {
+            parent: None,
+            chunk_size: CHUNK_SIZE,
+            compression: None,
+            regions,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const REGION_SIZE: u64 = 3 * CHUNK_SIZE;
+
+    // Write a snapshot of the region at 0 holding `chunks`, each chunk being
+    // filled with the byte given along with its index.
+    fn write_snapshot(parent: Option<&Path>, chunks: &[(usize, u8)]) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        let file = File::create(dir.path().join("memory-region-0")).unwrap();
+        file.set_len(REGION_SIZE).unwrap();
+
+        let mut region_chunks = vec![None; num_chunks(REGION_SIZE)];
+        for (index, byte) in chunks {
+            let data = vec![*byte; CHUNK_SIZE as usize];
+            file.write_all_at(&data, *index as u64 * CHUNK_SIZE)
+                .unwrap();
+            region_chunks[*index] = Some(checksum(&data));
+        }
+        File::create(dir.path().join(VM_SNAPSHOT_FILE)).unwrap();
+
+        write_manifest(
+            dir.path(),
+            &MemoryManifest {
+                parent: parent.map(|p| p.to_path_buf()),
+                chunk_size: CHUNK_SIZE,
+                compression: None,
+                regions: vec![RegionManifest {
+                    backing_file: PathBuf::from("memory-region-0"),
+                    start_addr: 0,
+                    size: REGION_SIZE,
+                    chunks: region_chunks,
+                    extents: Vec::new(),
+                }],
+            },
+        )
+        .unwrap();
+
+        dir
+    }
+
+    // First byte of each chunk of the region, as read through the chain.
+    fn read_bytes(dir: &Path) -> Result<Vec<u8>, MigratableError> {
+        let chain = read_chain(dir)?;
+        let mut bytes = Vec::new();
+        read_region(&chain, 0, REGION_SIZE, |_, data| {
+            bytes.push(data[0]);
+            Ok(())
+        })?;
+
+        Ok(bytes)
+    }
+
+    #[test]
+    fn test_snapshot_chain() {
+        let base = write_snapshot(None, &[(0, 1), (1, 1), (2, 1)]);
+        let first = write_snapshot(Some(base.path()), &[(1, 2)]);
+        let second = write_snapshot(Some(first.path()), &[(0, 3), (1, 3)]);
+
+        assert_eq!(read_chain(second.path()).unwrap().len(), 3);
+        assert_eq!(read_bytes(base.path()).unwrap(), vec![1, 1, 1]);
+        assert_eq!(read_bytes(first.path()).unwrap(), vec![1, 2, 1]);
+        assert_eq!(read_bytes(second.path()).unwrap(), vec![3, 3, 1]);
+
+        let consolidated = TempDir::new().unwrap();
+        consolidate(second.path(), consolidated.path()).unwrap();
+        let manifest = read_manifest(consolidated.path()).unwrap().unwrap();
+        assert!(manifest.parent.is_none());
+        assert!(manifest.regions[0].chunks.iter().all(|c| c.is_some()));
+        assert_eq!(read_bytes(consolidated.path()).unwrap(), vec![3, 3, 1]);
+    }
+
+    #[test]
+    fn test_snapshot_chain_corrupted() {
+        let base = write_snapshot(None, &[(0, 1), (1, 1), (2, 1)]);
+        let first = write_snapshot(Some(base.path()), &[(1, 2)]);
+
+        // A corrupted chunk of the base snapshot is only read if the first
+        // snapshot doesn't override it.
+        let file = OpenOptions::new()
+            .write(true)
+            .open(base.path().join("memory-region-0"))
+            .unwrap();
+        file.write_all_at(&[4], CHUNK_SIZE + 10).unwrap();
+        assert_eq!(read_bytes(first.path()).unwrap(), vec![1, 2, 1]);
+        file.write_all_at(&[4], 2 * CHUNK_SIZE + 10).unwrap();
+        assert!(read_bytes(first.path()).is_err());
+
+        let consolidated = TempDir::new().unwrap();
+        assert!(consolidate(first.path(), consolidated.path()).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_chain_missing_chunk() {
+        // The base of a chain must hold every chunk.
+        let base = write_snapshot(None, &[(0, 1), (2, 1)]);
+        assert!(read_bytes(base.path()).is_err());
+
+        // A whole snapshot can't be part of a chain.
+        let whole = TempDir::new().unwrap();
+        let first = write_snapshot(Some(whole.path()), &[(0, 1), (1, 1), (2, 1)]);
+        assert!(read_chain(first.path()).is_err());
+    }
+
+    // Memory of `size` bytes at 0, the chunk `i` being filled by `fill(i)`.
+    fn guest_memory(size: u64, fill: impl Fn(usize, &mut [u8])) -> GuestMemoryMmap {
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), size as usize)]).unwrap();
+        for index in 0..num_chunks(size) {
+            let offset = index as u64 * CHUNK_SIZE;
+            let mut data = vec![0u8; cmp::min(CHUNK_SIZE, size - offset) as usize];
+            fill(index, &mut data);
+            guest_memory
+                .write_slice(&data, GuestAddress(offset))
+                .unwrap();
+        }
+
+        guest_memory
+    }
+
+    // Bytes which don't compress.
+    fn fill_random(seed: usize, data: &mut [u8]) {
+        let mut x = seed as u64 * 0x9e37_79b9_7f4a_7c15 + 1;
+        for b in data.iter_mut() {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            *b = x as u8;
+        }
+    }
+
+    fn write_memory(
+        parent: Option<&Path>,
+        guest_memory: &GuestMemoryMmap,
+        size: u64,
+        previous: Option<Vec<Option<u32>>>,
+        options: &WriteOptions,
+    ) -> (TempDir, Vec<WrittenRegion>) {
+        let dir = TempDir::new().unwrap();
+        let written = write_regions(
+            dir.path(),
+            guest_memory,
+            vec![RegionToWrite {
+                backing_file: PathBuf::from("memory-region-0"),
+                start_addr: 0,
+                size,
+                data_ranges: vec![(0, size)],
+                previous,
+            }],
+            None,
+            options,
+        )
+        .unwrap();
+        write_manifest(
+            dir.path(),
+            &MemoryManifest {
+                parent: parent.map(|p| p.to_path_buf()),
+                chunk_size: CHUNK_SIZE,
+                compression: options.compression_level.map(|_| ChunkCompression::Zstd),
+                regions: written.iter().map(|r| r.manifest.clone()).collect(),
+            },
+        )
+        .unwrap();
+
+        (dir, written)
+    }
+
+    fn restore_memory(
+        dir: &Path,
+        size: u64,
+        num_threads: usize,
+    ) -> Result<Vec<u8>, MigratableError> {
+        let chain = read_chain(dir)?;
+        let guest_memory = guest_memory(size, |_, _| {});
+        restore_regions(&chain, &guest_memory, &[(0, size)], num_threads)?;
+        let mut data = vec![0u8; size as usize];
+        guest_memory.read_slice(&mut data, GuestAddress(0)).unwrap();
+
+        Ok(data)
+    }
+
+    #[test]
+    fn test_snapshot_compressed() {
+        // The last chunk is shorter than the others.
+        let size = 3 * CHUNK_SIZE + 0x1000;
+        let options = WriteOptions {
+            compression_level: Some(3),
+            threads: 4,
+        };
+        let source = guest_memory(size, |index, data| match index {
+            0 => fill_random(0, data),
+            1 => {
+                for b in data.iter_mut() {
+                    *b = 1;
+                }
+            }
+            _ => {}
+        });
+        let (base, written) = write_memory(None, &source, size, None, &options);
+
+        let extents = &written[0].manifest.extents;
+        assert_eq!(extents[0].unwrap().1, CHUNK_SIZE);
+        assert!(extents[1].unwrap().1 < CHUNK_SIZE);
+        let expected = restore_memory(base.path(), size, 1).unwrap();
+        assert_eq!(restore_memory(base.path(), size, 4).unwrap(), expected);
+        let mut data = vec![0u8; size as usize];
+        source.read_slice(&mut data, GuestAddress(0)).unwrap();
+        assert_eq!(expected, data);
+
+        // The next snapshot of the chain only holds the chunk which changed.
+        source
+            .write_slice(&[2u8; 0x1000], GuestAddress(3 * CHUNK_SIZE))
+            .unwrap();
+        let (next, next_written) = write_memory(
+            Some(base.path()),
+            &source,
+            size,
+            Some(written[0].checksums.clone()),
+            &options,
+        );
+        let chunks = &next_written[0].manifest.chunks;
+        assert_eq!(chunks.iter().filter(|c| c.is_some()).count(), 1);
+        assert!(chunks[3].is_some());
+        source.read_slice(&mut data, GuestAddress(0)).unwrap();
+        assert_eq!(restore_memory(next.path(), size, 4).unwrap(), data);
+
+        // Uncompressed snapshots are written at the offset of each chunk.
+        let (raw, raw_written) = write_memory(None, &source, size, None, &WriteOptions::default());
+        assert!(raw_written[0].manifest.extents.is_empty());
+        assert_eq!(
+            std::fs::read(raw.path().join("memory-region-0")).unwrap(),
+            data
+        );
+        assert_eq!(restore_memory(raw.path(), size, 4).unwrap(), data);
+    }
+
+    #[test]
+    fn test_snapshot_compressed_corrupted() {
+        let size = 2 * CHUNK_SIZE;
+        let options = WriteOptions {
+            compression_level: Some(3),
+            threads: 2,
+        };
+        let source = guest_memory(size, |index, data| {
+            for (i, b) in data.iter_mut().enumerate() {
+                *b = (i / 512 + index) as u8;
+            }
+        });
+        let (dir, written) = write_memory(None, &source, size, None, &options);
+
+        let (offset, len) = written[0].manifest.extents[1].unwrap();
+        assert!(len < CHUNK_SIZE);
+        let file = OpenOptions::new()
+            .write(true)
+            .open(dir.path().join("memory-region-0"))
+            .unwrap();
+        file.write_all_at(&[0xff; 16], offset + len / 2).unwrap();
+        assert!(restore_memory(dir.path(), size, 2).is_err());
+    }
+
+    // Time taken to write a compressed snapshot of 256 MiB, one chunk out
+    // of four being dirtied with bytes which don't compress, the others
+    // holding a repeating pattern, with one thread and then with four.
+    //
+    // cargo test -p vmm --release -- --ignored bench_snapshot_threads --nocapture
+    #[test]
+    #[ignore]
+    fn bench_snapshot_threads() {
+        let size = 256 << 20;
+        let source = guest_memory(size, |index, data| {
+            if index % 4 == 0 {
+                fill_random(index, data);
+            } else {
+                for (i, b) in data.iter_mut().enumerate() {
+                    *b = (i % 61) as u8;
+                }
+            }
+        });
+
+        let mut elapsed = Vec::new();
+        for threads in [1, 4].iter() {
+            let options = WriteOptions {
+                compression_level: Some(3),
+                threads: *threads,
+            };
+            let start = std::time::Instant::now();
+            let (dir, _) = write_memory(None, &source, size, None, &options);
+            elapsed.push(start.elapsed());
+            println!(
+                "{} thread(s): {:?}, {} bytes",
+                threads,
+                elapsed.last().unwrap(),
+                std::fs::metadata(dir.path().join("memory-region-0"))
+                    .unwrap()
+                    .len()
+            );
+        }
+
+        // Safe because sysconf() doesn't access any memory.
+        if unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } >= 4 {
+            assert!(elapsed[1] < elapsed[0]);
+        }
+    }
+}
@@ -0,0 +1,564 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Programming of the SR-IOV virtual functions passed through to the guest.
+//!
+//! The MAC address and VLAN of a VF can only be set through the network
+//! interface of its physical function, found from sysfs, which is done with
+//! rtnetlink requests. The settings the VF had beforehand are restored once
+//! it gets released. The link state of the VF is set the same way, for the
+//! guest to switch over to a virtio-net standby device.
+
+use net_util::{MacAddr, MAC_ADDR_LEN};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+// From linux/netlink.h and linux/rtnetlink.h.
+const NLMSG_HDR_LEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const NLA_HDR_LEN: usize = 4;
+const NLA_TYPE_MASK: u16 = !(1 << 15 | 1 << 14);
+const RTM_NEWLINK: u16 = 16;
+const RTM_GETLINK: u16 = 18;
+const RTM_SETLINK: u16 = 19;
+const RTEXT_FILTER_VF: u32 = 1;
+const IFINFOMSG_LEN: usize = 16;
+
+// From linux/if_link.h.
+const IFLA_VFINFO_LIST: u16 = 22;
+const IFLA_EXT_MASK: u16 = 29;
+const IFLA_VF_INFO: u16 = 1;
+const IFLA_VF_MAC: u16 = 1;
+const IFLA_VF_VLAN: u16 = 2;
+const IFLA_VF_LINK_STATE: u16 = 5;
+const IFLA_VF_LINK_STATE_AUTO: u32 = 0;
+const IFLA_VF_LINK_STATE_DISABLE: u32 = 2;
+// Size of the address in struct ifla_vf_mac.
+const IFLA_VF_MAC_ADDR_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The device isn't an SR-IOV virtual function.
+    NotVirtualFunction(PathBuf),
+    /// No network interface was found for the physical function.
+    NoPhysicalFunctionInterface(PathBuf),
+    /// Failed to read the sysfs description of the device.
+    Sysfs(PathBuf, io::Error),
+    /// Failed to communicate with the kernel through netlink.
+    Netlink(io::Error),
+    /// The physical function didn't report the settings of the VF.
+    MissingVfSettings(String, u32),
+    /// The physical function refused the VF settings.
+    SetVfSettings(String, u32, io::Error),
+    /// The physical function refused the VF link state.
+    SetVfLinkState(String, u32, io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            NotVirtualFunction(path) => {
+                write!(f, "{} is not an SR-IOV virtual function", path.display())
+            }
+            NoPhysicalFunctionInterface(path) => write!(
+                f,
+                "No network interface found for the physical function {}",
+                path.display()
+            ),
+            Sysfs(path, e) => write!(f, "Error reading {}: {}", path.display(), e),
+            Netlink(e) => write!(f, "Error sending netlink request: {}", e),
+            MissingVfSettings(pf, vf) => {
+                write!(f, "{} didn't report the settings of its VF {}", pf, vf)
+            }
+            SetVfSettings(pf, vf, e) => {
+                write!(f, "{} refused the settings of its VF {}: {}", pf, vf, e)
+            }
+            SetVfLinkState(pf, vf, e) => {
+                write!(f, "{} refused the link state of its VF {}: {}", pf, vf, e)
+            }
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Settings of a VF, the ones set to None being left untouched.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct VfSettings {
+    mac: Option<MacAddr>,
+    vlan: Option<u16>,
+    link_state: Option<u32>,
+}
+
+/// Virtual function whose settings were programmed, these being restored
+/// once it gets dropped.
+pub struct VirtualFunction {
+    pf_name: String,
+    pf_ifindex: i32,
+    index: u32,
+    original: VfSettings,
+    // Link state reported by the physical function before any change.
+    link_state: u32,
+}
+
+impl VirtualFunction {
+    /// Program `mac` and `vlan` on the VF described by `device_path` in sysfs.
+    pub fn configure(
+        device_path: &Path,
+        mac: Option<MacAddr>,
+        vlan: Option<u16>,
+    ) -> Result<VirtualFunction> {
+        let (pf_name, pf_ifindex, index) = find_physical_function(device_path)?;
+
+        let socket = NetlinkSocket::new().map_err(Error::Netlink)?;
+        let current = get_vf_settings(&socket, pf_ifindex, index)
+            .map_err(Error::Netlink)?
+            .ok_or_else(|| Error::MissingVfSettings(pf_name.clone(), index))?;
+        set_vf_settings(
+            &socket,
+            pf_ifindex,
+            index,
+            VfSettings {
+                mac,
+                vlan,
+                link_state: None,
+            },
+        )
+        .map_err(|e| Error::SetVfSettings(pf_name.clone(), index, e))?;
+
+        info!(
+            "Programmed VF {} of {} with MAC {:?} and VLAN {:?}",
+            index, pf_name, mac, vlan
+        );
+
+        Ok(VirtualFunction {
+            pf_name,
+            pf_ifindex,
+            index,
+            original: VfSettings {
+                mac: mac.and(current.mac),
+                vlan: vlan.and(current.vlan),
+                link_state: None,
+            },
+            link_state: current.link_state.unwrap_or(IFLA_VF_LINK_STATE_AUTO),
+        })
+    }
+
+    /// Bring the link of the VF down, or back to following the link of the
+    /// physical function.
+    pub fn set_link_up(&mut self, link_up: bool) -> Result<()> {
+        let link_state = if link_up {
+            IFLA_VF_LINK_STATE_AUTO
+        } else {
+            IFLA_VF_LINK_STATE_DISABLE
+        };
+        let settings = VfSettings {
+            link_state: Some(link_state),
+            ..Default::default()
+        };
+
+        NetlinkSocket::new()
+            .and_then(|socket| set_vf_settings(&socket, self.pf_ifindex, self.index, settings))
+            .map_err(|e| Error::SetVfLinkState(self.pf_name.clone(), self.index, e))?;
+
+        self.original.link_state = Some(self.link_state);
+        info!(
+            "Set the link of VF {} of {} {}",
+            self.index,
+            self.pf_name,
+            if link_up { "up" } else { "down" }
+        );
+
+        Ok(())
+    }
+}
+
+impl Drop for VirtualFunction {
+    fn drop(&mut self) {
+        if let Err(e) = NetlinkSocket::new()
+            .and_then(|socket| set_vf_settings(&socket, self.pf_ifindex, self.index, self.original))
+        {
+            warn!(
+                "Failed to restore the settings of VF {} of {}: {}",
+                self.index, self.pf_name, e
+            );
+        }
+    }
+}
+
+// Name and index of the network interface of the physical function the VF
+// belongs to, along with the index of the VF.
+fn find_physical_function(device_path: &Path) -> Result<(String, i32, u32)> {
+    let sysfs_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |e| Error::Sysfs(path, e)
+    };
+
+    let physfn = device_path.join("physfn");
+    if !physfn.exists() {
+        return Err(Error::NotVirtualFunction(device_path.to_path_buf()));
+    }
+
+    // The physical function links to each of its VFs as virtfn<index>.
+    let device = fs::canonicalize(device_path).map_err(sysfs_error(device_path))?;
+    let mut index = None;
+    for entry in fs::read_dir(&physfn).map_err(sysfs_error(&physfn))? {
+        let entry = entry.map_err(sysfs_error(&physfn))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("virtfn") {
+            continue;
+        }
+        if fs::canonicalize(entry.path()).ok().as_ref() == Some(&device) {
+            index = name["virtfn".len()..].parse::<u32>().ok();
+            break;
+        }
+    }
+    let index = index.ok_or_else(|| Error::NotVirtualFunction(device_path.to_path_buf()))?;
+
+    let net = physfn.join("net");
+    let pf_name = fs::read_dir(&net)
+        .ok()
+        .and_then(|mut entries| {
+            entries.find_map(|entry| entry.ok()?.file_name().into_string().ok())
+        })
+        .ok_or_else(|| Error::NoPhysicalFunctionInterface(physfn.clone()))?;
+
+    let ifindex_path = net.join(&pf_name).join("ifindex");
+    let pf_ifindex = fs::read_to_string(&ifindex_path)
+        .map_err(sysfs_error(&ifindex_path))?
+        .trim()
+        .parse::<i32>()
+        .map_err(|e| {
+            Error::Sysfs(
+                ifindex_path.clone(),
+                io::Error::new(io::ErrorKind::InvalidData, e),
+            )
+        })?;
+
+    Ok((pf_name, pf_ifindex, index))
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn nla_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+// Link message under construction, made of the netlink header, the ifinfomsg
+// header and the attributes.
+struct LinkMessage {
+    buf: Vec<u8>,
+}
+
+impl LinkMessage {
+    fn new(msg_type: u16, flags: u16, ifindex: i32) -> Self {
+        let mut buf = vec![0u8; NLMSG_HDR_LEN + IFINFOMSG_LEN];
+        buf[4..6].copy_from_slice(&msg_type.to_ne_bytes());
+        buf[6..8].copy_from_slice(&flags.to_ne_bytes());
+        buf[8..12].copy_from_slice(&1u32.to_ne_bytes());
+        buf[NLMSG_HDR_LEN] = libc::AF_UNSPEC as u8;
+        buf[NLMSG_HDR_LEN + 4..NLMSG_HDR_LEN + 8].copy_from_slice(&ifindex.to_ne_bytes());
+        LinkMessage { buf }
+    }
+
+    // Start an attribute, closed by end_attr() once its payload was pushed.
+    fn begin_attr(&mut self, attr_type: u16) -> usize {
+        let start = self.buf.len();
+        self.buf.extend_from_slice(&[0u8; NLA_HDR_LEN]);
+        self.buf[start + 2..start + 4].copy_from_slice(&attr_type.to_ne_bytes());
+        start
+    }
+
+    fn end_attr(&mut self, start: usize) {
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        self.buf.resize(nla_align(self.buf.len()), 0);
+    }
+
+    fn push_attr(&mut self, attr_type: u16, data: &[u8]) {
+        let start = self.begin_attr(attr_type);
+        self.buf.extend_from_slice(data);
+        self.end_attr(start);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
+
+// Attributes found in `data`, as their type and payload.
+fn parse_attrs(mut data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    while data.len() >= NLA_HDR_LEN {
+        let len = read_u16(data, 0) as usize;
+        if len < NLA_HDR_LEN || len > data.len() {
+            break;
+        }
+        attrs.push((read_u16(data, 2) & NLA_TYPE_MASK, &data[NLA_HDR_LEN..len]));
+        data = &data[std::cmp::min(nla_align(len), data.len())..];
+    }
+    attrs
+}
+
+// Settings of the VF `index` from the RTM_NEWLINK message describing its
+// physical function.
+fn parse_vf_settings(msg: &[u8], index: u32) -> Option<VfSettings> {
+    let attrs = parse_attrs(msg.get(NLMSG_HDR_LEN + IFINFOMSG_LEN..)?);
+    let (_, vf_list) = attrs
+        .into_iter()
+        .find(|(attr_type, _)| *attr_type == IFLA_VFINFO_LIST)?;
+
+    for (_, vf_info) in parse_attrs(vf_list)
+        .into_iter()
+        .filter(|(attr_type, _)| *attr_type == IFLA_VF_INFO)
+    {
+        let mut vf = None;
+        let mut settings = VfSettings::default();
+        for (attr_type, data) in parse_attrs(vf_info) {
+            match attr_type {
+                IFLA_VF_MAC if data.len() >= 4 + MAC_ADDR_LEN => {
+                    vf = Some(read_u32(data, 0));
+                    settings.mac = Some(MacAddr::from_bytes_unchecked(&data[4..4 + MAC_ADDR_LEN]));
+                }
+                IFLA_VF_VLAN if data.len() >= 8 => {
+                    vf = Some(read_u32(data, 0));
+                    settings.vlan = Some(read_u32(data, 4) as u16);
+                }
+                IFLA_VF_LINK_STATE if data.len() >= 8 => {
+                    vf = Some(read_u32(data, 0));
+                    settings.link_state = Some(read_u32(data, 4));
+                }
+                _ => {}
+            }
+        }
+        if vf == Some(index) {
+            return Some(settings);
+        }
+    }
+
+    None
+}
+
+struct NetlinkSocket {
+    fd: RawFd,
+}
+
+impl NetlinkSocket {
+    fn new() -> io::Result<Self> {
+        // SAFETY: FFI call with constant arguments, its result being checked.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(NetlinkSocket { fd })
+    }
+
+    // Send `request` to the kernel and return its reply, failing with the
+    // error the kernel reports if any.
+    fn request(&self, request: &[u8]) -> io::Result<Vec<u8>> {
+        // SAFETY: the buffer is valid for its whole length.
+        let ret = unsafe {
+            libc::send(
+                self.fd,
+                request.as_ptr() as *const libc::c_void,
+                request.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Peek at the size of the reply first, which grows with the number
+        // of VFs.
+        // SAFETY: nothing gets written with a zero length.
+        let len = unsafe {
+            libc::recv(
+                self.fd,
+                std::ptr::null_mut(),
+                0,
+                libc::MSG_PEEK | libc::MSG_TRUNC,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut reply = vec![0u8; len as usize];
+        // SAFETY: the buffer is valid for its whole length.
+        let len = unsafe {
+            libc::recv(
+                self.fd,
+                reply.as_mut_ptr() as *mut libc::c_void,
+                reply.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        reply.truncate(len as usize);
+
+        if reply.len() < NLMSG_HDR_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated netlink reply",
+            ));
+        }
+        let msg_len = std::cmp::min(read_u32(&reply, 0) as usize, reply.len());
+        reply.truncate(msg_len);
+
+        // The error message starts with the negated errno, zero acknowledging
+        // the request.
+        if read_u16(&reply, 4) == NLMSG_ERROR && reply.len() >= NLMSG_HDR_LEN + 4 {
+            let errno = read_u32(&reply, NLMSG_HDR_LEN) as i32;
+            if errno != 0 {
+                return Err(io::Error::from_raw_os_error(-errno));
+            }
+        }
+
+        Ok(reply)
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        // SAFETY: the file descriptor is owned by the socket.
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn get_vf_settings(
+    socket: &NetlinkSocket,
+    pf_ifindex: i32,
+    index: u32,
+) -> io::Result<Option<VfSettings>> {
+    let mut msg = LinkMessage::new(RTM_GETLINK, NLM_F_REQUEST, pf_ifindex);
+    msg.push_attr(IFLA_EXT_MASK, &RTEXT_FILTER_VF.to_ne_bytes());
+
+    let reply = socket.request(&msg.finish())?;
+    if read_u16(&reply, 4) != RTM_NEWLINK {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected netlink reply",
+        ));
+    }
+
+    Ok(parse_vf_settings(&reply, index))
+}
+
+fn set_vf_settings(
+    socket: &NetlinkSocket,
+    pf_ifindex: i32,
+    index: u32,
+    settings: VfSettings,
+) -> io::Result<()> {
+    let mut msg = LinkMessage::new(RTM_SETLINK, NLM_F_REQUEST | NLM_F_ACK, pf_ifindex);
+    let vf_list = msg.begin_attr(IFLA_VFINFO_LIST);
+    push_vf_info(&mut msg, index, settings);
+    msg.end_attr(vf_list);
+    socket.request(&msg.finish()).map(|_| ())
+}
+
+fn push_vf_info(msg: &mut LinkMessage, index: u32, settings: VfSettings) {
+    let vf_info = msg.begin_attr(IFLA_VF_INFO);
+
+    if let Some(mac) = settings.mac {
+        // struct ifla_vf_mac
+        let mut data = index.to_ne_bytes().to_vec();
+        let mut addr = [0u8; IFLA_VF_MAC_ADDR_LEN];
+        addr[..MAC_ADDR_LEN].copy_from_slice(mac.get_bytes());
+        data.extend_from_slice(&addr);
+        msg.push_attr(IFLA_VF_MAC, &data);
+    }
+
+    if let Some(vlan) = settings.vlan {
+        // struct ifla_vf_vlan, with a zero QoS.
+        let mut data = index.to_ne_bytes().to_vec();
+        data.extend_from_slice(&u32::from(vlan).to_ne_bytes());
+        data.extend_from_slice(&0u32.to_ne_bytes());
+        msg.push_attr(IFLA_VF_VLAN, &data);
+    }
+
+    if let Some(link_state) = settings.link_state {
+        // struct ifla_vf_link_state
+        let mut data = index.to_ne_bytes().to_vec();
+        data.extend_from_slice(&link_state.to_ne_bytes());
+        msg.push_attr(IFLA_VF_LINK_STATE, &data);
+    }
+
+    msg.end_attr(vf_info);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vf_settings() {
+        let settings = |mac: &str, vlan, link_state| VfSettings {
+            mac: Some(MacAddr::parse_str(mac).unwrap()),
+            vlan: Some(vlan),
+            link_state: Some(link_state),
+        };
+
+        // RTM_NEWLINK reply of a physical function with two VFs, unrelated
+        // attributes being skipped.
+        let mut msg = LinkMessage::new(RTM_NEWLINK, 0, 4);
+        msg.push_attr(3, b"eth0\0");
+        let vf_list = msg.begin_attr(IFLA_VFINFO_LIST);
+        push_vf_info(
+            &mut msg,
+            0,
+            settings("12:34:56:78:9a:bc", 0, IFLA_VF_LINK_STATE_AUTO),
+        );
+        push_vf_info(
+            &mut msg,
+            1,
+            settings("12:34:56:78:9a:bd", 42, IFLA_VF_LINK_STATE_DISABLE),
+        );
+        msg.end_attr(vf_list);
+        let msg = msg.finish();
+
+        assert_eq!(
+            parse_vf_settings(&msg, 0),
+            Some(settings("12:34:56:78:9a:bc", 0, IFLA_VF_LINK_STATE_AUTO))
+        );
+        assert_eq!(
+            parse_vf_settings(&msg, 1),
+            Some(settings(
+                "12:34:56:78:9a:bd",
+                42,
+                IFLA_VF_LINK_STATE_DISABLE
+            ))
+        );
+        assert_eq!(parse_vf_settings(&msg, 2), None);
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers shared by the unit tests of the VMM.
+
+use crate::memory_manager::DIRTY_LOG_PAGE_SIZE;
+use anyhow::anyhow;
+use hypervisor::vm::{DataMatch, Result};
+#[cfg(target_arch = "x86_64")]
+use hypervisor::ClockData;
+#[cfg(target_arch = "aarch64")]
+use hypervisor::VcpuInit;
+use hypervisor::{
+    Cap, CreateDevice, Device, HypervisorVmError, IoEventAddress, IrqRoutingEntry, MemoryRegion,
+    Vcpu, Vm,
+};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+
+/// VM handed to the code under test instead of a KVM one, recording the
+/// dirty log and clock requests it gets and answering them with the pages
+/// and clock it was given. It has no vCPUs nor devices.
+#[derive(Default)]
+pub struct MockVm {
+    /// Whether the VM supports the dirty rings.
+    pub dirty_ring: bool,
+    /// Bitmaps returned by the next `get_dirty_log()` of every slot.
+    pub bitmaps: Mutex<BTreeMap<u32, Vec<u64>>>,
+    /// Pages returned by the next `harvest_dirty_log()`.
+    pub harvested: Mutex<Vec<(u32, u64)>>,
+    #[cfg(target_arch = "x86_64")]
+    /// Clock returned by `get_clock()`.
+    pub clock: ClockData,
+    #[cfg(target_arch = "x86_64")]
+    /// Clocks passed to `set_clock()`, in order.
+    pub set_clocks: Mutex<Vec<ClockData>>,
+    /// Dirty log and clock requests, in order.
+    pub calls: Mutex<Vec<&'static str>>,
+}
+
+impl MockVm {
+    fn call(&self, name: &'static str) {
+        self.calls.lock().unwrap().push(name);
+    }
+}
+
+impl Vm for MockVm {
+    #[cfg(target_arch = "x86_64")]
+    fn set_tss_address(&self, _offset: usize) -> Result<()> {
+        Ok(())
+    }
+    fn create_irq_chip(&self) -> Result<()> {
+        Ok(())
+    }
+    fn register_irqfd(&self, _fd: &EventFd, _gsi: u32) -> Result<()> {
+        Ok(())
+    }
+    fn unregister_irqfd(&self, _fd: &EventFd, _gsi: u32) -> Result<()> {
+        Ok(())
+    }
+    fn create_vcpu(&self, _id: u8) -> Result<Arc<dyn Vcpu>> {
+        Err(HypervisorVmError::CreateVcpu(anyhow!("No vCPU in a mock")))
+    }
+    fn register_ioevent(
+        &self,
+        _fd: &EventFd,
+        _addr: &IoEventAddress,
+        _datamatch: Option<DataMatch>,
+    ) -> Result<()> {
+        Ok(())
+    }
+    fn unregister_ioevent(&self, _fd: &EventFd, _addr: &IoEventAddress) -> Result<()> {
+        Ok(())
+    }
+    fn set_gsi_routing(&self, _entries: &[IrqRoutingEntry]) -> Result<()> {
+        Ok(())
+    }
+    fn make_user_memory_region(
+        &self,
+        slot: u32,
+        guest_phys_addr: u64,
+        memory_size: u64,
+        userspace_addr: u64,
+        _readonly: bool,
+        _log_dirty_pages: bool,
+    ) -> MemoryRegion {
+        MemoryRegion {
+            slot,
+            guest_phys_addr,
+            memory_size,
+            userspace_addr,
+            flags: 0,
+        }
+    }
+    fn set_user_memory_region(&self, _user_memory_region: MemoryRegion) -> Result<()> {
+        Ok(())
+    }
+    fn create_device(&self, _device: &mut CreateDevice) -> Result<Arc<dyn Device>> {
+        Err(HypervisorVmError::CreateDevice(anyhow!(
+            "No device in a mock"
+        )))
+    }
+    #[cfg(target_arch = "aarch64")]
+    fn get_preferred_target(&self, _kvi: &mut VcpuInit) -> Result<()> {
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    fn enable_split_irq(&self) -> Result<()> {
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    fn get_clock(&self) -> Result<ClockData> {
+        self.call("get_clock");
+        Ok(self.clock)
+    }
+    #[cfg(target_arch = "x86_64")]
+    fn set_clock(&self, data: &ClockData) -> Result<()> {
+        self.call("set_clock");
+        self.set_clocks.lock().unwrap().push(*data);
+        Ok(())
+    }
+    fn check_extension(&self, _c: Cap) -> bool {
+        false
+    }
+    fn create_passthrough_device(&self) -> Result<Arc<dyn Device>> {
+        Err(HypervisorVmError::CreatePassthroughDevice(anyhow!(
+            "No device in a mock"
+        )))
+    }
+    fn get_dirty_log(&self, slot: u32, memory_size: u64) -> Result<Vec<u64>> {
+        self.call("get_dirty_log");
+        let pages = memory_size / DIRTY_LOG_PAGE_SIZE;
+        let mut bitmap = self
+            .bitmaps
+            .lock()
+            .unwrap()
+            .remove(&slot)
+            .unwrap_or_default();
+        bitmap.resize(((pages + 63) / 64) as usize, 0);
+        Ok(bitmap)
+    }
+    fn enable_dirty_ring(&self) -> Result<bool> {
+        self.call("enable_dirty_ring");
+        Ok(self.dirty_ring)
+    }
+    fn harvest_dirty_log(&self) -> Result<Vec<(u32, u64)>> {
+        self.call("harvest_dirty_log");
+        if !self.dirty_ring {
+            return Err(HypervisorVmError::HarvestDirtyLog(anyhow!(
+                "The dirty rings aren't enabled"
+            )));
+        }
+        Ok(std::mem::take(&mut *self.harvested.lock().unwrap()))
+    }
+}
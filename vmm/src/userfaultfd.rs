@@ -0,0 +1,167 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Minimal wrapper over the userfaultfd interface of Linux.
+//!
+//! Once a range of memory is registered, the threads touching one of its
+//! missing pages, including the vCPUs through KVM, are put to sleep and the
+//! fault is reported through the file descriptor. They are woken up when
+//! the page gets copied in place with `UFFDIO_COPY`.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+
+// Ioctls and messages from the userfaultfd kernel interface, see
+// include/uapi/linux/userfaultfd.h.
+const UFFDIO: u32 = 0xAA;
+const UFFD_API: u64 = 0xAA;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+ioctl_iowr_nr!(UFFDIO_API, UFFDIO, 0x3f, UffdioApi);
+ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, UffdioRegister);
+ioctl_iowr_nr!(UFFDIO_COPY, UFFDIO, 0x03, UffdioCopy);
+
+// Size of the messages read from a userfaultfd, the address of a page
+// fault being found at offset 16.
+const UFFD_MSG_SIZE: usize = 32;
+
+pub struct Userfaultfd {
+    file: File,
+}
+
+impl Userfaultfd {
+    /// Create a non blocking userfaultfd, reporting the faults on missing
+    /// pages.
+    pub fn new() -> io::Result<Userfaultfd> {
+        // Safe because the syscall doesn't access any memory, and we check
+        // the return value.
+        let fd =
+            unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we own the file descriptor we just created.
+        let file = unsafe { File::from_raw_fd(fd as RawFd) };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            ..Default::default()
+        };
+        // Safe because the kernel only writes to the struct we own.
+        let ret = unsafe { ioctl_with_mut_ref(&file, UFFDIO_API(), &mut api) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Userfaultfd { file })
+    }
+
+    /// Report the faults on the missing pages of the `len` bytes at
+    /// `addr`, which must be page aligned.
+    pub fn register(&self, addr: u64, len: u64) -> io::Result<()> {
+        let mut register = UffdioRegister {
+            range: UffdioRange { start: addr, len },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ..Default::default()
+        };
+        // Safe because the kernel only writes to the struct we own.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_REGISTER(), &mut register) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Copy `data` to the missing pages at `addr`, waking up the threads
+    /// which faulted on them. Pages already in place are left untouched.
+    pub fn copy(&self, addr: u64, data: &[u8]) -> io::Result<()> {
+        let mut copy = UffdioCopy {
+            dst: addr,
+            src: data.as_ptr() as u64,
+            len: data.len() as u64,
+            ..Default::default()
+        };
+        // Safe because the kernel only reads the `data` we were given, only
+        // writes to the registered pages, or to the struct we own.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_COPY(), &mut copy) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EEXIST) {
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Address of the next page fault, or `None` if no fault is pending.
+    pub fn read_fault(&self) -> io::Result<Option<u64>> {
+        let mut msg = [0u8; UFFD_MSG_SIZE];
+        loop {
+            match (&self.file).read(&mut msg) {
+                Ok(UFFD_MSG_SIZE) => {}
+                Ok(len) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Short userfaultfd message of {} bytes", len),
+                    ))
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+
+            // Only the page faults are reported, as no other feature was
+            // requested.
+            if msg[0] == UFFD_EVENT_PAGEFAULT {
+                let mut address = [0u8; 8];
+                address.copy_from_slice(&msg[16..24]);
+                return Ok(Some(u64::from_ne_bytes(address)));
+            }
+        }
+    }
+}
+
+impl AsRawFd for Userfaultfd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Monitoring of the errors the host detects on the passthrough devices.
+//!
+//! VFIO signals an eventfd whenever the host AER driver gets an error
+//! reported by a passthrough device. A thread per device waits on it and
+//! reports the errors found in the AER capability of the device. The guest
+//! sees that capability in the config space of the device, but there's no
+//! emulated root port to forward the error messages to, meaning the guest
+//! only finds out about the errors by looking at the status registers.
+
+use pci::{AerStatus, VfioPciDevice};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use virtio_devices::{EpollHelper, EpollHelperHandler, EPOLL_HELPER_EVENT_LAST};
+use vmm_sys_util::eventfd::EventFd;
+
+const ERROR_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+
+struct ErrorHandler {
+    id: String,
+    device: Arc<Mutex<VfioPciDevice>>,
+}
+
+impl ErrorHandler {
+    fn report(&self, status: AerStatus) {
+        if status.is_empty() {
+            // The host cleared the status before we could look at it.
+            warn!("Error notified on VFIO device {}", self.id);
+            return;
+        }
+        if status.correctable != 0 {
+            warn!(
+                "Correctable errors on VFIO device {}: {:#x}",
+                self.id, status.correctable
+            );
+        }
+        if status.nonfatal != 0 {
+            error!(
+                "Non fatal uncorrectable errors on VFIO device {}: {:#x}",
+                self.id, status.nonfatal
+            );
+        }
+        if status.fatal != 0 {
+            error!(
+                "Fatal uncorrectable errors on VFIO device {}: {:#x}",
+                self.id, status.fatal
+            );
+        }
+    }
+}
+
+impl EpollHelperHandler for ErrorHandler {
+    fn handle_event(&mut self, _helper: &mut EpollHelper, event: u16) -> bool {
+        match event {
+            ERROR_EVENT => {
+                let status = self.device.lock().unwrap().handle_error_notification();
+                if let Some(status) = status {
+                    self.report(status);
+                }
+            }
+            _ => {
+                error!("Unknown event for VFIO error monitor");
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+/// Thread reporting the errors of a passthrough device, until dropped.
+pub struct ErrorMonitor {
+    kill_evt: EventFd,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ErrorMonitor {
+    /// Report the errors signaled through `error_evt`, as returned by
+    /// `VfioPciDevice::enable_error_notification()`.
+    pub fn start(
+        id: String,
+        device: Arc<Mutex<VfioPciDevice>>,
+        error_evt: EventFd,
+    ) -> io::Result<Self> {
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        let pause_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        let thread_kill_evt = kill_evt.try_clone()?;
+
+        let thread = thread::Builder::new()
+            .name("vfio_error".to_string())
+            .spawn(move || {
+                let mut handler = ErrorHandler { id, device };
+                let result =
+                    EpollHelper::new(&thread_kill_evt, &pause_evt).and_then(|mut helper| {
+                        helper.add_event(error_evt.as_raw_fd(), ERROR_EVENT)?;
+                        helper.run(Arc::new(AtomicBool::new(false)), &mut handler)
+                    });
+                if let Err(e) = result {
+                    error!(
+                        "Error monitor of VFIO device {} failed: {:?}",
+                        handler.id, e
+                    );
+                }
+            })?;
+
+        Ok(ErrorMonitor {
+            kill_evt,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for ErrorMonitor {
+    // The thread holds a reference onto the device, which must be gone once
+    // the monitor is.
+    fn drop(&mut self) {
+        if let Err(e) = self.kill_evt.write(1) {
+            error!("Failed to stop VFIO error monitor: {}", e);
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("VFIO error monitor thread panicked");
+            }
+        }
+    }
+}
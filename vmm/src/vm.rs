@@ -24,13 +24,27 @@ extern crate vm_allocator;
 extern crate vm_memory;
 
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, HotplugMethod, NetConfig, PmemConfig, ValidationError,
-    VmConfig, VsockConfig,
+    ConsolePortConfig, DeviceConfig, DiskConfig, FsConfig, HotplugMethod, NetConfig, PmemConfig,
+    ValidationError, VmConfig, VsockConfig,
 };
+#[cfg(target_arch = "x86_64")]
+use crate::console_socket::Endpoint;
 use crate::cpu;
 use crate::device_manager::{self, get_win_size, Console, DeviceManager, DeviceManagerError};
-use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
-use crate::migration::{get_vm_snapshot, url_to_path, VM_SNAPSHOT_FILE};
+#[cfg(target_arch = "x86_64")]
+use crate::gdb;
+use crate::handoff::{self, HandoffFds};
+use crate::memory_manager::{
+    Error as MemoryManagerError, MemoryManager, MemoryZoneHints, DIRTY_LOG_PAGE_SIZE,
+};
+use crate::migration::{
+    self, get_vm_snapshot, read_vm_snapshot, send_memory_postcopy, send_memory_precopy,
+    send_vm_snapshot, tcp_url_address, url_to_path, write_vm_snapshot, PrecopyConfig,
+};
+use crate::migration_progress::{pages_per_second, DirtyRate, MigrationPhase, MigrationProgress};
+use crate::migration_stream::MigrationStream;
+use crate::postcopy::send_postcopy_pages;
+use crate::snapshot_chain::WriteOptions;
 use crate::{
     PciDeviceInfo, CPU_MANAGER_SNAPSHOT_ID, DEVICE_MANAGER_SNAPSHOT_ID, MEMORY_MANAGER_SNAPSHOT_ID,
 };
@@ -45,25 +59,32 @@ use linux_loader::loader::elf::Error::InvalidElfMagicNumber;
 #[cfg(target_arch = "x86_64")]
 use linux_loader::loader::elf::PvhBootCapability::PvhEntryPresent;
 use linux_loader::loader::KernelLoader;
+use net_util::MacAddr;
+use seccomp::SeccompAction;
 use signal_hook::{iterator::Signals, SIGINT, SIGTERM, SIGWINCH};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::ffi::CString;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
+use std::fs::File;
+use std::io::{self, Read, Write};
 use std::io::{Seek, SeekFrom};
+use std::net::TcpStream;
 use std::num::Wrapping;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use std::{result, str, thread};
 use url::Url;
 use vm_memory::{Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryMmap};
 use vm_migration::{
+    protocol::{accept_compression, request_compression, Compression, MemoryRangeTable},
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
     Transportable,
 };
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
 use vmm_sys_util::terminal::Terminal;
 
 // 64 bit direct boot entry offset for bzImage
@@ -141,6 +162,9 @@ pub enum Error {
     /// Cannot clone EventFd.
     EventFdClone(io::Error),
 
+    /// Cannot write to EventFd.
+    EventFdWrite(io::Error),
+
     /// Invalid VM state transition
     InvalidStateTransition(VmState, VmState),
 
@@ -171,6 +195,9 @@ pub enum Error {
     /// No PCI support
     NoPciSupport,
 
+    /// The guest did not eject the device in time
+    DeviceEjectTimeout(String),
+
     /// Eventfd write error
     EventfdError(std::io::Error),
 
@@ -183,6 +210,18 @@ pub enum Error {
     /// Cannot send VM snapshot
     SnapshotSend(MigratableError),
 
+    /// Cannot consolidate an incremental snapshot chain
+    SnapshotConsolidate(MigratableError),
+
+    /// Cannot send the VM through a live migration
+    MigrateSend(MigratableError),
+
+    /// Cannot receive the VM through a live migration
+    MigrateReceive(MigratableError),
+
+    /// Cannot measure the rate the guest dirties its memory at
+    DirtyRate(MigratableError),
+
     /// Cannot convert source URL from Path into &str
     RestoreSourceUrlPathToStr,
 
@@ -192,8 +231,34 @@ pub enum Error {
     /// No more that one virtio-vsock device
     TooManyVsockDevices,
 
+    /// No balloon device to get statistics from
+    NoBalloon,
+
+    /// No vsock device to get connections from
+    NoVsock,
+
+    /// No GPU device to take a screenshot from
+    NoGpu,
+
+    /// Cannot write the screenshot
+    Screenshot(io::Error),
+
+    /// No memory zone backed by a memfd
+    NoMemfdZone,
+
+    /// Cannot send the memfd file descriptors
+    SendMemoryFds(io::Error),
+
     /// Failed serializing into JSON
     SerializeJson(serde_json::Error),
+
+    /// Cannot start the GDB stub
+    #[cfg(target_arch = "x86_64")]
+    GdbServer(gdb::Error),
+
+    /// Cannot get the vCPUs debug EventFd
+    #[cfg(target_arch = "x86_64")]
+    GdbDebugEventFd(cpu::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -255,6 +320,8 @@ pub struct Vm {
     vm: Arc<dyn hypervisor::Vm>,
     #[cfg(target_arch = "x86_64")]
     saved_clock: Option<hypervisor::ClockData>,
+    #[cfg(target_arch = "x86_64")]
+    gdb_server: Option<gdb::GdbServer>,
 }
 
 impl Vm {
@@ -265,7 +332,10 @@ impl Vm {
         vm: Arc<dyn hypervisor::Vm>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        watchdog_evt: EventFd,
+        panic_evt: EventFd,
         vmm_path: PathBuf,
+        seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         _saved_clock: Option<hypervisor::ClockData>,
     ) -> Result<Self> {
@@ -281,7 +351,10 @@ impl Vm {
             memory_manager.clone(),
             &exit_evt,
             &reset_evt,
+            &watchdog_evt,
+            &panic_evt,
             vmm_path,
+            seccomp_action.clone(),
         )
         .map_err(Error::DeviceManager)?;
 
@@ -323,14 +396,20 @@ impl Vm {
             vm,
             #[cfg(target_arch = "x86_64")]
             saved_clock: _saved_clock,
+            #[cfg(target_arch = "x86_64")]
+            gdb_server: None,
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: Arc<Mutex<VmConfig>>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        watchdog_evt: EventFd,
+        panic_evt: EventFd,
         vmm_path: PathBuf,
+        seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
     ) -> Result<Self> {
         #[cfg(target_arch = "x86_64")]
@@ -363,7 +442,10 @@ impl Vm {
             vm,
             exit_evt,
             reset_evt,
+            watchdog_evt,
+            panic_evt,
             vmm_path,
+            seccomp_action,
             hypervisor,
             None,
         )?;
@@ -380,13 +462,18 @@ impl Vm {
         Ok(new_vm)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new_from_snapshot(
         snapshot: &Snapshot,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        watchdog_evt: EventFd,
+        panic_evt: EventFd,
         vmm_path: PathBuf,
         source_url: &str,
         prefault: bool,
+        memory_fds: Option<&[File]>,
+        seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
     ) -> Result<Self> {
         #[cfg(target_arch = "x86_64")]
@@ -397,16 +484,40 @@ impl Vm {
         let vm_snapshot = get_vm_snapshot(snapshot).map_err(Error::Restore)?;
         let config = vm_snapshot.config.clone();
 
+        // Refuse to restore nested guests whose state this host can't
+        // restore, before receiving anything else.
+        #[cfg(target_arch = "x86_64")]
+        if let Some(format) = vm_snapshot.nested_state_format {
+            if hypervisor.nested_state_format() != Some(format) {
+                return Err(Error::Restore(MigratableError::Restore(anyhow!(
+                    "Nested state of format {:?}, the host supports {:?}",
+                    format,
+                    hypervisor.nested_state_format()
+                ))));
+            }
+        }
+
         let memory_manager = if let Some(memory_manager_snapshot) =
             snapshot.snapshots.get(MEMORY_MANAGER_SNAPSHOT_ID)
         {
-            MemoryManager::new_from_snapshot(
-                memory_manager_snapshot,
-                vm.clone(),
-                &config.lock().unwrap().memory.clone(),
-                source_url,
-                prefault,
-            )
+            // The memory handed over by another process is mapped as is,
+            // rather than filled from the source.
+            let memory_config = config.lock().unwrap().memory.clone();
+            match memory_fds {
+                Some(files) => MemoryManager::new_from_handoff(
+                    memory_manager_snapshot,
+                    vm.clone(),
+                    &memory_config,
+                    files,
+                ),
+                None => MemoryManager::new_from_snapshot(
+                    memory_manager_snapshot,
+                    vm.clone(),
+                    &memory_config,
+                    source_url,
+                    prefault,
+                ),
+            }
             .map_err(Error::MemoryManager)?
         } else {
             return Err(Error::Restore(MigratableError::Restore(anyhow!(
@@ -414,19 +525,36 @@ impl Vm {
             ))));
         };
 
-        Vm::new_from_memory_manager(
+        let new_vm = Vm::new_from_memory_manager(
             config,
             memory_manager,
             vm,
             exit_evt,
             reset_evt,
+            watchdog_evt,
+            panic_evt,
             vmm_path,
+            seccomp_action,
             hypervisor,
             #[cfg(target_arch = "x86_64")]
             vm_snapshot.clock,
             #[cfg(target_arch = "aarch64")]
             None,
-        )
+        )?;
+
+        // Refuse to restore a guest which could rely on CPU features this
+        // host doesn't have, before any vCPU gets created.
+        #[cfg(target_arch = "x86_64")]
+        if let Some(cpu_features) = &vm_snapshot.cpu_features {
+            new_vm
+                .cpu_manager
+                .lock()
+                .unwrap()
+                .restrict_cpu_features(cpu_features, vm_snapshot.cpuid_features.as_deref())
+                .map_err(Error::CpuManager)?;
+        }
+
+        Ok(new_vm)
     }
 
     fn load_initramfs(&mut self, guest_mem: &GuestMemoryMmap) -> Result<arch::InitramfsConfig> {
@@ -552,6 +680,31 @@ impl Vm {
         }
     }
 
+    // The guest NUMA nodes, as described to the guest through the ACPI tables
+    // or the device tree.
+    #[cfg(any(feature = "acpi", target_arch = "aarch64"))]
+    fn numa_nodes(&self) -> arch::NumaNodes {
+        let mut numa_nodes = arch::NumaNodes::new();
+        if let Some(numa) = &self.config.lock().unwrap().numa {
+            for node in numa.iter() {
+                numa_nodes.insert(
+                    node.guest_numa_id,
+                    arch::NumaNode {
+                        cpus: node.cpus.clone().unwrap_or_default(),
+                        distances: node
+                            .distances
+                            .iter()
+                            .flatten()
+                            .map(|d| (d.destination, d.distance))
+                            .collect(),
+                    },
+                );
+            }
+        }
+
+        numa_nodes
+    }
+
     #[cfg(target_arch = "x86_64")]
     fn configure_system(&mut self, entry_addr: EntryPoint) -> Result<()> {
         let cmdline_cstring = self.get_cmdline()?;
@@ -564,6 +717,7 @@ impl Vm {
         };
 
         let boot_vcpus = self.cpu_manager.lock().unwrap().boot_vcpus();
+        let topology = self.cpu_manager.lock().unwrap().get_vcpu_topology();
 
         #[allow(unused_mut, unused_assignments)]
         let mut rsdp_addr: Option<GuestAddress> = None;
@@ -575,6 +729,7 @@ impl Vm {
                 &self.device_manager,
                 &self.cpu_manager,
                 &self.memory_manager,
+                &self.numa_nodes(),
             ));
         }
 
@@ -594,6 +749,7 @@ impl Vm {
                     cmdline_cstring.to_bytes().len() + 1,
                     &initramfs_config,
                     boot_vcpus,
+                    topology,
                     Some(hdr),
                     rsdp_addr,
                     BootProtocol::LinuxBoot,
@@ -608,6 +764,7 @@ impl Vm {
                     cmdline_cstring.to_bytes().len() + 1,
                     &initramfs_config,
                     boot_vcpus,
+                    topology,
                     None,
                     rsdp_addr,
                     entry_addr.protocol,
@@ -623,6 +780,7 @@ impl Vm {
     fn configure_system(&mut self, _entry_addr: EntryPoint) -> Result<()> {
         let cmdline_cstring = self.get_cmdline()?;
         let vcpu_mpidrs = self.cpu_manager.lock().unwrap().get_mpidrs();
+        let vcpu_topology = self.cpu_manager.lock().unwrap().get_vcpu_topology();
         let guest_memory = self.memory_manager.lock().as_ref().unwrap().guest_memory();
         let mem = guest_memory.memory();
         let initramfs_config = match self.initramfs {
@@ -668,6 +826,8 @@ impl Vm {
             &cmdline_cstring,
             self.cpu_manager.lock().unwrap().boot_vcpus() as u64,
             vcpu_mpidrs,
+            vcpu_topology,
+            &self.numa_nodes(),
             device_info,
             &initramfs_config,
             &pci_space,
@@ -703,6 +863,12 @@ impl Vm {
             signals.close();
         }
 
+        // Detach any debugger, the stub resuming the vCPUs it stopped.
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.gdb_server = None;
+        }
+
         // Wake up the DeviceManager threads so they will get terminated cleanly
         self.device_manager
             .lock()
@@ -716,6 +882,10 @@ impl Vm {
             .shutdown()
             .map_err(Error::CpuManager)?;
 
+        // The guest can't make any more requests, the disks are flushed for
+        // the writes it completed to be durable.
+        self.device_manager.lock().unwrap().shutdown();
+
         // Wait for all the threads to finish
         for thread in self.threads.drain(..) {
             thread.join().map_err(Error::ThreadCleanup)?
@@ -796,6 +966,40 @@ impl Vm {
         Ok(())
     }
 
+    pub fn resize_zone(&mut self, id: String, desired_memory: u64) -> Result<()> {
+        let new_region = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .resize_zone(&id, desired_memory)
+            .map_err(Error::MemoryManager)?;
+
+        if let Some(new_region) = &new_region {
+            self.device_manager
+                .lock()
+                .unwrap()
+                .update_memory(&new_region)
+                .map_err(Error::DeviceManager)?;
+        }
+
+        // Keep track of the zone size so that the same amount of memory is
+        // plugged if the VM reboots.
+        if let Some(zone) = self
+            .config
+            .lock()
+            .unwrap()
+            .memory
+            .zones
+            .iter_mut()
+            .flatten()
+            .find(|zone| zone.id == id)
+        {
+            zone.hotplugged_size = Some(desired_memory);
+        }
+
+        Ok(())
+    }
+
     #[cfg(not(feature = "pci_support"))]
     pub fn add_device(&mut self, mut _device_cfg: DeviceConfig) -> Result<PciDeviceInfo> {
         Err(Error::NoPciSupport)
@@ -830,7 +1034,7 @@ impl Vm {
         Ok(pci_device_info)
     }
 
-    pub fn remove_device(&mut self, _id: String) -> Result<()> {
+    pub fn remove_device(&mut self, _id: String, _timeout_ms: Option<u64>) -> Result<()> {
         if cfg!(feature = "pci_support") {
             #[cfg(feature = "pci_support")]
             {
@@ -840,6 +1044,26 @@ impl Vm {
                     .remove_device(_id.clone())
                     .map_err(Error::DeviceManager)?;
 
+                self.device_manager
+                    .lock()
+                    .unwrap()
+                    .notify_hotplug(HotPlugNotificationFlags::PCI_DEVICES_CHANGED)
+                    .map_err(Error::DeviceManager)?;
+
+                // The guest is only waited for when asked to. A device it
+                // didn't eject in time stays attached, as if its removal had
+                // never been requested.
+                #[cfg(feature = "acpi")]
+                {
+                    if let Err(e) = self.wait_for_device_eject(&_id, _timeout_ms.unwrap_or(0)) {
+                        self.device_manager
+                            .lock()
+                            .unwrap()
+                            .cancel_device_removal(&_id);
+                        return Err(e);
+                    }
+                }
+
                 // Update VmConfig by removing the device. This is important to
                 // ensure the device would not be created in case of a reboot.
                 {
@@ -872,17 +1096,45 @@ impl Vm {
                         }
                     }
                 }
+            }
+            Ok(())
+        } else {
+            Err(Error::NoPciSupport)
+        }
+    }
 
+    // The host resources of the device are only released once the guest
+    // ejected it, the device manager lock must not be held meanwhile as the
+    // eject comes from a vCPU thread.
+    #[cfg(all(feature = "pci_support", feature = "acpi"))]
+    fn wait_for_device_eject(&self, id: &str, timeout_ms: u64) -> Result<()> {
+        use std::time::{Duration, Instant};
+
+        const POLL_INTERVAL_MS: u64 = 10;
+        const RETRIGGER_INTERVAL_MS: u64 = 500;
+
+        if timeout_ms == 0 {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        let mut retriggered = Instant::now();
+        while self.device_manager.lock().unwrap().is_device_present(id) {
+            if start.elapsed() >= Duration::from_millis(timeout_ms) {
+                return Err(Error::DeviceEjectTimeout(id.to_owned()));
+            }
+            if retriggered.elapsed() >= Duration::from_millis(RETRIGGER_INTERVAL_MS) {
                 self.device_manager
                     .lock()
                     .unwrap()
-                    .notify_hotplug(HotPlugNotificationFlags::PCI_DEVICES_CHANGED)
+                    .retrigger_hotplug()
                     .map_err(Error::DeviceManager)?;
+                retriggered = Instant::now();
             }
-            Ok(())
-        } else {
-            Err(Error::NoPciSupport)
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
         }
+
+        Ok(())
     }
 
     #[cfg(not(feature = "pci_support"))]
@@ -1055,10 +1307,177 @@ impl Vm {
         Ok(pci_device_info)
     }
 
+    pub fn add_console_port(
+        &mut self,
+        mut port_cfg: ConsolePortConfig,
+    ) -> Result<ConsolePortConfig> {
+        // Check the new port against the existing ones before creating it.
+        {
+            let mut config = self.config.lock().unwrap().clone();
+            config
+                .console_ports
+                .get_or_insert_with(Vec::new)
+                .push(port_cfg.clone());
+            config
+                .validate_console_ports()
+                .map_err(Error::ConfigValidation)?;
+        }
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .add_console_port(&mut port_cfg)
+            .map_err(Error::DeviceManager)?;
+
+        // Update VmConfig by adding the new port. This is important to
+        // ensure the port would be created in case of a reboot.
+        {
+            let mut config = self.config.lock().unwrap();
+            config
+                .console_ports
+                .get_or_insert_with(Vec::new)
+                .push(port_cfg.clone());
+        }
+
+        Ok(port_cfg)
+    }
+
     pub fn counters(&self) -> Result<HashMap<String, HashMap<&'static str, Wrapping<u64>>>> {
         Ok(self.device_manager.lock().unwrap().counters())
     }
 
+    pub fn balloon_stats(&self) -> Result<virtio_devices::BalloonStats> {
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .balloon_stats()
+            .ok_or(Error::NoBalloon)
+    }
+
+    /// Save the content of the GPU scanout as a PNG image.
+    pub fn screenshot(&self, path: &Path) -> Result<()> {
+        let framebuffer = self
+            .device_manager
+            .lock()
+            .unwrap()
+            .gpu_framebuffer()
+            .cloned()
+            .ok_or(Error::NoGpu)?;
+
+        let file = File::create(path).map_err(Error::Screenshot)?;
+        let mut writer = io::BufWriter::new(file);
+        framebuffer
+            .lock()
+            .unwrap()
+            .write_png(&mut writer)
+            .map_err(Error::Screenshot)?;
+        writer.flush().map_err(Error::Screenshot)
+    }
+
+    /// Send the file descriptors of the memory zones backed by a memfd to
+    /// the process listening on the UNIX socket at `path`. They come along
+    /// with the JSON description of the zones, in the same order.
+    pub fn send_memory_fds(&self, path: &Path) -> Result<()> {
+        let (zones, fds): (Vec<_>, Vec<_>) = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .memfd_zones()
+            .into_iter()
+            .unzip();
+        if zones.is_empty() {
+            return Err(Error::NoMemfdZone);
+        }
+
+        let message = serde_json::to_vec(&zones).map_err(Error::SerializeJson)?;
+        let socket = UnixStream::connect(path).map_err(Error::SendMemoryFds)?;
+        socket
+            .send_with_fds(&[&message[..]], &fds)
+            .map_err(|e| Error::SendMemoryFds(io::Error::from_raw_os_error(e.errno())))?;
+
+        Ok(())
+    }
+
+    pub fn memory_zone_hints(&self) -> Vec<MemoryZoneHints> {
+        self.memory_manager.lock().unwrap().memory_zone_hints()
+    }
+
+    pub fn watchdog_info(&self) -> Option<virtio_devices::WatchdogInfo> {
+        self.device_manager.lock().unwrap().watchdog_info()
+    }
+
+    pub fn guest_panic_info(&self) -> Option<devices::legacy::PvpanicInfo> {
+        self.device_manager.lock().unwrap().pvpanic_info()
+    }
+
+    pub fn clear_guest_panic(&self) {
+        self.device_manager.lock().unwrap().clear_pvpanic()
+    }
+
+    pub fn balloon_info(&self) -> Option<virtio_devices::BalloonInfo> {
+        self.memory_manager.lock().unwrap().balloon_info()
+    }
+
+    pub fn vsock_info(&self) -> Result<virtio_devices::VsockInfo> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .vsock_info()
+            .ok_or(Error::NoVsock)
+    }
+
+    pub fn net_link(
+        &self,
+        id: &str,
+        link_up: Option<bool>,
+    ) -> Result<virtio_devices::NetLinkState> {
+        let device_manager = self.device_manager.lock().unwrap();
+        if let Some(link_up) = link_up {
+            device_manager.set_net_link_up(id, link_up)
+        } else {
+            device_manager.net_link_state(id)
+        }
+        .map_err(Error::DeviceManager)
+    }
+
+    pub fn net_mac(&self, id: &str, mac: Option<MacAddr>) -> Result<virtio_devices::NetMacState> {
+        let device_manager = self.device_manager.lock().unwrap();
+        if let Some(mac) = mac {
+            device_manager.set_net_mac(id, mac)
+        } else {
+            device_manager.net_mac_state(id)
+        }
+        .map_err(Error::DeviceManager)
+    }
+
+    pub fn net_queues(
+        &self,
+        id: &str,
+        queue_pairs: Option<u16>,
+    ) -> Result<virtio_devices::NetQueuesState> {
+        let device_manager = self.device_manager.lock().unwrap();
+        if let Some(queue_pairs) = queue_pairs {
+            device_manager.set_net_queue_pairs(id, queue_pairs)
+        } else {
+            device_manager.net_queues_state(id)
+        }
+        .map_err(Error::DeviceManager)
+    }
+
+    pub fn net_flow_rules(
+        &self,
+        id: &str,
+        rules: Option<Vec<virtio_devices::FlowRule>>,
+    ) -> Result<Vec<virtio_devices::FlowRule>> {
+        let device_manager = self.device_manager.lock().unwrap();
+        if let Some(rules) = rules {
+            device_manager.set_net_flow_rules(id, rules)
+        } else {
+            device_manager.net_flow_rules(id)
+        }
+        .map_err(Error::DeviceManager)
+    }
+
     fn os_signal_handler(signals: Signals, console_input_clone: Arc<Console>, on_tty: bool) {
         for signal in signals.forever() {
             match signal {
@@ -1100,12 +1519,31 @@ impl Vm {
 
         self.configure_system(entry_point)?;
 
+        // Memory zones with some memory plugged from boot are added to the
+        // guest memory only now, so that they are not described as boot RAM.
+        let regions = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .insert_hotplugged_zones()
+            .map_err(Error::MemoryManager)?;
+        for region in regions.iter() {
+            self.device_manager
+                .lock()
+                .unwrap()
+                .update_memory(region)
+                .map_err(Error::DeviceManager)?;
+        }
+
         self.cpu_manager
             .lock()
             .unwrap()
             .start_boot_vcpus()
             .map_err(Error::CpuManager)?;
 
+        #[cfg(target_arch = "x86_64")]
+        self.start_gdb_server()?;
+
         if self
             .device_manager
             .lock()
@@ -1144,6 +1582,34 @@ impl Vm {
         Ok(())
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn start_gdb_server(&mut self) -> Result<()> {
+        let gdb = match self.config.lock().unwrap().gdb.clone() {
+            Some(gdb) => gdb,
+            None => return Ok(()),
+        };
+        let endpoint = match (gdb.socket, gdb.tcp) {
+            (Some(path), _) => Endpoint::Unix(path),
+            (None, Some(addr)) => Endpoint::Tcp(addr),
+            (None, None) => return Ok(()),
+        };
+
+        let debug_evt = self
+            .cpu_manager
+            .lock()
+            .unwrap()
+            .debug_evt()
+            .map_err(Error::GdbDebugEventFd)?;
+        let target = gdb::VmTarget::new(
+            self.cpu_manager.clone(),
+            self.memory_manager.lock().unwrap().guest_memory(),
+        );
+        self.gdb_server =
+            Some(gdb::GdbServer::new(endpoint, target, debug_evt).map_err(Error::GdbServer)?);
+
+        Ok(())
+    }
+
     pub fn handle_stdin(&self) -> Result<()> {
         let mut out = [0u8; 64];
         let count = io::stdin()
@@ -1183,6 +1649,61 @@ impl Vm {
     }
 }
 
+// Pausing the VM pauses the vCPUs first, then saves the guest clock, so that
+// the guest doesn't see any time passing between the two, and pauses the
+// devices last.
+fn pause_in_order(
+    pause_vcpus: impl FnOnce() -> std::result::Result<(), MigratableError>,
+    save_clock: impl FnOnce() -> std::result::Result<(), MigratableError>,
+    pause_devices: impl FnOnce() -> std::result::Result<(), MigratableError>,
+) -> std::result::Result<(), MigratableError> {
+    pause_vcpus()?;
+    save_clock()?;
+    pause_devices()
+}
+
+// Resuming the VM sets the guest clock back and resumes the devices before
+// any vCPU runs, so that the guest doesn't see its clock jump nor its timers
+// expire before the devices can handle them.
+fn resume_in_order(
+    restore_clock: impl FnOnce() -> std::result::Result<(), MigratableError>,
+    resume_devices: impl FnOnce() -> std::result::Result<(), MigratableError>,
+    resume_vcpus: impl FnOnce() -> std::result::Result<(), MigratableError>,
+) -> std::result::Result<(), MigratableError> {
+    restore_clock()?;
+    resume_devices()?;
+    resume_vcpus()
+}
+
+// The guest clock as saved when pausing the VM.
+#[cfg(target_arch = "x86_64")]
+fn save_clock(
+    vm: &dyn hypervisor::Vm,
+) -> std::result::Result<hypervisor::ClockData, MigratableError> {
+    let mut clock = vm
+        .get_clock()
+        .map_err(|e| MigratableError::Pause(anyhow!("Could not get VM clock: {}", e)))?;
+    // The flags report whether the clock is stable across the host CPUs,
+    // and are not accepted back by KVM.
+    clock.flags = 0;
+    Ok(clock)
+}
+
+// Set the guest clock back to where it was when the VM got paused, if it
+// was saved.
+#[cfg(target_arch = "x86_64")]
+fn restore_clock(
+    vm: &dyn hypervisor::Vm,
+    clock: Option<&hypervisor::ClockData>,
+) -> std::result::Result<(), MigratableError> {
+    if let Some(clock) = clock {
+        vm.set_clock(clock)
+            .map_err(|e| MigratableError::Resume(anyhow!("Could not set VM clock: {}", e)))?;
+        debug!("Guest clock set back to {} ns", clock.clock);
+    }
+    Ok(())
+}
+
 impl Pausable for Vm {
     fn pause(&mut self) -> std::result::Result<(), MigratableError> {
         let mut state = self
@@ -1195,18 +1716,29 @@ impl Pausable for Vm {
             .valid_transition(new_state)
             .map_err(|e| MigratableError::Pause(anyhow!("Invalid transition: {:?}", e)))?;
 
+        // The guest has to be running to move its traffic off the VFs.
+        #[cfg(all(feature = "pci_support", feature = "kvm"))]
+        self.device_manager
+            .lock()
+            .unwrap()
+            .failover(true)
+            .map_err(|e| MigratableError::Pause(anyhow!("Could not fail over: {:?}", e)))?;
+
+        let cpu_manager = &self.cpu_manager;
+        let device_manager = &self.device_manager;
         #[cfg(target_arch = "x86_64")]
-        {
-            let mut clock = self
-                .vm
-                .get_clock()
-                .map_err(|e| MigratableError::Pause(anyhow!("Could not get VM clock: {}", e)))?;
-            // Reset clock flags.
-            clock.flags = 0;
-            self.saved_clock = Some(clock);
-        }
-        self.cpu_manager.lock().unwrap().pause()?;
-        self.device_manager.lock().unwrap().pause()?;
+        let (vm, saved_clock) = (&self.vm, &mut self.saved_clock);
+        pause_in_order(
+            || cpu_manager.lock().unwrap().pause(),
+            || {
+                #[cfg(target_arch = "x86_64")]
+                {
+                    *saved_clock = Some(save_clock(vm.as_ref())?);
+                }
+                Ok(())
+            },
+            || device_manager.lock().unwrap().pause(),
+        )?;
 
         *state = new_state;
 
@@ -1224,16 +1756,22 @@ impl Pausable for Vm {
             .valid_transition(new_state)
             .map_err(|e| MigratableError::Resume(anyhow!("Invalid transition: {:?}", e)))?;
 
-        self.cpu_manager.lock().unwrap().resume()?;
-        #[cfg(target_arch = "x86_64")]
-        {
-            if let Some(clock) = &self.saved_clock {
-                self.vm.set_clock(clock).map_err(|e| {
-                    MigratableError::Resume(anyhow!("Could not set VM clock: {}", e))
-                })?;
-            }
-        }
-        self.device_manager.lock().unwrap().resume()?;
+        resume_in_order(
+            || {
+                #[cfg(target_arch = "x86_64")]
+                restore_clock(self.vm.as_ref(), self.saved_clock.as_ref())?;
+                Ok(())
+            },
+            || self.device_manager.lock().unwrap().resume(),
+            || self.cpu_manager.lock().unwrap().resume(),
+        )?;
+
+        #[cfg(all(feature = "pci_support", feature = "kvm"))]
+        self.device_manager
+            .lock()
+            .unwrap()
+            .failover(false)
+            .map_err(|e| MigratableError::Resume(anyhow!("Could not fail back: {:?}", e)))?;
 
         // And we're back to the Running state.
         *state = new_state;
@@ -1247,6 +1785,21 @@ pub struct VmSnapshot {
     pub config: Arc<Mutex<VmConfig>>,
     #[cfg(target_arch = "x86_64")]
     pub clock: Option<hypervisor::ClockData>,
+    /// Known CPU features exposed to the guest, which the host restoring
+    /// the VM must support.
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub cpu_features: Option<Vec<String>>,
+    /// CPUID leaves holding the feature bits exposed to the guest, named or
+    /// not, compared bit by bit with the ones of the host restoring the VM.
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub cpuid_features: Option<Vec<crate::cpu::CpuidFeatureLeaf>>,
+    /// Format of the state of the nested guests saved along with the vCPUs,
+    /// which the host restoring the VM must support.
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub nested_state_format: Option<hypervisor::arch::x86::NestedStateFormat>,
 }
 
 pub const VM_SNAPSHOT_ID: &str = "vm";
@@ -1264,20 +1817,10 @@ impl Snapshottable for Vm {
         }
 
         let mut vm_snapshot = Snapshot::new(VM_SNAPSHOT_ID);
-        let vm_snapshot_data = serde_json::to_vec(&VmSnapshot {
-            config: self.get_config(),
-            #[cfg(target_arch = "x86_64")]
-            clock: self.saved_clock,
-        })
-        .map_err(|e| MigratableError::Snapshot(e.into()))?;
-
         vm_snapshot.add_snapshot(self.cpu_manager.lock().unwrap().snapshot()?);
         vm_snapshot.add_snapshot(self.memory_manager.lock().unwrap().snapshot()?);
         vm_snapshot.add_snapshot(self.device_manager.lock().unwrap().snapshot()?);
-        vm_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", VM_SNAPSHOT_ID),
-            snapshot: vm_snapshot_data,
-        });
+        vm_snapshot.add_data_section(self.snapshot_data_section()?);
 
         Ok(vm_snapshot)
     }
@@ -1378,30 +1921,30 @@ impl Transportable for Vm {
         snapshot: &Snapshot,
         destination_url: &str,
     ) -> std::result::Result<(), MigratableError> {
+        // The snapshot is followed by the guest memory on the same stream,
+        // received by the destination as soon as it created the VM.
+        if let Some(address) = tcp_url_address(destination_url, None)? {
+            let mut stream =
+                TcpStream::connect(&address).map_err(|e| MigratableError::MigrateSend(e.into()))?;
+            send_vm_snapshot(snapshot, &mut stream)?;
+
+            let memory_manager = self.memory_manager.lock().unwrap();
+            let compression = request_compression(&mut stream, Compression::None)?;
+            memory_manager.send_memory_regions(
+                &memory_manager.memory_range_table(),
+                &mut stream,
+                compression,
+            )?;
+            return MemoryRangeTable::default().write_to(&mut stream);
+        }
+
         let url = Url::parse(destination_url).map_err(|e| {
             MigratableError::MigrateSend(anyhow!("Could not parse destination URL: {}", e))
         })?;
 
         match url.scheme() {
             "file" => {
-                let mut vm_snapshot_path = url_to_path(&url)?;
-                vm_snapshot_path.push(VM_SNAPSHOT_FILE);
-
-                // Create the snapshot file
-                let mut vm_snapshot_file = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create_new(true)
-                    .open(vm_snapshot_path)
-                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
-
-                // Serialize and write the snapshot
-                let vm_snapshot = serde_json::to_vec(snapshot)
-                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
-
-                vm_snapshot_file
-                    .write(&vm_snapshot)
-                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                write_vm_snapshot(snapshot, &url_to_path(&url)?)?;
 
                 // Tell the memory manager to also send/write its own snapshot.
                 if let Some(memory_manager_snapshot) =
@@ -1429,10 +1972,350 @@ impl Transportable for Vm {
 }
 impl Migratable for Vm {}
 
+impl Vm {
+    /// Write `snapshot` to `destination_url`, the guest memory being
+    /// written as described by `options` when the destination is a
+    /// directory.
+    pub fn send_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        destination_url: &str,
+        options: &WriteOptions,
+    ) -> std::result::Result<(), MigratableError> {
+        if tcp_url_address(destination_url, None)?.is_some() {
+            if options.compression_level.is_some() {
+                return Err(MigratableError::MigrateSend(anyhow!(
+                    "Compressed snapshots can only be written to a directory"
+                )));
+            }
+            return self.send(snapshot, destination_url);
+        }
+        let url = Url::parse(destination_url).map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Could not parse destination URL: {}", e))
+        })?;
+        let path = url_to_path(&url)?;
+
+        write_vm_snapshot(snapshot, &path)?;
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .send_full(&path, options)
+    }
+
+    /// Write `snapshot` to the directory `destination_url` as part of an
+    /// incremental chain, the guest memory only holding what changed since
+    /// the previous snapshot of the chain, if any.
+    pub fn send_incremental(
+        &self,
+        snapshot: &Snapshot,
+        destination_url: &str,
+        options: &WriteOptions,
+    ) -> std::result::Result<(), MigratableError> {
+        if tcp_url_address(destination_url, None)?.is_some() {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Incremental snapshots can only be written to a directory"
+            )));
+        }
+        let url = Url::parse(destination_url).map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Could not parse destination URL: {}", e))
+        })?;
+        let path = url_to_path(&url)?;
+
+        write_vm_snapshot(snapshot, &path)?;
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .send_incremental(&path, options)
+    }
+
+    fn snapshot_data_section(&self) -> std::result::Result<SnapshotDataSection, MigratableError> {
+        let vm_snapshot_data = serde_json::to_vec(&VmSnapshot {
+            config: self.get_config(),
+            #[cfg(target_arch = "x86_64")]
+            clock: self.saved_clock,
+            #[cfg(target_arch = "x86_64")]
+            cpu_features: Some(self.cpu_manager.lock().unwrap().cpu_features()),
+            #[cfg(target_arch = "x86_64")]
+            cpuid_features: Some(self.cpu_manager.lock().unwrap().cpuid_feature_leaves()),
+            #[cfg(target_arch = "x86_64")]
+            nested_state_format: self.cpu_manager.lock().unwrap().nested_state_format(),
+        })
+        .map_err(|e| MigratableError::Snapshot(e.into()))?;
+
+        Ok(SnapshotDataSection {
+            id: format!("{}-section", VM_SNAPSHOT_ID),
+            snapshot: vm_snapshot_data,
+        })
+    }
+
+    // Snapshot of what the destination of a live migration needs to create
+    // the VM, its configuration and memory layout, taken while the guest
+    // keeps running.
+    fn migration_snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        let mut vm_snapshot = Snapshot::new(VM_SNAPSHOT_ID);
+        vm_snapshot.add_snapshot(self.memory_manager.lock().unwrap().snapshot()?);
+        vm_snapshot.add_data_section(self.snapshot_data_section()?);
+
+        Ok(vm_snapshot)
+    }
+
+    /// Measure the rate the guest dirties its memory at, from the pages its
+    /// vCPUs and virtio devices write while they are logged for `sample`.
+    pub fn dirty_rate(&self, sample: Duration) -> Result<DirtyRate> {
+        if self.get_state()? != VmState::Running {
+            return Err(Error::VmNotRunning);
+        }
+
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .start_dirty_log()
+            .map_err(Error::DirtyRate)?;
+        let started = Instant::now();
+        thread::sleep(sample);
+
+        let mut memory_manager = self.memory_manager.lock().unwrap();
+        let dirty = memory_manager.dirty_log();
+        let elapsed = started.elapsed();
+        memory_manager.stop_dirty_log().map_err(Error::DirtyRate)?;
+        let dirty_pages = dirty.map_err(Error::DirtyRate)?.effective_size() / DIRTY_LOG_PAGE_SIZE;
+
+        Ok(DirtyRate {
+            dirty_pages,
+            sample_ms: elapsed.as_millis() as u64,
+            dirty_rate: pages_per_second(dirty_pages, elapsed),
+        })
+    }
+
+    /// Live migrate the VM to the destination at the other end of `stream`.
+    ///
+    /// The guest memory is sent while the VM keeps running, then the VM is
+    /// paused and its state sent. The pre-copy rounds go on until the pages
+    /// dirtied by the guest are few enough to be sent while it is paused,
+    /// as set by `precopy`, which may let them throttle the vCPUs. With
+    /// `postcopy`, the VM is paused right after the memory was sent
+    /// once, and the guest runs on the destination before the pages it
+    /// dirtied in the meantime are sent, the ones it faults on first. The
+    /// VM is left paused once the destination has got everything. The
+    /// phases of the migration are reported through `progress`.
+    pub fn send_migration(
+        &mut self,
+        mut stream: MigrationStream,
+        postcopy: bool,
+        precopy: &PrecopyConfig,
+        progress: &MigrationProgress,
+    ) -> std::result::Result<(), MigratableError> {
+        migration::check_config(&self.config.lock().unwrap())?;
+
+        // Fail before anything was sent if the stream can't be shared
+        // with the thread receiving the page requests.
+        let requests = if postcopy {
+            Some(
+                stream
+                    .try_clone()
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?,
+            )
+        } else {
+            None
+        };
+
+        send_vm_snapshot(&self.migration_snapshot()?, &mut stream)?;
+        let compression = request_compression(&mut stream, Compression::None)?;
+
+        let memory_manager = self.memory_manager.clone();
+        let pending = {
+            let mut memory_manager = memory_manager.lock().unwrap();
+            let table = memory_manager.memory_range_table();
+            let send = |memory_manager: &MemoryManager, table: &MemoryRangeTable| {
+                // An empty table would end the memory stream early.
+                if table.is_empty() {
+                    return Ok(());
+                }
+                memory_manager.send_memory_regions(table, &mut stream, compression)
+            };
+
+            if postcopy {
+                send_memory_postcopy(&mut *memory_manager, table, progress, send, || self.pause())?
+            } else {
+                let cpu_manager = self.cpu_manager.clone();
+                send_memory_precopy(
+                    &mut *memory_manager,
+                    table,
+                    precopy,
+                    progress,
+                    send,
+                    || self.pause(),
+                    |percent| {
+                        cpu_manager
+                            .lock()
+                            .unwrap()
+                            .set_throttle(percent)
+                            .map_err(|e| {
+                                MigratableError::MigrateSend(anyhow!(
+                                    "Error throttling the vCPUs: {:?}",
+                                    e
+                                ))
+                            })
+                    },
+                )?;
+                MemoryRangeTable::default()
+            }
+        };
+        MemoryRangeTable::default().write_to(&mut stream)?;
+
+        // The state of the paused VM, then the pages it dirtied since they
+        // were sent, to be sent once the guest runs on the destination.
+        progress.set_phase(MigrationPhase::DeviceState);
+        send_vm_snapshot(&self.snapshot()?, &mut stream)?;
+        if pending.is_empty() {
+            return pending.write_to(&mut stream);
+        }
+        // The destination may resume the guest as soon as it got the
+        // pending pages, the migration can't be aborted from then on.
+        progress.set_phase(MigrationPhase::Postcopy);
+        pending.write_to(&mut stream)?;
+
+        info!(
+            "Resuming the guest on the destination before sending {} bytes",
+            pending.effective_size()
+        );
+        let mut requests = requests.unwrap();
+        // The destination only requests pages when the guest faults on
+        // them, possibly long after the previous ones.
+        requests
+            .clear_read_timeout()
+            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        progress.start_round(pending.effective_size());
+        let memory_manager = memory_manager.lock().unwrap();
+        send_postcopy_pages(&pending, DIRTY_LOG_PAGE_SIZE, requests, |table| {
+            memory_manager.send_memory_regions(table, &mut stream, compression)
+        })
+    }
+
+    /// Hand the VM over to another VMM process of the same host, the guest
+    /// memory, the tap interfaces and the disk images being passed along as
+    /// file descriptors rather than copied. The VM stays paused once the
+    /// destination let it go.
+    pub fn send_handoff(
+        &mut self,
+        mut stream: MigrationStream,
+        progress: &MigrationProgress,
+    ) -> std::result::Result<(), MigratableError> {
+        handoff::check_config(&self.config.lock().unwrap())?;
+        let memory = self.memory_manager.lock().unwrap().handoff_fds()?;
+
+        progress.set_phase(MigrationPhase::DeviceState);
+        self.pause()?;
+        let (taps, disks) = {
+            let device_manager = self.device_manager.lock().unwrap();
+            device_manager.flush_devices().map_err(|e| {
+                MigratableError::MigrateSend(anyhow!("Error flushing the devices: {:?}", e))
+            })?;
+            device_manager.handoff_fds()
+        };
+
+        let snapshot = self.snapshot()?;
+        HandoffFds {
+            memory,
+            taps,
+            disks,
+        }
+        .send(&mut stream)?;
+        send_vm_snapshot(&snapshot, &mut stream)?;
+        handoff::release(&mut stream)
+    }
+
+    /// Take the tap interfaces and disk images handed over by another
+    /// process, for the devices restored from then on.
+    pub fn take_over_devices(
+        &self,
+        taps: Vec<(String, Vec<File>)>,
+        disks: Vec<(String, File)>,
+    ) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .take_over(taps, disks)
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Receive a live migration sent by `send_migration()` through
+    /// `stream`, the VM having been created from the snapshot which came
+    /// first. The snapshot of the paused VM is returned to restore it from.
+    /// When the post-copy phase follows, the guest memory is registered with
+    /// a userfaultfd, and the pages left on the source are received in the
+    /// background from then on, that phase being reported through
+    /// `progress` until they all have been.
+    pub fn receive_migration(
+        &mut self,
+        mut stream: MigrationStream,
+        progress: &MigrationProgress,
+    ) -> std::result::Result<Snapshot, MigratableError> {
+        let compression =
+            self.receive_memory(&mut stream, &[Compression::None, Compression::Lz4])?;
+        progress.set_phase(MigrationPhase::DeviceState);
+        let vm_snapshot = read_vm_snapshot(&mut stream)?;
+
+        // The clock was saved once the VM got paused on the source, after
+        // the snapshot the VM was created from had been taken.
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.saved_clock = get_vm_snapshot(&vm_snapshot)?.clock;
+        }
+
+        let pending = MemoryRangeTable::read_from(&mut stream)?;
+        if !pending.is_empty() {
+            info!(
+                "Resuming the guest before receiving {} bytes",
+                pending.effective_size()
+            );
+            // The pages arrive as the guest faults on them, or as the source
+            // sends the remaining ones.
+            stream
+                .clear_read_timeout()
+                .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+            let requests = stream
+                .try_clone()
+                .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+            progress.set_phase(MigrationPhase::Postcopy);
+            progress.start_round(pending.effective_size());
+            self.memory_manager.lock().unwrap().start_postcopy(
+                &pending,
+                stream,
+                requests,
+                compression,
+            )?;
+        }
+
+        Ok(vm_snapshot)
+    }
+
+    /// Receive the guest memory sent by `send_migration()`, or along with a
+    /// snapshot sent to a TCP URL, each round overwriting the pages sent by
+    /// the previous ones. The pages can be compressed with any of the
+    /// `supported` codecs, the one agreed on being returned.
+    pub fn receive_memory<S: Read + Write>(
+        &self,
+        fd: &mut S,
+        supported: &[Compression],
+    ) -> std::result::Result<Compression, MigratableError> {
+        let compression = accept_compression(fd, supported)?;
+        let memory_manager = self.memory_manager.lock().unwrap();
+        while !memory_manager
+            .receive_memory_regions(fd, compression)?
+            .is_empty()
+        {}
+
+        Ok(compression)
+    }
+}
+
 #[cfg(target_arch = "x86_64")]
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::MockVm;
+    use std::cell::RefCell;
 
     fn test_vm_state_transitions(state: VmState) {
         match state {
@@ -1486,6 +2369,110 @@ mod tests {
     fn test_vm_paused_transitions() {
         test_vm_state_transitions(VmState::Paused);
     }
+
+    #[test]
+    fn test_vm_pause_resume_order() {
+        let steps = RefCell::new(Vec::new());
+        let step = |name: &'static str| {
+            let steps = &steps;
+            move || {
+                steps.borrow_mut().push(name);
+                Ok(())
+            }
+        };
+
+        pause_in_order(
+            step("pause vcpus"),
+            step("save clock"),
+            step("pause devices"),
+        )
+        .unwrap();
+        resume_in_order(
+            step("restore clock"),
+            step("resume devices"),
+            step("resume vcpus"),
+        )
+        .unwrap();
+        assert_eq!(
+            *steps.borrow(),
+            vec![
+                "pause vcpus",
+                "save clock",
+                "pause devices",
+                "restore clock",
+                "resume devices",
+                "resume vcpus",
+            ]
+        );
+
+        // The vCPUs don't run again if the clock can't be set back.
+        steps.borrow_mut().clear();
+        assert!(resume_in_order(
+            || Err(MigratableError::Resume(anyhow!("Could not set VM clock"))),
+            step("resume devices"),
+            step("resume vcpus"),
+        )
+        .is_err());
+        assert!(steps.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_vm_resume_set_clock() {
+        let mut clock = hypervisor::ClockData::default();
+        clock.clock = 42_000_000_000;
+        // KVM_CLOCK_TSC_STABLE
+        clock.flags = 2;
+        let vm = MockVm {
+            clock,
+            ..Default::default()
+        };
+        let step = |name: &'static str| {
+            let calls = &vm.calls;
+            move || {
+                calls.lock().unwrap().push(name);
+                Ok(())
+            }
+        };
+
+        // The clock is set back to the value it had once the vCPUs were
+        // paused, before they run again.
+        let mut saved_clock = None;
+        pause_in_order(
+            step("pause vcpus"),
+            || {
+                saved_clock = Some(save_clock(&vm)?);
+                Ok(())
+            },
+            step("pause devices"),
+        )
+        .unwrap();
+        resume_in_order(
+            || restore_clock(&vm, saved_clock.as_ref()),
+            step("resume devices"),
+            step("resume vcpus"),
+        )
+        .unwrap();
+        assert_eq!(
+            *vm.calls.lock().unwrap(),
+            vec![
+                "pause vcpus",
+                "get_clock",
+                "pause devices",
+                "set_clock",
+                "resume devices",
+                "resume vcpus",
+            ]
+        );
+        let set_clocks = vm.set_clocks.lock().unwrap();
+        assert_eq!(set_clocks.len(), 1);
+        assert_eq!(set_clocks[0].clock, 42_000_000_000);
+        assert_eq!(set_clocks[0].flags, 0);
+        drop(set_clocks);
+
+        // Nothing is set back when no clock was saved.
+        restore_clock(&vm, None).unwrap();
+        assert_eq!(vm.set_clocks.lock().unwrap().len(), 1);
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -1558,6 +2545,8 @@ mod tests {
             &mem,
             &CString::new("console=tty0").unwrap(),
             vec![0],
+            Some((1, 1, 1)),
+            &arch::NumaNodes::new(),
             &dev_info,
             &gic,
             &None,
@@ -1597,6 +2586,7 @@ pub fn test_vm() {
             region.len() as u64,
             region.as_ptr() as u64,
             false,
+            false,
         );
 
         vm.set_user_memory_region(mem_region)